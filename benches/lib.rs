@@ -8,4 +8,5 @@ extern crate ipnetwork;
 extern crate pnet;
 extern crate rips;
 
+mod tx;
 mod udp;
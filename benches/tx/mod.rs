@@ -0,0 +1,51 @@
+use pnet::packet::ethernet::EtherTypes;
+use pnet::util::MacAddr;
+
+use rips::ethernet::{BasicEthernetPayload, EthernetTx};
+use rips::testing;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use test::Bencher;
+
+lazy_static! {
+    static ref DST: MacAddr = MacAddr::new(0x02, 0, 0, 0, 0, 1);
+}
+
+#[bench]
+fn ethernet_tx_1byte(b: &mut Bencher) {
+    let (mut stack, interface, _, _read_handle) = testing::dummy_stack();
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(*DST);
+    b.iter(|| tx.send(1, 1, BasicEthernetPayload::new(EtherTypes::Ipv4, &[0])).unwrap());
+}
+
+/// Same as `ethernet_tx_1byte`, with another thread sending on the same
+/// interface all the time.
+#[bench]
+fn ethernet_tx_1byte_contended(b: &mut Bencher) {
+    let (mut stack, interface, _, _read_handle) = testing::dummy_stack();
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(*DST);
+    let mut other_tx = stack.interface(&interface).unwrap().ethernet_tx(*DST);
+    let stop = Arc::new(AtomicBool::new(false));
+    let other = {
+        let stop = stop.clone();
+        thread::spawn(move || while !stop.load(Ordering::SeqCst) {
+            other_tx.send(1, 1, BasicEthernetPayload::new(EtherTypes::Ipv4, &[0])).unwrap();
+        })
+    };
+    b.iter(|| tx.send(1, 1, BasicEthernetPayload::new(EtherTypes::Ipv4, &[0])).unwrap());
+    stop.store(true, Ordering::SeqCst);
+    other.join().unwrap();
+}
+
+/// Sending on a tx-object the stack invalidated, which only has to read its
+/// version.
+#[bench]
+fn ethernet_tx_outdated(b: &mut Bencher) {
+    let (mut stack, interface, _, _read_handle) = testing::dummy_stack();
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(*DST);
+    stack.interface(&interface).unwrap().set_mtu(1400).unwrap();
+    b.iter(|| tx.send(1, 1, BasicEthernetPayload::new(EtherTypes::Ipv4, &[0])).is_err());
+}
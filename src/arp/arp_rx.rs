@@ -38,7 +38,10 @@ impl ArpRx {
 
 impl EthernetListener for ArpRx {
     fn recv(&mut self, _time: SystemTime, pkg: &EthernetPacket) -> RxResult {
-        let arp_pkg = ArpPacket::new(pkg.payload()).unwrap();
+        let arp_pkg = match ArpPacket::new(pkg.payload()) {
+            Some(arp_pkg) => arp_pkg,
+            None => return Err(RxError::InvalidLength),
+        };
         // TODO: Check all other fields so they are correct.
         match arp_pkg.get_operation() {
             ArpOperations::Request => self.handle_request(&arp_pkg),
@@ -1,10 +1,13 @@
 use ethernet::EthernetListener;
+use histogram::Histogram;
 
 use pnet::util::MacAddr;
+use reactor::Waker;
 use stack::StackInterfaceMsg;
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use std::sync::mpsc::{self, Receiver, Sender};
 
@@ -12,12 +15,23 @@ mod arp_rx;
 mod arp_tx;
 
 pub use self::arp_rx::ArpRx;
-pub use self::arp_tx::{ArpBuilder, ArpRequestTx, ArpReplyTx};
+pub use self::arp_tx::{ArpRequestTx, ArpReplyTx};
+pub use proto::arp::ArpBuilder;
 
 #[derive(Default)]
 pub struct TableData {
     pub table: HashMap<Ipv4Addr, MacAddr>,
     pub listeners: HashMap<Ipv4Addr, Vec<Sender<MacAddr>>>,
+    pub wakers: HashMap<Ipv4Addr, Vec<Waker>>,
+    /// Lookups that found the address
+    pub hits: u64,
+    /// Lookups that did not
+    pub misses: u64,
+    /// When the addresses not known yet were first looked up
+    pub looked_up: HashMap<Ipv4Addr, Instant>,
+    /// How long addresses took to become known once looked up, in
+    /// microseconds
+    pub resolve_times: Histogram,
 }
 
 impl TableData {
@@ -25,6 +39,11 @@ impl TableData {
         TableData {
             table: HashMap::new(),
             listeners: HashMap::new(),
+            wakers: HashMap::new(),
+            hits: 0,
+            misses: 0,
+            looked_up: HashMap::new(),
+            resolve_times: Histogram::new(),
         }
     }
 }
@@ -63,22 +82,61 @@ impl ArpTable {
     /// until a reply has arrived
     pub fn get(&mut self, target_ip: Ipv4Addr) -> Result<MacAddr, Receiver<MacAddr>> {
         let mut data = self.data.lock().unwrap();
-        if let Some(mac) = data.table.get(&target_ip) {
-            return Ok(*mac);
+        if let Some(mac) = data.table.get(&target_ip).cloned() {
+            data.hits += 1;
+            return Ok(mac);
         }
+        data.misses += 1;
+        data.looked_up.entry(target_ip).or_insert_with(Instant::now);
         Err(Self::add_listener(&mut data, target_ip))
     }
 
+    /// Same as `get`, but instead of returning a channel, makes `waker` be
+    /// called when the MAC of `target_ip` is known. Does not send a request.
+    pub fn get_or_wake(&mut self, target_ip: Ipv4Addr, waker: Waker) -> Option<MacAddr> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(mac) = data.table.get(&target_ip).cloned() {
+            data.hits += 1;
+            return Some(mac);
+        }
+        data.misses += 1;
+        data.looked_up.entry(target_ip).or_insert_with(Instant::now);
+        data.wakers.entry(target_ip).or_insert_with(Vec::new).push(waker);
+        None
+    }
+
+    /// Returns how many lookups with `get` and `get_or_wake` found the
+    /// address, and how many did not.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        let data = self.data.lock().unwrap();
+        (data.hits, data.misses)
+    }
+
+    /// Returns how long the addresses looked up with `get` and
+    /// `get_or_wake` took to be inserted, in microseconds.
+    pub fn resolve_times(&self) -> Histogram {
+        self.data.lock().unwrap().resolve_times.clone()
+    }
+
     /// Manually insert an IP -> MAC mapping into this Arp table and notify all
     /// listeners for that IP. Will return `true` if this insertion changed the
     /// table.
     pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) -> bool {
-        let mut data = self.data.lock().expect("Unable to lock Arp::table for writing");
-        let old_mac = data.table.insert(ip, mac);
-        if let Some(listeners) = data.listeners.remove(&ip) {
-            for listener in listeners {
-                listener.send(mac).unwrap_or(());
+        let (old_mac, wakers) = {
+            let mut data = self.data.lock().expect("Unable to lock Arp::table for writing");
+            let old_mac = data.table.insert(ip, mac);
+            if let Some(looked_up) = data.looked_up.remove(&ip) {
+                data.resolve_times.record_duration(looked_up.elapsed());
+            }
+            if let Some(listeners) = data.listeners.remove(&ip) {
+                for listener in listeners {
+                    listener.send(mac).unwrap_or(());
+                }
             }
+            (old_mac, data.wakers.remove(&ip).unwrap_or_default())
+        };
+        for waker in wakers {
+            (*waker)();
         }
         old_mac.is_none() || old_mac != Some(mac)
     }
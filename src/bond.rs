@@ -0,0 +1,432 @@
+//! Active-backup link bonding.
+//!
+//! A `Bond` joins the `EthernetChannel`s of several links, its members, into
+//! one logical interface, added to a `NetworkStack` with
+//! `Bond::add_interface`. All traffic goes through the active member while
+//! the others stand by, and frames received on them are dropped. The members
+//! must receive frames to the MAC address of the bond, so their channels
+//! have to be promiscuous.
+//!
+//! When the active member loses carrier the bond fails over to the first
+//! member that has it, and sends gratuitous Arp for the addresses of the
+//! interface on it so the switches and neighbours learn the new path right
+//! away.
+//!
+//! pnet does not report carrier, so a member is considered down when sending
+//! or receiving on it fails, and up again when it receives frames again.
+//! `Bond::start_link_monitor` polls the carrier of the members on Linux, and
+//! `Bond::set_carrier` lets the application report it from elsewhere.
+
+use {EthernetChannel, Interface, NetworkStack, Payload, StackError, StackResult};
+use arp::ArpBuilder;
+use rx::ChannelReceiver;
+
+use pnet::datalink::{EthernetDataLinkReceiver, EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before receiving on a member again after it failed, in
+/// milliseconds.
+static RX_RETRY_MS: u64 = 100;
+
+/// Identifies a member of a `Bond`, in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MemberId(pub usize);
+
+/// Reads the carrier of the interface `name` from sysfs. Returns `None` if
+/// it is not known there, as on other platforms than Linux.
+pub fn sysfs_carrier(name: &str) -> Option<bool> {
+    let mut file = match File::open(format!("/sys/class/net/{}/carrier", name)) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    // Reading fails while the interface is administratively down
+    let mut carrier = String::new();
+    match file.read_to_string(&mut carrier) {
+        Ok(_) => Some(carrier.trim() == "1"),
+        Err(_) => Some(false),
+    }
+}
+
+struct Member {
+    name: String,
+    tx: Mutex<Box<EthernetDataLinkSender>>,
+}
+
+/// The carrier of each member and which one is active.
+struct Links {
+    carrier: Vec<bool>,
+    active: Option<MemberId>,
+}
+
+/// The interface of a `NetworkStack` on the bond.
+struct Local {
+    mac: MacAddr,
+    inject: Sender<Box<[u8]>>,
+    addresses: Arc<RwLock<HashSet<Ipv4Addr>>>,
+}
+
+struct BondData {
+    members: RwLock<Vec<Member>>,
+    links: Mutex<Links>,
+    local: Mutex<Option<Local>>,
+    /// Incremented whenever the link monitor is started or stopped, telling
+    /// the thread running it to stop
+    monitor_generation: AtomicUsize,
+}
+
+impl BondData {
+    /// Handles `frame`, received on `member`.
+    fn receive(&self, member: MemberId, frame: &[u8]) {
+        self.set_carrier(member, true);
+        if self.links.lock().unwrap().active != Some(member) {
+            return;
+        }
+        if let Some(ref local) = *self.local.lock().unwrap() {
+            local.inject.send(frame.to_vec().into_boxed_slice()).unwrap_or(());
+        }
+    }
+
+    /// Sends `frame` on the active member. Fails over and tries again if
+    /// sending fails.
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        loop {
+            let active = self.links.lock().unwrap().active;
+            let member = match active {
+                Some(member) => member,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                              "No bond member has carrier"))
+                }
+            };
+            match self.send_on(member, frame) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Bond: Unable to send on {}: {}", self.name(member), e);
+                    self.set_carrier(member, false);
+                }
+            }
+        }
+    }
+
+    fn send_on(&self, member: MemberId, frame: &[u8]) -> io::Result<()> {
+        let members = self.members.read().unwrap();
+        let eth_pkg = EthernetPacket::new(frame).unwrap();
+        let result = members[member.0].tx.lock().unwrap().send_to(&eth_pkg, None);
+        match result {
+            Some(result) => result,
+            None => Err(io::Error::new(io::ErrorKind::Other, "Insufficient buffer space")),
+        }
+    }
+
+    fn name(&self, member: MemberId) -> String {
+        self.members.read().unwrap()[member.0].name.clone()
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.members.read().unwrap().iter().map(|member| member.name.clone()).collect()
+    }
+
+    /// Records the carrier of `member`, and fails over if the active member
+    /// lost it or there was no active member.
+    fn set_carrier(&self, member: MemberId, carrier: bool) {
+        let switched = {
+            let mut links = self.links.lock().unwrap();
+            if links.carrier[member.0] == carrier {
+                return;
+            }
+            links.carrier[member.0] = carrier;
+            let reselect = match links.active {
+                Some(active) => active == member && !carrier,
+                None => carrier,
+            };
+            if !reselect {
+                return;
+            }
+            links.active = links.carrier.iter().position(|&carrier| carrier).map(MemberId);
+            links.active
+        };
+        match switched {
+            Some(active) => {
+                debug!("Bond: Switched over to {}", self.name(active));
+                self.announce(active);
+            }
+            None => warn!("Bond: No member has carrier"),
+        }
+    }
+
+    /// Sends gratuitous Arp for all addresses of the local interface on
+    /// `member`.
+    fn announce(&self, member: MemberId) {
+        let frames = match *self.local.lock().unwrap() {
+            Some(ref local) => {
+                let addresses = local.addresses.read().unwrap();
+                addresses.iter().map(|&ip| gratuitous_arp(local.mac, ip)).collect::<Vec<_>>()
+            }
+            None => return,
+        };
+        for frame in frames {
+            if let Err(e) = self.send_on(member, &frame) {
+                warn!("Bond: Unable to announce on {}: {}", self.name(member), e);
+            }
+        }
+    }
+}
+
+/// Builds a gratuitous Arp request frame from `mac` for `ip`.
+fn gratuitous_arp(mac: MacAddr, ip: Ipv4Addr) -> Vec<u8> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
+                             ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(mac);
+        eth_pkg.set_destination(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        ArpBuilder::new_request(mac, ip, ip).build(eth_pkg.payload_mut());
+    }
+    buffer
+}
+
+/// An active-backup bond of any number of links. Clones refer to the same
+/// bond.
+#[derive(Clone)]
+pub struct Bond {
+    data: Arc<BondData>,
+}
+
+impl Bond {
+    pub fn new() -> Bond {
+        Bond {
+            data: Arc::new(BondData {
+                members: RwLock::new(Vec::new()),
+                links: Mutex::new(Links {
+                    carrier: Vec::new(),
+                    active: None,
+                }),
+                local: Mutex::new(None),
+                monitor_generation: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Adds a member named `name`, sending and receiving on `channel`. It is
+    /// assumed to have carrier, and becomes active if no other member is.
+    /// The bond handles everything received on the channel from now on.
+    pub fn add_member(&self, name: &str, channel: EthernetChannel) -> MemberId {
+        let EthernetChannel(sender, receiver) = channel;
+        let member = {
+            let mut members = self.data.members.write().unwrap();
+            members.push(Member {
+                name: name.to_owned(),
+                tx: Mutex::new(sender),
+            });
+            self.data.links.lock().unwrap().carrier.push(false);
+            MemberId(members.len() - 1)
+        };
+        self.data.set_carrier(member, true);
+        let data = self.data.clone();
+        thread::spawn(move || run_member_rx(data, member, receiver));
+        member
+    }
+
+    /// Returns the names of the members, indexed by `MemberId`.
+    pub fn members(&self) -> Vec<String> {
+        self.data.names()
+    }
+
+    /// Adds an interface named `name` with the MAC address `mac` to `stack`,
+    /// sending and receiving through the active member.
+    ///
+    /// Fails with `StackError::AlreadyExists` if the bond already has an
+    /// interface.
+    pub fn add_interface(&self,
+                         stack: &mut NetworkStack,
+                         name: &str,
+                         mac: MacAddr)
+                         -> StackResult<Interface> {
+        let interface = Interface {
+            name: name.to_owned(),
+            mac: mac,
+        };
+        let (inject, frames) = mpsc::channel();
+        {
+            let mut local = self.data.local.lock().unwrap();
+            if local.is_some() {
+                return Err(StackError::AlreadyExists("Interface of the bond".to_owned()));
+            }
+            *local = Some(Local {
+                mac: mac,
+                inject: inject,
+                addresses: Arc::new(RwLock::new(HashSet::new())),
+            });
+        }
+        let sender = BondSender { data: self.data.clone() };
+        let channel = EthernetChannel(Box::new(sender), Box::new(ChannelReceiver::new(frames)));
+        if let Err(e) = stack.add_interface(interface.clone(), channel) {
+            *self.data.local.lock().unwrap() = None;
+            return Err(e);
+        }
+        let addresses = stack.interface(&interface)?.ipv4_addresses();
+        if let Some(ref mut local) = *self.data.local.lock().unwrap() {
+            local.addresses = addresses;
+        }
+        Ok(interface)
+    }
+
+    /// Returns the member all traffic goes through, `None` if no member has
+    /// carrier.
+    pub fn active(&self) -> Option<MemberId> {
+        self.data.links.lock().unwrap().active
+    }
+
+    /// Returns whether `member` has carrier, `None` if it does not exist.
+    pub fn carrier(&self, member: MemberId) -> Option<bool> {
+        self.data.links.lock().unwrap().carrier.get(member.0).cloned()
+    }
+
+    /// Reports the carrier of `member`. Fails over if the active member lost
+    /// it.
+    ///
+    /// Fails with `StackError::NotFound` if `member` does not exist.
+    pub fn set_carrier(&self, member: MemberId, carrier: bool) -> StackResult<()> {
+        self.check_member(member)?;
+        self.data.set_carrier(member, carrier);
+        Ok(())
+    }
+
+    /// Switches over to `member`, and announces the addresses of the
+    /// interface on it.
+    ///
+    /// Fails with `StackError::NotFound` if `member` does not exist, and with
+    /// `StackError::InvalidState` if it has no carrier.
+    pub fn set_active(&self, member: MemberId) -> StackResult<()> {
+        {
+            let mut links = self.data.links.lock().unwrap();
+            match links.carrier.get(member.0) {
+                Some(&true) => (),
+                Some(&false) => {
+                    let msg = format!("Member {} has no carrier", member.0);
+                    return Err(StackError::InvalidState(msg));
+                }
+                None => return Err(StackError::NotFound(format!("Member {}", member.0))),
+            }
+            if links.active == Some(member) {
+                return Ok(());
+            }
+            links.active = Some(member);
+        }
+        self.data.announce(member);
+        Ok(())
+    }
+
+    /// Starts polling the carrier of the members every `interval`, or
+    /// restarts with the new `interval` if already polling. Members whose
+    /// carrier can not be read with `sysfs_carrier` are left alone.
+    pub fn start_link_monitor(&self, interval: Duration) {
+        let generation = self.data.monitor_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let data = Arc::downgrade(&self.data);
+        thread::spawn(move || run_link_monitor(data, interval, generation));
+    }
+
+    pub fn stop_link_monitor(&self) {
+        self.data.monitor_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn check_member(&self, member: MemberId) -> StackResult<()> {
+        match self.carrier(member) {
+            Some(..) => Ok(()),
+            None => Err(StackError::NotFound(format!("Member {}", member.0))),
+        }
+    }
+}
+
+impl Default for Bond {
+    fn default() -> Bond {
+        Bond::new()
+    }
+}
+
+/// Hands the frames received on `member` to the bond. Unlike `rx::spawn`
+/// this survives errors, which count as loss of carrier.
+fn run_member_rx(data: Arc<BondData>,
+                 member: MemberId,
+                 mut receiver: Box<EthernetDataLinkReceiver>) {
+    let mut rx_iter = receiver.iter();
+    loop {
+        match rx_iter.next() {
+            Ok(packet) => data.receive(member, packet.packet()),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut ||
+                          e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => {
+                warn!("Bond: Unable to receive on {}: {}", data.name(member), e);
+                data.set_carrier(member, false);
+                thread::sleep(Duration::from_millis(RX_RETRY_MS));
+            }
+        }
+    }
+}
+
+/// Polls the carrier of the members of the bond until it is gone or the
+/// monitor is started or stopped again.
+fn run_link_monitor(data: Weak<BondData>, interval: Duration, generation: usize) {
+    loop {
+        thread::sleep(interval);
+        let data = match data.upgrade() {
+            Some(data) => data,
+            None => break,
+        };
+        if data.monitor_generation.load(Ordering::SeqCst) != generation {
+            break;
+        }
+        for (i, name) in data.names().iter().enumerate() {
+            if let Some(carrier) = sysfs_carrier(name) {
+                data.set_carrier(MemberId(i), carrier);
+            }
+        }
+    }
+    debug!("Bond link monitor thread is quitting");
+}
+
+/// Sending half of the channel of the interface on a bond.
+struct BondSender {
+    data: Arc<BondData>,
+}
+
+impl EthernetDataLinkSender for BondSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0; packet_size];
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            if let Err(e) = self.data.send(&buffer) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        Some(self.data.send(packet.packet()))
+    }
+}
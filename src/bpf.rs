@@ -0,0 +1,436 @@
+//! Classic BPF packet filters, the bytecode used by tcpdump and Linux socket
+//! filters. A `Program` attached to an interface with
+//! `StackInterface::set_rx_filter`, or to a socket with
+//! `UdpSocket::set_filter`, drops everything it does not accept before the
+//! stack spends any time parsing it.
+//!
+//! Programs are usually not written by hand but compiled from a filter
+//! expression with `tcpdump -dd`, whose output `Program::parse` accepts.
+//! Interface filters see the whole ethernet frame, socket filters the IPv4
+//! packet without link layer header.
+
+use std::str::FromStr;
+
+/// Maximum number of instructions in a program, same as in Linux.
+pub const MAX_INSTRUCTIONS: usize = 4096;
+
+/// Number of words in the scratch memory of a program.
+pub const MEMORY_WORDS: usize = 16;
+
+// Instruction classes
+const LD: u16 = 0x00;
+const LDX: u16 = 0x01;
+const ST: u16 = 0x02;
+const STX: u16 = 0x03;
+const ALU: u16 = 0x04;
+const JMP: u16 = 0x05;
+const RET: u16 = 0x06;
+const MISC: u16 = 0x07;
+
+// Load sizes
+const W: u16 = 0x00;
+const H: u16 = 0x08;
+const B: u16 = 0x10;
+
+// Load modes
+const IMM: u16 = 0x00;
+const ABS: u16 = 0x20;
+const IND: u16 = 0x40;
+const MEM: u16 = 0x60;
+const LEN: u16 = 0x80;
+const MSH: u16 = 0xa0;
+
+// Alu operations
+const ADD: u16 = 0x00;
+const SUB: u16 = 0x10;
+const MUL: u16 = 0x20;
+const DIV: u16 = 0x30;
+const OR: u16 = 0x40;
+const AND: u16 = 0x50;
+const LSH: u16 = 0x60;
+const RSH: u16 = 0x70;
+const NEG: u16 = 0x80;
+const MOD: u16 = 0x90;
+const XOR: u16 = 0xa0;
+
+// Jump conditions
+const JA: u16 = 0x00;
+const JEQ: u16 = 0x10;
+const JGT: u16 = 0x20;
+const JGE: u16 = 0x30;
+const JSET: u16 = 0x40;
+
+// Operand sources, K for the constant and X for the index register. RET
+// can also return the accumulator
+const K: u16 = 0x00;
+const X: u16 = 0x08;
+const A: u16 = 0x10;
+
+// Register transfers
+const TAX: u16 = 0x00;
+const TXA: u16 = 0x80;
+
+/// One BPF instruction, with the same layout as `struct sock_filter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub code: u16,
+    /// Offset of the instruction to jump to if the condition is true
+    pub jt: u8,
+    /// Offset of the instruction to jump to if the condition is false
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Instruction {
+    pub fn new(code: u16, jt: u8, jf: u8, k: u32) -> Instruction {
+        Instruction {
+            code: code,
+            jt: jt,
+            jf: jf,
+            k: k,
+        }
+    }
+}
+
+/// Why a program was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramError {
+    /// The program has no instructions
+    Empty,
+    /// The program has more than `MAX_INSTRUCTIONS` instructions
+    TooLong,
+    /// The instruction at this index is unknown, jumps out of the program,
+    /// divides by zero or accesses memory out of bounds. The last
+    /// instruction must return.
+    InvalidInstruction(usize),
+    /// The text given to `Program::parse` has a syntax error on this line
+    Syntax(usize),
+}
+
+/// A validated BPF program. Validation guarantees that running it
+/// terminates without panicking, whatever the packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+}
+
+impl Program {
+    pub fn new(instructions: Vec<Instruction>) -> Result<Program, ProgramError> {
+        if instructions.is_empty() {
+            return Err(ProgramError::Empty);
+        }
+        if instructions.len() > MAX_INSTRUCTIONS {
+            return Err(ProgramError::TooLong);
+        }
+        for (pc, instruction) in instructions.iter().enumerate() {
+            if !Self::is_valid(pc, instruction, instructions.len()) {
+                return Err(ProgramError::InvalidInstruction(pc));
+            }
+        }
+        if instructions.last().unwrap().code & 0x07 != RET {
+            return Err(ProgramError::InvalidInstruction(instructions.len() - 1));
+        }
+        Ok(Program { instructions: instructions })
+    }
+
+    /// Parses the output of `tcpdump -dd`, one `{ code, jt, jf, k },`
+    /// instruction per line. The output of `tcpdump -ddd`, with decimal
+    /// numbers separated by spaces and the instruction count first, is also
+    /// accepted.
+    pub fn parse(text: &str) -> Result<Program, ProgramError> {
+        let mut instructions = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim().trim_right_matches(',');
+            let line = line.trim_left_matches('{').trim_right_matches('}');
+            let fields = line.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|field| !field.is_empty())
+                .map(parse_number)
+                .collect::<Option<Vec<u32>>>();
+            let fields = match fields {
+                Some(fields) => fields,
+                None => return Err(ProgramError::Syntax(i + 1)),
+            };
+            match fields.len() {
+                0 => (),
+                // The instruction count of `tcpdump -ddd`
+                1 if instructions.is_empty() => (),
+                4 if fields[0] <= 0xffff && fields[1] <= 0xff && fields[2] <= 0xff => {
+                    let (code, jt, jf) = (fields[0] as u16, fields[1] as u8, fields[2] as u8);
+                    instructions.push(Instruction::new(code, jt, jf, fields[3]));
+                }
+                _ => return Err(ProgramError::Syntax(i + 1)),
+            }
+        }
+        Program::new(instructions)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    fn is_valid(pc: usize, instruction: &Instruction, len: usize) -> bool {
+        let code = instruction.code;
+        let k = instruction.k as usize;
+        let jumps_within = |offset: usize| pc + 1 + offset < len;
+        if code > 0xff {
+            return false;
+        }
+        match code & 0x07 {
+            LD => {
+                let size_ok = code & 0x18 != 0x18;
+                match code & 0xe0 {
+                    IMM | LEN => code & 0x18 == W,
+                    ABS | IND => size_ok,
+                    MEM => code & 0x18 == W && k < MEMORY_WORDS,
+                    _ => false,
+                }
+            }
+            LDX => {
+                match code & 0xe0 {
+                    IMM | LEN => code & 0x18 == W,
+                    MEM => code & 0x18 == W && k < MEMORY_WORDS,
+                    MSH => code & 0x18 == B,
+                    _ => false,
+                }
+            }
+            ST | STX => code & 0xf8 == 0 && k < MEMORY_WORDS,
+            ALU => {
+                let op = code & 0xf0;
+                let divides = op == DIV || op == MOD;
+                match op {
+                    NEG => code & 0x08 == K,
+                    ADD | SUB | MUL | DIV | OR | AND | LSH | RSH | MOD | XOR => {
+                        !(divides && code & 0x08 == K && k == 0)
+                    }
+                    _ => false,
+                }
+            }
+            JMP => {
+                match code & 0xf0 {
+                    JA => code & 0x08 == K && jumps_within(k),
+                    JEQ | JGT | JGE | JSET => {
+                        jumps_within(instruction.jt as usize) &&
+                        jumps_within(instruction.jf as usize)
+                    }
+                    _ => false,
+                }
+            }
+            RET => code & 0xe0 == 0 && code & 0x18 != 0x18,
+            MISC => code == MISC | TAX || code == MISC | TXA,
+            _ => false,
+        }
+    }
+
+    /// Runs the program on `packet`, returning how many bytes of it are
+    /// accepted. Zero means the packet is dropped.
+    pub fn run(&self, packet: &[u8]) -> u32 {
+        let mut a: u32 = 0;
+        let mut x: u32 = 0;
+        let mut mem = [0u32; MEMORY_WORDS];
+        let mut pc = 0;
+        loop {
+            let instruction = self.instructions[pc];
+            let code = instruction.code;
+            let k = instruction.k;
+            pc += 1;
+            match code & 0x07 {
+                LD => {
+                    a = match code & 0xe0 {
+                        IMM => k,
+                        LEN => packet.len() as u32,
+                        MEM => mem[k as usize],
+                        mode => {
+                            let offset = if mode == IND { x.wrapping_add(k) } else { k };
+                            match load(packet, offset, code & 0x18) {
+                                Some(value) => value,
+                                None => return 0,
+                            }
+                        }
+                    }
+                }
+                LDX => {
+                    x = match code & 0xe0 {
+                        IMM => k,
+                        LEN => packet.len() as u32,
+                        MEM => mem[k as usize],
+                        _ => {
+                            match packet.get(k as usize) {
+                                Some(&byte) => 4 * (byte as u32 & 0x0f),
+                                None => return 0,
+                            }
+                        }
+                    }
+                }
+                ST => mem[k as usize] = a,
+                STX => mem[k as usize] = x,
+                ALU => {
+                    let operand = if code & 0x08 == X { x } else { k };
+                    a = match code & 0xf0 {
+                        ADD => a.wrapping_add(operand),
+                        SUB => a.wrapping_sub(operand),
+                        MUL => a.wrapping_mul(operand),
+                        DIV | MOD if operand == 0 => return 0,
+                        DIV => a / operand,
+                        MOD => a % operand,
+                        OR => a | operand,
+                        AND => a & operand,
+                        LSH => a.checked_shl(operand).unwrap_or(0),
+                        RSH => a.checked_shr(operand).unwrap_or(0),
+                        XOR => a ^ operand,
+                        _ => (!a).wrapping_add(1),
+                    }
+                }
+                JMP => {
+                    let taken = match code & 0xf0 {
+                        JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        condition => {
+                            let operand = if code & 0x08 == X { x } else { k };
+                            match condition {
+                                JEQ => a == operand,
+                                JGT => a > operand,
+                                JGE => a >= operand,
+                                _ => a & operand != 0,
+                            }
+                        }
+                    };
+                    pc += if taken { instruction.jt } else { instruction.jf } as usize;
+                }
+                RET => {
+                    return match code & 0x18 {
+                        A => a,
+                        X => x,
+                        _ => k,
+                    };
+                }
+                _ => {
+                    if code & 0xf8 == TAX {
+                        x = a;
+                    } else {
+                        a = x;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns true if the program accepts `packet`.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        self.run(packet) != 0
+    }
+}
+
+impl FromStr for Program {
+    type Err = ProgramError;
+
+    fn from_str(s: &str) -> Result<Program, ProgramError> {
+        Program::parse(s)
+    }
+}
+
+/// Loads a big endian value of `size` at `offset`.
+fn load(packet: &[u8], offset: u32, size: u16) -> Option<u32> {
+    let len = match size {
+        W => 4,
+        H => 2,
+        _ => 1,
+    };
+    let offset = offset as usize;
+    if offset.checked_add(len).map_or(true, |end| end > packet.len()) {
+        return None;
+    }
+    Some(packet[offset..offset + len].iter().fold(0, |value, &byte| value << 8 | byte as u32))
+}
+
+fn parse_number(field: &str) -> Option<u32> {
+    if field.starts_with("0x") || field.starts_with("0X") {
+        u32::from_str_radix(&field[2..], 16).ok()
+    } else {
+        field.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `tcpdump -dd ip proto udp`
+    static IP_PROTO_UDP: &'static str = "{ 0x28, 0, 0, 0x0000000c },
+                                         { 0x15, 0, 3, 0x00000800 },
+                                         { 0x30, 0, 0, 0x00000017 },
+                                         { 0x15, 0, 1, 0x00000011 },
+                                         { 0x6, 0, 0, 0x00040000 },
+                                         { 0x6, 0, 0, 0x00000000 },";
+
+    fn frame(ether_type: u16, protocol: u8, dst_port: u16) -> Vec<u8> {
+        let mut frame = vec![0; 14 + 20 + 8];
+        frame[12] = (ether_type >> 8) as u8;
+        frame[13] = ether_type as u8;
+        frame[14] = 0x45;
+        frame[23] = protocol;
+        frame[36] = (dst_port >> 8) as u8;
+        frame[37] = dst_port as u8;
+        frame
+    }
+
+    #[test]
+    fn parse_and_run() {
+        let program = Program::parse(IP_PROTO_UDP).unwrap();
+        assert_eq!(program.instructions().len(), 6);
+        assert_eq!(program.run(&frame(0x0800, 17, 53)), 0x40000);
+        assert!(!program.matches(&frame(0x0800, 6, 53)));
+        assert!(!program.matches(&frame(0x0806, 17, 53)));
+        // Too short to load the protocol
+        assert!(!program.matches(&frame(0x0800, 17, 53)[..20]));
+
+        let ddd = "6\n40 0 0 12\n21 0 3 2048\n48 0 0 23\n21 0 1 17\n6 0 0 262144\n6 0 0 0\n";
+        assert_eq!(Program::parse(ddd), Ok(program));
+    }
+
+    #[test]
+    fn indexed_load() {
+        // ldxb 4*([14]&0xf); ldh [x + 16]; jeq #53; ret #-1; ret #0
+        let program = Program::new(vec![Instruction::new(LDX | B | MSH, 0, 0, 14),
+                                        Instruction::new(LD | H | IND, 0, 0, 16),
+                                        Instruction::new(JMP | JEQ | K, 0, 1, 53),
+                                        Instruction::new(RET | K, 0, 0, 0xffffffff),
+                                        Instruction::new(RET | K, 0, 0, 0)])
+            .unwrap();
+        assert!(program.matches(&frame(0x0800, 17, 53)));
+        assert!(!program.matches(&frame(0x0800, 17, 54)));
+    }
+
+    #[test]
+    fn alu_and_memory() {
+        // a = len; mem[3] = a; a = 0; a = mem[3]; a *= 2; x = a; ret x
+        let program = Program::new(vec![Instruction::new(LD | W | LEN, 0, 0, 0),
+                                        Instruction::new(ST, 0, 0, 3),
+                                        Instruction::new(LD | W | IMM, 0, 0, 0),
+                                        Instruction::new(LD | W | MEM, 0, 0, 3),
+                                        Instruction::new(ALU | MUL | K, 0, 0, 2),
+                                        Instruction::new(MISC | TAX, 0, 0, 0),
+                                        Instruction::new(RET | X, 0, 0, 0)])
+            .unwrap();
+        assert_eq!(program.run(&[0; 21]), 42);
+    }
+
+    #[test]
+    fn invalid() {
+        let ret = Instruction::new(RET | K, 0, 0, 0);
+        assert_eq!(Program::new(vec![]), Err(ProgramError::Empty));
+        assert_eq!(Program::new(vec![ret; MAX_INSTRUCTIONS + 1]),
+                   Err(ProgramError::TooLong));
+        // Jumps past the end
+        assert_eq!(Program::new(vec![Instruction::new(JMP | JEQ | K, 1, 0, 0), ret]),
+                   Err(ProgramError::InvalidInstruction(0)));
+        assert_eq!(Program::new(vec![Instruction::new(ALU | DIV | K, 0, 0, 0), ret]),
+                   Err(ProgramError::InvalidInstruction(0)));
+        assert_eq!(Program::new(vec![Instruction::new(ST, 0, 0, 16), ret]),
+                   Err(ProgramError::InvalidInstruction(0)));
+        assert_eq!(Program::new(vec![Instruction::new(LD | W | IMM, 0, 0, 0)]),
+                   Err(ProgramError::InvalidInstruction(0)));
+        assert_eq!(Program::parse("{ 0x6, 0, 0 }"), Err(ProgramError::Syntax(1)));
+    }
+}
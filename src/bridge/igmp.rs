@@ -0,0 +1,625 @@
+//! IGMP snooping, constraining multicast flooding to the ports with members.
+//!
+//! `Snooping` follows the membership reports and leaves hosts send on each
+//! port, and the queries of multicast routers. IPv4 multicast to a group is
+//! then only forwarded to the ports with members of the group and to the
+//! ports with a multicast router behind them. Group addresses in
+//! 224.0.0.0/24 are used for local control traffic and are always flooded,
+//! as are queries. Reports and leaves only go to the router ports.
+//!
+//! Without a multicast router on the network nobody queries for members, so
+//! memberships expire. A `Querier` can be configured for the bridge to send
+//! the queries itself while no querier with a lower address is heard.
+//!
+//! Like `Stp`, `Snooping` does no I/O itself. The bridge feeds it frames and
+//! advances its timers, and floods the queries it returns.
+
+use super::PortId;
+use ethernet;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::util::{self, MacAddr};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+pub const MEMBERSHIP_QUERY: u8 = 0x11;
+pub const V1_MEMBERSHIP_REPORT: u8 = 0x12;
+pub const V2_MEMBERSHIP_REPORT: u8 = 0x16;
+pub const LEAVE_GROUP: u8 = 0x17;
+pub const V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+const MODE_IS_INCLUDE: u8 = 1;
+const MODE_IS_EXCLUDE: u8 = 2;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+const ALLOW_NEW_SOURCES: u8 = 5;
+
+/// Length of IGMP messages other than IGMPv3 reports.
+const IGMP_LEN: usize = 8;
+
+/// How many times messages are assumed to get lost, by default.
+pub static DEFAULT_ROBUSTNESS: u32 = 2;
+
+/// Seconds between general queries by default.
+pub static DEFAULT_QUERY_INTERVAL: u64 = 125;
+
+/// Seconds hosts get to answer general queries by default.
+pub static DEFAULT_QUERY_RESPONSE_INTERVAL: u64 = 10;
+
+/// Seconds hosts get to answer group specific queries by default.
+pub static DEFAULT_LAST_MEMBER_QUERY_INTERVAL: u64 = 1;
+
+/// An IGMP message relevant to snooping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A membership query. `group` is unspecified for general queries.
+    Query { source: Ipv4Addr, group: Ipv4Addr },
+    /// A membership report or leave, of any IGMP version
+    Report {
+        joined: Vec<Ipv4Addr>,
+        left: Vec<Ipv4Addr>,
+    },
+}
+
+impl Message {
+    /// Parses the IGMP message in the Ethernet frame `frame`. Returns `None`
+    /// if it does not carry a valid one.
+    pub fn parse(frame: &[u8]) -> Option<Message> {
+        let eth_pkg = match EthernetPacket::new(frame) {
+            Some(eth_pkg) => eth_pkg,
+            None => return None,
+        };
+        if eth_pkg.get_ethertype() != EtherTypes::Ipv4 {
+            return None;
+        }
+        let packet = eth_pkg.payload();
+        let ip_pkg = match Ipv4Packet::new(packet) {
+            Some(ip_pkg) => ip_pkg,
+            None => return None,
+        };
+        let header_len = ip_pkg.get_header_length() as usize * 4;
+        let total_len = ip_pkg.get_total_length() as usize;
+        if ip_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Igmp ||
+           header_len < Ipv4Packet::minimum_packet_size() || total_len > packet.len() ||
+           total_len < header_len + IGMP_LEN {
+            return None;
+        }
+        let igmp = &packet[header_len..total_len];
+        if read_u16(&igmp[2..4]) != util::checksum(igmp, 1) {
+            return None;
+        }
+        let group = read_addr(&igmp[4..8]);
+        match igmp[0] {
+            MEMBERSHIP_QUERY => {
+                Some(Message::Query {
+                    source: ip_pkg.get_source(),
+                    group: group,
+                })
+            }
+            V1_MEMBERSHIP_REPORT | V2_MEMBERSHIP_REPORT => {
+                Some(Message::Report {
+                    joined: vec![group],
+                    left: vec![],
+                })
+            }
+            LEAVE_GROUP => {
+                Some(Message::Report {
+                    joined: vec![],
+                    left: vec![group],
+                })
+            }
+            V3_MEMBERSHIP_REPORT => parse_v3_report(igmp),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the group records of an IGMPv3 report. Sources are not tracked,
+/// any interest in a group counts as a membership.
+fn parse_v3_report(igmp: &[u8]) -> Option<Message> {
+    let records = read_u16(&igmp[6..8]);
+    let mut joined = vec![];
+    let mut left = vec![];
+    let mut offset = IGMP_LEN;
+    for _ in 0..records {
+        if igmp.len() < offset + 8 {
+            return None;
+        }
+        let record = &igmp[offset..];
+        let sources = read_u16(&record[2..4]) as usize;
+        let group = read_addr(&record[4..8]);
+        match (record[0], sources) {
+            (MODE_IS_INCLUDE, 0) |
+            (CHANGE_TO_INCLUDE_MODE, 0) => left.push(group),
+            (MODE_IS_INCLUDE, _) |
+            (MODE_IS_EXCLUDE, _) |
+            (CHANGE_TO_INCLUDE_MODE, _) |
+            (CHANGE_TO_EXCLUDE_MODE, _) |
+            (ALLOW_NEW_SOURCES, _) => joined.push(group),
+            _ => (),
+        }
+        offset += 8 + sources * 4 + record[1] as usize * 4;
+    }
+    if offset > igmp.len() {
+        return None;
+    }
+    Some(Message::Report {
+        joined: joined,
+        left: left,
+    })
+}
+
+/// Returns the destination group of the IPv4 multicast in the Ethernet
+/// frame `frame`, or `None` if it is something else.
+pub fn multicast_group(frame: &[u8]) -> Option<Ipv4Addr> {
+    let eth_pkg = match EthernetPacket::new(frame) {
+        Some(eth_pkg) => eth_pkg,
+        None => return None,
+    };
+    if eth_pkg.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    match Ipv4Packet::new(eth_pkg.payload()).map(|ip_pkg| ip_pkg.get_destination()) {
+        Some(group) if group.is_multicast() => Some(group),
+        _ => None,
+    }
+}
+
+/// Returns the MAC address IPv4 multicast to `group` is sent to.
+pub fn multicast_mac(group: Ipv4Addr) -> MacAddr {
+    ethernet::ipv4_multicast_mac(group)
+}
+
+/// Returns true for the groups in 224.0.0.0/24, which are always flooded.
+fn is_link_local(group: Ipv4Addr) -> bool {
+    group.octets()[..3] == [224, 0, 0]
+}
+
+/// Where the queries of the bridge come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Querier {
+    pub address: Ipv4Addr,
+    pub mac: MacAddr,
+}
+
+/// IGMP snooping configuration. The timers follow RFC 3376 and must match
+/// those of the multicast routers on the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnoopingConfig {
+    /// Send queries as `Querier` while no other querier is heard
+    pub querier: Option<Querier>,
+    pub robustness: u32,
+    pub query_interval: Duration,
+    pub query_response_interval: Duration,
+    pub last_member_query_interval: Duration,
+}
+
+impl SnoopingConfig {
+    /// Creates a configuration with the default timers and no querier.
+    pub fn new() -> SnoopingConfig {
+        SnoopingConfig {
+            querier: None,
+            robustness: DEFAULT_ROBUSTNESS,
+            query_interval: Duration::from_secs(DEFAULT_QUERY_INTERVAL),
+            query_response_interval: Duration::from_secs(DEFAULT_QUERY_RESPONSE_INTERVAL),
+            last_member_query_interval: Duration::from_secs(DEFAULT_LAST_MEMBER_QUERY_INTERVAL),
+        }
+    }
+
+    /// How long a port stays a member of a group without reporting it.
+    pub fn membership_interval(&self) -> Duration {
+        self.query_interval * self.robustness + self.query_response_interval
+    }
+
+    /// How long a port stays a router port, and another querier is assumed
+    /// present, without hearing a query.
+    pub fn other_querier_interval(&self) -> Duration {
+        self.query_interval * self.robustness + self.query_response_interval / 2
+    }
+
+    /// How long members that left a group are kept waiting for other
+    /// members to report it.
+    pub fn last_member_query_time(&self) -> Duration {
+        self.last_member_query_interval * self.robustness
+    }
+}
+
+impl Default for SnoopingConfig {
+    fn default() -> SnoopingConfig {
+        SnoopingConfig::new()
+    }
+}
+
+/// Where `Snooping` wants a multicast frame forwarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Forward {
+    /// To all ports
+    Flood,
+    /// Only to these ports, possibly none
+    Ports(Vec<PortId>),
+}
+
+/// The IGMP snooping state of a bridge.
+pub struct Snooping {
+    config: SnoopingConfig,
+    /// The member ports of each group, and when their membership expires
+    groups: HashMap<Ipv4Addr, HashMap<PortId, Instant>>,
+    /// The ports queries are heard on, and when they stop being router ports
+    routers: HashMap<PortId, Instant>,
+    /// Until when a querier with a lower address than ours is assumed present
+    other_querier: Option<Instant>,
+    next_query: Instant,
+    /// Groups to send group specific queries for
+    queries: Vec<Ipv4Addr>,
+}
+
+impl Snooping {
+    /// Starts snooping with no known members or routers. If a querier is
+    /// configured, the first query is sent right away.
+    pub fn new(config: SnoopingConfig, now: Instant) -> Snooping {
+        Snooping {
+            config: config,
+            groups: HashMap::new(),
+            routers: HashMap::new(),
+            other_querier: None,
+            next_query: now,
+            queries: vec![],
+        }
+    }
+
+    pub fn config(&self) -> SnoopingConfig {
+        self.config
+    }
+
+    /// Returns the member ports of each group, in order.
+    pub fn groups(&self) -> HashMap<Ipv4Addr, Vec<PortId>> {
+        self.groups
+            .iter()
+            .map(|(group, members)| {
+                let mut ports = members.keys().cloned().collect::<Vec<_>>();
+                ports.sort();
+                (*group, ports)
+            })
+            .collect()
+    }
+
+    /// Returns the ports with a multicast router behind them, in order.
+    pub fn routers(&self) -> Vec<PortId> {
+        let mut ports = self.routers.keys().cloned().collect::<Vec<_>>();
+        ports.sort();
+        ports
+    }
+
+    /// Returns true if the bridge is sending the queries.
+    pub fn is_querier(&self, now: Instant) -> bool {
+        self.config.querier.is_some() && self.other_querier.map_or(true, |until| until <= now)
+    }
+
+    /// Handles the multicast frame `frame` received on `ingress`, or sent by
+    /// the local interface if `ingress` is `None`, and returns where to
+    /// forward it.
+    pub fn receive(&mut self, ingress: Option<PortId>, frame: &[u8], now: Instant) -> Forward {
+        let group = match multicast_group(frame) {
+            Some(group) => group,
+            None => return Forward::Flood,
+        };
+        match (ingress, Message::parse(frame)) {
+            (Some(port), Some(message)) => self.receive_message(port, message, now),
+            (None, Some(..)) => Forward::Flood,
+            (_, None) if is_link_local(group) => Forward::Flood,
+            (_, None) => Forward::Ports(self.egress(group)),
+        }
+    }
+
+    fn receive_message(&mut self, port: PortId, message: Message, now: Instant) -> Forward {
+        match message {
+            Message::Query { source, group } => {
+                // Other snooping bridges query from the unspecified address
+                if source != Ipv4Addr::new(0, 0, 0, 0) {
+                    let until = now + self.config.other_querier_interval();
+                    self.routers.insert(port, until);
+                    let lower = match self.config.querier {
+                        Some(querier) => u32::from(source) < u32::from(querier.address),
+                        None => true,
+                    };
+                    if lower {
+                        self.other_querier = Some(until);
+                        self.queries.clear();
+                    }
+                }
+                if group != Ipv4Addr::new(0, 0, 0, 0) {
+                    let until = now + self.config.last_member_query_time();
+                    if let Some(members) = self.groups.get_mut(&group) {
+                        for expires in members.values_mut().filter(|expires| **expires > until) {
+                            *expires = until;
+                        }
+                    }
+                }
+                Forward::Flood
+            }
+            Message::Report { joined, left } => {
+                let until = now + self.config.membership_interval();
+                for group in joined.into_iter().filter(|&group| !is_link_local(group)) {
+                    self.groups.entry(group).or_insert_with(HashMap::new).insert(port, until);
+                }
+                for group in left {
+                    self.leave(port, group, now);
+                }
+                Forward::Ports(self.routers())
+            }
+        }
+    }
+
+    /// Lets the membership of `port` in `group` expire unless another member
+    /// on the port answers a group specific query.
+    fn leave(&mut self, port: PortId, group: Ipv4Addr, now: Instant) {
+        let until = now + self.config.last_member_query_time();
+        if let Some(expires) = self.groups.get_mut(&group).and_then(|m| m.get_mut(&port)) {
+            if *expires > until {
+                *expires = until;
+            }
+        }
+        if self.is_querier(now) && !self.queries.contains(&group) {
+            self.queries.push(group);
+        }
+    }
+
+    /// Returns the member and router ports of `group`, in order.
+    fn egress(&self, group: Ipv4Addr) -> Vec<PortId> {
+        let mut ports = self.routers();
+        if let Some(members) = self.groups.get(&group) {
+            ports.extend(members.keys().cloned());
+        }
+        ports.sort();
+        ports.dedup();
+        ports
+    }
+
+    /// Expires memberships, router ports and other queriers. Returns the
+    /// query frames to flood, if the bridge is the querier.
+    pub fn tick(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        for members in self.groups.values_mut() {
+            expire(members, now);
+        }
+        let empty = self.groups
+            .iter()
+            .filter(|&(_, members)| members.is_empty())
+            .map(|(group, _)| *group)
+            .collect::<Vec<_>>();
+        for group in empty {
+            self.groups.remove(&group);
+        }
+        expire(&mut self.routers, now);
+        if self.other_querier.map_or(false, |until| until <= now) {
+            self.other_querier = None;
+        }
+
+        let querier = match self.config.querier {
+            Some(querier) if self.is_querier(now) => querier,
+            _ => return vec![],
+        };
+        let interval = self.config.last_member_query_interval;
+        let mut frames = self.queries
+            .drain(..)
+            .map(|group| query(querier, group, interval))
+            .collect::<Vec<_>>();
+        if now >= self.next_query {
+            self.next_query = now + self.config.query_interval;
+            let response_interval = self.config.query_response_interval;
+            frames.push(query(querier, Ipv4Addr::new(0, 0, 0, 0), response_interval));
+        }
+        frames
+    }
+}
+
+fn expire(timers: &mut HashMap<PortId, Instant>, now: Instant) {
+    let expired = timers.iter()
+        .filter(|&(_, until)| *until <= now)
+        .map(|(port, _)| *port)
+        .collect::<Vec<_>>();
+    for port in expired {
+        timers.remove(&port);
+    }
+}
+
+/// Builds an IGMPv2 membership query frame from `querier`, asking for
+/// reports within `max_response`. A general query if `group` is unspecified,
+/// otherwise specific to `group`.
+pub fn query(querier: Querier, group: Ipv4Addr, max_response: Duration) -> Vec<u8> {
+    let dst = if group == Ipv4Addr::new(0, 0, 0, 0) {
+        Ipv4Addr::new(224, 0, 0, 1)
+    } else {
+        group
+    };
+    let ip_len = Ipv4Packet::minimum_packet_size() + IGMP_LEN;
+    let mut buffer = vec![0; 60];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(multicast_mac(dst));
+        eth_pkg.set_source(querier.mac);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(ip_len as u16);
+        ip_pkg.set_ttl(1);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+        ip_pkg.set_source(querier.address);
+        ip_pkg.set_destination(dst);
+        let csum = ipv4::checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    let tenths = max_response.as_secs() * 10 + max_response.subsec_nanos() as u64 / 100_000_000;
+    let offset = EthernetPacket::minimum_packet_size() + Ipv4Packet::minimum_packet_size();
+    {
+        let igmp = &mut buffer[offset..offset + IGMP_LEN];
+        igmp[0] = MEMBERSHIP_QUERY;
+        igmp[1] = cmp::min(tenths, 255) as u8;
+        igmp[4..8].copy_from_slice(&group.octets());
+        let csum = util::checksum(igmp, 1);
+        igmp[2] = (csum >> 8) as u8;
+        igmp[3] = csum as u8;
+    }
+    buffer
+}
+
+fn read_u16(buffer: &[u8]) -> u16 {
+    (buffer[0] as u16) << 8 | buffer[1] as u16
+}
+
+fn read_addr(buffer: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buffer[0], buffer[1], buffer[2], buffer[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use bridge::PortId;
+
+    use pnet::packet::MutablePacket;
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::util::{self, MacAddr};
+
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn querier() -> Querier {
+        Querier {
+            address: Ipv4Addr::new(10, 0, 0, 5),
+            mac: MacAddr::new(2, 0, 0, 0, 0, 5),
+        }
+    }
+
+    /// Builds a frame with an IGMP message `igmp` sent to `dst`.
+    fn igmp_frame(dst: Ipv4Addr, mut igmp: Vec<u8>) -> Vec<u8> {
+        let csum = util::checksum(&igmp, 1);
+        igmp[2] = (csum >> 8) as u8;
+        igmp[3] = csum as u8;
+        let mut frame = vec![0; 34];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut frame[..]).unwrap();
+            eth_pkg.set_destination(multicast_mac(dst));
+            eth_pkg.set_ethertype(EtherTypes::Ipv4);
+            let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(20 + igmp.len() as u16);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+            ip_pkg.set_source(Ipv4Addr::new(10, 0, 0, 9));
+            ip_pkg.set_destination(dst);
+        }
+        frame.extend(igmp);
+        frame
+    }
+
+    fn report(group: Ipv4Addr) -> Vec<u8> {
+        let mut igmp = vec![V2_MEMBERSHIP_REPORT, 0, 0, 0];
+        igmp.extend_from_slice(&group.octets());
+        igmp_frame(group, igmp)
+    }
+
+    fn data(group: Ipv4Addr) -> Vec<u8> {
+        let mut frame = igmp_frame(group, vec![0; 8]);
+        frame[23] = 17;
+        frame
+    }
+
+    #[test]
+    fn parse_v3_report() {
+        let mut igmp = vec![V3_MEMBERSHIP_REPORT, 0, 0, 0, 0, 0, 0, 3];
+        // An exclude record without sources, an include record with one
+        // source and an include record to leave
+        igmp.extend_from_slice(&[MODE_IS_EXCLUDE, 0, 0, 0, 239, 0, 0, 1]);
+        igmp.extend_from_slice(&[ALLOW_NEW_SOURCES, 0, 0, 1, 239, 0, 0, 2, 10, 0, 0, 1]);
+        igmp.extend_from_slice(&[CHANGE_TO_INCLUDE_MODE, 0, 0, 0, 239, 0, 0, 3]);
+        let frame = igmp_frame(Ipv4Addr::new(224, 0, 0, 22), igmp);
+        assert_eq!(Message::parse(&frame),
+                   Some(Message::Report {
+                       joined: vec![Ipv4Addr::new(239, 0, 0, 1), Ipv4Addr::new(239, 0, 0, 2)],
+                       left: vec![Ipv4Addr::new(239, 0, 0, 3)],
+                   }));
+        assert_eq!(Message::parse(&frame[..frame.len() - 4]), None);
+    }
+
+    #[test]
+    fn constrain_to_members() {
+        let now = Instant::now();
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let mut testee = Snooping::new(SnoopingConfig::new(), now);
+        // Nobody gets multicast for unknown groups, except local control
+        assert_eq!(testee.receive(Some(PortId(0)), &data(group), now), Forward::Ports(vec![]));
+        let local = Ipv4Addr::new(224, 0, 0, 251);
+        assert_eq!(testee.receive(Some(PortId(0)), &data(local), now), Forward::Flood);
+
+        assert_eq!(testee.receive(Some(PortId(2)), &report(group), now), Forward::Ports(vec![]));
+        let query = query(querier(), Ipv4Addr::new(0, 0, 0, 0), Duration::from_secs(10));
+        assert_eq!(testee.receive(Some(PortId(1)), &query, now), Forward::Flood);
+        assert_eq!(testee.routers(), vec![PortId(1)]);
+        assert_eq!(testee.receive(Some(PortId(3)), &report(group), now),
+                   Forward::Ports(vec![PortId(1)]));
+        assert_eq!(testee.receive(None, &data(group), now),
+                   Forward::Ports(vec![PortId(1), PortId(2), PortId(3)]));
+
+        // Port 2 leaves, and the membership expires without another report
+        let mut leave = vec![LEAVE_GROUP, 0, 0, 0];
+        leave.extend_from_slice(&group.octets());
+        let leave = igmp_frame(Ipv4Addr::new(224, 0, 0, 2), leave);
+        testee.receive(Some(PortId(2)), &leave, now);
+        testee.tick(now + Duration::from_secs(2));
+        assert_eq!(testee.groups()[&group], vec![PortId(3)]);
+
+        testee.tick(now + Duration::from_secs(260));
+        assert!(testee.groups().is_empty());
+        assert!(testee.routers().is_empty());
+    }
+
+    #[test]
+    fn querier_fallback() {
+        let now = Instant::now();
+        let mut config = SnoopingConfig::new();
+        config.querier = Some(querier());
+        let mut testee = Snooping::new(config, now);
+        assert!(testee.is_querier(now));
+
+        let queries = testee.tick(now);
+        assert_eq!(queries.len(), 1);
+        assert_eq!(Message::parse(&queries[0]),
+                   Some(Message::Query {
+                       source: querier().address,
+                       group: Ipv4Addr::new(0, 0, 0, 0),
+                   }));
+        assert!(testee.tick(now + Duration::from_secs(1)).is_empty());
+        assert_eq!(testee.tick(now + Duration::from_secs(125)).len(), 1);
+
+        // A router with a higher address does not take over
+        let higher = Querier {
+            address: Ipv4Addr::new(10, 0, 0, 6),
+            ..querier()
+        };
+        let query_from = |querier| query(querier, Ipv4Addr::new(0, 0, 0, 0), Duration::new(10, 0));
+        testee.receive(Some(PortId(0)), &query_from(higher), now + Duration::from_secs(125));
+        assert!(testee.is_querier(now + Duration::from_secs(125)));
+
+        let lower = Querier {
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            ..querier()
+        };
+        let later = now + Duration::from_secs(126);
+        testee.receive(Some(PortId(0)), &query_from(lower), later);
+        assert!(!testee.is_querier(later));
+        assert!(testee.tick(later + Duration::from_secs(250)).is_empty());
+        // Until the other querier goes quiet
+        let quiet = later + Duration::from_secs(255);
+        assert_eq!(testee.tick(quiet).len(), 1);
+        assert!(testee.is_querier(quiet));
+    }
+}
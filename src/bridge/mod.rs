@@ -0,0 +1,567 @@
+//! Software bridges, joining interfaces at layer 2 like a switch.
+//!
+//! A `Bridge` takes over the `EthernetChannel`s of its ports. It learns
+//! which port each source MAC address is seen on, and forwards frames to the
+//! port their destination was learned on. Frames to unknown, broadcast and
+//! multicast addresses are flooded to all other ports.
+//!
+//! The bridge itself can be added to a `NetworkStack` as an interface with
+//! `Bridge::add_interface`, giving it addresses of its own.
+//!
+//! With `Bridge::enable_stp` the bridge takes part in the rapid spanning
+//! tree protocol, see `stp`, and ports that would close a loop discard all
+//! frames. BPDUs are bridged like any other frames while it is disabled.
+//!
+//! With `Bridge::enable_igmp_snooping` IPv4 multicast is only forwarded to
+//! the ports with members of the group, or a multicast router, see `igmp`.
+
+use {EthernetChannel, Interface, NetworkStack, RxResult, StackError, StackResult};
+use rx::{self, ChannelReceiver, RxListener};
+
+use self::igmp::{Forward, Snooping, SnoopingConfig};
+use self::stp::{Actions, Bpdu, BridgeId, PortState, PortStatus, Stp, StpConfig};
+
+use pnet::datalink::{EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub mod igmp;
+pub mod stp;
+
+/// How long learned MAC addresses are remembered by default, in seconds.
+pub static DEFAULT_AGEING_TIME: u64 = 300;
+
+/// Maximum number of MAC addresses learned. Expired entries are dropped
+/// when the table grows beyond this, then new addresses are not learned
+/// until there is room again.
+pub static MAX_FDB_ENTRIES: usize = 4096;
+
+/// How often the spanning tree and IGMP snooping timers are advanced, in
+/// milliseconds.
+static TICK_MS: u64 = 100;
+
+/// Identifies a port of a `Bridge`, in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PortId(pub usize);
+
+/// The forwarding database of a bridge, mapping learned MAC addresses to the
+/// port they were last seen on.
+pub type Fdb = HashMap<MacAddr, PortId>;
+
+#[derive(Debug, Clone, Copy)]
+struct FdbEntry {
+    port: PortId,
+    seen: Instant,
+}
+
+struct Port {
+    name: String,
+    tx: Mutex<Box<EthernetDataLinkSender>>,
+}
+
+/// The interface of a `NetworkStack` on the bridge.
+struct Local {
+    mac: MacAddr,
+    inject: Sender<Box<[u8]>>,
+}
+
+struct BridgeData {
+    ports: RwLock<Vec<Port>>,
+    fdb: Mutex<HashMap<MacAddr, FdbEntry>>,
+    ageing_time: RwLock<Duration>,
+    local: Mutex<Option<Local>>,
+    stp: Mutex<Option<Stp>>,
+    snooping: Mutex<Option<Snooping>>,
+    /// Incremented whenever the spanning tree or IGMP snooping is enabled or
+    /// disabled, telling the thread running the timers to stop
+    timer_generation: AtomicUsize,
+}
+
+impl BridgeData {
+    /// Forwards the frame `frame`, received on `ingress` or sent by the
+    /// local interface if `ingress` is `None`.
+    fn forward(&self, ingress: Option<PortId>, frame: &[u8]) {
+        let (src, dst) = match EthernetPacket::new(frame) {
+            Some(eth_pkg) => (eth_pkg.get_source(), eth_pkg.get_destination()),
+            None => return,
+        };
+        if let Some(port) = ingress {
+            if dst == stp::BPDU_ADDR && self.receive_bpdu(port, frame) {
+                return;
+            }
+            let state = self.port_state(port);
+            if state == PortState::Discarding {
+                return;
+            }
+            self.learn(src, port);
+            if state == PortState::Learning {
+                return;
+            }
+        }
+        let local_mac = self.local.lock().unwrap().as_ref().map(|local| local.mac);
+        if Some(dst) == local_mac {
+            if ingress.is_some() {
+                self.deliver(frame);
+            }
+            return;
+        }
+        let group = dst.0 & 0x01 != 0;
+        let egress = if group { None } else { self.lookup(dst) };
+        match egress {
+            Some(port) => {
+                if Some(port) != ingress && self.port_state(port) == PortState::Forwarding {
+                    self.send(port, frame);
+                }
+            }
+            None => {
+                let snooped = if group { self.snoop(ingress, frame) } else { None };
+                let ports = match snooped {
+                    Some(ports) => ports,
+                    None => (0..self.ports.read().unwrap().len()).map(PortId).collect(),
+                };
+                for port in ports.into_iter().filter(|&port| Some(port) != ingress) {
+                    if self.port_state(port) == PortState::Forwarding {
+                        self.send(port, frame);
+                    }
+                }
+                if group && ingress.is_some() {
+                    self.deliver(frame);
+                }
+            }
+        }
+    }
+
+    fn learn(&self, mac: MacAddr, port: PortId) {
+        if mac.0 & 0x01 != 0 {
+            return;
+        }
+        let mut fdb = self.fdb.lock().unwrap();
+        if fdb.len() >= MAX_FDB_ENTRIES && !fdb.contains_key(&mac) {
+            let ageing_time = *self.ageing_time.read().unwrap();
+            let expired = fdb.iter()
+                .filter(|&(_, entry)| entry.seen.elapsed() >= ageing_time)
+                .map(|(mac, _)| *mac)
+                .collect::<Vec<_>>();
+            for mac in expired {
+                fdb.remove(&mac);
+            }
+            if fdb.len() >= MAX_FDB_ENTRIES {
+                return;
+            }
+        }
+        fdb.insert(mac,
+                   FdbEntry {
+                       port: port,
+                       seen: Instant::now(),
+                   });
+    }
+
+    fn lookup(&self, mac: MacAddr) -> Option<PortId> {
+        let ageing_time = *self.ageing_time.read().unwrap();
+        match self.fdb.lock().unwrap().get(&mac) {
+            Some(entry) if entry.seen.elapsed() < ageing_time => Some(entry.port),
+            _ => None,
+        }
+    }
+
+    fn port_state(&self, port: PortId) -> PortState {
+        match *self.stp.lock().unwrap() {
+            Some(ref stp) => stp.state(port),
+            None => PortState::Forwarding,
+        }
+    }
+
+    /// Hands the BPDU in `frame`, received on `port`, to the spanning tree.
+    /// Returns false if the spanning tree is disabled.
+    fn receive_bpdu(&self, port: PortId, frame: &[u8]) -> bool {
+        let bpdu = Bpdu::parse(frame);
+        self.run_stp(|stp, now| match bpdu {
+            Some(ref bpdu) => stp.receive(port, bpdu, now),
+            None => Actions::default(),
+        })
+    }
+
+    /// Runs `f` on the spanning tree, if enabled, and carries out the
+    /// resulting actions. Returns false if the spanning tree is disabled.
+    fn run_stp<F>(&self, f: F) -> bool
+        where F: FnOnce(&mut Stp, Instant) -> Actions
+    {
+        let (actions, mac) = {
+            let mut stp = self.stp.lock().unwrap();
+            match *stp {
+                Some(ref mut stp) => (f(stp, Instant::now()), stp.config().mac),
+                None => return false,
+            }
+        };
+        let Actions { send, flush } = actions;
+        for (port, bpdu) in send {
+            self.send(port, &bpdu.frame(mac));
+        }
+        if !flush.is_empty() {
+            let mut fdb = self.fdb.lock().unwrap();
+            let flushed = fdb.iter()
+                .filter(|&(_, entry)| flush.contains(&entry.port))
+                .map(|(mac, _)| *mac)
+                .collect::<Vec<_>>();
+            for mac in flushed {
+                fdb.remove(&mac);
+            }
+        }
+        true
+    }
+
+    /// Hands the multicast `frame` to IGMP snooping. Returns the ports to
+    /// forward it to, or `None` to flood it.
+    fn snoop(&self, ingress: Option<PortId>, frame: &[u8]) -> Option<Vec<PortId>> {
+        match *self.snooping.lock().unwrap() {
+            Some(ref mut snooping) => {
+                match snooping.receive(ingress, frame, Instant::now()) {
+                    Forward::Flood => None,
+                    Forward::Ports(ports) => Some(ports),
+                }
+            }
+            None => None,
+        }
+    }
+
+    /// Advances the IGMP snooping timers, if enabled, and floods the queries
+    /// to send.
+    fn tick_snooping(&self) {
+        let queries = match *self.snooping.lock().unwrap() {
+            Some(ref mut snooping) => snooping.tick(Instant::now()),
+            None => return,
+        };
+        let ports = self.ports.read().unwrap().len();
+        for query in queries {
+            for port in (0..ports).map(PortId) {
+                if self.port_state(port) == PortState::Forwarding {
+                    self.send(port, &query);
+                }
+            }
+        }
+    }
+
+    fn send(&self, port: PortId, frame: &[u8]) {
+        let ports = self.ports.read().unwrap();
+        let port = &ports[port.0];
+        let eth_pkg = EthernetPacket::new(frame).unwrap();
+        let result = port.tx.lock().unwrap().send_to(&eth_pkg, None);
+        match result {
+            Some(Ok(())) => (),
+            Some(Err(e)) => warn!("Bridge: Unable to send on {}: {}", port.name, e),
+            None => warn!("Bridge: Unable to send on {}: Insufficient buffer space", port.name),
+        }
+    }
+
+    fn deliver(&self, frame: &[u8]) {
+        if let Some(ref local) = *self.local.lock().unwrap() {
+            local.inject.send(frame.to_vec().into_boxed_slice()).unwrap_or(());
+        }
+    }
+}
+
+/// A layer 2 bridge between any number of ports. Clones refer to the same
+/// bridge.
+#[derive(Clone)]
+pub struct Bridge {
+    data: Arc<BridgeData>,
+}
+
+impl Bridge {
+    pub fn new() -> Bridge {
+        Bridge {
+            data: Arc::new(BridgeData {
+                ports: RwLock::new(Vec::new()),
+                fdb: Mutex::new(HashMap::new()),
+                ageing_time: RwLock::new(Duration::from_secs(DEFAULT_AGEING_TIME)),
+                local: Mutex::new(None),
+                stp: Mutex::new(None),
+                snooping: Mutex::new(None),
+                timer_generation: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Adds a port named `name`, sending and receiving on `channel`. The
+    /// bridge handles everything received on the channel from now on.
+    pub fn add_port(&self, name: &str, channel: EthernetChannel) -> PortId {
+        let EthernetChannel(sender, receiver) = channel;
+        let port = {
+            let mut ports = self.data.ports.write().unwrap();
+            ports.push(Port {
+                name: name.to_owned(),
+                tx: Mutex::new(sender),
+            });
+            PortId(ports.len() - 1)
+        };
+        let port_rx = PortRx {
+            data: self.data.clone(),
+            port: port,
+        };
+        rx::spawn(receiver, port_rx);
+        self.data.run_stp(|stp, now| stp.add_port(now));
+        port
+    }
+
+    /// Returns the names of the ports, indexed by `PortId`.
+    pub fn ports(&self) -> Vec<String> {
+        self.data.ports.read().unwrap().iter().map(|port| port.name.clone()).collect()
+    }
+
+    /// Adds an interface named `name` with the MAC address `mac` to `stack`,
+    /// sending and receiving through the bridge as if it was attached to a
+    /// port of its own.
+    ///
+    /// Fails with `StackError::AlreadyExists` if the bridge already has an
+    /// interface.
+    pub fn add_interface(&self,
+                         stack: &mut NetworkStack,
+                         name: &str,
+                         mac: MacAddr)
+                         -> StackResult<Interface> {
+        let interface = Interface {
+            name: name.to_owned(),
+            mac: mac,
+        };
+        let (inject, frames) = mpsc::channel();
+        {
+            let mut local = self.data.local.lock().unwrap();
+            if local.is_some() {
+                return Err(StackError::AlreadyExists("Interface of the bridge".to_owned()));
+            }
+            *local = Some(Local {
+                mac: mac,
+                inject: inject,
+            });
+        }
+        let sender = BridgeSender { data: self.data.clone() };
+        let channel = EthernetChannel(Box::new(sender), Box::new(ChannelReceiver::new(frames)));
+        if let Err(e) = stack.add_interface(interface.clone(), channel) {
+            *self.data.local.lock().unwrap() = None;
+            return Err(e);
+        }
+        Ok(interface)
+    }
+
+    /// Sets how long learned MAC addresses are remembered after they were
+    /// last seen.
+    pub fn set_ageing_time(&self, ageing_time: Duration) {
+        *self.data.ageing_time.write().unwrap() = ageing_time;
+    }
+
+    pub fn ageing_time(&self) -> Duration {
+        *self.data.ageing_time.read().unwrap()
+    }
+
+    /// Returns the MAC addresses learned and not yet expired.
+    pub fn fdb(&self) -> Fdb {
+        let ageing_time = self.ageing_time();
+        self.data
+            .fdb
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, entry)| entry.seen.elapsed() < ageing_time)
+            .map(|(mac, entry)| (*mac, entry.port))
+            .collect()
+    }
+
+    /// Forgets all learned MAC addresses.
+    pub fn flush_fdb(&self) {
+        self.data.fdb.lock().unwrap().clear();
+    }
+
+    /// Starts taking part in the spanning tree with `config`, or starts over
+    /// with it if already enabled. All ports start out designated and
+    /// discarding, and have the default cost and are not edge ports.
+    pub fn enable_stp(&self, config: StpConfig) {
+        {
+            let mut stp = self.data.stp.lock().unwrap();
+            let ports = self.data.ports.read().unwrap().len();
+            *stp = Some(Stp::new(config, ports, Instant::now()));
+        }
+        self.restart_timers();
+    }
+
+    /// Stops taking part in the spanning tree. All ports forward again.
+    pub fn disable_stp(&self) {
+        *self.data.stp.lock().unwrap() = None;
+        self.restart_timers();
+    }
+
+    pub fn stp_config(&self) -> Option<StpConfig> {
+        self.data.stp.lock().unwrap().as_ref().map(|stp| stp.config())
+    }
+
+    /// Returns the spanning tree role and state of `port`, if the spanning
+    /// tree is enabled.
+    pub fn port_status(&self, port: PortId) -> Option<PortStatus> {
+        self.data.stp.lock().unwrap().as_ref().and_then(|stp| stp.status(port))
+    }
+
+    /// Returns the identifier of the root bridge, if the spanning tree is
+    /// enabled.
+    pub fn root_bridge(&self) -> Option<BridgeId> {
+        self.data.stp.lock().unwrap().as_ref().map(|stp| stp.root())
+    }
+
+    /// Returns the port towards the root bridge. `None` if this is the root
+    /// bridge or the spanning tree is disabled.
+    pub fn root_port(&self) -> Option<PortId> {
+        self.data.stp.lock().unwrap().as_ref().and_then(|stp| stp.root_port())
+    }
+
+    /// Sets the spanning tree path cost of `port`.
+    ///
+    /// Fails with `StackError::NotFound` if the spanning tree is disabled or
+    /// `port` does not exist.
+    pub fn set_port_cost(&self, port: PortId, cost: u32) -> StackResult<()> {
+        self.check_stp_port(port)?;
+        self.data.run_stp(|stp, now| stp.set_cost(port, cost, now));
+        Ok(())
+    }
+
+    /// Makes `port` an edge port, forwarding without waiting for the
+    /// spanning tree, or a normal port again. Ports stop being edge ports
+    /// when they receive a BPDU.
+    ///
+    /// Fails with `StackError::NotFound` if the spanning tree is disabled or
+    /// `port` does not exist.
+    pub fn set_edge_port(&self, port: PortId, edge: bool) -> StackResult<()> {
+        self.check_stp_port(port)?;
+        self.data.run_stp(|stp, now| stp.set_edge(port, edge, now));
+        Ok(())
+    }
+
+    fn check_stp_port(&self, port: PortId) -> StackResult<()> {
+        match self.port_status(port) {
+            Some(..) => Ok(()),
+            None => Err(StackError::NotFound(format!("Spanning tree port {}", port.0))),
+        }
+    }
+
+    /// Starts IGMP snooping with `config`, or starts over with it if already
+    /// enabled. No memberships are known at first, so multicast only reaches
+    /// the members that have reported since.
+    pub fn enable_igmp_snooping(&self, config: SnoopingConfig) {
+        *self.data.snooping.lock().unwrap() = Some(Snooping::new(config, Instant::now()));
+        self.restart_timers();
+    }
+
+    /// Stops IGMP snooping. All multicast is flooded again.
+    pub fn disable_igmp_snooping(&self) {
+        *self.data.snooping.lock().unwrap() = None;
+        self.restart_timers();
+    }
+
+    pub fn igmp_snooping_config(&self) -> Option<SnoopingConfig> {
+        self.data.snooping.lock().unwrap().as_ref().map(|snooping| snooping.config())
+    }
+
+    /// Returns the member ports of each multicast group. Empty if IGMP
+    /// snooping is disabled.
+    pub fn multicast_groups(&self) -> HashMap<Ipv4Addr, Vec<PortId>> {
+        match *self.data.snooping.lock().unwrap() {
+            Some(ref snooping) => snooping.groups(),
+            None => HashMap::new(),
+        }
+    }
+
+    /// Returns the ports multicast routers were heard on. Empty if IGMP
+    /// snooping is disabled.
+    pub fn multicast_router_ports(&self) -> Vec<PortId> {
+        match *self.data.snooping.lock().unwrap() {
+            Some(ref snooping) => snooping.routers(),
+            None => vec![],
+        }
+    }
+
+    /// Stops the thread running the timers, and starts a new one if the
+    /// spanning tree or IGMP snooping is enabled.
+    fn restart_timers(&self) {
+        let generation = self.data.timer_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.data.stp.lock().unwrap().is_some() ||
+           self.data.snooping.lock().unwrap().is_some() {
+            let data = Arc::downgrade(&self.data);
+            thread::spawn(move || run_timers(data, generation));
+        }
+    }
+}
+
+/// Advances the spanning tree and IGMP snooping timers of the bridge until
+/// it is gone or the timers are restarted.
+fn run_timers(data: Weak<BridgeData>, generation: usize) {
+    loop {
+        thread::sleep(Duration::from_millis(TICK_MS));
+        let data = match data.upgrade() {
+            Some(data) => data,
+            None => break,
+        };
+        if data.timer_generation.load(Ordering::SeqCst) != generation {
+            break;
+        }
+        data.run_stp(|stp, now| stp.tick(now));
+        data.tick_snooping();
+    }
+    debug!("Bridge timer thread is quitting");
+}
+
+impl Default for Bridge {
+    fn default() -> Bridge {
+        Bridge::new()
+    }
+}
+
+/// Hands the frames received on a port to the bridge.
+struct PortRx {
+    data: Arc<BridgeData>,
+    port: PortId,
+}
+
+impl RxListener for PortRx {
+    fn recv(&mut self, _time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        self.data.forward(Some(self.port), packet.packet());
+        Ok(())
+    }
+}
+
+/// Sending half of the channel of the interface on a bridge.
+struct BridgeSender {
+    data: Arc<BridgeData>,
+}
+
+impl EthernetDataLinkSender for BridgeSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0; packet_size];
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            self.data.forward(None, &buffer);
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        self.data.forward(None, packet.packet());
+        Some(Ok(()))
+    }
+}
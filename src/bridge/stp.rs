@@ -0,0 +1,802 @@
+//! Rapid Spanning Tree Protocol, IEEE 802.1D-2004 clause 17.
+//!
+//! Bridges exchange BPDUs to elect a root bridge and give every port a role
+//! in the tree spanning the network. Ports that would close a loop become
+//! alternate or backup ports and discard all frames. Designated ports start
+//! forwarding as soon as the bridge on the other end agrees to their
+//! proposal, or else after twice the forward delay.
+//!
+//! `Stp` is the state machine of one bridge. It does no I/O itself, the
+//! BPDUs to send and the learned addresses to flush are returned as
+//! `Actions`. Legacy STP configuration and topology change BPDUs are
+//! understood, but only RST BPDUs are sent.
+
+use pnet::util::MacAddr;
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::PortId;
+
+/// The group address BPDUs are sent to.
+pub static BPDU_ADDR: MacAddr = MacAddr(0x01, 0x80, 0xc2, 0x00, 0x00, 0x00);
+
+pub static DEFAULT_PRIORITY: u16 = 32768;
+pub static DEFAULT_PORT_PRIORITY: u8 = 128;
+
+/// Path cost of a port by default, that of a 1 Gb/s link.
+pub static DEFAULT_PATH_COST: u32 = 20_000;
+
+pub const FLAG_TC: u8 = 0x01;
+pub const FLAG_PROPOSAL: u8 = 0x02;
+pub const FLAG_LEARNING: u8 = 0x10;
+pub const FLAG_FORWARDING: u8 = 0x20;
+pub const FLAG_AGREEMENT: u8 = 0x40;
+pub const FLAG_TC_ACK: u8 = 0x80;
+
+const ROLE_SHIFT: u8 = 2;
+const ROLE_MASK: u8 = 0x03 << ROLE_SHIFT;
+
+/// The LLC header of BPDUs, with the spanning tree SAPs.
+const LLC_HEADER: [u8; 3] = [0x42, 0x42, 0x03];
+
+const CONFIG_BPDU_LEN: usize = 35;
+const RST_BPDU_LEN: usize = 36;
+const TCN_BPDU_LEN: usize = 4;
+
+/// Priority in the upper 16 bits, MAC address in the lower 48. Lower is
+/// better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BridgeId(pub u64);
+
+impl BridgeId {
+    pub fn new(priority: u16, mac: MacAddr) -> BridgeId {
+        let MacAddr(a, b, c, d, e, f) = mac;
+        let mac = [a, b, c, d, e, f].iter().fold(0, |id, &byte| id << 8 | byte as u64);
+        BridgeId((priority as u64) << 48 | mac)
+    }
+
+    pub fn priority(&self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        let byte = |n: u64| (self.0 >> (8 * n)) as u8;
+        MacAddr::new(byte(5), byte(4), byte(3), byte(2), byte(1), byte(0))
+    }
+}
+
+/// What a BPDU tells about the tree, compared field by field. Lower is
+/// better.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PriorityVector {
+    pub root: BridgeId,
+    pub root_cost: u32,
+    /// The bridge sending the BPDU
+    pub bridge: BridgeId,
+    /// The port sending the BPDU, priority in the upper 4 bits and port
+    /// number in the lower 12
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortRole {
+    /// The port towards the root bridge
+    Root,
+    /// The port towards the segment it connects to the tree
+    Designated,
+    /// Another way towards the root bridge, discarding
+    Alternate,
+    /// Another way to a segment this bridge is designated for, discarding
+    Backup,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Discarding,
+    /// Learning addresses, but not forwarding yet
+    Learning,
+    Forwarding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStatus {
+    pub role: PortRole,
+    pub state: PortState,
+    /// If it's an edge port, with no bridge on the other end
+    pub edge: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BpduType {
+    Config,
+    Rst,
+    TopologyChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bpdu {
+    pub bpdu_type: BpduType,
+    pub flags: u8,
+    pub vector: PriorityVector,
+    /// Times in 1/256 seconds
+    pub message_age: u16,
+    pub max_age: u16,
+    pub hello_time: u16,
+    pub forward_delay: u16,
+}
+
+impl Bpdu {
+    /// Parses the BPDU in the ethernet frame `frame`. Returns `None` if
+    /// `frame` is not a valid BPDU.
+    pub fn parse(frame: &[u8]) -> Option<Bpdu> {
+        let header_len = 14 + LLC_HEADER.len();
+        if frame.len() < header_len + TCN_BPDU_LEN || frame[..6] != BPDU_ADDR_BYTES ||
+           frame[14..header_len] != LLC_HEADER {
+            return None;
+        }
+        let bpdu = &frame[header_len..];
+        if bpdu[0] != 0 || bpdu[1] != 0 {
+            return None;
+        }
+        let bpdu_type = match bpdu[3] {
+            0x00 if bpdu.len() >= CONFIG_BPDU_LEN => BpduType::Config,
+            0x02 if bpdu.len() >= RST_BPDU_LEN && bpdu[2] >= 2 => BpduType::Rst,
+            0x80 => BpduType::TopologyChange,
+            _ => return None,
+        };
+        if bpdu_type == BpduType::TopologyChange {
+            return Some(Bpdu {
+                bpdu_type: bpdu_type,
+                flags: 0,
+                vector: PriorityVector {
+                    root: BridgeId(0),
+                    root_cost: 0,
+                    bridge: BridgeId(0),
+                    port: 0,
+                },
+                message_age: 0,
+                max_age: 0,
+                hello_time: 0,
+                forward_delay: 0,
+            });
+        }
+        let read16 = |at: usize| (bpdu[at] as u16) << 8 | bpdu[at + 1] as u16;
+        let read32 = |at: usize| (read16(at) as u32) << 16 | read16(at + 2) as u32;
+        let read64 = |at: usize| (read32(at) as u64) << 32 | read32(at + 4) as u64;
+        Some(Bpdu {
+            bpdu_type: bpdu_type,
+            flags: bpdu[4],
+            vector: PriorityVector {
+                root: BridgeId(read64(5)),
+                root_cost: read32(13),
+                bridge: BridgeId(read64(17)),
+                port: read16(25),
+            },
+            message_age: read16(27),
+            max_age: read16(29),
+            hello_time: read16(31),
+            forward_delay: read16(33),
+        })
+    }
+
+    /// Returns the ethernet frame carrying this BPDU, sent from `src`.
+    pub fn frame(&self, src: MacAddr) -> Vec<u8> {
+        let (bpdu_type, version, len) = match self.bpdu_type {
+            BpduType::Config => (0x00, 0, CONFIG_BPDU_LEN),
+            BpduType::Rst => (0x02, 2, RST_BPDU_LEN),
+            BpduType::TopologyChange => (0x80, 0, TCN_BPDU_LEN),
+        };
+        let llc_len = LLC_HEADER.len() + len;
+        let MacAddr(a, b, c, d, e, f) = src;
+        let mut frame = BPDU_ADDR_BYTES.to_vec();
+        frame.extend_from_slice(&[a, b, c, d, e, f, (llc_len >> 8) as u8, llc_len as u8]);
+        frame.extend_from_slice(&LLC_HEADER);
+        frame.extend_from_slice(&[0, 0, version, bpdu_type]);
+        if self.bpdu_type != BpduType::TopologyChange {
+            frame.push(self.flags);
+            push64(&mut frame, self.vector.root.0);
+            push32(&mut frame, self.vector.root_cost);
+            push64(&mut frame, self.vector.bridge.0);
+            for &value in &[self.vector.port,
+                            self.message_age,
+                            self.max_age,
+                            self.hello_time,
+                            self.forward_delay] {
+                push16(&mut frame, value);
+            }
+        }
+        if self.bpdu_type == BpduType::Rst {
+            // Version 1 length
+            frame.push(0);
+        }
+        // Pad to the minimum ethernet frame length
+        let min_len = cmp::max(frame.len(), 60);
+        frame.resize(min_len, 0);
+        frame
+    }
+
+    /// Returns the role of the port that sent this BPDU, if known.
+    /// Configuration BPDUs are only sent by designated ports.
+    pub fn role(&self) -> Option<PortRole> {
+        match self.bpdu_type {
+            BpduType::Config => Some(PortRole::Designated),
+            BpduType::TopologyChange => None,
+            BpduType::Rst => {
+                match (self.flags & ROLE_MASK) >> ROLE_SHIFT {
+                    0b01 => Some(PortRole::Alternate),
+                    0b10 => Some(PortRole::Root),
+                    0b11 => Some(PortRole::Designated),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+const BPDU_ADDR_BYTES: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x00];
+
+fn push16(frame: &mut Vec<u8>, value: u16) {
+    frame.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+}
+
+fn push32(frame: &mut Vec<u8>, value: u32) {
+    push16(frame, (value >> 16) as u16);
+    push16(frame, value as u16);
+}
+
+fn push64(frame: &mut Vec<u8>, value: u64) {
+    push32(frame, (value >> 32) as u32);
+    push32(frame, value as u32);
+}
+
+/// Converts `duration` to the 1/256 seconds used in BPDUs.
+fn to_ticks(duration: Duration) -> u16 {
+    (duration.as_secs() * 256 + duration.subsec_nanos() as u64 * 256 / 1_000_000_000) as u16
+}
+
+fn from_ticks(ticks: u16) -> Duration {
+    Duration::new(ticks as u64 / 256, ((ticks as u64 % 256) * 1_000_000_000 / 256) as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StpConfig {
+    /// The MAC address in the bridge identifier and the source of BPDUs
+    pub mac: MacAddr,
+    pub priority: u16,
+    pub hello_time: Duration,
+    pub max_age: Duration,
+    pub forward_delay: Duration,
+}
+
+impl StpConfig {
+    /// The default priority and times, for a bridge with the address `mac`.
+    pub fn new(mac: MacAddr) -> StpConfig {
+        StpConfig {
+            mac: mac,
+            priority: DEFAULT_PRIORITY,
+            hello_time: Duration::from_secs(2),
+            max_age: Duration::from_secs(20),
+            forward_delay: Duration::from_secs(15),
+        }
+    }
+}
+
+/// What to do after handing something to `Stp`.
+#[derive(Debug, Default)]
+pub struct Actions {
+    /// BPDUs to send and the ports to send them on
+    pub send: Vec<(PortId, Bpdu)>,
+    /// Ports to forget the addresses learned on, after a topology change
+    pub flush: Vec<PortId>,
+}
+
+/// The best BPDU received on a port from the designated bridge of its
+/// segment.
+#[derive(Debug, Clone, Copy)]
+struct Received {
+    vector: PriorityVector,
+    message_age: u16,
+    expires: Instant,
+}
+
+#[derive(Debug)]
+struct StpPort {
+    id: u16,
+    cost: u32,
+    edge: bool,
+    role: PortRole,
+    state: PortState,
+    received: Option<Received>,
+    /// Waiting for an agreement to a proposal to forward
+    proposing: bool,
+    /// When the next forward delay state transition is due
+    transition: Option<Instant>,
+}
+
+/// The spanning tree state of a bridge.
+#[derive(Debug)]
+pub struct Stp {
+    config: StpConfig,
+    id: BridgeId,
+    ports: Vec<StpPort>,
+    root: PriorityVector,
+    root_port: Option<PortId>,
+    /// Until when sent BPDUs carry the topology change flag
+    tc_until: Option<Instant>,
+    next_hello: Instant,
+}
+
+impl Stp {
+    /// Starts with `ports` ports, all of them designated and discarding.
+    pub fn new(config: StpConfig, ports: usize, now: Instant) -> Stp {
+        let id = BridgeId::new(config.priority, config.mac);
+        let mut stp = Stp {
+            config: config,
+            id: id,
+            ports: vec![],
+            root: PriorityVector {
+                root: id,
+                root_cost: 0,
+                bridge: id,
+                port: 0,
+            },
+            root_port: None,
+            tc_until: None,
+            next_hello: now,
+        };
+        for _ in 0..ports {
+            stp.add_port(now);
+        }
+        stp
+    }
+
+    pub fn config(&self) -> StpConfig {
+        self.config
+    }
+
+    pub fn id(&self) -> BridgeId {
+        self.id
+    }
+
+    /// Returns the identifier of the root bridge.
+    pub fn root(&self) -> BridgeId {
+        self.root.root
+    }
+
+    pub fn root_port(&self) -> Option<PortId> {
+        self.root_port
+    }
+
+    /// Adds a designated port and returns the BPDU to propose forwarding on
+    /// it.
+    pub fn add_port(&mut self, now: Instant) -> Actions {
+        let number = self.ports.len() as u16 + 1;
+        self.ports.push(StpPort {
+            id: (DEFAULT_PORT_PRIORITY as u16) << 8 | (number & 0xfff),
+            cost: DEFAULT_PATH_COST,
+            edge: false,
+            role: PortRole::Designated,
+            state: PortState::Discarding,
+            received: None,
+            proposing: true,
+            transition: Some(now + self.config.forward_delay),
+        });
+        let port = PortId(self.ports.len() - 1);
+        let mut actions = Actions::default();
+        actions.send.push((port, self.bpdu(port.0, 0, now)));
+        actions
+    }
+
+    pub fn status(&self, port: PortId) -> Option<PortStatus> {
+        self.ports.get(port.0).map(|stp_port| {
+            PortStatus {
+                role: stp_port.role,
+                state: stp_port.state,
+                edge: stp_port.edge,
+            }
+        })
+    }
+
+    /// Returns the state of `port`, forwarding if it's not known.
+    pub fn state(&self, port: PortId) -> PortState {
+        self.ports.get(port.0).map_or(PortState::Forwarding, |stp_port| stp_port.state)
+    }
+
+    pub fn set_cost(&mut self, port: PortId, cost: u32, now: Instant) -> Actions {
+        let mut actions = Actions::default();
+        if let Some(stp_port) = self.ports.get_mut(port.0) {
+            stp_port.cost = cost;
+        }
+        if self.update_roles(now, &mut actions) {
+            self.send_designated(now, &mut actions);
+        }
+        actions
+    }
+
+    /// Makes `port` an edge port, going forwarding right away while
+    /// designated, or a normal port again. Receiving a BPDU on an edge port
+    /// makes it a normal port.
+    pub fn set_edge(&mut self, port: PortId, edge: bool, now: Instant) -> Actions {
+        let mut actions = Actions::default();
+        if port.0 < self.ports.len() {
+            self.ports[port.0].edge = edge;
+            if edge && self.ports[port.0].role == PortRole::Designated {
+                self.ports[port.0].proposing = false;
+                self.ports[port.0].transition = None;
+                self.set_state(port.0, PortState::Forwarding, now, &mut actions);
+            }
+        }
+        actions
+    }
+
+    /// Processes `bpdu`, received on `port`.
+    pub fn receive(&mut self, port: PortId, bpdu: &Bpdu, now: Instant) -> Actions {
+        let mut actions = Actions::default();
+        let i = port.0;
+        if i >= self.ports.len() {
+            return actions;
+        }
+        self.ports[i].edge = false;
+        if bpdu.bpdu_type == BpduType::TopologyChange || bpdu.flags & FLAG_TC != 0 {
+            self.topology_change(i, now, &mut actions);
+        }
+        match bpdu.role() {
+            Some(PortRole::Designated) => {
+                if bpdu.message_age >= bpdu.max_age {
+                    return actions;
+                }
+                let hello_time = from_ticks(bpdu.hello_time) * 3;
+                let max_age = from_ticks(bpdu.max_age - bpdu.message_age);
+                self.ports[i].received = Some(Received {
+                    vector: bpdu.vector,
+                    message_age: bpdu.message_age,
+                    expires: now + cmp::min(hello_time, max_age),
+                });
+                let changed = self.update_roles(now, &mut actions);
+                if self.ports[i].role == PortRole::Root && bpdu.flags & FLAG_PROPOSAL != 0 {
+                    self.sync(i, now, &mut actions);
+                    actions.send.push((port, self.bpdu(i, FLAG_AGREEMENT, now)));
+                }
+                if changed {
+                    self.send_designated(now, &mut actions);
+                }
+            }
+            Some(_) => {
+                // The other end is not designated on this segment, so what
+                // it sent before is outdated
+                let outdated = self.ports[i]
+                    .received
+                    .map_or(false, |received| received.vector.bridge == bpdu.vector.bridge);
+                if outdated {
+                    self.ports[i].received = None;
+                    if self.update_roles(now, &mut actions) {
+                        self.send_designated(now, &mut actions);
+                    }
+                }
+                let agreed = bpdu.flags & FLAG_AGREEMENT != 0;
+                if agreed && self.ports[i].role == PortRole::Designated && self.ports[i].proposing {
+                    self.ports[i].proposing = false;
+                    self.ports[i].transition = None;
+                    self.set_state(i, PortState::Forwarding, now, &mut actions);
+                }
+            }
+            None => (),
+        }
+        actions
+    }
+
+    /// Advances the timers to `now`.
+    pub fn tick(&mut self, now: Instant) -> Actions {
+        let mut actions = Actions::default();
+        let mut expired = false;
+        for stp_port in &mut self.ports {
+            if stp_port.received.map_or(false, |received| received.expires <= now) {
+                stp_port.received = None;
+                expired = true;
+            }
+        }
+        if expired && self.update_roles(now, &mut actions) {
+            self.send_designated(now, &mut actions);
+        }
+        for i in 0..self.ports.len() {
+            match self.ports[i].transition {
+                Some(transition) if transition <= now => (),
+                _ => continue,
+            }
+            if self.ports[i].state == PortState::Discarding {
+                self.ports[i].transition = Some(now + self.config.forward_delay);
+                self.set_state(i, PortState::Learning, now, &mut actions);
+            } else {
+                self.ports[i].transition = None;
+                self.ports[i].proposing = false;
+                self.set_state(i, PortState::Forwarding, now, &mut actions);
+            }
+        }
+        if self.tc_until.map_or(false, |tc_until| tc_until <= now) {
+            self.tc_until = None;
+        }
+        if now >= self.next_hello {
+            self.next_hello = now + self.config.hello_time;
+            self.send_designated(now, &mut actions);
+        }
+        actions
+    }
+
+    /// Chooses the root port and the roles of all other ports. Returns true
+    /// if anything changed.
+    fn update_roles(&mut self, now: Instant, actions: &mut Actions) -> bool {
+        let mut best = PriorityVector {
+            root: self.id,
+            root_cost: 0,
+            bridge: self.id,
+            port: 0,
+        };
+        let mut best_port: Option<(usize, u16)> = None;
+        for (i, stp_port) in self.ports.iter().enumerate() {
+            let received = match stp_port.received {
+                Some(received) if received.vector.bridge != self.id => received,
+                _ => continue,
+            };
+            let candidate = PriorityVector {
+                root_cost: received.vector.root_cost.saturating_add(stp_port.cost),
+                ..received.vector
+            };
+            let better = match best_port {
+                Some((_, best_id)) => (candidate, stp_port.id) < (best, best_id),
+                None => candidate < best,
+            };
+            if better {
+                best = candidate;
+                best_port = Some((i, stp_port.id));
+            }
+        }
+        let root_port = best_port.map(|(i, _)| PortId(i));
+        let mut changed = best != self.root || root_port != self.root_port;
+        self.root = best;
+        self.root_port = root_port;
+
+        for i in 0..self.ports.len() {
+            let designated = self.designated_vector(i);
+            let role = if root_port == Some(PortId(i)) {
+                PortRole::Root
+            } else {
+                match self.ports[i].received {
+                    Some(received) if received.vector < designated => {
+                        if received.vector.bridge == self.id {
+                            PortRole::Backup
+                        } else {
+                            PortRole::Alternate
+                        }
+                    }
+                    _ => PortRole::Designated,
+                }
+            };
+            if role != self.ports[i].role {
+                self.set_role(i, role, now, actions);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn set_role(&mut self, i: usize, role: PortRole, now: Instant, actions: &mut Actions) {
+        self.ports[i].role = role;
+        self.ports[i].proposing = false;
+        self.ports[i].transition = None;
+        match role {
+            PortRole::Root => self.set_state(i, PortState::Forwarding, now, actions),
+            PortRole::Designated => {
+                if self.ports[i].edge {
+                    self.set_state(i, PortState::Forwarding, now, actions);
+                } else if self.ports[i].state != PortState::Forwarding {
+                    self.ports[i].proposing = true;
+                    self.ports[i].transition = Some(now + self.config.forward_delay);
+                }
+            }
+            PortRole::Alternate | PortRole::Backup => {
+                self.set_state(i, PortState::Discarding, now, actions)
+            }
+        }
+    }
+
+    fn set_state(&mut self, i: usize, state: PortState, now: Instant, actions: &mut Actions) {
+        let was_forwarding = self.ports[i].state == PortState::Forwarding;
+        self.ports[i].state = state;
+        if state == PortState::Forwarding && !was_forwarding && !self.ports[i].edge {
+            self.topology_change(i, now, actions);
+        }
+    }
+
+    /// Flushes the addresses learned on all ports but `i`, and starts
+    /// telling the other bridges about it.
+    fn topology_change(&mut self, i: usize, now: Instant, actions: &mut Actions) {
+        actions.flush.extend((0..self.ports.len()).filter(|&j| j != i).map(PortId));
+        self.tc_until = Some(now + self.config.hello_time * 2);
+    }
+
+    /// Puts all other non edge designated ports back to discarding before
+    /// agreeing to the proposal received on the root port `i`, so agreeing
+    /// can not create a loop.
+    fn sync(&mut self, i: usize, now: Instant, actions: &mut Actions) {
+        for j in 0..self.ports.len() {
+            let stp_port = &mut self.ports[j];
+            if j == i || stp_port.edge || stp_port.role != PortRole::Designated ||
+               stp_port.proposing {
+                continue;
+            }
+            stp_port.state = PortState::Discarding;
+            stp_port.proposing = true;
+            stp_port.transition = Some(now + self.config.forward_delay);
+        }
+        for j in 0..self.ports.len() {
+            if j != i && self.ports[j].role == PortRole::Designated && self.ports[j].proposing {
+                actions.send.push((PortId(j), self.bpdu(j, 0, now)));
+            }
+        }
+    }
+
+    fn send_designated(&self, now: Instant, actions: &mut Actions) {
+        for i in 0..self.ports.len() {
+            if self.ports[i].role == PortRole::Designated {
+                actions.send.push((PortId(i), self.bpdu(i, 0, now)));
+            }
+        }
+    }
+
+    fn designated_vector(&self, i: usize) -> PriorityVector {
+        PriorityVector {
+            root: self.root.root,
+            root_cost: self.root.root_cost,
+            bridge: self.id,
+            port: self.ports[i].id,
+        }
+    }
+
+    /// Returns the BPDU to send on port `i`, with `flags` set on top of the
+    /// ones from the state of the port.
+    fn bpdu(&self, i: usize, flags: u8, now: Instant) -> Bpdu {
+        let stp_port = &self.ports[i];
+        let role = match stp_port.role {
+            PortRole::Root => 0b10,
+            PortRole::Designated => 0b11,
+            PortRole::Alternate | PortRole::Backup => 0b01,
+        };
+        let mut flags = flags | role << ROLE_SHIFT;
+        if stp_port.state != PortState::Discarding {
+            flags |= FLAG_LEARNING;
+        }
+        if stp_port.state == PortState::Forwarding {
+            flags |= FLAG_FORWARDING;
+        }
+        if stp_port.proposing && stp_port.role == PortRole::Designated {
+            flags |= FLAG_PROPOSAL;
+        }
+        if self.tc_until.map_or(false, |tc_until| tc_until > now) {
+            flags |= FLAG_TC;
+        }
+        let message_age = match self.root_port {
+            Some(root_port) => {
+                self.ports[root_port.0]
+                    .received
+                    .map_or(0, |received| received.message_age.saturating_add(256))
+            }
+            None => 0,
+        };
+        Bpdu {
+            bpdu_type: BpduType::Rst,
+            flags: flags,
+            vector: self.designated_vector(i),
+            message_age: message_age,
+            max_age: to_ticks(self.config.max_age),
+            hello_time: to_ticks(self.config.hello_time),
+            forward_delay: to_ticks(self.config.forward_delay),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::util::MacAddr;
+
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use super::super::PortId;
+
+    fn superior_bpdu(port: u16, flags: u8) -> Bpdu {
+        let root = BridgeId::new(0, MacAddr::new(2, 0, 0, 0, 0, 1));
+        Bpdu {
+            bpdu_type: BpduType::Rst,
+            flags: flags | 0b11 << ROLE_SHIFT,
+            vector: PriorityVector {
+                root: root,
+                root_cost: 0,
+                bridge: root,
+                port: port,
+            },
+            message_age: 0,
+            max_age: 20 * 256,
+            hello_time: 2 * 256,
+            forward_delay: 15 * 256,
+        }
+    }
+
+    fn stp(ports: usize, now: Instant) -> Stp {
+        Stp::new(StpConfig::new(MacAddr::new(2, 0, 0, 0, 0, 9)), ports, now)
+    }
+
+    #[test]
+    fn bpdu_frame() {
+        let bpdu = superior_bpdu(0x8001, FLAG_PROPOSAL | FLAG_TC);
+        let frame = bpdu.frame(MacAddr::new(2, 0, 0, 0, 0, 1));
+        assert_eq!(frame.len(), 60);
+        assert_eq!(&frame[12..17], &[0, 39, 0x42, 0x42, 0x03]);
+        assert_eq!(Bpdu::parse(&frame), Some(bpdu));
+        assert_eq!(bpdu.role(), Some(PortRole::Designated));
+        assert_eq!(Bpdu::parse(&frame[..20]), None);
+
+        let id = BridgeId::new(0x8001, MacAddr::new(1, 2, 3, 4, 5, 6));
+        assert_eq!(id.0, 0x8001_0102_0304_0506);
+        assert_eq!(id.priority(), 0x8001);
+        assert_eq!(id.mac(), MacAddr::new(1, 2, 3, 4, 5, 6));
+    }
+
+    #[test]
+    fn block_redundant_port() {
+        let now = Instant::now();
+        let mut stp = stp(3, now);
+        assert_eq!(stp.root(), stp.id());
+        stp.receive(PortId(0), &superior_bpdu(0x8001, 0), now);
+        stp.receive(PortId(1), &superior_bpdu(0x8002, 0), now);
+        assert_eq!(stp.root_port(), Some(PortId(0)));
+        assert_eq!(stp.root(), superior_bpdu(0, 0).vector.root);
+        let status = stp.status(PortId(1)).unwrap();
+        assert_eq!((status.role, status.state), (PortRole::Alternate, PortState::Discarding));
+        assert_eq!(stp.status(PortId(2)).unwrap().role, PortRole::Designated);
+
+        // Losing the root port fails over to the alternate one
+        let later = now + Duration::from_secs(7);
+        stp.receive(PortId(1), &superior_bpdu(0x8002, 0), later);
+        stp.tick(later);
+        assert_eq!(stp.root_port(), Some(PortId(1)));
+        assert_eq!(stp.state(PortId(1)), PortState::Forwarding);
+        assert_eq!(stp.status(PortId(0)).unwrap().role, PortRole::Designated);
+    }
+
+    #[test]
+    fn proposal_and_agreement() {
+        let now = Instant::now();
+        let mut stp = stp(2, now);
+        let actions = stp.receive(PortId(0), &superior_bpdu(0x8001, FLAG_PROPOSAL), now);
+        let (port, agreement) = *actions.send.iter().find(|&&(port, _)| port == PortId(0)).unwrap();
+        assert_eq!(port, PortId(0));
+        assert_eq!(agreement.role(), Some(PortRole::Root));
+        assert!(agreement.flags & FLAG_AGREEMENT != 0);
+        assert_eq!(agreement.vector.root_cost, DEFAULT_PATH_COST);
+
+        // The designated port forwards once the other end agrees
+        assert_eq!(stp.state(PortId(1)), PortState::Discarding);
+        let mut reply = agreement;
+        reply.flags = FLAG_AGREEMENT | 0b10 << ROLE_SHIFT;
+        reply.vector.bridge = BridgeId::new(DEFAULT_PRIORITY, MacAddr::new(2, 0, 0, 0, 0, 8));
+        reply.vector.root_cost += DEFAULT_PATH_COST;
+        let actions = stp.receive(PortId(1), &reply, now);
+        assert_eq!(stp.state(PortId(1)), PortState::Forwarding);
+        assert_eq!(actions.flush, vec![PortId(0)]);
+    }
+
+    #[test]
+    fn forward_delay() {
+        let now = Instant::now();
+        let mut stp = stp(1, now);
+        let actions = stp.tick(now);
+        assert_eq!(actions.send.len(), 1);
+        assert!(actions.send[0].1.flags & FLAG_PROPOSAL != 0);
+        assert_eq!(stp.state(PortId(0)), PortState::Discarding);
+        stp.tick(now + Duration::from_secs(15));
+        assert_eq!(stp.state(PortId(0)), PortState::Learning);
+        stp.tick(now + Duration::from_secs(30));
+        assert_eq!(stp.state(PortId(0)), PortState::Forwarding);
+    }
+}
@@ -0,0 +1,122 @@
+//! Following the carrier of the interfaces of a stack.
+//!
+//! When the cable of an interface is pulled its routes should no longer be
+//! used, so traffic fails over to routes through other interfaces. The
+//! stack is told about carrier changes with `NetworkStack::set_carrier`. A
+//! `CarrierMonitor` does so by polling the link state the OS reports for
+//! each interface in sysfs, which only exists on Linux. Elsewhere, or for
+//! interfaces the OS does not know, such as dummy ones, applications call
+//! `set_carrier` themselves.
+
+use NetworkStack;
+
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How often a `CarrierMonitor` started with `CarrierMonitor::start` looks at
+/// the link state.
+pub static DEFAULT_INTERVAL_MS: u64 = 500;
+
+/// Reads the carrier of the interface `name` from sysfs. Returns `None` if
+/// it is not known there, as on other platforms than Linux.
+pub fn sysfs_carrier(name: &str) -> Option<bool> {
+    let mut carrier = String::new();
+    match File::open(format!("/sys/class/net/{}/carrier", name)) {
+        // Reading fails while the interface is administratively down
+        Ok(mut file) => {
+            if file.read_to_string(&mut carrier).is_err() {
+                return Some(false);
+            }
+        }
+        Err(_) => return None,
+    }
+    match carrier.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// A thread updating the carrier of the interfaces of a stack from sysfs.
+/// Stops when dropped.
+pub struct CarrierMonitor {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CarrierMonitor {
+    /// Starts monitoring all interfaces of `stack`, including ones added
+    /// later, every `DEFAULT_INTERVAL_MS`.
+    pub fn start(stack: Arc<Mutex<NetworkStack>>) -> CarrierMonitor {
+        Self::with_interval(stack, Duration::from_millis(DEFAULT_INTERVAL_MS))
+    }
+
+    /// Same as `start`, but looks at the link state every `interval`.
+    pub fn with_interval(stack: Arc<Mutex<NetworkStack>>, interval: Duration) -> CarrierMonitor {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                update(&stack, sysfs_carrier);
+                thread::sleep(interval);
+            }
+        });
+        CarrierMonitor {
+            stop: stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the monitoring thread and waits for it to quit.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for CarrierMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Sets the carrier of every interface of `stack` that `carrier` knows it
+/// for.
+fn update<F: Fn(&str) -> Option<bool>>(stack: &Mutex<NetworkStack>, carrier: F) {
+    let mut stack = stack.lock().unwrap();
+    for interface in stack.interfaces() {
+        if let Some(carrier) = carrier(&interface.name) {
+            stack.set_carrier(&interface, carrier).unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use testing;
+
+    use super::*;
+
+    #[test]
+    fn unknown_interface() {
+        assert_eq!(sysfs_carrier("no-such-interface"), None);
+    }
+
+    #[test]
+    fn update_known() {
+        let (stack, interface, _, _) = testing::dummy_stack();
+        let stack = Mutex::new(stack);
+        update(&stack, |_| None);
+        assert!(stack.lock().unwrap().interface(&interface).unwrap().has_carrier());
+        update(&stack, |_| Some(false));
+        assert!(!stack.lock().unwrap().interface(&interface).unwrap().has_carrier());
+    }
+}
@@ -0,0 +1,92 @@
+//! Verifying the checksums of the packets received. The internet checksum
+//! itself is computed by `proto::checksum`, re-exported here.
+//!
+//! Received IPv4 headers, UDP datagrams and Icmp messages with invalid
+//! checksums are dropped and counted as malformed, see `malformed`. Each of
+//! the three checks can be turned off with `RxChecksums`, for backends
+//! verifying checksums in hardware, or to save the time.
+
+pub use proto::checksum::{Checksum, checksum, is_valid_udp, update};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Which checksums of the packets received are verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RxChecksums {
+    pub ipv4: bool,
+    pub udp: bool,
+    pub icmp: bool,
+}
+
+impl RxChecksums {
+    /// Verifies none of them, such as for a backend that already did.
+    pub fn none() -> RxChecksums {
+        RxChecksums {
+            ipv4: false,
+            udp: false,
+            icmp: false,
+        }
+    }
+}
+
+impl Default for RxChecksums {
+    /// Verifies all of them.
+    fn default() -> RxChecksums {
+        RxChecksums {
+            ipv4: true,
+            udp: true,
+            icmp: true,
+        }
+    }
+}
+
+/// The `RxChecksums` of an interface, shared with the layers receiving on it
+/// so they can be changed while receiving.
+#[derive(Debug)]
+pub struct RxChecksumControl {
+    ipv4: AtomicBool,
+    udp: AtomicBool,
+    icmp: AtomicBool,
+}
+
+impl RxChecksumControl {
+    pub fn new(checksums: RxChecksums) -> RxChecksumControl {
+        RxChecksumControl {
+            ipv4: AtomicBool::new(checksums.ipv4),
+            udp: AtomicBool::new(checksums.udp),
+            icmp: AtomicBool::new(checksums.icmp),
+        }
+    }
+
+    pub fn get(&self) -> RxChecksums {
+        RxChecksums {
+            ipv4: self.ipv4(),
+            udp: self.udp(),
+            icmp: self.icmp(),
+        }
+    }
+
+    pub fn set(&self, checksums: RxChecksums) {
+        self.ipv4.store(checksums.ipv4, Ordering::Relaxed);
+        self.udp.store(checksums.udp, Ordering::Relaxed);
+        self.icmp.store(checksums.icmp, Ordering::Relaxed);
+    }
+
+    pub fn ipv4(&self) -> bool {
+        self.ipv4.load(Ordering::Relaxed)
+    }
+
+    pub fn udp(&self) -> bool {
+        self.udp.load(Ordering::Relaxed)
+    }
+
+    pub fn icmp(&self) -> bool {
+        self.icmp.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for RxChecksumControl {
+    fn default() -> RxChecksumControl {
+        RxChecksumControl::new(RxChecksums::default())
+    }
+}
@@ -0,0 +1,82 @@
+//! Where the stack takes the time from.
+//!
+//! The parts of the stack that act on time passing, such as the timer wheels
+//! of the interfaces, see `timer`, and the timeout of fragment reassembly,
+//! read it from the `Clock` of their stack instead of from `Instant::now`.
+//! It is set with `NetworkStack::set_clock` before any interfaces are added.
+//! Tests set a `MockClock` and advance it by hand, together with a polled
+//! stack, see `NetworkStack::new_polled`, everything happens
+//! deterministically without sleeping.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of the current time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The clock of the system, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Returns the clock of the system, shared.
+pub fn system() -> Arc<Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    /// Creates a clock standing at the current time of the system.
+    pub fn new() -> MockClock {
+        MockClock { now: Mutex::new(Instant::now()) }
+    }
+
+    /// Moves the clock `duration` forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn mock_stands_still() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now().duration_since(start), Duration::from_secs(3));
+    }
+}
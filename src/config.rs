@@ -0,0 +1,427 @@
+//! Setting up a stack from a declarative configuration.
+//!
+//! A `StackConfig` describes the interfaces of a stack, with their MTUs,
+//! addresses and routes, so test topologies and deployments can be
+//! reproduced from a file instead of a sequence of calls. It is applied to
+//! a stack whose interfaces were already added, such as a testing one, with
+//! `StackConfig::apply`, or `StackConfig::open` creates a stack on the OS
+//! interfaces it names.
+//!
+//! Configurations are read from a subset of TOML: `key = value` pairs where
+//! a value is a string, an integer, a boolean or a single line array of
+//! strings, an `[[interface]]` table per interface and an
+//! `[[interface.route]]` table per route of the interface above it.
+//! Comments start with `#`. Unknown keys are rejected so typos are noticed.
+//!
+//! ```toml
+//! mtu = 1400
+//!
+//! [[interface]]
+//! name = "eth0"
+//! addresses = ["10.0.0.2/24"]
+//!
+//! [[interface.route]]
+//! net = "0.0.0.0/0"
+//! gw = "10.0.0.1"
+//! metric = 10
+//! ```
+//!
+//! The fields of the configuration are public, so it can also be built in
+//! code, or deserialized with any other format.
+
+use {NetworkStack, StackError, StackResult, convert_interface};
+
+use ipnetwork::Ipv4Network;
+
+use pnet::datalink;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Why a configuration could not be read.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Reading the file failed
+    Io(io::Error),
+    /// This line is not a table header or a `key = value` pair
+    Syntax(usize),
+    /// The key on this line is unknown, or not allowed where it is
+    UnknownKey(usize),
+    /// The value on this line has the wrong type or can not be parsed
+    InvalidValue(usize),
+    /// The interface table starting on this line has no name
+    MissingName(usize),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+/// The configuration of a whole stack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StackConfig {
+    /// MTU of the interfaces that do not set their own
+    pub mtu: Option<usize>,
+    pub interfaces: Vec<InterfaceConfig>,
+}
+
+/// The configuration of one interface, identified by its name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    pub name: String,
+    pub mtu: Option<usize>,
+    /// Networks attached with `NetworkStack::add_ipv4`
+    pub addresses: Vec<Ipv4Network>,
+    pub routes: Vec<RouteConfig>,
+    /// See `StackInterface::set_promiscuous`
+    pub promiscuous: bool,
+    /// See `StackInterface::set_nonblocking`
+    pub nonblocking: bool,
+}
+
+/// A route out on the interface it is configured on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteConfig {
+    pub net: Ipv4Network,
+    pub gw: Option<Ipv4Addr>,
+    pub metric: u32,
+}
+
+impl StackConfig {
+    /// Parses a configuration in the TOML subset described in the module
+    /// documentation.
+    pub fn from_toml_str(text: &str) -> Result<StackConfig, ConfigError> {
+        Parser::default().parse(text)
+    }
+
+    /// Reads a configuration from the TOML file at `path`.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<StackConfig, ConfigError> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Self::from_toml_str(&text)
+    }
+
+    /// Configures the interfaces of `stack` with the same names as the
+    /// configured ones. Fails with `StackError::NoSuchInterface` if one of
+    /// them is not in the stack.
+    pub fn apply(&self, stack: &mut NetworkStack) -> StackResult<()> {
+        for config in &self.interfaces {
+            let interface = stack.interfaces()
+                .into_iter()
+                .find(|interface| interface.name == config.name);
+            let interface = match interface {
+                Some(interface) => interface,
+                None => return Err(StackError::NoSuchInterface(config.name.clone())),
+            };
+            {
+                let stack_interface = stack.interface(&interface)?;
+                if let Some(mtu) = config.mtu.or(self.mtu) {
+                    stack_interface.set_mtu(mtu)?;
+                }
+                stack_interface.set_promiscuous(config.promiscuous);
+                stack_interface.set_nonblocking(config.nonblocking);
+            }
+            for net in &config.addresses {
+                stack.add_ipv4(&interface, *net)?;
+            }
+            for route in &config.routes {
+                stack.add_route_with_metric(route.net, route.gw, interface.clone(), route.metric);
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a stack managing the configured interfaces of the OS, see
+    /// `default_stack`, and applies the configuration to it.
+    pub fn open(&self) -> StackResult<NetworkStack> {
+        let mut stack = NetworkStack::new();
+        let os_interfaces = datalink::interfaces();
+        for config in &self.interfaces {
+            let os_interface = os_interfaces.iter().find(|interface| interface.name == config.name);
+            let os_interface = match os_interface {
+                Some(os_interface) => os_interface,
+                None => return Err(StackError::NoSuchInterface(config.name.clone())),
+            };
+            let interface = convert_interface(os_interface)
+                .map_err(|_| StackError::NoSuchInterface(config.name.clone()))?;
+            ::add_os_interface(&mut stack, os_interface, interface)?;
+        }
+        self.apply(&mut stack)?;
+        Ok(stack)
+    }
+}
+
+/// The table the pairs being parsed belong to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Table {
+    Stack,
+    Interface,
+    Route,
+}
+
+enum Value {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+struct Parser {
+    config: StackConfig,
+    table: Table,
+    /// Line of the header of the interface table being parsed
+    interface_line: usize,
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser {
+            config: StackConfig::default(),
+            table: Table::Stack,
+            interface_line: 0,
+        }
+    }
+}
+
+impl Parser {
+    fn parse(mut self, text: &str) -> Result<StackConfig, ConfigError> {
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                self.header(line, line_number)?;
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(ConfigError::Syntax(line_number)),
+            };
+            let value = parse_value(value).ok_or(ConfigError::Syntax(line_number))?;
+            self.pair(key, value, line_number)?;
+        }
+        self.check_name()?;
+        Ok(self.config)
+    }
+
+    fn header(&mut self, line: &str, line_number: usize) -> Result<(), ConfigError> {
+        match line {
+            "[[interface]]" => {
+                self.check_name()?;
+                self.config.interfaces.push(InterfaceConfig::default());
+                self.table = Table::Interface;
+                self.interface_line = line_number;
+            }
+            "[[interface.route]]" if self.table != Table::Stack => {
+                let net = Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap();
+                self.interface().routes.push(RouteConfig {
+                    net: net,
+                    gw: None,
+                    metric: 0,
+                });
+                self.table = Table::Route;
+            }
+            _ => return Err(ConfigError::UnknownKey(line_number)),
+        }
+        Ok(())
+    }
+
+    fn pair(&mut self, key: &str, value: Value, line_number: usize) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(line_number);
+        match (self.table, key, value) {
+            (Table::Stack, "mtu", Value::Int(mtu)) => self.config.mtu = Some(mtu as usize),
+            (Table::Interface, "name", Value::Str(name)) => self.interface().name = name,
+            (Table::Interface, "mtu", Value::Int(mtu)) => self.interface().mtu = Some(mtu as usize),
+            (Table::Interface, "addresses", Value::Array(addresses)) => {
+                for address in addresses {
+                    let net = Ipv4Network::from_str(&address).map_err(|_| invalid())?;
+                    self.interface().addresses.push(net);
+                }
+            }
+            (Table::Interface, "promiscuous", Value::Bool(b)) => self.interface().promiscuous = b,
+            (Table::Interface, "nonblocking", Value::Bool(b)) => self.interface().nonblocking = b,
+            (Table::Route, "net", Value::Str(net)) => {
+                self.route().net = Ipv4Network::from_str(&net).map_err(|_| invalid())?;
+            }
+            (Table::Route, "gw", Value::Str(gw)) => {
+                self.route().gw = Some(Ipv4Addr::from_str(&gw).map_err(|_| invalid())?);
+            }
+            (Table::Route, "metric", Value::Int(metric)) if metric <= u32::max_value() as u64 => {
+                self.route().metric = metric as u32;
+            }
+            (table, key, _) => {
+                return if is_key(table, key) {
+                    Err(invalid())
+                } else {
+                    Err(ConfigError::UnknownKey(line_number))
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn check_name(&self) -> Result<(), ConfigError> {
+        match self.config.interfaces.last() {
+            Some(interface) if interface.name.is_empty() => {
+                Err(ConfigError::MissingName(self.interface_line))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn interface(&mut self) -> &mut InterfaceConfig {
+        self.config.interfaces.last_mut().unwrap()
+    }
+
+    fn route(&mut self) -> &mut RouteConfig {
+        self.interface().routes.last_mut().unwrap()
+    }
+}
+
+static STACK_KEYS: [&'static str; 1] = ["mtu"];
+static INTERFACE_KEYS: [&'static str; 5] = ["name", "mtu", "addresses", "promiscuous",
+                                            "nonblocking"];
+static ROUTE_KEYS: [&'static str; 3] = ["net", "gw", "metric"];
+
+fn is_key(table: Table, key: &str) -> bool {
+    let keys: &[&str] = match table {
+        Table::Stack => &STACK_KEYS,
+        Table::Interface => &INTERFACE_KEYS,
+        Table::Route => &ROUTE_KEYS,
+    };
+    keys.contains(&key)
+}
+
+/// Removes a comment from the end of `line`, leaving `#` in strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    if value.starts_with('[') && value.ends_with(']') {
+        let items = value[1..value.len() - 1].trim().trim_right_matches(',');
+        if items.trim().is_empty() {
+            return Some(Value::Array(Vec::new()));
+        }
+        return items.split(',')
+            .map(|item| parse_string(item.trim()))
+            .collect::<Option<Vec<_>>>()
+            .map(Value::Array);
+    }
+    match value {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => {
+            parse_string(value)
+                .map(Value::Str)
+                .or_else(|| value.replace('_', "").parse::<u64>().ok().map(Value::Int))
+        }
+    }
+}
+
+/// Parses a basic string without escapes, the config has no use for them.
+fn parse_string(value: &str) -> Option<String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let content = &value[1..value.len() - 1];
+        if !content.contains('"') && !content.contains('\\') {
+            return Some(content.to_owned());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnetwork::Ipv4Network;
+
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use super::*;
+
+    static CONFIG: &'static str = "# A router\n\
+                                   mtu = 1400\n\
+                                   \n\
+                                   [[interface]]\n\
+                                   name = \"eth0\" # uplink\n\
+                                   addresses = [\"10.0.0.2/24\", \"10.0.1.2/24\"]\n\
+                                   promiscuous = true\n\
+                                   \n\
+                                   [[interface.route]]\n\
+                                   net = \"0.0.0.0/0\"\n\
+                                   gw = \"10.0.0.1\"\n\
+                                   metric = 10\n\
+                                   \n\
+                                   [[interface]]\n\
+                                   name = \"eth1\"\n\
+                                   mtu = 9_000\n";
+
+    #[test]
+    fn parse() {
+        let config = StackConfig::from_toml_str(CONFIG).unwrap();
+        assert_eq!(config.mtu, Some(1400));
+        assert_eq!(config.interfaces.len(), 2);
+        let eth0 = &config.interfaces[0];
+        assert_eq!(eth0.name, "eth0");
+        assert_eq!(eth0.mtu, None);
+        assert_eq!(eth0.addresses,
+                   vec![Ipv4Network::from_str("10.0.0.2/24").unwrap(),
+                        Ipv4Network::from_str("10.0.1.2/24").unwrap()]);
+        assert!(eth0.promiscuous);
+        assert!(!eth0.nonblocking);
+        assert_eq!(eth0.routes,
+                   vec![RouteConfig {
+                            net: Ipv4Network::from_str("0.0.0.0/0").unwrap(),
+                            gw: Some(Ipv4Addr::new(10, 0, 0, 1)),
+                            metric: 10,
+                        }]);
+        assert_eq!(config.interfaces[1].mtu, Some(9000));
+        assert!(config.interfaces[1].routes.is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| StackConfig::from_toml_str(text).unwrap_err();
+        match error("mtu 1400") {
+            ConfigError::Syntax(1) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("[[interface]]\nname = \"eth0\"\nmtuu = 1400") {
+            ConfigError::UnknownKey(3) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("[[interface.route]]") {
+            ConfigError::UnknownKey(1) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("[[interface]]\nname = \"eth0\"\naddresses = [\"10.0.0.300/24\"]") {
+            ConfigError::InvalidValue(3) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("mtu = \"big\"") {
+            ConfigError::InvalidValue(1) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+        match error("[[interface]]\nmtu = 1400\n[[interface]]\nname = \"eth1\"") {
+            ConfigError::MissingName(1) => (),
+            e => panic!("Unexpected {:?}", e),
+        }
+    }
+}
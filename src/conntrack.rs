@@ -0,0 +1,538 @@
+//! Connection tracking. Follows the flows of forwarded traffic by their
+//! 5-tuple, so packets can be classified as starting a new connection or
+//! belonging to an established one, and per flow state such as NAT mappings
+//! can be expired when the connection ends.
+//!
+//! UDP and Icmp echo flows are established once traffic has been seen in
+//! both directions. TCP connections follow the handshake and teardown, with
+//! connections already in progress picked up as established. Icmp errors
+//! about a tracked flow are related to it.
+
+use ipv4::MORE_FRAGMENTS;
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETER_PROBLEM: u8 = 12;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// The protocol, source and destination of a flow. For Icmp echo messages
+/// the identifier is used as both ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: IpNextHeaderProtocol,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+}
+
+impl FlowKey {
+    /// Returns the flow of the IPv4 packet in `packet`, or `None` if it's not
+    /// a complete UDP, TCP or Icmp echo packet.
+    pub fn from_packet(packet: &[u8]) -> Option<FlowKey> {
+        let (key, payload) = match Self::parse(packet) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+        if key.protocol == IpNextHeaderProtocols::Icmp && !is_echo(payload[0]) {
+            None
+        } else {
+            Some(key)
+        }
+    }
+
+    /// The same flow seen from the other end.
+    pub fn reverse(&self) -> FlowKey {
+        FlowKey {
+            protocol: self.protocol,
+            src: self.dst,
+            dst: self.src,
+        }
+    }
+
+    fn parse(packet: &[u8]) -> Option<(FlowKey, &[u8])> {
+        let ip_pkg = match Ipv4Packet::new(packet) {
+            Some(ip_pkg) => ip_pkg,
+            None => return None,
+        };
+        let header_len = ip_pkg.get_header_length() as usize * 4;
+        let is_fragment = ip_pkg.get_fragment_offset() != 0 ||
+                          ip_pkg.get_flags() & MORE_FRAGMENTS != 0;
+        if packet.len() < header_len || header_len < 20 || is_fragment {
+            return None;
+        }
+        let protocol = ip_pkg.get_next_level_protocol();
+        let payload = &packet[header_len..];
+        let (src_port, dst_port) = match protocol {
+            IpNextHeaderProtocols::Udp | IpNextHeaderProtocols::Tcp => {
+                let min_len = if protocol == IpNextHeaderProtocols::Tcp { 20 } else { 8 };
+                if payload.len() < min_len {
+                    return None;
+                }
+                (read_u16(&payload[0..2]), read_u16(&payload[2..4]))
+            }
+            IpNextHeaderProtocols::Icmp => {
+                if payload.len() < 8 {
+                    return None;
+                }
+                let ident = if is_echo(payload[0]) {
+                    read_u16(&payload[4..6])
+                } else {
+                    0
+                };
+                (ident, ident)
+            }
+            _ => return None,
+        };
+        let key = FlowKey {
+            protocol: protocol,
+            src: SocketAddrV4::new(ip_pkg.get_source(), src_port),
+            dst: SocketAddrV4::new(ip_pkg.get_destination(), dst_port),
+        };
+        Some((key, payload))
+    }
+}
+
+fn is_echo(icmp_type: u8) -> bool {
+    icmp_type == ICMP_ECHO_REQUEST || icmp_type == ICMP_ECHO_REPLY
+}
+
+/// How a packet relates to the tracked connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnState {
+    /// Starts a new connection, or belongs to one that has not seen any
+    /// reply yet
+    New,
+    /// Belongs to a connection that has seen traffic in both directions
+    Established,
+    /// An Icmp error about a tracked connection
+    Related,
+    /// Can't be tracked, such as TCP segments other than SYN for unknown
+    /// connections, or protocols that are not tracked
+    Invalid,
+}
+
+/// Where a TCP connection is in its lifetime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    SynReceived,
+    Established,
+    /// One side has sent FIN
+    Closing,
+    /// Both sides have sent FIN
+    TimeWait,
+    /// Reset by either side
+    Closed,
+}
+
+/// How long connections are kept without traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnTimeouts {
+    pub tcp_syn: Duration,
+    pub tcp_established: Duration,
+    pub tcp_closing: Duration,
+    pub tcp_time_wait: Duration,
+    pub tcp_closed: Duration,
+    /// UDP flows that have not seen a reply
+    pub udp_unreplied: Duration,
+    pub udp_established: Duration,
+    pub icmp: Duration,
+}
+
+impl Default for ConnTimeouts {
+    /// The defaults of the Linux conntrack.
+    fn default() -> ConnTimeouts {
+        ConnTimeouts {
+            tcp_syn: Duration::from_secs(120),
+            tcp_established: Duration::from_secs(5 * 24 * 60 * 60),
+            tcp_closing: Duration::from_secs(120),
+            tcp_time_wait: Duration::from_secs(120),
+            tcp_closed: Duration::from_secs(10),
+            udp_unreplied: Duration::from_secs(30),
+            udp_established: Duration::from_secs(180),
+            icmp: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A tracked connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    /// The flow of the first packet of the connection
+    pub original: FlowKey,
+    /// If any traffic has been seen in the reply direction
+    pub replied: bool,
+    /// The state of TCP connections, `None` for other protocols
+    pub tcp_state: Option<TcpState>,
+    fin_original: bool,
+    fin_reply: bool,
+    last_seen: Instant,
+}
+
+impl Connection {
+    fn new(original: FlowKey, tcp_state: Option<TcpState>, now: Instant) -> Connection {
+        Connection {
+            original: original,
+            replied: false,
+            tcp_state: tcp_state,
+            fin_original: false,
+            fin_reply: false,
+            last_seen: now,
+        }
+    }
+
+    fn timeout(&self, timeouts: &ConnTimeouts) -> Duration {
+        match self.tcp_state {
+            Some(TcpState::SynSent) |
+            Some(TcpState::SynReceived) => timeouts.tcp_syn,
+            Some(TcpState::Established) => timeouts.tcp_established,
+            Some(TcpState::Closing) => timeouts.tcp_closing,
+            Some(TcpState::TimeWait) => timeouts.tcp_time_wait,
+            Some(TcpState::Closed) => timeouts.tcp_closed,
+            None if self.original.protocol == IpNextHeaderProtocols::Icmp => timeouts.icmp,
+            None if self.replied => timeouts.udp_established,
+            None => timeouts.udp_unreplied,
+        }
+    }
+
+    fn update_tcp(&mut self, flags: u8, reply: bool) {
+        let state = match self.tcp_state {
+            Some(state) => state,
+            None => return,
+        };
+        let new_state = if flags & TCP_RST != 0 {
+            TcpState::Closed
+        } else {
+            if flags & TCP_FIN != 0 {
+                if reply {
+                    self.fin_reply = true;
+                } else {
+                    self.fin_original = true;
+                }
+            }
+            match state {
+                _ if self.fin_original && self.fin_reply => TcpState::TimeWait,
+                _ if self.fin_original || self.fin_reply => TcpState::Closing,
+                TcpState::SynSent if reply && flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK => {
+                    TcpState::SynReceived
+                }
+                TcpState::SynReceived if !reply && flags & TCP_ACK != 0 => TcpState::Established,
+                state => state,
+            }
+        };
+        self.tcp_state = Some(new_state);
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    /// Connections by their original flow
+    connections: HashMap<FlowKey, Connection>,
+    /// Original flows by reply flow
+    replies: HashMap<FlowKey, FlowKey>,
+    last_expire: Option<Instant>,
+}
+
+/// The connection tracking table of a stack.
+#[derive(Default)]
+pub struct ConnTrack {
+    timeouts: Mutex<ConnTimeouts>,
+    table: Mutex<Table>,
+}
+
+impl ConnTrack {
+    pub fn new(timeouts: ConnTimeouts) -> ConnTrack {
+        ConnTrack {
+            timeouts: Mutex::new(timeouts),
+            table: Mutex::new(Table::default()),
+        }
+    }
+
+    pub fn timeouts(&self) -> ConnTimeouts {
+        *self.timeouts.lock().unwrap()
+    }
+
+    pub fn set_timeouts(&self, timeouts: ConnTimeouts) {
+        *self.timeouts.lock().unwrap() = timeouts;
+    }
+
+    /// Updates the table with the IPv4 packet in `packet` and returns how the
+    /// packet relates to the tracked connections.
+    pub fn track(&self, packet: &[u8]) -> ConnState {
+        let (key, payload) = match FlowKey::parse(packet) {
+            Some(parsed) => parsed,
+            None => return ConnState::Invalid,
+        };
+        let now = Instant::now();
+        self.expire_periodically(now);
+        let mut table = self.table.lock().unwrap();
+        if key.protocol == IpNextHeaderProtocols::Icmp && !is_echo(payload[0]) {
+            return Self::related_state(&table, payload);
+        }
+        let tcp_flags = if key.protocol == IpNextHeaderProtocols::Tcp {
+            Some(payload[13])
+        } else {
+            None
+        };
+        if let Some(connection) = table.connections.get_mut(&key) {
+            connection.last_seen = now;
+            if let Some(flags) = tcp_flags {
+                connection.update_tcp(flags, false);
+            }
+            return if connection.replied {
+                ConnState::Established
+            } else {
+                ConnState::New
+            };
+        }
+        if let Some(original) = table.replies.get(&key).cloned() {
+            let connection = table.connections.get_mut(&original).unwrap();
+            connection.last_seen = now;
+            connection.replied = true;
+            if let Some(flags) = tcp_flags {
+                connection.update_tcp(flags, true);
+            }
+            return ConnState::Established;
+        }
+        let tcp_state = match tcp_flags {
+            Some(flags) if flags & (TCP_RST | TCP_FIN) != 0 => return ConnState::Invalid,
+            Some(flags) if flags & (TCP_SYN | TCP_ACK) == TCP_SYN => Some(TcpState::SynSent),
+            Some(flags) if flags & TCP_SYN != 0 => return ConnState::Invalid,
+            Some(_) => Some(TcpState::Established),
+            None => None,
+        };
+        let mut connection = Connection::new(key, tcp_state, now);
+        // Connections picked up midway have obviously seen both directions
+        connection.replied = tcp_state == Some(TcpState::Established);
+        let state = if connection.replied {
+            ConnState::Established
+        } else {
+            ConnState::New
+        };
+        table.replies.insert(key.reverse(), key);
+        table.connections.insert(key, connection);
+        state
+    }
+
+    /// Returns the state of an Icmp error, given the Icmp message.
+    fn related_state(table: &Table, icmp: &[u8]) -> ConnState {
+        let is_error = icmp[0] == ICMP_DESTINATION_UNREACHABLE || icmp[0] == ICMP_TIME_EXCEEDED ||
+                       icmp[0] == ICMP_PARAMETER_PROBLEM;
+        if !is_error {
+            return ConnState::Invalid;
+        }
+        // The error quotes the IPv4 header and the first 8 bytes of the
+        // payload of the packet causing it, enough for the ports
+        let quoted = &icmp[8..];
+        let header_len = match Ipv4Packet::new(quoted) {
+            Some(ip_pkg) => ip_pkg.get_header_length() as usize * 4,
+            None => return ConnState::Invalid,
+        };
+        if quoted.len() < header_len + 8 {
+            return ConnState::Invalid;
+        }
+        let mut padded = quoted.to_vec();
+        padded.resize(header_len + 20, 0);
+        let quoted_key = match FlowKey::parse(&padded) {
+            Some((key, _)) => key,
+            None => return ConnState::Invalid,
+        };
+        if table.connections.contains_key(&quoted_key) || table.replies.contains_key(&quoted_key) {
+            ConnState::Related
+        } else {
+            ConnState::Invalid
+        }
+    }
+
+    /// Returns the connection the flow `key` belongs to, in either direction,
+    /// unless it has timed out.
+    pub fn get(&self, key: &FlowKey) -> Option<Connection> {
+        let timeouts = self.timeouts();
+        let table = self.table.lock().unwrap();
+        let original = table.replies.get(key).unwrap_or(key);
+        match table.connections.get(original) {
+            Some(c) if Instant::now() - c.last_seen < c.timeout(&timeouts) => Some(c.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the flow `key` belongs to a tracked connection.
+    pub fn contains(&self, key: &FlowKey) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns all tracked connections.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.table.lock().unwrap().connections.values().cloned().collect()
+    }
+
+    /// Removes the connections that have been idle longer than their
+    /// timeout.
+    pub fn expire(&self) {
+        let timeouts = self.timeouts();
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        let expired = table.connections
+            .values()
+            .filter(|c| now - c.last_seen >= c.timeout(&timeouts))
+            .map(|c| c.original)
+            .collect::<Vec<_>>();
+        for key in expired {
+            table.connections.remove(&key);
+            table.replies.remove(&key.reverse());
+        }
+        table.last_expire = Some(now);
+    }
+
+    fn expire_periodically(&self, now: Instant) {
+        let last_expire = self.table.lock().unwrap().last_expire;
+        if last_expire.map_or(true, |last| now - last >= Duration::from_secs(1)) {
+            self.expire();
+        }
+    }
+}
+
+fn read_u16(buffer: &[u8]) -> u16 {
+    ((buffer[0] as u16) << 8) | buffer[1] as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn packet(src: SocketAddrV4, dst: SocketAddrV4, tcp_flags: Option<u8>) -> Vec<u8> {
+        let mut buffer = vec![0; 40];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(40);
+            ip_pkg.set_source(*src.ip());
+            ip_pkg.set_destination(*dst.ip());
+            let protocol = if tcp_flags.is_some() {
+                IpNextHeaderProtocols::Tcp
+            } else {
+                IpNextHeaderProtocols::Udp
+            };
+            ip_pkg.set_next_level_protocol(protocol);
+        }
+        buffer[20] = (src.port() >> 8) as u8;
+        buffer[21] = src.port() as u8;
+        buffer[22] = (dst.port() >> 8) as u8;
+        buffer[23] = dst.port() as u8;
+        buffer[33] = tcp_flags.unwrap_or(0);
+        buffer
+    }
+
+    fn addrs() -> (SocketAddrV4, SocketAddrV4) {
+        (SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000),
+         SocketAddrV4::new(Ipv4Addr::new(10, 0, 1, 1), 80))
+    }
+
+    #[test]
+    fn udp() {
+        let (a, b) = addrs();
+        let conntrack = ConnTrack::default();
+        assert_eq!(conntrack.track(&packet(a, b, None)), ConnState::New);
+        assert_eq!(conntrack.track(&packet(a, b, None)), ConnState::New);
+        assert_eq!(conntrack.track(&packet(b, a, None)), ConnState::Established);
+        assert_eq!(conntrack.track(&packet(a, b, None)), ConnState::Established);
+        let connections = conntrack.connections();
+        assert_eq!(connections.len(), 1);
+        assert!(connections[0].replied);
+        assert_eq!(connections[0].original.src, a);
+    }
+
+    #[test]
+    fn tcp_lifetime() {
+        let (a, b) = addrs();
+        let conntrack = ConnTrack::default();
+        let state = |conntrack: &ConnTrack| {
+            let key = FlowKey::from_packet(&packet(a, b, Some(0))).unwrap();
+            conntrack.get(&key.reverse()).unwrap().tcp_state.unwrap()
+        };
+        assert_eq!(conntrack.track(&packet(a, b, Some(TCP_SYN))), ConnState::New);
+        assert_eq!(state(&conntrack), TcpState::SynSent);
+        assert_eq!(conntrack.track(&packet(b, a, Some(TCP_SYN | TCP_ACK))),
+                   ConnState::Established);
+        assert_eq!(state(&conntrack), TcpState::SynReceived);
+        conntrack.track(&packet(a, b, Some(TCP_ACK)));
+        assert_eq!(state(&conntrack), TcpState::Established);
+        conntrack.track(&packet(a, b, Some(TCP_FIN | TCP_ACK)));
+        assert_eq!(state(&conntrack), TcpState::Closing);
+        conntrack.track(&packet(b, a, Some(TCP_FIN | TCP_ACK)));
+        assert_eq!(state(&conntrack), TcpState::TimeWait);
+        conntrack.track(&packet(b, a, Some(TCP_RST)));
+        assert_eq!(state(&conntrack), TcpState::Closed);
+    }
+
+    #[test]
+    fn tcp_invalid_and_pickup() {
+        let (a, b) = addrs();
+        let conntrack = ConnTrack::default();
+        assert_eq!(conntrack.track(&packet(a, b, Some(TCP_RST))), ConnState::Invalid);
+        assert_eq!(conntrack.track(&packet(a, b, Some(TCP_SYN | TCP_ACK))),
+                   ConnState::Invalid);
+        assert!(conntrack.connections().is_empty());
+        assert_eq!(conntrack.track(&packet(a, b, Some(TCP_ACK))), ConnState::Established);
+    }
+
+    #[test]
+    fn related_icmp_error() {
+        let (a, b) = addrs();
+        let conntrack = ConnTrack::default();
+        let original = packet(a, b, None);
+        conntrack.track(&original);
+
+        let mut error = vec![0; 20 + 8 + 28];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut error).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(56);
+            ip_pkg.set_source(*b.ip());
+            ip_pkg.set_destination(*a.ip());
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        }
+        error[20] = ICMP_DESTINATION_UNREACHABLE;
+        error[28..].copy_from_slice(&original[..28]);
+        assert_eq!(conntrack.track(&error), ConnState::Related);
+
+        let c = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 5000);
+        error[28..].copy_from_slice(&packet(c, b, None)[..28]);
+        assert_eq!(conntrack.track(&error), ConnState::Invalid);
+    }
+
+    #[test]
+    fn expire() {
+        let (a, b) = addrs();
+        let timeouts = ConnTimeouts {
+            udp_unreplied: Duration::from_secs(0),
+            ..ConnTimeouts::default()
+        };
+        let conntrack = ConnTrack::new(timeouts);
+        conntrack.track(&packet(a, b, None));
+        assert_eq!(conntrack.connections().len(), 1);
+        conntrack.expire();
+        assert!(conntrack.connections().is_empty());
+    }
+}
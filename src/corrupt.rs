@@ -0,0 +1,255 @@
+//! Deliberately malformed packets, for testing how other devices handle
+//! corrupt traffic.
+//!
+//! The tx-objects of every layer take the errors to make in the headers
+//! they build with `set_corruption`: `EthernetTxImpl` an
+//! `EthernetCorruption`, `Ipv4TxImpl` an `Ipv4Corruption`, `UdpTx` a
+//! `UdpCorruption` and `IcmpTx` an `IcmpCorruption`. Packets are built as
+//! usual and changed afterwards, so they only have the errors asked for: a
+//! wrong length field comes with a checksum matching it, unless a bad
+//! checksum is asked for too.
+//!
+//! ```rust,ignore
+//! let mut udp_tx = stack.udp_tx(dst, 4000, 7)?;
+//! udp_tx.set_corruption(UdpCorruption { length: Some(100), ..UdpCorruption::default() });
+//! udp_tx.send(b"shorter than it says")?;
+//! ```
+//!
+//! Corrupted IPv4 packets are sent whole instead of as fragments, so they
+//! have to fit the MTU. Illegal Ethernet source addresses, such as group
+//! addresses, need no corruption, see `EthernetTxImpl::set_src`. What the
+//! stack does with malformed packets it receives is up to `malformed`.
+
+use checksum::{self, Checksum};
+
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::net::Ipv4Addr;
+
+/// Errors in the header of Ethernet frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EthernetCorruption {
+    /// Written in place of the EtherType, such as an 802.3 length, below
+    /// 0x600, that does not match the frame
+    pub ether_type: Option<u16>,
+    /// Cuts frames after this many bytes, truncating the header of the
+    /// payload. Datalinks do not send frames shorter than the 14 bytes of
+    /// the Ethernet header.
+    pub truncate: Option<usize>,
+}
+
+impl EthernetCorruption {
+    /// Makes the errors in `frame`, a valid Ethernet frame.
+    pub fn apply(&self, frame: &mut Vec<u8>) {
+        if let Some(ether_type) = self.ether_type {
+            write_u16(frame, 12, ether_type);
+        }
+        truncate(frame, self.truncate);
+    }
+}
+
+/// Errors in the header of IPv4 packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ipv4Corruption {
+    pub bad_checksum: bool,
+    /// Written into the total length field instead of the length of the
+    /// packet
+    pub total_length: Option<u16>,
+    /// Written into the four bits of the header length, in 32 bit words,
+    /// instead of 5
+    pub header_length: Option<u8>,
+    /// Written into the three flag bits, such as the reserved bit, or don't
+    /// fragment and more fragments together
+    pub flags: Option<u8>,
+    /// Cuts packets after this many bytes, so less than 20 truncates the
+    /// header
+    pub truncate: Option<usize>,
+}
+
+impl Ipv4Corruption {
+    /// Makes the errors in `packet`, a valid IPv4 packet without options.
+    pub fn apply(&self, packet: &mut Vec<u8>) {
+        if let Some(total_length) = self.total_length {
+            write_u16(packet, 2, total_length);
+        }
+        if let Some(header_length) = self.header_length {
+            packet[0] = 0x40 | header_length & 0xf;
+        }
+        if let Some(flags) = self.flags {
+            packet[6] = (flags & 0b111) << 5 | packet[6] & 0x1f;
+        }
+        let header_len = Ipv4Packet::minimum_packet_size();
+        write_u16(packet, 10, 0);
+        let checksum = checksum::checksum(&packet[..header_len]);
+        write_u16(packet, 10, written_checksum(checksum, self.bad_checksum));
+        truncate(packet, self.truncate);
+    }
+}
+
+/// Errors in the header of UDP datagrams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpCorruption {
+    pub bad_checksum: bool,
+    /// Written into the length field instead of the length of the datagram
+    pub length: Option<u16>,
+    /// Cuts datagrams after this many bytes, so less than 8 truncates the
+    /// header. The IPv4 packet is as long as what is left.
+    pub truncate: Option<usize>,
+}
+
+impl UdpCorruption {
+    /// Makes the errors in `datagram`, a valid UDP datagram from `src` to
+    /// `dst`.
+    pub fn apply(&self, src: Ipv4Addr, dst: Ipv4Addr, datagram: &mut Vec<u8>) {
+        if let Some(length) = self.length {
+            write_u16(datagram, 4, length);
+        }
+        write_u16(datagram, 6, 0);
+        let mut sum = Checksum::new();
+        sum.add_pseudo_header(src, dst, IpNextHeaderProtocols::Udp, datagram.len());
+        sum.add(datagram);
+        write_u16(datagram, 6, written_checksum(sum.finish(), self.bad_checksum));
+        truncate(datagram, self.truncate);
+    }
+}
+
+/// Errors in the header of Icmp packets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IcmpCorruption {
+    pub bad_checksum: bool,
+    /// Cuts packets after this many bytes, so less than 8 truncates the
+    /// header. The IPv4 packet is as long as what is left.
+    pub truncate: Option<usize>,
+}
+
+impl IcmpCorruption {
+    /// Makes the errors in `packet`, a valid Icmp packet.
+    pub fn apply(&self, packet: &mut Vec<u8>) {
+        write_u16(packet, 2, 0);
+        let checksum = checksum::checksum(packet);
+        write_u16(packet, 2, written_checksum(checksum, self.bad_checksum));
+        truncate(packet, self.truncate);
+    }
+}
+
+/// Returns `checksum`, or one that is wrong for the same data if `bad`. One
+/// more in ones' complement, which is never zero, so UDP receivers do not
+/// take it for a missing checksum.
+fn written_checksum(checksum: u16, bad: bool) -> u16 {
+    if bad {
+        checksum.checked_add(1).unwrap_or(1)
+    } else {
+        checksum
+    }
+}
+
+fn truncate(packet: &mut Vec<u8>, len: Option<usize>) {
+    if let Some(len) = len {
+        packet.truncate(len);
+    }
+}
+
+fn write_u16(packet: &mut [u8], offset: usize, value: u16) {
+    packet[offset] = (value >> 8) as u8;
+    packet[offset + 1] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use checksum;
+
+    use pnet::packet::ipv4::Ipv4Packet;
+
+    use std::net::Ipv4Addr;
+
+    /// A valid IPv4 packet carrying the UDP datagram of `udp`.
+    fn ipv4_udp() -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 30, 0, 1, 0x40, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&udp());
+        let checksum = checksum::checksum(&packet[..20]);
+        write_u16(&mut packet, 10, checksum);
+        packet
+    }
+
+    fn udp() -> Vec<u8> {
+        let mut datagram = vec![0x0f, 0xa0, 0, 7, 0, 10, 0, 0, 1, 2];
+        UdpCorruption::default().apply(Ipv4Addr::new(10, 0, 0, 1),
+                                       Ipv4Addr::new(10, 0, 0, 2),
+                                       &mut datagram);
+        datagram
+    }
+
+    #[test]
+    fn ipv4() {
+        let valid = ipv4_udp();
+        let mut packet = valid.clone();
+        Ipv4Corruption::default().apply(&mut packet);
+        assert_eq!(packet, valid);
+
+        let corruption = Ipv4Corruption {
+            total_length: Some(1000),
+            header_length: Some(4),
+            flags: Some(0b111),
+            ..Ipv4Corruption::default()
+        };
+        corruption.apply(&mut packet);
+        let pkg = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(pkg.get_total_length(), 1000);
+        assert_eq!(pkg.get_header_length(), 4);
+        assert_eq!(pkg.get_flags(), 0b111);
+        assert_eq!(pkg.get_fragment_offset(), 0);
+        assert_eq!(checksum::checksum(&packet[..20]), 0);
+
+        let mut packet = valid.clone();
+        let corruption = Ipv4Corruption {
+            bad_checksum: true,
+            truncate: Some(12),
+            ..Ipv4Corruption::default()
+        };
+        corruption.apply(&mut packet);
+        assert_eq!(packet.len(), 12);
+        assert_eq!(&packet[..10], &valid[..10]);
+        assert!(packet[10..] != valid[10..12]);
+        let mut packet = valid.clone();
+        Ipv4Corruption { bad_checksum: true, ..Ipv4Corruption::default() }.apply(&mut packet);
+        assert!(checksum::checksum(&packet[..20]) != 0);
+        assert_eq!(&packet[20..], &valid[20..]);
+    }
+
+    #[test]
+    fn udp_icmp() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        assert!(checksum::is_valid_udp(&udp(), src, dst));
+        let mut datagram = udp();
+        let corruption = UdpCorruption { length: Some(4), ..UdpCorruption::default() };
+        corruption.apply(src, dst, &mut datagram);
+        assert_eq!(&datagram[4..6], &[0, 4]);
+        assert!(checksum::is_valid_udp(&datagram, src, dst));
+        let mut datagram = udp();
+        let corruption = UdpCorruption { bad_checksum: true, ..UdpCorruption::default() };
+        corruption.apply(src, dst, &mut datagram);
+        assert!(!checksum::is_valid_udp(&datagram, src, dst));
+        let mut datagram = udp();
+        let corruption = UdpCorruption { truncate: Some(6), ..UdpCorruption::default() };
+        corruption.apply(src, dst, &mut datagram);
+        assert_eq!(datagram.len(), 6);
+
+        let mut echo = vec![8, 0, 0, 0, 0, 1, 0, 1, 42];
+        IcmpCorruption { bad_checksum: true, truncate: None }.apply(&mut echo);
+        assert!(checksum::checksum(&echo) != 0);
+        IcmpCorruption::default().apply(&mut echo);
+        assert_eq!(checksum::checksum(&echo), 0);
+
+        assert_eq!(written_checksum(0xffff, true), 1);
+        assert_eq!(written_checksum(0xfffe, true), 0xffff);
+        assert_eq!(written_checksum(0x1234, false), 0x1234);
+
+        let mut frame = vec![0; 20];
+        EthernetCorruption { ether_type: Some(100), truncate: Some(16) }.apply(&mut frame);
+        assert_eq!(frame, vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 100, 0, 0]);
+    }
+}
@@ -0,0 +1,479 @@
+//! Backends for the links the stack sends and receives Ethernet frames on.
+//!
+//! The stack talks to its links through `pnet::datalink`, but a backend does
+//! not have to bring a pnet channel. Anything implementing `Datalink` can be
+//! added with `NetworkStack::add_datalink`, which wraps it in an
+//! `EthernetChannel` with `channel`:
+//!
+//! ```rust,ignore
+//! let link: Arc<Datalink> = Arc::new(MyBackend::open()?);
+//! let interface = stack.add_datalink("my0", link)?;
+//! ```
+//!
+//! Sending is done from the threads of the stack and of applications, and
+//! receiving from the rx thread of the interface, so the methods take
+//! `&self`. `PnetDatalink` is the implementation on top of pnet, for links
+//! opened like the ones of `default_stack`. `DriverDatalink` is the one on
+//! top of a `proto::FrameDriver`, the driver of the protocol core.
+
+use {EthernetChannel, Interface, RxError, RxResult, StackError, StackResult};
+use ipv4;
+use pool::BufferPool;
+use proto::FrameDriver;
+use rx::{self, RxFailure, RxHandle, RxListener};
+use stack::{self, MAX_MTU};
+
+use pnet::datalink::{self, EthernetDataLinkChannelIterator, EthernetDataLinkReceiver,
+                     EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A link sending and receiving Ethernet frames.
+pub trait Datalink: Send + Sync {
+    /// The MAC address of the link.
+    fn mac(&self) -> MacAddr;
+
+    /// The MTU of the link, if the backend knows it. The interface gets
+    /// `DEFAULT_MTU` otherwise.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// Sends the whole Ethernet frame `frame`.
+    fn send(&self, frame: &[u8]) -> io::Result<()>;
+
+    /// Sends the whole Ethernet frames `frames`, which the stack builds in
+    /// batches when it can. Sends them one by one by default, backends that
+    /// can hand several frames to the link at once override this.
+    fn send_batch(&self, frames: &[&[u8]]) -> io::Result<()> {
+        for frame in frames {
+            self.send(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Builds `num_frames` Ethernet frames of `frame_size` bytes with `build`
+    /// right in buffers of the link, and sends them. Returns `None` by
+    /// default, for links without buffers of their own, the stack then builds
+    /// the frames in memory of its own and sends them with `send_batch`.
+    ///
+    /// This is the hook for backends sending without copying, like one over
+    /// the poll mode drivers of DPDK, which would hand out the data of its
+    /// mbufs here and send them with a single burst.
+    fn build_and_send(&self,
+                      _num_frames: usize,
+                      _frame_size: usize,
+                      _build: &mut FnMut(&mut [u8]))
+                      -> Option<io::Result<()>> {
+        None
+    }
+
+    /// Waits for the next Ethernet frame. Should give up with
+    /// `io::ErrorKind::TimedOut` after about `rx::POLL_INTERVAL_MS`, so the
+    /// rx thread notices when the stack is shut down. Any other error stops
+    /// receiving on the interface. Links meant for stacks that busy poll,
+    /// see `NetworkStackBuilder::busy_poll`, should instead fail with
+    /// `io::ErrorKind::WouldBlock` right away when no frame is waiting.
+    fn recv(&self) -> io::Result<Box<[u8]>>;
+}
+
+/// Returns a channel sending and receiving through `datalink`.
+pub fn channel(datalink: Arc<Datalink>) -> EthernetChannel {
+    channel_with_pool(datalink, BufferPool::default())
+}
+
+/// Same as `channel`, but builds the frames the link has no buffers for in
+/// buffers from `pool`.
+pub fn channel_with_pool(datalink: Arc<Datalink>, pool: BufferPool) -> EthernetChannel {
+    let sender = Sender {
+        datalink: datalink.clone(),
+        pool: pool,
+    };
+    EthernetChannel(Box::new(sender), Box::new(Receiver(datalink)))
+}
+
+/// Returns the MTU the OS reports for the interface `name`, capped at
+/// `MAX_MTU`. Returns `None` if it is not known or too small for IPv4.
+pub fn os_mtu(name: &str) -> Option<usize> {
+    stack::sysfs_mtu(name)
+        .and_then(|mtu| if mtu >= ipv4::MIN_MTU { Some(mtu) } else { None })
+        .map(|mtu| cmp::min(mtu, MAX_MTU))
+}
+
+struct Sender {
+    datalink: Arc<Datalink>,
+    pool: BufferPool,
+}
+
+impl EthernetDataLinkSender for Sender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        if packet_size < EthernetPacket::minimum_packet_size() {
+            return None;
+        }
+        let built = self.datalink.build_and_send(num_packets, packet_size, &mut |buffer| {
+            func(MutableEthernetPacket::new(buffer).unwrap())
+        });
+        if built.is_some() {
+            return built;
+        }
+        let mut buffers = Vec::with_capacity(num_packets);
+        for _ in 0..num_packets {
+            let mut buffer = self.pool.get(packet_size);
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            buffers.push(buffer);
+        }
+        let frames: Vec<&[u8]> = buffers.iter().map(|buffer| &buffer[..]).collect();
+        Some(self.datalink.send_batch(&frames))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        Some(self.datalink.send(packet.packet()))
+    }
+}
+
+struct Receiver(Arc<Datalink>);
+
+impl EthernetDataLinkReceiver for Receiver {
+    fn iter<'a>(&'a mut self) -> Box<EthernetDataLinkChannelIterator + 'a> {
+        Box::new(ReceiverIterator {
+            datalink: self.0.clone(),
+            current: None,
+        })
+    }
+}
+
+struct ReceiverIterator {
+    datalink: Arc<Datalink>,
+    current: Option<Box<[u8]>>,
+}
+
+impl<'a> EthernetDataLinkChannelIterator<'a> for ReceiverIterator {
+    fn next(&mut self) -> io::Result<EthernetPacket> {
+        self.current = Some(self.datalink.recv()?);
+        EthernetPacket::new(self.current.as_ref().unwrap()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Frame shorter than an Ethernet header")
+        })
+    }
+}
+
+/// A `Datalink` on top of a pnet channel. A thread of its own receives from
+/// the channel, the pnet receivers can only be iterated once.
+pub struct PnetDatalink {
+    mac: MacAddr,
+    mtu: Option<usize>,
+    tx: Mutex<Box<EthernetDataLinkSender>>,
+    frames: Mutex<mpsc::Receiver<io::Result<Box<[u8]>>>>,
+    rx_thread: Mutex<RxHandle>,
+}
+
+impl PnetDatalink {
+    /// Wraps `channel` of the link with the MAC address `mac`.
+    pub fn new(mac: MacAddr, mtu: Option<usize>, channel: EthernetChannel) -> PnetDatalink {
+        let EthernetChannel(tx, rx) = channel;
+        let (frames_tx, frames) = mpsc::channel();
+        let failures = frames_tx.clone();
+        let rx_thread = rx::spawn_reporting(rx, Forward(frames_tx), move |failure| {
+            let e = match failure {
+                RxFailure::Datalink(e) => e,
+                RxFailure::Panic(msg) => io::Error::new(io::ErrorKind::Other, msg),
+            };
+            failures.send(Err(e)).unwrap_or(());
+        });
+        PnetDatalink {
+            mac: mac,
+            mtu: mtu,
+            tx: Mutex::new(tx),
+            frames: Mutex::new(frames),
+            rx_thread: Mutex::new(rx_thread),
+        }
+    }
+
+    /// Opens the OS interface `name` like `default_stack` does, with the MTU
+    /// the OS reports for it. Fails with `StackError::NoSuchInterface` if
+    /// there is no interface `name` with a MAC address.
+    pub fn open(name: &str) -> StackResult<PnetDatalink> {
+        let os_interface = datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == name)
+            .ok_or_else(|| StackError::NoSuchInterface(name.to_owned()))?;
+        let Interface { mac, .. } = ::convert_interface(&os_interface)
+            .map_err(|_| StackError::NoSuchInterface(name.to_owned()))?;
+        let channel = ::os_channel(&os_interface)?;
+        Ok(PnetDatalink::new(mac, os_mtu(name), channel))
+    }
+}
+
+impl Datalink for PnetDatalink {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let packet = EthernetPacket::new(frame).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Frame shorter than an Ethernet header")
+            })?;
+        match self.tx.lock().unwrap().send_to(&packet, None) {
+            Some(result) => result,
+            None => Err(io::Error::new(io::ErrorKind::Other, "Frame does not fit send buffer")),
+        }
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let timeout = Duration::from_millis(rx::POLL_INTERVAL_MS);
+        match self.frames.lock().unwrap().recv_timeout(timeout) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received"))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "Channel closed"))
+            }
+        }
+    }
+}
+
+impl Drop for PnetDatalink {
+    fn drop(&mut self) {
+        self.rx_thread.lock().unwrap().stop();
+    }
+}
+
+/// Forwards the frames of a pnet receiver to a `PnetDatalink`.
+struct Forward(mpsc::Sender<io::Result<Box<[u8]>>>);
+
+impl RxListener for Forward {
+    fn recv(&mut self, _time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        self.0
+            .send(Ok(packet.packet().to_vec().into_boxed_slice()))
+            .map_err(|_| RxError::NoListener("PnetDatalink dropped".to_owned()))
+    }
+}
+
+/// A `Datalink` on top of a `proto::FrameDriver`, so a driver written for
+/// the protocol core also works with the threaded stack. Receiving polls the
+/// driver every millisecond while no frame is waiting, without holding the
+/// lock of the driver between the polls so senders can get to it.
+pub struct DriverDatalink<D: FrameDriver> {
+    mac: MacAddr,
+    mtu: Option<usize>,
+    driver: Mutex<D>,
+}
+
+impl<D: FrameDriver> DriverDatalink<D> {
+    pub fn new(driver: D) -> DriverDatalink<D> {
+        DriverDatalink {
+            mac: driver.mac(),
+            mtu: driver.mtu(),
+            driver: Mutex::new(driver),
+        }
+    }
+}
+
+impl<D: FrameDriver + Send> Datalink for DriverDatalink<D> {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.driver.lock().unwrap().send(frame)
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let deadline = Instant::now() + Duration::from_millis(rx::POLL_INTERVAL_MS);
+        let mut buffer = vec![0; MAX_MTU + EthernetPacket::minimum_packet_size()];
+        loop {
+            match self.driver.lock().unwrap().recv(&mut buffer) {
+                Ok(len) => {
+                    buffer.truncate(len);
+                    return Ok(buffer.into_boxed_slice());
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received"));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::{self, FrameDriver};
+    use proto::arp::ArpBuilder;
+    use testing;
+
+    use pnet::packet::Packet;
+    use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+    use pnet::util::MacAddr;
+
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::Sender;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn pnet_datalink() {
+        let (channel, interface, inject, read) = testing::dummy_ethernet();
+        let datalink = PnetDatalink::new(interface.mac, None, channel);
+        assert_eq!(datalink.mac(), interface.mac);
+
+        datalink.send(&[1; 14]).unwrap();
+        assert_eq!(&*read.recv_timeout(Duration::from_secs(1)).unwrap(), &[1; 14]);
+        for i in 0..2 {
+            inject.send(Ok(vec![i; 20].into_boxed_slice())).unwrap();
+            assert_eq!(&*datalink.recv().unwrap(), &[i; 20][..]);
+        }
+    }
+
+    /// A link with buffers of its own, recording the frames built in them.
+    struct Buffered(Mutex<Vec<Vec<u8>>>);
+
+    impl Datalink for Buffered {
+        fn mac(&self) -> MacAddr {
+            MacAddr::new(2, 0, 0, 0, 0, 1)
+        }
+
+        fn send(&self, _frame: &[u8]) -> io::Result<()> {
+            panic!("Frame built outside the buffers of the link");
+        }
+
+        fn recv(&self) -> io::Result<Box<[u8]>> {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "No frame"))
+        }
+
+        fn build_and_send(&self,
+                          num_frames: usize,
+                          frame_size: usize,
+                          build: &mut FnMut(&mut [u8]))
+                          -> Option<io::Result<()>> {
+            let mut buffers = self.0.lock().unwrap();
+            for _ in 0..num_frames {
+                let mut buffer = vec![0; frame_size];
+                build(&mut buffer);
+                buffers.push(buffer);
+            }
+            Some(Ok(()))
+        }
+    }
+
+    #[test]
+    fn build_in_link_buffers() {
+        let datalink = Arc::new(Buffered(Mutex::new(Vec::new())));
+        let EthernetChannel(mut tx, _) = channel(datalink.clone());
+        tx.build_and_send(3, 20, &mut |mut packet: MutableEthernetPacket| {
+                packet.set_source(MacAddr::new(2, 0, 0, 0, 0, 1));
+            })
+            .unwrap()
+            .unwrap();
+        let buffers = datalink.0.lock().unwrap();
+        assert_eq!(buffers.len(), 3);
+        assert!(buffers.iter().all(|buffer| buffer.len() == 20 && buffer[6] == 2));
+        assert!(tx.build_and_send(1, 10, &mut |_| ()).is_none());
+    }
+
+    #[test]
+    fn channel_over_datalink() {
+        let (pnet_channel, interface, inject, read) = testing::dummy_ethernet();
+        let datalink = PnetDatalink::new(interface.mac, None, pnet_channel);
+        let datalink: Arc<Datalink> = Arc::new(datalink);
+        let EthernetChannel(mut tx, mut rx) = channel(datalink);
+
+        tx.build_and_send(2, 14, &mut |mut packet: MutableEthernetPacket| {
+                packet.set_source(interface.mac);
+            })
+            .unwrap()
+            .unwrap();
+        for _ in 0..2 {
+            let frame = read.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(EthernetPacket::new(&frame).unwrap().get_source(), interface.mac);
+        }
+        inject.send(Ok(vec![3; 14].into_boxed_slice())).unwrap();
+        let mut frames = rx.iter();
+        assert_eq!(frames.next().unwrap().packet(), &[3; 14]);
+    }
+
+    /// A driver receiving `frames` and sending to `sent`.
+    struct QueueDriver {
+        frames: VecDeque<Vec<u8>>,
+        sent: Sender<Vec<u8>>,
+    }
+
+    impl FrameDriver for QueueDriver {
+        fn mac(&self) -> MacAddr {
+            MacAddr::new(2, 0, 0, 0, 0, 1)
+        }
+
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.sent.send(frame.to_vec()).unwrap();
+            Ok(())
+        }
+
+        fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            match self.frames.pop_front() {
+                Some(frame) => {
+                    buffer[..frame.len()].copy_from_slice(&frame);
+                    Ok(frame.len())
+                }
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "No frame")),
+            }
+        }
+    }
+
+    #[test]
+    fn driver_datalink() {
+        let (sent, read) = mpsc::channel();
+        let mut driver = QueueDriver {
+            frames: vec![vec![7; 60]].into_iter().collect(),
+            sent: sent,
+        };
+        let mut buffer = [0; 60];
+        let request = ArpBuilder::new_request(driver.mac(),
+                                              Ipv4Addr::new(10, 0, 0, 1),
+                                              Ipv4Addr::new(10, 0, 0, 2));
+        let dst = MacAddr::new(2, 0, 0, 0, 0, 2);
+        proto::send_frame(&mut driver, dst, request, &mut buffer).unwrap();
+        let datalink = DriverDatalink::new(driver);
+        assert_eq!(datalink.mac(), MacAddr::new(2, 0, 0, 0, 0, 1));
+
+        // The frame built by the core and one sent by the stack end up alike
+        let frame = read.try_recv().unwrap();
+        assert_eq!(EthernetPacket::new(&frame).unwrap().get_destination(), dst);
+        datalink.send(&frame).unwrap();
+        assert_eq!(read.try_recv().unwrap(), frame);
+
+        assert_eq!(&*datalink.recv().unwrap(), &[7; 60][..]);
+        assert_eq!(datalink.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,1051 @@
+//! DHCP, RFC 2131: a client for configuring an interface from the network,
+//! and a server in `server`.
+//!
+//! `DhcpClient::start` obtains a lease for an interface of the stack with
+//! the DISCOVER, OFFER, REQUEST, ACK exchange. It adds the leased address to
+//! the interface with `NetworkStack::add_ipv4`, makes the first router of
+//! the lease the default gateway and keeps the DNS servers it was told
+//! about:
+//!
+//! ```rust,ignore
+//! let client = DhcpClient::start(stack.clone(), &interface, DhcpConfig::default())?;
+//! let lease = client.lease().unwrap();
+//! println!("{} via {:?}, DNS {:?}", lease.address, lease.router(), lease.dns_servers);
+//! ```
+//!
+//! A thread of the client then keeps the lease. At the renewal time (T1) it
+//! asks the server that granted the lease to extend it, at the rebinding
+//! time (T2) any server, and once the lease expires it removes the address
+//! with `NetworkStack::remove_ipv4` and starts over with a DISCOVER.
+//! The client waits for these times and for its retransmissions on the
+//! timer wheel of the interface, see `timer`, and takes the time from the
+//! `Clock` of the stack and the transaction ids from its random number
+//! generator. On a polled stack with a `clock::MockClock` it moves on only
+//! as the stack is polled and the clock advanced.
+//! Stopping the client leaves the address in place, `DhcpClient::release`
+//! gives it back to the server.
+//!
+//! Before it has a lease the interface has no address to receive replies
+//! on, so the client sends its messages as frames of its own, asking servers
+//! to broadcast their replies, and takes the replies from an observer of the
+//! interface, see `observe`. The stack itself still counts them as dropped.
+//! Offered addresses are not probed with Arp before they are used, and
+//! options overloaded into the `sname` and `file` fields are not looked for.
+
+pub mod server;
+
+use {Interface, NetworkStack, StackError, StackResult, TxError};
+use clock::Clock;
+use ethernet::{BasicEthernetPayload, EthernetTx};
+use ipv4::{DEFAULT_TTL, MORE_FRAGMENTS};
+use observe::{Observer, ObserverId};
+use pcap::Direction;
+use timer::Timers;
+
+use ipnetwork::{Ipv4Network, ipv4_mask_to_prefix};
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket, ipv4_checksum};
+use pnet::util::MacAddr;
+
+use std::cmp;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The UDP port of DHCP servers.
+pub const SERVER_PORT: u16 = 67;
+
+/// The UDP port of DHCP clients.
+pub const CLIENT_PORT: u16 = 68;
+
+/// BOOTP operations.
+pub const BOOTREQUEST: u8 = 1;
+pub const BOOTREPLY: u8 = 2;
+
+/// Starts the options of every DHCP message.
+pub const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Message types, the values of the `MESSAGE_TYPE` option.
+pub const DISCOVER: u8 = 1;
+pub const OFFER: u8 = 2;
+pub const REQUEST: u8 = 3;
+pub const DECLINE: u8 = 4;
+pub const ACK: u8 = 5;
+pub const NAK: u8 = 6;
+pub const RELEASE: u8 = 7;
+pub const INFORM: u8 = 8;
+
+/// Options, RFC 2132.
+pub const PAD: u8 = 0;
+pub const SUBNET_MASK: u8 = 1;
+pub const ROUTER: u8 = 3;
+pub const DNS_SERVERS: u8 = 6;
+pub const HOST_NAME: u8 = 12;
+pub const DOMAIN_NAME: u8 = 15;
+pub const BROADCAST_ADDRESS: u8 = 28;
+pub const REQUESTED_ADDRESS: u8 = 50;
+pub const LEASE_TIME: u8 = 51;
+pub const MESSAGE_TYPE: u8 = 53;
+pub const SERVER_ID: u8 = 54;
+pub const PARAMETER_REQUEST_LIST: u8 = 55;
+pub const RENEWAL_TIME: u8 = 58;
+pub const REBINDING_TIME: u8 = 59;
+pub const CLIENT_ID: u8 = 61;
+pub const END: u8 = 255;
+
+/// The lease time meaning a lease never expires.
+pub const INFINITE: u32 = 0xffff_ffff;
+
+/// Length of the fixed part of a message, up to the magic cookie.
+const HEADER_LEN: usize = 236;
+const BROADCAST_FLAG: u16 = 0x8000;
+const HTYPE_ETHERNET: u8 = 1;
+
+/// The longest retransmission timeout while obtaining a lease.
+const MAX_TIMEOUT_SECS: u64 = 64;
+
+/// The shortest time between requests renewing a lease.
+const MIN_RENEW_INTERVAL_SECS: u64 = 60;
+
+static BROADCAST_MAC: MacAddr = MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+/// Configuration of a DHCP client. The `Default` retransmission timing is
+/// the one RFC 2131 suggests.
+#[derive(Debug, Clone)]
+pub struct DhcpConfig {
+    /// Time to wait for an answer to the first DISCOVER or REQUEST. Doubled
+    /// for every retransmission, up to 64 seconds
+    pub timeout: Duration,
+    /// Number of times to send each DISCOVER and REQUEST before giving up
+    pub attempts: u32,
+    /// Address to ask for, such as the one of an earlier lease
+    pub requested_address: Option<Ipv4Addr>,
+    /// Client identifier. The hardware type and MAC address of the interface
+    /// if `None`
+    pub client_id: Option<Vec<u8>>,
+    pub host_name: Option<String>,
+    /// Whether to make the router of the lease the default gateway
+    pub default_route: bool,
+}
+
+impl Default for DhcpConfig {
+    fn default() -> DhcpConfig {
+        DhcpConfig {
+            timeout: Duration::from_secs(4),
+            attempts: 4,
+            requested_address: None,
+            client_id: None,
+            host_name: None,
+            default_route: true,
+        }
+    }
+}
+
+/// One option of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpOption {
+    pub code: u8,
+    pub value: Vec<u8>,
+}
+
+impl DhcpOption {
+    pub fn new(code: u8, value: Vec<u8>) -> DhcpOption {
+        DhcpOption {
+            code: code,
+            value: value,
+        }
+    }
+
+    pub fn ipv4(code: u8, ip: Ipv4Addr) -> DhcpOption {
+        DhcpOption::new(code, ip.octets().to_vec())
+    }
+
+    pub fn u32(code: u8, value: u32) -> DhcpOption {
+        let mut bytes = vec![0; 4];
+        write_u32(&mut bytes, value);
+        DhcpOption::new(code, bytes)
+    }
+}
+
+/// A DHCP message, the BOOTP fields rips uses and the options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub op: u8,
+    pub xid: u32,
+    /// Seconds since the client began obtaining or renewing its lease
+    pub secs: u16,
+    /// Asks the server to broadcast its reply
+    pub broadcast: bool,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: MacAddr,
+    pub options: Vec<DhcpOption>,
+}
+
+impl Message {
+    /// Creates a message without options and with all addresses
+    /// unspecified.
+    pub fn new(op: u8, xid: u32, chaddr: MacAddr) -> Message {
+        let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+        Message {
+            op: op,
+            xid: xid,
+            secs: 0,
+            broadcast: false,
+            ciaddr: unspecified,
+            yiaddr: unspecified,
+            siaddr: unspecified,
+            giaddr: unspecified,
+            chaddr: chaddr,
+            options: vec![],
+        }
+    }
+
+    /// Parses a message for an Ethernet hardware address. Options appearing
+    /// more than once are joined into one, as RFC 3396 has it. Returns
+    /// `None` if `data` is not such a message or its options are
+    /// malformed.
+    pub fn parse(data: &[u8]) -> Option<Message> {
+        if data.len() < HEADER_LEN + MAGIC_COOKIE.len() || data[1] != HTYPE_ETHERNET ||
+           data[2] != 6 || data[HEADER_LEN..HEADER_LEN + 4] != MAGIC_COOKIE {
+            return None;
+        }
+        let mut options: Vec<DhcpOption> = vec![];
+        let mut rest = &data[HEADER_LEN + 4..];
+        while !rest.is_empty() {
+            let code = rest[0];
+            if code == END {
+                break;
+            }
+            if code == PAD {
+                rest = &rest[1..];
+                continue;
+            }
+            if rest.len() < 2 || rest.len() < 2 + rest[1] as usize {
+                return None;
+            }
+            let value = &rest[2..2 + rest[1] as usize];
+            match options.iter().position(|option| option.code == code) {
+                Some(i) => options[i].value.extend_from_slice(value),
+                None => options.push(DhcpOption::new(code, value.to_vec())),
+            }
+            rest = &rest[2 + value.len()..];
+        }
+        Some(Message {
+            op: data[0],
+            xid: read_u32(&data[4..8]),
+            secs: read_u16(&data[8..10]),
+            broadcast: read_u16(&data[10..12]) & BROADCAST_FLAG != 0,
+            ciaddr: read_ipv4(&data[12..16]),
+            yiaddr: read_ipv4(&data[16..20]),
+            siaddr: read_ipv4(&data[20..24]),
+            giaddr: read_ipv4(&data[24..28]),
+            chaddr: MacAddr::new(data[28], data[29], data[30], data[31], data[32], data[33]),
+            options: options,
+        })
+    }
+
+    /// Returns the message as sent. Options longer than 255 bytes are split
+    /// into several, as RFC 3396 has it.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![0; HEADER_LEN];
+        buffer[0] = self.op;
+        buffer[1] = HTYPE_ETHERNET;
+        buffer[2] = 6;
+        write_u32(&mut buffer[4..8], self.xid);
+        write_u16(&mut buffer[8..10], self.secs);
+        write_u16(&mut buffer[10..12],
+                  if self.broadcast { BROADCAST_FLAG } else { 0 });
+        let addresses = [self.ciaddr, self.yiaddr, self.siaddr, self.giaddr];
+        for (i, ip) in addresses.iter().enumerate() {
+            buffer[12 + i * 4..16 + i * 4].copy_from_slice(&ip.octets());
+        }
+        let MacAddr(a, b, c, d, e, f) = self.chaddr;
+        buffer[28..34].copy_from_slice(&[a, b, c, d, e, f]);
+        buffer.extend_from_slice(&MAGIC_COOKIE);
+        for option in &self.options {
+            for chunk in option.value.chunks(255) {
+                buffer.push(option.code);
+                buffer.push(chunk.len() as u8);
+                buffer.extend_from_slice(chunk);
+            }
+            if option.value.is_empty() {
+                buffer.extend_from_slice(&[option.code, 0]);
+            }
+        }
+        buffer.push(END);
+        buffer
+    }
+
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options.iter().find(|option| option.code == code).map(|option| &option.value[..])
+    }
+
+    /// Returns the value of the `MESSAGE_TYPE` option.
+    pub fn message_type(&self) -> Option<u8> {
+        match self.option(MESSAGE_TYPE) {
+            Some(value) if value.len() == 1 => Some(value[0]),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of an option holding one address.
+    pub fn ipv4_option(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.option(code) {
+            Some(value) if value.len() == 4 => Some(read_ipv4(value)),
+            _ => None,
+        }
+    }
+
+    /// Returns the addresses of an option holding a list of them, none if
+    /// it is missing or malformed.
+    pub fn ipv4_list(&self, code: u8) -> Vec<Ipv4Addr> {
+        match self.option(code) {
+            Some(value) if value.len() % 4 == 0 => value.chunks(4).map(read_ipv4).collect(),
+            _ => vec![],
+        }
+    }
+
+    pub fn u32_option(&self, code: u8) -> Option<u32> {
+        match self.option(code) {
+            Some(value) if value.len() == 4 => Some(read_u32(value)),
+            _ => None,
+        }
+    }
+}
+
+/// An address leased from a DHCP server, with the configuration that came
+/// with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// The leased address, with the prefix of the subnet mask, 32 if the
+    /// server did not send one
+    pub address: Ipv4Network,
+    /// The server identifier of the server that granted the lease
+    pub server: Ipv4Addr,
+    /// The routers on the subnet, in order of preference
+    pub routers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// How long the lease is valid, counting from `acquired`
+    pub lease_time: Duration,
+    /// When to renew the lease, T1, counting from `acquired`
+    pub renewal_time: Duration,
+    /// When to rebind the lease, T2, counting from `acquired`
+    pub rebinding_time: Duration,
+    /// When the request the lease was granted for was sent
+    pub acquired: Instant,
+}
+
+impl Lease {
+    /// Reads the lease granted by `ack`, an answer to a request sent at
+    /// `acquired`. T1 and T2 default to half and seven eighths of the lease
+    /// time. Returns `None` if `ack` lacks the address, the server
+    /// identifier or the lease time.
+    pub fn from_ack(ack: &Message, acquired: Instant) -> Option<Lease> {
+        let server = ack.ipv4_option(SERVER_ID);
+        let lease_secs = ack.u32_option(LEASE_TIME);
+        let (server, lease_secs) = match (server, lease_secs) {
+            (Some(server), Some(lease_secs)) if is_specified(ack.yiaddr) => (server, lease_secs),
+            _ => return None,
+        };
+        let prefix = ack.ipv4_option(SUBNET_MASK)
+            .and_then(|mask| ipv4_mask_to_prefix(mask).ok())
+            .unwrap_or(32);
+        let (renewal_secs, rebinding_secs) = if lease_secs == INFINITE {
+            (INFINITE, INFINITE)
+        } else {
+            let rebinding =
+                ack.u32_option(REBINDING_TIME).unwrap_or((lease_secs as u64 * 7 / 8) as u32);
+            let rebinding = cmp::min(rebinding, lease_secs);
+            let renewal = ack.u32_option(RENEWAL_TIME).unwrap_or(lease_secs / 2);
+            (cmp::min(renewal, rebinding), rebinding)
+        };
+        Some(Lease {
+            address: Ipv4Network::new(ack.yiaddr, prefix).unwrap(),
+            server: server,
+            routers: ack.ipv4_list(ROUTER),
+            dns_servers: ack.ipv4_list(DNS_SERVERS),
+            lease_time: Duration::from_secs(lease_secs as u64),
+            renewal_time: Duration::from_secs(renewal_secs as u64),
+            rebinding_time: Duration::from_secs(rebinding_secs as u64),
+            acquired: acquired,
+        })
+    }
+
+    /// The preferred router, the default gateway of the lease.
+    pub fn router(&self) -> Option<Ipv4Addr> {
+        self.routers.first().cloned()
+    }
+
+    pub fn renews_at(&self) -> Instant {
+        self.acquired + self.renewal_time
+    }
+
+    pub fn rebinds_at(&self) -> Instant {
+        self.acquired + self.rebinding_time
+    }
+
+    pub fn expires_at(&self) -> Instant {
+        self.acquired + self.lease_time
+    }
+}
+
+/// A DHCP client keeping a lease on one interface of a stack, from a thread
+/// of its own. See the module documentation. Stops when dropped.
+pub struct DhcpClient {
+    binding: Arc<Binding>,
+    observer: Option<ObserverId>,
+    events: Option<Sender<Event>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DhcpClient {
+    /// Obtains a lease for `interface` and configures the interface with it.
+    /// Blocks until a server acknowledged a request, which takes two round
+    /// trips when servers answer. Then keeps the lease until stopped.
+    ///
+    /// Fails with an `io::Error` of kind `TimedOut` if no server answered,
+    /// or `ConnectionRefused` if the server declined the request.
+    pub fn start(stack: Arc<Mutex<NetworkStack>>,
+                 interface: &Interface,
+                 config: DhcpConfig)
+                 -> StackResult<DhcpClient> {
+        let (events, received) = mpsc::channel();
+        let replies = Replies {
+            mac: interface.mac,
+            events: Mutex::new(events.clone()),
+        };
+        let (observer, timers, clock) = {
+            let mut stack = stack.lock().unwrap();
+            let clock = stack.clock();
+            let stack_interface = stack.interface(interface)?;
+            (stack_interface.add_observer(replies), stack_interface.timers(), clock)
+        };
+        let binding = Arc::new(Binding {
+            stack: stack,
+            interface: interface.clone(),
+            config: config,
+            clock: clock,
+            bound: Mutex::new(None),
+        });
+        let mut client = Client {
+            binding: binding.clone(),
+            events: received,
+            timers: timers,
+            timeouts: events.clone(),
+            timeout: 0,
+            stopping: false,
+        };
+        let result = client.acquire().and_then(|bound| binding.bind(bound));
+        if let Err(e) = result {
+            binding.remove_observer(observer);
+            return Err(e);
+        }
+        let thread = thread::spawn(move || client.run());
+        Ok(DhcpClient {
+            binding: binding,
+            observer: Some(observer),
+            events: Some(events),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn interface(&self) -> &Interface {
+        &self.binding.interface
+    }
+
+    /// Returns the lease the interface is configured with, `None` if it
+    /// expired and no server granted a new one yet.
+    pub fn lease(&self) -> Option<Lease> {
+        self.binding.bound.lock().unwrap().as_ref().map(|bound| bound.lease.clone())
+    }
+
+    /// Stops keeping the lease and waits for the thread to quit. The
+    /// interface stays configured with the lease.
+    pub fn stop(&mut self) {
+        if let Some(events) = self.events.take() {
+            events.send(Event::Stop).unwrap_or(());
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+        if let Some(observer) = self.observer.take() {
+            self.binding.remove_observer(observer);
+        }
+    }
+
+    /// Stops the client and gives the lease back to its server with a
+    /// RELEASE, removing the address from the interface. Does nothing more
+    /// than `stop` if there is no lease.
+    pub fn release(&mut self) -> StackResult<()> {
+        self.stop();
+        let bound = self.binding.bound.lock().unwrap().clone();
+        if let Some(bound) = bound {
+            let ip = bound.lease.address.ip();
+            let xid = self.binding.random_xid();
+            let mut release = self.binding.message(RELEASE, xid, ip);
+            release.options.push(DhcpOption::ipv4(SERVER_ID, bound.lease.server));
+            let sent = self.binding.send((bound.server_mac, bound.lease.server), ip, &release);
+            self.binding.unbind()?;
+            sent?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DhcpClient {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// A lease, with the MAC address its server is reached at.
+#[derive(Debug, Clone)]
+struct Bound {
+    lease: Lease,
+    /// The source of the ACK of the lease, where renewals are sent
+    server_mac: MacAddr,
+}
+
+/// What the `DhcpClient` handle and the thread of a client share.
+struct Binding {
+    stack: Arc<Mutex<NetworkStack>>,
+    interface: Interface,
+    config: DhcpConfig,
+    clock: Arc<Clock>,
+    bound: Mutex<Option<Bound>>,
+}
+
+impl Binding {
+    /// Configures the interface with the lease of `bound`, replacing the
+    /// configuration from the current lease where it differs. There is no
+    /// lease if the address could not be added.
+    fn bind(&self, bound: Bound) -> StackResult<()> {
+        let old = self.bound.lock().unwrap().clone();
+        {
+            let lease = &bound.lease;
+            let mut stack = self.stack.lock().unwrap();
+            let moved = old.as_ref().map_or(true, |old| old.lease.address != lease.address);
+            if moved {
+                if let Some(ref old) = old {
+                    stack.remove_ipv4(&self.interface, old.lease.address.ip()).ok();
+                }
+                if let Err(e) = stack.add_ipv4(&self.interface, lease.address) {
+                    *self.bound.lock().unwrap() = None;
+                    return Err(e);
+                }
+                info!("DHCP: Leased {} on {} from {}",
+                      lease.address,
+                      self.interface.name,
+                      lease.server);
+            }
+            let rerouted = moved || old.map_or(true, |old| old.lease.router() != lease.router());
+            if let Some(router) = lease.router() {
+                if self.config.default_route && rerouted {
+                    if let Err(e) = stack.set_default_route(router, self.interface.clone()) {
+                        warn!("DHCP: Unable to route through {}: {}", router, e);
+                    }
+                }
+            }
+        }
+        *self.bound.lock().unwrap() = Some(bound);
+        Ok(())
+    }
+
+    /// Removes the address of the current lease from the interface, and with
+    /// it the routes through its routers.
+    fn unbind(&self) -> StackResult<()> {
+        let bound = self.bound.lock().unwrap().take();
+        if let Some(bound) = bound {
+            info!("DHCP: Lost the lease of {} on {}", bound.lease.address, self.interface.name);
+            self.stack.lock().unwrap().remove_ipv4(&self.interface, bound.lease.address.ip())?;
+        }
+        Ok(())
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns a new transaction id from the random number generator of the
+    /// stack.
+    fn random_xid(&self) -> u32 {
+        self.stack.lock().unwrap().random()
+    }
+
+    /// Creates a request of type `kind` from the address `ciaddr`,
+    /// unspecified while the client has none, which servers are asked to
+    /// answer with a broadcast.
+    fn message(&self, kind: u8, xid: u32, ciaddr: Ipv4Addr) -> Message {
+        let mut message = Message::new(BOOTREQUEST, xid, self.interface.mac);
+        message.ciaddr = ciaddr;
+        message.broadcast = !is_specified(ciaddr);
+        message.options.push(DhcpOption::new(MESSAGE_TYPE, vec![kind]));
+        let client_id = match self.config.client_id {
+            Some(ref client_id) => client_id.clone(),
+            None => {
+                let MacAddr(a, b, c, d, e, f) = self.interface.mac;
+                vec![HTYPE_ETHERNET, a, b, c, d, e, f]
+            }
+        };
+        message.options.push(DhcpOption::new(CLIENT_ID, client_id));
+        if kind == RELEASE {
+            return message;
+        }
+        if let Some(ref host_name) = self.config.host_name {
+            message.options.push(DhcpOption::new(HOST_NAME, host_name.as_bytes().to_vec()));
+        }
+        let parameters = vec![SUBNET_MASK, ROUTER, DNS_SERVERS, RENEWAL_TIME, REBINDING_TIME];
+        message.options.push(DhcpOption::new(PARAMETER_REQUEST_LIST, parameters));
+        message
+    }
+
+    /// Sends `message` from `src` to the server port of the MAC and IPv4
+    /// addresses `dst`.
+    fn send(&self, dst: (MacAddr, Ipv4Addr), src: Ipv4Addr, message: &Message) -> StackResult<()> {
+        let src = SocketAddrV4::new(src, CLIENT_PORT);
+        let dst = (dst.0, SocketAddrV4::new(dst.1, SERVER_PORT));
+        send_frame(&mut self.stack.lock().unwrap(), &self.interface, dst, src, message)
+    }
+
+    fn remove_observer(&self, observer: ObserverId) {
+        let mut stack = self.stack.lock().unwrap();
+        if let Ok(stack_interface) = stack.interface(&self.interface) {
+            stack_interface.remove_observer(observer);
+        }
+    }
+}
+
+/// What the thread of a client receives.
+enum Event {
+    /// A reply to the client, and the MAC address it came from
+    Reply(MacAddr, Message),
+    /// The timeout with the given number expired
+    Timeout(usize),
+    Stop,
+}
+
+/// The observer handing the replies to a client to its thread.
+struct Replies {
+    mac: MacAddr,
+    events: Mutex<Sender<Event>>,
+}
+
+impl Observer for Replies {
+    fn observe(&self,
+               direction: Direction,
+               _interface: &Interface,
+               _time: SystemTime,
+               frame: &EthernetPacket) {
+        if direction != Direction::Inbound {
+            return;
+        }
+        if let Some(reply) = parse_reply(self.mac, frame) {
+            let event = Event::Reply(frame.get_source(), reply);
+            self.events.lock().unwrap().send(event).unwrap_or(());
+        }
+    }
+}
+
+/// Returns the DHCP reply to the client with the MAC address `mac` carried
+/// by `frame`, if it is one.
+fn parse_reply(mac: MacAddr, frame: &EthernetPacket) -> Option<Message> {
+    let dst = frame.get_destination();
+    if frame.get_ethertype() != EtherTypes::Ipv4 || (dst != mac && dst != BROADCAST_MAC) {
+        return None;
+    }
+    let ip_pkg = match Ipv4Packet::new(frame.payload()) {
+        Some(ip_pkg) => ip_pkg,
+        None => return None,
+    };
+    let header_len = ip_pkg.get_header_length() as usize * 4;
+    let total_len = cmp::min(ip_pkg.get_total_length() as usize, ip_pkg.packet().len());
+    if ip_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Udp || header_len > total_len ||
+       ip_pkg.get_flags() & MORE_FRAGMENTS != 0 || ip_pkg.get_fragment_offset() != 0 {
+        return None;
+    }
+    let udp_pkg = match UdpPacket::new(&ip_pkg.packet()[header_len..total_len]) {
+        Some(udp_pkg) => udp_pkg,
+        None => return None,
+    };
+    if udp_pkg.get_source() != SERVER_PORT || udp_pkg.get_destination() != CLIENT_PORT {
+        return None;
+    }
+    Message::parse(udp_pkg.payload())
+        .and_then(|reply| if reply.op == BOOTREPLY && reply.chaddr == mac {
+            Some(reply)
+        } else {
+            None
+        })
+}
+
+/// Sends `message` from `src` on `interface` as a frame of its own, to the
+/// MAC address and socket address `dst`, without Arp or routing.
+fn send_frame(stack: &mut NetworkStack,
+              interface: &Interface,
+              dst: (MacAddr, SocketAddrV4),
+              src: SocketAddrV4,
+              message: &Message)
+              -> StackResult<()> {
+    let datagram = datagram(src, dst.1, &message.to_bytes());
+    let stack_interface = stack.interface(interface)?;
+    tx_send!(|| stack_interface.ethernet_tx(dst.0);
+             1, datagram.len(), BasicEthernetPayload::new(EtherTypes::Ipv4, &datagram))?;
+    Ok(())
+}
+
+/// Returns an IPv4 packet carrying `payload` in a UDP datagram from `src`
+/// to `dst`.
+fn datagram(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; 20 + 8 + payload.len()];
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut buffer[..]).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length((20 + 8 + payload.len()) as u16);
+        ip_pkg.set_ttl(DEFAULT_TTL);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_pkg.set_source(*src.ip());
+        ip_pkg.set_destination(*dst.ip());
+        {
+            let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+            udp_pkg.set_source(src.port());
+            udp_pkg.set_destination(dst.port());
+            udp_pkg.set_length((8 + payload.len()) as u16);
+            udp_pkg.set_payload(payload);
+            let csum = ipv4_checksum(&udp_pkg.to_immutable(), *src.ip(), *dst.ip());
+            udp_pkg.set_checksum(csum);
+        }
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    buffer
+}
+
+/// The state machine of a client, run by `DhcpClient::start` until there is
+/// a lease and then on the thread of the client.
+struct Client {
+    binding: Arc<Binding>,
+    events: Receiver<Event>,
+    /// The timer wheel of the interface, for the timeouts of the client
+    timers: Timers,
+    /// Where the timeouts of the client are sent when they expire
+    timeouts: Sender<Event>,
+    /// The number of the last timeout scheduled
+    timeout: usize,
+    /// Set once the handle asked the thread to quit
+    stopping: bool,
+}
+
+impl Client {
+    fn run(mut self) {
+        while !self.stopping {
+            if let Err(e) = self.step() {
+                if self.stopping {
+                    break;
+                }
+                warn!("DHCP: {} on {}", e, self.binding.interface.name);
+                let retry = self.binding.now() + self.binding.config.timeout;
+                self.sleep(retry);
+            }
+        }
+    }
+
+    /// Makes progress with the lease: obtains one if there is none, waits
+    /// for the renewal time and then renews or rebinds it.
+    fn step(&mut self) -> StackResult<()> {
+        let bound = self.binding.bound.lock().unwrap().clone();
+        let Bound { lease, server_mac } = match bound {
+            Some(bound) => bound,
+            None => {
+                let bound = self.acquire()?;
+                return self.binding.bind(bound);
+            }
+        };
+        let now = self.binding.now();
+        if now < lease.renews_at() {
+            self.sleep(lease.renews_at());
+            return Ok(());
+        }
+        if now >= lease.expires_at() {
+            return self.binding.unbind();
+        }
+        // RFC 2131 4.4.5: Waiting half the time left, down to a minute
+        let rebinding = now >= lease.rebinds_at();
+        let (until, dst) = if rebinding {
+            (lease.expires_at(), (BROADCAST_MAC, Ipv4Addr::new(255, 255, 255, 255)))
+        } else {
+            (lease.rebinds_at(), (server_mac, lease.server))
+        };
+        let interval = cmp::max((until - now) / 2, Duration::from_secs(MIN_RENEW_INTERVAL_SECS));
+        let deadline = cmp::min(until, now + interval);
+        let ip = lease.address.ip();
+        let xid = self.binding.random_xid();
+        self.binding.send(dst, ip, &self.binding.message(REQUEST, xid, ip))?;
+        while let Some((src, reply)) = self.recv(xid, deadline)? {
+            match reply.message_type() {
+                Some(ACK) => {
+                    if let Some(lease) = Lease::from_ack(&reply, now) {
+                        let bound = Bound {
+                            lease: lease,
+                            server_mac: src,
+                        };
+                        return self.binding.bind(bound);
+                    }
+                }
+                Some(NAK) => return self.binding.unbind(),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Obtains a lease with the DISCOVER, OFFER, REQUEST, ACK exchange.
+    fn acquire(&mut self) -> StackResult<Bound> {
+        let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+        let started = self.binding.now();
+        let xid = self.binding.random_xid();
+        let mut discover = self.binding.message(DISCOVER, xid, unspecified);
+        if let Some(ip) = self.binding.config.requested_address {
+            discover.options.push(DhcpOption::ipv4(REQUESTED_ADDRESS, ip));
+        }
+        let offer = self.exchange(started, discover, |_, reply| {
+                let server = reply.ipv4_option(SERVER_ID);
+                match (reply.message_type(), server) {
+                    (Some(OFFER), Some(server)) if is_specified(reply.yiaddr) => {
+                        Some((reply.yiaddr, server))
+                    }
+                    _ => None,
+                }
+            })?;
+        let (address, server) = offer;
+        debug!("DHCP: {} offered {}", server, address);
+
+        let mut request = self.binding.message(REQUEST, xid, unspecified);
+        request.options.push(DhcpOption::ipv4(REQUESTED_ADDRESS, address));
+        request.options.push(DhcpOption::ipv4(SERVER_ID, server));
+        let requested = self.binding.now();
+        let answer = self.exchange(started, request, |src, reply| {
+                if reply.ipv4_option(SERVER_ID).map_or(false, |id| id != server) {
+                    return None;
+                }
+                match reply.message_type() {
+                    Some(ACK) => {
+                        Lease::from_ack(reply, requested).map(|lease| {
+                            Ok(Bound {
+                                lease: lease,
+                                server_mac: src,
+                            })
+                        })
+                    }
+                    Some(NAK) => Some(Err(())),
+                    _ => None,
+                }
+            })?;
+        answer.map_err(|_| {
+            let msg = format!("{} declined the request for {}", server, address);
+            StackError::IoError(io::Error::new(io::ErrorKind::ConnectionRefused, msg))
+        })
+    }
+
+    /// Broadcasts `request` until a reply `accept` returns something for
+    /// arrives, with the retransmission timeouts of `DhcpConfig::timeout`.
+    fn exchange<F, T>(&mut self,
+                      started: Instant,
+                      mut request: Message,
+                      mut accept: F)
+                      -> StackResult<T>
+        where F: FnMut(MacAddr, &Message) -> Option<T>
+    {
+        let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+        let broadcast = (BROADCAST_MAC, Ipv4Addr::new(255, 255, 255, 255));
+        let mut timeout = self.binding.config.timeout;
+        for _ in 0..self.binding.config.attempts {
+            let now = self.binding.now();
+            request.secs = cmp::min((now - started).as_secs(), 0xffff) as u16;
+            self.binding.send(broadcast, unspecified, &request)?;
+            let deadline = now + timeout;
+            while let Some((src, reply)) = self.recv(request.xid, deadline)? {
+                if let Some(result) = accept(src, &reply) {
+                    return Ok(result);
+                }
+            }
+            timeout = cmp::min(timeout * 2, Duration::from_secs(MAX_TIMEOUT_SECS));
+        }
+        let what = if request.message_type() == Some(DISCOVER) { "DISCOVER" } else { "REQUEST" };
+        let msg = format!("No answer to {}", what);
+        Err(StackError::IoError(io::Error::new(io::ErrorKind::TimedOut, msg)))
+    }
+
+    /// Waits until `deadline` for a reply with the transaction id `xid`,
+    /// with a timeout on the timer wheel of the interface.
+    fn recv(&mut self, xid: u32, deadline: Instant) -> StackResult<Option<(MacAddr, Message)>> {
+        let now = self.binding.now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        self.timeout += 1;
+        let (timeout, timeouts) = (self.timeout, self.timeouts.clone());
+        let timer = self.timers.schedule(deadline - now, move || {
+            timeouts.send(Event::Timeout(timeout)).unwrap_or(());
+        });
+        let result = self.wait(xid, timeout);
+        self.timers.cancel(timer);
+        result
+    }
+
+    /// Waits for a reply with the transaction id `xid` until the timeout
+    /// `timeout` expires.
+    fn wait(&mut self, xid: u32, timeout: usize) -> StackResult<Option<(MacAddr, Message)>> {
+        loop {
+            match self.events.recv() {
+                Ok(Event::Reply(src, reply)) => {
+                    if reply.xid == xid {
+                        return Ok(Some((src, reply)));
+                    }
+                }
+                Ok(Event::Timeout(expired)) => {
+                    if expired == timeout {
+                        return Ok(None);
+                    }
+                }
+                Ok(Event::Stop) | Err(_) => {
+                    self.stopping = true;
+                    return Err(StackError::InvalidState("DHCP client stopped".to_owned()));
+                }
+            }
+        }
+    }
+
+    /// Waits until `deadline`, ignoring replies, or until stopped.
+    fn sleep(&mut self, deadline: Instant) {
+        while let Ok(Some(_)) = self.recv(0, deadline) {}
+    }
+}
+
+fn is_specified(ip: Ipv4Addr) -> bool {
+    ip != Ipv4Addr::new(0, 0, 0, 0)
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+fn read_ipv4(data: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(data[0], data[1], data[2], data[3])
+}
+
+fn write_u16(data: &mut [u8], value: u16) {
+    data[0] = (value >> 8) as u8;
+    data[1] = value as u8;
+}
+
+fn write_u32(data: &mut [u8], value: u32) {
+    data[0] = (value >> 24) as u8;
+    data[1] = (value >> 16) as u8;
+    data[2] = (value >> 8) as u8;
+    data[3] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn ack(options: Vec<DhcpOption>) -> Message {
+        let mut ack = Message::new(BOOTREPLY, 7, MacAddr::new(2, 0, 0, 0, 0, 1));
+        ack.yiaddr = Ipv4Addr::new(10, 0, 0, 5);
+        ack.options = options;
+        ack
+    }
+
+    #[test]
+    fn message_bytes() {
+        let mut message = Message::new(BOOTREQUEST, 0x01020304, MacAddr::new(2, 0, 0, 0, 0, 1));
+        message.secs = 3;
+        message.broadcast = true;
+        message.ciaddr = Ipv4Addr::new(10, 0, 0, 5);
+        message.options = vec![DhcpOption::new(MESSAGE_TYPE, vec![REQUEST]),
+                               DhcpOption::new(HOST_NAME, vec![b'a'; 300]),
+                               DhcpOption::u32(LEASE_TIME, 3600)];
+        let bytes = message.to_bytes();
+        assert_eq!(&bytes[..12], &[1, 1, 6, 0, 1, 2, 3, 4, 0, 3, 0x80, 0]);
+        assert_eq!(&bytes[12..16], &[10, 0, 0, 5]);
+        assert_eq!(&bytes[28..34], &[2, 0, 0, 0, 0, 1]);
+        assert_eq!(&bytes[236..243], &[99, 130, 83, 99, MESSAGE_TYPE, 1, REQUEST]);
+        // The host name is split in two
+        assert_eq!(&bytes[243..245], &[HOST_NAME, 255]);
+        assert_eq!(&bytes[500..502], &[HOST_NAME, 45]);
+        assert_eq!(bytes.len(), 243 + 2 * 2 + 300 + 6 + 1);
+        assert_eq!(bytes.last(), Some(&END));
+
+        let parsed = Message::parse(&bytes).unwrap();
+        assert_eq!(parsed, message);
+        assert_eq!(parsed.message_type(), Some(REQUEST));
+        assert_eq!(parsed.u32_option(LEASE_TIME), Some(3600));
+
+        let mut padded = bytes.clone();
+        padded.insert(240, PAD);
+        assert_eq!(Message::parse(&padded), Some(message));
+        assert!(Message::parse(&bytes[..239]).is_none());
+        let mut truncated = bytes[..245].to_vec();
+        truncated.push(END);
+        assert!(Message::parse(&truncated).is_none());
+        let mut wrong_cookie = bytes;
+        wrong_cookie[236] = 0;
+        assert!(Message::parse(&wrong_cookie).is_none());
+    }
+
+    #[test]
+    fn lease() {
+        let now = Instant::now();
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        let dns = [Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(10, 0, 0, 54)];
+        let mut dns_option = dns[0].octets().to_vec();
+        dns_option.extend_from_slice(&dns[1].octets());
+        let options = vec![DhcpOption::ipv4(SERVER_ID, server),
+                           DhcpOption::u32(LEASE_TIME, 800),
+                           DhcpOption::ipv4(SUBNET_MASK, Ipv4Addr::new(255, 255, 255, 0)),
+                           DhcpOption::ipv4(ROUTER, server),
+                           DhcpOption::new(DNS_SERVERS, dns_option)];
+        let lease = Lease::from_ack(&ack(options.clone()), now).unwrap();
+        assert_eq!(lease.address, Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 5), 24).unwrap());
+        assert_eq!((lease.server, lease.router()), (server, Some(server)));
+        assert_eq!(lease.dns_servers, dns.to_vec());
+        assert_eq!((lease.renewal_time, lease.rebinding_time, lease.lease_time),
+                   (Duration::from_secs(400), Duration::from_secs(700), Duration::from_secs(800)));
+        assert_eq!(lease.expires_at(), now + Duration::from_secs(800));
+
+        // T1 and T2 are kept within the lease time
+        let mut times = options[..2].to_vec();
+        times.push(DhcpOption::u32(RENEWAL_TIME, 900));
+        times.push(DhcpOption::u32(REBINDING_TIME, 1000));
+        let lease = Lease::from_ack(&ack(times), now).unwrap();
+        assert_eq!(lease.address.prefix(), 32);
+        assert_eq!((lease.renewal_time, lease.rebinding_time),
+                   (Duration::from_secs(800), Duration::from_secs(800)));
+        assert!(lease.routers.is_empty());
+
+        assert!(Lease::from_ack(&ack(options[1..].to_vec()), now).is_none());
+        let mut no_address = ack(options);
+        no_address.yiaddr = Ipv4Addr::new(0, 0, 0, 0);
+        assert!(Lease::from_ack(&no_address, now).is_none());
+    }
+}
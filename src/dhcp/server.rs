@@ -0,0 +1,689 @@
+//! DHCP server, for handing out addresses on a network of the stack.
+//!
+//! `spawn` serves the network of one address of the stack. It leases
+//! addresses from a pool to the clients asking for one, always the same one
+//! to clients with a reservation, along with the subnet mask and the
+//! routers, DNS servers and domain name of the `DhcpServerConfig`:
+//!
+//! ```rust,ignore
+//! let first = Ipv4Addr::new(10, 0, 0, 100);
+//! let mut config = DhcpServerConfig::new(first, Ipv4Addr::new(10, 0, 0, 199));
+//! config.routers = vec![Ipv4Addr::new(10, 0, 0, 1)];
+//! config.reservations.insert(printer_mac, Ipv4Addr::new(10, 0, 0, 9));
+//! let server = server::spawn(stack.clone(), Ipv4Addr::new(10, 0, 0, 1), config)?;
+//! ```
+//!
+//! `DhcpServer` holds the leases and answers the messages of clients without
+//! doing any IO itself. Leases are only kept in memory. Requests for
+//! addresses the server can not lease are declined with a NAK, the way an
+//! authoritative server does, and requests for free addresses of the pool
+//! are granted, so clients keep their addresses over a restart of the
+//! server. Requests forwarded by relay agents are answered through the
+//! relay, with addresses of the same pool.
+
+use {Interface, NetworkStack, StackError, StackResult};
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::UdpSocket;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::util::MacAddr;
+
+use super::{ACK, BOOTREPLY, BOOTREQUEST, BROADCAST_ADDRESS, BROADCAST_MAC, CLIENT_ID, CLIENT_PORT,
+            DECLINE, DISCOVER, DNS_SERVERS, DOMAIN_NAME, DhcpOption, HOST_NAME, HTYPE_ETHERNET,
+            INFINITE, INFORM, LEASE_TIME, MESSAGE_TYPE, Message, NAK, OFFER, REBINDING_TIME,
+            RELEASE, RENEWAL_TIME, REQUEST, REQUESTED_ADDRESS, ROUTER, SERVER_ID, SERVER_PORT,
+            SUBNET_MASK, is_specified, send_frame};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long the thread of `spawn` waits for a request before checking if it
+/// is to stop.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// What a `DhcpServer` leases, and what it tells clients.
+#[derive(Debug, Clone)]
+pub struct DhcpServerConfig {
+    /// The first address of the pool
+    pub first: Ipv4Addr,
+    /// The last address of the pool, inclusive
+    pub last: Ipv4Addr,
+    /// Addresses leased to the clients with these MAC addresses only. They
+    /// do not have to be in the pool
+    pub reservations: HashMap<MacAddr, Ipv4Addr>,
+    pub lease_time: Duration,
+    /// How long an address offered to a client is kept for it
+    pub offer_time: Duration,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub domain_name: Option<String>,
+}
+
+impl DhcpServerConfig {
+    /// Leases the addresses from `first` to `last` for an hour, without
+    /// reservations, routers, DNS servers or domain name.
+    pub fn new(first: Ipv4Addr, last: Ipv4Addr) -> DhcpServerConfig {
+        DhcpServerConfig {
+            first: first,
+            last: last,
+            reservations: HashMap::new(),
+            lease_time: Duration::from_secs(3600),
+            offer_time: Duration::from_secs(60),
+            routers: vec![],
+            dns_servers: vec![],
+            domain_name: None,
+        }
+    }
+}
+
+/// An address leased to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerLease {
+    pub address: Ipv4Addr,
+    pub mac: MacAddr,
+    /// The client identifier of the client, its hardware type and MAC
+    /// address if it sent none
+    pub client_id: Vec<u8>,
+    pub host_name: Option<String>,
+    pub expires: Instant,
+}
+
+/// An address offered or leased to a client.
+#[derive(Debug, Clone)]
+struct Allocation {
+    lease: ServerLease,
+    /// False while the address is only offered
+    bound: bool,
+}
+
+/// The leases of a DHCP server for one network. Does not do any IO itself,
+/// the messages of clients are fed to it and it returns the replies.
+pub struct DhcpServer {
+    server_id: Ipv4Addr,
+    network: Ipv4Network,
+    config: DhcpServerConfig,
+    allocations: HashMap<Ipv4Addr, Allocation>,
+    /// Addresses clients found in use by some other host, with when to try
+    /// them again
+    declined: HashMap<Ipv4Addr, Instant>,
+}
+
+impl DhcpServer {
+    /// Creates a server for `network` with the address `server_id`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if the pool is empty, or if
+    /// it or a reservation is not in `network`.
+    pub fn new(server_id: Ipv4Addr,
+               network: Ipv4Network,
+               config: DhcpServerConfig)
+               -> StackResult<DhcpServer> {
+        let invalid = |msg: String| Err(StackError::InvalidArgument(msg));
+        if u32::from(config.first) > u32::from(config.last) {
+            return invalid(format!("Empty pool {} - {}", config.first, config.last));
+        }
+        if !network.contains(config.first) || !network.contains(config.last) {
+            return invalid(format!("Pool {} - {} is not in {}",
+                                   config.first,
+                                   config.last,
+                                   network));
+        }
+        if let Some(ip) = config.reservations.values().find(|ip| !network.contains(**ip)) {
+            return invalid(format!("Reserved address {} is not in {}", ip, network));
+        }
+        Ok(DhcpServer {
+            server_id: server_id,
+            network: network,
+            config: config,
+            allocations: HashMap::new(),
+            declined: HashMap::new(),
+        })
+    }
+
+    pub fn config(&self) -> &DhcpServerConfig {
+        &self.config
+    }
+
+    /// Returns the addresses leased to clients, by address. Not the ones
+    /// only offered.
+    pub fn leases(&self) -> Vec<ServerLease> {
+        let mut leases = self.allocations
+            .values()
+            .filter(|allocation| allocation.bound)
+            .map(|allocation| allocation.lease.clone())
+            .collect::<Vec<_>>();
+        leases.sort_by_key(|lease| lease.address);
+        leases
+    }
+
+    /// Handles `request`, received at `now`. Returns the reply to send, if
+    /// there is one.
+    pub fn handle(&mut self, request: &Message, now: Instant) -> Option<Message> {
+        if request.op != BOOTREQUEST {
+            return None;
+        }
+        self.expire(now);
+        let client_id = match request.option(CLIENT_ID) {
+            Some(client_id) => client_id.to_vec(),
+            None => {
+                let MacAddr(a, b, c, d, e, f) = request.chaddr;
+                vec![HTYPE_ETHERNET, a, b, c, d, e, f]
+            }
+        };
+        match request.message_type() {
+            Some(DISCOVER) => self.discover(request, client_id, now),
+            Some(REQUEST) => self.request(request, client_id, now),
+            Some(DECLINE) => {
+                if request.ipv4_option(SERVER_ID) == Some(self.server_id) {
+                    if let Some(ip) = request.ipv4_option(REQUESTED_ADDRESS) {
+                        self.decline(ip, &client_id, now);
+                    }
+                }
+                None
+            }
+            Some(RELEASE) => {
+                if self.allocated_to(request.ciaddr, &client_id) {
+                    debug!("DHCP server: {} released {}", request.chaddr, request.ciaddr);
+                    self.allocations.remove(&request.ciaddr);
+                }
+                None
+            }
+            Some(INFORM) => Some(self.reply(request, ACK, Ipv4Addr::new(0, 0, 0, 0), false)),
+            _ => None,
+        }
+    }
+
+    fn discover(&mut self, request: &Message, client_id: Vec<u8>, now: Instant) -> Option<Message> {
+        let ip = match self.choose(request, &client_id) {
+            Some(ip) => ip,
+            None => {
+                warn!("DHCP server: No address left for {}", request.chaddr);
+                return None;
+            }
+        };
+        let offered = now + self.config.offer_time;
+        {
+            let allocation = self.allocations.entry(ip).or_insert_with(|| {
+                Allocation {
+                    lease: ServerLease {
+                        address: ip,
+                        mac: request.chaddr,
+                        client_id: client_id,
+                        host_name: None,
+                        expires: offered,
+                    },
+                    bound: false,
+                }
+            });
+            if !allocation.bound {
+                allocation.lease.expires = offered;
+            }
+        }
+        Some(self.reply(request, OFFER, ip, true))
+    }
+
+    fn request(&mut self, request: &Message, client_id: Vec<u8>, now: Instant) -> Option<Message> {
+        if let Some(server) = request.ipv4_option(SERVER_ID) {
+            if server != self.server_id {
+                // The client took the offer of another server
+                if let Some(ip) = self.address_of(&client_id) {
+                    if !self.allocations[&ip].bound {
+                        self.allocations.remove(&ip);
+                    }
+                }
+                return None;
+            }
+        }
+        let ip = match request.ipv4_option(REQUESTED_ADDRESS) {
+            Some(ip) => ip,
+            None if is_specified(request.ciaddr) => request.ciaddr,
+            None => return None,
+        };
+        if !self.assignable(ip, request.chaddr, &client_id) {
+            debug!("DHCP server: Declining {} to {}", ip, request.chaddr);
+            return Some(self.reply(request, NAK, Ipv4Addr::new(0, 0, 0, 0), false));
+        }
+        if let Some(old) = self.address_of(&client_id) {
+            if old != ip {
+                self.allocations.remove(&old);
+            }
+        }
+        let new = !self.allocated_to(ip, &client_id) || !self.allocations[&ip].bound;
+        if new {
+            info!("DHCP server: Leased {} to {}", ip, request.chaddr);
+        }
+        let host_name = request.option(HOST_NAME)
+            .map(|host_name| String::from_utf8_lossy(host_name).into_owned());
+        let allocation = Allocation {
+            lease: ServerLease {
+                address: ip,
+                mac: request.chaddr,
+                client_id: client_id,
+                host_name: host_name,
+                expires: now + self.config.lease_time,
+            },
+            bound: true,
+        };
+        self.allocations.insert(ip, allocation);
+        Some(self.reply(request, ACK, ip, true))
+    }
+
+    fn decline(&mut self, ip: Ipv4Addr, client_id: &[u8], now: Instant) {
+        if self.allocated_to(ip, client_id) {
+            warn!("DHCP server: {} is in use by a host without a lease", ip);
+            self.allocations.remove(&ip);
+            self.declined.insert(ip, now + self.config.lease_time);
+        }
+    }
+
+    /// Picks the address to offer: the reserved one, the one the client
+    /// already has, the one it asks for, or any free one, in this order.
+    fn choose(&self, request: &Message, client_id: &[u8]) -> Option<Ipv4Addr> {
+        if let Some(ip) = self.config.reservations.get(&request.chaddr) {
+            return Some(*ip);
+        }
+        if let Some(ip) = self.address_of(client_id) {
+            return Some(ip);
+        }
+        if let Some(ip) = request.ipv4_option(REQUESTED_ADDRESS) {
+            if self.is_free(ip) {
+                return Some(ip);
+            }
+        }
+        (u32::from(self.config.first) as u64..u32::from(self.config.last) as u64 + 1)
+            .map(|ip| Ipv4Addr::from(ip as u32))
+            .find(|ip| self.is_free(*ip))
+    }
+
+    /// Returns true if `ip` may be leased to the client with the MAC address
+    /// `mac` and `client_id`.
+    fn assignable(&self, ip: Ipv4Addr, mac: MacAddr, client_id: &[u8]) -> bool {
+        match self.config.reservations.get(&mac) {
+            Some(reserved) => *reserved == ip,
+            None => self.allocated_to(ip, client_id) || self.is_free(ip),
+        }
+    }
+
+    /// Returns true if `ip` is an address of the pool that is not reserved,
+    /// offered, leased or declined.
+    fn is_free(&self, ip: Ipv4Addr) -> bool {
+        let in_pool = u32::from(self.config.first) <= u32::from(ip) &&
+                      u32::from(ip) <= u32::from(self.config.last);
+        in_pool && ip != self.server_id && ip != self.network.network() &&
+        ip != self.network.broadcast() && !self.allocations.contains_key(&ip) &&
+        !self.declined.contains_key(&ip) &&
+        !self.config.reservations.values().any(|reserved| *reserved == ip)
+    }
+
+    fn allocated_to(&self, ip: Ipv4Addr, client_id: &[u8]) -> bool {
+        self.allocations
+            .get(&ip)
+            .map_or(false, |allocation| allocation.lease.client_id == client_id)
+    }
+
+    fn address_of(&self, client_id: &[u8]) -> Option<Ipv4Addr> {
+        self.allocations
+            .iter()
+            .find(|&(_, allocation)| allocation.lease.client_id == client_id)
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Forgets the offers and leases that expired at `now`.
+    fn expire(&mut self, now: Instant) {
+        let expired = self.allocations
+            .iter()
+            .filter(|&(_, allocation)| allocation.lease.expires <= now)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+        for ip in expired {
+            if self.allocations.remove(&ip).map_or(false, |allocation| allocation.bound) {
+                debug!("DHCP server: Lease of {} expired", ip);
+            }
+        }
+        let retried = self.declined
+            .iter()
+            .filter(|&(_, until)| *until <= now)
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+        for ip in retried {
+            self.declined.remove(&ip);
+        }
+    }
+
+    /// Creates a reply of type `kind` to `request`, leasing `yiaddr` if
+    /// `lease` is true.
+    fn reply(&self, request: &Message, kind: u8, yiaddr: Ipv4Addr, lease: bool) -> Message {
+        let mut reply = Message::new(BOOTREPLY, request.xid, request.chaddr);
+        reply.yiaddr = yiaddr;
+        reply.giaddr = request.giaddr;
+        reply.broadcast = request.broadcast;
+        if kind == ACK {
+            reply.ciaddr = request.ciaddr;
+        }
+        reply.options.push(DhcpOption::new(MESSAGE_TYPE, vec![kind]));
+        reply.options.push(DhcpOption::ipv4(SERVER_ID, self.server_id));
+        if let Some(client_id) = request.option(CLIENT_ID) {
+            reply.options.push(DhcpOption::new(CLIENT_ID, client_id.to_vec()));
+        }
+        if kind == NAK {
+            return reply;
+        }
+        if lease {
+            let secs = cmp::min(self.config.lease_time.as_secs(), INFINITE as u64) as u32;
+            reply.options.push(DhcpOption::u32(LEASE_TIME, secs));
+            if secs != INFINITE {
+                reply.options.push(DhcpOption::u32(RENEWAL_TIME, secs / 2));
+                reply.options.push(DhcpOption::u32(REBINDING_TIME, (secs as u64 * 7 / 8) as u32));
+            }
+        }
+        reply.options.push(DhcpOption::ipv4(SUBNET_MASK, self.network.mask()));
+        reply.options.push(DhcpOption::ipv4(BROADCAST_ADDRESS, self.network.broadcast()));
+        let lists = [(ROUTER, &self.config.routers), (DNS_SERVERS, &self.config.dns_servers)];
+        for &(code, ips) in lists.iter().filter(|&&(_, ips)| !ips.is_empty()) {
+            let value = ips.iter().flat_map(|ip| ip.octets().to_vec()).collect();
+            reply.options.push(DhcpOption::new(code, value));
+        }
+        if let Some(ref domain_name) = self.config.domain_name {
+            reply.options.push(DhcpOption::new(DOMAIN_NAME, domain_name.as_bytes().to_vec()));
+        }
+        reply
+    }
+}
+
+/// Handle to a DHCP server running in a background thread. Stops when
+/// dropped.
+pub struct DhcpServerHandle {
+    server: Arc<Mutex<DhcpServer>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DhcpServerHandle {
+    /// Returns the addresses leased to clients, see `DhcpServer::leases`.
+    pub fn leases(&self) -> Vec<ServerLease> {
+        self.server.lock().unwrap().leases()
+    }
+
+    /// Stops serving and waits for the thread to quit.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for DhcpServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Starts serving the network of the local address `local_ip`, from a UDP
+/// socket bound to it.
+///
+/// Fails with `StackError::NoSourceAddress` if no interface has `local_ip`,
+/// and like `DhcpServer::new` if `config` does not fit its network.
+pub fn spawn(stack: Arc<Mutex<NetworkStack>>,
+             local_ip: Ipv4Addr,
+             config: DhcpServerConfig)
+             -> StackResult<DhcpServerHandle> {
+    let (interface, network) = {
+        let mut stack = stack.lock().unwrap();
+        let mut found = None;
+        for interface in stack.interfaces() {
+            let networks = stack.interface(&interface)?.ipv4_networks();
+            if let Some(net) = networks.into_iter().find(|net| net.ip() == local_ip) {
+                found = Some((interface, net));
+            }
+        }
+        found.ok_or(StackError::NoSourceAddress(local_ip))?
+    };
+    let server = Arc::new(Mutex::new(DhcpServer::new(local_ip, network, config)?));
+    let local = SocketAddrV4::new(local_ip, SERVER_PORT);
+    let mut socket = UdpSocket::bind(stack.clone(), local)?;
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+    let running = Arc::new(AtomicBool::new(true));
+
+    let thread_server = server.clone();
+    let thread_running = running.clone();
+    let thread = thread::spawn(move || {
+        let mut buffer = vec![0; 1 << 16];
+        while thread_running.load(Ordering::SeqCst) {
+            let len = match socket.recv_from(&mut buffer) {
+                Ok((len, _)) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    warn!("DHCP server: Dropping datagram: {}", e);
+                    continue;
+                }
+            };
+            let request = match Message::parse(&buffer[..len]) {
+                Some(request) => request,
+                None => continue,
+            };
+            let reply = thread_server.lock().unwrap().handle(&request, Instant::now());
+            if let Some(reply) = reply {
+                let sent = send_reply(&stack, &interface, &mut socket, local, &request, &reply);
+                if let Err(e) = sent {
+                    warn!("DHCP server: Unable to answer {}: {}", request.chaddr, e);
+                }
+            }
+        }
+    });
+    Ok(DhcpServerHandle {
+        server: server,
+        running: running,
+        thread: Some(thread),
+    })
+}
+
+/// Sends `reply` where RFC 2131 section 4.1 says: to the relay agent that
+/// forwarded `request`, to the address of a client that has one, or else
+/// to the client's MAC address, or as a broadcast if the client asked for
+/// one.
+fn send_reply(stack: &Arc<Mutex<NetworkStack>>,
+              interface: &Interface,
+              socket: &mut UdpSocket,
+              local: SocketAddrV4,
+              request: &Message,
+              reply: &Message)
+              -> StackResult<()> {
+    if is_specified(request.giaddr) {
+        socket.send_to(&reply.to_bytes(), SocketAddrV4::new(request.giaddr, SERVER_PORT))?;
+        return Ok(());
+    }
+    let all = SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), CLIENT_PORT);
+    let broadcast = (BROADCAST_MAC, all);
+    let dst = if reply.message_type() == Some(NAK) {
+        broadcast
+    } else if is_specified(request.ciaddr) {
+        socket.send_to(&reply.to_bytes(), SocketAddrV4::new(request.ciaddr, CLIENT_PORT))?;
+        return Ok(());
+    } else if request.broadcast {
+        broadcast
+    } else {
+        (request.chaddr, SocketAddrV4::new(reply.yiaddr, CLIENT_PORT))
+    };
+    send_frame(&mut stack.lock().unwrap(), interface, dst, local, reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnetwork::Ipv4Network;
+
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn server(first: u8, last: u8) -> DhcpServer {
+        let mut config = DhcpServerConfig::new(Ipv4Addr::new(10, 0, 0, first),
+                                               Ipv4Addr::new(10, 0, 0, last));
+        config.routers = vec![Ipv4Addr::new(10, 0, 0, 1)];
+        config.dns_servers = vec![Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(10, 0, 0, 54)];
+        config.domain_name = Some("lab".to_owned());
+        let network = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+        DhcpServer::new(Ipv4Addr::new(10, 0, 0, 1), network, config).unwrap()
+    }
+
+    fn request(kind: u8, client: u8, options: Vec<DhcpOption>) -> Message {
+        let mac = MacAddr::new(2, 0, 0, 0, 0, client);
+        let mut request = Message::new(BOOTREQUEST, client as u32, mac);
+        request.options.push(DhcpOption::new(MESSAGE_TYPE, vec![kind]));
+        request.options.extend(options);
+        request
+    }
+
+    /// Runs the DISCOVER, OFFER, REQUEST, ACK exchange for `client`,
+    /// returning the address it got.
+    fn lease(server: &mut DhcpServer, client: u8, now: Instant) -> Ipv4Addr {
+        let offer = server.handle(&request(DISCOVER, client, vec![]), now).unwrap();
+        assert_eq!(offer.message_type(), Some(OFFER));
+        let options = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, offer.yiaddr),
+                           DhcpOption::ipv4(SERVER_ID, Ipv4Addr::new(10, 0, 0, 1))];
+        let ack = server.handle(&request(REQUEST, client, options), now).unwrap();
+        assert_eq!((ack.message_type(), ack.yiaddr), (Some(ACK), offer.yiaddr));
+        ack.yiaddr
+    }
+
+    #[test]
+    fn pool() {
+        let now = Instant::now();
+        let mut server = server(1, 3);
+        let offer = server.handle(&request(DISCOVER, 1, vec![]), now).unwrap();
+        // The server's own address is not leased
+        assert_eq!((offer.op, offer.xid, offer.yiaddr), (BOOTREPLY, 1, Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(offer.ipv4_option(SERVER_ID), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(offer.u32_option(LEASE_TIME), Some(3600));
+        assert_eq!(offer.u32_option(RENEWAL_TIME), Some(1800));
+        assert_eq!(offer.u32_option(REBINDING_TIME), Some(3150));
+        assert_eq!(offer.ipv4_option(SUBNET_MASK), Some(Ipv4Addr::new(255, 255, 255, 0)));
+        assert_eq!(offer.ipv4_option(BROADCAST_ADDRESS), Some(Ipv4Addr::new(10, 0, 0, 255)));
+        assert_eq!(offer.ipv4_option(ROUTER), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(offer.ipv4_list(DNS_SERVERS).len(), 2);
+        assert_eq!(offer.option(DOMAIN_NAME), Some(&b"lab"[..]));
+        // Offers are not leases
+        assert!(server.leases().is_empty());
+
+        assert_eq!(lease(&mut server, 1, now), Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(lease(&mut server, 2, now), Ipv4Addr::new(10, 0, 0, 3));
+        assert!(server.handle(&request(DISCOVER, 3, vec![]), now).is_none());
+        let leases = server.leases();
+        assert_eq!(leases.len(), 2);
+        assert_eq!((leases[0].address, leases[0].mac), (Ipv4Addr::new(10, 0, 0, 2),
+                                                         MacAddr::new(2, 0, 0, 0, 0, 1)));
+        assert_eq!(leases[0].client_id, vec![1, 2, 0, 0, 0, 0, 1]);
+        // A client asking again gets the address it has
+        assert_eq!(lease(&mut server, 2, now), Ipv4Addr::new(10, 0, 0, 3));
+
+        // Renewing
+        let mut renewal = request(REQUEST, 1, vec![]);
+        renewal.ciaddr = Ipv4Addr::new(10, 0, 0, 2);
+        let later = now + Duration::from_secs(1800);
+        let ack = server.handle(&renewal, later).unwrap();
+        assert_eq!((ack.message_type(), ack.ciaddr), (Some(ACK), renewal.ciaddr));
+        assert_eq!(server.leases()[0].expires, later + Duration::from_secs(3600));
+
+        // Client 2 lets its lease expire
+        let offer = server.handle(&request(DISCOVER, 3, vec![]), now + Duration::from_secs(3600));
+        assert_eq!(offer.unwrap().yiaddr, Ipv4Addr::new(10, 0, 0, 3));
+    }
+
+    #[test]
+    fn requests() {
+        let now = Instant::now();
+        let mut server = server(10, 20);
+        let requested = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, Ipv4Addr::new(10, 0, 0, 15))];
+        let offer = server.handle(&request(DISCOVER, 1, requested.clone()), now).unwrap();
+        assert_eq!(offer.yiaddr, Ipv4Addr::new(10, 0, 0, 15));
+
+        // Taken by client 1, outside the pool, and on another network
+        for ip in &[15, 30] {
+            let options = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, Ipv4Addr::new(10, 0, 0, *ip))];
+            let nak = server.handle(&request(REQUEST, 2, options), now).unwrap();
+            assert_eq!((nak.message_type(), nak.yiaddr), (Some(NAK), Ipv4Addr::new(0, 0, 0, 0)));
+            assert_eq!(nak.option(LEASE_TIME), None);
+        }
+        let other = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, Ipv4Addr::new(192, 168, 0, 2))];
+        assert_eq!(server.handle(&request(REQUEST, 2, other), now).unwrap().message_type(),
+                   Some(NAK));
+        // A free address of the pool is granted without an offer
+        let free = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, Ipv4Addr::new(10, 0, 0, 12))];
+        assert_eq!(server.handle(&request(REQUEST, 2, free), now).unwrap().message_type(),
+                   Some(ACK));
+
+        // Client 1 takes the offer of another server
+        let mut options = requested.clone();
+        options.push(DhcpOption::ipv4(SERVER_ID, Ipv4Addr::new(10, 0, 0, 2)));
+        assert!(server.handle(&request(REQUEST, 1, options), now).is_none());
+        assert_eq!(server.handle(&request(DISCOVER, 3, requested), now).unwrap().yiaddr,
+                   Ipv4Addr::new(10, 0, 0, 15));
+
+        // Released
+        let mut release = request(RELEASE, 2, vec![]);
+        release.ciaddr = Ipv4Addr::new(10, 0, 0, 12);
+        assert!(server.handle(&release, now).is_none());
+        assert!(server.leases().is_empty());
+
+        // Informed
+        let mut inform = request(INFORM, 4, vec![]);
+        inform.ciaddr = Ipv4Addr::new(10, 0, 0, 40);
+        let ack = server.handle(&inform, now).unwrap();
+        assert_eq!((ack.message_type(), ack.yiaddr), (Some(ACK), Ipv4Addr::new(0, 0, 0, 0)));
+        assert_eq!((ack.ciaddr, ack.option(LEASE_TIME)), (inform.ciaddr, None));
+        assert_eq!(ack.ipv4_option(ROUTER), Some(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn reservations_and_declines() {
+        let now = Instant::now();
+        let mut server = server(10, 11);
+        {
+            let reservations = &mut server.config.reservations;
+            reservations.insert(MacAddr::new(2, 0, 0, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 9));
+            reservations.insert(MacAddr::new(2, 0, 0, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 10));
+        }
+        assert_eq!(lease(&mut server, 1, now), Ipv4Addr::new(10, 0, 0, 9));
+        assert_eq!(lease(&mut server, 3, now), Ipv4Addr::new(10, 0, 0, 11));
+        // Clients with a reservation get nothing else
+        let options = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, Ipv4Addr::new(10, 0, 0, 11))];
+        assert_eq!(server.handle(&request(REQUEST, 2, options), now).unwrap().message_type(),
+                   Some(NAK));
+        assert_eq!(lease(&mut server, 2, now), Ipv4Addr::new(10, 0, 0, 10));
+
+        let options = vec![DhcpOption::ipv4(REQUESTED_ADDRESS, Ipv4Addr::new(10, 0, 0, 11)),
+                           DhcpOption::ipv4(SERVER_ID, Ipv4Addr::new(10, 0, 0, 1))];
+        assert!(server.handle(&request(DECLINE, 3, options), now).is_none());
+        assert_eq!(server.leases().len(), 2);
+        assert!(server.handle(&request(DISCOVER, 3, vec![]), now).is_none());
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(lease(&mut server, 3, later), Ipv4Addr::new(10, 0, 0, 11));
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let network = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+        let server_id = Ipv4Addr::new(10, 0, 0, 1);
+        let empty = DhcpServerConfig::new(Ipv4Addr::new(10, 0, 0, 20), Ipv4Addr::new(10, 0, 0, 10));
+        let outside = DhcpServerConfig::new(Ipv4Addr::new(10, 0, 0, 10),
+                                            Ipv4Addr::new(10, 0, 1, 10));
+        let mut reserved = DhcpServerConfig::new(Ipv4Addr::new(10, 0, 0, 10),
+                                                 Ipv4Addr::new(10, 0, 0, 20));
+        reserved.reservations.insert(MacAddr::new(2, 0, 0, 0, 0, 1), Ipv4Addr::new(10, 1, 0, 1));
+        for config in vec![empty, outside, reserved] {
+            match DhcpServer::new(server_id, network, config) {
+                Err(StackError::InvalidArgument(_)) => (),
+                _ => panic!("Accepted an invalid config"),
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Handing received packets to listeners on threads of their own.
+//!
+//! Listeners are called on the thread receiving on their interface, so a
+//! slow one holds up everything else received there. Wrapping a listener in
+//! a `Queued` one makes it be called from a thread of its own instead, fed
+//! through a bounded queue. The receiving thread only copies the packet into
+//! the queue. When the queue is full, packets are dropped as its
+//! `DropPolicy` says, and counted in its `DispatchStats`.
+//!
+//! ```rust,ignore
+//! let listener = Queued::udp(MyListener, DispatchConfig::default());
+//! let queue = listener.handle();
+//! stack.udp_listen("10.0.0.2:53", listener)?;
+//! ...
+//! println!("{} dropped", queue.stats().dropped);
+//! ```
+//!
+//! Clones of a `Queued` listener, such as the ones a stack makes for every
+//! interface it binds on, share the queue and thread. The thread quits after
+//! delivering what is left in the queue once all clones are dropped, or as
+//! soon as a UDP listener returns that it does not want more packets.
+
+use RxResult;
+use icmp::IcmpListener;
+use ipv4::Ipv4Listener;
+use pool::{BufferPool, PooledBuffer};
+use udp::UdpListener;
+
+use pnet::packet::Packet;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+/// Number of packets a queue holds by default.
+pub static DEFAULT_CAPACITY: usize = 1024;
+
+/// What to do with a packet arriving at a full queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the arriving packet
+    Newest,
+    /// Drop the packet that has waited longest, making room for the arriving
+    /// one
+    Oldest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatchConfig {
+    /// Packets the queue holds before dropping, at least one
+    pub capacity: usize,
+    pub policy: DropPolicy,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> DispatchConfig {
+        DispatchConfig {
+            capacity: DEFAULT_CAPACITY,
+            policy: DropPolicy::Newest,
+        }
+    }
+}
+
+/// Counters for one queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DispatchStats {
+    /// Packets waiting in the queue right now
+    pub queued: usize,
+    pub delivered: usize,
+    /// Packets dropped because the queue was full
+    pub dropped: usize,
+}
+
+struct Queue {
+    packets: VecDeque<(SystemTime, PooledBuffer)>,
+    stats: DispatchStats,
+    /// Set when all listeners feeding the queue are gone
+    closed: bool,
+    /// Set when the thread quit because the listener did not want more
+    stopped: bool,
+}
+
+struct Shared {
+    config: DispatchConfig,
+    /// Holds the copies of the queued packets
+    pool: BufferPool,
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// Closes the queue when the last clone of a `Queued` listener is dropped.
+struct Closer(Arc<Shared>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().closed = true;
+        self.0.ready.notify_one();
+    }
+}
+
+/// A listener queueing the packets it receives for the listener `L`, which
+/// is called from a thread of its own. See the module documentation.
+pub struct Queued<L> {
+    shared: Arc<Shared>,
+    _closer: Arc<Closer>,
+    _listener: PhantomData<L>,
+}
+
+impl<L> Clone for Queued<L> {
+    fn clone(&self) -> Queued<L> {
+        Queued {
+            shared: self.shared.clone(),
+            _closer: self._closer.clone(),
+            _listener: PhantomData,
+        }
+    }
+}
+
+impl<L: UdpListener + 'static> Queued<L> {
+    pub fn udp(mut listener: L, config: DispatchConfig) -> Queued<L> {
+        Self::spawn(config, move |time, packet| listener.recv(time, &packet).1)
+    }
+}
+
+impl<L: IcmpListener + 'static> Queued<L> {
+    pub fn icmp(mut listener: L, config: DispatchConfig) -> Queued<L> {
+        Self::spawn(config, move |time, packet| {
+            listener.recv(time, &packet);
+            true
+        })
+    }
+}
+
+impl<L: Ipv4Listener + 'static> Queued<L> {
+    /// The wrapped listener can not report errors to the receiving thread,
+    /// they are only logged.
+    pub fn ipv4(mut listener: L, config: DispatchConfig) -> Queued<L> {
+        Self::spawn(config, move |time, packet| {
+            if let Err(e) = listener.recv(time, packet) {
+                debug!("Queued Ipv4 listener failed: {:?}", e);
+            }
+            true
+        })
+    }
+}
+
+impl<L> Queued<L> {
+    fn spawn<F>(config: DispatchConfig, deliver: F) -> Queued<L>
+        where F: FnMut(SystemTime, Ipv4Packet) -> bool + Send + 'static
+    {
+        assert!(config.capacity > 0, "Queue capacity must be at least one");
+        let shared = Arc::new(Shared {
+            config: config,
+            pool: BufferPool::default(),
+            queue: Mutex::new(Queue {
+                packets: VecDeque::new(),
+                stats: DispatchStats::default(),
+                closed: false,
+                stopped: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::spawn(move || run(thread_shared, deliver));
+        Queued {
+            shared: shared.clone(),
+            _closer: Arc::new(Closer(shared)),
+            _listener: PhantomData,
+        }
+    }
+
+    /// Returns a handle for reading the counters of the queue, also after the
+    /// listener was given to the stack.
+    pub fn handle(&self) -> QueueHandle {
+        QueueHandle { shared: self.shared.clone() }
+    }
+
+    /// Queues `packet`. Returns false if the listener does not want more.
+    fn push(&self, time: SystemTime, packet: &Ipv4Packet) -> bool {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.stopped {
+            return false;
+        }
+        if queue.packets.len() >= self.shared.config.capacity {
+            queue.stats.dropped += 1;
+            match self.shared.config.policy {
+                DropPolicy::Newest => return true,
+                DropPolicy::Oldest => {
+                    queue.packets.pop_front();
+                }
+            }
+        }
+        queue.packets.push_back((time, self.shared.pool.copy_of(packet.packet())));
+        self.shared.ready.notify_one();
+        true
+    }
+}
+
+impl<L: UdpListener + 'static> UdpListener for Queued<L> {
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+        (Ok(()), self.push(time, packet))
+    }
+}
+
+impl<L: IcmpListener + 'static> IcmpListener for Queued<L> {
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) {
+        self.push(time, packet);
+    }
+}
+
+impl<L: Ipv4Listener + 'static> Ipv4Listener for Queued<L> {
+    fn recv(&mut self, time: SystemTime, packet: Ipv4Packet) -> RxResult {
+        self.push(time, &packet);
+        Ok(())
+    }
+}
+
+/// Reads the counters of the queue of a `Queued` listener.
+#[derive(Clone)]
+pub struct QueueHandle {
+    shared: Arc<Shared>,
+}
+
+impl QueueHandle {
+    pub fn stats(&self) -> DispatchStats {
+        let queue = self.shared.queue.lock().unwrap();
+        DispatchStats { queued: queue.packets.len(), ..queue.stats }
+    }
+}
+
+fn run<F>(shared: Arc<Shared>, mut deliver: F)
+    where F: FnMut(SystemTime, Ipv4Packet) -> bool
+{
+    loop {
+        let (time, data) = {
+            let mut queue = shared.queue.lock().unwrap();
+            while queue.packets.is_empty() && !queue.closed {
+                queue = shared.ready.wait(queue).unwrap();
+            }
+            match queue.packets.pop_front() {
+                Some(packet) => packet,
+                None => return,
+            }
+        };
+        let resume = deliver(time, Ipv4Packet::new(&data).unwrap());
+        let mut queue = shared.queue.lock().unwrap();
+        queue.stats.delivered += 1;
+        if !resume {
+            queue.stopped = true;
+            queue.packets.clear();
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use RxResult;
+    use udp::UdpListener;
+
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    /// Reports the identification of every packet, after waiting for a go
+    /// from the test.
+    struct Gated {
+        started: Sender<u16>,
+        go: Receiver<()>,
+    }
+
+    impl UdpListener for Gated {
+        fn recv(&mut self, _time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+            self.started.send(packet.get_identification()).unwrap();
+            self.go.recv().unwrap();
+            (Ok(()), packet.get_identification() != 0)
+        }
+    }
+
+    fn send(listener: &mut Queued<Gated>, identification: u16) -> bool {
+        let mut buffer = [0; 20];
+        let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+        packet.set_identification(identification);
+        listener.recv(SystemTime::now(), &packet.to_immutable()).1
+    }
+
+    fn gated(policy: DropPolicy) -> (Queued<Gated>, Receiver<u16>, Sender<()>) {
+        let (started_tx, started) = mpsc::channel();
+        let (go, go_rx) = mpsc::channel();
+        let config = DispatchConfig {
+            capacity: 2,
+            policy: policy,
+        };
+        let listener = Queued::udp(Gated {
+                                       started: started_tx,
+                                       go: go_rx,
+                                   },
+                                   config);
+        (listener, started, go)
+    }
+
+    fn delivered_after_overflow(policy: DropPolicy) -> Vec<u16> {
+        let (mut listener, started, go) = gated(policy);
+        let queue = listener.handle();
+        assert!(send(&mut listener, 1));
+        // The listener holds 1, so 2, 3 and 4 go to the queue of two
+        assert_eq!(started.recv_timeout(Duration::from_secs(1)), Ok(1));
+        for identification in 2..5 {
+            assert!(send(&mut listener, identification));
+        }
+        let stats = queue.stats();
+        assert_eq!((stats.queued, stats.dropped), (2, 1));
+        let mut delivered = vec![1];
+        for _ in 0..3 {
+            go.send(()).unwrap();
+            if let Ok(identification) = started.recv_timeout(Duration::from_millis(100)) {
+                delivered.push(identification);
+            }
+        }
+        delivered
+    }
+
+    #[test]
+    fn drop_newest() {
+        assert_eq!(delivered_after_overflow(DropPolicy::Newest), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        assert_eq!(delivered_after_overflow(DropPolicy::Oldest), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn stop_when_not_resumed() {
+        let (mut listener, started, go) = gated(DropPolicy::Newest);
+        let queue = listener.handle();
+        assert!(send(&mut listener, 0));
+        assert_eq!(started.recv_timeout(Duration::from_secs(1)), Ok(0));
+        go.send(()).unwrap();
+        while queue.stats().delivered == 0 {
+            thread::yield_now();
+        }
+        assert!(!send(&mut listener, 1));
+    }
+}
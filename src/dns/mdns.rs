@@ -0,0 +1,1425 @@
+//! Multicast DNS, RFC 6762, and DNS-based service discovery, RFC 6763.
+//!
+//! A `Responder` claims a host name in `.local` for a local address, and the
+//! names of the services it advertises, and answers the queries for them
+//! sent to the mDNS group 224.0.0.251. The host and its services then show
+//! up in Avahi and Bonjour tools:
+//!
+//! ```rust,ignore
+//! let mut config = MdnsConfig::new("sensor");
+//! config.services.push(Service::new("Sensor web", "_http._tcp", 80));
+//! let responder = mdns::spawn(stack.clone(), Ipv4Addr::new(10, 0, 0, 2), config)?;
+//! // `avahi-browse -r _http._tcp` finds it on sensor.local, port 80
+//! ```
+//!
+//! Names are probed before they are used, with three queries 250
+//! milliseconds apart asking if anyone has them already. A name someone
+//! answers for is taken, and the next one is tried: `sensor-2`, `sensor-3`
+//! for the host, `Sensor web (2)` for a service. When two hosts probe for a
+//! name at the same time, the one whose records compare lower probes again a
+//! second later. Once probed, the records are announced twice and the names
+//! are defended: probes for them are answered, and a host announcing other
+//! records for one of them makes the responder probe for it again. Stopping
+//! sends the records with a TTL of zero, so caches drop them.
+//!
+//! Queries are answered to the group, to the sender if it asked for a
+//! unicast reply, and to the port it asked from if that is not the mDNS
+//! port, as for one-shot queries. The answers a query says it knows already
+//! are left out of the reply to it.
+//!
+//! `browse` lists the instances of a service type on the link, and
+//! `lookup_host` the addresses of a `.local` name, with one-shot queries
+//! from a socket of their own.
+//!
+//! The group is received by joining its multicast MAC address on the
+//! interface of the local address, see `StackInterface::join_multicast`,
+//! and sent to from the local address without a route to it.
+
+use {Interface, NetworkStack, StackError, StackResult};
+use ethernet;
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::{UdpSocket, UdpTx};
+
+use super::{CLASS_IN, HEADER_LEN, MAX_LABEL_LEN, RESPONSE, encode_labels, read_labels, read_u16,
+            read_u32, reverse_name, write_u16};
+
+use std::cmp;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The port mDNS is sent from and to.
+pub const MDNS_PORT: u16 = 5353;
+
+/// How long the thread of `spawn` waits for a query before checking if it
+/// is to stop, or has something to send.
+const POLL_INTERVAL_MS: u64 = 50;
+
+const PROBES: usize = 3;
+const PROBE_INTERVAL_MS: u64 = 250;
+const ANNOUNCEMENTS: usize = 2;
+const ANNOUNCE_INTERVAL_MS: u64 = 1000;
+/// How long the loser of a simultaneous probe waits before probing again
+const LOST_TIEBREAK_MS: u64 = 1000;
+/// Conflicts after which probing slows down to once every 5 seconds
+const MAX_CONFLICTS: usize = 15;
+const CONFLICT_BACKOFF_SECS: u64 = 5;
+/// Highest TTL given in replies to one-shot queries
+const LEGACY_TTL: u32 = 10;
+
+const AUTHORITATIVE: u16 = 0x0400;
+/// Set in a question that asks for a unicast reply, and in a record that
+/// replaces the other records of its name and type in caches
+const TOP_BIT: u16 = 0x8000;
+
+const A: u16 = 1;
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const SRV: u16 = 33;
+const ANY: u16 = 255;
+
+/// Returns the group mDNS is sent to, 224.0.0.251.
+pub fn group() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 251)
+}
+
+/// A service advertised by a `Responder`, as an instance of its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// The name of the instance, shown to users. May hold spaces and dots
+    pub instance: String,
+    /// The service and protocol labels, like `_http._tcp`
+    pub service_type: String,
+    pub port: u16,
+    /// The `key=value` strings of the TXT record
+    pub txt: Vec<String>,
+}
+
+impl Service {
+    /// Creates a service without TXT strings.
+    pub fn new(instance: &str, service_type: &str, port: u16) -> Service {
+        Service {
+            instance: instance.to_owned(),
+            service_type: service_type.to_owned(),
+            port: port,
+            txt: Vec::new(),
+        }
+    }
+}
+
+/// The names a `Responder` claims, and how long its records are cached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsConfig {
+    /// The host name, one label without `.local`
+    pub host_name: String,
+    pub services: Vec<Service>,
+    /// TTL of the records of the host name, and of the SRV records of
+    /// services
+    pub host_ttl: Duration,
+    /// TTL of the other records of services
+    pub service_ttl: Duration,
+}
+
+impl MdnsConfig {
+    /// Claims `host_name` with no services, with the TTLs RFC 6762
+    /// recommends: two minutes for the host and 75 minutes for services.
+    pub fn new(host_name: &str) -> MdnsConfig {
+        MdnsConfig {
+            host_name: host_name.to_owned(),
+            services: Vec::new(),
+            host_ttl: Duration::from_secs(120),
+            service_ttl: Duration::from_secs(75 * 60),
+        }
+    }
+}
+
+/// Where a `Responder` is with claiming its names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// `sent` probes went out, the next one at `next`
+    Probing { sent: usize, next: Instant },
+    Announcing { sent: usize, next: Instant },
+    Claimed,
+}
+
+/// The mDNS responder of one local address, claiming the names of its
+/// config and answering for them, without doing any IO itself. See the
+/// module documentation.
+#[derive(Debug, Clone)]
+pub struct Responder {
+    local_ip: Ipv4Addr,
+    config: MdnsConfig,
+    /// The host label and service instances in use, renamed after conflicts
+    host: String,
+    instances: Vec<String>,
+    /// Conflicts of the host and of each service, for naming the next try
+    host_conflicts: usize,
+    instance_conflicts: Vec<usize>,
+    /// When the conflicts of the last 10 seconds started, and how many
+    conflicts: (Instant, usize),
+    state: State,
+}
+
+impl Responder {
+    /// Creates a responder for `local_ip` at `now`, sending the first probe
+    /// after `probe_delay`. RFC 6762 section 8.1 wants that delay random, up
+    /// to `PROBE_INTERVAL_MS`, so hosts starting together do not collide.
+    ///
+    /// Fails with `StackError::InvalidArgument` if the host name is not a
+    /// label, or a service has an empty instance name, a type other than
+    /// `_service._tcp` or `_service._udp`, or a TXT string of more than 255
+    /// bytes.
+    pub fn new(local_ip: Ipv4Addr,
+               config: MdnsConfig,
+               now: Instant,
+               probe_delay: Duration)
+               -> StackResult<Responder> {
+        let invalid = |msg: String| Err(StackError::InvalidArgument(msg));
+        if !is_label(&config.host_name) || config.host_name.contains('.') {
+            return invalid(format!("Invalid host name: {}", config.host_name));
+        }
+        for service in &config.services {
+            let labels = service.service_type.split('.').collect::<Vec<_>>();
+            let type_valid = labels.len() == 2 && labels[0].len() > 1 &&
+                             labels[0].starts_with('_') && is_label(labels[0]) &&
+                             (labels[1] == "_tcp" || labels[1] == "_udp");
+            if !type_valid {
+                return invalid(format!("Invalid service type: {}", service.service_type));
+            }
+            if !is_label(&service.instance) {
+                return invalid(format!("Invalid instance name: {}", service.instance));
+            }
+            if service.txt.iter().any(|txt| txt.len() > 255) {
+                return invalid(format!("TXT string too long for {}", service.instance));
+            }
+        }
+        let mut responder = Responder {
+            local_ip: local_ip,
+            host: config.host_name.clone(),
+            instances: config.services.iter().map(|service| service.instance.clone()).collect(),
+            host_conflicts: 0,
+            instance_conflicts: vec![0; config.services.len()],
+            conflicts: (now, 0),
+            config: config,
+            state: State::Claimed,
+        };
+        responder.probe(now + probe_delay);
+        Ok(responder)
+    }
+
+    pub fn config(&self) -> &MdnsConfig {
+        &self.config
+    }
+
+    /// Returns the host name in use, with `.local`.
+    pub fn host_name(&self) -> String {
+        format!("{}.local", self.host)
+    }
+
+    /// Returns the services of the config, with the instance names in use.
+    pub fn services(&self) -> Vec<Service> {
+        self.config
+            .services
+            .iter()
+            .zip(self.instances.iter())
+            .map(|(service, instance)| Service { instance: instance.clone(), ..service.clone() })
+            .collect()
+    }
+
+    /// Returns true once the names in use are probed, and answered for.
+    pub fn is_claimed(&self) -> bool {
+        match self.state {
+            State::Probing { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Returns the probe or announcement due at `now`, to send to the
+    /// group, if any.
+    pub fn poll(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.state {
+            State::Probing { sent, next } if next <= now => {
+                if sent == PROBES {
+                    info!("mDNS: Claimed {} on {}", self.host_name(), self.local_ip);
+                    self.state = State::Announcing { sent: 0, next: now };
+                    return self.poll(now);
+                }
+                let next = now + Duration::from_millis(PROBE_INTERVAL_MS);
+                self.state = State::Probing {
+                    sent: sent + 1,
+                    next: next,
+                };
+                Some(self.probe_message())
+            }
+            State::Announcing { sent, next } if next <= now => {
+                self.state = if sent + 1 == ANNOUNCEMENTS {
+                    State::Claimed
+                } else {
+                    State::Announcing {
+                        sent: sent + 1,
+                        next: now + Duration::from_millis(ANNOUNCE_INTERVAL_MS),
+                    }
+                };
+                Some(response(0, &[], &self.records(), &[]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles the message `packet` from `src` and returns the replies to
+    /// send, with where to send them.
+    pub fn handle(&mut self,
+                  packet: &[u8],
+                  src: SocketAddrV4,
+                  now: Instant)
+                  -> Vec<(Vec<u8>, SocketAddrV4)> {
+        let message = match Message::parse(packet) {
+            Some(message) => message,
+            None => return Vec::new(),
+        };
+        if *src.ip() == self.local_ip {
+            return Vec::new();
+        }
+        if message.flags & RESPONSE != 0 {
+            self.check_response(&message, now);
+            Vec::new()
+        } else {
+            self.check_probe(&message, now);
+            if self.is_claimed() {
+                self.answer(&message, src).into_iter().collect()
+            } else {
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns the records announced with a TTL of zero, to send to the
+    /// group when stopping, if the names were claimed.
+    pub fn goodbye(&self) -> Option<Vec<u8>> {
+        if !self.is_claimed() {
+            return None;
+        }
+        let records = self.records()
+            .into_iter()
+            .map(|record| OwnRecord { ttl: 0, ..record })
+            .collect::<Vec<_>>();
+        Some(response(0, &[], &records, &[]))
+    }
+
+    /// Starts probing at `next`, slowed down after many conflicts.
+    fn probe(&mut self, next: Instant) {
+        let next = if self.conflicts.1 > MAX_CONFLICTS {
+            cmp::max(next, self.conflicts.0 + Duration::from_secs(CONFLICT_BACKOFF_SECS))
+        } else {
+            next
+        };
+        self.state = State::Probing {
+            sent: 0,
+            next: next,
+        };
+    }
+
+    /// Counts a conflict at `now`, restarting the count every 10 seconds.
+    fn count_conflict(&mut self, now: Instant) {
+        if now.duration_since(self.conflicts.0) > Duration::from_secs(10) {
+            self.conflicts = (now, 0);
+        }
+        self.conflicts.1 += 1;
+    }
+
+    /// Takes the next name for the host, or the service `index`.
+    fn rename(&mut self, index: Option<usize>) {
+        match index {
+            None => {
+                self.host_conflicts += 1;
+                let old = self.host_name();
+                self.host = format!("{}-{}", self.config.host_name, self.host_conflicts + 1);
+                info!("mDNS: {} is taken, probing for {}", old, self.host_name());
+            }
+            Some(index) => {
+                self.instance_conflicts[index] += 1;
+                let new = format!("{} ({})",
+                                  self.config.services[index].instance,
+                                  self.instance_conflicts[index] + 1);
+                info!("mDNS: {} is taken, probing for {}", self.instances[index], new);
+                self.instances[index] = new;
+            }
+        }
+    }
+
+    /// Returns the names only this responder may have records of: `None`
+    /// for the host name, and the indices of the services with theirs.
+    fn unique_names(&self) -> Vec<(Option<usize>, Vec<String>)> {
+        let mut names = vec![(None, local(&[&self.host]))];
+        for (index, service) in self.config.services.iter().enumerate() {
+            names.push((Some(index), self.instance_name(index, service)));
+        }
+        names
+    }
+
+    fn instance_name(&self, index: usize, service: &Service) -> Vec<String> {
+        let mut name = vec![self.instances[index].clone()];
+        name.extend(service_name(&service.service_type));
+        name
+    }
+
+    /// Renames what `message`, a response of another host, has records of,
+    /// while probing. Once claimed, probes again for names it has other
+    /// unique records of.
+    fn check_response(&mut self, message: &Message, now: Instant) {
+        let own = self.records();
+        let mut conflicts = Vec::new();
+        for (index, name) in self.unique_names() {
+            let mut records = message.answers.iter().chain(message.additionals.iter());
+            let conflict = if self.is_claimed() {
+                records.any(|record| {
+                    same_name(&record.name, &name) && record.class & TOP_BIT != 0 &&
+                    !own.iter().any(|own| own.matches(record))
+                })
+            } else {
+                records.any(|record| same_name(&record.name, &name))
+            };
+            if conflict {
+                conflicts.push(index);
+            }
+        }
+        if conflicts.is_empty() {
+            return;
+        }
+        self.count_conflict(now);
+        if self.is_claimed() {
+            info!("mDNS: Another host announced records of {}, probing again",
+                  self.host_name());
+        } else {
+            for index in conflicts {
+                self.rename(index);
+            }
+        }
+        self.probe(now);
+    }
+
+    /// Probes again later if `message` is a probe of a name being probed
+    /// for with records that win the tie-break of RFC 6762 section 8.2.
+    fn check_probe(&mut self, message: &Message, now: Instant) {
+        if self.is_claimed() || message.authorities.is_empty() {
+            return;
+        }
+        let own = self.records();
+        for (_, name) in self.unique_names() {
+            let probed = message.questions.iter().any(|question| same_name(&question.name, &name));
+            if !probed {
+                continue;
+            }
+            let sorted = |records: Vec<(u16, u16, Vec<u8>)>| {
+                let mut records = records;
+                records.sort();
+                records
+            };
+            let ours = sorted(own.iter()
+                .filter(|record| same_name(&record.name, &name))
+                .map(|record| (CLASS_IN, record.record_type, record.data.clone()))
+                .collect());
+            let theirs = sorted(message.authorities
+                .iter()
+                .filter(|record| same_name(&record.name, &name))
+                .map(|record| (record.class & !TOP_BIT, record.record_type, record.data.clone()))
+                .collect());
+            if theirs > ours {
+                info!("mDNS: Lost a simultaneous probe for {}, probing again",
+                      name.join("."));
+                self.probe(now + Duration::from_millis(LOST_TIEBREAK_MS));
+                return;
+            }
+        }
+    }
+
+    /// Returns the reply to the query `message` from `src`, if it asks for
+    /// records this responder has.
+    fn answer(&self, message: &Message, src: SocketAddrV4) -> Option<(Vec<u8>, SocketAddrV4)> {
+        let own = self.records();
+        let mut answers: Vec<OwnRecord> = Vec::new();
+        let mut unicast = src.port() != MDNS_PORT;
+        for question in &message.questions {
+            if question.class & !TOP_BIT != CLASS_IN {
+                continue;
+            }
+            let mut answered = false;
+            for record in &own {
+                let type_matches = question.record_type == ANY ||
+                                   question.record_type == record.record_type;
+                if !type_matches || !same_name(&question.name, &record.name) {
+                    continue;
+                }
+                answered = true;
+                let known = message.answers.iter().any(|known| {
+                    own_matches_known(record, known)
+                });
+                if !known && !answers.contains(record) {
+                    answers.push(record.clone());
+                }
+            }
+            unicast = unicast || answered && question.class & TOP_BIT != 0;
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        let additionals = own.iter()
+            .filter(|record| {
+                !answers.contains(record) &&
+                answers.iter().any(|answer| answer.implies(record))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        // And the records of the names the additional records point to
+        let additionals = own.iter()
+            .filter(|record| {
+                !answers.contains(record) &&
+                (additionals.contains(record) ||
+                 additionals.iter().any(|additional| additional.implies(record)))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if src.port() != MDNS_PORT {
+            let legacy = |records: Vec<OwnRecord>| {
+                records.into_iter()
+                    .map(|record| {
+                        OwnRecord {
+                            ttl: cmp::min(record.ttl, LEGACY_TTL),
+                            unique: false,
+                            ..record
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let reply = response(message.id,
+                                 &message.questions,
+                                 &legacy(answers),
+                                 &legacy(additionals));
+            Some((reply, src))
+        } else if unicast {
+            Some((response(0, &[], &answers, &additionals), src))
+        } else {
+            let dst = SocketAddrV4::new(group(), MDNS_PORT);
+            Some((response(0, &[], &answers, &additionals), dst))
+        }
+    }
+
+    /// Returns the query probing for the names in use, with the records
+    /// claimed for them in its authority section.
+    fn probe_message(&self) -> Vec<u8> {
+        let questions = self.unique_names()
+            .into_iter()
+            .map(|(_, name)| {
+                Question {
+                    name: name,
+                    record_type: ANY,
+                    class: CLASS_IN | TOP_BIT,
+                }
+            })
+            .collect::<Vec<_>>();
+        let authorities = self.records()
+            .into_iter()
+            .filter(|record| record.unique && record.record_type != PTR)
+            .collect::<Vec<_>>();
+        let mut message = header(0, 0, &[questions.len(), 0, authorities.len(), 0]);
+        for question in &questions {
+            question.write(&mut message);
+        }
+        for record in &authorities {
+            record.write(&mut message);
+        }
+        message
+    }
+
+    /// Returns all records of the names in use.
+    fn records(&self) -> Vec<OwnRecord> {
+        let host_ttl = self.config.host_ttl.as_secs() as u32;
+        let service_ttl = self.config.service_ttl.as_secs() as u32;
+        let host = local(&[&self.host]);
+        let reverse = reverse_name(IpAddr::V4(self.local_ip));
+        let address = self.local_ip.octets().to_vec();
+        let host_data = name_data(&host);
+        let mut records = vec![OwnRecord::new(host.clone(), A, true, host_ttl, address),
+                               OwnRecord::new(split(&reverse), PTR, true, host_ttl, host_data)];
+        let enumeration = local(&["_services", "_dns-sd", "_udp"]);
+        for (index, service) in self.config.services.iter().enumerate() {
+            let service_name = service_name(&service.service_type);
+            let instance = self.instance_name(index, service);
+            let mut srv = vec![0, 0, 0, 0];
+            write_u16(&mut srv, service.port);
+            srv.extend(name_data(&host));
+            let mut txt = Vec::new();
+            for string in &service.txt {
+                txt.push(string.len() as u8);
+                txt.extend_from_slice(string.as_bytes());
+            }
+            if txt.is_empty() {
+                txt.push(0);
+            }
+            records.push(OwnRecord::new(enumeration.clone(),
+                                        PTR,
+                                        false,
+                                        service_ttl,
+                                        name_data(&service_name)));
+            let instance_data = name_data(&instance);
+            records.push(OwnRecord::new(service_name, PTR, false, service_ttl, instance_data));
+            records.push(OwnRecord::new(instance.clone(), SRV, true, host_ttl, srv));
+            records.push(OwnRecord::new(instance, TXT, true, service_ttl, txt));
+        }
+        let mut unique = Vec::new();
+        for record in records {
+            if !unique.contains(&record) {
+                unique.push(record);
+            }
+        }
+        unique
+    }
+}
+
+/// A record of the names of a `Responder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OwnRecord {
+    name: Vec<String>,
+    record_type: u16,
+    /// If no other host may have records of the name and type
+    unique: bool,
+    ttl: u32,
+    /// The record data, with names uncompressed
+    data: Vec<u8>,
+}
+
+impl OwnRecord {
+    fn new(name: Vec<String>,
+           record_type: u16,
+           unique: bool,
+           ttl: u32,
+           data: Vec<u8>)
+           -> OwnRecord {
+        OwnRecord {
+            name: name,
+            record_type: record_type,
+            unique: unique,
+            ttl: ttl,
+            data: data,
+        }
+    }
+
+    /// Returns true if `record` has the name, type and data of this one.
+    fn matches(&self, record: &MessageRecord) -> bool {
+        self.record_type == record.record_type && same_name(&self.name, &record.name) &&
+        same_data(self.record_type, &self.data, &record.data)
+    }
+
+    /// Returns true if `other` is a record of the name this one points to,
+    /// sent along with it as additional record, see RFC 6763 section 12.
+    fn implies(&self, other: &OwnRecord) -> bool {
+        let target = match self.record_type {
+            PTR => read_labels(&self.data, 0),
+            SRV => read_labels(&self.data[6..], 0),
+            _ => None,
+        };
+        match target {
+            Some((target, _)) => other.record_type != PTR && same_name(&target, &other.name),
+            None => false,
+        }
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>) {
+        write_name(&self.name, buffer);
+        write_u16(buffer, self.record_type);
+        write_u16(buffer, if self.unique { CLASS_IN | TOP_BIT } else { CLASS_IN });
+        write_u16(buffer, (self.ttl >> 16) as u16);
+        write_u16(buffer, self.ttl as u16);
+        write_u16(buffer, self.data.len() as u16);
+        buffer.extend_from_slice(&self.data);
+    }
+}
+
+/// Returns true if the known answer `known` of a query holds `record`, with
+/// at least half its TTL left, see RFC 6762 section 7.1.
+fn own_matches_known(record: &OwnRecord, known: &MessageRecord) -> bool {
+    record.matches(known) && known.ttl >= record.ttl / 2
+}
+
+/// A question of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Question {
+    name: Vec<String>,
+    record_type: u16,
+    /// The class, with `TOP_BIT` set to ask for a unicast reply
+    class: u16,
+}
+
+impl Question {
+    fn write(&self, buffer: &mut Vec<u8>) {
+        write_name(&self.name, buffer);
+        write_u16(buffer, self.record_type);
+        write_u16(buffer, self.class);
+    }
+}
+
+/// A record of a message, with the names in its data uncompressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MessageRecord {
+    name: Vec<String>,
+    record_type: u16,
+    /// The class, with `TOP_BIT` set if it replaces cached records
+    class: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+/// An mDNS query or response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<Question>,
+    answers: Vec<MessageRecord>,
+    authorities: Vec<MessageRecord>,
+    additionals: Vec<MessageRecord>,
+}
+
+impl Message {
+    fn parse(packet: &[u8]) -> Option<Message> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+        let mut offset = HEADER_LEN;
+        let mut questions = Vec::new();
+        for _ in 0..read_u16(packet, 4) {
+            let (name, next) = match read_labels(packet, offset) {
+                Some(name) => name,
+                None => return None,
+            };
+            if next + 4 > packet.len() {
+                return None;
+            }
+            questions.push(Question {
+                name: name,
+                record_type: read_u16(packet, next),
+                class: read_u16(packet, next + 2),
+            });
+            offset = next + 4;
+        }
+        let mut sections = Vec::new();
+        for count_offset in &[6, 8, 10] {
+            let mut records = Vec::new();
+            for _ in 0..read_u16(packet, *count_offset) {
+                let (record, next) = match MessageRecord::parse(packet, offset) {
+                    Some(record) => record,
+                    None => return None,
+                };
+                records.push(record);
+                offset = next;
+            }
+            sections.push(records);
+        }
+        let additionals = sections.pop().unwrap();
+        let authorities = sections.pop().unwrap();
+        Some(Message {
+            id: read_u16(packet, 0),
+            flags: read_u16(packet, 2),
+            questions: questions,
+            answers: sections.pop().unwrap(),
+            authorities: authorities,
+            additionals: additionals,
+        })
+    }
+
+    /// Returns all records of the answer and additional sections.
+    fn records(&self) -> Vec<MessageRecord> {
+        self.answers.iter().chain(self.additionals.iter()).cloned().collect()
+    }
+}
+
+impl MessageRecord {
+    /// Reads the record at `offset` of `packet`, returning it with the
+    /// offset after it.
+    fn parse(packet: &[u8], offset: usize) -> Option<(MessageRecord, usize)> {
+        let (name, next) = match read_labels(packet, offset) {
+            Some(name) => name,
+            None => return None,
+        };
+        if next + 10 > packet.len() {
+            return None;
+        }
+        let record_type = read_u16(packet, next);
+        let len = read_u16(packet, next + 8) as usize;
+        let start = next + 10;
+        if start + len > packet.len() {
+            return None;
+        }
+        let name_at = |offset: usize| read_labels(packet, offset).map(|(name, _)| name_data(&name));
+        let data = match record_type {
+            PTR => name_at(start),
+            SRV if len > 6 => {
+                name_at(start + 6).map(|target| {
+                    let mut data = packet[start..start + 6].to_vec();
+                    data.extend(target);
+                    data
+                })
+            }
+            _ => Some(packet[start..start + len].to_vec()),
+        };
+        let data = match data {
+            Some(data) => data,
+            None => return None,
+        };
+        let record = MessageRecord {
+            name: name,
+            record_type: record_type,
+            class: read_u16(packet, next + 2),
+            ttl: read_u32(packet, next + 4),
+            data: data,
+        };
+        Some((record, start + len))
+    }
+}
+
+/// Returns a message header with the identifier `id`, `flags` and the
+/// counts of the four sections.
+fn header(id: u16, flags: u16, counts: &[usize; 4]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(512);
+    write_u16(&mut header, id);
+    write_u16(&mut header, flags);
+    for count in counts {
+        write_u16(&mut header, *count as u16);
+    }
+    header
+}
+
+/// Returns an authoritative response with the identifier `id`, repeating
+/// `questions`.
+fn response(id: u16,
+            questions: &[Question],
+            answers: &[OwnRecord],
+            additionals: &[OwnRecord])
+            -> Vec<u8> {
+    let counts = [questions.len(), answers.len(), 0, additionals.len()];
+    let mut message = header(id, RESPONSE | AUTHORITATIVE, &counts);
+    for question in questions {
+        question.write(&mut message);
+    }
+    for record in answers.iter().chain(additionals.iter()) {
+        record.write(&mut message);
+    }
+    message
+}
+
+/// Returns a one-shot query with the identifier `id` for the records of
+/// `record_type` of `name`.
+fn query(id: u16, name: &[String], record_type: u16) -> Vec<u8> {
+    let question = Question {
+        name: name.to_vec(),
+        record_type: record_type,
+        class: CLASS_IN,
+    };
+    let mut message = header(id, 0, &[1, 0, 0, 0]);
+    question.write(&mut message);
+    message
+}
+
+/// Returns true if `label` fits in a label of a name.
+fn is_label(label: &str) -> bool {
+    !label.is_empty() && label.len() <= MAX_LABEL_LEN
+}
+
+/// Returns the labels of `name`, split at its dots.
+fn split(name: &str) -> Vec<String> {
+    name.split('.').map(|label| label.to_owned()).collect()
+}
+
+/// Returns the name of `labels` in `.local`.
+fn local(labels: &[&str]) -> Vec<String> {
+    labels.iter().chain(["local"].iter()).map(|label| label.to_string()).collect()
+}
+
+/// Returns the name service instances of `service_type` are in.
+fn service_name(service_type: &str) -> Vec<String> {
+    let mut name = split(service_type);
+    name.push("local".to_owned());
+    name
+}
+
+fn write_name(name: &[String], buffer: &mut Vec<u8>) {
+    let labels = name.iter().map(|label| label.as_str()).collect::<Vec<_>>();
+    // Only names of valid labels are written
+    encode_labels(&labels, buffer).unwrap();
+}
+
+/// Returns `name` as record data.
+fn name_data(name: &[String]) -> Vec<u8> {
+    let mut data = Vec::new();
+    let labels = name.iter().map(|label| label.as_str()).collect::<Vec<_>>();
+    if encode_labels(&labels, &mut data).is_err() {
+        // A root or overlong name from a message, kept as a root name
+        data = vec![0];
+    }
+    data
+}
+
+/// Returns true if the names `a` and `b` are the same, ignoring case.
+fn same_name(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a.to_lowercase() == b.to_lowercase())
+}
+
+/// Returns true if `a` and `b` are the same data of records of
+/// `record_type`, ignoring the case of the names in it.
+fn same_data(record_type: u16, a: &[u8], b: &[u8]) -> bool {
+    let name = |data: &[u8]| read_labels(data, 0).map(|(name, _)| name);
+    match record_type {
+        PTR => {
+            match (name(a), name(b)) {
+                (Some(a), Some(b)) => same_name(&a, &b),
+                _ => a == b,
+            }
+        }
+        SRV if a.len() > 6 && b.len() > 6 => a[..6] == b[..6] && same_data(PTR, &a[6..], &b[6..]),
+        _ => a == b,
+    }
+}
+
+/// Stops the thread of a responder started with `spawn` when dropped,
+/// sending the goodbye of the responder.
+pub struct MdnsHandle {
+    responder: Arc<Mutex<Responder>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MdnsHandle {
+    /// See `Responder::host_name`.
+    pub fn host_name(&self) -> String {
+        self.responder.lock().unwrap().host_name()
+    }
+
+    /// See `Responder::services`.
+    pub fn services(&self) -> Vec<Service> {
+        self.responder.lock().unwrap().services()
+    }
+
+    /// See `Responder::is_claimed`.
+    pub fn is_claimed(&self) -> bool {
+        self.responder.lock().unwrap().is_claimed()
+    }
+
+    /// Stops responding and waits for the thread to quit.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for MdnsHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Starts a responder for the local address `local_ip`, from a UDP socket
+/// bound to it on `MDNS_PORT`.
+///
+/// Fails with `StackError::NoSourceAddress` if no interface has `local_ip`,
+/// and like `Responder::new` if `config` is invalid.
+pub fn spawn(stack: Arc<Mutex<NetworkStack>>,
+             local_ip: Ipv4Addr,
+             config: MdnsConfig)
+             -> StackResult<MdnsHandle> {
+    let delay = stack.lock().unwrap().random::<u64>() % PROBE_INTERVAL_MS;
+    let responder = Responder::new(local_ip, config, Instant::now(), Duration::from_millis(delay))?;
+    let responder = Arc::new(Mutex::new(responder));
+    let interface = interface_of(&mut stack.lock().unwrap(), local_ip)?;
+    let mut socket = UdpSocket::bind(stack.clone(), SocketAddrV4::new(local_ip, MDNS_PORT))?;
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+    let group_mac = ethernet::ipv4_multicast_mac(group());
+    stack.lock().unwrap().interface(&interface)?.join_multicast(group_mac)?;
+    let running = Arc::new(AtomicBool::new(true));
+
+    let thread_responder = responder.clone();
+    let thread_running = running.clone();
+    let thread = thread::spawn(move || {
+        let mut buffer = vec![0; 9000];
+        while thread_running.load(Ordering::SeqCst) {
+            let due = thread_responder.lock().unwrap().poll(Instant::now());
+            if let Some(message) = due {
+                if let Err(e) = send_to_group(&stack, local_ip, MDNS_PORT, &message) {
+                    warn!("mDNS: Unable to send to the group: {}", e);
+                }
+            }
+            let (len, src) = match socket.recv_from(&mut buffer) {
+                Ok((len, SocketAddr::V4(src))) => (len, src),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    warn!("mDNS: Dropping datagram: {}", e);
+                    continue;
+                }
+            };
+            let replies = thread_responder.lock()
+                .unwrap()
+                .handle(&buffer[..len], src, Instant::now());
+            for (reply, dst) in replies {
+                let sent = if dst.ip().is_multicast() {
+                    send_to_group(&stack, local_ip, MDNS_PORT, &reply)
+                } else {
+                    socket.send_to(&reply, dst).map(|_| ()).map_err(StackError::from)
+                };
+                if let Err(e) = sent {
+                    warn!("mDNS: Unable to answer {}: {}", src, e);
+                }
+            }
+        }
+        let goodbye = thread_responder.lock().unwrap().goodbye();
+        if let Some(goodbye) = goodbye {
+            if let Err(e) = send_to_group(&stack, local_ip, MDNS_PORT, &goodbye) {
+                warn!("mDNS: Unable to send the goodbye: {}", e);
+            }
+        }
+        let mut stack = stack.lock().unwrap();
+        if let Ok(stack_interface) = stack.interface(&interface) {
+            stack_interface.leave_multicast(group_mac).unwrap_or(());
+        }
+    });
+    Ok(MdnsHandle {
+        responder: responder,
+        running: running,
+        thread: Some(thread),
+    })
+}
+
+/// An instance of a service type found by `browse`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub instance: String,
+    pub service_type: String,
+    /// The host the service is on, with `.local`, and its port, if a
+    /// responder sent the SRV record
+    pub host: Option<(String, u16)>,
+    pub txt: Vec<String>,
+    /// The addresses of the host, if a responder sent them
+    pub addresses: Vec<Ipv4Addr>,
+}
+
+/// Lists the instances of `service_type`, like `_http._tcp`, that answer a
+/// query sent from `local_ip` within `wait`, sorted by name.
+///
+/// Fails with `StackError::InvalidArgument` if `service_type` is no name.
+pub fn browse(stack: Arc<Mutex<NetworkStack>>,
+              local_ip: Ipv4Addr,
+              service_type: &str,
+              wait: Duration)
+              -> StackResult<Vec<ServiceInstance>> {
+    let name = service_name(service_type);
+    let records = ask(&stack, local_ip, &name, PTR, wait, |_| false)?;
+    let mut instances: Vec<ServiceInstance> = Vec::new();
+    for record in &records {
+        if record.record_type != PTR || !same_name(&record.name, &name) {
+            continue;
+        }
+        let instance_name = match read_labels(&record.data, 0) {
+            Some((instance_name, _)) => instance_name,
+            None => continue,
+        };
+        let is_instance = instance_name.len() == name.len() + 1 &&
+                          same_name(&instance_name[1..], &name);
+        if !is_instance || instances.iter().any(|found| found.instance == instance_name[0]) {
+            continue;
+        }
+        let of_instance = |record_type: u16| {
+            records.iter()
+                .find(|record| {
+                    record.record_type == record_type && same_name(&record.name, &instance_name)
+                })
+        };
+        let host = of_instance(SRV).and_then(|srv| if srv.data.len() > 6 {
+            read_labels(&srv.data[6..], 0)
+                .map(|(target, _)| (target.join("."), read_u16(&srv.data, 4)))
+        } else {
+            None
+        });
+        let addresses = match host {
+            Some((ref target, _)) => {
+                records.iter()
+                    .filter(|record| {
+                        record.record_type == A && record.data.len() == 4 &&
+                        same_name(&record.name, &split(target))
+                    })
+                    .map(|record| address(&record.data))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        instances.push(ServiceInstance {
+            instance: instance_name[0].clone(),
+            service_type: service_type.to_owned(),
+            host: host,
+            txt: of_instance(TXT).map(|txt| txt_strings(&txt.data)).unwrap_or_default(),
+            addresses: addresses,
+        });
+    }
+    instances.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(instances)
+}
+
+/// Returns the addresses of `name`, a name in `.local`, from the first
+/// answer to a query sent from `local_ip` within `wait`.
+///
+/// Fails with `StackError::NotFound` if no responder answers for `name`,
+/// and with `StackError::InvalidArgument` if it is no name.
+pub fn lookup_host(stack: Arc<Mutex<NetworkStack>>,
+                   local_ip: Ipv4Addr,
+                   name: &str,
+                   wait: Duration)
+                   -> StackResult<Vec<Ipv4Addr>> {
+    let name = split(name.trim_right_matches('.'));
+    let is_address = |record: &MessageRecord| {
+        record.record_type == A && record.data.len() == 4 && same_name(&record.name, &name)
+    };
+    let records = ask(&stack,
+                      local_ip,
+                      &name,
+                      A,
+                      wait,
+                      |records| records.iter().any(&is_address))?;
+    let addresses = records.iter()
+        .filter(|record| is_address(record))
+        .map(|record| address(&record.data))
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        Err(StackError::NotFound(format!("No mDNS responder answered for {}", name.join("."))))
+    } else {
+        Ok(addresses)
+    }
+}
+
+/// Sends a one-shot query for the records of `record_type` of `name` to the
+/// group, from a socket bound to `local_ip`. Returns the records of the
+/// replies received within `wait`, or until `done` returns true for them.
+fn ask<F>(stack: &Arc<Mutex<NetworkStack>>,
+          local_ip: Ipv4Addr,
+          name: &[String],
+          record_type: u16,
+          wait: Duration,
+          done: F)
+          -> StackResult<Vec<MessageRecord>>
+    where F: Fn(&[MessageRecord]) -> bool
+{
+    {
+        let labels = name.iter().map(|label| label.as_str()).collect::<Vec<_>>();
+        encode_labels(&labels, &mut Vec::new())?;
+    }
+    let socket = UdpSocket::bind(stack.clone(), SocketAddrV4::new(local_ip, 0))?;
+    let port = socket.local_addr()?.port();
+    let query = query(stack.lock().unwrap().random(), name, record_type);
+    send_to_group(stack, local_ip, port, &query)?;
+    let deadline = Instant::now() + wait;
+    let mut records = Vec::new();
+    let mut buffer = vec![0; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline || done(&records) {
+            return Ok(records);
+        }
+        socket.set_opt(ReadTimeout, Some(deadline - now))?;
+        let len = match socket.recv_from(&mut buffer) {
+            Ok((len, _)) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(reply) = Message::parse(&buffer[..len]) {
+            if reply.flags & RESPONSE != 0 && reply.id == read_u16(&query, 0) {
+                records.extend(reply.records());
+            }
+        }
+    }
+}
+
+/// Returns the address of the A record data `data`.
+fn address(data: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(data[0], data[1], data[2], data[3])
+}
+
+/// Returns the strings of the TXT record data `data`.
+fn txt_strings(data: &[u8]) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let len = data[offset] as usize;
+        let end = cmp::min(offset + 1 + len, data.len());
+        if len > 0 {
+            strings.push(String::from_utf8_lossy(&data[offset + 1..end]).into_owned());
+        }
+        offset = end;
+    }
+    strings
+}
+
+/// Returns the interface with the address `local_ip`.
+fn interface_of(stack: &mut NetworkStack, local_ip: Ipv4Addr) -> StackResult<Interface> {
+    for interface in stack.interfaces() {
+        if stack.interface(&interface)?.has_ipv4(local_ip) {
+            return Ok(interface);
+        }
+    }
+    Err(StackError::NoSourceAddress(local_ip))
+}
+
+/// Sends `message` to the group from `local_ip`, port `src_port`, with the
+/// TTL of 255 receivers check.
+fn send_to_group(stack: &Mutex<NetworkStack>,
+                 local_ip: Ipv4Addr,
+                 src_port: u16,
+                 message: &[u8])
+                 -> StackResult<()> {
+    let ipv4_tx = stack.lock().unwrap().ipv4_tx_with_src(local_ip, group())?;
+    let mut udp_tx = UdpTx::new(ipv4_tx, src_port, MDNS_PORT);
+    udp_tx.set_ttl(255);
+    udp_tx.send(message)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn responder(now: Instant) -> Responder {
+        let mut config = MdnsConfig::new("rips");
+        let mut service = Service::new("Web.ui", "_http._tcp", 8080);
+        service.txt.push("path=/".to_owned());
+        config.services.push(service);
+        Responder::new(Ipv4Addr::new(10, 0, 0, 2), config, now, ms(0)).unwrap()
+    }
+
+    /// Returns a responder of `responder` that claimed its names, at the
+    /// time it did.
+    fn claimed(now: Instant) -> (Responder, Instant) {
+        let mut responder = responder(now);
+        let mut now = now;
+        while !responder.is_claimed() {
+            now += ms(PROBE_INTERVAL_MS);
+            responder.poll(now);
+        }
+        (responder, now)
+    }
+
+    fn peer(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 9), port)
+    }
+
+    /// Returns a response of another host with `records`.
+    fn other_response(records: &[OwnRecord]) -> Vec<u8> {
+        response(0, &[], records, &[])
+    }
+
+    fn host_a(name: &str, ip: u8) -> OwnRecord {
+        OwnRecord::new(split(name), A, true, 120, vec![10, 0, 0, ip])
+    }
+
+    #[test]
+    fn probing_and_announcing() {
+        let start = Instant::now();
+        let mut responder = responder(start);
+        let mut probes = Vec::new();
+        let mut now = start + ms(PROBE_INTERVAL_MS);
+        for _ in 0..PROBES {
+            probes.push(Message::parse(&responder.poll(now).unwrap()).unwrap());
+            assert_eq!(responder.poll(now), None);
+            assert!(!responder.is_claimed());
+            now += ms(PROBE_INTERVAL_MS);
+        }
+        let probe = &probes[0];
+        assert_eq!(probe.flags & RESPONSE, 0);
+        let questions =
+            probe.questions.iter().map(|question| question.name.join(".")).collect::<Vec<_>>();
+        assert_eq!(questions, vec!["rips.local", "Web.ui._http._tcp.local"]);
+        assert!(probe.questions.iter().all(|question| question.record_type == ANY));
+        let types = probe.authorities.iter().map(|record| record.record_type).collect::<Vec<_>>();
+        assert_eq!(types, vec![A, SRV, TXT]);
+
+        let announcement = Message::parse(&responder.poll(now).unwrap()).unwrap();
+        assert!(responder.is_claimed());
+        assert_eq!(announcement.flags, RESPONSE | AUTHORITATIVE);
+        assert_eq!(announcement.answers.len(), 6);
+        let reverse = &announcement.answers[1];
+        assert_eq!(reverse.name.join("."), "2.0.0.10.in-addr.arpa");
+        assert_eq!(read_labels(&reverse.data, 0).unwrap().0, split("rips.local"));
+        let srv = &announcement.answers[4];
+        assert_eq!((srv.class, read_u16(&srv.data, 4)), (CLASS_IN | TOP_BIT, 8080));
+        assert_eq!(txt_strings(&announcement.answers[5].data), vec!["path=/".to_owned()]);
+        assert_eq!(announcement.answers[2].class, CLASS_IN);
+
+        assert_eq!(responder.poll(now + ms(500)), None);
+        assert!(responder.poll(now + ms(ANNOUNCE_INTERVAL_MS)).is_some());
+        assert_eq!(responder.poll(now + ms(10 * ANNOUNCE_INTERVAL_MS)), None);
+
+        let goodbye = Message::parse(&responder.goodbye().unwrap()).unwrap();
+        assert!(goodbye.answers.iter().all(|record| record.ttl == 0));
+        assert_eq!(Responder::new(Ipv4Addr::new(10, 0, 0, 2), MdnsConfig::new("a"), start, ms(0))
+                       .unwrap()
+                       .goodbye(),
+                   None);
+    }
+
+    #[test]
+    fn conflicts() {
+        let now = Instant::now();
+        let mut responder = responder(now);
+        responder.poll(now + ms(PROBE_INTERVAL_MS));
+        let taken = other_response(&[host_a("RIPS.local", 9)]);
+        assert!(responder.handle(&taken, peer(MDNS_PORT), now).is_empty());
+        assert_eq!(responder.host_name(), "rips-2.local");
+        let mut srv = vec![0, 0, 0, 0, 0, 80];
+        srv.extend(name_data(&split("other.local")));
+        let mut instance = vec!["Web.ui".to_owned()];
+        instance.extend(split("_http._tcp.local"));
+        let taken = other_response(&[OwnRecord::new(instance, SRV, true, 120, srv)]);
+        responder.handle(&taken, peer(MDNS_PORT), now);
+        assert_eq!(responder.services()[0].instance, "Web.ui (2)");
+        assert_eq!(responder.host_name(), "rips-2.local");
+        // Its own messages, looped back, are no conflict
+        let own = other_response(&[host_a("rips-2.local", 2)]);
+        responder.handle(&own, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), MDNS_PORT), now);
+        assert_eq!(responder.host_name(), "rips-2.local");
+
+        // Simultaneous probes, lost to a higher address and won against a
+        // lower one
+        let probe = |ip: u8| {
+            let question = Question {
+                name: split("rips-2.local"),
+                record_type: ANY,
+                class: CLASS_IN | TOP_BIT,
+            };
+            let mut probe = header(0, 0, &[1, 0, 1, 0]);
+            question.write(&mut probe);
+            host_a("rips-2.local", ip).write(&mut probe);
+            probe
+        };
+        let (mut winner, mut loser) = (responder.clone(), responder);
+        assert!(winner.handle(&probe(1), peer(MDNS_PORT), now).is_empty());
+        assert!(loser.handle(&probe(3), peer(MDNS_PORT), now).is_empty());
+        let mut later = now;
+        for _ in 0..PROBES + 1 {
+            winner.poll(later);
+            loser.poll(later);
+            later += ms(PROBE_INTERVAL_MS);
+        }
+        assert!(winner.is_claimed());
+        assert!(!loser.is_claimed());
+        assert_eq!(loser.host_name(), "rips-2.local");
+
+        // Once claimed, other records of its name make it probe again
+        let (mut responder, now) = claimed(now);
+        let shared = other_response(&[OwnRecord::new(split("_http._tcp.local"),
+                                                     PTR,
+                                                     false,
+                                                     4500,
+                                                     name_data(&split("x._http._tcp.local")))]);
+        responder.handle(&shared, peer(MDNS_PORT), now);
+        let same = other_response(&[host_a("rips.local", 2)]);
+        responder.handle(&same, peer(MDNS_PORT), now);
+        assert!(responder.is_claimed());
+        responder.handle(&other_response(&[host_a("rips.local", 9)]), peer(MDNS_PORT), now);
+        assert!(!responder.is_claimed());
+        assert_eq!(responder.host_name(), "rips.local");
+    }
+
+    #[test]
+    fn answers() {
+        let (mut responder, now) = claimed(Instant::now());
+        let group = SocketAddrV4::new(group(), MDNS_PORT);
+
+        let ptr_query = query(0, &split("_http._tcp.local"), PTR);
+        let replies = responder.handle(&ptr_query, peer(MDNS_PORT), now);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1, group);
+        let reply = Message::parse(&replies[0].0).unwrap();
+        assert_eq!((reply.id, reply.questions.len()), (0, 0));
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(read_labels(&reply.answers[0].data, 0).unwrap().0[0], "Web.ui");
+        let types = reply.additionals.iter().map(|record| record.record_type).collect::<Vec<_>>();
+        assert_eq!(types, vec![A, SRV, TXT]);
+
+        // Asking for a unicast reply, and with the answer it knows
+        let mut unicast = ptr_query.clone();
+        let len = unicast.len();
+        unicast[len - 2] |= 0x80;
+        assert_eq!(responder.handle(&unicast, peer(MDNS_PORT), now)[0].1, peer(MDNS_PORT));
+        let known = reply.answers[0].clone();
+        let mut with_known = ptr_query.clone();
+        with_known[7] = 1;
+        OwnRecord::new(known.name.clone(), PTR, false, 4000, known.data.clone())
+            .write(&mut with_known);
+        assert!(responder.handle(&with_known, peer(MDNS_PORT), now).is_empty());
+        let mut stale = ptr_query.clone();
+        stale[7] = 1;
+        OwnRecord::new(known.name, PTR, false, 60, known.data).write(&mut stale);
+        assert_eq!(responder.handle(&stale, peer(MDNS_PORT), now).len(), 1);
+
+        // One-shot
+        let host_query = query(0, &split("RIPS.local"), A);
+        let replies = responder.handle(&host_query, peer(40000), now);
+        assert_eq!(replies[0].1, peer(40000));
+        let reply = Message::parse(&replies[0].0).unwrap();
+        assert_eq!(reply.id, read_u16(&host_query, 0));
+        assert_eq!(reply.questions[0].name, split("RIPS.local"));
+        assert_eq!((reply.answers.len(), reply.additionals.len()), (1, 0));
+        assert_eq!((reply.answers[0].ttl, reply.answers[0].class), (LEGACY_TTL, CLASS_IN));
+        assert_eq!(reply.answers[0].data, vec![10, 0, 0, 2]);
+
+        let types = query(0, &split("_services._dns-sd._udp.local"), PTR);
+        let reply = Message::parse(&responder.handle(&types, peer(MDNS_PORT), now)[0].0).unwrap();
+        assert_eq!(read_labels(&reply.answers[0].data, 0).unwrap().0,
+                   split("_http._tcp.local"));
+        assert!(reply.additionals.is_empty());
+        let reverse = query(0, &split("2.0.0.10.in-addr.arpa"), PTR);
+        assert_eq!(responder.handle(&reverse, peer(MDNS_PORT), now).len(), 1);
+        let mut instance = vec!["web.UI".to_owned()];
+        instance.extend(split("_http._tcp.local"));
+        let reply = responder.handle(&query(0, &instance, ANY), peer(MDNS_PORT), now);
+        let reply = Message::parse(&reply[0].0).unwrap();
+        assert_eq!((reply.answers.len(), reply.additionals.len()), (2, 1));
+
+        for unknown in &[query(0, &split("other.local"), A), query(0, &split("rips.local"), TXT)] {
+            assert!(responder.handle(unknown, peer(MDNS_PORT), now).is_empty());
+        }
+        assert!(responder.handle(&[0; 5], peer(MDNS_PORT), now).is_empty());
+    }
+
+    #[test]
+    fn invalid_configs() {
+        let now = Instant::now();
+        let ip = Ipv4Addr::new(10, 0, 0, 2);
+        for host_name in &["", "rips.local", &"a".repeat(64)] {
+            assert!(Responder::new(ip, MdnsConfig::new(host_name), now, ms(0)).is_err());
+        }
+        let services = vec![Service::new("", "_http._tcp", 80),
+                            Service::new("x", "http._tcp", 80),
+                            Service::new("x", "_http._sctp", 80),
+                            Service::new("x", "_http", 80),
+                            Service {
+                                txt: vec!["a".repeat(256)],
+                                ..Service::new("x", "_http._tcp", 80)
+                            }];
+        for service in services {
+            let mut config = MdnsConfig::new("rips");
+            config.services.push(service.clone());
+            match Responder::new(ip, config, now, ms(0)) {
+                Err(StackError::InvalidArgument(_)) => (),
+                _ => panic!("Accepted {:?}", service),
+            }
+        }
+    }
+}
@@ -0,0 +1,1106 @@
+//! DNS stub resolver, RFC 1035, asking servers through the stack's own UDP.
+//!
+//! A `Resolver` sends A, AAAA and PTR queries to the servers of its
+//! `ResolverConfig`, from UDP sockets of the stack bound to a local address,
+//! and returns what they answer as typed records. The DNS servers of a DHCP
+//! lease, see `dhcp::Lease`, are the usual ones to ask:
+//!
+//! ```rust,ignore
+//! let config = ResolverConfig::new(lease.dns_servers.clone());
+//! let resolver = Resolver::new(stack.clone(), lease.address.ip(), config)?;
+//! let ips = resolver.lookup_ipv4("example.com")?;
+//! let names = resolver.reverse(IpAddr::V4(ips[0]))?;
+//! ```
+//!
+//! Every query is sent from a random port with a random identifier, both
+//! from the generator of the stack, see `NetworkStack::set_rng`, and only
+//! a reply from the server asked, to that query, is taken. A server that
+//! does not answer within `ResolverConfig::timeout`, or answers with a
+//! failure, is skipped for the next one, for `ResolverConfig::attempts`
+//! rounds over all servers. A name a server says does not exist is not
+//! asked for again. Aliases are followed within the answer of the server.
+//!
+//! Answers are cached for as long as their TTLs say, up to
+//! `ResolverConfig::max_ttl`, and so are answers that a name or its records
+//! do not exist, for the time the SOA record of the reply gives or
+//! `ResolverConfig::negative_ttl`, see RFC 2308. Failures are not cached.
+//! Once `ResolverConfig::cache_size` answers are cached, the ones expiring
+//! first make room. `Resolver::flush` and `Resolver::flush_name` forget
+//! answers before they expire, after a network change for example.
+//!
+//! Rips has no TCP yet, so replies truncated to fit a datagram are not
+//! asked for again over TCP, the records they hold are used as they are.
+//!
+//! Given to the stack with `NetworkStack::set_resolver`, a resolver also
+//! looks up the host names given to the sockets of the stack. Their
+//! addresses are `ToStackAddr`, the counterpart of `std::net::ToSocketAddrs`
+//! that asks the resolver of the stack instead of the one of the OS:
+//!
+//! ```rust,ignore
+//! stack.lock().unwrap().set_resolver(Some(resolver));
+//! let mut socket = UdpSocket::bind(stack.clone(), "10.0.0.2:0")?;
+//! socket.send_to(b"hello", ("echo.example.com", 7))?;
+//! socket.send_to(b"hello", "echo.example.com:7")?;
+//! ```
+//!
+//! The resolver the stack keeps does not keep the stack alive, and the
+//! stack is not locked while names are looked up.
+//!
+//! Names in `.local` are not asked of servers, see `mdns` for finding hosts
+//! and services on the link.
+
+pub mod mdns;
+
+use {NetworkStack, StackError, StackResult};
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::UdpSocket;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The UDP port of DNS servers.
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+/// Longest a name is in its wire format
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// How many compression pointers a name may be made of, against loops
+const MAX_POINTERS: usize = 16;
+const CLASS_IN: u16 = 1;
+const SOA: u16 = 6;
+
+const RESPONSE: u16 = 0x8000;
+const TRUNCATED: u16 = 0x0200;
+const RECURSION_DESIRED: u16 = 0x0100;
+
+const NO_ERROR: u8 = 0;
+const NAME_ERROR: u8 = 3;
+const REFUSED: u8 = 5;
+
+/// The type of a resource record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+    Ptr,
+    /// Any other type, by its code
+    Other(u16),
+}
+
+impl RecordType {
+    pub fn from_code(code: u16) -> RecordType {
+        match code {
+            1 => RecordType::A,
+            5 => RecordType::Cname,
+            12 => RecordType::Ptr,
+            28 => RecordType::Aaaa,
+            code => RecordType::Other(code),
+        }
+    }
+
+    pub fn code(&self) -> u16 {
+        match *self {
+            RecordType::A => 1,
+            RecordType::Cname => 5,
+            RecordType::Ptr => 12,
+            RecordType::Aaaa => 28,
+            RecordType::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecordType::A => fmt.write_str("A"),
+            RecordType::Aaaa => fmt.write_str("AAAA"),
+            RecordType::Cname => fmt.write_str("CNAME"),
+            RecordType::Ptr => fmt.write_str("PTR"),
+            RecordType::Other(code) => write!(fmt, "TYPE{}", code),
+        }
+    }
+}
+
+/// The data of a resource record, decoded for the types the resolver asks
+/// for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Ptr(String),
+    /// The data of a record of another type, or of one too short for its
+    /// type, as it came
+    Other(RecordType, Vec<u8>),
+}
+
+impl RecordData {
+    pub fn record_type(&self) -> RecordType {
+        match *self {
+            RecordData::A(_) => RecordType::A,
+            RecordData::Aaaa(_) => RecordType::Aaaa,
+            RecordData::Cname(_) => RecordType::Cname,
+            RecordData::Ptr(_) => RecordType::Ptr,
+            RecordData::Other(record_type, _) => record_type,
+        }
+    }
+}
+
+/// A resource record of an answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The name the record is for, without the trailing dot
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+/// Which servers a `Resolver` asks, and how patiently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverConfig {
+    /// Asked in this order, on `DNS_PORT`
+    pub servers: Vec<Ipv4Addr>,
+    /// How long to wait for the answer of one server
+    pub timeout: Duration,
+    /// How many times every server is asked before giving up
+    pub attempts: usize,
+    /// Starts every lookup with the server after the one the last lookup
+    /// started with, spreading the queries over the servers
+    pub rotate: bool,
+    /// How many answers are cached at most. Zero disables the cache
+    pub cache_size: usize,
+    /// Longest an answer is cached, whatever its TTL
+    pub max_ttl: Duration,
+    /// How long an answer that a name or its records do not exist is
+    /// cached, if the server does not say
+    pub negative_ttl: Duration,
+}
+
+impl ResolverConfig {
+    /// Asks `servers` twice each, waiting two seconds for every answer, and
+    /// caches up to 256 answers for a day at most.
+    pub fn new(servers: Vec<Ipv4Addr>) -> ResolverConfig {
+        ResolverConfig {
+            servers: servers,
+            timeout: Duration::from_secs(2),
+            attempts: 2,
+            rotate: false,
+            cache_size: 256,
+            max_ttl: Duration::from_secs(24 * 3600),
+            negative_ttl: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A DNS stub resolver, asking the servers of its config from a local
+/// address of a stack. See the module documentation. Clones share the cache
+/// and the rotation over the servers.
+#[derive(Clone)]
+pub struct Resolver {
+    stack: StackRef,
+    local_ip: Ipv4Addr,
+    config: ResolverConfig,
+    cache: Arc<Mutex<Cache>>,
+    /// The server the next lookup starts with, if rotating
+    next: Arc<AtomicUsize>,
+}
+
+impl Resolver {
+    /// Creates a resolver sending from `local_ip`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `config` has no servers
+    /// or no attempts.
+    pub fn new(stack: Arc<Mutex<NetworkStack>>,
+               local_ip: Ipv4Addr,
+               config: ResolverConfig)
+               -> StackResult<Resolver> {
+        if config.servers.is_empty() || config.attempts == 0 {
+            let msg = "A resolver needs servers and attempts".to_owned();
+            return Err(StackError::InvalidArgument(msg));
+        }
+        Ok(Resolver {
+            stack: StackRef::Shared(stack),
+            local_ip: local_ip,
+            cache: Arc::new(Mutex::new(Cache::new(config.cache_size))),
+            config: config,
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    pub fn config(&self) -> &ResolverConfig {
+        &self.config
+    }
+
+    /// Returns a resolver sharing the cache of this one that does not keep
+    /// the stack alive, and fails with `StackError::InvalidState` once the
+    /// stack is gone. `NetworkStack::set_resolver` keeps such a resolver.
+    pub fn weak(&self) -> Resolver {
+        let stack = match self.stack {
+            StackRef::Shared(ref stack) => Arc::downgrade(stack),
+            StackRef::Weak(ref stack) => stack.clone(),
+        };
+        Resolver { stack: StackRef::Weak(stack), ..self.clone() }
+    }
+
+    /// Forgets all cached answers.
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().entries.clear();
+    }
+
+    /// Forgets the cached answers for `name`, of all types.
+    pub fn flush_name(&self, name: &str) {
+        let name = name.trim_right_matches('.').to_lowercase();
+        self.cache.lock().unwrap().remove(|&(ref cached, _), _| *cached == name);
+    }
+
+    /// Returns the address `addr` is, the first IPv4 address of its host if
+    /// it names one.
+    pub fn resolve<A: ToStackAddr>(&self, addr: A) -> StackResult<SocketAddrV4> {
+        match addr.to_stack_addr()? {
+            StackAddr::Addr(addr) => Ok(addr),
+            StackAddr::Host(host, port) => {
+                let ips = self.lookup_ipv4(&host)?;
+                Ok(SocketAddrV4::new(ips[0], port))
+            }
+        }
+    }
+
+    /// Returns the IPv4 addresses of `name`.
+    pub fn lookup_ipv4(&self, name: &str) -> StackResult<Vec<Ipv4Addr>> {
+        let records = self.lookup(name, RecordType::A)?;
+        Ok(records.into_iter()
+            .filter_map(|record| match record.data {
+                RecordData::A(ip) => Some(ip),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Returns the IPv6 addresses of `name`.
+    pub fn lookup_ipv6(&self, name: &str) -> StackResult<Vec<Ipv6Addr>> {
+        let records = self.lookup(name, RecordType::Aaaa)?;
+        Ok(records.into_iter()
+            .filter_map(|record| match record.data {
+                RecordData::Aaaa(ip) => Some(ip),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Returns the names `ip` has, from the PTR records of its name under
+    /// `in-addr.arpa` or `ip6.arpa`.
+    pub fn reverse(&self, ip: IpAddr) -> StackResult<Vec<String>> {
+        let records = self.lookup(&reverse_name(ip), RecordType::Ptr)?;
+        Ok(records.into_iter()
+            .filter_map(|record| match record.data {
+                RecordData::Ptr(name) => Some(name),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Returns the records of type `record_type` that `name`, or the name it
+    /// is an alias of, has. Never empty. Answered from the cache while the
+    /// TTL of the answer lasts, with the TTLs of the records counted down.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `name` is not a valid
+    /// name and with `StackError::NotFound` if it does not exist or has no
+    /// such records. If no server answers, fails with an IO error of kind
+    /// `TimedOut`, and with the error of the last server otherwise.
+    pub fn lookup(&self, name: &str, record_type: RecordType) -> StackResult<Vec<Record>> {
+        let name = name.trim_right_matches('.');
+        encode_name(name, &mut Vec::new())?;
+        let cached = self.cache.lock().unwrap().get(name, record_type, Instant::now());
+        let answer = match cached {
+            Some(answer) => answer,
+            None => {
+                let (answer, ttl) = self.ask(name, record_type)?;
+                let mut cache = self.cache.lock().unwrap();
+                cache.insert(name, record_type, answer.clone(), ttl, Instant::now());
+                answer
+            }
+        };
+        match answer {
+            Answer::Records(records) => Ok(records),
+            Answer::NoSuchName => Err(StackError::NotFound(format!("No such name: {}", name))),
+            Answer::NoRecords => {
+                let msg = format!("No {} records for {}", record_type, name);
+                Err(StackError::NotFound(msg))
+            }
+        }
+    }
+
+    /// Asks the servers in turn until one answers, returning the answer and
+    /// how long it may be cached.
+    fn ask(&self, name: &str, record_type: RecordType) -> StackResult<(Answer, Duration)> {
+        let servers = &self.config.servers;
+        let first = if self.config.rotate {
+            self.next.fetch_add(1, Ordering::SeqCst) % servers.len()
+        } else {
+            0
+        };
+        let mut last_error = None;
+        for _ in 0..self.config.attempts {
+            for i in 0..servers.len() {
+                let server = servers[(first + i) % servers.len()];
+                let reply = match self.exchange(server, name, record_type) {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        debug!("DNS: No answer from {} for {}: {}", server, name, e);
+                        last_error = Some(e);
+                        continue;
+                    }
+                };
+                if reply.truncated {
+                    debug!("DNS: Truncated answer from {} for {}", server, name);
+                }
+                let negative_ttl = reply.negative_ttl
+                    .map_or(self.config.negative_ttl, |ttl| Duration::from_secs(ttl as u64));
+                let records = answers(reply.records, name, record_type);
+                let (answer, ttl) = if reply.rcode == NAME_ERROR {
+                    (Answer::NoSuchName, negative_ttl)
+                } else if records.is_empty() {
+                    (Answer::NoRecords, negative_ttl)
+                } else {
+                    let ttl = records.iter().map(|record| record.ttl).min().unwrap();
+                    (Answer::Records(records), Duration::from_secs(ttl as u64))
+                };
+                return Ok((answer, cmp::min(ttl, self.config.max_ttl)));
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    /// Asks `server` for the records of type `record_type` of `name`, and
+    /// waits for its answer.
+    fn exchange(&self,
+                server: Ipv4Addr,
+                name: &str,
+                record_type: RecordType)
+                -> StackResult<Reply> {
+        let dst = SocketAddrV4::new(server, DNS_PORT);
+        let stack = match self.stack {
+            StackRef::Shared(ref stack) => stack.clone(),
+            StackRef::Weak(ref stack) => {
+                match stack.upgrade() {
+                    Some(stack) => stack,
+                    None => return Err(StackError::InvalidState("The stack is gone".to_owned())),
+                }
+            }
+        };
+        let id = stack.lock().unwrap().random();
+        let query = query(id, name, record_type)?;
+        let mut socket = UdpSocket::bind(stack, SocketAddrV4::new(self.local_ip, 0))?;
+        socket.send_to(&query, dst)?;
+        let deadline = Instant::now() + self.config.timeout;
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                let msg = format!("No answer from {}", server);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, msg).into());
+            }
+            socket.set_opt(ReadTimeout, Some(deadline - now))?;
+            let len = match socket.recv_from(&mut buffer) {
+                Ok((len, src)) if src == SocketAddr::V4(dst) => len,
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let reply = match Reply::parse(&buffer[..len]) {
+                Some(reply) => reply,
+                None => continue,
+            };
+            if reply.id != id || !reply.answers(name, record_type) {
+                continue;
+            }
+            return match reply.rcode {
+                NO_ERROR | NAME_ERROR => Ok(reply),
+                REFUSED => {
+                    let msg = format!("{} refused to answer", server);
+                    Err(io::Error::new(io::ErrorKind::ConnectionRefused, msg).into())
+                }
+                rcode => {
+                    let msg = format!("{} failed with rcode {}", server, rcode);
+                    Err(io::Error::new(io::ErrorKind::Other, msg).into())
+                }
+            };
+        }
+    }
+}
+
+/// The stack a resolver sends through.
+#[derive(Clone)]
+enum StackRef {
+    Shared(Arc<Mutex<NetworkStack>>),
+    /// Not keeping the stack alive, for resolvers the stack keeps itself
+    Weak(Weak<Mutex<NetworkStack>>),
+}
+
+/// An address given to the stack: a socket address, or a host name and a
+/// port.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StackAddr {
+    Addr(SocketAddrV4),
+    Host(String, u16),
+}
+
+impl fmt::Display for StackAddr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackAddr::Addr(addr) => write!(fmt, "{}", addr),
+            StackAddr::Host(ref host, port) => write!(fmt, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Something that is an address for the stack, without asking the OS. Host
+/// names are looked up with the resolver of the stack, see
+/// `NetworkStack::set_resolver`.
+///
+/// Implemented for the socket addresses of `std::net`, for tuples of an IP
+/// address or a host name and a port, and for strings of the form
+/// `host:port`, where the host is an IPv4 address or a name.
+pub trait ToStackAddr {
+    /// Fails with `StackError::InvalidArgument` if this is not an IPv4
+    /// address or a host name with a port.
+    fn to_stack_addr(&self) -> StackResult<StackAddr>;
+}
+
+impl ToStackAddr for StackAddr {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        Ok(self.clone())
+    }
+}
+
+impl ToStackAddr for SocketAddrV4 {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        Ok(StackAddr::Addr(*self))
+    }
+}
+
+impl ToStackAddr for SocketAddr {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        match *self {
+            SocketAddr::V4(addr) => Ok(StackAddr::Addr(addr)),
+            SocketAddr::V6(_) => {
+                Err(StackError::InvalidArgument("Rips does not support IPv6 yet".to_owned()))
+            }
+        }
+    }
+}
+
+impl ToStackAddr for (Ipv4Addr, u16) {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        Ok(StackAddr::Addr(SocketAddrV4::new(self.0, self.1)))
+    }
+}
+
+impl ToStackAddr for (IpAddr, u16) {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        SocketAddr::new(self.0, self.1).to_stack_addr()
+    }
+}
+
+impl<'a> ToStackAddr for (&'a str, u16) {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        match self.0.parse::<IpAddr>() {
+            Ok(ip) => (ip, self.1).to_stack_addr(),
+            Err(_) if self.0.contains(':') || self.0.starts_with('[') => {
+                Err(StackError::InvalidArgument("Rips does not support IPv6 yet".to_owned()))
+            }
+            Err(_) => {
+                let host = self.0.trim_right_matches('.');
+                encode_name(host, &mut Vec::new())?;
+                Ok(StackAddr::Host(host.to_owned(), self.1))
+            }
+        }
+    }
+}
+
+impl ToStackAddr for (String, u16) {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        (&self.0[..], self.1).to_stack_addr()
+    }
+}
+
+impl ToStackAddr for str {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        let invalid = || StackError::InvalidArgument(format!("Not host:port: {}", self));
+        let colon = self.rfind(':').ok_or_else(&invalid)?;
+        let port = self[colon + 1..].parse().map_err(|_| invalid())?;
+        (&self[..colon], port).to_stack_addr()
+    }
+}
+
+impl ToStackAddr for String {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        self[..].to_stack_addr()
+    }
+}
+
+impl<'a, T: ToStackAddr + ?Sized> ToStackAddr for &'a T {
+    fn to_stack_addr(&self) -> StackResult<StackAddr> {
+        (**self).to_stack_addr()
+    }
+}
+
+/// Returns the address `addr` is, looking host names up with the resolver of
+/// `stack`. The stack is only locked to get the resolver.
+///
+/// Fails with `StackError::InvalidState` for host names if the stack has no
+/// resolver, and like `Resolver::lookup` if they can not be looked up.
+pub fn resolve<A: ToStackAddr>(stack: &Mutex<NetworkStack>, addr: A) -> StackResult<SocketAddrV4> {
+    match addr.to_stack_addr()? {
+        StackAddr::Addr(addr) => Ok(addr),
+        host => {
+            let resolver = stack.lock().unwrap().resolver();
+            match resolver {
+                Some(resolver) => resolver.resolve(host),
+                None => {
+                    let msg = format!("No resolver to look up {}", host);
+                    Err(StackError::InvalidState(msg))
+                }
+            }
+        }
+    }
+}
+
+/// What a server answered to a question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Answer {
+    Records(Vec<Record>),
+    /// The name does not exist
+    NoSuchName,
+    /// The name exists, without records of the type asked for
+    NoRecords,
+}
+
+/// Answers of servers, by the name, in lower case, and type asked for.
+struct Cache {
+    capacity: usize,
+    entries: HashMap<(String, RecordType), CacheEntry>,
+}
+
+struct CacheEntry {
+    answer: Answer,
+    cached: Instant,
+    expires: Instant,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity: capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the answer cached for `name` and `record_type`, if it has
+    /// not expired by `now`, with the TTLs of its records counted down.
+    fn get(&mut self, name: &str, record_type: RecordType, now: Instant) -> Option<Answer> {
+        let key = (name.to_lowercase(), record_type);
+        let expired = match self.entries.get(&key) {
+            Some(entry) if entry.expires > now => {
+                let age = (now - entry.cached).as_secs();
+                return Some(match entry.answer {
+                    Answer::Records(ref records) => {
+                        let records = records.iter()
+                            .map(|record| {
+                                let ttl = (record.ttl as u64).saturating_sub(age) as u32;
+                                Record { ttl: ttl, ..record.clone() }
+                            })
+                            .collect();
+                        Answer::Records(records)
+                    }
+                    ref answer => answer.clone(),
+                });
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            self.entries.remove(&key);
+        }
+        None
+    }
+
+    /// Caches `answer` for `ttl` from `now`. Makes room by dropping the
+    /// expired answers, or else the one expiring first.
+    fn insert(&mut self,
+              name: &str,
+              record_type: RecordType,
+              answer: Answer,
+              ttl: Duration,
+              now: Instant) {
+        if self.capacity == 0 || ttl == Duration::from_secs(0) {
+            return;
+        }
+        let key = (name.to_lowercase(), record_type);
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.remove(|_, entry| entry.expires <= now);
+            if self.entries.len() >= self.capacity {
+                let first = self.entries
+                    .iter()
+                    .min_by_key(|&(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone())
+                    .unwrap();
+                self.entries.remove(&first);
+            }
+        }
+        let entry = CacheEntry {
+            answer: answer,
+            cached: now,
+            expires: now + ttl,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    /// Removes the entries `matches` returns true for.
+    fn remove<F>(&mut self, matches: F)
+        where F: Fn(&(String, RecordType), &CacheEntry) -> bool
+    {
+        let keys = self.entries
+            .iter()
+            .filter(|&(key, entry)| matches(key, entry))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// Returns the name of the PTR records of `ip`.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(ip) => {
+            let mut name = String::new();
+            for byte in ip.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", byte & 0xf, byte >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Returns the records of type `record_type` of `name` in `records`, those
+/// of the names it is an alias of included, with the aliases leading to
+/// them.
+fn answers(records: Vec<Record>, name: &str, record_type: RecordType) -> Vec<Record> {
+    let mut names = vec![name.to_lowercase()];
+    // Every record adds a name at most once, in whatever order they come
+    for _ in 0..records.len() {
+        for record in &records {
+            if let RecordData::Cname(ref target) = record.data {
+                let target = target.to_lowercase();
+                if names.contains(&record.name.to_lowercase()) && !names.contains(&target) {
+                    names.push(target);
+                }
+            }
+        }
+    }
+    records.into_iter()
+        .filter(|record| {
+            let record_type_matches = record.data.record_type() == record_type ||
+                                      record.data.record_type() == RecordType::Cname;
+            record_type_matches && names.contains(&record.name.to_lowercase())
+        })
+        .collect()
+}
+
+/// Returns a query with the identifier `id` for the records of type
+/// `record_type` of `name`, asking for recursion.
+fn query(id: u16, name: &str, record_type: RecordType) -> StackResult<Vec<u8>> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    write_u16(&mut query, id);
+    write_u16(&mut query, RECURSION_DESIRED);
+    for count in &[1, 0, 0, 0] {
+        write_u16(&mut query, *count);
+    }
+    encode_name(name, &mut query)?;
+    write_u16(&mut query, record_type.code());
+    write_u16(&mut query, CLASS_IN);
+    Ok(query)
+}
+
+/// Appends `name`, without trailing dot, to `buffer` as labels.
+fn encode_name(name: &str, buffer: &mut Vec<u8>) -> StackResult<()> {
+    encode_labels(&name.split('.').collect::<Vec<_>>(), buffer)
+}
+
+/// Appends the name made of `labels` to `buffer`. Labels may hold dots, as
+/// the instance names of DNS-SD do.
+fn encode_labels(labels: &[&str], buffer: &mut Vec<u8>) -> StackResult<()> {
+    let len = labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+    if labels.is_empty() || len > MAX_NAME_LEN ||
+       labels.iter().any(|label| label.is_empty() || label.len() > MAX_LABEL_LEN) {
+        let msg = format!("Invalid name: {}", labels.join("."));
+        return Err(StackError::InvalidArgument(msg));
+    }
+    for label in labels {
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label.as_bytes());
+    }
+    buffer.push(0);
+    Ok(())
+}
+
+/// A reply of a server, with the records of its answer section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reply {
+    id: u16,
+    rcode: u8,
+    truncated: bool,
+    /// The name, type and class asked for
+    question: Option<(String, u16, u16)>,
+    records: Vec<Record>,
+    /// How long a negative answer holds, from the SOA record of the
+    /// authority section, see RFC 2308
+    negative_ttl: Option<u32>,
+}
+
+impl Reply {
+    fn parse(packet: &[u8]) -> Option<Reply> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+        let flags = read_u16(packet, 2);
+        if flags & RESPONSE == 0 {
+            return None;
+        }
+        let (questions, answers) = (read_u16(packet, 4), read_u16(packet, 6) as usize);
+        let authorities = read_u16(packet, 8) as usize;
+        let mut offset = HEADER_LEN;
+        let mut question = None;
+        for _ in 0..questions {
+            let (name, next) = match read_name(packet, offset) {
+                Some(name) => name,
+                None => return None,
+            };
+            if next + 4 > packet.len() {
+                return None;
+            }
+            question = Some((name, read_u16(packet, next), read_u16(packet, next + 2)));
+            offset = next + 4;
+        }
+        let mut records = Vec::new();
+        let mut negative_ttl = None;
+        for i in 0..answers + authorities {
+            let (name, next) = match read_name(packet, offset) {
+                Some(name) => name,
+                None => return None,
+            };
+            if next + 10 > packet.len() {
+                return None;
+            }
+            let record_type = RecordType::from_code(read_u16(packet, next));
+            let class = read_u16(packet, next + 2);
+            let ttl = read_u32(packet, next + 4);
+            let len = read_u16(packet, next + 8) as usize;
+            let start = next + 10;
+            if start + len > packet.len() {
+                return None;
+            }
+            offset = start + len;
+            if class != CLASS_IN {
+                continue;
+            }
+            if i >= answers {
+                // The minimum field ends the SOA record
+                if record_type == RecordType::Other(SOA) && len >= 22 {
+                    negative_ttl = Some(cmp::min(ttl, read_u32(packet, offset - 4)));
+                }
+                continue;
+            }
+            let rdata = &packet[start..offset];
+            let data = match record_type {
+                RecordType::A if len == 4 => {
+                    RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+                }
+                RecordType::Aaaa if len == 16 => {
+                    let mut segments = [0; 8];
+                    for (i, segment) in segments.iter_mut().enumerate() {
+                        *segment = read_u16(rdata, i * 2);
+                    }
+                    RecordData::Aaaa(Ipv6Addr::new(segments[0],
+                                                   segments[1],
+                                                   segments[2],
+                                                   segments[3],
+                                                   segments[4],
+                                                   segments[5],
+                                                   segments[6],
+                                                   segments[7]))
+                }
+                RecordType::Cname | RecordType::Ptr => {
+                    let target = match read_name(packet, start) {
+                        Some((target, _)) => target,
+                        None => return None,
+                    };
+                    if record_type == RecordType::Cname {
+                        RecordData::Cname(target)
+                    } else {
+                        RecordData::Ptr(target)
+                    }
+                }
+                record_type => RecordData::Other(record_type, rdata.to_vec()),
+            };
+            records.push(Record {
+                name: name,
+                ttl: ttl,
+                data: data,
+            });
+        }
+        Some(Reply {
+            id: read_u16(packet, 0),
+            rcode: (flags & 0xf) as u8,
+            truncated: flags & TRUNCATED != 0,
+            question: question,
+            records: records,
+            negative_ttl: negative_ttl,
+        })
+    }
+
+    /// Returns true if this is a reply to a question for the records of
+    /// type `record_type` of `name`.
+    fn answers(&self, name: &str, record_type: RecordType) -> bool {
+        match self.question {
+            Some((ref asked, code, class)) => {
+                asked.to_lowercase() == name.to_lowercase() && code == record_type.code() &&
+                class == CLASS_IN
+            }
+            None => false,
+        }
+    }
+}
+
+/// Reads the name at `offset` of `packet`, following compression pointers.
+/// Returns it without trailing dot, with the offset after it.
+fn read_name(packet: &[u8], offset: usize) -> Option<(String, usize)> {
+    read_labels(packet, offset).map(|(labels, next)| (labels.join("."), next))
+}
+
+/// Same as `read_name`, but returns the labels of the name.
+fn read_labels(packet: &[u8], mut offset: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    let mut len = 1;
+    loop {
+        if offset >= packet.len() {
+            return None;
+        }
+        let label_len = packet[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            if offset + 1 >= packet.len() {
+                return None;
+            }
+            let target = (label_len & 0x3f) << 8 | packet[offset + 1] as usize;
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            end = end.or(Some(offset + 2));
+            offset = target;
+        } else if label_len & 0xc0 != 0 {
+            return None;
+        } else if label_len == 0 {
+            return Some((labels, end.unwrap_or(offset + 1)));
+        } else {
+            len += label_len + 1;
+            if len > MAX_NAME_LEN || offset + 1 + label_len > packet.len() {
+                return None;
+            }
+            let label = &packet[offset + 1..offset + 1 + label_len];
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + label_len;
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    (data[offset] as u16) << 8 | data[offset + 1] as u16
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    (read_u16(data, offset) as u32) << 16 | read_u16(data, offset + 2) as u32
+}
+
+fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        encode_name(name, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn queries() {
+        let bytes = query(0x1234, "www.example.com", RecordType::Aaaa).unwrap();
+        assert_eq!(&bytes[..12], &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[12..29], &b"\x03www\x07example\x03com\x00"[..]);
+        assert_eq!(&bytes[29..], &[0, 28, 0, 1]);
+
+        let long_label = "a".repeat(64);
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        for invalid in &["", "a..b", ".a", &long_label, &long_name] {
+            match query(1, invalid, RecordType::A) {
+                Err(StackError::InvalidArgument(_)) => (),
+                _ => panic!("Accepted {:?}", invalid),
+            }
+        }
+        assert!(query(1, &long_name[2..], RecordType::A).is_ok());
+    }
+
+    #[test]
+    fn replies() {
+        let mut packet = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        packet.extend(name("WWW.example.com"));
+        packet.extend_from_slice(&[0, 1, 0, 1]);
+        // www.example.com CNAME web.example.com, through pointers
+        packet.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6]);
+        packet.extend_from_slice(&[3, b'w', b'e', b'b', 0xc0, 16]);
+        packet.extend_from_slice(&[0xc0, 45, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 10, 0, 0, 1]);
+        packet.extend_from_slice(&[0xc0, 45, 0, 1, 0, 1, 0, 0, 1, 0, 0, 2, 10, 0]);
+        let reply = Reply::parse(&packet).unwrap();
+        assert_eq!((reply.id, reply.rcode, reply.truncated), (0x1234, NO_ERROR, false));
+        assert!(reply.answers("www.example.com", RecordType::A));
+        assert!(!reply.answers("www.example.com", RecordType::Aaaa));
+        assert_eq!(reply.records[0],
+                   Record {
+                       name: "WWW.example.com".to_owned(),
+                       ttl: 60,
+                       data: RecordData::Cname("web.example.com".to_owned()),
+                   });
+        assert_eq!((&reply.records[1].name[..], reply.records[1].ttl), ("web.example.com", 256));
+        assert_eq!(reply.records[1].data, RecordData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(reply.records[2].data, RecordData::Other(RecordType::A, vec![10, 0]));
+
+        let records = answers(reply.records.clone(), "www.example.com", RecordType::A);
+        assert_eq!(records.len(), 3);
+        assert!(answers(reply.records, "example.com", RecordType::A).is_empty());
+
+        assert!(Reply::parse(&packet[..packet.len() - 1]).is_none());
+        // Not a response
+        packet[2] = 0x01;
+        assert!(Reply::parse(&packet).is_none());
+        // Pointing at itself
+        let mut looping = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0];
+        looping.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert!(Reply::parse(&looping).is_none());
+        // Pointing out of the packet
+        looping[13] = 200;
+        assert!(Reply::parse(&looping).is_none());
+    }
+
+    #[test]
+    fn negative_ttls() {
+        let mut packet = vec![0, 1, 0x81, 0x83, 0, 1, 0, 0, 0, 1, 0, 0];
+        packet.extend(name("missing.example.com"));
+        packet.extend_from_slice(&[0, 1, 0, 1]);
+        // example.com SOA, with a TTL of 3600 and a minimum of 300
+        packet.extend_from_slice(&[0xc0, 20, 0, 6, 0, 1, 0, 0, 0x0e, 0x10, 0, 28]);
+        packet.extend_from_slice(&[1, b'a', 0xc0, 20, 1, b'b', 0xc0, 20]);
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 1, 44]);
+        let reply = Reply::parse(&packet).unwrap();
+        assert_eq!((reply.rcode, reply.negative_ttl), (NAME_ERROR, Some(300)));
+        assert!(reply.records.is_empty());
+        // The TTL of the record if lower
+        packet[45] = 0;
+        packet[46] = 30;
+        assert_eq!(Reply::parse(&packet).unwrap().negative_ttl, Some(30));
+    }
+
+    #[test]
+    fn cache() {
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let record = |name: &str, ttl| {
+            Record {
+                name: name.to_owned(),
+                ttl: ttl,
+                data: RecordData::A(Ipv4Addr::new(10, 0, 0, 1)),
+            }
+        };
+        let mut cache = Cache::new(2);
+        let records = Answer::Records(vec![record("a.example.com", 60)]);
+        cache.insert("A.example.com", RecordType::A, records, secs(60), now);
+        cache.insert("b.example.com", RecordType::A, Answer::NoSuchName, secs(10), now);
+        assert_eq!(cache.get("a.example.com", RecordType::A, now + secs(15)),
+                   Some(Answer::Records(vec![record("a.example.com", 45)])));
+        assert_eq!(cache.get("a.example.com", RecordType::Aaaa, now), None);
+        assert_eq!(cache.get("b.example.com", RecordType::A, now + secs(9)),
+                   Some(Answer::NoSuchName));
+        assert_eq!(cache.get("b.example.com", RecordType::A, now + secs(10)), None);
+        assert_eq!(cache.entries.len(), 1);
+
+        // Full, the answer expiring first goes
+        cache.insert("b.example.com", RecordType::A, Answer::NoRecords, secs(90), now);
+        cache.insert("c.example.com", RecordType::A, Answer::NoRecords, secs(30), now);
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get("a.example.com", RecordType::A, now).is_none());
+        assert!(cache.get("b.example.com", RecordType::A, now).is_some());
+        // Expired ones first
+        cache.insert("d.example.com", RecordType::A, Answer::NoRecords, secs(1), now + secs(30));
+        assert!(cache.get("b.example.com", RecordType::A, now + secs(30)).is_some());
+
+        cache.insert("e.example.com", RecordType::A, Answer::NoRecords, secs(0), now);
+        assert!(cache.get("e.example.com", RecordType::A, now).is_none());
+        let mut disabled = Cache::new(0);
+        disabled.insert("a.example.com", RecordType::A, Answer::NoRecords, secs(60), now);
+        assert!(disabled.entries.is_empty());
+    }
+
+    #[test]
+    fn stack_addrs() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+        let host = |host: &str, port| Some(StackAddr::Host(host.to_owned(), port));
+        assert_eq!("10.0.0.1:53".to_stack_addr().ok(), Some(StackAddr::Addr(addr)));
+        assert_eq!(("10.0.0.1", 53).to_stack_addr().ok(), Some(StackAddr::Addr(addr)));
+        assert_eq!(SocketAddr::V4(addr).to_stack_addr().ok(), Some(StackAddr::Addr(addr)));
+        assert_eq!("example.com.:80".to_stack_addr().ok(), host("example.com", 80));
+        assert_eq!(("example.com".to_owned(), 80).to_stack_addr().ok(),
+                   host("example.com", 80));
+        assert_eq!(format!("{}", host("example.com", 80).unwrap()), "example.com:80");
+        for invalid in &["example.com", "example.com:http", ":80", "a..b:80", "[::1]:80", "::1"] {
+            match invalid.to_stack_addr() {
+                Err(StackError::InvalidArgument(_)) => (),
+                result => panic!("Unexpected result for {}: {:?}", invalid, result),
+            }
+        }
+        assert!((IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 80).to_stack_addr().is_err());
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(reverse_name(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 2))), "2.1.0.10.in-addr.arpa");
+        let ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x12);
+        let name = reverse_name(IpAddr::V6(ip));
+        assert!(name.starts_with("2.1.0.0.0.0.0.0."));
+        assert!(name.ends_with(".8.b.d.0.1.0.0.2.ip6.arpa"));
+        assert_eq!(name.len(), 32 * 2 + "ip6.arpa".len());
+    }
+}
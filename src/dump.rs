@@ -0,0 +1,325 @@
+//! Logging the frames of an interface, decoded and as hex, for debugging.
+//!
+//! `StackInterface::set_dump` switches dumping on or off while the stack
+//! runs. Every frame the interface sends or receives that the `DumpConfig`
+//! selects is logged through the `log` crate, at `DumpConfig::level` and
+//! with this module as target, as a one line summary of its headers
+//! followed by a hex dump of its start:
+//!
+//! ```text
+//! eth0 in 60 bytes: 02:00:00:00:00:02 > 02:00:00:00:00:01, IPv4 10.0.0.2 > 10.0.0.1 ttl 64, ...
+//! 0000  0200 0000 0001 0200 0000 0002 0800 4500  ..............E.
+//! ```
+//!
+//! Frames can be selected by direction, by the protocols they carry and with
+//! a BPF program, see `bpf`, as on the command line of tcpdump:
+//!
+//! ```rust,ignore
+//! let config = DumpConfig {
+//!     protocols: vec![Layer::Arp, Layer::Icmp],
+//!     direction: Some(Direction::Inbound),
+//!     ..DumpConfig::default()
+//! };
+//! stack.interface(&interface)?.set_dump(Some(config));
+//! ```
+//!
+//! A dump is an observer of the interface, see `observe`, so it sees
+//! received frames before any filter drops them, and costs nothing while
+//! switched off.
+
+use Interface;
+use bpf::Program;
+use ipv4::MORE_FRAGMENTS;
+use malformed::Layer;
+use observe::Observer;
+use pcap::Direction;
+use stats;
+
+use log::LogLevel;
+
+use pnet::packet::Packet;
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+
+use std::cmp;
+use std::time::SystemTime;
+
+/// Bytes of a row of a hex dump.
+const ROW_LEN: usize = 16;
+
+/// What frames a dump logs, and how.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpConfig {
+    /// Only frames carrying one of these protocols, all frames if empty
+    pub protocols: Vec<Layer>,
+    /// Only frames this program accepts, run on the whole frame
+    pub filter: Option<Program>,
+    /// Only frames going this way, both if `None`
+    pub direction: Option<Direction>,
+    /// How many bytes of the start of a frame the hex dump shows. Zero
+    /// logs summaries only
+    pub hex_len: usize,
+    pub level: LogLevel,
+}
+
+impl Default for DumpConfig {
+    fn default() -> DumpConfig {
+        DumpConfig {
+            protocols: Vec::new(),
+            filter: None,
+            direction: None,
+            hex_len: 128,
+            level: LogLevel::Debug,
+        }
+    }
+}
+
+impl DumpConfig {
+    /// Returns true if `frame`, going in `direction`, is to be dumped.
+    pub fn selects(&self, direction: Direction, frame: &[u8]) -> bool {
+        if self.direction.map_or(false, |selected| selected != direction) {
+            return false;
+        }
+        if !self.protocols.is_empty() {
+            let mut carried = false;
+            stats::layers(frame, |layer, _| carried |= self.protocols.contains(&layer));
+            if !carried {
+                return false;
+            }
+        }
+        self.filter.as_ref().map_or(true, |filter| filter.matches(frame))
+    }
+}
+
+/// An observer logging the frames `config` selects. Added to an interface
+/// by `StackInterface::set_dump`.
+pub struct Dump {
+    config: DumpConfig,
+}
+
+impl Dump {
+    pub fn new(config: DumpConfig) -> Dump {
+        Dump { config: config }
+    }
+}
+
+impl Observer for Dump {
+    fn observe(&self,
+               direction: Direction,
+               interface: &Interface,
+               _time: SystemTime,
+               frame: &EthernetPacket) {
+        let level = self.config.level;
+        if !log_enabled!(level) || !self.config.selects(direction, frame.packet()) {
+            return;
+        }
+        let direction = match direction {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        };
+        let data = frame.packet();
+        let summary = summary(frame);
+        if self.config.hex_len == 0 {
+            log!(level, "{} {} {} bytes: {}", interface.name, direction, data.len(), summary);
+        } else {
+            let hex = hex(&data[..cmp::min(data.len(), self.config.hex_len)]);
+            log!(level,
+                 "{} {} {} bytes: {}\n{}",
+                 interface.name,
+                 direction,
+                 data.len(),
+                 summary,
+                 hex);
+        }
+    }
+}
+
+/// Returns the headers of `frame` decoded into one line, as far as they
+/// are of protocols rips knows and are not truncated.
+pub fn summary(frame: &EthernetPacket) -> String {
+    let mut summary = format!("{} > {}", frame.get_source(), frame.get_destination());
+    let ether_type = frame.get_ethertype();
+    let payload = frame.payload();
+    if ether_type == EtherTypes::Arp {
+        match ArpPacket::new(payload) {
+            Some(ref arp) if payload.len() >= ArpPacket::minimum_packet_size() => {
+                let operation = arp.get_operation();
+                if operation == ArpOperations::Request {
+                    summary.push_str(&format!(", Arp who-has {} tell {}",
+                                              arp.get_target_proto_addr(),
+                                              arp.get_sender_proto_addr()));
+                } else if operation == ArpOperations::Reply {
+                    summary.push_str(&format!(", Arp {} is-at {}",
+                                              arp.get_sender_proto_addr(),
+                                              arp.get_sender_hw_addr()));
+                } else {
+                    summary.push_str(&format!(", Arp operation {}", operation.0));
+                }
+            }
+            _ => summary.push_str(", Arp truncated"),
+        }
+    } else if ether_type == EtherTypes::Ipv4 {
+        match Ipv4Packet::new(payload) {
+            Some(ip_pkg) => summary.push_str(&ipv4_summary(&ip_pkg)),
+            None => summary.push_str(", IPv4 truncated"),
+        }
+    } else if ether_type == EtherTypes::Vlan && payload.len() >= 4 {
+        let vid = ((payload[0] as u16) << 8 | payload[1] as u16) & 0x0fff;
+        let inner = (payload[2] as u16) << 8 | payload[3] as u16;
+        summary.push_str(&format!(", 802.1Q vid {} ethertype 0x{:04x}", vid, inner));
+    } else {
+        summary.push_str(&format!(", ethertype 0x{:04x}", ether_type.0));
+    }
+    summary
+}
+
+fn ipv4_summary(ip_pkg: &Ipv4Packet) -> String {
+    let mut summary = format!(", IPv4 {} > {} ttl {}",
+                              ip_pkg.get_source(),
+                              ip_pkg.get_destination(),
+                              ip_pkg.get_ttl());
+    let fragment_offset = ip_pkg.get_fragment_offset();
+    let more_fragments = ip_pkg.get_flags() & MORE_FRAGMENTS != 0;
+    if fragment_offset != 0 || more_fragments {
+        summary.push_str(&format!(" frag {}@{}{}",
+                                  ip_pkg.get_identification(),
+                                  fragment_offset as usize * 8,
+                                  if more_fragments { "+" } else { "" }));
+    }
+    if fragment_offset != 0 {
+        return summary;
+    }
+    let protocol = ip_pkg.get_next_level_protocol();
+    let header_len = ip_pkg.get_header_length() as usize * 4;
+    let total_len = cmp::min(ip_pkg.get_total_length() as usize, ip_pkg.packet().len());
+    let payload = if header_len <= total_len {
+        &ip_pkg.packet()[header_len..total_len]
+    } else {
+        &[]
+    };
+    if protocol == IpNextHeaderProtocols::Udp {
+        match UdpPacket::new(payload) {
+            Some(udp_pkg) => {
+                summary.push_str(&format!(", UDP {} > {} len {}",
+                                          udp_pkg.get_source(),
+                                          udp_pkg.get_destination(),
+                                          udp_pkg.get_length()))
+            }
+            None => summary.push_str(", UDP truncated"),
+        }
+    } else if protocol == IpNextHeaderProtocols::Icmp {
+        match IcmpPacket::new(payload) {
+            Some(icmp_pkg) => {
+                summary.push_str(&format!(", Icmp type {} code {}",
+                                          icmp_pkg.get_icmp_type().0,
+                                          icmp_pkg.get_icmp_code().0))
+            }
+            None => summary.push_str(", Icmp truncated"),
+        }
+    } else {
+        summary.push_str(&format!(", protocol {}", protocol.0));
+    }
+    summary
+}
+
+/// Returns `data` as rows of an offset, 16 bytes in hex and the same bytes
+/// as ASCII, with dots for what is not printable.
+pub fn hex(data: &[u8]) -> String {
+    let rows = data.chunks(ROW_LEN)
+        .enumerate()
+        .map(|(i, row)| {
+            let mut line = format!("{:04x} ", i * ROW_LEN);
+            for (j, byte) in row.iter().enumerate() {
+                if j % 2 == 0 {
+                    line.push(' ');
+                }
+                line.push_str(&format!("{:02x}", byte));
+            }
+            // Aligning the ASCII of a last row that is not full
+            let missing = ROW_LEN - row.len();
+            for _ in 0..missing * 2 + missing / 2 + 2 {
+                line.push(' ');
+            }
+            line.extend(row.iter()
+                .map(|&byte| if byte >= 0x20 && byte < 0x7f { byte as char } else { '.' }));
+            line
+        })
+        .collect::<Vec<_>>();
+    rows.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use bpf::{Instruction, Program};
+    use malformed::Layer;
+    use pcap::Direction;
+
+    use pnet::packet::ethernet::EthernetPacket;
+
+    use super::*;
+
+    fn udp_frame() -> Vec<u8> {
+        let mut frame = vec![0; 14 + 20 + 8 + 2];
+        frame[5] = 1;
+        frame[11] = 2;
+        frame[12] = 0x08;
+        frame[14] = 0x45;
+        frame[14 + 3] = 30;
+        frame[14 + 8] = 64;
+        frame[14 + 9] = 17;
+        frame[14 + 12..14 + 20].copy_from_slice(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        frame[14 + 20..14 + 28].copy_from_slice(&[0x30, 0x39, 0, 53, 0, 10, 0, 0]);
+        frame
+    }
+
+    #[test]
+    fn summaries() {
+        let frame = udp_frame();
+        assert_eq!(summary(&EthernetPacket::new(&frame).unwrap()),
+                   "00:00:00:00:00:02 > 00:00:00:00:00:01, IPv4 10.0.0.2 > 10.0.0.1 ttl 64, \
+                    UDP 12345 > 53 len 10");
+
+        let mut fragment = frame.clone();
+        fragment[14 + 6] = 0x20;
+        fragment[14 + 5] = 7;
+        assert!(summary(&EthernetPacket::new(&fragment).unwrap())
+            .ends_with("ttl 64 frag 7@0+, UDP 12345 > 53 len 10"));
+        fragment[14 + 7] = 1;
+        assert!(summary(&EthernetPacket::new(&fragment).unwrap()).ends_with("frag 7@8+"));
+
+        let mut other = frame[..20].to_vec();
+        other[12] = 0x88;
+        other[13] = 0xb5;
+        assert!(summary(&EthernetPacket::new(&other).unwrap()).ends_with(", ethertype 0x88b5"));
+    }
+
+    #[test]
+    fn hex_rows() {
+        let data = (0x40..0x40 + 18).collect::<Vec<u8>>();
+        assert_eq!(hex(&data),
+                   format!("0000  4041 4243 4445 4647 4849 4a4b 4c4d 4e4f  @ABCDEFGHIJKLMNO\n\
+                            0010  5051{}PQ",
+                           " ".repeat(37)));
+        assert_eq!(hex(&[0, 0x7f]), format!("0000  007f{}..", " ".repeat(37)));
+    }
+
+    #[test]
+    fn selection() {
+        let frame = udp_frame();
+        let mut config = DumpConfig::default();
+        assert!(config.selects(Direction::Outbound, &frame));
+        config.direction = Some(Direction::Inbound);
+        assert!(!config.selects(Direction::Outbound, &frame));
+        config.protocols = vec![Layer::Arp, Layer::Icmp];
+        assert!(!config.selects(Direction::Inbound, &frame));
+        config.protocols = vec![Layer::Udp];
+        assert!(config.selects(Direction::Inbound, &frame));
+        // Accepting nothing
+        config.filter = Some(Program::new(vec![Instruction::new(0x06, 0, 0, 0)]).unwrap());
+        assert!(!config.selects(Direction::Inbound, &frame));
+    }
+}
@@ -0,0 +1,251 @@
+//! 802.1X port access control with an external supplicant.
+//!
+//! On a port protected by 802.1X the switch drops everything but EAPOL
+//! frames until the host has authenticated, usually with some EAP method
+//! spoken between a supplicant on the host and an authenticator on the
+//! switch. rips implements no EAP method itself. A `Supplicant` set on an
+//! interface with `StackInterface::set_supplicant` receives all EAPOL frames
+//! arriving on it and sends its own through the `Port` it is given, and
+//! decides with `Port::set_authorized` when the port may be used for other
+//! traffic. Until then all other frames received on the interface are
+//! dropped and sending on it fails with `TxError::Unauthorized`.
+
+use {BasicPayload, RxError, RxResult, TxResult, TxSent};
+use stack::{LinkChange, LinkWatchers, TxBarrier};
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// The `EtherType` of EAPOL frames.
+pub const EAPOL: EtherType = EtherType(0x888e);
+
+/// The group address of port access entities, where EAPOL frames are sent
+/// when the MAC address of the authenticator is not known.
+pub static PAE_GROUP_MAC: MacAddr = MacAddr(0x01, 0x80, 0xc2, 0x00, 0x00, 0x03);
+
+/// The EAPOL protocol version of 802.1X-2004, sent in all frames.
+pub const VERSION: u8 = 2;
+
+/// EAPOL packet types.
+pub const EAP_PACKET: u8 = 0;
+pub const START: u8 = 1;
+pub const LOGOFF: u8 = 2;
+pub const KEY: u8 = 3;
+
+const HEADER_LEN: usize = 4;
+
+/// A parsed EAPOL frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EapolFrame<'a> {
+    pub src: MacAddr,
+    pub dst: MacAddr,
+    pub version: u8,
+    pub packet_type: u8,
+    /// The body after the header, without padding
+    pub body: &'a [u8],
+}
+
+impl<'a> EapolFrame<'a> {
+    /// Parses `packet`. Returns `None` if it is not an EAPOL frame or
+    /// shorter than its length field says.
+    pub fn parse(packet: &'a EthernetPacket) -> Option<EapolFrame<'a>> {
+        let data = packet.payload();
+        if packet.get_ethertype() != EAPOL || data.len() < HEADER_LEN {
+            return None;
+        }
+        let length = (data[2] as usize) << 8 | data[3] as usize;
+        if data.len() < HEADER_LEN + length {
+            return None;
+        }
+        Some(EapolFrame {
+            src: packet.get_source(),
+            dst: packet.get_destination(),
+            version: data[0],
+            packet_type: data[1],
+            body: &data[HEADER_LEN..HEADER_LEN + length],
+        })
+    }
+}
+
+/// An implementation of the supplicant side of 802.1X, handling the EAPOL
+/// frames of one interface.
+pub trait Supplicant: Send {
+    /// Called when the supplicant is set on an interface, with the port it
+    /// controls. Usually sends an EAPOL-Start frame.
+    fn start(&mut self, port: Port);
+
+    /// Called with every EAPOL frame received on the port, to its MAC address
+    /// or `PAE_GROUP_MAC`.
+    fn recv(&mut self, time: SystemTime, frame: &EapolFrame) -> RxResult;
+}
+
+/// The access control state of an interface, shared with its receiving
+/// thread.
+pub struct PortAccess {
+    authorized: Arc<AtomicBool>,
+    supplicant: Mutex<Option<Box<Supplicant>>>,
+    link_watchers: Arc<LinkWatchers>,
+}
+
+impl PortAccess {
+    /// Creates the state of an interface without a supplicant, which is
+    /// always authorized. Changes are sent to `link_watchers`.
+    pub fn new(link_watchers: Arc<LinkWatchers>) -> PortAccess {
+        PortAccess {
+            authorized: Arc::new(AtomicBool::new(true)),
+            supplicant: Mutex::new(None),
+            link_watchers: link_watchers,
+        }
+    }
+
+    pub fn is_authorized(&self) -> bool {
+        self.authorized.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the supplicant with `supplicant`, starting it on the port of
+    /// the interface with the MAC address `mac` sending on `tx`. The port is
+    /// unauthorized until the new supplicant authorizes it, or authorized for
+    /// good if `supplicant` is `None`.
+    pub fn set_supplicant(&self,
+                          supplicant: Option<Box<Supplicant>>,
+                          mac: MacAddr,
+                          tx: Arc<Mutex<TxBarrier>>) {
+        let port = self.port(mac, tx);
+        let mut current = self.supplicant.lock().unwrap();
+        port.set_authorized(supplicant.is_none());
+        // Started while holding the lock so replies to what it sends from
+        // `start` can not slip past it
+        *current = supplicant;
+        if let Some(ref mut supplicant) = *current {
+            supplicant.start(port);
+        }
+    }
+
+    pub fn has_supplicant(&self) -> bool {
+        self.supplicant.lock().unwrap().is_some()
+    }
+
+    /// Drops the supplicant, if any, sending an EAPOL-Logoff frame on the
+    /// port of the interface with the MAC address `mac` sending on `tx` on
+    /// its behalf. Used when the interface is shut down.
+    pub fn logoff(&self, mac: MacAddr, tx: Arc<Mutex<TxBarrier>>) -> TxResult {
+        match self.supplicant.lock().unwrap().take() {
+            Some(_) => self.port(mac, tx).send(PAE_GROUP_MAC, LOGOFF, &[]),
+            None => Ok(TxSent::default()),
+        }
+    }
+
+    fn port(&self, mac: MacAddr, tx: Arc<Mutex<TxBarrier>>) -> Port {
+        Port {
+            mac: mac,
+            authorized: self.authorized.clone(),
+            tx: tx,
+            link_watchers: self.link_watchers.clone(),
+        }
+    }
+
+    /// Hands `packet`, an EAPOL frame received on the interface with the MAC
+    /// address `mac`, to the supplicant. Returns `None` if there is none, so
+    /// the frame should be received as any other.
+    pub fn recv(&self,
+                mac: MacAddr,
+                time: SystemTime,
+                packet: &EthernetPacket)
+                -> Option<RxResult> {
+        let mut supplicant = self.supplicant.lock().unwrap();
+        let supplicant = match *supplicant {
+            Some(ref mut supplicant) => supplicant,
+            None => return None,
+        };
+        let dst = packet.get_destination();
+        if dst != mac && dst != PAE_GROUP_MAC {
+            return Some(Ok(()));
+        }
+        match EapolFrame::parse(packet) {
+            Some(frame) => Some(supplicant.recv(time, &frame)),
+            None => Some(Err(RxError::InvalidLength)),
+        }
+    }
+}
+
+/// The handle a `Supplicant` controls its interface with.
+#[derive(Clone)]
+pub struct Port {
+    mac: MacAddr,
+    authorized: Arc<AtomicBool>,
+    tx: Arc<Mutex<TxBarrier>>,
+    link_watchers: Arc<LinkWatchers>,
+}
+
+impl Port {
+    /// Returns the MAC address of the interface.
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    /// Sends an EAPOL frame of type `packet_type` with `body` to `dst`,
+    /// authorized or not.
+    pub fn send(&self, dst: MacAddr, packet_type: u8, body: &[u8]) -> TxResult {
+        let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + HEADER_LEN + body.len()];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            eth_pkg.set_destination(dst);
+            eth_pkg.set_source(self.mac);
+            eth_pkg.set_ethertype(EAPOL);
+            let data = eth_pkg.payload_mut();
+            data[0] = VERSION;
+            data[1] = packet_type;
+            data[2] = (body.len() >> 8) as u8;
+            data[3] = body.len() as u8;
+            data[HEADER_LEN..].copy_from_slice(body);
+        }
+        let payload = BasicPayload::new(&buffer);
+        self.tx.lock().unwrap().send_unblocked(1, buffer.len(), payload)
+    }
+
+    /// Allows or stops all other traffic on the interface.
+    pub fn set_authorized(&self, authorized: bool) {
+        let was_authorized = self.authorized.swap(authorized, Ordering::SeqCst);
+        self.tx.lock().unwrap().set_blocked(!authorized);
+        if authorized != was_authorized {
+            self.link_watchers.notify(LinkChange::Authorized(authorized));
+        }
+    }
+
+    pub fn is_authorized(&self) -> bool {
+        self.authorized.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mut buffer = vec![0; 14 + 4 + 5 + 10];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            eth_pkg.set_destination(PAE_GROUP_MAC);
+            eth_pkg.set_ethertype(EAPOL);
+        }
+        buffer[14..23].copy_from_slice(&[VERSION, EAP_PACKET, 0, 5, 1, 2, 0, 5, 1]);
+        {
+            let eth_pkg = EthernetPacket::new(&buffer).unwrap();
+            let frame = EapolFrame::parse(&eth_pkg).unwrap();
+            assert_eq!((frame.version, frame.packet_type), (VERSION, EAP_PACKET));
+            // The padding is not part of the body
+            assert_eq!(frame.body, &[1, 2, 0, 5, 1]);
+        }
+
+        buffer[17] = 16;
+        assert!(EapolFrame::parse(&EthernetPacket::new(&buffer).unwrap()).is_none());
+    }
+}
@@ -1,6 +1,60 @@
+use Interface;
+
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::net::Ipv4Addr;
+
+/// What kind of failure an error is, for deciding what to do about it
+/// without matching on every variant. Returned by `TxError::kind` and
+/// `StackError::kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// An argument is not one the operation takes
+    InvalidInput,
+
+    /// The stack is not in a state the operation is possible in
+    InvalidState,
+
+    /// What was to be added is already there
+    AlreadyExists,
+
+    /// What was referred to is not there
+    NotFound,
+
+    /// Nothing leads to the destination, until routes or addresses change
+    Unreachable,
+
+    /// The firewall or the 802.1X port refused the packet
+    Refused,
+
+    /// The tx-object is outdated. Creating a new one and sending again may
+    /// succeed
+    Outdated,
+
+    /// A buffer or queue is full right now. Sending again later may succeed
+    Busy,
+
+    /// Any other failure of the OS or the datalink
+    Io,
+}
+
+impl ErrorKind {
+    fn of(e: &io::Error) -> ErrorKind {
+        match e.kind() {
+            io::ErrorKind::WouldBlock |
+            io::ErrorKind::TimedOut |
+            io::ErrorKind::Interrupted => ErrorKind::Busy,
+            io::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
+            io::ErrorKind::AlreadyExists |
+            io::ErrorKind::AddrInUse => ErrorKind::AlreadyExists,
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied |
+            io::ErrorKind::ConnectionRefused => ErrorKind::Refused,
+            _ => ErrorKind::Io,
+        }
+    }
+}
 
 /// Enum representing errors happening while trying to send packets to the
 /// network
@@ -19,8 +73,40 @@ pub enum TxError {
     /// Returned when there was an `IoError` during transmission
     IoError(io::Error),
 
-    /// Any other error not covered by the more specific enum variants
-    Other(String),
+    /// Returned when the packet was dropped by the firewall
+    Filtered,
+
+    /// Returned when sending on an 802.1X port that is not authorized, see
+    /// `eapol`
+    Unauthorized,
+
+    /// Returned by interfaces in non-blocking mode when the frame does not
+    /// fit in their buffer or egress queue right now, see
+    /// `StackInterface::set_nonblocking`
+    WouldBlock,
+
+    /// Returned when the frames did not fit in the send buffer of the
+    /// datalink. Datalinks sending from a ring of their own return this while
+    /// the ring is full
+    BufferFull,
+}
+
+impl TxError {
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            TxError::InvalidTx => ErrorKind::Outdated,
+            TxError::TooLargePayload => ErrorKind::InvalidInput,
+            TxError::IoError(ref e) => ErrorKind::of(e),
+            TxError::Filtered | TxError::Unauthorized => ErrorKind::Refused,
+            TxError::WouldBlock | TxError::BufferFull => ErrorKind::Busy,
+        }
+    }
+
+    /// Returns true if sending again later may succeed, like when a buffer
+    /// was full, and false if it fails the same way again.
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Busy
+    }
 }
 
 impl From<io::Error> for TxError {
@@ -34,20 +120,27 @@ impl From<TxError> for io::Error {
         let other = |msg| io::Error::new(io::ErrorKind::Other, msg);
         match e {
             TxError::InvalidTx => other("Outdated constructor".to_owned()),
-            TxError::TooLargePayload => other("Too large payload".to_owned()),
+            TxError::TooLargePayload => {
+                io::Error::new(io::ErrorKind::InvalidInput, "Too large payload")
+            }
             TxError::IoError(e2) => e2,
-            TxError::Other(msg) => other(format!("Other: {}", msg)),
+            TxError::Filtered => {
+                io::Error::new(io::ErrorKind::PermissionDenied, "Dropped by firewall")
+            }
+            TxError::Unauthorized => {
+                io::Error::new(io::ErrorKind::PermissionDenied, "Port not authorized")
+            }
+            TxError::WouldBlock => io::Error::new(io::ErrorKind::WouldBlock, "Send buffer full"),
+            TxError::BufferFull => other("Insufficient buffer space".to_owned()),
         }
     }
 }
 
 impl fmt::Display for TxError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        use TxError::*;
         fmt.write_str(self.description())?;
         match *self {
-            IoError(ref e) => fmt.write_str(&format!(": {}", e)),
-            Other(ref s) => fmt.write_str(&format!(": {}", s)),
+            TxError::IoError(ref e) => write!(fmt, ": {}", e),
             _ => Ok(()),
         }
     }
@@ -60,7 +153,10 @@ impl Error for TxError {
             InvalidTx => "Invalid Tx instance",
             TooLargePayload => "Too large payload",
             IoError(..) => "IO error",
-            Other(..) => "Other error",
+            Filtered => "Dropped by firewall",
+            Unauthorized => "Port not authorized",
+            WouldBlock => "Send buffer full",
+            BufferFull => "Insufficient buffer space",
         }
     }
 
@@ -92,6 +188,13 @@ pub enum RxError {
     /// When other packet content is invalid.
     InvalidContent,
 
+    /// When the packet was dropped by the firewall.
+    Filtered,
+
+    /// When a fragment was dropped, along with its packet, because
+    /// reassembling it would take more memory than the limits allow.
+    ReassemblyLimit,
+
     /// Some error that was not covered by the more specific errors in this
     /// enum.
     Other(String),
@@ -117,6 +220,8 @@ impl Error for RxError {
             InvalidChecksum => "Invalid checksum in packet",
             InvalidLength => "Invalid length field in packet",
             InvalidContent => "Invalid content in packet",
+            Filtered => "Dropped by firewall",
+            ReassemblyLimit => "Reassembly memory limit reached",
             Other(..) => "Other error",
         }
     }
@@ -128,13 +233,64 @@ impl Error for RxError {
 /// Error returned upon invalid usage or state of the stack.
 #[derive(Debug)]
 pub enum StackError {
-    IllegalArgument,
-    NoRouteToHost,
-    InvalidInterface,
+    /// An argument is not one the operation takes, for this reason
+    InvalidArgument(String),
+
+    /// An MTU smaller than `ipv4::MIN_MTU` or larger than `MAX_MTU`
+    InvalidMtu(usize),
+
+    /// The operation is not possible right now, for this reason
+    InvalidState(String),
+
+    /// What was to be added is already there, as described
+    AlreadyExists(String),
+
+    /// The address is already configured on the stack
+    AddressInUse(Ipv4Addr),
+
+    /// There already is an interface of this name
+    InterfaceExists(String),
+
+    /// What was referred to is not there, as described
+    NotFound(String),
+
+    /// There is no interface of this name, in the stack or the OS
+    NoSuchInterface(String),
+
+    /// There is no VRF of this name
+    NoSuchVrf(String),
+
+    /// No route leads to this address
+    NoRouteToHost(Ipv4Addr),
+
+    /// No address of the stack can send to this address
+    NoSourceAddress(Ipv4Addr),
+
     TxError(TxError),
     IoError(io::Error),
 }
 
+impl StackError {
+    pub fn kind(&self) -> ErrorKind {
+        use StackError::*;
+        match *self {
+            InvalidArgument(..) | InvalidMtu(..) => ErrorKind::InvalidInput,
+            InvalidState(..) => ErrorKind::InvalidState,
+            AlreadyExists(..) | AddressInUse(..) | InterfaceExists(..) => ErrorKind::AlreadyExists,
+            NotFound(..) | NoSuchInterface(..) | NoSuchVrf(..) => ErrorKind::NotFound,
+            NoRouteToHost(..) | NoSourceAddress(..) => ErrorKind::Unreachable,
+            TxError(ref e) => e.kind(),
+            IoError(ref e) => ErrorKind::of(e),
+        }
+    }
+
+    /// Returns true if doing the same again later may succeed, see
+    /// `TxError::is_retryable`.
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Busy
+    }
+}
+
 impl From<TxError> for StackError {
     fn from(e: TxError) -> StackError {
         StackError::TxError(e)
@@ -149,14 +305,21 @@ impl From<io::Error> for StackError {
 
 impl From<StackError> for io::Error {
     fn from(e: StackError) -> io::Error {
-        let other = |msg| io::Error::new(io::ErrorKind::Other, msg);
-        match e {
-            StackError::IllegalArgument => other("Illegal argument".to_owned()),
-            StackError::NoRouteToHost => other("No route to host".to_owned()),
-            StackError::InvalidInterface => other("Invalid interface".to_owned()),
-            StackError::IoError(io_e) => io_e,
-            StackError::TxError(txe) => txe.into(),
-        }
+        let kind = match e {
+            StackError::IoError(io_e) => return io_e,
+            StackError::TxError(txe) => return txe.into(),
+            StackError::AddressInUse(..) => io::ErrorKind::AddrInUse,
+            StackError::NoSourceAddress(..) => io::ErrorKind::AddrNotAvailable,
+            _ => {
+                match e.kind() {
+                    ErrorKind::InvalidInput => io::ErrorKind::InvalidInput,
+                    ErrorKind::AlreadyExists => io::ErrorKind::AlreadyExists,
+                    ErrorKind::NotFound => io::ErrorKind::NotFound,
+                    _ => io::ErrorKind::Other,
+                }
+            }
+        };
+        io::Error::new(kind, e.to_string())
     }
 }
 
@@ -165,9 +328,17 @@ impl fmt::Display for StackError {
         use StackError::*;
         fmt.write_str(self.description())?;
         match *self {
-            TxError(ref e) => fmt.write_str(&format!(": {}", e)),
-            IoError(ref e) => fmt.write_str(&format!(": {}", e)),
-            _ => Ok(()),
+            InvalidArgument(ref s) |
+            InvalidState(ref s) |
+            AlreadyExists(ref s) |
+            NotFound(ref s) |
+            NoSuchInterface(ref s) |
+            InterfaceExists(ref s) |
+            NoSuchVrf(ref s) => write!(fmt, ": {}", s),
+            InvalidMtu(mtu) => write!(fmt, ": {}", mtu),
+            AddressInUse(ip) | NoRouteToHost(ip) | NoSourceAddress(ip) => write!(fmt, ": {}", ip),
+            TxError(ref e) => write!(fmt, ": {}", e),
+            IoError(ref e) => write!(fmt, ": {}", e),
         }
     }
 }
@@ -176,9 +347,17 @@ impl Error for StackError {
     fn description(&self) -> &str {
         use StackError::*;
         match *self {
-            IllegalArgument => "Illegal argument",
-            NoRouteToHost => "No route to host",
-            InvalidInterface => "Invalid interface",
+            InvalidArgument(..) => "Invalid argument",
+            InvalidMtu(..) => "Invalid MTU",
+            InvalidState(..) => "Invalid state",
+            AlreadyExists(..) => "Already exists",
+            AddressInUse(..) => "Address in use",
+            InterfaceExists(..) => "Interface exists",
+            NotFound(..) => "Not found",
+            NoSuchInterface(..) => "No such interface",
+            NoSuchVrf(..) => "No such VRF",
+            NoRouteToHost(..) => "No route to host",
+            NoSourceAddress(..) => "No source address",
             TxError(..) => "Transmission error",
             IoError(..) => "IO error",
         }
@@ -193,3 +372,37 @@ impl Error for StackError {
         }
     }
 }
+
+/// A failure of a background thread of an interface, after which the
+/// interface no longer receives. Reported to the watchers from
+/// `StackInterface::watch_errors` and `NetworkStack::watch_errors`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThreadError {
+    /// Receiving on the interface failed with an error of this kind and
+    /// message, and its rx thread quit
+    Datalink(Interface, io::ErrorKind, String),
+
+    /// A thread of the interface panicked with this message
+    Panic(Interface, String),
+}
+
+impl fmt::Display for ThreadError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use ThreadError::*;
+        fmt.write_str(self.description())?;
+        match *self {
+            Datalink(ref interface, _, ref msg) |
+            Panic(ref interface, ref msg) => write!(fmt, " on {}: {}", interface.name, msg),
+        }
+    }
+}
+
+impl Error for ThreadError {
+    fn description(&self) -> &str {
+        use ThreadError::*;
+        match *self {
+            Datalink(..) => "Receiving failed",
+            Panic(..) => "Thread panicked",
+        }
+    }
+}
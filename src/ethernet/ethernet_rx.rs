@@ -1,10 +1,12 @@
 use ::{RxResult, RxError};
+use bpf::Program;
 use pnet::packet::Packet;
 use pnet::packet::ethernet::{EtherType, EthernetPacket};
 use ::rx::RxListener;
 
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::Sender;
 use std::time::SystemTime;
 
@@ -29,10 +31,17 @@ impl BasicEthernetListener {
     pub fn new(ether_type: EtherType,
                tx: Sender<(SystemTime, EthernetPacket<'static>)>)
                -> Box<EthernetListener> {
-        Box::new(BasicEthernetListener {
+        Box::new(Self::unboxed(ether_type, tx))
+    }
+
+    /// Same as `new`, but returns the listener itself, such as for a `Chain`.
+    pub fn unboxed(ether_type: EtherType,
+                   tx: Sender<(SystemTime, EthernetPacket<'static>)>)
+                   -> BasicEthernetListener {
+        BasicEthernetListener {
             ether_type: ether_type,
             tx: tx,
-        })
+        }
     }
 }
 
@@ -50,11 +59,18 @@ impl EthernetListener for BasicEthernetListener {
     }
 }
 
+/// `EthernetListener`s that can be added and removed while the `EthernetRx`
+/// is running, by `EtherType`.
+pub type EthernetListenerLookup = HashMap<EtherType, Box<EthernetListener>>;
+
 /// Receiver and parser of ethernet frames. Distributes them to
 /// `EthernetListener`s based on `EtherType` in the frame.
 /// This is the lowest level *Rx* type.
 pub struct EthernetRx {
     listeners: HashMap<EtherType, Box<EthernetListener>>,
+    filter: Arc<RwLock<Option<Program>>>,
+    /// Consulted for the `EtherType`s not handled by `listeners`
+    extra_listeners: Arc<Mutex<EthernetListenerLookup>>,
 }
 
 impl EthernetRx {
@@ -66,8 +82,31 @@ impl EthernetRx {
     /// Panics if `listeners` contain multiple listeners that listens to the
     /// same ether type.
     pub fn new(listeners: Vec<Box<EthernetListener>>) -> EthernetRx {
+        Self::with_filter(listeners, Arc::new(RwLock::new(None)))
+    }
+
+    /// Same as `new`, but silently drops all frames not accepted by the
+    /// program in `filter`, if there is one.
+    pub fn with_filter(listeners: Vec<Box<EthernetListener>>,
+                       filter: Arc<RwLock<Option<Program>>>)
+                       -> EthernetRx {
+        Self::with_extra_listeners(listeners, filter, Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    /// Same as `with_filter`, but also hands frames with other `EtherType`s
+    /// than the ones of `listeners` to the listeners in `extra_listeners`.
+    /// Unlike `listeners` they can be changed later, at the cost of a lock
+    /// for every frame they get.
+    pub fn with_extra_listeners(listeners: Vec<Box<EthernetListener>>,
+                                filter: Arc<RwLock<Option<Program>>>,
+                                extra_listeners: Arc<Mutex<EthernetListenerLookup>>)
+                                -> EthernetRx {
         let map_listeners = Self::expand_listeners(listeners);
-        EthernetRx { listeners: map_listeners }
+        EthernetRx {
+            listeners: map_listeners,
+            filter: filter,
+            extra_listeners: extra_listeners,
+        }
     }
 
     fn expand_listeners(listeners: Vec<Box<EthernetListener>>)
@@ -86,14 +125,125 @@ impl EthernetRx {
 
 impl RxListener for EthernetRx {
     fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        if !passes(&self.filter, packet) {
+            return Ok(());
+        }
+        let ethertype = packet.get_ethertype();
+        if let Some(listener) = self.listeners.get_mut(&ethertype) {
+            return listener.recv(time, packet);
+        }
+        recv_extra(&self.extra_listeners, ethertype, time, packet)
+    }
+}
+
+/// A set of listeners known at compile time, for `StaticEthernetRx`. Every
+/// `EthernetListener` is a set of one, and `Chain` joins two sets.
+pub trait StaticListeners: Send {
+    /// Hands `packet` to the listener in the set for `ether_type`. Returns
+    /// `None` if there is none.
+    fn dispatch(&mut self,
+                ether_type: EtherType,
+                time: SystemTime,
+                packet: &EthernetPacket)
+                -> Option<RxResult>;
+}
+
+impl<L: EthernetListener> StaticListeners for L {
+    #[inline]
+    fn dispatch(&mut self,
+                ether_type: EtherType,
+                time: SystemTime,
+                packet: &EthernetPacket)
+                -> Option<RxResult> {
+        if self.ether_type() == ether_type {
+            Some(self.recv(time, packet))
+        } else {
+            None
+        }
+    }
+}
+
+/// The listeners of `A` followed by the ones of `B`. Where both have one
+/// for the same `EtherType`, the one of `A` gets the frames.
+pub struct Chain<A, B>(pub A, pub B);
+
+impl<A: StaticListeners, B: StaticListeners> StaticListeners for Chain<A, B> {
+    #[inline]
+    fn dispatch(&mut self,
+                ether_type: EtherType,
+                time: SystemTime,
+                packet: &EthernetPacket)
+                -> Option<RxResult> {
+        match self.0.dispatch(ether_type, time, packet) {
+            None => self.1.dispatch(ether_type, time, packet),
+            result => result,
+        }
+    }
+}
+
+/// Same as an `EthernetRx` created with `with_extra_listeners`, but with
+/// the listeners given as a `StaticListeners` of concrete types instead of
+/// boxed `EthernetListener`s. Handing a frame to one of them is a comparison
+/// per listener the compiler can inline, rather than a map lookup and a
+/// virtual call. Interfaces of the stack receive with one of these, with
+/// their IPv4, Arp and VLAN listeners in it.
+///
+/// ```rust,ignore
+/// let listeners = Chain(ipv4_rx, Chain(ArpRx::new(arp_tx), VlanRx::new(vlans)));
+/// let ethernet_rx = StaticEthernetRx::new(listeners, filter, extra_listeners);
+/// ```
+pub struct StaticEthernetRx<S: StaticListeners> {
+    listeners: S,
+    filter: Arc<RwLock<Option<Program>>>,
+    /// Consulted for the `EtherType`s not handled by `listeners`
+    extra_listeners: Arc<Mutex<EthernetListenerLookup>>,
+}
+
+impl<S: StaticListeners> StaticEthernetRx<S> {
+    pub fn new(listeners: S,
+               filter: Arc<RwLock<Option<Program>>>,
+               extra_listeners: Arc<Mutex<EthernetListenerLookup>>)
+               -> StaticEthernetRx<S> {
+        StaticEthernetRx {
+            listeners: listeners,
+            filter: filter,
+            extra_listeners: extra_listeners,
+        }
+    }
+}
+
+impl<S: StaticListeners> RxListener for StaticEthernetRx<S> {
+    fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        if !passes(&self.filter, packet) {
+            return Ok(());
+        }
         let ethertype = packet.get_ethertype();
-        match self.listeners.get_mut(&ethertype) {
-            Some(listener) => listener.recv(time, packet),
-            None => Err(RxError::NoListener(format!("Ethernet: No listener for {}", ethertype))),
+        match self.listeners.dispatch(ethertype, time, packet) {
+            Some(result) => result,
+            None => recv_extra(&self.extra_listeners, ethertype, time, packet),
         }
     }
 }
 
+/// Returns true if there is no program in `filter` or it accepts `packet`.
+fn passes(filter: &RwLock<Option<Program>>, packet: &EthernetPacket) -> bool {
+    match *filter.read().unwrap() {
+        Some(ref filter) => filter.matches(packet.packet()),
+        None => true,
+    }
+}
+
+fn recv_extra(extra_listeners: &Mutex<EthernetListenerLookup>,
+              ethertype: EtherType,
+              time: SystemTime,
+              packet: &EthernetPacket)
+              -> RxResult {
+    match extra_listeners.lock().unwrap().get_mut(&ethertype) {
+        Some(listener) => listener.recv(time, packet),
+        None => Err(RxError::NoListener(format!("Ethernet: No listener for {}", ethertype))),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use RxError;
@@ -150,6 +300,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn ethernet_rx_filter() {
+        use bpf::{Instruction, Program};
+        use std::sync::{Arc, RwLock};
+
+        let (listener, rx) = create_listener(EtherTypes::Arp);
+        // Accepts frames of at least 16 bytes
+        let program = Program::new(vec![Instruction::new(0x80, 0, 0, 0),
+                                        Instruction::new(0x35, 0, 1, 16),
+                                        Instruction::new(0x06, 0, 0, 0xffff),
+                                        Instruction::new(0x06, 0, 0, 0)])
+            .unwrap();
+        let filter = Arc::new(RwLock::new(Some(program)));
+        let mut testee = EthernetRx::with_filter(vec![listener], filter.clone());
+        testee.recv(SystemTime::now(), &create_arp_packet()).unwrap();
+        assert!(rx.try_recv().is_err());
+        *filter.write().unwrap() = None;
+        testee.recv(SystemTime::now(), &create_arp_packet()).unwrap();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn ethernet_rx_extra_listeners() {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex, RwLock};
+
+        let (listener, rx) = create_listener(EtherTypes::Ipv4);
+        let extra_listeners = Arc::new(Mutex::new(HashMap::new()));
+        let mut testee = EthernetRx::with_extra_listeners(vec![listener],
+                                                          Arc::new(RwLock::new(None)),
+                                                          extra_listeners.clone());
+        assert!(testee.recv(SystemTime::now(), &create_arp_packet()).is_err());
+
+        let (extra_listener, extra_rx) = create_listener(EtherTypes::Arp);
+        extra_listeners.lock().unwrap().insert(EtherTypes::Arp, extra_listener);
+        testee.recv(SystemTime::now(), &create_arp_packet()).unwrap();
+        assert!(extra_rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn ethernet_rx_recv() {
         let (listener1, rx1) = create_listener(EtherTypes::Arp);
@@ -165,6 +355,39 @@ mod tests {
         assert_eq!([56], output_packet.payload());
     }
 
+    #[test]
+    fn static_ethernet_rx_recv() {
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex, RwLock};
+
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+        let (tx3, rx3) = mpsc::channel();
+        // The first of two listeners for Arp gets the frames
+        let listeners = Chain(BasicEthernetListener::unboxed(EtherTypes::Ipv4, tx1),
+                              Chain(BasicEthernetListener::unboxed(EtherTypes::Arp, tx2),
+                                    BasicEthernetListener::unboxed(EtherTypes::Arp, tx3)));
+        let extra_listeners = Arc::new(Mutex::new(HashMap::new()));
+        let mut testee = StaticEthernetRx::new(listeners,
+                                               Arc::new(RwLock::new(None)),
+                                               extra_listeners.clone());
+        testee.recv(SystemTime::now(), &create_arp_packet()).unwrap();
+        assert!(rx1.try_recv().is_err());
+        assert_eq!(rx2.try_recv().unwrap().1.payload(), [56]);
+        assert!(rx3.try_recv().is_err());
+
+        let mut packet = MutableEthernetPacket::owned(vec![0; 15]).unwrap();
+        packet.set_ethertype(EtherTypes::Rarp);
+        let packet = packet.consume_to_immutable();
+        match testee.recv(SystemTime::now(), &packet) {
+            Err(RxError::NoListener(_)) => (),
+            _ => panic!("Expected NoListener error"),
+        }
+        let (extra_listener, extra_rx) = create_listener(EtherTypes::Rarp);
+        extra_listeners.lock().unwrap().insert(EtherTypes::Rarp, extra_listener);
+        testee.recv(SystemTime::now(), &packet).unwrap();
+        assert!(extra_rx.try_recv().is_ok());
+    }
 
     fn create_listener
         (ether_type: EtherType)
@@ -1,121 +1,36 @@
-use {Payload, HasPayload, BasicPayload, Tx, TxResult};
+use {Payload, BasicPayload, Tx, TxResult, TxSent};
+use corrupt::EthernetCorruption;
+use proto::ethernet::{EthernetBuilder, EthernetPayload};
 
-use pnet::packet::MutablePacket;
-use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ethernet::EthernetPacket;
 use pnet::util::MacAddr;
 
-/// Trait for anything wishing to be the payload of an Ethernet frame.
-pub trait EthernetPayload: Payload {
-    fn ether_type(&self) -> EtherType;
-}
-
-
-/// Basic reference implementation of an `EthernetPayload`.
-/// Can be used to construct Ethernet frames with arbitrary payload from a
-/// vector.
-#[derive(Clone)]
-pub struct BasicEthernetPayload<'a> {
-    ether_type: EtherType,
-    payload: BasicPayload<'a>,
-}
-
-impl<'a> BasicEthernetPayload<'a> {
-    pub fn new(ether_type: EtherType, payload: &'a [u8]) -> Self {
-        BasicEthernetPayload {
-            ether_type: ether_type,
-            payload: BasicPayload::new(payload),
-        }
-    }
-}
-
-impl<'a> EthernetPayload for BasicEthernetPayload<'a> {
-    fn ether_type(&self) -> EtherType {
-        self.ether_type
-    }
-}
-
-impl<'a> HasPayload for BasicEthernetPayload<'a> {
-    fn get_payload(&self) -> &Payload {
-        &self.payload
-    }
-
-    fn get_payload_mut(&mut self) -> &mut Payload {
-        &mut self.payload
-    }
-}
-
-#[cfg(test)]
-mod basic_ethernet_payload_tests {
-    use Payload;
-    use pnet::packet::ethernet::EtherTypes;
-    use super::*;
-
-    #[test]
-    fn ether_type() {
-        let testee = BasicEthernetPayload::new(EtherTypes::Ipv6, &[]);
-        assert_eq!(EtherTypes::Ipv6, testee.ether_type());
-    }
-
-    #[test]
-    fn len_zero() {
-        let testee = BasicEthernetPayload::new(EtherTypes::Arp, &[]);
-        assert_eq!(0, testee.len());
-    }
-
-    #[test]
-    fn len_three() {
-        let data = &[5, 6, 7];
-        let testee = BasicEthernetPayload::new(EtherTypes::Arp, data);
-        assert_eq!(3, testee.len());
-    }
-
-    #[test]
-    fn build_without_data() {
-        let mut testee = BasicEthernetPayload::new(EtherTypes::Arp, &[]);
-        let mut buffer = vec![99; 1];
-        testee.build(&mut buffer);
-        assert_eq!(99, buffer[0]);
-    }
-
-    #[test]
-    fn build_with_data() {
-        let data = &[5, 6, 7];
-        let mut testee = BasicEthernetPayload::new(EtherTypes::Arp, data);
-        let mut buffer = vec![0; 1];
-        testee.build(&mut buffer[0..0]);
-
-        testee.build(&mut buffer);
-        assert_eq!(5, buffer[0]);
-        testee.build(&mut buffer);
-        assert_eq!(6, buffer[0]);
-        testee.build(&mut buffer);
-        assert_eq!(7, buffer[0]);
-
-        testee.build(&mut buffer[0..0]);
-    }
-
-    #[test]
-    fn build_with_larger_buffer() {
-        let data = &[5, 6];
-        let mut testee = BasicEthernetPayload::new(EtherTypes::Arp, data);
-        let mut buffer = vec![0; 3];
-        testee.build(&mut buffer);
-        assert_eq!(&[5, 6, 0], &buffer[..]);
-    }
-}
-
-
 pub trait EthernetTx {
     fn src(&self) -> MacAddr;
     fn dst(&self) -> MacAddr;
     fn send<P>(&mut self, num_packets: usize, packet_size: usize, payload: P) -> TxResult
         where P: EthernetPayload;
+
+    /// Sends a frame for every payload of `payloads`, each as large as its
+    /// payload, in one go. See `Tx::send_all`.
+    fn send_all<P, I>(&mut self, payloads: I) -> TxResult
+        where P: EthernetPayload,
+              I: IntoIterator<Item = P>
+    {
+        let mut sent = TxSent::default();
+        for payload in payloads {
+            let len = payload.len();
+            sent += self.send(1, len, payload)?;
+        }
+        Ok(sent)
+    }
 }
 
 pub struct EthernetTxImpl<T: Tx> {
     src: MacAddr,
     dst: MacAddr,
     tx: T,
+    corruption: EthernetCorruption,
 }
 
 impl<T: Tx> EthernetTxImpl<T> {
@@ -124,8 +39,26 @@ impl<T: Tx> EthernetTxImpl<T> {
             src: src,
             dst: dst,
             tx: tx,
+            corruption: EthernetCorruption::default(),
         }
     }
+
+    /// Sets the source MAC address of the frames sent from now on. Does not
+    /// have to be the address of the interface, for example when testing
+    /// switches or sending from a virtual MAC address.
+    pub fn set_src(&mut self, src: MacAddr) {
+        self.src = src;
+    }
+
+    /// Makes the errors of `corruption` in the frames sent from now on, see
+    /// `corrupt`.
+    pub fn set_corruption(&mut self, corruption: EthernetCorruption) {
+        self.corruption = corruption;
+    }
+
+    pub fn corruption(&self) -> EthernetCorruption {
+        self.corruption
+    }
 }
 
 impl<T: Tx> EthernetTx for EthernetTxImpl<T> {
@@ -139,64 +72,58 @@ impl<T: Tx> EthernetTx for EthernetTxImpl<T> {
 
     /// Send ethernet packets to the network.
     ///
-    /// For every packet, all `header_size+size` bytes will be sent, no
-    /// matter how small payload is provided to the `MutableEthernetPacket` in
-    /// the call to `builder`. So in total `packets * (header_size+size)` bytes
-    /// will be sent. This is  usually not a problem since the IP layer has the
-    /// length in the header and the extra bytes should thus not cause any
-    /// trouble.
+    /// Every frame is exactly `header_size+size` bytes, so `payload` has to
+    /// fill `size` bytes of each. Callers sending packets of different sizes
+    /// send them in separate calls, like `Ipv4TxImpl` does with the last
+    /// fragment of a packet.
     fn send<P>(&mut self, num_packets: usize, packet_size: usize, payload: P) -> TxResult
         where P: EthernetPayload
     {
-        let builder = EthernetBuilder::new(self.src, self.dst, payload);
+        let mut builder = EthernetBuilder::new(self.src, self.dst, payload);
         let size_with_header = packet_size + EthernetPacket::minimum_packet_size();
-        self.tx.send(num_packets, size_with_header, builder)
-    }
-}
-
-
-/// Struct building Ethernet frames
-pub struct EthernetBuilder<P: EthernetPayload> {
-    src: MacAddr,
-    dst: MacAddr,
-    payload: P,
-}
-
-impl<P: EthernetPayload> EthernetBuilder<P> {
-    /// Creates a new `EthernetBuilder` with the given parameters
-    pub fn new(src: MacAddr, dst: MacAddr, payload: P) -> Self {
-        EthernetBuilder {
-            src: src,
-            dst: dst,
-            payload: payload,
+        if self.corruption == EthernetCorruption::default() {
+            return self.tx.send(num_packets, size_with_header, builder);
         }
-    }
-}
-
-impl<P: EthernetPayload> Payload for EthernetBuilder<P> {
-    fn len(&self) -> usize {
-        EthernetPacket::minimum_packet_size() + self.payload.len()
+        let mut sent = TxSent::default();
+        for _ in 0..num_packets {
+            let mut frame = vec![0; size_with_header];
+            builder.build(&mut frame);
+            self.corruption.apply(&mut frame);
+            sent += self.tx.send(1, frame.len(), BasicPayload::new(&frame))?;
+        }
+        Ok(sent)
     }
 
-    fn build(&mut self, buffer: &mut [u8]) {
-        let mut pkg = MutableEthernetPacket::new(buffer).unwrap();
-        pkg.set_source(self.src);
-        pkg.set_destination(self.dst);
-        pkg.set_ethertype(self.payload.ether_type());
-        self.payload.build(pkg.payload_mut());
+    fn send_all<P, I>(&mut self, payloads: I) -> TxResult
+        where P: EthernetPayload,
+              I: IntoIterator<Item = P>
+    {
+        if self.corruption != EthernetCorruption::default() {
+            let mut sent = TxSent::default();
+            for payload in payloads {
+                let len = payload.len();
+                sent += self.send(1, len, payload)?;
+            }
+            return Ok(sent);
+        }
+        let (src, dst) = (self.src, self.dst);
+        let builders = payloads.into_iter().map(|payload| EthernetBuilder::new(src, dst, payload));
+        self.tx.send_all(builders)
     }
 }
 
 
 #[cfg(test)]
 mod ethernet_tx_tests {
-    use {TxResult, TxError, Tx, Payload};
+    use {TxResult, TxSent, Tx, Payload};
+    use proto::ethernet::BasicEthernetPayload;
 
     use pnet::packet::Packet;
     use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
     use pnet::util::MacAddr;
 
     use std::error::Error;
+    use std::io;
     use std::sync::mpsc::{self, Sender, Receiver};
 
     use super::*;
@@ -221,9 +148,9 @@ mod ethernet_tx_tests {
                 payload.build(&mut buffer[..]);
                 self.chan
                     .send(buffer.into_boxed_slice())
-                    .map_err(|e| TxError::Other(e.description().to_owned()))?;
+                    .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.description()))?;
             }
-            Ok(())
+            Ok(TxSent::new(num_packets, packet_size))
         }
     }
 
@@ -259,4 +186,34 @@ mod ethernet_tx_tests {
         assert_eq!(EtherTypes::Arp, pkg.get_ethertype());
         assert_eq!(data, pkg.payload());
     }
+
+    #[test]
+    fn set_src() {
+        let (mock_tx, rx) = MockTx::new();
+        let mut testee = EthernetTxImpl::new(mock_tx, *SRC, *DST);
+        let src = MacAddr::new(0, 0, 0x5e, 0, 1, 7);
+        testee.set_src(src);
+        assert_eq!(src, testee.src());
+
+        testee.send(1, 1, BasicEthernetPayload::new(EtherTypes::Arp, &[1])).unwrap();
+        let buffer = rx.try_recv().unwrap();
+        assert_eq!(src, EthernetPacket::new(&buffer).unwrap().get_source());
+    }
+
+    #[test]
+    fn send_all() {
+        let (mock_tx, rx) = MockTx::new();
+        let mut testee = EthernetTxImpl::new(mock_tx, *SRC, *DST);
+        let (data1, data2) = ([1, 2, 3], [4]);
+        let payloads = vec![BasicEthernetPayload::new(EtherTypes::Arp, &data1),
+                            BasicEthernetPayload::new(EtherTypes::Ipv4, &data2)];
+        let sent = testee.send_all(payloads).unwrap();
+        assert_eq!(sent, TxSent { packets: 2, bytes: 14 + 3 + 14 + 1 });
+
+        let buffer = rx.try_recv().unwrap();
+        assert_eq!(&buffer[12..], &[0x08, 0x06, 1, 2, 3]);
+        let buffer = rx.try_recv().unwrap();
+        assert_eq!(&buffer[12..], &[0x08, 0x00, 4]);
+        assert!(rx.try_recv().is_err());
+    }
 }
@@ -0,0 +1,302 @@
+//! IEEE 802.3 frames with 802.2 LLC and SNAP headers.
+//!
+//! Frames where the `EtherType` field holds a length of at most
+//! `MAX_LENGTH` instead of a type carry an LLC header, naming the service
+//! access points (SAPs) of the sender and receiver. They are handed to the
+//! `LlcListener` for their destination SAP, such as 0x42 for spanning tree
+//! BPDUs. Frames to SAP 0xaa carry a SNAP header after it, with an
+//! organizationally unique identifier (OUI) and a protocol, and go to the
+//! listener for those instead. SNAP with OUI 0 is an `EtherType` in
+//! disguise, see `decapsulate`.
+
+use {Payload, RxError, RxResult, BasicPayload};
+use ethernet::EthernetPayload;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherType, EthernetPacket};
+use pnet::util::MacAddr;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Largest value of the `EtherType` field that is a length.
+pub const MAX_LENGTH: u16 = 1500;
+
+/// The SAP of frames with a SNAP header.
+pub const SNAP_SAP: u8 = 0xaa;
+
+/// Control field of unnumbered information frames, used by most protocols.
+pub const UI: u8 = 0x03;
+
+/// Returns true if `ether_type` is the length of an 802.3 frame.
+pub fn is_length(ether_type: EtherType) -> bool {
+    ether_type.0 <= MAX_LENGTH
+}
+
+/// What an `LlcListener` listens to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LlcKey {
+    /// Frames to a destination SAP, other than `SNAP_SAP`
+    Sap(u8),
+    /// SNAP frames with an OUI and protocol
+    Snap { oui: u32, protocol: u16 },
+}
+
+/// A parsed 802.3 frame with an LLC header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LlcFrame<'a> {
+    pub src: MacAddr,
+    pub dst: MacAddr,
+    pub dsap: u8,
+    pub ssap: u8,
+    /// One byte for unnumbered frames, two for the others
+    pub control: u16,
+    /// The OUI and protocol of the SNAP header, if there is one
+    pub snap: Option<(u32, u16)>,
+    /// What follows the headers, without padding
+    pub payload: &'a [u8],
+}
+
+impl<'a> LlcFrame<'a> {
+    /// Parses `packet`. Returns `None` if it is not an 802.3 frame or too
+    /// short for its headers.
+    pub fn parse(packet: &'a EthernetPacket) -> Option<LlcFrame<'a>> {
+        let length = packet.get_ethertype().0 as usize;
+        let data = packet.payload();
+        if !is_length(packet.get_ethertype()) || data.len() < length || length < 3 {
+            return None;
+        }
+        let data = &data[..length];
+        let (control, mut offset) = if data[2] & 0x03 == 0x03 {
+            (data[2] as u16, 3)
+        } else if length >= 4 {
+            ((data[2] as u16) << 8 | data[3] as u16, 4)
+        } else {
+            return None;
+        };
+        let snap = if data[0] == SNAP_SAP {
+            if length < offset + 5 {
+                return None;
+            }
+            let snap = &data[offset..offset + 5];
+            offset += 5;
+            let oui = (snap[0] as u32) << 16 | (snap[1] as u32) << 8 | snap[2] as u32;
+            Some((oui, (snap[3] as u16) << 8 | snap[4] as u16))
+        } else {
+            None
+        };
+        Some(LlcFrame {
+            src: packet.get_source(),
+            dst: packet.get_destination(),
+            dsap: data[0],
+            ssap: data[1],
+            control: control,
+            snap: snap,
+            payload: &data[offset..],
+        })
+    }
+
+    /// Returns the key of the listener this frame is for.
+    pub fn key(&self) -> LlcKey {
+        match self.snap {
+            Some((oui, protocol)) => {
+                LlcKey::Snap {
+                    oui: oui,
+                    protocol: protocol,
+                }
+            }
+            None => LlcKey::Sap(self.dsap),
+        }
+    }
+}
+
+/// Turns a SNAP frame with OUI 0 (RFC 1042) into the Ethernet II frame with
+/// the `EtherType` in its protocol field. Returns `None` for all other
+/// frames.
+pub fn decapsulate(packet: &EthernetPacket) -> Option<Vec<u8>> {
+    let (protocol, payload) = match LlcFrame::parse(packet) {
+        Some(LlcFrame { snap: Some((0, protocol)), payload, .. }) => (protocol, payload),
+        _ => return None,
+    };
+    let mut frame = Vec::with_capacity(EthernetPacket::minimum_packet_size() + payload.len());
+    frame.extend_from_slice(&packet.packet()[..12]);
+    frame.extend_from_slice(&[(protocol >> 8) as u8, protocol as u8]);
+    frame.extend_from_slice(payload);
+    Some(frame)
+}
+
+/// Anyone interested in receiving 802.3 frames with LLC headers must
+/// implement this.
+pub trait LlcListener: Send {
+    fn recv(&mut self, time: SystemTime, frame: &LlcFrame) -> RxResult;
+
+    /// Should return the SAP, or SNAP OUI and protocol, to listen to.
+    fn key(&self) -> LlcKey;
+}
+
+pub type LlcListenerLookup = HashMap<LlcKey, Box<LlcListener>>;
+
+/// Receiver of 802.3 frames, distributing them to `LlcListener`s.
+pub struct LlcRx {
+    listeners: Arc<Mutex<LlcListenerLookup>>,
+}
+
+impl LlcRx {
+    pub fn new(listeners: Arc<Mutex<LlcListenerLookup>>) -> LlcRx {
+        LlcRx { listeners: listeners }
+    }
+
+    pub fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        let frame = match LlcFrame::parse(packet) {
+            Some(frame) => frame,
+            None => return Err(RxError::InvalidLength),
+        };
+        let key = frame.key();
+        match self.listeners.lock().unwrap().get_mut(&key) {
+            Some(listener) => listener.recv(time, &frame),
+            None => Err(RxError::NoListener(format!("Llc: No listener for {:?}", key))),
+        }
+    }
+}
+
+/// An `EthernetPayload` with an LLC header, and possibly a SNAP header,
+/// before `payload`. Its `EtherType` is the length of the frame.
+pub struct LlcPayload<'a> {
+    header: Vec<u8>,
+    payload: BasicPayload<'a>,
+}
+
+impl<'a> LlcPayload<'a> {
+    /// Creates an unnumbered information frame from `ssap` to `dsap`.
+    pub fn new(dsap: u8, ssap: u8, payload: &'a [u8]) -> LlcPayload<'a> {
+        LlcPayload {
+            header: vec![dsap, ssap, UI],
+            payload: BasicPayload::new(payload),
+        }
+    }
+
+    /// Creates a SNAP frame for the protocol `protocol` of `oui`.
+    pub fn snap(oui: u32, protocol: u16, payload: &'a [u8]) -> LlcPayload<'a> {
+        LlcPayload {
+            header: vec![SNAP_SAP,
+                         SNAP_SAP,
+                         UI,
+                         (oui >> 16) as u8,
+                         (oui >> 8) as u8,
+                         oui as u8,
+                         (protocol >> 8) as u8,
+                         protocol as u8],
+            payload: BasicPayload::new(payload),
+        }
+    }
+}
+
+impl<'a> EthernetPayload for LlcPayload<'a> {
+    fn ether_type(&self) -> EtherType {
+        EtherType(self.len() as u16)
+    }
+}
+
+impl<'a> Payload for LlcPayload<'a> {
+    fn len(&self) -> usize {
+        self.header.len() + self.payload.len()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let header = self.header.len();
+        buffer[..header].copy_from_slice(&self.header);
+        self.payload.build(&mut buffer[header..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {Payload, RxError, RxResult};
+    use ethernet::EthernetPayload;
+
+    use pnet::packet::Packet;
+    use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Sender};
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn frame(mut payload: LlcPayload) -> Vec<u8> {
+        let mut buffer = vec![0; 14 + payload.len() + 4];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            eth_pkg.set_ethertype(payload.ether_type());
+        }
+        payload.build(&mut buffer[14..]);
+        buffer
+    }
+
+    struct Listener(LlcKey, Sender<Vec<u8>>);
+
+    impl LlcListener for Listener {
+        fn recv(&mut self, _time: SystemTime, frame: &LlcFrame) -> RxResult {
+            self.1.send(frame.payload.to_vec()).unwrap();
+            Ok(())
+        }
+
+        fn key(&self) -> LlcKey {
+            self.0
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let buffer = frame(LlcPayload::new(0x42, 0x42, &[1, 2, 3]));
+        let eth_pkg = EthernetPacket::new(&buffer).unwrap();
+        let llc = LlcFrame::parse(&eth_pkg).unwrap();
+        assert_eq!((llc.dsap, llc.ssap, llc.control), (0x42, 0x42, UI as u16));
+        // The padding is not part of the payload
+        assert_eq!(llc.payload, &[1, 2, 3]);
+        assert_eq!(llc.key(), LlcKey::Sap(0x42));
+
+        let buffer = frame(LlcPayload::snap(0x00000c, 0x2000, &[4]));
+        let eth_pkg = EthernetPacket::new(&buffer).unwrap();
+        let llc = LlcFrame::parse(&eth_pkg).unwrap();
+        assert_eq!(llc.key(),
+                   LlcKey::Snap {
+                       oui: 0x00000c,
+                       protocol: 0x2000,
+                   });
+        assert_eq!(llc.payload, &[4]);
+        assert_eq!(decapsulate(&eth_pkg), None);
+    }
+
+    #[test]
+    fn decapsulate_rfc1042() {
+        let buffer = frame(LlcPayload::snap(0, 0x0800, &[0x45, 0]));
+        let frame = decapsulate(&EthernetPacket::new(&buffer).unwrap()).unwrap();
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth_pkg.get_ethertype(), EtherTypes::Ipv4);
+        assert_eq!(eth_pkg.payload(), &[0x45, 0]);
+    }
+
+    #[test]
+    fn demux() {
+        let (tx, rx) = mpsc::channel();
+        let mut listeners = HashMap::new();
+        let listener = Listener(LlcKey::Sap(0x42), tx);
+        listeners.insert(listener.key(), Box::new(listener) as Box<LlcListener>);
+        let mut testee = LlcRx::new(Arc::new(Mutex::new(listeners)));
+
+        let buffer = frame(LlcPayload::new(0x42, 0x42, &[9]));
+        testee.recv(SystemTime::now(), &EthernetPacket::new(&buffer).unwrap()).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), vec![9]);
+
+        let buffer = frame(LlcPayload::new(0xfe, 0xfe, &[9]));
+        match testee.recv(SystemTime::now(), &EthernetPacket::new(&buffer).unwrap()) {
+            Err(RxError::NoListener(..)) => (),
+            _ => panic!("Expected NoListener error"),
+        }
+        let packet = EthernetPacket::new(&buffer[..15]).unwrap();
+        assert!(testee.recv(SystemTime::now(), &packet).is_err());
+    }
+}
@@ -2,9 +2,24 @@
 //! an underlying
 //! network adapter.
 
+use pnet::util::MacAddr;
+
+use std::net::Ipv4Addr;
+
+pub mod llc;
+
 mod ethernet_rx;
 mod ethernet_tx;
 
-pub use self::ethernet_rx::{BasicEthernetListener, EthernetListener, EthernetRx};
-pub use self::ethernet_tx::{BasicEthernetPayload, EthernetBuilder, EthernetPayload, EthernetTx,
-                            EthernetTxImpl};
+pub use self::ethernet_rx::{BasicEthernetListener, Chain, EthernetListener,
+                            EthernetListenerLookup, EthernetRx, StaticEthernetRx,
+                            StaticListeners};
+pub use self::ethernet_tx::{EthernetTx, EthernetTxImpl};
+pub use proto::ethernet::{BasicEthernetPayload, EthernetBuilder, EthernetPayload};
+
+/// Returns the multicast MAC address IPv4 multicast to `group` is sent to,
+/// the low 23 bits of `group` in 01:00:5e:00:00:00.
+pub fn ipv4_multicast_mac(group: Ipv4Addr) -> MacAddr {
+    let octets = group.octets();
+    MacAddr::new(0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3])
+}
@@ -0,0 +1,423 @@
+//! Failing over between default routes when their gateways stop answering.
+//!
+//! A stack can have several default routes with different metrics, see
+//! `NetworkStack::add_default_route`. Traffic takes the one with the lowest
+//! metric, whether its gateway is there or not. A `GatewayMonitor` checks
+//! the gateways of all default routes with Arp and echo requests, see
+//! `probe`, and withdraws the routes through a gateway that misses
+//! `FailoverConfig::failures` checks in a row, so traffic goes through the
+//! next one. The routes are restored as soon as the gateway answers again.
+//!
+//! ```rust,ignore
+//! stack.add_default_route(primary, interface.clone(), 0)?;
+//! stack.add_default_route(backup, interface, 10)?;
+//! let monitor = GatewayMonitor::start(stack, FailoverConfig::default())?;
+//! for event in monitor.watch() {
+//!     info!("{:?}", event);
+//! }
+//! ```
+//!
+//! Only the default routes of the main routing table are checked. The
+//! gateways a monitor withdrew are restored when it stops.
+
+use {NetworkStack, RouteEntry, StackError, StackResult};
+use probe::{self, Outcome, ProbeConfig};
+use routing;
+
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// A change a `GatewayMonitor` made, or saw, to the default routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverEvent {
+    /// The routes through the gateway were withdrawn
+    GatewayDown(Ipv4Addr),
+    /// The gateway answers again and its routes were restored
+    GatewayUp(Ipv4Addr),
+    /// Traffic goes through the default route of `to` instead of that of
+    /// `from`, `None` if there was or is none
+    Switched {
+        from: Option<Ipv4Addr>,
+        to: Option<Ipv4Addr>,
+    },
+}
+
+/// How a `GatewayMonitor` checks gateways.
+#[derive(Debug, Clone)]
+pub struct FailoverConfig {
+    /// How long to wait between checks
+    pub interval: Duration,
+    /// How many checks in a row a gateway has to miss to be withdrawn
+    pub failures: usize,
+    /// How every check probes the gateways. Arp and echo requests, with a
+    /// timeout of 500 ms and no retries, by default.
+    pub probe: ProbeConfig,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> FailoverConfig {
+        let mut probe = ProbeConfig::default();
+        probe.timeout = Duration::from_millis(500);
+        probe.retries = 0;
+        FailoverConfig {
+            interval: Duration::from_secs(1),
+            failures: 3,
+            probe: probe,
+        }
+    }
+}
+
+/// A thread checking the gateways of the default routes of a stack.
+/// Stops when dropped.
+pub struct GatewayMonitor {
+    state: Arc<Mutex<Monitor>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GatewayMonitor {
+    /// Starts checking the gateways of all default routes of `stack`,
+    /// including ones added later.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `config` takes gateways
+    /// down without missed checks or probes with no method.
+    pub fn start(stack: Arc<Mutex<NetworkStack>>,
+                 config: FailoverConfig)
+                 -> StackResult<GatewayMonitor> {
+        if config.failures == 0 {
+            let msg = "Gateways need at least one failure to go down".to_owned();
+            return Err(StackError::InvalidArgument(msg));
+        }
+        if !config.probe.echo && !config.probe.arp {
+            let msg = "Checking gateways needs echo or Arp requests".to_owned();
+            return Err(StackError::InvalidArgument(msg));
+        }
+        let interval = config.interval;
+        let state = Arc::new(Mutex::new(Monitor::new(stack, config)));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_state = state.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                let checks = thread_state.lock().unwrap().checks();
+                let results = checks.into_iter()
+                    .flat_map(|check| check.run())
+                    .collect::<Vec<_>>();
+                let mut state = thread_state.lock().unwrap();
+                for (gw, alive) in results {
+                    state.record(gw, alive);
+                }
+                state.switch();
+                drop(state);
+                thread::sleep(interval);
+            }
+            thread_state.lock().unwrap().restore();
+        });
+        Ok(GatewayMonitor {
+            state: state,
+            stop: stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns a channel receiving the events of the monitor from now on.
+    pub fn watch(&self) -> Receiver<FailoverEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.state.lock().unwrap().watchers.push(tx);
+        rx
+    }
+
+    /// Returns the gateways whose routes the monitor withdrew.
+    pub fn down_gateways(&self) -> Vec<Ipv4Addr> {
+        let mut down = self.state.lock().unwrap().down.iter().cloned().collect::<Vec<_>>();
+        down.sort();
+        down
+    }
+
+    /// Stops the monitoring thread and waits for it to quit.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for GatewayMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Probing the gateways reached from one local address.
+struct Check {
+    stack: Arc<Mutex<NetworkStack>>,
+    src: Ipv4Addr,
+    gateways: Vec<Ipv4Addr>,
+    config: ProbeConfig,
+}
+
+impl Check {
+    /// Returns if each gateway answered.
+    fn run(self) -> Vec<(Ipv4Addr, bool)> {
+        let gateways = self.gateways.clone();
+        match probe::sweep(self.stack, self.src, gateways, self.config) {
+            Ok(sweep) => {
+                sweep.map(|result| {
+                        let alive = match result.outcome {
+                            Outcome::Alive { .. } => true,
+                            _ => false,
+                        };
+                        (result.target, alive)
+                    })
+                    .collect()
+            }
+            Err(e) => {
+                warn!("Failover: Unable to probe from {}: {}", self.src, e);
+                self.gateways.into_iter().map(|gw| (gw, false)).collect()
+            }
+        }
+    }
+}
+
+/// The state of a `GatewayMonitor`.
+struct Monitor {
+    stack: Arc<Mutex<NetworkStack>>,
+    config: FailoverConfig,
+    /// The checks missed in a row by each gateway
+    misses: HashMap<Ipv4Addr, usize>,
+    /// The gateways withdrawn by the monitor
+    down: HashSet<Ipv4Addr>,
+    /// The gateway of the default route in use
+    active: Option<Ipv4Addr>,
+    watchers: Vec<Sender<FailoverEvent>>,
+}
+
+impl Monitor {
+    fn new(stack: Arc<Mutex<NetworkStack>>, config: FailoverConfig) -> Monitor {
+        let active = active_gateway(&mut stack.lock().unwrap());
+        Monitor {
+            stack: stack,
+            config: config,
+            misses: HashMap::new(),
+            down: HashSet::new(),
+            active: active,
+            watchers: Vec::new(),
+        }
+    }
+
+    /// Returns the checks of the gateways of the current default routes,
+    /// and forgets, and restores, gateways whose routes are gone.
+    fn checks(&mut self) -> Vec<Check> {
+        let mut stack = self.stack.lock().unwrap();
+        let routes = stack.routing_table().default_routes();
+        let mut checks: Vec<Check> = Vec::new();
+        let mut gateways = HashSet::new();
+        for route in &routes {
+            let gw = match route.gw {
+                Some(gw) => gw,
+                None => continue,
+            };
+            if !gateways.insert(gw) {
+                continue;
+            }
+            let src = match source(&mut stack, route) {
+                Some(src) => src,
+                None => {
+                    debug!("Failover: No address to check {} from", gw);
+                    continue;
+                }
+            };
+            if let Some(check) = checks.iter_mut().find(|check| check.src == src) {
+                check.gateways.push(gw);
+                continue;
+            }
+            checks.push(Check {
+                stack: self.stack.clone(),
+                src: src,
+                gateways: vec![gw],
+                config: self.config.probe.clone(),
+            });
+        }
+        let forgotten = self.misses
+            .keys()
+            .filter(|gw| !gateways.contains(gw))
+            .cloned()
+            .collect::<Vec<_>>();
+        for gw in forgotten {
+            self.misses.remove(&gw);
+        }
+        let gone = self.down.difference(&gateways).cloned().collect::<Vec<_>>();
+        for gw in gone {
+            self.down.remove(&gw);
+            stack.set_gateway_up(gw, true);
+        }
+        checks
+    }
+
+    /// Takes note of a check of `gw`, withdrawing or restoring it.
+    fn record(&mut self, gw: Ipv4Addr, alive: bool) {
+        if alive {
+            self.misses.insert(gw, 0);
+            if self.down.remove(&gw) {
+                self.stack.lock().unwrap().set_gateway_up(gw, true);
+                self.notify(FailoverEvent::GatewayUp(gw));
+            }
+            return;
+        }
+        let misses = {
+            let misses = self.misses.entry(gw).or_insert(0);
+            *misses += 1;
+            *misses
+        };
+        if misses >= self.config.failures && self.down.insert(gw) {
+            warn!("Failover: Gateway {} missed {} checks", gw, misses);
+            self.stack.lock().unwrap().set_gateway_up(gw, false);
+            self.notify(FailoverEvent::GatewayDown(gw));
+        }
+    }
+
+    /// Tells the watchers if traffic goes through another gateway than
+    /// before.
+    fn switch(&mut self) {
+        let active = active_gateway(&mut self.stack.lock().unwrap());
+        if active != self.active {
+            let event = FailoverEvent::Switched {
+                from: self.active,
+                to: active,
+            };
+            self.active = active;
+            self.notify(event);
+        }
+    }
+
+    /// Restores all gateways the monitor withdrew.
+    fn restore(&mut self) {
+        let mut stack = self.stack.lock().unwrap();
+        for gw in self.down.drain() {
+            stack.set_gateway_up(gw, true);
+        }
+    }
+
+    /// Sends `event` to all watchers, forgetting those that stopped.
+    fn notify(&mut self, event: FailoverEvent) {
+        self.watchers.retain(|watcher| watcher.send(event).is_ok());
+    }
+}
+
+fn active_gateway(stack: &mut NetworkStack) -> Option<Ipv4Addr> {
+    stack.routing_table().active_default_route().and_then(|route| route.gw)
+}
+
+/// Returns the address to check the gateway of `route` from, the source
+/// address of the route if it has one.
+fn source(stack: &mut NetworkStack, route: &RouteEntry) -> Option<Ipv4Addr> {
+    let gw = match route.gw {
+        Some(gw) => gw,
+        None => return None,
+    };
+    let nets = match stack.interface(&route.interface) {
+        Ok(stack_interface) => stack_interface.ipv4_networks(),
+        Err(_) => return None,
+    };
+    let own = route.src.and_then(|src| nets.iter().find(|net| net.ip() == src));
+    match own {
+        Some(net) => Some(net.ip()),
+        None => routing::select_source(&nets, gw, gw),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use NetworkStack;
+    use testing;
+
+    use ipnetwork::Ipv4Network;
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn ip(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, last)
+    }
+
+    fn stack() -> Arc<Mutex<NetworkStack>> {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::new(ip(2), 24).unwrap()).unwrap();
+        stack.add_default_route(ip(3), interface.clone(), 10).unwrap();
+        stack.add_default_route(ip(1), interface, 0).unwrap();
+        Arc::new(Mutex::new(stack))
+    }
+
+    fn gateway(stack: &Arc<Mutex<NetworkStack>>) -> Ipv4Addr {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+        stack.lock().unwrap().routing_table().route(*dst.ip()).unwrap().0.unwrap()
+    }
+
+    #[test]
+    fn fail_over_and_back() {
+        let stack = stack();
+        let mut config = FailoverConfig::default();
+        config.failures = 2;
+        let mut monitor = Monitor::new(stack.clone(), config);
+        let (tx, rx) = mpsc::channel();
+        monitor.watchers.push(tx);
+        assert_eq!(monitor.active, Some(ip(1)));
+        let checks = monitor.checks();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].src, ip(2));
+        assert_eq!(checks[0].gateways, vec![ip(1), ip(3)]);
+
+        monitor.record(ip(1), false);
+        monitor.switch();
+        assert!(rx.try_recv().is_err());
+        monitor.record(ip(3), true);
+        monitor.record(ip(1), false);
+        assert_eq!(rx.try_recv(), Ok(FailoverEvent::GatewayDown(ip(1))));
+        assert_eq!(gateway(&stack), ip(3));
+        monitor.record(ip(1), false);
+        monitor.switch();
+        assert_eq!(rx.try_recv(),
+                   Ok(FailoverEvent::Switched {
+                       from: Some(ip(1)),
+                       to: Some(ip(3)),
+                   }));
+        assert!(rx.try_recv().is_err());
+
+        monitor.record(ip(1), true);
+        monitor.switch();
+        assert_eq!(rx.try_recv(), Ok(FailoverEvent::GatewayUp(ip(1))));
+        assert_eq!(rx.try_recv(),
+                   Ok(FailoverEvent::Switched {
+                       from: Some(ip(3)),
+                       to: Some(ip(1)),
+                   }));
+        assert_eq!(gateway(&stack), ip(1));
+    }
+
+    #[test]
+    fn removed_routes_restored() {
+        let stack = stack();
+        let mut config = FailoverConfig::default();
+        config.failures = 1;
+        let mut monitor = Monitor::new(stack.clone(), config);
+        monitor.record(ip(3), false);
+        assert!(!stack.lock().unwrap().routing_table().is_gateway_up(ip(3)));
+        let default_net = Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap();
+        stack.lock().unwrap().remove_route_with_metric(default_net, 10).unwrap();
+        assert_eq!(monitor.checks()[0].gateways, vec![ip(1)]);
+        assert!(stack.lock().unwrap().routing_table().is_gateway_up(ip(3)));
+
+        monitor.record(ip(1), false);
+        assert_eq!(stack.lock().unwrap().routing_table().active_default_route(), None);
+        monitor.restore();
+        assert_eq!(gateway(&stack), ip(1));
+    }
+}
@@ -0,0 +1,294 @@
+//! Handling the frames received on one interface on several threads.
+//!
+//! An interface normally handles everything it receives on its rx thread,
+//! so one core is the limit of how fast it receives. With
+//! `NetworkStackBuilder::rx_workers` the rx thread only reads the frames and
+//! hands each to one of several worker threads, picked by `flow_hash`.
+//! Frames of the same flow always go to the same worker, so they are
+//! handled in the order they arrived. Frames of different flows may not be.
+//!
+//! ```rust,ignore
+//! let stack = NetworkStackBuilder::new()
+//!     .rx_workers(4)
+//!     .pin_threads(ThreadKind::RxWorker, vec![2, 3, 4, 5])
+//!     .build();
+//! ```
+//!
+//! Every worker has a bounded queue. Frames arriving at a full one are
+//! dropped and counted in `FanOutStats`. Fragmented IPv4 packets are
+//! reassembled by each worker on its own, so the reassembly limits apply
+//! per worker.
+
+use RxResult;
+use pool::{BufferPool, PooledBuffer};
+use rx::RxListener;
+use threads::ThreadConfig;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::SystemTime;
+
+/// Frames each worker queues by default.
+pub static DEFAULT_QUEUE_LEN: usize = 1024;
+
+/// Counters of a `FanOut`, over all of its workers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FanOutStats {
+    /// Frames handed to a worker
+    pub queued: usize,
+    /// Frames dropped because the queue of their worker was full
+    pub dropped: usize,
+}
+
+/// Returns a hash of the flow the Ethernet frame `frame` belongs to. IPv4
+/// packets hash by their addresses and protocol, and by their ports for
+/// TCP and UDP. Fragments hash without ports, as only the first one has
+/// any, so all fragments of a packet hash the same. Everything else hashes
+/// to 0.
+pub fn flow_hash(frame: &[u8]) -> u64 {
+    let eth_pkg = match EthernetPacket::new(frame) {
+        Some(eth_pkg) => eth_pkg,
+        None => return 0,
+    };
+    if eth_pkg.get_ethertype() != EtherTypes::Ipv4 {
+        return 0;
+    }
+    let packet = eth_pkg.payload();
+    if packet.len() < 20 {
+        return 0;
+    }
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let protocol = packet[9];
+    let fragmented = (packet[6] & 0x3f) != 0 || packet[7] != 0;
+    let mut hash = Fnv::new();
+    hash.write(&packet[12..20]);
+    hash.write(&[protocol]);
+    let has_ports = protocol == 6 || protocol == 17;
+    if has_ports && !fragmented && packet.len() >= header_len + 4 {
+        hash.write(&packet[header_len..header_len + 4]);
+    }
+    hash.finish()
+}
+
+/// 64 bit FNV-1a, good enough to spread flows and cheap per frame.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+struct Queue {
+    frames: VecDeque<(SystemTime, PooledBuffer)>,
+    /// Set when the `FanOut` feeding the queue is dropped
+    closed: bool,
+}
+
+struct Worker {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+/// An `RxListener` handing every frame to one of several worker threads,
+/// each calling a listener of its own. See the module documentation.
+pub struct FanOut {
+    workers: Vec<Arc<Worker>>,
+    queue_len: usize,
+    pool: BufferPool,
+    stats: Arc<Mutex<FanOutStats>>,
+}
+
+impl FanOut {
+    /// Spawns `workers` threads, the one with index `i` as `thread(i)` says
+    /// and calling the listener `listener(i)`. Copies of the frames are
+    /// taken from `pool`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `workers` is zero.
+    pub fn spawn<L, F, T>(workers: usize,
+                          thread: T,
+                          mut listener: F,
+                          pool: BufferPool)
+                          -> FanOut
+        where L: RxListener + 'static,
+              F: FnMut(usize) -> L,
+              T: Fn(usize) -> ThreadConfig
+    {
+        assert!(workers > 0, "A FanOut needs at least one worker");
+        let workers = (0..workers)
+            .map(|i| {
+                let worker = Arc::new(Worker {
+                    queue: Mutex::new(Queue {
+                        frames: VecDeque::new(),
+                        closed: false,
+                    }),
+                    ready: Condvar::new(),
+                });
+                let thread_worker = worker.clone();
+                let worker_listener = listener(i);
+                thread(i).spawn(move || run(thread_worker, worker_listener));
+                worker
+            })
+            .collect();
+        FanOut {
+            workers: workers,
+            queue_len: DEFAULT_QUEUE_LEN,
+            pool: pool,
+            stats: Arc::new(Mutex::new(FanOutStats::default())),
+        }
+    }
+
+    /// Returns a handle to the counters, for reading them after the `FanOut`
+    /// was handed to an rx thread.
+    pub fn stats(&self) -> Arc<Mutex<FanOutStats>> {
+        self.stats.clone()
+    }
+}
+
+impl RxListener for FanOut {
+    fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        let index = (flow_hash(packet.packet()) % self.workers.len() as u64) as usize;
+        let worker = &self.workers[index];
+        let mut queue = worker.queue.lock().unwrap();
+        if queue.frames.len() >= self.queue_len {
+            self.stats.lock().unwrap().dropped += 1;
+            return Ok(());
+        }
+        queue.frames.push_back((time, self.pool.copy_of(packet.packet())));
+        worker.ready.notify_one();
+        self.stats.lock().unwrap().queued += 1;
+        Ok(())
+    }
+}
+
+impl Drop for FanOut {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.queue.lock().unwrap().closed = true;
+            worker.ready.notify_one();
+        }
+    }
+}
+
+/// Hands the frames of `worker` to `listener` until its `FanOut` is dropped
+/// and the queue is empty.
+fn run<L: RxListener>(worker: Arc<Worker>, mut listener: L) {
+    loop {
+        let (time, frame) = {
+            let mut queue = worker.queue.lock().unwrap();
+            while queue.frames.is_empty() && !queue.closed {
+                queue = worker.ready.wait(queue).unwrap();
+            }
+            match queue.frames.pop_front() {
+                Some(frame) => frame,
+                None => return,
+            }
+        };
+        if let Err(e) = listener.recv(time, &EthernetPacket::new(&frame).unwrap()) {
+            debug!("Rx worker listener failed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use RxResult;
+    use pool::BufferPool;
+    use rx::RxListener;
+    use threads::ThreadConfig;
+
+    use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+
+    use std::sync::mpsc::{self, Sender};
+    use std::time::SystemTime;
+
+    use super::*;
+
+    /// A UDP datagram from port `src_port`, or a fragment of one without
+    /// ports if `fragment`.
+    fn udp_frame(src_port: u16, fragment: bool) -> Vec<u8> {
+        let mut frame = vec![0; 14 + 20 + 8];
+        MutableEthernetPacket::new(&mut frame).unwrap().set_ethertype(EtherTypes::Ipv4);
+        frame[14] = 0x45;
+        frame[14 + 9] = 17;
+        frame[14 + 12..14 + 20].copy_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        if fragment {
+            frame[14 + 7] = 1;
+        } else {
+            frame[34] = (src_port >> 8) as u8;
+            frame[35] = src_port as u8;
+        }
+        frame
+    }
+
+    #[test]
+    fn hash_flows() {
+        assert_eq!(flow_hash(&udp_frame(1000, false)), flow_hash(&udp_frame(1000, false)));
+        assert!(flow_hash(&udp_frame(1000, false)) != flow_hash(&udp_frame(1001, false)));
+        // Fragments without ports hash like the first one of their packet
+        let mut first = udp_frame(0, false);
+        first[14 + 6] = 0x20;
+        assert_eq!(flow_hash(&first), flow_hash(&udp_frame(1000, true)));
+        let mut arp = vec![0; 60];
+        MutableEthernetPacket::new(&mut arp).unwrap().set_ethertype(EtherTypes::Arp);
+        assert_eq!(flow_hash(&arp), 0);
+    }
+
+    struct Recorder(usize, Sender<(usize, u8, u8)>);
+
+    impl RxListener for Recorder {
+        fn recv(&mut self, _time: SystemTime, packet: &EthernetPacket) -> RxResult {
+            // The frames end with their flow and sequence number
+            let frame = packet.packet();
+            let len = frame.len();
+            self.1.send((self.0, frame[len - 2], frame[len - 1])).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flows_keep_order() {
+        let (tx, rx) = mpsc::channel();
+        let mut fan_out = FanOut::spawn(4,
+                                        |_| ThreadConfig::default(),
+                                        |i| Recorder(i, tx.clone()),
+                                        BufferPool::default());
+        let ports = [1000, 2000, 3000, 4000, 5000];
+        for seq in 0..20 {
+            for (flow, &port) in ports.iter().enumerate() {
+                let mut frame = udp_frame(port, false);
+                frame.extend_from_slice(&[flow as u8, seq]);
+                fan_out.recv(SystemTime::now(), &EthernetPacket::new(&frame).unwrap()).unwrap();
+            }
+        }
+        let stats = fan_out.stats();
+        assert_eq!(stats.lock().unwrap().queued, 100);
+        // The workers quit and drop their senders once the queues are empty
+        drop(fan_out);
+        drop(tx);
+        let received = rx.iter().collect::<Vec<_>>();
+        assert_eq!(received.len(), 100);
+        for flow in 0..ports.len() as u8 {
+            let frames = received.iter().filter(|&&(_, f, _)| f == flow).collect::<Vec<_>>();
+            assert!(frames.iter().all(|&&(worker, _, _)| worker == frames[0].0));
+            let seqs = frames.iter().map(|&&(_, _, seq)| seq).collect::<Vec<_>>();
+            assert_eq!(seqs, (0..20).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,442 @@
+//! Packet filtering. IPv4 packets pass through hook points on their way
+//! through the stack, modeled after netfilter:
+//!
+//! ```text
+//!  rx -> Prerouting -+-> Input -> local listeners
+//!                    |
+//!                    +-> Forward -+-> Postrouting -> tx
+//!                                 |
+//!  local tx -> Output ------------+
+//! ```
+//!
+//! Each hook has a chain of rules, tried in order until one of them accepts
+//! or drops the packet. Packets no rule decides on get the policy of the
+//! chain, accepting by default. Rules either match on header fields, or call
+//! user code that may inspect and modify the packet.
+//!
+//! Connection state can only be matched in the `Forward` hook, since only
+//! forwarded traffic is tracked, see `NetworkStack::conntrack`.
+
+use Interface;
+use conntrack::ConnState;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The points in the stack where packets are filtered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hook {
+    /// All received packets, before deciding if they are for this host
+    Prerouting,
+    /// Received packets about to be delivered to local listeners
+    Input,
+    /// Received packets about to be forwarded
+    Forward,
+    /// Packets sent by this host
+    Output,
+    /// All packets about to be sent, sent by this host or forwarded
+    Postrouting,
+}
+
+/// What to do with a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+    /// Let the next rule in the chain decide
+    Continue,
+}
+
+/// What is known about a packet at a hook, apart from its content.
+#[derive(Debug, Clone, Copy)]
+pub struct PacketInfo<'a> {
+    /// The interface the packet arrived on
+    pub in_interface: Option<&'a Interface>,
+    /// The interface the packet is sent out on
+    pub out_interface: Option<&'a Interface>,
+    /// The connection tracking state of the packet, only known in the
+    /// `Forward` hook
+    pub state: Option<ConnState>,
+}
+
+/// User code called from a rule. Implemented for all closures with the same
+/// signature as `filter`.
+pub trait FirewallHook: Send + Sync {
+    /// Decides on the IPv4 packet in `packet`. The packet may be modified,
+    /// its checksums are then up to the hook to update.
+    fn filter(&self, hook: Hook, info: &PacketInfo, packet: &mut Vec<u8>) -> Verdict;
+}
+
+impl<F> FirewallHook for F
+    where F: Fn(Hook, &PacketInfo, &mut Vec<u8>) -> Verdict + Send + Sync
+{
+    fn filter(&self, hook: Hook, info: &PacketInfo, packet: &mut Vec<u8>) -> Verdict {
+        self(hook, info, packet)
+    }
+}
+
+/// Header fields a rule matches on. Fields that are `None` match anything.
+/// Ports only match UDP and TCP packets, or their first fragment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Match {
+    pub protocol: Option<IpNextHeaderProtocol>,
+    pub src: Option<Ipv4Network>,
+    pub dst: Option<Ipv4Network>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub in_interface: Option<Interface>,
+    pub out_interface: Option<Interface>,
+    pub state: Option<ConnState>,
+}
+
+impl Match {
+    /// Matches all packets.
+    pub fn any() -> Match {
+        Match::default()
+    }
+
+    fn matches(&self, info: &PacketInfo, packet: &[u8]) -> bool {
+        let ip_pkg = match Ipv4Packet::new(packet) {
+            Some(ip_pkg) => ip_pkg,
+            None => return false,
+        };
+        let protocol = ip_pkg.get_next_level_protocol();
+        if self.protocol.map_or(false, |p| p != protocol) ||
+           self.src.map_or(false, |net| !net.contains(ip_pkg.get_source())) ||
+           self.dst.map_or(false, |net| !net.contains(ip_pkg.get_destination())) ||
+           !interface_matches(&self.in_interface, info.in_interface) ||
+           !interface_matches(&self.out_interface, info.out_interface) ||
+           self.state.map_or(false, |state| info.state != Some(state)) {
+            return false;
+        }
+        if self.src_port.is_none() && self.dst_port.is_none() {
+            return true;
+        }
+        let header_len = ip_pkg.get_header_length() as usize * 4;
+        let has_ports = protocol == IpNextHeaderProtocols::Udp ||
+                        protocol == IpNextHeaderProtocols::Tcp;
+        if !has_ports || ip_pkg.get_fragment_offset() != 0 || packet.len() < header_len + 4 {
+            return false;
+        }
+        let ports = &packet[header_len..];
+        let src_port = ((ports[0] as u16) << 8) | ports[1] as u16;
+        let dst_port = ((ports[2] as u16) << 8) | ports[3] as u16;
+        self.src_port.map_or(true, |port| port == src_port) &&
+        self.dst_port.map_or(true, |port| port == dst_port)
+    }
+}
+
+fn interface_matches(wanted: &Option<Interface>, actual: Option<&Interface>) -> bool {
+    match (wanted.as_ref(), actual) {
+        (Some(wanted), Some(actual)) => wanted == actual,
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+/// What a rule does with the packets it matches.
+#[derive(Clone)]
+pub enum Target {
+    Accept,
+    Drop,
+    /// Passes the packet to user code, which gives the verdict
+    Hook(Arc<FirewallHook>),
+}
+
+impl fmt::Debug for Target {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Accept => fmt.write_str("Accept"),
+            Target::Drop => fmt.write_str("Drop"),
+            Target::Hook(..) => fmt.write_str("Hook"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub matches: Match,
+    pub target: Target,
+}
+
+impl Rule {
+    pub fn new(matches: Match, target: Target) -> Rule {
+        Rule {
+            matches: matches,
+            target: target,
+        }
+    }
+
+    /// A rule passing all packets to `hook`.
+    pub fn hook<H: FirewallHook + 'static>(hook: H) -> Rule {
+        Rule::new(Match::any(), Target::Hook(Arc::new(hook)))
+    }
+}
+
+/// Identifies a rule added to a `Firewall`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuleId(usize);
+
+/// The packets and bytes matched by a rule.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuleCounters {
+    pub packets: usize,
+    pub bytes: usize,
+}
+
+struct ChainRule {
+    id: RuleId,
+    rule: Rule,
+    packets: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl ChainRule {
+    fn counters(&self) -> RuleCounters {
+        RuleCounters {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Chain {
+    rules: Vec<ChainRule>,
+    policy: Verdict,
+}
+
+impl Default for Chain {
+    fn default() -> Chain {
+        Chain {
+            rules: Vec::new(),
+            policy: Verdict::Accept,
+        }
+    }
+}
+
+/// The rule chains of all hooks of a stack. See the module documentation.
+#[derive(Default)]
+pub struct Firewall {
+    chains: RwLock<HashMap<Hook, Chain>>,
+    next_id: AtomicUsize,
+}
+
+impl Firewall {
+    pub fn new() -> Firewall {
+        Firewall::default()
+    }
+
+    /// Appends `rule` to the chain of `hook`.
+    pub fn add_rule(&self, hook: Hook, rule: Rule) -> RuleId {
+        let id = RuleId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut chains = self.chains.write().unwrap();
+        chains.entry(hook).or_insert_with(Chain::default).rules.push(ChainRule {
+            id: id,
+            rule: rule,
+            packets: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+        });
+        id
+    }
+
+    /// Removes a rule, returning it if it existed.
+    pub fn remove_rule(&self, id: RuleId) -> Option<Rule> {
+        let mut chains = self.chains.write().unwrap();
+        for chain in chains.values_mut() {
+            if let Some(index) = chain.rules.iter().position(|r| r.id == id) {
+                return Some(chain.rules.remove(index).rule);
+            }
+        }
+        None
+    }
+
+    /// Removes all rules of `hook` and resets its policy.
+    pub fn flush(&self, hook: Hook) {
+        self.chains.write().unwrap().remove(&hook);
+    }
+
+    /// Returns the rules of `hook` in order, with their counters.
+    pub fn rules(&self, hook: Hook) -> Vec<(RuleId, Rule, RuleCounters)> {
+        let chains = self.chains.read().unwrap();
+        match chains.get(&hook) {
+            Some(chain) => {
+                chain.rules.iter().map(|r| (r.id, r.rule.clone(), r.counters())).collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn counters(&self, id: RuleId) -> Option<RuleCounters> {
+        let chains = self.chains.read().unwrap();
+        chains.values()
+            .flat_map(|chain| chain.rules.iter())
+            .find(|r| r.id == id)
+            .map(|r| r.counters())
+    }
+
+    /// Sets what happens to packets at `hook` that no rule decides on.
+    /// `Verdict::Continue` is the same as `Verdict::Accept`.
+    pub fn set_policy(&self, hook: Hook, policy: Verdict) {
+        let mut chains = self.chains.write().unwrap();
+        chains.entry(hook).or_insert_with(Chain::default).policy = policy;
+    }
+
+    pub fn policy(&self, hook: Hook) -> Verdict {
+        self.chains.read().unwrap().get(&hook).map_or(Verdict::Accept, |chain| chain.policy)
+    }
+
+    /// Returns true if there are no rules and all packets are accepted, so
+    /// filtering can be skipped completely.
+    pub fn is_empty(&self) -> bool {
+        self.chains
+            .read()
+            .unwrap()
+            .values()
+            .all(|chain| chain.rules.is_empty() && chain.policy != Verdict::Drop)
+    }
+
+    /// Runs the IPv4 packet in `packet` through the chain of `hook`.
+    /// Returns true if the packet is accepted, in which case it may have
+    /// been modified.
+    pub fn filter(&self, hook: Hook, info: &PacketInfo, packet: &mut Vec<u8>) -> bool {
+        let chains = self.chains.read().unwrap();
+        let chain = match chains.get(&hook) {
+            Some(chain) => chain,
+            None => return true,
+        };
+        for chain_rule in &chain.rules {
+            if !chain_rule.rule.matches.matches(info, packet) {
+                continue;
+            }
+            chain_rule.packets.fetch_add(1, Ordering::Relaxed);
+            chain_rule.bytes.fetch_add(packet.len(), Ordering::Relaxed);
+            let verdict = match chain_rule.rule.target {
+                Target::Accept => Verdict::Accept,
+                Target::Drop => Verdict::Drop,
+                Target::Hook(ref user_hook) => user_hook.filter(hook, info, packet),
+            };
+            match verdict {
+                Verdict::Accept => return true,
+                Verdict::Drop => return false,
+                Verdict::Continue => (),
+            }
+        }
+        chain.policy != Verdict::Drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Interface;
+    use conntrack::ConnState;
+
+    use ipnetwork::Ipv4Network;
+
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn udp_packet(src: Ipv4Addr, dst_port: u16) -> Vec<u8> {
+        let mut buffer = vec![0; 28];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(28);
+            ip_pkg.set_ttl(64);
+            ip_pkg.set_source(src);
+            ip_pkg.set_destination(Ipv4Addr::new(10, 0, 0, 1));
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        }
+        buffer[22] = (dst_port >> 8) as u8;
+        buffer[23] = dst_port as u8;
+        buffer
+    }
+
+    fn no_info() -> PacketInfo<'static> {
+        PacketInfo {
+            in_interface: None,
+            out_interface: None,
+            state: None,
+        }
+    }
+
+    #[test]
+    fn rules_in_order() {
+        let firewall = Firewall::new();
+        assert!(firewall.is_empty());
+        let accept_dns = firewall.add_rule(Hook::Input,
+                                           Rule::new(Match { dst_port: Some(53), ..Match::any() },
+                                                     Target::Accept));
+        let src = Ipv4Network::from_str("192.168.0.0/16").unwrap();
+        let drop_private = firewall.add_rule(Hook::Input,
+                                             Rule::new(Match { src: Some(src), ..Match::any() },
+                                                       Target::Drop));
+        assert!(!firewall.is_empty());
+
+        let private = Ipv4Addr::new(192, 168, 0, 1);
+        let public = Ipv4Addr::new(8, 8, 8, 8);
+        let info = no_info();
+        assert!(firewall.filter(Hook::Input, &info, &mut udp_packet(private, 53)));
+        assert!(!firewall.filter(Hook::Input, &info, &mut udp_packet(private, 80)));
+        assert!(firewall.filter(Hook::Input, &info, &mut udp_packet(public, 80)));
+        // Other hooks are not affected
+        assert!(firewall.filter(Hook::Output, &info, &mut udp_packet(private, 80)));
+
+        assert_eq!(firewall.counters(accept_dns), Some(RuleCounters { packets: 1, bytes: 28 }));
+        assert_eq!(firewall.counters(drop_private), Some(RuleCounters { packets: 1, bytes: 28 }));
+        assert!(firewall.remove_rule(drop_private).is_some());
+        assert_eq!(firewall.counters(drop_private), None);
+        assert_eq!(firewall.rules(Hook::Input).len(), 1);
+    }
+
+    #[test]
+    fn policy_and_state() {
+        let firewall = Firewall::new();
+        firewall.set_policy(Hook::Forward, Verdict::Drop);
+        firewall.add_rule(Hook::Forward,
+                          Rule::new(Match { state: Some(ConnState::Established), ..Match::any() },
+                                    Target::Accept));
+        let mut info = no_info();
+        let src = Ipv4Addr::new(8, 8, 8, 8);
+        assert!(!firewall.filter(Hook::Forward, &info, &mut udp_packet(src, 53)));
+        info.state = Some(ConnState::Established);
+        assert!(firewall.filter(Hook::Forward, &info, &mut udp_packet(src, 53)));
+        firewall.flush(Hook::Forward);
+        assert!(firewall.is_empty());
+    }
+
+    #[test]
+    fn hook_modifies() {
+        let firewall = Firewall::new();
+        let interface = Interface {
+            name: "eth0".to_owned(),
+            mac: MacAddr::new(0, 0, 0, 0, 0, 1),
+        };
+        let set_ttl = |_: Hook, _: &PacketInfo, packet: &mut Vec<u8>| {
+            packet[8] = 1;
+            Verdict::Continue
+        };
+        firewall.add_rule(Hook::Prerouting,
+                          Rule::new(Match { in_interface: Some(interface.clone()), ..Match::any() },
+                                    Target::Hook(Arc::new(set_ttl))));
+        let mut packet = udp_packet(Ipv4Addr::new(8, 8, 8, 8), 53);
+        assert!(firewall.filter(Hook::Prerouting, &no_info(), &mut packet));
+        assert_eq!(packet[8], 64);
+        let info = PacketInfo { in_interface: Some(&interface), ..no_info() };
+        assert!(firewall.filter(Hook::Prerouting, &info, &mut packet));
+        assert_eq!(packet[8], 1);
+    }
+}
@@ -0,0 +1,584 @@
+//! Flow export with IPFIX, RFC 7011.
+//!
+//! A `FlowExporter` makes a stack a flow probe. It watches the IPv4 packets
+//! every interface sends and receives, see `observe`, and keeps a
+//! `FlowRecord` per flow: packets of the same protocol between the same
+//! addresses and ports, in the same direction. Flows end once idle for
+//! `FlowConfig::idle_timeout`, and long lived ones are cut every
+//! `FlowConfig::active_timeout`. The records of ended flows are sent to a
+//! collector as IPFIX messages, from a UDP socket of the stack itself:
+//!
+//! ```rust,ignore
+//! let collector = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 9), flow::IPFIX_PORT);
+//! let local = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 0);
+//! let exporter = FlowExporter::start(stack.clone(), local, collector, FlowConfig::default())?;
+//! ```
+//!
+//! Every message carries the template of its records, so a collector
+//! started after the exporter understands the first message it gets. Icmp
+//! flows have no ports, their type and code are kept in the destination
+//! port as `type * 256 + code`, the way NetFlow does, and exported as
+//! `icmpTypeCodeIPv4`. Fragments other than the first carry no ports
+//! either, so they count to flows of their own. Frames with VLAN tags are
+//! not looked at.
+
+use {Interface, NetworkStack};
+use observe::{Observer, ObserverId};
+use pcap::Direction;
+use udp::UdpSocket;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The UDP port of IPFIX collectors.
+pub const IPFIX_PORT: u16 = 4739;
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+/// The identifier of the one template the records are sent with
+const TEMPLATE_ID: u16 = 256;
+const HEADER_LEN: usize = 16;
+const SET_HEADER_LEN: usize = 4;
+
+/// The information elements of a record, and their lengths.
+const FIELDS: [(u16, u16); 12] = [(8, 4), // sourceIPv4Address
+                                  (12, 4), // destinationIPv4Address
+                                  (4, 1), // protocolIdentifier
+                                  (7, 2), // sourceTransportPort
+                                  (11, 2), // destinationTransportPort
+                                  (32, 2), // icmpTypeCodeIPv4
+                                  (1, 8), // octetDeltaCount
+                                  (2, 8), // packetDeltaCount
+                                  (152, 8), // flowStartMilliseconds
+                                  (153, 8), // flowEndMilliseconds
+                                  (61, 1), // flowDirection
+                                  (136, 1)]; // flowEndReason
+const RECORD_LEN: usize = 49;
+const TEMPLATE_SET_LEN: usize = SET_HEADER_LEN + 4 + 4 * 12;
+
+/// The largest message sent, so the datagrams fit an Ethernet frame.
+pub const MAX_MESSAGE_LEN: usize = 1400;
+
+/// Records of one message, as many as fit `MAX_MESSAGE_LEN`.
+const RECORDS_PER_MESSAGE: usize = (MAX_MESSAGE_LEN - HEADER_LEN - TEMPLATE_SET_LEN -
+                                    SET_HEADER_LEN) / RECORD_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowConfig {
+    /// How long a flow lasts without packets
+    pub idle_timeout: Duration,
+    /// How long a flow lasts at most. A flow still going on after that is
+    /// exported, and continues as a new one
+    pub active_timeout: Duration,
+    /// The most flows kept at a time. Packets of new flows beyond these are
+    /// exported as flows of one packet each, ended for lack of resources
+    pub max_flows: usize,
+    /// How often a `FlowExporter` looks for ended flows and exports them
+    pub export_interval: Duration,
+    /// Sent in every message, tells the exporters of a collector apart
+    pub observation_domain: u32,
+}
+
+impl Default for FlowConfig {
+    fn default() -> FlowConfig {
+        FlowConfig {
+            idle_timeout: Duration::from_secs(15),
+            active_timeout: Duration::from_secs(60),
+            max_flows: 65536,
+            export_interval: Duration::from_secs(1),
+            observation_domain: 0,
+        }
+    }
+}
+
+/// What tells the packets of one flow from those of others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    /// Zero for protocols without ports
+    pub src_port: u16,
+    /// Zero for protocols without ports, `type * 256 + code` for Icmp
+    pub dst_port: u16,
+    pub direction: Direction,
+}
+
+impl FlowKey {
+    /// Returns the key of the flow of `packet`.
+    pub fn of(direction: Direction, packet: &Ipv4Packet) -> FlowKey {
+        let protocol = packet.get_next_level_protocol();
+        let payload = packet.payload();
+        let first_fragment = packet.get_fragment_offset() == 0;
+        let (src_port, dst_port) = if !first_fragment {
+            (0, 0)
+        } else if (protocol == IpNextHeaderProtocols::Udp ||
+                    protocol == IpNextHeaderProtocols::Tcp) && payload.len() >= 4 {
+            ((payload[0] as u16) << 8 | payload[1] as u16,
+             (payload[2] as u16) << 8 | payload[3] as u16)
+        } else if protocol == IpNextHeaderProtocols::Icmp && payload.len() >= 2 {
+            (0, (payload[0] as u16) << 8 | payload[1] as u16)
+        } else {
+            (0, 0)
+        };
+        FlowKey {
+            src: packet.get_source(),
+            dst: packet.get_destination(),
+            protocol: protocol.0,
+            src_port: src_port,
+            dst_port: dst_port,
+            direction: direction,
+        }
+    }
+}
+
+/// Why a flow was exported, the values of IPFIX `flowEndReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    IdleTimeout = 1,
+    ActiveTimeout = 2,
+    /// The exporter stopped while the flow was going on
+    ForcedEnd = 4,
+    LackOfResources = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowRecord {
+    pub key: FlowKey,
+    pub packets: u64,
+    /// The IPv4 packets, headers included
+    pub bytes: u64,
+    /// When the first packet was seen
+    pub start: SystemTime,
+    /// When the last packet was seen
+    pub end: SystemTime,
+    /// Why the flow ended. `IdleTimeout` until it did
+    pub reason: EndReason,
+}
+
+/// Counters of a `FlowTable`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    /// Flows going on
+    pub active: usize,
+    /// Records of ended flows taken from the table
+    pub exported: u64,
+    /// Packets of new flows that fit neither the table nor the records
+    /// waiting to be taken
+    pub lost: u64,
+}
+
+/// The flows of the packets handed to it, see `record`.
+pub struct FlowTable {
+    config: FlowConfig,
+    flows: HashMap<FlowKey, FlowRecord>,
+    /// Records of flows that ended early, not taken yet
+    ended: Vec<FlowRecord>,
+    exported: u64,
+    lost: u64,
+}
+
+impl FlowTable {
+    pub fn new(config: FlowConfig) -> FlowTable {
+        FlowTable {
+            config: config,
+            flows: HashMap::new(),
+            ended: Vec::new(),
+            exported: 0,
+            lost: 0,
+        }
+    }
+
+    /// Counts `packet`, seen going in `direction` at `time`, to its flow.
+    pub fn record(&mut self, direction: Direction, time: SystemTime, packet: &Ipv4Packet) {
+        let key = FlowKey::of(direction, packet);
+        let bytes = packet.get_total_length() as u64;
+        if let Some(flow) = self.flows.get_mut(&key) {
+            flow.packets += 1;
+            flow.bytes += bytes;
+            flow.end = time;
+            return;
+        }
+        let mut flow = FlowRecord {
+            key: key,
+            packets: 1,
+            bytes: bytes,
+            start: time,
+            end: time,
+            reason: EndReason::IdleTimeout,
+        };
+        if self.flows.len() < self.config.max_flows {
+            self.flows.insert(key, flow);
+        } else if self.ended.len() < self.config.max_flows {
+            flow.reason = EndReason::LackOfResources;
+            self.ended.push(flow);
+        } else {
+            self.lost += 1;
+        }
+    }
+
+    /// Returns the records of the flows that ended by `now`, and forgets
+    /// them.
+    pub fn expire(&mut self, now: SystemTime) -> Vec<FlowRecord> {
+        let config = self.config;
+        let since = |time: SystemTime| now.duration_since(time).unwrap_or(Duration::from_secs(0));
+        let ended = self.flows
+            .values()
+            .filter_map(|flow| if since(flow.end) >= config.idle_timeout {
+                Some((flow.key, EndReason::IdleTimeout))
+            } else if since(flow.start) >= config.active_timeout {
+                Some((flow.key, EndReason::ActiveTimeout))
+            } else {
+                None
+            })
+            .collect::<Vec<_>>();
+        let mut records = self.ended.drain(..).collect::<Vec<_>>();
+        for (key, reason) in ended {
+            let mut flow = self.flows.remove(&key).unwrap();
+            flow.reason = reason;
+            records.push(flow);
+        }
+        self.exported += records.len() as u64;
+        records
+    }
+
+    /// Returns the records of all flows, ending the ones going on, and
+    /// forgets them.
+    pub fn flush(&mut self) -> Vec<FlowRecord> {
+        let mut records = self.ended.drain(..).collect::<Vec<_>>();
+        for (_, mut flow) in self.flows.drain() {
+            flow.reason = EndReason::ForcedEnd;
+            records.push(flow);
+        }
+        self.exported += records.len() as u64;
+        records
+    }
+
+    pub fn stats(&self) -> FlowStats {
+        FlowStats {
+            active: self.flows.len(),
+            exported: self.exported,
+            lost: self.lost,
+        }
+    }
+}
+
+/// Encodes `FlowRecord`s as IPFIX messages, numbering them as one
+/// exporter sending them in order.
+pub struct IpfixEncoder {
+    observation_domain: u32,
+    /// Records encoded so far, as the sequence number counts them
+    sequence: u32,
+}
+
+impl IpfixEncoder {
+    pub fn new(observation_domain: u32) -> IpfixEncoder {
+        IpfixEncoder {
+            observation_domain: observation_domain,
+            sequence: 0,
+        }
+    }
+
+    /// Returns the messages of `records`, as many as they need to have
+    /// none longer than `MAX_MESSAGE_LEN`, exported at `time`. Returns none
+    /// if there are no records.
+    pub fn encode(&mut self, time: SystemTime, records: &[FlowRecord]) -> Vec<Vec<u8>> {
+        let export_time = millis(time) / 1000;
+        records.chunks(RECORDS_PER_MESSAGE)
+            .map(|records| {
+                let len = HEADER_LEN + TEMPLATE_SET_LEN + SET_HEADER_LEN +
+                          records.len() * RECORD_LEN;
+                let mut message = Vec::with_capacity(len);
+                push_u16(&mut message, VERSION);
+                push_u16(&mut message, len as u16);
+                push_u32(&mut message, export_time as u32);
+                push_u32(&mut message, self.sequence);
+                push_u32(&mut message, self.observation_domain);
+
+                push_u16(&mut message, TEMPLATE_SET_ID);
+                push_u16(&mut message, TEMPLATE_SET_LEN as u16);
+                push_u16(&mut message, TEMPLATE_ID);
+                push_u16(&mut message, FIELDS.len() as u16);
+                for &(element, element_len) in &FIELDS {
+                    push_u16(&mut message, element);
+                    push_u16(&mut message, element_len);
+                }
+
+                push_u16(&mut message, TEMPLATE_ID);
+                push_u16(&mut message, (SET_HEADER_LEN + records.len() * RECORD_LEN) as u16);
+                for record in records {
+                    push_record(&mut message, record);
+                }
+                self.sequence = self.sequence.wrapping_add(records.len() as u32);
+                message
+            })
+            .collect()
+    }
+}
+
+fn push_record(message: &mut Vec<u8>, record: &FlowRecord) {
+    let key = &record.key;
+    message.extend_from_slice(&key.src.octets());
+    message.extend_from_slice(&key.dst.octets());
+    message.push(key.protocol);
+    if key.protocol == IpNextHeaderProtocols::Icmp.0 {
+        push_u16(message, 0);
+        push_u16(message, 0);
+        push_u16(message, key.dst_port);
+    } else {
+        push_u16(message, key.src_port);
+        push_u16(message, key.dst_port);
+        push_u16(message, 0);
+    }
+    push_u64(message, record.bytes);
+    push_u64(message, record.packets);
+    push_u64(message, millis(record.start));
+    push_u64(message, millis(record.end));
+    message.push(match key.direction {
+        Direction::Inbound => 0,
+        Direction::Outbound => 1,
+    });
+    message.push(record.reason as u8);
+}
+
+fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000
+}
+
+fn push_u16(message: &mut Vec<u8>, value: u16) {
+    message.push((value >> 8) as u8);
+    message.push(value as u8);
+}
+
+fn push_u32(message: &mut Vec<u8>, value: u32) {
+    push_u16(message, (value >> 16) as u16);
+    push_u16(message, value as u16);
+}
+
+fn push_u64(message: &mut Vec<u8>, value: u64) {
+    push_u32(message, (value >> 32) as u32);
+    push_u32(message, value as u32);
+}
+
+/// An observer recording the IPv4 packets of the frames of an interface in
+/// a table.
+struct FlowMeter {
+    table: Arc<Mutex<FlowTable>>,
+}
+
+impl Observer for FlowMeter {
+    fn observe(&self,
+               direction: Direction,
+               _interface: &Interface,
+               time: SystemTime,
+               frame: &EthernetPacket) {
+        if frame.get_ethertype() != EtherTypes::Ipv4 {
+            return;
+        }
+        if let Some(packet) = Ipv4Packet::new(frame.payload()) {
+            self.table.lock().unwrap().record(direction, time, &packet);
+        }
+    }
+}
+
+/// A flow probe on all interfaces of a stack, exporting from a thread of
+/// its own. See the module documentation. Stops when dropped.
+pub struct FlowExporter {
+    stack: Arc<Mutex<NetworkStack>>,
+    table: Arc<Mutex<FlowTable>>,
+    observers: Vec<(Interface, ObserverId)>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FlowExporter {
+    /// Starts watching the interfaces `stack` has now, and exporting to
+    /// `collector` from a UDP socket bound to `local`.
+    pub fn start(stack: Arc<Mutex<NetworkStack>>,
+                 local: SocketAddrV4,
+                 collector: SocketAddrV4,
+                 config: FlowConfig)
+                 -> io::Result<FlowExporter> {
+        let mut socket = UdpSocket::bind(stack.clone(), local)?;
+        let table = Arc::new(Mutex::new(FlowTable::new(config)));
+        let mut observers = Vec::new();
+        {
+            let mut stack = stack.lock().unwrap();
+            for interface in stack.interfaces() {
+                let meter = FlowMeter { table: table.clone() };
+                let id = stack.interface(&interface)?.add_observer(meter);
+                observers.push((interface, id));
+            }
+        }
+        let (stop, stopped) = mpsc::channel();
+        let thread_table = table.clone();
+        let thread = thread::spawn(move || {
+            let mut encoder = IpfixEncoder::new(config.observation_domain);
+            loop {
+                let (records, stopping) = match stopped.recv_timeout(config.export_interval) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        (thread_table.lock().unwrap().expire(SystemTime::now()), false)
+                    }
+                    _ => (thread_table.lock().unwrap().flush(), true),
+                };
+                for message in encoder.encode(SystemTime::now(), &records) {
+                    if let Err(e) = socket.send_to(&message, collector) {
+                        warn!("Flow: Unable to export to {}: {}", collector, e);
+                    }
+                }
+                if stopping {
+                    break;
+                }
+            }
+        });
+        Ok(FlowExporter {
+            stack: stack,
+            table: table,
+            observers: observers,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn stats(&self) -> FlowStats {
+        self.table.lock().unwrap().stats()
+    }
+
+    /// Stops watching the interfaces, exports the flows going on and waits
+    /// for the exporting thread to quit.
+    pub fn stop(&mut self) {
+        if !self.observers.is_empty() {
+            let mut stack = self.stack.lock().unwrap();
+            for (interface, id) in self.observers.drain(..) {
+                if let Ok(stack_interface) = stack.interface(&interface) {
+                    stack_interface.remove_observer(id);
+                }
+            }
+        }
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for FlowExporter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pcap::Direction;
+
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn udp_packet(src_port: u16, len: usize) -> Vec<u8> {
+        let mut buffer = vec![0; len];
+        {
+            let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+            packet.set_version(4);
+            packet.set_header_length(5);
+            packet.set_total_length(len as u16);
+            packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            packet.set_source(Ipv4Addr::new(10, 0, 0, 1));
+            packet.set_destination(Ipv4Addr::new(10, 0, 0, 2));
+        }
+        buffer[20] = (src_port >> 8) as u8;
+        buffer[21] = src_port as u8;
+        buffer[23] = 53;
+        buffer
+    }
+
+    #[test]
+    fn timeouts() {
+        let config = FlowConfig {
+            idle_timeout: Duration::from_secs(10),
+            active_timeout: Duration::from_secs(30),
+            max_flows: 2,
+            ..FlowConfig::default()
+        };
+        let mut table = FlowTable::new(config);
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        for (i, &port) in [1000, 1000, 1001, 1002].iter().enumerate() {
+            let packet = udp_packet(port, 28);
+            table.record(Direction::Inbound, at(i as u64), &Ipv4Packet::new(&packet).unwrap());
+        }
+        // Port 1002 did not fit
+        let records = table.expire(at(4));
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].key.src_port, records[0].reason),
+                   (1002, EndReason::LackOfResources));
+
+        let packet = udp_packet(1000, 100);
+        for secs in 5..32 {
+            table.record(Direction::Inbound, at(secs), &Ipv4Packet::new(&packet).unwrap());
+        }
+        let records = table.expire(at(32));
+        assert_eq!(records.len(), 2);
+        for record in records {
+            match record.key.src_port {
+                1000 => {
+                    assert_eq!(record.reason, EndReason::ActiveTimeout);
+                    assert_eq!((record.packets, record.bytes), (2 + 27, 2 * 28 + 27 * 100));
+                    assert_eq!((record.start, record.end), (at(0), at(31)));
+                }
+                1001 => assert_eq!(record.reason, EndReason::IdleTimeout),
+                port => panic!("Unexpected flow from port {}", port),
+            }
+        }
+        assert_eq!(table.stats(),
+                   FlowStats {
+                       active: 0,
+                       exported: 3,
+                       lost: 0,
+                   });
+    }
+
+    #[test]
+    fn messages() {
+        let packet = udp_packet(1000, 28);
+        let record = FlowRecord {
+            key: FlowKey::of(Direction::Outbound, &Ipv4Packet::new(&packet).unwrap()),
+            packets: 3,
+            bytes: 84,
+            start: UNIX_EPOCH + Duration::from_millis(1500),
+            end: UNIX_EPOCH + Duration::from_millis(2500),
+            reason: EndReason::ForcedEnd,
+        };
+        let mut encoder = IpfixEncoder::new(7);
+        let records = vec![record; RECORDS_PER_MESSAGE + 1];
+        let messages = encoder.encode(UNIX_EPOCH + Duration::from_secs(3), &records);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].len() <= MAX_MESSAGE_LEN);
+        let second = &messages[1];
+        assert_eq!(second.len(), HEADER_LEN + TEMPLATE_SET_LEN + SET_HEADER_LEN + RECORD_LEN);
+        assert_eq!(&second[..16],
+                   &[0, 10, 0, second.len() as u8, 0, 0, 0, 3, 0, 0, 0, RECORDS_PER_MESSAGE as u8,
+                     0, 0, 0, 7]);
+        let record = &second[second.len() - RECORD_LEN..];
+        assert_eq!(&record[..15], &[10, 0, 0, 1, 10, 0, 0, 2, 17, 0x03, 0xe8, 0, 53, 0, 0]);
+        assert_eq!(&record[15..23], &[0, 0, 0, 0, 0, 0, 0, 84]);
+        assert_eq!(&record[31..39], &[0, 0, 0, 0, 0, 0, 0x05, 0xdc]);
+        assert_eq!(&record[47..], &[1, 4]);
+        assert!(encoder.encode(UNIX_EPOCH, &[]).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! Entry points for fuzzing the parsers of received frames.
+//!
+//! Every function of this module hands `data` to a stack as if it arrived on
+//! an interface, at the layer it is named after, and returns what the stack
+//! made of it. No datalink is involved, so a cargo-fuzz target is one line:
+//!
+//! ```rust,ignore
+//! fuzz_target!(|data: &[u8]| {
+//!     let _ = rips::fuzz::ipv4(data);
+//! });
+//! ```
+//!
+//! The stack is a `FuzzTarget`, created once per thread and kept between
+//! inputs, so state such as fragments under reassembly and the Arp table
+//! carries over from one input to the next, as it does on a real interface.
+//! Its interface is promiscuous and has `FuzzTarget::address` in a /24, with
+//! a UDP listener on `UDP_PORT` and an Icmp listener for echo requests. What it
+//! sends in response is discarded. For `arp`, `ipv4`, `udp` and `icmp` the
+//! headers below the data are built here, valid and addressed to the
+//! interface, so the inputs get to the parser they are meant for. For the
+//! same reason the target verifies no checksums, see `RxChecksums`, a
+//! fuzzer would hardly ever get one right.
+
+use {Interface, NetworkStack, RxResult};
+use checksum::{self, RxChecksums};
+use datalink::Datalink;
+use icmp::IcmpListener;
+use rx;
+use udp::UdpCallbackListener;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The port the UDP listener of a `FuzzTarget` listens on.
+pub static UDP_PORT: u16 = 7;
+
+/// The address the packets built by a `FuzzTarget` come from.
+fn peer_address() -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 0, 2)
+}
+
+/// A link discarding everything sent on it and never receiving anything.
+struct NullDatalink;
+
+impl Datalink for NullDatalink {
+    fn mac(&self) -> MacAddr {
+        MacAddr::new(2, 0, 0, 0, 0xf2, 1)
+    }
+
+    fn send(&self, _frame: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        thread::sleep(Duration::from_millis(rx::POLL_INTERVAL_MS));
+        Err(io::Error::new(io::ErrorKind::TimedOut, "Nothing is ever received"))
+    }
+}
+
+struct NullIcmpListener;
+
+impl IcmpListener for NullIcmpListener {
+    fn recv(&mut self, _time: SystemTime, _packet: &Ipv4Packet) {}
+}
+
+/// A polled stack with one interface taking inputs. See the module
+/// documentation.
+pub struct FuzzTarget {
+    stack: NetworkStack,
+    interface: Interface,
+}
+
+impl FuzzTarget {
+    pub fn new() -> FuzzTarget {
+        let mut stack = NetworkStack::builder()
+            .polled(true)
+            .rx_checksums(RxChecksums::none())
+            .build()
+            .unwrap();
+        let interface = stack.add_datalink("fuzz0", Arc::new(NullDatalink))
+            .expect("Not able to add the interface of a fuzz target");
+        let address = Self::address();
+        stack.add_ipv4(&interface, Ipv4Network::new(address, 24).unwrap()).unwrap();
+        stack.udp_listen(SocketAddrV4::new(address, UDP_PORT), UdpCallbackListener::new(|_| ()))
+            .unwrap();
+        {
+            let stack_interface = stack.interface(&interface).unwrap();
+            stack_interface.set_promiscuous(true);
+            stack_interface.icmp_listen(address, IcmpTypes::EchoRequest, NullIcmpListener)
+                .unwrap();
+        }
+        FuzzTarget {
+            stack: stack,
+            interface: interface,
+        }
+    }
+
+    /// The address of the interface.
+    pub fn address() -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, 1)
+    }
+
+    pub fn interface(&self) -> &Interface {
+        &self.interface
+    }
+
+    /// Handles `data` as a whole Ethernet frame.
+    pub fn ethernet(&mut self, data: &[u8]) -> RxResult {
+        let stack_interface = self.stack.interface(&self.interface).unwrap();
+        stack_interface.inject(SystemTime::now(), data)
+    }
+
+    /// Handles `data` as the payload of an Ethernet frame of type `ether_type`
+    /// to the interface.
+    pub fn ethernet_payload(&mut self, ether_type: EtherType, data: &[u8]) -> RxResult {
+        let mut frame = Vec::with_capacity(14 + data.len());
+        frame.extend_from_slice(&mac_octets(self.interface.mac));
+        frame.extend_from_slice(&[2, 0, 0, 0, 0, 2]);
+        frame.extend_from_slice(&[(ether_type.0 >> 8) as u8, ether_type.0 as u8]);
+        frame.extend_from_slice(data);
+        self.ethernet(&frame)
+    }
+
+    /// Handles `data` as an Arp packet.
+    pub fn arp(&mut self, data: &[u8]) -> RxResult {
+        self.ethernet_payload(EtherTypes::Arp, data)
+    }
+
+    /// Handles `data` as an IPv4 packet, header included.
+    pub fn ipv4(&mut self, data: &[u8]) -> RxResult {
+        self.ethernet_payload(EtherTypes::Ipv4, data)
+    }
+
+    /// Handles `data` as the payload of an unfragmented IPv4 packet of
+    /// `protocol` to the interface. `data` is cut to the longest payload an
+    /// IPv4 packet can have.
+    pub fn ipv4_payload(&mut self, protocol: IpNextHeaderProtocol, data: &[u8]) -> RxResult {
+        let data = &data[..cmp::min(data.len(), 0xffff - 20)];
+        let total_length = 20 + data.len();
+        let mut packet = vec![0x45, 0,
+                              (total_length >> 8) as u8, total_length as u8,
+                              0, 0, 0, 0,
+                              64, protocol.0, 0, 0];
+        packet.extend_from_slice(&peer_address().octets());
+        packet.extend_from_slice(&Self::address().octets());
+        let csum = checksum::checksum(&packet);
+        packet[10] = (csum >> 8) as u8;
+        packet[11] = csum as u8;
+        packet.extend_from_slice(data);
+        self.ipv4(&packet)
+    }
+
+    /// Handles `data` as a UDP datagram, header included.
+    pub fn udp(&mut self, data: &[u8]) -> RxResult {
+        self.ipv4_payload(IpNextHeaderProtocols::Udp, data)
+    }
+
+    /// Handles `data` as an Icmp message, header included.
+    pub fn icmp(&mut self, data: &[u8]) -> RxResult {
+        self.ipv4_payload(IpNextHeaderProtocols::Icmp, data)
+    }
+}
+
+impl Default for FuzzTarget {
+    fn default() -> FuzzTarget {
+        FuzzTarget::new()
+    }
+}
+
+fn mac_octets(mac: MacAddr) -> [u8; 6] {
+    let MacAddr(a, b, c, d, e, f) = mac;
+    [a, b, c, d, e, f]
+}
+
+thread_local! {
+    static TARGET: RefCell<FuzzTarget> = RefCell::new(FuzzTarget::new());
+}
+
+/// Handles `data` as a whole Ethernet frame, see `FuzzTarget::ethernet`.
+pub fn ethernet(data: &[u8]) -> RxResult {
+    TARGET.with(|target| target.borrow_mut().ethernet(data))
+}
+
+/// Handles `data` as an Arp packet, see `FuzzTarget::arp`.
+pub fn arp(data: &[u8]) -> RxResult {
+    TARGET.with(|target| target.borrow_mut().arp(data))
+}
+
+/// Handles `data` as an IPv4 packet, see `FuzzTarget::ipv4`.
+pub fn ipv4(data: &[u8]) -> RxResult {
+    TARGET.with(|target| target.borrow_mut().ipv4(data))
+}
+
+/// Handles `data` as a UDP datagram, see `FuzzTarget::udp`.
+pub fn udp(data: &[u8]) -> RxResult {
+    TARGET.with(|target| target.borrow_mut().udp(data))
+}
+
+/// Handles `data` as an Icmp message, see `FuzzTarget::icmp`.
+pub fn icmp(data: &[u8]) -> RxResult {
+    TARGET.with(|target| target.borrow_mut().icmp(data))
+}
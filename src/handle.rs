@@ -0,0 +1,285 @@
+//! A cloneable handle to a `NetworkStack` shared between threads.
+//!
+//! Most methods of `NetworkStack` take `&mut self`, so a stack used from
+//! several threads has to be behind a `Mutex`. A `StackHandle` is such a
+//! shared stack, with methods creating sockets and tx-objects that hold the
+//! lock as briefly as possible. The tx-objects are routed with the stack
+//! locked, and then created with only the locks of the interface they send
+//! on, see `TxRoute`. In particular the stack is not locked while waiting
+//! for Arp replies, as `NetworkStack::ipv4_tx` does, so a neighbour that is
+//! slow to answer does not stall every other thread using the stack. Arp
+//! replies are waited for at most `RESOLVE_TIMEOUT_MS`, or the timeout set
+//! with `StackHandle::set_resolve_timeout`.
+//!
+//! Tx-objects stop working whenever something in the stack changes, see the
+//! crate documentation. A `RefreshingTx` recreates its tx-object when that
+//! happens instead of failing with `TxError::InvalidTx`.
+
+use {DatalinkTx, NetworkStack, StackError, StackResult, TxError, TxResult, TxSent};
+use stack::TxRoute;
+use ethernet::EthernetTxImpl;
+use icmp::IcmpTx;
+use dns::{self, ToStackAddr};
+use ipv4::Ipv4TxImpl;
+use udp::{UdpSender, UdpSocket, UdpTx};
+
+use pnet::util::MacAddr;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long tx-objects wait for the Arp reply of their next hop before
+/// failing with an `io::Error` of kind `TimedOut`, unless set otherwise.
+pub static RESOLVE_TIMEOUT_MS: u64 = 3000;
+
+/// The tx-object `NetworkStack::ipv4_tx` returns.
+pub type StackIpv4Tx = Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>;
+
+/// A `NetworkStack` shared between threads. Clones refer to the same stack.
+#[derive(Clone)]
+pub struct StackHandle {
+    stack: Arc<Mutex<NetworkStack>>,
+    resolve_timeout: Duration,
+}
+
+impl StackHandle {
+    pub fn new(stack: NetworkStack) -> StackHandle {
+        Self::from_shared(Arc::new(Mutex::new(stack)))
+    }
+
+    /// Creates a handle to a stack that is already shared as `stack`.
+    pub fn from_shared(stack: Arc<Mutex<NetworkStack>>) -> StackHandle {
+        StackHandle {
+            stack: stack,
+            resolve_timeout: Duration::from_millis(RESOLVE_TIMEOUT_MS),
+        }
+    }
+
+    /// Sets how long tx-objects created with this handle, but not with its
+    /// existing clones, wait for the Arp reply of their next hop.
+    pub fn set_resolve_timeout(&mut self, timeout: Duration) {
+        self.resolve_timeout = timeout;
+    }
+
+    pub fn resolve_timeout(&self) -> Duration {
+        self.resolve_timeout
+    }
+
+    /// Returns the shared stack, as taken by `UdpSocket::bind`,
+    /// `tunnel::add_tunnel` and others.
+    pub fn shared(&self) -> Arc<Mutex<NetworkStack>> {
+        self.stack.clone()
+    }
+
+    /// Locks the stack for everything the handle has no method for, blocking
+    /// until no other thread holds it.
+    pub fn lock(&self) -> MutexGuard<NetworkStack> {
+        self.stack.lock().unwrap()
+    }
+
+    pub fn ipv4_tx(&self, dst: Ipv4Addr) -> StackResult<StackIpv4Tx> {
+        self.ipv4_tx_in_vrf(None, dst)
+    }
+
+    /// See `NetworkStack::ipv4_tx_in_vrf`.
+    pub fn ipv4_tx_in_vrf(&self,
+                          vrf: Option<&str>,
+                          dst: Ipv4Addr)
+                          -> StackResult<StackIpv4Tx> {
+        let deadline = Instant::now() + self.resolve_timeout;
+        loop {
+            let mut route = self.tx_route_in_vrf(vrf, dst)?;
+            let pending = match route.start_resolve()? {
+                Some(pending) => pending,
+                None => return route.ipv4_tx(),
+            };
+            // The route may have changed while waiting, so it is taken again
+            wait_resolved(dst, pending, deadline)?;
+        }
+    }
+
+    /// Returns what creating a tx-object towards `dst` in `vrf` takes, see
+    /// `NetworkStack::tx_route_in_vrf`.
+    pub fn tx_route_in_vrf(&self, vrf: Option<&str>, dst: Ipv4Addr) -> StackResult<TxRoute> {
+        self.stack.lock().unwrap().tx_route_in_vrf(vrf, dst)
+    }
+
+    pub fn icmp_tx(&self, dst_ip: Ipv4Addr) -> StackResult<IcmpTx<StackIpv4Tx>> {
+        Ok(IcmpTx::new(self.ipv4_tx(dst_ip)?))
+    }
+
+    pub fn udp_tx(&self,
+                  dst_ip: Ipv4Addr,
+                  src: u16,
+                  dst_port: u16)
+                  -> StackResult<UdpTx<StackIpv4Tx>> {
+        self.udp_tx_in_vrf(None, dst_ip, src, dst_port)
+    }
+
+    /// See `NetworkStack::udp_tx_in_vrf`.
+    pub fn udp_tx_in_vrf(&self,
+                         vrf: Option<&str>,
+                         dst_ip: Ipv4Addr,
+                         src: u16,
+                         dst_port: u16)
+                         -> StackResult<UdpTx<StackIpv4Tx>> {
+        Ok(UdpTx::new(self.ipv4_tx_in_vrf(vrf, dst_ip)?, src, dst_port))
+    }
+
+    /// Same as `udp_tx`, but sends to `dst`, an address or a host name looked
+    /// up with the resolver of the stack. See `dns::ToStackAddr`.
+    pub fn udp_tx_to<A: ToStackAddr>(&self, dst: A, src: u16) -> StackResult<UdpTx<StackIpv4Tx>> {
+        let dst = self.resolve(dst)?;
+        self.udp_tx(*dst.ip(), src, dst.port())
+    }
+
+    /// Returns the address `addr` is, looking host names up with the
+    /// resolver of the stack, see `dns::resolve`.
+    pub fn resolve<A: ToStackAddr>(&self, addr: A) -> StackResult<SocketAddrV4> {
+        dns::resolve(&self.stack, addr)
+    }
+
+    /// Same as `ipv4_tx`, but returns a tx-object that is recreated when the
+    /// stack invalidates it.
+    pub fn refreshing_ipv4_tx(&self, dst: Ipv4Addr) -> StackResult<RefreshingTx<StackIpv4Tx>> {
+        RefreshingTx::new(self.shared(),
+                          None,
+                          dst,
+                          move |stack, vrf| stack.ipv4_tx_in_vrf(vrf, dst))
+    }
+
+    /// Same as `icmp_tx`, but returns a tx-object that is recreated when the
+    /// stack invalidates it.
+    pub fn refreshing_icmp_tx(&self,
+                              dst_ip: Ipv4Addr)
+                              -> StackResult<RefreshingTx<IcmpTx<StackIpv4Tx>>> {
+        RefreshingTx::new(self.shared(), None, dst_ip, move |stack, _| stack.icmp_tx(dst_ip))
+    }
+
+    /// Same as `udp_tx`, but returns a tx-object that is recreated when the
+    /// stack invalidates it.
+    pub fn refreshing_udp_tx(&self,
+                             dst_ip: Ipv4Addr,
+                             src: u16,
+                             dst_port: u16)
+                             -> StackResult<RefreshingTx<UdpTx<StackIpv4Tx>>> {
+        RefreshingTx::new(self.shared(),
+                          None,
+                          dst_ip,
+                          move |stack, vrf| stack.udp_tx_in_vrf(vrf, dst_ip, src, dst_port))
+    }
+
+    /// Creates a `UdpSender` sending from `src_port` on this stack.
+    pub fn udp_sender(&self, src_port: u16) -> UdpSender {
+        UdpSender::new(self.stack.clone(), src_port)
+    }
+
+    /// Binds a `UdpSocket` on this stack, see `UdpSocket::bind`.
+    pub fn udp_bind<A: ToStackAddr>(&self, addr: A) -> io::Result<UdpSocket> {
+        UdpSocket::bind(self.stack.clone(), addr)
+    }
+
+    /// Binds a `UdpSocket` in the VRF `vrf`, see `UdpSocket::bind_in_vrf`.
+    pub fn udp_bind_in_vrf<A: ToStackAddr>(&self, vrf: &str, addr: A) -> io::Result<UdpSocket> {
+        UdpSocket::bind_in_vrf(self.stack.clone(), vrf, addr)
+    }
+}
+
+/// A tx-object towards one destination that is recreated from the stack
+/// when it has been invalidated.
+pub struct RefreshingTx<T> {
+    stack: Arc<Mutex<NetworkStack>>,
+    vrf: Option<String>,
+    dst: Ipv4Addr,
+    create: Box<FnMut(&mut NetworkStack, Option<&str>) -> StackResult<T> + Send>,
+    tx: T,
+}
+
+impl<T> RefreshingTx<T> {
+    /// Creates a tx-object towards `dst`, routed in `vrf`, with `create`.
+    /// `create` is called with the stack and `vrf` again every time the
+    /// tx-object has to be recreated.
+    pub fn new<F>(stack: Arc<Mutex<NetworkStack>>,
+                  vrf: Option<&str>,
+                  dst: Ipv4Addr,
+                  mut create: F)
+                  -> StackResult<RefreshingTx<T>>
+        where F: FnMut(&mut NetworkStack, Option<&str>) -> StackResult<T> + Send + 'static
+    {
+        let tx = with_resolved(&stack, vrf, dst, |stack| create(stack, vrf))?;
+        Ok(RefreshingTx {
+            stack: stack,
+            vrf: vrf.map(|vrf| vrf.to_owned()),
+            dst: dst,
+            create: Box::new(create),
+            tx: tx,
+        })
+    }
+
+    /// Recreates the tx-object with the current state of the stack.
+    pub fn refresh(&mut self) -> StackResult<()> {
+        let vrf = self.vrf.as_ref().map(|vrf| vrf.as_str());
+        let create = &mut self.create;
+        self.tx = with_resolved(&self.stack, vrf, self.dst, |stack| create(stack, vrf))?;
+        Ok(())
+    }
+
+    /// Calls `send` with the tx-object. If it fails with `TxError::InvalidTx`
+    /// the tx-object is recreated and `send` is called once more.
+    pub fn send_with<F>(&mut self, mut send: F) -> StackResult<TxSent>
+        where F: FnMut(&mut T) -> TxResult
+    {
+        match send(&mut self.tx) {
+            Err(TxError::InvalidTx) => {
+                self.refresh()?;
+                send(&mut self.tx).map_err(StackError::TxError)
+            }
+            result => result.map_err(StackError::TxError),
+        }
+    }
+
+    /// Returns the tx-object as it is, possibly invalidated.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.tx
+    }
+}
+
+/// Calls `f` with the locked `stack` once the next hop towards `dst` in
+/// `vrf` is resolved, so creating a tx-object towards it will not block.
+/// Arp replies are waited for without holding the lock, at most
+/// `RESOLVE_TIMEOUT_MS`, and then this fails with an `io::Error` of kind
+/// `TimedOut`.
+pub fn with_resolved<F, T>(stack: &Mutex<NetworkStack>,
+                           vrf: Option<&str>,
+                           dst: Ipv4Addr,
+                           f: F)
+                           -> StackResult<T>
+    where F: FnOnce(&mut NetworkStack) -> StackResult<T>
+{
+    let deadline = Instant::now() + Duration::from_millis(RESOLVE_TIMEOUT_MS);
+    loop {
+        let pending = {
+            let mut stack = stack.lock().unwrap();
+            match stack.resolve_in_vrf(vrf, dst)? {
+                Some(pending) => pending,
+                None => return f(&mut stack),
+            }
+        };
+        // The route may have changed while waiting, so it is resolved again
+        wait_resolved(dst, pending, deadline)?;
+    }
+}
+
+/// Waits until `deadline` for the MAC address of the next hop towards `dst`
+/// from `pending`.
+fn wait_resolved(dst: Ipv4Addr, pending: Receiver<MacAddr>, deadline: Instant) -> StackResult<()> {
+    let now = Instant::now();
+    if now < deadline && pending.recv_timeout(deadline - now) != Err(RecvTimeoutError::Timeout) {
+        return Ok(());
+    }
+    let msg = format!("No Arp reply on the way to {}", dst);
+    Err(StackError::IoError(io::Error::new(io::ErrorKind::TimedOut, msg)))
+}
@@ -0,0 +1,204 @@
+//! Histograms of latencies, in the style of HdrHistogram.
+//!
+//! A `Histogram` counts values in buckets whose width grows with the
+//! values, so it holds anything from a microsecond to hours in a few
+//! hundred counters, with every value known to within about 6%:
+//! values below 32 are counted exactly, and each power of two above that is
+//! split into 16 buckets. Quantiles are reported as the highest value of
+//! their bucket, so they never understate a latency.
+//!
+//! `stats::LatencyStats` keeps the latencies the stack measures in
+//! microseconds:
+//!
+//! ```rust,ignore
+//! let echo = stack.latencies().icmp_echo;
+//! println!("{} pings, p50 {}us, p99 {}us, max {}us",
+//!          echo.count(),
+//!          echo.value_at_quantile(0.5),
+//!          echo.value_at_quantile(0.99),
+//!          echo.max());
+//! ```
+
+use std::cmp;
+use std::time::Duration;
+
+/// Values below this are counted in buckets of their own.
+const LINEAR_BUCKETS: usize = 32;
+
+/// Buckets each power of two above `LINEAR_BUCKETS` is split into.
+const SUB_BUCKETS: u64 = 16;
+
+/// Counts of values, by buckets of about 6% of their value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Grown to the highest bucket recorded in
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram::default()
+    }
+
+    /// Counts `value` once.
+    pub fn record(&mut self, value: u64) {
+        let index = bucket(value);
+        if self.counts.len() <= index {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.min = if self.count == 0 { value } else { cmp::min(self.min, value) };
+        self.max = cmp::max(self.max, value);
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+
+    /// Counts `duration` once, in microseconds.
+    pub fn record_duration(&mut self, duration: Duration) {
+        let micros = duration.as_secs()
+            .saturating_mul(1_000_000)
+            .saturating_add(duration.subsec_nanos() as u64 / 1000);
+        self.record(micros);
+    }
+
+    /// Adds the values counted by `other` to these.
+    pub fn add(&mut self, other: &Histogram) {
+        if other.count == 0 {
+            return;
+        }
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other_count;
+        }
+        self.min = if self.count == 0 { other.min } else { cmp::min(self.min, other.min) };
+        self.max = cmp::max(self.max, other.max);
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+
+    /// Returns how many values were counted.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the lowest value counted, zero if none was.
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+
+    /// Returns the highest value counted, zero if none was.
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Returns the mean of the values counted, zero if none was.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// Returns the value that `quantile`, between 0 and 1, of the values
+    /// counted are at or below, rounded up to the highest value of its
+    /// bucket. Zero if no value was counted.
+    pub fn value_at_quantile(&self, quantile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = cmp::max(1, (quantile * self.count as f64).ceil() as u64);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                return cmp::min(cmp::max(highest(index), self.min), self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Returns the highest value of each bucket holding values, with how
+    /// many it holds, from the lowest bucket up.
+    pub fn buckets(&self) -> Vec<(u64, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, count)| *count != 0)
+            .map(|(index, count)| (highest(index), *count))
+            .collect()
+    }
+}
+
+/// Returns the index of the bucket counting `value`.
+fn bucket(value: u64) -> usize {
+    if value < LINEAR_BUCKETS as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros() as u64;
+    let sub_bucket = (value >> (exponent - 4)) - SUB_BUCKETS;
+    LINEAR_BUCKETS + ((exponent - 5) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// Returns the highest value counted in bucket `index`.
+fn highest(index: usize) -> u64 {
+    if index < LINEAR_BUCKETS {
+        return index as u64;
+    }
+    let index = (index - LINEAR_BUCKETS) as u64;
+    let shift = index / SUB_BUCKETS + 1;
+    let lowest = (SUB_BUCKETS + index % SUB_BUCKETS) << shift;
+    lowest + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn buckets_cover_values() {
+        for value in (0..5000).chain(vec![u64::max_value() - 1, u64::max_value()]) {
+            let index = bucket(value);
+            assert!(highest(index) >= value);
+            assert!(index == 0 || highest(index - 1) < value);
+        }
+        assert_eq!(bucket(31), 31);
+        assert_eq!((bucket(32), highest(32)), (32, 33));
+        assert_eq!(highest(bucket(1000)), 1023);
+    }
+
+    #[test]
+    fn quantiles() {
+        let mut histogram = Histogram::new();
+        assert_eq!((histogram.value_at_quantile(0.5), histogram.mean()), (0, 0.0));
+        for value in 1..101 {
+            histogram.record(value);
+        }
+        assert_eq!((histogram.count(), histogram.min(), histogram.max()), (100, 1, 100));
+        assert_eq!(histogram.mean(), 50.5);
+        assert_eq!(histogram.value_at_quantile(0.0), 1);
+        assert_eq!(histogram.value_at_quantile(0.25), 25);
+        assert_eq!(histogram.value_at_quantile(0.5), 51);
+        assert_eq!(histogram.value_at_quantile(0.99), 99);
+        assert_eq!(histogram.value_at_quantile(1.0), 100);
+
+        let mut other = Histogram::new();
+        other.record_duration(Duration::new(2, 500_000));
+        histogram.add(&other);
+        assert_eq!((histogram.count(), histogram.max()), (101, 2_000_500));
+        assert_eq!(histogram.value_at_quantile(0.5), 51);
+        assert_eq!(histogram.value_at_quantile(1.0), 2_000_500);
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], (1, 1));
+        assert_eq!(buckets.last(), Some(&(2_031_615, 1)));
+        assert_eq!(buckets.iter().map(|&(_, count)| count).sum::<u64>(), 101);
+    }
+}
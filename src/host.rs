@@ -0,0 +1,190 @@
+//! Reading the network configuration of the host operating system, so a
+//! `NetworkStack` can be set up to mirror it.
+
+use {NetworkStack, StackError, StackResult};
+
+use ipnetwork::{Ipv4Network, ipv4_mask_to_prefix};
+
+use pnet::datalink;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Route is usable
+const RTF_UP: u32 = 0x0001;
+/// Destination is reached through a gateway
+const RTF_GATEWAY: u32 = 0x0002;
+
+/// One IPv4 route in the routing table of the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRoute {
+    /// Name of the interface the route goes out on
+    pub interface_name: String,
+    pub net: Ipv4Network,
+    pub gw: Option<Ipv4Addr>,
+    pub metric: u32,
+    pub mtu: Option<usize>,
+}
+
+/// Returns the IPv4 routing table of the host. Only implemented on Linux,
+/// where it is read from `/proc/net/route`.
+#[cfg(target_os = "linux")]
+pub fn routes() -> io::Result<Vec<HostRoute>> {
+    let mut content = String::new();
+    File::open("/proc/net/route")?.read_to_string(&mut content)?;
+    Ok(parse_proc_net_route(&content))
+}
+
+/// Returns the IPv4 routing table of the host. Only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn routes() -> io::Result<Vec<HostRoute>> {
+    let msg = "Reading the host routing table is not supported on this platform".to_owned();
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+/// Parses the content of a Linux `/proc/net/route` file. Lines that can't be
+/// parsed and routes that are not up are skipped.
+pub fn parse_proc_net_route(content: &str) -> Vec<HostRoute> {
+    content.lines().skip(1).filter_map(|line| parse_proc_net_route_line(line).ok()).collect()
+}
+
+fn parse_proc_net_route_line(line: &str) -> Result<HostRoute, ()> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    if fields.len() < 9 {
+        return Err(());
+    }
+    let dst = parse_hex_ip(fields[1])?;
+    let gw = parse_hex_ip(fields[2])?;
+    let flags = u32::from_str_radix(fields[3], 16).map_err(|_| ())?;
+    let metric = fields[6].parse::<u32>().map_err(|_| ())?;
+    let mask = parse_hex_ip(fields[7])?;
+    let mtu = fields[8].parse::<usize>().map_err(|_| ())?;
+    if flags & RTF_UP == 0 {
+        return Err(());
+    }
+    let prefix = ipv4_mask_to_prefix(mask).map_err(|_| ())?;
+    Ok(HostRoute {
+        interface_name: fields[0].to_owned(),
+        net: Ipv4Network::new(dst, prefix).map_err(|_| ())?,
+        gw: if flags & RTF_GATEWAY != 0 { Some(gw) } else { None },
+        metric: metric,
+        mtu: if mtu == 0 { None } else { Some(mtu) },
+    })
+}
+
+/// The addresses in `/proc/net/route` are the network byte order integers
+/// printed in host byte order.
+fn parse_hex_ip(s: &str) -> Result<Ipv4Addr, ()> {
+    u32::from_str_radix(s, 16).map(|ip| Ipv4Addr::from(u32::from_be(ip))).map_err(|_| ())
+}
+
+/// Returns the IPv4 networks configured on the host interface with the given
+/// name. The operating system only reports the addresses, so the prefix
+/// length of each address is taken from the most specific directly
+/// connected route in `routes` that contains it, or 32 if there is none.
+pub fn addresses(interface_name: &str, routes: &[HostRoute]) -> Vec<Ipv4Network> {
+    let ips = datalink::interfaces()
+        .into_iter()
+        .filter(|interface| interface.name == interface_name)
+        .flat_map(|interface| interface.ips.unwrap_or_else(Vec::new))
+        .filter_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .collect::<Vec<_>>();
+    ips.into_iter().map(|ip| address_network(ip, interface_name, routes)).collect()
+}
+
+fn address_network(ip: Ipv4Addr, interface_name: &str, routes: &[HostRoute]) -> Ipv4Network {
+    let prefix = routes.iter()
+        .filter(|route| {
+            route.gw.is_none() && route.interface_name == interface_name &&
+            route.net.contains(ip)
+        })
+        .map(|route| route.net.prefix())
+        .max()
+        .unwrap_or(32);
+    Ipv4Network::new(ip, prefix).unwrap()
+}
+
+/// Adds the host's IPv4 addresses and routes to the interfaces in `stack`
+/// with the same names, so the stack shadows the configuration of the host.
+/// Host interfaces not present in the stack are ignored, as are addresses
+/// already configured in the stack.
+pub fn import(stack: &mut NetworkStack) -> StackResult<()> {
+    let routes = routes().map_err(StackError::from)?;
+    for interface in stack.interfaces() {
+        let nets = addresses(&interface.name, &routes);
+        for net in &nets {
+            if !stack.interface(&interface)?.has_ipv4(net.ip()) {
+                stack.add_ipv4(&interface, *net)?;
+            }
+        }
+        for route in routes.iter().filter(|route| route.interface_name == interface.name) {
+            let connected = route.gw.is_none() &&
+                            nets.iter().any(|net| {
+                net.prefix() == route.net.prefix() && net.contains(route.net.ip())
+            });
+            if connected {
+                // Already added by add_ipv4
+                continue;
+            }
+            stack.add_route_with_metric(route.net, route.gw, interface.clone(), route.metric);
+            if route.mtu.is_some() {
+                stack.set_route_mtu(route.net, route.mtu)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnetwork::Ipv4Network;
+
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    use super::*;
+
+    static PROC_NET_ROUTE: &'static str =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+         eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+         eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+         tun0\t0010A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t1400\t0\t0\n\
+         eth1\t0000A8C0\t00000000\t0000\t0\t0\t0\t0000FFFF\t0\t0\t0\n\
+         garbage\n";
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn parse_routes() {
+        let routes = parse_proc_net_route(PROC_NET_ROUTE);
+        assert_eq!(routes.len(), 3);
+
+        assert_eq!(routes[0].interface_name, "eth0");
+        assert_eq!(routes[0].net, Ipv4Network::from_str("0/0").unwrap());
+        assert_eq!(routes[0].gw, Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(routes[0].metric, 100);
+        assert_eq!(routes[0].mtu, None);
+
+        assert_eq!(routes[1].net, Ipv4Network::from_str("10.0.0.0/24").unwrap());
+        assert_eq!(routes[1].gw, None);
+
+        assert_eq!(routes[2].interface_name, "tun0");
+        assert_eq!(routes[2].net,
+                   Ipv4Network::from_str("192.168.16.0/24").unwrap());
+        assert_eq!(routes[2].mtu, Some(1400));
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn address_prefix_from_connected_route() {
+        let routes = parse_proc_net_route(PROC_NET_ROUTE);
+        let ip = Ipv4Addr::new(10, 0, 0, 7);
+        assert_eq!(address_network(ip, "eth0", &routes),
+                   Ipv4Network::new(ip, 24).unwrap());
+        assert_eq!(address_network(ip, "tun0", &routes),
+                   Ipv4Network::new(ip, 32).unwrap());
+    }
+}
@@ -1,4 +1,5 @@
 use {RxError, RxResult};
+use checksum::{self, RxChecksumControl};
 use ipv4::Ipv4Listener;
 
 use pnet::packet::Packet;
@@ -6,7 +7,9 @@ use pnet::packet::icmp::{IcmpPacket, IcmpType};
 use pnet::packet::ipv4::Ipv4Packet;
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::Sender;
 use std::time::SystemTime;
 
 /// Trait that must be implemented by any struct who want to receive Icmp
@@ -16,34 +19,88 @@ pub trait IcmpListener: Send {
     fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet);
 }
 
+/// Sends every Icmp packet it receives, with the time it was received and
+/// the address it came from, to a channel. The packet is the whole Icmp
+/// message, starting with its type and code.
+#[derive(Clone)]
+pub struct BasicIcmpListener {
+    tx: Sender<(SystemTime, Ipv4Addr, Box<[u8]>)>,
+}
+
+impl BasicIcmpListener {
+    pub fn new(tx: Sender<(SystemTime, Ipv4Addr, Box<[u8]>)>) -> BasicIcmpListener {
+        BasicIcmpListener { tx: tx }
+    }
+}
+
+impl IcmpListener for BasicIcmpListener {
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) {
+        let icmp_packet = packet.payload().to_vec().into_boxed_slice();
+        // Icmp listeners can not be removed, so nothing to do if nobody reads
+        self.tx.send((time, packet.get_source(), icmp_packet)).unwrap_or(());
+    }
+}
+
 /// Type binding for how the listeners in `IcmpRx` are structured.
-pub type IcmpListenerLookup = HashMap<IcmpType, Vec<Box<IcmpListener>>>;
+pub type IcmpListenerLookup = HashMap<IcmpType, Vec<Mutex<Box<IcmpListener>>>>;
 
 /// Listener and parser of Icmp packets.
 pub struct IcmpRx {
-    listeners: Arc<Mutex<IcmpListenerLookup>>,
+    listeners: Arc<RwLock<IcmpListenerLookup>>,
+    /// Listening to every local address, see `with_wildcard`
+    wildcard: Arc<RwLock<IcmpListenerLookup>>,
+    checksums: Arc<RxChecksumControl>,
 }
 
 impl IcmpRx {
     /// Constructs a new `IcmpRx` with the given listeners.
     /// Casted before return to make it easy to add to the desired `Ipv4Rx`.
-    pub fn new(listeners: Arc<Mutex<IcmpListenerLookup>>) -> IcmpRx {
-        IcmpRx { listeners: listeners }
+    pub fn new(listeners: Arc<RwLock<IcmpListenerLookup>>) -> IcmpRx {
+        Self::with_checksums(listeners, Arc::new(RxChecksumControl::default()))
+    }
+
+    /// Same as `new`, but only verifies the checksums of the messages while
+    /// `checksums` says so.
+    pub fn with_checksums(listeners: Arc<RwLock<IcmpListenerLookup>>,
+                          checksums: Arc<RxChecksumControl>)
+                          -> IcmpRx {
+        Self::with_wildcard(listeners, Arc::new(RwLock::new(HashMap::new())), checksums)
+    }
+
+    /// Same as `with_checksums`, but also gives the messages to `wildcard`,
+    /// listeners shared by the `IcmpRx` of every local address.
+    pub fn with_wildcard(listeners: Arc<RwLock<IcmpListenerLookup>>,
+                         wildcard: Arc<RwLock<IcmpListenerLookup>>,
+                         checksums: Arc<RxChecksumControl>)
+                         -> IcmpRx {
+        IcmpRx {
+            listeners: listeners,
+            wildcard: wildcard,
+            checksums: checksums,
+        }
     }
 }
 
 impl Ipv4Listener for IcmpRx {
     fn recv(&mut self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
-        let (icmp_type, _icmp_code) = {
-            let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
-            (icmp_pkg.get_icmp_type(), icmp_pkg.get_icmp_code())
+        let icmp_type = match IcmpPacket::new(ip_pkg.payload()) {
+            Some(icmp_pkg) => icmp_pkg.get_icmp_type(),
+            None => return Err(RxError::InvalidLength),
         };
+        if self.checksums.icmp() && checksum::checksum(ip_pkg.payload()) != 0 {
+            return Err(RxError::InvalidChecksum);
+        }
         trace!("Icmp got a packet with {} bytes!", ip_pkg.payload().len());
-        let mut listeners = self.listeners.lock().unwrap();
-        if let Some(type_listeners) = listeners.get_mut(&icmp_type) {
-            for listener in type_listeners {
-                listener.recv(time, &ip_pkg);
+        let mut delivered = false;
+        for listeners in &[&self.listeners, &self.wildcard] {
+            if let Some(type_listeners) = listeners.read().unwrap().get(&icmp_type) {
+                for listener in type_listeners {
+                    listener.lock().unwrap().recv(time, &ip_pkg);
+                }
+                delivered |= !type_listeners.is_empty();
             }
+        }
+        if delivered {
             Ok(())
         } else {
             Err(RxError::NoListener(format!("Icmp, {:?}", icmp_type)))
@@ -1,69 +1,33 @@
-use {Payload, HasPayload, BasicPayload, TxResult};
-use ipv4::{Ipv4Payload, Ipv4Tx};
-
-use pnet::packet::MutablePacket;
-use pnet::packet::icmp::{IcmpCode, IcmpType, MutableIcmpPacket, checksum, IcmpTypes};
-use pnet::packet::icmp::echo_request::IcmpCodes;
-use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-
-/// Trait for anything wishing to be the payload of an Icmp packet.
-pub trait IcmpPayload: Payload {
-    fn icmp_type(&self) -> IcmpType;
-
-    fn icmp_code(&self) -> IcmpCode;
-
-    fn build_header(&self, header: &mut MutableIcmpPacket);
-}
-
-#[derive(Clone)]
-pub struct BasicIcmpPayload<'a> {
-    icmp_type: IcmpType,
-    icmp_code: IcmpCode,
-    payload: BasicPayload<'a>,
-}
-
-impl<'a> BasicIcmpPayload<'a> {
-    pub fn new(icmp_type: IcmpType, icmp_code: IcmpCode, payload: &'a [u8]) -> Self {
-        BasicIcmpPayload {
-            icmp_type: icmp_type,
-            icmp_code: icmp_code,
-            payload: BasicPayload::new(payload),
-        }
-    }
-}
-
-impl<'a> IcmpPayload for BasicIcmpPayload<'a> {
-    fn icmp_type(&self) -> IcmpType {
-        self.icmp_type
-    }
-
-    fn icmp_code(&self) -> IcmpCode {
-        self.icmp_code
-    }
-
-    fn build_header(&self, _header: &mut MutableIcmpPacket) {}
-}
-
-impl<'a> HasPayload for BasicIcmpPayload<'a> {
-    fn get_payload(&self) -> &Payload {
-        &self.payload
-    }
-
-    fn get_payload_mut(&mut self) -> &mut Payload {
-        &mut self.payload
-    }
-}
+use {Payload, TxResult};
+use corrupt::IcmpCorruption;
+use ipv4::{BasicIpv4Payload, Ipv4Tx};
+use proto::icmp::{IcmpBuilder, IcmpPayload, PingBuilder};
 
+use pnet::packet::ip::IpNextHeaderProtocols;
 
 /// Icmp packet sender struct.
 pub struct IcmpTx<T: Ipv4Tx> {
     ipv4: T,
+    corruption: IcmpCorruption,
 }
 
 impl<T: Ipv4Tx> IcmpTx<T> {
     /// Creates a new `IcmpTx` based on `ipv4`
     pub fn new(ipv4: T) -> Self {
-        IcmpTx { ipv4: ipv4 }
+        IcmpTx {
+            ipv4: ipv4,
+            corruption: IcmpCorruption::default(),
+        }
+    }
+
+    /// Makes the errors of `corruption` in the packets sent from now on, see
+    /// `corrupt`.
+    pub fn set_corruption(&mut self, corruption: IcmpCorruption) {
+        self.corruption = corruption;
+    }
+
+    pub fn corruption(&self) -> IcmpCorruption {
+        self.corruption
     }
 
     /// Sends a general Icmp packet. Should not be called directly in general,
@@ -71,8 +35,14 @@ impl<T: Ipv4Tx> IcmpTx<T> {
     pub fn send<P>(&mut self, payload: P) -> TxResult
         where P: IcmpPayload
     {
-        let builder = IcmpBuilder::new(payload);
-        self.ipv4.send(builder)
+        let mut builder = IcmpBuilder::new(payload);
+        if self.corruption == IcmpCorruption::default() {
+            return self.ipv4.send(builder);
+        }
+        let mut packet = vec![0; builder.len()];
+        builder.build(&mut packet);
+        self.corruption.apply(&mut packet);
+        self.ipv4.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Icmp, &packet))
     }
 
     /// Sends an Echo Request packet (ping) with the given payload.
@@ -83,76 +53,10 @@ impl<T: Ipv4Tx> IcmpTx<T> {
 }
 
 
-pub struct IcmpBuilder<P: IcmpPayload> {
-    builder: P,
-}
-
-impl<P: IcmpPayload> IcmpBuilder<P> {
-    pub fn new(builder: P) -> IcmpBuilder<P> {
-        IcmpBuilder { builder: builder }
-    }
-}
-
-impl<P: IcmpPayload> Ipv4Payload for IcmpBuilder<P> {
-    fn next_level_protocol(&self) -> IpNextHeaderProtocol {
-        IpNextHeaderProtocols::Icmp
-    }
-}
-
-impl<P: IcmpPayload> Payload for IcmpBuilder<P> {
-    fn len(&self) -> usize {
-        8 + self.builder.len()
-    }
-
-    fn build(&mut self, buffer: &mut [u8]) {
-        let mut pkg = MutableIcmpPacket::new(buffer).unwrap();
-        {
-            let mut header_pkg = MutableIcmpPacket::new(&mut pkg.packet_mut()[..8]).unwrap();
-            header_pkg.set_icmp_type(self.builder.icmp_type());
-            header_pkg.set_icmp_code(self.builder.icmp_code());
-            self.builder.build_header(&mut header_pkg);
-        }
-        self.builder.build(&mut pkg.packet_mut()[8..]);
-        let checksum = checksum(&pkg.to_immutable());
-        pkg.set_checksum(checksum);
-    }
-}
-
-pub struct PingBuilder<'a> {
-    payload: BasicPayload<'a>,
-}
-
-impl<'a> PingBuilder<'a> {
-    pub fn new(payload: &'a [u8]) -> PingBuilder<'a> {
-        PingBuilder { payload: BasicPayload::new(payload) }
-    }
-}
-
-impl<'a> IcmpPayload for PingBuilder<'a> {
-    fn icmp_type(&self) -> IcmpType {
-        IcmpTypes::EchoRequest
-    }
-
-    fn icmp_code(&self) -> IcmpCode {
-        IcmpCodes::NoCode
-    }
-
-    fn build_header(&self, _header: &mut MutableIcmpPacket) {}
-}
-
-impl<'a> HasPayload for PingBuilder<'a> {
-    fn get_payload(&self) -> &Payload {
-        &self.payload
-    }
-
-    fn get_payload_mut(&mut self) -> &mut Payload {
-        &mut self.payload
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use {TxResult, TxError};
+    use {TxResult, TxSent};
+    use checksum;
     use ipv4::{Ipv4Payload, Ipv4Tx};
 
     use pnet::packet::Packet;
@@ -161,6 +65,7 @@ mod tests {
     use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 
     use std::error::Error;
+    use std::io;
     use std::net::Ipv4Addr;
     use std::sync::mpsc::{self, Sender, Receiver};
 
@@ -192,8 +97,8 @@ mod tests {
             payload.build(&mut buffer);
             self.tx
                 .send((payload.next_level_protocol(), buffer.into_boxed_slice()))
-                .map_err(|e| TxError::Other(e.description().to_owned()))?;
-            Ok(())
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.description()))?;
+            Ok(TxSent::new(1, payload.len()))
         }
     }
 
@@ -212,4 +117,18 @@ mod tests {
         assert_eq!([9, 55], echo_pkg.payload());
     }
 
+    #[test]
+    fn test_send_echo_with_identifier() {
+        let (ipv4, read_handle) = MockIpv4Tx::new();
+        let mut testee = IcmpTx::new(ipv4);
+        testee.send(PingBuilder::with_identifier(0x1234, 7, &[9, 55])).unwrap();
+
+        let (_, data) = read_handle.try_recv().unwrap();
+        let echo_pkg = EchoRequestPacket::new(&data).unwrap();
+        assert_eq!(0x1234, echo_pkg.get_identifier());
+        assert_eq!(7, echo_pkg.get_sequence_number());
+        assert_eq!([9, 55], echo_pkg.payload());
+        assert_eq!(0, checksum::checksum(&data));
+    }
+
 }
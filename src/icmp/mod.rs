@@ -1,8 +1,9 @@
 mod icmp_rx;
 mod icmp_tx;
 
-pub use self::icmp_rx::{IcmpListener, IcmpListenerLookup, IcmpRx};
-pub use self::icmp_tx::{BasicIcmpPayload, IcmpBuilder, IcmpPayload, IcmpTx, PingBuilder};
+pub use self::icmp_rx::{BasicIcmpListener, IcmpListener, IcmpListenerLookup, IcmpRx};
+pub use self::icmp_tx::IcmpTx;
+pub use proto::icmp::{BasicIcmpPayload, IcmpBuilder, IcmpPayload, PingBuilder};
 
 
 // pub struct PingSocket {
@@ -0,0 +1,489 @@
+//! Making a link worse on purpose, like netem does.
+//!
+//! `Impaired` wraps a `Datalink` and delays, drops, duplicates and reorders
+//! the frames going through it, and can limit its bandwidth. Added to a stack
+//! in place of the link it wraps, it shows how the protocols cope with a bad
+//! network without leaving the process:
+//!
+//! ```rust,ignore
+//! let lossy = Impairment {
+//!     latency: Duration::from_millis(50),
+//!     jitter: Duration::from_millis(10),
+//!     loss: 0.01,
+//!     ..Impairment::default()
+//! };
+//! let backend = Arc::new(MyBackend::open()?);
+//! let link = Arc::new(Impaired::new(backend, lossy, Impairment::default()));
+//! let interface = stack.add_datalink("lossy0", link.clone())?;
+//! ...
+//! println!("{} frames lost", link.stats(Direction::Outbound).lost);
+//! ```
+//!
+//! Frames sent and received are impaired separately, each direction as its
+//! own `Impairment` says. Frames wait in a queue per direction until they
+//! are due, sent ones are handed to the wrapped link from a thread of their
+//! own. Jitter may reorder frames sent close to each other, as on a real
+//! network. The randomness is not seeded, so tests should only rely on
+//! probabilities of 0 and 1.
+
+use datalink::Datalink;
+use pcap::Direction;
+use rx;
+use shaping::{RateLimit, TokenBucket};
+
+use pnet::util::MacAddr;
+
+use rand::{self, Rng, XorShiftRng};
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frames each direction queues by default.
+pub static DEFAULT_QUEUE_LEN: usize = 1000;
+
+/// What happens to the frames going one way through an `Impaired` link. The
+/// default changes nothing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairment {
+    /// How long every frame is delayed
+    pub latency: Duration,
+    /// How much more or less than `latency` a frame is delayed at most,
+    /// evenly distributed
+    pub jitter: Duration,
+    /// Probability of a frame being dropped, from 0 to 1
+    pub loss: f64,
+    /// Probability of a frame being sent twice
+    pub duplicate: f64,
+    /// Probability of a frame skipping the delay, and so overtaking the
+    /// frames queued before it
+    pub reorder: f64,
+    /// The bandwidth of the link, counted in bytes of Ethernet frames.
+    /// Frames queue up behind each other while it is used up
+    pub rate: Option<RateLimit>,
+    /// Frames queued at most, more are dropped
+    pub queue_len: usize,
+}
+
+impl Default for Impairment {
+    fn default() -> Impairment {
+        Impairment {
+            latency: Duration::new(0, 0),
+            jitter: Duration::new(0, 0),
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            rate: None,
+            queue_len: DEFAULT_QUEUE_LEN,
+        }
+    }
+}
+
+/// What an `Impaired` link did to the frames going one way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImpairStats {
+    /// Frames going through, before any were dropped or duplicated
+    pub frames: usize,
+    /// Frames dropped as `loss` says
+    pub lost: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    /// Frames dropped since the queue was full
+    pub overflowed: usize,
+}
+
+/// A frame waiting to be due.
+struct Queued {
+    due: Instant,
+    /// Keeps frames due at the same time in order
+    seq: u64,
+    frame: Box<[u8]>,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    /// Reversed, so the `BinaryHeap` pops the frame due first.
+    fn cmp(&self, other: &Queued) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+struct LineState {
+    impairment: Impairment,
+    bucket: Option<TokenBucket>,
+    rng: XorShiftRng,
+    queue: BinaryHeap<Queued>,
+    next_seq: u64,
+    stats: ImpairStats,
+    /// The error the wrapped link failed to receive with, for `recv`
+    error: Option<io::Error>,
+    /// Set when the `Impaired` link is dropped
+    closed: bool,
+}
+
+/// The frames going one way, between being impaired and being due.
+struct Line {
+    state: Mutex<LineState>,
+    ready: Condvar,
+}
+
+impl Line {
+    fn new(impairment: Impairment) -> Line {
+        Line {
+            state: Mutex::new(LineState {
+                impairment: impairment,
+                bucket: impairment.rate.map(TokenBucket::new),
+                rng: rand::weak_rng(),
+                queue: BinaryHeap::new(),
+                next_seq: 0,
+                stats: ImpairStats::default(),
+                error: None,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn set_impairment(&self, impairment: Impairment) {
+        let mut state = self.state.lock().unwrap();
+        if state.impairment.rate != impairment.rate {
+            state.bucket = impairment.rate.map(TokenBucket::new);
+        }
+        state.impairment = impairment;
+    }
+
+    /// Queues `frame`, as many times and as long as the impairment says.
+    fn push(&self, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let impairment = state.impairment;
+        state.stats.frames += 1;
+        if state.rng.gen::<f64>() < impairment.loss {
+            state.stats.lost += 1;
+            return;
+        }
+        let copies = if state.rng.gen::<f64>() < impairment.duplicate {
+            state.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        let now = Instant::now();
+        for _ in 0..copies {
+            if state.queue.len() >= impairment.queue_len {
+                state.stats.overflowed += 1;
+                continue;
+            }
+            let wait = match state.bucket {
+                Some(ref mut bucket) => bucket.take(frame.len()),
+                None => Duration::new(0, 0),
+            };
+            let delay = if state.rng.gen::<f64>() < impairment.reorder {
+                state.stats.reordered += 1;
+                Duration::new(0, 0)
+            } else {
+                let spread = state.rng.gen_range(-1.0, 1.0);
+                jittered(impairment.latency, impairment.jitter, spread)
+            };
+            state.queue.push(Queued {
+                due: now + wait + delay,
+                seq: state.next_seq,
+                frame: frame.to_vec().into_boxed_slice(),
+            });
+            state.next_seq += 1;
+        }
+        self.ready.notify_all();
+    }
+
+    /// Waits up to `timeout` for a frame to be due. Gives up right away once
+    /// the line is closed.
+    fn pop(&self, timeout: Duration) -> Option<Box<[u8]>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return None;
+            }
+            let until = match state.queue.peek() {
+                Some(queued) if queued.due <= now => break,
+                Some(queued) if queued.due < deadline => queued.due,
+                _ => deadline,
+            };
+            state = self.ready.wait_timeout(state, until - now).unwrap().0;
+        }
+        state.queue.pop().map(|queued| queued.frame)
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+/// Returns `latency` plus `spread`, from -1 to 1, times `jitter`, but not
+/// less than zero.
+fn jittered(latency: Duration, jitter: Duration, spread: f64) -> Duration {
+    let secs = |duration: Duration| {
+        duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+    };
+    let delay = (secs(latency) + spread * secs(jitter)).max(0.0);
+    Duration::new(delay as u64, (delay.fract() * 1e9) as u32)
+}
+
+/// A `Datalink` impairing the frames of another one. See the module
+/// documentation.
+pub struct Impaired {
+    datalink: Arc<Datalink>,
+    tx: Arc<Line>,
+    rx: Arc<Line>,
+}
+
+impl Impaired {
+    /// Wraps `datalink`, impairing the frames sent on it as `tx` says and
+    /// those received as `rx` does.
+    pub fn new(datalink: Arc<Datalink>, tx: Impairment, rx: Impairment) -> Impaired {
+        let tx = Arc::new(Line::new(tx));
+        let rx = Arc::new(Line::new(rx));
+        let (tx_datalink, tx_line) = (datalink.clone(), tx.clone());
+        thread::spawn(move || run_tx(tx_datalink, tx_line));
+        let (rx_datalink, rx_line) = (datalink.clone(), rx.clone());
+        thread::spawn(move || run_rx(rx_datalink, rx_line));
+        Impaired {
+            datalink: datalink,
+            tx: tx,
+            rx: rx,
+        }
+    }
+
+    fn line(&self, direction: Direction) -> &Line {
+        match direction {
+            Direction::Inbound => &self.rx,
+            Direction::Outbound => &self.tx,
+        }
+    }
+
+    /// Impairs the frames going `direction` from now on as `impairment`
+    /// says. Frames queued already stay as they are.
+    pub fn set_impairment(&self, direction: Direction, impairment: Impairment) {
+        self.line(direction).set_impairment(impairment);
+    }
+
+    pub fn impairment(&self, direction: Direction) -> Impairment {
+        self.line(direction).state.lock().unwrap().impairment
+    }
+
+    pub fn stats(&self, direction: Direction) -> ImpairStats {
+        self.line(direction).state.lock().unwrap().stats
+    }
+}
+
+impl Drop for Impaired {
+    fn drop(&mut self) {
+        self.tx.close();
+        self.rx.close();
+    }
+}
+
+/// Sends the frames of `line` on `datalink` as they become due.
+fn run_tx(datalink: Arc<Datalink>, line: Arc<Line>) {
+    let poll_interval = Duration::from_millis(rx::POLL_INTERVAL_MS);
+    while !line.is_closed() {
+        if let Some(frame) = line.pop(poll_interval) {
+            if let Err(e) = datalink.send(&frame) {
+                debug!("Impaired link failed to send: {}", e);
+            }
+        }
+    }
+}
+
+/// Queues the frames received on `datalink` in `line`, until receiving
+/// fails or the line is closed.
+fn run_rx(datalink: Arc<Datalink>, line: Arc<Line>) {
+    while !line.is_closed() {
+        match datalink.recv() {
+            Ok(frame) => line.push(&frame),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+            Err(e) => {
+                line.state.lock().unwrap().error = Some(e);
+                line.ready.notify_all();
+                return;
+            }
+        }
+    }
+}
+
+impl Datalink for Impaired {
+    fn mac(&self) -> MacAddr {
+        self.datalink.mac()
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.datalink.mtu()
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.tx.push(frame);
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        match self.rx.pop(Duration::from_millis(rx::POLL_INTERVAL_MS)) {
+            Some(frame) => Ok(frame),
+            None => {
+                match self.rx.state.lock().unwrap().error.take() {
+                    Some(e) => Err(e),
+                    None => Err(io::Error::new(io::ErrorKind::TimedOut, "No frame due")),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datalink::Datalink;
+    use pcap::Direction;
+    use shaping::RateLimit;
+
+    use pnet::util::MacAddr;
+
+    use std::io;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// A link handing the frames sent on it to the test.
+    struct Recorder(Mutex<mpsc::Sender<(Instant, Box<[u8]>)>>);
+
+    impl Datalink for Recorder {
+        fn mac(&self) -> MacAddr {
+            MacAddr::new(2, 0, 0, 0, 0, 1)
+        }
+
+        fn send(&self, frame: &[u8]) -> io::Result<()> {
+            let sent = (Instant::now(), frame.to_vec().into_boxed_slice());
+            self.0.lock().unwrap().send(sent).unwrap();
+            Ok(())
+        }
+
+        fn recv(&self) -> io::Result<Box<[u8]>> {
+            thread::sleep(Duration::from_millis(10));
+            Err(io::Error::new(io::ErrorKind::TimedOut, "No frame"))
+        }
+    }
+
+    fn impaired(tx: Impairment) -> (Impaired, mpsc::Receiver<(Instant, Box<[u8]>)>) {
+        let (sent, frames) = mpsc::channel();
+        let recorder = Arc::new(Recorder(Mutex::new(sent)));
+        (Impaired::new(recorder, tx, Impairment::default()), frames)
+    }
+
+    #[test]
+    fn delays() {
+        let (link, frames) = impaired(Impairment {
+            latency: Duration::from_millis(50),
+            ..Impairment::default()
+        });
+        let start = Instant::now();
+        link.send(&[1]).unwrap();
+        let (sent, frame) = frames.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(&frame[..], &[1]);
+        assert!(sent - start >= Duration::from_millis(50));
+        assert!(sent - start < Duration::from_millis(500));
+        assert_eq!(link.stats(Direction::Outbound).frames, 1);
+    }
+
+    #[test]
+    fn loses_and_duplicates() {
+        let (link, frames) = impaired(Impairment {
+            loss: 1.0,
+            ..Impairment::default()
+        });
+        link.send(&[1]).unwrap();
+        link.set_impairment(Direction::Outbound,
+                            Impairment {
+                                duplicate: 1.0,
+                                ..Impairment::default()
+                            });
+        link.send(&[2]).unwrap();
+        for _ in 0..2 {
+            let (_, frame) = frames.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(&frame[..], &[2]);
+        }
+        assert!(frames.recv_timeout(Duration::from_millis(50)).is_err());
+        let stats = link.stats(Direction::Outbound);
+        assert_eq!((stats.frames, stats.lost, stats.duplicated), (2, 1, 1));
+    }
+
+    #[test]
+    fn reorders() {
+        let (link, frames) = impaired(Impairment {
+            latency: Duration::from_millis(100),
+            ..Impairment::default()
+        });
+        link.send(&[1]).unwrap();
+        let mut impairment = link.impairment(Direction::Outbound);
+        impairment.reorder = 1.0;
+        link.set_impairment(Direction::Outbound, impairment);
+        link.send(&[2]).unwrap();
+        let order = (0..2)
+            .map(|_| frames.recv_timeout(Duration::from_secs(1)).unwrap().1[0])
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![2, 1]);
+        assert_eq!(link.stats(Direction::Outbound).reordered, 1);
+    }
+
+    #[test]
+    fn limits_rate() {
+        let (link, frames) = impaired(Impairment {
+            rate: Some(RateLimit {
+                rate: 10_000,
+                burst: 100,
+            }),
+            latency: Duration::from_millis(50),
+            queue_len: 4,
+            ..Impairment::default()
+        });
+        let start = Instant::now();
+        for _ in 0..5 {
+            link.send(&[0; 100]).unwrap();
+        }
+        // The first two use up the burst, the next two follow at 100 bytes per
+        // 10 ms, and the last finds the queue full
+        for _ in 0..4 {
+            frames.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(70));
+        assert_eq!(link.stats(Direction::Outbound).overflowed, 1);
+    }
+
+    #[test]
+    fn jitter_stays_above_zero() {
+        let ms = Duration::from_millis;
+        assert_eq!(jittered(ms(10), ms(4), -0.5), ms(8));
+        assert_eq!(jittered(ms(10), ms(20), -1.0), ms(0));
+    }
+}
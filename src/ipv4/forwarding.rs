@@ -0,0 +1,103 @@
+use {Interface, NetworkStack};
+use nat::Nat;
+
+use pnet::packet::Packet;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::SystemTime;
+
+/// A received packet that is not for this host, on its way to
+/// `NetworkStack::forward`.
+pub struct ForwardedPacket {
+    pub time: SystemTime,
+    /// The interface the packet arrived on
+    pub interface: Option<Interface>,
+    /// The whole IPv4 packet, reassembled if it was fragmented
+    pub packet: Vec<u8>,
+}
+
+/// Decides which packets an `Ipv4Rx` passes on for forwarding instead of
+/// delivering them locally. Nothing is forwarded until a queue is set.
+#[derive(Default)]
+pub struct ForwardingControl {
+    interface: Option<Interface>,
+    queue: Mutex<Option<Sender<ForwardedPacket>>>,
+    nat: RwLock<Option<Arc<Nat>>>,
+}
+
+impl ForwardingControl {
+    /// Creates a control for the `Ipv4Rx` of `interface`.
+    pub fn new(interface: Interface) -> ForwardingControl {
+        ForwardingControl { interface: Some(interface), ..ForwardingControl::default() }
+    }
+
+    /// The interface of the `Ipv4Rx` this control is for.
+    pub fn interface(&self) -> Option<&Interface> {
+        self.interface.as_ref()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.lock().unwrap().is_some()
+    }
+
+    /// Sets where to send packets to forward, or disables forwarding if
+    /// `queue` is `None`.
+    pub fn set_queue(&self, queue: Option<Sender<ForwardedPacket>>) {
+        *self.queue.lock().unwrap() = queue;
+    }
+
+    /// Returns the NAT masquerading the traffic going out on this interface,
+    /// if any.
+    pub fn nat(&self) -> Option<Arc<Nat>> {
+        self.nat.read().unwrap().clone()
+    }
+
+    pub fn set_nat(&self, nat: Option<Arc<Nat>>) {
+        *self.nat.write().unwrap() = nat;
+    }
+
+    /// Takes `ip_pkg` for forwarding if forwarding is enabled and the packet
+    /// is either not for this host, or it's `local` but turns out to be
+    /// return traffic of a NAT mapping. Returns true if the packet was taken.
+    pub fn offer(&self, time: SystemTime, ip_pkg: &Ipv4Packet, local: bool) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let queue = match *queue {
+            Some(ref queue) => queue,
+            None => return false,
+        };
+        let mut packet = ip_pkg.packet().to_vec();
+        if local {
+            let translated = match *self.nat.read().unwrap() {
+                Some(ref nat) => nat.translate_inbound(&mut packet),
+                None => false,
+            };
+            if !translated {
+                return false;
+            }
+        }
+        let forwarded = ForwardedPacket {
+            time: time,
+            interface: self.interface.clone(),
+            packet: packet,
+        };
+        queue.send(forwarded).is_ok()
+    }
+}
+
+/// Enables forwarding on `stack` and spawns a thread forwarding the packets.
+/// The thread quits when forwarding is disabled with
+/// `NetworkStack::disable_forwarding`.
+pub fn spawn_forwarding(stack: Arc<Mutex<NetworkStack>>) {
+    let packets = stack.lock().unwrap().enable_forwarding();
+    thread::spawn(move || {
+        while let Ok(packet) = packets.recv() {
+            if let Err(e) = stack.lock().unwrap().forward(packet) {
+                debug!("Unable to forward packet: {:?}", e);
+            }
+        }
+        debug!("Forwarding thread is quitting");
+    });
+}
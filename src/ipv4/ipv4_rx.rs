@@ -1,18 +1,25 @@
 use {RxError, RxResult};
+use checksum::RxChecksumControl;
+use clock::{self, Clock};
 use ethernet::EthernetListener;
+use firewall::{Firewall, Hook, PacketInfo};
+use malformed::{self, Layer, Malformed};
+use stats::Counters;
 
 use pnet::packet::Packet;
 use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
 use pnet::packet::ip::IpNextHeaderProtocol;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 
+use ipnetwork::Ipv4Network;
+
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::mpsc::Sender;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::{MORE_FRAGMENTS, NO_FLAGS};
+use super::{ForwardingControl, MORE_FRAGMENTS, NO_FLAGS};
 use util::Buffer;
 
 /// Anyone interested in receiving IPv4 packets from `Ipv4` must implement this.
@@ -41,49 +48,279 @@ impl Ipv4Listener for BasicIpv4Listener {
     }
 }
 
-/// Type binding for how the listeners in `Ipv4Rx` are structured.
-pub type IpListenerLookup = HashMap<Ipv4Addr, HashMap<IpNextHeaderProtocol, Box<Ipv4Listener>>>;
+/// Type binding for how the listeners in `Ipv4Rx` are structured. Received
+/// packets only take the read lock of the lookup, and the lock of the one
+/// listener they go to, so they do not wait for each other or for listeners
+/// being added.
+pub type IpListenerLookup = HashMap<Ipv4Addr,
+                                    HashMap<IpNextHeaderProtocol, Mutex<Box<Ipv4Listener>>>>;
 
 // Header fields that are used to identify fragments as belonging to the same
 // packet
 type FragmentIdent = (Ipv4Addr, Ipv4Addr, u16);
 
+/// Limits on the memory an `Ipv4Rx` spends on reassembling fragmented
+/// packets. Packets exceeding them are discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyLimits {
+    /// Maximum number of bytes buffered for all incomplete packets
+    pub max_bytes: usize,
+    /// Maximum number of bytes buffered for incomplete packets from one
+    /// source address
+    pub max_bytes_per_source: usize,
+    /// How long to wait for the remaining fragments of a packet
+    pub timeout: Duration,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> ReassemblyLimits {
+        ReassemblyLimits {
+            max_bytes: 4 * 1024 * 1024,
+            max_bytes_per_source: 256 * 1024,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Counters of packets discarded during reassembly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// Packets with a fragment overlapping already received data
+    pub overlapping: u64,
+    /// Packets that would have exceeded a `ReassemblyLimits` memory limit
+    pub over_limit: u64,
+    /// Packets that were not complete within the timeout
+    pub timed_out: u64,
+    /// Fragments rejected for other reasons, such as arriving out of order
+    pub invalid: u64,
+}
+
+impl ReassemblyStats {
+    /// Adds the counters of `other` to these.
+    pub fn add(&mut self, other: &ReassemblyStats) {
+        self.overlapping += other.overlapping;
+        self.over_limit += other.over_limit;
+        self.timed_out += other.timed_out;
+        self.invalid += other.invalid;
+    }
+}
+
+/// The `ReassemblyLimits` and `ReassemblyStats` of `Ipv4Rx` instances,
+/// shared so they can be changed and read while receiving.
+#[derive(Debug)]
+pub struct ReassemblyControl {
+    limits: RwLock<ReassemblyLimits>,
+    stats: Mutex<ReassemblyStats>,
+    /// What the reassembly timeout is counted on
+    clock: Arc<Clock>,
+}
+
+impl ReassemblyControl {
+    pub fn new(limits: ReassemblyLimits) -> ReassemblyControl {
+        Self::with_clock(limits, clock::system())
+    }
+
+    /// Same as `new`, but times out packets under reassembly by `clock`.
+    pub fn with_clock(limits: ReassemblyLimits, clock: Arc<Clock>) -> ReassemblyControl {
+        ReassemblyControl {
+            limits: RwLock::new(limits),
+            stats: Mutex::new(ReassemblyStats::default()),
+            clock: clock,
+        }
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn limits(&self) -> ReassemblyLimits {
+        *self.limits.read().unwrap()
+    }
+
+    pub fn set_limits(&self, limits: ReassemblyLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn count<F: FnOnce(&mut ReassemblyStats)>(&self, f: F) {
+        f(&mut *self.stats.lock().unwrap());
+    }
+}
+
+impl Default for ReassemblyControl {
+    fn default() -> ReassemblyControl {
+        ReassemblyControl::new(ReassemblyLimits::default())
+    }
+}
+
+/// A packet under reassembly.
+struct PartialPacket {
+    buffer: Buffer,
+    /// Length of the complete packet, 0 until the last fragment arrived
+    total_length: usize,
+    started: Instant,
+}
+
 /// Listener and parser for IPv4 packets. Receives ethernet frames from the
 /// `EthernetRx` it's owned by and forwards them to the correct `Ipv4Listener`.
 /// Will cache and reassemble fragmented packets before forwarding them.
 pub struct Ipv4Rx {
-    listeners: Arc<Mutex<IpListenerLookup>>,
-    buffers: HashMap<FragmentIdent, (Buffer, usize)>,
+    listeners: Arc<RwLock<IpListenerLookup>>,
+    networks: Arc<RwLock<Vec<Ipv4Network>>>,
+    reassembly: Arc<ReassemblyControl>,
+    forwarding: Arc<ForwardingControl>,
+    firewall: Arc<Firewall>,
+    buffers: HashMap<FragmentIdent, PartialPacket>,
+    buffered_bytes: usize,
+    buffered_bytes_per_source: HashMap<Ipv4Addr, usize>,
+    /// Where the packets failing to parse are recorded, if anywhere
+    malformed: Option<Arc<Malformed>>,
+    /// Where the packets dropped are counted, if anywhere
+    counters: Option<Arc<Counters>>,
+    checksums: Arc<RxChecksumControl>,
 }
 
 impl Ipv4Rx {
     /// Creates a new `Ipv4Rx` with the given listeners. Listeners can't be
     /// changed later. Returns the instance casted for easy addition to
     /// the `EthernetRx` listener `Vec`.
-    pub fn new(listeners: Arc<Mutex<IpListenerLookup>>) -> Box<EthernetListener> {
-        let this = Ipv4Rx {
+    pub fn new(listeners: Arc<RwLock<IpListenerLookup>>) -> Box<EthernetListener> {
+        Self::with_networks(listeners, Arc::new(RwLock::new(Vec::new())))
+    }
+
+    /// Same as `new`, but also delivers packets to the broadcast address of
+    /// any of `networks` to the listeners of the address of that network.
+    /// Packets to the limited broadcast address are always delivered to the
+    /// listeners of all addresses, and so are packets to multicast groups.
+    /// Which groups arrive at all is up to the multicast MAC addresses the
+    /// interface joined, see `StackInterface::join_multicast`.
+    pub fn with_networks(listeners: Arc<RwLock<IpListenerLookup>>,
+                         networks: Arc<RwLock<Vec<Ipv4Network>>>)
+                         -> Box<EthernetListener> {
+        let reassembly = Arc::new(ReassemblyControl::default());
+        Self::with_reassembly(listeners, networks, reassembly)
+    }
+
+    /// Same as `with_networks`, but reassembles fragments within the limits
+    /// of `reassembly` and counts discarded packets there.
+    pub fn with_reassembly(listeners: Arc<RwLock<IpListenerLookup>>,
+                           networks: Arc<RwLock<Vec<Ipv4Network>>>,
+                           reassembly: Arc<ReassemblyControl>)
+                           -> Box<EthernetListener> {
+        let forwarding = Arc::new(ForwardingControl::default());
+        Self::with_forwarding(listeners, networks, reassembly, forwarding)
+    }
+
+    /// Same as `with_reassembly`, but passes packets taken by `forwarding`
+    /// on for forwarding instead of delivering them locally.
+    pub fn with_forwarding(listeners: Arc<RwLock<IpListenerLookup>>,
+                           networks: Arc<RwLock<Vec<Ipv4Network>>>,
+                           reassembly: Arc<ReassemblyControl>,
+                           forwarding: Arc<ForwardingControl>)
+                           -> Box<EthernetListener> {
+        let firewall = Arc::new(Firewall::default());
+        Self::with_firewall(listeners, networks, reassembly, forwarding, firewall)
+    }
+
+    /// Same as `with_forwarding`, but filters the received packets in the
+    /// `Prerouting` and `Input` hooks of `firewall`.
+    pub fn with_firewall(listeners: Arc<RwLock<IpListenerLookup>>,
+                         networks: Arc<RwLock<Vec<Ipv4Network>>>,
+                         reassembly: Arc<ReassemblyControl>,
+                         forwarding: Arc<ForwardingControl>,
+                         firewall: Arc<Firewall>)
+                         -> Box<EthernetListener> {
+        let this = Self::unboxed(listeners, networks, reassembly, forwarding, firewall);
+        Box::new(this) as Box<EthernetListener>
+    }
+
+    /// Same as `with_firewall`, but returns the `Ipv4Rx` itself, such as for
+    /// a `Chain`.
+    pub fn unboxed(listeners: Arc<RwLock<IpListenerLookup>>,
+                   networks: Arc<RwLock<Vec<Ipv4Network>>>,
+                   reassembly: Arc<ReassemblyControl>,
+                   forwarding: Arc<ForwardingControl>,
+                   firewall: Arc<Firewall>)
+                   -> Ipv4Rx {
+        Ipv4Rx {
             listeners: listeners,
+            networks: networks,
+            reassembly: reassembly,
+            forwarding: forwarding,
+            firewall: firewall,
             buffers: HashMap::new(),
-        };
-        Box::new(this) as Box<EthernetListener>
+            buffered_bytes: 0,
+            buffered_bytes_per_source: HashMap::new(),
+            malformed: None,
+            counters: None,
+            checksums: Arc::new(RxChecksumControl::default()),
+        }
+    }
+
+    /// Records the packets failing to parse in `malformed` from now on, as
+    /// failing at `Layer::Ipv4`. Only the IPv4 header and reassembly count,
+    /// what the listeners fail to parse is theirs to record. See
+    /// `malformed::Guarded`.
+    pub fn set_malformed(&mut self, malformed: Arc<Malformed>) {
+        self.malformed = Some(malformed);
+    }
+
+    /// Counts the packets dropped in `counters` from now on, as dropped at
+    /// `Layer::Ipv4`, the ones no address or protocol listens for and the
+    /// ones the firewall drops.
+    pub fn set_counters(&mut self, counters: Arc<Counters>) {
+        self.counters = Some(counters);
+    }
+
+    /// Counts a packet dropped because of `error`, and returns the error.
+    fn dropped(&self, error: RxError) -> RxError {
+        if let Some(ref counters) = self.counters {
+            counters.dropped(Layer::Ipv4);
+        }
+        error
+    }
+
+    /// Only verifies the checksums of IPv4 headers while `checksums` says
+    /// so from now on.
+    pub fn set_checksums(&mut self, checksums: Arc<RxChecksumControl>) {
+        self.checksums = checksums;
+    }
+
+    /// Returns the complete packet in `eth_pkg`, if it is not a fragment
+    /// of a packet still under reassembly.
+    fn reassemble<'a>(&mut self,
+                      eth_pkg: &'a EthernetPacket)
+                      -> Result<Option<Ipv4Packet<'a>>, RxError> {
+        let ip_pkg = Self::get_ipv4_pkg(eth_pkg, self.checksums.ipv4())?;
+        if Self::is_fragment(&ip_pkg) {
+            self.save_fragment(ip_pkg)
+        } else {
+            Ok(Some(ip_pkg))
+        }
     }
 
     /// Returns the Ipv4Packet contained in this EthernetPacket if it looks
-    /// valid
-    fn get_ipv4_pkg<'a>(eth_pkg: &'a EthernetPacket) -> Result<Ipv4Packet<'a>, RxError> {
+    /// valid. Its checksum is only verified if `verify_checksum`.
+    fn get_ipv4_pkg<'a>(eth_pkg: &'a EthernetPacket,
+                        verify_checksum: bool)
+                        -> Result<Ipv4Packet<'a>, RxError> {
         let eth_payload = eth_pkg.payload();
         if eth_payload.len() < Ipv4Packet::minimum_packet_size() {
             return Err(RxError::InvalidLength);
         }
-        let total_length = {
+        let (total_length, header_length) = {
             let ip_pkg = Ipv4Packet::new(eth_payload).unwrap();
-            ip_pkg.get_total_length() as usize
+            (ip_pkg.get_total_length() as usize, ip_pkg.get_header_length() as usize * 4)
         };
-        if total_length > eth_payload.len() || total_length < Ipv4Packet::minimum_packet_size() {
+        if total_length > eth_payload.len() || header_length > total_length ||
+           header_length < Ipv4Packet::minimum_packet_size() {
             Err(RxError::InvalidLength)
         } else {
             let ip_pkg = Ipv4Packet::new(&eth_payload[..total_length]).unwrap();
-            if ip_pkg.get_checksum() != checksum(&ip_pkg) {
+            if verify_checksum && ip_pkg.get_checksum() != checksum(&ip_pkg) {
                 Err(RxError::InvalidChecksum)
             } else {
                 Ok(ip_pkg)
@@ -99,58 +336,122 @@ impl Ipv4Rx {
 
     /// Saves a packet fragment to a buffer for reassembly. If the Ipv4Packet
     /// becomes complete with the addition of `ip_pkg` then the complete
-    /// reassembled packet is returned in a Buffer.
+    /// reassembled packet is returned.
+    ///
+    /// A packet is discarded as a whole if it would exceed the
+    /// `ReassemblyLimits` or if one of its fragments overlaps data already
+    /// received, since overlapping fragments are only sent by attackers.
     fn save_fragment(&mut self,
                      ip_pkg: Ipv4Packet)
                      -> Result<Option<Ipv4Packet<'static>>, RxError> {
+        let limits = self.reassembly.limits();
+        let now = self.reassembly.now();
+        self.expire(now, limits.timeout);
         let ident = Self::get_fragment_identification(&ip_pkg);
-        if !self.buffers.contains_key(&ident) {
-            try!(self.start_new_fragment(ip_pkg, ident));
-            Ok(None)
+        let (offset, data) = if ip_pkg.get_fragment_offset() == 0 {
+            (0, ip_pkg.packet())
         } else {
-            let pkg_done = {
-                let &mut (ref mut buffer, ref mut total_length) =
-                    self.buffers.get_mut(&ident).unwrap();
-                let offset = Ipv4Packet::minimum_packet_size() +
-                             ip_pkg.get_fragment_offset() as usize * 8;
-                // Check if this is the last fragment
-                if (ip_pkg.get_flags() & MORE_FRAGMENTS) == 0 {
-                    if *total_length != 0 {
-                        return Err(RxError::InvalidContent);
-                    } else {
-                        *total_length = offset + ip_pkg.payload().len();
-                    }
+            (Ipv4Packet::minimum_packet_size() + ip_pkg.get_fragment_offset() as usize * 8,
+             ip_pkg.payload())
+        };
+
+        let received = match self.buffers.get(&ident) {
+            Some(partial) => partial.buffer.len(),
+            None if offset == 0 => 0,
+            None => {
+                self.reassembly.count(|stats| stats.invalid += 1);
+                return Err(RxError::InvalidContent);
+            }
+        };
+        if offset < received {
+            self.remove(&ident);
+            self.reassembly.count(|stats| stats.overlapping += 1);
+            return Err(RxError::InvalidContent);
+        }
+        let source_bytes = self.buffered_bytes_per_source.get(&ident.0).cloned().unwrap_or(0);
+        if self.buffered_bytes + data.len() > limits.max_bytes ||
+           source_bytes + data.len() > limits.max_bytes_per_source {
+            self.remove(&ident);
+            self.reassembly.count(|stats| stats.over_limit += 1);
+            return Err(RxError::ReassemblyLimit);
+        }
+
+        let pkg_done = {
+            let partial = self.buffers.entry(ident).or_insert_with(|| {
+                PartialPacket {
+                    buffer: Buffer::new(::std::u16::MAX as usize),
+                    total_length: 0,
+                    started: now,
                 }
-                match buffer.push(offset, ip_pkg.payload()) {
-                    Ok(i) => i == *total_length,
-                    Err(_) => {
-                        return Err(RxError::InvalidContent);
-                    }
+            });
+            // Check if this is the last fragment
+            if (ip_pkg.get_flags() & MORE_FRAGMENTS) == 0 {
+                if partial.total_length != 0 {
+                    None
+                } else {
+                    partial.total_length = offset + data.len();
+                    partial.buffer.push(offset, data).ok().map(|i| i == partial.total_length)
                 }
-            };
-            if pkg_done {
-                let (buffer, len) = self.buffers.remove(&ident).unwrap();
-                let mut ip_pkg = MutableIpv4Packet::owned(buffer.into_vec()).unwrap();
+            } else {
+                partial.buffer.push(offset, data).ok().map(|i| i == partial.total_length)
+            }
+        };
+        match pkg_done {
+            None => {
+                self.reassembly.count(|stats| stats.invalid += 1);
+                Err(RxError::InvalidContent)
+            }
+            Some(false) => {
+                self.buffered_bytes += data.len();
+                *self.buffered_bytes_per_source.entry(ident.0).or_insert(0) += data.len();
+                Ok(None)
+            }
+            Some(true) => {
+                // Account for the data just pushed, then release all of it
+                self.buffered_bytes += data.len();
+                *self.buffered_bytes_per_source.entry(ident.0).or_insert(0) += data.len();
+                let partial = self.remove(&ident).unwrap();
+                let len = partial.total_length;
+                let mut ip_pkg = MutableIpv4Packet::owned(partial.buffer.into_vec()).unwrap();
                 ip_pkg.set_flags(NO_FLAGS);
                 ip_pkg.set_total_length(len as u16);
                 let csum = checksum(&ip_pkg.to_immutable());
                 ip_pkg.set_checksum(csum);
                 Ok(Some(ip_pkg.consume_to_immutable()))
-            } else {
-                Ok(None)
             }
         }
     }
 
-    fn start_new_fragment(&mut self, ip_pkg: Ipv4Packet, ident: FragmentIdent) -> RxResult {
-        if ip_pkg.get_fragment_offset() == 0 {
-            let mut buffer = Buffer::new(::std::u16::MAX as usize);
-            buffer.push(0, ip_pkg.packet()).unwrap();
-            self.buffers.insert(ident, (buffer, 0));
-            Ok(())
-        } else {
-            Err(RxError::InvalidContent)
+    /// Drops all packets that have been under reassembly for longer than
+    /// `timeout` at `now`.
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        let expired = self.buffers
+            .iter()
+            .filter(|&(_, partial)| now.duration_since(partial.started) >= timeout)
+            .map(|(ident, _)| *ident)
+            .collect::<Vec<_>>();
+        for ident in expired {
+            self.remove(&ident);
+            self.reassembly.count(|stats| stats.timed_out += 1);
+        }
+    }
+
+    /// Removes a packet under reassembly and releases its memory accounting.
+    fn remove(&mut self, ident: &FragmentIdent) -> Option<PartialPacket> {
+        let partial = self.buffers.remove(ident);
+        if let Some(ref partial) = partial {
+            let len = partial.buffer.len();
+            self.buffered_bytes -= len;
+            let remaining = {
+                let source_bytes = self.buffered_bytes_per_source.get_mut(&ident.0).unwrap();
+                *source_bytes -= len;
+                *source_bytes
+            };
+            if remaining == 0 {
+                self.buffered_bytes_per_source.remove(&ident.0);
+            }
         }
+        partial
     }
 
     fn get_fragment_identification(ip_pkg: &Ipv4Packet) -> FragmentIdent {
@@ -162,33 +463,127 @@ impl Ipv4Rx {
 
     /// Forwards a complete packet to its listener
     fn forward(&self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
+        if self.firewall.is_empty() {
+            self.route(time, ip_pkg)
+        } else {
+            let ip_pkg = self.filter(Hook::Prerouting, &ip_pkg)?;
+            self.route(time, ip_pkg)
+        }
+    }
+
+    /// Delivers a packet to local listeners, or passes it on for forwarding
+    fn route(&self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
         let dest_ip = ip_pkg.get_destination();
-        let next_level_protocol = ip_pkg.get_next_level_protocol();
         trace!("Ipv4 got a packet to {}!", dest_ip);
-        let mut listeners = self.listeners.lock().unwrap();
-        if let Some(mut listeners) = listeners.get_mut(&dest_ip) {
-            if let Some(mut listener) = listeners.get_mut(&next_level_protocol) {
-                listener.recv(time, ip_pkg)
+        let listeners = self.listeners.read().unwrap();
+        let local_ips = if listeners.contains_key(&dest_ip) {
+            if self.forwarding.offer(time, &ip_pkg, true) {
+                return Ok(());
+            }
+            vec![dest_ip]
+        } else {
+            let local_ips = self.broadcast_recipients(dest_ip, &listeners);
+            if local_ips.is_empty() && !dest_ip.is_broadcast() && !dest_ip.is_multicast() &&
+               self.forwarding.offer(time, &ip_pkg, false) {
+                return Ok(());
+            }
+            local_ips
+        };
+        if local_ips.is_empty() {
+            return Err(self.dropped(RxError::NoListener(format!("Ipv4 {}", dest_ip))));
+        }
+        if self.firewall.is_empty() {
+            self.deliver(&listeners, local_ips, time, ip_pkg)
+        } else {
+            let ip_pkg = self.filter(Hook::Input, &ip_pkg)?;
+            self.deliver(&listeners, local_ips, time, ip_pkg)
+        }
+    }
+
+    /// Delivers a packet to the listeners of all of `local_ips`
+    fn deliver(&self,
+               listeners: &IpListenerLookup,
+               local_ips: Vec<Ipv4Addr>,
+               time: SystemTime,
+               ip_pkg: Ipv4Packet)
+               -> RxResult {
+        let mut result = Ok(());
+        for (i, local_ip) in local_ips.into_iter().enumerate() {
+            let pkg = Ipv4Packet::new(ip_pkg.packet()).unwrap();
+            let local_result = self.forward_to(listeners, local_ip, time, pkg);
+            if i == 0 || result.is_err() {
+                result = local_result;
+            }
+        }
+        result
+    }
+
+    /// Runs a packet through `hook` of the firewall. Returns the packet,
+    /// possibly modified, if it was accepted.
+    fn filter(&self, hook: Hook, ip_pkg: &Ipv4Packet) -> Result<Ipv4Packet<'static>, RxError> {
+        let info = PacketInfo {
+            in_interface: self.forwarding.interface(),
+            out_interface: None,
+            state: None,
+        };
+        let mut packet = ip_pkg.packet().to_vec();
+        if !self.firewall.filter(hook, &info, &mut packet) {
+            return Err(self.dropped(RxError::Filtered));
+        }
+        Ipv4Packet::owned(packet).ok_or(RxError::InvalidLength)
+    }
+
+    /// Returns the local addresses a packet to `dest_ip` should be delivered
+    /// to if it's a broadcast, or to a multicast group.
+    fn broadcast_recipients(&self,
+                            dest_ip: Ipv4Addr,
+                            listeners: &IpListenerLookup)
+                            -> Vec<Ipv4Addr> {
+        if dest_ip.is_broadcast() || dest_ip.is_multicast() {
+            listeners.keys().cloned().collect()
+        } else {
+            let networks = self.networks.read().unwrap();
+            networks.iter()
+                .filter(|net| net.prefix() < 31 && net.broadcast() == dest_ip)
+                .map(|net| net.ip())
+                .filter(|ip| listeners.contains_key(ip))
+                .collect()
+        }
+    }
+
+    fn forward_to(&self,
+                  listeners: &IpListenerLookup,
+                  local_ip: Ipv4Addr,
+                  time: SystemTime,
+                  ip_pkg: Ipv4Packet)
+                  -> RxResult {
+        let next_level_protocol = ip_pkg.get_next_level_protocol();
+        if let Some(listeners) = listeners.get(&local_ip) {
+            if let Some(listener) = listeners.get(&next_level_protocol) {
+                listener.lock().unwrap().recv(time, ip_pkg)
             } else {
-                Err(RxError::NoListener(format!("Ipv4 {:?}", next_level_protocol)))
+                Err(self.dropped(RxError::NoListener(format!("Ipv4 {:?}", next_level_protocol))))
             }
         } else {
-            Err(RxError::NoListener(format!("Ipv4 {}", dest_ip)))
+            Err(self.dropped(RxError::NoListener(format!("Ipv4 {}", local_ip))))
         }
     }
 }
 
 impl EthernetListener for Ipv4Rx {
     fn recv(&mut self, time: SystemTime, eth_pkg: &EthernetPacket) -> RxResult {
-        let ip_pkg = try!(Self::get_ipv4_pkg(eth_pkg));
-        if Self::is_fragment(&ip_pkg) {
-            if let Some(reassembled_pkg) = try!(self.save_fragment(ip_pkg)) {
-                self.forward(time, reassembled_pkg)
-            } else {
-                Ok(())
+        match self.reassemble(eth_pkg) {
+            Ok(Some(ip_pkg)) => self.forward(time, ip_pkg),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                if !malformed::is_malformed(&e) {
+                    return Err(self.dropped(e));
+                }
+                if let Some(ref malformed) = self.malformed {
+                    malformed.record(Layer::Ipv4, &e, time, eth_pkg.packet());
+                }
+                Err(e)
             }
-        } else {
-            self.forward(time, ip_pkg)
         }
     }
 
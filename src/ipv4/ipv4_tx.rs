@@ -1,51 +1,16 @@
-use {Payload, HasPayload, BasicPayload, TxResult};
-use ethernet::EthernetPayload;
+use {Payload, TxError, TxResult};
+use corrupt::Ipv4Corruption;
+use ethernet::{BasicEthernetPayload, EthernetPayload};
 use ethernet::EthernetTx;
+use proto::ipv4::{Ipv4Builder, Ipv4Payload};
 
-use pnet::packet::{MutablePacket, Packet};
 use pnet::packet::ethernet::{EtherType, EtherTypes};
-use pnet::packet::ip::IpNextHeaderProtocol;
-use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::ipv4::Ipv4Packet;
 
+use std::cell::RefCell;
 use std::net::Ipv4Addr;
 
-use super::{MORE_FRAGMENTS, NO_FLAGS};
-
-pub trait Ipv4Payload: Payload {
-    fn next_level_protocol(&self) -> IpNextHeaderProtocol;
-}
-
-#[derive(Clone)]
-pub struct BasicIpv4Payload<'a> {
-    next_level_protocol: IpNextHeaderProtocol,
-    payload: BasicPayload<'a>,
-}
-
-impl<'a> BasicIpv4Payload<'a> {
-    pub fn new(next_level_protocol: IpNextHeaderProtocol, payload: &'a [u8]) -> Self {
-        BasicIpv4Payload {
-            next_level_protocol: next_level_protocol,
-            payload: BasicPayload::new(payload),
-        }
-    }
-}
-
-impl<'a> Ipv4Payload for BasicIpv4Payload<'a> {
-    fn next_level_protocol(&self) -> IpNextHeaderProtocol {
-        self.next_level_protocol
-    }
-}
-
-impl<'a> HasPayload for BasicIpv4Payload<'a> {
-    fn get_payload(&self) -> &Payload {
-        &self.payload
-    }
-
-    fn get_payload_mut(&mut self) -> &mut Payload {
-        &mut self.payload
-    }
-}
-
+use super::DEFAULT_TTL;
 
 pub trait Ipv4Tx {
     fn src(&self) -> Ipv4Addr;
@@ -61,6 +26,9 @@ pub struct Ipv4TxImpl<T: EthernetTx> {
     mtu: usize,
     ethernet: T,
     next_identification: u16,
+    ttl: u8,
+    tos: u8,
+    corruption: Ipv4Corruption,
 }
 
 impl<T: EthernetTx> Ipv4TxImpl<T> {
@@ -77,9 +45,47 @@ impl<T: EthernetTx> Ipv4TxImpl<T> {
             mtu: mtu,
             ethernet: ethernet,
             next_identification: 0,
+            ttl: DEFAULT_TTL,
+            tos: 0,
+            corruption: Ipv4Corruption::default(),
         }
     }
 
+    /// Sets the time to live of the packets sent after this.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    /// Sets the type of service byte, the DSCP and ECN bits, of the packets
+    /// sent after this.
+    pub fn set_tos(&mut self, tos: u8) {
+        self.tos = tos;
+    }
+
+    pub fn tos(&self) -> u8 {
+        self.tos
+    }
+
+    /// Makes the errors of `corruption` in the packets sent from now on, see
+    /// `corrupt`. Corrupted packets are not fragmented, sending one larger
+    /// than the MTU fails with `TxError::TooLargePayload`.
+    pub fn set_corruption(&mut self, corruption: Ipv4Corruption) {
+        self.corruption = corruption;
+    }
+
+    pub fn corruption(&self) -> Ipv4Corruption {
+        self.corruption
+    }
+
+    /// Returns the MTU packets are fragmented to fit.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
     pub fn max_payload_per_fragment(&self) -> usize {
         (self.mtu - Ipv4Packet::minimum_packet_size()) & !0b111
     }
@@ -96,95 +102,74 @@ impl<T: EthernetTx> Ipv4Tx for Ipv4TxImpl<T> {
 
     fn send<P: Ipv4Payload>(&mut self, payload: P) -> TxResult {
         let payload_len = payload.len() as usize;
-        let builder = Ipv4Builder::new(self.src, self.dst, self.next_identification, payload);
+        let mut builder = Ipv4Builder::new(self.src, self.dst, self.next_identification, payload);
+        builder.set_ttl(self.ttl);
+        builder.set_tos(self.tos);
         self.next_identification.wrapping_add(1);
 
+        if self.corruption != Ipv4Corruption::default() {
+            let mut packet = vec![0; payload_len + Ipv4Packet::minimum_packet_size()];
+            if packet.len() > self.mtu {
+                return Err(TxError::TooLargePayload);
+            }
+            builder.build(&mut packet);
+            self.corruption.apply(&mut packet);
+            let len = packet.len();
+            return self.ethernet.send(1, len, BasicEthernetPayload::new(EtherTypes::Ipv4, &packet));
+        }
+        // Only fragments other than the last need a multiple of eight bytes
         let max_payload_per_fragment = self.max_payload_per_fragment();
-        if payload_len <= max_payload_per_fragment {
+        if payload_len + Ipv4Packet::minimum_packet_size() <= self.mtu {
             let size = payload_len + Ipv4Packet::minimum_packet_size();
             self.ethernet.send(1, size, builder)
         } else {
             let fragments = 1 + ((payload_len - 1) / max_payload_per_fragment);
             let size = max_payload_per_fragment + Ipv4Packet::minimum_packet_size();
-            self.ethernet.send(fragments, size, builder)
+            // The last fragment only gets what is left of the payload
+            let last_size = payload_len - (fragments - 1) * max_payload_per_fragment +
+                            Ipv4Packet::minimum_packet_size();
+            // All fragments go out in one `send_all`, so a tx-object that
+            // became outdated fails the whole packet before any fragment is
+            // sent, and a retry does not send the first fragments twice.
+            let builder = RefCell::new(builder);
+            let fragments = (0..fragments).map(|i| {
+                Fragment {
+                    builder: &builder,
+                    size: if i + 1 == fragments { last_size } else { size },
+                }
+            });
+            self.ethernet.send_all(fragments)
         }
     }
 }
 
-
-pub struct Ipv4Builder<P: Ipv4Payload> {
-    src: Ipv4Addr,
-    dst: Ipv4Addr,
-    offset: usize,
-    identification: u16,
-    payload: P,
-    payload_len: usize,
-}
-
-impl<P: Ipv4Payload> Ipv4Builder<P> {
-    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, identification: u16, payload: P) -> Self {
-        let payload_len = payload.len();
-        Ipv4Builder {
-            src: src,
-            dst: dst,
-            offset: 0,
-            identification: identification,
-            payload: payload,
-            payload_len: payload_len,
-        }
-    }
+/// One fragment of the packet an `Ipv4Builder` builds, `size` bytes large.
+/// The builder moves on to the next fragment every time one is built.
+struct Fragment<'a, P: Ipv4Payload + 'a> {
+    builder: &'a RefCell<Ipv4Builder<P>>,
+    size: usize,
 }
 
-impl<P: Ipv4Payload> EthernetPayload for Ipv4Builder<P> {
+impl<'a, P: Ipv4Payload> EthernetPayload for Fragment<'a, P> {
     fn ether_type(&self) -> EtherType {
         EtherTypes::Ipv4
     }
 }
 
-impl<P: Ipv4Payload> Payload for Ipv4Builder<P> {
+impl<'a, P: Ipv4Payload> Payload for Fragment<'a, P> {
     fn len(&self) -> usize {
-        Ipv4Packet::minimum_packet_size() + self.payload.len()
+        self.size
     }
 
     fn build(&mut self, buffer: &mut [u8]) {
-        let mut pkg = MutableIpv4Packet::new(buffer).expect("Too small buffer given");
-        pkg.set_version(4);
-        pkg.set_dscp(0); // https://en.wikipedia.org/wiki/Differentiated_services
-        pkg.set_ecn(0); // https://en.wikipedia.org/wiki/Explicit_Congestion_Notification
-        pkg.set_ttl(40);
-        // ip_pkg.set_options(vec![]); // We currently don't support options
-        pkg.set_header_length(5); // 5 is for no option fields
-        pkg.set_identification(self.identification);
-        pkg.set_source(self.src);
-        pkg.set_destination(self.dst);
-        pkg.set_fragment_offset((self.offset / 8) as u16);
-
-        let bytes_remaining = self.payload_len - self.offset;
-        let bytes_max = pkg.payload().len();
-        let payload_size = if bytes_remaining <= bytes_max {
-            pkg.set_flags(NO_FLAGS);
-            bytes_remaining
-        } else {
-            pkg.set_flags(MORE_FRAGMENTS);
-            bytes_max & !0b111 // Round down to divisable by 8
-        };
-        let total_length = payload_size + Ipv4Packet::minimum_packet_size();
-        pkg.set_total_length(total_length as u16);
-
-        pkg.set_next_level_protocol(self.payload.next_level_protocol());
-        self.payload.build(&mut pkg.payload_mut()[..payload_size]);
-
-        let checksum = checksum(&pkg.to_immutable());
-        pkg.set_checksum(checksum);
-
-        self.offset += payload_size;
+        self.builder.borrow_mut().build(buffer)
     }
 }
 
 
 #[cfg(test)]
 mod ipv4_tx_tests {
-    use {TxResult, TxError};
+    use {TxError, TxResult, TxSent};
     use ethernet::{EthernetPayload, EthernetTx};
 
     use pnet::packet::Packet;
@@ -193,9 +178,12 @@ mod ipv4_tx_tests {
     use pnet::util::MacAddr;
 
     use std::error::Error;
+    use std::io;
     use std::net::Ipv4Addr;
     use std::sync::mpsc;
 
+    use proto::ipv4::BasicIpv4Payload;
+
     use super::*;
     use super::super::MORE_FRAGMENTS;
 
@@ -232,9 +220,9 @@ mod ipv4_tx_tests {
                 payload.build(&mut buffer[..]);
                 self.chan
                     .send(buffer.into_boxed_slice())
-                    .map_err(|e| TxError::Other(e.description().to_owned()))?;
+                    .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.description()))?;
             }
-            Ok(())
+            Ok(TxSent::new(packets, packet_size))
         }
     }
 
@@ -266,7 +254,7 @@ mod ipv4_tx_tests {
 
         let data = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
         let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Tcp, data);
-        testee.send(payload).unwrap();
+        assert_eq!(testee.send(payload).unwrap(), TxSent::new(1, 28) + TxSent::new(1, 22));
 
         let pkg1 = rx.try_recv().unwrap();
         let pkg2 = rx.try_recv().unwrap();
@@ -275,6 +263,69 @@ mod ipv4_tx_tests {
         let id1 = check_pkg(&pkg1, *SRC_IP, *DST_IP, true, 0, &[0, 1, 2, 3, 4, 5, 6, 7]);
         let id2 = check_pkg(&pkg2, *SRC_IP, *DST_IP, false, 8, &[8, 9]);
         assert_eq!(id1, id2);
+        // Nothing is sent after the end of the last fragment
+        assert_eq!(pkg2.len(), 20 + 2);
+    }
+
+    /// Refuses the first batch it's given like an outdated tx-object, without
+    /// sending any of it.
+    struct RefusingEthernetTx {
+        refused: bool,
+        tx: MockEthernetTx,
+    }
+
+    impl EthernetTx for RefusingEthernetTx {
+        fn src(&self) -> MacAddr {
+            self.tx.src()
+        }
+
+        fn dst(&self) -> MacAddr {
+            self.tx.dst()
+        }
+
+        fn send<P>(&mut self, _packets: usize, _packet_size: usize, _payload: P) -> TxResult
+            where P: EthernetPayload
+        {
+            panic!("Fragments sent one call at a time");
+        }
+
+        fn send_all<P, I>(&mut self, payloads: I) -> TxResult
+            where P: EthernetPayload,
+                  I: IntoIterator<Item = P>
+        {
+            if !self.refused {
+                self.refused = true;
+                return Err(TxError::InvalidTx);
+            }
+            let mut sent = TxSent::default();
+            for payload in payloads {
+                let len = payload.len();
+                sent += self.tx.send(1, len, payload)?;
+            }
+            Ok(sent)
+        }
+    }
+
+    #[test]
+    fn tx_fragmented_fails_whole() {
+        let (eth_tx, rx) = MockEthernetTx::new();
+        let eth_tx = RefusingEthernetTx {
+            refused: false,
+            tx: eth_tx,
+        };
+        let mut testee = Ipv4TxImpl::new(eth_tx, *SRC_IP, *DST_IP, 20 + 8);
+        let data = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Tcp, data);
+        assert!(testee.send(payload.clone()).is_err());
+        // Not even the fragments before the last went out
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(testee.send(payload).unwrap(), TxSent::new(1, 28) + TxSent::new(1, 22));
+        let pkg1 = rx.try_recv().unwrap();
+        let pkg2 = rx.try_recv().unwrap();
+        assert!(rx.try_recv().is_err());
+        check_pkg(&pkg1, *SRC_IP, *DST_IP, true, 0, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        check_pkg(&pkg2, *SRC_IP, *DST_IP, false, 8, &[8, 9]);
     }
 
     #[test]
@@ -292,6 +343,20 @@ mod ipv4_tx_tests {
         check_pkg(&pkg, *SRC_IP, *DST_IP, false, 0, &payload_data);
     }
 
+    #[test]
+    fn tx_ttl_tos() {
+        let (eth_tx, rx) = MockEthernetTx::new();
+        let mut ipv4_tx = Ipv4TxImpl::new(eth_tx, *SRC_IP, *DST_IP, 1500);
+        ipv4_tx.set_ttl(7);
+        ipv4_tx.set_tos(0b1011_1001);
+        ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Tcp, &[0])).unwrap();
+
+        let buffer = rx.try_recv().unwrap();
+        let pkg = Ipv4Packet::new(&buffer).unwrap();
+        assert_eq!(pkg.get_ttl(), 7);
+        assert_eq!((pkg.get_dscp(), pkg.get_ecn()), (0b10_1110, 0b01));
+    }
+
     fn check_pkg(pkg_buffer: &[u8],
                  src: Ipv4Addr,
                  dst: Ipv4Addr,
@@ -1,18 +1,25 @@
+mod forwarding;
 mod ipv4_rx;
 mod ipv4_tx;
 
-pub use self::ipv4_rx::{BasicIpv4Listener, IpListenerLookup, Ipv4Listener, Ipv4Rx};
-pub use self::ipv4_tx::{BasicIpv4Payload, Ipv4Builder, Ipv4Payload, Ipv4Tx, Ipv4TxImpl};
+pub use self::forwarding::{ForwardedPacket, ForwardingControl, spawn_forwarding};
+pub use self::ipv4_rx::{BasicIpv4Listener, IpListenerLookup, Ipv4Listener, Ipv4Rx,
+                        ReassemblyControl, ReassemblyLimits, ReassemblyStats};
+pub use self::ipv4_tx::{Ipv4Tx, Ipv4TxImpl};
+pub use proto::ipv4::{BasicIpv4Payload, Ipv4Builder, Ipv4Payload};
 
-pub const MORE_FRAGMENTS: u8 = 0b001;
-pub const DONT_FRAGMENT: u8 = 0b010;
-pub const NO_FLAGS: u8 = 0b000;
+pub use proto::ipv4::{DEFAULT_TTL, DONT_FRAGMENT, MORE_FRAGMENTS, NO_FLAGS};
+
+/// The smallest MTU every IPv4 host must support, according to RFC 791.
+pub const MIN_MTU: usize = 68;
 
 #[cfg(test)]
 mod tests {
-    use RxError;
+    use {RxError, RxResult};
     use ethernet::EthernetListener;
 
+    use ipnetwork::Ipv4Network;
+
     use pnet::packet::MutablePacket;
     use pnet::packet::ethernet::MutableEthernetPacket;
     use pnet::packet::ip::IpNextHeaderProtocols;
@@ -20,9 +27,9 @@ mod tests {
 
     use std::collections::HashMap;
     use std::net::Ipv4Addr;
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, RwLock};
     use std::sync::mpsc::{self, Receiver};
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
 
     use super::*;
 
@@ -112,18 +119,145 @@ mod tests {
         assert!(rx.try_recv().is_err());
     }
 
+    #[test]
+    fn rx_broadcast() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let (tx, rx) = mpsc::channel();
+        let mut ip_listeners = HashMap::new();
+        ip_listeners.insert(IpNextHeaderProtocols::Icmp, Mutex::new(BasicIpv4Listener::new(tx)));
+        let mut listeners = HashMap::new();
+        listeners.insert(local_ip, ip_listeners);
+        let networks = vec![Ipv4Network::new(local_ip, 24).unwrap()];
+        let mut ipv4_rx = Ipv4Rx::with_networks(Arc::new(RwLock::new(listeners)),
+                                                Arc::new(RwLock::new(networks)));
+
+        let mut buffer = vec![0; 100];
+        for &(dst, delivered) in &[(Ipv4Addr::new(10, 0, 0, 255), true),
+                                   (Ipv4Addr::new(255, 255, 255, 255), true),
+                                   (Ipv4Addr::new(10, 0, 1, 255), false)] {
+            let mut pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+            {
+                let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+                ip_pkg.set_destination(dst);
+                ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+                ip_pkg.set_header_length(5); // No options
+                ip_pkg.set_total_length(20 + 15);
+                let csum = checksum(&ip_pkg.to_immutable());
+                ip_pkg.set_checksum(csum);
+            }
+            let result = ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable());
+            assert_eq!(result.is_ok(), delivered);
+            assert_eq!(rx.try_recv().map(|(_, pkg)| pkg.get_destination()).ok(),
+                       if delivered { Some(dst) } else { None });
+        }
+    }
+
+    #[test]
+    fn rx_fragment_overlap() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let (mut ipv4_rx, rx, reassembly) = setup_reassembly_rx(dst, ReassemblyLimits::default());
+        let mut buffer = vec![0; 100];
+
+        send_fragment(&mut *ipv4_rx, &mut buffer, dst, 0, 16, true).unwrap();
+        // Second fragment overlaps the first one by 8 bytes
+        assert_eq!(send_fragment(&mut *ipv4_rx, &mut buffer, dst, 8, 16, false),
+                   Err(RxError::InvalidContent));
+        assert_eq!(reassembly.stats().overlapping, 1);
+        // The packet was discarded, so the correct fragment can not complete it
+        assert!(send_fragment(&mut *ipv4_rx, &mut buffer, dst, 16, 8, false).is_err());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(reassembly.stats().invalid, 1);
+    }
+
+    #[test]
+    fn rx_fragment_memory_limits() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let limits = ReassemblyLimits {
+            max_bytes: 100,
+            max_bytes_per_source: 50,
+            ..ReassemblyLimits::default()
+        };
+        let (mut ipv4_rx, rx, reassembly) = setup_reassembly_rx(dst, limits);
+        let mut buffer = vec![0; 100];
+
+        // 20 byte header + 16 bytes payload fits within the per source limit
+        send_fragment(&mut *ipv4_rx, &mut buffer, dst, 0, 16, true).unwrap();
+        assert_eq!(send_fragment(&mut *ipv4_rx, &mut buffer, dst, 16, 16, true),
+                   Err(RxError::ReassemblyLimit));
+        assert_eq!(reassembly.stats().over_limit, 1);
+
+        // Memory was released, so a new packet can be reassembled
+        send_fragment(&mut *ipv4_rx, &mut buffer, dst, 0, 16, true).unwrap();
+        send_fragment(&mut *ipv4_rx, &mut buffer, dst, 16, 8, false).unwrap();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn rx_fragment_timeout() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let limits = ReassemblyLimits { timeout: Duration::from_secs(0), ..Default::default() };
+        let (mut ipv4_rx, rx, reassembly) = setup_reassembly_rx(dst, limits);
+        let mut buffer = vec![0; 100];
+
+        send_fragment(&mut *ipv4_rx, &mut buffer, dst, 0, 16, true).unwrap();
+        assert!(send_fragment(&mut *ipv4_rx, &mut buffer, dst, 16, 8, false).is_err());
+        assert_eq!(reassembly.stats().timed_out, 1);
+        assert!(rx.try_recv().is_err());
+    }
+
+    /// Sends a fragment with `len` bytes of payload at byte offset `offset`.
+    fn send_fragment(ipv4_rx: &mut EthernetListener,
+                     buffer: &mut [u8],
+                     dst: Ipv4Addr,
+                     offset: u16,
+                     len: u16,
+                     more_fragments: bool)
+                     -> RxResult {
+        let mut pkg = MutableEthernetPacket::new(buffer).unwrap();
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            ip_pkg.set_flags(if more_fragments { MORE_FRAGMENTS } else { NO_FLAGS });
+            ip_pkg.set_fragment_offset(offset / 8);
+            ip_pkg.set_identification(137);
+            ip_pkg.set_header_length(5); // No options
+            ip_pkg.set_total_length(20 + len);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable())
+    }
+
+    fn setup_reassembly_rx(dst: Ipv4Addr,
+                           limits: ReassemblyLimits)
+                           -> (Box<EthernetListener>,
+                               Receiver<(SystemTime, Ipv4Packet<'static>)>,
+                               Arc<ReassemblyControl>) {
+        let (tx, rx) = mpsc::channel();
+        let mut ip_listeners = HashMap::new();
+        ip_listeners.insert(IpNextHeaderProtocols::Icmp, Mutex::new(BasicIpv4Listener::new(tx)));
+        let mut listeners = HashMap::new();
+        listeners.insert(dst, ip_listeners);
+        let reassembly = Arc::new(ReassemblyControl::new(limits));
+        let ipv4_rx = Ipv4Rx::with_reassembly(Arc::new(RwLock::new(listeners)),
+                                              Arc::new(RwLock::new(Vec::new())),
+                                              reassembly.clone());
+        (ipv4_rx, rx, reassembly)
+    }
+
     fn setup_rx(dst: Ipv4Addr)
                 -> (Box<EthernetListener>, Receiver<(SystemTime, Ipv4Packet<'static>)>) {
         let (tx, rx) = mpsc::channel();
         let arp_listener = BasicIpv4Listener::new(tx);
 
         let mut ip_listeners = HashMap::new();
-        ip_listeners.insert(IpNextHeaderProtocols::Icmp, arp_listener);
+        ip_listeners.insert(IpNextHeaderProtocols::Icmp, Mutex::new(arp_listener));
 
         let mut listeners = HashMap::new();
         listeners.insert(dst, ip_listeners);
 
-        let listeners = Arc::new(Mutex::new(listeners));
+        let listeners = Arc::new(RwLock::new(listeners));
         let ipv4_rx = Ipv4Rx::new(listeners);
         (ipv4_rx, rx)
     }
@@ -44,6 +44,8 @@
 //! extern crate rips;
 //!
 //! let stack = rips::default_stack();
+//! // Or, just one interface configured like the host
+//! let stack = rips::NetworkStack::from_os_interface("eth0");
 //! ...
 //! ```
 //!
@@ -63,13 +65,13 @@
 //!   - [ ] Correctly pick an identification field on outgoing IPv4
 //!   - [ ] Reassembling incoming packets
 //!     - [x] Works in standard case
-//!     - [ ] Timing out caches of packets that were never completed
+//!     - [x] Timing out caches of packets that were never completed
 //!     - [ ] Support reassemble out of order fragments?
 //!   - [ ] Header options
 //!   - [ ] Routing
 //!     - [x] Works in standard case
 //!     - [ ] Invalidate existing Tx on update
-//!     - [ ] Metrics
+//!     - [x] Metrics
 //!   - [ ] Possible to change TTL
 //! - [ ] IPv6
 //!   - [ ] Path MTU discovery
@@ -117,6 +119,10 @@
 //! with no locking or lookups inside their `send` methods. As soon as a change
 //! happens inside the stack all existing tx-objects become invalid and must be
 //! recreated (which is cheap).
+//! Senders that want to know why, for example to size what they send to a new
+//! MTU before sending it, can watch the interface with
+//! `StackInterface::watch_link`. A `handle::RefreshingTx` recreates its
+//! tx-object by itself when it is invalidated.
 //!
 //! [1]: This will change when IPv6 is implemented so that `UdpTx` can be used
 //! on top of both.
@@ -172,6 +178,7 @@
 extern crate rand;
 extern crate pnet;
 extern crate ipnetwork;
+extern crate libc;
 #[macro_use]
 extern crate lazy_static;
 
@@ -181,7 +188,11 @@ extern crate log;
 #[cfg(feature = "bench")]
 extern crate test;
 
-use pnet::datalink::{self, NetworkInterface};
+use pnet::datalink::NetworkInterface;
+
+use std::cmp;
+use std::ops;
+use std::time::Duration;
 
 #[macro_use]
 mod macros;
@@ -189,8 +200,8 @@ mod macros;
 mod errors;
 pub use errors::*;
 
-mod payload;
-pub use payload::{Payload, BasicPayload, HasPayload};
+pub mod proto;
+pub use proto::{Payload, BasicPayload, HasPayload, VectoredPayload};
 
 pub mod rx;
 
@@ -210,16 +221,135 @@ pub mod icmp;
 pub mod udp;
 
 mod routing;
-pub use routing::RoutingTable;
+pub use routing::{DEFAULT_METRIC, RouteEntry, RouteOrigin, RoutingTable};
 
 mod util;
 
+pub mod host;
+
+pub mod link_local;
+
+pub mod macvlan;
+
+pub mod bond;
+
+pub mod bpf;
+
+pub mod bridge;
+
+pub mod carrier;
+
+pub mod checksum;
+
+pub mod clock;
+
+pub mod config;
+
+pub mod conntrack;
+
+pub mod corrupt;
+
+pub mod datalink;
+
+pub mod dhcp;
+
+pub mod dispatch;
+
+pub mod dns;
+
+pub mod dump;
+
+pub mod eapol;
+
+pub mod failover;
+
+pub mod fanout;
+
+pub mod handle;
+
+pub mod histogram;
+
+pub mod firewall;
+
+pub mod flow;
+
+pub mod fuzz;
+
+pub mod impair;
+
+pub mod malformed;
+
+pub mod metrics;
+
+pub mod nat;
+
+#[cfg(all(any(target_os = "linux", target_os = "freebsd"), target_pointer_width = "64"))]
+pub mod netmap;
+
+pub mod observe;
+
+pub mod pcap;
+
+pub mod perf;
+
+pub mod pool;
+
+pub mod portmap;
+
+pub mod pppoe;
+
+pub mod probe;
+
+pub mod qos;
+
+pub mod reactor;
+
+pub mod replay;
+
+pub mod rip;
+
+pub mod sampling;
+
+pub mod sctp;
+
+pub mod select;
+
+pub mod shaping;
+
+pub mod sim;
+
+pub mod snapshot;
+
+pub mod sockopt;
+
+pub mod stats;
+
+#[cfg(target_os = "linux")]
+pub mod tap;
+
+pub mod tftp;
+
+pub mod threads;
+
+pub mod timer;
+
+pub mod tun;
+
+pub mod tunnel;
+
+pub mod vlan;
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+pub mod xdp;
+
 pub mod testing;
 
 mod stack;
 
 pub use pnet::util::MacAddr;
-pub use stack::{NetworkStack, StackResult, DatalinkTx};
+pub use stack::{NetworkStack, NetworkStackBuilder, StackResult, DatalinkTx, LinkChange, TxRoute,
+                DEFAULT_MTU, MAX_MTU};
+pub use handle::StackHandle;
 
 pub static DEFAULT_BUFFER_SIZE: usize = 1024 * 128;
 
@@ -248,41 +378,118 @@ impl Interface {
 /// Represents the channel used for sending to and reading from one network
 /// interface. Basically a simplification of `pnet::datalink::Channel` but
 /// guaranteed to be be ethernet.
-pub struct EthernetChannel(pub Box<datalink::EthernetDataLinkSender>,
-                           pub Box<datalink::EthernetDataLinkReceiver>);
+pub struct EthernetChannel(pub Box<pnet::datalink::EthernetDataLinkSender>,
+                           pub Box<pnet::datalink::EthernetDataLinkReceiver>);
+
 
+/// What a send handed to the datalink, counted in Ethernet frames and their
+/// bytes. Frames dropped by the firewall are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TxSent {
+    pub packets: usize,
+    pub bytes: usize,
+}
+
+impl TxSent {
+    /// `packets` frames of `packet_size` bytes each.
+    pub fn new(packets: usize, packet_size: usize) -> TxSent {
+        TxSent {
+            packets: packets,
+            bytes: packets * packet_size,
+        }
+    }
+}
+
+impl ops::Add for TxSent {
+    type Output = TxSent;
+
+    fn add(self, other: TxSent) -> TxSent {
+        TxSent {
+            packets: self.packets + other.packets,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+impl ops::AddAssign for TxSent {
+    fn add_assign(&mut self, other: TxSent) {
+        *self = *self + other;
+    }
+}
 
 /// Type binding for the type of `Result` that a send method returns.
-pub type TxResult = Result<(), TxError>;
+pub type TxResult = Result<TxSent, TxError>;
 
 /// Simple type definition for return type of `recv` on `*Rx` objects.
 pub type RxResult = Result<(), RxError>;
 
 
 pub trait Tx {
+    /// Sends `num_packets` packets of exactly `packet_size` bytes each,
+    /// letting `payload` build them one after the other.
     fn send<P: Payload>(&mut self, num_packets: usize, packet_size: usize, payload: P) -> TxResult;
+
+    /// Sends a packet for every payload of `payloads`, each exactly as large
+    /// as the `len` of its payload. Implementations sending on a shared
+    /// datalink send them all without other senders getting in between.
+    fn send_all<P, I>(&mut self, payloads: I) -> TxResult
+        where P: Payload,
+              I: IntoIterator<Item = P>
+    {
+        let mut sent = TxSent::default();
+        for payload in payloads {
+            let len = payload.len();
+            sent += self.send(1, len, payload)?;
+        }
+        Ok(sent)
+    }
 }
 
 /// Create a default stack managing all interfaces given by
-/// `pnet::datalink::interfaces()`.
+/// `pnet::datalink::interfaces()`. Each interface gets the MTU the OS
+/// reports for it, capped at `MAX_MTU`, or `DEFAULT_MTU` if that is not
+/// known. To set up a stack for one interface with the addresses and routes
+/// of the host, see `NetworkStack::from_os_interface`.
 pub fn default_stack() -> StackResult<NetworkStack> {
     let mut stack = NetworkStack::new();
-    for interface in datalink::interfaces() {
+    for interface in pnet::datalink::interfaces() {
         if let Ok(rips_interface) = convert_interface(&interface) {
-            let mut config = datalink::Config::default();
-            config.write_buffer_size = DEFAULT_BUFFER_SIZE;
-            config.read_buffer_size = DEFAULT_BUFFER_SIZE;
-            let channel = match try!(datalink::channel(&interface, config)
-                .map_err(StackError::from)) {
-                datalink::Channel::Ethernet(tx, rx) => EthernetChannel(tx, rx),
-                _ => unreachable!(),
-            };
-            try!(stack.add_interface(rips_interface, channel));
+            try!(add_os_interface(&mut stack, &interface, rips_interface));
         }
     }
     Ok(stack)
 }
 
+/// Opens a datalink channel to the OS interface `interface` and adds it to
+/// `stack` as `rips_interface`, with the MTU the OS reports for it.
+fn add_os_interface(stack: &mut NetworkStack,
+                    interface: &NetworkInterface,
+                    rips_interface: Interface)
+                    -> StackResult<()> {
+    let channel = try!(os_channel(interface));
+    try!(stack.add_interface(rips_interface.clone(), channel));
+    if let Some(mtu) = stack::sysfs_mtu(&interface.name) {
+        if mtu >= ipv4::MIN_MTU {
+            let mtu = cmp::min(mtu, MAX_MTU);
+            try!(try!(stack.interface(&rips_interface)).set_mtu(mtu));
+        }
+    }
+    Ok(())
+}
+
+/// Opens a datalink channel to the OS interface `interface`.
+fn os_channel(interface: &NetworkInterface) -> StackResult<EthernetChannel> {
+    let mut config = pnet::datalink::Config::default();
+    config.write_buffer_size = DEFAULT_BUFFER_SIZE;
+    config.read_buffer_size = DEFAULT_BUFFER_SIZE;
+    // Lets the rx threads notice `NetworkStack::shutdown`
+    config.read_timeout = Some(Duration::from_millis(rx::POLL_INTERVAL_MS));
+    match try!(pnet::datalink::channel(interface, config).map_err(StackError::from)) {
+        pnet::datalink::Channel::Ethernet(tx, rx) => Ok(EthernetChannel(tx, rx)),
+        _ => unreachable!(),
+    }
+}
+
 // pub fn stack<Datalink>(_datalink_provider: Datalink) ->
 // StackResult<NetworkStack>
 //     where Datalink: datalink::Datalink
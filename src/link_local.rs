@@ -0,0 +1,220 @@
+//! Dynamic configuration of IPv4 link-local addresses, RFC 3927.
+//!
+//! An interface without any other address can claim an address in
+//! 169.254.0.0/16 by probing with Arp that no other host on the link uses it.
+//! This lets hosts talk to each other on links without a DHCP server.
+
+use {Interface, NetworkStack, StackError, StackResult, TxError};
+
+use ipnetwork::Ipv4Network;
+
+use pnet::util::MacAddr;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The prefix length of the link-local network.
+pub const PREFIX: u8 = 16;
+
+/// Timing and retry parameters of the address claiming. The `Default` values
+/// are the ones given in RFC 3927.
+#[derive(Debug, Clone)]
+pub struct LinkLocalConfig {
+    /// Upper bound of the random delay before the first probe
+    pub probe_wait: Duration,
+    /// Number of probes to send for each candidate address
+    pub probe_num: u32,
+    /// Lower bound of the random delay between probes
+    pub probe_min: Duration,
+    /// Upper bound of the random delay between probes
+    pub probe_max: Duration,
+    /// Time to wait for conflicts after the last probe
+    pub announce_wait: Duration,
+    /// Number of announcements to send after claiming an address
+    pub announce_num: u32,
+    /// Delay between announcements
+    pub announce_interval: Duration,
+    /// Number of conflicting candidates after which to give up
+    pub max_conflicts: u32,
+}
+
+impl Default for LinkLocalConfig {
+    fn default() -> LinkLocalConfig {
+        LinkLocalConfig {
+            probe_wait: Duration::from_secs(1),
+            probe_num: 3,
+            probe_min: Duration::from_secs(1),
+            probe_max: Duration::from_secs(2),
+            announce_wait: Duration::from_secs(2),
+            announce_num: 2,
+            announce_interval: Duration::from_secs(2),
+            max_conflicts: 10,
+        }
+    }
+}
+
+/// Returns the link-local network, 169.254.0.0/16.
+pub fn network() -> Ipv4Network {
+    Ipv4Network::new(Ipv4Addr::new(169, 254, 0, 0), PREFIX).unwrap()
+}
+
+/// Returns true if `ip` may be claimed as link-local address. The first and
+/// last 256 addresses of the network are reserved.
+pub fn is_assignable(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    network().contains(ip) && octets[2] != 0 && octets[2] != 255
+}
+
+/// Claims a link-local address for `interface` unless it already has an
+/// IPv4 address. Blocks while probing, which takes a few seconds with the
+/// default `config`. Returns the network that was added to the interface, or
+/// `None` if it already had an address.
+///
+/// Fails with `StackError::AddressInUse`, with the last of them, if
+/// `config.max_conflicts` candidate addresses were all in use by other
+/// hosts.
+pub fn configure(stack: Arc<Mutex<NetworkStack>>,
+                 interface: &Interface,
+                 config: &LinkLocalConfig)
+                 -> StackResult<Option<Ipv4Network>> {
+    if !stack.lock().unwrap().interface(interface)?.ipv4_networks().is_empty() {
+        return Ok(None);
+    }
+    let mut rng = seeded_rng(interface.mac);
+    let mut in_use = None;
+    for _ in 0..config.max_conflicts {
+        let ip = candidate(&mut rng);
+        let conflicts = stack.lock().unwrap().interface(interface)?.watch_arp(ip);
+        if !probe(&stack, interface, ip, &conflicts, config, &mut rng)? {
+            debug!("Link-local address {} is in use, trying another", ip);
+            in_use = Some(ip);
+            continue;
+        }
+        let net = Ipv4Network::new(ip, PREFIX).unwrap();
+        stack.lock().unwrap().add_ipv4(interface, net)?;
+        for i in 0..config.announce_num {
+            if i != 0 {
+                thread::sleep(config.announce_interval);
+            }
+            send_arp(&stack, interface, ip, ip)?;
+        }
+        return Ok(Some(net));
+    }
+    match in_use {
+        Some(ip) => Err(StackError::AddressInUse(ip)),
+        None => Err(StackError::InvalidArgument("No candidate addresses to try".to_owned())),
+    }
+}
+
+/// Probes for `ip`. Returns `false` if another host turned out to use it.
+fn probe<R: Rng>(stack: &Arc<Mutex<NetworkStack>>,
+                 interface: &Interface,
+                 ip: Ipv4Addr,
+                 conflicts: &Receiver<MacAddr>,
+                 config: &LinkLocalConfig,
+                 rng: &mut R)
+                 -> StackResult<bool> {
+    if !wait(conflicts, random_duration(rng, Duration::from_secs(0), config.probe_wait)) {
+        return Ok(false);
+    }
+    for i in 0..config.probe_num {
+        send_arp(stack, interface, Ipv4Addr::new(0, 0, 0, 0), ip)?;
+        let delay = if i + 1 == config.probe_num {
+            config.announce_wait
+        } else {
+            random_duration(rng, config.probe_min, config.probe_max)
+        };
+        if !wait(conflicts, delay) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Waits for `duration`. Returns `false` if a conflict was detected.
+fn wait(conflicts: &Receiver<MacAddr>, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        match conflicts.recv_timeout(deadline - now) {
+            Ok(mac) => {
+                debug!("Link-local conflict with {}", mac);
+                return false;
+            }
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return true,
+        }
+    }
+}
+
+fn send_arp(stack: &Arc<Mutex<NetworkStack>>,
+            interface: &Interface,
+            sender_ip: Ipv4Addr,
+            target_ip: Ipv4Addr)
+            -> StackResult<()> {
+    let mut stack = stack.lock().unwrap();
+    let stack_interface = stack.interface(interface)?;
+    tx_send!(|| stack_interface.arp_request_tx(); sender_ip, target_ip)?;
+    Ok(())
+}
+
+/// Picks a random assignable address, 169.254.1.0 - 169.254.254.255.
+fn candidate<R: Rng>(rng: &mut R) -> Ipv4Addr {
+    let host = rng.gen_range(0x0100u32, 0xff00u32);
+    Ipv4Addr::new(169, 254, (host >> 8) as u8, host as u8)
+}
+
+fn random_duration<R: Rng>(rng: &mut R, min: Duration, max: Duration) -> Duration {
+    if max <= min {
+        return min;
+    }
+    let span = max - min;
+    let span_ms = span.as_secs() * 1000 + (span.subsec_nanos() / 1_000_000) as u64;
+    min + Duration::from_millis(rng.gen_range(0, span_ms + 1))
+}
+
+/// The RFC recommends seeding from the MAC address, so an interface picks the
+/// same address every time when there are no conflicts.
+fn seeded_rng(mac: MacAddr) -> XorShiftRng {
+    let MacAddr(a, b, c, d, e, f) = mac;
+    let high = ((a as u32) << 24) | ((b as u32) << 16) | ((c as u32) << 8) | d as u32;
+    let low = ((e as u32) << 8) | f as u32;
+    XorShiftRng::from_seed([high ^ 0x9e37_79b9, low ^ 0x7f4a_7c15, 0xf39c_c060, 0x5ced_c834])
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn assignable() {
+        assert!(is_assignable(Ipv4Addr::new(169, 254, 1, 0)));
+        assert!(is_assignable(Ipv4Addr::new(169, 254, 254, 255)));
+        assert!(!is_assignable(Ipv4Addr::new(169, 254, 0, 7)));
+        assert!(!is_assignable(Ipv4Addr::new(169, 254, 255, 7)));
+        assert!(!is_assignable(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn candidates_are_assignable_and_stable() {
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let mut rng = seeded_rng(mac);
+        let first = candidate(&mut rng);
+        for _ in 0..1000 {
+            assert!(is_assignable(candidate(&mut rng)));
+        }
+        assert_eq!(candidate(&mut seeded_rng(mac)), first);
+    }
+}
@@ -0,0 +1,181 @@
+//! MACVLAN virtual interfaces.
+//!
+//! A MACVLAN is added to a `NetworkStack` with `NetworkStack::add_macvlan`
+//! as an interface of its own on top of a physical one, with its own MAC
+//! address and so its own addresses and routes. This makes one process look
+//! like several hosts on the link. Frames sent on it go out on the parent
+//! interface as they are. Frames received on the parent are handed to the
+//! MACVLAN with their destination MAC instead of to the parent, and
+//! broadcast and multicast frames to the parent and all its MACVLANs.
+//!
+//! Like the bridge mode of Linux macvlan devices, frames between MACVLANs of
+//! the same parent are delivered directly, without going out on the link.
+//! Frames between a MACVLAN and its parent are not.
+
+use {BasicPayload, Tx};
+use stack::TxBarrier;
+
+use pnet::datalink::{EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+/// Where the frames to each MACVLAN of an interface are sent, by MAC.
+pub type MacvlanTable = HashMap<MacAddr, Sender<Box<[u8]>>>;
+
+fn is_group(mac: MacAddr) -> bool {
+    mac.0 & 0x01 != 0
+}
+
+/// Hands `packet`, received on the parent interface, to the MACVLANs in
+/// `macvlans` it is for. Returns true if it was unicast to one of them, so
+/// the parent should not see it. MACVLANs whose receiving end is gone are
+/// removed.
+pub fn deliver(macvlans: &Mutex<MacvlanTable>, packet: &EthernetPacket) -> bool {
+    deliver_except(macvlans, packet.packet(), None)
+}
+
+fn deliver_except(macvlans: &Mutex<MacvlanTable>, frame: &[u8], except: Option<MacAddr>) -> bool {
+    let dst = match EthernetPacket::new(frame) {
+        Some(eth_pkg) => eth_pkg.get_destination(),
+        None => return false,
+    };
+    let mut macvlans = macvlans.lock().unwrap();
+    if macvlans.is_empty() {
+        return false;
+    }
+    let mut gone = vec![];
+    let consumed = if is_group(dst) {
+        for (&mac, inject) in macvlans.iter() {
+            if Some(mac) != except && inject.send(frame.to_vec().into_boxed_slice()).is_err() {
+                gone.push(mac);
+            }
+        }
+        false
+    } else {
+        match macvlans.get(&dst) {
+            Some(inject) => {
+                if inject.send(frame.to_vec().into_boxed_slice()).is_err() {
+                    gone.push(dst);
+                }
+                true
+            }
+            None => false,
+        }
+    };
+    for mac in gone {
+        macvlans.remove(&mac);
+    }
+    consumed
+}
+
+/// Sending half of the channel of a MACVLAN, sending the frames on the
+/// parent interface or directly to its sibling MACVLANs.
+pub struct MacvlanSender {
+    mac: MacAddr,
+    macvlans: Arc<Mutex<MacvlanTable>>,
+    tx: Arc<Mutex<TxBarrier>>,
+}
+
+impl MacvlanSender {
+    pub fn new(mac: MacAddr,
+               macvlans: Arc<Mutex<MacvlanTable>>,
+               tx: Arc<Mutex<TxBarrier>>)
+               -> MacvlanSender {
+        MacvlanSender {
+            mac: mac,
+            macvlans: macvlans,
+            tx: tx,
+        }
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if deliver_except(&self.macvlans, frame, Some(self.mac)) {
+            return Ok(());
+        }
+        let payload = BasicPayload::new(frame);
+        self.tx.lock().unwrap().send(1, frame.len(), payload).map(|_| ()).map_err(io::Error::from)
+    }
+}
+
+impl EthernetDataLinkSender for MacvlanSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0; packet_size];
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            if let Err(e) = self.send_frame(&buffer) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        Some(self.send_frame(packet.packet()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+    use pnet::util::MacAddr;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn frame(dst: MacAddr) -> Vec<u8> {
+        let mut buffer = vec![0; 60];
+        MutableEthernetPacket::new(&mut buffer[..]).unwrap().set_destination(dst);
+        buffer
+    }
+
+    #[test]
+    fn demux_by_mac() {
+        let a = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let b = MacAddr::new(2, 0, 0, 0, 0, 2);
+        let (inject_a, frames_a) = mpsc::channel();
+        let (inject_b, frames_b) = mpsc::channel();
+        let mut macvlans = HashMap::new();
+        macvlans.insert(a, inject_a);
+        macvlans.insert(b, inject_b);
+        let macvlans = Mutex::new(macvlans);
+
+        let to_a = frame(a);
+        assert!(deliver(&macvlans, &EthernetPacket::new(&to_a).unwrap()));
+        assert_eq!(frames_a.try_iter().count(), 1);
+        assert_eq!(frames_b.try_iter().count(), 0);
+
+        let to_other = frame(MacAddr::new(2, 0, 0, 0, 0, 3));
+        assert!(!deliver(&macvlans, &EthernetPacket::new(&to_other).unwrap()));
+
+        // Group frames go to every MACVLAN, and to the parent too
+        let broadcast = frame(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+        assert!(!deliver_except(&macvlans, &broadcast, Some(b)));
+        assert_eq!(frames_a.try_iter().count(), 1);
+        assert_eq!(frames_b.try_iter().count(), 0);
+
+        // Dropping the receiving end removes the MACVLAN
+        drop(frames_a);
+        assert!(deliver(&macvlans, &EthernetPacket::new(&to_a).unwrap()));
+        assert!(!macvlans.lock().unwrap().contains_key(&a));
+        assert!(!deliver(&macvlans, &EthernetPacket::new(&to_a).unwrap()));
+    }
+}
@@ -0,0 +1,318 @@
+//! What happens to the packets the stack can't parse.
+//!
+//! A truncated frame, or one with invalid headers, is dropped by the layer
+//! that fails to parse it. Every interface counts these packets per layer,
+//! see `StackInterface::parse_errors` and `NetworkStack::parse_errors`. What
+//! else happens to them is up to the `MalformedPolicy` of the interface, set
+//! with `NetworkStackBuilder::malformed_policy` or
+//! `NetworkStack::set_malformed_policy`. It can drop them without a trace,
+//! count them, log one in every so many, or hand them to a hook for
+//! quarantining:
+//!
+//! ```rust,ignore
+//! let samples = Arc::new(Mutex::new(Vec::new()));
+//! let quarantine_samples = samples.clone();
+//! let quarantine = move |layer: Layer, _error: &RxError, _time: SystemTime, data: &[u8]| {
+//!     quarantine_samples.lock().unwrap().push((layer, data.to_vec()));
+//! };
+//! let stack = NetworkStack::builder()
+//!     .malformed_policy(MalformedPolicy::Quarantine(Arc::new(quarantine)))
+//!     .build()?;
+//! ```
+//!
+//! A packet is malformed if parsing it failed with
+//! `RxError::InvalidLength`, `InvalidContent` or `InvalidChecksum`. Packets
+//! nobody listens for, or that the firewall drops, are not. The policy does
+//! the logging of malformed packets, so the rx threads only log them at
+//! debug level.
+
+use {RxError, RxResult};
+use ethernet::EthernetListener;
+use ipv4::Ipv4Listener;
+use stats::Counters;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherType, EthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// How often `MalformedPolicy::default` logs malformed packets, one in every
+/// this many.
+pub static DEFAULT_LOG_INTERVAL: usize = 100;
+
+/// The layer a malformed packet failed to parse at. VLAN tags count as part
+/// of Ethernet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Ethernet,
+    Arp,
+    Ipv4,
+    Udp,
+    Icmp,
+}
+
+/// Malformed packets received on an interface, counted by the layer they
+/// failed at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseErrors {
+    pub ethernet: u64,
+    pub arp: u64,
+    pub ipv4: u64,
+    pub udp: u64,
+    pub icmp: u64,
+    /// Of all of the above, the packets with invalid checksums
+    pub checksums: u64,
+}
+
+impl ParseErrors {
+    /// Returns the counter of `layer`.
+    pub fn get(&self, layer: Layer) -> u64 {
+        match layer {
+            Layer::Ethernet => self.ethernet,
+            Layer::Arp => self.arp,
+            Layer::Ipv4 => self.ipv4,
+            Layer::Udp => self.udp,
+            Layer::Icmp => self.icmp,
+        }
+    }
+
+    /// Returns the number of malformed packets, the sum of the counters of
+    /// the layers.
+    pub fn total(&self) -> u64 {
+        self.ethernet + self.arp + self.ipv4 + self.udp + self.icmp
+    }
+
+    /// Adds the counters of `other` to these.
+    pub fn add(&mut self, other: &ParseErrors) {
+        self.ethernet += other.ethernet;
+        self.arp += other.arp;
+        self.ipv4 += other.ipv4;
+        self.udp += other.udp;
+        self.icmp += other.icmp;
+        self.checksums += other.checksums;
+    }
+
+    fn count(&mut self, layer: Layer, error: &RxError) {
+        if *error == RxError::InvalidChecksum {
+            self.checksums += 1;
+        }
+        match layer {
+            Layer::Ethernet => self.ethernet += 1,
+            Layer::Arp => self.arp += 1,
+            Layer::Ipv4 => self.ipv4 += 1,
+            Layer::Udp => self.udp += 1,
+            Layer::Icmp => self.icmp += 1,
+        }
+    }
+}
+
+/// Code taking the malformed packets of a `MalformedPolicy::Quarantine`.
+/// `data` is what the failing layer was given: the whole frame for
+/// Ethernet, Arp and IPv4, the IPv4 packet for UDP and Icmp. Called on the
+/// thread receiving, so it should be quick. Implemented for all closures
+/// with the same signature as `quarantine`.
+pub trait Quarantine: Send + Sync {
+    fn quarantine(&self, layer: Layer, error: &RxError, time: SystemTime, data: &[u8]);
+}
+
+impl<F> Quarantine for F
+    where F: Fn(Layer, &RxError, SystemTime, &[u8]) + Send + Sync
+{
+    fn quarantine(&self, layer: Layer, error: &RxError, time: SystemTime, data: &[u8]) {
+        self(layer, error, time, data)
+    }
+}
+
+/// What an interface does with the malformed packets it drops.
+#[derive(Clone)]
+pub enum MalformedPolicy {
+    /// Nothing, they are not even counted
+    Drop,
+    /// Counts them
+    Count,
+    /// Counts them, and logs the first and then one in every this many at
+    /// warn level. Zero logs none.
+    Log(usize),
+    /// Counts them, and hands every one of them to the hook
+    Quarantine(Arc<Quarantine>),
+}
+
+impl Default for MalformedPolicy {
+    fn default() -> MalformedPolicy {
+        MalformedPolicy::Log(DEFAULT_LOG_INTERVAL)
+    }
+}
+
+/// Returns true if `error` means the packet failed to parse.
+pub fn is_malformed(error: &RxError) -> bool {
+    match *error {
+        RxError::InvalidLength | RxError::InvalidContent | RxError::InvalidChecksum => true,
+        _ => false,
+    }
+}
+
+/// The `MalformedPolicy` and `ParseErrors` of an interface, shared by the
+/// layers receiving on it.
+pub struct Malformed {
+    policy: RwLock<MalformedPolicy>,
+    stats: Mutex<ParseErrors>,
+}
+
+impl Malformed {
+    pub fn new(policy: MalformedPolicy) -> Malformed {
+        Malformed {
+            policy: RwLock::new(policy),
+            stats: Mutex::new(ParseErrors::default()),
+        }
+    }
+
+    pub fn policy(&self) -> MalformedPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Applies `policy` to the packets failing to parse from now on. The
+    /// counters are kept.
+    pub fn set_policy(&self, policy: MalformedPolicy) {
+        *self.policy.write().unwrap() = policy;
+    }
+
+    pub fn stats(&self) -> ParseErrors {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Applies the policy to the packet `data` that failed to parse at
+    /// `layer` with `error`. Does nothing if `error` is not one of a
+    /// malformed packet.
+    pub fn record(&self, layer: Layer, error: &RxError, time: SystemTime, data: &[u8]) {
+        if !is_malformed(error) {
+            return;
+        }
+        let policy = self.policy();
+        if let MalformedPolicy::Drop = policy {
+            return;
+        }
+        let total = {
+            let mut stats = self.stats.lock().unwrap();
+            stats.count(layer, error);
+            stats.total()
+        };
+        match policy {
+            MalformedPolicy::Log(every) if every > 0 && (total - 1) % every as u64 == 0 => {
+                warn!("Malformed {:?} packet of {} bytes: {}", layer, data.len(), error);
+            }
+            MalformedPolicy::Quarantine(ref hook) => hook.quarantine(layer, error, time, data),
+            _ => (),
+        }
+    }
+}
+
+impl Default for Malformed {
+    fn default() -> Malformed {
+        Malformed::new(MalformedPolicy::default())
+    }
+}
+
+/// A listener recording the packets it fails on as failing at `layer`, the
+/// malformed ones in `malformed` and the others as dropped in `counters`.
+/// Only for listeners that don't hand packets on to other layers, whose
+/// errors would be recorded at the wrong one.
+pub struct Guarded<L> {
+    layer: Layer,
+    malformed: Arc<Malformed>,
+    counters: Arc<Counters>,
+    listener: L,
+}
+
+impl<L> Guarded<L> {
+    pub fn new(layer: Layer,
+               malformed: Arc<Malformed>,
+               counters: Arc<Counters>,
+               listener: L)
+               -> Guarded<L> {
+        Guarded {
+            layer: layer,
+            malformed: malformed,
+            counters: counters,
+            listener: listener,
+        }
+    }
+
+    fn failed(&self, error: &RxError, time: SystemTime, data: &[u8]) {
+        if is_malformed(error) {
+            self.malformed.record(self.layer, error, time, data);
+        } else {
+            self.counters.dropped(self.layer);
+        }
+    }
+}
+
+impl<L: EthernetListener> EthernetListener for Guarded<L> {
+    fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        let result = self.listener.recv(time, packet);
+        if let Err(ref e) = result {
+            self.failed(e, time, packet.packet());
+        }
+        result
+    }
+
+    fn ether_type(&self) -> EtherType {
+        self.listener.ether_type()
+    }
+}
+
+impl<L: Ipv4Listener> Ipv4Listener for Guarded<L> {
+    fn recv(&mut self, time: SystemTime, packet: Ipv4Packet) -> RxResult {
+        let result = self.listener.recv(time, Ipv4Packet::new(packet.packet()).unwrap());
+        if let Err(ref e) = result {
+            self.failed(e, time, packet.packet());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use RxError;
+
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn counts_by_layer() {
+        let malformed = Malformed::new(MalformedPolicy::Count);
+        malformed.record(Layer::Udp, &RxError::InvalidLength, UNIX_EPOCH, &[]);
+        malformed.record(Layer::Udp, &RxError::InvalidChecksum, UNIX_EPOCH, &[]);
+        malformed.record(Layer::Arp, &RxError::InvalidContent, UNIX_EPOCH, &[]);
+        // Not malformed
+        malformed.record(Layer::Udp, &RxError::NoListener("".to_owned()), UNIX_EPOCH, &[]);
+        malformed.record(Layer::Ipv4, &RxError::Filtered, UNIX_EPOCH, &[]);
+        let stats = malformed.stats();
+        assert_eq!(stats.get(Layer::Udp), 2);
+        assert_eq!(stats.arp, 1);
+        assert_eq!(stats.checksums, 1);
+        assert_eq!(stats.total(), 3);
+
+        malformed.set_policy(MalformedPolicy::Drop);
+        malformed.record(Layer::Udp, &RxError::InvalidLength, UNIX_EPOCH, &[]);
+        assert_eq!(malformed.stats(), stats);
+    }
+
+    #[test]
+    fn quarantines() {
+        let quarantined = Arc::new(Mutex::new(Vec::new()));
+        let hook_quarantined = quarantined.clone();
+        let hook = move |layer: Layer, error: &RxError, _time: SystemTime, data: &[u8]| {
+            assert_eq!(*error, RxError::InvalidLength);
+            hook_quarantined.lock().unwrap().push((layer, data.to_vec()));
+        };
+        let malformed = Malformed::new(MalformedPolicy::Quarantine(Arc::new(hook)));
+        malformed.record(Layer::Icmp, &RxError::InvalidLength, UNIX_EPOCH, &[8]);
+        malformed.record(Layer::Icmp, &RxError::Other("".to_owned()), UNIX_EPOCH, &[0]);
+        assert_eq!(*quarantined.lock().unwrap(), vec![(Layer::Icmp, vec![8])]);
+        assert_eq!(malformed.stats().icmp, 1);
+    }
+}
@@ -0,0 +1,468 @@
+//! Exporting the counters of a stack to monitoring systems.
+//!
+//! `collect` turns a `StackSnapshot` into a flat list of `Metric`s: the
+//! counters of `stats`, `malformed` and fragment reassembly, and gauges such
+//! as the size of the Arp caches, labeled with the interface and protocol
+//! they are of. A `MetricsExporter` takes a snapshot every so often and hands
+//! the metrics to a `MetricsSink`. `PrometheusSink` keeps them encoded in the
+//! Prometheus text format, for serving to a scraper from whatever HTTP
+//! server the application already has:
+//!
+//! ```rust,ignore
+//! let sink = PrometheusSink::new();
+//! let _exporter = MetricsExporter::start(stack.clone(), sink.clone());
+//! // In the handler of GET /metrics
+//! respond(200, metrics::CONTENT_TYPE, sink.page());
+//! ```
+//!
+//! `TextfileSink` writes them to a file instead, for the textfile collector
+//! of the Prometheus node exporter. Other systems are a closure away, every
+//! `FnMut(SystemTime, &[Metric])` is a sink.
+
+use NetworkStack;
+use snapshot::StackSnapshot;
+use stats::LayerStats;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// How often a `MetricsExporter` started with `MetricsExporter::start`
+/// exports.
+pub static DEFAULT_INTERVAL_SECS: u64 = 15;
+
+/// The content type of the pages `prometheus` encodes.
+pub static CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// Only ever goes up, until the stack is restarted
+    Counter,
+    /// A value at the time of the snapshot
+    Gauge,
+}
+
+/// One value of a stack, as monitoring systems take them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metric {
+    /// Such as `rips_rx_packets_total`. The same for all the interfaces and
+    /// protocols a value is of
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    /// Names and values of the labels telling the metrics of one name apart,
+    /// such as `("interface", "eth0")`
+    pub labels: Vec<(&'static str, String)>,
+    pub value: u64,
+}
+
+/// Metrics of any interface, by protocol.
+struct LayerCounter<'a> {
+    name: &'static str,
+    help: &'static str,
+    value: &'a Fn(&LayerStats) -> u64,
+}
+
+/// Returns the metrics of the stack `snapshot` was taken of.
+pub fn collect(snapshot: &StackSnapshot) -> Vec<Metric> {
+    let mut metrics = Vec::new();
+    {
+        let mut add = |name, help, kind, labels: Vec<(&'static str, String)>, value| {
+            metrics.push(Metric {
+                name: name,
+                help: help,
+                kind: kind,
+                labels: labels,
+                value: value,
+            });
+        };
+        let layer_counters = [LayerCounter {
+                                  name: "rips_rx_packets_total",
+                                  help: "Packets received",
+                                  value: &|stats| stats.rx_packets,
+                              },
+                              LayerCounter {
+                                  name: "rips_rx_bytes_total",
+                                  help: "Bytes received",
+                                  value: &|stats| stats.rx_bytes,
+                              },
+                              LayerCounter {
+                                  name: "rips_rx_dropped_total",
+                                  help: "Packets received but not delivered",
+                                  value: &|stats| stats.rx_dropped,
+                              },
+                              LayerCounter {
+                                  name: "rips_rx_errors_total",
+                                  help: "Malformed packets received",
+                                  value: &|stats| stats.rx_errors,
+                              },
+                              LayerCounter {
+                                  name: "rips_tx_packets_total",
+                                  help: "Packets sent",
+                                  value: &|stats| stats.tx_packets,
+                              },
+                              LayerCounter {
+                                  name: "rips_tx_bytes_total",
+                                  help: "Bytes sent",
+                                  value: &|stats| stats.tx_bytes,
+                              },
+                              LayerCounter {
+                                  name: "rips_tx_errors_total",
+                                  help: "Packets the datalink failed to send",
+                                  value: &|stats| stats.tx_errors,
+                              }];
+        for interface in &snapshot.interfaces {
+            let labels = || vec![("interface", interface.name.clone())];
+            let with = |name: &'static str, value: &str| {
+                let mut labels = labels();
+                labels.push((name, value.to_owned()));
+                labels
+            };
+            let stats = &interface.stats;
+            for counter in &layer_counters {
+                for &(protocol, layer_stats) in &[("ethernet", &stats.ethernet),
+                                                  ("arp", &stats.arp),
+                                                  ("ipv4", &stats.ipv4),
+                                                  ("icmp", &stats.icmp),
+                                                  ("udp", &stats.udp)] {
+                    add(counter.name,
+                        counter.help,
+                        MetricKind::Counter,
+                        with("protocol", protocol),
+                        (counter.value)(layer_stats));
+                }
+            }
+            add("rips_checksum_errors_total",
+                "Packets received with invalid checksums",
+                MetricKind::Counter,
+                labels(),
+                interface.parse_errors.checksums);
+            let reassembly = &interface.reassembly;
+            for &(reason, value) in &[("overlapping", reassembly.overlapping),
+                                      ("over_limit", reassembly.over_limit),
+                                      ("timed_out", reassembly.timed_out),
+                                      ("invalid", reassembly.invalid)] {
+                add("rips_reassembly_failures_total",
+                    "Fragmented packets that could not be reassembled",
+                    MetricKind::Counter,
+                    with("reason", reason),
+                    value);
+            }
+            add("rips_arp_cache_hits_total",
+                "Lookups of the Arp cache finding the address",
+                MetricKind::Counter,
+                labels(),
+                stats.arp_cache_hits);
+            add("rips_arp_cache_misses_total",
+                "Lookups of the Arp cache that had to wait for a reply",
+                MetricKind::Counter,
+                labels(),
+                stats.arp_cache_misses);
+            add("rips_arp_cache_entries",
+                "Addresses in the Arp cache",
+                MetricKind::Gauge,
+                labels(),
+                interface.arp.len() as u64);
+            add("rips_arp_pending",
+                "Addresses an Arp reply is waited for",
+                MetricKind::Gauge,
+                labels(),
+                interface.arp_pending.len() as u64);
+            add("rips_carrier",
+                "1 if the interface has carrier",
+                MetricKind::Gauge,
+                labels(),
+                interface.carrier as u64);
+            add("rips_listeners",
+                "UDP and Icmp listeners",
+                MetricKind::Gauge,
+                labels(),
+                interface.listeners.len() as u64);
+            if let Some(ref queues) = interface.queues {
+                for (i, queue) in queues.iter().enumerate() {
+                    let queue_labels = || with("queue", &i.to_string());
+                    add("rips_queue_frames",
+                        "Frames waiting in an egress queue",
+                        MetricKind::Gauge,
+                        queue_labels(),
+                        queue.queued as u64);
+                    add("rips_queue_sent_total",
+                        "Frames sent from an egress queue",
+                        MetricKind::Counter,
+                        queue_labels(),
+                        queue.sent as u64);
+                    add("rips_queue_dropped_total",
+                        "Frames dropped because an egress queue was full",
+                        MetricKind::Counter,
+                        queue_labels(),
+                        queue.dropped as u64);
+                }
+            }
+        }
+        add("rips_routes",
+            "Routes in a routing table, the main one if there is no vrf label",
+            MetricKind::Gauge,
+            Vec::new(),
+            snapshot.routes.len() as u64);
+        for vrf in &snapshot.vrfs {
+            add("rips_routes",
+                "Routes in a routing table, the main one if there is no vrf label",
+                MetricKind::Gauge,
+                vec![("vrf", vrf.name.clone())],
+                vrf.routes.len() as u64);
+        }
+        add("rips_forwarding",
+            "1 if the stack forwards packets",
+            MetricKind::Gauge,
+            Vec::new(),
+            snapshot.forwarding as u64);
+        add("rips_tracked_connections",
+            "Connections tracked by the firewall",
+            MetricKind::Gauge,
+            Vec::new(),
+            snapshot.connections as u64);
+    }
+    metrics
+}
+
+/// Encodes `metrics` in the Prometheus text exposition format. Metrics of
+/// the same name are written together, under one `HELP` and `TYPE` line,
+/// in the order their names first appear in `metrics`.
+pub fn prometheus(metrics: &[Metric]) -> String {
+    let mut names = Vec::new();
+    for metric in metrics {
+        if !names.contains(&metric.name) {
+            names.push(metric.name);
+        }
+    }
+    let mut page = String::new();
+    for name in names {
+        let mut samples = metrics.iter().filter(|metric| metric.name == name).peekable();
+        if let Some(first) = samples.peek() {
+            let kind = match first.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            page.push_str(&format!("# HELP {} {}\n", name, escape(first.help, false)));
+            page.push_str(&format!("# TYPE {} {}\n", name, kind));
+        }
+        for metric in samples {
+            page.push_str(name);
+            if !metric.labels.is_empty() {
+                let labels = metric.labels
+                    .iter()
+                    .map(|&(label, ref value)| format!("{}=\"{}\"", label, escape(value, true)))
+                    .collect::<Vec<_>>();
+                page.push_str(&format!("{{{}}}", labels.join(",")));
+            }
+            page.push_str(&format!(" {}\n", metric.value));
+        }
+    }
+    page
+}
+
+/// Escapes backslashes and line feeds, and double quotes in label values.
+fn escape(text: &str, label_value: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if label_value => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Something taking the metrics of a `MetricsExporter`. Called on the
+/// thread of the exporter, with the time the snapshot was taken.
+/// Implemented for all closures with the same signature as `export`.
+pub trait MetricsSink: Send {
+    fn export(&mut self, time: SystemTime, metrics: &[Metric]);
+}
+
+impl<F> MetricsSink for F
+    where F: FnMut(SystemTime, &[Metric]) + Send
+{
+    fn export(&mut self, time: SystemTime, metrics: &[Metric]) {
+        self(time, metrics)
+    }
+}
+
+/// A sink keeping the last metrics exported encoded by `prometheus`. Clones
+/// share the page.
+#[derive(Clone, Default)]
+pub struct PrometheusSink {
+    page: Arc<Mutex<String>>,
+}
+
+impl PrometheusSink {
+    pub fn new() -> PrometheusSink {
+        PrometheusSink::default()
+    }
+
+    /// Returns the page to answer a scrape with. Empty until the first
+    /// export.
+    pub fn page(&self) -> String {
+        self.page.lock().unwrap().clone()
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn export(&mut self, _time: SystemTime, metrics: &[Metric]) {
+        let page = prometheus(metrics);
+        *self.page.lock().unwrap() = page;
+    }
+}
+
+/// A sink writing the metrics encoded by `prometheus` to a file, for the
+/// textfile collector of the node exporter. The file is replaced as a
+/// whole, so the collector never reads half of one.
+pub struct TextfileSink {
+    path: PathBuf,
+}
+
+impl TextfileSink {
+    /// Writes to `path`, which should end in `.prom` for the collector to
+    /// pick it up.
+    pub fn new<P: Into<PathBuf>>(path: P) -> TextfileSink {
+        TextfileSink { path: path.into() }
+    }
+
+    fn write(&self, page: &str) -> io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        File::create(&tmp_path)?.write_all(page.as_bytes())?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+impl MetricsSink for TextfileSink {
+    fn export(&mut self, _time: SystemTime, metrics: &[Metric]) {
+        if let Err(e) = self.write(&prometheus(metrics)) {
+            warn!("Unable to write metrics to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// A thread exporting the metrics of a stack periodically. Stops when
+/// dropped.
+pub struct MetricsExporter {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsExporter {
+    /// Starts exporting the metrics of `stack` to `sink` every
+    /// `DEFAULT_INTERVAL_SECS`.
+    pub fn start<S>(stack: Arc<Mutex<NetworkStack>>, sink: S) -> MetricsExporter
+        where S: MetricsSink + 'static
+    {
+        Self::with_interval(stack, Duration::from_secs(DEFAULT_INTERVAL_SECS), sink)
+    }
+
+    /// Same as `start`, but exports every `interval`. The first export is
+    /// one `interval` from now.
+    pub fn with_interval<S>(stack: Arc<Mutex<NetworkStack>>,
+                            interval: Duration,
+                            mut sink: S)
+                            -> MetricsExporter
+        where S: MetricsSink + 'static
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // Not holding the lock of the stack while exporting
+                let snapshot = stack.lock().unwrap().snapshot();
+                sink.export(SystemTime::now(), &collect(&snapshot));
+            }
+        });
+        MetricsExporter {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the exporting thread and waits for it to quit. An export in
+    /// progress is finished first.
+    pub fn stop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for MetricsExporter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::time::{Duration, SystemTime};
+
+    use testing;
+
+    use super::*;
+
+    fn metric(name: &'static str, kind: MetricKind, interface: &str, value: u64) -> Metric {
+        Metric {
+            name: name,
+            help: "Some\nthing",
+            kind: kind,
+            labels: vec![("interface", interface.to_owned())],
+            value: value,
+        }
+    }
+
+    #[test]
+    fn prometheus_text() {
+        let metrics = vec![metric("a_total", MetricKind::Counter, "eth0", 1),
+                           metric("b", MetricKind::Gauge, "eth0", 2),
+                           metric("a_total", MetricKind::Counter, "e\"t\\h1", 3),
+                           Metric { labels: Vec::new(), ..metric("c", MetricKind::Gauge, "", 4) }];
+        assert_eq!(prometheus(&metrics),
+                   "# HELP a_total Some\\nthing\n\
+                    # TYPE a_total counter\n\
+                    a_total{interface=\"eth0\"} 1\n\
+                    a_total{interface=\"e\\\"t\\\\h1\"} 3\n\
+                    # HELP b Some\\nthing\n\
+                    # TYPE b gauge\n\
+                    b{interface=\"eth0\"} 2\n\
+                    # HELP c Some\\nthing\n\
+                    # TYPE c gauge\n\
+                    c 4\n");
+    }
+
+    #[test]
+    fn exports_periodically() {
+        let (stack, interface, _, _) = testing::dummy_stack();
+        let stack = Arc::new(Mutex::new(stack));
+        let (exported_tx, exported) = mpsc::channel();
+        let sink = move |_time: SystemTime, metrics: &[Metric]| {
+            exported_tx.send(metrics.to_vec()).unwrap_or(());
+        };
+        let mut exporter = MetricsExporter::with_interval(stack, Duration::from_millis(10), sink);
+        for _ in 0..2 {
+            let metrics = exported.recv_timeout(Duration::from_secs(1)).unwrap();
+            let carrier = metrics.iter().find(|metric| metric.name == "rips_carrier").unwrap();
+            assert_eq!(carrier.labels, vec![("interface", interface.name.clone())]);
+            assert_eq!((carrier.kind, carrier.value), (MetricKind::Gauge, 1));
+            let rx_packets = metrics.iter()
+                .filter(|metric| metric.name == "rips_rx_packets_total")
+                .count();
+            assert_eq!(rx_packets, 5);
+        }
+        exporter.stop();
+        // The sink is dropped with the thread
+        while exported.recv_timeout(Duration::from_secs(1)).is_ok() {}
+    }
+}
@@ -0,0 +1,431 @@
+//! NAT44 masquerading. Rewrites the source of packets forwarded out an
+//! interface to the address of that interface, and reverses it on the return
+//! traffic. Lets a stack with forwarding enabled act as a home router style
+//! gateway between a private network and the outside.
+//!
+//! UDP and TCP are translated by port, Icmp echo requests by identifier.
+//! Other protocols, and Icmp errors, are not translated and get dropped.
+
+use checksum;
+use conntrack::{ConnTrack, FlowKey};
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::MutableIpv4Packet;
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// First external port handed out for mappings.
+pub const MIN_PORT: u16 = 1024;
+
+/// How often new flows have the mappings looked through for expired ones.
+pub const EXPIRE_INTERVAL_MS: u64 = 1000;
+
+/// One translated flow. For Icmp the ports are the echo identifier and the
+/// remote port is always zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatMapping {
+    pub protocol: IpNextHeaderProtocol,
+    /// The private address and port of the flow
+    pub inside: SocketAddrV4,
+    /// The address and port the flow goes to
+    pub remote: SocketAddrV4,
+    /// The port on the external address the flow is translated to
+    pub external_port: u16,
+    /// The untranslated flow, as tracked by the `ConnTrack`
+    flow: FlowKey,
+}
+
+type OutboundKey = (IpNextHeaderProtocol, SocketAddrV4, SocketAddrV4);
+type InboundKey = (IpNextHeaderProtocol, u16, SocketAddrV4);
+
+#[derive(Default)]
+struct NatTable {
+    outbound: HashMap<OutboundKey, InboundKey>,
+    inbound: HashMap<InboundKey, NatMapping>,
+    used_ports: HashSet<(IpNextHeaderProtocol, u16)>,
+    next_port: u16,
+    last_expire: Option<Instant>,
+}
+
+/// The translation state of one masquerading interface. Mappings live as
+/// long as the connection of their flow is tracked, so the untranslated
+/// traffic must be passed through `ConnTrack::track`, as
+/// `NetworkStack::forward` does.
+pub struct Nat {
+    external_ip: Ipv4Addr,
+    conntrack: Arc<ConnTrack>,
+    table: Mutex<NatTable>,
+}
+
+impl Nat {
+    /// Creates a NAT translating to `external_ip`, with mappings expiring
+    /// with the connections in `conntrack`.
+    pub fn new(external_ip: Ipv4Addr, conntrack: Arc<ConnTrack>) -> Nat {
+        Nat {
+            external_ip: external_ip,
+            conntrack: conntrack,
+            table: Mutex::new(NatTable::default()),
+        }
+    }
+
+    pub fn external_ip(&self) -> Ipv4Addr {
+        self.external_ip
+    }
+
+    pub fn conntrack(&self) -> &Arc<ConnTrack> {
+        &self.conntrack
+    }
+
+    /// Returns all current mappings.
+    pub fn mappings(&self) -> Vec<NatMapping> {
+        self.table.lock().unwrap().inbound.values().cloned().collect()
+    }
+
+    /// Removes the mappings whose connection is no longer tracked. Done
+    /// every `EXPIRE_INTERVAL_MS` as new flows are translated anyway.
+    pub fn expire(&self) {
+        self.table.lock().unwrap().expire(&self.conntrack, Instant::now());
+    }
+
+    /// Rewrites the source of the IPv4 packet in `packet`, creating a
+    /// mapping if this is a new flow. Returns false if the packet can't be
+    /// translated. The checksums are updated along.
+    pub fn translate_outbound(&self, packet: &mut [u8]) -> bool {
+        let flow = match FlowKey::from_packet(packet) {
+            Some(flow) => flow,
+            None => return false,
+        };
+        let (protocol, src, dst) = (flow.protocol, flow.src, flow.dst);
+        let external_port = {
+            let mut table = self.table.lock().unwrap();
+            let existing = table.outbound.get(&(protocol, src, dst)).cloned();
+            let inbound_key = match existing {
+                Some(inbound_key) => inbound_key,
+                None => {
+                    table.expire_periodically(&self.conntrack, Instant::now());
+                    let port = match table.allocate_port(protocol, src.port()) {
+                        Some(port) => port,
+                        None => return false,
+                    };
+                    let inbound_key = (protocol, port, remote_key(protocol, dst));
+                    table.outbound.insert((protocol, src, dst), inbound_key);
+                    table.inbound.insert(inbound_key,
+                                         NatMapping {
+                                             protocol: protocol,
+                                             inside: src,
+                                             remote: remote_key(protocol, dst),
+                                             external_port: port,
+                                             flow: flow,
+                                         });
+                    inbound_key
+                }
+            };
+            table.inbound[&inbound_key].external_port
+        };
+        rewrite(packet,
+                SocketAddrV4::new(self.external_ip, external_port),
+                dst,
+                protocol);
+        true
+    }
+
+    /// Rewrites the destination of the IPv4 packet in `packet` if it's
+    /// return traffic of a mapping whose connection is still tracked. Returns
+    /// false, leaving the packet untouched, otherwise. The checksums are
+    /// updated along.
+    pub fn translate_inbound(&self, packet: &mut [u8]) -> bool {
+        let (protocol, src, dst) = match FlowKey::from_packet(packet) {
+            Some(flow) => (flow.protocol, flow.src, flow.dst),
+            None => return false,
+        };
+        if *dst.ip() != self.external_ip {
+            return false;
+        }
+        let inside = {
+            let table = self.table.lock().unwrap();
+            let key = (protocol, dst.port(), remote_key(protocol, src));
+            match table.inbound.get(&key) {
+                Some(mapping) if self.conntrack.contains(&mapping.flow) => mapping.inside,
+                _ => return false,
+            }
+        };
+        rewrite(packet, src, inside, protocol);
+        true
+    }
+}
+
+impl NatTable {
+    fn expire(&mut self, conntrack: &ConnTrack, now: Instant) {
+        let expired = self.inbound
+            .iter()
+            .filter(|&(_, m)| !conntrack.contains(&m.flow))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired {
+            let mapping = self.inbound.remove(&key).unwrap();
+            self.outbound.remove(&(mapping.protocol, mapping.inside, mapping.remote));
+            self.used_ports.remove(&(mapping.protocol, mapping.external_port));
+        }
+        self.last_expire = Some(now);
+    }
+
+    fn expire_periodically(&mut self, conntrack: &ConnTrack, now: Instant) {
+        let interval = Duration::from_millis(EXPIRE_INTERVAL_MS);
+        if self.last_expire.map_or(true, |last| now - last >= interval) {
+            self.expire(conntrack, now);
+        }
+    }
+
+    /// Picks a free external port, preferring to keep `port` unchanged.
+    fn allocate_port(&mut self, protocol: IpNextHeaderProtocol, port: u16) -> Option<u16> {
+        if port >= MIN_PORT && self.used_ports.insert((protocol, port)) {
+            return Some(port);
+        }
+        let range = 65536 - MIN_PORT as u32;
+        for i in 0..range {
+            let offset = (self.next_port as u32 + i) % range;
+            let candidate = MIN_PORT + offset as u16;
+            if self.used_ports.insert((protocol, candidate)) {
+                self.next_port = ((offset + 1) % range) as u16;
+                return Some(candidate);
+            }
+        }
+        None
+    }
+}
+
+/// Icmp echo replies come back with the identifier as "source port", so the
+/// remote side of Icmp mappings is keyed without port.
+fn remote_key(protocol: IpNextHeaderProtocol, remote: SocketAddrV4) -> SocketAddrV4 {
+    if protocol == IpNextHeaderProtocols::Icmp {
+        SocketAddrV4::new(*remote.ip(), 0)
+    } else {
+        remote
+    }
+}
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// Sets the source and destination of a packet with a `FlowKey` and
+/// updates the checksums from the fields changed.
+fn rewrite(packet: &mut [u8],
+           src: SocketAddrV4,
+           dst: SocketAddrV4,
+           protocol: IpNextHeaderProtocol) {
+    let new_addresses = addresses(*src.ip(), *dst.ip());
+    let (header_len, old_addresses) = {
+        let mut ip_pkg = MutableIpv4Packet::new(packet).unwrap();
+        let old_addresses = addresses(ip_pkg.get_source(), ip_pkg.get_destination());
+        ip_pkg.set_source(*src.ip());
+        ip_pkg.set_destination(*dst.ip());
+        let csum = checksum::update(ip_pkg.get_checksum(), &old_addresses, &new_addresses);
+        ip_pkg.set_checksum(csum);
+        (ip_pkg.get_header_length() as usize * 4, old_addresses)
+    };
+    let payload = &mut packet[header_len..];
+    match protocol {
+        IpNextHeaderProtocols::Icmp => {
+            let ident = if payload[0] == ICMP_ECHO_REQUEST {
+                src.port()
+            } else {
+                dst.port()
+            };
+            let old_ident = [payload[4], payload[5]];
+            write_u16(&mut payload[4..6], ident);
+            let csum = checksum::update(read_u16(&payload[2..4]), &old_ident, &payload[4..6]);
+            write_u16(&mut payload[2..4], csum);
+        }
+        _ => {
+            let old_ports = [payload[0], payload[1], payload[2], payload[3]];
+            write_u16(&mut payload[0..2], src.port());
+            write_u16(&mut payload[2..4], dst.port());
+            let checksum_offset = if protocol == IpNextHeaderProtocols::Tcp { 16 } else { 6 };
+            let csum = read_u16(&payload[checksum_offset..checksum_offset + 2]);
+            // A zero UDP checksum means there is none
+            if protocol == IpNextHeaderProtocols::Udp && csum == 0 {
+                return;
+            }
+            // The addresses are part of the pseudo header
+            let csum = checksum::update(csum, &old_addresses, &new_addresses);
+            let csum = checksum::update(csum, &old_ports, &payload[0..4]);
+            let csum = if protocol == IpNextHeaderProtocols::Udp && csum == 0 {
+                0xffff
+            } else {
+                csum
+            };
+            write_u16(&mut payload[checksum_offset..checksum_offset + 2], csum);
+        }
+    }
+}
+
+fn addresses(src: Ipv4Addr, dst: Ipv4Addr) -> [u8; 8] {
+    let (src, dst) = (src.octets(), dst.octets());
+    [src[0], src[1], src[2], src[3], dst[0], dst[1], dst[2], dst[3]]
+}
+
+fn read_u16(buffer: &[u8]) -> u16 {
+    ((buffer[0] as u16) << 8) | buffer[1] as u16
+}
+
+fn write_u16(buffer: &mut [u8], value: u16) {
+    buffer[0] = (value >> 8) as u8;
+    buffer[1] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::Packet;
+    use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+    use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+    use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+
+    use conntrack::{ConnTimeouts, ConnTrack};
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4) -> Vec<u8> {
+        let mut buffer = vec![0; 20 + 8 + 3];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(31);
+            ip_pkg.set_source(*src.ip());
+            ip_pkg.set_destination(*dst.ip());
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            let csum = ipv4::checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        {
+            let mut udp_pkg = MutableUdpPacket::new(&mut buffer[20..]).unwrap();
+            udp_pkg.set_source(src.port());
+            udp_pkg.set_destination(dst.port());
+            udp_pkg.set_length(11);
+            udp_pkg.set_payload(&[1, 2, 3]);
+            let csum = udp::ipv4_checksum(&udp_pkg.to_immutable(), *src.ip(), *dst.ip());
+            udp_pkg.set_checksum(csum);
+        }
+        buffer
+    }
+
+    fn assert_udp(packet: &[u8], src: SocketAddrV4, dst: SocketAddrV4) {
+        let ip_pkg = Ipv4Packet::new(packet).unwrap();
+        assert_eq!(ip_pkg.get_source(), *src.ip());
+        assert_eq!(ip_pkg.get_destination(), *dst.ip());
+        assert_eq!(ip_pkg.get_checksum(), ipv4::checksum(&ip_pkg));
+        let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+        assert_eq!(udp_pkg.get_source(), src.port());
+        assert_eq!(udp_pkg.get_destination(), dst.port());
+        assert_eq!(udp_pkg.get_checksum(),
+                   udp::ipv4_checksum(&udp_pkg, *src.ip(), *dst.ip()));
+    }
+
+    fn addr(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port)
+    }
+
+    fn nat(timeouts: ConnTimeouts) -> Nat {
+        Nat::new(Ipv4Addr::new(1, 2, 3, 4), Arc::new(ConnTrack::new(timeouts)))
+    }
+
+    /// Tracks and translates `packet` like `NetworkStack::forward`
+    fn outbound(nat: &Nat, packet: &mut [u8]) -> bool {
+        nat.conntrack().track(packet);
+        nat.translate_outbound(packet)
+    }
+
+    #[test]
+    fn udp_roundtrip() {
+        let nat = nat(ConnTimeouts::default());
+        let inside = addr(192, 168, 0, 10, 5000);
+        let remote = addr(8, 8, 8, 8, 53);
+        let external = addr(1, 2, 3, 4, 5000);
+
+        let mut packet = udp_packet(inside, remote);
+        assert!(outbound(&nat, &mut packet));
+        assert_udp(&packet, external, remote);
+
+        let mut reply = udp_packet(remote, external);
+        assert!(nat.translate_inbound(&mut reply));
+        assert_udp(&reply, remote, inside);
+
+        // Traffic from other remotes, or to other ports, is not let in
+        let mut other = udp_packet(addr(8, 8, 4, 4, 53), external);
+        assert!(!nat.translate_inbound(&mut other));
+        let mut other = udp_packet(remote, addr(1, 2, 3, 4, 5001));
+        assert!(!nat.translate_inbound(&mut other));
+        assert_eq!(nat.mappings().len(), 1);
+    }
+
+    #[test]
+    fn port_conflict() {
+        let nat = nat(ConnTimeouts::default());
+        let remote = addr(8, 8, 8, 8, 53);
+        let mut first = udp_packet(addr(192, 168, 0, 10, 5000), remote);
+        let mut second = udp_packet(addr(192, 168, 0, 11, 5000), remote);
+        assert!(outbound(&nat, &mut first));
+        assert!(outbound(&nat, &mut second));
+        let port = |packet: &[u8]| {
+            UdpPacket::new(Ipv4Packet::new(packet).unwrap().payload()).unwrap().get_source()
+        };
+        assert_eq!(port(&first), 5000);
+        assert!(port(&second) != 5000);
+        assert!(port(&second) >= MIN_PORT);
+    }
+
+    #[test]
+    fn expire() {
+        let timeouts = ConnTimeouts {
+            udp_unreplied: Duration::from_secs(0),
+            ..ConnTimeouts::default()
+        };
+        let nat = nat(timeouts);
+        let remote = addr(8, 8, 8, 8, 53);
+        let mut packet = udp_packet(addr(192, 168, 0, 10, 5000), remote);
+        assert!(outbound(&nat, &mut packet));
+        assert_eq!(nat.mappings().len(), 1);
+        // The connection has timed out, so replies are no longer let in
+        let mut reply = udp_packet(remote, addr(1, 2, 3, 4, 5000));
+        assert!(!nat.translate_inbound(&mut reply));
+        nat.expire();
+        assert!(nat.mappings().is_empty());
+    }
+
+    #[test]
+    fn expire_periodically() {
+        let timeouts = ConnTimeouts {
+            udp_unreplied: Duration::from_secs(0),
+            ..ConnTimeouts::default()
+        };
+        let nat = nat(timeouts);
+        let remote = addr(8, 8, 8, 8, 53);
+        let mut first = udp_packet(addr(192, 168, 0, 10, 5000), remote);
+        assert!(outbound(&nat, &mut first));
+        // Too soon after the last time to look for expired mappings again
+        let mut second = udp_packet(addr(192, 168, 0, 11, 5000), remote);
+        assert!(outbound(&nat, &mut second));
+        assert_eq!(nat.mappings().len(), 2);
+
+        nat.table.lock().unwrap().last_expire = Some(Instant::now() - Duration::from_secs(2));
+        let mut third = udp_packet(addr(192, 168, 0, 12, 5000), remote);
+        assert!(outbound(&nat, &mut third));
+        assert_eq!(nat.mappings().len(), 1);
+    }
+
+    #[test]
+    fn untranslatable() {
+        let nat = nat(ConnTimeouts::default());
+        let mut packet = udp_packet(addr(192, 168, 0, 10, 5000), addr(8, 8, 8, 8, 53));
+        MutableIpv4Packet::new(&mut packet)
+            .unwrap()
+            .set_next_level_protocol(IpNextHeaderProtocol(47));
+        assert!(!outbound(&nat, &mut packet));
+    }
+}
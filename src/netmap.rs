@@ -0,0 +1,458 @@
+//! netmap ports as links of a stack, for high packet rates.
+//!
+//! netmap, built into FreeBSD and available as a kernel module on Linux,
+//! maps the rings of a NIC and their buffers into the process. Frames are
+//! received and sent by moving the `head` of those rings, and the kernel is
+//! only called to sync a whole ring at a time, without the weight of DPDK.
+//!
+//! ```rust,ignore
+//! let port = NetmapPort::open("eth0", 0)?;
+//! let interface = stack.add_datalink("eth0", Arc::new(port))?;
+//! ```
+//!
+//! A `NetmapPort` registers one queue of the interface, so the stack gets
+//! what the NIC steers to that queue. While it is open, the interface is
+//! disconnected from the host stack of the OS. Received frames are copied
+//! out of the netmap buffers, frames to send are built right in them with
+//! `Datalink::build_and_send`. Only 64 bit Linux and FreeBSD are supported.
+
+use {Interface, StackError, StackResult};
+use datalink::{self, Datalink};
+use ipv4;
+use rx;
+use tun;
+
+use libc;
+
+use pnet::util::MacAddr;
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+use std::sync::atomic::{self, Ordering};
+
+/// Length of interface names including the terminating NUL
+const IFNAMSIZ: usize = 16;
+
+/// Version of the `nmreq` API
+const NETMAP_API: u32 = 11;
+/// Register the one hardware ring pair in `nr_ringid`
+const NR_REG_ONE_NIC: u32 = 2;
+
+const NIOCREGIF: libc::c_ulong = 0xc0406992;
+#[cfg(target_os = "linux")]
+const NIOCTXSYNC: libc::c_ulong = 0x6995;
+#[cfg(target_os = "freebsd")]
+const NIOCTXSYNC: libc::c_ulong = 0x20006995;
+
+/// Offset of the slots from the start of a ring, after the cache aligned
+/// semaphore area
+const SLOTS_OFFSET: isize = 256;
+
+/// Room for an Ethernet header with a VLAN tag in a netmap buffer
+const FRAME_OVERHEAD: usize = 18;
+
+/// `struct nmreq` of the legacy netmap API.
+#[repr(C)]
+struct NmReq {
+    name: [libc::c_char; IFNAMSIZ],
+    version: u32,
+    offset: u32,
+    memsize: u32,
+    tx_slots: u32,
+    rx_slots: u32,
+    tx_rings: u16,
+    rx_rings: u16,
+    ringid: u16,
+    cmd: u16,
+    arg1: u16,
+    arg2: u16,
+    arg3: u32,
+    flags: u32,
+    spare: u32,
+}
+
+/// The start of `struct netmap_if`, followed by the ring offsets.
+#[repr(C)]
+struct NetmapIf {
+    name: [libc::c_char; IFNAMSIZ],
+    version: u32,
+    flags: u32,
+    tx_rings: u32,
+    rx_rings: u32,
+    bufs_head: u32,
+    host_tx_rings: u32,
+    host_rx_rings: u32,
+    spare: [u32; 3],
+}
+
+/// The start of `struct netmap_ring`.
+#[repr(C)]
+struct RingHeader {
+    buf_ofs: i64,
+    num_slots: u32,
+    buf_size: u32,
+    ringid: u16,
+    dir: u16,
+    head: u32,
+    cur: u32,
+    tail: u32,
+    flags: u32,
+}
+
+/// `struct netmap_slot`.
+#[repr(C)]
+struct Slot {
+    buf_idx: u32,
+    len: u16,
+    flags: u16,
+    ptr: u64,
+}
+
+/// One netmap ring. The process owns the slots from `head` up to `tail`,
+/// the kernel the rest.
+struct Ring {
+    base: *mut u8,
+}
+
+impl Ring {
+    fn header(&self) -> *mut RingHeader {
+        self.base as *mut RingHeader
+    }
+
+    fn num_slots(&self) -> u32 {
+        unsafe { (*self.header()).num_slots }
+    }
+
+    fn buf_size(&self) -> usize {
+        unsafe { (*self.header()).buf_size as usize }
+    }
+
+    fn head(&self) -> u32 {
+        unsafe { ptr::read_volatile(&(*self.header()).head) }
+    }
+
+    fn tail(&self) -> u32 {
+        let tail = unsafe { ptr::read_volatile(&(*self.header()).tail) };
+        atomic::fence(Ordering::Acquire);
+        tail
+    }
+
+    /// Slots from `head` up to `tail`.
+    fn space(&self) -> u32 {
+        let (head, tail) = (self.head(), self.tail());
+        if tail >= head {
+            tail - head
+        } else {
+            tail + self.num_slots() - head
+        }
+    }
+
+    /// Hands the slot at `head` to the kernel.
+    fn advance(&self) {
+        let next = (self.head() + 1) % self.num_slots();
+        atomic::fence(Ordering::Release);
+        unsafe {
+            ptr::write_volatile(&mut (*self.header()).head, next);
+            ptr::write_volatile(&mut (*self.header()).cur, next);
+        }
+    }
+
+    fn slot(&self, index: u32) -> *mut Slot {
+        unsafe { (self.base.offset(SLOTS_OFFSET) as *mut Slot).offset(index as isize) }
+    }
+
+    /// The buffer of the slot at `head`, which the process has to own.
+    /// Returns `None` if the slot holds no valid buffer.
+    fn buffer(&self) -> Option<&mut [u8]> {
+        let buf_size = self.buf_size();
+        unsafe {
+            let slot = self.slot(self.head());
+            let offset = (*self.header()).buf_ofs + (*slot).buf_idx as i64 * buf_size as i64;
+            if (*slot).buf_idx == 0 || offset < 0 {
+                return None;
+            }
+            Some(slice::from_raw_parts_mut(self.base.offset(offset as isize), buf_size))
+        }
+    }
+}
+
+/// The memory the kernel shares with the port.
+struct Mapping {
+    area: *mut libc::c_void,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.area, self.len) };
+    }
+}
+
+/// One queue of an interface opened with netmap. See the module
+/// documentation.
+pub struct NetmapPort {
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+    _mapping: Mapping,
+    file: File,
+    mac: MacAddr,
+    mtu: Option<usize>,
+}
+
+// The rings and buffers are only mapped by the port owning them, and each
+// ring is only used under its lock.
+unsafe impl Send for NetmapPort {}
+unsafe impl Sync for NetmapPort {}
+
+impl NetmapPort {
+    /// Opens the hardware queue `queue` of the interface `name` through
+    /// `/dev/netmap`. Fails with `StackError::NoSuchInterface` if there is
+    /// no interface `name` with a MAC address.
+    pub fn open(name: &str, queue: u16) -> StackResult<NetmapPort> {
+        if name.len() >= IFNAMSIZ {
+            return Err(StackError::NoSuchInterface(name.to_owned()));
+        }
+        let os_interface = ::pnet::datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == name)
+            .ok_or_else(|| StackError::NoSuchInterface(name.to_owned()))?;
+        let Interface { mac, .. } = ::convert_interface(&os_interface)
+            .map_err(|_| StackError::NoSuchInterface(name.to_owned()))?;
+
+        let file = OpenOptions::new().read(true).write(true).open("/dev/netmap")?;
+        let mut request = NmReq {
+            name: [0; IFNAMSIZ],
+            version: NETMAP_API,
+            offset: 0,
+            memsize: 0,
+            tx_slots: 0,
+            rx_slots: 0,
+            tx_rings: 0,
+            rx_rings: 0,
+            ringid: queue,
+            cmd: 0,
+            arg1: 0,
+            arg2: 0,
+            arg3: 0,
+            flags: NR_REG_ONE_NIC,
+            spare: 0,
+        };
+        for (dst, &byte) in request.name.iter_mut().zip(name.as_bytes()) {
+            *dst = byte as libc::c_char;
+        }
+        // The kernel only reads and writes within the nmreq given
+        if unsafe { libc::ioctl(file.as_raw_fd(), NIOCREGIF as _, &mut request) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let len = request.memsize as usize;
+        let area = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED,
+                       file.as_raw_fd(),
+                       0)
+        };
+        if area == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let mapping = Mapping {
+            area: area,
+            len: len,
+        };
+
+        // The interface and its rings lie within the mapping, at the
+        // offsets the kernel put there
+        let (tx, rx) = unsafe {
+            let nifp = (area as *mut u8).offset(request.offset as isize);
+            let interface = &*(nifp as *const NetmapIf);
+            let ring_ofs = nifp.offset(::std::mem::size_of::<NetmapIf>() as isize) as *const isize;
+            // Kernels from before the host ring counts have one host ring
+            let host_tx_rings = cmp::max(interface.host_tx_rings, 1);
+            let rx_index = interface.tx_rings + host_tx_rings + queue as u32;
+            (Ring { base: nifp.offset(*ring_ofs.offset(queue as isize)) },
+             Ring { base: nifp.offset(*ring_ofs.offset(rx_index as isize)) })
+        };
+        let max_mtu = tx.buf_size() - FRAME_OVERHEAD;
+        let mtu = datalink::os_mtu(name)
+            .map(|mtu| cmp::min(mtu, max_mtu))
+            .and_then(|mtu| if mtu >= ipv4::MIN_MTU { Some(mtu) } else { None });
+        Ok(NetmapPort {
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+            _mapping: mapping,
+            file: file,
+            mac: mac,
+            mtu: mtu,
+        })
+    }
+
+    /// Fills `num_frames` buffers of the tx ring, the `i`th one with `len(i)`
+    /// bytes from `fill(i, buffer)`, and syncs the ring once.
+    fn queue_frames<L, F>(&self, num_frames: usize, len: L, mut fill: F) -> io::Result<()>
+        where L: Fn(usize) -> usize,
+              F: FnMut(usize, &mut [u8])
+    {
+        let tx = self.tx.lock().unwrap();
+        for i in 0..num_frames {
+            let len = len(i);
+            if len > tx.buf_size() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Frame larger than netmap buffers"));
+            }
+            if tx.space() == 0 {
+                self.sync_tx()?;
+                if tx.space() == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "Tx ring full"));
+                }
+            }
+            {
+                let buffer = tx.buffer()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Invalid tx buffer"))?;
+                fill(i, &mut buffer[..len]);
+            }
+            unsafe { (*tx.slot(tx.head())).len = len as u16 };
+            tx.advance();
+        }
+        self.sync_tx()
+    }
+
+    fn sync_tx(&self) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), NIOCTXSYNC as _) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Datalink for NetmapPort {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.send_batch(&[frame])
+    }
+
+    /// Puts all of `frames` on the tx ring and then syncs it once.
+    fn send_batch(&self, frames: &[&[u8]]) -> io::Result<()> {
+        self.queue_frames(frames.len(),
+                          |i| frames[i].len(),
+                          |i, buffer| buffer.copy_from_slice(frames[i]))
+    }
+
+    /// Builds the frames in the netmap buffers directly, without copying
+    /// them.
+    fn build_and_send(&self,
+                      num_frames: usize,
+                      frame_size: usize,
+                      build: &mut FnMut(&mut [u8]))
+                      -> Option<io::Result<()>> {
+        Some(self.queue_frames(num_frames, |_| frame_size, |_, buffer| build(buffer)))
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let rx = self.rx.lock().unwrap();
+        // Polling syncs the rx ring, handing back the slots read before
+        if rx.space() == 0 {
+            tun::poll_readable(&self.file, rx::POLL_INTERVAL_MS)?;
+        }
+        if rx.space() == 0 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received"));
+        }
+        let frame = take_frame(&rx);
+        rx.advance();
+        frame
+    }
+}
+
+/// Copies the frame in the slot at `head` of `ring`.
+fn take_frame(ring: &Ring) -> io::Result<Box<[u8]>> {
+    let len = unsafe { (*ring.slot(ring.head())).len as usize };
+    match ring.buffer() {
+        Some(ref buffer) if len <= buffer.len() => Ok(buffer[..len].to_vec().into_boxed_slice()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid rx slot")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use StackError;
+
+    use std::mem;
+
+    use super::*;
+
+    /// A ring of four slots with buffers of 64 bytes, laid out like netmap,
+    /// in `memory`.
+    fn ring(memory: &mut Vec<u64>) -> Ring {
+        memory.clear();
+        memory.resize((SLOTS_OFFSET as usize + 4 * 16 + 5 * 64) / 8, 0);
+        let ring = Ring { base: memory.as_mut_ptr() as *mut u8 };
+        unsafe {
+            let header = &mut *ring.header();
+            // Buffer 0 is never handed out, the slots get buffers 1 to 4
+            header.buf_ofs = SLOTS_OFFSET as i64 + 4 * 16;
+            header.num_slots = 4;
+            header.buf_size = 64;
+            for index in 0..4 {
+                (*ring.slot(index)).buf_idx = index + 1;
+            }
+        }
+        assert_eq!(mem::size_of::<Slot>(), 16);
+        ring
+    }
+
+    #[test]
+    fn unknown_interface() {
+        match NetmapPort::open("no-such-interface", 0) {
+            Err(StackError::NoSuchInterface(..)) => (),
+            _ => panic!("Expected NoSuchInterface"),
+        }
+        match NetmapPort::open("no-such-if", 0) {
+            Err(StackError::NoSuchInterface(..)) => (),
+            _ => panic!("Expected NoSuchInterface"),
+        }
+    }
+
+    #[test]
+    fn ring_space_wraps() {
+        let mut memory = Vec::new();
+        let ring = ring(&mut memory);
+        unsafe { (*ring.header()).tail = 3 };
+        assert_eq!(ring.space(), 3);
+        for _ in 0..3 {
+            ring.advance();
+        }
+        assert_eq!((ring.head(), ring.space()), (3, 0));
+        unsafe { (*ring.header()).tail = 2 };
+        assert_eq!(ring.space(), 3);
+        ring.advance();
+        assert_eq!(ring.head(), 0);
+    }
+
+    #[test]
+    fn take_frames() {
+        let mut memory = Vec::new();
+        let ring = ring(&mut memory);
+        unsafe { (*ring.header()).tail = 2 };
+        for (index, frame) in [[1u8; 3], [2u8; 3]].iter().enumerate() {
+            ring.buffer().unwrap()[..3].copy_from_slice(frame);
+            unsafe { (*ring.slot(index as u32)).len = 3 };
+            assert_eq!(&*take_frame(&ring).unwrap(), frame);
+            ring.advance();
+        }
+        assert_eq!(ring.space(), 0);
+        unsafe { (*ring.slot(ring.head())).len = 65 };
+        assert_eq!(take_frame(&ring).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
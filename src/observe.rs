@@ -0,0 +1,223 @@
+//! Watching every frame an interface sends and receives.
+//!
+//! An `Observer` added with `StackInterface::add_observer` is called for
+//! every frame on the datalink of the interface, with the direction, the
+//! interface and the time it was seen. It only gets to look at the frames,
+//! what the stack does with them is unchanged. That is enough for logging,
+//! for counting traffic by whatever the frames contain, or for an intrusion
+//! detection system:
+//!
+//! ```rust,ignore
+//! let log = |direction: Direction,
+//!            interface: &Interface,
+//!            _time: SystemTime,
+//!            frame: &EthernetPacket| debug!("{:?} on {}: {:?}", direction, interface.name, frame);
+//! let id = stack.interface(&interface)?.add_observer(log);
+//! ...
+//! stack.interface(&interface)?.remove_observer(id);
+//! ```
+//!
+//! Observers see frames where a capture does, see `pcap`: received frames
+//! before any filter, sent frames after the egress queues. They are called
+//! on the rx thread of the interface and on the threads sending, one frame
+//! at a time, so they should be quick and must not panic. While an interface
+//! has no observers and no capture its datalink pays two atomic loads per
+//! frame.
+
+use {EthernetChannel, Interface};
+use pcap::{CapturePoint, Direction};
+
+use pnet::datalink::{EthernetDataLinkChannelIterator, EthernetDataLinkReceiver,
+                     EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Code seeing the frames of an interface. Implemented for all closures
+/// with the same signature as `observe`.
+pub trait Observer: Send + Sync {
+    fn observe(&self,
+               direction: Direction,
+               interface: &Interface,
+               time: SystemTime,
+               frame: &EthernetPacket);
+}
+
+impl<F> Observer for F
+    where F: Fn(Direction, &Interface, SystemTime, &EthernetPacket) + Send + Sync
+{
+    fn observe(&self,
+               direction: Direction,
+               interface: &Interface,
+               time: SystemTime,
+               frame: &EthernetPacket) {
+        self(direction, interface, time, frame)
+    }
+}
+
+/// Identifies an observer added to `Observers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(usize);
+
+/// Everything watching the frames of one interface, its capture and its
+/// observers. See `channel`.
+pub struct Observers {
+    interface: Interface,
+    capture: Arc<CapturePoint>,
+    observers: RwLock<Vec<(ObserverId, Arc<Observer>)>>,
+    /// Set while `observers` is not empty
+    observed: AtomicBool,
+    next_id: AtomicUsize,
+}
+
+impl Observers {
+    pub fn new(interface: Interface, capture: Arc<CapturePoint>) -> Observers {
+        Observers {
+            interface: interface,
+            capture: capture,
+            observers: RwLock::new(Vec::new()),
+            observed: AtomicBool::new(false),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Calls `observer` for every frame from now on.
+    pub fn add(&self, observer: Arc<Observer>) -> ObserverId {
+        let id = ObserverId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        let mut observers = self.observers.write().unwrap();
+        observers.push((id, observer));
+        self.observed.store(true, Ordering::SeqCst);
+        id
+    }
+
+    /// Removes an observer, returning it if it existed.
+    pub fn remove(&self, id: ObserverId) -> Option<Arc<Observer>> {
+        let mut observers = self.observers.write().unwrap();
+        let index = match observers.iter().position(|&(other, _)| other == id) {
+            Some(index) => index,
+            None => return None,
+        };
+        let (_, observer) = observers.remove(index);
+        self.observed.store(!observers.is_empty(), Ordering::SeqCst);
+        Some(observer)
+    }
+
+    /// Returns true if anything watches the frames, an observer or a capture.
+    pub fn is_active(&self) -> bool {
+        self.observed.load(Ordering::Relaxed) || self.capture.is_active()
+    }
+
+    fn notify(&self, direction: Direction, frame: &[u8]) {
+        let time = SystemTime::now();
+        self.capture.record(direction, time, frame);
+        if !self.observed.load(Ordering::Relaxed) {
+            return;
+        }
+        let packet = match EthernetPacket::new(frame) {
+            Some(packet) => packet,
+            None => return,
+        };
+        for &(_, ref observer) in self.observers.read().unwrap().iter() {
+            observer.observe(direction, &self.interface, time, &packet);
+        }
+    }
+}
+
+/// Returns `channel` showing everything sent and received on it to
+/// `observers`.
+pub fn channel(channel: EthernetChannel, observers: Arc<Observers>) -> EthernetChannel {
+    let EthernetChannel(tx, rx) = channel;
+    let sender = Sender {
+        tx: tx,
+        observers: observers.clone(),
+    };
+    let receiver = Receiver {
+        rx: rx,
+        observers: observers,
+    };
+    EthernetChannel(Box::new(sender), Box::new(receiver))
+}
+
+struct Sender {
+    tx: Box<EthernetDataLinkSender>,
+    observers: Arc<Observers>,
+}
+
+impl EthernetDataLinkSender for Sender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        if !self.observers.is_active() {
+            return self.tx.build_and_send(num_packets, packet_size, func);
+        }
+        // Built here first, the frames can't be read back from the datalink
+        let mut frames = Vec::with_capacity(num_packets);
+        for _ in 0..num_packets {
+            let mut frame = vec![0; packet_size];
+            match MutableEthernetPacket::new(&mut frame) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            frames.push(frame);
+        }
+        let mut next = frames.iter();
+        let mut copy = |mut packet: MutableEthernetPacket| {
+            packet.packet_mut().copy_from_slice(next.next().unwrap())
+        };
+        let result = self.tx.build_and_send(num_packets, packet_size, &mut copy);
+        if let Some(Ok(())) = result {
+            for frame in &frames {
+                self.observers.notify(Direction::Outbound, frame);
+            }
+        }
+        result
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        let result = self.tx.send_to(packet, dst);
+        if let Some(Ok(())) = result {
+            if self.observers.is_active() {
+                self.observers.notify(Direction::Outbound, packet.packet());
+            }
+        }
+        result
+    }
+}
+
+struct Receiver {
+    rx: Box<EthernetDataLinkReceiver>,
+    observers: Arc<Observers>,
+}
+
+impl EthernetDataLinkReceiver for Receiver {
+    fn iter<'a>(&'a mut self) -> Box<EthernetDataLinkChannelIterator + 'a> {
+        Box::new(ReceiverIterator {
+            rx: self.rx.iter(),
+            observers: self.observers.clone(),
+        })
+    }
+}
+
+struct ReceiverIterator<'a> {
+    rx: Box<EthernetDataLinkChannelIterator<'a> + 'a>,
+    observers: Arc<Observers>,
+}
+
+impl<'a> EthernetDataLinkChannelIterator<'a> for ReceiverIterator<'a> {
+    fn next(&mut self) -> io::Result<EthernetPacket> {
+        let packet = self.rx.next()?;
+        if self.observers.is_active() {
+            self.observers.notify(Direction::Inbound, packet.packet());
+        }
+        Ok(packet)
+    }
+}
@@ -0,0 +1,778 @@
+//! Capturing the frames of an interface to files Wireshark opens.
+//!
+//! Every interface can write the frames it sends and receives to a capture
+//! file, set up with `StackInterface::set_capture`:
+//!
+//! ```rust,ignore
+//! let mut config = CaptureConfig::new("/tmp/eth0.pcapng");
+//! config.format = Format::Pcapng;
+//! config.rotation = Some(Rotation { max_bytes: 10 << 20, max_files: 5 });
+//! stack.interface(&interface)?.set_capture(Some(config))?;
+//! ```
+//!
+//! Frames are captured right on the datalink, so received frames are
+//! captured before any filter decides what to do with them, and sent frames
+//! after the egress queues, Arp included. The pcapng
+//! format also records whether each frame was sent or received, and the
+//! name of the interface.
+//!
+//! `PcapReader` reads such files back, and those of tcpdump and Wireshark,
+//! see `replay` for feeding them into a stack.
+//!
+//! A capture stops by itself, with a warning in the log, when writing to its
+//! file fails. While a capture is set, as while any `observe::Observer` is,
+//! every frame sent is built in a buffer of its own and copied to the
+//! datalink.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The snaplen of captures by default, enough for any frame.
+pub static DEFAULT_SNAPLEN: usize = 65535;
+
+/// The link type of Ethernet in both formats.
+const LINKTYPE_ETHERNET: u16 = 1;
+
+/// The file formats a capture can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The classic libpcap format, with microsecond timestamps
+    Pcap,
+    /// pcapng, with the interface name and the direction of every frame
+    Pcapng,
+}
+
+/// Whether a frame was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// When a capture starts a new file, and how many old ones it keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Size at which the current file is closed and a new one started
+    pub max_bytes: u64,
+    /// Files kept, including the current one. With `path` the current one,
+    /// the older ones are `path.1`, `path.2` and so on, the oldest is
+    /// deleted when a new file would make one too many
+    pub max_files: usize,
+}
+
+/// Where and how a capture writes the frames. See the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub path: PathBuf,
+    pub format: Format,
+    /// Bytes of every frame written at most, the rest is cut off
+    pub snaplen: usize,
+    /// Writes one file that grows without limit if `None`
+    pub rotation: Option<Rotation>,
+}
+
+impl CaptureConfig {
+    /// A capture of whole frames to `path` in the classic pcap format,
+    /// without rotation.
+    pub fn new<P: Into<PathBuf>>(path: P) -> CaptureConfig {
+        CaptureConfig {
+            path: path.into(),
+            format: Format::Pcap,
+            snaplen: DEFAULT_SNAPLEN,
+            rotation: None,
+        }
+    }
+}
+
+/// Counters of a `Capture`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CaptureStats {
+    /// Frames written
+    pub frames: u64,
+    /// Bytes written to all files, headers included
+    pub bytes: u64,
+    /// Files started, one more with every rotation
+    pub files: usize,
+}
+
+/// Writes frames in one of the capture formats to `W`.
+pub struct PcapWriter<W: Write> {
+    out: W,
+    format: Format,
+    snaplen: usize,
+    written: u64,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header to `out`, naming the interface `interface` in
+    /// pcapng.
+    pub fn new(out: W, format: Format, snaplen: usize, interface: &str) -> io::Result<Self> {
+        let mut writer = PcapWriter {
+            out: out,
+            format: format,
+            snaplen: snaplen,
+            written: 0,
+        };
+        let mut header = Vec::new();
+        match format {
+            Format::Pcap => {
+                put_u32(&mut header, 0xa1b2c3d4);
+                put_u16(&mut header, 2);
+                put_u16(&mut header, 4);
+                // Timestamps in UTC, and their accuracy unknown
+                put_u32(&mut header, 0);
+                put_u32(&mut header, 0);
+                put_u32(&mut header, snaplen as u32);
+                put_u32(&mut header, LINKTYPE_ETHERNET as u32);
+            }
+            Format::Pcapng => {
+                let mut section = Vec::new();
+                put_u32(&mut section, 0x1a2b3c4d);
+                put_u16(&mut section, 1);
+                put_u16(&mut section, 0);
+                // The length of the section is not known up front
+                section.extend_from_slice(&[0xff; 8]);
+                put_block(&mut header, 0x0a0d0d0a, &section);
+
+                let mut description = Vec::new();
+                put_u16(&mut description, LINKTYPE_ETHERNET);
+                put_u16(&mut description, 0);
+                put_u32(&mut description, snaplen as u32);
+                put_option(&mut description, 2, interface.as_bytes());
+                put_option(&mut description, 0, &[]);
+                put_block(&mut header, 1, &description);
+            }
+        }
+        writer.write_all(&header)?;
+        Ok(writer)
+    }
+
+    /// Writes `frame`, sent or received at `time`.
+    pub fn write(&mut self,
+                 direction: Direction,
+                 time: SystemTime,
+                 frame: &[u8])
+                 -> io::Result<()> {
+        let captured = &frame[..cmp::min(frame.len(), self.snaplen)];
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+        let micros = since_epoch.as_secs() * 1_000_000 + since_epoch.subsec_nanos() as u64 / 1000;
+        let mut record = Vec::with_capacity(captured.len() + 48);
+        match self.format {
+            Format::Pcap => {
+                put_u32(&mut record, since_epoch.as_secs() as u32);
+                put_u32(&mut record, since_epoch.subsec_nanos() / 1000);
+                put_u32(&mut record, captured.len() as u32);
+                put_u32(&mut record, frame.len() as u32);
+                record.extend_from_slice(captured);
+            }
+            Format::Pcapng => {
+                let mut packet = Vec::with_capacity(captured.len() + 32);
+                // The only interface of the section
+                put_u32(&mut packet, 0);
+                put_u32(&mut packet, (micros >> 32) as u32);
+                put_u32(&mut packet, micros as u32);
+                put_u32(&mut packet, captured.len() as u32);
+                put_u32(&mut packet, frame.len() as u32);
+                packet.extend_from_slice(captured);
+                pad(&mut packet);
+                let flags = match direction {
+                    Direction::Inbound => 1u32,
+                    Direction::Outbound => 2,
+                };
+                let mut value = Vec::new();
+                put_u32(&mut value, flags);
+                put_option(&mut packet, 2, &value);
+                put_option(&mut packet, 0, &[]);
+                put_block(&mut record, 6, &packet);
+            }
+        }
+        self.write_all(&record)
+    }
+
+    /// Returns the bytes written so far, the header included.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Both formats are written little endian, readers tell by the magic number.
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    put_u16(out, value as u16);
+    put_u16(out, (value >> 16) as u16);
+}
+
+/// Pads `out` with zeroes to a multiple of 32 bits, as pcapng wants.
+fn pad(out: &mut Vec<u8>) {
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+fn put_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    put_u16(out, code);
+    put_u16(out, value.len() as u16);
+    out.extend_from_slice(value);
+    pad(out);
+}
+
+/// Writes a pcapng block of type `block_type` around the already padded
+/// `body`.
+fn put_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    let len = 12 + body.len() as u32;
+    put_u32(out, block_type);
+    put_u32(out, len);
+    out.extend_from_slice(body);
+    put_u32(out, len);
+}
+
+/// A frame read from a capture file by a `PcapReader`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: SystemTime,
+    /// Whether the frame was sent or received, if the file says
+    pub direction: Option<Direction>,
+    /// The frame, as much of it as was captured
+    pub data: Vec<u8>,
+    /// The length the frame had on the wire
+    pub len: usize,
+}
+
+/// An interface of a pcapng section.
+struct ReaderInterface {
+    ethernet: bool,
+    snaplen: usize,
+    /// Timestamp units per second
+    resolution: u64,
+}
+
+/// Reads the Ethernet frames of a capture file in either format, as written
+/// by `PcapWriter` or by tcpdump and Wireshark, in either byte order.
+pub struct PcapReader<R: Read> {
+    input: R,
+    format: Format,
+    big_endian: bool,
+    /// Timestamp units per second of a pcap file
+    resolution: u64,
+    /// The interfaces of the current pcapng section
+    interfaces: Vec<ReaderInterface>,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Reads the file header from `input`. Fails with
+    /// `io::ErrorKind::InvalidData` if it is not a capture file, or a pcap
+    /// file of another link type than Ethernet.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic)?;
+        let mut reader = PcapReader {
+            input: input,
+            format: Format::Pcap,
+            big_endian: false,
+            resolution: 1_000_000,
+            interfaces: Vec::new(),
+        };
+        match get_u32(&magic, false) {
+            0x0a0d0d0a => {
+                reader.format = Format::Pcapng;
+                reader.read_section(&magic)?;
+            }
+            magic => {
+                let (big_endian, resolution) = match magic {
+                    0xa1b2c3d4 => (false, 1_000_000),
+                    0xd4c3b2a1 => (true, 1_000_000),
+                    0xa1b23c4d => (false, 1_000_000_000),
+                    0x4d3cb2a1 => (true, 1_000_000_000),
+                    _ => return Err(invalid_data("Not a capture file")),
+                };
+                reader.big_endian = big_endian;
+                reader.resolution = resolution;
+                let mut header = [0; 20];
+                reader.input.read_exact(&mut header)?;
+                if get_u32(&header[16..], big_endian) & 0xffff != LINKTYPE_ETHERNET as u32 {
+                    return Err(invalid_data("Not a capture of Ethernet frames"));
+                }
+            }
+        }
+        Ok(reader)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Returns the next frame, or `None` at the end of the file. Frames of
+    /// pcapng interfaces of other link types than Ethernet are skipped.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        match self.format {
+            Format::Pcap => self.next_pcap_record(),
+            Format::Pcapng => self.next_pcapng_record(),
+        }
+    }
+
+    fn next_pcap_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0; 16];
+        if !read_or_eof(&mut self.input, &mut header)? {
+            return Ok(None);
+        }
+        let seconds = get_u32(&header, self.big_endian) as u64;
+        let fraction = get_u32(&header[4..], self.big_endian) as u64;
+        let captured = get_u32(&header[8..], self.big_endian) as usize;
+        let len = get_u32(&header[12..], self.big_endian) as usize;
+        if captured > MAX_RECORD_LEN {
+            return Err(invalid_data("Record longer than any frame"));
+        }
+        let mut data = vec![0; captured];
+        self.input.read_exact(&mut data)?;
+        Ok(Some(Record {
+            time: timestamp(seconds * self.resolution + fraction, self.resolution),
+            direction: None,
+            data: data,
+            len: len,
+        }))
+    }
+
+    fn next_pcapng_record(&mut self) -> io::Result<Option<Record>> {
+        loop {
+            let mut head = [0; 8];
+            if !read_or_eof(&mut self.input, &mut head)? {
+                return Ok(None);
+            }
+            if get_u32(&head, false) == 0x0a0d0d0a {
+                self.read_section(&head[..4])?;
+                continue;
+            }
+            let block_type = get_u32(&head, self.big_endian);
+            let body = self.read_block_body(&head)?;
+            match block_type {
+                1 => self.read_interface(&body)?,
+                // Enhanced packets
+                6 if body.len() >= 20 => {
+                    let interface = get_u32(&body, self.big_endian) as usize;
+                    let high = get_u32(&body[4..], self.big_endian) as u64;
+                    let low = get_u32(&body[8..], self.big_endian) as u64;
+                    let captured = get_u32(&body[12..], self.big_endian) as usize;
+                    let len = get_u32(&body[16..], self.big_endian) as usize;
+                    let end = 20 + captured;
+                    if end > body.len() {
+                        return Err(invalid_data("Packet longer than its block"));
+                    }
+                    let (ethernet, resolution) = match self.interfaces.get(interface) {
+                        Some(interface) => (interface.ethernet, interface.resolution),
+                        None => return Err(invalid_data("Packet of an unknown interface")),
+                    };
+                    if !ethernet {
+                        continue;
+                    }
+                    let options = &body[cmp::min((end + 3) & !3, body.len())..];
+                    let flags = find_option(options, 2, self.big_endian);
+                    let direction = match flags {
+                        Some(flags) if flags.len() >= 4 => {
+                            match get_u32(flags, self.big_endian) & 3 {
+                                1 => Some(Direction::Inbound),
+                                2 => Some(Direction::Outbound),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    return Ok(Some(Record {
+                        time: timestamp(high << 32 | low, resolution),
+                        direction: direction,
+                        data: body[20..end].to_vec(),
+                        len: len,
+                    }));
+                }
+                // Simple packets, without a timestamp, of the first interface
+                3 if body.len() >= 4 => {
+                    let interface = match self.interfaces.first() {
+                        Some(interface) => interface,
+                        None => return Err(invalid_data("Packet of an unknown interface")),
+                    };
+                    if !interface.ethernet {
+                        continue;
+                    }
+                    let len = get_u32(&body, self.big_endian) as usize;
+                    let captured = cmp::min(cmp::min(len, interface.snaplen), body.len() - 4);
+                    return Ok(Some(Record {
+                        time: UNIX_EPOCH,
+                        direction: None,
+                        data: body[4..4 + captured].to_vec(),
+                        len: len,
+                    }));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads the rest of a section header block starting with `block_type`,
+    /// which may change the byte order.
+    fn read_section(&mut self, block_type: &[u8]) -> io::Result<()> {
+        let mut head = [0; 12];
+        head[..4].copy_from_slice(block_type);
+        self.input.read_exact(&mut head[4..])?;
+        self.big_endian = match get_u32(&head[8..], false) {
+            0x1a2b3c4d => false,
+            0x4d3c2b1a => true,
+            _ => return Err(invalid_data("Not a capture file")),
+        };
+        self.interfaces.clear();
+        let mut rest = vec![0; self.block_len(&head)? - 12];
+        self.input.read_exact(&mut rest)?;
+        Ok(())
+    }
+
+    fn read_interface(&mut self, body: &[u8]) -> io::Result<()> {
+        if body.len() < 8 {
+            return Err(invalid_data("Interface block too short"));
+        }
+        // A power of ten, or of two if the high bit is set
+        let resolution = match find_option(&body[8..], 9, self.big_endian) {
+            Some(value) if value.len() == 1 && value[0] & 0x80 == 0 => 10u64.pow(value[0] as u32),
+            Some(value) if value.len() == 1 => 1 << (value[0] & 0x7f),
+            _ => 1_000_000,
+        };
+        self.interfaces.push(ReaderInterface {
+            ethernet: get_u16(body, self.big_endian) == LINKTYPE_ETHERNET,
+            snaplen: get_u32(&body[4..], self.big_endian) as usize,
+            resolution: resolution,
+        });
+        Ok(())
+    }
+
+    /// Reads the body of the block starting with `head`, and its trailing
+    /// length.
+    fn read_block_body(&mut self, head: &[u8]) -> io::Result<Vec<u8>> {
+        let mut body = vec![0; self.block_len(head)? - 8];
+        self.input.read_exact(&mut body)?;
+        let len = body.len() - 4;
+        body.truncate(len);
+        Ok(body)
+    }
+
+    fn block_len(&self, head: &[u8]) -> io::Result<usize> {
+        let len = get_u32(&head[4..], self.big_endian) as usize;
+        if len < 12 || len % 4 != 0 || len > MAX_RECORD_LEN {
+            return Err(invalid_data("Invalid block length"));
+        }
+        Ok(len)
+    }
+}
+
+/// Records longer than this are taken for a corrupt file.
+const MAX_RECORD_LEN: usize = 1 << 18;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn get_u16(data: &[u8], big_endian: bool) -> u16 {
+    if big_endian {
+        (data[0] as u16) << 8 | data[1] as u16
+    } else {
+        (data[1] as u16) << 8 | data[0] as u16
+    }
+}
+
+fn get_u32(data: &[u8], big_endian: bool) -> u32 {
+    let first = get_u16(data, big_endian) as u32;
+    let second = get_u16(&data[2..], big_endian) as u32;
+    if big_endian {
+        first << 16 | second
+    } else {
+        second << 16 | first
+    }
+}
+
+/// Returns the value of the pcapng option `code` in `options`, if set.
+fn find_option(options: &[u8], code: u16, big_endian: bool) -> Option<&[u8]> {
+    let mut options = options;
+    while options.len() >= 4 {
+        let (this_code, len) = (get_u16(options, big_endian), get_u16(&options[2..], big_endian));
+        let len = len as usize;
+        if this_code == 0 || 4 + len > options.len() {
+            return None;
+        }
+        if this_code == code {
+            return Some(&options[4..4 + len]);
+        }
+        options = &options[cmp::min((4 + len + 3) & !3, options.len())..];
+    }
+    None
+}
+
+/// Returns the time `units` of `resolution` per second after the epoch.
+fn timestamp(units: u64, resolution: u64) -> SystemTime {
+    let nanos = (units % resolution) as f64 * 1e9 / resolution as f64;
+    UNIX_EPOCH + Duration::new(units / resolution, nanos as u32)
+}
+
+/// Fills `buffer` from `input`. Returns false if `input` was at its end
+/// already.
+fn read_or_eof<R: Read>(input: &mut R, buffer: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read == 0 {
+        match input.read(buffer) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read = n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    input.read_exact(&mut buffer[read..])?;
+    Ok(true)
+}
+
+/// A capture of the frames of one interface to files, rotated as its
+/// config says.
+pub struct Capture {
+    config: CaptureConfig,
+    interface: String,
+    writer: PcapWriter<BufWriter<File>>,
+    stats: CaptureStats,
+    /// Bytes written to the files closed before the current one
+    closed_bytes: u64,
+}
+
+impl Capture {
+    /// Creates the file of `config`, replacing it if it exists, for the
+    /// frames of the interface named `interface`.
+    pub fn open(config: CaptureConfig, interface: &str) -> io::Result<Capture> {
+        let writer = Self::create(&config, interface)?;
+        Ok(Capture {
+            config: config,
+            interface: interface.to_owned(),
+            writer: writer,
+            stats: CaptureStats {
+                files: 1,
+                ..CaptureStats::default()
+            },
+            closed_bytes: 0,
+        })
+    }
+
+    fn create(config: &CaptureConfig, interface: &str) -> io::Result<PcapWriter<BufWriter<File>>> {
+        let file = BufWriter::new(File::create(&config.path)?);
+        PcapWriter::new(file, config.format, config.snaplen, interface)
+    }
+
+    pub fn config(&self) -> &CaptureConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> CaptureStats {
+        CaptureStats {
+            bytes: self.closed_bytes + self.writer.written(),
+            ..self.stats
+        }
+    }
+
+    /// Writes `frame` and flushes it to the file, so it can be looked at
+    /// while the capture is still running.
+    pub fn record(&mut self,
+                  direction: Direction,
+                  time: SystemTime,
+                  frame: &[u8])
+                  -> io::Result<()> {
+        if let Some(rotation) = self.config.rotation {
+            if self.writer.written() >= rotation.max_bytes {
+                self.rotate(rotation.max_files)?;
+            }
+        }
+        self.writer.write(direction, time, frame)?;
+        self.writer.flush()?;
+        self.stats.frames += 1;
+        Ok(())
+    }
+
+    /// Moves the files one number up, dropping the oldest, and starts a new
+    /// one.
+    fn rotate(&mut self, max_files: usize) -> io::Result<()> {
+        self.writer.flush()?;
+        if max_files > 1 {
+            let path = &self.config.path;
+            let numbered = |i: usize| {
+                let mut numbered = path.clone().into_os_string();
+                numbered.push(format!(".{}", i));
+                PathBuf::from(numbered)
+            };
+            for i in (1..max_files - 1).rev() {
+                if numbered(i).exists() {
+                    fs::rename(numbered(i), numbered(i + 1))?;
+                }
+            }
+            fs::rename(path, numbered(1))?;
+        }
+        self.closed_bytes += self.writer.written();
+        self.writer = Self::create(&self.config, &self.interface)?;
+        self.stats.files += 1;
+        Ok(())
+    }
+}
+
+/// Where an interface captures its frames to, if anywhere. Fed by
+/// `observe::channel`.
+#[derive(Default)]
+pub struct CapturePoint {
+    active: AtomicBool,
+    capture: Mutex<Option<Capture>>,
+}
+
+impl CapturePoint {
+    /// Starts capturing to `capture`, or stops if it is `None`. Returns the
+    /// previous capture, its files are complete once it is dropped.
+    pub fn set(&self, capture: Option<Capture>) -> Option<Capture> {
+        let mut current = self.capture.lock().unwrap();
+        self.active.store(capture.is_some(), Ordering::SeqCst);
+        ::std::mem::replace(&mut *current, capture)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counters of the capture, if there is one.
+    pub fn stats(&self) -> Option<CaptureStats> {
+        self.capture.lock().unwrap().as_ref().map(|capture| capture.stats())
+    }
+
+    /// Writes `frame` to the capture, if there is one. A capture failing to
+    /// write is stopped.
+    pub fn record(&self, direction: Direction, time: SystemTime, frame: &[u8]) {
+        if !self.is_active() {
+            return;
+        }
+        let mut capture = self.capture.lock().unwrap();
+        let failed = match *capture {
+            Some(ref mut capture) => {
+                match capture.record(direction, time, frame) {
+                    Ok(()) => false,
+                    Err(e) => {
+                        warn!("Capture to {} stopped: {}", capture.config.path.display(), e);
+                        true
+                    }
+                }
+            }
+            None => false,
+        };
+        if failed {
+            *capture = None;
+            self.active.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn pcap_records() {
+        let mut writer = PcapWriter::new(Vec::new(), Format::Pcap, 4, "eth0").unwrap();
+        let time = UNIX_EPOCH + Duration::new(7, 5000);
+        writer.write(Direction::Inbound, time, &[1, 2, 3, 4, 5, 6]).unwrap();
+        let out = writer.into_inner();
+        assert_eq!(&out[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(out.len(), 24 + 16 + 4);
+        // Seconds, microseconds, captured and original length, then the data
+        assert_eq!(&out[24..],
+                   &[7, 0, 0, 0, 5, 0, 0, 0, 4, 0, 0, 0, 6, 0, 0, 0, 1, 2, 3, 4]);
+    }
+
+    fn round_trip(format: Format) {
+        let mut writer = PcapWriter::new(Vec::new(), format, 4, "eth0").unwrap();
+        let time = UNIX_EPOCH + Duration::new(1_500_000_000, 123_456_000);
+        writer.write(Direction::Inbound, time, &[1, 2, 3]).unwrap();
+        writer.write(Direction::Outbound, time, &[4, 5, 6, 7, 8]).unwrap();
+        let file = writer.into_inner();
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        assert_eq!(reader.format(), format);
+        let first = reader.next_record().unwrap().unwrap();
+        assert_eq!((first.time, &first.data[..], first.len), (time, &[1, 2, 3][..], 3));
+        let second = reader.next_record().unwrap().unwrap();
+        assert_eq!((&second.data[..], second.len), (&[4, 5, 6, 7][..], 5));
+        if format == Format::Pcapng {
+            assert_eq!(first.direction, Some(Direction::Inbound));
+            assert_eq!(second.direction, Some(Direction::Outbound));
+        } else {
+            assert_eq!(second.direction, None);
+        }
+        assert_eq!(reader.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn read_back() {
+        round_trip(Format::Pcap);
+        round_trip(Format::Pcapng);
+    }
+
+    #[test]
+    fn read_big_endian_nanoseconds() {
+        let mut file = vec![0xa1, 0xb2, 0x3c, 0x4d, 0, 2, 0, 4];
+        file.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 1]);
+        file.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 1, 9]);
+        {
+            let mut reader = PcapReader::new(&file[..]).unwrap();
+            let record = reader.next_record().unwrap().unwrap();
+            assert_eq!(record.time, UNIX_EPOCH + Duration::new(2, 7));
+            assert_eq!(record.data, vec![9]);
+        }
+        // A truncated record is an error, not the end of the file
+        file.extend_from_slice(&[0, 0, 0, 2]);
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        reader.next_record().unwrap();
+        assert!(reader.next_record().is_err());
+        assert!(PcapReader::new(&[1, 2, 3, 4, 5][..]).is_err());
+    }
+
+    #[test]
+    fn pcapng_blocks() {
+        let mut writer = PcapWriter::new(Vec::new(), Format::Pcapng, 100, "eth0").unwrap();
+        let header_len = writer.written() as usize;
+        let time = UNIX_EPOCH + Duration::new(1, 0);
+        writer.write(Direction::Outbound, time, &[9; 5]).unwrap();
+        let out = writer.into_inner();
+        // The section header, then the interface with its name
+        assert_eq!(&out[..4], &[0x0a, 0x0d, 0x0d, 0x0a]);
+        assert_eq!(&out[8..12], &[0x4d, 0x3c, 0x2b, 0x1a]);
+        assert_eq!(&out[28..32], &[1, 0, 0, 0]);
+        assert_eq!(&out[44..52], &[2, 0, 4, 0, b'e', b't', b'h', b'0']);
+        assert_eq!(header_len, 28 + 32);
+
+        let block = &out[header_len..];
+        assert_eq!(&block[..4], &[6, 0, 0, 0]);
+        let len = block[4] as usize;
+        assert_eq!(len, block.len());
+        assert_eq!(&block[len - 4..], &block[4..8]);
+        // One second in microseconds, in the low half of the timestamp
+        assert_eq!(&block[16..20], &[0x40, 0x42, 0x0f, 0]);
+        assert_eq!(&block[28..36], &[9, 9, 9, 9, 9, 0, 0, 0]);
+        // The flags say it was sent
+        assert_eq!(&block[36..44], &[2, 0, 4, 0, 2, 0, 0, 0]);
+    }
+}
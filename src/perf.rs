@@ -0,0 +1,334 @@
+//! Measuring how fast the stack sends and receives, like iperf does.
+//!
+//! A `Generator` sends numbered UDP datagrams, as fast as it can or at a
+//! `RateLimit`, and reports how many it got out per second. A `Sink`
+//! listening on the destination counts what arrives and, from the numbers,
+//! how much was lost or reordered on the way. The two can run on stacks
+//! connected by a `testing::veth_pair`, on the same stack over loopback, or
+//! the generator alone against an external host discarding the datagrams.
+//!
+//! ```rust,ignore
+//! let sink = Sink::new();
+//! stack_b.lock().unwrap().udp_listen("10.0.0.2:5001", sink.clone())?;
+//!
+//! let config = GeneratorConfig { duration: Duration::from_secs(5), ..Default::default() };
+//! let sender = UdpSender::new(stack_a, 5000);
+//! let mut generator = Generator::new(sender, "10.0.0.2:5001".parse().unwrap(), config);
+//! let sent = generator.run()?;
+//! println!("{:.0} pps sent, {:.0} pps received, {} lost",
+//!          sent.pps(), sink.report().pps(), sink.report().lost());
+//! ```
+//!
+//! Every datagram starts with `HEADER_LEN` bytes: `MAGIC` followed by its
+//! number as a big endian `u64`. TCP is not measured yet, the stack has
+//! none.
+
+use {RxResult, StackError, StackResult, TxSent};
+use shaping::{RateLimit, TokenBucket};
+use stack::DEFAULT_MTU;
+use udp::{UdpListener, UdpSender};
+
+use pnet::packet::Packet;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The bytes every datagram of a `Generator` starts with.
+pub const MAGIC: &'static [u8] = b"rips";
+
+/// Length of the header of the datagrams, `MAGIC` and the number.
+pub const HEADER_LEN: usize = 4 + 8;
+
+/// Returns `count` per second over `elapsed`, zero if no time passed.
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    if secs == 0.0 {
+        0.0
+    } else {
+        count as f64 / secs
+    }
+}
+
+/// What a `Generator` sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeneratorConfig {
+    /// Bytes of UDP payload in every datagram, at least `HEADER_LEN`
+    pub payload_len: usize,
+    /// The rate to send at, counted in bytes of IPv4 packets like
+    /// `UdpSocket::set_rate_limit` does. As fast as possible if `None`
+    pub rate: Option<RateLimit>,
+    /// How long to send for
+    pub duration: Duration,
+    /// Datagrams to send at most, without limit if `None`
+    pub count: Option<u64>,
+}
+
+impl Default for GeneratorConfig {
+    /// Datagrams filling the default MTU, as fast as possible for ten
+    /// seconds.
+    fn default() -> GeneratorConfig {
+        GeneratorConfig {
+            payload_len: DEFAULT_MTU - 20 - 8,
+            rate: None,
+            duration: Duration::from_secs(10),
+            count: None,
+        }
+    }
+}
+
+/// What a `Generator::run` sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GeneratorReport {
+    /// Datagrams sent
+    pub datagrams: u64,
+    /// Bytes of UDP payload sent
+    pub bytes: u64,
+    /// The Ethernet frames, and their bytes, handed to the datalink
+    pub sent: TxSent,
+    /// Sends retried since the interface had no room, see
+    /// `TxError::is_retryable`
+    pub retries: u64,
+    pub elapsed: Duration,
+}
+
+impl GeneratorReport {
+    /// Datagrams sent per second.
+    pub fn pps(&self) -> f64 {
+        per_second(self.datagrams, self.elapsed)
+    }
+
+    /// Bits of UDP payload sent per second.
+    pub fn bps(&self) -> f64 {
+        per_second(self.bytes * 8, self.elapsed)
+    }
+}
+
+/// Sends numbered datagrams to one destination. See the module
+/// documentation.
+pub struct Generator {
+    sender: UdpSender,
+    dst: SocketAddrV4,
+    config: GeneratorConfig,
+    /// The number of the next datagram, runs continue where the last one
+    /// stopped
+    next: u64,
+}
+
+impl Generator {
+    /// Creates a generator sending to `dst` with `sender`, which also decides
+    /// the source port and VRF.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `payload_len` of `config` is shorter than `HEADER_LEN`.
+    pub fn new(sender: UdpSender, dst: SocketAddrV4, config: GeneratorConfig) -> Generator {
+        assert!(config.payload_len >= HEADER_LEN,
+                "Payloads must fit the header of {} bytes",
+                HEADER_LEN);
+        Generator {
+            sender: sender,
+            dst: dst,
+            config: config,
+            next: 0,
+        }
+    }
+
+    pub fn config(&self) -> GeneratorConfig {
+        self.config
+    }
+
+    /// Sends datagrams until the `duration` of the config passed or `count`
+    /// of them were sent. Sends failing with an error that is retryable are
+    /// retried right away, any other error ends the run.
+    pub fn run(&mut self) -> StackResult<GeneratorReport> {
+        let mut payload = vec![0; self.config.payload_len];
+        payload[..MAGIC.len()].copy_from_slice(MAGIC);
+        let mut bucket = self.config.rate.map(TokenBucket::new);
+        let mut report = GeneratorReport::default();
+        let start = Instant::now();
+        while self.config.count.map_or(true, |count| report.datagrams < count) &&
+              start.elapsed() < self.config.duration {
+            if let Some(ref mut bucket) = bucket {
+                let wait = bucket.take(20 + 8 + payload.len());
+                if wait > Duration::new(0, 0) {
+                    thread::sleep(wait);
+                }
+            }
+            write_number(&mut payload[MAGIC.len()..HEADER_LEN], self.next);
+            loop {
+                match self.sender.send_to(&payload, self.dst) {
+                    Ok(sent) => {
+                        report.sent += sent;
+                        break;
+                    }
+                    Err(StackError::TxError(ref e)) if e.is_retryable() => {
+                        report.retries += 1;
+                        thread::yield_now();
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            self.next += 1;
+            report.datagrams += 1;
+            report.bytes += payload.len() as u64;
+        }
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+}
+
+fn write_number(buffer: &mut [u8], number: u64) {
+    for (i, byte) in buffer.iter_mut().enumerate() {
+        *byte = (number >> (8 * (7 - i))) as u8;
+    }
+}
+
+fn read_number(buffer: &[u8]) -> u64 {
+    buffer.iter().fold(0, |number, &byte| number << 8 | byte as u64)
+}
+
+/// What a `Sink` received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SinkReport {
+    /// Datagrams of a `Generator` received
+    pub datagrams: u64,
+    /// Bytes of UDP payload of them
+    pub bytes: u64,
+    /// One more than the highest number received, the datagrams sent as far
+    /// as the sink can tell
+    pub expected: u64,
+    /// Datagrams with a lower number than one received before them
+    pub reordered: u64,
+    /// Datagrams without the header of a `Generator`, not counted otherwise
+    pub foreign: u64,
+    /// From the first to the last datagram received
+    pub elapsed: Duration,
+}
+
+impl SinkReport {
+    /// Returns the number of datagrams that were expected but not received.
+    /// Duplicates make up for lost datagrams, as they are not told apart.
+    pub fn lost(&self) -> u64 {
+        self.expected.saturating_sub(self.datagrams)
+    }
+
+    /// Returns the share of datagrams lost, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        if self.expected == 0 {
+            0.0
+        } else {
+            self.lost() as f64 / self.expected as f64
+        }
+    }
+
+    /// Datagrams received per second.
+    pub fn pps(&self) -> f64 {
+        per_second(self.datagrams, self.elapsed)
+    }
+
+    /// Bits of UDP payload received per second.
+    pub fn bps(&self) -> f64 {
+        per_second(self.bytes * 8, self.elapsed)
+    }
+}
+
+#[derive(Default)]
+struct SinkState {
+    report: SinkReport,
+    first: Option<SystemTime>,
+}
+
+/// A `UdpListener` counting the datagrams of a `Generator`. Clones share the
+/// counters, so keep one to read the report from after handing another to
+/// `NetworkStack::udp_listen`.
+#[derive(Clone, Default)]
+pub struct Sink {
+    state: Arc<Mutex<SinkState>>,
+}
+
+impl Sink {
+    pub fn new() -> Sink {
+        Sink::default()
+    }
+
+    pub fn report(&self) -> SinkReport {
+        self.state.lock().unwrap().report
+    }
+
+    /// Forgets everything received so far, for measuring another run.
+    pub fn reset(&self) {
+        *self.state.lock().unwrap() = SinkState::default();
+    }
+
+    fn count(&self, time: SystemTime, payload: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if payload.len() < HEADER_LEN || &payload[..MAGIC.len()] != MAGIC {
+            state.report.foreign += 1;
+            return;
+        }
+        let number = read_number(&payload[MAGIC.len()..HEADER_LEN]);
+        let first = state.first.unwrap_or(time);
+        state.first = Some(first);
+        let report = &mut state.report;
+        report.datagrams += 1;
+        report.bytes += payload.len() as u64;
+        if number < report.expected {
+            report.reordered += 1;
+        } else {
+            report.expected = number + 1;
+        }
+        report.elapsed = time.duration_since(first).unwrap_or(Duration::new(0, 0));
+    }
+}
+
+impl UdpListener for Sink {
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+        let udp_pkg = UdpPacket::new(packet.payload()).unwrap();
+        self.count(time, udp_pkg.payload());
+        (Ok(()), true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    fn datagram(number: u64) -> Vec<u8> {
+        let mut payload = vec![0; 20];
+        payload[..4].copy_from_slice(MAGIC);
+        write_number(&mut payload[4..12], number);
+        payload
+    }
+
+    #[test]
+    fn numbers() {
+        let mut buffer = [0; 8];
+        write_number(&mut buffer, 0x0102030405060708);
+        assert_eq!(buffer, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(read_number(&buffer), 0x0102030405060708);
+    }
+
+    #[test]
+    fn sink_counts_loss() {
+        let sink = Sink::new();
+        let start = SystemTime::now();
+        for (i, &number) in [0, 1, 3, 2, 6].iter().enumerate() {
+            sink.count(start + Duration::from_millis(100 * i as u64), &datagram(number));
+        }
+        sink.count(start, &[1, 2, 3]);
+        let report = sink.report();
+        assert_eq!((report.datagrams, report.bytes), (5, 100));
+        assert_eq!((report.expected, report.lost(), report.reordered), (7, 2, 1));
+        assert_eq!(report.foreign, 1);
+        assert_eq!(report.elapsed, Duration::from_millis(400));
+        assert_eq!(report.pps(), 12.5);
+        sink.reset();
+        assert_eq!(sink.report(), SinkReport::default());
+    }
+}
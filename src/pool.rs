@@ -0,0 +1,235 @@
+//! Reusing frame buffers instead of allocating them for every frame.
+//!
+//! Where the stack has to hold on to a frame, such as in egress queues or
+//! while a polled interface waits for `poll`, or has to build one in memory
+//! of its own, it takes a buffer from the `BufferPool` of the stack rather
+//! than allocating one. Buffers come in a few size classes, see
+//! `SIZE_CLASSES`, and go back to the pool when the `PooledBuffer` holding
+//! them is dropped. Every class keeps a bounded number of free buffers, so
+//! a burst does not pin its memory forever.
+//!
+//! ```rust,ignore
+//! let stats = stack.buffer_pool().stats();
+//! println!("{:.1}% of buffers reused", 100.0 * stats.hit_rate());
+//! ```
+//!
+//! Requests larger than the largest class are allocated, counted as misses
+//! and freed again when dropped.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The sizes of the buffers a pool hands out. Requests get a buffer of the
+/// smallest class they fit in. The largest fits a jumbo frame with a VLAN
+/// tag.
+pub static SIZE_CLASSES: [usize; 4] = [128, 512, 2048, 9216];
+
+/// Free buffers each size class keeps by default.
+pub static DEFAULT_MAX_FREE: usize = 256;
+
+/// Counters of a `BufferPool`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers handed out that were reused from the pool
+    pub hits: usize,
+    /// Buffers handed out that had to be allocated
+    pub misses: usize,
+    /// Buffers that went back to the pool
+    pub returned: usize,
+    /// Buffers freed because their class already kept enough
+    pub discarded: usize,
+}
+
+impl PoolStats {
+    /// Returns the share of buffers handed out that were reused, from 0 to 1.
+    /// Zero if none were handed out yet.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+struct Inner {
+    /// Free buffers, one list per entry of `SIZE_CLASSES`
+    classes: Vec<Mutex<Vec<Vec<u8>>>>,
+    max_free: usize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    returned: AtomicUsize,
+    discarded: AtomicUsize,
+}
+
+impl Inner {
+    fn put_back(&self, mut buffer: Vec<u8>) {
+        let class = match SIZE_CLASSES.iter().position(|&size| size == buffer.capacity()) {
+            Some(class) => class,
+            None => return,
+        };
+        let mut free = self.classes[class].lock().unwrap();
+        if free.len() < self.max_free {
+            buffer.clear();
+            free.push(buffer);
+            self.returned.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A pool of frame buffers. Clones share the buffers and counters. See the
+/// module documentation.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new(DEFAULT_MAX_FREE)
+    }
+}
+
+impl BufferPool {
+    /// Creates a pool keeping at most `max_free` free buffers per size class.
+    pub fn new(max_free: usize) -> BufferPool {
+        BufferPool {
+            inner: Arc::new(Inner {
+                classes: SIZE_CLASSES.iter().map(|_| Mutex::new(Vec::new())).collect(),
+                max_free: max_free,
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+                returned: AtomicUsize::new(0),
+                discarded: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns a zeroed buffer of `len` bytes.
+    pub fn get(&self, len: usize) -> PooledBuffer {
+        let class = SIZE_CLASSES.iter().position(|&size| size >= len);
+        let reused = class.and_then(|class| self.inner.classes[class].lock().unwrap().pop());
+        let mut buffer = match reused {
+            Some(buffer) => {
+                self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.inner.misses.fetch_add(1, Ordering::Relaxed);
+                // Exactly the size of the class, so it finds its way back
+                Vec::with_capacity(class.map_or(len, |class| SIZE_CLASSES[class]))
+            }
+        };
+        buffer.resize(len, 0);
+        PooledBuffer {
+            buffer: buffer,
+            pool: if class.is_some() {
+                Some(self.inner.clone())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns a buffer holding a copy of `data`.
+    pub fn copy_of(&self, data: &[u8]) -> PooledBuffer {
+        let mut buffer = self.get(data.len());
+        buffer.copy_from_slice(data);
+        buffer
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            returned: self.inner.returned.load(Ordering::Relaxed),
+            discarded: self.inner.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of free buffers kept in each size class.
+    pub fn free(&self) -> Vec<usize> {
+        self.inner.classes.iter().map(|free| free.lock().unwrap().len()).collect()
+    }
+}
+
+/// A buffer from a `BufferPool`, going back to it when dropped.
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    /// The pool to return to, if the buffer is of one of its classes
+    pool: Option<Arc<Inner>>,
+}
+
+impl PooledBuffer {
+    /// Shortens the buffer to `len` bytes, keeping its capacity.
+    pub fn truncate(&mut self, len: usize) {
+        self.buffer.truncate(len);
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put_back(::std::mem::replace(&mut self.buffer, Vec::new()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::default();
+        {
+            let mut buffer = pool.get(100);
+            assert_eq!(buffer.len(), 100);
+            buffer[0] = 1;
+        }
+        assert_eq!(pool.free(), vec![1, 0, 0, 0]);
+        let buffer = pool.get(60);
+        // Handed out zeroed, even though it was written before
+        assert!(buffer.iter().all(|&byte| byte == 0));
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.returned), (1, 1, 1));
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn size_classes() {
+        let pool = BufferPool::default();
+        drop(pool.get(129));
+        drop(pool.get(1514));
+        drop(pool.get(10_000));
+        assert_eq!(pool.free(), vec![0, 1, 1, 0]);
+        assert_eq!(pool.stats().misses, 3);
+    }
+
+    #[test]
+    fn bounded() {
+        let pool = BufferPool::new(1);
+        let buffers = vec![pool.get(10), pool.get(10)];
+        drop(buffers);
+        assert_eq!(pool.free(), vec![1, 0, 0, 0]);
+        assert_eq!(pool.stats().discarded, 1);
+    }
+}
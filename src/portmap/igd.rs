@@ -0,0 +1,540 @@
+//! Port mappings on UPnP Internet gateway devices, with the calls of their
+//! `WANIPConnection` and `WANPPPConnection` services.
+//!
+//! The calls are SOAP over HTTP, and so over TCP, which rips does not have
+//! yet. This module writes the requests and reads the responses, and leaves
+//! the connection to the caller: any `Read + Write` stream to the address of
+//! the URL does, such as a `TcpStream` of the host's own stack.
+//!
+//! ```rust,ignore
+//! let wait = Duration::from_secs(2);
+//! let found = ssdp::search(stack, local_ip, ssdp::IGD_SEARCH_TARGET, wait)?;
+//! let location = &found[0].location;
+//! let mut stream = TcpStream::connect(igd::url_addr(location)?)?;
+//! let gateway = Gateway::from_description(location, &igd::fetch(&mut stream, location)?)?;
+//! let mut stream = TcpStream::connect(gateway.control_addr()?)?;
+//! let internal = SocketAddrV4::new(local_ip, 4000);
+//! gateway.add_port_mapping(&mut stream, Protocol::Udp, 4000, internal, lifetime, "rips")?;
+//! ```
+//!
+//! Requests ask the gateway to close the connection after answering, so
+//! every call takes a stream of its own.
+
+use {StackError, StackResult};
+
+use super::Protocol;
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str;
+use std::time::Duration;
+
+/// The service types of the port mapping services, without their versions.
+const SERVICE_TYPES: [&'static str; 2] = ["urn:schemas-upnp-org:service:WANIPConnection:",
+                                          "urn:schemas-upnp-org:service:WANPPPConnection:"];
+
+const HTTP_OK: u16 = 200;
+
+/// The port mapping service of a gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    /// The absolute URL the calls are posted to
+    pub control_url: String,
+    /// The service type, such as `urn:schemas-upnp-org:service:WANIPConnection:1`
+    pub service_type: String,
+}
+
+impl Gateway {
+    /// Reads the first port mapping service out of the device description
+    /// fetched from `location`, resolving its control URL against the base
+    /// URL of the description, or `location` if it has none.
+    ///
+    /// Fails with `StackError::NotFound` if the description has no such
+    /// service.
+    pub fn from_description(location: &str, description: &str) -> StackResult<Gateway> {
+        let base = element(description, "URLBase").map_or(location.to_owned(), unescape);
+        for service in elements(description, "service") {
+            let service_type = match element(service, "serviceType") {
+                Some(service_type) => unescape(service_type),
+                None => continue,
+            };
+            if !SERVICE_TYPES.iter().any(|prefix| service_type.starts_with(prefix)) {
+                continue;
+            }
+            if let Some(control_url) = element(service, "controlURL") {
+                return Ok(Gateway {
+                    control_url: resolve(&base, &unescape(control_url)),
+                    service_type: service_type,
+                });
+            }
+        }
+        let msg = format!("No port mapping service in the description of {}", location);
+        Err(StackError::NotFound(msg))
+    }
+
+    /// Returns the address the calls are sent to.
+    pub fn control_addr(&self) -> StackResult<SocketAddrV4> {
+        url_addr(&self.control_url)
+    }
+
+    /// Asks the gateway for its external address.
+    pub fn external_ip_address<S: Read + Write>(&self, stream: &mut S) -> StackResult<Ipv4Addr> {
+        let response = self.call(stream, "GetExternalIPAddress", &[])?;
+        match element(&response, "NewExternalIPAddress").and_then(|ip| ip.trim().parse().ok()) {
+            Some(ip) => Ok(ip),
+            None => {
+                let msg = format!("No external address in the answer of {}", self.control_url);
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg).into())
+            }
+        }
+    }
+
+    /// Asks the gateway to forward `external_port` to `internal` for
+    /// `lifetime`, or for good if it is zero, which the gateways of IGD:1
+    /// often insist on.
+    ///
+    /// A gateway refusing fails the call with an IO error of the kind
+    /// matching its reason, or with `StackError::AlreadyExists` if the port
+    /// is mapped to someone else.
+    pub fn add_port_mapping<S: Read + Write>(&self,
+                                             stream: &mut S,
+                                             protocol: Protocol,
+                                             external_port: u16,
+                                             internal: SocketAddrV4,
+                                             lifetime: Duration,
+                                             description: &str)
+                                             -> StackResult<()> {
+        let arguments = [("NewRemoteHost", String::new()),
+                         ("NewExternalPort", external_port.to_string()),
+                         ("NewProtocol", protocol.to_string()),
+                         ("NewInternalPort", internal.port().to_string()),
+                         ("NewInternalClient", internal.ip().to_string()),
+                         ("NewEnabled", "1".to_owned()),
+                         ("NewPortMappingDescription", description.to_owned()),
+                         ("NewLeaseDuration", lifetime.as_secs().to_string())];
+        self.call(stream, "AddPortMapping", &arguments).map(|_| ())
+    }
+
+    /// Asks the gateway to remove the mapping of `external_port`. Fails with
+    /// `StackError::NotFound` if there is none.
+    pub fn delete_port_mapping<S: Read + Write>(&self,
+                                                stream: &mut S,
+                                                protocol: Protocol,
+                                                external_port: u16)
+                                                -> StackResult<()> {
+        let arguments = [("NewRemoteHost", String::new()),
+                         ("NewExternalPort", external_port.to_string()),
+                         ("NewProtocol", protocol.to_string())];
+        self.call(stream, "DeletePortMapping", &arguments).map(|_| ())
+    }
+
+    /// Posts `action` with `arguments` to the control URL and returns the
+    /// body of the answer.
+    fn call<S: Read + Write>(&self,
+                             stream: &mut S,
+                             action: &str,
+                             arguments: &[(&str, String)])
+                             -> StackResult<String> {
+        let (addr, path) = split_url(&self.control_url)?;
+        let mut body = format!(concat!("<?xml version=\"1.0\"?>",
+                                       "<s:Envelope xmlns:s=\"{}\" s:encodingStyle=\"{}\">",
+                                       "<s:Body><u:{} xmlns:u=\"{}\">"),
+                               "http://schemas.xmlsoap.org/soap/envelope/",
+                               "http://schemas.xmlsoap.org/soap/encoding/",
+                               action,
+                               self.service_type);
+        for &(name, ref value) in arguments {
+            body.push_str(&format!("<{0}>{1}</{0}>", name, escape(value)));
+        }
+        body.push_str(&format!("</u:{}></s:Body></s:Envelope>", action));
+        let request = format!(concat!("POST {} HTTP/1.1\r\n",
+                                      "Host: {}\r\n",
+                                      "Content-Type: text/xml; charset=\"utf-8\"\r\n",
+                                      "SOAPAction: \"{}#{}\"\r\n",
+                                      "Content-Length: {}\r\n",
+                                      "Connection: close\r\n\r\n",
+                                      "{}"),
+                              path,
+                              addr,
+                              self.service_type,
+                              action,
+                              body.len(),
+                              body);
+        let (status, response) = exchange(stream, request.as_bytes())?;
+        if status == HTTP_OK {
+            Ok(response)
+        } else {
+            Err(fault(action, status, &response))
+        }
+    }
+}
+
+/// Returns the address of an `http` URL, whose host must be an IPv4
+/// address.
+pub fn url_addr(url: &str) -> StackResult<SocketAddrV4> {
+    split_url(url).map(|(addr, _)| addr)
+}
+
+/// Gets the document at `url`, such as a device description, over
+/// `stream`.
+pub fn fetch<S: Read + Write>(stream: &mut S, url: &str) -> StackResult<String> {
+    let (addr, path) = split_url(url)?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                          path,
+                          addr);
+    match exchange(stream, request.as_bytes())? {
+        (HTTP_OK, body) => Ok(body),
+        (status, _) => {
+            let msg = format!("Getting {} failed with HTTP status {}", url, status);
+            Err(io::Error::new(io::ErrorKind::Other, msg).into())
+        }
+    }
+}
+
+/// Sends `request` and reads the answer until the stream closes, returning
+/// its status and body.
+fn exchange<S: Read + Write>(stream: &mut S, request: &[u8]) -> StackResult<(u16, String)> {
+    stream.write_all(request)?;
+    stream.flush()?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    match parse_response(&response) {
+        Some(answer) => Ok(answer),
+        None => {
+            let msg = "Invalid HTTP response".to_owned();
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg).into())
+        }
+    }
+}
+
+fn parse_response(response: &[u8]) -> Option<(u16, String)> {
+    let end = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return None,
+    };
+    let head = match str::from_utf8(&response[..end]) {
+        Ok(head) => head,
+        Err(_) => return None,
+    };
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    if !status_line.starts_with("HTTP/1.") {
+        return None;
+    }
+    let status = match status_line.split(' ').nth(1).and_then(|status| status.parse().ok()) {
+        Some(status) => status,
+        None => return None,
+    };
+    let mut body = response[end + 4..].to_vec();
+    for line in lines {
+        let colon = match line.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        let value = line[colon + 1..].trim();
+        match &line[..colon].trim().to_lowercase()[..] {
+            "transfer-encoding" if value.to_lowercase().contains("chunked") => {
+                body = match dechunk(&body) {
+                    Some(body) => body,
+                    None => return None,
+                };
+            }
+            "content-length" => {
+                if let Ok(len) = value.parse::<usize>() {
+                    if len < body.len() {
+                        body.truncate(len);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Some((status, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// Joins the chunks of a body sent with the chunked transfer encoding.
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut joined = Vec::new();
+    loop {
+        let line_end = match body.windows(2).position(|window| window == b"\r\n") {
+            Some(line_end) => line_end,
+            None => return None,
+        };
+        let size = str::from_utf8(&body[..line_end]).ok().and_then(|line| {
+            let size = line.split(';').next().unwrap().trim();
+            usize::from_str_radix(size, 16).ok()
+        });
+        let size = match size {
+            Some(size) => size,
+            None => return None,
+        };
+        if size == 0 {
+            return Some(joined);
+        }
+        let start = line_end + 2;
+        if body.len() < start + size {
+            return None;
+        }
+        joined.extend_from_slice(&body[start..start + size]);
+        body = &body[cmp::min(body.len(), start + size + 2)..];
+    }
+}
+
+/// Returns the error of a SOAP fault, or of an HTTP error without one.
+fn fault(action: &str, status: u16, body: &str) -> StackError {
+    let code = match element(body, "errorCode").and_then(|code| code.trim().parse().ok()) {
+        Some(code) => code,
+        None => {
+            let msg = format!("{} failed with HTTP status {}", action, status);
+            return StackError::IoError(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let description = element(body, "errorDescription").map_or(String::new(), unescape);
+    let msg = format!("{} failed: {} (UPnP error {})", action, description, code);
+    let kind = match code {
+        714 => return StackError::NotFound(msg),
+        718 => return StackError::AlreadyExists(msg),
+        606 => io::ErrorKind::PermissionDenied,
+        402 | 716 | 724 | 725 | 726 | 727 => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    StackError::IoError(io::Error::new(kind, msg))
+}
+
+/// Splits an `http` URL into the address of its host and its path.
+fn split_url(url: &str) -> StackResult<(SocketAddrV4, String)> {
+    let invalid = || StackError::InvalidArgument(format!("Invalid URL: {}", url));
+    if url.len() < 7 || url[..7].to_lowercase() != "http://" {
+        return Err(invalid());
+    }
+    let rest = &url[7..];
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) => {
+            let port = authority[colon + 1..].parse().map_err(|_| invalid())?;
+            (&authority[..colon], port)
+        }
+        None => (authority, 80),
+    };
+    let ip = host.parse().map_err(|_| {
+            let msg = format!("The host of {} is not an IPv4 address", url);
+            StackError::InvalidArgument(msg)
+        })?;
+    Ok((SocketAddrV4::new(ip, port), path.to_owned()))
+}
+
+/// Resolves `url` against the absolute `base`.
+fn resolve(base: &str, url: &str) -> String {
+    if url.to_lowercase().starts_with("http://") {
+        return url.to_owned();
+    }
+    let authority_start = base.find("://").map_or(0, |scheme_end| scheme_end + 3);
+    let authority_end = base[authority_start..]
+        .find('/')
+        .map_or(base.len(), |slash| authority_start + slash);
+    if url.starts_with('/') {
+        format!("{}{}", &base[..authority_end], url)
+    } else {
+        let dir_end = base[authority_end..]
+            .rfind('/')
+            .map_or(base.len(), |slash| authority_end + slash);
+        format!("{}/{}", &base[..dir_end], url)
+    }
+}
+
+/// Returns the contents of the first element called `name`.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    elements(xml, name).into_iter().next()
+}
+
+/// Returns the contents of the elements called `name`, as long as they do
+/// not nest.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open[..]) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close[..]) {
+            Some(end) => {
+                found.push(&rest[..end]);
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn unescape(text: &str) -> String {
+    text.trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use portmap::Protocol;
+    use StackError;
+
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    /// A stream answering with `response` and keeping what is written.
+    struct Stream {
+        response: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Stream {
+        fn new(response: &str) -> Stream {
+            Stream {
+                response: Cursor::new(response.as_bytes().to_vec()),
+                written: Vec::new(),
+            }
+        }
+
+        fn request(&self) -> String {
+            String::from_utf8(self.written.clone()).unwrap()
+        }
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn gateway() -> Gateway {
+        Gateway {
+            control_url: "http://192.168.1.1:5000/ctl/IPConn".to_owned(),
+            service_type: "urn:schemas-upnp-org:service:WANIPConnection:1".to_owned(),
+        }
+    }
+
+    #[test]
+    fn descriptions() {
+        let description = concat!("<root><device><serviceList><service>",
+                                  "<serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1",
+                                  "</serviceType><controlURL>/ctl/L3F</controlURL></service>",
+                                  "</serviceList><deviceList><device><serviceList><service>",
+                                  "<serviceType>urn:schemas-upnp-org:service:WANIPConnection:2",
+                                  "</serviceType><controlURL>ctl?a=1&amp;b=2</controlURL>",
+                                  "</service></serviceList></device></deviceList></device></root>");
+        let location = "http://192.168.1.1:5000/desc/root.xml";
+        let gateway = Gateway::from_description(location, description).unwrap();
+        assert_eq!(&gateway.control_url[..], "http://192.168.1.1:5000/desc/ctl?a=1&b=2");
+        assert_eq!(&gateway.service_type[..], "urn:schemas-upnp-org:service:WANIPConnection:2");
+        assert_eq!(gateway.control_addr().unwrap(),
+                   SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 5000));
+
+        let based = description.replace("<root>",
+                                         "<root><URLBase>http://192.168.1.1:80</URLBase>");
+        let gateway = Gateway::from_description(location, &based).unwrap();
+        assert_eq!(&gateway.control_url[..], "http://192.168.1.1:80/ctl?a=1&b=2");
+        assert_eq!(resolve("http://10.0.0.1", "/x"), "http://10.0.0.1/x");
+
+        match Gateway::from_description(location, "<root></root>") {
+            Err(StackError::NotFound(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(url_addr("http://router.lan/").is_err());
+        assert!(url_addr("https://192.168.1.1/").is_err());
+        assert_eq!(url_addr("http://192.168.1.1").unwrap().port(), 80);
+    }
+
+    #[test]
+    fn calls() {
+        let mut stream = Stream::new(concat!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n",
+                                             "\r\n10\r\n<NewExternalIPAd\r\n",
+                                             "20\r\ndress>203.0.113.7</NewExternalIP\r\n",
+                                             "8\r\nAddress>\r\n0\r\n\r\n"));
+        let ip = gateway().external_ip_address(&mut stream).unwrap();
+        assert_eq!(ip, Ipv4Addr::new(203, 0, 113, 7));
+        let request = stream.request();
+        assert!(request.starts_with("POST /ctl/IPConn HTTP/1.1\r\nHost: 192.168.1.1:5000\r\n"));
+        assert!(request.contains(concat!("SOAPAction: \"urn:schemas-upnp-org:service:",
+                                         "WANIPConnection:1#GetExternalIPAddress\"")));
+
+        let mut stream = Stream::new("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let internal = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 20), 4000);
+        gateway()
+            .add_port_mapping(&mut stream,
+                              Protocol::Udp,
+                              4001,
+                              internal,
+                              Duration::from_secs(3600),
+                              "a <b>")
+            .unwrap();
+        let request = stream.request();
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        assert!(request.contains(&format!("Content-Length: {}\r\n", body.len())));
+        for argument in &["<NewExternalPort>4001</NewExternalPort>",
+                          "<NewProtocol>UDP</NewProtocol>",
+                          "<NewInternalPort>4000</NewInternalPort>",
+                          "<NewInternalClient>192.168.1.20</NewInternalClient>",
+                          "<NewPortMappingDescription>a &lt;b&gt;</NewPortMappingDescription>",
+                          "<NewLeaseDuration>3600</NewLeaseDuration>"] {
+            assert!(body.contains(argument), "No {} in {}", argument, body);
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let fault = |code: u16| {
+            let body = format!(concat!("<s:Envelope><s:Body><s:Fault><detail><UPnPError>",
+                                       "<errorCode>{}</errorCode>",
+                                       "<errorDescription>Reason</errorDescription>",
+                                       "</UPnPError></detail></s:Fault></s:Body></s:Envelope>"),
+                               code);
+            let response = format!("HTTP/1.1 500 Internal Server Error\r\n\r\n{}", body);
+            gateway().delete_port_mapping(&mut Stream::new(&response), Protocol::Tcp, 80)
+        };
+        match fault(714) {
+            Err(StackError::NotFound(msg)) => assert!(msg.contains("Reason")),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match fault(718) {
+            Err(StackError::AlreadyExists(_)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match fault(606) {
+            Err(StackError::IoError(e)) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let mut stream = Stream::new("HTTP/1.1 404 Not Found\r\n\r\n");
+        assert!(fetch(&mut stream, "http://192.168.1.1/desc.xml").is_err());
+        assert_eq!(stream.request(),
+                   "GET /desc.xml HTTP/1.1\r\nHost: 192.168.1.1:80\r\nConnection: close\r\n\r\n");
+        assert!(gateway().delete_port_mapping(&mut Stream::new("garbage"), Protocol::Tcp, 80)
+            .is_err());
+    }
+}
@@ -0,0 +1,501 @@
+//! Port mappings requested from home gateways, so that hosts behind a NAT
+//! can be reached from outside it.
+//!
+//! A `PortMapClient` asks the gateway with PCP, RFC 6887, and falls back to
+//! NAT-PMP, RFC 6886, when the gateway answers that it only speaks the older
+//! protocol:
+//!
+//! ```rust,ignore
+//! let gateway = Ipv4Addr::new(192, 168, 1, 1);
+//! let client = PortMapClient::new(stack, local_ip, gateway, PortMapConfig::default())?;
+//! let mapping = client.map(Protocol::Udp, 4000, 0, Duration::from_secs(7200))?;
+//! // Peers outside reach local_ip:4000 on mapping.external
+//! ```
+//!
+//! Requests go to port 5351 of the gateway and are sent again, with the
+//! timeout doubled each time, until it answers. A mapping lasts as long as
+//! the gateway granted, which can be shorter than asked for. Mapping the
+//! same internal port again renews it, and `unmap` removes it early.
+//!
+//! Gateways speaking neither are found with `ssdp::search` and asked with
+//! the UPnP IGD calls of `igd`.
+
+use {NetworkStack, StackError, StackResult};
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::UdpSocket;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub mod igd;
+
+pub mod ssdp;
+
+/// The port NAT-PMP and PCP servers listen on.
+pub const PORT_MAP_PORT: u16 = 5351;
+
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+
+/// Set in the opcode of responses.
+const RESPONSE: u8 = 0x80;
+
+const EXTERNAL_ADDRESS: u8 = 0;
+const PCP_MAP: u8 = 1;
+
+const PCP_HEADER_LEN: usize = 24;
+const PCP_MAP_LEN: usize = 36;
+const NONCE_LEN: usize = 12;
+
+/// The result code of both protocols for a version the server does not speak.
+const UNSUPPORTED_VERSION: u16 = 1;
+
+/// The transport protocol of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl Protocol {
+    fn nat_pmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        }
+    }
+
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Udp => 17,
+            Protocol::Tcp => 6,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Protocol::Udp => fmt.write_str("UDP"),
+            Protocol::Tcp => fmt.write_str("TCP"),
+        }
+    }
+}
+
+/// The protocol a gateway is asked with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Pcp,
+    NatPmp,
+}
+
+/// A port of the gateway forwarded to one of the local address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    /// The external address and port of the gateway that are forwarded
+    pub external: SocketAddrV4,
+    /// How long the mapping lasts unless renewed
+    pub lifetime: Duration,
+    /// The protocol the gateway granted the mapping with
+    pub version: Version,
+}
+
+/// How a `PortMapClient` asks its gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapConfig {
+    /// How long to wait for the first answer. Doubled each time the request
+    /// is sent again.
+    pub initial_timeout: Duration,
+    /// How many times a request is sent before giving up
+    pub attempts: usize,
+    /// The protocol to use, or `None` to try PCP and fall back to NAT-PMP
+    pub version: Option<Version>,
+}
+
+impl Default for PortMapConfig {
+    /// Waits 250 milliseconds for the first answer and sends nine times, as
+    /// RFC 6886 asks, trying PCP first.
+    fn default() -> PortMapConfig {
+        PortMapConfig {
+            initial_timeout: Duration::from_millis(250),
+            attempts: 9,
+            version: None,
+        }
+    }
+}
+
+/// Requests port mappings for a local address from its gateway.
+pub struct PortMapClient {
+    stack: Arc<Mutex<NetworkStack>>,
+    local_ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    config: PortMapConfig,
+    /// The protocol the gateway answered, once known
+    version: Mutex<Option<Version>>,
+    /// The PCP nonces of the mappings, which renewals and removals repeat
+    nonces: Mutex<HashMap<(Protocol, u16), [u8; NONCE_LEN]>>,
+}
+
+impl PortMapClient {
+    /// Creates a client asking `gateway` for mappings to `local_ip`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `config` sends no
+    /// requests or waits no time for them.
+    pub fn new(stack: Arc<Mutex<NetworkStack>>,
+               local_ip: Ipv4Addr,
+               gateway: Ipv4Addr,
+               config: PortMapConfig)
+               -> StackResult<PortMapClient> {
+        if config.attempts == 0 || config.initial_timeout == Duration::from_millis(0) {
+            let msg = format!("Invalid attempts or timeout: {:?}", config);
+            return Err(StackError::InvalidArgument(msg));
+        }
+        Ok(PortMapClient {
+            stack: stack,
+            local_ip: local_ip,
+            gateway: gateway,
+            config: config,
+            version: Mutex::new(None),
+            nonces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.gateway
+    }
+
+    /// Returns the protocol the gateway granted mappings with, if it has
+    /// granted any yet.
+    pub fn version(&self) -> Option<Version> {
+        self.config.version.or(*self.version.lock().unwrap())
+    }
+
+    /// Asks the gateway for its external address, with NAT-PMP. PCP has no
+    /// such request, the mappings it grants carry the address instead.
+    ///
+    /// If the gateway does not answer, fails with an IO error of kind
+    /// `TimedOut`.
+    pub fn external_address(&self) -> StackResult<Ipv4Addr> {
+        let request = [NAT_PMP_VERSION, EXTERNAL_ADDRESS];
+        let reply = self.exchange(&request, |reply| {
+                is_nat_pmp_reply(reply, EXTERNAL_ADDRESS) &&
+                (reply.len() >= 12 || nat_pmp_result(reply) != 0)
+            })?;
+        match nat_pmp_result(reply.as_slice()) {
+            0 => Ok(Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11])),
+            result => Err(nat_pmp_error(self.gateway, result)),
+        }
+    }
+
+    /// Asks the gateway to forward `external_port`, or any port if zero, to
+    /// `internal_port` of the local address for `lifetime`. The gateway can
+    /// pick another external port, and grant a shorter lifetime.
+    ///
+    /// Fails with `StackError::InvalidArgument` for a zero internal port or
+    /// lifetime, with an IO error of kind `TimedOut` if the gateway does not
+    /// answer, and with one of the kind matching the reason if it refuses.
+    pub fn map(&self,
+               protocol: Protocol,
+               internal_port: u16,
+               external_port: u16,
+               lifetime: Duration)
+               -> StackResult<Mapping> {
+        if internal_port == 0 || lifetime.as_secs() == 0 {
+            let msg = format!("Invalid port {} or lifetime {:?}", internal_port, lifetime);
+            return Err(StackError::InvalidArgument(msg));
+        }
+        let seconds = if lifetime.as_secs() > u32::max_value() as u64 {
+            u32::max_value()
+        } else {
+            lifetime.as_secs() as u32
+        };
+        let version = self.version();
+        if version != Some(Version::NatPmp) {
+            match self.pcp_map(protocol, internal_port, external_port, seconds)? {
+                Some(mapping) => {
+                    *self.version.lock().unwrap() = Some(Version::Pcp);
+                    return Ok(mapping);
+                }
+                None if version == Some(Version::Pcp) => {
+                    let msg = format!("{} does not speak PCP", self.gateway);
+                    return Err(io::Error::new(io::ErrorKind::Other, msg).into());
+                }
+                None => debug!("Port mapping: {} does not speak PCP", self.gateway),
+            }
+        }
+        let mapping = self.nat_pmp_map(protocol, internal_port, external_port, seconds)?
+            .ok_or_else(|| {
+                let msg = format!("{} speaks neither PCP nor NAT-PMP", self.gateway);
+                StackError::from(io::Error::new(io::ErrorKind::Other, msg))
+            })?;
+        *self.version.lock().unwrap() = Some(Version::NatPmp);
+        Ok(mapping)
+    }
+
+    /// Asks the gateway to remove `mapping` before it expires.
+    pub fn unmap(&self, mapping: &Mapping) -> StackResult<()> {
+        let removed = match mapping.version {
+            Version::Pcp => self.pcp_map(mapping.protocol, mapping.internal_port, 0, 0)?,
+            Version::NatPmp => self.nat_pmp_map(mapping.protocol, mapping.internal_port, 0, 0)?,
+        };
+        self.nonces.lock().unwrap().remove(&(mapping.protocol, mapping.internal_port));
+        match removed {
+            Some(_) => Ok(()),
+            None => {
+                let msg = format!("{} no longer speaks {:?}", self.gateway, mapping.version);
+                Err(io::Error::new(io::ErrorKind::Other, msg).into())
+            }
+        }
+    }
+
+    /// Sends a PCP MAP request, returning `None` if the gateway does not
+    /// speak PCP.
+    fn pcp_map(&self,
+               protocol: Protocol,
+               internal_port: u16,
+               external_port: u16,
+               lifetime: u32)
+               -> StackResult<Option<Mapping>> {
+        let stack = &self.stack;
+        let nonce = *self.nonces
+            .lock()
+            .unwrap()
+            .entry((protocol, internal_port))
+            .or_insert_with(|| stack.lock().unwrap().random());
+        let request = pcp_request(self.local_ip,
+                                  &nonce,
+                                  protocol,
+                                  internal_port,
+                                  external_port,
+                                  lifetime);
+        let reply = self.exchange(&request, |reply| {
+                reply.len() >= 4 && reply[1] == RESPONSE | PCP_MAP &&
+                (reply[0] == NAT_PMP_VERSION ||
+                 reply[0] == PCP_VERSION && reply.len() >= PCP_HEADER_LEN + PCP_MAP_LEN &&
+                 reply[PCP_HEADER_LEN..PCP_HEADER_LEN + NONCE_LEN] == nonce)
+            })?;
+        if reply[0] == NAT_PMP_VERSION {
+            return if nat_pmp_result(&reply) == UNSUPPORTED_VERSION {
+                Ok(None)
+            } else {
+                Err(nat_pmp_error(self.gateway, nat_pmp_result(&reply)))
+            };
+        }
+        match reply[3] as u16 {
+            0 => (),
+            UNSUPPORTED_VERSION => return Ok(None),
+            result => return Err(pcp_error(self.gateway, result as u8)),
+        }
+        let payload = &reply[PCP_HEADER_LEN..];
+        let external_ip = Ipv4Addr::new(payload[32], payload[33], payload[34], payload[35]);
+        Ok(Some(Mapping {
+            protocol: protocol,
+            internal_port: internal_port,
+            external: SocketAddrV4::new(external_ip, read_u16(payload, 18)),
+            lifetime: Duration::from_secs(read_u32(&reply, 4) as u64),
+            version: Version::Pcp,
+        }))
+    }
+
+    /// Sends a NAT-PMP mapping request, asking for the external address too
+    /// unless it removes the mapping. Returns `None` if the gateway does not
+    /// speak NAT-PMP.
+    fn nat_pmp_map(&self,
+                   protocol: Protocol,
+                   internal_port: u16,
+                   external_port: u16,
+                   lifetime: u32)
+                   -> StackResult<Option<Mapping>> {
+        let opcode = protocol.nat_pmp_opcode();
+        let request = nat_pmp_request(opcode, internal_port, external_port, lifetime);
+        let reply = self.exchange(&request, |reply| {
+                is_nat_pmp_reply(reply, opcode) &&
+                (reply.len() >= 16 && read_u16(reply, 8) == internal_port ||
+                 nat_pmp_result(reply) != 0)
+            })?;
+        match nat_pmp_result(&reply) {
+            0 => (),
+            UNSUPPORTED_VERSION => return Ok(None),
+            result => return Err(nat_pmp_error(self.gateway, result)),
+        }
+        let external_ip = if lifetime == 0 {
+            Ipv4Addr::new(0, 0, 0, 0)
+        } else {
+            self.external_address()?
+        };
+        Ok(Some(Mapping {
+            protocol: protocol,
+            internal_port: internal_port,
+            external: SocketAddrV4::new(external_ip, read_u16(&reply, 10)),
+            lifetime: Duration::from_secs(read_u32(&reply, 12) as u64),
+            version: Version::NatPmp,
+        }))
+    }
+
+    /// Sends `request` to the gateway until it answers, waiting twice as
+    /// long each time, and returns the first reply `accept` takes.
+    fn exchange<F>(&self, request: &[u8], accept: F) -> StackResult<Vec<u8>>
+        where F: Fn(&[u8]) -> bool
+    {
+        let dst = SocketAddrV4::new(self.gateway, PORT_MAP_PORT);
+        let mut socket = UdpSocket::bind(self.stack.clone(), SocketAddrV4::new(self.local_ip, 0))?;
+        let mut timeout = self.config.initial_timeout;
+        let mut buffer = vec![0; 1100];
+        for _ in 0..self.config.attempts {
+            socket.send_to(request, dst)?;
+            let deadline = Instant::now() + timeout;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                socket.set_opt(ReadTimeout, Some(deadline - now))?;
+                let len = match socket.recv_from(&mut buffer) {
+                    Ok((len, src)) if src == SocketAddr::V4(dst) => len,
+                    Ok(_) => continue,
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if accept(&buffer[..len]) {
+                    return Ok(buffer[..len].to_vec());
+                }
+            }
+            timeout = timeout * 2;
+        }
+        let msg = format!("No answer from {}", self.gateway);
+        Err(io::Error::new(io::ErrorKind::TimedOut, msg).into())
+    }
+}
+
+fn nat_pmp_request(opcode: u8, internal_port: u16, external_port: u16, lifetime: u32) -> Vec<u8> {
+    let mut request = vec![NAT_PMP_VERSION, opcode, 0, 0];
+    push_u16(&mut request, internal_port);
+    push_u16(&mut request, external_port);
+    push_u32(&mut request, lifetime);
+    request
+}
+
+fn pcp_request(local_ip: Ipv4Addr,
+               nonce: &[u8; NONCE_LEN],
+               protocol: Protocol,
+               internal_port: u16,
+               external_port: u16,
+               lifetime: u32)
+               -> Vec<u8> {
+    let mut request = vec![PCP_VERSION, PCP_MAP, 0, 0];
+    push_u32(&mut request, lifetime);
+    push_mapped(&mut request, local_ip);
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[protocol.number(), 0, 0, 0]);
+    push_u16(&mut request, internal_port);
+    push_u16(&mut request, external_port);
+    push_mapped(&mut request, Ipv4Addr::new(0, 0, 0, 0));
+    request
+}
+
+fn is_nat_pmp_reply(reply: &[u8], opcode: u8) -> bool {
+    reply.len() >= 4 && reply[0] == NAT_PMP_VERSION && reply[1] == RESPONSE | opcode
+}
+
+fn nat_pmp_result(reply: &[u8]) -> u16 {
+    read_u16(reply, 2)
+}
+
+fn nat_pmp_error(gateway: Ipv4Addr, result: u16) -> StackError {
+    let (kind, reason) = match result {
+        1 => (io::ErrorKind::Other, "unsupported version"),
+        2 => (io::ErrorKind::PermissionDenied, "not authorized"),
+        3 => (io::ErrorKind::Other, "network failure"),
+        4 => (io::ErrorKind::Other, "out of resources"),
+        5 => (io::ErrorKind::InvalidInput, "unsupported opcode"),
+        _ => (io::ErrorKind::Other, "unknown error"),
+    };
+    let msg = format!("{} refused: {} (NAT-PMP result {})", gateway, reason, result);
+    StackError::IoError(io::Error::new(kind, msg))
+}
+
+fn pcp_error(gateway: Ipv4Addr, result: u8) -> StackError {
+    let (kind, reason) = match result {
+        2 => (io::ErrorKind::PermissionDenied, "not authorized"),
+        3 => (io::ErrorKind::InvalidInput, "malformed request"),
+        4 => (io::ErrorKind::InvalidInput, "unsupported opcode"),
+        5 => (io::ErrorKind::InvalidInput, "unsupported option"),
+        6 => (io::ErrorKind::InvalidInput, "malformed option"),
+        7 => (io::ErrorKind::Other, "network failure"),
+        8 => (io::ErrorKind::Other, "out of resources"),
+        9 => (io::ErrorKind::InvalidInput, "unsupported protocol"),
+        10 => (io::ErrorKind::PermissionDenied, "quota exceeded"),
+        11 => (io::ErrorKind::AddrInUse, "cannot provide the external port"),
+        12 => (io::ErrorKind::InvalidInput, "address mismatch"),
+        13 => (io::ErrorKind::Other, "excessive remote peers"),
+        _ => (io::ErrorKind::Other, "unknown error"),
+    };
+    let msg = format!("{} refused: {} (PCP result {})", gateway, reason, result);
+    StackError::IoError(io::Error::new(kind, msg))
+}
+
+/// Writes `ip` as an IPv4-mapped IPv6 address, as PCP carries addresses.
+fn push_mapped(buffer: &mut Vec<u8>, ip: Ipv4Addr) {
+    buffer.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+    buffer.extend_from_slice(&ip.octets());
+}
+
+fn push_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+}
+
+fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+    push_u16(buffer, (value >> 16) as u16);
+    push_u16(buffer, value as u16);
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    (buffer[offset] as u16) << 8 | buffer[offset + 1] as u16
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    (read_u16(buffer, offset) as u32) << 16 | read_u16(buffer, offset + 2) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn requests() {
+        assert_eq!(nat_pmp_request(Protocol::Tcp.nat_pmp_opcode(), 8080, 80, 7200),
+                   vec![0, 2, 0, 0, 0x1f, 0x90, 0, 80, 0, 0, 0x1c, 0x20]);
+
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let request = pcp_request(Ipv4Addr::new(10, 0, 0, 2), &nonce, Protocol::Udp, 4000, 0, 60);
+        assert_eq!(request.len(), PCP_HEADER_LEN + PCP_MAP_LEN);
+        assert_eq!(&request[..8], &[2, 1, 0, 0, 0, 0, 0, 60]);
+        assert_eq!(&request[18..24], &[0xff, 0xff, 10, 0, 0, 2]);
+        assert_eq!(&request[24..36], &nonce);
+        assert_eq!(&request[36..44], &[17, 0, 0, 0, 0x0f, 0xa0, 0, 0]);
+        assert_eq!(&request[54..], &[0xff, 0xff, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn errors() {
+        let gateway = Ipv4Addr::new(10, 0, 0, 1);
+        let kind = |error: StackError| match error {
+            StackError::IoError(e) => e.kind(),
+            e => panic!("Unexpected error: {:?}", e),
+        };
+        assert_eq!(kind(nat_pmp_error(gateway, 2)), io::ErrorKind::PermissionDenied);
+        assert_eq!(kind(nat_pmp_error(gateway, 99)), io::ErrorKind::Other);
+        assert_eq!(kind(pcp_error(gateway, 11)), io::ErrorKind::AddrInUse);
+        assert_eq!(kind(pcp_error(gateway, 9)), io::ErrorKind::InvalidInput);
+        assert!(format!("{}", pcp_error(gateway, 8)).contains("out of resources"));
+    }
+}
@@ -0,0 +1,215 @@
+//! SSDP searches for UPnP devices, from the UPnP Device Architecture.
+//!
+//! `search` multicasts an `M-SEARCH` request to 239.255.255.250:1900 and
+//! collects the answers devices send back to the port it asked from. Each
+//! answer carries the URL of the description of the device, which
+//! `igd::Gateway::from_description` reads the port mapping service of a
+//! gateway from.
+
+use {NetworkStack, StackError, StackResult};
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::{UdpSocket, UdpTx};
+
+use std::cmp;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The port SSDP requests are multicast to.
+pub const SSDP_PORT: u16 = 1900;
+
+/// The search target of Internet gateway devices.
+pub const IGD_SEARCH_TARGET: &'static str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// The search target every device answers.
+pub const ALL_SEARCH_TARGET: &'static str = "ssdp:all";
+
+/// The TTL the UPnP Device Architecture gives multicast requests.
+const TTL: u8 = 2;
+
+/// The longest devices may be asked to wait before answering, in seconds.
+const MAX_WAIT_SECS: u64 = 5;
+
+/// The SSDP multicast group, 239.255.255.250.
+pub fn group() -> Ipv4Addr {
+    Ipv4Addr::new(239, 255, 255, 250)
+}
+
+/// What a device answered a search with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchResponse {
+    /// The URL of the description of the device
+    pub location: String,
+    /// The search target the device answered for
+    pub search_target: String,
+    /// The unique service name of the device
+    pub usn: String,
+    pub server: Option<String>,
+    /// How long the answer is valid
+    pub max_age: Option<Duration>,
+    /// Where the answer came from
+    pub src: SocketAddrV4,
+}
+
+impl SearchResponse {
+    /// Parses an answer to a search, returning `None` unless it is a
+    /// successful HTTP response with a location, search target and USN.
+    pub fn parse(packet: &[u8], src: SocketAddrV4) -> Option<SearchResponse> {
+        let text = match str::from_utf8(packet) {
+            Ok(text) => text,
+            Err(_) => return None,
+        };
+        let mut lines = text.split("\r\n");
+        let status = lines.next().unwrap_or("");
+        if !status.starts_with("HTTP/1.") || status.split(' ').nth(1) != Some("200") {
+            return None;
+        }
+        let (mut location, mut search_target, mut usn) = (None, None, None);
+        let (mut server, mut max_age) = (None, None);
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let colon = match line.find(':') {
+                Some(colon) => colon,
+                None => continue,
+            };
+            let value = line[colon + 1..].trim().to_owned();
+            match &line[..colon].trim().to_lowercase()[..] {
+                "location" => location = Some(value),
+                "st" => search_target = Some(value),
+                "usn" => usn = Some(value),
+                "server" => server = Some(value),
+                "cache-control" => max_age = parse_max_age(&value),
+                _ => (),
+            }
+        }
+        match (location, search_target, usn) {
+            (Some(location), Some(search_target), Some(usn)) => {
+                Some(SearchResponse {
+                    location: location,
+                    search_target: search_target,
+                    usn: usn,
+                    server: server,
+                    max_age: max_age,
+                    src: src,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Searches the link of `local_ip` for devices of `search_target`, and
+/// returns those answering within `wait`, once each. Devices are asked to
+/// answer within `wait` too, of at least one and at most five seconds.
+///
+/// Fails with `StackError::InvalidArgument` if `search_target` is empty or
+/// spans lines.
+pub fn search(stack: Arc<Mutex<NetworkStack>>,
+              local_ip: Ipv4Addr,
+              search_target: &str,
+              wait: Duration)
+              -> StackResult<Vec<SearchResponse>> {
+    if search_target.is_empty() || search_target.contains(|c: char| c == '\r' || c == '\n') {
+        let msg = format!("Invalid search target: {:?}", search_target);
+        return Err(StackError::InvalidArgument(msg));
+    }
+    let socket = UdpSocket::bind(stack.clone(), SocketAddrV4::new(local_ip, 0))?;
+    let port = socket.local_addr()?.port();
+    let max_wait = cmp::max(1, cmp::min(MAX_WAIT_SECS, wait.as_secs()));
+    let request = request(search_target, max_wait);
+    let ipv4_tx = stack.lock().unwrap().ipv4_tx_with_src(local_ip, group())?;
+    let mut udp_tx = UdpTx::new(ipv4_tx, port, SSDP_PORT);
+    udp_tx.set_ttl(TTL);
+    udp_tx.send(&request)?;
+
+    let deadline = Instant::now() + wait;
+    let mut responses: Vec<SearchResponse> = Vec::new();
+    let mut buffer = vec![0; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(responses);
+        }
+        socket.set_opt(ReadTimeout, Some(deadline - now))?;
+        let (len, src) = match socket.recv_from(&mut buffer) {
+            Ok((len, SocketAddr::V4(src))) => (len, src),
+            Ok(_) => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match SearchResponse::parse(&buffer[..len], src) {
+            Some(response) => {
+                if !responses.iter().any(|found| found.usn == response.usn) {
+                    responses.push(response);
+                }
+            }
+            None => debug!("SSDP: Ignoring datagram from {}", src),
+        }
+    }
+}
+
+fn request(search_target: &str, max_wait: u64) -> Vec<u8> {
+    format!(concat!("M-SEARCH * HTTP/1.1\r\n",
+                    "HOST: {}:{}\r\n",
+                    "MAN: \"ssdp:discover\"\r\n",
+                    "MX: {}\r\n",
+                    "ST: {}\r\n\r\n"),
+            group(),
+            SSDP_PORT,
+            max_wait,
+            search_target)
+        .into_bytes()
+}
+
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',')
+        .filter_map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().to_lowercase() == "max-age" => {
+                    value.trim().parse().ok().map(Duration::from_secs)
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::str;
+    use std::time::Duration;
+
+    #[test]
+    fn requests() {
+        let request = request(IGD_SEARCH_TARGET, 2);
+        let request = str::from_utf8(&request).unwrap();
+        assert!(request.starts_with("M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\n"));
+        assert!(request.contains("\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n"));
+        assert!(request.ends_with(&format!("\r\nST: {}\r\n\r\n", IGD_SEARCH_TARGET)));
+    }
+
+    #[test]
+    fn responses() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), 1900);
+        let packet = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: no-cache, max-age = 1800\r\n\
+                       Location: http://192.168.1.1:5000/rootDesc.xml\r\nSERVER: router\r\n\
+                       st: upnp:rootdevice\r\nUSN: uuid:1234::upnp:rootdevice\r\nEXT:\r\n\r\n";
+        let response = SearchResponse::parse(packet, src).unwrap();
+        assert_eq!(&response.location[..], "http://192.168.1.1:5000/rootDesc.xml");
+        assert_eq!(&response.search_target[..], "upnp:rootdevice");
+        assert_eq!(&response.usn[..], "uuid:1234::upnp:rootdevice");
+        assert_eq!(response.server, Some("router".to_owned()));
+        assert_eq!(response.max_age, Some(Duration::from_secs(1800)));
+        assert_eq!(response.src, src);
+
+        let no_usn = b"HTTP/1.1 200 OK\r\nLOCATION: http://a/\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(SearchResponse::parse(no_usn, src), None);
+        let notify = b"NOTIFY * HTTP/1.1\r\nLOCATION: http://a/\r\nNT: x\r\nUSN: y\r\n\r\n";
+        assert_eq!(SearchResponse::parse(notify, src), None);
+    }
+}
@@ -0,0 +1,896 @@
+//! PPPoE client, RFC 2516, for carrying IPv4 over DSL-style access networks.
+//!
+//! `connect` finds an access concentrator on an ethernet interface of the
+//! stack with the PPPoE discovery stage, and brings up PPP on the session it
+//! is given: LCP, PAP if the access concentrator asks for it, and IPCP to
+//! obtain an address. The session is then added to the stack as a virtual
+//! point to point interface, see `tunnel::add_codec_interface`, with the
+//! negotiated address and a route to the peer.
+//!
+//! Sessions are not re-established. Once the access concentrator ends the
+//! session, or stops answering echo requests, the interface stays in the
+//! stack but drops everything routed to it, and a new session has to be
+//! set up with `connect` under another interface name.
+
+pub mod ppp;
+
+use {Interface, NetworkStack, RxError, StackError, StackResult, TxError};
+use ethernet::{BasicEthernetListener, BasicEthernetPayload, EthernetTx, EthernetTxImpl};
+use stack::DatalinkTx;
+use tunnel::{self, Codec, CodecRx};
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet as PnetPacket;
+use pnet::packet::ethernet::{EtherType, EthernetPacket};
+use pnet::util::MacAddr;
+
+use rand::Rand;
+
+use self::ppp::{ControlPacket, IpcpOptions, LcpOptions, Negotiation, Options};
+
+use std::cmp;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The `EtherType` of discovery stage frames.
+pub const DISCOVERY: EtherType = EtherType(0x8863);
+
+/// The `EtherType` of session stage frames.
+pub const SESSION: EtherType = EtherType(0x8864);
+
+/// Discovery codes. Session stage packets have code 0.
+pub const PADI: u8 = 0x09;
+pub const PADO: u8 = 0x07;
+pub const PADR: u8 = 0x19;
+pub const PADS: u8 = 0x65;
+pub const PADT: u8 = 0xa7;
+pub const SESSION_DATA: u8 = 0x00;
+
+/// Discovery tags.
+pub const END_OF_LIST: u16 = 0x0000;
+pub const SERVICE_NAME: u16 = 0x0101;
+pub const AC_NAME: u16 = 0x0102;
+pub const HOST_UNIQ: u16 = 0x0103;
+pub const AC_COOKIE: u16 = 0x0104;
+pub const RELAY_SESSION_ID: u16 = 0x0110;
+pub const SERVICE_NAME_ERROR: u16 = 0x0201;
+pub const AC_SYSTEM_ERROR: u16 = 0x0202;
+pub const GENERIC_ERROR: u16 = 0x0203;
+
+/// Length of the PPPoE header.
+pub const HEADER_LEN: usize = 6;
+
+/// The largest MRU a PPPoE session can have, the Ethernet MTU minus the
+/// PPPoE header and the PPP protocol field.
+pub const MAX_MRU: u16 = 1492;
+
+const VERSION_TYPE: u8 = 0x11;
+
+/// A PPPoE packet, the payload of a discovery or session frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub code: u8,
+    pub session_id: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(code: u8, session_id: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            code: code,
+            session_id: session_id,
+            payload: payload,
+        }
+    }
+
+    /// Returns the session packet carrying `data` of the PPP protocol
+    /// `protocol`.
+    pub fn session(session_id: u16, protocol: u16, data: &[u8]) -> Packet {
+        let mut payload = vec![(protocol >> 8) as u8, protocol as u8];
+        payload.extend_from_slice(data);
+        Self::new(SESSION_DATA, session_id, payload)
+    }
+
+    /// Parses `data`, ignoring the padding of short frames. Returns `None`
+    /// if it is not a version 1 PPPoE packet.
+    pub fn parse(data: &[u8]) -> Option<Packet> {
+        if data.len() < HEADER_LEN || data[0] != VERSION_TYPE {
+            return None;
+        }
+        let length = read_u16(&data[4..6]) as usize;
+        if data.len() < HEADER_LEN + length {
+            return None;
+        }
+        let payload = data[HEADER_LEN..HEADER_LEN + length].to_vec();
+        Some(Self::new(data[1], read_u16(&data[2..4]), payload))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let length = self.payload.len();
+        let mut buffer = vec![VERSION_TYPE,
+                              self.code,
+                              (self.session_id >> 8) as u8,
+                              self.session_id as u8,
+                              (length >> 8) as u8,
+                              length as u8];
+        buffer.extend_from_slice(&self.payload);
+        buffer
+    }
+
+    /// Returns the PPP protocol and data of a session packet.
+    pub fn ppp(&self) -> Option<(u16, &[u8])> {
+        if self.code != SESSION_DATA || self.payload.len() < 2 {
+            return None;
+        }
+        Some((read_u16(&self.payload), &self.payload[2..]))
+    }
+}
+
+/// A tag of a discovery packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+impl Tag {
+    pub fn new(kind: u16, value: Vec<u8>) -> Tag {
+        Tag {
+            kind: kind,
+            value: value,
+        }
+    }
+}
+
+/// Parses the tags of a discovery packet, up to the End-Of-List tag if there
+/// is one. Returns `None` if they are malformed.
+pub fn parse_tags(mut data: &[u8]) -> Option<Vec<Tag>> {
+    let mut tags = vec![];
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return None;
+        }
+        let kind = read_u16(&data[0..2]);
+        let length = read_u16(&data[2..4]) as usize;
+        if kind == END_OF_LIST {
+            break;
+        }
+        if data.len() < 4 + length {
+            return None;
+        }
+        tags.push(Tag::new(kind, data[4..4 + length].to_vec()));
+        data = &data[4 + length..];
+    }
+    Some(tags)
+}
+
+pub fn tags_to_bytes(tags: &[Tag]) -> Vec<u8> {
+    let mut buffer = vec![];
+    for tag in tags {
+        let length = tag.value.len();
+        buffer.extend_from_slice(&[(tag.kind >> 8) as u8,
+                                   tag.kind as u8,
+                                   (length >> 8) as u8,
+                                   length as u8]);
+        buffer.extend_from_slice(&tag.value);
+    }
+    buffer
+}
+
+fn find_tag(tags: &[Tag], kind: u16) -> Option<&Tag> {
+    tags.iter().find(|tag| tag.kind == kind)
+}
+
+/// Configuration of a PPPoE client.
+#[derive(Debug, Clone)]
+pub struct PppoeConfig {
+    /// The service to ask for, any service if empty
+    pub service_name: String,
+    /// Only accept offers from the access concentrator with this name
+    pub ac_name: Option<String>,
+    /// User name and password, for access concentrators requiring PAP
+    pub credentials: Option<(String, String)>,
+    /// Time to wait for an answer before sending a request again
+    pub timeout: Duration,
+    /// Number of times to send each request before giving up
+    pub attempts: u32,
+    /// Interval of the LCP echo requests checking that the session is still
+    /// alive, `None` to not send any
+    pub echo_interval: Option<Duration>,
+    /// Number of unanswered echo requests before the session is considered
+    /// down
+    pub echo_failures: u32,
+    /// Whether to make the peer the default gateway
+    pub default_route: bool,
+}
+
+impl Default for PppoeConfig {
+    fn default() -> PppoeConfig {
+        PppoeConfig {
+            service_name: String::new(),
+            ac_name: None,
+            credentials: None,
+            timeout: Duration::from_secs(3),
+            attempts: 5,
+            echo_interval: Some(Duration::from_secs(30)),
+            echo_failures: 4,
+            default_route: true,
+        }
+    }
+}
+
+/// Runs PPPoE discovery on `ethernet` and brings up PPP on the session.
+/// Blocks until IPCP assigned an address, which takes a few round trips to
+/// the access concentrator. Then adds the session to `stack` as the point
+/// to point interface `name`, and returns it.
+///
+/// Fails with `StackError::AlreadyExists` if there already is a PPPoE
+/// client on `ethernet`, and with an `io::Error` of kind `TimedOut` if the
+/// access concentrator did not answer, `PermissionDenied` if authentication
+/// failed or `ConnectionRefused` if the access concentrator refused the
+/// session in some other way.
+pub fn connect(stack: Arc<Mutex<NetworkStack>>,
+               ethernet: &Interface,
+               name: &str,
+               config: &PppoeConfig)
+               -> StackResult<Pppoe> {
+    let (tx, frames) = mpsc::channel();
+    {
+        let mut stack = stack.lock().unwrap();
+        stack.ethernet_listen(ethernet, BasicEthernetListener::new(DISCOVERY, tx.clone()))?;
+        let session_listener = BasicEthernetListener::new(SESSION, tx);
+        if let Err(e) = stack.ethernet_listen(ethernet, session_listener) {
+            stack.interface(ethernet)?.remove_ethernet_listener(DISCOVERY);
+            return Err(e);
+        }
+    }
+    let client = Client {
+        stack: stack.clone(),
+        ethernet: ethernet.clone(),
+        config: config,
+        frames: frames,
+    };
+    let result = client.run(name);
+    if result.is_err() {
+        remove_listeners(&stack, ethernet);
+    }
+    result
+}
+
+/// A PPPoE session brought up by `connect`.
+pub struct Pppoe {
+    interface: Interface,
+    session: Arc<Session>,
+    address: Ipv4Addr,
+    peer: Ipv4Addr,
+    dns_servers: Vec<Ipv4Addr>,
+}
+
+impl Pppoe {
+    /// The point to point interface carrying the IPv4 traffic of the session.
+    pub fn interface(&self) -> &Interface {
+        &self.interface
+    }
+
+    pub fn session_id(&self) -> u16 {
+        self.session.id
+    }
+
+    /// The MAC address of the access concentrator.
+    pub fn access_concentrator(&self) -> MacAddr {
+        self.session.ac
+    }
+
+    /// The address IPCP assigned to the interface.
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    /// The address of the other end of the link.
+    pub fn peer(&self) -> Ipv4Addr {
+        self.peer
+    }
+
+    /// The DNS servers the peer told us about, if any.
+    pub fn dns_servers(&self) -> &[Ipv4Addr] {
+        &self.dns_servers
+    }
+
+    /// Returns false once the session has ended.
+    pub fn is_up(&self) -> bool {
+        self.session.up.load(Ordering::SeqCst)
+    }
+
+    /// Ends the session, telling the access concentrator with an LCP
+    /// Terminate-Request and a PADT. Does nothing if it already ended.
+    pub fn close(&self) -> StackResult<()> {
+        self.session.close()
+    }
+}
+
+/// What the `Pppoe` handle and the threads of a session share.
+struct Session {
+    stack: Arc<Mutex<NetworkStack>>,
+    ethernet: Interface,
+    ac: MacAddr,
+    id: u16,
+    up: AtomicBool,
+}
+
+impl Session {
+    fn link(&self) -> Link {
+        Link {
+            wire: Wire::new(self.stack.clone(), self.ethernet.clone(), self.ac),
+            session_id: self.id,
+        }
+    }
+
+    fn close(&self) -> StackResult<()> {
+        if !self.down() {
+            return Ok(());
+        }
+        let mut link = self.link();
+        let terminate = ControlPacket::new(ppp::TERMINATE_REQUEST, random(&self.stack), vec![]);
+        let result = link.send_control(ppp::LCP, &terminate);
+        link.wire.send(DISCOVERY, &Packet::new(PADT, self.id, vec![])).and(result)
+    }
+
+    /// Marks the session as ended. Returns false if it already was.
+    fn down(&self) -> bool {
+        let was_up = self.up.swap(false, Ordering::SeqCst);
+        if was_up {
+            remove_listeners(&self.stack, &self.ethernet);
+        }
+        was_up
+    }
+}
+
+fn remove_listeners(stack: &Arc<Mutex<NetworkStack>>, ethernet: &Interface) {
+    let mut stack = stack.lock().unwrap();
+    if let Ok(stack_interface) = stack.interface(ethernet) {
+        stack_interface.remove_ethernet_listener(DISCOVERY);
+        stack_interface.remove_ethernet_listener(SESSION);
+    }
+}
+
+type Frames = Receiver<(SystemTime, EthernetPacket<'static>)>;
+
+/// Sends PPPoE packets to one destination on the ethernet interface.
+struct Wire {
+    stack: Arc<Mutex<NetworkStack>>,
+    ethernet: Interface,
+    dst: MacAddr,
+    tx: Option<EthernetTxImpl<DatalinkTx>>,
+}
+
+impl Wire {
+    fn new(stack: Arc<Mutex<NetworkStack>>, ethernet: Interface, dst: MacAddr) -> Wire {
+        Wire {
+            stack: stack,
+            ethernet: ethernet,
+            dst: dst,
+            tx: None,
+        }
+    }
+
+    fn send(&mut self, ether_type: EtherType, packet: &Packet) -> StackResult<()> {
+        let data = packet.to_bytes();
+        loop {
+            if self.tx.is_none() {
+                let mut stack = self.stack.lock().unwrap();
+                self.tx = Some(stack.interface(&self.ethernet)?.ethernet_tx(self.dst));
+            }
+            let payload = BasicEthernetPayload::new(ether_type, &data);
+            match self.tx.as_mut().unwrap().send(1, data.len(), payload) {
+                Err(TxError::InvalidTx) => self.tx = None,
+                result => return result.map(|_| ()).map_err(StackError::from),
+            }
+        }
+    }
+}
+
+/// Sends PPP packets on a session.
+struct Link {
+    wire: Wire,
+    session_id: u16,
+}
+
+impl Link {
+    fn send_ppp(&mut self, protocol: u16, data: &[u8]) -> StackResult<()> {
+        self.wire.send(SESSION, &Packet::session(self.session_id, protocol, data))
+    }
+
+    fn send_control(&mut self, protocol: u16, packet: &ControlPacket) -> StackResult<()> {
+        self.send_ppp(protocol, &packet.to_bytes())
+    }
+
+    /// Answers the LCP packets that keep an open link running. Fails if the
+    /// peer terminates the link.
+    fn maintain(&mut self, magic: u32, packet: &ControlPacket) -> StackResult<()> {
+        match packet.code {
+            ppp::ECHO_REQUEST if packet.data.len() >= 4 => {
+                let mut data = packet.data.clone();
+                write_u32(&mut data[..4], magic);
+                let reply = ControlPacket::new(ppp::ECHO_REPLY, packet.id, data);
+                self.send_control(ppp::LCP, &reply)
+            }
+            ppp::TERMINATE_REQUEST => {
+                let ack = ControlPacket::new(ppp::TERMINATE_ACK, packet.id, vec![]);
+                self.send_control(ppp::LCP, &ack)?;
+                Err(error(io::ErrorKind::ConnectionAborted, "Peer terminated the link"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn error(kind: io::ErrorKind, msg: &str) -> StackError {
+    StackError::IoError(io::Error::new(kind, msg))
+}
+
+fn timed_out(what: &str) -> StackError {
+    error(io::ErrorKind::TimedOut, &format!("No answer to {}", what))
+}
+
+/// Returns the source and PPPoE packet of `frame`.
+fn parse_frame(frame: &EthernetPacket) -> Option<(EtherType, MacAddr, Packet)> {
+    Packet::parse(frame.payload())
+        .map(|packet| (frame.get_ethertype(), frame.get_source(), packet))
+}
+
+/// The blocking part of `connect`, up to the end of IPCP.
+struct Client<'a> {
+    stack: Arc<Mutex<NetworkStack>>,
+    ethernet: Interface,
+    config: &'a PppoeConfig,
+    frames: Frames,
+}
+
+impl<'a> Client<'a> {
+    fn run(self, name: &str) -> StackResult<Pppoe> {
+        let (ac, id) = self.discover()?;
+        debug!("PPPoE: Session {} with {}", id, ac);
+        let session = Arc::new(Session {
+            stack: self.stack.clone(),
+            ethernet: self.ethernet.clone(),
+            ac: ac,
+            id: id,
+            up: AtomicBool::new(true),
+        });
+        let mut link = session.link();
+        let result = self.establish(&session, &mut link)
+            .and_then(|(lcp, ipcp)| self.add_interface(&session, name, lcp, ipcp));
+        match result {
+            Ok((pppoe, codec_rx, magic)) => {
+                let echo = (self.config.echo_interval, self.config.echo_failures);
+                let frames = self.frames;
+                let rx_session = session.clone();
+                thread::spawn(move || run_session(rx_session, link, frames, codec_rx, magic, echo));
+                Ok(pppoe)
+            }
+            Err(e) => {
+                session.close().unwrap_or(());
+                Err(e)
+            }
+        }
+    }
+
+    /// Waits until `deadline` for a discovery or session frame.
+    fn recv(&self, deadline: Instant) -> StackResult<Option<(EtherType, MacAddr, Packet)>> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            match self.frames.recv_timeout(deadline - now) {
+                Ok((_, frame)) => {
+                    if let Some(parsed) = parse_frame(&frame) {
+                        return Ok(Some(parsed));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(error(io::ErrorKind::NotConnected, "PPPoE listeners were removed"));
+                }
+            }
+        }
+    }
+
+    /// Sends `request` until a discovery packet `accept` returns something
+    /// for arrives.
+    fn exchange<F, T>(&self, wire: &mut Wire, request: &Packet, mut accept: F) -> StackResult<T>
+        where F: FnMut(MacAddr, &Packet) -> Option<T>
+    {
+        for _ in 0..self.config.attempts {
+            wire.send(DISCOVERY, request)?;
+            let deadline = Instant::now() + self.config.timeout;
+            while let Some((ether_type, src, packet)) = self.recv(deadline)? {
+                if ether_type != DISCOVERY {
+                    continue;
+                }
+                if let Some(result) = accept(src, &packet) {
+                    return Ok(result);
+                }
+            }
+        }
+        Err(timed_out(if request.code == PADI { "PADI" } else { "PADR" }))
+    }
+
+    /// The discovery stage. Returns the access concentrator that offered a
+    /// session first and the id of the session.
+    fn discover(&self) -> StackResult<(MacAddr, u16)> {
+        let host_uniq = {
+            let mut host_uniq = vec![0; 4];
+            write_u32(&mut host_uniq, random(&self.stack));
+            Tag::new(HOST_UNIQ, host_uniq)
+        };
+        let service_name = Tag::new(SERVICE_NAME, self.config.service_name.as_bytes().to_vec());
+        let mut tags = vec![service_name, host_uniq.clone()];
+        let padi = Packet::new(PADI, 0, tags_to_bytes(&tags));
+        let broadcast = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+        let mut wire = Wire::new(self.stack.clone(), self.ethernet.clone(), broadcast);
+        let ac_name = self.config.ac_name.as_ref().map(|name| name.as_bytes().to_vec());
+        let (ac, offer) = self.exchange(&mut wire, &padi, |src, packet| {
+                if packet.code != PADO || packet.session_id != 0 {
+                    return None;
+                }
+                let offer = match parse_tags(&packet.payload) {
+                    Some(offer) => offer,
+                    None => return None,
+                };
+                if find_tag(&offer, HOST_UNIQ) != Some(&host_uniq) ||
+                   find_tag(&offer, SERVICE_NAME).is_none() {
+                    return None;
+                }
+                let name_matches = match (&ac_name, find_tag(&offer, AC_NAME)) {
+                    (&Some(ref name), Some(tag)) => *name == tag.value,
+                    (&Some(_), None) => false,
+                    (&None, _) => true,
+                };
+                if name_matches {
+                    Some((src, offer))
+                } else {
+                    None
+                }
+            })?;
+
+        for &kind in &[AC_COOKIE, RELAY_SESSION_ID] {
+            if let Some(tag) = find_tag(&offer, kind) {
+                tags.push(tag.clone());
+            }
+        }
+        let padr = Packet::new(PADR, 0, tags_to_bytes(&tags));
+        let mut wire = Wire::new(self.stack.clone(), self.ethernet.clone(), ac);
+        let confirmation = self.exchange(&mut wire, &padr, |src, packet| {
+                if src != ac || packet.code != PADS {
+                    return None;
+                }
+                parse_tags(&packet.payload).and_then(|tags| {
+                    if find_tag(&tags, HOST_UNIQ) == Some(&host_uniq) {
+                        Some((packet.session_id, tags))
+                    } else {
+                        None
+                    }
+                })
+            })?;
+        let (id, tags) = confirmation;
+        for &kind in &[SERVICE_NAME_ERROR, AC_SYSTEM_ERROR, GENERIC_ERROR] {
+            if let Some(tag) = find_tag(&tags, kind) {
+                let msg = format!("Session refused: {}", String::from_utf8_lossy(&tag.value));
+                return Err(error(io::ErrorKind::ConnectionRefused, &msg));
+            }
+        }
+        if id == 0 {
+            return Err(error(io::ErrorKind::ConnectionRefused, "Session refused"));
+        }
+        Ok((ac, id))
+    }
+
+    /// Waits until `deadline` for a control packet on the session. Fails if
+    /// the access concentrator ends the session.
+    fn recv_control(&self,
+                    session: &Session,
+                    deadline: Instant)
+                    -> StackResult<Option<(u16, ControlPacket)>> {
+        while let Some((ether_type, src, packet)) = self.recv(deadline)? {
+            if src != session.ac || packet.session_id != session.id {
+                continue;
+            }
+            if ether_type == DISCOVERY && packet.code == PADT {
+                return Err(error(io::ErrorKind::ConnectionAborted, "Session ended by PADT"));
+            }
+            let control = packet.ppp().and_then(|(protocol, data)| {
+                ControlPacket::parse(data).map(|control| (protocol, control))
+            });
+            if let Some(control) = control {
+                return Ok(Some(control));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs `negotiation` of `protocol` until it is open. LCP is expected to
+    /// be open already unless that is what is negotiated, with `magic` as
+    /// our magic number.
+    fn negotiate<O: Options>(&self,
+                             session: &Session,
+                             link: &mut Link,
+                             protocol: u16,
+                             negotiation: &mut Negotiation<O>,
+                             magic: u32)
+                             -> StackResult<()> {
+        for _ in 0..self.config.attempts {
+            link.send_control(protocol, &negotiation.request())?;
+            let deadline = Instant::now() + self.config.timeout;
+            while let Some((received, packet)) = self.recv_control(session, deadline)? {
+                if received == protocol {
+                    for reply in negotiation.receive(&packet) {
+                        link.send_control(protocol, &reply)?;
+                    }
+                    if negotiation.is_terminated() {
+                        return Err(error(io::ErrorKind::ConnectionAborted, "Peer terminated"));
+                    }
+                    if negotiation.is_open() {
+                        return Ok(());
+                    }
+                } else if received == ppp::LCP {
+                    link.maintain(magic, &packet)?;
+                }
+            }
+        }
+        Err(timed_out(if protocol == ppp::LCP { "LCP" } else { "IPCP" }))
+    }
+
+    fn authenticate(&self,
+                    session: &Session,
+                    link: &mut Link,
+                    magic: u32)
+                    -> StackResult<()> {
+        let (user, password) = match self.config.credentials {
+            Some((ref user, ref password)) => (user, password),
+            None => return Err(error(io::ErrorKind::PermissionDenied, "No credentials")),
+        };
+        let mut id: u8 = random(&self.stack);
+        for _ in 0..self.config.attempts {
+            id = id.wrapping_add(1);
+            link.send_control(ppp::PAP, &ppp::pap_request(id, user, password))?;
+            let deadline = Instant::now() + self.config.timeout;
+            while let Some((protocol, packet)) = self.recv_control(session, deadline)? {
+                match (protocol, packet.code) {
+                    (ppp::PAP, ppp::PAP_ACK) if packet.id == id => return Ok(()),
+                    (ppp::PAP, ppp::PAP_NAK) if packet.id == id => {
+                        return Err(error(io::ErrorKind::PermissionDenied,
+                                         "Authentication failed"));
+                    }
+                    (ppp::LCP, _) => link.maintain(magic, &packet)?,
+                    _ => (),
+                }
+            }
+        }
+        Err(timed_out("PAP"))
+    }
+
+    /// Brings up LCP, authenticates and brings up IPCP.
+    fn establish(&self,
+                 session: &Session,
+                 link: &mut Link)
+                 -> StackResult<(LcpOptions, IpcpOptions)> {
+        let pap = self.config.credentials.is_some();
+        let lcp_options = LcpOptions::new(MAX_MRU, pap, random(&self.stack));
+        let mut lcp = Negotiation::new(lcp_options, random(&self.stack));
+        self.negotiate(session, link, ppp::LCP, &mut lcp, 0)?;
+        let magic = lcp.options().magic();
+        if lcp.options().auth() == Some(ppp::PAP) {
+            self.authenticate(session, link, magic)?;
+        }
+        let mut ipcp = Negotiation::new(IpcpOptions::new(), random(&self.stack));
+        self.negotiate(session, link, ppp::IPCP, &mut ipcp, magic)?;
+        Ok((lcp.options().clone(), ipcp.options().clone()))
+    }
+
+    /// Adds the interface for the session to the stack.
+    fn add_interface(&self,
+                     session: &Arc<Session>,
+                     name: &str,
+                     lcp: LcpOptions,
+                     ipcp: IpcpOptions)
+                     -> StackResult<(Pppoe, CodecRx<PppCodec>, u32)> {
+        let refused = |msg: &str| error(io::ErrorKind::ConnectionRefused, msg);
+        let address = ipcp.address().ok_or_else(|| refused("No address assigned"))?;
+        let peer = ipcp.peer().ok_or_else(|| refused("No peer address"))?;
+        let interface = Interface {
+            name: name.to_owned(),
+            mac: ptp_mac(address),
+        };
+        let mtu = cmp::min(MAX_MRU, lcp.peer_mru()) as usize;
+        let (codec_tx, codec_rx) = {
+            let mut stack = self.stack.lock().unwrap();
+            let channels = tunnel::add_codec_interface(&mut stack, interface.clone(), PppCodec)?;
+            stack.interface(&interface)?.set_mtu(mtu)?;
+            stack.add_ipv4(&interface, Ipv4Network::new(address, 32).unwrap())?;
+            stack.add_route(Ipv4Network::new(peer, 32).unwrap(), None, interface.clone());
+            if self.config.default_route {
+                stack.set_default_route(peer, interface.clone())?;
+            }
+            channels
+        };
+
+        let tx_session = session.clone();
+        let mut tx_link = session.link();
+        thread::spawn(move || {
+            while let Some(packet) = codec_tx.recv() {
+                if !tx_session.up.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Err(e) = tx_link.send_ppp(ppp::IPV4, &packet) {
+                    warn!("PPPoE: Unable to send on session {}: {}", tx_session.id, e);
+                }
+            }
+        });
+        let pppoe = Pppoe {
+            interface: interface,
+            session: session.clone(),
+            address: address,
+            peer: peer,
+            dns_servers: ipcp.dns_servers(),
+        };
+        Ok((pppoe, codec_rx, lcp.magic()))
+    }
+}
+
+/// Handles the frames of an established session until it ends.
+fn run_session(session: Arc<Session>,
+               mut link: Link,
+               frames: Frames,
+               codec_rx: CodecRx<PppCodec>,
+               magic: u32,
+               echo: (Option<Duration>, u32)) {
+    let (echo_interval, echo_failures) = echo;
+    let mut unanswered = 0;
+    let mut id: u8 = random(&session.stack);
+    loop {
+        let received = match echo_interval {
+            Some(interval) => frames.recv_timeout(interval),
+            None => frames.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let frame = match received {
+            Ok((_, frame)) => frame,
+            Err(RecvTimeoutError::Timeout) => {
+                if unanswered >= echo_failures {
+                    warn!("PPPoE: Session {} does not answer echo requests", session.id);
+                    session.close().unwrap_or(());
+                    break;
+                }
+                unanswered += 1;
+                id = id.wrapping_add(1);
+                let mut data = vec![0; 4];
+                write_u32(&mut data, magic);
+                let request = ControlPacket::new(ppp::ECHO_REQUEST, id, data);
+                link.send_control(ppp::LCP, &request).unwrap_or(());
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let (ether_type, src, packet) = match parse_frame(&frame) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        if src != session.ac || packet.session_id != session.id {
+            continue;
+        }
+        if ether_type == DISCOVERY {
+            if packet.code == PADT {
+                info!("PPPoE: Session {} ended by the access concentrator", session.id);
+                break;
+            }
+            continue;
+        }
+        // Anything from the peer shows the session is alive
+        unanswered = 0;
+        let (protocol, data) = match packet.ppp() {
+            Some(ppp) => ppp,
+            None => continue,
+        };
+        match protocol {
+            ppp::IPV4 => {
+                if let Err(e) = codec_rx.deliver(data) {
+                    debug!("PPPoE: Invalid packet on session {}: {:?}", session.id, e);
+                }
+            }
+            ppp::LCP => {
+                let control = match ControlPacket::parse(data) {
+                    Some(control) => control,
+                    None => continue,
+                };
+                if link.maintain(magic, &control).is_err() {
+                    info!("PPPoE: Session {} terminated by the peer", session.id);
+                    break;
+                }
+            }
+            ppp::IPCP | ppp::PAP => (),
+            _ => {
+                id = id.wrapping_add(1);
+                let mut rejected = vec![(protocol >> 8) as u8, protocol as u8];
+                rejected.extend_from_slice(data);
+                let reject = ControlPacket::new(ppp::PROTOCOL_REJECT, id, rejected);
+                link.send_control(ppp::LCP, &reject).unwrap_or(());
+            }
+        }
+    }
+    session.down();
+}
+
+/// PPP carries IPv4 packets as they are, so the codec interface is only
+/// used for its point to point channel. The overhead is the PPPoE header and
+/// the PPP protocol field.
+struct PppCodec;
+
+impl Codec for PppCodec {
+    fn overhead(&self) -> usize {
+        HEADER_LEN + 2
+    }
+
+    fn encode(&mut self, packet: &[u8]) -> Vec<u8> {
+        packet.to_vec()
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, RxError> {
+        Ok(data.to_vec())
+    }
+}
+
+/// PPP links have no MAC addresses, so the interface gets a locally
+/// administered one derived from its address.
+fn ptp_mac(address: Ipv4Addr) -> MacAddr {
+    let octets = address.octets();
+    MacAddr::new(0x02, 0, octets[0], octets[1], octets[2], octets[3])
+}
+
+/// Returns a random value from the generator of `stack`.
+fn random<T: Rand>(stack: &Mutex<NetworkStack>) -> T {
+    stack.lock().unwrap().random()
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn write_u32(buffer: &mut [u8], value: u32) {
+    buffer[0] = (value >> 24) as u8;
+    buffer[1] = (value >> 16) as u8;
+    buffer[2] = (value >> 8) as u8;
+    buffer[3] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet() {
+        let packet = Packet::session(0x1234, ppp::IPV4, &[0x45]);
+        let mut data = packet.to_bytes();
+        assert_eq!(data, vec![0x11, 0, 0x12, 0x34, 0, 3, 0, 0x21, 0x45]);
+        data.extend_from_slice(&[0; 4]);
+        let parsed = Packet::parse(&data).unwrap();
+        assert_eq!(parsed, packet);
+        assert_eq!(parsed.ppp(), Some((ppp::IPV4, &[0x45][..])));
+        assert_eq!(Packet::parse(&data[..8]), None);
+    }
+
+    #[test]
+    fn tags() {
+        let tags = vec![Tag::new(SERVICE_NAME, vec![]), Tag::new(AC_NAME, b"ac".to_vec())];
+        let mut data = tags_to_bytes(&tags);
+        assert_eq!(&data[..4], &[0x01, 0x01, 0, 0]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0xff]);
+        assert_eq!(parse_tags(&data), Some(tags));
+        assert_eq!(parse_tags(&[0x01, 0x02, 0, 3, b'a']), None);
+    }
+}
@@ -0,0 +1,616 @@
+//! The parts of the Point-to-Point Protocol, RFC 1661, needed to bring up a
+//! link carrying IPv4: the option negotiation of LCP and IPCP (RFC 1332, with
+//! the DNS options of RFC 1877) and PAP authentication (RFC 1334).
+//!
+//! Nothing here does any I/O. `Negotiation` is fed the control packets
+//! received from the peer and returns the ones to send back.
+
+use rand::{Rng, XorShiftRng};
+
+use std::cmp;
+use std::net::Ipv4Addr;
+
+/// PPP protocol numbers.
+pub const IPV4: u16 = 0x0021;
+pub const IPCP: u16 = 0x8021;
+pub const LCP: u16 = 0xc021;
+pub const PAP: u16 = 0xc023;
+pub const CHAP: u16 = 0xc223;
+
+/// Control packet codes. LCP uses all of them, IPCP only the first seven.
+pub const CONFIGURE_REQUEST: u8 = 1;
+pub const CONFIGURE_ACK: u8 = 2;
+pub const CONFIGURE_NAK: u8 = 3;
+pub const CONFIGURE_REJECT: u8 = 4;
+pub const TERMINATE_REQUEST: u8 = 5;
+pub const TERMINATE_ACK: u8 = 6;
+pub const CODE_REJECT: u8 = 7;
+pub const PROTOCOL_REJECT: u8 = 8;
+pub const ECHO_REQUEST: u8 = 9;
+pub const ECHO_REPLY: u8 = 10;
+pub const DISCARD_REQUEST: u8 = 11;
+
+/// LCP configuration options.
+pub const LCP_MRU: u8 = 1;
+pub const LCP_AUTH: u8 = 3;
+pub const LCP_MAGIC: u8 = 5;
+
+/// IPCP configuration options.
+pub const IPCP_ADDRESS: u8 = 3;
+pub const IPCP_PRIMARY_DNS: u8 = 129;
+pub const IPCP_SECONDARY_DNS: u8 = 131;
+
+/// The MRU of links that did not negotiate one.
+pub const DEFAULT_MRU: u16 = 1500;
+
+/// PAP codes.
+pub const PAP_REQUEST: u8 = 1;
+pub const PAP_ACK: u8 = 2;
+pub const PAP_NAK: u8 = 3;
+
+/// A packet of a PPP control protocol, such as LCP, IPCP or PAP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlPacket {
+    pub code: u8,
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+impl ControlPacket {
+    pub fn new(code: u8, id: u8, data: Vec<u8>) -> ControlPacket {
+        ControlPacket {
+            code: code,
+            id: id,
+            data: data,
+        }
+    }
+
+    /// Parses `data`, ignoring any padding after the length given in the
+    /// header. Returns `None` if `data` is shorter than that.
+    pub fn parse(data: &[u8]) -> Option<ControlPacket> {
+        if data.len() < 4 {
+            return None;
+        }
+        let length = read_u16(&data[2..4]) as usize;
+        if length < 4 || data.len() < length {
+            return None;
+        }
+        Some(ControlPacket::new(data[0], data[1], data[4..length].to_vec()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let length = 4 + self.data.len();
+        let mut buffer = vec![self.code, self.id, (length >> 8) as u8, length as u8];
+        buffer.extend_from_slice(&self.data);
+        buffer
+    }
+}
+
+/// A configuration option of LCP or IPCP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigOption {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl ConfigOption {
+    pub fn new(kind: u8, data: Vec<u8>) -> ConfigOption {
+        ConfigOption {
+            kind: kind,
+            data: data,
+        }
+    }
+
+    pub fn u16(kind: u8, value: u16) -> ConfigOption {
+        Self::new(kind, vec![(value >> 8) as u8, value as u8])
+    }
+
+    pub fn u32(kind: u8, value: u32) -> ConfigOption {
+        let mut data = vec![0; 4];
+        write_u32(&mut data, value);
+        Self::new(kind, data)
+    }
+
+    pub fn ipv4(kind: u8, ip: Ipv4Addr) -> ConfigOption {
+        Self::new(kind, ip.octets().to_vec())
+    }
+
+    /// Returns the data of this option as a number, `None` if it has the
+    /// wrong length.
+    pub fn as_u16(&self) -> Option<u16> {
+        if self.data.len() == 2 {
+            Some(read_u16(&self.data))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.data.len() == 4 {
+            Some(read_u32(&self.data))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_ipv4(&self) -> Option<Ipv4Addr> {
+        self.as_u32().map(Ipv4Addr::from)
+    }
+}
+
+/// Parses the options of a Configure packet. Returns `None` if they are
+/// malformed.
+pub fn parse_options(mut data: &[u8]) -> Option<Vec<ConfigOption>> {
+    let mut options = vec![];
+    while !data.is_empty() {
+        if data.len() < 2 || (data[1] as usize) < 2 || data.len() < data[1] as usize {
+            return None;
+        }
+        let length = data[1] as usize;
+        options.push(ConfigOption::new(data[0], data[2..length].to_vec()));
+        data = &data[length..];
+    }
+    Some(options)
+}
+
+pub fn options_to_bytes(options: &[ConfigOption]) -> Vec<u8> {
+    let mut buffer = vec![];
+    for option in options {
+        buffer.push(option.kind);
+        buffer.push(2 + option.data.len() as u8);
+        buffer.extend_from_slice(&option.data);
+    }
+    buffer
+}
+
+/// Returns the PAP Authenticate-Request for `user` and `password`.
+pub fn pap_request(id: u8, user: &str, password: &str) -> ControlPacket {
+    let mut data = vec![user.len() as u8];
+    data.extend_from_slice(user.as_bytes());
+    data.push(password.len() as u8);
+    data.extend_from_slice(password.as_bytes());
+    ControlPacket::new(PAP_REQUEST, id, data)
+}
+
+/// What to answer to an option requested by the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Ack,
+    /// The option is known but the value is not acceptable, suggest this
+    /// one instead
+    Nak(ConfigOption),
+    Reject,
+}
+
+/// The options of one control protocol, what to request and what to accept.
+pub trait Options {
+    /// Returns the options to put in our Configure-Request.
+    fn request(&self) -> Vec<ConfigOption>;
+
+    /// Judges an option in a Configure-Request from the peer.
+    fn check(&mut self, option: &ConfigOption) -> Verdict;
+
+    /// Called when the peer suggests another value for one of our options.
+    fn nak(&mut self, option: &ConfigOption);
+
+    /// Called when the peer does not know or want one of our options.
+    fn reject(&mut self, option: &ConfigOption);
+}
+
+/// The negotiation of one control protocol with the peer. Open once both
+/// sides acknowledged the Configure-Request of the other.
+pub struct Negotiation<O: Options> {
+    options: O,
+    id: u8,
+    acked: bool,
+    peer_acked: bool,
+    terminated: bool,
+}
+
+impl<O: Options> Negotiation<O> {
+    /// Starts negotiating `options`. The identifiers of the requests sent
+    /// count up from `id`, which should be random so replies to the requests
+    /// of an earlier negotiation are not taken for this one.
+    pub fn new(options: O, id: u8) -> Negotiation<O> {
+        Negotiation {
+            options: options,
+            id: id,
+            acked: false,
+            peer_acked: false,
+            terminated: false,
+        }
+    }
+
+    pub fn options(&self) -> &O {
+        &self.options
+    }
+
+    /// Returns true once both sides agreed on a configuration.
+    pub fn is_open(&self) -> bool {
+        self.acked && self.peer_acked
+    }
+
+    /// Returns true if the peer asked to close the protocol.
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    /// Returns a new Configure-Request, for starting the negotiation or
+    /// retransmitting after a timeout. It has to be acknowledged again.
+    pub fn request(&mut self) -> ControlPacket {
+        self.id = self.id.wrapping_add(1);
+        self.acked = false;
+        let data = options_to_bytes(&self.options.request());
+        ControlPacket::new(CONFIGURE_REQUEST, self.id, data)
+    }
+
+    /// Handles `packet` from the peer and returns the packets to answer with.
+    /// Packets that are not part of the negotiation are ignored.
+    pub fn receive(&mut self, packet: &ControlPacket) -> Vec<ControlPacket> {
+        match packet.code {
+            CONFIGURE_REQUEST => vec![self.configure(packet)],
+            CONFIGURE_ACK if packet.id == self.id => {
+                self.acked = true;
+                vec![]
+            }
+            CONFIGURE_NAK | CONFIGURE_REJECT if packet.id == self.id => {
+                for option in parse_options(&packet.data).unwrap_or_default() {
+                    if packet.code == CONFIGURE_NAK {
+                        self.options.nak(&option);
+                    } else {
+                        self.options.reject(&option);
+                    }
+                }
+                vec![self.request()]
+            }
+            TERMINATE_REQUEST => {
+                self.terminated = true;
+                vec![ControlPacket::new(TERMINATE_ACK, packet.id, vec![])]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Answers a Configure-Request. Rejects take precedence over Naks, and
+    /// the request is only acknowledged if all options are acceptable.
+    fn configure(&mut self, packet: &ControlPacket) -> ControlPacket {
+        let options = match parse_options(&packet.data) {
+            Some(options) => options,
+            None => return ControlPacket::new(CONFIGURE_REJECT, packet.id, packet.data.clone()),
+        };
+        let mut naks = vec![];
+        let mut rejects = vec![];
+        for option in options {
+            match self.options.check(&option) {
+                Verdict::Ack => (),
+                Verdict::Nak(suggestion) => naks.push(suggestion),
+                Verdict::Reject => rejects.push(option),
+            }
+        }
+        // Nothing that was checked counts if the peer has to send another
+        // request anyway
+        if !rejects.is_empty() {
+            self.peer_acked = false;
+            ControlPacket::new(CONFIGURE_REJECT, packet.id, options_to_bytes(&rejects))
+        } else if !naks.is_empty() {
+            self.peer_acked = false;
+            ControlPacket::new(CONFIGURE_NAK, packet.id, options_to_bytes(&naks))
+        } else {
+            self.peer_acked = true;
+            ControlPacket::new(CONFIGURE_ACK, packet.id, packet.data.clone())
+        }
+    }
+}
+
+/// The LCP options of a PPPoE client. Asks for an MRU and a magic number,
+/// and accepts PAP authentication if it has credentials.
+#[derive(Clone)]
+pub struct LcpOptions {
+    mru: Option<u16>,
+    magic: Option<u32>,
+    pap: bool,
+    peer_mru: u16,
+    auth: Option<u16>,
+    /// Draws the magic numbers
+    rng: XorShiftRng,
+}
+
+impl LcpOptions {
+    /// Creates the options for a link that can receive `mru` bytes, with
+    /// PAP allowed if `pap` is true. The magic numbers are drawn from `rng`,
+    /// normally taken from the generator of the stack.
+    pub fn new(mru: u16, pap: bool, mut rng: XorShiftRng) -> LcpOptions {
+        LcpOptions {
+            mru: Some(mru),
+            magic: Some(rng.gen()),
+            pap: pap,
+            peer_mru: DEFAULT_MRU,
+            auth: None,
+            rng: rng,
+        }
+    }
+
+    /// Our magic number, 0 if the peer rejected it.
+    pub fn magic(&self) -> u32 {
+        self.magic.unwrap_or(0)
+    }
+
+    /// The largest packet the peer can receive.
+    pub fn peer_mru(&self) -> u16 {
+        self.peer_mru
+    }
+
+    /// The protocol the peer wants us to authenticate with.
+    pub fn auth(&self) -> Option<u16> {
+        self.auth
+    }
+}
+
+impl Options for LcpOptions {
+    fn request(&self) -> Vec<ConfigOption> {
+        let mut options = vec![];
+        if let Some(mru) = self.mru {
+            options.push(ConfigOption::u16(LCP_MRU, mru));
+        }
+        if let Some(magic) = self.magic {
+            options.push(ConfigOption::u32(LCP_MAGIC, magic));
+        }
+        options
+    }
+
+    fn check(&mut self, option: &ConfigOption) -> Verdict {
+        match (option.kind, option.as_u16(), option.as_u32()) {
+            (LCP_MRU, Some(mru), _) => {
+                self.peer_mru = mru;
+                Verdict::Ack
+            }
+            (LCP_MAGIC, _, Some(magic)) => {
+                // The same magic number on both ends means the link is
+                // looped back, or just bad luck
+                if Some(magic) == self.magic {
+                    Verdict::Nak(ConfigOption::u32(LCP_MAGIC, self.rng.gen()))
+                } else {
+                    Verdict::Ack
+                }
+            }
+            (LCP_AUTH, _, _) if self.pap => {
+                if option.data == [(PAP >> 8) as u8, PAP as u8] {
+                    self.auth = Some(PAP);
+                    Verdict::Ack
+                } else {
+                    Verdict::Nak(ConfigOption::u16(LCP_AUTH, PAP))
+                }
+            }
+            _ => Verdict::Reject,
+        }
+    }
+
+    fn nak(&mut self, option: &ConfigOption) {
+        match option.kind {
+            LCP_MRU => {
+                if let (Some(mru), Some(suggested)) = (self.mru, option.as_u16()) {
+                    self.mru = Some(cmp::min(mru, suggested));
+                }
+            }
+            LCP_MAGIC => self.magic = Some(self.rng.gen()),
+            _ => (),
+        }
+    }
+
+    fn reject(&mut self, option: &ConfigOption) {
+        match option.kind {
+            LCP_MRU => self.mru = None,
+            LCP_MAGIC => self.magic = None,
+            _ => (),
+        }
+    }
+}
+
+/// The IPCP options of a client. Asks the peer for an address and DNS
+/// servers, and accepts any address for the peer itself.
+#[derive(Debug, Clone)]
+pub struct IpcpOptions {
+    address: Option<Ipv4Addr>,
+    dns: [Option<Ipv4Addr>; 2],
+    peer: Option<Ipv4Addr>,
+}
+
+impl IpcpOptions {
+    pub fn new() -> IpcpOptions {
+        let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+        IpcpOptions {
+            address: Some(unspecified),
+            dns: [Some(unspecified), Some(unspecified)],
+            peer: None,
+        }
+    }
+
+    /// Our address, `None` until the peer assigned one.
+    pub fn address(&self) -> Option<Ipv4Addr> {
+        self.address.and_then(specified)
+    }
+
+    /// The address of the peer, if it told us.
+    pub fn peer(&self) -> Option<Ipv4Addr> {
+        self.peer
+    }
+
+    /// The DNS servers the peer gave us.
+    pub fn dns_servers(&self) -> Vec<Ipv4Addr> {
+        self.dns.iter().filter_map(|dns| dns.and_then(specified)).collect()
+    }
+}
+
+impl Default for IpcpOptions {
+    fn default() -> IpcpOptions {
+        IpcpOptions::new()
+    }
+}
+
+fn specified(ip: Ipv4Addr) -> Option<Ipv4Addr> {
+    if ip == Ipv4Addr::new(0, 0, 0, 0) {
+        None
+    } else {
+        Some(ip)
+    }
+}
+
+impl Options for IpcpOptions {
+    fn request(&self) -> Vec<ConfigOption> {
+        let mut options = vec![];
+        if let Some(address) = self.address {
+            options.push(ConfigOption::ipv4(IPCP_ADDRESS, address));
+        }
+        let kinds = [IPCP_PRIMARY_DNS, IPCP_SECONDARY_DNS];
+        for (&kind, dns) in kinds.iter().zip(self.dns.iter()) {
+            if let Some(dns) = *dns {
+                options.push(ConfigOption::ipv4(kind, dns));
+            }
+        }
+        options
+    }
+
+    fn check(&mut self, option: &ConfigOption) -> Verdict {
+        // We have no address to give the peer if it asks for one
+        match (option.kind, option.as_ipv4().and_then(specified)) {
+            (IPCP_ADDRESS, Some(peer)) => {
+                self.peer = Some(peer);
+                Verdict::Ack
+            }
+            _ => Verdict::Reject,
+        }
+    }
+
+    fn nak(&mut self, option: &ConfigOption) {
+        let ip = match option.as_ipv4() {
+            Some(ip) => ip,
+            None => return,
+        };
+        match option.kind {
+            IPCP_ADDRESS => self.address = Some(ip),
+            IPCP_PRIMARY_DNS => self.dns[0] = Some(ip),
+            IPCP_SECONDARY_DNS => self.dns[1] = Some(ip),
+            _ => (),
+        }
+    }
+
+    fn reject(&mut self, option: &ConfigOption) {
+        match option.kind {
+            IPCP_ADDRESS => self.address = None,
+            IPCP_PRIMARY_DNS => self.dns[0] = None,
+            IPCP_SECONDARY_DNS => self.dns[1] = None,
+            _ => (),
+        }
+    }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+fn write_u32(buffer: &mut [u8], value: u32) {
+    buffer[0] = (value >> 24) as u8;
+    buffer[1] = (value >> 16) as u8;
+    buffer[2] = (value >> 8) as u8;
+    buffer[3] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn rng() -> XorShiftRng {
+        XorShiftRng::from_seed([1, 2, 3, 4])
+    }
+
+    fn configure(code: u8, id: u8, options: &[ConfigOption]) -> ControlPacket {
+        ControlPacket::new(code, id, options_to_bytes(options))
+    }
+
+    #[test]
+    fn control_packet() {
+        let packet = ControlPacket::new(ECHO_REQUEST, 7, vec![1, 2, 3, 4]);
+        let mut data = packet.to_bytes();
+        assert_eq!(&data[..4], &[ECHO_REQUEST, 7, 0, 8]);
+        data.extend_from_slice(&[0, 0]);
+        assert_eq!(ControlPacket::parse(&data), Some(packet));
+        assert_eq!(ControlPacket::parse(&data[..7]), None);
+        assert_eq!(parse_options(&[1, 4, 5, 0xd4, 3]), None);
+    }
+
+    #[test]
+    fn lcp() {
+        let mut testee = Negotiation::new(LcpOptions::new(1492, false, rng()), 7);
+        let request = testee.request();
+        let options = parse_options(&request.data).unwrap();
+        assert_eq!(options[0], ConfigOption::u16(LCP_MRU, 1492));
+
+        // Without credentials authentication is rejected
+        let auth = ConfigOption::u16(LCP_AUTH, PAP);
+        let mru = ConfigOption::u16(LCP_MRU, 1480);
+        let reply = testee.receive(&configure(CONFIGURE_REQUEST, 1, &[mru.clone(), auth.clone()]));
+        assert_eq!(reply, vec![configure(CONFIGURE_REJECT, 1, &[auth])]);
+        let reply = testee.receive(&configure(CONFIGURE_REQUEST, 2, &[mru.clone()]));
+        assert_eq!(reply, vec![configure(CONFIGURE_ACK, 2, &[mru])]);
+        assert_eq!(testee.options().peer_mru(), 1480);
+        assert!(!testee.is_open());
+
+        // The magic number is dropped when rejected
+        let reply = testee.receive(&configure(CONFIGURE_REJECT, request.id, &[options[1].clone()]));
+        assert_eq!(parse_options(&reply[0].data).unwrap(), vec![options[0].clone()]);
+        testee.receive(&configure(CONFIGURE_ACK, request.id, &options));
+        assert!(!testee.is_open());
+        testee.receive(&ControlPacket::new(CONFIGURE_ACK, reply[0].id, reply[0].data.clone()));
+        assert!(testee.is_open());
+        assert_eq!(testee.options().magic(), 0);
+    }
+
+    #[test]
+    fn lcp_pap() {
+        let mut testee = Negotiation::new(LcpOptions::new(1492, true, rng()), 7);
+        let chap = ConfigOption::new(LCP_AUTH, vec![0xc2, 0x23, 5]);
+        let reply = testee.receive(&configure(CONFIGURE_REQUEST, 1, &[chap]));
+        assert_eq!(reply, vec![configure(CONFIGURE_NAK, 1, &[ConfigOption::u16(LCP_AUTH, PAP)])]);
+        let pap = ConfigOption::u16(LCP_AUTH, PAP);
+        testee.receive(&configure(CONFIGURE_REQUEST, 2, &[pap]));
+        assert_eq!(testee.options().auth(), Some(PAP));
+        assert_eq!(pap_request(3, "a", "bc").to_bytes(),
+                   vec![PAP_REQUEST, 3, 0, 9, 1, b'a', 2, b'b', b'c']);
+    }
+
+    #[test]
+    fn ipcp() {
+        let mut testee = Negotiation::new(IpcpOptions::new(), 7);
+        let request = testee.request();
+        assert_eq!(parse_options(&request.data).unwrap().len(), 3);
+
+        let address = Ipv4Addr::new(10, 0, 0, 2);
+        let dns = Ipv4Addr::new(10, 0, 0, 53);
+        let naks = [ConfigOption::ipv4(IPCP_ADDRESS, address),
+                    ConfigOption::ipv4(IPCP_PRIMARY_DNS, dns)];
+        testee.receive(&configure(CONFIGURE_NAK, request.id, &naks));
+        let secondary = ConfigOption::ipv4(IPCP_SECONDARY_DNS, Ipv4Addr::new(0, 0, 0, 0));
+        let id = request.id.wrapping_add(1);
+        let reply = testee.receive(&configure(CONFIGURE_REJECT, id, &[secondary]));
+        assert_eq!(parse_options(&reply[0].data).unwrap(), naks.to_vec());
+        testee.receive(&ControlPacket::new(CONFIGURE_ACK, reply[0].id, reply[0].data.clone()));
+
+        let peer = ConfigOption::ipv4(IPCP_ADDRESS, Ipv4Addr::new(10, 0, 0, 1));
+        let compression = ConfigOption::new(2, vec![0, 0x2d, 0x0f, 1]);
+        let reply = testee.receive(&configure(CONFIGURE_REQUEST, 9, &[peer.clone(), compression]));
+        assert_eq!(reply[0].code, CONFIGURE_REJECT);
+        testee.receive(&configure(CONFIGURE_REQUEST, 10, &[peer]));
+        assert!(testee.is_open());
+        assert_eq!(testee.options().address(), Some(address));
+        assert_eq!(testee.options().peer(), Some(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(testee.options().dns_servers(), vec![dns]);
+    }
+}
@@ -0,0 +1,660 @@
+//! Probing many hosts at once, for finding what is alive on a network.
+//!
+//! `sweep` sends Icmp echo requests, and Arp requests to hosts on the link,
+//! to a list of targets from a thread of its own, and hands back a result
+//! per target as it gets one:
+//!
+//! ```rust,ignore
+//! let targets = probe::hosts(Ipv4Network::from_str("10.0.0.0/24")?);
+//! for result in probe::sweep(stack, local_ip, targets, ProbeConfig::default())? {
+//!     if let Outcome::Alive { rtt, .. } = result.outcome {
+//!         println!("{} is up, {:?}", result.target, rtt);
+//!     }
+//! }
+//! ```
+//!
+//! Up to `ProbeConfig::window` targets are probed at the same time. All
+//! echo requests of a sweep carry the same random identifier, and a new
+//! sequence number each, so replies are told apart from those of other
+//! sweeps and pings. Replies are seen with an observer on the interface of
+//! the local address, see `observe`, which is removed when the sweep is
+//! done.
+
+use {Interface, NetworkStack, StackError, StackResult};
+use icmp::{IcmpTx, PingBuilder};
+use pcap::Direction;
+use shaping::{RateLimit, TokenBucket};
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the thread of a sweep looks for timeouts and resolved
+/// addresses while nothing arrives.
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// How a target was found to be alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// The target answered an Icmp echo request
+    Echo,
+    /// The target answered an Arp request
+    Arp,
+}
+
+/// What became of probing one target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The target answered, after `rtt`. `mac` is the MAC address it
+    /// answered an Arp request from.
+    Alive {
+        method: Method,
+        rtt: Duration,
+        mac: Option<MacAddr>,
+    },
+    /// `from`, usually a router, answered an echo request with destination
+    /// unreachable, of code `code`
+    Unreachable { from: Ipv4Addr, code: u8 },
+    /// Nothing answered any of the attempts
+    TimedOut,
+    /// The target could not be probed, for example because there is no
+    /// route to it, or it is not on the link and only Arp is enabled
+    Unprobed(String),
+}
+
+/// The result of probing one target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeResult {
+    pub target: Ipv4Addr,
+    pub outcome: Outcome,
+    /// How many times the target was probed
+    pub attempts: usize,
+}
+
+/// How a sweep probes its targets.
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    /// Sends Icmp echo requests to all targets
+    pub echo: bool,
+    /// Sends Arp requests to the targets on the link
+    pub arp: bool,
+    /// How long to wait for an answer to each attempt
+    pub timeout: Duration,
+    /// How many more attempts to make after the first one times out
+    pub retries: usize,
+    /// How many targets to probe at the same time
+    pub window: usize,
+    /// Limits how fast attempts are sent, counting them instead of bytes.
+    /// Echo and Arp requests to the same target are one attempt.
+    pub rate: Option<RateLimit>,
+    /// The number of bytes after the header of echo requests
+    pub payload_size: usize,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> ProbeConfig {
+        ProbeConfig {
+            echo: true,
+            arp: true,
+            timeout: Duration::from_secs(1),
+            retries: 1,
+            window: 64,
+            rate: None,
+            payload_size: 56,
+        }
+    }
+}
+
+/// Returns the addresses of the hosts in `net`, all but its network and
+/// broadcast address unless it is a /31 or /32.
+pub fn hosts(net: Ipv4Network) -> Vec<Ipv4Addr> {
+    let first = u32::from(net.network());
+    let last = u32::from(net.broadcast());
+    let (first, last) = if net.prefix() < 31 {
+        (first + 1, last - 1)
+    } else {
+        (first, last)
+    };
+    (first as u64..last as u64 + 1).map(|ip| Ipv4Addr::from(ip as u32)).collect()
+}
+
+/// The results of a sweep started with `sweep`, as an iterator blocking
+/// until the next one is known. Stops the sweep when dropped.
+pub struct Sweep {
+    results: Receiver<ProbeResult>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sweep {
+    /// Returns the result known next, or `None` if there is none yet or the
+    /// sweep is done.
+    pub fn try_next(&self) -> Option<ProbeResult> {
+        self.results.try_recv().ok()
+    }
+
+    /// Stops probing and waits for the thread to quit. Targets without a
+    /// result yet get none.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Iterator for Sweep {
+    type Item = ProbeResult;
+
+    fn next(&mut self) -> Option<ProbeResult> {
+        self.results.recv().ok()
+    }
+}
+
+impl Drop for Sweep {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Starts probing `targets` from the local address `local_ip`. Every
+/// target gets one result, in the order they are known.
+///
+/// Fails with `StackError::NoSourceAddress` if no interface has `local_ip`,
+/// and with `StackError::InvalidArgument` if `config` enables no method or
+/// has a window of zero.
+pub fn sweep<I>(stack: Arc<Mutex<NetworkStack>>,
+                local_ip: Ipv4Addr,
+                targets: I,
+                config: ProbeConfig)
+                -> StackResult<Sweep>
+    where I: IntoIterator<Item = Ipv4Addr>
+{
+    if !config.echo && !config.arp {
+        let msg = "Probing needs echo or Arp requests".to_owned();
+        return Err(StackError::InvalidArgument(msg));
+    }
+    if config.window == 0 {
+        return Err(StackError::InvalidArgument("Probing window is zero".to_owned()));
+    }
+    let (interface, identifier) = {
+        let mut stack = stack.lock().unwrap();
+        (interface_of(&mut stack, local_ip)?, stack.random())
+    };
+    let (events_tx, events) = mpsc::channel();
+    let events_tx = Mutex::new(events_tx);
+    let observer = move |direction: Direction,
+                         _interface: &Interface,
+                         time: SystemTime,
+                         frame: &EthernetPacket| {
+        if direction != Direction::Inbound {
+            return;
+        }
+        if let Some(event) = parse(frame, local_ip, identifier) {
+            events_tx.lock().unwrap().send((time, event)).unwrap_or(());
+        }
+    };
+    let observer_id = stack.lock().unwrap().interface(&interface)?.add_observer(observer);
+    let (results_tx, results) = mpsc::channel();
+    let running = Arc::new(AtomicBool::new(true));
+    let mut prober = Prober {
+        stack: stack,
+        local_ip: local_ip,
+        interface: interface,
+        identifier: identifier,
+        next_sequence: 0,
+        payload: vec![0; config.payload_size],
+        bucket: config.rate.map(TokenBucket::new),
+        ready_at: Instant::now(),
+        config: config,
+        queue: targets.into_iter().collect(),
+        probes: HashMap::new(),
+        sequences: HashMap::new(),
+        events: events,
+        results: results_tx,
+    };
+    let thread_running = running.clone();
+    let thread = thread::spawn(move || {
+        while thread_running.load(Ordering::SeqCst) && prober.step() {}
+        let mut stack = prober.stack.lock().unwrap();
+        if let Ok(stack_interface) = stack.interface(&prober.interface) {
+            stack_interface.remove_observer(observer_id);
+        }
+    });
+    Ok(Sweep {
+        results: results,
+        running: running,
+        thread: Some(thread),
+    })
+}
+
+/// An answer to a probe, seen by the observer of a sweep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Event {
+    Echo { src: Ipv4Addr, sequence: u16 },
+    Arp { src: Ipv4Addr, mac: MacAddr },
+    Unreachable {
+        from: Ipv4Addr,
+        target: Ipv4Addr,
+        sequence: u16,
+        code: u8,
+    },
+}
+
+/// Returns what `frame`, received on the interface of `local_ip`, says
+/// about the probes of a sweep with `identifier`.
+fn parse(frame: &EthernetPacket, local_ip: Ipv4Addr, identifier: u16) -> Option<Event> {
+    let ethertype = frame.get_ethertype();
+    if ethertype == EtherTypes::Arp {
+        return ArpPacket::new(frame.payload()).and_then(|arp_pkg| {
+            if arp_pkg.get_operation() == ArpOperations::Reply &&
+               arp_pkg.get_target_proto_addr() == local_ip {
+                Some(Event::Arp {
+                    src: arp_pkg.get_sender_proto_addr(),
+                    mac: arp_pkg.get_sender_hw_addr(),
+                })
+            } else {
+                None
+            }
+        });
+    }
+    if ethertype != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ip_pkg = match Ipv4Packet::new(frame.payload()) {
+        Some(ip_pkg) => ip_pkg,
+        None => return None,
+    };
+    if ip_pkg.get_destination() != local_ip ||
+       ip_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+        return None;
+    }
+    let icmp = ip_pkg.payload();
+    if icmp.len() < 8 {
+        return None;
+    }
+    if icmp[0] == IcmpTypes::EchoReply.0 && read_u16(icmp, 4) == identifier {
+        return Some(Event::Echo {
+            src: ip_pkg.get_source(),
+            sequence: read_u16(icmp, 6),
+        });
+    }
+    if icmp[0] != IcmpTypes::DestinationUnreachable.0 {
+        return None;
+    }
+    // The header of the request, and the first eight bytes of its payload
+    let quoted = match Ipv4Packet::new(&icmp[8..]) {
+        Some(quoted) => quoted,
+        None => return None,
+    };
+    let header_len = quoted.get_header_length() as usize * 4;
+    let request = &icmp[8..];
+    if quoted.get_source() != local_ip ||
+       quoted.get_next_level_protocol() != IpNextHeaderProtocols::Icmp ||
+       request.len() < header_len + 8 || request[header_len] != IcmpTypes::EchoRequest.0 ||
+       read_u16(request, header_len + 4) != identifier {
+        return None;
+    }
+    Some(Event::Unreachable {
+        from: ip_pkg.get_source(),
+        target: quoted.get_destination(),
+        sequence: read_u16(request, header_len + 6),
+        code: icmp[1],
+    })
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    (data[offset] as u16) << 8 | data[offset + 1] as u16
+}
+
+/// A target being probed.
+struct Probe {
+    attempts: usize,
+    /// When the last attempt was made
+    sent: Instant,
+    /// When the last Arp request went out
+    arp_sent: Option<SystemTime>,
+    /// The sequence numbers used for the target
+    sequences: Vec<u16>,
+    /// Receives the MAC address of the target, or its gateway, once the
+    /// echo request waiting for it can be sent
+    resolving: Option<Receiver<MacAddr>>,
+}
+
+/// The state of a sweep, run by the thread of a `Sweep`.
+struct Prober {
+    stack: Arc<Mutex<NetworkStack>>,
+    local_ip: Ipv4Addr,
+    interface: Interface,
+    config: ProbeConfig,
+    identifier: u16,
+    next_sequence: u16,
+    payload: Vec<u8>,
+    bucket: Option<TokenBucket>,
+    /// When the next attempt may be sent
+    ready_at: Instant,
+    queue: VecDeque<Ipv4Addr>,
+    probes: HashMap<Ipv4Addr, Probe>,
+    /// When each echo request in flight was sent, and to what target
+    sequences: HashMap<u16, (Ipv4Addr, SystemTime)>,
+    events: Receiver<(SystemTime, Event)>,
+    results: Sender<ProbeResult>,
+}
+
+impl Prober {
+    /// Sends what is due and handles the answers arriving until the next
+    /// poll. Returns false once every target has a result.
+    fn step(&mut self) -> bool {
+        let now = Instant::now();
+        let expired: Vec<Ipv4Addr> = self.probes
+            .iter()
+            .filter(|&(_, probe)| now >= probe.sent + self.config.timeout)
+            .map(|(target, _)| *target)
+            .collect();
+        for target in expired {
+            if self.probes[&target].attempts > self.config.retries {
+                self.finish(target, Outcome::TimedOut);
+            } else if Instant::now() >= self.ready_at {
+                self.attempt(target);
+            }
+        }
+        self.send_resolved();
+        while self.probes.len() < self.config.window && Instant::now() >= self.ready_at {
+            match self.queue.pop_front() {
+                Some(target) => self.attempt(target),
+                None => break,
+            }
+        }
+        if self.probes.is_empty() && self.queue.is_empty() {
+            return false;
+        }
+        let timeout = Duration::from_millis(POLL_INTERVAL_MS);
+        match self.events.recv_timeout(timeout) {
+            Ok((time, event)) => self.handle(time, event),
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+        while let Ok((time, event)) = self.events.try_recv() {
+            self.handle(time, event);
+        }
+        true
+    }
+
+    /// Probes `target`, once more if it was before.
+    fn attempt(&mut self, target: Ipv4Addr) {
+        if let Some(bucket) = self.bucket.as_mut() {
+            bucket.take(1);
+            // Taking nothing tells how long until the bucket is out of debt
+            self.ready_at = Instant::now() + bucket.take(0);
+        }
+        {
+            let probe = self.probes.entry(target).or_insert_with(|| {
+                Probe {
+                    attempts: 0,
+                    sent: Instant::now(),
+                    arp_sent: None,
+                    sequences: Vec::new(),
+                    resolving: None,
+                }
+            });
+            probe.attempts += 1;
+            probe.sent = Instant::now();
+        }
+        match self.send(target) {
+            Ok((arp_sent, resolving)) => {
+                let probe = self.probes.get_mut(&target).unwrap();
+                if arp_sent {
+                    probe.arp_sent = Some(SystemTime::now());
+                }
+                if resolving.is_some() {
+                    probe.resolving = resolving;
+                }
+            }
+            Err(msg) => self.finish(target, Outcome::Unprobed(msg)),
+        }
+    }
+
+    /// Sends the requests of one attempt to `target`. Returns if an Arp
+    /// request went out, and the channel receiving the MAC address an echo
+    /// request is waiting for.
+    fn send(&mut self, target: Ipv4Addr) -> Result<(bool, Option<Receiver<MacAddr>>), String> {
+        let stack = self.stack.clone();
+        let mut stack = stack.lock().unwrap();
+        let on_link = match stack.routing_table().route(target) {
+            Some((None, ref interface)) => *interface == self.interface,
+            _ => false,
+        };
+        if !self.config.echo && !on_link {
+            return Err(format!("{} is not on the link of {}", target, self.local_ip));
+        }
+        let mut arp_sent = false;
+        let mut resolving = None;
+        if self.config.echo {
+            match stack.resolve_in_vrf(None, target) {
+                Ok(Some(rx)) => {
+                    // Resolving sent an Arp request already
+                    arp_sent = on_link;
+                    resolving = Some(rx);
+                }
+                Ok(None) => {
+                    if let Err(e) = self.send_echo(&mut stack, target) {
+                        return Err(e.to_string());
+                    }
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        if self.config.arp && on_link && !arp_sent {
+            let mut arp_tx = match stack.interface(&self.interface) {
+                Ok(stack_interface) => stack_interface.arp_request_tx(),
+                Err(e) => return Err(e.to_string()),
+            };
+            if let Err(e) = arp_tx.send(self.local_ip, target) {
+                return Err(e.to_string());
+            }
+            arp_sent = true;
+        }
+        Ok((arp_sent, resolving))
+    }
+
+    fn send_echo(&mut self, stack: &mut NetworkStack, target: Ipv4Addr) -> StackResult<()> {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        let ipv4_tx = stack.ipv4_tx_with_src(self.local_ip, target)?;
+        let mut icmp_tx = IcmpTx::new(ipv4_tx);
+        if let Some(old) = self.sequences.insert(sequence, (target, SystemTime::now())) {
+            debug!("Probe: Sequence number {} of {} reused", sequence, old.0);
+        }
+        if let Some(probe) = self.probes.get_mut(&target) {
+            probe.sequences.push(sequence);
+        }
+        icmp_tx.send(PingBuilder::with_identifier(self.identifier, sequence, &self.payload))?;
+        Ok(())
+    }
+
+    /// Sends the echo requests of the targets whose MAC address, or that of
+    /// their gateway, arrived.
+    fn send_resolved(&mut self) {
+        let resolved: Vec<Ipv4Addr> = self.probes
+            .iter()
+            .filter(|&(_, probe)| match probe.resolving {
+                Some(ref rx) => rx.try_recv() != Err(TryRecvError::Empty),
+                None => false,
+            })
+            .map(|(target, _)| *target)
+            .collect();
+        for target in resolved {
+            self.probes.get_mut(&target).unwrap().resolving = None;
+            let stack = self.stack.clone();
+            let result = self.send_echo(&mut stack.lock().unwrap(), target);
+            if let Err(e) = result {
+                self.finish(target, Outcome::Unprobed(e.to_string()));
+            }
+        }
+    }
+
+    fn handle(&mut self, time: SystemTime, event: Event) {
+        match event {
+            Event::Echo { src, sequence } => {
+                let sent = match self.sequences.get(&sequence) {
+                    Some(&(target, sent)) if target == src => sent,
+                    _ => return,
+                };
+                let outcome = Outcome::Alive {
+                    method: Method::Echo,
+                    rtt: elapsed(sent, time),
+                    mac: None,
+                };
+                self.finish(src, outcome);
+            }
+            Event::Arp { src, mac } => {
+                let sent = match self.probes.get(&src) {
+                    Some(probe) if self.config.arp => probe.arp_sent,
+                    _ => return,
+                };
+                if let Some(sent) = sent {
+                    let outcome = Outcome::Alive {
+                        method: Method::Arp,
+                        rtt: elapsed(sent, time),
+                        mac: Some(mac),
+                    };
+                    self.finish(src, outcome);
+                }
+            }
+            Event::Unreachable { from, target, sequence, code } => {
+                match self.sequences.get(&sequence) {
+                    Some(&(sent_to, _)) if sent_to == target => (),
+                    _ => return,
+                }
+                self.finish(target,
+                            Outcome::Unreachable {
+                                from: from,
+                                code: code,
+                            });
+            }
+        }
+    }
+
+    /// Reports the result of `target` and stops probing it.
+    fn finish(&mut self, target: Ipv4Addr, outcome: Outcome) {
+        let probe = match self.probes.remove(&target) {
+            Some(probe) => probe,
+            None => return,
+        };
+        for sequence in &probe.sequences {
+            self.sequences.remove(sequence);
+        }
+        let result = ProbeResult {
+            target: target,
+            outcome: outcome,
+            attempts: probe.attempts,
+        };
+        // Nothing to do if the sweep is not read any more
+        self.results.send(result).unwrap_or(());
+    }
+}
+
+/// The time from `sent` until `received`, zero if the clock went back.
+fn elapsed(sent: SystemTime, received: SystemTime) -> Duration {
+    received.duration_since(sent).unwrap_or(Duration::new(0, 0))
+}
+
+fn interface_of(stack: &mut NetworkStack, local_ip: Ipv4Addr) -> StackResult<Interface> {
+    for interface in stack.interfaces() {
+        if stack.interface(&interface)?.has_ipv4(local_ip) {
+            return Ok(interface);
+        }
+    }
+    Err(StackError::NoSourceAddress(local_ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ipnetwork::Ipv4Network;
+
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    #[test]
+    fn host_addresses() {
+        let hosts = hosts(Ipv4Network::from_str("10.0.0.0/30").unwrap());
+        assert_eq!(hosts, vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+        let hosts = super::hosts(Ipv4Network::from_str("10.0.0.6/31").unwrap());
+        assert_eq!(hosts, vec![Ipv4Addr::new(10, 0, 0, 6), Ipv4Addr::new(10, 0, 0, 7)]);
+        let hosts = super::hosts(Ipv4Network::from_str("10.0.0.9/32").unwrap());
+        assert_eq!(hosts, vec![Ipv4Addr::new(10, 0, 0, 9)]);
+        assert_eq!(super::hosts(Ipv4Network::from_str("10.0.0.0/16").unwrap()).len(), 65534);
+    }
+
+    /// Returns an Ethernet frame with an IPv4 header from `src` to `dst`,
+    /// carrying `icmp`.
+    fn frame(src: [u8; 4], dst: [u8; 4], icmp: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00, 0x45, 0, 0, 20 + icmp.len() as u8]);
+        frame.extend_from_slice(&[0, 0, 0, 0, 64, 1, 0, 0]);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(icmp);
+        frame
+    }
+
+    #[test]
+    fn events() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let reply = frame([10, 0, 0, 1], [10, 0, 0, 2], &[0, 0, 0, 0, 0x12, 0x34, 0, 7, 1]);
+        let event = parse(&EthernetPacket::new(&reply).unwrap(), local_ip, 0x1234);
+        assert_eq!(event,
+                   Some(Event::Echo {
+                       src: Ipv4Addr::new(10, 0, 0, 1),
+                       sequence: 7,
+                   }));
+        assert_eq!(parse(&EthernetPacket::new(&reply).unwrap(), local_ip, 0x4321), None);
+        let other_ip = Ipv4Addr::new(10, 0, 0, 3);
+        assert_eq!(parse(&EthernetPacket::new(&reply).unwrap(), other_ip, 0x1234), None);
+
+        let request = frame([10, 0, 0, 2], [192, 168, 0, 5], &[8, 0, 0, 0, 0x12, 0x34, 0, 9]);
+        let mut unreachable = vec![3, 1, 0, 0, 0, 0, 0, 0];
+        unreachable.extend_from_slice(&request[14..]);
+        let unreachable = frame([10, 0, 0, 1], [10, 0, 0, 2], &unreachable);
+        let event = parse(&EthernetPacket::new(&unreachable).unwrap(), local_ip, 0x1234);
+        assert_eq!(event,
+                   Some(Event::Unreachable {
+                       from: Ipv4Addr::new(10, 0, 0, 1),
+                       target: Ipv4Addr::new(192, 168, 0, 5),
+                       sequence: 9,
+                       code: 1,
+                   }));
+        let cut = &unreachable[..unreachable.len() - 1];
+        assert_eq!(parse(&EthernetPacket::new(cut).unwrap(), local_ip, 0x1234), None);
+
+        let mut arp_reply = vec![0; 12];
+        arp_reply.extend_from_slice(&[0x08, 0x06, 0, 1, 0x08, 0, 6, 4, 0, 2]);
+        arp_reply.extend_from_slice(&[2, 0, 0, 0, 0, 1, 10, 0, 0, 1]);
+        arp_reply.extend_from_slice(&[2, 0, 0, 0, 0, 2, 10, 0, 0, 2]);
+        let event = parse(&EthernetPacket::new(&arp_reply).unwrap(), local_ip, 0x1234);
+        assert_eq!(event,
+                   Some(Event::Arp {
+                       src: Ipv4Addr::new(10, 0, 0, 1),
+                       mac: MacAddr::new(2, 0, 0, 0, 0, 1),
+                   }));
+    }
+}
@@ -0,0 +1,234 @@
+//! The internet checksum of IPv4, Icmp, UDP and TCP, see RFC 1071.
+//!
+//! `Checksum` adds up the data it is given 32 bits at a time into a 64 bit
+//! sum, and folds that down to 16 bits only once in `finish`. Data can be
+//! added in pieces of any length, such as a pseudo header, a header and a
+//! payload in several parts:
+//!
+//! ```rust,ignore
+//! let mut sum = Checksum::new();
+//! sum.add_pseudo_header(src, dst, IpNextHeaderProtocols::Udp, 8 + payload.len());
+//! sum.add(&header);
+//! sum.add(payload);
+//! let csum = sum.finish();
+//! ```
+//!
+//! When only a few fields of a packet change, like the TTL when forwarding
+//! or the addresses and ports when translating them, `update` adjusts the
+//! checksum from the old and new values of the fields, see RFC 1624, without
+//! reading the rest of the packet.
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+use std::net::Ipv4Addr;
+
+/// A checksum being computed. See the module documentation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checksum {
+    sum: u64,
+    /// The last byte added, if an odd number of bytes were, waiting for the
+    /// byte completing its word
+    odd: Option<u8>,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum::default()
+    }
+
+    /// Adds `data` as if it followed the data added so far.
+    pub fn add(&mut self, data: &[u8]) {
+        let mut data = data;
+        if let Some(high) = self.odd.take() {
+            match data.split_first() {
+                Some((&low, rest)) => {
+                    self.sum += (high as u64) << 8 | low as u64;
+                    data = rest;
+                }
+                None => {
+                    self.odd = Some(high);
+                    return;
+                }
+            }
+        }
+        let whole = data.len() & !3;
+        let mut sum = self.sum;
+        // Two 16 bit words at a time, 2^16 is one in ones' complement. Can't
+        // overflow with less than 16 GiB of data.
+        for word in data[..whole].chunks(4) {
+            sum += (word[0] as u64) << 24 | (word[1] as u64) << 16 | (word[2] as u64) << 8 |
+                   word[3] as u64;
+        }
+        let rest = &data[whole..];
+        if rest.len() >= 2 {
+            sum += (rest[0] as u64) << 8 | rest[1] as u64;
+        }
+        if rest.len() % 2 == 1 {
+            self.odd = Some(rest[rest.len() - 1]);
+        }
+        self.sum = sum;
+    }
+
+    /// Adds the 16 bit word `word`.
+    pub fn add_u16(&mut self, word: u16) {
+        self.add(&[(word >> 8) as u8, word as u8]);
+    }
+
+    /// Adds the pseudo header UDP and TCP checksums include, for a datagram
+    /// or segment of `len` bytes.
+    pub fn add_pseudo_header(&mut self,
+                             src: Ipv4Addr,
+                             dst: Ipv4Addr,
+                             protocol: IpNextHeaderProtocol,
+                             len: usize) {
+        self.add(&src.octets());
+        self.add(&dst.octets());
+        self.add(&[0, protocol.0, (len >> 8) as u8, len as u8]);
+    }
+
+    /// Returns the checksum of the data added, padded with a zero byte if
+    /// its length is odd.
+    pub fn finish(&self) -> u16 {
+        let mut sum = self.sum + self.odd.map_or(0, |high| (high as u64) << 8);
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+}
+
+/// Returns the checksum of `data`. Over a header including a valid checksum
+/// the result is zero.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add(data);
+    sum.finish()
+}
+
+/// Returns `checksum` updated for fields of the data it covers changing from
+/// `old` to `new`. Both have to be of the same length and start at an even
+/// offset into the data.
+///
+/// # Panics
+///
+/// Panics if `old` and `new` are not of the same length.
+pub fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    assert_eq!(old.len(), new.len());
+    // RFC 1624, eqn. 3: ~(~checksum + ~old + new)
+    let mut sum = Checksum::new();
+    sum.add_u16(!checksum);
+    for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+        let old_word = (old[0] as u16) << 8 | old.get(1).map_or(0, |&low| low as u16);
+        sum.add_u16(!old_word);
+        sum.add(new);
+    }
+    sum.finish()
+}
+
+/// Returns true if `datagram`, a whole UDP datagram from `src` to `dst`,
+/// has a valid checksum or none at all. A checksum of zero means the sender
+/// did not compute one.
+pub fn is_valid_udp(datagram: &[u8], src: Ipv4Addr, dst: Ipv4Addr) -> bool {
+    if datagram.len() < 8 {
+        return false;
+    }
+    if datagram[6] == 0 && datagram[7] == 0 {
+        return true;
+    }
+    let mut sum = Checksum::new();
+    sum.add_pseudo_header(src, dst, IpNextHeaderProtocols::Udp, datagram.len());
+    sum.add(datagram);
+    sum.finish() == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::util;
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    #[test]
+    fn same_as_pnet() {
+        for len in 0..40 {
+            let data = data(len);
+            // pnet skips the word at the given index, here past the end
+            assert_eq!(checksum(&data), util::checksum(&data, len / 2), "length {}", len);
+        }
+        let data = data(1500);
+        assert_eq!(checksum(&data), util::checksum(&data, 750));
+    }
+
+    #[test]
+    fn pieces() {
+        let data = data(101);
+        for &(a, b) in &[(0, 101), (1, 3), (3, 4), (50, 51), (5, 100)] {
+            let mut sum = Checksum::new();
+            sum.add(&data[..a]);
+            sum.add(&data[a..b]);
+            sum.add(&data[b..]);
+            assert_eq!(sum.finish(), checksum(&data), "split at {} and {}", a, b);
+        }
+    }
+
+    #[test]
+    fn pseudo_header() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let mut segment = data(25);
+        segment[6] = 0;
+        segment[7] = 0;
+        let mut sum = Checksum::new();
+        sum.add_pseudo_header(src, dst, IpNextHeaderProtocols::Udp, segment.len());
+        sum.add(&segment);
+        assert_eq!(sum.finish(),
+                   util::ipv4_checksum(&segment, 3, &[], src, dst, IpNextHeaderProtocols::Udp));
+    }
+
+    #[test]
+    fn valid_header_sums_to_zero() {
+        let mut header = data(20);
+        header[10] = 0;
+        header[11] = 0;
+        let csum = checksum(&header);
+        header[10] = (csum >> 8) as u8;
+        header[11] = csum as u8;
+        assert_eq!(checksum(&header), 0);
+    }
+
+    #[test]
+    fn udp() {
+        let (src, dst) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let mut datagram = vec![0x30, 0x39, 0, 53, 0, 11, 0, 0, 1, 2, 3];
+        // Sent without a checksum
+        assert!(is_valid_udp(&datagram, src, dst));
+        let mut sum = Checksum::new();
+        sum.add_pseudo_header(src, dst, IpNextHeaderProtocols::Udp, datagram.len());
+        sum.add(&datagram);
+        let csum = sum.finish();
+        datagram[6] = (csum >> 8) as u8;
+        datagram[7] = csum as u8;
+        assert!(is_valid_udp(&datagram, src, dst));
+        datagram[10] ^= 1;
+        assert!(!is_valid_udp(&datagram, src, dst));
+        assert!(!is_valid_udp(&datagram[..7], src, dst));
+    }
+
+    #[test]
+    fn incremental() {
+        let mut packet = data(60);
+        let csum = checksum(&packet);
+        // A TTL decrement, and an address and port changing
+        for &(offset, ref new) in &[(8, vec![0x3f, 6]), (12, vec![192, 168, 1, 1, 0x13, 0x88])] {
+            let old = packet[offset..offset + new.len()].to_vec();
+            packet[offset..offset + new.len()].copy_from_slice(new);
+            assert_eq!(update(csum, &old, new), checksum(&packet));
+            packet[offset..offset + new.len()].copy_from_slice(&old);
+        }
+    }
+}
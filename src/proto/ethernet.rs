@@ -0,0 +1,139 @@
+//! Building Ethernet frames.
+
+use proto::{BasicPayload, HasPayload, Payload};
+
+use pnet::packet::MutablePacket;
+use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+/// Trait for anything wishing to be the payload of an Ethernet frame.
+pub trait EthernetPayload: Payload {
+    fn ether_type(&self) -> EtherType;
+}
+
+
+/// Basic reference implementation of an `EthernetPayload`.
+/// Can be used to construct Ethernet frames with arbitrary payload from a
+/// vector.
+#[derive(Clone)]
+pub struct BasicEthernetPayload<'a> {
+    ether_type: EtherType,
+    payload: BasicPayload<'a>,
+}
+
+impl<'a> BasicEthernetPayload<'a> {
+    pub fn new(ether_type: EtherType, payload: &'a [u8]) -> Self {
+        BasicEthernetPayload {
+            ether_type: ether_type,
+            payload: BasicPayload::new(payload),
+        }
+    }
+}
+
+impl<'a> EthernetPayload for BasicEthernetPayload<'a> {
+    fn ether_type(&self) -> EtherType {
+        self.ether_type
+    }
+}
+
+impl<'a> HasPayload for BasicEthernetPayload<'a> {
+    fn get_payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    fn get_payload_mut(&mut self) -> &mut Payload {
+        &mut self.payload
+    }
+}
+
+/// Struct building Ethernet frames
+pub struct EthernetBuilder<P: EthernetPayload> {
+    src: MacAddr,
+    dst: MacAddr,
+    payload: P,
+}
+
+impl<P: EthernetPayload> EthernetBuilder<P> {
+    /// Creates a new `EthernetBuilder` with the given parameters
+    pub fn new(src: MacAddr, dst: MacAddr, payload: P) -> Self {
+        EthernetBuilder {
+            src: src,
+            dst: dst,
+            payload: payload,
+        }
+    }
+}
+
+impl<P: EthernetPayload> Payload for EthernetBuilder<P> {
+    fn len(&self) -> usize {
+        EthernetPacket::minimum_packet_size() + self.payload.len()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let mut pkg = MutableEthernetPacket::new(buffer).unwrap();
+        pkg.set_source(self.src);
+        pkg.set_destination(self.dst);
+        pkg.set_ethertype(self.payload.ether_type());
+        self.payload.build(pkg.payload_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proto::Payload;
+    use pnet::packet::ethernet::EtherTypes;
+    use super::*;
+
+    #[test]
+    fn ether_type() {
+        let testee = BasicEthernetPayload::new(EtherTypes::Ipv6, &[]);
+        assert_eq!(EtherTypes::Ipv6, testee.ether_type());
+    }
+
+    #[test]
+    fn len_zero() {
+        let testee = BasicEthernetPayload::new(EtherTypes::Arp, &[]);
+        assert_eq!(0, testee.len());
+    }
+
+    #[test]
+    fn len_three() {
+        let data = &[5, 6, 7];
+        let testee = BasicEthernetPayload::new(EtherTypes::Arp, data);
+        assert_eq!(3, testee.len());
+    }
+
+    #[test]
+    fn build_without_data() {
+        let mut testee = BasicEthernetPayload::new(EtherTypes::Arp, &[]);
+        let mut buffer = vec![99; 1];
+        testee.build(&mut buffer);
+        assert_eq!(99, buffer[0]);
+    }
+
+    #[test]
+    fn build_with_data() {
+        let data = &[5, 6, 7];
+        let mut testee = BasicEthernetPayload::new(EtherTypes::Arp, data);
+        let mut buffer = vec![0; 1];
+        testee.build(&mut buffer[0..0]);
+
+        testee.build(&mut buffer);
+        assert_eq!(5, buffer[0]);
+        testee.build(&mut buffer);
+        assert_eq!(6, buffer[0]);
+        testee.build(&mut buffer);
+        assert_eq!(7, buffer[0]);
+
+        testee.build(&mut buffer[0..0]);
+    }
+
+    #[test]
+    fn build_with_larger_buffer() {
+        let data = &[5, 6];
+        let mut testee = BasicEthernetPayload::new(EtherTypes::Arp, data);
+        let mut buffer = vec![0; 3];
+        testee.build(&mut buffer);
+        assert_eq!(&[5, 6, 0], &buffer[..]);
+    }
+}
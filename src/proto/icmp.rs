@@ -0,0 +1,145 @@
+//! Building Icmp packets.
+
+use proto::{BasicPayload, HasPayload, Payload};
+use proto::checksum;
+use proto::ipv4::Ipv4Payload;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::icmp::{IcmpCode, IcmpType, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::icmp::echo_request::IcmpCodes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+/// Trait for anything wishing to be the payload of an Icmp packet.
+pub trait IcmpPayload: Payload {
+    fn icmp_type(&self) -> IcmpType;
+
+    fn icmp_code(&self) -> IcmpCode;
+
+    fn build_header(&self, header: &mut MutableIcmpPacket);
+}
+
+#[derive(Clone)]
+pub struct BasicIcmpPayload<'a> {
+    icmp_type: IcmpType,
+    icmp_code: IcmpCode,
+    payload: BasicPayload<'a>,
+}
+
+impl<'a> BasicIcmpPayload<'a> {
+    pub fn new(icmp_type: IcmpType, icmp_code: IcmpCode, payload: &'a [u8]) -> Self {
+        BasicIcmpPayload {
+            icmp_type: icmp_type,
+            icmp_code: icmp_code,
+            payload: BasicPayload::new(payload),
+        }
+    }
+}
+
+impl<'a> IcmpPayload for BasicIcmpPayload<'a> {
+    fn icmp_type(&self) -> IcmpType {
+        self.icmp_type
+    }
+
+    fn icmp_code(&self) -> IcmpCode {
+        self.icmp_code
+    }
+
+    fn build_header(&self, _header: &mut MutableIcmpPacket) {}
+}
+
+impl<'a> HasPayload for BasicIcmpPayload<'a> {
+    fn get_payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    fn get_payload_mut(&mut self) -> &mut Payload {
+        &mut self.payload
+    }
+}
+
+
+pub struct IcmpBuilder<P: IcmpPayload> {
+    builder: P,
+}
+
+impl<P: IcmpPayload> IcmpBuilder<P> {
+    pub fn new(builder: P) -> IcmpBuilder<P> {
+        IcmpBuilder { builder: builder }
+    }
+}
+
+impl<P: IcmpPayload> Ipv4Payload for IcmpBuilder<P> {
+    fn next_level_protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Icmp
+    }
+}
+
+impl<P: IcmpPayload> Payload for IcmpBuilder<P> {
+    fn len(&self) -> usize {
+        8 + self.builder.len()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let mut pkg = MutableIcmpPacket::new(buffer).unwrap();
+        {
+            let mut header_pkg = MutableIcmpPacket::new(&mut pkg.packet_mut()[..8]).unwrap();
+            header_pkg.set_icmp_type(self.builder.icmp_type());
+            header_pkg.set_icmp_code(self.builder.icmp_code());
+            self.builder.build_header(&mut header_pkg);
+        }
+        self.builder.build(&mut pkg.packet_mut()[8..]);
+        pkg.set_checksum(0);
+        let checksum = checksum::checksum(pkg.packet());
+        pkg.set_checksum(checksum);
+    }
+}
+
+pub struct PingBuilder<'a> {
+    identifier: u16,
+    sequence: u16,
+    payload: BasicPayload<'a>,
+}
+
+impl<'a> PingBuilder<'a> {
+    pub fn new(payload: &'a [u8]) -> PingBuilder<'a> {
+        Self::with_identifier(0, 0, payload)
+    }
+
+    /// Same as `new`, but with the identifier and sequence number replies
+    /// are matched to the request by.
+    pub fn with_identifier(identifier: u16, sequence: u16, payload: &'a [u8]) -> PingBuilder<'a> {
+        PingBuilder {
+            identifier: identifier,
+            sequence: sequence,
+            payload: BasicPayload::new(payload),
+        }
+    }
+}
+
+impl<'a> IcmpPayload for PingBuilder<'a> {
+    fn icmp_type(&self) -> IcmpType {
+        IcmpTypes::EchoRequest
+    }
+
+    fn icmp_code(&self) -> IcmpCode {
+        IcmpCodes::NoCode
+    }
+
+    fn build_header(&self, header: &mut MutableIcmpPacket) {
+        let rest = header.payload_mut();
+        rest[0] = (self.identifier >> 8) as u8;
+        rest[1] = self.identifier as u8;
+        rest[2] = (self.sequence >> 8) as u8;
+        rest[3] = self.sequence as u8;
+    }
+}
+
+impl<'a> HasPayload for PingBuilder<'a> {
+    fn get_payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    fn get_payload_mut(&mut self) -> &mut Payload {
+        &mut self.payload
+    }
+}
@@ -0,0 +1,142 @@
+//! Building IPv4 packets, fragmenting them if they don't fit the buffers
+//! they are built into.
+
+use proto::{BasicPayload, HasPayload, Payload};
+use proto::checksum;
+use proto::ethernet::EthernetPayload;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherType, EtherTypes};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+
+use std::net::Ipv4Addr;
+
+pub const MORE_FRAGMENTS: u8 = 0b001;
+pub const DONT_FRAGMENT: u8 = 0b010;
+pub const NO_FLAGS: u8 = 0b000;
+
+/// Time to live of the packets sent, unless set otherwise.
+pub const DEFAULT_TTL: u8 = 40;
+
+pub trait Ipv4Payload: Payload {
+    fn next_level_protocol(&self) -> IpNextHeaderProtocol;
+}
+
+#[derive(Clone)]
+pub struct BasicIpv4Payload<'a> {
+    next_level_protocol: IpNextHeaderProtocol,
+    payload: BasicPayload<'a>,
+}
+
+impl<'a> BasicIpv4Payload<'a> {
+    pub fn new(next_level_protocol: IpNextHeaderProtocol, payload: &'a [u8]) -> Self {
+        BasicIpv4Payload {
+            next_level_protocol: next_level_protocol,
+            payload: BasicPayload::new(payload),
+        }
+    }
+}
+
+impl<'a> Ipv4Payload for BasicIpv4Payload<'a> {
+    fn next_level_protocol(&self) -> IpNextHeaderProtocol {
+        self.next_level_protocol
+    }
+}
+
+impl<'a> HasPayload for BasicIpv4Payload<'a> {
+    fn get_payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    fn get_payload_mut(&mut self) -> &mut Payload {
+        &mut self.payload
+    }
+}
+
+
+pub struct Ipv4Builder<P: Ipv4Payload> {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    offset: usize,
+    identification: u16,
+    ttl: u8,
+    tos: u8,
+    payload: P,
+    payload_len: usize,
+}
+
+impl<P: Ipv4Payload> Ipv4Builder<P> {
+    pub fn new(src: Ipv4Addr, dst: Ipv4Addr, identification: u16, payload: P) -> Self {
+        let payload_len = payload.len();
+        Ipv4Builder {
+            src: src,
+            dst: dst,
+            offset: 0,
+            identification: identification,
+            ttl: DEFAULT_TTL,
+            tos: 0,
+            payload: payload,
+            payload_len: payload_len,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    /// Sets the type of service byte, the DSCP in the upper six bits and the
+    /// ECN in the lower two.
+    pub fn set_tos(&mut self, tos: u8) {
+        self.tos = tos;
+    }
+}
+
+impl<P: Ipv4Payload> EthernetPayload for Ipv4Builder<P> {
+    fn ether_type(&self) -> EtherType {
+        EtherTypes::Ipv4
+    }
+}
+
+impl<P: Ipv4Payload> Payload for Ipv4Builder<P> {
+    fn len(&self) -> usize {
+        Ipv4Packet::minimum_packet_size() + self.payload.len()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let mut pkg = MutableIpv4Packet::new(buffer).expect("Too small buffer given");
+        pkg.set_version(4);
+        // https://en.wikipedia.org/wiki/Differentiated_services
+        pkg.set_dscp(self.tos >> 2);
+        // https://en.wikipedia.org/wiki/Explicit_Congestion_Notification
+        pkg.set_ecn(self.tos & 0b11);
+        pkg.set_ttl(self.ttl);
+        // ip_pkg.set_options(vec![]); // We currently don't support options
+        pkg.set_header_length(5); // 5 is for no option fields
+        pkg.set_identification(self.identification);
+        pkg.set_source(self.src);
+        pkg.set_destination(self.dst);
+        pkg.set_fragment_offset((self.offset / 8) as u16);
+
+        let bytes_remaining = self.payload_len - self.offset;
+        let bytes_max = pkg.payload().len();
+        let payload_size = if bytes_remaining <= bytes_max {
+            pkg.set_flags(NO_FLAGS);
+            bytes_remaining
+        } else {
+            pkg.set_flags(MORE_FRAGMENTS);
+            bytes_max & !0b111 // Round down to divisable by 8
+        };
+        let total_length = payload_size + Ipv4Packet::minimum_packet_size();
+        pkg.set_total_length(total_length as u16);
+
+        pkg.set_next_level_protocol(self.payload.next_level_protocol());
+        self.payload.build(&mut pkg.payload_mut()[..payload_size]);
+
+        pkg.set_checksum(0);
+        let checksum = checksum::checksum(&pkg.packet()[..Ipv4Packet::minimum_packet_size()]);
+        pkg.set_checksum(checksum);
+
+        self.offset += payload_size;
+    }
+}
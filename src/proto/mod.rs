@@ -0,0 +1,142 @@
+//! The protocol core of rips: building packets, with no threads, locks or
+//! channels involved. Everything here works on buffers
+//! given by the caller, so it can run in a single loop on a target without
+//! an OS scheduler, talking to the link through a `FrameDriver`.
+//!
+//! The threaded `NetworkStack` is one frontend on top of it. Its tx-objects
+//! build their packets with the builders here, and `datalink::DriverDatalink`
+//! makes a `FrameDriver` a link the stack can send and receive on.
+//!
+//! This is a first step towards a `no_std` core, and it only covers the
+//! builders, of Ethernet, Arp, IPv4, UDP and Icmp. They are still on top of
+//! the packet types of `pnet`, which needs `std`, as does the `std::io` of
+//! `FrameDriver`. The parsing of received packets and the protocol state
+//! machines live with the rx-objects of the stack.
+//!
+//! ```rust,ignore
+//! let mut buffer = [0; 1514];
+//! let request = ArpBuilder::new_request(driver.mac(), local_ip, target_ip);
+//! proto::send_frame(&mut driver, broadcast_mac, request, &mut buffer)?;
+//! ```
+
+use pnet::util::MacAddr;
+
+use std::io;
+
+pub mod arp;
+pub mod checksum;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod udp;
+
+mod payload;
+pub use self::payload::{Payload, BasicPayload, HasPayload, VectoredPayload};
+
+use self::ethernet::{EthernetBuilder, EthernetPayload};
+
+/// A link sending and receiving whole Ethernet frames, provided by the user
+/// of the protocol core. Unlike `datalink::Datalink` it does not have to be
+/// shared between threads, it's only ever called by its owner.
+pub trait FrameDriver {
+    /// The MAC address of the link.
+    fn mac(&self) -> MacAddr;
+
+    /// The MTU of the link, if the driver knows it.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// Sends the whole Ethernet frame `frame`.
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Receives the next Ethernet frame into `buffer` and returns its length.
+    /// Fails with `io::ErrorKind::WouldBlock` right away when no frame is
+    /// waiting.
+    fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Builds an Ethernet frame with `payload` from the MAC address of `driver`
+/// to `dst` in the start of `buffer`, and sends it. Returns the length of the
+/// frame, or an `io::ErrorKind::InvalidInput` error if `buffer` can't hold it.
+pub fn send_frame<D, P>(driver: &mut D,
+                        dst: MacAddr,
+                        payload: P,
+                        buffer: &mut [u8])
+                        -> io::Result<usize>
+    where D: FrameDriver + ?Sized,
+          P: EthernetPayload
+{
+    let mut builder = EthernetBuilder::new(driver.mac(), dst, payload);
+    let len = builder.len();
+    if len > buffer.len() {
+        let msg = format!("Frame of {} bytes does not fit the buffer", len);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    builder.build(&mut buffer[..len]);
+    driver.send(&buffer[..len])?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::Packet;
+    use pnet::packet::arp::{ArpOperations, ArpPacket};
+    use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use super::arp::ArpBuilder;
+
+    static BROADCAST_MAC: MacAddr = MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+    struct MockDriver {
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl FrameDriver for MockDriver {
+        fn mac(&self) -> MacAddr {
+            MacAddr::new(2, 0, 0, 0, 0, 1)
+        }
+
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.sent.push(frame.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self, _buffer: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "No frame"))
+        }
+    }
+
+    #[test]
+    fn send_arp_request() {
+        let mut driver = MockDriver { sent: vec![] };
+        let mut buffer = [0; 100];
+        let (local, target) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let request = ArpBuilder::new_request(driver.mac(), local, target);
+        let len = send_frame(&mut driver, BROADCAST_MAC, request, &mut buffer).unwrap();
+        assert_eq!(len, 14 + 28);
+        assert_eq!(driver.sent.len(), 1);
+
+        let frame = EthernetPacket::new(&driver.sent[0]).unwrap();
+        assert_eq!(frame.get_source(), MacAddr::new(2, 0, 0, 0, 0, 1));
+        assert_eq!(frame.get_destination(), BROADCAST_MAC);
+        assert_eq!(frame.get_ethertype(), EtherTypes::Arp);
+        let arp = ArpPacket::new(frame.payload()).unwrap();
+        assert_eq!(arp.get_operation(), ArpOperations::Request);
+        assert_eq!(arp.get_target_proto_addr(), target);
+    }
+
+    #[test]
+    fn send_too_large() {
+        let mut driver = MockDriver { sent: vec![] };
+        let mut buffer = [0; 20];
+        let request = ArpBuilder::new_request(driver.mac(), Ipv4Addr::new(10, 0, 0, 1),
+                                              Ipv4Addr::new(10, 0, 0, 2));
+        let result = send_frame(&mut driver, BROADCAST_MAC, request, &mut buffer);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(driver.sent.is_empty());
+    }
+}
@@ -57,6 +57,55 @@ impl<'a> Payload for BasicPayload<'a> {
     }
 }
 
+/// A payload made of several buffers one after the other, such as a header
+/// and a body kept apart by the application. They are copied straight into
+/// the packet, without being concatenated first. `parts` can be anything
+/// holding the buffers, like `[&[u8]; 2]` or `&[&[u8]]`.
+#[derive(Clone)]
+pub struct VectoredPayload<T> {
+    parts: T,
+    /// The part being built, and the bytes of it built already
+    part: usize,
+    offset: usize,
+}
+
+impl<'a, T: AsRef<[&'a [u8]]>> VectoredPayload<T> {
+    pub fn new(parts: T) -> Self {
+        VectoredPayload {
+            parts: parts,
+            part: 0,
+            offset: 0,
+        }
+    }
+
+    pub fn parts(&self) -> &[&'a [u8]] {
+        self.parts.as_ref()
+    }
+}
+
+impl<'a, T: AsRef<[&'a [u8]]>> Payload for VectoredPayload<T> {
+    fn len(&self) -> usize {
+        self.parts().iter().map(|part| part.len()).sum()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let mut built = 0;
+        let parts = self.parts.as_ref();
+        while built < buffer.len() && self.part < parts.len() {
+            let part = &parts[self.part][self.offset..];
+            let len = cmp::min(part.len(), buffer.len() - built);
+            buffer[built..built + len].copy_from_slice(&part[..len]);
+            built += len;
+            if len == part.len() {
+                self.part += 1;
+                self.offset = 0;
+            } else {
+                self.offset += len;
+            }
+        }
+    }
+}
+
 pub trait HasPayload {
     fn get_payload(&self) -> &Payload;
     fn get_payload_mut(&mut self) -> &mut Payload;
@@ -73,3 +122,24 @@ impl<T> Payload for T
         self.get_payload_mut().build(buffer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectored_payload() {
+        let parts: [&[u8]; 3] = [&[1, 2, 3], &[], &[4, 5, 6, 7]];
+        let mut payload = VectoredPayload::new(parts);
+        assert_eq!(payload.len(), 7);
+        let mut buffer = [0; 5];
+        payload.build(&mut buffer);
+        assert_eq!(buffer, [1, 2, 3, 4, 5]);
+        payload.build(&mut buffer);
+        assert_eq!(buffer, [6, 7, 3, 4, 5]);
+        // Everything was built, nothing more is written
+        payload.build(&mut buffer);
+        assert_eq!(buffer, [6, 7, 3, 4, 5]);
+        assert_eq!(payload.len(), 7);
+    }
+}
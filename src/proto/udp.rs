@@ -0,0 +1,147 @@
+//! Building UDP datagrams.
+
+use proto::{Payload, VectoredPayload};
+use proto::checksum::Checksum;
+use proto::ipv4::Ipv4Payload;
+
+use pnet::packet::Packet;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
+
+use std::net::SocketAddrV4;
+
+/// Builds a UDP datagram. The payload is one buffer, or several ones sent
+/// one after the other if the builder is made with `vectored`.
+pub struct UdpBuilder<T> {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    header_sent: bool,
+    payload: VectoredPayload<T>,
+}
+
+impl<'a> UdpBuilder<[&'a [u8]; 1]> {
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, payload: &'a [u8]) -> Self {
+        UdpBuilder::vectored(src, dst, [payload])
+    }
+}
+
+impl<'a, T: AsRef<[&'a [u8]]>> UdpBuilder<T> {
+    /// Same as `new`, with the payload made of all of `parts`.
+    pub fn vectored(src: SocketAddrV4, dst: SocketAddrV4, parts: T) -> Self {
+        UdpBuilder {
+            src: src,
+            dst: dst,
+            header_sent: false,
+            payload: VectoredPayload::new(parts),
+        }
+    }
+}
+
+impl<'a, T: AsRef<[&'a [u8]]>> Ipv4Payload for UdpBuilder<T> {
+    fn next_level_protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Udp
+    }
+}
+
+impl<'a, T: AsRef<[&'a [u8]]>> Payload for UdpBuilder<T> {
+    fn len(&self) -> usize {
+        UdpPacket::minimum_packet_size() + self.payload.len()
+    }
+
+    fn build(&mut self, buffer: &mut [u8]) {
+        let payload_buffer = if !self.header_sent {
+            self.header_sent = true;
+            {
+                let header_buffer = &mut buffer[..UdpPacket::minimum_packet_size()];
+                let mut pkg = MutableUdpPacket::new(header_buffer).unwrap();
+                pkg.set_source(self.src.port());
+                pkg.set_destination(self.dst.port());
+                pkg.set_length(self.len() as u16);
+                pkg.set_checksum(0);
+                let mut checksum = Checksum::new();
+                checksum.add_pseudo_header(*self.src.ip(),
+                                           *self.dst.ip(),
+                                           IpNextHeaderProtocols::Udp,
+                                           self.len());
+                checksum.add(pkg.packet());
+                for part in self.payload.parts() {
+                    checksum.add(part);
+                }
+                pkg.set_checksum(checksum.finish());
+            }
+            &mut buffer[UdpPacket::minimum_packet_size()..]
+        } else {
+            buffer
+        };
+        self.payload.build(payload_buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::Packet;
+    use pnet::packet::udp::{UdpPacket, ipv4_checksum};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use super::*;
+
+    lazy_static! {
+        static ref ADDR1: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 99, 250, 15), 8080);
+        static ref ADDR2: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 105), 22);
+    }
+
+    #[test]
+    #[should_panic]
+    fn udp_builder_too_short() {
+        let mut buffer = vec![0; 7];
+        let mut builder = UdpBuilder::new(*ADDR1, *ADDR2, &[]);
+        builder.build(&mut buffer);
+    }
+
+    #[test]
+    fn udp_builder_header() {
+        let mut buffer = vec![0; 1000];
+        let data = &[3, 2];
+        let mut builder = UdpBuilder::new(*ADDR1, *ADDR2, data);
+        builder.build(&mut buffer);
+
+        let pkg = UdpPacket::new(&buffer).unwrap();
+        assert_eq!(ADDR1.port(), pkg.get_source());
+        assert_eq!(ADDR2.port(), pkg.get_destination());
+        assert_eq!(10, pkg.get_length());
+        assert_eq!(5806, pkg.get_checksum());
+        assert_eq!([3, 2], pkg.payload()[0..2]);
+    }
+
+    #[test]
+    fn udp_builder_two_build_calls() {
+        let mut buffer = vec![0; 8];
+        let data = &[11, 12, 13, 14, 15, 16, 17, 18, 19];
+        let mut builder = UdpBuilder::new(*ADDR1, *ADDR2, data);
+        // Build header, but we don't test that here
+        builder.build(&mut buffer);
+
+        // Build first 8 bytes of payload
+        builder.build(&mut buffer);
+        assert_eq!([11, 12, 13, 14, 15, 16, 17, 18], buffer[..]);
+
+        // Build last payload byte
+        builder.build(&mut buffer);
+        assert_eq!([19], buffer[..1]);
+    }
+
+    #[test]
+    fn udp_builder_vectored() {
+        let mut buffer = vec![0; 1000];
+        let parts: [&[u8]; 3] = [&[1, 2, 3], &[4], &[5, 6]];
+        let mut builder = UdpBuilder::vectored(*ADDR1, *ADDR2, &parts[..]);
+        assert_eq!(builder.len(), 8 + 6);
+        builder.build(&mut buffer);
+
+        let mut expected = vec![0; 1000];
+        UdpBuilder::new(*ADDR1, *ADDR2, &[1, 2, 3, 4, 5, 6]).build(&mut expected);
+        assert_eq!(buffer, expected);
+        let pkg = UdpPacket::new(&buffer[..14]).unwrap();
+        let checksum = ipv4_checksum(&pkg, *ADDR1.ip(), *ADDR2.ip());
+        assert_eq!(checksum, pkg.get_checksum());
+    }
+}
@@ -0,0 +1,531 @@
+//! Priority queueing of the frames sent out on an interface. With a
+//! `QosConfig` set by `StackInterface::set_qos`, sending a frame only puts it
+//! in one of several egress queues, picked by the classifier of the config.
+//! A scheduler thread sends the queued frames on in the order given by the
+//! `Scheduling`, so control traffic such as Arp, ICMP and TCP
+//! acknowledgements is not stuck behind bulk data.
+//!
+//! Queue 0 has the highest priority. A rate limit set on the interface is
+//! applied by the scheduler instead of the senders, so that frames in higher
+//! priority queues can overtake the ones already waiting.
+//!
+//! With the single queue of `QosConfig::fifo` the scheduler thread is just a
+//! dedicated TX thread: senders only copy their frames into the queue, and
+//! slow writes to the datalink hold up the scheduler instead of them. When
+//! the queue is full they wait for room, or fail with `TxError::WouldBlock`
+//! in non-blocking mode.
+
+use pnet::datalink::{EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use pool::{BufferPool, PooledBuffer};
+use reactor::Writability;
+use shaping::Shaper;
+use threads::ThreadConfig;
+use vlan;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+
+/// Number of frames each queue holds by default, the same as the default
+/// `txqueuelen` of Linux.
+pub static DEFAULT_QUEUE_LEN: usize = 1000;
+
+/// Picks the queue of an ethernet frame, given the number of queues.
+pub type Classifier = fn(&[u8], usize) -> usize;
+
+/// In which order frames are taken from the queues.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scheduling {
+    /// The given number of queues, always sending from the highest priority
+    /// one that is not empty.
+    Strict(usize),
+    /// Weighted round robin, one queue per weight. Each round sends up to
+    /// as many frames from a queue as its weight.
+    Weighted(Vec<usize>),
+}
+
+pub struct QosConfig {
+    pub scheduling: Scheduling,
+    /// Maximum number of frames in each queue. Frames sent while their queue
+    /// is full are dropped, or fail with `TxError::WouldBlock` on interfaces
+    /// in non-blocking mode.
+    pub queue_len: usize,
+    pub classifier: Classifier,
+    /// Makes senders wait for room in a full queue instead of dropping their
+    /// frame. Interfaces in non-blocking mode fail with `TxError::WouldBlock`
+    /// either way.
+    pub backpressure: bool,
+}
+
+impl QosConfig {
+    /// Strict priority between `queues` queues, classified by `classify`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `queues` is zero.
+    pub fn strict(queues: usize) -> QosConfig {
+        assert!(queues > 0, "Need at least one queue");
+        QosConfig {
+            scheduling: Scheduling::Strict(queues),
+            queue_len: DEFAULT_QUEUE_LEN,
+            classifier: classify,
+            backpressure: false,
+        }
+    }
+
+    /// A single queue with backpressure, sending frames in the order they
+    /// were sent from a thread of its own.
+    pub fn fifo() -> QosConfig {
+        QosConfig { backpressure: true, ..QosConfig::strict(1) }
+    }
+
+    /// Weighted round robin between one queue per weight, classified by
+    /// `classify`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weights` is empty or contains a zero.
+    pub fn weighted(weights: Vec<usize>) -> QosConfig {
+        assert!(!weights.is_empty(), "Need at least one queue");
+        assert!(weights.iter().all(|&weight| weight > 0), "Weights must not be zero");
+        QosConfig {
+            scheduling: Scheduling::Weighted(weights),
+            queue_len: DEFAULT_QUEUE_LEN,
+            classifier: classify,
+            backpressure: false,
+        }
+    }
+
+    /// Returns the number of queues.
+    pub fn queues(&self) -> usize {
+        match self.scheduling {
+            Scheduling::Strict(queues) => queues,
+            Scheduling::Weighted(ref weights) => weights.len(),
+        }
+    }
+}
+
+impl Clone for QosConfig {
+    fn clone(&self) -> QosConfig {
+        QosConfig {
+            scheduling: self.scheduling.clone(),
+            queue_len: self.queue_len,
+            classifier: self.classifier,
+            backpressure: self.backpressure,
+        }
+    }
+}
+
+/// The default `Classifier`. Arp, ICMP and TCP segments without data, such
+/// as SYNs and pure acknowledgements, go to the highest priority queue. Other
+/// IPv4 packets are spread over the queues by the precedence bits of their
+/// DSCP, and everything else goes to the lowest priority queue. VLAN tagged
+/// frames are classified by what they carry.
+pub fn classify(frame: &[u8], queues: usize) -> usize {
+    let lowest = queues.saturating_sub(1);
+    let untagged;
+    let frame = match vlan::untag(frame) {
+        Some((_, frame)) => {
+            untagged = frame;
+            &untagged[..]
+        }
+        None => frame,
+    };
+    let eth_pkg = match EthernetPacket::new(frame) {
+        Some(eth_pkg) => eth_pkg,
+        None => return lowest,
+    };
+    match eth_pkg.get_ethertype() {
+        EtherTypes::Arp => return 0,
+        EtherTypes::Ipv4 => (),
+        _ => return lowest,
+    }
+    let ip_pkg = match Ipv4Packet::new(eth_pkg.payload()) {
+        Some(ip_pkg) => ip_pkg,
+        None => return lowest,
+    };
+    if is_control(&ip_pkg) {
+        return 0;
+    }
+    let precedence = (ip_pkg.get_dscp() >> 3) as usize;
+    cmp::min((7 - precedence) * queues / 8, lowest)
+}
+
+fn is_control(ip_pkg: &Ipv4Packet) -> bool {
+    let protocol = ip_pkg.get_next_level_protocol();
+    if protocol == IpNextHeaderProtocols::Icmp {
+        return true;
+    }
+    if protocol != IpNextHeaderProtocols::Tcp || ip_pkg.get_fragment_offset() != 0 {
+        return false;
+    }
+    let header_len = ip_pkg.get_header_length() as usize * 4;
+    let total_len = ip_pkg.get_total_length() as usize;
+    let packet = ip_pkg.packet();
+    if total_len > packet.len() || total_len < header_len + 20 {
+        return false;
+    }
+    let data_offset = (packet[header_len + 12] >> 4) as usize * 4;
+    total_len == header_len + data_offset
+}
+
+/// Counters for one queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Frames waiting in the queue right now
+    pub queued: usize,
+    pub sent: usize,
+    /// Frames dropped because the queue was full
+    pub dropped: usize,
+}
+
+/// Picks the queue to send the next frame from.
+struct Scheduler {
+    scheduling: Scheduling,
+    current: usize,
+    /// Frames the current queue may still send this round
+    credit: usize,
+}
+
+impl Scheduler {
+    fn new(scheduling: Scheduling) -> Scheduler {
+        let credit = match scheduling {
+            Scheduling::Strict(..) => 0,
+            Scheduling::Weighted(ref weights) => weights[0],
+        };
+        Scheduler {
+            scheduling: scheduling,
+            current: 0,
+            credit: credit,
+        }
+    }
+
+    fn next<T>(&mut self, frames: &[VecDeque<T>]) -> Option<usize> {
+        if frames.iter().all(|queue| queue.is_empty()) {
+            return None;
+        }
+        match self.scheduling {
+            Scheduling::Strict(..) => frames.iter().position(|queue| !queue.is_empty()),
+            Scheduling::Weighted(ref weights) => {
+                while self.credit == 0 || frames[self.current].is_empty() {
+                    self.current = (self.current + 1) % weights.len();
+                    self.credit = weights[self.current];
+                }
+                self.credit -= 1;
+                Some(self.current)
+            }
+        }
+    }
+}
+
+struct Queues {
+    frames: Vec<VecDeque<PooledBuffer>>,
+    stats: Vec<QueueStats>,
+    /// Set when the scheduler should send what is left and stop
+    closed: bool,
+    /// If senders wait for room in a full queue, see `set_waiting`
+    waiting: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    ready: Condvar,
+    /// Notified whenever a frame leaves the queues
+    room: Condvar,
+}
+
+/// The egress queues of an interface and the thread scheduling them.
+pub struct EgressQueues {
+    config: QosConfig,
+    shared: Arc<Shared>,
+    pool: BufferPool,
+    thread: Option<JoinHandle<Box<EthernetDataLinkSender>>>,
+}
+
+impl EgressQueues {
+    /// Creates empty queues. Nothing is sent until `start` is called.
+    pub fn new(config: QosConfig) -> EgressQueues {
+        Self::with_pool(config, BufferPool::default())
+    }
+
+    /// Same as `new`, but holds the queued frames in buffers from `pool`.
+    pub fn with_pool(config: QosConfig, pool: BufferPool) -> EgressQueues {
+        let queues = config.queues();
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Queues {
+                frames: (0..queues).map(|_| VecDeque::new()).collect(),
+                stats: vec![QueueStats::default(); queues],
+                closed: false,
+                waiting: config.backpressure,
+            }),
+            ready: Condvar::new(),
+            room: Condvar::new(),
+        });
+        EgressQueues {
+            config: config,
+            shared: shared,
+            pool: pool,
+            thread: None,
+        }
+    }
+
+    pub fn config(&self) -> &QosConfig {
+        &self.config
+    }
+
+    /// Returns an `EthernetDataLinkSender` putting the frames sent to it in
+    /// these queues.
+    pub fn sender(&self) -> Box<EthernetDataLinkSender> {
+        Box::new(QueueSender {
+            shared: self.shared.clone(),
+            pool: self.pool.clone(),
+            classifier: self.config.classifier,
+            queues: self.config.queues(),
+            queue_len: self.config.queue_len,
+        })
+    }
+
+    /// Starts sending the queued frames on `tx`, paced by `shaper`, from a
+    /// thread spawned as `thread` says. Marks `writability` writable whenever
+    /// a frame leaves the queues.
+    pub fn start(&mut self,
+                 tx: Box<EthernetDataLinkSender>,
+                 shaper: Arc<Shaper>,
+                 writability: Arc<Writability>,
+                 thread: &ThreadConfig) {
+        let shared = self.shared.clone();
+        let scheduler = Scheduler::new(self.config.scheduling.clone());
+        self.thread = Some(thread.spawn(move || run(shared, scheduler, tx, shaper, writability)));
+    }
+
+    /// Makes senders wait for room in a full queue if the config asks for
+    /// `backpressure` and `waiting` is true. Interfaces in non-blocking mode
+    /// turn it off.
+    pub fn set_waiting(&self, waiting: bool) {
+        self.shared.queues.lock().unwrap().waiting = waiting && self.config.backpressure;
+        self.shared.room.notify_all();
+    }
+
+    /// Sends the frames still queued, stops the scheduler and returns the
+    /// `EthernetDataLinkSender` given to `start`. Frames sent to the queues
+    /// after this are dropped.
+    pub fn stop(&mut self) -> Option<Box<EthernetDataLinkSender>> {
+        self.shared.queues.lock().unwrap().closed = true;
+        self.shared.ready.notify_one();
+        self.shared.room.notify_all();
+        self.thread.take().map(|thread| thread.join().unwrap())
+    }
+
+    pub fn stats(&self) -> Vec<QueueStats> {
+        let queues = self.shared.queues.lock().unwrap();
+        let mut stats = queues.stats.clone();
+        for (stats, frames) in stats.iter_mut().zip(queues.frames.iter()) {
+            stats.queued = frames.len();
+        }
+        stats
+    }
+}
+
+impl Drop for EgressQueues {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(shared: Arc<Shared>,
+       mut scheduler: Scheduler,
+       mut tx: Box<EthernetDataLinkSender>,
+       shaper: Arc<Shaper>,
+       writability: Arc<Writability>)
+       -> Box<EthernetDataLinkSender> {
+    while let Some(frame) = next_frame(&shared, &mut scheduler) {
+        writability.set_writable();
+        shaper.wait(frame.len());
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        match tx.send_to(&eth_pkg, None) {
+            Some(Ok(())) => (),
+            Some(Err(e)) => warn!("Unable to send queued frame: {}", e),
+            None => warn!("Unable to send queued frame: Insufficient buffer space"),
+        }
+    }
+    debug!("Egress queue scheduler is quitting");
+    tx
+}
+
+/// Blocks until there is a frame to send, or returns `None` once the queues
+/// are closed and empty.
+fn next_frame(shared: &Shared, scheduler: &mut Scheduler) -> Option<PooledBuffer> {
+    let mut queues = shared.queues.lock().unwrap();
+    loop {
+        if let Some(queue) = scheduler.next(&queues.frames) {
+            queues.stats[queue].sent += 1;
+            shared.room.notify_all();
+            return queues.frames[queue].pop_front();
+        }
+        if queues.closed {
+            return None;
+        }
+        queues = shared.ready.wait(queues).unwrap();
+    }
+}
+
+/// Classifies the frames sent to it into `EgressQueues`.
+struct QueueSender {
+    shared: Arc<Shared>,
+    pool: BufferPool,
+    classifier: Classifier,
+    queues: usize,
+    queue_len: usize,
+}
+
+impl QueueSender {
+    /// Queues `frame`, returning false if it had to be dropped.
+    fn enqueue(&self, frame: PooledBuffer) -> bool {
+        let queue = cmp::min((self.classifier)(&frame, self.queues), self.queues - 1);
+        let mut queues = self.shared.queues.lock().unwrap();
+        while queues.waiting && !queues.closed && queues.frames[queue].len() >= self.queue_len {
+            queues = self.shared.room.wait(queues).unwrap();
+        }
+        if queues.closed || queues.frames[queue].len() >= self.queue_len {
+            queues.stats[queue].dropped += 1;
+            return false;
+        }
+        queues.frames[queue].push_back(frame);
+        self.shared.ready.notify_one();
+        true
+    }
+
+    fn result(queued: bool) -> Option<io::Result<()>> {
+        if queued {
+            Some(Ok(()))
+        } else {
+            Some(Err(io::Error::new(io::ErrorKind::WouldBlock, "Egress queue full")))
+        }
+    }
+}
+
+impl EthernetDataLinkSender for QueueSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        let mut queued = true;
+        for _ in 0..num_packets {
+            let mut buffer = self.pool.get(packet_size);
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            queued &= self.enqueue(buffer);
+        }
+        Self::result(queued)
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        let queued = self.enqueue(self.pool.copy_of(packet.packet()));
+        Self::result(queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+    use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+    use pnet::packet::ipv4::MutableIpv4Packet;
+
+    use std::collections::VecDeque;
+
+    use vlan;
+
+    use super::*;
+
+    fn frame(protocol: IpNextHeaderProtocol, dscp: u8, payload_len: usize) -> Vec<u8> {
+        let mut frame = vec![0; 14 + 20 + 20 + payload_len];
+        MutableEthernetPacket::new(&mut frame).unwrap().set_ethertype(EtherTypes::Ipv4);
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length((20 + 20 + payload_len) as u16);
+            ip_pkg.set_dscp(dscp);
+            ip_pkg.set_next_level_protocol(protocol);
+        }
+        // TCP data offset of 5 words
+        frame[14 + 20 + 12] = 5 << 4;
+        frame
+    }
+
+    #[test]
+    fn classify_frames() {
+        let mut arp = vec![0; 42];
+        MutableEthernetPacket::new(&mut arp).unwrap().set_ethertype(EtherTypes::Arp);
+        assert_eq!(classify(&arp, 3), 0);
+        assert_eq!(classify(&frame(IpNextHeaderProtocols::Icmp, 0, 8), 3), 0);
+
+        let tcp = IpNextHeaderProtocols::Tcp;
+        assert_eq!(classify(&frame(tcp, 0, 0), 3), 0);
+        assert_eq!(classify(&frame(tcp, 0, 100), 3), 2);
+        // Expedited forwarding
+        assert_eq!(classify(&frame(tcp, 46, 100), 3), 0);
+        assert_eq!(classify(&frame(IpNextHeaderProtocols::Udp, 0, 100), 1), 0);
+        assert_eq!(classify(&[0; 6], 3), 2);
+        assert_eq!(classify(&vlan::tag(&arp, 10, 0), 3), 0);
+    }
+
+    fn queues(lens: &[usize]) -> Vec<VecDeque<Box<[u8]>>> {
+        lens.iter()
+            .map(|&len| (0..len).map(|_| vec![].into_boxed_slice()).collect())
+            .collect()
+    }
+
+    fn order(scheduler: &mut Scheduler, frames: &mut [VecDeque<Box<[u8]>>]) -> Vec<usize> {
+        let mut order = vec![];
+        while let Some(queue) = scheduler.next(frames) {
+            frames[queue].pop_front().unwrap();
+            order.push(queue);
+        }
+        order
+    }
+
+    #[test]
+    fn strict_priority() {
+        let mut scheduler = Scheduler::new(Scheduling::Strict(3));
+        let mut frames = queues(&[1, 0, 2]);
+        assert_eq!(order(&mut scheduler, &mut frames), vec![0, 2, 2]);
+        frames = queues(&[0, 2, 1]);
+        assert_eq!(order(&mut scheduler, &mut frames), vec![1, 1, 2]);
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let mut scheduler = Scheduler::new(Scheduling::Weighted(vec![2, 1]));
+        let mut frames = queues(&[5, 3]);
+        assert_eq!(order(&mut scheduler, &mut frames), vec![0, 0, 1, 0, 0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn tail_drop() {
+        let mut config = QosConfig::strict(2);
+        config.queue_len = 1;
+        let queues = EgressQueues::new(config);
+        let mut sender = queues.sender();
+        let udp = frame(IpNextHeaderProtocols::Udp, 0, 100);
+        let eth_pkg = EthernetPacket::new(&udp).unwrap();
+        assert!(sender.send_to(&eth_pkg, None).unwrap().is_ok());
+        assert!(sender.send_to(&eth_pkg, None).unwrap().is_err());
+        let stats = queues.stats();
+        assert_eq!(stats[0], QueueStats::default());
+        assert_eq!(stats[1], QueueStats { queued: 1, sent: 0, dropped: 1 });
+    }
+}
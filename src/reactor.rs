@@ -0,0 +1,399 @@
+//! Non-blocking variants of the operations of the stack that wait, for
+//! driving rips from an event loop, such as the reactor of a futures
+//! executor, instead of from threads of its own.
+//!
+//! The `poll_*` methods, like `udp::UdpSocket::poll_recv_from`,
+//! `udp::UdpSocket::poll_send_to` and `NetworkStack::poll_resolve_in_vrf`,
+//! never wait for the network. They return `Async::NotReady` if the
+//! operation can not complete yet, after arranging for the given `Waker` to
+//! be called once it might, and should then be polled again. rips does not
+//! depend on any futures crate itself. An adapter for an executor wraps its
+//! current task in a `Waker` and maps `Async` to its own type, for futures
+//! 0.1 something like:
+//!
+//! ```rust,ignore
+//! impl Future for RecvFrom {
+//!     type Item = (usize, SocketAddr);
+//!     type Error = io::Error;
+//!
+//!     fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+//!         let task = futures::task::current();
+//!         let waker: Waker = Arc::new(move || task.notify());
+//!         match self.socket.poll_recv_from(&mut self.buf, &waker)? {
+//!             reactor::Async::Ready(result) => Ok(futures::Async::Ready(result)),
+//!             reactor::Async::NotReady => Ok(futures::Async::NotReady),
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Wakers are called from the threads receiving on the interfaces, so they
+//! should only schedule the work, not do it. A waker may be called without
+//! the operation being ready, and stays registered until it is called.
+//!
+//! Event loops polling file descriptors, such as mio, can instead watch the
+//! file descriptor of a `Readiness`, for example the one from
+//! `udp::UdpSocket::readiness_fd`, and receive with the non-blocking
+//! `try_*` methods, which fail with `io::ErrorKind::WouldBlock` when nothing
+//! is ready. With mio it is registered through `mio::unix::EventedFd`.
+//!
+//! The file descriptor is level-triggered: it is readable for as long as
+//! something is ready. Registered edge-triggered, as mio does by default, an
+//! event is only delivered when it becomes readable again after everything
+//! ready was taken, so receive until `WouldBlock` after every event.
+//!
+//! Without any event loop, a `select::Selector` waits for several sockets
+//! on one thread.
+//!
+//! Sending waits for the datalink to take the frame unless the interface is
+//! in non-blocking mode, see `StackInterface::set_nonblocking`. Sends then
+//! fail with `TxError::WouldBlock` when the frame does not fit, and the
+//! `Writability` of the interface tells when to try again.
+
+#[cfg(unix)]
+use std::io::{self, Read, Write};
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Called when a pending operation might be able to complete.
+pub type Waker = Arc<Fn() + Send + Sync>;
+
+/// The outcome of polling an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Async<T> {
+    /// The operation completed with this
+    Ready(T),
+    /// The operation can not complete yet, the `Waker` given is called when
+    /// it might
+    NotReady,
+}
+
+impl<T> Async<T> {
+    pub fn is_ready(&self) -> bool {
+        match *self {
+            Async::Ready(_) => true,
+            Async::NotReady => false,
+        }
+    }
+
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Async<U> {
+        match self {
+            Async::Ready(t) => Async::Ready(f(t)),
+            Async::NotReady => Async::NotReady,
+        }
+    }
+}
+
+/// The `Waker`s waiting for the same event.
+#[derive(Default)]
+pub struct Wakers {
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Wakers {
+    pub fn register(&self, waker: Waker) {
+        self.wakers.lock().unwrap().push(waker);
+    }
+
+    /// Same as `register`, but does nothing if `waker` already is. For
+    /// wakers that are registered again every time they are polled, like the
+    /// one of a `select::Selector`.
+    pub fn register_unique(&self, waker: Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|registered| Arc::ptr_eq(registered, &waker)) {
+            wakers.push(waker);
+        }
+    }
+
+    /// Calls and forgets all registered wakers.
+    pub fn wake_all(&self) {
+        let wakers = mem::replace(&mut *self.wakers.lock().unwrap(), Vec::new());
+        for waker in wakers {
+            (*waker)();
+        }
+    }
+}
+
+/// Counts the items queued in a channel, and keeps a file descriptor
+/// readable while there are any. See the module documentation.
+#[derive(Default)]
+pub struct Readiness {
+    state: Mutex<ReadinessState>,
+}
+
+#[derive(Default)]
+struct ReadinessState {
+    queued: usize,
+    /// The reading and writing end of the pipe signalling readiness, created
+    /// when first asked for
+    #[cfg(unix)]
+    pipe: Option<(UnixStream, UnixStream)>,
+}
+
+impl ReadinessState {
+    #[cfg(unix)]
+    fn signal(&mut self, ready: bool) {
+        if let Some((ref mut reader, ref mut writer)) = self.pipe {
+            let result = if ready {
+                writer.write(&[1]).map(|_| ())
+            } else {
+                reader.read(&mut [0]).map(|_| ())
+            };
+            if let Err(e) = result {
+                warn!("Unable to signal readiness: {}", e);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn signal(&mut self, _ready: bool) {}
+}
+
+impl Readiness {
+    /// Sends `item` on `tx` and counts it as queued. Returns false if the
+    /// receiving end is gone.
+    pub fn send<T>(&self, tx: &Sender<T>, item: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if tx.send(item).is_err() {
+            return false;
+        }
+        state.queued += 1;
+        if state.queued == 1 {
+            state.signal(true);
+        }
+        true
+    }
+
+    /// Returns the number of items sent and not yet received.
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queued
+    }
+
+    /// Takes an item sent with `send` from `rx`, if one is queued.
+    pub fn try_recv<T>(&self, rx: &Receiver<T>) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        let item = rx.try_recv().ok();
+        if item.is_some() {
+            Self::dequeued(&mut state);
+        }
+        item
+    }
+
+    /// Waits for an item sent with `send` on `rx`.
+    pub fn recv<T>(&self, rx: &Receiver<T>) -> Option<T> {
+        // Not locked while waiting, the item was counted before it was sent
+        let item = rx.recv().ok();
+        if item.is_some() {
+            Self::dequeued(&mut self.state.lock().unwrap());
+        }
+        item
+    }
+
+    /// Same as `recv`, but gives up after `timeout`.
+    pub fn recv_timeout<T>(&self, rx: &Receiver<T>, timeout: Duration) -> Option<T> {
+        let item = rx.recv_timeout(timeout).ok();
+        if item.is_some() {
+            Self::dequeued(&mut self.state.lock().unwrap());
+        }
+        item
+    }
+
+    fn dequeued(state: &mut ReadinessState) {
+        state.queued -= 1;
+        if state.queued == 0 {
+            state.signal(false);
+        }
+    }
+
+    /// Returns a file descriptor that is readable while items are queued.
+    /// Only reading it from outside would break the signalling, it is only
+    /// meant to be polled.
+    #[cfg(unix)]
+    pub fn fd(&self) -> io::Result<RawFd> {
+        let mut state = self.state.lock().unwrap();
+        if state.pipe.is_none() {
+            let (reader, writer) = UnixStream::pair()?;
+            reader.set_nonblocking(true)?;
+            writer.set_nonblocking(true)?;
+            state.pipe = Some((reader, writer));
+            if state.queued > 0 {
+                state.signal(true);
+            }
+        }
+        Ok(state.pipe.as_ref().unwrap().0.as_raw_fd())
+    }
+}
+
+/// Whether an interface in non-blocking mode can take more frames.
+///
+/// It is marked full when a send fails with `TxError::WouldBlock` and
+/// writable again when its egress queues have room, see `qos`. Datalinks
+/// sending frames directly give no notice when their buffer drains, so
+/// those are assumed writable again after `retry` has passed.
+pub struct Writability {
+    full: Mutex<bool>,
+    writable: Condvar,
+    wakers: Wakers,
+}
+
+impl Default for Writability {
+    fn default() -> Writability {
+        Writability {
+            full: Mutex::new(false),
+            writable: Condvar::new(),
+            wakers: Wakers::default(),
+        }
+    }
+}
+
+impl Writability {
+    pub fn is_writable(&self) -> bool {
+        !*self.full.lock().unwrap()
+    }
+
+    /// Marks the interface full, and writable again after `retry` if given.
+    pub fn set_full(this: &Arc<Writability>, retry: Option<Duration>) {
+        let mut full = this.full.lock().unwrap();
+        if *full {
+            return;
+        }
+        *full = true;
+        if let Some(retry) = retry {
+            let writability = this.clone();
+            thread::spawn(move || {
+                thread::sleep(retry);
+                writability.set_writable();
+            });
+        }
+    }
+
+    /// Marks the interface writable, waking everyone waiting for it.
+    pub fn set_writable(&self) {
+        {
+            let mut full = self.full.lock().unwrap();
+            if !*full {
+                return;
+            }
+            *full = false;
+            self.writable.notify_all();
+        }
+        self.wakers.wake_all();
+    }
+
+    /// Waits until the interface is writable or `timeout` has passed.
+    /// Returns whether it is writable.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut full = self.full.lock().unwrap();
+        while *full {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            full = self.writable.wait_timeout(full, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Returns `Async::Ready` if the interface is writable, else makes
+    /// `waker` be called when it is.
+    pub fn poll(&self, waker: &Waker) -> Async<()> {
+        let full = self.full.lock().unwrap();
+        if !*full {
+            return Async::Ready(());
+        }
+        // Registered under the lock so `set_writable` can not slip past
+        self.wakers.register(waker.clone());
+        Async::NotReady
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Returns how many bytes are waiting in the pipe of `readiness`.
+    #[cfg(unix)]
+    fn signalled(readiness: &Readiness) -> usize {
+        let mut state = readiness.state.lock().unwrap();
+        let (ref mut reader, ref mut writer) = *state.pipe.as_mut().unwrap();
+        let mut buf = [0; 16];
+        let len = reader.read(&mut buf).unwrap_or(0);
+        writer.write_all(&buf[..len]).unwrap();
+        len
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readiness_level() {
+        let readiness = Readiness::default();
+        let (tx, rx) = mpsc::channel();
+        assert!(readiness.send(&tx, 1));
+        readiness.fd().unwrap();
+        assert_eq!(signalled(&readiness), 1);
+        assert!(readiness.send(&tx, 2));
+        assert_eq!(signalled(&readiness), 1);
+        assert_eq!(readiness.try_recv(&rx), Some(1));
+        assert_eq!(signalled(&readiness), 1);
+        assert_eq!(readiness.recv(&rx), Some(2));
+        assert_eq!(signalled(&readiness), 0);
+        assert_eq!(readiness.try_recv(&rx), None);
+        assert_eq!(signalled(&readiness), 0);
+        assert!(readiness.send(&tx, 3));
+        assert_eq!(signalled(&readiness), 1);
+    }
+
+    #[test]
+    fn writability_retry() {
+        let writability = Arc::new(Writability::default());
+        let woken = Arc::new(AtomicUsize::new(0));
+        let counter = woken.clone();
+        let waker: Waker = Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(writability.poll(&waker).is_ready());
+        Writability::set_full(&writability, Some(Duration::from_millis(50)));
+        assert!(!writability.is_writable());
+        assert_eq!(writability.poll(&waker), Async::NotReady);
+        assert!(!writability.wait(Duration::from_millis(10)));
+        assert!(writability.wait(Duration::from_secs(1)));
+        // The wakers are called right after the waiting threads are notified
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(woken.load(Ordering::SeqCst), 1);
+
+        Writability::set_full(&writability, None);
+        assert!(!writability.wait(Duration::from_millis(100)));
+        writability.set_writable();
+        assert!(writability.is_writable());
+    }
+
+    #[test]
+    fn wake_once() {
+        let woken = Arc::new(AtomicUsize::new(0));
+        let counter = woken.clone();
+        let waker: Waker = Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let wakers = Wakers::default();
+        wakers.register(waker.clone());
+        wakers.register(waker);
+        wakers.wake_all();
+        assert_eq!(woken.load(Ordering::SeqCst), 2);
+        wakers.wake_all();
+        assert_eq!(woken.load(Ordering::SeqCst), 2);
+    }
+}
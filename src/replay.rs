@@ -0,0 +1,246 @@
+//! Replaying capture files into a stack.
+//!
+//! `Replay` is a `Datalink` whose received frames come from a pcap or
+//! pcapng file, so a problem captured in the field can be reproduced
+//! offline, or a capture turned into a regression test:
+//!
+//! ```rust,ignore
+//! let replay = Arc::new(Replay::open("field.pcapng", mac, Timing::Accelerated(10.0))?);
+//! let interface = stack.add_datalink("replay0", replay.clone())?;
+//! stack.add_ipv4(&interface, "10.0.0.2/24".parse().unwrap())?;
+//! while !replay.is_finished() {
+//!     thread::sleep(Duration::from_millis(100));
+//! }
+//! ```
+//!
+//! Frames go through the rx thread of the interface as any received frame
+//! does. Frames a pcapng file marks as sent are skipped, they were sent by
+//! the host that captured them, not to it. What the stack sends on the
+//! interface is counted and dropped. Once the file is read to its end the
+//! link receives nothing more, but stays up.
+
+use datalink::Datalink;
+use pcap::{Direction, PcapReader, Record};
+use rx;
+
+use pnet::util::MacAddr;
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How a `Replay` paces the frames of its file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    /// With the gaps between them the file has
+    Original,
+    /// With the gaps of the file divided by the factor
+    Accelerated(f64),
+    /// One right after the other
+    Immediate,
+}
+
+struct ReplayState {
+    reader: PcapReader<Box<Read + Send>>,
+    /// The frame to receive next, read ahead when it was not due yet
+    next: Option<Record>,
+    /// When the first frame was received, and the time it was captured at
+    start: Option<(Instant, SystemTime)>,
+    finished: bool,
+}
+
+/// A `Datalink` receiving the frames of a capture file. See the module
+/// documentation.
+pub struct Replay {
+    mac: MacAddr,
+    timing: Timing,
+    state: Mutex<ReplayState>,
+    replayed: AtomicUsize,
+    sent: AtomicUsize,
+}
+
+/// What `Replay::recv` does next.
+enum Step {
+    Receive(Record),
+    Wait(Duration),
+    Idle,
+}
+
+impl Replay {
+    /// Replays the file at `path` on a link with the MAC address `mac`.
+    pub fn open<P: AsRef<Path>>(path: P, mac: MacAddr, timing: Timing) -> io::Result<Replay> {
+        let file = File::open(path)?;
+        Replay::new(BufReader::new(file), mac, timing)
+    }
+
+    /// Replays the capture read from `input`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timing` accelerates by a factor that is not above zero.
+    pub fn new<R: Read + Send + 'static>(input: R,
+                                         mac: MacAddr,
+                                         timing: Timing)
+                                         -> io::Result<Replay> {
+        if let Timing::Accelerated(factor) = timing {
+            assert!(factor > 0.0, "Replays can only be accelerated by positive factors");
+        }
+        let reader = PcapReader::new(Box::new(input) as Box<Read + Send>)?;
+        Ok(Replay {
+            mac: mac,
+            timing: timing,
+            state: Mutex::new(ReplayState {
+                reader: reader,
+                next: None,
+                start: None,
+                finished: false,
+            }),
+            replayed: AtomicUsize::new(0),
+            sent: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the number of frames received so far.
+    pub fn replayed(&self) -> usize {
+        self.replayed.load(Ordering::SeqCst)
+    }
+
+    /// Returns true once every frame of the file was received.
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().finished
+    }
+
+    /// Returns the number of frames the stack sent on the link.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    fn step(&self) -> io::Result<Step> {
+        let mut state = self.state.lock().unwrap();
+        if state.next.is_none() {
+            loop {
+                match state.reader.next_record()? {
+                    Some(ref record) if record.direction == Some(Direction::Outbound) => (),
+                    Some(record) => {
+                        state.next = Some(record);
+                        break;
+                    }
+                    None => {
+                        state.finished = true;
+                        return Ok(Step::Idle);
+                    }
+                }
+            }
+        }
+        let captured = state.next.as_ref().unwrap().time;
+        let (start, first_captured) = match state.start {
+            Some(start) => start,
+            None => (Instant::now(), captured),
+        };
+        state.start = Some((start, first_captured));
+        let offset = captured.duration_since(first_captured).unwrap_or(Duration::new(0, 0));
+        let offset = match self.timing {
+            Timing::Original => offset,
+            Timing::Accelerated(factor) => scale(offset, 1.0 / factor),
+            Timing::Immediate => Duration::new(0, 0),
+        };
+        let elapsed = start.elapsed();
+        if offset > elapsed {
+            Ok(Step::Wait(offset - elapsed))
+        } else {
+            Ok(Step::Receive(state.next.take().unwrap()))
+        }
+    }
+}
+
+fn scale(duration: Duration, factor: f64) -> Duration {
+    let secs = (duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9) * factor;
+    Duration::new(secs as u64, (secs.fract() * 1e9) as u32)
+}
+
+impl Datalink for Replay {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn send(&self, _frame: &[u8]) -> io::Result<()> {
+        self.sent.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let poll_interval = Duration::from_millis(rx::POLL_INTERVAL_MS);
+        loop {
+            // Sleeping without the lock, so the counters can be read
+            match self.step()? {
+                Step::Receive(record) => {
+                    self.replayed.fetch_add(1, Ordering::SeqCst);
+                    return Ok(record.data.into_boxed_slice());
+                }
+                Step::Wait(wait) if wait <= poll_interval => thread::sleep(wait),
+                Step::Wait(_) | Step::Idle => {
+                    thread::sleep(poll_interval);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "No frame due"));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datalink::Datalink;
+    use pcap::{Direction, Format, PcapWriter};
+
+    use pnet::util::MacAddr;
+
+    use std::io;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use super::*;
+
+    /// A capture of frames numbered by their first byte, at the given
+    /// seconds.
+    fn capture(frames: &[(u64, Direction)]) -> Vec<u8> {
+        let mut writer = PcapWriter::new(Vec::new(), Format::Pcapng, 100, "eth0").unwrap();
+        for (i, &(secs, direction)) in frames.iter().enumerate() {
+            writer.write(direction, UNIX_EPOCH + Duration::from_secs(secs), &[i as u8; 60])
+                .unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn skips_sent_frames() {
+        let file = capture(&[(0, Direction::Inbound),
+                             (0, Direction::Outbound),
+                             (0, Direction::Inbound)]);
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let replay = Replay::new(io::Cursor::new(file), mac, Timing::Immediate).unwrap();
+        assert_eq!(replay.recv().unwrap()[0], 0);
+        assert_eq!(replay.recv().unwrap()[0], 2);
+        assert!(!replay.is_finished());
+        assert_eq!(replay.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(replay.is_finished());
+        assert_eq!(replay.replayed(), 2);
+    }
+
+    #[test]
+    fn accelerated() {
+        let file = capture(&[(10, Direction::Inbound), (12, Direction::Inbound)]);
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let replay = Replay::new(io::Cursor::new(file), mac, Timing::Accelerated(20.0)).unwrap();
+        let start = Instant::now();
+        replay.recv().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // Two seconds apart in the capture, a tenth of a second replayed
+        replay.recv().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,787 @@
+//! Routing Information Protocol version 2, RFC 2453.
+//!
+//! A `RipRouter` learns routes from the responses of its neighbors and
+//! installs them in the `RoutingTable` of the stack, with the RIP hop count as
+//! route metric. Learned routes time out if they are not refreshed.
+//! The routes of the stack that were not learned over RIP are advertised back.
+//!
+//! Since rips has no multicast support yet, updates are sent as unicast to a
+//! configured list of neighbors instead of to 224.0.0.9.
+
+use {Interface, NetworkStack, RouteEntry, RouteOrigin, RxError, StackError};
+use rx::POLL_INTERVAL_MS;
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::UdpSocket;
+
+use ipnetwork::{Ipv4Network, ipv4_mask_to_prefix};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The UDP port RIP is spoken on.
+pub const RIP_PORT: u16 = 520;
+
+/// The metric representing an unreachable destination.
+pub const INFINITY: u32 = 16;
+
+/// Seconds between regular updates sent to neighbors.
+pub const UPDATE_INTERVAL_SECS: u64 = 30;
+
+/// Seconds a learned route stays valid without being refreshed.
+pub const TIMEOUT_SECS: u64 = 180;
+
+/// Seconds a timed out route is still advertised as unreachable before it
+/// is forgotten.
+pub const GARBAGE_COLLECTION_SECS: u64 = 120;
+
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 20;
+const MAX_ENTRIES: usize = 25;
+const AF_INET: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RipCommand {
+    Request,
+    Response,
+}
+
+/// One route in a RIP message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RipEntry {
+    pub net: Ipv4Network,
+    /// Where to send traffic for `net`. `None` means the sender of the message
+    pub next_hop: Option<Ipv4Addr>,
+    pub metric: u32,
+    pub route_tag: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RipMessage {
+    pub command: RipCommand,
+    pub entries: Vec<RipEntry>,
+}
+
+impl RipMessage {
+    /// Parses a RIPv2 message from the payload of a UDP datagram. Entries for
+    /// other address families than IPv4, such as authentication entries, are
+    /// skipped.
+    pub fn parse(data: &[u8]) -> Result<RipMessage, RxError> {
+        if data.len() < HEADER_LEN || (data.len() - HEADER_LEN) % ENTRY_LEN != 0 {
+            return Err(RxError::InvalidLength);
+        }
+        let command = match data[0] {
+            1 => RipCommand::Request,
+            2 => RipCommand::Response,
+            _ => return Err(RxError::InvalidContent),
+        };
+        if data[1] != VERSION {
+            return Err(RxError::InvalidContent);
+        }
+        let mut entries = Vec::new();
+        for entry in data[HEADER_LEN..].chunks(ENTRY_LEN) {
+            if read_u16(&entry[0..2]) != AF_INET {
+                continue;
+            }
+            let ip = Ipv4Addr::from(read_u32(&entry[4..8]));
+            let mask = Ipv4Addr::from(read_u32(&entry[8..12]));
+            let next_hop = Ipv4Addr::from(read_u32(&entry[12..16]));
+            let prefix = ipv4_mask_to_prefix(mask).map_err(|_| RxError::InvalidContent)?;
+            entries.push(RipEntry {
+                net: Ipv4Network::new(ip, prefix).map_err(|_| RxError::InvalidContent)?,
+                next_hop: if next_hop == Ipv4Addr::new(0, 0, 0, 0) {
+                    None
+                } else {
+                    Some(next_hop)
+                },
+                metric: read_u32(&entry[16..20]),
+                route_tag: read_u16(&entry[2..4]),
+            });
+        }
+        Ok(RipMessage {
+            command: command,
+            entries: entries,
+        })
+    }
+
+    /// Serializes this message into the payload of a UDP datagram.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + ENTRY_LEN * self.entries.len());
+        data.push(match self.command {
+            RipCommand::Request => 1,
+            RipCommand::Response => 2,
+        });
+        data.extend_from_slice(&[VERSION, 0, 0]);
+        for entry in &self.entries {
+            write_u16(&mut data, AF_INET);
+            write_u16(&mut data, entry.route_tag);
+            write_u32(&mut data, u32::from(entry.net.network()));
+            write_u32(&mut data, u32::from(entry.net.mask()));
+            write_u32(&mut data,
+                      u32::from(entry.next_hop.unwrap_or(Ipv4Addr::new(0, 0, 0, 0))));
+            write_u32(&mut data, entry.metric);
+        }
+        data
+    }
+
+    /// Returns true if this is a request for the entire routing table.
+    pub fn is_whole_table_request(&self) -> bool {
+        self.command == RipCommand::Request && self.entries.len() == 1 &&
+        self.entries[0].metric == INFINITY && self.entries[0].net.prefix() == 0
+    }
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    ((data[0] as u16) << 8) | data[1] as u16
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    ((read_u16(&data[0..2]) as u32) << 16) | read_u16(&data[2..4]) as u32
+}
+
+fn write_u16(data: &mut Vec<u8>, value: u16) {
+    data.push((value >> 8) as u8);
+    data.push(value as u8);
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+    write_u16(data, (value >> 16) as u16);
+    write_u16(data, value as u16);
+}
+
+/// A route learned from a neighbor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LearnedRoute {
+    pub net: Ipv4Network,
+    pub gw: Ipv4Addr,
+    pub metric: u32,
+    updated: Instant,
+    /// When the route became unreachable, if it did
+    deleted: Option<Instant>,
+}
+
+/// The RIP route database for one interface. Does not do any IO itself,
+/// incoming messages are fed to it and it returns the messages to send.
+pub struct RipRouter {
+    interface: Interface,
+    routes: HashMap<(Ipv4Addr, u8), LearnedRoute>,
+}
+
+impl RipRouter {
+    /// Creates a router installing the routes it learns as going out on
+    /// `interface`.
+    pub fn new(interface: Interface) -> RipRouter {
+        RipRouter {
+            interface: interface,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Returns all routes currently in the database, including unreachable
+    /// routes awaiting garbage collection.
+    pub fn routes(&self) -> Vec<LearnedRoute> {
+        self.routes.values().cloned().collect()
+    }
+
+    /// Handles an incoming message from `src`. Responses update the routes
+    /// of `stack`, but only if they come from the RIP port of a neighbor on
+    /// one of the networks directly connected to the interface of this
+    /// router. Returns the messages to send back to `src`.
+    pub fn handle_message(&mut self,
+                          stack: &mut NetworkStack,
+                          src: SocketAddrV4,
+                          msg: &RipMessage,
+                          now: Instant)
+                          -> Vec<RipMessage> {
+        match msg.command {
+            RipCommand::Request => {
+                if msg.is_whole_table_request() {
+                    self.advertisement(&stack.routing_table().routes())
+                } else {
+                    vec![self.answer_request(stack, msg)]
+                }
+            }
+            RipCommand::Response => {
+                if src.port() != RIP_PORT {
+                    debug!("Rip: Ignoring response from {}, not the RIP port", src);
+                } else if !stack.routing_table().is_on_link(*src.ip(), &self.interface) {
+                    debug!("Rip: Ignoring response from {}, not a neighbor", src);
+                } else {
+                    for entry in &msg.entries {
+                        self.handle_entry(stack, *src.ip(), entry, now);
+                    }
+                }
+                vec![]
+            }
+        }
+    }
+
+    fn handle_entry(&mut self,
+                    stack: &mut NetworkStack,
+                    src: Ipv4Addr,
+                    entry: &RipEntry,
+                    now: Instant) {
+        if entry.metric < 1 || entry.metric > INFINITY {
+            return;
+        }
+        let metric = ::std::cmp::min(entry.metric + 1, INFINITY);
+        // A next hop that is not directly reachable is treated as 0.0.0.0,
+        // meaning the sender itself (RFC 2453 section 4.4)
+        let gw = match entry.next_hop {
+            Some(next_hop) => {
+                if stack.routing_table().is_on_link(next_hop, &self.interface) {
+                    next_hop
+                } else {
+                    src
+                }
+            }
+            None => src,
+        };
+        let key = (entry.net.network(), entry.net.prefix());
+        let (update, old_metric) = match self.routes.get(&key) {
+            None => (metric < INFINITY, None),
+            Some(route) => {
+                let from_current_gw = route.gw == gw;
+                let better = metric < route.metric;
+                let changed = metric != route.metric || route.deleted.is_some();
+                ((from_current_gw && changed) || better, Some(route.metric))
+            }
+        };
+        if update {
+            if let Some(old_metric) = old_metric {
+                self.uninstall(stack, entry.net, old_metric);
+            }
+            let route = LearnedRoute {
+                net: entry.net,
+                gw: gw,
+                metric: metric,
+                updated: now,
+                deleted: None,
+            };
+            if metric < INFINITY {
+                let interface = self.interface.clone();
+                match stack.add_route_with_origin(entry.net,
+                                                  Some(gw),
+                                                  interface,
+                                                  metric,
+                                                  RouteOrigin::Rip) {
+                    Ok(_) => {
+                        self.routes.insert(key, route);
+                    }
+                    Err(e) => {
+                        // Routes added by other means take precedence
+                        debug!("Rip: Not installing route to {}: {}", entry.net, e);
+                        self.routes.remove(&key);
+                    }
+                }
+            } else {
+                self.routes.insert(key, LearnedRoute { deleted: Some(now), ..route });
+            }
+        } else if let Some(route) = self.routes.get_mut(&key) {
+            if route.gw == gw && route.deleted.is_none() {
+                route.updated = now;
+            }
+        }
+    }
+
+    fn uninstall(&self, stack: &mut NetworkStack, net: Ipv4Network, metric: u32) {
+        if metric < INFINITY {
+            stack.remove_route_with_origin(net, metric, &self.interface, RouteOrigin::Rip);
+        }
+    }
+
+    fn answer_request(&self, stack: &mut NetworkStack, msg: &RipMessage) -> RipMessage {
+        let entries = msg.entries
+            .iter()
+            .map(|entry| {
+                let metric = match stack.routing_table().lookup(entry.net.ip()) {
+                    Some(route) if route.net.prefix() == entry.net.prefix() => {
+                        ::std::cmp::min(route.metric + 1, INFINITY)
+                    }
+                    _ => INFINITY,
+                };
+                RipEntry { metric: metric, ..*entry }
+            })
+            .collect();
+        RipMessage {
+            command: RipCommand::Response,
+            entries: entries,
+        }
+    }
+
+    /// Times out routes that were not refreshed and forgets routes that
+    /// have been unreachable long enough.
+    pub fn expire(&mut self, stack: &mut NetworkStack, now: Instant) {
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        let garbage_collection = Duration::from_secs(GARBAGE_COLLECTION_SECS);
+        let mut forget = Vec::new();
+        for (key, route) in &mut self.routes {
+            match route.deleted {
+                None if now.duration_since(route.updated) >= timeout => {
+                    if route.metric < INFINITY {
+                        stack.remove_route_with_origin(route.net,
+                                                       route.metric,
+                                                       &self.interface,
+                                                       RouteOrigin::Rip);
+                    }
+                    route.metric = INFINITY;
+                    route.deleted = Some(now);
+                }
+                Some(deleted) if now.duration_since(deleted) >= garbage_collection => {
+                    forget.push(*key);
+                }
+                _ => (),
+            }
+        }
+        for key in forget {
+            self.routes.remove(&key);
+        }
+    }
+
+    /// Builds the response messages advertising `routes`, normally all routes
+    /// of the stack. Routes learned over RIP on this interface are left out
+    /// (split horizon), except the ones timing out which are advertised as
+    /// unreachable.
+    pub fn advertisement(&self, routes: &[RouteEntry]) -> Vec<RipMessage> {
+        let mut entries = Vec::new();
+        for route in routes {
+            let metric = match route.origin {
+                RouteOrigin::Rip if route.interface == self.interface => continue,
+                RouteOrigin::Rip => route.metric,
+                RouteOrigin::Static if route.gw.is_none() => 1,
+                RouteOrigin::Static => 2,
+            };
+            entries.push(RipEntry {
+                net: route.net,
+                next_hop: None,
+                metric: metric,
+                route_tag: 0,
+            });
+        }
+        for route in self.routes.values().filter(|route| route.deleted.is_some()) {
+            entries.push(RipEntry {
+                net: route.net,
+                next_hop: None,
+                metric: INFINITY,
+                route_tag: 0,
+            });
+        }
+        entries.chunks(MAX_ENTRIES)
+            .map(|entries| {
+                RipMessage {
+                    command: RipCommand::Response,
+                    entries: entries.to_vec(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Handle to a RIP router running in background threads. Dropping it stops
+/// the threads and waits for them to quit.
+pub struct RipHandle {
+    router: Arc<Mutex<RipRouter>>,
+    running: Arc<AtomicBool>,
+    /// Wakes the update thread when dropped
+    stop: Option<Sender<()>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl RipHandle {
+    /// Returns all routes currently learned.
+    pub fn routes(&self) -> Vec<LearnedRoute> {
+        self.router.lock().unwrap().routes()
+    }
+}
+
+impl Drop for RipHandle {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.stop.take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+/// Starts speaking RIP on the local address `local_ip`, sending updates to
+/// `neighbors`. A request for their entire tables is sent to all neighbors
+/// directly.
+pub fn spawn(stack: Arc<Mutex<NetworkStack>>,
+             local_ip: Ipv4Addr,
+             neighbors: Vec<Ipv4Addr>)
+             -> io::Result<RipHandle> {
+    let interface = {
+        let mut stack = stack.lock().unwrap();
+        let interfaces = stack.interfaces();
+        let mut interface = None;
+        for i in interfaces {
+            if stack.interface(&i)?.has_ipv4(local_ip) {
+                interface = Some(i);
+            }
+        }
+        interface.ok_or(StackError::NoSourceAddress(local_ip))?
+    };
+    let mut socket = UdpSocket::bind(stack.clone(), SocketAddrV4::new(local_ip, RIP_PORT))?;
+    // Shared with the clone, which only sends. Lets the rx thread notice when
+    // to quit
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+    let mut update_socket = socket.try_clone()?;
+    let router = Arc::new(Mutex::new(RipRouter::new(interface)));
+    let running = Arc::new(AtomicBool::new(true));
+
+    let request = RipMessage {
+        command: RipCommand::Request,
+        entries: vec![RipEntry {
+                          net: Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap(),
+                          next_hop: None,
+                          metric: INFINITY,
+                          route_tag: 0,
+                      }],
+    };
+    for neighbor in &neighbors {
+        socket.send_to(&request.to_bytes(), SocketAddrV4::new(*neighbor, RIP_PORT))?;
+    }
+
+    let rx_stack = stack.clone();
+    let rx_router = router.clone();
+    let rx_running = running.clone();
+    let rx_thread = thread::spawn(move || {
+        let mut buffer = vec![0; HEADER_LEN + ENTRY_LEN * MAX_ENTRIES];
+        while rx_running.load(Ordering::SeqCst) {
+            let (len, src) = match socket.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+                    debug!("Rip: Dropping datagram: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Rip: Receiving failed, quitting: {}", e);
+                    break;
+                }
+            };
+            let src = match src {
+                SocketAddr::V4(src) => src,
+                SocketAddr::V6(_) => continue,
+            };
+            let msg = match RipMessage::parse(&buffer[..len]) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    debug!("Rip: Invalid message from {}: {}", src, e);
+                    continue;
+                }
+            };
+            let mut router = rx_router.lock().unwrap();
+            let replies = {
+                let mut stack = rx_stack.lock().unwrap();
+                router.handle_message(&mut stack, src, &msg, Instant::now())
+            };
+            for reply in replies {
+                if let Err(e) = socket.send_to(&reply.to_bytes(), src) {
+                    warn!("Rip: Unable to reply to {}: {}", src, e);
+                }
+            }
+        }
+    });
+
+    let update_router = router.clone();
+    let (stop_tx, stop_rx) = mpsc::channel();
+    let update_thread = thread::spawn(move || {
+        loop {
+            let mut router = update_router.lock().unwrap();
+            let updates = {
+                let mut stack = stack.lock().unwrap();
+                router.expire(&mut stack, Instant::now());
+                router.advertisement(&stack.routing_table().routes())
+            };
+            for neighbor in &neighbors {
+                for update in &updates {
+                    let dst = SocketAddrV4::new(*neighbor, RIP_PORT);
+                    if let Err(e) = update_socket.send_to(&update.to_bytes(), dst) {
+                        warn!("Rip: Unable to send update to {}: {}", dst, e);
+                    }
+                }
+            }
+            drop(router);
+            match stop_rx.recv_timeout(Duration::from_secs(UPDATE_INTERVAL_SECS)) {
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+    });
+
+    Ok(RipHandle {
+        router: router,
+        running: running,
+        stop: Some(stop_tx),
+        threads: vec![rx_thread, update_thread],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnetwork::Ipv4Network;
+
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use testing;
+
+    #[test]
+    fn message_roundtrip() {
+        let msg = RipMessage {
+            command: RipCommand::Response,
+            entries: vec![RipEntry {
+                              net: Ipv4Network::from_str("192.168.5.0/24").unwrap(),
+                              next_hop: Some(Ipv4Addr::new(10, 0, 0, 9)),
+                              metric: 3,
+                              route_tag: 7,
+                          },
+                          RipEntry {
+                              net: Ipv4Network::from_str("0/0").unwrap(),
+                              next_hop: None,
+                              metric: 16,
+                              route_tag: 0,
+                          }],
+        };
+        let data = msg.to_bytes();
+        assert_eq!(data.len(), 4 + 2 * 20);
+        assert_eq!(&data[..4], &[2, 2, 0, 0]);
+        assert_eq!(&data[4..24],
+                   &[0, 2, 0, 7, 192, 168, 5, 0, 255, 255, 255, 0, 10, 0, 0, 9, 0, 0, 0, 3]);
+        assert_eq!(RipMessage::parse(&data).unwrap(), msg);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(RipMessage::parse(&[2, 2, 0]), Err(RxError::InvalidLength));
+        assert_eq!(RipMessage::parse(&[2, 2, 0, 0, 0]),
+                   Err(RxError::InvalidLength));
+        // Version 1
+        assert_eq!(RipMessage::parse(&[2, 1, 0, 0]),
+                   Err(RxError::InvalidContent));
+        assert_eq!(RipMessage::parse(&[9, 2, 0, 0]),
+                   Err(RxError::InvalidContent));
+    }
+
+    #[test]
+    fn parse_skips_authentication() {
+        let mut data = vec![2, 2, 0, 0, 0xff, 0xff, 0, 2];
+        data.extend_from_slice(&[0; 16]);
+        let msg = RipMessage::parse(&data).unwrap();
+        assert!(msg.entries.is_empty());
+    }
+
+    #[test]
+    fn learn_and_expire() {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let neighbor = Ipv4Addr::new(10, 0, 0, 1);
+        let src = SocketAddrV4::new(neighbor, RIP_PORT);
+        let remote_ip = Ipv4Addr::new(192, 168, 5, 1);
+        let mut router = RipRouter::new(interface.clone());
+
+        let start = Instant::now();
+        let response = response(vec![entry("192.168.5.0/24", 2)]);
+        assert!(router.handle_message(&mut stack, src, &response, start).is_empty());
+        {
+            let route = stack.routing_table().lookup(remote_ip).unwrap();
+            assert_eq!(route.gw, Some(neighbor));
+            assert_eq!(route.metric, 3);
+            assert_eq!(route.interface, interface);
+        }
+
+        // Refreshing keeps the route alive past the original timeout
+        let refresh = start + Duration::from_secs(100);
+        router.handle_message(&mut stack, src, &response, refresh);
+        router.expire(&mut stack, start + Duration::from_secs(TIMEOUT_SECS));
+        assert!(stack.routing_table().lookup(remote_ip).is_some());
+
+        let timeout = refresh + Duration::from_secs(TIMEOUT_SECS);
+        router.expire(&mut stack, timeout);
+        assert!(stack.routing_table().lookup(remote_ip).is_none());
+        assert_eq!(router.routes()[0].metric, INFINITY);
+        // Unreachable routes are advertised with infinite metric
+        let advertisement = router.advertisement(&[]);
+        assert_eq!(advertisement[0].entries[0].metric, INFINITY);
+
+        router.expire(&mut stack,
+                      timeout + Duration::from_secs(GARBAGE_COLLECTION_SECS));
+        assert!(router.routes().is_empty());
+    }
+
+    #[test]
+    fn better_metric_replaces() {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let neighbor1 = Ipv4Addr::new(10, 0, 0, 1);
+        let neighbor2 = Ipv4Addr::new(10, 0, 0, 3);
+        let (src1, src2) = (SocketAddrV4::new(neighbor1, RIP_PORT),
+                            SocketAddrV4::new(neighbor2, RIP_PORT));
+        let remote_ip = Ipv4Addr::new(192, 168, 5, 1);
+        let mut router = RipRouter::new(interface);
+        let now = Instant::now();
+
+        router.handle_message(&mut stack,
+                              src1,
+                              &response(vec![entry("192.168.5.0/24", 5)]),
+                              now);
+        // Worse route from other neighbor is ignored
+        router.handle_message(&mut stack,
+                              src2,
+                              &response(vec![entry("192.168.5.0/24", 7)]),
+                              now);
+        assert_eq!(stack.routing_table().lookup(remote_ip).unwrap().gw,
+                   Some(neighbor1));
+
+        router.handle_message(&mut stack,
+                              src2,
+                              &response(vec![entry("192.168.5.0/24", 1)]),
+                              now);
+        assert_eq!(stack.routing_table().routes().len(), 2);
+        {
+            let route = stack.routing_table().lookup(remote_ip).unwrap();
+            assert_eq!(route.gw, Some(neighbor2));
+            assert_eq!(route.metric, 2);
+        }
+
+        // Current gateway announcing the route as unreachable removes it
+        router.handle_message(&mut stack,
+                              src2,
+                              &response(vec![entry("192.168.5.0/24", INFINITY)]),
+                              now);
+        assert!(stack.routing_table().lookup(remote_ip).is_none());
+    }
+
+    #[test]
+    fn ignore_responses_not_from_neighbors() {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let mut router = RipRouter::new(interface);
+        let response = response(vec![entry("192.168.5.0/24", 2)]);
+        let now = Instant::now();
+
+        let wrong_port = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000);
+        router.handle_message(&mut stack, wrong_port, &response, now);
+        let off_link = SocketAddrV4::new(Ipv4Addr::new(10, 9, 0, 1), RIP_PORT);
+        router.handle_message(&mut stack, off_link, &response, now);
+        assert!(router.routes().is_empty());
+        assert_eq!(stack.routing_table().routes().len(), 1);
+    }
+
+    #[test]
+    fn off_link_next_hop_is_sender() {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let neighbor = Ipv4Addr::new(10, 0, 0, 1);
+        let mut router = RipRouter::new(interface);
+        let mut far = entry("192.168.5.0/24", 2);
+        far.next_hop = Some(Ipv4Addr::new(10, 9, 0, 1));
+        let mut near = entry("192.168.6.0/24", 2);
+        near.next_hop = Some(Ipv4Addr::new(10, 0, 0, 3));
+
+        router.handle_message(&mut stack,
+                              SocketAddrV4::new(neighbor, RIP_PORT),
+                              &response(vec![far, near]),
+                              Instant::now());
+        assert_eq!(stack.routing_table().lookup(Ipv4Addr::new(192, 168, 5, 1)).unwrap().gw,
+                   Some(neighbor));
+        assert_eq!(stack.routing_table().lookup(Ipv4Addr::new(192, 168, 6, 1)).unwrap().gw,
+                   Some(Ipv4Addr::new(10, 0, 0, 3)));
+    }
+
+    #[test]
+    fn static_routes_stay() {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let net = Ipv4Network::from_str("192.168.5.0/24").unwrap();
+        let static_gw = Ipv4Addr::new(10, 0, 0, 3);
+        stack.add_route_with_metric(net, Some(static_gw), interface.clone(), 3);
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), RIP_PORT);
+        let mut router = RipRouter::new(interface);
+        let start = Instant::now();
+
+        // Learned with the same metric as the static route
+        router.handle_message(&mut stack, src, &response(vec![entry("192.168.5.0/24", 2)]), start);
+        assert_eq!(stack.routing_table().lookup(net.ip()).unwrap().gw, Some(static_gw));
+        let advertisement = router.advertisement(&stack.routing_table().routes());
+        assert!(advertisement[0].entries.contains(&entry("192.168.5.0/24", 2)));
+
+        // Learned with another metric, then expired and withdrawn
+        router.handle_message(&mut stack, src, &response(vec![entry("192.168.5.0/24", 1)]), start);
+        assert_eq!(stack.routing_table().routes().len(), 3);
+        let advertisement = router.advertisement(&stack.routing_table().routes());
+        assert!(advertisement[0].entries.contains(&entry("192.168.5.0/24", 2)));
+        router.expire(&mut stack, start + Duration::from_secs(TIMEOUT_SECS));
+        router.handle_message(&mut stack,
+                              src,
+                              &response(vec![entry("192.168.5.0/24", INFINITY)]),
+                              start);
+        assert_eq!(stack.routing_table().routes().len(), 2);
+        let route = stack.routing_table().lookup(net.ip()).unwrap().clone();
+        assert_eq!(route.gw, Some(static_gw));
+        assert_eq!(route.origin, RouteOrigin::Static);
+    }
+
+    #[test]
+    fn drop_stops_threads() {
+        let (mut stack, interface, _inject_handle, _read_handle) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let neighbor = Ipv4Addr::new(10, 0, 0, 1);
+        let neighbor_mac = MacAddr::new(9, 8, 7, 6, 5, 4);
+        stack.interface(&interface).unwrap().arp_table().insert(neighbor, neighbor_mac);
+        let stack = Arc::new(Mutex::new(stack));
+        let handle = spawn(stack.clone(), Ipv4Addr::new(10, 0, 0, 2), vec![neighbor]).unwrap();
+
+        let start = Instant::now();
+        drop(handle);
+        assert!(start.elapsed() < Duration::from_secs(UPDATE_INTERVAL_SECS));
+        // Neither thread holds on to the stack any more
+        assert_eq!(Arc::strong_count(&stack), 1);
+    }
+
+    #[test]
+    fn whole_table_request() {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.0.0.2/24").unwrap()).unwrap();
+        let mut router = RipRouter::new(interface);
+        let mut request = response(vec![entry("0/0", INFINITY)]);
+        request.command = RipCommand::Request;
+
+        let replies = router.handle_message(&mut stack,
+                                            SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 5000),
+                                            &request,
+                                            Instant::now());
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].command, RipCommand::Response);
+        assert_eq!(replies[0].entries,
+                   vec![entry("10.0.0.2/24", 1)]);
+    }
+
+    fn response(entries: Vec<RipEntry>) -> RipMessage {
+        RipMessage {
+            command: RipCommand::Response,
+            entries: entries,
+        }
+    }
+
+    fn entry(net: &str, metric: u32) -> RipEntry {
+        RipEntry {
+            net: Ipv4Network::from_str(net).unwrap(),
+            next_hop: None,
+            metric: metric,
+            route_tag: 0,
+        }
+    }
+}
@@ -1,50 +1,442 @@
-use Interface;
+use {Interface, StackError, StackResult};
+use ipv4::MIN_MTU;
 
 use ipnetwork::Ipv4Network;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::net::Ipv4Addr;
 
-// TODO: Add metric
-#[derive(Debug)]
-struct RouteEntry {
+/// The metric given to routes added without an explicit metric.
+pub const DEFAULT_METRIC: u32 = 0;
+
+/// Who added a route to the `RoutingTable`. Routing protocols only ever
+/// replace and remove the routes they added themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteOrigin {
+    /// Added by the user, or by the stack for a configured address
+    Static,
+    /// Learned from a neighbor by a `rip::RipRouter`
+    Rip,
+}
+
+/// One entry in the `RoutingTable`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    /// The destination network this route matches
     pub net: Ipv4Network,
+
+    /// The gateway to send packets through, or `None` if `net` is directly
+    /// reachable on `interface`
     pub gw: Option<Ipv4Addr>,
+
+    /// The interface packets matching this route should go out on
     pub interface: Interface,
+
+    /// The cost of this route. Among routes with equally long prefixes the
+    /// one with the lowest metric is used.
+    pub metric: u32,
+
+    /// MTU override for traffic using this route. When set, packets are
+    /// sized by the smaller of this and the MTU of `interface`.
+    pub mtu: Option<usize>,
+
+    /// Preferred source address for traffic using this route. Overrides the
+    /// automatic source address selection as long as the address is
+    /// configured on `interface`.
+    pub src: Option<Ipv4Addr>,
+
+    /// Who added this route
+    pub origin: RouteOrigin,
 }
 
+impl RouteEntry {
+    /// Returns true if this entry is for the same destination network as
+    /// `net`. Host bits in either network are ignored.
+    fn same_destination(&self, net: Ipv4Network) -> bool {
+        self.net.prefix() == net.prefix() && self.net.network() == net.network()
+    }
+}
+
+/// Table of IPv4 routes.
+///
+/// A route is identified by its destination network and its metric, so
+/// there can be multiple routes to the same network as long as they have
+/// different metrics. When looking up a destination the route selection is:
+///
+/// 1. The route with the longest prefix containing the destination wins.
+/// 2. Among routes to that network, the one with the lowest metric wins.
+///
+/// Since no two routes share both destination and metric this selection is
+/// always deterministic and does not depend on insertion order.
+///
+/// Routes going out on interfaces that lost their carrier, see
+/// `set_link_up`, and routes through gateways that stopped answering, see
+/// `set_gateway_up`, are skipped.
 #[derive(Default)]
 pub struct RoutingTable {
+    /// Routes grouped by prefix length. Every `Vec` is kept sorted by metric
     table: BTreeMap<u8, Vec<RouteEntry>>,
+    /// Interfaces whose routes are withdrawn
+    down: HashSet<Interface>,
+    /// Gateways whose routes are withdrawn
+    down_gateways: HashSet<Ipv4Addr>,
+    version: u64,
 }
 
 impl RoutingTable {
     pub fn new() -> RoutingTable {
-        RoutingTable { table: BTreeMap::new() }
+        RoutingTable {
+            table: BTreeMap::new(),
+            down: HashSet::new(),
+            down_gateways: HashSet::new(),
+            version: 0,
+        }
     }
 
-    // TODO: Check for collision
-    // TODO: Increment Tx version counter
-    pub fn add_route(&mut self, net: Ipv4Network, gw: Option<Ipv4Addr>, interface: Interface) {
-        let prefix = net.prefix();
+    /// Withdraws all routes going out on `interface` while `up` is false, so
+    /// lookups fall back to other routes, or restores them. The routes stay
+    /// in the table. Returns true if this changed anything.
+    pub fn set_link_up(&mut self, interface: &Interface, up: bool) -> bool {
+        let changed = if up {
+            self.down.remove(interface)
+        } else {
+            self.down.insert(interface.clone())
+        };
+        if changed {
+            self.version = self.version.wrapping_add(1);
+        }
+        changed
+    }
+
+    pub fn is_link_up(&self, interface: &Interface) -> bool {
+        !self.down.contains(interface)
+    }
+
+    /// Withdraws all routes through the gateway `gw` while `up` is false,
+    /// like `set_link_up` does for interfaces. Used by a
+    /// `failover::GatewayMonitor` when a gateway stops answering. Returns
+    /// true if this changed anything.
+    pub fn set_gateway_up(&mut self, gw: Ipv4Addr, up: bool) -> bool {
+        let changed = if up {
+            self.down_gateways.remove(&gw)
+        } else {
+            self.down_gateways.insert(gw)
+        };
+        if changed {
+            self.version = self.version.wrapping_add(1);
+        }
+        changed
+    }
+
+    pub fn is_gateway_up(&self, gw: Ipv4Addr) -> bool {
+        !self.down_gateways.contains(&gw)
+    }
+
+    /// Returns true unless `entry` is withdrawn, by `set_link_up` or
+    /// `set_gateway_up`.
+    fn is_usable(&self, entry: &RouteEntry) -> bool {
+        self.is_link_up(&entry.interface) && entry.gw.map_or(true, |gw| self.is_gateway_up(gw))
+    }
+
+    /// Returns a counter that is incremented every time the table changes.
+    /// Can be used to detect if results of earlier lookups are outdated.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Adds a route to `net` with the default metric. See `add_route_with_metric`.
+    pub fn add_route(&mut self,
+                     net: Ipv4Network,
+                     gw: Option<Ipv4Addr>,
+                     interface: Interface)
+                     -> Option<RouteEntry> {
+        self.add_route_with_metric(net, gw, interface, DEFAULT_METRIC)
+    }
+
+    /// Adds a route to `net` with the given `metric`. If there already is a
+    /// route to the same destination network with the same metric it is
+    /// replaced and the old entry is returned.
+    pub fn add_route_with_metric(&mut self,
+                                 net: Ipv4Network,
+                                 gw: Option<Ipv4Addr>,
+                                 interface: Interface,
+                                 metric: u32)
+                                 -> Option<RouteEntry> {
+        let old_entry = self.remove_route_with_metric(net, metric);
+        self.insert(net, gw, interface, metric, RouteOrigin::Static);
+        old_entry
+    }
+
+    /// Same as `add_route_with_metric`, but for a route added by `origin`.
+    /// Only replaces a route to `net` with `metric` that was added by the
+    /// same origin. Fails with `StackError::AlreadyExists` if the route there
+    /// is from another origin, leaving the table unchanged.
+    pub fn add_route_with_origin(&mut self,
+                                 net: Ipv4Network,
+                                 gw: Option<Ipv4Addr>,
+                                 interface: Interface,
+                                 metric: u32,
+                                 origin: RouteOrigin)
+                                 -> StackResult<Option<RouteEntry>> {
+        let taken = self.table.get(&net.prefix()).map_or(false, |entries| {
+            entries.iter().any(|entry| {
+                entry.same_destination(net) && entry.metric == metric && entry.origin != origin
+            })
+        });
+        if taken {
+            let msg = format!("Route to {} with metric {}", net, metric);
+            return Err(StackError::AlreadyExists(msg));
+        }
+        let old_entry = self.remove_route_with_metric(net, metric);
+        self.insert(net, gw, interface, metric, origin);
+        Ok(old_entry)
+    }
+
+    fn insert(&mut self,
+              net: Ipv4Network,
+              gw: Option<Ipv4Addr>,
+              interface: Interface,
+              metric: u32,
+              origin: RouteOrigin) {
         let entry = RouteEntry {
             net: net,
             gw: gw,
             interface: interface,
+            metric: metric,
+            mtu: None,
+            src: None,
+            origin: origin,
         };
-        self.table.entry(prefix).or_insert_with(Vec::new).push(entry);
+        let entries = self.table.entry(net.prefix()).or_insert_with(Vec::new);
+        let i = entries.iter().position(|e| e.metric > metric).unwrap_or(entries.len());
+        entries.insert(i, entry);
+        self.version = self.version.wrapping_add(1);
     }
 
-    pub fn route(&self, ip: Ipv4Addr) -> Option<(Option<Ipv4Addr>, Interface)> {
+    /// Removes the currently preferred route to `net`, the one with the
+    /// lowest metric. Returns the removed entry, or `None` if there was no
+    /// route to that network.
+    pub fn remove_route(&mut self, net: Ipv4Network) -> Option<RouteEntry> {
+        self.remove_where(net, |_| true)
+    }
+
+    /// Removes the route to `net` with the given `metric`. Returns the
+    /// removed entry, or `None` if there was no such route.
+    pub fn remove_route_with_metric(&mut self,
+                                    net: Ipv4Network,
+                                    metric: u32)
+                                    -> Option<RouteEntry> {
+        self.remove_where(net, |entry| entry.metric == metric)
+    }
+
+    /// Same as `remove_route_with_metric`, but only removes the route if it
+    /// was added by `origin`.
+    pub fn remove_route_with_origin(&mut self,
+                                    net: Ipv4Network,
+                                    metric: u32,
+                                    origin: RouteOrigin)
+                                    -> Option<RouteEntry> {
+        self.remove_where(net, |entry| entry.metric == metric && entry.origin == origin)
+    }
+
+    /// Removes the gateway-less route to `net` going out on `interface`,
+    /// and then every route through a gateway on `interface` that is no
+    /// longer on link. Used when an address in `net` is removed from the
+    /// interface. Returns the removed entries.
+    pub fn remove_network(&mut self, net: Ipv4Network, interface: &Interface) -> Vec<RouteEntry> {
+        let mut removed = Vec::new();
+        let connected = |entry: &RouteEntry| entry.gw.is_none() && entry.interface == *interface;
+        removed.extend(self.remove_where(net, connected));
+        let stranded = self.routes()
+            .into_iter()
+            .filter(|entry| {
+                entry.interface == *interface &&
+                entry.gw.map_or(false, |gw| !self.is_on_link(gw, interface))
+            })
+            .collect::<Vec<_>>();
+        for entry in stranded {
+            let metric = entry.metric;
+            removed.extend(self.remove_where(entry.net, |entry| {
+                entry.metric == metric && entry.interface == *interface
+            }));
+        }
+        removed
+    }
+
+    /// Sets the MTU override of all routes to `net`, or removes it if `mtu`
+    /// is `None`. Fails with `StackError::InvalidMtu` if `mtu` is smaller
+    /// than the minimum IPv4 MTU and with `StackError::NotFound` if there is
+    /// no route to `net`.
+    pub fn set_route_mtu(&mut self, net: Ipv4Network, mtu: Option<usize>) -> StackResult<()> {
+        if let Some(mtu) = mtu {
+            if mtu < MIN_MTU {
+                return Err(StackError::InvalidMtu(mtu));
+            }
+        }
+        let mut found = false;
+        if let Some(entries) = self.table.get_mut(&net.prefix()) {
+            for entry in entries.iter_mut().filter(|entry| entry.same_destination(net)) {
+                entry.mtu = mtu;
+                found = true;
+            }
+        }
+        if found {
+            self.version = self.version.wrapping_add(1);
+            Ok(())
+        } else {
+            Err(StackError::NotFound(format!("Route to {}", net)))
+        }
+    }
+
+    /// Sets the preferred source address of all routes to `net`, or removes
+    /// it if `src` is `None`. Fails with `StackError::NotFound` if there is
+    /// no route to `net`.
+    pub fn set_route_src(&mut self, net: Ipv4Network, src: Option<Ipv4Addr>) -> StackResult<()> {
+        let mut found = false;
+        if let Some(entries) = self.table.get_mut(&net.prefix()) {
+            for entry in entries.iter_mut().filter(|entry| entry.same_destination(net)) {
+                entry.src = src;
+                found = true;
+            }
+        }
+        if found {
+            self.version = self.version.wrapping_add(1);
+            Ok(())
+        } else {
+            Err(StackError::NotFound(format!("Route to {}", net)))
+        }
+    }
+
+    /// Sets the default route (0.0.0.0/0) to go through `gw` on `interface`,
+    /// replacing any existing default route with the default metric. The
+    /// gateway must be directly reachable on `interface`, meaning there must
+    /// be a gateway-less route containing `gw` going out on that interface.
+    /// Fails with `StackError::NoRouteToHost` otherwise.
+    pub fn set_default_route(&mut self,
+                             gw: Ipv4Addr,
+                             interface: Interface)
+                             -> StackResult<Option<RouteEntry>> {
+        self.add_default_route(gw, interface, DEFAULT_METRIC)
+    }
+
+    /// Same as `set_default_route`, but with the metric `metric`, so there
+    /// can be several default routes. Traffic uses the one with the lowest
+    /// metric that is not withdrawn.
+    pub fn add_default_route(&mut self,
+                             gw: Ipv4Addr,
+                             interface: Interface,
+                             metric: u32)
+                             -> StackResult<Option<RouteEntry>> {
+        if !self.is_on_link(gw, &interface) {
+            return Err(StackError::NoRouteToHost(gw));
+        }
+        Ok(self.add_route_with_metric(default_net(), Some(gw), interface, metric))
+    }
+
+    /// Returns the preferred default route, if there is one.
+    pub fn default_route(&self) -> Option<&RouteEntry> {
+        self.table.get(&0).and_then(|entries| entries.first())
+    }
+
+    /// Returns the default route traffic uses, the first one of
+    /// `default_routes` that is not withdrawn.
+    pub fn active_default_route(&self) -> Option<&RouteEntry> {
+        self.table
+            .get(&0)
+            .and_then(|entries| entries.iter().find(|entry| self.is_usable(entry)))
+    }
+
+    /// Returns all default routes, lowest metric first, including withdrawn
+    /// ones.
+    pub fn default_routes(&self) -> Vec<RouteEntry> {
+        self.table.get(&0).cloned().unwrap_or_default()
+    }
+
+    /// Checks if `ip` is directly reachable on `interface`, meaning a
+    /// gateway-less route containing it goes out on that interface.
+    pub fn is_on_link(&self, ip: Ipv4Addr, interface: &Interface) -> bool {
+        self.table.values().flat_map(|entries| entries.iter()).any(|entry| {
+            entry.gw.is_none() && entry.interface == *interface && entry.net.contains(ip)
+        })
+    }
+
+    fn remove_where<F>(&mut self, net: Ipv4Network, f: F) -> Option<RouteEntry>
+        where F: Fn(&RouteEntry) -> bool
+    {
+        let prefix = net.prefix();
+        let (old_entry, now_empty) = match self.table.get_mut(&prefix) {
+            Some(entries) => {
+                let old_entry = entries.iter()
+                    .position(|entry| entry.same_destination(net) && f(entry))
+                    .map(|i| entries.remove(i));
+                (old_entry, entries.is_empty())
+            }
+            None => return None,
+        };
+        if now_empty {
+            self.table.remove(&prefix);
+        }
+        if old_entry.is_some() {
+            self.version = self.version.wrapping_add(1);
+        }
+        old_entry
+    }
+
+    /// Returns all routes in the table in the order they are preferred. Most
+    /// specific prefixes first and lowest metric first within each prefix.
+    /// Includes the routes withdrawn with `set_link_up` and `set_gateway_up`.
+    pub fn routes(&self) -> Vec<RouteEntry> {
+        self.table.values().rev().flat_map(|entries| entries.iter().cloned()).collect()
+    }
+
+    /// Returns the route entry that would be used for traffic to `ip`.
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<&RouteEntry> {
         for (_prefix, entries) in self.table.iter().rev() {
             for entry in entries {
-                if entry.net.contains(ip) {
-                    return Some((entry.gw, entry.interface.clone()));
+                if entry.net.contains(ip) && self.is_usable(entry) {
+                    return Some(entry);
                 }
             }
         }
         None
     }
+
+    pub fn route(&self, ip: Ipv4Addr) -> Option<(Option<Ipv4Addr>, Interface)> {
+        self.lookup(ip).map(|entry| (entry.gw, entry.interface.clone()))
+    }
+}
+
+/// Selects which of the local addresses in `nets`, all configured on the
+/// egress interface, to use as source address for packets to `dst` that are
+/// sent to `next_hop` on the link. `next_hop` is `dst` itself for directly
+/// reachable destinations. The rules, in order of precedence:
+///
+/// 1. If `dst` is one of the local addresses, use that address.
+/// 2. Prefer addresses whose network contains `next_hop`.
+/// 3. Prefer addresses whose network contains `dst`.
+/// 4. Prefer the address sharing the longest prefix with `dst`.
+/// 5. Prefer the numerically lowest address, to keep the selection
+///    deterministic.
+///
+/// Returns `None` only if `nets` is empty.
+pub fn select_source<'a, I>(nets: I, dst: Ipv4Addr, next_hop: Ipv4Addr) -> Option<Ipv4Addr>
+    where I: IntoIterator<Item = &'a Ipv4Network>
+{
+    let dst_bits = u32::from(dst);
+    nets.into_iter()
+        .max_by_key(|net| {
+            let ip_bits = u32::from(net.ip());
+            (net.ip() == dst,
+             net.contains(next_hop),
+             net.contains(dst),
+             (ip_bits ^ dst_bits).leading_zeros(),
+             !ip_bits)
+        })
+        .map(|net| net.ip())
+}
+
+fn default_net() -> Ipv4Network {
+    Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap()
 }
 
 
@@ -115,6 +507,371 @@ mod tests {
         assert_eq!(out_eth2, iface("eth1"));
     }
 
+    #[test]
+    fn replace_duplicate() {
+        let gw = Ipv4Addr::new(10, 0, 0, 1);
+        let net = Ipv4Network::from_str("10.0.0.0/24").unwrap();
+
+        let mut table = RoutingTable::new();
+        assert!(table.add_route(net, None, iface("eth0")).is_none());
+        let old_entry = table.add_route(Ipv4Network::from_str("10.0.0.5/24").unwrap(),
+                                        Some(gw),
+                                        iface("eth1"))
+            .unwrap();
+        assert_eq!(old_entry.gw, None);
+        assert_eq!(old_entry.interface, iface("eth0"));
+
+        assert_eq!(table.routes().len(), 1);
+        let (out_gw, out_eth) = table.route(Ipv4Addr::new(10, 0, 0, 20)).unwrap();
+        assert_eq!(out_gw, Some(gw));
+        assert_eq!(out_eth, iface("eth1"));
+    }
+
+    #[test]
+    fn remove() {
+        let net = Ipv4Network::from_str("10.0.0.0/24").unwrap();
+
+        let mut table = RoutingTable::new();
+        table.add_route(net, None, iface("eth0"));
+        table.add_route(Ipv4Network::from_str("0/0").unwrap(), None, iface("eth1"));
+        assert!(table.remove_route(Ipv4Network::from_str("10.0.0.0/16").unwrap()).is_none());
+
+        let removed = table.remove_route(net).unwrap();
+        assert_eq!(removed.net, net);
+        assert!(table.remove_route(net).is_none());
+
+        let (_, out_eth) = table.route(Ipv4Addr::new(10, 0, 0, 20)).unwrap();
+        assert_eq!(out_eth, iface("eth1"));
+    }
+
+    #[test]
+    fn routes_most_specific_first() {
+        let mut table = RoutingTable::new();
+        assert!(table.routes().is_empty());
+        table.add_route(Ipv4Network::from_str("0/0").unwrap(), None, iface("eth1"));
+        table.add_route(Ipv4Network::from_str("10.0.0.0/24").unwrap(),
+                        None,
+                        iface("eth0"));
+        table.add_route(Ipv4Network::from_str("10/8").unwrap(), None, iface("eth0"));
+
+        let prefixes = table.routes().iter().map(|entry| entry.net.prefix()).collect::<Vec<_>>();
+        assert_eq!(prefixes, vec![24, 8, 0]);
+    }
+
+    #[test]
+    fn overlapping_prefixes() {
+        let mut table = RoutingTable::new();
+        table.add_route(Ipv4Network::from_str("0/0").unwrap(), None, iface("eth0"));
+        table.add_route(Ipv4Network::from_str("10/8").unwrap(), None, iface("eth1"));
+        table.add_route(Ipv4Network::from_str("10.1/16").unwrap(), None, iface("eth2"));
+        table.add_route(Ipv4Network::from_str("10.1.1/24").unwrap(), None, iface("eth3"));
+
+        let out_eth = |ip| table.route(ip).unwrap().1;
+        assert_eq!(out_eth(Ipv4Addr::new(192, 168, 0, 1)), iface("eth0"));
+        assert_eq!(out_eth(Ipv4Addr::new(10, 2, 0, 1)), iface("eth1"));
+        assert_eq!(out_eth(Ipv4Addr::new(10, 1, 2, 1)), iface("eth2"));
+        assert_eq!(out_eth(Ipv4Addr::new(10, 1, 1, 1)), iface("eth3"));
+    }
+
+    #[test]
+    fn prefix_beats_metric() {
+        let mut table = RoutingTable::new();
+        table.add_route_with_metric(Ipv4Network::from_str("10/8").unwrap(),
+                                    None,
+                                    iface("eth0"),
+                                    1);
+        table.add_route_with_metric(Ipv4Network::from_str("10.0/16").unwrap(),
+                                    None,
+                                    iface("eth1"),
+                                    100);
+        let (_, out_eth) = table.route(Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+        assert_eq!(out_eth, iface("eth1"));
+    }
+
+    #[test]
+    fn lowest_metric_wins() {
+        let net = Ipv4Network::from_str("0/0").unwrap();
+        let gw1 = Ipv4Addr::new(10, 0, 0, 1);
+        let gw2 = Ipv4Addr::new(10, 0, 0, 2);
+
+        let mut table = RoutingTable::new();
+        assert!(table.add_route_with_metric(net, Some(gw1), iface("eth0"), 20).is_none());
+        assert!(table.add_route_with_metric(net, Some(gw2), iface("eth1"), 10).is_none());
+        assert_eq!(table.routes().len(), 2);
+
+        {
+            let entry = table.lookup(Ipv4Addr::new(1, 2, 3, 4)).unwrap();
+            assert_eq!(entry.gw, Some(gw2));
+            assert_eq!(entry.metric, 10);
+        }
+
+        // Removing the preferred route falls back to the next one
+        assert_eq!(table.remove_route(net).unwrap().gw, Some(gw2));
+        let entry = table.lookup(Ipv4Addr::new(1, 2, 3, 4)).unwrap();
+        assert_eq!(entry.gw, Some(gw1));
+    }
+
+    #[test]
+    fn insertion_order_irrelevant() {
+        let net = Ipv4Network::from_str("10.0.0.0/24").unwrap();
+        let mut table1 = RoutingTable::new();
+        table1.add_route_with_metric(net, None, iface("eth0"), 5);
+        table1.add_route_with_metric(net, None, iface("eth1"), 7);
+        let mut table2 = RoutingTable::new();
+        table2.add_route_with_metric(net, None, iface("eth1"), 7);
+        table2.add_route_with_metric(net, None, iface("eth0"), 5);
+
+        assert_eq!(table1.routes(), table2.routes());
+        let ip = Ipv4Addr::new(10, 0, 0, 9);
+        assert_eq!(table1.route(ip), table2.route(ip));
+    }
+
+    #[test]
+    fn remove_with_metric() {
+        let net = Ipv4Network::from_str("10.0.0.0/24").unwrap();
+        let mut table = RoutingTable::new();
+        table.add_route_with_metric(net, None, iface("eth0"), 5);
+        table.add_route_with_metric(net, None, iface("eth1"), 7);
+
+        assert!(table.remove_route_with_metric(net, 6).is_none());
+        assert_eq!(table.remove_route_with_metric(net, 7).unwrap().interface,
+                   iface("eth1"));
+        let (_, out_eth) = table.route(Ipv4Addr::new(10, 0, 0, 9)).unwrap();
+        assert_eq!(out_eth, iface("eth0"));
+    }
+
+    #[test]
+    fn version() {
+        let net = Ipv4Network::from_str("10/8").unwrap();
+        let mut table = RoutingTable::new();
+        let v0 = table.version();
+        table.add_route(net, None, iface("eth0"));
+        let v1 = table.version();
+        assert!(v1 != v0);
+        table.route(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(table.remove_route(Ipv4Network::from_str("10/16").unwrap()).is_none());
+        assert_eq!(v1, table.version());
+        table.remove_route(net);
+        assert!(table.version() != v1);
+    }
+
+    #[test]
+    fn route_mtu() {
+        let net = Ipv4Network::from_str("10/8").unwrap();
+        let mut table = RoutingTable::new();
+        assert!(table.set_route_mtu(net, Some(1400)).is_err());
+
+        table.add_route_with_metric(net, None, iface("eth0"), 1);
+        table.add_route_with_metric(net, None, iface("eth1"), 2);
+        assert!(table.lookup(Ipv4Addr::new(10, 0, 0, 1)).unwrap().mtu.is_none());
+        assert!(table.set_route_mtu(net, Some(MIN_MTU - 1)).is_err());
+
+        let version = table.version();
+        table.set_route_mtu(net, Some(1400)).unwrap();
+        assert!(table.version() != version);
+        assert!(table.routes().iter().all(|entry| entry.mtu == Some(1400)));
+
+        table.set_route_mtu(net, None).unwrap();
+        assert!(table.routes().iter().all(|entry| entry.mtu.is_none()));
+    }
+
+    #[test]
+    fn set_default_route() {
+        let gw = Ipv4Addr::new(10, 0, 0, 1);
+
+        let mut table = RoutingTable::new();
+        assert!(table.default_route().is_none());
+        table.add_route(Ipv4Network::from_str("10/16").unwrap(), None, iface("eth0"));
+        assert!(table.set_default_route(gw, iface("eth0")).unwrap().is_none());
+
+        {
+            let entry = table.default_route().unwrap();
+            assert_eq!(entry.net, Ipv4Network::from_str("0/0").unwrap());
+            assert_eq!(entry.gw, Some(gw));
+            assert_eq!(entry.interface, iface("eth0"));
+        }
+        let (out_gw, out_eth) = table.route(Ipv4Addr::new(8, 8, 8, 8)).unwrap();
+        assert_eq!(out_gw, Some(gw));
+        assert_eq!(out_eth, iface("eth0"));
+
+        let gw2 = Ipv4Addr::new(10, 0, 0, 2);
+        let old_entry = table.set_default_route(gw2, iface("eth0")).unwrap().unwrap();
+        assert_eq!(old_entry.gw, Some(gw));
+        assert_eq!(table.default_route().unwrap().gw, Some(gw2));
+    }
+
+    #[test]
+    fn set_default_route_unreachable_gw() {
+        let mut table = RoutingTable::new();
+        table.add_route(Ipv4Network::from_str("10/16").unwrap(), None, iface("eth0"));
+        table.add_route(Ipv4Network::from_str("192.168.0.0/24").unwrap(),
+                        Some(Ipv4Addr::new(10, 0, 0, 1)),
+                        iface("eth0"));
+
+        // Wrong interface
+        assert!(table.set_default_route(Ipv4Addr::new(10, 0, 0, 1), iface("eth1")).is_err());
+        // Not on any connected network
+        assert!(table.set_default_route(Ipv4Addr::new(10, 1, 0, 1), iface("eth0")).is_err());
+        // Only reachable through another gateway
+        assert!(table.set_default_route(Ipv4Addr::new(192, 168, 0, 1), iface("eth0")).is_err());
+        assert!(table.default_route().is_none());
+    }
+
+    #[test]
+    fn remove_network() {
+        let net = Ipv4Network::from_str("10.0.0.5/24").unwrap();
+        let other = Ipv4Network::from_str("10.1.0.0/16").unwrap();
+        let mut table = RoutingTable::new();
+        table.add_route(net, None, iface("eth0"));
+        table.add_route(other, None, iface("eth0"));
+        table.add_route_with_metric(net, None, iface("eth1"), DEFAULT_METRIC + 1);
+        table.set_default_route(Ipv4Addr::new(10, 0, 0, 1), iface("eth0")).unwrap();
+        table.add_route(Ipv4Network::from_str("192.168.0.0/24").unwrap(),
+                        Some(Ipv4Addr::new(10, 1, 0, 1)),
+                        iface("eth0"));
+
+        let removed = table.remove_network(net, &iface("eth0"));
+        assert_eq!(removed.iter().map(|entry| entry.net.prefix()).collect::<Vec<_>>(),
+                   vec![24, 0]);
+        assert!(table.default_route().is_none());
+        // Still reachable through the other network of the interface
+        assert!(table.route(Ipv4Addr::new(192, 168, 0, 1)).is_some());
+        assert_eq!(table.route(Ipv4Addr::new(10, 0, 0, 9)), Some((None, iface("eth1"))));
+        assert!(table.remove_network(net, &iface("eth0")).is_empty());
+    }
+
+    #[test]
+    fn route_src() {
+        let net = Ipv4Network::from_str("10/8").unwrap();
+        let src = Ipv4Addr::new(10, 0, 0, 5);
+        let mut table = RoutingTable::new();
+        assert!(table.set_route_src(net, Some(src)).is_err());
+        table.add_route(net, None, iface("eth0"));
+        table.set_route_src(net, Some(src)).unwrap();
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 1, 1, 1)).unwrap().src, Some(src));
+    }
+
+    #[test]
+    fn link_down_withdraws() {
+        let gw = Ipv4Addr::new(10, 1, 0, 1);
+        let net = Ipv4Network::from_str("10.0.0.0/24").unwrap();
+        let mut table = RoutingTable::new();
+        table.add_route(net, None, iface("eth0"));
+        table.add_route_with_metric(net, Some(gw), iface("eth1"), 10);
+        let dst = Ipv4Addr::new(10, 0, 0, 5);
+
+        let version = table.version();
+        assert!(table.set_link_up(&iface("eth0"), false));
+        assert!(!table.set_link_up(&iface("eth0"), false));
+        assert!(table.version() != version);
+        assert!(!table.is_link_up(&iface("eth0")));
+        assert_eq!(table.route(dst), Some((Some(gw), iface("eth1"))));
+        assert_eq!(table.routes().len(), 2);
+
+        assert!(table.set_link_up(&iface("eth1"), false));
+        assert!(table.route(dst).is_none());
+        assert!(table.set_link_up(&iface("eth0"), true));
+        assert_eq!(table.route(dst), Some((None, iface("eth0"))));
+    }
+
+    #[test]
+    fn gateway_down_withdraws() {
+        let (gw1, gw2) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let mut table = RoutingTable::new();
+        table.add_route(Ipv4Network::from_str("10.0.0.0/24").unwrap(), None, iface("eth0"));
+        table.add_default_route(gw2, iface("eth0"), 10).unwrap();
+        table.add_default_route(gw1, iface("eth0"), 5).unwrap();
+        assert!(table.add_default_route(Ipv4Addr::new(10, 1, 0, 1), iface("eth0"), 1).is_err());
+        let dst = Ipv4Addr::new(8, 8, 8, 8);
+        assert_eq!(table.route(dst), Some((Some(gw1), iface("eth0"))));
+
+        let version = table.version();
+        assert!(table.set_gateway_up(gw1, false));
+        assert!(!table.set_gateway_up(gw1, false));
+        assert!(table.version() != version);
+        assert!(!table.is_gateway_up(gw1));
+        assert_eq!(table.route(dst), Some((Some(gw2), iface("eth0"))));
+        assert_eq!(table.active_default_route().unwrap().gw, Some(gw2));
+        assert_eq!(table.default_route().unwrap().gw, Some(gw1));
+        let gws: Vec<_> = table.default_routes().iter().map(|entry| entry.gw).collect();
+        assert_eq!(gws, vec![Some(gw1), Some(gw2)]);
+        // Directly connected routes have no gateway to go down
+        assert_eq!(table.route(gw1), Some((None, iface("eth0"))));
+
+        assert!(table.set_gateway_up(gw2, false));
+        assert!(table.route(dst).is_none());
+        assert!(table.active_default_route().is_none());
+        assert!(table.set_gateway_up(gw1, true));
+        assert_eq!(table.route(dst), Some((Some(gw1), iface("eth0"))));
+    }
+
+    #[test]
+    fn origin_keeps_routes_apart() {
+        let net = Ipv4Network::from_str("10.1.0.0/16").unwrap();
+        let (gw1, gw2) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let mut table = RoutingTable::new();
+        table.add_route_with_metric(net, Some(gw1), iface("eth0"), 2);
+        assert!(table.add_route_with_origin(net, Some(gw2), iface("eth0"), 2, RouteOrigin::Rip)
+            .is_err());
+        assert!(table.remove_route_with_origin(net, 2, RouteOrigin::Rip).is_none());
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 1, 0, 1)).unwrap().origin,
+                   RouteOrigin::Static);
+
+        table.add_route_with_origin(net, Some(gw2), iface("eth0"), 3, RouteOrigin::Rip).unwrap();
+        let old = table.add_route_with_origin(net, Some(gw1), iface("eth0"), 3, RouteOrigin::Rip)
+            .unwrap();
+        assert_eq!(old.unwrap().gw, Some(gw2));
+        assert!(table.remove_route_with_origin(net, 3, RouteOrigin::Rip).is_some());
+        assert_eq!(table.routes().len(), 1);
+    }
+
+    #[test]
+    fn select_source_empty() {
+        let ip = Ipv4Addr::new(10, 0, 0, 1);
+        assert!(select_source(&[], ip, ip).is_none());
+    }
+
+    #[test]
+    fn select_source_own_address() {
+        let nets = [Ipv4Network::from_str("10.0.0.1/24").unwrap(),
+                    Ipv4Network::from_str("10.0.0.2/24").unwrap()];
+        let dst = Ipv4Addr::new(10, 0, 0, 2);
+        assert_eq!(select_source(&nets, dst, dst), Some(dst));
+    }
+
+    #[test]
+    fn select_source_next_hop_network() {
+        let nets = [Ipv4Network::from_str("192.168.0.5/24").unwrap(),
+                    Ipv4Network::from_str("10.0.0.5/24").unwrap()];
+        let gw = Ipv4Addr::new(10, 0, 0, 1);
+        let dst = Ipv4Addr::new(192, 168, 1, 1);
+        assert_eq!(select_source(&nets, dst, gw),
+                   Some(Ipv4Addr::new(10, 0, 0, 5)));
+    }
+
+    #[test]
+    fn select_source_longest_prefix() {
+        let nets = [Ipv4Network::from_str("10.0.0.5/32").unwrap(),
+                    Ipv4Network::from_str("172.16.0.5/32").unwrap()];
+        // Gateway outside of all local networks
+        let gw = Ipv4Addr::new(10, 137, 8, 1);
+        let dst = Ipv4Addr::new(172, 16, 99, 1);
+        assert_eq!(select_source(&nets, dst, gw),
+                   Some(Ipv4Addr::new(172, 16, 0, 5)));
+    }
+
+    #[test]
+    fn select_source_deterministic() {
+        let nets1 = [Ipv4Network::from_str("10.0.0.6/24").unwrap(),
+                     Ipv4Network::from_str("10.0.0.5/24").unwrap()];
+        let nets2 = [nets1[1], nets1[0]];
+        let dst = Ipv4Addr::new(10, 0, 0, 4);
+        assert_eq!(select_source(&nets1, dst, dst),
+                   Some(Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(select_source(&nets2, dst, dst),
+                   Some(Ipv4Addr::new(10, 0, 0, 5)));
+    }
+
     fn iface(name: &str) -> Interface {
         Interface {
             name: name.to_string(),
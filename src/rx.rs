@@ -1,48 +1,238 @@
-use RxResult;
+use {RxError, RxResult};
+use malformed;
+use threads::ThreadConfig;
+use util;
 
-use pnet::datalink::EthernetDataLinkReceiver;
+use pnet::datalink::{EthernetDataLinkChannelIterator, EthernetDataLinkReceiver};
 use pnet::packet::ethernet::EthernetPacket;
 
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+/// How long receivers of this crate wait for a frame before returning a
+/// `TimedOut` error, letting their rx thread check whether it should stop.
+pub static POLL_INTERVAL_MS: u64 = 100;
 
 pub trait RxListener: Send {
     fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult;
 }
 
-pub fn spawn<L>(receiver: Box<EthernetDataLinkReceiver>, listener: L)
+/// Logs `error`, returned for a frame received. Malformed frames are only
+/// logged at debug level, their interface logs them as its
+/// `MalformedPolicy` says.
+pub fn log_error(error: &RxError) {
+    if malformed::is_malformed(error) {
+        debug!("RxError: {:?}", error);
+    } else {
+        warn!("RxError: {:?}", error);
+    }
+}
+
+/// Handle to a thread started with `spawn`.
+pub struct RxHandle {
+    stop: Arc<AtomicBool>,
+    done: Receiver<()>,
+}
+
+impl RxHandle {
+    /// Asks the thread to stop. It does so the next time its receiver
+    /// returns, so a receiver without a read timeout must see a frame or an
+    /// error first.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits at most `timeout` for the thread to quit. Returns true if it
+    /// did.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        match self.done.recv_timeout(timeout) {
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+        }
+    }
+}
+
+/// Why a thread started with `spawn_reporting` quit before it was stopped.
+#[derive(Debug)]
+pub enum RxFailure {
+    /// The receiver failed with this error
+    Datalink(io::Error),
+    /// The listener panicked with this message
+    Panic(String),
+}
+
+/// Spawns a thread handing every frame from `receiver` to `listener`, until
+/// stopped through the returned handle or `receiver` fails. `TimedOut` and
+/// `WouldBlock` errors from `receiver` are ignored.
+pub fn spawn<L>(receiver: Box<EthernetDataLinkReceiver>, listener: L) -> RxHandle
     where L: RxListener + 'static
 {
-    let rx_thread = RxThread::new(receiver, listener);
-    thread::spawn(move || {
-        rx_thread.run();
+    spawn_reporting(receiver, listener, |_| ())
+}
+
+/// Same as `spawn`, but calls `report` from the thread if it quits because
+/// `receiver` failed or `listener` panicked.
+pub fn spawn_reporting<L, F>(receiver: Box<EthernetDataLinkReceiver>,
+                             listener: L,
+                             report: F)
+                             -> RxHandle
+    where L: RxListener + 'static,
+          F: FnOnce(RxFailure) + Send + 'static
+{
+    spawn_with(&ThreadConfig::default(), receiver, listener, report)
+}
+
+/// Same as `spawn_reporting`, but spawns the thread as `config` says.
+pub fn spawn_with<L, F>(config: &ThreadConfig,
+                        receiver: Box<EthernetDataLinkReceiver>,
+                        listener: L,
+                        report: F)
+                        -> RxHandle
+    where L: RxListener + 'static,
+          F: FnOnce(RxFailure) + Send + 'static
+{
+    spawn_busy_polling(config, None, receiver, listener, report)
+}
+
+/// Same as `spawn_with`, but with a `busy_poll` budget the thread spins on
+/// `receiver` instead of leaving the waiting to it. Every time `receiver`
+/// returns `TimedOut` or `WouldBlock` it is asked again right away, and
+/// only after `budget` such polls in a row does the thread yield the CPU
+/// to other threads once. This only saves time if `receiver` returns right
+/// away when there is no frame, like a pnet channel opened with a zero
+/// `read_timeout`. A receiver waiting for frames keeps waiting as long as
+/// it does without busy polling.
+pub fn spawn_busy_polling<L, F>(config: &ThreadConfig,
+                                busy_poll: Option<usize>,
+                                receiver: Box<EthernetDataLinkReceiver>,
+                                listener: L,
+                                report: F)
+                                -> RxHandle
+    where L: RxListener + 'static,
+          F: FnOnce(RxFailure) + Send + 'static
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let (done_tx, done) = mpsc::channel();
+    let rx_thread = RxThread::new(receiver, listener, stop.clone(), busy_poll);
+    config.spawn(move || {
+        // Dropped when the thread quits, even by panicking
+        let _done_tx = done_tx;
+        match panic::catch_unwind(AssertUnwindSafe(|| rx_thread.run())) {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => report(RxFailure::Datalink(e)),
+            Err(payload) => report(RxFailure::Panic(util::panic_message(&payload))),
+        }
     });
+    RxHandle {
+        stop: stop,
+        done: done,
+    }
 }
 
 struct RxThread<L: RxListener> {
     receiver: Box<EthernetDataLinkReceiver>,
     listener: L,
+    stop: Arc<AtomicBool>,
+    /// Empty polls before yielding, if busy polling
+    busy_poll: Option<usize>,
 }
 
 impl<L: RxListener> RxThread<L> {
-    pub fn new(receiver: Box<EthernetDataLinkReceiver>, listener: L) -> Self {
+    pub fn new(receiver: Box<EthernetDataLinkReceiver>,
+               listener: L,
+               stop: Arc<AtomicBool>,
+               busy_poll: Option<usize>)
+               -> Self {
         RxThread {
             receiver: receiver,
             listener: listener,
+            stop: stop,
+            busy_poll: busy_poll,
         }
     }
 
-    fn run(mut self) {
+    /// Receives until stopped, or until the receiver fails with the
+    /// returned error.
+    fn run(mut self) -> io::Result<()> {
         let mut rx_iter = self.receiver.iter();
-        loop {
+        let mut empty_polls = 0;
+        while !self.stop.load(Ordering::SeqCst) {
             match rx_iter.next() {
                 Ok(packet) => {
+                    empty_polls = 0;
                     let time = SystemTime::now();
                     if let Err(e) = self.listener.recv(time, &packet) {
-                        warn!("RxError: {:?}", e);
+                        log_error(&e);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut ||
+                              e.kind() == io::ErrorKind::WouldBlock => {
+                    if let Some(budget) = self.busy_poll {
+                        empty_polls += 1;
+                        if empty_polls > budget {
+                            thread::yield_now();
+                            empty_polls = 0;
+                        }
                     }
                 }
-                Err(e) => panic!("RxThread crash: {}", e),
+                // Whatever fails while stopping is no failure
+                Err(_) if self.stop.load(Ordering::SeqCst) => break,
+                Err(e) => {
+                    error!("RxThread quitting on error: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+        debug!("RxThread is quitting");
+        Ok(())
+    }
+}
+
+/// An `EthernetDataLinkReceiver` yielding the frames sent to a channel. Used
+/// by virtual interfaces, which get their frames from other parts of the
+/// stack.
+pub struct ChannelReceiver {
+    frames: Option<Receiver<Box<[u8]>>>,
+}
+
+impl ChannelReceiver {
+    pub fn new(frames: Receiver<Box<[u8]>>) -> ChannelReceiver {
+        ChannelReceiver { frames: Some(frames) }
+    }
+}
+
+impl EthernetDataLinkReceiver for ChannelReceiver {
+    fn iter<'a>(&'a mut self) -> Box<EthernetDataLinkChannelIterator + 'a> {
+        Box::new(ChannelReceiverIterator {
+            frames: self.frames.take().expect("Only one receiver allowed"),
+            current: None,
+        })
+    }
+}
+
+struct ChannelReceiverIterator {
+    frames: Receiver<Box<[u8]>>,
+    current: Option<Box<[u8]>>,
+}
+
+impl<'a> EthernetDataLinkChannelIterator<'a> for ChannelReceiverIterator {
+    fn next(&mut self) -> io::Result<EthernetPacket> {
+        match self.frames.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+            Ok(frame) => {
+                self.current = Some(frame);
+                Ok(EthernetPacket::new(self.current.as_ref().unwrap()).unwrap())
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received"))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                // Whatever fed the channel is gone, so the rx thread can quit
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "Channel closed"))
             }
         }
     }
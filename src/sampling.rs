@@ -0,0 +1,518 @@
+//! Sampling one in every so many received frames, as sFlow does.
+//!
+//! Looking at every frame is too much on a busy link. A `Sampler` added as
+//! an observer of an interface, see `observe`, picks one in every
+//! `SamplingConfig::rate` frames the interface receives at random, and hands
+//! the first `SamplingConfig::header_len` bytes of it to a `SampleConsumer`,
+//! with the counters needed to scale what it sees back up to the whole
+//! traffic:
+//!
+//! ```rust,ignore
+//! let consumer = |sample: &PacketSample| {
+//!     debug!("1 in {}: {} bytes on {}", sample.rate, sample.frame_len, sample.interface.name);
+//! };
+//! stack.interface(&interface)?.add_observer(Sampler::new(SamplingConfig::default(), consumer));
+//! ```
+//!
+//! Frames are sampled where observers see them, before any filter, so the
+//! samples show what arrived on the link rather than what the stack
+//! accepted. An `SflowExporter` samples every interface of a stack and
+//! sends the samples to an sFlow collector, as version 5 datagrams of flow
+//! samples with raw packet headers, from a UDP socket of the stack itself.
+
+use {Interface, NetworkStack};
+use observe::{Observer, ObserverId};
+use pcap::Direction;
+use udp::UdpSocket;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::EthernetPacket;
+
+use rand::{self, XorShiftRng};
+use rand::distributions::{IndependentSample, Range};
+
+use std::cmp;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The UDP port of sFlow collectors.
+pub const SFLOW_PORT: u16 = 6343;
+
+/// The largest datagram an `SflowExporter` sends.
+pub const MAX_DATAGRAM_LEN: usize = 1400;
+
+/// Samples an `SflowExporter` holds while the thread sending them is busy.
+/// Samples beyond these are dropped, and reported as such to the collector.
+pub const QUEUE_LEN: usize = 1024;
+
+/// How long an `SflowExporter` waits for more samples to fill a datagram.
+pub static FLUSH_INTERVAL_MS: u64 = 250;
+
+const VERSION: u32 = 5;
+const ADDRESS_IPV4: u32 = 1;
+/// Formats of the standard structures, in enterprise 0
+const FLOW_SAMPLE: u32 = 1;
+const RAW_PACKET_HEADER: u32 = 1;
+const HEADER_PROTOCOL_ETHERNET: u32 = 1;
+const DATAGRAM_HEADER_LEN: usize = 7 * 4;
+/// A flow sample with one raw packet header record, without the header
+const SAMPLE_LEN: usize = 10 * 4 + 6 * 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplingConfig {
+    /// One in this many frames is sampled, on average. One samples all
+    pub rate: u32,
+    /// Bytes of the start of a frame a sample carries, at most
+    pub header_len: usize,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> SamplingConfig {
+        SamplingConfig {
+            rate: 1000,
+            header_len: 128,
+        }
+    }
+}
+
+/// A frame picked by a `Sampler`.
+#[derive(Debug)]
+pub struct PacketSample<'a> {
+    pub interface: &'a Interface,
+    pub time: SystemTime,
+    /// Samples taken before this one by the sampler
+    pub sequence: u32,
+    /// The `SamplingConfig::rate` the frame was sampled at
+    pub rate: u32,
+    /// Frames the sampler has seen, wrapping around
+    pub pool: u32,
+    /// The length of the whole frame
+    pub frame_len: usize,
+    /// The start of the frame, up to `SamplingConfig::header_len` bytes
+    pub header: &'a [u8],
+}
+
+/// Code taking the samples of a `Sampler`. Called on the rx thread of the
+/// interface, so it should be quick. Implemented for all closures with the
+/// same signature as `consume`.
+pub trait SampleConsumer: Send + Sync {
+    fn consume(&self, sample: &PacketSample);
+}
+
+impl<F> SampleConsumer for F
+    where F: Fn(&PacketSample) + Send + Sync
+{
+    fn consume(&self, sample: &PacketSample) {
+        self(sample)
+    }
+}
+
+/// An observer sampling the frames an interface receives. See the module
+/// documentation.
+pub struct Sampler<C> {
+    config: SamplingConfig,
+    /// Frames to go until the next sample
+    skip: AtomicUsize,
+    pool: AtomicUsize,
+    sequence: AtomicUsize,
+    rng: Mutex<XorShiftRng>,
+    consumer: C,
+}
+
+impl<C: SampleConsumer> Sampler<C> {
+    /// Creates a sampler handing samples to `consumer`. A `rate` of zero is
+    /// taken as one.
+    pub fn new(config: SamplingConfig, consumer: C) -> Sampler<C> {
+        let config = SamplingConfig { rate: cmp::max(config.rate, 1), ..config };
+        let mut rng = rand::weak_rng();
+        let skip = Self::next_skip(config.rate, &mut rng);
+        Sampler {
+            config: config,
+            skip: AtomicUsize::new(skip),
+            pool: AtomicUsize::new(0),
+            sequence: AtomicUsize::new(0),
+            rng: Mutex::new(rng),
+            consumer: consumer,
+        }
+    }
+
+    /// Picks how many frames to go until the next sample, between 1 and
+    /// twice `rate`, so one in `rate` is sampled on average without the
+    /// samples following a pattern of the traffic.
+    fn next_skip(rate: u32, rng: &mut XorShiftRng) -> usize {
+        if rate == 1 {
+            1
+        } else {
+            Range::new(1, 2 * rate as usize).ind_sample(rng)
+        }
+    }
+}
+
+impl<C: SampleConsumer> Observer for Sampler<C> {
+    fn observe(&self,
+               direction: Direction,
+               interface: &Interface,
+               time: SystemTime,
+               frame: &EthernetPacket) {
+        if direction != Direction::Inbound {
+            return;
+        }
+        let pool = self.pool.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        // Frames are received one at a time, nothing else counts down
+        if self.skip.fetch_sub(1, Ordering::Relaxed) != 1 {
+            return;
+        }
+        let skip = Self::next_skip(self.config.rate, &mut self.rng.lock().unwrap());
+        self.skip.store(skip, Ordering::Relaxed);
+        let frame = frame.packet();
+        let sample = PacketSample {
+            interface: interface,
+            time: time,
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed) as u32,
+            rate: self.config.rate,
+            pool: pool as u32,
+            frame_len: frame.len(),
+            header: &frame[..cmp::min(frame.len(), self.config.header_len)],
+        };
+        self.consumer.consume(&sample);
+    }
+}
+
+/// A sample as an `SflowExporter` encodes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowSample {
+    /// The index of the interface the frame was received on, starting at
+    /// one
+    pub if_index: u32,
+    pub sequence: u32,
+    pub rate: u32,
+    pub pool: u32,
+    /// Samples the exporter dropped so far
+    pub drops: u32,
+    pub frame_len: u32,
+    pub header: Vec<u8>,
+}
+
+impl FlowSample {
+    fn encoded_len(&self) -> usize {
+        SAMPLE_LEN + padded(self.header.len())
+    }
+}
+
+/// Encodes `FlowSample`s as sFlow version 5 datagrams, numbering them as
+/// one agent sending them in order.
+pub struct SflowEncoder {
+    agent: Ipv4Addr,
+    sub_agent_id: u32,
+    sequence: u32,
+    started: Instant,
+}
+
+impl SflowEncoder {
+    /// Creates an encoder for the agent at `agent`. Its uptime starts now.
+    pub fn new(agent: Ipv4Addr, sub_agent_id: u32) -> SflowEncoder {
+        SflowEncoder {
+            agent: agent,
+            sub_agent_id: sub_agent_id,
+            sequence: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the datagrams of `samples`, as many as they need to have
+    /// none longer than `MAX_DATAGRAM_LEN`. Returns none if there are no
+    /// samples.
+    pub fn encode(&mut self, samples: &[FlowSample]) -> Vec<Vec<u8>> {
+        let mut datagrams = Vec::new();
+        let mut start = 0;
+        while start < samples.len() {
+            let mut len = DATAGRAM_HEADER_LEN + samples[start].encoded_len();
+            let mut end = start + 1;
+            while end < samples.len() && len + samples[end].encoded_len() <= MAX_DATAGRAM_LEN {
+                len += samples[end].encoded_len();
+                end += 1;
+            }
+            datagrams.push(self.datagram(&samples[start..end], len));
+            start = end;
+        }
+        datagrams
+    }
+
+    fn datagram(&mut self, samples: &[FlowSample], len: usize) -> Vec<u8> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let uptime = self.started.elapsed();
+        let uptime = uptime.as_secs() * 1000 + uptime.subsec_nanos() as u64 / 1_000_000;
+        let mut datagram = Vec::with_capacity(len);
+        push_u32(&mut datagram, VERSION);
+        push_u32(&mut datagram, ADDRESS_IPV4);
+        datagram.extend_from_slice(&self.agent.octets());
+        push_u32(&mut datagram, self.sub_agent_id);
+        push_u32(&mut datagram, self.sequence);
+        push_u32(&mut datagram, uptime as u32);
+        push_u32(&mut datagram, samples.len() as u32);
+        for sample in samples {
+            let header_len = sample.header.len();
+            push_u32(&mut datagram, FLOW_SAMPLE);
+            push_u32(&mut datagram, (sample.encoded_len() - 8) as u32);
+            push_u32(&mut datagram, sample.sequence);
+            // Source type 0, an ifIndex
+            push_u32(&mut datagram, sample.if_index);
+            push_u32(&mut datagram, sample.rate);
+            push_u32(&mut datagram, sample.pool);
+            push_u32(&mut datagram, sample.drops);
+            push_u32(&mut datagram, sample.if_index);
+            // Output interface, not known
+            push_u32(&mut datagram, 0);
+            push_u32(&mut datagram, 1);
+
+            push_u32(&mut datagram, RAW_PACKET_HEADER);
+            push_u32(&mut datagram, (4 * 4 + padded(header_len)) as u32);
+            push_u32(&mut datagram, HEADER_PROTOCOL_ETHERNET);
+            push_u32(&mut datagram, sample.frame_len);
+            // Bytes removed from the frame, the datalink has no FCS to remove
+            push_u32(&mut datagram, 0);
+            push_u32(&mut datagram, header_len as u32);
+            datagram.extend_from_slice(&sample.header);
+            datagram.extend_from_slice(&[0; 3][..padded(header_len) - header_len]);
+        }
+        datagram
+    }
+}
+
+/// Rounds `len` up to whole 4 byte words, as XDR pads opaque data.
+fn padded(len: usize) -> usize {
+    (len + 3) / 4 * 4
+}
+
+fn push_u32(datagram: &mut Vec<u8>, value: u32) {
+    datagram.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                                 value as u8]);
+}
+
+/// Queues the samples of one interface for the thread of an
+/// `SflowExporter`.
+struct Queue {
+    if_index: u32,
+    samples: Mutex<SyncSender<FlowSample>>,
+    drops: Arc<AtomicUsize>,
+}
+
+impl SampleConsumer for Queue {
+    fn consume(&self, sample: &PacketSample) {
+        let sample = FlowSample {
+            if_index: self.if_index,
+            sequence: sample.sequence,
+            rate: sample.rate,
+            pool: sample.pool,
+            drops: self.drops.load(Ordering::Relaxed) as u32,
+            frame_len: sample.frame_len as u32,
+            header: sample.header.to_vec(),
+        };
+        if let Err(TrySendError::Full(_)) = self.samples.lock().unwrap().try_send(sample) {
+            self.drops.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Samples all interfaces of a stack and exports the samples with sFlow,
+/// from a thread of its own. See the module documentation. Stops when
+/// dropped.
+pub struct SflowExporter {
+    stack: Arc<Mutex<NetworkStack>>,
+    observers: Vec<(Interface, ObserverId)>,
+    drops: Arc<AtomicUsize>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SflowExporter {
+    /// Starts sampling the interfaces `stack` has now with `config`, and
+    /// exporting to `collector` from a UDP socket bound to `local`. The
+    /// interfaces are numbered from one in the order of their names, and
+    /// the agent address is the one of `local`.
+    pub fn start(stack: Arc<Mutex<NetworkStack>>,
+                 local: SocketAddrV4,
+                 collector: SocketAddrV4,
+                 config: SamplingConfig)
+                 -> io::Result<SflowExporter> {
+        let socket = UdpSocket::bind(stack.clone(), local)?;
+        let (samples_tx, samples) = mpsc::sync_channel(QUEUE_LEN);
+        let drops = Arc::new(AtomicUsize::new(0));
+        let mut observers = Vec::new();
+        {
+            let mut stack = stack.lock().unwrap();
+            let mut interfaces = stack.interfaces();
+            interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+            for (i, interface) in interfaces.into_iter().enumerate() {
+                let queue = Queue {
+                    if_index: i as u32 + 1,
+                    samples: Mutex::new(samples_tx.clone()),
+                    drops: drops.clone(),
+                };
+                let id = stack.interface(&interface)?.add_observer(Sampler::new(config, queue));
+                observers.push((interface, id));
+            }
+        }
+        let encoder = SflowEncoder::new(*local.ip(), 0);
+        let thread = thread::spawn(move || run(samples, encoder, socket, collector));
+        Ok(SflowExporter {
+            stack: stack,
+            observers: observers,
+            drops: drops,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the number of samples dropped because the exporting thread
+    /// did not keep up.
+    pub fn drops(&self) -> usize {
+        self.drops.load(Ordering::Relaxed)
+    }
+
+    /// Stops sampling, exports the samples taken and waits for the
+    /// exporting thread to quit.
+    pub fn stop(&mut self) {
+        if !self.observers.is_empty() {
+            let mut stack = self.stack.lock().unwrap();
+            for (interface, id) in self.observers.drain(..) {
+                if let Ok(stack_interface) = stack.interface(&interface) {
+                    stack_interface.remove_observer(id);
+                }
+            }
+        }
+        // The thread quits once the samplers, and their queues, are gone
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for SflowExporter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(samples: Receiver<FlowSample>,
+       mut encoder: SflowEncoder,
+       mut socket: UdpSocket,
+       collector: SocketAddrV4) {
+    let flush_interval = Duration::from_millis(FLUSH_INTERVAL_MS);
+    let mut pending = Vec::new();
+    let mut running = true;
+    while running {
+        match samples.recv_timeout(flush_interval) {
+            Ok(sample) => {
+                pending.push(sample);
+                // Waiting a little for more, unless a datagram is full
+                let pending_len = pending.iter().map(|s| s.encoded_len()).sum::<usize>();
+                if DATAGRAM_HEADER_LEN + pending_len < MAX_DATAGRAM_LEN {
+                    continue;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => running = false,
+        }
+        for datagram in encoder.encode(&pending) {
+            if let Err(e) = socket.send_to(&datagram, collector) {
+                warn!("Sflow: Unable to export to {}: {}", collector, e);
+            }
+        }
+        pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Interface;
+    use pcap::Direction;
+    use observe::Observer;
+
+    use pnet::packet::ethernet::EthernetPacket;
+    use pnet::util::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    /// Keeps the sequence number, pool and header length of samples.
+    #[derive(Clone, Default)]
+    struct Taken(Arc<Mutex<Vec<(u32, u32, usize)>>>);
+
+    impl SampleConsumer for Taken {
+        fn consume(&self, sample: &PacketSample) {
+            self.0.lock().unwrap().push((sample.sequence, sample.pool, sample.header.len()));
+        }
+    }
+
+    fn observe_frames<C: SampleConsumer>(sampler: &Sampler<C>, count: usize) {
+        let interface = Interface::new("eth0".to_owned(), MacAddr::new(2, 0, 0, 0, 0, 1));
+        let frame = [0; 60];
+        let packet = EthernetPacket::new(&frame).unwrap();
+        for _ in 0..count {
+            sampler.observe(Direction::Inbound, &interface, UNIX_EPOCH, &packet);
+            sampler.observe(Direction::Outbound, &interface, UNIX_EPOCH, &packet);
+        }
+    }
+
+    #[test]
+    fn samples_one_in_rate() {
+        let taken = Taken::default();
+        let config = SamplingConfig {
+            rate: 1,
+            header_len: 14,
+        };
+        observe_frames(&Sampler::new(config, taken.clone()), 3);
+        assert_eq!(*taken.0.lock().unwrap(), vec![(0, 1, 14), (1, 2, 14), (2, 3, 14)]);
+
+        taken.0.lock().unwrap().clear();
+        let config = SamplingConfig {
+            rate: 10,
+            header_len: 128,
+        };
+        observe_frames(&Sampler::new(config, taken.clone()), 10_000);
+        let taken = taken.0.lock().unwrap();
+        assert!(taken.len() > 800 && taken.len() < 1200, "{} samples", taken.len());
+        assert!(taken.iter().all(|&(_, _, len)| len == 60));
+        assert!(taken.windows(2).all(|samples| samples[0].1 < samples[1].1));
+    }
+
+    #[test]
+    fn datagrams() {
+        let sample = FlowSample {
+            if_index: 2,
+            sequence: 7,
+            rate: 100,
+            pool: 700,
+            drops: 0,
+            frame_len: 60,
+            header: vec![0xff; 14],
+        };
+        let mut encoder = SflowEncoder::new(Ipv4Addr::new(10, 0, 0, 1), 0);
+        assert_eq!(sample.encoded_len(), 64 + 16);
+        let fitting = (MAX_DATAGRAM_LEN - DATAGRAM_HEADER_LEN) / sample.encoded_len();
+        let datagrams = encoder.encode(&vec![sample.clone(); fitting + 1]);
+        assert_eq!(datagrams.len(), 2);
+        assert!(datagrams[0].len() <= MAX_DATAGRAM_LEN);
+        let datagram = &datagrams[1];
+        assert_eq!(datagram.len(), DATAGRAM_HEADER_LEN + sample.encoded_len());
+        assert_eq!(&datagram[..20], &[0, 0, 0, 5, 0, 0, 0, 1, 10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(&datagram[24..28], &[0, 0, 0, 1]);
+        let flow_sample = &datagram[DATAGRAM_HEADER_LEN..];
+        assert_eq!(&flow_sample[..12], &[0, 0, 0, 1, 0, 0, 0, 72, 0, 0, 0, 7]);
+        assert_eq!(&flow_sample[16..24], &[0, 0, 0, 100, 0, 0, 0x02, 0xbc]);
+        let record = &flow_sample[40..];
+        assert_eq!(&record[..8], &[0, 0, 0, 1, 0, 0, 0, 32]);
+        assert_eq!(&record[12..16], &[0, 0, 0, 60]);
+        assert_eq!(&record[20..24], &[0, 0, 0, 14]);
+        assert_eq!(&record[24..38], &[0xff; 14]);
+        assert_eq!(&record[38..], &[0, 0]);
+        assert!(encoder.encode(&[]).is_empty());
+    }
+}
@@ -0,0 +1,1536 @@
+//! The state of one SCTP association, RFC 4960 sections 5 to 9.
+//!
+//! Nothing here does any I/O. An `Association` is fed the packets received
+//! from its peer with `recv_packet` and the passing of time with
+//! `on_timeout`, and returns the packets to send from `transmit`.
+//!
+//! Messages are split into DATA chunks fitting `SctpConfig::mtu` and put
+//! back together by the receiver. Every stream delivers its messages in the
+//! order they were sent, independent of the other streams, so a lost chunk
+//! only holds up its own stream. Unordered messages are delivered as soon as
+//! they are complete.
+//!
+//! Received chunks are acknowledged with SACKs, right away when chunks are
+//! missing or every second packet, else after `SctpConfig::sack_delay`.
+//! Chunks are sent again when the retransmission timeout, computed from the
+//! round trip times measured, runs out, or as soon as three SACKs report
+//! them missing. The congestion window grows and shrinks as in section 7.
+//!
+//! Restarts, INIT chunks received on an existing association, are ignored,
+//! as are ERROR chunks.
+
+use super::SctpConfig;
+use super::chunk::{self, Chunk, Data, Init, Packet, Sack};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::u16;
+
+/// Length of the IPv4 header the packets are sent in.
+const IPV4_HEADER_LEN: usize = 20;
+
+/// Reports of a chunk missing that make it be sent again right away.
+const FAST_RETRANSMIT_REPORTS: usize = 3;
+
+/// Where an association is in its life, RFC 4960 section 4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// The INIT was sent
+    CookieWait,
+    /// The COOKIE ECHO was sent
+    CookieEchoed,
+    Established,
+    /// The user shut the association down, the queued messages are still
+    /// being sent
+    ShutdownPending,
+    ShutdownSent,
+    /// The peer shut the association down, the queued messages are still
+    /// being sent
+    ShutdownReceived,
+    ShutdownAckSent,
+    Closed,
+}
+
+/// The random values the local end of a new association starts out with,
+/// drawn by the endpoint from the generator of its stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalKeys {
+    /// The verification tag expected in all packets from the peer, never 0
+    pub tag: u32,
+    pub initial_tsn: u32,
+}
+
+/// Why an association closed without a graceful shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The peer answered with an ABORT before the association was set up,
+    /// as endpoints do for ports nothing listens on
+    Refused,
+    /// The association was aborted, by the peer or the local user
+    Aborted,
+    /// The peer stopped answering
+    TimedOut,
+}
+
+/// A message received or to send on a stream of an association.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub stream: u16,
+    /// The payload protocol identifier, 0 unless the application protocol
+    /// has one
+    pub ppid: u32,
+    /// If the message is delivered as soon as it is complete, instead of in
+    /// the order of its stream
+    pub unordered: bool,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    /// Returns an ordered message with payload protocol identifier 0.
+    pub fn new(stream: u16, data: Vec<u8>) -> Message {
+        Message {
+            stream: stream,
+            ppid: 0,
+            unordered: false,
+            data: data,
+        }
+    }
+}
+
+/// The state of an association a listening endpoint hands out in the INIT
+/// ACK, instead of keeping it, and sets the association up from once it
+/// comes back in a COOKIE ECHO. RFC 4960 section 5.1.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cookie {
+    /// The parameters of the INIT ACK
+    pub local: Init,
+    /// The parameters of the INIT
+    pub peer: Init,
+    pub local_port: u16,
+    pub peer_port: u16,
+    /// When the cookie was made, in milliseconds since the Unix epoch
+    pub created: u64,
+}
+
+impl Cookie {
+    /// Returns the cookie as sent, followed by a hash of its content keyed
+    /// with `secret`. That keeps peers from making up cookies without
+    /// having received them, it is not meant to withstand cryptanalysis.
+    pub fn to_bytes(&self, secret: &[u8]) -> Vec<u8> {
+        let mut bytes = self.local.to_bytes();
+        bytes.extend_from_slice(&self.peer.to_bytes());
+        put_u64(&mut bytes, (self.local_port as u64) << 16 | self.peer_port as u64);
+        put_u64(&mut bytes, self.created);
+        let mac = mac(secret, &bytes);
+        put_u64(&mut bytes, mac);
+        bytes
+    }
+
+    /// Parses `data`, a cookie made by `to_bytes` with the same `secret`.
+    /// Returns `None` if it was made with another secret or changed since.
+    pub fn parse(data: &[u8], secret: &[u8]) -> Option<Cookie> {
+        let len = 2 * chunk::INIT_LEN + 16;
+        if data.len() != len + 8 || read_u64(&data[len..]) != mac(secret, &data[..len]) {
+            return None;
+        }
+        let ports = read_u64(&data[2 * chunk::INIT_LEN..]);
+        Some(Cookie {
+            local: Init::parse(data).unwrap(),
+            peer: Init::parse(&data[chunk::INIT_LEN..]).unwrap(),
+            local_port: (ports >> 16) as u16,
+            peer_port: ports as u16,
+            created: read_u64(&data[2 * chunk::INIT_LEN + 8..]),
+        })
+    }
+}
+
+/// A DATA chunk sent and not yet acknowledged by the cumulative TSN of the
+/// peer.
+#[derive(Debug)]
+struct Outstanding {
+    data: Data,
+    sent: Instant,
+    transmissions: usize,
+    /// If the latest SACK reported it received
+    gap_acked: bool,
+    /// SACKs in a row reporting it missing
+    missing: usize,
+    fast_retransmitted: bool,
+    /// If it is to be sent again with the next `transmit`
+    retransmit: bool,
+}
+
+/// The receiving end of a stream.
+#[derive(Debug, Default)]
+struct InStream {
+    next_ssn: u16,
+    /// Ordered messages received ahead of `next_ssn`
+    waiting: HashMap<u16, Message>,
+}
+
+pub struct Association {
+    config: SctpConfig,
+    local_port: u16,
+    peer_port: u16,
+    local_tag: u32,
+    peer_tag: u32,
+    state: State,
+    failure: Option<Failure>,
+    /// The INIT, or COOKIE ECHO, sent again until it is answered
+    handshake: Option<Chunk>,
+    send_handshake: bool,
+    /// Chunks sent with the next `transmit`, other than DATA and SACK
+    control: Vec<Chunk>,
+    /// Timeouts in a row without an answer
+    error_count: usize,
+    /// The INIT and COOKIE ECHO timer
+    t1: Option<Instant>,
+    /// The SHUTDOWN and SHUTDOWN ACK timer
+    t2: Option<Instant>,
+    /// The retransmission timer
+    t3: Option<Instant>,
+    rto: Duration,
+    /// The smoothed round trip time and its variation
+    srtt: Option<(Duration, Duration)>,
+
+    out_streams: u16,
+    next_ssn: Vec<u16>,
+    next_tsn: u32,
+    /// Chunks of sent messages not sent yet, without their TSNs
+    pending: VecDeque<Data>,
+    outstanding: VecDeque<Outstanding>,
+    /// Bytes of messages pending or outstanding
+    queued: usize,
+    peer_rwnd: u32,
+    cwnd: usize,
+    ssthresh: usize,
+    partial_bytes_acked: usize,
+    /// The highest TSN outstanding when fast recovery began
+    fast_recovery: Option<u32>,
+
+    in_streams: Vec<InStream>,
+    /// The TSN up to which all chunks were received
+    cum_tsn: u32,
+    /// TSNs received beyond `cum_tsn`
+    above: HashSet<u32>,
+    /// Received fragments not yet put together into messages
+    fragments: HashMap<u32, Data>,
+    received: VecDeque<Message>,
+    /// Bytes of received messages and fragments not yet read
+    buffered: usize,
+    duplicates: Vec<u32>,
+    sack_now: bool,
+    sack_timer: Option<Instant>,
+    /// Packets with DATA received since the last SACK
+    unacked_packets: usize,
+    /// The receiver window of the last SACK
+    advertised: u32,
+}
+
+impl Association {
+    /// Starts setting up an association from `local_port` to `peer_port`,
+    /// with the verification tag and initial TSN of `keys`. The INIT is
+    /// returned by the next `transmit`.
+    pub fn connect(config: SctpConfig,
+                   local_port: u16,
+                   peer_port: u16,
+                   keys: LocalKeys,
+                   now: Instant)
+                   -> Association {
+        let local = local_init(&config, keys);
+        let mut association = Self::new(config, local_port, peer_port, local, State::CookieWait);
+        association.handshake = Some(Chunk::Init(local));
+        association.send_handshake = true;
+        association.t1 = Some(now + association.rto);
+        association
+    }
+
+    /// Returns the INIT ACK answering `init`, received on `local_port` from
+    /// `peer_port`, with a cookie made with `secret`. The association it
+    /// sets up has the verification tag and initial TSN of `keys`.
+    pub fn answer_init(config: &SctpConfig,
+                       secret: &[u8],
+                       local_port: u16,
+                       peer_port: u16,
+                       init: &Init,
+                       keys: LocalKeys)
+                       -> Packet {
+        let cookie = Cookie {
+            local: local_init(config, keys),
+            peer: *init,
+            local_port: local_port,
+            peer_port: peer_port,
+            created: millis(SystemTime::now()),
+        };
+        let init_ack = Chunk::InitAck(cookie.local, cookie.to_bytes(secret));
+        Packet::new(local_port, peer_port, init.tag, vec![init_ack])
+    }
+
+    /// Sets up the association of `packet`, starting with a COOKIE ECHO
+    /// with a cookie made by `answer_init` with the same `secret`. The
+    /// chunks bundled after it are processed as by `recv_packet`.
+    ///
+    /// Returns `None` if the cookie is not valid for the packet or older
+    /// than `SctpConfig::cookie_lifetime`.
+    pub fn accept(config: SctpConfig,
+                  secret: &[u8],
+                  packet: &Packet,
+                  now: Instant)
+                  -> Option<Association> {
+        let cookie = match packet.chunks.first() {
+            Some(&Chunk::CookieEcho(ref cookie)) => Cookie::parse(cookie, secret),
+            _ => None,
+        };
+        let cookie = match cookie {
+            Some(cookie) => cookie,
+            None => return None,
+        };
+        let age = millis(SystemTime::now()).saturating_sub(cookie.created);
+        if cookie.local_port != packet.dst_port || cookie.peer_port != packet.src_port ||
+           cookie.local.tag != packet.tag || age > millis_of(config.cookie_lifetime) {
+            return None;
+        }
+        let mut association = Self::new(config,
+                                        cookie.local_port,
+                                        cookie.peer_port,
+                                        cookie.local,
+                                        State::Established);
+        association.set_peer(&cookie.peer);
+        association.control.push(Chunk::CookieAck);
+        let rest = Packet::new(packet.src_port,
+                               packet.dst_port,
+                               packet.tag,
+                               packet.chunks[1..].to_vec());
+        association.recv_packet(&rest, now);
+        Some(association)
+    }
+
+    fn new(config: SctpConfig,
+           local_port: u16,
+           peer_port: u16,
+           local: Init,
+           state: State)
+           -> Association {
+        let mtu = config.mtu;
+        Association {
+            config: config,
+            local_port: local_port,
+            peer_port: peer_port,
+            local_tag: local.tag,
+            peer_tag: 0,
+            state: state,
+            failure: None,
+            handshake: None,
+            send_handshake: false,
+            control: Vec::new(),
+            error_count: 0,
+            t1: None,
+            t2: None,
+            t3: None,
+            rto: config.rto_initial,
+            srtt: None,
+            out_streams: local.out_streams,
+            next_ssn: Vec::new(),
+            next_tsn: local.initial_tsn,
+            pending: VecDeque::new(),
+            outstanding: VecDeque::new(),
+            queued: 0,
+            peer_rwnd: 0,
+            cwnd: cmp::min(4 * mtu, cmp::max(2 * mtu, 4380)),
+            ssthresh: 0,
+            partial_bytes_acked: 0,
+            fast_recovery: None,
+            in_streams: Vec::new(),
+            cum_tsn: 0,
+            above: HashSet::new(),
+            fragments: HashMap::new(),
+            received: VecDeque::new(),
+            buffered: 0,
+            duplicates: Vec::new(),
+            sack_now: false,
+            sack_timer: None,
+            unacked_packets: 0,
+            advertised: config.recv_window,
+        }
+    }
+
+    /// Takes the parameters of the INIT or INIT ACK of the peer.
+    fn set_peer(&mut self, peer: &Init) {
+        self.peer_tag = peer.tag;
+        self.peer_rwnd = peer.a_rwnd;
+        self.ssthresh = peer.a_rwnd as usize;
+        self.out_streams = cmp::min(self.out_streams, peer.in_streams);
+        self.next_ssn = vec![0; self.out_streams as usize];
+        let in_streams = cmp::min(self.config.max_in_streams, peer.out_streams);
+        self.in_streams = (0..in_streams).map(|_| InStream::default()).collect();
+        self.cum_tsn = peer.initial_tsn.wrapping_sub(1);
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Returns why the association closed, `None` if it is open or was shut
+    /// down gracefully.
+    pub fn failure(&self) -> Option<Failure> {
+        self.failure
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    pub fn peer_port(&self) -> u16 {
+        self.peer_port
+    }
+
+    /// Returns the verification tag the peer puts in its packets.
+    pub fn local_tag(&self) -> u32 {
+        self.local_tag
+    }
+
+    /// Returns the verification tag put in packets to the peer, 0 until
+    /// its INIT ACK is received.
+    pub fn peer_tag(&self) -> u32 {
+        self.peer_tag
+    }
+
+    /// Returns the number of streams messages can be sent on, and received
+    /// on, once the association is established.
+    pub fn streams(&self) -> (u16, u16) {
+        (self.out_streams, self.in_streams.len() as u16)
+    }
+
+    /// Returns the current retransmission timeout.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Returns the current congestion window, in bytes.
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Returns if `send` takes a message of `len` bytes now, or fails with
+    /// `io::ErrorKind::WouldBlock` because the send buffer is full.
+    pub fn has_room(&self, len: usize) -> bool {
+        self.queued == 0 || self.queued + len <= self.config.send_buffer
+    }
+
+    /// Returns if all sent messages were acknowledged.
+    pub fn is_flushed(&self) -> bool {
+        self.pending.is_empty() && self.outstanding.is_empty()
+    }
+
+    /// Returns if no more messages will be received: the association closed
+    /// or the peer shut it down, and all messages were read.
+    pub fn at_end(&self) -> bool {
+        let peer_done = match self.state {
+            State::ShutdownReceived | State::ShutdownAckSent | State::Closed => true,
+            _ => false,
+        };
+        peer_done && self.received.is_empty()
+    }
+
+    /// Returns if `transmit` has something to send right away.
+    pub fn wants_transmit(&self) -> bool {
+        self.sack_now || self.send_handshake || !self.control.is_empty() ||
+        !self.pending.is_empty() || self.outstanding.iter().any(|chunk| chunk.retransmit)
+    }
+
+    /// Queues `message` for sending.
+    ///
+    /// Fails with `io::ErrorKind::WouldBlock` if the send buffer is full,
+    /// `io::ErrorKind::InvalidInput` if the stream is not one the
+    /// association has or the message is empty, and with
+    /// `io::ErrorKind::NotConnected` if the association is not established.
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match self.state {
+            State::Established => (),
+            State::ShutdownPending | State::ShutdownSent | State::ShutdownReceived |
+            State::ShutdownAckSent => {
+                return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                          "The association is shutting down"))
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::NotConnected,
+                                          "The association is not established"))
+            }
+        }
+        if message.stream >= self.out_streams {
+            let msg = format!("No stream {}, the association has {}",
+                              message.stream,
+                              self.out_streams);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if message.data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty message"));
+        }
+        if !self.has_room(message.data.len()) {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "Send buffer full"));
+        }
+        let ssn = if message.unordered {
+            0
+        } else {
+            let next_ssn = &mut self.next_ssn[message.stream as usize];
+            let ssn = *next_ssn;
+            *next_ssn = ssn.wrapping_add(1);
+            ssn
+        };
+        let fragments = message.data.chunks(self.max_fragment()).collect::<Vec<_>>();
+        let last = fragments.len() - 1;
+        for (i, fragment) in fragments.into_iter().enumerate() {
+            self.pending.push_back(Data {
+                tsn: 0,
+                stream: message.stream,
+                ssn: ssn,
+                ppid: message.ppid,
+                unordered: message.unordered,
+                begin: i == 0,
+                end: i == last,
+                data: fragment.to_vec(),
+            });
+        }
+        self.queued += message.data.len();
+        Ok(())
+    }
+
+    /// Returns the next received message, if there is one.
+    pub fn recv(&mut self) -> Option<Message> {
+        let message = self.received.pop_front();
+        if let Some(ref message) = message {
+            self.buffered -= message.data.len();
+            // Tell the peer the window opened, if it is much larger now
+            if self.rwnd() >= self.advertised.saturating_add(self.config.recv_window / 2) {
+                self.sack_now = true;
+            }
+        }
+        message
+    }
+
+    /// Starts a graceful shutdown. The messages already sent are delivered
+    /// first.
+    pub fn shutdown(&mut self, now: Instant) {
+        match self.state {
+            State::Established => {
+                self.state = State::ShutdownPending;
+                self.check_shutdown(now);
+            }
+            State::CookieWait | State::CookieEchoed => self.close(None),
+            _ => (),
+        }
+    }
+
+    /// Closes the association right away, telling the peer with an ABORT.
+    pub fn abort(&mut self) {
+        if self.state != State::Closed {
+            if self.peer_tag != 0 {
+                self.control.push(Chunk::Abort { reflected: false });
+            }
+            self.close(Some(Failure::Aborted));
+        }
+    }
+
+    /// Returns when `on_timeout` has to be called next.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        [self.t1, self.t2, self.t3, self.sack_timer].iter().filter_map(|timer| *timer).min()
+    }
+
+    /// Handles the timers that ran out by `now`.
+    pub fn on_timeout(&mut self, now: Instant) {
+        if expired(self.t1, now) {
+            self.error_count += 1;
+            if self.error_count > self.config.max_init_retransmits {
+                self.close(Some(Failure::TimedOut));
+                return;
+            }
+            self.back_off();
+            self.send_handshake = true;
+            self.t1 = Some(now + self.rto);
+        }
+        if expired(self.t3, now) {
+            self.t3 = None;
+            self.error_count += 1;
+            if self.error_count > self.config.max_retransmits {
+                self.control.push(Chunk::Abort { reflected: false });
+                self.close(Some(Failure::TimedOut));
+                return;
+            }
+            self.back_off();
+            self.ssthresh = cmp::max(self.cwnd / 2, 4 * self.config.mtu);
+            self.cwnd = self.config.mtu;
+            self.partial_bytes_acked = 0;
+            self.fast_recovery = None;
+            for chunk in self.outstanding.iter_mut().filter(|chunk| !chunk.gap_acked) {
+                chunk.retransmit = true;
+            }
+        }
+        if expired(self.t2, now) {
+            self.error_count += 1;
+            if self.error_count > self.config.max_retransmits {
+                self.control.push(Chunk::Abort { reflected: false });
+                self.close(Some(Failure::TimedOut));
+                return;
+            }
+            self.back_off();
+            let chunk = match self.state {
+                State::ShutdownSent => Chunk::Shutdown { cum_tsn: self.cum_tsn },
+                _ => Chunk::ShutdownAck,
+            };
+            self.control.push(chunk);
+            self.t2 = Some(now + self.rto);
+        }
+        if expired(self.sack_timer, now) {
+            self.sack_timer = None;
+            self.sack_now = true;
+        }
+    }
+
+    /// Handles `packet`, received from the peer. Packets without the right
+    /// verification tag are dropped.
+    pub fn recv_packet(&mut self, packet: &Packet, now: Instant) {
+        if self.state == State::Closed || !self.has_valid_tag(packet) {
+            return;
+        }
+        let mut data_received = false;
+        for chunk in &packet.chunks {
+            match *chunk {
+                Chunk::Data(ref data) => {
+                    self.recv_data(data);
+                    data_received = true;
+                }
+                Chunk::InitAck(ref init, ref cookie) => {
+                    if self.state == State::CookieWait {
+                        self.set_peer(init);
+                        self.handshake = Some(Chunk::CookieEcho(cookie.clone()));
+                        self.send_handshake = true;
+                        self.state = State::CookieEchoed;
+                        self.error_count = 0;
+                        self.t1 = Some(now + self.rto);
+                    }
+                }
+                Chunk::CookieAck => {
+                    if self.state == State::CookieEchoed {
+                        self.state = State::Established;
+                        self.handshake = None;
+                        self.send_handshake = false;
+                        self.error_count = 0;
+                        self.t1 = None;
+                    }
+                }
+                Chunk::CookieEcho(_) => {
+                    // The peer did not get the COOKIE ACK
+                    if self.state == State::Established {
+                        self.control.push(Chunk::CookieAck);
+                    }
+                }
+                Chunk::Sack(ref sack) => self.recv_sack(sack, now),
+                Chunk::Heartbeat(ref info) => self.control.push(Chunk::HeartbeatAck(info.clone())),
+                Chunk::Abort { .. } => {
+                    let failure = match self.state {
+                        State::CookieWait | State::CookieEchoed => Failure::Refused,
+                        _ => Failure::Aborted,
+                    };
+                    self.close(Some(failure));
+                    return;
+                }
+                Chunk::Shutdown { cum_tsn } => self.recv_shutdown(cum_tsn, now),
+                Chunk::ShutdownAck => {
+                    if self.state == State::ShutdownSent || self.state == State::ShutdownAckSent {
+                        self.control.push(Chunk::ShutdownComplete { reflected: false });
+                        self.close(None);
+                        return;
+                    }
+                }
+                Chunk::ShutdownComplete { .. } => {
+                    if self.state == State::ShutdownAckSent {
+                        self.close(None);
+                        return;
+                    }
+                }
+                Chunk::Error(_) | Chunk::HeartbeatAck(_) | Chunk::Init(_) => (),
+                Chunk::Unknown { kind, .. } => {
+                    // The highest bit of the type tells if the rest of the
+                    // packet is to be processed
+                    if kind & 0x80 == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        if data_received {
+            self.unacked_packets += 1;
+            if self.unacked_packets >= 2 || !self.above.is_empty() || !self.duplicates.is_empty() {
+                self.sack_now = true;
+            } else if self.sack_timer.is_none() {
+                self.sack_timer = Some(now + self.config.sack_delay);
+            }
+            if self.state == State::ShutdownSent {
+                self.sack_now = true;
+                self.control.push(Chunk::Shutdown { cum_tsn: self.cum_tsn });
+            }
+        }
+    }
+
+    /// Returns the packets to send now: the retransmissions and new chunks
+    /// the congestion and receiver windows take, together with the SACK and
+    /// the control chunks due.
+    pub fn transmit(&mut self, now: Instant) -> Vec<Packet> {
+        let mut bundler = Bundler::new(self.local_port,
+                                       self.peer_port,
+                                       self.peer_tag,
+                                       self.config.mtu - IPV4_HEADER_LEN);
+        if self.send_handshake {
+            self.send_handshake = false;
+            match self.handshake {
+                // INIT chunks are sent alone, with verification tag 0
+                Some(ref init @ Chunk::Init(_)) => {
+                    let chunks = vec![init.clone()];
+                    bundler.packets.push(Packet::new(self.local_port, self.peer_port, 0, chunks));
+                }
+                Some(ref cookie_echo) => bundler.add(cookie_echo.clone()),
+                None => (),
+            }
+        }
+        for chunk in self.control.drain(..) {
+            match chunk {
+                Chunk::ShutdownComplete { .. } => bundler.add_alone(chunk),
+                chunk => bundler.add(chunk),
+            }
+        }
+        if self.sack_now && self.peer_tag != 0 {
+            let sack = self.sack();
+            self.advertised = sack.a_rwnd;
+            bundler.add(Chunk::Sack(sack));
+            self.sack_now = false;
+            self.sack_timer = None;
+            self.unacked_packets = 0;
+            self.duplicates.clear();
+        }
+        let sending = match self.state {
+            State::Established | State::ShutdownPending | State::ShutdownReceived => true,
+            _ => false,
+        };
+        if sending {
+            self.transmit_data(&mut bundler, now);
+        }
+        bundler.finish()
+    }
+
+    fn transmit_data(&mut self, bundler: &mut Bundler, now: Instant) {
+        let mut flight = self.flight();
+        for chunk in self.outstanding.iter_mut().filter(|chunk| chunk.retransmit) {
+            if flight >= self.cwnd {
+                break;
+            }
+            chunk.retransmit = false;
+            chunk.transmissions += 1;
+            chunk.sent = now;
+            flight += chunk.data.data.len();
+            bundler.add(Chunk::Data(chunk.data.clone()));
+        }
+        loop {
+            let len = match self.pending.front() {
+                Some(data) => data.data.len(),
+                None => break,
+            };
+            // With nothing in flight, one chunk is sent even into a closed
+            // receiver window, to learn when it opens again
+            if flight >= self.cwnd || (len as u32 > self.peer_rwnd && flight > 0) {
+                break;
+            }
+            let mut data = self.pending.pop_front().unwrap();
+            data.tsn = self.next_tsn;
+            self.next_tsn = self.next_tsn.wrapping_add(1);
+            self.peer_rwnd = self.peer_rwnd.saturating_sub(len as u32);
+            flight += len;
+            bundler.add(Chunk::Data(data.clone()));
+            self.outstanding.push_back(Outstanding {
+                data: data,
+                sent: now,
+                transmissions: 1,
+                gap_acked: false,
+                missing: 0,
+                fast_retransmitted: false,
+                retransmit: false,
+            });
+        }
+        if !self.outstanding.is_empty() && self.t3.is_none() {
+            self.t3 = Some(now + self.rto);
+        }
+    }
+
+    fn recv_data(&mut self, data: &Data) {
+        if self.state == State::CookieWait || self.in_streams.is_empty() {
+            return;
+        }
+        let tsn = data.tsn;
+        if !lt(self.cum_tsn, tsn) || self.above.contains(&tsn) {
+            self.duplicates.push(tsn);
+            return;
+        }
+        // Gaps are reported as 16 bit offsets from the cumulative TSN
+        let offset = tsn.wrapping_sub(self.cum_tsn);
+        if offset > u16::MAX as u32 ||
+           (self.buffered >= self.config.recv_window as usize && offset != 1) {
+            debug!("Sctp: Dropping TSN {}, beyond the receiver window", tsn);
+            return;
+        }
+        if offset == 1 {
+            self.cum_tsn = tsn;
+            while self.above.remove(&self.cum_tsn.wrapping_add(1)) {
+                self.cum_tsn = self.cum_tsn.wrapping_add(1);
+            }
+        } else {
+            self.above.insert(tsn);
+        }
+        if data.stream as usize >= self.in_streams.len() {
+            debug!("Sctp: Dropping data on stream {}, not one of the association",
+                   data.stream);
+            return;
+        }
+        self.buffered += data.data.len();
+        self.fragments.insert(tsn, data.clone());
+        self.reassemble(tsn);
+    }
+
+    /// Puts the message with the fragment `tsn` together, if all its
+    /// fragments were received.
+    fn reassemble(&mut self, tsn: u32) {
+        let mut first = tsn;
+        while !self.fragments[&first].begin {
+            match self.fragments.get(&first.wrapping_sub(1)) {
+                Some(fragment) if !fragment.end => first = first.wrapping_sub(1),
+                _ => return,
+            }
+        }
+        let mut last = tsn;
+        while !self.fragments[&last].end {
+            match self.fragments.get(&last.wrapping_add(1)) {
+                Some(fragment) if !fragment.begin => last = last.wrapping_add(1),
+                _ => return,
+            }
+        }
+        let head = self.fragments.remove(&first).unwrap();
+        let mut message = Message {
+            stream: head.stream,
+            ppid: head.ppid,
+            unordered: head.unordered,
+            data: head.data,
+        };
+        let mut tsn = first;
+        while tsn != last {
+            tsn = tsn.wrapping_add(1);
+            message.data.extend_from_slice(&self.fragments.remove(&tsn).unwrap().data);
+        }
+        if message.unordered {
+            self.received.push_back(message);
+            return;
+        }
+        let stream = &mut self.in_streams[message.stream as usize];
+        stream.waiting.insert(head.ssn, message);
+        loop {
+            let ssn = stream.next_ssn;
+            match stream.waiting.remove(&ssn) {
+                Some(message) => self.received.push_back(message),
+                None => break,
+            }
+            stream.next_ssn = ssn.wrapping_add(1);
+        }
+    }
+
+    fn recv_sack(&mut self, sack: &Sack, now: Instant) {
+        if self.ack(sack.cum_tsn, &sack.gaps, now) {
+            let flight = self.flight() as u32;
+            self.peer_rwnd = sack.a_rwnd.saturating_sub(flight);
+        }
+    }
+
+    fn recv_shutdown(&mut self, cum_tsn: u32, now: Instant) {
+        match self.state {
+            State::Established | State::ShutdownPending | State::ShutdownReceived => {
+                self.ack(cum_tsn, &[], now);
+                self.state = State::ShutdownReceived;
+                self.check_shutdown(now);
+            }
+            State::ShutdownSent => {
+                self.ack(cum_tsn, &[], now);
+                self.control.push(Chunk::ShutdownAck);
+                self.state = State::ShutdownAckSent;
+                self.t2 = Some(now + self.rto);
+            }
+            _ => (),
+        }
+    }
+
+    /// Takes note of the peer having received all chunks up to `cum_tsn`,
+    /// and the ones in the `gaps` beyond it. Returns false if the
+    /// acknowledgement is older than one taken note of before.
+    fn ack(&mut self, cum_tsn: u32, gaps: &[(u16, u16)], now: Instant) -> bool {
+        let last_cum_tsn = match self.outstanding.front() {
+            Some(chunk) => chunk.data.tsn.wrapping_sub(1),
+            None => self.next_tsn.wrapping_sub(1),
+        };
+        if lt(cum_tsn, last_cum_tsn) || !lt(cum_tsn, self.next_tsn) {
+            return false;
+        }
+        let flight_before = self.flight();
+        let mut acked_bytes = 0;
+        let mut rtt = None;
+        while self.outstanding.front().map_or(false, |chunk| !lt(cum_tsn, chunk.data.tsn)) {
+            let chunk = self.outstanding.pop_front().unwrap();
+            if !chunk.gap_acked {
+                acked_bytes += chunk.data.data.len();
+            }
+            self.queued -= chunk.data.data.len();
+            // Karn's algorithm, only chunks sent once are timed
+            if chunk.transmissions == 1 {
+                rtt = Some(now.duration_since(chunk.sent));
+            }
+        }
+        let cum_advanced = cum_tsn != last_cum_tsn;
+
+        let mut highest_gap_acked = None;
+        for chunk in &mut self.outstanding {
+            let offset = chunk.data.tsn.wrapping_sub(cum_tsn);
+            let gap_acked = gaps.iter().any(|&(start, end)| {
+                offset >= start as u32 && offset <= end as u32
+            });
+            if gap_acked {
+                if !chunk.gap_acked {
+                    acked_bytes += chunk.data.data.len();
+                    chunk.retransmit = false;
+                }
+                highest_gap_acked = Some(chunk.data.tsn);
+            }
+            chunk.gap_acked = gap_acked;
+        }
+        let mut fast_retransmit = false;
+        if let Some(highest) = highest_gap_acked {
+            if acked_bytes > 0 {
+                for chunk in &mut self.outstanding {
+                    if !chunk.gap_acked && lt(chunk.data.tsn, highest) &&
+                       !chunk.fast_retransmitted {
+                        chunk.missing += 1;
+                        if chunk.missing >= FAST_RETRANSMIT_REPORTS {
+                            chunk.fast_retransmitted = true;
+                            chunk.retransmit = true;
+                            fast_retransmit = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mtu = self.config.mtu;
+        if let Some(exit) = self.fast_recovery {
+            if !lt(cum_tsn, exit) {
+                self.fast_recovery = None;
+            }
+        }
+        if fast_retransmit && self.fast_recovery.is_none() {
+            self.ssthresh = cmp::max(self.cwnd / 2, 4 * mtu);
+            self.cwnd = self.ssthresh;
+            self.partial_bytes_acked = 0;
+            self.fast_recovery = Some(self.next_tsn.wrapping_sub(1));
+        } else if cum_advanced && self.fast_recovery.is_none() && flight_before >= self.cwnd {
+            if self.cwnd <= self.ssthresh {
+                self.cwnd += cmp::min(acked_bytes, mtu);
+            } else {
+                self.partial_bytes_acked += acked_bytes;
+                if self.partial_bytes_acked >= self.cwnd {
+                    self.partial_bytes_acked -= self.cwnd;
+                    self.cwnd += mtu;
+                }
+            }
+        }
+
+        if let Some(rtt) = rtt {
+            self.measured(rtt);
+        }
+        if cum_advanced {
+            self.error_count = 0;
+        }
+        if self.outstanding.is_empty() {
+            self.t3 = None;
+        } else if cum_advanced {
+            self.t3 = Some(now + self.rto);
+        }
+        self.check_shutdown(now);
+        true
+    }
+
+    /// Sends the SHUTDOWN, or SHUTDOWN ACK, once a shutdown is under way
+    /// and all messages are acknowledged.
+    fn check_shutdown(&mut self, now: Instant) {
+        if !self.is_flushed() {
+            return;
+        }
+        match self.state {
+            State::ShutdownPending => {
+                self.control.push(Chunk::Shutdown { cum_tsn: self.cum_tsn });
+                self.state = State::ShutdownSent;
+            }
+            State::ShutdownReceived => {
+                self.control.push(Chunk::ShutdownAck);
+                self.state = State::ShutdownAckSent;
+            }
+            _ => return,
+        }
+        self.t2 = Some(now + self.rto);
+    }
+
+    /// Updates the retransmission timeout with a round trip time measured,
+    /// RFC 4960 section 6.3.1.
+    fn measured(&mut self, rtt: Duration) {
+        let (srtt, rttvar) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some((srtt, rttvar)) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + delta / 4)
+            }
+        };
+        self.srtt = Some((srtt, rttvar));
+        self.rto = cmp::min(cmp::max(srtt + rttvar * 4, self.config.rto_min),
+                            self.config.rto_max);
+    }
+
+    /// Doubles the retransmission timeout after a timer ran out.
+    fn back_off(&mut self) {
+        self.rto = cmp::min(self.rto * 2, self.config.rto_max);
+    }
+
+    fn close(&mut self, failure: Option<Failure>) {
+        self.state = State::Closed;
+        self.failure = failure;
+        self.handshake = None;
+        self.send_handshake = false;
+        self.t1 = None;
+        self.t2 = None;
+        self.t3 = None;
+        self.sack_timer = None;
+        self.sack_now = false;
+        self.pending.clear();
+        self.outstanding.clear();
+        self.queued = 0;
+    }
+
+    fn has_valid_tag(&self, packet: &Packet) -> bool {
+        let reflected = packet.chunks.iter().any(|chunk| match *chunk {
+            Chunk::Abort { reflected } |
+            Chunk::ShutdownComplete { reflected } => reflected,
+            _ => false,
+        });
+        if reflected {
+            packet.tag == self.peer_tag
+        } else {
+            packet.tag == self.local_tag
+        }
+    }
+
+    /// Returns the SACK of what was received so far.
+    fn sack(&self) -> Sack {
+        let mut offsets = self.above
+            .iter()
+            .map(|tsn| tsn.wrapping_sub(self.cum_tsn) as u16)
+            .collect::<Vec<_>>();
+        offsets.sort();
+        let mut gaps: Vec<(u16, u16)> = Vec::new();
+        for offset in offsets {
+            if let Some(gap) = gaps.last_mut() {
+                if gap.1 + 1 == offset {
+                    gap.1 = offset;
+                    continue;
+                }
+            }
+            gaps.push((offset, offset));
+        }
+        Sack {
+            cum_tsn: self.cum_tsn,
+            a_rwnd: self.rwnd(),
+            gaps: gaps,
+            duplicates: self.duplicates.clone(),
+        }
+    }
+
+    /// Returns the receiver window, the room left for received messages.
+    fn rwnd(&self) -> u32 {
+        self.config.recv_window.saturating_sub(self.buffered as u32)
+    }
+
+    /// Returns the bytes of chunks sent and neither acknowledged nor to be
+    /// sent again, that are thought to still be on their way.
+    fn flight(&self) -> usize {
+        self.outstanding
+            .iter()
+            .filter(|chunk| !chunk.gap_acked && !chunk.retransmit)
+            .map(|chunk| chunk.data.data.len())
+            .sum()
+    }
+
+    /// Returns the most user data of one DATA chunk that fits in the MTU.
+    fn max_fragment(&self) -> usize {
+        (self.config.mtu - IPV4_HEADER_LEN - chunk::COMMON_HEADER_LEN - chunk::DATA_HEADER_LEN) &
+        !3
+    }
+}
+
+/// Returns the answer to `packet`, received for no association and with
+/// neither an INIT nor a COOKIE ECHO, RFC 4960 section 8.4.
+pub fn out_of_the_blue(packet: &Packet) -> Option<Packet> {
+    let reply = |chunk| {
+        Some(Packet::new(packet.dst_port, packet.src_port, packet.tag, vec![chunk]))
+    };
+    for chunk in &packet.chunks {
+        match *chunk {
+            Chunk::Abort { .. } |
+            Chunk::ShutdownComplete { .. } |
+            Chunk::CookieAck |
+            Chunk::Error(_) => return None,
+            Chunk::ShutdownAck => return reply(Chunk::ShutdownComplete { reflected: true }),
+            _ => (),
+        }
+    }
+    reply(Chunk::Abort { reflected: true })
+}
+
+/// Packs chunks into as few packets as they fit in.
+struct Bundler {
+    packets: Vec<Packet>,
+    chunks: Vec<Chunk>,
+    len: usize,
+    max_len: usize,
+    src_port: u16,
+    dst_port: u16,
+    tag: u32,
+}
+
+impl Bundler {
+    fn new(src_port: u16, dst_port: u16, tag: u32, max_len: usize) -> Bundler {
+        Bundler {
+            packets: Vec::new(),
+            chunks: Vec::new(),
+            len: chunk::COMMON_HEADER_LEN,
+            max_len: max_len,
+            src_port: src_port,
+            dst_port: dst_port,
+            tag: tag,
+        }
+    }
+
+    fn add(&mut self, chunk: Chunk) {
+        if self.len + chunk.len() > self.max_len {
+            self.flush();
+        }
+        self.len += chunk.len();
+        self.chunks.push(chunk);
+    }
+
+    /// Adds `chunk` in a packet of its own.
+    fn add_alone(&mut self, chunk: Chunk) {
+        self.flush();
+        self.add(chunk);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        if !self.chunks.is_empty() {
+            let chunks = self.chunks.drain(..).collect();
+            self.packets.push(Packet::new(self.src_port, self.dst_port, self.tag, chunks));
+            self.len = chunk::COMMON_HEADER_LEN;
+        }
+    }
+
+    fn finish(mut self) -> Vec<Packet> {
+        self.flush();
+        self.packets
+    }
+}
+
+/// Returns the parameters of an INIT or INIT ACK, with the verification tag
+/// and initial TSN of `keys`.
+fn local_init(config: &SctpConfig, keys: LocalKeys) -> Init {
+    Init {
+        tag: keys.tag,
+        a_rwnd: config.recv_window,
+        out_streams: config.out_streams,
+        in_streams: config.max_in_streams,
+        initial_tsn: keys.initial_tsn,
+    }
+}
+
+/// Returns if `a` comes before `b`, in serial number arithmetic.
+fn lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn expired(timer: Option<Instant>, now: Instant) -> bool {
+    timer.map_or(false, |timer| timer <= now)
+}
+
+fn mac(secret: &[u8], data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(secret);
+    hasher.write(data);
+    hasher.finish()
+}
+
+fn millis(time: SystemTime) -> u64 {
+    millis_of(time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0)))
+}
+
+fn millis_of(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    data[..8].iter().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    for i in (0..8).rev() {
+        buffer.push((value >> (8 * i)) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::chunk::Chunk;
+
+    static SECRET: &'static [u8] = b"0123456789abcdef";
+
+    fn config() -> SctpConfig {
+        let mut config = SctpConfig::default();
+        config.mtu = 200;
+        config
+    }
+
+    /// Returns the keys of end `n` of an association, with the TSNs of the
+    /// two ends far apart.
+    fn keys(n: u32) -> LocalKeys {
+        LocalKeys {
+            tag: 0x5c7a_0000 + n,
+            initial_tsn: n.wrapping_mul(0x8000_0000).wrapping_sub(5),
+        }
+    }
+
+    /// Returns `packet` after a trip through its wire format.
+    fn wire(packet: Packet) -> Packet {
+        Packet::parse(&packet.to_bytes(), true).unwrap()
+    }
+
+    fn init_of(packets: &[Packet]) -> Init {
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].tag, 0);
+        assert_eq!(packets[0].chunks.len(), 1);
+        match packets[0].chunks[0] {
+            Chunk::Init(init) => init,
+            ref chunk => panic!("Expected an INIT, got {:?}", chunk),
+        }
+    }
+
+    /// Sends the packets of `a` and `b` to each other until neither has
+    /// anything more to send.
+    fn exchange(a: &mut Association, b: &mut Association, now: Instant) {
+        for _ in 0..100 {
+            let from_a = a.transmit(now);
+            let from_b = b.transmit(now);
+            if from_a.is_empty() && from_b.is_empty() {
+                return;
+            }
+            for packet in from_a {
+                b.recv_packet(&wire(packet), now);
+            }
+            for packet in from_b {
+                a.recv_packet(&wire(packet), now);
+            }
+        }
+        panic!("Associations never stopped sending");
+    }
+
+    /// Same as `exchange`, then lets the delayed SACKs go out. Returns the
+    /// time after that.
+    fn settle(a: &mut Association, b: &mut Association, now: Instant) -> Instant {
+        exchange(a, b, now);
+        let later = now + config().sack_delay;
+        a.on_timeout(later);
+        b.on_timeout(later);
+        exchange(a, b, later);
+        later
+    }
+
+    fn establish(client_config: SctpConfig,
+                 server_config: SctpConfig,
+                 now: Instant)
+                 -> (Association, Association) {
+        let mut client = Association::connect(client_config, 5000, 2905, keys(1), now);
+        assert_eq!(client.state(), State::CookieWait);
+        let init = init_of(&client.transmit(now));
+        let init_ack = Association::answer_init(&server_config, SECRET, 2905, 5000, &init, keys(2));
+        client.recv_packet(&wire(init_ack), now);
+        assert_eq!(client.state(), State::CookieEchoed);
+        let cookie_echo = client.transmit(now);
+        assert_eq!(cookie_echo.len(), 1);
+        let cookie_echo = wire(cookie_echo[0].clone());
+        let mut server = Association::accept(server_config, SECRET, &cookie_echo, now).unwrap();
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.state(), State::Established);
+        assert_eq!(server.state(), State::Established);
+        assert_eq!(client.peer_tag(), server.local_tag());
+        assert_eq!(server.peer_tag(), client.local_tag());
+        (client, server)
+    }
+
+    fn received(association: &mut Association) -> Vec<(u16, Vec<u8>)> {
+        let mut messages = Vec::new();
+        while let Some(message) = association.recv() {
+            messages.push((message.stream, message.data));
+        }
+        messages
+    }
+
+    #[test]
+    fn handshake() {
+        let mut server_config = config();
+        server_config.max_in_streams = 4;
+        let (client, server) = establish(config(), server_config, Instant::now());
+        assert_eq!(client.streams(), (4, 10));
+        assert_eq!(server.streams(), (10, 4));
+        assert!(client.next_timeout().is_none());
+        assert!(server.next_timeout().is_none());
+    }
+
+    #[test]
+    fn init_timeout() {
+        let mut config = config();
+        config.max_init_retransmits = 2;
+        let mut now = Instant::now();
+        let mut association = Association::connect(config, 5000, 2905, keys(1), now);
+        init_of(&association.transmit(now));
+        for i in 0..2 {
+            now = association.next_timeout().unwrap();
+            association.on_timeout(now);
+            assert_eq!(association.rto(), config.rto_initial * (2 << i));
+            init_of(&association.transmit(now));
+        }
+        now = association.next_timeout().unwrap();
+        association.on_timeout(now);
+        assert_eq!(association.state(), State::Closed);
+        assert_eq!(association.failure(), Some(Failure::TimedOut));
+        assert!(association.transmit(now).is_empty());
+    }
+
+    #[test]
+    fn refused() {
+        let now = Instant::now();
+        let mut association = Association::connect(config(), 5000, 2905, keys(1), now);
+        let init = init_of(&association.transmit(now));
+        let abort = Packet::new(2905, 5000, init.tag, vec![Chunk::Abort { reflected: false }]);
+        association.recv_packet(&Packet::new(2905, 5000, init.tag ^ 1, abort.chunks.clone()), now);
+        assert_eq!(association.state(), State::CookieWait);
+        association.recv_packet(&abort, now);
+        assert_eq!(association.state(), State::Closed);
+        assert_eq!(association.failure(), Some(Failure::Refused));
+    }
+
+    #[test]
+    fn invalid_cookies() {
+        let now = Instant::now();
+        let config = config();
+        let mut client = Association::connect(config, 5000, 2905, keys(1), now);
+        let init = init_of(&client.transmit(now));
+        let init_ack = Association::answer_init(&config, SECRET, 2905, 5000, &init, keys(2));
+        let (local, cookie) = match init_ack.chunks[0] {
+            Chunk::InitAck(local, ref cookie) => (local, cookie.clone()),
+            ref chunk => panic!("Expected an INIT ACK, got {:?}", chunk),
+        };
+        let echo = |tag, cookie| Packet::new(5000, 2905, tag, vec![Chunk::CookieEcho(cookie)]);
+        let valid = echo(local.tag, cookie.clone());
+        assert!(Association::accept(config, SECRET, &valid, now).is_some());
+        assert!(Association::accept(config, b"another secret", &valid, now).is_none());
+        assert!(Association::accept(config, SECRET, &echo(local.tag ^ 1, cookie.clone()), now)
+            .is_none());
+        let mut forged = cookie.clone();
+        forged[0] ^= 1;
+        assert!(Association::accept(config, SECRET, &echo(local.tag, forged), now).is_none());
+
+        let mut parsed = Cookie::parse(&cookie, SECRET).unwrap();
+        assert_eq!(parsed.peer, init);
+        parsed.created -= 2 * millis_of(config.cookie_lifetime);
+        let expired = parsed.to_bytes(SECRET);
+        assert!(Association::accept(config, SECRET, &echo(local.tag, expired), now).is_none());
+    }
+
+    #[test]
+    fn streams_and_fragments() {
+        let now = Instant::now();
+        let (mut client, mut server) = establish(config(), config(), now);
+        let large = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        client.send(&Message::new(1, large.clone())).unwrap();
+        client.send(&Message::new(2, b"small".to_vec())).unwrap();
+        client.send(&Message::new(1, b"after large".to_vec())).unwrap();
+        assert_eq!(client.send(&Message::new(10, b"x".to_vec())).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+        assert_eq!(client.send(&Message::new(1, vec![])).unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+
+        settle(&mut client, &mut server, now);
+        assert_eq!(received(&mut server),
+                   vec![(1, large), (2, b"small".to_vec()), (1, b"after large".to_vec())]);
+        assert!(client.is_flushed());
+        assert!(client.cwnd() > 2 * config().mtu);
+    }
+
+    #[test]
+    fn retransmission() {
+        let mut now = Instant::now();
+        let (mut client, mut server) = establish(config(), config(), now);
+        // Each message fills a packet of its own
+        let messages = (0..3).map(|i| vec![i; 150]).collect::<Vec<_>>();
+        for message in &messages {
+            client.send(&Message::new(0, message.clone())).unwrap();
+        }
+        let packets = client.transmit(now);
+        assert_eq!(packets.len(), 3);
+        for packet in packets.into_iter().skip(1) {
+            server.recv_packet(&wire(packet), now);
+        }
+        assert!(server.recv().is_none());
+        // The gap is reported right away
+        for packet in server.transmit(now) {
+            client.recv_packet(&wire(packet), now);
+        }
+        assert!(!client.wants_transmit());
+
+        now = client.next_timeout().unwrap();
+        client.on_timeout(now);
+        assert_eq!(client.rto(), config().rto_initial * 2);
+        assert_eq!(client.cwnd(), config().mtu);
+        settle(&mut client, &mut server, now);
+        let expected = messages.into_iter().map(|message| (0, message)).collect::<Vec<_>>();
+        assert_eq!(received(&mut server), expected);
+        assert!(client.is_flushed());
+        assert!(client.next_timeout().is_none());
+    }
+
+    #[test]
+    fn fast_retransmit() {
+        let now = Instant::now();
+        let mut config = config();
+        config.mtu = 1000;
+        let (mut client, mut server) = establish(config, config, now);
+        for i in 0..5 {
+            client.send(&Message::new(0, vec![i; 800])).unwrap();
+        }
+        let packets = client.transmit(now);
+        assert_eq!(packets.len(), 5);
+        for packet in packets.into_iter().skip(1) {
+            server.recv_packet(&wire(packet), now);
+            for sack in server.transmit(now) {
+                client.recv_packet(&wire(sack), now);
+            }
+        }
+        // Three SACKs reported the first chunk missing, it is sent again
+        // without waiting for the timeout
+        assert!(client.wants_transmit());
+        settle(&mut client, &mut server, now);
+        assert_eq!(received(&mut server).len(), 5);
+        assert!(client.is_flushed());
+    }
+
+    #[test]
+    fn delayed_sack() {
+        let now = Instant::now();
+        let (mut client, mut server) = establish(config(), config(), now);
+        client.send(&Message::new(0, b"hello".to_vec())).unwrap();
+        exchange(&mut client, &mut server, now);
+        assert!(!client.is_flushed());
+        let sack_timeout = server.next_timeout().unwrap();
+        assert_eq!(sack_timeout, now + config().sack_delay);
+        server.on_timeout(sack_timeout);
+        exchange(&mut client, &mut server, sack_timeout);
+        assert!(client.is_flushed());
+    }
+
+    #[test]
+    fn unordered() {
+        let now = Instant::now();
+        let (mut client, mut server) = establish(config(), config(), now);
+        for data in &[b"first", b"later"] {
+            let mut message = Message::new(3, data.to_vec());
+            message.unordered = true;
+            message.ppid = 51;
+            client.send(&message).unwrap();
+        }
+        let mut packets = client.transmit(now);
+        assert_eq!(packets.len(), 1);
+        // Swap the chunks, unordered messages are delivered as they come
+        packets[0].chunks.reverse();
+        server.recv_packet(&wire(packets.remove(0)), now);
+        let message = server.recv().unwrap();
+        assert_eq!((message.data, message.ppid, message.unordered),
+                   (b"later".to_vec(), 51, true));
+        assert_eq!(server.recv().unwrap().data, b"first".to_vec());
+    }
+
+    #[test]
+    fn shutdown() {
+        let now = Instant::now();
+        let (mut client, mut server) = establish(config(), config(), now);
+        client.send(&Message::new(0, b"last words".to_vec())).unwrap();
+        client.shutdown(now);
+        assert_eq!(client.state(), State::ShutdownPending);
+        assert_eq!(client.send(&Message::new(0, b"more".to_vec())).unwrap_err().kind(),
+                   io::ErrorKind::NotConnected);
+        assert!(!server.at_end());
+        settle(&mut client, &mut server, now);
+        assert_eq!(client.state(), State::Closed);
+        assert_eq!(server.state(), State::Closed);
+        assert_eq!(client.failure(), None);
+        assert_eq!(server.failure(), None);
+        assert!(!server.at_end());
+        assert_eq!(server.recv().unwrap().data, b"last words".to_vec());
+        assert!(server.at_end());
+    }
+
+    #[test]
+    fn abort() {
+        let now = Instant::now();
+        let (mut client, mut server) = establish(config(), config(), now);
+        server.abort();
+        assert_eq!(server.failure(), Some(Failure::Aborted));
+        exchange(&mut client, &mut server, now);
+        assert_eq!(client.state(), State::Closed);
+        assert_eq!(client.failure(), Some(Failure::Aborted));
+    }
+
+    #[test]
+    fn out_of_the_blue_packets() {
+        let data = Chunk::Data(Data {
+            tsn: 1,
+            stream: 0,
+            ssn: 0,
+            ppid: 0,
+            unordered: false,
+            begin: true,
+            end: true,
+            data: vec![1],
+        });
+        let packet = |chunk| Packet::new(5000, 2905, 1234, vec![chunk]);
+        let reply = out_of_the_blue(&packet(data)).unwrap();
+        assert_eq!(reply,
+                   Packet::new(2905, 5000, 1234, vec![Chunk::Abort { reflected: true }]));
+        let reply = out_of_the_blue(&packet(Chunk::ShutdownAck)).unwrap();
+        assert_eq!(reply.chunks, vec![Chunk::ShutdownComplete { reflected: true }]);
+        for chunk in vec![Chunk::Abort { reflected: false },
+                          Chunk::ShutdownComplete { reflected: false },
+                          Chunk::CookieAck] {
+            assert_eq!(out_of_the_blue(&packet(chunk)), None);
+        }
+    }
+}
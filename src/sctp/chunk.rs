@@ -0,0 +1,554 @@
+//! The wire format of SCTP packets, RFC 4960 section 3, with the CRC32c
+//! checksum of RFC 3309.
+//!
+//! Nothing here does any I/O. `Packet::parse` checks the checksum and the
+//! lengths of the chunks, `Packet::to_bytes` builds a packet with a valid
+//! checksum. Parameters of INIT and INIT ACK chunks other than the state
+//! cookie are skipped, as are the causes of ABORT and ERROR chunks.
+
+use RxError;
+
+use std::cmp;
+
+/// Length of the common header, in front of the chunks.
+pub const COMMON_HEADER_LEN: usize = 12;
+
+/// Length of the header of DATA chunks, in front of the user data.
+pub const DATA_HEADER_LEN: usize = 16;
+
+/// Length of the fixed part of INIT and INIT ACK chunks, in front of their
+/// parameters.
+pub const INIT_LEN: usize = 16;
+
+/// Chunk types.
+pub const DATA: u8 = 0;
+pub const INIT: u8 = 1;
+pub const INIT_ACK: u8 = 2;
+pub const SACK: u8 = 3;
+pub const HEARTBEAT: u8 = 4;
+pub const HEARTBEAT_ACK: u8 = 5;
+pub const ABORT: u8 = 6;
+pub const SHUTDOWN: u8 = 7;
+pub const SHUTDOWN_ACK: u8 = 8;
+pub const ERROR: u8 = 9;
+pub const COOKIE_ECHO: u8 = 10;
+pub const COOKIE_ACK: u8 = 11;
+pub const SHUTDOWN_COMPLETE: u8 = 14;
+
+/// The parameter of INIT ACK chunks carrying the state cookie.
+pub const STATE_COOKIE: u16 = 7;
+
+/// The flags of DATA chunks.
+const UNORDERED: u8 = 0x04;
+const BEGINNING: u8 = 0x02;
+const ENDING: u8 = 0x01;
+
+/// The flag of ABORT and SHUTDOWN COMPLETE chunks telling that the
+/// verification tag is the one of the sender, not of the receiver.
+const REFLECTED: u8 = 0x01;
+
+/// An SCTP packet, the payload of an IPv4 packet with protocol 132.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// The verification tag, the one the receiver picked for the
+    /// association, or 0 in packets with an INIT chunk
+    pub tag: u32,
+    pub chunks: Vec<Chunk>,
+}
+
+/// The parameters of an INIT or INIT ACK chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Init {
+    /// The verification tag the sender expects in all packets to it
+    pub tag: u32,
+    /// The receiver window of the sender, in bytes
+    pub a_rwnd: u32,
+    pub out_streams: u16,
+    pub in_streams: u16,
+    pub initial_tsn: u32,
+}
+
+impl Init {
+    /// Parses the fixed part of an INIT or INIT ACK chunk at the start of
+    /// `data`.
+    pub fn parse(data: &[u8]) -> Option<Init> {
+        if data.len() < INIT_LEN {
+            return None;
+        }
+        Some(Init {
+            tag: read_u32(&data[0..4]),
+            a_rwnd: read_u32(&data[4..8]),
+            out_streams: read_u16(&data[8..10]),
+            in_streams: read_u16(&data[10..12]),
+            initial_tsn: read_u32(&data[12..16]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(INIT_LEN);
+        put_u32(&mut buffer, self.tag);
+        put_u32(&mut buffer, self.a_rwnd);
+        put_u16(&mut buffer, self.out_streams);
+        put_u16(&mut buffer, self.in_streams);
+        put_u32(&mut buffer, self.initial_tsn);
+        buffer
+    }
+}
+
+/// A DATA chunk, one message or a fragment of one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Data {
+    pub tsn: u32,
+    pub stream: u16,
+    /// The stream sequence number, the same in all fragments of a message
+    pub ssn: u16,
+    /// The payload protocol identifier, passed on to the application
+    pub ppid: u32,
+    pub unordered: bool,
+    /// If this is the first fragment of the message
+    pub begin: bool,
+    /// If this is the last fragment of the message
+    pub end: bool,
+    pub data: Vec<u8>,
+}
+
+/// A selective acknowledgement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sack {
+    /// All TSNs up to this one were received
+    pub cum_tsn: u32,
+    pub a_rwnd: u32,
+    /// The blocks of TSNs received beyond `cum_tsn`, as first and last
+    /// offset from it
+    pub gaps: Vec<(u16, u16)>,
+    /// TSNs received more than once since the last SACK
+    pub duplicates: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    Data(Data),
+    Init(Init),
+    /// The parameters of the sender and its state cookie
+    InitAck(Init, Vec<u8>),
+    Sack(Sack),
+    /// The heartbeat information, sent back as it is
+    Heartbeat(Vec<u8>),
+    HeartbeatAck(Vec<u8>),
+    /// `reflected` if the packet carries the verification tag of its
+    /// sender
+    Abort { reflected: bool },
+    Shutdown { cum_tsn: u32 },
+    ShutdownAck,
+    /// The causes of the error, unparsed
+    Error(Vec<u8>),
+    CookieEcho(Vec<u8>),
+    CookieAck,
+    ShutdownComplete { reflected: bool },
+    /// A chunk of any other type, with its flags and value
+    Unknown { kind: u8, flags: u8, value: Vec<u8> },
+}
+
+impl Chunk {
+    /// Returns the chunk type.
+    pub fn kind(&self) -> u8 {
+        match *self {
+            Chunk::Data(_) => DATA,
+            Chunk::Init(_) => INIT,
+            Chunk::InitAck(..) => INIT_ACK,
+            Chunk::Sack(_) => SACK,
+            Chunk::Heartbeat(_) => HEARTBEAT,
+            Chunk::HeartbeatAck(_) => HEARTBEAT_ACK,
+            Chunk::Abort { .. } => ABORT,
+            Chunk::Shutdown { .. } => SHUTDOWN,
+            Chunk::ShutdownAck => SHUTDOWN_ACK,
+            Chunk::Error(_) => ERROR,
+            Chunk::CookieEcho(_) => COOKIE_ECHO,
+            Chunk::CookieAck => COOKIE_ACK,
+            Chunk::ShutdownComplete { .. } => SHUTDOWN_COMPLETE,
+            Chunk::Unknown { kind, .. } => kind,
+        }
+    }
+
+    /// Returns the length of this chunk on the wire, padding included.
+    pub fn len(&self) -> usize {
+        padded(4 + self.value().len())
+    }
+
+    /// Parses the chunk at the start of `data`. Returns it and the length it
+    /// takes up, padding included.
+    fn parse(data: &[u8]) -> Result<(Chunk, usize), RxError> {
+        if data.len() < 4 {
+            return Err(RxError::InvalidLength);
+        }
+        let (kind, flags) = (data[0], data[1]);
+        let length = read_u16(&data[2..4]) as usize;
+        if length < 4 || data.len() < length {
+            return Err(RxError::InvalidLength);
+        }
+        let value = &data[4..length];
+        let chunk = match kind {
+            DATA => {
+                if value.len() <= DATA_HEADER_LEN - 4 {
+                    return Err(RxError::InvalidLength);
+                }
+                Chunk::Data(Data {
+                    tsn: read_u32(&value[0..4]),
+                    stream: read_u16(&value[4..6]),
+                    ssn: read_u16(&value[6..8]),
+                    ppid: read_u32(&value[8..12]),
+                    unordered: flags & UNORDERED != 0,
+                    begin: flags & BEGINNING != 0,
+                    end: flags & ENDING != 0,
+                    data: value[12..].to_vec(),
+                })
+            }
+            INIT => Chunk::Init(try!(parse_init(value)).0),
+            INIT_ACK => {
+                let (init, cookie) = try!(parse_init(value));
+                match cookie {
+                    Some(cookie) => Chunk::InitAck(init, cookie),
+                    None => return Err(RxError::InvalidContent),
+                }
+            }
+            SACK => Chunk::Sack(try!(parse_sack(value))),
+            HEARTBEAT => Chunk::Heartbeat(value.to_vec()),
+            HEARTBEAT_ACK => Chunk::HeartbeatAck(value.to_vec()),
+            ABORT => Chunk::Abort { reflected: flags & REFLECTED != 0 },
+            SHUTDOWN => {
+                if value.len() != 4 {
+                    return Err(RxError::InvalidLength);
+                }
+                Chunk::Shutdown { cum_tsn: read_u32(value) }
+            }
+            SHUTDOWN_ACK => Chunk::ShutdownAck,
+            ERROR => Chunk::Error(value.to_vec()),
+            COOKIE_ECHO => Chunk::CookieEcho(value.to_vec()),
+            COOKIE_ACK => Chunk::CookieAck,
+            SHUTDOWN_COMPLETE => Chunk::ShutdownComplete { reflected: flags & REFLECTED != 0 },
+            _ => {
+                Chunk::Unknown {
+                    kind: kind,
+                    flags: flags,
+                    value: value.to_vec(),
+                }
+            }
+        };
+        Ok((chunk, padded(length)))
+    }
+
+    fn flags(&self) -> u8 {
+        let flag = |set: bool, flag: u8| if set { flag } else { 0 };
+        match *self {
+            Chunk::Data(ref data) => {
+                flag(data.unordered, UNORDERED) | flag(data.begin, BEGINNING) |
+                flag(data.end, ENDING)
+            }
+            Chunk::Abort { reflected } |
+            Chunk::ShutdownComplete { reflected } => flag(reflected, REFLECTED),
+            Chunk::Unknown { flags, .. } => flags,
+            _ => 0,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        let mut value = Vec::new();
+        match *self {
+            Chunk::Data(ref data) => {
+                put_u32(&mut value, data.tsn);
+                put_u16(&mut value, data.stream);
+                put_u16(&mut value, data.ssn);
+                put_u32(&mut value, data.ppid);
+                value.extend_from_slice(&data.data);
+            }
+            Chunk::Init(ref init) => value.extend_from_slice(&init.to_bytes()),
+            Chunk::InitAck(ref init, ref cookie) => {
+                value.extend_from_slice(&init.to_bytes());
+                put_u16(&mut value, STATE_COOKIE);
+                put_u16(&mut value, 4 + cookie.len() as u16);
+                value.extend_from_slice(cookie);
+            }
+            Chunk::Sack(ref sack) => {
+                put_u32(&mut value, sack.cum_tsn);
+                put_u32(&mut value, sack.a_rwnd);
+                put_u16(&mut value, sack.gaps.len() as u16);
+                put_u16(&mut value, sack.duplicates.len() as u16);
+                for &(start, end) in &sack.gaps {
+                    put_u16(&mut value, start);
+                    put_u16(&mut value, end);
+                }
+                for duplicate in &sack.duplicates {
+                    put_u32(&mut value, *duplicate);
+                }
+            }
+            Chunk::Shutdown { cum_tsn } => put_u32(&mut value, cum_tsn),
+            Chunk::Heartbeat(ref info) |
+            Chunk::HeartbeatAck(ref info) |
+            Chunk::Error(ref info) |
+            Chunk::CookieEcho(ref info) |
+            Chunk::Unknown { value: ref info, .. } => value.extend_from_slice(info),
+            Chunk::Abort { .. } |
+            Chunk::ShutdownAck |
+            Chunk::CookieAck |
+            Chunk::ShutdownComplete { .. } => (),
+        }
+        value
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>) {
+        let value = self.value();
+        buffer.push(self.kind());
+        buffer.push(self.flags());
+        put_u16(buffer, 4 + value.len() as u16);
+        buffer.extend_from_slice(&value);
+        while buffer.len() % 4 != 0 {
+            buffer.push(0);
+        }
+    }
+}
+
+impl Packet {
+    pub fn new(src_port: u16, dst_port: u16, tag: u32, chunks: Vec<Chunk>) -> Packet {
+        Packet {
+            src_port: src_port,
+            dst_port: dst_port,
+            tag: tag,
+            chunks: chunks,
+        }
+    }
+
+    /// Parses `data`, the payload of an IPv4 packet.
+    ///
+    /// Fails with `RxError::InvalidChecksum` if the CRC32c is wrong, unless
+    /// `checksum` is false, and with `RxError::InvalidLength` if a chunk does
+    /// not fit. Chunks of unknown types are kept as `Chunk::Unknown`.
+    pub fn parse(data: &[u8], checksum: bool) -> Result<Packet, RxError> {
+        if data.len() < COMMON_HEADER_LEN {
+            return Err(RxError::InvalidLength);
+        }
+        if checksum && read_u32_le(&data[8..12]) != crc32c_of_packet(data) {
+            return Err(RxError::InvalidChecksum);
+        }
+        let mut chunks = Vec::new();
+        let mut offset = COMMON_HEADER_LEN;
+        while offset < data.len() {
+            let (chunk, len) = try!(Chunk::parse(&data[offset..]));
+            chunks.push(chunk);
+            offset += len;
+        }
+        Ok(Packet::new(read_u16(&data[0..2]), read_u16(&data[2..4]), read_u32(&data[4..8]), chunks))
+    }
+
+    /// Returns the length of this packet on the wire.
+    pub fn len(&self) -> usize {
+        COMMON_HEADER_LEN + self.chunks.iter().map(|chunk| chunk.len()).sum::<usize>()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.len());
+        put_u16(&mut buffer, self.src_port);
+        put_u16(&mut buffer, self.dst_port);
+        put_u32(&mut buffer, self.tag);
+        put_u32(&mut buffer, 0);
+        for chunk in &self.chunks {
+            chunk.write(&mut buffer);
+        }
+        let crc = crc32c(&buffer);
+        // The CRC32c is the only field of SCTP in little endian order
+        buffer[8] = crc as u8;
+        buffer[9] = (crc >> 8) as u8;
+        buffer[10] = (crc >> 16) as u8;
+        buffer[11] = (crc >> 24) as u8;
+        buffer
+    }
+}
+
+/// Returns the length of a chunk of `len` bytes padded to four bytes.
+pub fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn parse_init(value: &[u8]) -> Result<(Init, Option<Vec<u8>>), RxError> {
+    let init = match Init::parse(value) {
+        Some(init) => init,
+        None => return Err(RxError::InvalidLength),
+    };
+    let mut cookie = None;
+    let mut params = &value[INIT_LEN..];
+    while params.len() >= 4 {
+        let kind = read_u16(&params[0..2]);
+        let length = read_u16(&params[2..4]) as usize;
+        if length < 4 || params.len() < length {
+            return Err(RxError::InvalidLength);
+        }
+        if kind == STATE_COOKIE {
+            cookie = Some(params[4..length].to_vec());
+        }
+        params = &params[cmp::min(padded(length), params.len())..];
+    }
+    Ok((init, cookie))
+}
+
+fn parse_sack(value: &[u8]) -> Result<Sack, RxError> {
+    if value.len() < 12 {
+        return Err(RxError::InvalidLength);
+    }
+    let gaps = read_u16(&value[8..10]) as usize;
+    let duplicates = read_u16(&value[10..12]) as usize;
+    if value.len() < 12 + 4 * (gaps + duplicates) {
+        return Err(RxError::InvalidLength);
+    }
+    let gap_blocks = &value[12..12 + 4 * gaps];
+    let duplicate_tsns = &value[12 + 4 * gaps..12 + 4 * (gaps + duplicates)];
+    Ok(Sack {
+        cum_tsn: read_u32(&value[0..4]),
+        a_rwnd: read_u32(&value[4..8]),
+        gaps: gap_blocks.chunks(4)
+            .map(|block| (read_u16(&block[0..2]), read_u16(&block[2..4])))
+            .collect(),
+        duplicates: duplicate_tsns.chunks(4).map(read_u32).collect(),
+    })
+}
+
+lazy_static! {
+    static ref CRC32C_TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { crc >> 1 ^ 0x82f63b78 } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+/// Returns the CRC32c, the Castagnoli CRC, of `data`.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !update_crc32c(!0, data)
+}
+
+/// Returns the CRC32c of `packet` with its checksum field taken as zero.
+fn crc32c_of_packet(packet: &[u8]) -> u32 {
+    let crc = update_crc32c(!0, &packet[..8]);
+    let crc = update_crc32c(crc, &[0; 4]);
+    !update_crc32c(crc, &packet[COMMON_HEADER_LEN..])
+}
+
+fn update_crc32c(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ crc >> 8
+    })
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    (data[0] as u32) << 24 | (data[1] as u32) << 16 | (data[2] as u32) << 8 | data[3] as u32
+}
+
+fn read_u32_le(data: &[u8]) -> u32 {
+    (data[3] as u32) << 24 | (data[2] as u32) << 16 | (data[1] as u32) << 8 | data[0] as u32
+}
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    put_u16(buffer, (value >> 16) as u16);
+    put_u16(buffer, value as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    use RxError;
+
+    use super::*;
+
+    fn data(tsn: u32, data: &[u8]) -> Chunk {
+        Chunk::Data(Data {
+            tsn: tsn,
+            stream: 2,
+            ssn: 7,
+            ppid: 46,
+            unordered: true,
+            begin: true,
+            end: false,
+            data: data.to_vec(),
+        })
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a9136aa);
+    }
+
+    #[test]
+    fn round_trip() {
+        let init = Init {
+            tag: 0xdeadbeef,
+            a_rwnd: 65536,
+            out_streams: 10,
+            in_streams: 65535,
+            initial_tsn: 1,
+        };
+        let sack = Sack {
+            cum_tsn: 100,
+            a_rwnd: 1000,
+            gaps: vec![(2, 3), (5, 5)],
+            duplicates: vec![99],
+        };
+        let chunks = vec![Chunk::Init(init),
+                          Chunk::InitAck(init, vec![1, 2, 3]),
+                          data(5, &[1, 2, 3, 4, 5]),
+                          Chunk::Sack(sack),
+                          Chunk::Heartbeat(vec![0, 1, 0, 6, 9, 9]),
+                          Chunk::Abort { reflected: true },
+                          Chunk::Shutdown { cum_tsn: 3 },
+                          Chunk::ShutdownAck,
+                          Chunk::CookieEcho(vec![4; 33]),
+                          Chunk::CookieAck,
+                          Chunk::ShutdownComplete { reflected: false },
+                          Chunk::Unknown {
+                              kind: 0xc1,
+                              flags: 3,
+                              value: vec![8],
+                          }];
+        let packet = Packet::new(5000, 36412, 42, chunks);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.len(), packet.len());
+        assert_eq!(bytes.len() % 4, 0);
+        assert_eq!(Packet::parse(&bytes, true), Ok(packet));
+    }
+
+    #[test]
+    fn invalid() {
+        let mut bytes = Packet::new(1, 2, 3, vec![data(1, &[9])]).to_bytes();
+        // Data chunk of 17 bytes, padded to 20
+        assert_eq!(bytes.len(), COMMON_HEADER_LEN + 20);
+        assert_eq!(&bytes[12..16], &[DATA, 0x06, 0, 17]);
+        bytes[28] = 8;
+        assert_eq!(Packet::parse(&bytes, true), Err(RxError::InvalidChecksum));
+        assert!(Packet::parse(&bytes, false).is_ok());
+        bytes[15] = 40;
+        assert_eq!(Packet::parse(&bytes, false), Err(RxError::InvalidLength));
+        // Data chunk without user data
+        bytes[15] = 16;
+        assert_eq!(Packet::parse(&bytes, false), Err(RxError::InvalidLength));
+        assert_eq!(Packet::parse(&bytes[..8], false), Err(RxError::InvalidLength));
+        // INIT ACK without a state cookie
+        let mut init_ack = vec![INIT_ACK, 0, 0, 20];
+        init_ack.extend_from_slice(&[0; 16]);
+        let mut bytes = Packet::new(1, 2, 3, vec![]).to_bytes();
+        bytes.extend_from_slice(&init_ack);
+        assert_eq!(Packet::parse(&bytes, false), Err(RxError::InvalidContent));
+    }
+}
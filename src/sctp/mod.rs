@@ -0,0 +1,810 @@
+//! SCTP, RFC 4960, a reliable transport carrying messages on several
+//! independent streams of one association.
+//!
+//! An `SctpEndpoint` takes the SCTP packets to one address of the stack and
+//! runs all associations on it from a thread of its own. An `SctpListener`
+//! accepts the associations peers set up to a port of the endpoint, and
+//! `SctpEndpoint::connect` sets one up to a remote endpoint:
+//!
+//! ```rust,ignore
+//! let endpoint = SctpEndpoint::bind(stack.clone(), local_ip, SctpConfig::default())?;
+//! let listener = endpoint.listen(2905)?;
+//! let association = endpoint.connect(0, SocketAddrV4::new(remote_ip, 2905))?;
+//! association.send(1, b"on stream 1")?;
+//! let message = listener.accept()?.recv()?;
+//! ```
+//!
+//! Associations are set up with the four way handshake of INIT, INIT ACK,
+//! COOKIE ECHO and COOKIE ACK. The listening end keeps no state until the
+//! cookie it handed out in its INIT ACK comes back, so a flood of INITs fills
+//! up no tables. How messages are carried once the association is up is
+//! described in `association`. `chunk` builds and parses packets of any
+//! kind, for testing how middleboxes handle them.
+//!
+//! Endpoints have a single address, multi-homing is not supported. The
+//! protocol stays registered on the address after the endpoint is dropped,
+//! see `NetworkStack::ipv4_listen`, so an address takes one endpoint for the
+//! life of the stack.
+
+pub mod association;
+pub mod chunk;
+
+use {NetworkStack, RxError, RxResult, StackError, StackResult, TxError};
+use ethernet::EthernetTxImpl;
+use handle;
+use ipv4::{BasicIpv4Payload, Ipv4Listener, Ipv4Tx, Ipv4TxImpl};
+use sockopt::{HasSocketOptions, SocketOptions};
+use stack::{DEFAULT_MTU, DatalinkTx};
+
+use pnet::packet::Packet as PnetPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use self::association::{Association, LocalKeys};
+pub use self::association::{Failure, Message, State};
+use self::chunk::{Chunk, Packet};
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Maximum number of peer addresses an endpoint keeps tx-objects for. The
+/// cache is flushed when it grows beyond this.
+pub static TX_CACHE_SIZE: usize = 1024;
+
+/// How associations behave. The timers and limits default to the
+/// protocol parameters of RFC 4960 section 15.
+#[derive(Debug, Clone, Copy)]
+pub struct SctpConfig {
+    /// Streams to open towards the peer, fewer if it takes fewer
+    pub out_streams: u16,
+    /// Most streams to take from the peer
+    pub max_in_streams: u16,
+    /// Bytes of received messages held until they are read, the receiver
+    /// window advertised to the peer
+    pub recv_window: u32,
+    /// Bytes of sent messages held until they are acknowledged. Sending
+    /// waits while it is full.
+    pub send_buffer: usize,
+    /// The MTU of the path to the peers. Packets are sized to fit it, so
+    /// they are not fragmented.
+    pub mtu: usize,
+    pub rto_initial: Duration,
+    pub rto_min: Duration,
+    pub rto_max: Duration,
+    /// Times the INIT and the COOKIE ECHO are sent again before giving up
+    pub max_init_retransmits: usize,
+    /// Retransmission timeouts in a row before giving up on an established
+    /// association
+    pub max_retransmits: usize,
+    /// How long the cookie of an INIT ACK is valid
+    pub cookie_lifetime: Duration,
+    /// How long acknowledging received chunks may be held back
+    pub sack_delay: Duration,
+}
+
+impl Default for SctpConfig {
+    fn default() -> SctpConfig {
+        SctpConfig {
+            out_streams: 10,
+            max_in_streams: 10,
+            recv_window: 65536,
+            send_buffer: 131072,
+            mtu: DEFAULT_MTU,
+            rto_initial: Duration::from_secs(3),
+            rto_min: Duration::from_secs(1),
+            rto_max: Duration::from_secs(60),
+            max_init_retransmits: 8,
+            max_retransmits: 10,
+            cookie_lifetime: Duration::from_secs(60),
+            sack_delay: Duration::from_millis(200),
+        }
+    }
+}
+
+/// The SCTP endpoint of one address of a stack. Aborts all its associations
+/// when dropped.
+pub struct SctpEndpoint {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SctpEndpoint {
+    /// Starts taking the SCTP packets to `local_ip`, an address of `stack`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `config` has no streams
+    /// or an MTU below the 68 bytes of RFC 791, and with an IO error if the
+    /// address is not in the stack or already has an endpoint.
+    pub fn bind(stack: Arc<Mutex<NetworkStack>>,
+                local_ip: Ipv4Addr,
+                config: SctpConfig)
+                -> StackResult<SctpEndpoint> {
+        if config.out_streams == 0 || config.max_in_streams == 0 {
+            let msg = "Associations need at least one stream each way".to_owned();
+            return Err(StackError::InvalidArgument(msg));
+        }
+        if config.mtu < 68 {
+            let msg = format!("MTU {} is below the IPv4 minimum of 68", config.mtu);
+            return Err(StackError::InvalidArgument(msg));
+        }
+        let (events_tx, events_rx) = mpsc::channel();
+        let rx = SctpRx { events: events_tx.clone() };
+        let secret = {
+            let mut stack = stack.lock().unwrap();
+            stack.ipv4_listen(local_ip, IpNextHeaderProtocols::Sctp, Box::new(rx))?;
+            stack.random()
+        };
+        let shared = Arc::new(Shared {
+            local_ip: local_ip,
+            config: config,
+            stack: stack.clone(),
+            endpoint: Mutex::new(Endpoint::new(secret)),
+            changed: Condvar::new(),
+            events: Mutex::new(events_tx),
+        });
+        let packet_tx = PacketTx {
+            stack: stack,
+            local_ip: local_ip,
+            txs: HashMap::new(),
+        };
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || run(thread_shared, events_rx, packet_tx));
+        Ok(SctpEndpoint {
+            shared: shared,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_ip(&self) -> Ipv4Addr {
+        self.shared.local_ip
+    }
+
+    pub fn config(&self) -> &SctpConfig {
+        &self.shared.config
+    }
+
+    /// Starts accepting the associations peers set up to `port`.
+    ///
+    /// Fails with `io::ErrorKind::AddrInUse` if something listens on the
+    /// port already.
+    pub fn listen(&self, port: u16) -> io::Result<SctpListener> {
+        if port == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Listening on port 0"));
+        }
+        let mut endpoint = self.shared.endpoint.lock().unwrap();
+        if endpoint.listeners.contains_key(&port) {
+            let msg = format!("Port {} is already listened on", port);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+        }
+        endpoint.listeners.insert(port, VecDeque::new());
+        Ok(SctpListener {
+            shared: self.shared.clone(),
+            port: port,
+        })
+    }
+
+    /// Sets up an association from `local_port`, or a random free port of
+    /// the local port range of the stack if it is 0, to `remote`, waiting
+    /// until it is established.
+    ///
+    /// Fails with `io::ErrorKind::ConnectionRefused` if the remote endpoint
+    /// answers with an ABORT, as for ports it does not listen on, and with
+    /// `io::ErrorKind::TimedOut` if it never answers.
+    pub fn connect(&self, local_port: u16, remote: SocketAddrV4) -> io::Result<SctpAssociation> {
+        if remote.port() == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Connecting to port 0"));
+        }
+        let keys = local_keys(&self.shared.stack.lock().unwrap());
+        let (id, local_port) = {
+            let mut endpoint = self.shared.endpoint.lock().unwrap();
+            if endpoint.stopped {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "Endpoint is stopped"));
+            }
+            let local_port = if local_port == 0 {
+                endpoint.free_port(&self.shared.stack.lock().unwrap())
+            } else if endpoint.find(local_port, remote).is_some() {
+                let msg = format!("Port {} already has an association to {}", local_port, remote);
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+            } else {
+                local_port
+            };
+            let association = Association::connect(self.shared.config,
+                                                   local_port,
+                                                   remote.port(),
+                                                   keys,
+                                                   Instant::now());
+            (endpoint.insert(remote, association), local_port)
+        };
+        let association = SctpAssociation {
+            shared: self.shared.clone(),
+            id: id,
+            local_addr: SocketAddrV4::new(self.shared.local_ip, local_port),
+            peer_addr: remote,
+            options: RwLock::new(SocketOptions::default()),
+        };
+        association.with_association(true, None, |association| match association.state() {
+                State::CookieWait | State::CookieEchoed => None,
+                State::Closed => Some(Err(closed_error(association.failure()))),
+                _ => Some(Ok(())),
+            })?;
+        Ok(association)
+    }
+}
+
+impl Drop for SctpEndpoint {
+    fn drop(&mut self) {
+        self.shared.events.lock().unwrap().send(Event::Stop).unwrap_or(());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+/// Accepts the associations peers set up to one port. Associations not
+/// yet accepted are aborted when dropped.
+pub struct SctpListener {
+    shared: Arc<Shared>,
+    port: u16,
+}
+
+impl SctpListener {
+    /// Waits for a peer to set up an association, and returns it.
+    pub fn accept(&self) -> io::Result<SctpAssociation> {
+        self.accept_opt(true)
+    }
+
+    /// Same as `accept`, but fails with `io::ErrorKind::WouldBlock` instead
+    /// of waiting if no association was set up.
+    pub fn try_accept(&self) -> io::Result<SctpAssociation> {
+        self.accept_opt(false)
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.shared.local_ip, self.port)
+    }
+
+    fn accept_opt(&self, block: bool) -> io::Result<SctpAssociation> {
+        let mut endpoint = self.shared.endpoint.lock().unwrap();
+        loop {
+            if endpoint.stopped {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "Endpoint is stopped"));
+            }
+            let id = endpoint.listeners.get_mut(&self.port).and_then(|backlog| backlog.pop_front());
+            if let Some(id) = id {
+                return Ok(SctpAssociation {
+                    shared: self.shared.clone(),
+                    id: id,
+                    local_addr: self.local_addr(),
+                    peer_addr: endpoint.associations[&id].peer,
+                    options: RwLock::new(SocketOptions::default()),
+                });
+            }
+            if !block {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "No association set up"));
+            }
+            endpoint = self.shared.changed.wait(endpoint).unwrap();
+        }
+    }
+}
+
+impl Drop for SctpListener {
+    fn drop(&mut self) {
+        let mut endpoint = self.shared.endpoint.lock().unwrap();
+        if let Some(backlog) = endpoint.listeners.remove(&self.port) {
+            for id in backlog {
+                if let Some(entry) = endpoint.associations.get_mut(&id) {
+                    entry.association.abort();
+                    entry.orphaned = true;
+                }
+            }
+        }
+        drop(endpoint);
+        self.shared.wake();
+    }
+}
+
+/// An association with a peer. Shuts down gracefully when dropped, with
+/// the messages already sent still being delivered.
+///
+/// Of the `sockopt` options, only `ReadTimeout` applies to associations.
+pub struct SctpAssociation {
+    shared: Arc<Shared>,
+    id: usize,
+    local_addr: SocketAddrV4,
+    peer_addr: SocketAddrV4,
+    options: RwLock<SocketOptions>,
+}
+
+impl SctpAssociation {
+    /// Sends `data` as an ordered message on `stream`.
+    pub fn send(&self, stream: u16, data: &[u8]) -> io::Result<()> {
+        self.send_message(&Message::new(stream, data.to_vec()))
+    }
+
+    /// Sends `message`, waiting while the send buffer is full.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if the association has no
+    /// such stream, see `streams`, and with `io::ErrorKind::NotConnected`
+    /// once it is shutting down.
+    pub fn send_message(&self, message: &Message) -> io::Result<()> {
+        self.with_association(true, None, |association| {
+            if association.state() == State::Closed {
+                return Some(Err(closed_error(association.failure())));
+            }
+            match association.send(message) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+                result => Some(result),
+            }
+        })
+    }
+
+    /// Receives the next message, waiting for one at most as long as the
+    /// `sockopt::ReadTimeout` of this association. Returns `None` once the
+    /// peer shut the association down and all its messages were received.
+    ///
+    /// Fails with `io::ErrorKind::ConnectionReset` if the association was
+    /// aborted, and with `io::ErrorKind::TimedOut` if the peer stopped
+    /// answering.
+    pub fn recv(&self) -> io::Result<Option<Message>> {
+        let timeout = self.options.read().unwrap().read_timeout;
+        self.recv_opt(true, timeout)
+    }
+
+    /// Same as `recv`, but fails with `io::ErrorKind::WouldBlock` instead of
+    /// waiting if no message was received yet.
+    pub fn try_recv(&self) -> io::Result<Option<Message>> {
+        self.recv_opt(false, None)
+    }
+
+    /// Starts a graceful shutdown. Messages sent already are still
+    /// delivered, and the messages of the peer can still be received.
+    pub fn shutdown(&self) {
+        let result = self.with_association(false, None, |association| {
+            association.shutdown(Instant::now());
+            Some(Ok(()))
+        });
+        result.unwrap_or(())
+    }
+
+    /// Closes the association right away, dropping what was not delivered.
+    pub fn abort(&self) {
+        let result = self.with_association(false, None, |association| {
+            association.abort();
+            Some(Ok(()))
+        });
+        result.unwrap_or(())
+    }
+
+    pub fn state(&self) -> State {
+        self.with_association(false, None, |association| Some(Ok(association.state())))
+            .unwrap_or(State::Closed)
+    }
+
+    /// Returns why the association closed, `None` if it is open or was
+    /// shut down gracefully.
+    pub fn failure(&self) -> Option<Failure> {
+        self.with_association(false, None, |association| Some(Ok(association.failure())))
+            .unwrap_or(Some(Failure::Aborted))
+    }
+
+    /// Returns the number of streams messages can be sent on, and received
+    /// on.
+    pub fn streams(&self) -> (u16, u16) {
+        self.with_association(false, None, |association| Some(Ok(association.streams())))
+            .unwrap_or((0, 0))
+    }
+
+    /// Waits until all messages sent were acknowledged by the peer, at most
+    /// `timeout`.
+    pub fn flush(&self, timeout: Duration) -> io::Result<()> {
+        self.with_association(true, Some(timeout), |association| {
+            if association.is_flushed() {
+                Some(Ok(()))
+            } else if association.state() == State::Closed {
+                Some(Err(closed_error(association.failure())))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.peer_addr
+    }
+
+    fn recv_opt(&self, block: bool, timeout: Option<Duration>) -> io::Result<Option<Message>> {
+        self.with_association(block, timeout, |association| {
+            if let Some(message) = association.recv() {
+                Some(Ok(Some(message)))
+            } else if association.at_end() {
+                match association.failure() {
+                    Some(failure) => Some(Err(closed_error(Some(failure)))),
+                    None => Some(Ok(None)),
+                }
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Calls `f` with the association until it returns a result, waiting
+    /// for the endpoint to change in between. The time waited is limited by
+    /// `timeout`, if not `block`ing it fails with `io::ErrorKind::WouldBlock`
+    /// instead.
+    fn with_association<T, F>(&self,
+                              block: bool,
+                              timeout: Option<Duration>,
+                              mut f: F)
+                              -> io::Result<T>
+        where F: FnMut(&mut Association) -> Option<io::Result<T>>
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut endpoint = self.shared.endpoint.lock().unwrap();
+        loop {
+            let result = match endpoint.associations.get_mut(&self.id) {
+                Some(entry) => f(&mut entry.association),
+                None => Some(Err(closed_error(Some(Failure::Aborted)))),
+            };
+            if let Some(result) = result {
+                drop(endpoint);
+                self.shared.wake();
+                return result;
+            }
+            if !block {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "Association not ready"));
+            }
+            endpoint = match deadline {
+                None => self.shared.changed.wait(endpoint).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"));
+                    }
+                    self.shared.changed.wait_timeout(endpoint, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+impl HasSocketOptions for SctpAssociation {
+    fn socket_options(&self) -> &RwLock<SocketOptions> {
+        &self.options
+    }
+}
+
+impl Drop for SctpAssociation {
+    fn drop(&mut self) {
+        let mut endpoint = self.shared.endpoint.lock().unwrap();
+        if let Some(entry) = endpoint.associations.get_mut(&self.id) {
+            entry.association.shutdown(Instant::now());
+            entry.orphaned = true;
+        }
+        drop(endpoint);
+        self.shared.wake();
+    }
+}
+
+/// What the endpoint, its listeners and associations share with the thread
+/// of the endpoint.
+struct Shared {
+    local_ip: Ipv4Addr,
+    config: SctpConfig,
+    /// Draws the random values of the endpoint. Locked after `endpoint`
+    /// when both are
+    stack: Arc<Mutex<NetworkStack>>,
+    endpoint: Mutex<Endpoint>,
+    /// Notified whenever the thread ran
+    changed: Condvar,
+    events: Mutex<Sender<Event>>,
+}
+
+impl Shared {
+    /// Makes the thread send what the associations have to send.
+    fn wake(&self) {
+        self.events.lock().unwrap().send(Event::Wake).unwrap_or(());
+    }
+}
+
+enum Event {
+    Packet(Ipv4Addr, Packet),
+    Wake,
+    Stop,
+}
+
+/// An association and who it is with.
+struct Entry {
+    association: Association,
+    peer: SocketAddrV4,
+    /// If the handle of the association was dropped, so it is forgotten
+    /// once closed
+    orphaned: bool,
+}
+
+/// The associations and listeners of an endpoint.
+struct Endpoint {
+    secret: [u8; 16],
+    associations: HashMap<usize, Entry>,
+    next_id: usize,
+    /// The associations set up to each port listened on, not yet accepted
+    listeners: HashMap<u16, VecDeque<usize>>,
+    /// Packets sent for no association, to the address they are to
+    replies: Vec<(Ipv4Addr, Packet)>,
+    stopped: bool,
+}
+
+impl Endpoint {
+    fn new(secret: [u8; 16]) -> Endpoint {
+        Endpoint {
+            secret: secret,
+            associations: HashMap::new(),
+            next_id: 0,
+            listeners: HashMap::new(),
+            replies: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    fn insert(&mut self, peer: SocketAddrV4, association: Association) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        let entry = Entry {
+            association: association,
+            peer: peer,
+            orphaned: false,
+        };
+        self.associations.insert(id, entry);
+        id
+    }
+
+    /// Returns the association of `local_port` with `peer`, if any.
+    fn find(&self, local_port: u16, peer: SocketAddrV4) -> Option<usize> {
+        self.associations
+            .iter()
+            .find(|&(_, entry)| {
+                entry.peer == peer && entry.association.local_port() == local_port &&
+                entry.association.state() != State::Closed
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Returns a random port of the local port range of `stack` without
+    /// listener or association.
+    fn free_port(&self, stack: &NetworkStack) -> u16 {
+        loop {
+            let port = stack.random_local_port();
+            let on_port = |entry: &Entry| entry.association.local_port() == port;
+            if !self.listeners.contains_key(&port) && !self.associations.values().any(on_port) {
+                return port;
+            }
+        }
+    }
+
+    fn handle(&mut self,
+              config: &SctpConfig,
+              stack: &Mutex<NetworkStack>,
+              src: Ipv4Addr,
+              packet: Packet,
+              now: Instant) {
+        let peer = SocketAddrV4::new(src, packet.src_port);
+        if let Some(id) = self.find(packet.dst_port, peer) {
+            let entry = self.associations.get_mut(&id).unwrap();
+            entry.association.recv_packet(&packet, now);
+            return;
+        }
+        let listening = self.listeners.contains_key(&packet.dst_port);
+        match packet.chunks.first() {
+            Some(&Chunk::Init(ref init)) => {
+                // INIT chunks come alone, with verification tag 0
+                if packet.tag != 0 || packet.chunks.len() != 1 {
+                    return;
+                }
+                let reply = if listening {
+                    Association::answer_init(config,
+                                             &self.secret,
+                                             packet.dst_port,
+                                             packet.src_port,
+                                             init,
+                                             local_keys(&stack.lock().unwrap()))
+                } else {
+                    let abort = Chunk::Abort { reflected: false };
+                    Packet::new(packet.dst_port, packet.src_port, init.tag, vec![abort])
+                };
+                self.replies.push((src, reply));
+                return;
+            }
+            Some(&Chunk::CookieEcho(_)) if listening => {
+                match Association::accept(*config, &self.secret, &packet, now) {
+                    Some(association) => {
+                        let id = self.insert(peer, association);
+                        self.listeners.get_mut(&packet.dst_port).unwrap().push_back(id);
+                    }
+                    None => debug!("Sctp: Dropping invalid cookie from {}", peer),
+                }
+                return;
+            }
+            _ => (),
+        }
+        if let Some(reply) = association::out_of_the_blue(&packet) {
+            self.replies.push((src, reply));
+        }
+    }
+
+    fn next_timeout(&self) -> Option<Instant> {
+        self.associations.values().filter_map(|entry| entry.association.next_timeout()).min()
+    }
+
+    fn on_timeout(&mut self, now: Instant) {
+        for entry in self.associations.values_mut() {
+            if entry.association.next_timeout().map_or(false, |timeout| timeout <= now) {
+                entry.association.on_timeout(now);
+            }
+        }
+    }
+
+    /// Returns what all associations have to send, with the addresses they
+    /// are to.
+    fn transmit(&mut self, now: Instant) -> Vec<(Ipv4Addr, Packet)> {
+        let mut packets = self.replies.drain(..).collect::<Vec<_>>();
+        for entry in self.associations.values_mut() {
+            let dst = *entry.peer.ip();
+            packets.extend(entry.association.transmit(now).into_iter().map(|packet| (dst, packet)));
+        }
+        packets
+    }
+
+    /// Forgets the closed associations without a handle.
+    fn reap(&mut self) {
+        let closed = self.associations
+            .iter()
+            .filter(|&(_, entry)| entry.orphaned && entry.association.state() == State::Closed)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in closed {
+            self.associations.remove(&id);
+        }
+    }
+}
+
+/// Runs the associations of an endpoint until the endpoint is dropped.
+fn run(shared: Arc<Shared>, events: Receiver<Event>, mut packet_tx: PacketTx) {
+    loop {
+        let timeout = shared.endpoint.lock().unwrap().next_timeout();
+        let event = match timeout {
+            Some(timeout) => {
+                let now = Instant::now();
+                let wait = if timeout > now { timeout - now } else { Duration::from_millis(0) };
+                events.recv_timeout(wait)
+            }
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let now = Instant::now();
+        let stop = match event {
+            Ok(Event::Stop) | Err(RecvTimeoutError::Disconnected) => true,
+            _ => false,
+        };
+        let packets = {
+            let mut endpoint = shared.endpoint.lock().unwrap();
+            match event {
+                Ok(Event::Packet(src, packet)) => {
+                    endpoint.handle(&shared.config, &shared.stack, src, packet, now)
+                }
+                _ if stop => {
+                    endpoint.stopped = true;
+                    for entry in endpoint.associations.values_mut() {
+                        entry.association.abort();
+                    }
+                }
+                _ => (),
+            }
+            endpoint.on_timeout(now);
+            let packets = endpoint.transmit(now);
+            endpoint.reap();
+            shared.changed.notify_all();
+            packets
+        };
+        for (dst, packet) in packets {
+            packet_tx.send(dst, &packet);
+        }
+        if stop {
+            break;
+        }
+    }
+    debug!("Sctp endpoint on {} is quitting", shared.local_ip);
+}
+
+/// Draws the verification tag and initial TSN of a new association from the
+/// generator of `stack`.
+fn local_keys(stack: &NetworkStack) -> LocalKeys {
+    let mut tag = 0;
+    while tag == 0 {
+        tag = stack.random();
+    }
+    LocalKeys {
+        tag: tag,
+        initial_tsn: stack.random(),
+    }
+}
+
+type CachedTx = Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>;
+
+/// Sends the packets of an endpoint, keeping a tx-object per peer address.
+struct PacketTx {
+    stack: Arc<Mutex<NetworkStack>>,
+    local_ip: Ipv4Addr,
+    txs: HashMap<Ipv4Addr, CachedTx>,
+}
+
+impl PacketTx {
+    fn send(&mut self, dst: Ipv4Addr, packet: &Packet) {
+        let bytes = packet.to_bytes();
+        let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Sctp, &bytes);
+        // A second try with a new tx-object if the cached one is outdated
+        for _ in 0..2 {
+            if !self.txs.contains_key(&dst) {
+                let local_ip = self.local_ip;
+                let ipv4_tx = |stack: &mut NetworkStack| stack.ipv4_tx_with_src(local_ip, dst);
+                match handle::with_resolved(&self.stack, None, dst, ipv4_tx) {
+                    Ok(ipv4_tx) => {
+                        if self.txs.len() >= TX_CACHE_SIZE {
+                            self.txs.clear();
+                        }
+                        self.txs.insert(dst, ipv4_tx);
+                    }
+                    Err(e) => {
+                        warn!("Sctp: No way to reach {}: {}", dst, e);
+                        return;
+                    }
+                }
+            }
+            match self.txs.get_mut(&dst).unwrap().send(payload.clone()) {
+                Err(TxError::InvalidTx) => {
+                    self.txs.remove(&dst);
+                }
+                Err(e) => {
+                    warn!("Sctp: Unable to send to {}: {}", dst, e);
+                    return;
+                }
+                Ok(_) => return,
+            }
+        }
+    }
+}
+
+/// Hands the SCTP packets to the address of an endpoint to its thread.
+struct SctpRx {
+    events: Sender<Event>,
+}
+
+impl Ipv4Listener for SctpRx {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
+        let packet = Packet::parse(ip_pkg.payload(), true)?;
+        let event = Event::Packet(ip_pkg.get_source(), packet);
+        self.events.send(event).map_err(|_| RxError::NoListener("Sctp endpoint is gone".to_owned()))
+    }
+}
+
+/// Returns the error of using an association that closed, because of
+/// `failure` if it did not shut down gracefully.
+fn closed_error(failure: Option<Failure>) -> io::Error {
+    match failure {
+        Some(Failure::Refused) => {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "Association refused by the peer")
+        }
+        Some(Failure::Aborted) => {
+            io::Error::new(io::ErrorKind::ConnectionReset, "Association aborted")
+        }
+        Some(Failure::TimedOut) => {
+            io::Error::new(io::ErrorKind::TimedOut, "Peer stopped answering")
+        }
+        None => io::Error::new(io::ErrorKind::NotConnected, "Association shut down"),
+    }
+}
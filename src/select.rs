@@ -0,0 +1,157 @@
+//! Waiting for any of several sockets to become readable.
+//!
+//! A server handling many sockets on one thread, without an event loop of
+//! its own, waits for them with a `Selector` and then receives from the
+//! ones that are ready with their non-blocking `try_*` methods:
+//!
+//! ```rust,ignore
+//! let selector = Selector::new();
+//! loop {
+//!     for i in selector.select(&[&dns, &dhcp], Some(Duration::from_secs(1))) {
+//!         ...
+//!     }
+//!     // Timers and other periodic work
+//! }
+//! ```
+//!
+//! Any socket implementing `Selectable` can be waited for, today the UDP
+//! sockets. It builds on the `Waker`s of `reactor`, so it is woken by the
+//! threads receiving on the interfaces without polling.
+
+use reactor::{Async, Waker};
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Something a `Selector` can wait for.
+pub trait Selectable {
+    /// Returns `Async::Ready` if receiving would not block, else makes
+    /// `waker` be called when it might not. Registering the same waker again
+    /// before it was called must have no further effect.
+    fn poll_readable(&self, waker: &Waker) -> Async<()>;
+}
+
+struct Signal {
+    woken: Mutex<bool>,
+    cond: Condvar,
+}
+
+/// Waits for readability of several `Selectable`s at once. See the module
+/// documentation.
+pub struct Selector {
+    signal: Arc<Signal>,
+    waker: Waker,
+}
+
+impl Selector {
+    pub fn new() -> Selector {
+        let signal = Arc::new(Signal {
+            woken: Mutex::new(false),
+            cond: Condvar::new(),
+        });
+        let waker_signal = signal.clone();
+        let waker: Waker = Arc::new(move || {
+            *waker_signal.woken.lock().unwrap() = true;
+            waker_signal.cond.notify_all();
+        });
+        Selector {
+            signal: signal,
+            waker: waker,
+        }
+    }
+
+    /// Waits until at least one of `sources` is readable and returns the
+    /// indices of all that are. Returns an empty list if none became
+    /// readable within `timeout`, never if it's `None`. A zero `timeout`
+    /// only checks without waiting.
+    pub fn select(&self, sources: &[&Selectable], timeout: Option<Duration>) -> Vec<usize> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // Cleared before polling, so a wake-up in between is not missed
+            *self.signal.woken.lock().unwrap() = false;
+            let ready = sources.iter()
+                .enumerate()
+                .filter(|&(_, source)| source.poll_readable(&self.waker).is_ready())
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            if !ready.is_empty() {
+                return ready;
+            }
+            let mut woken = self.signal.woken.lock().unwrap();
+            while !*woken {
+                match deadline {
+                    None => woken = self.signal.cond.wait(woken).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Vec::new();
+                        }
+                        woken = self.signal.cond.wait_timeout(woken, deadline - now).unwrap().0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Selector {
+    fn default() -> Selector {
+        Selector::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reactor::{Async, Waker, Wakers};
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Flag {
+        ready: AtomicBool,
+        wakers: Wakers,
+    }
+
+    impl Flag {
+        fn set(&self) {
+            self.ready.store(true, Ordering::SeqCst);
+            self.wakers.wake_all();
+        }
+    }
+
+    impl Selectable for Flag {
+        fn poll_readable(&self, waker: &Waker) -> Async<()> {
+            self.wakers.register_unique(waker.clone());
+            if self.ready.load(Ordering::SeqCst) {
+                Async::Ready(())
+            } else {
+                Async::NotReady
+            }
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let selector = Selector::new();
+        let flag = Flag::default();
+        assert!(selector.select(&[&flag], Some(Duration::from_millis(10))).is_empty());
+        assert!(selector.select(&[&flag], Some(Duration::from_secs(0))).is_empty());
+    }
+
+    #[test]
+    fn woken() {
+        let selector = Selector::new();
+        let flags = Arc::new((Flag::default(), Flag::default()));
+        let thread_flags = flags.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            thread_flags.1.set();
+        });
+        assert_eq!(selector.select(&[&flags.0, &flags.1], None), vec![1]);
+    }
+}
@@ -0,0 +1,151 @@
+//! Egress traffic shaping with token buckets. A `RateLimit` set on an
+//! interface with `StackInterface::set_rate_limit`, or on a socket with
+//! `UdpSocket::set_rate_limit`, paces what is sent through it: sending
+//! blocks for as long as needed to keep to the rate.
+//!
+//! Interfaces count the bytes of whole ethernet frames, sockets the bytes of
+//! the IPv4 packets they send.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A sustained rate and how much can be sent above it in one burst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Bytes per second
+    pub rate: u64,
+    /// Bytes that can be sent at once after being idle
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// # Panics
+    ///
+    /// Panics if `rate` is zero.
+    pub fn new(rate: u64, burst: u64) -> RateLimit {
+        assert!(rate > 0, "Rate must not be zero");
+        RateLimit {
+            rate: rate,
+            burst: burst,
+        }
+    }
+}
+
+/// The state of a `RateLimit`. A packet may be sent as soon as the bucket
+/// is not in debt, even if it has fewer tokens than the packet needs. The
+/// difference is paid off before the next packet.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    /// Bytes that can be sent right now, negative while in debt
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: RateLimit) -> TokenBucket {
+        TokenBucket {
+            limit: limit,
+            tokens: limit.burst as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Takes the tokens for sending `bytes` and returns how long to wait
+    /// before sending them.
+    pub fn take(&mut self, bytes: usize) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now - self.updated;
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64)
+            .min(self.limit.burst as f64);
+        // Waiting for a debt from earlier packets
+        let wait = if self.tokens < 0.0 {
+            -self.tokens / self.limit.rate as f64
+        } else {
+            0.0
+        };
+        self.tokens -= bytes as f64;
+        Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+    }
+}
+
+/// An optional `TokenBucket` shared by everything sending through an
+/// interface or socket.
+#[derive(Debug, Default)]
+pub struct Shaper {
+    bucket: Mutex<Option<TokenBucket>>,
+}
+
+impl Shaper {
+    pub fn limit(&self) -> Option<RateLimit> {
+        self.bucket.lock().unwrap().as_ref().map(|bucket| bucket.limit())
+    }
+
+    /// Sets the limit, starting with a full bucket, or removes it if `limit`
+    /// is `None`.
+    pub fn set_limit(&self, limit: Option<RateLimit>) {
+        *self.bucket.lock().unwrap() = limit.map(TokenBucket::new);
+    }
+
+    /// Blocks until `bytes` may be sent.
+    pub fn wait(&self, bytes: usize) {
+        let wait = match *self.bucket.lock().unwrap() {
+            Some(ref mut bucket) => bucket.take(bytes),
+            None => return,
+        };
+        if wait > Duration::new(0, 0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn burst_then_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(1000, 1500));
+        bucket.updated = start;
+        assert_eq!(bucket.take_at(1000, start), Duration::new(0, 0));
+        assert_eq!(bucket.take_at(1000, start), Duration::new(0, 0));
+        // 500 bytes in debt, paid off after half a second
+        assert_eq!(bucket.take_at(1000, start), Duration::from_millis(500));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(100, later), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn refill_limited_by_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(1000, 100));
+        bucket.updated = start;
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take_at(100, later), Duration::new(0, 0));
+        assert_eq!(bucket.take_at(100, later), Duration::new(0, 0));
+        assert_eq!(bucket.take_at(100, later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn shaper_without_limit() {
+        let shaper = Shaper::default();
+        assert_eq!(shaper.limit(), None);
+        shaper.wait(1 << 30);
+        shaper.set_limit(Some(RateLimit::new(10, 10)));
+        assert_eq!(shaper.limit(), Some(RateLimit::new(10, 10)));
+    }
+}
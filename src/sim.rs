@@ -0,0 +1,404 @@
+//! Running several stacks against each other in virtual time.
+//!
+//! A `Simulation` owns polled stacks, see `NetworkStack::new_polled`, that
+//! share one `MockClock`, and wires connecting their interfaces. Frames sent
+//! on a wire arrive at the other end once the clock passed their latency,
+//! and everything the stacks do in response is handled in the thread running
+//! the simulation, in a fixed order. The rx threads of the interfaces only
+//! hand the frames over, so the outcome depends on the script alone and not
+//! on the scheduling of threads:
+//!
+//! ```rust,ignore
+//! let mut sim = Simulation::new();
+//! let (a, b) = (sim.add_stack(), sim.add_stack());
+//! let (wire, a_interface, b_interface) = sim.connect(a, b, Duration::from_millis(5));
+//! sim.stack(a).add_ipv4(&a_interface, "10.0.0.1/24".parse().unwrap())?;
+//! sim.stack(b).add_ipv4(&b_interface, "10.0.0.2/24".parse().unwrap())?;
+//!
+//! let resolved = sim.stack(a).resolve_in_vrf(None, Ipv4Addr::new(10, 0, 0, 2))?.unwrap();
+//! sim.schedule(Duration::from_millis(50), move |sim| sim.set_connected(wire, false));
+//! sim.advance(Duration::from_secs(1));
+//! assert!(resolved.try_recv().is_ok());
+//! ```
+//!
+//! Time only moves in `advance`, from one frame or scheduled action to the
+//! next and at least every `timer::TICK_MS`, so the timers of the stacks
+//! fire when they would in real time. Stacks in a simulation should have no
+//! interfaces but the ones of its wires, frames from anywhere else arrive
+//! whenever they do. Every stack draws its random numbers from a generator
+//! seeded with its `StackId`, see `NetworkStack::set_rng`.
+
+use {Interface, NetworkStack};
+use clock::{Clock, MockClock};
+use datalink::Datalink;
+use rx;
+use timer;
+
+use pnet::util::MacAddr;
+
+use rand::{SeedableRng, XorShiftRng};
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the rx thread of a stack to hand over the frames
+/// delivered to it, before taking it for stuck, in seconds.
+static HANDOVER_TIMEOUT_SECS: u64 = 5;
+
+/// Identifies a stack of a `Simulation`, in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StackId(pub usize);
+
+/// Identifies a wire of a `Simulation`, in the order they were connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WireId(pub usize);
+
+/// The frames that went over a wire, in both directions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WireStats {
+    /// Frames sent on either end
+    pub sent: usize,
+    /// Frames sent while the wire was disconnected
+    pub dropped: usize,
+    /// Frames that arrived at the other end
+    pub delivered: usize,
+}
+
+struct InFlight {
+    due: Instant,
+    /// The end the frame goes to, 0 or 1
+    to: usize,
+    frame: Box<[u8]>,
+}
+
+struct WireState {
+    latency: Duration,
+    connected: bool,
+    in_flight: VecDeque<InFlight>,
+    stats: WireStats,
+}
+
+struct Wire {
+    state: Arc<Mutex<WireState>>,
+    /// The stack behind each end, and where to deliver its frames
+    ends: [(usize, Sender<Box<[u8]>>); 2],
+}
+
+/// One end of a wire, the datalink of an interface of a simulated stack.
+struct WireEnd {
+    mac: MacAddr,
+    clock: Arc<MockClock>,
+    wire: Arc<Mutex<WireState>>,
+    /// The index of this end in the wire, 0 or 1
+    side: usize,
+    inbox: Mutex<Receiver<Box<[u8]>>>,
+}
+
+impl Datalink for WireEnd {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let mut wire = self.wire.lock().unwrap();
+        wire.stats.sent += 1;
+        if !wire.connected {
+            wire.stats.dropped += 1;
+            return Ok(());
+        }
+        let due = self.clock.now() + wire.latency;
+        wire.in_flight.push_back(InFlight {
+            due: due,
+            to: 1 - self.side,
+            frame: frame.to_vec().into_boxed_slice(),
+        });
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let timeout = Duration::from_millis(rx::POLL_INTERVAL_MS);
+        match self.inbox.lock().unwrap().recv_timeout(timeout) {
+            Ok(frame) => Ok(frame),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "No frame delivered"))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "Simulation dropped"))
+            }
+        }
+    }
+}
+
+struct SimStack {
+    stack: NetworkStack,
+    /// Frames delivered to the interfaces of the stack, and handled by it
+    delivered: usize,
+    handled: usize,
+    interfaces: usize,
+}
+
+/// Something to do at a point in virtual time, see `Simulation::schedule`.
+struct Action {
+    at: Instant,
+    /// Keeps actions scheduled for the same time in order
+    seq: u64,
+    action: Box<FnMut(&mut Simulation)>,
+}
+
+/// Stacks connected by wires, run in virtual time. See the module
+/// documentation.
+pub struct Simulation {
+    clock: Arc<MockClock>,
+    start: Instant,
+    stacks: Vec<SimStack>,
+    wires: Vec<Wire>,
+    actions: Vec<Action>,
+    next_seq: u64,
+}
+
+impl Simulation {
+    pub fn new() -> Simulation {
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        Simulation {
+            clock: clock,
+            start: start,
+            stacks: Vec::new(),
+            wires: Vec::new(),
+            actions: Vec::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Adds a polled stack without any interfaces.
+    pub fn add_stack(&mut self) -> StackId {
+        let id = self.stacks.len();
+        let mut stack = NetworkStack::new_polled();
+        stack.set_clock(self.clock.clone()).unwrap();
+        let seed = id as u32 + 1;
+        stack.set_rng(XorShiftRng::from_seed([seed, 0x9e37_79b9, 0x7f4a_7c15, 0xf39c_c060]));
+        self.stacks.push(SimStack {
+            stack: stack,
+            delivered: 0,
+            handled: 0,
+            interfaces: 0,
+        });
+        StackId(id)
+    }
+
+    /// Returns the stack `id`, for setting it up and using it between runs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the simulation has no stack `id`.
+    pub fn stack(&mut self, id: StackId) -> &mut NetworkStack {
+        &mut self.stacks[id.0].stack
+    }
+
+    /// Connects the stacks `a` and `b` with a wire delaying the frames by
+    /// `latency` each way. Adds an interface to each of them, named `sim0`,
+    /// `sim1` and so on per stack, and returns them with the wire.
+    pub fn connect(&mut self,
+                   a: StackId,
+                   b: StackId,
+                   latency: Duration)
+                   -> (WireId, Interface, Interface) {
+        let wire = Arc::new(Mutex::new(WireState {
+            latency: latency,
+            connected: true,
+            in_flight: VecDeque::new(),
+            stats: WireStats::default(),
+        }));
+        let (a_interface, a_inbox) = self.add_end(a, wire.clone(), 0);
+        let (b_interface, b_inbox) = self.add_end(b, wire.clone(), 1);
+        self.wires.push(Wire {
+            state: wire,
+            ends: [(a.0, a_inbox), (b.0, b_inbox)],
+        });
+        (WireId(self.wires.len() - 1), a_interface, b_interface)
+    }
+
+    fn add_end(&mut self,
+               id: StackId,
+               wire: Arc<Mutex<WireState>>,
+               side: usize)
+               -> (Interface, Sender<Box<[u8]>>) {
+        let (inbox_tx, inbox) = mpsc::channel();
+        let sim_stack = &mut self.stacks[id.0];
+        let end = WireEnd {
+            mac: MacAddr::new(2, 0, 0, 0x51, id.0 as u8, sim_stack.interfaces as u8),
+            clock: self.clock.clone(),
+            wire: wire,
+            side: side,
+            inbox: Mutex::new(inbox),
+        };
+        let name = format!("sim{}", sim_stack.interfaces);
+        let interface = sim_stack.stack
+            .add_datalink(&name, Arc::new(end))
+            .expect("Not able to add a wire to a simulated stack");
+        sim_stack.interfaces += 1;
+        (interface, inbox_tx)
+    }
+
+    /// Sets the latency of the frames sent on `wire` from now on.
+    pub fn set_latency(&self, wire: WireId, latency: Duration) {
+        let mut state = self.wires[wire.0].state.lock().unwrap();
+        state.latency = latency;
+    }
+
+    /// Connects or disconnects `wire`. Frames sent on a disconnected wire
+    /// are lost, frames on their way already still arrive.
+    pub fn set_connected(&self, wire: WireId, connected: bool) {
+        let mut state = self.wires[wire.0].state.lock().unwrap();
+        state.connected = connected;
+    }
+
+    pub fn wire_stats(&self, wire: WireId) -> WireStats {
+        self.wires[wire.0].state.lock().unwrap().stats
+    }
+
+    pub fn clock(&self) -> Arc<MockClock> {
+        self.clock.clone()
+    }
+
+    /// Returns the current virtual time.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the virtual time passed since the simulation was created.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now() - self.start
+    }
+
+    /// Calls `action` with the simulation once `after` passed in virtual
+    /// time, between the frames due then.
+    pub fn schedule<F>(&mut self, after: Duration, action: F)
+        where F: FnOnce(&mut Simulation) + 'static
+    {
+        let mut action = Some(action);
+        self.actions.push(Action {
+            at: self.clock.now() + after,
+            seq: self.next_seq,
+            action: Box::new(move |sim: &mut Simulation| {
+                if let Some(action) = action.take() {
+                    action(sim)
+                }
+            }),
+        });
+        self.next_seq += 1;
+    }
+
+    /// Moves the clock `duration` forward, handling the frames and actions
+    /// as they become due.
+    pub fn advance(&mut self, duration: Duration) {
+        let end = self.clock.now() + duration;
+        let tick = Duration::from_millis(timer::TICK_MS);
+        loop {
+            self.run_until_idle();
+            let now = self.clock.now();
+            if now >= end {
+                return;
+            }
+            let mut next = cmp::min(end, now + tick);
+            if let Some(due) = self.next_due() {
+                next = cmp::min(next, due);
+            }
+            self.clock.advance(next - now);
+        }
+    }
+
+    /// Handles everything due at the current virtual time, until the stacks
+    /// have nothing left to do without time passing. Returns the number of
+    /// frames delivered.
+    pub fn run_until_idle(&mut self) -> usize {
+        let mut total = 0;
+        loop {
+            self.poll_stacks();
+            let delivered = self.deliver_due();
+            let ran = self.run_due_action();
+            total += delivered;
+            if delivered == 0 && !ran {
+                return total;
+            }
+        }
+    }
+
+    /// Polls every stack until it handled all frames delivered to it.
+    fn poll_stacks(&mut self) {
+        let poll_interval = Duration::from_millis(rx::POLL_INTERVAL_MS);
+        for (id, sim_stack) in self.stacks.iter_mut().enumerate() {
+            sim_stack.handled += sim_stack.stack.poll();
+            // Real time, the rx thread hands the frames over in it
+            let deadline = Instant::now() + Duration::from_secs(HANDOVER_TIMEOUT_SECS);
+            while sim_stack.handled < sim_stack.delivered {
+                assert!(Instant::now() < deadline,
+                        "Simulated stack {} did not receive its frames",
+                        id);
+                sim_stack.handled += sim_stack.stack.poll_timeout(poll_interval);
+            }
+        }
+    }
+
+    /// Hands the frames due on all wires to the rx threads of their ends.
+    fn deliver_due(&mut self) -> usize {
+        let now = self.clock.now();
+        let mut count = 0;
+        for wire in &self.wires {
+            let mut state = wire.state.lock().unwrap();
+            let mut waiting = VecDeque::with_capacity(state.in_flight.len());
+            while let Some(in_flight) = state.in_flight.pop_front() {
+                if in_flight.due > now {
+                    waiting.push_back(in_flight);
+                    continue;
+                }
+                let (stack, ref inbox) = wire.ends[in_flight.to];
+                // The rx thread only stops once the simulation is dropped
+                inbox.send(in_flight.frame).unwrap();
+                self.stacks[stack].delivered += 1;
+                state.stats.delivered += 1;
+                count += 1;
+            }
+            state.in_flight = waiting;
+        }
+        count
+    }
+
+    /// Runs the action due first, if any is due. Returns true if one was.
+    fn run_due_action(&mut self) -> bool {
+        let now = self.clock.now();
+        let index = self.actions
+            .iter()
+            .enumerate()
+            .filter(|&(_, action)| action.at <= now)
+            .min_by_key(|&(_, action)| (action.at, action.seq))
+            .map(|(index, _)| index);
+        match index {
+            Some(index) => {
+                let mut action = self.actions.remove(index);
+                (action.action)(self);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns when the next frame or action is due, if any is waiting.
+    fn next_due(&self) -> Option<Instant> {
+        let frames = self.wires.iter().filter_map(|wire| {
+            wire.state.lock().unwrap().in_flight.iter().map(|in_flight| in_flight.due).min()
+        });
+        let actions = self.actions.iter().map(|action| action.at);
+        frames.chain(actions).min()
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Simulation {
+        Simulation::new()
+    }
+}
@@ -0,0 +1,354 @@
+//! A point in time view of the state of a stack, for diagnostics.
+//!
+//! `NetworkStack::snapshot` copies out the interfaces with their addresses,
+//! Arp caches, listeners and counters, and the routing tables, into plain
+//! values that no longer refer to the stack. Tools printing them like `ip`
+//! and `ss` do read the fields, and `StackSnapshot::to_json` serializes all
+//! of it for handing to other programs:
+//!
+//! ```rust,ignore
+//! let snapshot = stack.lock().unwrap().snapshot();
+//! for interface in &snapshot.interfaces {
+//!     println!("{}: mtu {} {:?}", interface.name, interface.mtu, interface.addresses);
+//! }
+//! println!("{}", snapshot.to_json());
+//! ```
+//!
+//! Sockets show up as the listeners they bind on their addresses.
+
+use ipv4::ReassemblyStats;
+use malformed::ParseErrors;
+use qos::QueueStats;
+use routing::RouteEntry;
+use stats::{LayerStats, StackStats};
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::icmp::IcmpType;
+use pnet::util::MacAddr;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackSnapshot {
+    /// Sorted by name
+    pub interfaces: Vec<InterfaceSnapshot>,
+    /// The main routing table
+    pub routes: Vec<RouteEntry>,
+    /// Sorted by name
+    pub vrfs: Vec<VrfSnapshot>,
+    pub forwarding: bool,
+    /// Connections tracked by the firewall, see `conntrack`
+    pub connections: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterfaceSnapshot {
+    pub name: String,
+    pub mac: MacAddr,
+    pub mtu: usize,
+    pub carrier: bool,
+    pub vrf: Option<String>,
+    pub promiscuous: bool,
+    pub nonblocking: bool,
+    pub addresses: Vec<Ipv4Network>,
+    /// The Arp cache, sorted by address
+    pub arp: Vec<(Ipv4Addr, MacAddr)>,
+    /// Addresses an Arp reply is waited for, sorted
+    pub arp_pending: Vec<Ipv4Addr>,
+    pub listeners: Vec<ListenerSnapshot>,
+    pub reassembly: ReassemblyStats,
+    /// Malformed packets received, see `malformed`
+    pub parse_errors: ParseErrors,
+    /// Packets sent and received by protocol, see `stats`
+    pub stats: StackStats,
+    /// The counters of the egress queues, if the interface has any, see
+    /// `qos`
+    pub queues: Option<Vec<QueueStats>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VrfSnapshot {
+    pub name: String,
+    pub routes: Vec<RouteEntry>,
+}
+
+/// Something receiving packets sent to one of the addresses of a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerSnapshot {
+    /// A UDP socket or other UDP listener
+    Udp(SocketAddrV4),
+    /// Listeners for one ICMP type, and how many there are
+    Icmp(Ipv4Addr, IcmpType, usize),
+}
+
+impl StackSnapshot {
+    /// Serializes the snapshot to a JSON object, with the same structure and
+    /// field names as the types here. Addresses and networks are strings.
+    pub fn to_json(&self) -> String {
+        let mut json = Json::default();
+        json.object(|json| {
+            json.field("interfaces");
+            json.array(&self.interfaces, |json, interface| interface.write_json(json));
+            json.field("routes");
+            json.array(&self.routes, write_route);
+            json.field("vrfs");
+            json.array(&self.vrfs, |json, vrf| {
+                json.object(|json| {
+                    json.field("name");
+                    json.string(&vrf.name);
+                    json.field("routes");
+                    json.array(&vrf.routes, write_route);
+                })
+            });
+            json.field("forwarding");
+            json.raw(&self.forwarding.to_string());
+            json.field("connections");
+            json.raw(&self.connections.to_string());
+        });
+        json.0
+    }
+}
+
+impl InterfaceSnapshot {
+    fn write_json(&self, json: &mut Json) {
+        json.object(|json| {
+            json.field("name");
+            json.string(&self.name);
+            json.field("mac");
+            json.string(&self.mac.to_string());
+            json.field("mtu");
+            json.raw(&self.mtu.to_string());
+            json.field("carrier");
+            json.raw(&self.carrier.to_string());
+            json.field("vrf");
+            json.optional(self.vrf.as_ref());
+            json.field("promiscuous");
+            json.raw(&self.promiscuous.to_string());
+            json.field("nonblocking");
+            json.raw(&self.nonblocking.to_string());
+            json.field("addresses");
+            json.array(&self.addresses, |json, net| json.string(&net.to_string()));
+            json.field("arp");
+            json.array(&self.arp, |json, &(ip, mac)| {
+                json.object(|json| {
+                    json.field("ip");
+                    json.string(&ip.to_string());
+                    json.field("mac");
+                    json.string(&mac.to_string());
+                })
+            });
+            json.field("arp_pending");
+            json.array(&self.arp_pending, |json, ip| json.string(&ip.to_string()));
+            json.field("listeners");
+            json.array(&self.listeners, write_listener);
+            json.field("reassembly");
+            json.object(|json| {
+                json.field("overlapping");
+                json.raw(&self.reassembly.overlapping.to_string());
+                json.field("over_limit");
+                json.raw(&self.reassembly.over_limit.to_string());
+                json.field("timed_out");
+                json.raw(&self.reassembly.timed_out.to_string());
+                json.field("invalid");
+                json.raw(&self.reassembly.invalid.to_string());
+            });
+            json.field("parse_errors");
+            json.object(|json| {
+                json.field("ethernet");
+                json.raw(&self.parse_errors.ethernet.to_string());
+                json.field("arp");
+                json.raw(&self.parse_errors.arp.to_string());
+                json.field("ipv4");
+                json.raw(&self.parse_errors.ipv4.to_string());
+                json.field("udp");
+                json.raw(&self.parse_errors.udp.to_string());
+                json.field("icmp");
+                json.raw(&self.parse_errors.icmp.to_string());
+                json.field("checksums");
+                json.raw(&self.parse_errors.checksums.to_string());
+            });
+            json.field("stats");
+            json.object(|json| {
+                for &(name, stats) in &[("ethernet", &self.stats.ethernet),
+                                        ("arp", &self.stats.arp),
+                                        ("ipv4", &self.stats.ipv4),
+                                        ("icmp", &self.stats.icmp),
+                                        ("udp", &self.stats.udp)] {
+                    json.field(name);
+                    write_layer_stats(json, stats);
+                }
+                json.field("arp_cache_hits");
+                json.raw(&self.stats.arp_cache_hits.to_string());
+                json.field("arp_cache_misses");
+                json.raw(&self.stats.arp_cache_misses.to_string());
+            });
+            json.field("queues");
+            match self.queues {
+                Some(ref queues) => {
+                    json.array(queues, |json, stats| {
+                        json.object(|json| {
+                            json.field("queued");
+                            json.raw(&stats.queued.to_string());
+                            json.field("sent");
+                            json.raw(&stats.sent.to_string());
+                            json.field("dropped");
+                            json.raw(&stats.dropped.to_string());
+                        })
+                    })
+                }
+                None => json.raw("null"),
+            }
+        })
+    }
+}
+
+fn write_layer_stats(json: &mut Json, stats: &LayerStats) {
+    json.object(|json| {
+        json.field("rx_packets");
+        json.raw(&stats.rx_packets.to_string());
+        json.field("rx_bytes");
+        json.raw(&stats.rx_bytes.to_string());
+        json.field("rx_dropped");
+        json.raw(&stats.rx_dropped.to_string());
+        json.field("rx_errors");
+        json.raw(&stats.rx_errors.to_string());
+        json.field("tx_packets");
+        json.raw(&stats.tx_packets.to_string());
+        json.field("tx_bytes");
+        json.raw(&stats.tx_bytes.to_string());
+        json.field("tx_errors");
+        json.raw(&stats.tx_errors.to_string());
+    })
+}
+
+fn write_route(json: &mut Json, route: &RouteEntry) {
+    json.object(|json| {
+        json.field("net");
+        json.string(&route.net.to_string());
+        json.field("gw");
+        json.optional(route.gw.as_ref());
+        json.field("interface");
+        json.string(&route.interface.name);
+        json.field("metric");
+        json.raw(&route.metric.to_string());
+        json.field("mtu");
+        match route.mtu {
+            Some(mtu) => json.raw(&mtu.to_string()),
+            None => json.raw("null"),
+        }
+        json.field("src");
+        json.optional(route.src.as_ref());
+        json.field("origin");
+        json.string(&format!("{:?}", route.origin));
+    })
+}
+
+fn write_listener(json: &mut Json, listener: &ListenerSnapshot) {
+    json.object(|json| match *listener {
+        ListenerSnapshot::Udp(addr) => {
+            json.field("protocol");
+            json.string("udp");
+            json.field("addr");
+            json.string(&addr.to_string());
+        }
+        ListenerSnapshot::Icmp(ip, icmp_type, count) => {
+            json.field("protocol");
+            json.string("icmp");
+            json.field("ip");
+            json.string(&ip.to_string());
+            json.field("type");
+            json.raw(&icmp_type.0.to_string());
+            json.field("count");
+            json.raw(&count.to_string());
+        }
+    })
+}
+
+/// Writes JSON, keeping track of where commas go.
+#[derive(Default)]
+struct Json(String, bool);
+
+impl Json {
+    fn separate(&mut self) {
+        if self.1 {
+            self.0.push(',');
+        }
+        self.1 = true;
+    }
+
+    fn raw(&mut self, value: &str) {
+        self.0.push_str(value);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.0.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                c if (c as u32) < 0x20 => self.0.push_str(&format!("\\u{:04x}", c as u32)),
+                c => self.0.push(c),
+            }
+        }
+        self.0.push('"');
+    }
+
+    fn optional<T: ToString>(&mut self, value: Option<&T>) {
+        match value {
+            Some(value) => self.string(&value.to_string()),
+            None => self.raw("null"),
+        }
+    }
+
+    /// Starts a field of the object being written, its value comes next.
+    fn field(&mut self, name: &str) {
+        self.separate();
+        self.string(name);
+        self.0.push(':');
+    }
+
+    fn object<F: FnOnce(&mut Json)>(&mut self, fields: F) {
+        self.0.push('{');
+        self.1 = false;
+        fields(self);
+        self.0.push('}');
+        self.1 = true;
+    }
+
+    fn array<T, F: FnMut(&mut Json, &T)>(&mut self, items: &[T], mut item: F) {
+        self.0.push('[');
+        self.1 = false;
+        for value in items {
+            self.separate();
+            item(self, value);
+        }
+        self.0.push(']');
+        self.1 = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escaping() {
+        let mut json = Json::default();
+        json.array(&["a\"b\\c\n", "d"], |json, s| json.string(s));
+        assert_eq!(json.0, r#"["a\"b\\c\u000a","d"]"#);
+    }
+
+    #[test]
+    fn empty_stack() {
+        let snapshot = StackSnapshot {
+            interfaces: Vec::new(),
+            routes: Vec::new(),
+            vrfs: Vec::new(),
+            forwarding: false,
+            connections: 0,
+        };
+        assert_eq!(snapshot.to_json(),
+                   r#"{"interfaces":[],"routes":[],"vrfs":[],"forwarding":false,"connections":0}"#);
+    }
+}
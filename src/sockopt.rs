@@ -0,0 +1,255 @@
+//! Options of sockets.
+//!
+//! Every socket type of the stack is configured the same way, instead of
+//! with setters of its own. An option is a type implementing `SocketOpt`,
+//! given to `HasSocketOptions::set_opt` with its value, or to
+//! `HasSocketOptions::opt` to read it back:
+//!
+//! ```rust,ignore
+//! use rips::sockopt::{HasSocketOptions, ReadTimeout, Ttl};
+//!
+//! socket.set_opt(Ttl, 64)?;
+//! socket.set_opt(ReadTimeout, Some(Duration::from_secs(1)))?;
+//! assert_eq!(socket.opt(Ttl), 64);
+//! ```
+//!
+//! The options of a socket are shared with its clones. Joining multicast
+//! groups is not among them, the groups an interface receives are joined
+//! with `StackInterface::join_multicast`, for all sockets.
+
+use ipv4;
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Time to live of packets sent to multicast groups, unless set otherwise.
+/// Keeps them on the local network like on most hosts.
+pub static DEFAULT_MULTICAST_TTL: u8 = 1;
+
+/// The values of all options of one socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Time to live of sent packets, other than to multicast groups
+    pub ttl: u8,
+    /// Type of service byte of sent packets, the DSCP and ECN bits
+    pub tos: u8,
+    pub multicast_ttl: u8,
+    /// How long receiving waits for a datagram, forever if `None`
+    pub read_timeout: Option<Duration>,
+    /// Bytes of received packets, headers included, held until they are
+    /// read. Packets arriving beyond it are dropped. Unlimited if `None`.
+    pub recv_buffer: Option<usize>,
+    /// If sending to broadcast addresses is allowed
+    pub broadcast: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            ttl: ipv4::DEFAULT_TTL,
+            tos: 0,
+            multicast_ttl: DEFAULT_MULTICAST_TTL,
+            read_timeout: None,
+            recv_buffer: None,
+            broadcast: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Returns the time to live of packets sent to `dst`.
+    pub fn ttl_to(&self, dst: Ipv4Addr) -> u8 {
+        if dst.is_multicast() {
+            self.multicast_ttl
+        } else {
+            self.ttl
+        }
+    }
+}
+
+/// An option of a socket, with values of type `Value`.
+pub trait SocketOpt: Copy {
+    type Value;
+
+    fn get(&self, options: &SocketOptions) -> Self::Value;
+
+    /// Fails with `io::ErrorKind::InvalidInput` if `value` is not valid for
+    /// this option, leaving `options` as they were.
+    fn set(&self, options: &mut SocketOptions, value: Self::Value) -> io::Result<()>;
+}
+
+/// Implemented by every socket, giving access to its options.
+pub trait HasSocketOptions {
+    fn socket_options(&self) -> &RwLock<SocketOptions>;
+
+    fn set_opt<O: SocketOpt>(&self, opt: O, value: O::Value) -> io::Result<()> {
+        opt.set(&mut self.socket_options().write().unwrap(), value)
+    }
+
+    fn opt<O: SocketOpt>(&self, opt: O) -> O::Value {
+        opt.get(&self.socket_options().read().unwrap())
+    }
+}
+
+fn invalid(msg: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned()))
+}
+
+/// Time to live of sent packets, other than to multicast groups. At least
+/// one.
+#[derive(Debug, Clone, Copy)]
+pub struct Ttl;
+
+impl SocketOpt for Ttl {
+    type Value = u8;
+
+    fn get(&self, options: &SocketOptions) -> u8 {
+        options.ttl
+    }
+
+    fn set(&self, options: &mut SocketOptions, value: u8) -> io::Result<()> {
+        if value == 0 {
+            return invalid("Time to live must be at least one");
+        }
+        options.ttl = value;
+        Ok(())
+    }
+}
+
+/// Type of service byte of sent packets, the DSCP in the upper six bits and
+/// the ECN in the lower two.
+#[derive(Debug, Clone, Copy)]
+pub struct Tos;
+
+impl SocketOpt for Tos {
+    type Value = u8;
+
+    fn get(&self, options: &SocketOptions) -> u8 {
+        options.tos
+    }
+
+    fn set(&self, options: &mut SocketOptions, value: u8) -> io::Result<()> {
+        options.tos = value;
+        Ok(())
+    }
+}
+
+/// Time to live of packets sent to multicast groups. Zero keeps them on
+/// this host.
+#[derive(Debug, Clone, Copy)]
+pub struct MulticastTtl;
+
+impl SocketOpt for MulticastTtl {
+    type Value = u8;
+
+    fn get(&self, options: &SocketOptions) -> u8 {
+        options.multicast_ttl
+    }
+
+    fn set(&self, options: &mut SocketOptions, value: u8) -> io::Result<()> {
+        options.multicast_ttl = value;
+        Ok(())
+    }
+}
+
+/// How long receiving waits for a datagram before failing with
+/// `io::ErrorKind::TimedOut`, forever if `None`. A zero timeout is invalid,
+/// like for the sockets of the standard library.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadTimeout;
+
+impl SocketOpt for ReadTimeout {
+    type Value = Option<Duration>;
+
+    fn get(&self, options: &SocketOptions) -> Option<Duration> {
+        options.read_timeout
+    }
+
+    fn set(&self, options: &mut SocketOptions, value: Option<Duration>) -> io::Result<()> {
+        if value == Some(Duration::from_secs(0)) {
+            return invalid("Zero read timeout");
+        }
+        options.read_timeout = value;
+        Ok(())
+    }
+}
+
+/// Bytes of received packets held until they are read, unlimited if
+/// `None`. Must fit at least one packet if set.
+#[derive(Debug, Clone, Copy)]
+pub struct RecvBuffer;
+
+impl SocketOpt for RecvBuffer {
+    type Value = Option<usize>;
+
+    fn get(&self, options: &SocketOptions) -> Option<usize> {
+        options.recv_buffer
+    }
+
+    fn set(&self, options: &mut SocketOptions, value: Option<usize>) -> io::Result<()> {
+        if value.map_or(false, |size| size < ipv4::MIN_MTU) {
+            return invalid("Receive buffer smaller than the minimum MTU");
+        }
+        options.recv_buffer = value;
+        Ok(())
+    }
+}
+
+/// If sending to broadcast addresses is allowed. Sending to them fails with
+/// `io::ErrorKind::PermissionDenied` otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Broadcast;
+
+impl SocketOpt for Broadcast {
+    type Value = bool;
+
+    fn get(&self, options: &SocketOptions) -> bool {
+        options.broadcast
+    }
+
+    fn set(&self, options: &mut SocketOptions, value: bool) -> io::Result<()> {
+        options.broadcast = value;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::RwLock;
+    use std::time::Duration;
+
+    use super::*;
+
+    struct Socket(RwLock<SocketOptions>);
+
+    impl HasSocketOptions for Socket {
+        fn socket_options(&self) -> &RwLock<SocketOptions> {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn set_and_get() {
+        let socket = Socket(RwLock::new(SocketOptions::default()));
+        socket.set_opt(Ttl, 64).unwrap();
+        socket.set_opt(Broadcast, true).unwrap();
+        assert_eq!(socket.opt(Ttl), 64);
+        assert!(socket.opt(Broadcast));
+        assert_eq!(socket.opt(MulticastTtl), DEFAULT_MULTICAST_TTL);
+        assert_eq!(socket.0.read().unwrap().ttl_to("224.0.0.9".parse().unwrap()), 1);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let socket = Socket(RwLock::new(SocketOptions::default()));
+        let kind = |result: io::Result<()>| result.unwrap_err().kind();
+        assert_eq!(kind(socket.set_opt(Ttl, 0)), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(socket.set_opt(ReadTimeout, Some(Duration::from_secs(0)))),
+                   io::ErrorKind::InvalidInput);
+        assert_eq!(kind(socket.set_opt(RecvBuffer, Some(10))), io::ErrorKind::InvalidInput);
+        assert_eq!(*socket.0.read().unwrap(), SocketOptions::default());
+    }
+}
@@ -1,59 +1,234 @@
-use ::{EthernetChannel, Interface, RoutingTable, TxError, TxResult, Tx, Payload};
-use StackError;
-use ::arp::{self, ArpRequestTx, ArpReplyTx, ArpTable};
-use ::ethernet::{EthernetRx, EthernetTxImpl};
+use ::{EthernetChannel, Interface, RouteEntry, RouteOrigin, RoutingTable, RxResult, TxError,
+       TxResult, TxSent, Tx, Payload};
+use {BasicPayload, RxError, StackError, ThreadError};
+use ::arp::{self, ArpReplyTx, ArpRequestTx, ArpRx, ArpTable};
+use bpf;
+use checksum::{self, RxChecksumControl, RxChecksums};
+use clock::{self, Clock};
+use eapol::{self, PortAccess, Supplicant};
+use fanout::{FanOut, FanOutStats};
+use ::ethernet::{BasicEthernetPayload, Chain, EthernetListener, EthernetListenerLookup,
+                 EthernetRx, EthernetTx, EthernetTxImpl, StaticEthernetRx};
+use ::ethernet::llc::{self, LlcKey, LlcListener, LlcListenerLookup, LlcRx};
 use ::icmp::{self, IcmpTx};
 
 use ipnetwork::Ipv4Network;
 use ::ipv4::{self, Ipv4TxImpl};
-
-use pnet::datalink::EthernetDataLinkSender;
-use pnet::packet::MutablePacket;
-use pnet::packet::ethernet::MutableEthernetPacket;
+use macvlan::{self, MacvlanSender, MacvlanTable};
+use malformed::{Guarded, Layer, Malformed, MalformedPolicy, ParseErrors};
+use conntrack;
+use datalink::Datalink;
+use dns::{Resolver, StackAddr, ToStackAddr};
+use dump::{Dump, DumpConfig};
+use firewall::{self, Firewall, Hook};
+use host;
+use nat;
+use observe::{self, Observer, ObserverId, Observers};
+use pcap::{Capture, CaptureConfig, CapturePoint, CaptureStats};
+use pool::{BufferPool, PooledBuffer};
+use qos::{EgressQueues, QosConfig, QueueStats};
+use reactor::{Async, Waker, Writability};
+use routing;
+use shaping::{RateLimit, Shaper};
+use snapshot::{InterfaceSnapshot, ListenerSnapshot, StackSnapshot, VrfSnapshot};
+use stats::{self, Counters, LatencyStats, StackStats};
+use threads::{self, ThreadConfig, ThreadKind};
+use timer::{self, TimerCallback, TimerId, TimerWheel, Timers};
+use tun::{self, IpLink};
+
+use pnet::datalink::{self, EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
 use pnet::packet::icmp::IcmpType;
-use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::util::MacAddr;
 
-use rand;
+use rand::{self, Rand, Rng};
 use rand::distributions::{IndependentSample, Range};
-use rx;
+use rand::isaac::Isaac64Rng;
+use rx::{self, ChannelReceiver, RxFailure, RxHandle, RxListener};
 
-use std::collections::{HashMap, HashSet};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
-use std::io;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Arc, Mutex, RwLock};
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread::{self, JoinHandle};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use udp::{self, UdpTx};
 use util;
+use vlan::{self, VlanRx, VlanSender};
 
+/// MTU of interfaces whose MTU is not known from the OS.
 pub static DEFAULT_MTU: usize = 1500;
+
+/// Largest MTU an interface can have, the usual size of jumbo frames.
+pub static MAX_MTU: usize = 9000;
+
+/// Reads the MTU of the interface `name` from sysfs. Returns `None` if it
+/// is not known there, as on other platforms than Linux.
+pub fn sysfs_mtu(name: &str) -> Option<usize> {
+    let mut mtu = String::new();
+    match File::open(format!("/sys/class/net/{}/mtu", name)) {
+        Ok(mut file) => {
+            if file.read_to_string(&mut mtu).is_err() {
+                return None;
+            }
+        }
+        Err(_) => return None,
+    }
+    mtu.trim().parse().ok()
+}
+
 pub static LOCAL_PORT_RANGE_START: u16 = 32768;
 pub static LOCAL_PORT_RANGE_END: u16 = 61000;
 
+/// Maximum number of destinations kept in the route cache of a
+/// `NetworkStack`. The cache is flushed when it grows beyond this.
+pub static ROUTE_CACHE_SIZE: usize = 1024;
+
+/// How long after a send on a datalink failed with `TxError::WouldBlock` an
+/// interface in non-blocking mode is assumed to be writable again, as the
+/// datalink gives no notice when its buffer drains.
+pub static WRITE_RETRY_MS: u64 = 1;
+
 pub type StackResult<T> = Result<T, StackError>;
 
 pub enum StackInterfaceMsg {
     UpdateArpTable(Ipv4Addr, MacAddr),
     ArpRequest(Ipv4Addr, MacAddr, Ipv4Addr),
+    /// Schedules a timeout expiring at the given time, see `timer`
+    AddTimer(TimerId, Instant, TimerCallback),
+    CancelTimer(TimerId),
     Shutdown,
 }
 
+/// The firewall hooks packets sent by this host pass through.
+static LOCAL_TX_HOOKS: &'static [Hook] = &[Hook::Output, Hook::Postrouting];
+
+static BROADCAST_MAC: MacAddr = MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+/// The `EtherType`s every interface handles itself.
+static BUILTIN_ETHER_TYPES: &'static [EtherType] = &[EtherTypes::Arp,
+                                                     EtherTypes::Ipv4,
+                                                     EtherTypes::Vlan];
+
+/// The firewall hooks forwarded packets pass through when they are sent.
+static FORWARDED_TX_HOOKS: &'static [Hook] = &[Hook::Postrouting];
+
+/// A change of the parameters of an interface that affects what can be
+/// sent on it, see `StackInterface::watch_link`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkChange {
+    /// The MTU changed to this
+    Mtu(usize),
+    /// The rate limit was set or removed
+    RateLimit(Option<RateLimit>),
+    /// A supplicant authorized the port, or made it unauthorized
+    Authorized(bool),
+    /// The interface gained or lost its carrier, see
+    /// `NetworkStack::set_carrier`
+    Carrier(bool),
+}
+
+/// The channels `LinkChange`s of an interface are sent to.
+#[derive(Default)]
+pub struct LinkWatchers {
+    watchers: Mutex<Vec<Sender<LinkChange>>>,
+}
+
+impl LinkWatchers {
+    pub fn watch(&self) -> Receiver<LinkChange> {
+        let (tx, rx) = mpsc::channel();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Sends `change` to all watchers, forgetting those that stopped.
+    pub fn notify(&self, change: LinkChange) {
+        self.watchers.lock().unwrap().retain(|watcher| watcher.send(change).is_ok());
+    }
+}
+
 struct StackInterfaceData {
     interface: Interface,
     tx: Arc<Mutex<TxBarrier>>,
-    ipv4_addresses: RwLock<HashSet<Ipv4Addr>>,
+    firewall: Arc<Firewall>,
+    shaper: Arc<Shaper>,
+    ipv4_addresses: Arc<RwLock<HashSet<Ipv4Addr>>>,
+    /// Notified with the MAC of every other host seen using or probing for
+    /// the IP they are registered for. See `StackInterface::watch_arp`.
+    arp_watchers: Mutex<HashMap<Ipv4Addr, Vec<Sender<MacAddr>>>>,
+    /// See `StackInterface::watch_errors`.
+    error_watchers: Mutex<Vec<Sender<ThreadError>>>,
+    clock: Arc<Clock>,
+    /// Length of a tick of the timer wheel of the interface thread
+    timer_tick: Duration,
+    /// Prefix of the names of the threads of the interface
+    thread_prefix: String,
+    /// CPUs the threads of the interface are pinned to
+    cpus: HashMap<ThreadKind, Vec<usize>>,
+    pool: BufferPool,
 }
 
 impl StackInterfaceData {
+    fn timer_wheel(&self) -> TimerWheel {
+        TimerWheel::new(self.clock.now(), self.timer_tick, timer::WHEEL_SLOTS)
+    }
+
+    fn thread_config(&self, kind: ThreadKind) -> ThreadConfig {
+        ThreadConfig {
+            name: Some(kind.thread_name(&self.thread_prefix, &self.interface.name)),
+            cpus: self.cpus.get(&kind).cloned().unwrap_or_else(Vec::new),
+        }
+    }
+
+    /// How to spawn the rx worker with index `worker`, see `ThreadKind::RxWorker`.
+    fn rx_worker_thread_config(&self, worker: usize) -> ThreadConfig {
+        let mut config = self.thread_config(ThreadKind::RxWorker);
+        config.name = config.name.map(|name| format!("{}-{}", name, worker));
+        if !config.cpus.is_empty() {
+            config.cpus = vec![config.cpus[worker % config.cpus.len()]];
+        }
+        config
+    }
+
     fn tx(&self) -> DatalinkTx {
-        let version = self.tx.lock().unwrap().version();
-        DatalinkTx::new(self.tx.clone(), version)
+        self.filtered_tx(&self.tx, LOCAL_TX_HOOKS, self.shaper.clone())
+    }
+
+    /// Creates a `DatalinkTx` towards `tx` filtering the IPv4 packets in
+    /// `hooks` of the firewall and pacing them with `shaper`.
+    fn filtered_tx(&self,
+                   tx: &Arc<Mutex<TxBarrier>>,
+                   hooks: &'static [Hook],
+                   shaper: Arc<Shaper>)
+                   -> DatalinkTx {
+        let (version, state) = {
+            let tx = tx.lock().unwrap();
+            (tx.version(), tx.state.clone())
+        };
+        let filter = TxFilter {
+            firewall: self.firewall.clone(),
+            interface: self.interface.clone(),
+            hooks: hooks,
+        };
+        DatalinkTx::new(tx.clone(), version, state, filter, shaper)
     }
 
     pub fn ethernet_tx(&self, dst: MacAddr) -> EthernetTxImpl<DatalinkTx> {
-        EthernetTxImpl::new(self.tx(), self.interface.mac, dst)
+        self.ethernet_tx_with_src(self.interface.mac, dst)
+    }
+
+    pub fn ethernet_tx_with_src(&self, src: MacAddr, dst: MacAddr) -> EthernetTxImpl<DatalinkTx> {
+        EthernetTxImpl::new(self.tx(), src, dst)
     }
 
     pub fn arp_request_tx(&self) -> ArpRequestTx<EthernetTxImpl<DatalinkTx>> {
@@ -65,54 +240,159 @@ impl StackInterfaceData {
         let dst_mac = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
         ArpReplyTx::new(self.ethernet_tx(dst_mac))
     }
+
+    /// Notifies the watchers of `ip` that `mac` uses or probes for it.
+    fn notify_arp_watchers(&self, ip: Ipv4Addr, mac: MacAddr) {
+        if mac == self.interface.mac {
+            return;
+        }
+        let mut arp_watchers = self.arp_watchers.lock().unwrap();
+        let empty = if let Some(watchers) = arp_watchers.get_mut(&ip) {
+            watchers.retain(|watcher| watcher.send(mac).is_ok());
+            watchers.is_empty()
+        } else {
+            false
+        };
+        if empty {
+            arp_watchers.remove(&ip);
+        }
+    }
+
+    /// Sends `error` to the error watchers, forgetting those that stopped.
+    fn notify_error_watchers(&self, error: ThreadError) {
+        let mut error_watchers = self.error_watchers.lock().unwrap();
+        error_watchers.retain(|watcher| watcher.send(error.clone()).is_ok());
+    }
 }
 
 struct StackInterfaceThread {
     queue: Receiver<StackInterfaceMsg>,
     data: Arc<StackInterfaceData>,
     arp_table: ArpTable,
+    timers: TimerWheel,
 }
 
 struct StackInterfaceThreadHandle {
     pub handle: Option<JoinHandle<()>>,
     pub tx: Sender<StackInterfaceMsg>,
+    /// Disconnected when the thread quits
+    done: Receiver<()>,
 }
 
-impl Drop for StackInterfaceThreadHandle {
-    fn drop(&mut self) {
+impl StackInterfaceThreadHandle {
+    /// Tells the thread to quit and waits at most `timeout` for it. Returns
+    /// true if it quit.
+    fn shutdown(&mut self, timeout: Duration) -> bool {
+        if self.handle.is_none() {
+            return true;
+        }
         if let Err(..) = self.tx.send(StackInterfaceMsg::Shutdown) {
             error!("Unable to send shutdown command to interface thread");
         }
-        self.handle.take().unwrap().join().unwrap();
+        match self.done.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => false,
+            _ => {
+                self.handle.take().unwrap().join().unwrap();
+                true
+            }
+        }
+    }
+}
+
+impl Drop for StackInterfaceThreadHandle {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if let Err(..) = self.tx.send(StackInterfaceMsg::Shutdown) {
+                error!("Unable to send shutdown command to interface thread");
+            }
+            handle.join().unwrap();
+        }
     }
 }
 
 impl StackInterfaceThread {
-    pub fn spawn(data: Arc<StackInterfaceData>, arp_table: ArpTable) -> StackInterfaceThreadHandle {
+    /// Creates the thread state without spawning it, with the handle its
+    /// messages are sent to. Used by polled interfaces, which process the
+    /// messages with `process_pending` instead.
+    fn unspawned(data: Arc<StackInterfaceData>,
+                 arp_table: ArpTable)
+                 -> (StackInterfaceThread, StackInterfaceThreadHandle) {
         let (thread_tx, rx) = mpsc::channel();
+        let (_, done) = mpsc::channel();
         let stack_interface_thread = StackInterfaceThread {
             queue: rx,
+            arp_table: arp_table,
+            timers: data.timer_wheel(),
             data: data,
+        };
+        let handle = StackInterfaceThreadHandle {
+            handle: None,
+            tx: thread_tx,
+            done: done,
+        };
+        (stack_interface_thread, handle)
+    }
+
+    pub fn spawn(data: Arc<StackInterfaceData>, arp_table: ArpTable) -> StackInterfaceThreadHandle {
+        let (thread_tx, rx) = mpsc::channel();
+        let (done_tx, done) = mpsc::channel();
+        let stack_interface_thread = StackInterfaceThread {
+            queue: rx,
+            data: data.clone(),
             arp_table: arp_table,
+            timers: data.timer_wheel(),
         };
-        let thread_handle = thread::spawn(move || {
-            stack_interface_thread.run();
+        let thread_handle = data.thread_config(ThreadKind::Interface).spawn(move || {
+            let _done_tx = done_tx;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| stack_interface_thread.run()));
+            if let Err(payload) = result {
+                let msg = util::panic_message(&payload);
+                data.notify_error_watchers(ThreadError::Panic(data.interface.clone(), msg));
+            }
         });
         StackInterfaceThreadHandle {
             handle: Some(thread_handle),
             tx: thread_tx,
+            done: done,
         }
     }
 
     fn run(mut self) {
-        while let Ok(msg) = self.queue.recv() {
-            if !self.process_msg(msg) {
-                break;
+        loop {
+            // Woken up for the next tick of the timer wheel, if it is used
+            let msg = match self.timers.next_timeout(self.data.clock.now()) {
+                Some(timeout) => {
+                    match self.queue.recv_timeout(timeout) {
+                        Ok(msg) => Some(msg),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => {
+                    match self.queue.recv() {
+                        Ok(msg) => Some(msg),
+                        Err(..) => break,
+                    }
+                }
+            };
+            self.timers.advance(self.data.clock.now());
+            if let Some(msg) = msg {
+                if !self.process_msg(msg) {
+                    break;
+                }
             }
         }
         debug!("StackInterfaceThread is quitting");
     }
 
+    /// Processes the messages queued so far and fires the timeouts expired.
+    fn process_pending(&mut self) {
+        while let Ok(msg) = self.queue.try_recv() {
+            self.process_msg(msg);
+        }
+        self.timers.advance(self.data.clock.now());
+    }
+
     fn process_msg(&mut self, msg: StackInterfaceMsg) -> bool {
         use self::StackInterfaceMsg::*;
         match msg {
@@ -120,12 +400,17 @@ impl StackInterfaceThread {
             ArpRequest(sender_ip, sender_mac, target_ip) => {
                 self.handle_arp_request(sender_ip, sender_mac, target_ip)
             }
+            AddTimer(id, deadline, callback) => self.timers.schedule(id, deadline, callback),
+            CancelTimer(id) => {
+                self.timers.cancel(id);
+            }
             Shutdown => return false,
         }
         true
     }
 
     fn update_arp(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        self.data.notify_arp_watchers(ip, mac);
         if self.arp_table.insert(ip, mac) {
             self.data.tx.lock().unwrap().inc();
         }
@@ -135,18 +420,24 @@ impl StackInterfaceThread {
                           sender_ip: Ipv4Addr,
                           sender_mac: MacAddr,
                           target_ip: Ipv4Addr) {
+        if sender_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            // Arp probe, someone is about to take `target_ip` into use
+            self.data.notify_arp_watchers(target_ip, sender_mac);
+        } else {
+            self.data.notify_arp_watchers(sender_ip, sender_mac);
+        }
         let ipv4_addresses = self.data.ipv4_addresses.read().unwrap();
         if ipv4_addresses.contains(&target_ip) {
             debug!("Incoming Arp request for me!! {}", target_ip);
-            tx_send!(|| self.data.arp_reply_tx(); target_ip, sender_mac, sender_ip).unwrap_or(());
+            drop(tx_send!(|| self.data.arp_reply_tx(); target_ip, sender_mac, sender_ip));
         }
     }
 }
 
 struct Ipv4Data {
     net: Ipv4Network,
-    udp_listeners: Arc<Mutex<udp::UdpListenerLookup>>,
-    icmp_listeners: Arc<Mutex<icmp::IcmpListenerLookup>>,
+    udp_listeners: Arc<RwLock<udp::UdpListenerLookup>>,
+    icmp_listeners: Arc<RwLock<icmp::IcmpListenerLookup>>,
 }
 
 /// Represents the stack on one physical interface.
@@ -154,43 +445,269 @@ struct Ipv4Data {
 pub struct StackInterface {
     data: Arc<StackInterfaceData>,
     mtu: usize,
-    _thread_handle: StackInterfaceThreadHandle,
+    thread_handle: StackInterfaceThreadHandle,
+    rx_thread: RxHandle,
+    /// Set on polled interfaces, see `poll`
+    poller: Option<Poller>,
+    /// Set on interfaces with several rx workers, see `rx_worker_stats`
+    fan_out: Option<Arc<Mutex<FanOutStats>>>,
     arp_table: ArpTable,
     ipv4_datas: HashMap<Ipv4Addr, Ipv4Data>,
-    ipv4_listeners: Arc<Mutex<ipv4::IpListenerLookup>>,
+    ipv4_listeners: Arc<RwLock<ipv4::IpListenerLookup>>,
+    ipv4_networks: Arc<RwLock<Vec<Ipv4Network>>>,
+    /// Icmp listeners of all local addresses, shared by the interfaces of a
+    /// stack, see `NetworkStack::icmp_listen`
+    icmp_wildcard_listeners: Arc<RwLock<icmp::IcmpListenerLookup>>,
+    /// Where packets to the addresses of this interface itself are sent.
+    loopback_tx: Arc<Mutex<TxBarrier>>,
+    reassembly: Arc<ipv4::ReassemblyControl>,
+    /// Counts the packets failing to parse, see `malformed`
+    malformed: Arc<Malformed>,
+    /// Counts the packets sent and received, see `stats`
+    counters: Arc<Counters>,
+    checksums: Arc<RxChecksumControl>,
+    forwarding: Arc<ipv4::ForwardingControl>,
+    rx_filter: Arc<RwLock<Option<bpf::Program>>>,
+    qos: Option<EgressQueues>,
+    capture: Arc<CapturePoint>,
+    observers: Arc<Observers>,
+    /// The observer logging frames, see `set_dump`
+    dump: Mutex<Option<ObserverId>>,
+    vlans: Arc<Mutex<vlan::VlanTable>>,
+    macvlans: Arc<Mutex<MacvlanTable>>,
+    ethernet_listeners: Arc<Mutex<EthernetListenerLookup>>,
+    llc_listeners: Arc<Mutex<LlcListenerLookup>>,
+    mac_filter: Arc<MacFilter>,
+    port_access: Arc<PortAccess>,
+    link_watchers: Arc<LinkWatchers>,
+    timers: Timers,
+    carrier: bool,
+    /// Set on interfaces exchanging IP packets without Ethernet, see `tun`
+    layer3: bool,
+    config_version: u64,
 }
 
 impl StackInterface {
     pub fn new(interface: Interface, channel: EthernetChannel) -> StackInterface {
-        let EthernetChannel(sender, receiver) = channel;
+        Self::with_firewall(interface, channel, Arc::new(Firewall::default()))
+    }
+
+    /// Same as `new`, but filters all IPv4 traffic through `firewall`.
+    pub fn with_firewall(interface: Interface,
+                         channel: EthernetChannel,
+                         firewall: Arc<Firewall>)
+                         -> StackInterface {
+        Self::build(interface, channel, firewall, None, &InterfaceOptions::default())
+    }
+
+    /// Same as `with_firewall`, but the frames received are only handled
+    /// when `poll` is called, in the calling thread. The rx thread of the
+    /// interface only reads the frames and sends a `()` to `wakeup` for each
+    /// of them. Arp replies are also sent and the Arp table updated from
+    /// `poll`, so resolving a MAC address blocks forever unless another
+    /// thread polls. Use `NetworkStack::resolve_in_vrf` and poll until its
+    /// `Receiver` yields instead.
+    pub fn polled(interface: Interface,
+                  channel: EthernetChannel,
+                  firewall: Arc<Firewall>,
+                  wakeup: Sender<()>)
+                  -> StackInterface {
+        Self::build(interface, channel, firewall, Some(wakeup), &InterfaceOptions::default())
+    }
+
+    fn build(interface: Interface,
+             channel: EthernetChannel,
+             firewall: Arc<Firewall>,
+             wakeup: Option<Sender<()>>,
+             options: &InterfaceOptions)
+             -> StackInterface {
+        let clock = options.clock.clone();
+        let capture = Arc::new(CapturePoint::default());
+        let observers = Arc::new(Observers::new(interface.clone(), capture.clone()));
+        let counters = Arc::new(Counters::default());
+        let channel = stats::channel(channel, counters.clone());
+        let EthernetChannel(sender, receiver) = observe::channel(channel, observers.clone());
+        let forwarding = Arc::new(ipv4::ForwardingControl::new(interface.clone()));
+        let loopback_forwarding = Arc::new(ipv4::ForwardingControl::new(interface.clone()));
 
         let stack_interface_data = Arc::new(StackInterfaceData {
             interface: interface,
             tx: Arc::new(Mutex::new(TxBarrier::new(sender))),
-            ipv4_addresses: RwLock::new(HashSet::new()),
+            firewall: firewall.clone(),
+            shaper: Arc::new(Shaper::default()),
+            ipv4_addresses: Arc::new(RwLock::new(HashSet::new())),
+            arp_watchers: Mutex::new(HashMap::new()),
+            error_watchers: Mutex::new(Vec::new()),
+            clock: clock.clone(),
+            timer_tick: options.timer_tick,
+            thread_prefix: options.thread_prefix.clone(),
+            cpus: options.cpus.clone(),
+            pool: options.pool.clone(),
         });
 
         let arp_table = arp::ArpTable::new();
 
-        let thread_handle = StackInterfaceThread::spawn(stack_interface_data.clone(),
-                                                        arp_table.clone());
-
-        let arp_rx = arp_table.arp_rx(thread_handle.tx.clone());
-
-        let ipv4_listeners = Arc::new(Mutex::new(HashMap::new()));
-        let ipv4_rx = ipv4::Ipv4Rx::new(ipv4_listeners.clone());
+        let (thread_handle, stack_interface_thread) = if wakeup.is_some() {
+            let (thread, handle) = StackInterfaceThread::unspawned(stack_interface_data.clone(),
+                                                                   arp_table.clone());
+            (handle, Some(thread))
+        } else {
+            let handle = StackInterfaceThread::spawn(stack_interface_data.clone(),
+                                                     arp_table.clone());
+            (handle, None)
+        };
 
-        let ethernet_listeners = vec![arp_rx, ipv4_rx];
-        let ethernet_rx = EthernetRx::new(ethernet_listeners);
-        rx::spawn(receiver, ethernet_rx);
+        let timers = Timers::new(thread_handle.tx.clone(), clock.clone());
+
+        let malformed = Arc::new(Malformed::new(options.malformed_policy.clone()));
+        let checksums = Arc::new(RxChecksumControl::new(options.rx_checksums));
+        let ipv4_listeners = Arc::new(RwLock::new(HashMap::new()));
+        let ipv4_networks = Arc::new(RwLock::new(Vec::new()));
+        let limits = ipv4::ReassemblyLimits::default();
+        let reassembly = Arc::new(ipv4::ReassemblyControl::with_clock(limits, clock));
+        let loopback_ipv4_rx = ipv4::Ipv4Rx::with_firewall(ipv4_listeners.clone(),
+                                                           ipv4_networks.clone(),
+                                                           reassembly.clone(),
+                                                           loopback_forwarding,
+                                                           firewall.clone());
+        let loopback = Arc::new(LoopbackQueue::new(EthernetRx::new(vec![loopback_ipv4_rx])));
+        let loopback_tx = Arc::new(Mutex::new(TxBarrier::loopback(loopback, options.pool.clone())));
+
+        let vlans = Arc::new(Mutex::new(HashMap::new()));
+        let rx_filter = Arc::new(RwLock::new(None));
+        let extra_ethernet_listeners = Arc::new(Mutex::new(HashMap::new()));
+        let llc_listeners = Arc::new(Mutex::new(HashMap::new()));
+        let macvlans = Arc::new(Mutex::new(HashMap::new()));
+        let mac_filter = Arc::new(MacFilter {
+            promiscuous: AtomicBool::new(true),
+            multicast: RwLock::new(HashMap::new()),
+            monitor: Mutex::new(None),
+        });
+        let link_watchers = Arc::new(LinkWatchers::default());
+        let port_access = Arc::new(PortAccess::new(link_watchers.clone()));
+        let report_data = stack_interface_data.clone();
+        let report = move |failure| {
+            let interface = report_data.interface.clone();
+            let error = match failure {
+                RxFailure::Datalink(e) => ThreadError::Datalink(interface, e.kind(), e.to_string()),
+                RxFailure::Panic(msg) => ThreadError::Panic(interface, msg),
+            };
+            report_data.notify_error_watchers(error);
+        };
+        let rx_thread = stack_interface_data.thread_config(ThreadKind::Rx);
+        let busy_poll = options.busy_poll;
+        let (rx_thread, poller, fan_out) = {
+            // Every rx worker has listeners of its own, sharing the tables
+            let mut interface_rx = |_: usize| {
+                let mut ipv4_rx = ipv4::Ipv4Rx::unboxed(ipv4_listeners.clone(),
+                                                        ipv4_networks.clone(),
+                                                        reassembly.clone(),
+                                                        forwarding.clone(),
+                                                        firewall.clone());
+                ipv4_rx.set_malformed(malformed.clone());
+                ipv4_rx.set_counters(counters.clone());
+                ipv4_rx.set_checksums(checksums.clone());
+                let arp_rx = Guarded::new(Layer::Arp,
+                                          malformed.clone(),
+                                          counters.clone(),
+                                          ArpRx::new(thread_handle.tx.clone()));
+                let vlan_rx = Guarded::new(Layer::Ethernet,
+                                           malformed.clone(),
+                                           counters.clone(),
+                                           VlanRx::new(vlans.clone()));
+                // IPv4 first, it gets most of the frames
+                let listeners = Chain(ipv4_rx, Chain(arp_rx, vlan_rx));
+                let ethernet_rx = StaticEthernetRx::new(listeners,
+                                                        rx_filter.clone(),
+                                                        extra_ethernet_listeners.clone());
+                InterfaceRx {
+                    mac: stack_interface_data.interface.mac,
+                    mac_filter: mac_filter.clone(),
+                    port_access: port_access.clone(),
+                    ethernet_rx: ethernet_rx,
+                    llc_rx: LlcRx::new(llc_listeners.clone()),
+                    macvlans: macvlans.clone(),
+                    counters: counters.clone(),
+                }
+            };
+            match (wakeup, stack_interface_thread) {
+                (Some(wakeup), Some(stack_interface_thread)) => {
+                    let (frames_tx, frames) = mpsc::channel();
+                    let queueing_rx = QueueingRx {
+                        frames: frames_tx,
+                        wakeup: wakeup,
+                        pool: options.pool.clone(),
+                    };
+                    let poller = Poller {
+                        frames: frames,
+                        interface_rx: interface_rx(0),
+                        thread: stack_interface_thread,
+                    };
+                    let rx_thread = rx::spawn_busy_polling(&rx_thread,
+                                                           busy_poll,
+                                                           receiver,
+                                                           queueing_rx,
+                                                           report);
+                    (rx_thread, Some(poller), None)
+                }
+                _ if options.rx_workers > 1 => {
+                    let fan_out = FanOut::spawn(options.rx_workers,
+                                                |i| stack_interface_data.rx_worker_thread_config(i),
+                                                &mut interface_rx,
+                                                options.pool.clone());
+                    let stats = fan_out.stats();
+                    let rx_thread = rx::spawn_busy_polling(&rx_thread,
+                                                           busy_poll,
+                                                           receiver,
+                                                           fan_out,
+                                                           report);
+                    (rx_thread, None, Some(stats))
+                }
+                _ => {
+                    let rx_thread = rx::spawn_busy_polling(&rx_thread,
+                                                           busy_poll,
+                                                           receiver,
+                                                           interface_rx(0),
+                                                           report);
+                    (rx_thread, None, None)
+                }
+            }
+        };
 
         StackInterface {
             data: stack_interface_data,
-            mtu: DEFAULT_MTU,
-            _thread_handle: thread_handle,
+            mtu: options.mtu,
+            thread_handle: thread_handle,
+            rx_thread: rx_thread,
+            poller: poller,
+            fan_out: fan_out,
             arp_table: arp_table,
             ipv4_datas: HashMap::new(),
             ipv4_listeners: ipv4_listeners,
+            ipv4_networks: ipv4_networks,
+            icmp_wildcard_listeners: Arc::new(RwLock::new(HashMap::new())),
+            loopback_tx: loopback_tx,
+            reassembly: reassembly,
+            malformed: malformed,
+            counters: counters,
+            checksums: checksums,
+            forwarding: forwarding,
+            rx_filter: rx_filter,
+            qos: None,
+            capture: capture,
+            observers: observers,
+            dump: Mutex::new(None),
+            vlans: vlans,
+            macvlans: macvlans,
+            ethernet_listeners: extra_ethernet_listeners,
+            llc_listeners: llc_listeners,
+            mac_filter: mac_filter,
+            port_access: port_access,
+            link_watchers: link_watchers,
+            timers: timers,
+            carrier: true,
+            layer3: false,
+            config_version: 0,
         }
     }
 
@@ -202,6 +719,12 @@ impl StackInterface {
         self.data.ethernet_tx(dst)
     }
 
+    /// Same as `ethernet_tx`, but sends from the MAC address `src` instead
+    /// of the one of the interface.
+    pub fn ethernet_tx_with_src(&self, src: MacAddr, dst: MacAddr) -> EthernetTxImpl<DatalinkTx> {
+        self.data.ethernet_tx_with_src(src, dst)
+    }
+
     pub fn arp_request_tx(&self) -> ArpRequestTx<EthernetTxImpl<DatalinkTx>> {
         self.data.arp_request_tx()
     }
@@ -210,24 +733,41 @@ impl StackInterface {
         &mut self.arp_table
     }
 
+    /// Returns the handle for scheduling timeouts run on the thread of this
+    /// interface. See `timer`.
+    pub fn timers(&self) -> Timers {
+        self.timers.clone()
+    }
+
     pub fn add_ipv4(&mut self, ip_net: Ipv4Network) -> StackResult<()> {
         let ip = ip_net.ip();
         match self.ipv4_datas.entry(ip) {
-            Entry::Occupied(_) => Err(StackError::IllegalArgument),
+            Entry::Occupied(_) => Err(StackError::AddressInUse(ip)),
             Entry::Vacant(entry) => {
                 let mut proto_listeners = HashMap::new();
 
-                let udp_listeners = Arc::new(Mutex::new(HashMap::new()));
-                let udp_rx = udp::UdpRx::new(udp_listeners.clone());
+                let udp_listeners = Arc::new(RwLock::new(HashMap::new()));
+                let udp_rx = udp::UdpRx::with_checksums(udp_listeners.clone(),
+                                                        self.checksums.clone());
+                let udp_rx = Guarded::new(Layer::Udp,
+                                          self.malformed.clone(),
+                                          self.counters.clone(),
+                                          udp_rx);
                 let udp_ipv4_listener = Box::new(udp_rx) as Box<ipv4::Ipv4Listener>;
-                proto_listeners.insert(IpNextHeaderProtocols::Udp, udp_ipv4_listener);
-
-                let icmp_listeners = Arc::new(Mutex::new(HashMap::new()));
-                let icmp_rx = icmp::IcmpRx::new(icmp_listeners.clone());
+                proto_listeners.insert(IpNextHeaderProtocols::Udp, Mutex::new(udp_ipv4_listener));
+
+                let icmp_listeners = Arc::new(RwLock::new(HashMap::new()));
+                let icmp_rx = icmp::IcmpRx::with_wildcard(icmp_listeners.clone(),
+                                                          self.icmp_wildcard_listeners.clone(),
+                                                          self.checksums.clone());
+                let icmp_rx = Guarded::new(Layer::Icmp,
+                                           self.malformed.clone(),
+                                           self.counters.clone(),
+                                           icmp_rx);
                 let icmp_listener = Box::new(icmp_rx) as Box<ipv4::Ipv4Listener>;
-                proto_listeners.insert(IpNextHeaderProtocols::Icmp, icmp_listener);
+                proto_listeners.insert(IpNextHeaderProtocols::Icmp, Mutex::new(icmp_listener));
                 {
-                    let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
+                    let mut ipv4_listeners = self.ipv4_listeners.write().unwrap();
                     ipv4_listeners.insert(ip, proto_listeners);
                 }
 
@@ -238,31 +778,162 @@ impl StackInterface {
                 };
                 entry.insert(data);
                 self.data.ipv4_addresses.write().unwrap().insert(ip);
+                self.ipv4_networks.write().unwrap().push(ip_net);
+                self.config_version = self.config_version.wrapping_add(1);
                 Ok(())
             }
         }
     }
 
+    /// Detaches the address `ip` from this interface, together with every
+    /// listener bound to it. Returns the network it was attached with, or
+    /// `None` if the interface did not have the address.
+    pub fn remove_ipv4(&mut self, ip: Ipv4Addr) -> Option<Ipv4Network> {
+        let ip_net = match self.ipv4_datas.remove(&ip) {
+            Some(data) => data.net,
+            None => return None,
+        };
+        self.ipv4_listeners.write().unwrap().remove(&ip);
+        self.data.ipv4_addresses.write().unwrap().remove(&ip);
+        self.ipv4_networks.write().unwrap().retain(|net| net.ip() != ip);
+        self.config_version = self.config_version.wrapping_add(1);
+        Some(ip_net)
+    }
+
     pub fn ipv4_tx(&mut self,
                    dst: Ipv4Addr,
                    gw: Option<Ipv4Addr>)
                    -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
-        let local_dst = gw.unwrap_or(dst);
-        if let Some(src) = self.closest_local_ip(local_dst) {
-            let dst_mac = match self.arp_table.get(local_dst) {
-                Ok(mac) => mac,
-                Err(rx) => {
-                    tx_send!(|| self.arp_request_tx(); src, local_dst)?;
-                    rx.recv().unwrap()
-                }
-            };
-            let ethernet_tx = self.ethernet_tx(dst_mac);
-            Ok(Ipv4TxImpl::new(ethernet_tx, src, dst, self.mtu))
+        if let Some(src) = self.source_address(dst, gw.unwrap_or(dst)) {
+            let mtu = self.mtu;
+            self.ipv4_tx_from(src, None, dst, gw, mtu)
+        } else {
+            Err(StackError::NoSourceAddress(dst))
+        }
+    }
+
+    /// Creates an `Ipv4Tx` sending from the already decided local address
+    /// `src` to `dst`, via `gw` if it's not `None`. Packets are fragmented to
+    /// fit `mtu`, which must not be larger than the interface MTU. The packets
+    /// carry `spoofed_src` instead of `src` if it's not `None`, while Arp
+    /// still resolves the next hop from `src`.
+    fn ipv4_tx_from(&mut self,
+                    src: Ipv4Addr,
+                    spoofed_src: Option<Ipv4Addr>,
+                    dst: Ipv4Addr,
+                    gw: Option<Ipv4Addr>,
+                    mtu: usize)
+                    -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        self.tx_route(src, spoofed_src, dst, gw, mtu).ipv4_tx()
+    }
+
+    /// Same as `ipv4_tx_from`, but returns what creating the tx-object takes
+    /// instead of creating it. See `TxRoute`.
+    fn tx_route(&self,
+                src: Ipv4Addr,
+                spoofed_src: Option<Ipv4Addr>,
+                dst: Ipv4Addr,
+                gw: Option<Ipv4Addr>,
+                mtu: usize)
+                -> TxRoute {
+        let mac = self.interface().mac;
+        let local = self.has_ipv4(dst);
+        let dst_mac = if local {
+            Ok(mac)
+        } else if self.is_broadcast(dst) {
+            Ok(BROADCAST_MAC)
+        } else if dst.is_multicast() {
+            Ok(::ethernet::ipv4_multicast_mac(dst))
+        } else if self.layer3 {
+            // The Ethernet header is stripped before sending anyway
+            Ok(mac)
+        } else {
+            Err(gw.unwrap_or(dst))
+        };
+        let (tx, shaper) = if local {
+            // Delivered directly to the local listeners without touching the wire
+            (self.loopback_tx.clone(), Arc::new(Shaper::default()))
         } else {
-            Err(StackError::IllegalArgument)
+            (self.data.tx.clone(), self.data.shaper.clone())
+        };
+        TxRoute {
+            data: self.data.clone(),
+            arp_table: self.arp_table.clone(),
+            tx: tx,
+            shaper: shaper,
+            src: src,
+            header_src: spoofed_src.unwrap_or(src),
+            dst: dst,
+            dst_mac: dst_mac,
+            mtu: cmp::min(mtu, self.mtu),
+        }
+    }
+
+    /// Returns the MAC address of `ip`, sending an Arp request from `src`
+    /// and blocking until the reply arrives if it's not known already.
+    fn resolve(&mut self, src: Ipv4Addr, ip: Ipv4Addr) -> StackResult<MacAddr> {
+        if self.layer3 {
+            // The Ethernet header is stripped before sending anyway
+            return Ok(self.interface().mac);
+        }
+        match self.arp_table.get(ip) {
+            Ok(mac) => Ok(mac),
+            Err(rx) => {
+                tx_send!(|| self.arp_request_tx(); src, ip)?;
+                Ok(rx.recv().unwrap())
+            }
+        }
+    }
+
+    /// Same as `resolve`, but returns a channel receiving the MAC address
+    /// instead of blocking if it's not known already.
+    fn start_resolve(&mut self,
+                     src: Ipv4Addr,
+                     ip: Ipv4Addr)
+                     -> StackResult<Option<Receiver<MacAddr>>> {
+        if self.layer3 {
+            return Ok(None);
+        }
+        match self.arp_table.get(ip) {
+            Ok(_) => Ok(None),
+            Err(rx) => {
+                tx_send!(|| self.arp_request_tx(); src, ip)?;
+                Ok(Some(rx))
+            }
         }
     }
 
+    /// Sends the already built IPv4 packet `packet`, to its destination if
+    /// that is one of the addresses of this interface, else to `next_hop`.
+    /// The Arp request for `next_hop`, if needed, is sent from `src`.
+    fn send_ipv4_packet(&mut self,
+                        src: Ipv4Addr,
+                        next_hop: Ipv4Addr,
+                        packet: &[u8])
+                        -> StackResult<()> {
+        let dst = match Ipv4Packet::new(packet) {
+            Some(ip_pkg) => ip_pkg.get_destination(),
+            None => return Err(StackError::InvalidArgument("Truncated IPv4 packet".to_owned())),
+        };
+        let mac = self.interface().mac;
+        let mut ethernet_tx = if self.has_ipv4(dst) {
+            let shaper = Arc::new(Shaper::default());
+            let tx = self.data.filtered_tx(&self.loopback_tx, FORWARDED_TX_HOOKS, shaper);
+            EthernetTxImpl::new(tx, mac, mac)
+        } else {
+            if packet.len() > self.mtu {
+                return Err(StackError::TxError(TxError::TooLargePayload));
+            }
+            let dst_mac = self.resolve(src, next_hop)?;
+            let shaper = self.data.shaper.clone();
+            let tx = self.data.filtered_tx(&self.data.tx, FORWARDED_TX_HOOKS, shaper);
+            EthernetTxImpl::new(tx, mac, dst_mac)
+        };
+        let payload = BasicEthernetPayload::new(EtherTypes::Ipv4, packet);
+        ethernet_tx.send(1, packet.len(), payload)?;
+        Ok(())
+    }
+
     pub fn icmp_listen<L>(&mut self,
                           local_ip: Ipv4Addr,
                           icmp_type: IcmpType,
@@ -271,8 +942,10 @@ impl StackInterface {
         where L: icmp::IcmpListener + 'static
     {
         if let Some(ip_data) = self.ipv4_datas.get(&local_ip) {
-            let mut icmp_listeners = ip_data.icmp_listeners.lock().unwrap();
-            icmp_listeners.entry(icmp_type).or_insert_with(Vec::new).push(Box::new(listener));
+            let mut icmp_listeners = ip_data.icmp_listeners.write().unwrap();
+            icmp_listeners.entry(icmp_type)
+                .or_insert_with(Vec::new)
+                .push(Mutex::new(Box::new(listener)));
             Ok(())
         } else {
             let msg = "Bind address does not exist on interface".to_owned();
@@ -280,165 +953,2277 @@ impl StackInterface {
         }
     }
 
+    /// Registers `listener` for all IPv4 packets with the protocol
+    /// `protocol` to `local_ip`. Fails if the protocol is already handled,
+    /// for example UDP and ICMP always are.
+    pub fn ipv4_listen(&mut self,
+                       local_ip: Ipv4Addr,
+                       protocol: IpNextHeaderProtocol,
+                       listener: Box<ipv4::Ipv4Listener>)
+                       -> io::Result<()> {
+        let mut ipv4_listeners = self.ipv4_listeners.write().unwrap();
+        match ipv4_listeners.get_mut(&local_ip) {
+            Some(proto_listeners) => {
+                if proto_listeners.contains_key(&protocol) {
+                    let msg = format!("Protocol {} is already handled on {}", protocol, local_ip);
+                    Err(io::Error::new(io::ErrorKind::AddrInUse, msg))
+                } else {
+                    proto_listeners.insert(protocol, Mutex::new(listener));
+                    Ok(())
+                }
+            }
+            None => {
+                let msg = "Bind address does not exist on interface".to_owned();
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+        }
+    }
+
+    /// Registers `listener` for all frames received on this interface with
+    /// its `EtherType`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if the `EtherType` is always
+    /// handled by the stack, like Arp, IPv4 and VLAN tagged frames are, and
+    /// with `StackError::AlreadyExists` if it is already listened to.
+    pub fn ethernet_listen(&mut self, listener: Box<EthernetListener>) -> StackResult<()> {
+        let ether_type = listener.ether_type();
+        let mut ethernet_listeners = self.ethernet_listeners.lock().unwrap();
+        if BUILTIN_ETHER_TYPES.contains(&ether_type) {
+            let msg = format!("EtherType 0x{:04x} is handled by the stack", ether_type.0);
+            return Err(StackError::InvalidArgument(msg));
+        }
+        if ethernet_listeners.contains_key(&ether_type) {
+            let msg = format!("Listener for EtherType 0x{:04x}", ether_type.0);
+            return Err(StackError::AlreadyExists(msg));
+        }
+        ethernet_listeners.insert(ether_type, listener);
+        Ok(())
+    }
+
+    /// Removes the listener registered with `ethernet_listen` for
+    /// `ether_type` and returns it.
+    pub fn remove_ethernet_listener(&mut self,
+                                    ether_type: EtherType)
+                                    -> Option<Box<EthernetListener>> {
+        self.ethernet_listeners.lock().unwrap().remove(&ether_type)
+    }
+
+    /// Registers `listener` for all 802.3 frames received on this interface
+    /// with its LLC SAP or SNAP protocol.
+    ///
+    /// Fails with `StackError::AlreadyExists` if the key is already taken,
+    /// or `StackError::InvalidArgument` if it can never match. SNAP frames
+    /// with OUI 0 are received as the Ethernet II frames they encapsulate,
+    /// and SNAP frames are never handed to a listener for `llc::SNAP_SAP`.
+    pub fn llc_listen(&mut self, listener: Box<LlcListener>) -> StackResult<()> {
+        let key = listener.key();
+        let unreachable = match key {
+            LlcKey::Sap(sap) => sap == llc::SNAP_SAP,
+            LlcKey::Snap { oui, .. } => oui == 0,
+        };
+        let mut llc_listeners = self.llc_listeners.lock().unwrap();
+        if unreachable {
+            return Err(StackError::InvalidArgument(format!("{:?} is never received", key)));
+        }
+        if llc_listeners.contains_key(&key) {
+            return Err(StackError::AlreadyExists(format!("Listener for {:?}", key)));
+        }
+        llc_listeners.insert(key, listener);
+        Ok(())
+    }
+
+    /// Removes the listener registered with `llc_listen` for `key` and
+    /// returns it.
+    pub fn remove_llc_listener(&mut self, key: LlcKey) -> Option<Box<LlcListener>> {
+        self.llc_listeners.lock().unwrap().remove(&key)
+    }
+
     pub fn get_mtu(&self) -> usize {
         self.mtu
     }
 
-    pub fn set_mtu(&mut self, mtu: usize) {
+    /// Sets the MTU of this interface and invalidates all existing
+    /// tx-objects. Watchers are notified if it changed. Fails with
+    /// `InvalidMtu` if it is smaller than `ipv4::MIN_MTU` or larger than
+    /// `MAX_MTU`.
+    pub fn set_mtu(&mut self, mtu: usize) -> StackResult<()> {
+        if mtu < ipv4::MIN_MTU || mtu > MAX_MTU {
+            return Err(StackError::InvalidMtu(mtu));
+        }
+        let changed = mtu != self.mtu;
         self.mtu = mtu;
-        self.data.tx.lock().unwrap().inc();
+        self.invalidate_tx();
+        if changed {
+            self.link_watchers.notify(LinkChange::Mtu(mtu));
+        }
+        Ok(())
     }
 
-    /// Finds which local IP is suitable as src ip for packets sent to `dst`.
-    /// TODO: Smarter algorithm
-    fn closest_local_ip(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
-        for (ip, ip_data) in &self.ipv4_datas {
-            if ip_data.net.contains(dst) {
-                return Some(*ip);
-            }
-        }
-        None
+    /// Returns a counter that is incremented whenever the addresses or link
+    /// state of this interface changes.
+    pub fn config_version(&self) -> u64 {
+        self.config_version
     }
-}
 
-impl Drop for StackInterface {
-    fn drop(&mut self) {
-        self.data.tx.lock().unwrap().inc();
+    /// Returns false while the interface has no carrier, see
+    /// `NetworkStack::set_carrier`.
+    pub fn has_carrier(&self) -> bool {
+        self.carrier
     }
-}
 
-/// The main struct of this library, managing an entire TCP/IP stack. Takes
-/// care of ARP, routing tables, threads, TCP resends/fragmentation etc. Most
-/// of this is still unimplemented.
-#[derive(Default)]
-pub struct NetworkStack {
-    interfaces: HashMap<Interface, StackInterface>,
-    routing_table: RoutingTable,
-}
+    /// Returns true if this interface exchanges IP packets rather than
+    /// Ethernet frames, and does no Arp. See `tun`.
+    pub fn is_layer3(&self) -> bool {
+        self.layer3
+    }
 
-impl NetworkStack {
-    pub fn new() -> NetworkStack {
-        NetworkStack {
-            interfaces: HashMap::new(),
-            routing_table: RoutingTable::new(),
+    /// Records the carrier state and tells the link watchers about it.
+    /// Returns true if it changed.
+    fn set_carrier(&mut self, carrier: bool) -> bool {
+        if carrier == self.carrier {
+            return false;
         }
+        self.carrier = carrier;
+        self.config_version = self.config_version.wrapping_add(1);
+        self.link_watchers.notify(LinkChange::Carrier(carrier));
+        true
     }
 
-    pub fn add_interface(&mut self,
-                         interface: Interface,
-                         channel: EthernetChannel)
-                         -> StackResult<()> {
-        match self.interfaces.entry(interface) {
-            Entry::Occupied(_) => Err(StackError::InvalidInterface),
-            Entry::Vacant(entry) => {
-                let interface = entry.key().clone();
-                entry.insert(StackInterface::new(interface, channel));
-                Ok(())
-            }
-        }
+    /// Makes all existing tx-objects created through this interface invalid,
+    /// forcing them to be recreated with the current state of the stack.
+    pub fn invalidate_tx(&self) {
+        self.data.tx.lock().unwrap().inc();
+        self.loopback_tx.lock().unwrap().inc();
     }
 
-    pub fn interfaces(&self) -> Vec<Interface> {
-        self.interfaces.keys().cloned().collect()
+    /// Finds which local IP is suitable as src ip for packets sent to `dst`
+    /// through `next_hop` on this interface. See `routing::select_source`.
+    pub fn source_address(&self, dst: Ipv4Addr, next_hop: Ipv4Addr) -> Option<Ipv4Addr> {
+        routing::select_source(self.ipv4_datas.values().map(|ip_data| &ip_data.net),
+                               dst,
+                               next_hop)
     }
 
-    pub fn interface(&mut self, interface: &Interface) -> StackResult<&mut StackInterface> {
-        match self.interfaces.get_mut(interface) {
-            Some(i) => Ok(i),
-            None => Err(StackError::InvalidInterface),
-        }
+    /// Returns true if `ip` is one of the addresses on this interface.
+    pub fn has_ipv4(&self, ip: Ipv4Addr) -> bool {
+        self.ipv4_datas.contains_key(&ip)
     }
 
-    pub fn interface_from_name(&mut self, name: &str) -> StackResult<&mut StackInterface> {
-        for (interface, stack_interface) in &mut self.interfaces {
-            if interface.name == name {
-                return Ok(stack_interface);
-            }
-        }
-        Err(StackError::InvalidInterface)
+    /// Returns true if `ip` is the limited broadcast address or the
+    /// broadcast address of one of the networks on this interface. Packets
+    /// to it are sent to the broadcast MAC address, without Arp.
+    pub fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip == Ipv4Addr::new(255, 255, 255, 255) ||
+        self.ipv4_datas
+            .values()
+            .any(|ip_data| ip_data.net.prefix() < 31 && ip_data.net.broadcast() == ip)
     }
 
-    pub fn routing_table(&mut self) -> &mut RoutingTable {
-        &mut self.routing_table
+    /// Returns the limits and drop counters of fragment reassembly on this
+    /// interface.
+    pub fn reassembly(&self) -> &ipv4::ReassemblyControl {
+        &self.reassembly
     }
 
-    /// Attach an IPv4 network to an interface.
-    /// TODO: Deprecate and make the routing stuff better instead
-    pub fn add_ipv4(&mut self, interface: &Interface, ip_net: Ipv4Network) -> StackResult<()> {
-        self.interface(interface)?.add_ipv4(ip_net)?;
-        self.routing_table.add_route(ip_net, None, interface.clone());
-        Ok(())
+    /// Returns the number of malformed packets received on this interface,
+    /// by the layer they failed to parse at. See `malformed`.
+    pub fn parse_errors(&self) -> ParseErrors {
+        self.malformed.stats()
     }
 
-    pub fn ipv4_tx(&mut self,
-                   dst: Ipv4Addr)
-                   -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
-        if let Some((gw, interface)) = self.routing_table.route(dst) {
-            if let Some(stack_interface) = self.interfaces.get_mut(&interface) {
-                stack_interface.ipv4_tx(dst, gw)
-            } else {
-                Err(StackError::IllegalArgument)
-            }
-        } else {
-            Err(StackError::NoRouteToHost)
-        }
+    pub fn malformed_policy(&self) -> MalformedPolicy {
+        self.malformed.policy()
     }
 
-    pub fn icmp_tx(&mut self,
-                   dst_ip: Ipv4Addr)
-                   -> StackResult<IcmpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>> {
-        let ipv4_tx = self.ipv4_tx(dst_ip)?;
-        Ok(icmp::IcmpTx::new(ipv4_tx))
+    /// Returns the packets sent and received on this interface, by
+    /// protocol, and the lookups of its Arp cache. See `stats`.
+    pub fn stats(&self) -> StackStats {
+        let mut stats = self.counters.stats(&self.malformed.stats());
+        let (hits, misses) = self.arp_table.cache_stats();
+        stats.arp_cache_hits = hits;
+        stats.arp_cache_misses = misses;
+        stats
     }
 
-    pub fn icmp_listen<L>(&mut self,
-                          local_ip: Ipv4Addr,
-                          icmp_type: IcmpType,
-                          listener: L)
-                          -> io::Result<()>
-        where L: icmp::IcmpListener + 'static + Clone
-    {
-        if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
-            let msg = "Rips does not support listening to all interfaces yet".to_owned();
-            Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
-        } else {
-            let mut added_to_interface = false;
-            for stack_interface in self.interfaces.values_mut() {
-                let result = stack_interface.icmp_listen(local_ip, icmp_type, listener.clone());
-                added_to_interface |= result.is_ok();
-            }
-            if added_to_interface {
-                Ok(())
-            } else {
-                let msg = "Bind address does not exist in stack".to_owned();
-                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
-            }
+    /// Returns how long the Arp resolutions and Icmp echoes of this
+    /// interface took. See `stats`.
+    pub fn latencies(&self) -> LatencyStats {
+        LatencyStats {
+            arp_resolve: self.arp_table.resolve_times(),
+            icmp_echo: self.counters.echo_round_trips(),
         }
     }
 
-    pub fn udp_tx(&mut self,
-                  dst_ip: Ipv4Addr,
-                  src: u16,
-                  dst_port: u16)
-                  -> StackResult<UdpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>> {
-        let ipv4_tx = self.ipv4_tx(dst_ip)?;
-        Ok(udp::UdpTx::new(ipv4_tx, src, dst_port))
+    /// Sets what is done with the malformed packets received on this
+    /// interface. Usually called through `NetworkStack::set_malformed_policy`.
+    pub fn set_malformed_policy(&self, policy: MalformedPolicy) {
+        self.malformed.set_policy(policy);
     }
 
+    pub fn rx_checksums(&self) -> RxChecksums {
+        self.checksums.get()
+    }
+
+    /// Sets which checksums of the packets received on this interface are
+    /// verified. Usually called through `NetworkStack::set_rx_checksums`.
+    pub fn set_rx_checksums(&self, checksums: RxChecksums) {
+        self.checksums.set(checksums);
+    }
+
+    /// Returns what decides which packets received on this interface are
+    /// forwarded.
+    pub fn forwarding(&self) -> &ipv4::ForwardingControl {
+        &self.forwarding
+    }
+
+    /// Sets a program all received frames have to pass before they are
+    /// parsed, or removes it if `filter` is `None`. Frames it does not accept
+    /// are dropped silently. See `bpf`.
+    pub fn set_rx_filter(&self, filter: Option<bpf::Program>) {
+        *self.rx_filter.write().unwrap() = filter;
+    }
+
+    pub fn rx_filter(&self) -> Option<bpf::Program> {
+        self.rx_filter.read().unwrap().clone()
+    }
+
+    /// Sets whether frames not addressed to this interface are handled as if
+    /// they were. On by default. Frames are addressed to the interface if
+    /// they are sent to its MAC address, to the broadcast address or to a
+    /// multicast address it joined.
+    ///
+    /// pnet gives no control over the mode of its channels, and opens them in
+    /// promiscuous mode on Linux and Windows. So this only decides what rips
+    /// does with the frames that the channel delivers.
+    pub fn set_promiscuous(&self, promiscuous: bool) {
+        self.mac_filter.promiscuous.store(promiscuous, Ordering::SeqCst);
+    }
+
+    pub fn is_promiscuous(&self) -> bool {
+        self.mac_filter.promiscuous.load(Ordering::SeqCst)
+    }
+
+    /// Hands every received frame not addressed to this interface to
+    /// `monitor`, promiscuous or not, or stops if `monitor` is `None`. The
+    /// monitor is removed when it returns an error. Frames are seen before
+    /// the rx filter is applied.
+    pub fn set_monitor(&self, monitor: Option<Box<RxListener>>) {
+        *self.mac_filter.monitor.lock().unwrap() = monitor;
+    }
+
+    /// Writes every frame sent and received on this interface to a capture
+    /// file as `config` says, or stops capturing if `config` is `None`. A
+    /// capture running already is stopped first. See `pcap`.
+    pub fn set_capture(&self, config: Option<CaptureConfig>) -> StackResult<()> {
+        let capture = match config {
+            Some(config) => Some(Capture::open(config, &self.data.interface.name)?),
+            None => None,
+        };
+        self.capture.set(capture);
+        Ok(())
+    }
+
+    /// Returns the counters of the capture set with `set_capture`, if any.
+    pub fn capture_stats(&self) -> Option<CaptureStats> {
+        self.capture.stats()
+    }
+
+    /// Calls `observer` for every frame sent and received on this interface
+    /// from now on, until it is removed. See `observe`.
+    pub fn add_observer<O: Observer + 'static>(&self, observer: O) -> ObserverId {
+        self.observers.add(Arc::new(observer))
+    }
+
+    /// Removes an observer, returning it if it existed.
+    pub fn remove_observer(&self, id: ObserverId) -> Option<Arc<Observer>> {
+        self.observers.remove(id)
+    }
+
+    /// Logs the frames sent and received on this interface that `config`
+    /// selects, or stops if `config` is `None`. A dump running already is
+    /// replaced. See `dump`.
+    pub fn set_dump(&self, config: Option<DumpConfig>) {
+        let mut dump = self.dump.lock().unwrap();
+        if let Some(id) = dump.take() {
+            self.observers.remove(id);
+        }
+        *dump = config.map(|config| self.add_observer(Dump::new(config)));
+    }
+
+    /// Returns true if frames are logged, see `set_dump`.
+    pub fn is_dumping(&self) -> bool {
+        self.dump.lock().unwrap().is_some()
+    }
+
+    /// Controls access to this interface with `supplicant`, as on a port
+    /// protected by 802.1X, or stops if `supplicant` is `None`. Until the
+    /// supplicant authorizes the port, only its EAPOL frames are sent and
+    /// received, other frames received are dropped and sending fails with
+    /// `TxError::Unauthorized`. Traffic to the addresses of the interface
+    /// itself is not affected. See `eapol`.
+    pub fn set_supplicant(&self, supplicant: Option<Box<Supplicant>>) {
+        let mac = self.data.interface.mac;
+        self.port_access.set_supplicant(supplicant, mac, self.data.tx.clone());
+    }
+
+    pub fn has_supplicant(&self) -> bool {
+        self.port_access.has_supplicant()
+    }
+
+    /// Returns false while a supplicant keeps this interface unauthorized.
+    pub fn is_authorized(&self) -> bool {
+        self.port_access.is_authorized()
+    }
+
+    /// Starts receiving the frames sent to the multicast MAC address `mac`
+    /// while not promiscuous. Joins are counted, so everything that joined
+    /// has to leave again before the frames are dropped.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `mac` is not a multicast
+    /// address, or is the broadcast address.
+    pub fn join_multicast(&self, mac: MacAddr) -> StackResult<()> {
+        if mac.0 & 0x01 == 0 || mac == BROADCAST_MAC {
+            return Err(StackError::InvalidArgument(format!("{} is no multicast address", mac)));
+        }
+        *self.mac_filter.multicast.write().unwrap().entry(mac).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Leaves the multicast MAC address `mac`, joined with `join_multicast`.
+    ///
+    /// Fails with `StackError::NotFound` if `mac` was not joined.
+    pub fn leave_multicast(&self, mac: MacAddr) -> StackResult<()> {
+        let mut multicast = self.mac_filter.multicast.write().unwrap();
+        let left = match multicast.get_mut(&mac) {
+            Some(joins) => {
+                *joins -= 1;
+                *joins == 0
+            }
+            None => return Err(StackError::NotFound(format!("Multicast group {}", mac))),
+        };
+        if left {
+            multicast.remove(&mac);
+        }
+        Ok(())
+    }
+
+    /// Returns the multicast MAC addresses joined on this interface.
+    pub fn multicast_macs(&self) -> Vec<MacAddr> {
+        self.mac_filter.multicast.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Limits the rate of everything sent out on this interface, or removes
+    /// the limit if `limit` is `None`. Senders block until their packets fit
+    /// the limit, see `shaping`. Traffic to the addresses of the interface
+    /// itself is not limited.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.data.shaper.set_limit(limit);
+        self.link_watchers.notify(LinkChange::RateLimit(limit));
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.data.shaper.limit()
+    }
+
+    /// Puts everything sent out on this interface in egress queues scheduled
+    /// by `config`, or sends it directly again if `config` is `None`. Frames
+    /// still queued are sent before this returns. See `qos`.
+    pub fn set_qos(&mut self, config: Option<QosConfig>) {
+        let mut tx = self.data.tx.lock().unwrap();
+        if let Some(mut queues) = self.qos.take() {
+            let sender = queues.stop().unwrap();
+            tx.replace(sender);
+        }
+        if let Some(config) = config {
+            let mut queues = EgressQueues::with_pool(config, self.data.pool.clone());
+            queues.set_waiting(!tx.nonblocking);
+            let sender = tx.replace(queues.sender());
+            let thread = self.data.thread_config(ThreadKind::Tx);
+            queues.start(sender, self.data.shaper.clone(), tx.writability.clone(), &thread);
+            self.qos = Some(queues);
+        }
+        tx.state.queued.store(self.qos.is_some(), Ordering::SeqCst);
+    }
+
+    /// Makes sending on this interface fail with `TxError::WouldBlock`
+    /// instead of waiting or dropping the frame when it does not fit in the
+    /// buffer of the datalink or, with `set_qos`, its egress queue. Wait for
+    /// the interface to take more with `wait_writable` or `poll_writable`.
+    /// Senders still wait to keep to a rate limit set with `set_rate_limit`.
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        // Before locking, lets senders waiting for room in the queues fail
+        if let Some(ref queues) = self.qos {
+            queues.set_waiting(!nonblocking);
+        }
+        self.data.tx.lock().unwrap().nonblocking = nonblocking;
+    }
+
+    pub fn is_nonblocking(&self) -> bool {
+        self.data.tx.lock().unwrap().nonblocking
+    }
+
+    /// Waits until a send that failed with `TxError::WouldBlock` may succeed
+    /// when retried, or for at most `timeout`. Returns false on timeout.
+    pub fn wait_writable(&self, timeout: Duration) -> bool {
+        self.writability().wait(timeout)
+    }
+
+    /// Returns `Async::Ready` if sending may succeed, else makes `waker` be
+    /// called when a send that failed with `TxError::WouldBlock` should be
+    /// retried. See `reactor`.
+    pub fn poll_writable(&self, waker: &Waker) -> Async<()> {
+        self.writability().poll(waker)
+    }
+
+    fn writability(&self) -> Arc<Writability> {
+        self.data.tx.lock().unwrap().writability.clone()
+    }
+
+    pub fn qos(&self) -> Option<QosConfig> {
+        self.qos.as_ref().map(|queues| queues.config().clone())
+    }
+
+    /// Returns the counters of each egress queue, if `set_qos` is in effect.
+    pub fn qos_stats(&self) -> Option<Vec<QueueStats>> {
+        self.qos.as_ref().map(|queues| queues.stats())
+    }
+
+    /// Returns the counters of the rx workers, if the interface has several.
+    /// See `NetworkStackBuilder::rx_workers`.
+    pub fn rx_worker_stats(&self) -> Option<FanOutStats> {
+        self.fan_out.as_ref().map(|stats| *stats.lock().unwrap())
+    }
+
+    /// Copies out the state of this interface, which is in the VRF `vrf`.
+    fn snapshot(&self, vrf: Option<String>) -> InterfaceSnapshot {
+        let (mut arp, mut arp_pending) = {
+            let data = self.arp_table.data();
+            let data = data.lock().unwrap();
+            let arp = data.table.iter().map(|(ip, mac)| (*ip, *mac)).collect::<Vec<_>>();
+            let pending = data.listeners
+                .keys()
+                .chain(data.wakers.keys())
+                .filter(|ip| !data.table.contains_key(ip))
+                .cloned()
+                .collect::<HashSet<_>>();
+            (arp, pending.into_iter().collect::<Vec<_>>())
+        };
+        arp.sort_by_key(|&(ip, _)| ip);
+        arp_pending.sort();
+        let mut addresses = self.ipv4_datas.values().map(|ip_data| ip_data.net).collect::<Vec<_>>();
+        addresses.sort_by_key(|net| net.ip());
+        let mut listeners = Vec::new();
+        for (icmp_type, icmp_listeners) in self.icmp_wildcard_listeners.read().unwrap().iter() {
+            let any = Ipv4Addr::new(0, 0, 0, 0);
+            listeners.push(ListenerSnapshot::Icmp(any, *icmp_type, icmp_listeners.len()));
+        }
+        for (ip, ip_data) in &self.ipv4_datas {
+            for port in ip_data.udp_listeners.read().unwrap().keys() {
+                listeners.push(ListenerSnapshot::Udp(SocketAddrV4::new(*ip, *port)));
+            }
+            for (icmp_type, icmp_listeners) in ip_data.icmp_listeners.read().unwrap().iter() {
+                listeners.push(ListenerSnapshot::Icmp(*ip, *icmp_type, icmp_listeners.len()));
+            }
+        }
+        listeners.sort_by_key(|listener| match *listener {
+            ListenerSnapshot::Udp(addr) => (0, *addr.ip(), addr.port()),
+            ListenerSnapshot::Icmp(ip, icmp_type, _) => (1, ip, icmp_type.0 as u16),
+        });
+        let interface = self.interface();
+        InterfaceSnapshot {
+            name: interface.name.clone(),
+            mac: interface.mac,
+            mtu: self.mtu,
+            carrier: self.carrier,
+            vrf: vrf,
+            promiscuous: self.is_promiscuous(),
+            nonblocking: self.is_nonblocking(),
+            addresses: addresses,
+            arp: arp,
+            arp_pending: arp_pending,
+            listeners: listeners,
+            reassembly: self.reassembly.stats(),
+            parse_errors: self.malformed.stats(),
+            stats: self.stats(),
+            queues: self.qos_stats(),
+        }
+    }
+
+    /// Returns the channel of a VLAN sub-interface of this interface with the
+    /// identifier `vid`. Usually called through `NetworkStack::add_vlan`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `vid` is reserved and
+    /// `StackError::AlreadyExists` if it is already in use on this interface.
+    pub fn vlan_channel(&self, vid: u16) -> StackResult<EthernetChannel> {
+        let mut vlans = self.vlans.lock().unwrap();
+        if vid == 0 || vid > vlan::MAX_VID {
+            return Err(StackError::InvalidArgument(format!("VLAN {} is reserved", vid)));
+        }
+        if vlans.contains_key(&vid) {
+            return Err(StackError::AlreadyExists(format!("VLAN {}", vid)));
+        }
+        let (inject, frames) = mpsc::channel();
+        vlans.insert(vid, inject);
+        let sender = VlanSender::new(vid, self.data.tx.clone());
+        Ok(EthernetChannel(Box::new(sender), Box::new(ChannelReceiver::new(frames))))
+    }
+
+    /// Returns the VIDs of the VLAN sub-interfaces of this interface.
+    pub fn vlans(&self) -> Vec<u16> {
+        self.vlans.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Creates the channel of a MACVLAN on this interface with the MAC
+    /// address `mac`. Usually called through `NetworkStack::add_macvlan`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `mac` is a group address,
+    /// and `StackError::AlreadyExists` if it is the address of this interface
+    /// or already used by another MACVLAN on it.
+    pub fn macvlan_channel(&self, mac: MacAddr) -> StackResult<EthernetChannel> {
+        let mut macvlans = self.macvlans.lock().unwrap();
+        if mac.0 & 0x01 != 0 {
+            return Err(StackError::InvalidArgument(format!("{} is a group address", mac)));
+        }
+        if mac == self.data.interface.mac || macvlans.contains_key(&mac) {
+            return Err(StackError::AlreadyExists(format!("MAC address {}", mac)));
+        }
+        let (inject, frames) = mpsc::channel();
+        macvlans.insert(mac, inject);
+        let sender = MacvlanSender::new(mac, self.macvlans.clone(), self.data.tx.clone());
+        Ok(EthernetChannel(Box::new(sender), Box::new(ChannelReceiver::new(frames))))
+    }
+
+    /// Returns the MAC addresses of the MACVLANs on this interface.
+    pub fn macvlans(&self) -> Vec<MacAddr> {
+        self.macvlans.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the IPv4 addresses of this interface. The set is shared with
+    /// the interface and follows later changes to its addresses.
+    pub fn ipv4_addresses(&self) -> Arc<RwLock<HashSet<Ipv4Addr>>> {
+        self.data.ipv4_addresses.clone()
+    }
+
+    /// Returns all IPv4 networks attached to this interface.
+    pub fn ipv4_networks(&self) -> Vec<Ipv4Network> {
+        self.ipv4_datas.values().map(|ip_data| ip_data.net).collect()
+    }
+
+    /// Returns a channel receiving the MAC address of every other host seen
+    /// on this interface sending Arp packets from `ip`, or probing for `ip`.
+    /// Used for address conflict detection. Stop watching by dropping the
+    /// `Receiver`.
+    pub fn watch_arp(&self, ip: Ipv4Addr) -> Receiver<MacAddr> {
+        let (tx, rx) = mpsc::channel();
+        let mut arp_watchers = self.data.arp_watchers.lock().unwrap();
+        arp_watchers.entry(ip).or_insert_with(Vec::new).push(tx);
+        rx
+    }
+
+    /// Returns a channel receiving every change of the MTU, rate limit and
+    /// authorization of this interface, so senders can size what they send
+    /// before their tx-objects fail with `TxError::InvalidTx`. Stop watching
+    /// by dropping the `Receiver`.
+    pub fn watch_link(&self) -> Receiver<LinkChange> {
+        self.link_watchers.watch()
+    }
+
+    /// Returns a channel receiving a `ThreadError` when receiving on this
+    /// interface fails or one of its threads panics, after which the
+    /// interface no longer receives. Stop watching by dropping the
+    /// `Receiver`.
+    pub fn watch_errors(&self) -> Receiver<ThreadError> {
+        let (tx, rx) = mpsc::channel();
+        self.add_error_watcher(tx);
+        rx
+    }
+
+    fn add_error_watcher(&self, watcher: Sender<ThreadError>) {
+        self.data.error_watchers.lock().unwrap().push(watcher);
+    }
+
+    /// Returns true if this interface only handles the frames it receives
+    /// when polled. See `polled`.
+    pub fn is_polled(&self) -> bool {
+        self.poller.is_some()
+    }
+
+    /// Handles the frames received on a polled interface since the last call,
+    /// in the calling thread, and returns how many there were. Does nothing
+    /// on other interfaces. See `polled`.
+    pub fn poll(&mut self) -> usize {
+        let poller = match self.poller {
+            Some(ref mut poller) => poller,
+            None => return 0,
+        };
+        let mut count = 0;
+        while let Ok((time, frame)) = poller.frames.try_recv() {
+            match EthernetPacket::new(&frame) {
+                Some(eth_pkg) => {
+                    if let Err(e) = poller.interface_rx.recv(time, &eth_pkg) {
+                        rx::log_error(&e);
+                    }
+                }
+                None => {
+                    self.malformed.record(Layer::Ethernet, &RxError::InvalidLength, time, &frame)
+                }
+            }
+            // Handles Arp as the frame left it, before the next one
+            poller.thread.process_pending();
+            count += 1;
+        }
+        poller.thread.process_pending();
+        count
+    }
+
+    /// Handles `frame` as if it was received on this polled interface at
+    /// `time`, in the calling thread and without going through the datalink.
+    /// Returns how handling it failed. Fails with `RxError::Other` on other
+    /// interfaces. See `fuzz`.
+    pub fn inject(&mut self, time: SystemTime, frame: &[u8]) -> RxResult {
+        let poller = match self.poller {
+            Some(ref mut poller) => poller,
+            None => return Err(RxError::Other("Interface is not polled".to_owned())),
+        };
+        let result = match EthernetPacket::new(frame) {
+            Some(eth_pkg) => poller.interface_rx.recv(time, &eth_pkg),
+            None => {
+                self.malformed.record(Layer::Ethernet, &RxError::InvalidLength, time, frame);
+                Err(RxError::InvalidLength)
+            }
+        };
+        poller.thread.process_pending();
+        result
+    }
+
+    /// Stops this interface. Receiving stops first, then the supplicant, if
+    /// any, logs off, the egress queues are flushed and all tx-objects are
+    /// invalidated. Waits at most `timeout` for the threads of the interface
+    /// to quit and returns true if they did. Usually called through
+    /// `NetworkStack::shutdown`.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.rx_thread.stop();
+        self.teardown();
+        let stopped = self.thread_handle.shutdown(remaining(deadline));
+        self.rx_thread.wait(remaining(deadline)) && stopped
+    }
+
+    /// Sends what must be sent before going away and flushes everything
+    /// queued.
+    fn teardown(&mut self) {
+        let mac = self.data.interface.mac;
+        if let Err(e) = self.port_access.logoff(mac, self.data.tx.clone()) {
+            warn!("Unable to log off {}: {:?}", self.data.interface.name, e);
+        }
+        self.set_qos(None);
+        self.invalidate_tx();
+    }
+}
+
+/// Returns the time left until `deadline`, or zero if it has passed.
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if now < deadline {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+impl Drop for StackInterface {
+    fn drop(&mut self) {
+        self.invalidate_tx();
+    }
+}
+
+/// The result of a routing decision, cached per destination by
+/// `NetworkStack`.
+#[derive(Clone)]
+struct CachedRoute {
+    gw: Option<Ipv4Addr>,
+    interface: Interface,
+    src: Ipv4Addr,
+    mtu: Option<usize>,
+    /// `RoutingTable::version` when this entry was created
+    table_version: u64,
+    /// `StackInterface::config_version` of `interface` when this entry was
+    /// created
+    interface_version: u64,
+}
+
+/// A routing domain isolated from the main routing table of a
+/// `NetworkStack` and from all other VRFs.
+#[derive(Default)]
+struct Vrf {
+    routing_table: RoutingTable,
+    route_cache: HashMap<Ipv4Addr, CachedRoute>,
+}
+
+/// How a stack sets up the interfaces added to it.
+#[derive(Clone)]
+struct InterfaceOptions {
+    clock: Arc<Clock>,
+    mtu: usize,
+    timer_tick: Duration,
+    /// Prefix of the names of the threads of each interface, see `threads`
+    thread_prefix: String,
+    cpus: HashMap<ThreadKind, Vec<usize>>,
+    qos: Option<QosConfig>,
+    pool: BufferPool,
+    /// Threads handling the frames received, see `fanout`
+    rx_workers: usize,
+    /// The budget of the rx threads, if they busy poll
+    busy_poll: Option<usize>,
+    malformed_policy: MalformedPolicy,
+    rx_checksums: RxChecksums,
+}
+
+impl Default for InterfaceOptions {
+    fn default() -> InterfaceOptions {
+        InterfaceOptions {
+            clock: clock::system(),
+            mtu: DEFAULT_MTU,
+            timer_tick: Duration::from_millis(timer::TICK_MS),
+            thread_prefix: threads::DEFAULT_PREFIX.to_owned(),
+            cpus: HashMap::new(),
+            qos: None,
+            pool: BufferPool::default(),
+            rx_workers: 1,
+            busy_poll: None,
+            malformed_policy: MalformedPolicy::default(),
+            rx_checksums: RxChecksums::default(),
+        }
+    }
+}
+
+/// Creates a `NetworkStack` with other settings than the defaults of
+/// `NetworkStack::new`. Settings that only affect how interfaces are set up
+/// apply to the interfaces added to the stack later.
+///
+/// ```rust,ignore
+/// let stack = NetworkStack::builder()
+///     .mtu(1400)
+///     .local_port_range(49152, 65535)
+///     .thread_name("stack1")
+///     .build()?;
+/// ```
+pub struct NetworkStackBuilder {
+    polled: bool,
+    options: InterfaceOptions,
+    local_ports: (u16, u16),
+    reassembly_limits: ipv4::ReassemblyLimits,
+    rng: Option<Box<Rng + Send>>,
+}
+
+impl Default for NetworkStackBuilder {
+    fn default() -> NetworkStackBuilder {
+        NetworkStackBuilder::new()
+    }
+}
+
+impl NetworkStackBuilder {
+    pub fn new() -> NetworkStackBuilder {
+        NetworkStackBuilder {
+            polled: false,
+            options: InterfaceOptions::default(),
+            local_ports: (LOCAL_PORT_RANGE_START, LOCAL_PORT_RANGE_END),
+            reassembly_limits: ipv4::ReassemblyLimits::default(),
+            rng: None,
+        }
+    }
+
+    /// Builds a polled stack, see `NetworkStack::new_polled`.
+    pub fn polled(mut self, polled: bool) -> NetworkStackBuilder {
+        self.polled = polled;
+        self
+    }
+
+    /// The MTU interfaces start out with instead of `DEFAULT_MTU`.
+    pub fn mtu(mut self, mtu: usize) -> NetworkStackBuilder {
+        self.options.mtu = mtu;
+        self
+    }
+
+    /// Pick ports from `start` up to, but not including, `end` when binding
+    /// to port 0, instead of from `LOCAL_PORT_RANGE_START` to
+    /// `LOCAL_PORT_RANGE_END`.
+    pub fn local_port_range(mut self, start: u16, end: u16) -> NetworkStackBuilder {
+        self.local_ports = (start, end);
+        self
+    }
+
+    /// Names the threads of every interface after `prefix` instead of
+    /// `threads::DEFAULT_PREFIX`, such as `<prefix>-rx-eth0`.
+    pub fn thread_name<S: Into<String>>(mut self, prefix: S) -> NetworkStackBuilder {
+        self.options.thread_prefix = prefix.into();
+        self
+    }
+
+    /// Pins the threads of kind `kind` of every interface to `cpus`, or
+    /// lets them run anywhere again if `cpus` is empty. See `threads`.
+    pub fn pin_threads(mut self, kind: ThreadKind, cpus: Vec<usize>) -> NetworkStackBuilder {
+        self.options.cpus.insert(kind, cpus);
+        self
+    }
+
+    /// The length of a tick of the timer wheels of the interfaces instead of
+    /// `timer::TICK_MS`. Timeouts fire up to one tick late.
+    pub fn timer_tick(mut self, tick: Duration) -> NetworkStackBuilder {
+        self.options.timer_tick = tick;
+        self
+    }
+
+    /// Gives every interface egress queues scheduled by `config`, see
+    /// `StackInterface::set_qos`. With `QosConfig::fifo` every interface
+    /// sends from a TX thread of its own.
+    pub fn qos(mut self, config: QosConfig) -> NetworkStackBuilder {
+        self.options.qos = Some(config);
+        self
+    }
+
+    /// Takes the buffers for frames from `pool` instead of a pool of the
+    /// stack's own, such as to share one pool between stacks. See `pool`.
+    pub fn buffer_pool(mut self, pool: BufferPool) -> NetworkStackBuilder {
+        self.options.pool = pool;
+        self
+    }
+
+    /// Handles the frames received on every interface in `workers` threads
+    /// instead of the rx thread, keeping the frames of each flow in order.
+    /// See `fanout`. Has no effect on polled stacks.
+    pub fn rx_workers(mut self, workers: usize) -> NetworkStackBuilder {
+        self.options.rx_workers = workers;
+        self
+    }
+
+    /// Makes the rx thread of every interface spin on its receiver, only
+    /// yielding the CPU after `budget` polls in a row found no frame. Cuts
+    /// the latency of receiving for as much CPU, for receivers that return
+    /// right away when there is no frame. See `rx::spawn_busy_polling`.
+    pub fn busy_poll(mut self, budget: usize) -> NetworkStackBuilder {
+        self.options.busy_poll = Some(budget);
+        self
+    }
+
+    /// See `NetworkStack::set_malformed_policy`.
+    pub fn malformed_policy(mut self, policy: MalformedPolicy) -> NetworkStackBuilder {
+        self.options.malformed_policy = policy;
+        self
+    }
+
+    /// See `NetworkStack::set_rx_checksums`.
+    pub fn rx_checksums(mut self, checksums: RxChecksums) -> NetworkStackBuilder {
+        self.options.rx_checksums = checksums;
+        self
+    }
+
+    /// See `NetworkStack::set_clock`.
+    pub fn clock(mut self, clock: Arc<Clock>) -> NetworkStackBuilder {
+        self.options.clock = clock;
+        self
+    }
+
+    /// See `NetworkStack::set_rng`.
+    pub fn rng<R: Rng + Send + 'static>(mut self, rng: R) -> NetworkStackBuilder {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// See `NetworkStack::set_reassembly_limits`.
+    pub fn reassembly_limits(mut self, limits: ipv4::ReassemblyLimits) -> NetworkStackBuilder {
+        self.reassembly_limits = limits;
+        self
+    }
+
+    /// Creates the stack. Fails with `StackError::InvalidMtu` if the MTU is
+    /// not between `ipv4::MIN_MTU` and `MAX_MTU`, and with
+    /// `StackError::InvalidArgument` if the port range is empty or includes
+    /// port 0, or the timer tick or number of rx workers is zero.
+    pub fn build(self) -> StackResult<NetworkStack> {
+        let (start, end) = self.local_ports;
+        let mtu = self.options.mtu;
+        let tick = self.options.timer_tick;
+        if mtu < ipv4::MIN_MTU || mtu > MAX_MTU {
+            return Err(StackError::InvalidMtu(mtu));
+        }
+        let invalid = |msg: &str| Err(StackError::InvalidArgument(msg.to_owned()));
+        if start == 0 || start >= end {
+            return invalid("Empty or zero local port range");
+        }
+        if tick == Duration::new(0, 0) {
+            return invalid("Zero timer tick");
+        }
+        if self.options.rx_workers == 0 {
+            return invalid("No rx workers");
+        }
+        let mut stack = if self.polled {
+            NetworkStack::new_polled()
+        } else {
+            NetworkStack::new()
+        };
+        stack.options = self.options;
+        stack.local_ports = self.local_ports;
+        stack.reassembly_limits = self.reassembly_limits;
+        if let Some(rng) = self.rng {
+            stack.rng = Mutex::new(rng);
+        }
+        Ok(stack)
+    }
+}
+
+/// The main struct of this library, managing an entire TCP/IP stack. Takes
+/// care of ARP, routing tables, threads, TCP resends/fragmentation etc. Most
+/// of this is still unimplemented.
+pub struct NetworkStack {
+    interfaces: HashMap<Interface, StackInterface>,
+    routing_table: RoutingTable,
+    route_cache: HashMap<Ipv4Addr, CachedRoute>,
+    path_mtus: HashMap<Ipv4Addr, usize>,
+    vrfs: HashMap<String, Vrf>,
+    interface_vrfs: HashMap<Interface, String>,
+    reassembly_limits: ipv4::ReassemblyLimits,
+    forwarding: Option<Sender<ipv4::ForwardedPacket>>,
+    conntrack: Arc<conntrack::ConnTrack>,
+    firewall: Arc<Firewall>,
+    /// Added to every interface, see `watch_errors`
+    error_watchers: Vec<Sender<ThreadError>>,
+    /// Shared with every interface, see `icmp_listen`
+    icmp_wildcard_listeners: Arc<RwLock<icmp::IcmpListenerLookup>>,
+    /// Woken up by the frames received on the interfaces of a polled stack,
+    /// see `new_polled`
+    wakeup: Option<(Sender<()>, Receiver<()>)>,
+    /// Given to every interface, the clock can be changed with `set_clock`
+    options: InterfaceOptions,
+    /// Picks local ports, see `set_rng`
+    rng: Mutex<Box<Rng + Send>>,
+    /// Ports picked when binding to port 0, the end is not included
+    local_ports: (u16, u16),
+    /// Looks up the host names given to sockets, see `set_resolver`
+    resolver: Option<Resolver>,
+}
+
+impl Default for NetworkStack {
+    fn default() -> NetworkStack {
+        NetworkStack::new()
+    }
+}
+
+impl NetworkStack {
+    pub fn new() -> NetworkStack {
+        NetworkStack {
+            interfaces: HashMap::new(),
+            routing_table: RoutingTable::new(),
+            route_cache: HashMap::new(),
+            path_mtus: HashMap::new(),
+            vrfs: HashMap::new(),
+            interface_vrfs: HashMap::new(),
+            reassembly_limits: ipv4::ReassemblyLimits::default(),
+            conntrack: Arc::new(conntrack::ConnTrack::default()),
+            firewall: Arc::new(Firewall::default()),
+            forwarding: None,
+            error_watchers: Vec::new(),
+            icmp_wildcard_listeners: Arc::new(RwLock::new(HashMap::new())),
+            wakeup: None,
+            options: InterfaceOptions::default(),
+            rng: Mutex::new(Box::new(rand::thread_rng().gen::<Isaac64Rng>())),
+            local_ports: (LOCAL_PORT_RANGE_START, LOCAL_PORT_RANGE_END),
+            resolver: None,
+        }
+    }
+
+    /// Returns a builder for a stack configured differently than by `new`.
+    pub fn builder() -> NetworkStackBuilder {
+        NetworkStackBuilder::new()
+    }
+
+    /// Creates a stack managing only the OS interface `name`, configured
+    /// like the host: with the MTU, IPv4 addresses and routes, including the
+    /// default route through the gateway, that the OS has for it. See
+    /// `host::import` for how the configuration is read. Fails with
+    /// `StackError::NoSuchInterface` if there is no interface `name` with a
+    /// MAC address.
+    pub fn from_os_interface(name: &str) -> StackResult<NetworkStack> {
+        let os_interface = datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == name);
+        let os_interface = match os_interface {
+            Some(os_interface) => os_interface,
+            None => return Err(StackError::NoSuchInterface(name.to_owned())),
+        };
+        let interface = ::convert_interface(&os_interface)
+            .map_err(|_| StackError::NoSuchInterface(name.to_owned()))?;
+        let mut stack = NetworkStack::new();
+        ::add_os_interface(&mut stack, &os_interface, interface)?;
+        host::import(&mut stack)?;
+        Ok(stack)
+    }
+
+    /// Creates a stack handling nothing in threads of its own, but only when
+    /// `poll` or `poll_timeout` is called, for example from an existing event
+    /// loop. All interfaces added to it are polled, see
+    /// `StackInterface::polled`. Handling everything in one thread also
+    /// makes tests deterministic.
+    pub fn new_polled() -> NetworkStack {
+        NetworkStack { wakeup: Some(mpsc::channel()), ..NetworkStack::new() }
+    }
+
+    pub fn is_polled(&self) -> bool {
+        self.wakeup.is_some()
+    }
+
+    /// Makes the interfaces of this stack take the time from `clock` instead
+    /// of the system, for their timers and fragment reassembly. See `clock`.
+    ///
+    /// Fails with `StackError::InvalidState` if interfaces were already
+    /// added, they keep the clock they were created with.
+    pub fn set_clock(&mut self, clock: Arc<Clock>) -> StackResult<()> {
+        if !self.interfaces.is_empty() {
+            return Err(StackError::InvalidState("Interfaces were added already".to_owned()));
+        }
+        self.options.clock = clock;
+        Ok(())
+    }
+
+    pub fn clock(&self) -> Arc<Clock> {
+        self.options.clock.clone()
+    }
+
+    /// The pool the interfaces of the stack take the buffers for frames
+    /// from, for its `PoolStats`. See `pool`.
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.options.pool
+    }
+
+    /// Makes the stack draw the random numbers it needs, such as the ports
+    /// picked when binding to port 0, from `rng` instead of a randomly seeded
+    /// generator. With a generator created from a fixed seed, such as a
+    /// `rand::XorShiftRng`, fuzzing runs and bugs can be reproduced.
+    pub fn set_rng<R: Rng + Send + 'static>(&mut self, rng: R) {
+        *self.rng.lock().unwrap() = Box::new(rng);
+    }
+
+    /// Returns a random value from the generator of the stack, see `set_rng`.
+    pub fn random<T: Rand>(&self) -> T {
+        self.rng.lock().unwrap().gen()
+    }
+
+    /// Returns a random port of the local port range, see
+    /// `NetworkStackBuilder::local_port_range`, from the generator of the
+    /// stack. For protocols picking their ports themselves, like SCTP.
+    pub fn random_local_port(&self) -> u16 {
+        let (start, end) = self.local_ports;
+        let mut rng = self.rng.lock().unwrap();
+        Range::new(start, end).ind_sample(&mut *rng)
+    }
+
+    /// Handles the frames received on all interfaces of a polled stack since
+    /// the last call, and returns how many there were.
+    pub fn poll(&mut self) -> usize {
+        if let Some((_, ref wakeup)) = self.wakeup {
+            while wakeup.try_recv().is_ok() {}
+        }
+        self.interfaces.values_mut().map(|stack_interface| stack_interface.poll()).sum()
+    }
+
+    /// Same as `poll`, but waits at most `timeout` for a frame if none was
+    /// received yet.
+    pub fn poll_timeout(&mut self, timeout: Duration) -> usize {
+        let count = self.poll();
+        if count > 0 {
+            return count;
+        }
+        let woken = match self.wakeup {
+            Some((_, ref wakeup)) => wakeup.recv_timeout(timeout).is_ok(),
+            None => false,
+        };
+        if woken {
+            self.poll()
+        } else {
+            0
+        }
+    }
+
+    pub fn add_interface(&mut self,
+                         interface: Interface,
+                         channel: EthernetChannel)
+                         -> StackResult<()> {
+        match self.interfaces.entry(interface) {
+            Entry::Occupied(entry) => Err(StackError::InterfaceExists(entry.key().name.clone())),
+            Entry::Vacant(entry) => {
+                let interface = entry.key().clone();
+                let firewall = self.firewall.clone();
+                let wakeup = self.wakeup.as_ref().map(|&(ref wakeup, _)| wakeup.clone());
+                let mut stack_interface =
+                    StackInterface::build(interface, channel, firewall, wakeup, &self.options);
+                if self.options.qos.is_some() {
+                    stack_interface.set_qos(self.options.qos.clone());
+                }
+                stack_interface.reassembly().set_limits(self.reassembly_limits);
+                stack_interface.forwarding().set_queue(self.forwarding.clone());
+                for watcher in &self.error_watchers {
+                    stack_interface.add_error_watcher(watcher.clone());
+                }
+                stack_interface.icmp_wildcard_listeners = self.icmp_wildcard_listeners.clone();
+                entry.insert(stack_interface);
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds an interface named `name` sending and receiving through the
+    /// backend `datalink`, with its MAC address and, if it knows it, its MTU.
+    /// See `datalink`. Fails with `StackError::InvalidMtu` if the MTU is not
+    /// one `StackInterface::set_mtu` takes.
+    pub fn add_datalink(&mut self, name: &str, datalink: Arc<Datalink>) -> StackResult<Interface> {
+        let interface = Interface::new(name.to_owned(), datalink.mac());
+        let mtu = datalink.mtu();
+        if let Some(mtu) = mtu {
+            if mtu < ipv4::MIN_MTU || mtu > MAX_MTU {
+                return Err(StackError::InvalidMtu(mtu));
+            }
+        }
+        let channel = ::datalink::channel_with_pool(datalink, self.options.pool.clone());
+        self.add_interface(interface.clone(), channel)?;
+        if let Some(mtu) = mtu {
+            self.interface(&interface)?.set_mtu(mtu)?;
+        }
+        Ok(interface)
+    }
+
+    /// Adds an interface named `name` exchanging IP packets through `link`,
+    /// such as a TUN device, with its MTU if it knows it. The interface has
+    /// no MAC address and does no Arp. See `tun`.
+    pub fn add_tun(&mut self, name: &str, link: Arc<IpLink>) -> StackResult<Interface> {
+        let interface = Interface::new(name.to_owned(), tun::MAC);
+        let mtu = link.mtu();
+        if let Some(mtu) = mtu {
+            if mtu < ipv4::MIN_MTU || mtu > MAX_MTU {
+                return Err(StackError::InvalidMtu(mtu));
+            }
+        }
+        let channel = tun::channel_with_pool(link, self.options.pool.clone());
+        self.add_interface(interface.clone(), channel)?;
+        let stack_interface = self.interfaces.get_mut(&interface).unwrap();
+        stack_interface.layer3 = true;
+        if let Some(mtu) = mtu {
+            stack_interface.set_mtu(mtu)?;
+        }
+        Ok(interface)
+    }
+
+    /// Adds a VLAN sub-interface named `name` on top of `parent`, sending and
+    /// receiving the frames tagged with `vid` on it. The new interface has
+    /// the MAC address and MTU of `parent`. See `vlan`.
+    pub fn add_vlan(&mut self,
+                    parent: &Interface,
+                    name: &str,
+                    vid: u16)
+                    -> StackResult<Interface> {
+        if self.interfaces.keys().any(|interface| interface.name == name) {
+            return Err(StackError::InterfaceExists(name.to_owned()));
+        }
+        let (channel, mtu) = {
+            let parent = self.interface(parent)?;
+            (parent.vlan_channel(vid)?, parent.get_mtu())
+        };
+        let interface = Interface {
+            name: name.to_owned(),
+            mac: parent.mac,
+        };
+        self.add_interface(interface.clone(), channel)?;
+        self.interface(&interface)?.set_mtu(mtu)?;
+        Ok(interface)
+    }
+
+    /// Adds a MACVLAN named `name` with the MAC address `mac` on top of
+    /// `parent`, receiving the frames to `mac` instead of `parent`. The new
+    /// interface has the MTU of `parent`. See `macvlan`.
+    pub fn add_macvlan(&mut self,
+                       parent: &Interface,
+                       name: &str,
+                       mac: MacAddr)
+                       -> StackResult<Interface> {
+        if self.interfaces.keys().any(|interface| interface.name == name) {
+            return Err(StackError::InterfaceExists(name.to_owned()));
+        }
+        let (channel, mtu) = {
+            let parent = self.interface(parent)?;
+            (parent.macvlan_channel(mac)?, parent.get_mtu())
+        };
+        let interface = Interface {
+            name: name.to_owned(),
+            mac: mac,
+        };
+        self.add_interface(interface.clone(), channel)?;
+        self.interface(&interface)?.set_mtu(mtu)?;
+        Ok(interface)
+    }
+
+    /// Registers `listener` for the frames with its `EtherType` received on
+    /// `interface`. See `StackInterface::ethernet_listen`.
+    pub fn ethernet_listen(&mut self,
+                           interface: &Interface,
+                           listener: Box<EthernetListener>)
+                           -> StackResult<()> {
+        self.interface(interface)?.ethernet_listen(listener)
+    }
+
+    /// Registers `listener` for the 802.3 frames with its LLC SAP or SNAP
+    /// protocol received on `interface`. See `StackInterface::llc_listen`.
+    pub fn llc_listen(&mut self,
+                      interface: &Interface,
+                      listener: Box<LlcListener>)
+                      -> StackResult<()> {
+        self.interface(interface)?.llc_listen(listener)
+    }
+
+    /// Returns a channel receiving the `ThreadError`s of all interfaces of
+    /// the stack, including the ones added later, so applications can notice
+    /// an interface no longer receives and recreate it. Stop watching by
+    /// dropping the `Receiver`. See `StackInterface::watch_errors`.
+    pub fn watch_errors(&mut self) -> Receiver<ThreadError> {
+        let (tx, rx) = mpsc::channel();
+        for stack_interface in self.interfaces.values() {
+            stack_interface.add_error_watcher(tx.clone());
+        }
+        self.error_watchers.push(tx);
+        rx
+    }
+
+    pub fn interfaces(&self) -> Vec<Interface> {
+        self.interfaces.keys().cloned().collect()
+    }
+
+    pub fn interface(&mut self, interface: &Interface) -> StackResult<&mut StackInterface> {
+        match self.interfaces.get_mut(interface) {
+            Some(i) => Ok(i),
+            None => Err(StackError::NoSuchInterface(interface.name.clone())),
+        }
+    }
+
+    pub fn interface_from_name(&mut self, name: &str) -> StackResult<&mut StackInterface> {
+        for (interface, stack_interface) in &mut self.interfaces {
+            if interface.name == name {
+                return Ok(stack_interface);
+            }
+        }
+        Err(StackError::NoSuchInterface(name.to_owned()))
+    }
+
+    pub fn routing_table(&mut self) -> &mut RoutingTable {
+        &mut self.routing_table
+    }
+
+    /// Sets the memory limits for reassembling fragmented packets. They
+    /// apply to each interface separately, including ones added later.
+    pub fn set_reassembly_limits(&mut self, limits: ipv4::ReassemblyLimits) {
+        self.reassembly_limits = limits;
+        for stack_interface in self.interfaces.values() {
+            stack_interface.reassembly().set_limits(limits);
+        }
+    }
+
+    pub fn reassembly_limits(&self) -> ipv4::ReassemblyLimits {
+        self.reassembly_limits
+    }
+
+    /// Returns the number of packets discarded during reassembly, summed
+    /// over all interfaces.
+    pub fn reassembly_stats(&self) -> ipv4::ReassemblyStats {
+        let mut stats = ipv4::ReassemblyStats::default();
+        for stack_interface in self.interfaces.values() {
+            stats.add(&stack_interface.reassembly().stats());
+        }
+        stats
+    }
+
+    /// Sets what is done with the malformed packets received on each
+    /// interface, including ones added later, instead of
+    /// `MalformedPolicy::default`. See `malformed`.
+    pub fn set_malformed_policy(&mut self, policy: MalformedPolicy) {
+        for stack_interface in self.interfaces.values() {
+            stack_interface.set_malformed_policy(policy.clone());
+        }
+        self.options.malformed_policy = policy;
+    }
+
+    pub fn malformed_policy(&self) -> MalformedPolicy {
+        self.options.malformed_policy.clone()
+    }
+
+    /// Sets which checksums of the packets received on each interface,
+    /// including ones added later, are verified. All of them are by
+    /// default. Packets with invalid checksums are dropped and counted as
+    /// malformed, see `malformed`.
+    pub fn set_rx_checksums(&mut self, checksums: RxChecksums) {
+        for stack_interface in self.interfaces.values() {
+            stack_interface.set_rx_checksums(checksums);
+        }
+        self.options.rx_checksums = checksums;
+    }
+
+    pub fn rx_checksums(&self) -> RxChecksums {
+        self.options.rx_checksums
+    }
+
+    /// Returns the number of malformed packets received, by the layer they
+    /// failed to parse at, summed over all interfaces.
+    pub fn parse_errors(&self) -> ParseErrors {
+        let mut stats = ParseErrors::default();
+        for stack_interface in self.interfaces.values() {
+            stats.add(&stack_interface.parse_errors());
+        }
+        stats
+    }
+
+    /// Returns the packets sent and received by protocol, summed over all
+    /// interfaces. See `stats`.
+    pub fn stats(&self) -> StackStats {
+        let mut stats = StackStats::default();
+        for stack_interface in self.interfaces.values() {
+            stats.add(&stack_interface.stats());
+        }
+        stats
+    }
+
+    /// Returns how long the Arp resolutions and Icmp echoes took, over all
+    /// interfaces. See `stats`.
+    pub fn latencies(&self) -> LatencyStats {
+        let mut latencies = LatencyStats::default();
+        for stack_interface in self.interfaces.values() {
+            latencies.add(&stack_interface.latencies());
+        }
+        latencies
+    }
+
+    /// Returns a copy of the state of this stack for diagnostics. See
+    /// `snapshot`.
+    pub fn snapshot(&self) -> StackSnapshot {
+        let mut interfaces = self.interfaces
+            .iter()
+            .map(|(interface, stack_interface)| {
+                stack_interface.snapshot(self.interface_vrf(interface).map(|vrf| vrf.to_owned()))
+            })
+            .collect::<Vec<_>>();
+        interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut vrfs = self.vrfs
+            .iter()
+            .map(|(name, vrf)| {
+                VrfSnapshot {
+                    name: name.clone(),
+                    routes: vrf.routing_table.routes(),
+                }
+            })
+            .collect::<Vec<_>>();
+        vrfs.sort_by(|a, b| a.name.cmp(&b.name));
+        StackSnapshot {
+            interfaces: interfaces,
+            routes: self.routing_table.routes(),
+            vrfs: vrfs,
+            forwarding: self.is_forwarding(),
+            connections: self.conntrack.connections().len(),
+        }
+    }
+
+    /// Enables forwarding of received packets that are not for this host.
+    /// They are sent to the returned `Receiver` from where they are expected
+    /// to be passed to `forward`, see `ipv4::spawn_forwarding`.
+    pub fn enable_forwarding(&mut self) -> Receiver<ipv4::ForwardedPacket> {
+        let (tx, rx) = mpsc::channel();
+        for stack_interface in self.interfaces.values() {
+            stack_interface.forwarding().set_queue(Some(tx.clone()));
+        }
+        self.forwarding = Some(tx);
+        rx
+    }
+
+    /// Disables forwarding. The `Receiver` returned by `enable_forwarding`
+    /// is disconnected once the packets already queued are drained.
+    pub fn disable_forwarding(&mut self) {
+        for stack_interface in self.interfaces.values() {
+            stack_interface.forwarding().set_queue(None);
+        }
+        self.forwarding = None;
+    }
+
+    pub fn is_forwarding(&self) -> bool {
+        self.forwarding.is_some()
+    }
+
+    /// Shuts the stack down instead of leaving it to the order its parts are
+    /// dropped in. Forwarding is disabled and receiving stops on all
+    /// interfaces, then each one is torn down and its pending frames sent as
+    /// by `StackInterface::shutdown`. All interfaces are removed from the
+    /// stack, so tx-objects fail with `TxError::InvalidTx` from now on.
+    ///
+    /// Waits at most `timeout` for the threads of all interfaces, and fails
+    /// with an `io::ErrorKind::TimedOut` error if some are still running.
+    /// Rx threads notice they should stop when their receiver returns, so
+    /// ones reading from a receiver without a read timeout must see another
+    /// frame first. The receivers of `default_stack` have one.
+    pub fn shutdown(&mut self, timeout: Duration) -> StackResult<()> {
+        let deadline = Instant::now() + timeout;
+        self.disable_forwarding();
+        for stack_interface in self.interfaces.values() {
+            stack_interface.rx_thread.stop();
+        }
+        let mut all_stopped = true;
+        for (interface, mut stack_interface) in self.interfaces.drain() {
+            if !stack_interface.shutdown(remaining(deadline)) {
+                warn!("Threads of {} did not quit in time", interface.name);
+                all_stopped = false;
+            }
+        }
+        self.route_cache.clear();
+        for vrf in self.vrfs.values_mut() {
+            vrf.route_cache.clear();
+        }
+        if all_stopped {
+            Ok(())
+        } else {
+            let msg = "Threads still running after shutdown";
+            Err(StackError::IoError(io::Error::new(io::ErrorKind::TimedOut, msg)))
+        }
+    }
+
+    /// Returns the connection tracking table of the stack. All forwarded
+    /// traffic passes through it, traffic to and from this host does not.
+    pub fn conntrack(&self) -> Arc<conntrack::ConnTrack> {
+        self.conntrack.clone()
+    }
+
+    /// Returns the firewall filtering all IPv4 traffic of the stack. Rules
+    /// can be changed at any time, also while traffic is flowing.
+    pub fn firewall(&self) -> Arc<Firewall> {
+        self.firewall.clone()
+    }
+
+    /// Makes `resolver` look up the host names given to the sockets of the
+    /// stack, such as to `UdpSocket::bind` and `UdpSocket::send_to`, or
+    /// stops looking names up if it's `None`. See `dns::ToStackAddr`. The
+    /// stack keeps `Resolver::weak` of it, so it does not keep itself alive.
+    pub fn set_resolver(&mut self, resolver: Option<Resolver>) {
+        self.resolver = resolver.map(|resolver| resolver.weak());
+    }
+
+    pub fn resolver(&self) -> Option<Resolver> {
+        self.resolver.clone()
+    }
+
+    /// Routes `packet` towards its destination, in the VRF of the interface
+    /// it arrived on. The TTL is decremented and packets whose TTL runs out
+    /// are dropped. Packets are tracked in `conntrack` and pass the `Forward`
+    /// hook of the firewall before packets going out on an interface with
+    /// NAT are translated, see `add_nat`.
+    ///
+    /// Packets larger than the MTU of the outgoing interface are dropped
+    /// with `TxError::TooLargePayload`, they are not fragmented.
+    pub fn forward(&mut self, packet: ipv4::ForwardedPacket) -> StackResult<()> {
+        let mut data = packet.packet;
+        let dst = {
+            let invalid = |msg: &str| Err(StackError::InvalidArgument(msg.to_owned()));
+            let mut ip_pkg = match MutableIpv4Packet::new(&mut data) {
+                Some(ip_pkg) => ip_pkg,
+                None => return invalid("Truncated IPv4 packet"),
+            };
+            let ttl = ip_pkg.get_ttl();
+            if ttl <= 1 {
+                return invalid("TTL expired");
+            }
+            ip_pkg.set_ttl(ttl - 1);
+            // The TTL shares its word with the protocol
+            let protocol = ip_pkg.get_next_level_protocol().0;
+            let csum = checksum::update(ip_pkg.get_checksum(),
+                                        &[ttl, protocol],
+                                        &[ttl - 1, protocol]);
+            ip_pkg.set_checksum(csum);
+            ip_pkg.get_destination()
+        };
+        let state = self.conntrack.track(&data);
+        let vrf = packet.interface
+            .as_ref()
+            .and_then(|interface| self.interface_vrf(interface))
+            .map(|vrf| vrf.to_owned());
+        let route = self.route(vrf.as_ref().map(|vrf| vrf.as_str()), dst)?;
+        let info = firewall::PacketInfo {
+            in_interface: packet.interface.as_ref(),
+            out_interface: Some(&route.interface),
+            state: Some(state),
+        };
+        if !self.firewall.filter(Hook::Forward, &info, &mut data) {
+            return Err(StackError::TxError(TxError::Filtered));
+        }
+        let stack_interface = match self.interfaces.get_mut(&route.interface) {
+            Some(stack_interface) => stack_interface,
+            None => return Err(StackError::NoSuchInterface(route.interface.name.clone())),
+        };
+        if let Some(nat) = stack_interface.forwarding().nat() {
+            if !stack_interface.has_ipv4(dst) && !nat.translate_outbound(&mut data) {
+                let msg = "Packet can not be translated by NAT".to_owned();
+                return Err(StackError::InvalidArgument(msg));
+            }
+        }
+        if route.mtu.map_or(false, |mtu| data.len() > mtu) {
+            return Err(StackError::TxError(TxError::TooLargePayload));
+        }
+        stack_interface.send_ipv4_packet(route.src, route.gw.unwrap_or(dst), &data)
+    }
+
+    /// Masquerades the traffic forwarded out on `interface` behind its first
+    /// IPv4 address, translating the return traffic back. Only traffic
+    /// forwarded by this stack is translated. Mappings expire with their
+    /// connection in `conntrack`. Returns the NAT, for inspecting its
+    /// mappings.
+    pub fn add_nat(&mut self, interface: &Interface) -> StackResult<Arc<nat::Nat>> {
+        let conntrack = self.conntrack.clone();
+        let stack_interface = self.interface(interface)?;
+        let external_ip = match stack_interface.ipv4_networks.read().unwrap().first() {
+            Some(net) => net.ip(),
+            None => {
+                let msg = format!("{} has no IPv4 address", interface.name);
+                return Err(StackError::InvalidState(msg));
+            }
+        };
+        let nat = Arc::new(nat::Nat::new(external_ip, conntrack));
+        stack_interface.forwarding().set_nat(Some(nat.clone()));
+        Ok(nat)
+    }
+
+    /// Stops masquerading traffic out on `interface`. Existing mappings are
+    /// forgotten.
+    pub fn remove_nat(&mut self, interface: &Interface) -> StackResult<()> {
+        self.interface(interface)?.forwarding().set_nat(None);
+        Ok(())
+    }
+
+    /// Adds a route to the routing table, replacing any existing route to
+    /// the same network. Unlike modifying the table directly via
+    /// `routing_table()` this invalidates all existing tx-objects so they
+    /// pick up the new route.
+    pub fn add_route(&mut self,
+                     net: Ipv4Network,
+                     gw: Option<Ipv4Addr>,
+                     interface: Interface)
+                     -> Option<RouteEntry> {
+        let old_entry = self.interface_routing_table(&interface).add_route(net, gw, interface);
+        self.invalidate_tx();
+        old_entry
+    }
+
+    /// Same as `add_route` but with an explicit route metric.
+    pub fn add_route_with_metric(&mut self,
+                                 net: Ipv4Network,
+                                 gw: Option<Ipv4Addr>,
+                                 interface: Interface,
+                                 metric: u32)
+                                 -> Option<RouteEntry> {
+        let old_entry = self.interface_routing_table(&interface)
+            .add_route_with_metric(net, gw, interface, metric);
+        self.invalidate_tx();
+        old_entry
+    }
+
+    /// Adds a route added by `origin` and invalidates all existing
+    /// tx-objects. See `RoutingTable::add_route_with_origin`.
+    pub fn add_route_with_origin(&mut self,
+                                 net: Ipv4Network,
+                                 gw: Option<Ipv4Addr>,
+                                 interface: Interface,
+                                 metric: u32,
+                                 origin: RouteOrigin)
+                                 -> StackResult<Option<RouteEntry>> {
+        let old_entry = try!(self.interface_routing_table(&interface)
+            .add_route_with_origin(net, gw, interface, metric, origin));
+        self.invalidate_tx();
+        Ok(old_entry)
+    }
+
+    /// Sets the default route of the routing table and invalidates all
+    /// existing tx-objects. See `RoutingTable::set_default_route`.
+    pub fn set_default_route(&mut self,
+                             gw: Ipv4Addr,
+                             interface: Interface)
+                             -> StackResult<Option<RouteEntry>> {
+        let old_entry = self.interface_routing_table(&interface).set_default_route(gw, interface)?;
+        self.invalidate_tx();
+        Ok(old_entry)
+    }
+
+    /// Adds one of several default routes and invalidates all existing
+    /// tx-objects. See `RoutingTable::add_default_route`.
+    pub fn add_default_route(&mut self,
+                             gw: Ipv4Addr,
+                             interface: Interface,
+                             metric: u32)
+                             -> StackResult<Option<RouteEntry>> {
+        let old_entry = self.interface_routing_table(&interface)
+            .add_default_route(gw, interface, metric)?;
+        self.invalidate_tx();
+        Ok(old_entry)
+    }
+
+    /// Withdraws the routes through `gw` in the routing table and those of
+    /// all VRFs while `up` is false, or restores them, see
+    /// `RoutingTable::set_gateway_up`. Existing tx-objects are invalidated
+    /// if this changed anything, which is returned.
+    pub fn set_gateway_up(&mut self, gw: Ipv4Addr, up: bool) -> bool {
+        let mut changed = self.routing_table.set_gateway_up(gw, up);
+        for vrf in self.vrfs.values_mut() {
+            changed |= vrf.routing_table.set_gateway_up(gw, up);
+        }
+        if changed {
+            info!("Gateway {} is {}", gw, if up { "up" } else { "down" });
+            self.invalidate_tx();
+        }
+        changed
+    }
+
+    /// Removes the route to `net` from the routing table and invalidates all
+    /// existing tx-objects.
+    pub fn remove_route(&mut self, net: Ipv4Network) -> Option<RouteEntry> {
+        let old_entry = self.routing_table.remove_route(net);
+        if old_entry.is_some() {
+            self.invalidate_tx();
+        }
+        old_entry
+    }
+
+    /// Same as `remove_route` but only removes the route with the given
+    /// metric.
+    pub fn remove_route_with_metric(&mut self,
+                                    net: Ipv4Network,
+                                    metric: u32)
+                                    -> Option<RouteEntry> {
+        let old_entry = self.routing_table.remove_route_with_metric(net, metric);
+        if old_entry.is_some() {
+            self.invalidate_tx();
+        }
+        old_entry
+    }
+
+    /// Removes the route to `net` with `metric` from the routing table
+    /// `interface` uses, but only if it was added by `origin`. Invalidates
+    /// all existing tx-objects if a route was removed.
+    pub fn remove_route_with_origin(&mut self,
+                                    net: Ipv4Network,
+                                    metric: u32,
+                                    interface: &Interface,
+                                    origin: RouteOrigin)
+                                    -> Option<RouteEntry> {
+        let old_entry = self.interface_routing_table(interface)
+            .remove_route_with_origin(net, metric, origin);
+        if old_entry.is_some() {
+            self.invalidate_tx();
+        }
+        old_entry
+    }
+
+    fn invalidate_tx(&self) {
+        for stack_interface in self.interfaces.values() {
+            stack_interface.invalidate_tx();
+        }
+    }
+
+    /// Tells the stack that `interface` lost its carrier, when `carrier` is
+    /// false, or got it back. Without carrier all routes going out on the
+    /// interface are withdrawn, so traffic fails over to other routes to the
+    /// same destinations, until they are restored with the carrier. All
+    /// existing tx-objects are invalidated either way. Called by a
+    /// `carrier::CarrierMonitor`, or by applications learning about the link
+    /// state some other way.
+    pub fn set_carrier(&mut self, interface: &Interface, carrier: bool) -> StackResult<()> {
+        let changed = self.interface(interface)?.set_carrier(carrier);
+        if changed {
+            info!("Carrier of {} is {}", interface.name, if carrier { "up" } else { "down" });
+            self.interface_routing_table(interface).set_link_up(interface, carrier);
+            self.invalidate_tx();
+        }
+        Ok(())
+    }
+
+    /// Attach an IPv4 network to an interface.
+    /// TODO: Deprecate and make the routing stuff better instead
+    pub fn add_ipv4(&mut self, interface: &Interface, ip_net: Ipv4Network) -> StackResult<()> {
+        self.interface(interface)?.add_ipv4(ip_net)?;
+        self.interface_routing_table(interface).add_route(ip_net, None, interface.clone());
+        Ok(())
+    }
+
+    /// Detaches the address `ip` from an interface, the reverse of
+    /// `add_ipv4`. The route to its network goes too, along with the routes
+    /// through gateways that are no longer on link without it, and all
+    /// existing tx-objects are invalidated. Fails with `StackError::NotFound`
+    /// if the interface does not have the address.
+    pub fn remove_ipv4(&mut self, interface: &Interface, ip: Ipv4Addr) -> StackResult<Ipv4Network> {
+        let ip_net = match self.interface(interface)?.remove_ipv4(ip) {
+            Some(ip_net) => ip_net,
+            None => {
+                return Err(StackError::NotFound(format!("Address {} on {}", ip, interface.name)))
+            }
+        };
+        self.interface_routing_table(interface).remove_network(ip_net, interface);
+        self.invalidate_tx();
+        Ok(ip_net)
+    }
+
+    pub fn ipv4_tx(&mut self,
+                   dst: Ipv4Addr)
+                   -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        self.ipv4_tx_in_vrf(None, dst)
+    }
+
+    /// Same as `ipv4_tx` but routes `dst` with the routing table of the VRF
+    /// `vrf`, or the main routing table if it's `None`.
+    pub fn ipv4_tx_in_vrf(&mut self,
+                          vrf: Option<&str>,
+                          dst: Ipv4Addr)
+                          -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        self.routed_ipv4_tx(vrf, None, None, dst)
+    }
+
+    /// Same as `ipv4_tx` but sending from `src` instead of the source address
+    /// selected by the routing. `src` must be configured on the outgoing
+    /// interface. Packets to multicast groups go out on the interface of
+    /// `src`, without a route to them.
+    pub fn ipv4_tx_with_src(&mut self,
+                            src: Ipv4Addr,
+                            dst: Ipv4Addr)
+                            -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        self.routed_ipv4_tx(None, Some(src), None, dst)
+    }
+
+    /// Same as `ipv4_tx`, but with `src` as the source address of the
+    /// packets, whether or not the stack has it. For scanners and
+    /// measurements that pose as other hosts, so an explicit opt-in: the
+    /// replies go to `src` and not to this stack, and even those reaching it
+    /// are not delivered to its listeners unless it has `src`. Arp requests
+    /// for the next hop are still sent from the address selected by the
+    /// routing, so neighbours do not learn `src` at the MAC of the stack.
+    ///
+    /// The spoofed source is part of this tx-object only. Once it's outdated,
+    /// the one `ipv4_tx` creates in its place, like `RefreshingTx` does,
+    /// silently sends from the routed source again. Call this again instead.
+    pub fn ipv4_tx_with_spoofed_src(&mut self,
+                                    src: Ipv4Addr,
+                                    dst: Ipv4Addr)
+                                    -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        self.routed_ipv4_tx(None, None, Some(src), dst)
+    }
+
+    /// Same as `ipv4_tx_in_vrf`, but returns what creating the tx-object
+    /// takes instead of creating it, so it can be created without the stack,
+    /// see `TxRoute`.
+    pub fn tx_route_in_vrf(&mut self, vrf: Option<&str>, dst: Ipv4Addr) -> StackResult<TxRoute> {
+        self.routed_tx(vrf, None, None, dst)
+    }
+
+    fn routed_ipv4_tx(&mut self,
+                      vrf: Option<&str>,
+                      src: Option<Ipv4Addr>,
+                      spoofed_src: Option<Ipv4Addr>,
+                      dst: Ipv4Addr)
+                      -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        self.routed_tx(vrf, src, spoofed_src, dst)?.ipv4_tx()
+    }
+
+    fn routed_tx(&mut self,
+                 vrf: Option<&str>,
+                 src: Option<Ipv4Addr>,
+                 spoofed_src: Option<Ipv4Addr>,
+                 dst: Ipv4Addr)
+                 -> StackResult<TxRoute> {
+        // Local delivery takes precedence over the routing table
+        let local_interface = self.interfaces
+            .iter()
+            .find(|&(interface, stack_interface)| {
+                stack_interface.has_ipv4(dst) && self.interface_vrf(interface) == vrf
+            })
+            .map(|(interface, _)| interface.clone());
+        if let Some(interface) = local_interface {
+            let stack_interface = &self.interfaces[&interface];
+            let mtu = stack_interface.get_mtu();
+            let src = src.unwrap_or(dst);
+            return Ok(stack_interface.tx_route(src, spoofed_src, dst, None, mtu));
+        }
+        if let (true, Some(src)) = (dst.is_multicast(), src) {
+            let multicast_interface = self.interfaces
+                .iter()
+                .find(|&(interface, stack_interface)| {
+                    stack_interface.has_ipv4(src) && self.interface_vrf(interface) == vrf
+                })
+                .map(|(interface, _)| interface.clone());
+            if let Some(interface) = multicast_interface {
+                let stack_interface = &self.interfaces[&interface];
+                let mtu = stack_interface.get_mtu();
+                return Ok(stack_interface.tx_route(src, spoofed_src, dst, None, mtu));
+            }
+        }
+        let route = self.route(vrf, dst)?;
+        let path_mtu = self.path_mtu(dst);
+        if let Some(stack_interface) = self.interfaces.get(&route.interface) {
+            let mtu = [route.mtu, path_mtu]
+                .iter()
+                .filter_map(|mtu| *mtu)
+                .fold(stack_interface.get_mtu(), cmp::min);
+            let src = match src {
+                Some(src) if !stack_interface.has_ipv4(src) => {
+                    let msg = format!("{} is no address of {}", src, route.interface.name);
+                    return Err(StackError::InvalidArgument(msg));
+                }
+                Some(src) => src,
+                None => route.src,
+            };
+            Ok(stack_interface.tx_route(src, spoofed_src, dst, route.gw, mtu))
+        } else {
+            Err(StackError::NoSuchInterface(route.interface.name))
+        }
+    }
+
+    /// Starts resolving the MAC address of the next hop `ipv4_tx_in_vrf`
+    /// needs to send to `dst`, without blocking. Returns a channel receiving
+    /// it, or `None` if creating the tx-object will not wait for an Arp reply.
+    /// Used by `StackHandle` to wait without holding the lock of the stack.
+    pub fn resolve_in_vrf(&mut self,
+                          vrf: Option<&str>,
+                          dst: Ipv4Addr)
+                          -> StackResult<Option<Receiver<MacAddr>>> {
+        match self.arp_next_hop(vrf, dst)? {
+            Some((interface, src, next_hop)) => {
+                self.interface(&interface)?.start_resolve(src, next_hop)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Same as `resolve_in_vrf`, but instead of returning a channel, makes
+    /// `waker` be called when the MAC address is known. Returns
+    /// `Async::Ready` once creating the tx-object will not wait for an Arp
+    /// reply. Every call while not ready sends a new Arp request. See
+    /// `reactor`.
+    pub fn poll_resolve_in_vrf(&mut self,
+                               vrf: Option<&str>,
+                               dst: Ipv4Addr,
+                               waker: &Waker)
+                               -> StackResult<Async<()>> {
+        let (interface, src, next_hop) = match self.arp_next_hop(vrf, dst)? {
+            Some(next_hop) => next_hop,
+            None => return Ok(Async::Ready(())),
+        };
+        let stack_interface = self.interface(&interface)?;
+        if stack_interface.arp_table.get_or_wake(next_hop, waker.clone()).is_some() {
+            return Ok(Async::Ready(()));
+        }
+        match stack_interface.start_resolve(src, next_hop)? {
+            Some(_) => Ok(Async::NotReady),
+            None => Ok(Async::Ready(())),
+        }
+    }
+
+    /// Returns `Async::Ready` if the interface sending to `dst` may take a
+    /// frame, else makes `waker` be called when a send that failed with
+    /// `TxError::WouldBlock` should be retried. See
+    /// `StackInterface::poll_writable`.
+    pub fn poll_writable_in_vrf(&mut self,
+                                vrf: Option<&str>,
+                                dst: Ipv4Addr,
+                                waker: &Waker)
+                                -> StackResult<Async<()>> {
+        let interface = match self.arp_next_hop(vrf, dst)? {
+            Some((interface, _, _)) => interface,
+            // Nothing local is sent out on an interface
+            None => return Ok(Async::Ready(())),
+        };
+        Ok(self.interface(&interface)?.poll_writable(waker))
+    }
+
+    /// Returns the interface, source address and next hop to resolve with
+    /// Arp to send to `dst`, or `None` if it is local.
+    fn arp_next_hop(&mut self,
+                    vrf: Option<&str>,
+                    dst: Ipv4Addr)
+                    -> StackResult<Option<(Interface, Ipv4Addr, Ipv4Addr)>> {
+        let local = self.interfaces
+            .iter()
+            .any(|(interface, stack_interface)| {
+                stack_interface.has_ipv4(dst) && self.interface_vrf(interface) == vrf
+            });
+        if local {
+            return Ok(None);
+        }
+        let route = self.route(vrf, dst)?;
+        let stack_interface = match self.interfaces.get(&route.interface) {
+            Some(stack_interface) => stack_interface,
+            None => return Err(StackError::NoSuchInterface(route.interface.name.clone())),
+        };
+        if stack_interface.has_ipv4(dst) || stack_interface.is_broadcast(dst) ||
+           dst.is_multicast() || stack_interface.is_layer3() {
+            return Ok(None);
+        }
+        Ok(Some((route.interface, route.src, route.gw.unwrap_or(dst))))
+    }
+
+    /// Returns true if `ip` is a broadcast address on one of the interfaces
+    /// in the VRF `vrf`, or in the main routing table if it's `None`. See
+    /// `StackInterface::is_broadcast`.
+    pub fn is_broadcast_in_vrf(&self, vrf: Option<&str>, ip: Ipv4Addr) -> bool {
+        self.interfaces.iter().any(|(interface, stack_interface)| {
+            self.interface_vrf(interface) == vrf && stack_interface.is_broadcast(ip)
+        })
+    }
+
+    /// Creates a VRF, a routing domain with its own routing table that is
+    /// isolated from the main routing table and all other VRFs. Interfaces
+    /// are moved into it with `set_interface_vrf`.
+    pub fn create_vrf(&mut self, name: &str) -> StackResult<()> {
+        match self.vrfs.entry(name.to_owned()) {
+            Entry::Occupied(_) => Err(StackError::AlreadyExists(format!("VRF {}", name))),
+            Entry::Vacant(entry) => {
+                entry.insert(Vrf::default());
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes a VRF. Its interfaces are moved back to the main routing
+    /// table together with their routes.
+    pub fn remove_vrf(&mut self, name: &str) -> StackResult<()> {
+        if !self.vrfs.contains_key(name) {
+            return Err(StackError::NoSuchVrf(name.to_owned()));
+        }
+        let interfaces = self.interface_vrfs
+            .iter()
+            .filter(|&(_, vrf)| vrf == name)
+            .map(|(interface, _)| interface.clone())
+            .collect::<Vec<_>>();
+        for interface in &interfaces {
+            self.set_interface_vrf(interface, None)?;
+        }
+        self.vrfs.remove(name);
+        Ok(())
+    }
+
+    /// Returns the names of all VRFs in this stack.
+    pub fn vrfs(&self) -> Vec<String> {
+        self.vrfs.keys().cloned().collect()
+    }
+
+    /// Returns the name of the VRF `interface` belongs to, or `None` if it
+    /// uses the main routing table.
+    pub fn interface_vrf(&self, interface: &Interface) -> Option<&str> {
+        self.interface_vrfs.get(interface).map(|vrf| vrf.as_str())
+    }
+
+    /// Moves `interface` into the VRF `vrf`, or back to the main routing
+    /// table if it's `None`. All routes going out on the interface are moved
+    /// along with it and all existing tx-objects are invalidated. A routing
+    /// table holds one route per network and metric, so interfaces sharing a
+    /// network must be moved into separate VRFs before the addresses are
+    /// added.
+    pub fn set_interface_vrf(&mut self,
+                             interface: &Interface,
+                             vrf: Option<&str>)
+                             -> StackResult<()> {
+        if !self.interfaces.contains_key(interface) {
+            return Err(StackError::NoSuchInterface(interface.name.clone()));
+        }
+        if let Some(vrf) = vrf {
+            if !self.vrfs.contains_key(vrf) {
+                return Err(StackError::NoSuchVrf(vrf.to_owned()));
+            }
+        }
+        let routes = {
+            let table = self.interface_routing_table(interface);
+            // The withdrawal of the routes follows them
+            table.set_link_up(interface, true);
+            let routes = table.routes()
+                .into_iter()
+                .filter(|entry| entry.interface == *interface)
+                .collect::<Vec<_>>();
+            for entry in &routes {
+                table.remove_route_with_metric(entry.net, entry.metric);
+            }
+            routes
+        };
+        match vrf {
+            Some(vrf) => self.interface_vrfs.insert(interface.clone(), vrf.to_owned()),
+            None => self.interface_vrfs.remove(interface),
+        };
+        let carrier = self.interfaces[interface].has_carrier();
+        {
+            let table = self.interface_routing_table(interface);
+            table.set_link_up(interface, carrier);
+            for entry in routes {
+                table.add_route_with_metric(entry.net, entry.gw, entry.interface, entry.metric);
+                if entry.mtu.is_some() {
+                    table.set_route_mtu(entry.net, entry.mtu)?;
+                }
+                if entry.src.is_some() {
+                    table.set_route_src(entry.net, entry.src)?;
+                }
+            }
+        }
+        self.invalidate_tx();
+        Ok(())
+    }
+
+    /// Returns the routing table of the VRF `vrf`. Just like with
+    /// `routing_table()`, modifying it directly does not invalidate existing
+    /// tx-objects.
+    pub fn vrf_routing_table(&mut self, vrf: &str) -> StackResult<&mut RoutingTable> {
+        match self.vrfs.get_mut(vrf) {
+            Some(vrf) => Ok(&mut vrf.routing_table),
+            None => Err(StackError::NoSuchVrf(vrf.to_owned())),
+        }
+    }
+
+    /// Removes the route to `net` from the routing table of the VRF `vrf`
+    /// and invalidates all existing tx-objects.
+    pub fn remove_vrf_route(&mut self,
+                            vrf: &str,
+                            net: Ipv4Network)
+                            -> StackResult<Option<RouteEntry>> {
+        let old_entry = self.vrf_routing_table(vrf)?.remove_route(net);
+        if old_entry.is_some() {
+            self.invalidate_tx();
+        }
+        Ok(old_entry)
+    }
+
+    /// Returns the routing table routes going out on `interface` belong in.
+    fn interface_routing_table(&mut self, interface: &Interface) -> &mut RoutingTable {
+        match self.interface_vrfs.get(interface) {
+            Some(vrf) => &mut self.vrfs.get_mut(vrf).unwrap().routing_table,
+            None => &mut self.routing_table,
+        }
+    }
+
+    /// Records the path MTU towards `dst`, or forgets it if `mtu` is `None`.
+    /// Packets to `dst` will be sized to fit the smallest of the path MTU,
+    /// the MTU of the route used and the MTU of the outgoing interface.
+    /// Existing tx-objects are invalidated.
+    pub fn set_path_mtu(&mut self, dst: Ipv4Addr, mtu: Option<usize>) -> StackResult<()> {
+        match mtu {
+            Some(mtu) if mtu < ipv4::MIN_MTU => return Err(StackError::InvalidMtu(mtu)),
+            Some(mtu) => self.path_mtus.insert(dst, mtu),
+            None => self.path_mtus.remove(&dst),
+        };
+        self.invalidate_tx();
+        Ok(())
+    }
+
+    /// Returns the known path MTU towards `dst`, if any.
+    pub fn path_mtu(&self, dst: Ipv4Addr) -> Option<usize> {
+        self.path_mtus.get(&dst).cloned()
+    }
+
+    /// Returns the MTU packets routed to `dst` are sized to fit: the
+    /// smallest of the MTU of the outgoing interface, the MTU of the route
+    /// and the path MTU.
+    pub fn mtu_to(&mut self, dst: Ipv4Addr) -> StackResult<usize> {
+        let route = self.route(None, dst)?;
+        let path_mtu = self.path_mtu(dst);
+        let interface_mtu = self.interface(&route.interface)?.get_mtu();
+        Ok([route.mtu, path_mtu].iter().filter_map(|mtu| *mtu).fold(interface_mtu, cmp::min))
+    }
+
+    /// Sets the MTU override of the routes to `net` and invalidates all
+    /// existing tx-objects. See `RoutingTable::set_route_mtu`.
+    pub fn set_route_mtu(&mut self, net: Ipv4Network, mtu: Option<usize>) -> StackResult<()> {
+        self.routing_table.set_route_mtu(net, mtu)?;
+        self.invalidate_tx();
+        Ok(())
+    }
+
+    /// Sets the preferred source address for traffic using the routes to
+    /// `net`, or goes back to automatic selection if `src` is `None`. The
+    /// address must be configured on the interfaces of those routes.
+    /// Invalidates all existing tx-objects.
+    pub fn set_route_src(&mut self, net: Ipv4Network, src: Option<Ipv4Addr>) -> StackResult<()> {
+        if let Some(src) = src {
+            let routes = self.routing_table.routes();
+            let mut interfaces = routes.iter()
+                .filter(|entry| entry.net.prefix() == net.prefix() && entry.net.contains(net.ip()))
+                .map(|entry| &entry.interface);
+            let all_owned = interfaces.all(|interface| {
+                self.interfaces.get(interface).map_or(false, |i| i.has_ipv4(src))
+            });
+            if !all_owned {
+                let msg = format!("{} is not on every interface of the routes to {}", src, net);
+                return Err(StackError::InvalidArgument(msg));
+            }
+        }
+        self.routing_table.set_route_src(net, src)?;
+        self.invalidate_tx();
+        Ok(())
+    }
+
+    /// Makes the routing decision for `dst` in the VRF `vrf`, or the main
+    /// routing table if it's `None`. Served from the route cache when
+    /// neither the routing table nor the outgoing interface changed since the
+    /// entry was cached.
+    fn route(&mut self, vrf: Option<&str>, dst: Ipv4Addr) -> StackResult<CachedRoute> {
+        let (routing_table, route_cache) = match vrf {
+            None => (&self.routing_table, &mut self.route_cache),
+            Some(vrf) => {
+                match self.vrfs.get_mut(vrf) {
+                    Some(vrf_data) => (&vrf_data.routing_table, &mut vrf_data.route_cache),
+                    None => return Err(StackError::NoSuchVrf(vrf.to_owned())),
+                }
+            }
+        };
+        let table_version = routing_table.version();
+        if let Some(route) = route_cache.get(&dst) {
+            if route.table_version == table_version {
+                if let Some(stack_interface) = self.interfaces.get(&route.interface) {
+                    if stack_interface.config_version() == route.interface_version {
+                        return Ok(route.clone());
+                    }
+                }
+            }
+        }
+
+        let (gw, interface, mtu, preferred_src) = match routing_table.lookup(dst) {
+            Some(entry) => (entry.gw, entry.interface.clone(), entry.mtu, entry.src),
+            None => return Err(StackError::NoRouteToHost(dst)),
+        };
+        let route = match self.interfaces.get(&interface) {
+            Some(stack_interface) => {
+                let src = match preferred_src {
+                    Some(src) if stack_interface.has_ipv4(src) => Some(src),
+                    _ => stack_interface.source_address(dst, gw.unwrap_or(dst)),
+                };
+                match src {
+                    Some(src) => {
+                        CachedRoute {
+                            gw: gw,
+                            interface: interface.clone(),
+                            src: src,
+                            mtu: mtu,
+                            table_version: table_version,
+                            interface_version: stack_interface.config_version(),
+                        }
+                    }
+                    None => return Err(StackError::NoSourceAddress(dst)),
+                }
+            }
+            None => return Err(StackError::NoSuchInterface(interface.name)),
+        };
+        if route_cache.len() >= ROUTE_CACHE_SIZE {
+            route_cache.clear();
+        }
+        route_cache.insert(dst, route.clone());
+        Ok(route)
+    }
+
+    /// Registers `listener` for IPv4 packets with the protocol `protocol` to
+    /// `local_ip`, on the interface that has that address.
+    pub fn ipv4_listen(&mut self,
+                       local_ip: Ipv4Addr,
+                       protocol: IpNextHeaderProtocol,
+                       listener: Box<ipv4::Ipv4Listener>)
+                       -> io::Result<()> {
+        for stack_interface in self.interfaces.values_mut() {
+            if stack_interface.has_ipv4(local_ip) {
+                return stack_interface.ipv4_listen(local_ip, protocol, listener);
+            }
+        }
+        let msg = "Bind address does not exist in stack".to_owned();
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+
+    pub fn icmp_tx(&mut self,
+                   dst_ip: Ipv4Addr)
+                   -> StackResult<IcmpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>> {
+        let ipv4_tx = self.ipv4_tx(dst_ip)?;
+        Ok(icmp::IcmpTx::new(ipv4_tx))
+    }
+
+    /// Registers `listener` for the Icmp messages of type `icmp_type` to
+    /// `local_ip`. If `local_ip` is 0.0.0.0 it gets those to every local
+    /// address, including ones added later, on any interface.
+    pub fn icmp_listen<L>(&mut self,
+                          local_ip: Ipv4Addr,
+                          icmp_type: IcmpType,
+                          listener: L)
+                          -> io::Result<()>
+        where L: icmp::IcmpListener + 'static + Clone
+    {
+        if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            let mut icmp_listeners = self.icmp_wildcard_listeners.write().unwrap();
+            icmp_listeners.entry(icmp_type)
+                .or_insert_with(Vec::new)
+                .push(Mutex::new(Box::new(listener)));
+            Ok(())
+        } else {
+            let mut added_to_interface = false;
+            for stack_interface in self.interfaces.values_mut() {
+                let result = stack_interface.icmp_listen(local_ip, icmp_type, listener.clone());
+                added_to_interface |= result.is_ok();
+            }
+            if added_to_interface {
+                Ok(())
+            } else {
+                let msg = "Bind address does not exist in stack".to_owned();
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+        }
+    }
+
+    pub fn udp_tx(&mut self,
+                  dst_ip: Ipv4Addr,
+                  src: u16,
+                  dst_port: u16)
+                  -> StackResult<UdpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>> {
+        self.udp_tx_in_vrf(None, dst_ip, src, dst_port)
+    }
+
+    /// Same as `udp_tx` but routes `dst_ip` in the VRF `vrf`. See
+    /// `ipv4_tx_in_vrf`.
+    pub fn udp_tx_in_vrf(&mut self,
+                         vrf: Option<&str>,
+                         dst_ip: Ipv4Addr,
+                         src: u16,
+                         dst_port: u16)
+                         -> StackResult<UdpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>> {
+        let ipv4_tx = self.ipv4_tx_in_vrf(vrf, dst_ip)?;
+        Ok(udp::UdpTx::new(ipv4_tx, src, dst_port))
+    }
+
+    /// Listens for datagrams to `addr`. Host names are not looked up with
+    /// the stack locked, `UdpSocket::bind` looks them up.
     pub fn udp_listen<A, L>(&mut self, addr: A, listener: L) -> io::Result<SocketAddr>
-        where A: ToSocketAddrs,
+        where A: ToStackAddr,
+              L: udp::UdpListener + 'static + Clone
+    {
+        self.udp_listen_in_vrf(None, addr, listener)
+    }
+
+    /// Same as `udp_listen` but only binds to addresses on interfaces in the
+    /// VRF `vrf`, or on interfaces using the main routing table if it's
+    /// `None`. Different VRFs may use the same addresses.
+    pub fn udp_listen_in_vrf<A, L>(&mut self,
+                                   vrf: Option<&str>,
+                                   addr: A,
+                                   listener: L)
+                                   -> io::Result<SocketAddr>
+        where A: ToStackAddr,
               L: udp::UdpListener + 'static + Clone
     {
-        match util::first_socket_addr(addr)? {
-            SocketAddr::V4(addr) => self.udp_listen_ipv4(addr, listener),
-            SocketAddr::V6(_) => {
-                let msg = "Rips does not support IPv6 yet".to_owned();
+        match addr.to_stack_addr()? {
+            StackAddr::Addr(addr) => self.udp_listen_ipv4(vrf, addr, listener),
+            StackAddr::Host(host, _) => {
+                let msg = format!("Not looking up {} with the stack locked", host);
                 Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
             }
         }
     }
 
-    fn udp_listen_ipv4<L>(&mut self, addr: SocketAddrV4, listener: L) -> io::Result<SocketAddr>
+    fn udp_listen_ipv4<L>(&mut self,
+                          vrf: Option<&str>,
+                          addr: SocketAddrV4,
+                          listener: L)
+                          -> io::Result<SocketAddr>
         where L: udp::UdpListener + 'static + Clone
     {
         let local_ip = addr.ip();
@@ -447,14 +3232,17 @@ impl NetworkStack {
             let msg = "Rips does not support listening to all interfaces yet".to_owned();
             Err(io::Error::new(io::ErrorKind::AddrNotAvailable, msg))
         } else {
-            for stack_interface in self.interfaces.values() {
+            for (interface, stack_interface) in &self.interfaces {
+                if self.interface_vrf(interface) != vrf {
+                    continue;
+                }
                 if let Some(ip_data) = stack_interface.ipv4_datas.get(local_ip) {
-                    let mut udp_listeners = ip_data.udp_listeners.lock().unwrap();
+                    let mut udp_listeners = ip_data.udp_listeners.write().unwrap();
                     if local_port == 0 {
                         local_port = self.get_random_port(&*udp_listeners);
                     }
                     if !udp_listeners.contains_key(&local_port) {
-                        udp_listeners.insert(local_port, Box::new(listener));
+                        udp_listeners.insert(local_port, Mutex::new(Box::new(listener)));
                         return Ok(SocketAddr::V4(SocketAddrV4::new(*local_ip, local_port)));
                     } else {
                         let msg =
@@ -469,11 +3257,12 @@ impl NetworkStack {
     }
 
     fn get_random_port(&self, listeners: &udp::UdpListenerLookup) -> u16 {
-        let range = Range::new(LOCAL_PORT_RANGE_START, LOCAL_PORT_RANGE_END);
-        let mut rng = rand::thread_rng();
+        let (start, end) = self.local_ports;
+        let range = Range::new(start, end);
+        let mut rng = self.rng.lock().unwrap();
         let mut port = 0;
         while port == 0 {
-            let n = range.ind_sample(&mut rng);
+            let n = range.ind_sample(&mut *rng);
             if !listeners.contains_key(&n) {
                 port = n;
                 break;
@@ -483,78 +3272,585 @@ impl NetworkStack {
     }
 }
 
+/// Everything creating a tx-object towards one destination takes from the
+/// stack, returned by `NetworkStack::tx_route_in_vrf`. Creating the tx-object
+/// from it only takes the locks of the interface it goes out on, so threads
+/// neither hold the stack while waiting for Arp replies nor wait for each
+/// other when sending on different interfaces. See `StackHandle`.
+///
+/// Changes to the stack after the route was taken are not noticed, so the
+/// tx-object should be created right away.
+pub struct TxRoute {
+    data: Arc<StackInterfaceData>,
+    arp_table: ArpTable,
+    tx: Arc<Mutex<TxBarrier>>,
+    shaper: Arc<Shaper>,
+    /// The address Arp requests are sent from
+    src: Ipv4Addr,
+    /// The source address of the packets
+    header_src: Ipv4Addr,
+    dst: Ipv4Addr,
+    /// The MAC address sent to, or the next hop to resolve it of
+    dst_mac: Result<MacAddr, Ipv4Addr>,
+    mtu: usize,
+}
+
+impl TxRoute {
+    pub fn interface(&self) -> &Interface {
+        &self.data.interface
+    }
+
+    /// Sends an Arp request for the next hop if its MAC address is not known
+    /// yet, and returns the channel receiving it. Returns `None` if creating
+    /// the tx-object will not wait.
+    pub fn start_resolve(&mut self) -> StackResult<Option<Receiver<MacAddr>>> {
+        let next_hop = match self.dst_mac {
+            Ok(_) => return Ok(None),
+            Err(next_hop) => next_hop,
+        };
+        match self.arp_table.get(next_hop) {
+            Ok(_) => Ok(None),
+            Err(rx) => {
+                tx_send!(|| self.data.arp_request_tx(); self.src, next_hop)?;
+                Ok(Some(rx))
+            }
+        }
+    }
+
+    /// Creates the tx-object, blocking until the MAC address of the next hop
+    /// is known. Call `start_resolve` first to wait for it some other way.
+    pub fn ipv4_tx(mut self) -> StackResult<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>> {
+        let dst_mac = match self.dst_mac {
+            Ok(dst_mac) => dst_mac,
+            Err(next_hop) => {
+                match self.arp_table.get(next_hop) {
+                    Ok(mac) => mac,
+                    Err(rx) => {
+                        tx_send!(|| self.data.arp_request_tx(); self.src, next_hop)?;
+                        rx.recv().unwrap()
+                    }
+                }
+            }
+        };
+        let tx = self.data.filtered_tx(&self.tx, LOCAL_TX_HOOKS, self.shaper);
+        let ethernet_tx = EthernetTxImpl::new(tx, self.data.interface.mac, dst_mac);
+        Ok(Ipv4TxImpl::new(ethernet_tx, self.header_src, self.dst, self.mtu))
+    }
+}
+
 pub struct DatalinkTx {
     tx: Arc<Mutex<TxBarrier>>,
     version: u64,
+    /// The state of `tx`, read without locking it
+    state: Arc<TxState>,
+    filter: TxFilter,
+    shaper: Arc<Shaper>,
 }
 
 impl DatalinkTx {
-    fn new(tx: Arc<Mutex<TxBarrier>>, version: u64) -> Self {
+    fn new(tx: Arc<Mutex<TxBarrier>>,
+           version: u64,
+           state: Arc<TxState>,
+           filter: TxFilter,
+           shaper: Arc<Shaper>)
+           -> Self {
         DatalinkTx {
             tx: tx,
             version: version,
+            state: state,
+            filter: filter,
+            shaper: shaper,
+        }
+    }
+
+    /// Fails with `TxError::InvalidTx` if the stack changed since this
+    /// `DatalinkTx` was created.
+    fn check_version(&self) -> Result<(), TxError> {
+        if self.state.version() != self.version {
+            Err(TxError::InvalidTx)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Paces `bytes` about to be sent, before taking the lock so other
+    /// senders are not blocked. Queued frames are paced by the egress queue
+    /// scheduler instead.
+    fn pace(&self, bytes: usize) {
+        if !self.state.queued.load(Ordering::SeqCst) {
+            self.shaper.wait(bytes);
         }
     }
 }
 
 impl Tx for DatalinkTx {
-    fn send<P: Payload>(&mut self, num_packets: usize, packet_size: usize, payload: P) -> TxResult {
-        let mut tx = self.tx.lock().unwrap();
-        if self.version != tx.version() {
-            Err(TxError::InvalidTx)
+    fn send<P: Payload>(&mut self,
+                        num_packets: usize,
+                        packet_size: usize,
+                        payload: P)
+                        -> TxResult {
+        self.check_version()?;
+        self.pace(num_packets * packet_size);
+        let result = {
+            let mut tx = self.tx.lock().unwrap();
+            // The stack might have changed while pacing
+            self.check_version()?;
+            self.send_locked(&mut tx, num_packets, packet_size, payload)
+        };
+        self.deliver_looped();
+        result
+    }
+
+    /// Sends all of `payloads` while holding the lock of the datalink once,
+    /// paced as one burst.
+    fn send_all<P, I>(&mut self, payloads: I) -> TxResult
+        where P: Payload,
+              I: IntoIterator<Item = P>
+    {
+        self.check_version()?;
+        let payloads = payloads.into_iter().collect::<Vec<_>>();
+        self.pace(payloads.iter().map(|payload| payload.len()).sum());
+        let result = {
+            let mut tx = self.tx.lock().unwrap();
+            self.check_version()?;
+            self.send_all_locked(&mut tx, payloads)
+        };
+        self.deliver_looped();
+        result
+    }
+}
+
+impl DatalinkTx {
+    fn send_all_locked<P: Payload>(&self, tx: &mut TxBarrier, payloads: Vec<P>) -> TxResult {
+        let mut sent = TxSent::default();
+        let mut filtered = false;
+        for payload in payloads {
+            let len = payload.len();
+            match self.send_locked(tx, 1, len, payload) {
+                Err(TxError::Filtered) => filtered = true,
+                Err(e) => return Err(e),
+                Ok(packet_sent) => sent += packet_sent,
+            }
+        }
+        if filtered {
+            Err(TxError::Filtered)
+        } else {
+            Ok(sent)
+        }
+    }
+
+    /// Delivers the frames sent to the loopback interface, now that the
+    /// lock of its barrier is released.
+    fn deliver_looped(&self) {
+        if let Some(ref loopback) = self.state.loopback {
+            loopback.drain();
+        }
+    }
+
+    /// Sends on `tx`, the locked barrier of this `DatalinkTx`, running the
+    /// frames through the firewall if it has any rules.
+    fn send_locked<P: Payload>(&self,
+                               tx: &mut TxBarrier,
+                               num_packets: usize,
+                               packet_size: usize,
+                               mut payload: P)
+                               -> TxResult {
+        if self.filter.firewall.is_empty() {
+            return tx.send(num_packets, packet_size, payload);
+        }
+        // Frames have to be built before they can be filtered
+        let mut sent = TxSent::default();
+        let mut filtered = false;
+        for _ in 0..num_packets {
+            let mut frame = vec![0; packet_size];
+            payload.build(&mut frame);
+            match self.filter.apply(frame) {
+                Some(frame) => sent += tx.send(1, frame.len(), BasicPayload::new(&frame))?,
+                None => filtered = true,
+            }
+        }
+        if filtered {
+            Err(TxError::Filtered)
         } else {
-            tx.send(num_packets, packet_size, payload)
+            Ok(sent)
+        }
+    }
+}
+
+/// Runs the IPv4 packets sent through a `DatalinkTx` through some hooks of
+/// the firewall.
+struct TxFilter {
+    firewall: Arc<Firewall>,
+    interface: Interface,
+    hooks: &'static [Hook],
+}
+
+impl TxFilter {
+    /// Returns the ethernet frame `frame`, possibly modified, if the firewall
+    /// accepts it. Frames not carrying IPv4 are always accepted.
+    fn apply(&self, frame: Vec<u8>) -> Option<Vec<u8>> {
+        let header_len = EthernetPacket::minimum_packet_size();
+        let is_ipv4 = EthernetPacket::new(&frame)
+            .map_or(false, |eth_pkg| eth_pkg.get_ethertype() == EtherTypes::Ipv4);
+        if !is_ipv4 {
+            return Some(frame);
+        }
+        let info = firewall::PacketInfo {
+            in_interface: None,
+            out_interface: Some(&self.interface),
+            state: None,
+        };
+        let mut packet = frame[header_len..].to_vec();
+        for &hook in self.hooks {
+            if !self.firewall.filter(hook, &info, &mut packet) {
+                return None;
+            }
+        }
+        let mut frame = frame;
+        frame.truncate(header_len);
+        frame.extend_from_slice(&packet);
+        Some(frame)
+    }
+}
+
+/// Which frames are addressed to an interface, and what it does with the
+/// others.
+struct MacFilter {
+    promiscuous: AtomicBool,
+    /// The joined multicast addresses, and how many times they were joined
+    multicast: RwLock<HashMap<MacAddr, usize>>,
+    monitor: Mutex<Option<Box<RxListener>>>,
+}
+
+impl MacFilter {
+    fn is_addressed_to(&self, mac: MacAddr, dst: MacAddr) -> bool {
+        dst == mac || dst == BROADCAST_MAC ||
+        dst.0 & 0x01 != 0 && self.multicast.read().unwrap().contains_key(&dst)
+    }
+}
+
+/// What a polled interface handles in `StackInterface::poll`.
+struct Poller {
+    frames: Receiver<(SystemTime, PooledBuffer)>,
+    interface_rx: InterfaceRx,
+    thread: StackInterfaceThread,
+}
+
+/// Queues the frames received on a polled interface for its `Poller`.
+struct QueueingRx {
+    frames: Sender<(SystemTime, PooledBuffer)>,
+    wakeup: Sender<()>,
+    pool: BufferPool,
+}
+
+impl RxListener for QueueingRx {
+    fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        let frame = self.pool.copy_of(packet.packet());
+        if self.frames.send((time, frame)).is_err() {
+            return Err(RxError::Other("Polled interface is gone".to_owned()));
+        }
+        self.wakeup.send(()).unwrap_or(());
+        Ok(())
+    }
+}
+
+/// Hands the frames received on an interface to its `EthernetRx`, except
+/// frames not addressed to it while it is not promiscuous. EAPOL frames go
+/// to the supplicant, if there is one, and nothing else is received until
+/// it authorized the port.
+/// The listeners of the protocols every interface handles, see
+/// `StaticEthernetRx`. Listeners of other protocols are added at runtime to
+/// `StackInterface::ethernet_listeners`.
+type BuiltinListeners = Chain<ipv4::Ipv4Rx, Chain<Guarded<ArpRx>, Guarded<VlanRx>>>;
+
+struct InterfaceRx {
+    mac: MacAddr,
+    mac_filter: Arc<MacFilter>,
+    port_access: Arc<PortAccess>,
+    ethernet_rx: StaticEthernetRx<BuiltinListeners>,
+    llc_rx: LlcRx,
+    macvlans: Arc<Mutex<MacvlanTable>>,
+    counters: Arc<Counters>,
+}
+
+impl InterfaceRx {
+    /// Counts `packet` as dropped at Ethernet if `result` is that of a
+    /// protocol nothing listens for. The builtin protocols count their own.
+    fn count(&self, packet: &EthernetPacket, result: RxResult) -> RxResult {
+        let builtin = match packet.get_ethertype() {
+            EtherTypes::Ipv4 | EtherTypes::Arp | EtherTypes::Vlan => true,
+            _ => false,
+        };
+        if let Err(RxError::NoListener(_)) = result {
+            if !builtin {
+                self.counters.dropped(Layer::Ethernet);
+            }
+        }
+        result
+    }
+}
+
+impl RxListener for InterfaceRx {
+    fn recv(&mut self, time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        if packet.get_ethertype() == eapol::EAPOL {
+            if let Some(result) = self.port_access.recv(self.mac, time, packet) {
+                return result;
+            }
+        }
+        if !self.port_access.is_authorized() {
+            return Ok(());
+        }
+        if macvlan::deliver(&self.macvlans, packet) {
+            return Ok(());
+        }
+        if !self.mac_filter.is_addressed_to(self.mac, packet.get_destination()) {
+            let mut monitor = self.mac_filter.monitor.lock().unwrap();
+            let failed = match *monitor {
+                Some(ref mut monitor) => monitor.recv(time, packet).is_err(),
+                None => false,
+            };
+            if failed {
+                *monitor = None;
+            }
+            if !self.mac_filter.promiscuous.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+        if !llc::is_length(packet.get_ethertype()) {
+            let result = self.ethernet_rx.recv(time, packet);
+            return self.count(packet, result);
+        }
+        match llc::decapsulate(packet) {
+            Some(frame) => {
+                let frame = EthernetPacket::new(&frame).unwrap();
+                let result = self.ethernet_rx.recv(time, &frame);
+                self.count(&frame, result)
+            }
+            None => {
+                let result = self.llc_rx.recv(time, packet);
+                self.count(packet, result)
+            }
+        }
+    }
+}
+
+/// Frames sent to a local address, waiting to be handed to an `EthernetRx`.
+/// They are queued while the lock of the loopback `TxBarrier` is held and
+/// delivered by the sending `DatalinkTx` after releasing it, so listeners
+/// can send to local addresses themselves.
+struct LoopbackQueue {
+    frames: Mutex<VecDeque<PooledBuffer>>,
+    rx: Mutex<EthernetRx>,
+    /// Set while a thread is delivering. Frames queued meanwhile, also by
+    /// the listeners it delivers to, are delivered by that thread
+    draining: AtomicBool,
+}
+
+impl LoopbackQueue {
+    fn new(rx: EthernetRx) -> LoopbackQueue {
+        LoopbackQueue {
+            frames: Mutex::new(VecDeque::new()),
+            rx: Mutex::new(rx),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, frame: PooledBuffer) {
+        self.frames.lock().unwrap().push_back(frame);
+    }
+
+    /// Delivers all queued frames, unless another call is already doing so.
+    fn drain(&self) {
+        loop {
+            if self.draining.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            loop {
+                let frame = match self.frames.lock().unwrap().pop_front() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                let packet = EthernetPacket::new(&frame[..]).unwrap();
+                if let Err(e) = self.rx.lock().unwrap().recv(SystemTime::now(), &packet) {
+                    warn!("Loopback RxError: {:?}", e);
+                }
+            }
+            self.draining.store(false, Ordering::SeqCst);
+            // A frame queued after the last check was left for this thread
+            if self.frames.lock().unwrap().is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+/// An `EthernetDataLinkSender` that puts all frames in a `LoopbackQueue`
+/// instead of sending them anywhere.
+struct LoopbackSender {
+    queue: Arc<LoopbackQueue>,
+    pool: BufferPool,
+}
+
+impl EthernetDataLinkSender for LoopbackSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = self.pool.get(packet_size);
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            self.queue.push(buffer);
         }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        let mut buffer = self.pool.get(packet.packet().len());
+        buffer.copy_from_slice(packet.packet());
+        self.queue.push(buffer);
+        Some(Ok(()))
+    }
+}
+
+/// The parts of a `TxBarrier` its `DatalinkTx`s read on every send, kept
+/// outside of its lock. Only the datalink sender itself is behind that.
+#[derive(Default)]
+struct TxState {
+    /// A `usize` rather than a `u64`, as only that has an atomic type on
+    /// every platform. It only has to change on every tick, not to never wrap
+    version: AtomicUsize,
+    /// If the barrier puts frames in `EgressQueues` rather than sending them
+    queued: AtomicBool,
+    /// Where the barrier of the loopback interface queues its frames
+    loopback: Option<Arc<LoopbackQueue>>,
+}
+
+impl TxState {
+    fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst) as u64
     }
 }
 
 pub struct TxBarrier {
     tx: Box<EthernetDataLinkSender>,
-    version: u64,
+    state: Arc<TxState>,
+    blocked: bool,
+    /// If sends that do not fit fail with `TxError::WouldBlock`
+    nonblocking: bool,
+    writability: Arc<Writability>,
 }
 
 impl TxBarrier {
     pub fn new(tx: Box<EthernetDataLinkSender>) -> TxBarrier {
         TxBarrier {
             tx: tx,
-            version: 0,
+            state: Arc::new(TxState::default()),
+            blocked: false,
+            nonblocking: false,
+            writability: Arc::new(Writability::default()),
         }
     }
 
+    /// Creates the barrier of the loopback interface, queueing all frames in
+    /// `queue` for the `DatalinkTx` sending them to deliver.
+    fn loopback(queue: Arc<LoopbackQueue>, pool: BufferPool) -> TxBarrier {
+        let sender = LoopbackSender {
+            queue: queue.clone(),
+            pool: pool,
+        };
+        let mut barrier = TxBarrier::new(Box::new(sender));
+        barrier.state = Arc::new(TxState { loopback: Some(queue), ..TxState::default() });
+        barrier
+    }
+
+    /// Sends through `tx` from now on, returning the previous sender.
+    fn replace(&mut self, tx: Box<EthernetDataLinkSender>) -> Box<EthernetDataLinkSender> {
+        mem::replace(&mut self.tx, tx)
+    }
+
     /// Increments the internal counter by one. Used to invalidate all `Tx`
     /// instances created towards this `TxBarrier`
     pub fn inc(&mut self) {
-        self.version = self.version.wrapping_add(1);
-        trace!("TxBarrier ticked to {}", self.version);
+        // fetch_add wraps around on overflow
+        let version = self.state.version.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        trace!("TxBarrier ticked to {}", version);
     }
 
     pub fn version(&self) -> u64 {
-        self.version
+        self.state.version()
+    }
+
+    /// Makes `send` fail with `TxError::Unauthorized` while `blocked`. Used
+    /// for 802.1X ports, whose EAPOL frames are sent with `send_unblocked`.
+    pub fn set_blocked(&mut self, blocked: bool) {
+        self.blocked = blocked;
+    }
+
+    /// Same as `send`, but also while blocked.
+    pub fn send_unblocked<P: Payload>(&mut self,
+                                      num_packets: usize,
+                                      packet_size: usize,
+                                      mut payload: P)
+                                      -> TxResult {
+        let mut eth_payload = |mut packet: MutableEthernetPacket| {
+            payload.build(packet.packet_mut());
+        };
+        let result = self.tx.build_and_send(num_packets, packet_size, &mut eth_payload);
+        self.io_result_to_tx_result(result, TxSent::new(num_packets, packet_size))
     }
 
-    fn io_result_to_tx_result(&self, r: Option<io::Result<()>>) -> TxResult {
+    /// Turns `r` of sending on the datalink into a `TxResult`, with `sent`
+    /// if it succeeded.
+    fn io_result_to_tx_result(&self, r: Option<io::Result<()>>, sent: TxSent) -> TxResult {
+        if self.nonblocking && would_block(&r) {
+            // The egress queues tell when they have room, datalinks do not
+            let retry = if self.state.queued.load(Ordering::SeqCst) {
+                None
+            } else {
+                Some(Duration::from_millis(WRITE_RETRY_MS))
+            };
+            Writability::set_full(&self.writability, retry);
+            return Err(TxError::WouldBlock);
+        }
         match r {
-            None => Err(TxError::Other("Insufficient buffer space".to_owned())),
+            None => Err(TxError::BufferFull),
             Some(ior) => {
                 match ior {
                     Err(e) => Err(TxError::from(e)),
-                    Ok(()) => Ok(()),
+                    Ok(()) => Ok(sent),
                 }
             }
         }
     }
 }
 
+/// Returns if `result` of sending on a datalink means the frame did not fit.
+fn would_block(result: &Option<io::Result<()>>) -> bool {
+    match *result {
+        None => true,
+        Some(Err(ref e)) => {
+            e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+        }
+        Some(Ok(())) => false,
+    }
+}
+
 impl Tx for TxBarrier {
     fn send<P: Payload>(&mut self,
                         num_packets: usize,
                         packet_size: usize,
-                        mut payload: P)
+                        payload: P)
                         -> TxResult {
-        let mut eth_payload = |mut packet: MutableEthernetPacket| {
-            payload.build(packet.packet_mut());
-        };
-        let result = self.tx.build_and_send(num_packets, packet_size, &mut eth_payload);
-        self.io_result_to_tx_result(result)
+        if self.blocked {
+            return Err(TxError::Unauthorized);
+        }
+        self.send_unblocked(num_packets, packet_size, payload)
     }
 }
@@ -0,0 +1,460 @@
+//! Counters of the packets each protocol received and sent.
+//!
+//! `NetworkStack::stats` returns a `StackStats` with a `LayerStats` for each
+//! of Ethernet, Arp, IPv4, Icmp and UDP, summed over the interfaces, and
+//! `StackInterface::stats` the ones of an interface:
+//!
+//! ```rust,ignore
+//! let stats = stack.stats();
+//! println!("udp: {} in, {} out, {} dropped",
+//!          stats.udp.rx_packets,
+//!          stats.udp.tx_packets,
+//!          stats.udp.rx_dropped);
+//! ```
+//!
+//! Packets and bytes are counted on the datalink, for every frame by the
+//! protocols it carries: a UDP datagram counts at Ethernet, IPv4 and UDP.
+//! Fragments count at IPv4, and the first fragment of a packet also at the
+//! protocol it carries, with the length the datagram has. VLAN tagged frames
+//! only count at Ethernet. Drops are counted by the layer that drops the
+//! packet, errors are the malformed packets of `ParseErrors`. None of the
+//! protocols implemented retransmits, so there is no counter for that yet.
+//!
+//! `NetworkStack::latencies` returns a `LatencyStats` with histograms, see
+//! `histogram`, of how long what the stack waits for took, in microseconds:
+//! Arp resolutions, from the first lookup missing the address to the reply,
+//! and Icmp echoes, from a request sent to the reply with the same
+//! destination, identifier and sequence number. A TCP handshake will be
+//! timed the same way once TCP is implemented.
+
+use EthernetChannel;
+use histogram::Histogram;
+use malformed::{Layer, ParseErrors};
+
+use pnet::datalink::{EthernetDataLinkChannelIterator, EthernetDataLinkReceiver,
+                     EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Echo requests waiting for their reply at most, past this new ones are not
+/// timed.
+pub const MAX_PENDING_ECHOES: usize = 1024;
+
+/// How long an echo request is waited for, before it is forgotten to make
+/// room for new ones.
+pub const ECHO_TIMEOUT_SECS: u64 = 60;
+
+/// What one protocol received and sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LayerStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// Packets received but not delivered, because nothing listened for
+    /// them or the firewall dropped them
+    pub rx_dropped: u64,
+    /// Malformed packets received, see `malformed`
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Packets the datalink failed to send
+    pub tx_errors: u64,
+}
+
+impl LayerStats {
+    /// Adds the counters of `other` to these.
+    pub fn add(&mut self, other: &LayerStats) {
+        self.rx_packets += other.rx_packets;
+        self.rx_bytes += other.rx_bytes;
+        self.rx_dropped += other.rx_dropped;
+        self.rx_errors += other.rx_errors;
+        self.tx_packets += other.tx_packets;
+        self.tx_bytes += other.tx_bytes;
+        self.tx_errors += other.tx_errors;
+    }
+}
+
+/// The counters of a stack, or of one of its interfaces. See the module
+/// documentation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackStats {
+    pub ethernet: LayerStats,
+    pub arp: LayerStats,
+    pub ipv4: LayerStats,
+    pub icmp: LayerStats,
+    pub udp: LayerStats,
+    /// Lookups of the Arp cache finding the address
+    pub arp_cache_hits: u64,
+    /// Lookups of the Arp cache that had to wait for a reply
+    pub arp_cache_misses: u64,
+}
+
+impl StackStats {
+    /// Returns the counters of `layer`.
+    pub fn get(&self, layer: Layer) -> &LayerStats {
+        match layer {
+            Layer::Ethernet => &self.ethernet,
+            Layer::Arp => &self.arp,
+            Layer::Ipv4 => &self.ipv4,
+            Layer::Udp => &self.udp,
+            Layer::Icmp => &self.icmp,
+        }
+    }
+
+    /// Adds the counters of `other` to these.
+    pub fn add(&mut self, other: &StackStats) {
+        self.ethernet.add(&other.ethernet);
+        self.arp.add(&other.arp);
+        self.ipv4.add(&other.ipv4);
+        self.icmp.add(&other.icmp);
+        self.udp.add(&other.udp);
+        self.arp_cache_hits += other.arp_cache_hits;
+        self.arp_cache_misses += other.arp_cache_misses;
+    }
+}
+
+/// The latencies of a stack, or of one of its interfaces, in microseconds.
+/// See the module documentation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub arp_resolve: Histogram,
+    pub icmp_echo: Histogram,
+}
+
+impl LatencyStats {
+    /// Adds the values counted by `other` to these.
+    pub fn add(&mut self, other: &LatencyStats) {
+        self.arp_resolve.add(&other.arp_resolve);
+        self.icmp_echo.add(&other.icmp_echo);
+    }
+}
+
+#[derive(Debug, Default)]
+struct LayerCounters {
+    rx_packets: AtomicUsize,
+    rx_bytes: AtomicUsize,
+    rx_dropped: AtomicUsize,
+    tx_packets: AtomicUsize,
+    tx_bytes: AtomicUsize,
+    tx_errors: AtomicUsize,
+}
+
+/// The counters of one interface, shared by its datalink and the layers
+/// receiving on it.
+#[derive(Debug, Default)]
+pub struct Counters {
+    layers: [LayerCounters; 5],
+    echoes: Mutex<Echoes>,
+}
+
+/// Peer, identifier and sequence number of an echo request or reply.
+type EchoKey = (Ipv4Addr, u16, u16);
+
+#[derive(Debug, Default)]
+struct Echoes {
+    /// When the requests not answered yet were sent
+    pending: HashMap<EchoKey, Instant>,
+    round_trips: Histogram,
+}
+
+impl Counters {
+    /// Counts a packet received at `layer` that was not delivered.
+    pub fn dropped(&self, layer: Layer) {
+        self.layer(layer).rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counters, with the errors taken from `parse_errors`.
+    /// The Arp cache counters are left at zero.
+    pub fn stats(&self, parse_errors: &ParseErrors) -> StackStats {
+        let stats_of = |layer: Layer| {
+            let counters = self.layer(layer);
+            let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as u64;
+            LayerStats {
+                rx_packets: load(&counters.rx_packets),
+                rx_bytes: load(&counters.rx_bytes),
+                rx_dropped: load(&counters.rx_dropped),
+                rx_errors: parse_errors.get(layer),
+                tx_packets: load(&counters.tx_packets),
+                tx_bytes: load(&counters.tx_bytes),
+                tx_errors: load(&counters.tx_errors),
+            }
+        };
+        StackStats {
+            ethernet: stats_of(Layer::Ethernet),
+            arp: stats_of(Layer::Arp),
+            ipv4: stats_of(Layer::Ipv4),
+            icmp: stats_of(Layer::Icmp),
+            udp: stats_of(Layer::Udp),
+            arp_cache_hits: 0,
+            arp_cache_misses: 0,
+        }
+    }
+
+    /// Returns the round trip times of the Icmp echoes sent, in
+    /// microseconds.
+    pub fn echo_round_trips(&self) -> Histogram {
+        self.echoes.lock().unwrap().round_trips.clone()
+    }
+
+    fn layer(&self, layer: Layer) -> &LayerCounters {
+        &self.layers[index(layer)]
+    }
+
+    fn received(&self, frame: &[u8]) {
+        layers(frame, |layer, bytes| {
+            let counters = self.layer(layer);
+            counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+            counters.rx_bytes.fetch_add(bytes, Ordering::Relaxed);
+        });
+        if let Some((false, key)) = echo(frame) {
+            let mut echoes = self.echoes.lock().unwrap();
+            if let Some(sent) = echoes.pending.remove(&key) {
+                echoes.round_trips.record_duration(sent.elapsed());
+            }
+        }
+    }
+
+    /// Starts timing `frame` if it is an echo request, about to be sent.
+    fn sending(&self, frame: &[u8]) {
+        let key = match echo(frame) {
+            Some((true, key)) => key,
+            _ => return,
+        };
+        let mut echoes = self.echoes.lock().unwrap();
+        if echoes.pending.len() >= MAX_PENDING_ECHOES {
+            let timeout = Duration::from_secs(ECHO_TIMEOUT_SECS);
+            let expired = echoes.pending
+                .iter()
+                .filter(|&(_, sent)| sent.elapsed() >= timeout)
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            for key in expired {
+                echoes.pending.remove(&key);
+            }
+            if echoes.pending.len() >= MAX_PENDING_ECHOES {
+                return;
+            }
+        }
+        echoes.pending.insert(key, Instant::now());
+    }
+
+    fn sent(&self, tally: &Tally, result: &Option<io::Result<()>>) {
+        for (counters, &(packets, bytes)) in self.layers.iter().zip(tally.iter()) {
+            if let Some(Ok(())) = *result {
+                counters.tx_packets.fetch_add(packets, Ordering::Relaxed);
+                counters.tx_bytes.fetch_add(bytes, Ordering::Relaxed);
+            } else {
+                counters.tx_errors.fetch_add(packets, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+fn index(layer: Layer) -> usize {
+    match layer {
+        Layer::Ethernet => 0,
+        Layer::Arp => 1,
+        Layer::Ipv4 => 2,
+        Layer::Udp => 3,
+        Layer::Icmp => 4,
+    }
+}
+
+/// Calls `f` with every layer `frame` carries and the bytes of that layer.
+pub fn layers<F: FnMut(Layer, usize)>(frame: &[u8], mut f: F) {
+    f(Layer::Ethernet, frame.len());
+    let packet = match EthernetPacket::new(frame) {
+        Some(packet) => packet,
+        None => return,
+    };
+    let ether_type = packet.get_ethertype();
+    let payload = packet.payload();
+    if ether_type == EtherTypes::Arp {
+        f(Layer::Arp, cmp::min(payload.len(), ArpPacket::minimum_packet_size()));
+    } else if ether_type == EtherTypes::Ipv4 {
+        let ip_pkg = match Ipv4Packet::new(payload) {
+            Some(ip_pkg) => ip_pkg,
+            None => return,
+        };
+        let total_length = cmp::min(ip_pkg.get_total_length() as usize, payload.len());
+        f(Layer::Ipv4, total_length);
+        let header_length = ip_pkg.get_header_length() as usize * 4;
+        if ip_pkg.get_fragment_offset() != 0 || header_length > total_length {
+            return;
+        }
+        let data = &payload[header_length..total_length];
+        let protocol = ip_pkg.get_next_level_protocol();
+        if protocol == IpNextHeaderProtocols::Udp && data.len() >= 8 {
+            f(Layer::Udp, (data[4] as usize) << 8 | data[5] as usize);
+        } else if protocol == IpNextHeaderProtocols::Icmp {
+            f(Layer::Icmp, data.len());
+        }
+    }
+}
+
+/// Returns whether `frame` is an Icmp echo request, or reply, and its key.
+/// The peer is the destination of a request and the source of a reply.
+fn echo(frame: &[u8]) -> Option<(bool, EchoKey)> {
+    let packet = match EthernetPacket::new(frame) {
+        Some(packet) => packet,
+        None => return None,
+    };
+    if packet.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let payload = packet.payload();
+    let ip_pkg = match Ipv4Packet::new(payload) {
+        Some(ip_pkg) => ip_pkg,
+        None => return None,
+    };
+    let header_length = ip_pkg.get_header_length() as usize * 4;
+    if ip_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Icmp ||
+       ip_pkg.get_fragment_offset() != 0 || payload.len() < header_length + 8 {
+        return None;
+    }
+    let icmp = &payload[header_length..];
+    let request = if icmp[0] == IcmpTypes::EchoRequest.0 {
+        true
+    } else if icmp[0] == IcmpTypes::EchoReply.0 {
+        false
+    } else {
+        return None;
+    };
+    let peer = if request { ip_pkg.get_destination() } else { ip_pkg.get_source() };
+    let identifier = (icmp[4] as u16) << 8 | icmp[5] as u16;
+    let sequence = (icmp[6] as u16) << 8 | icmp[7] as u16;
+    Some((request, (peer, identifier, sequence)))
+}
+
+/// Packets and bytes by layer, of frames counted once sent.
+type Tally = [(usize, usize); 5];
+
+/// Returns `channel` counting everything sent and received on it in
+/// `counters`.
+pub fn channel(channel: EthernetChannel, counters: Arc<Counters>) -> EthernetChannel {
+    let EthernetChannel(tx, rx) = channel;
+    let sender = Sender {
+        tx: tx,
+        counters: counters.clone(),
+    };
+    let receiver = Receiver {
+        rx: rx,
+        counters: counters,
+    };
+    EthernetChannel(Box::new(sender), Box::new(receiver))
+}
+
+struct Sender {
+    tx: Box<EthernetDataLinkSender>,
+    counters: Arc<Counters>,
+}
+
+impl EthernetDataLinkSender for Sender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        let mut tally = [(0, 0); 5];
+        let counters = &self.counters;
+        let result = {
+            // Read back in the buffer of the datalink once built
+            let mut count = |mut packet: MutableEthernetPacket| {
+                func(MutableEthernetPacket::new(packet.packet_mut()).unwrap());
+                counters.sending(packet.packet());
+                layers(packet.packet(), |layer, bytes| {
+                    let entry = &mut tally[index(layer)];
+                    entry.0 += 1;
+                    entry.1 += bytes;
+                });
+            };
+            self.tx.build_and_send(num_packets, packet_size, &mut count)
+        };
+        self.counters.sent(&tally, &result);
+        result
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        let mut tally = [(0, 0); 5];
+        layers(packet.packet(), |layer, bytes| tally[index(layer)] = (1, bytes));
+        self.counters.sending(packet.packet());
+        let result = self.tx.send_to(packet, dst);
+        self.counters.sent(&tally, &result);
+        result
+    }
+}
+
+struct Receiver {
+    rx: Box<EthernetDataLinkReceiver>,
+    counters: Arc<Counters>,
+}
+
+impl EthernetDataLinkReceiver for Receiver {
+    fn iter<'a>(&'a mut self) -> Box<EthernetDataLinkChannelIterator + 'a> {
+        Box::new(ReceiverIterator {
+            rx: self.rx.iter(),
+            counters: self.counters.clone(),
+        })
+    }
+}
+
+struct ReceiverIterator<'a> {
+    rx: Box<EthernetDataLinkChannelIterator<'a> + 'a>,
+    counters: Arc<Counters>,
+}
+
+impl<'a> EthernetDataLinkChannelIterator<'a> for ReceiverIterator<'a> {
+    fn next(&mut self) -> io::Result<EthernetPacket> {
+        let packet = self.rx.next()?;
+        self.counters.received(packet.packet());
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use malformed::Layer;
+
+    use super::*;
+
+    #[test]
+    fn layers_of_frames() {
+        let mut frame = vec![0; 14 + 20 + 8 + 4];
+        frame[12] = 0x08;
+        frame[14] = 0x45;
+        frame[14 + 3] = 20 + 8 + 4;
+        frame[14 + 9] = 17;
+        frame[14 + 20 + 5] = 8 + 4;
+        let mut seen = Vec::new();
+        layers(&frame, |layer, bytes| seen.push((layer, bytes)));
+        assert_eq!(seen, vec![(Layer::Ethernet, 46), (Layer::Ipv4, 32), (Layer::Udp, 12)]);
+
+        // A later fragment only counts at IPv4
+        frame[14 + 7] = 1;
+        seen.clear();
+        layers(&frame, |layer, bytes| seen.push((layer, bytes)));
+        assert_eq!(seen, vec![(Layer::Ethernet, 46), (Layer::Ipv4, 32)]);
+
+        // Padded Arp
+        let mut frame = vec![0; 60];
+        frame[12] = 0x08;
+        frame[13] = 0x06;
+        seen.clear();
+        layers(&frame, |layer, bytes| seen.push((layer, bytes)));
+        assert_eq!(seen, vec![(Layer::Ethernet, 60), (Layer::Arp, 28)]);
+    }
+}
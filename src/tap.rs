@@ -0,0 +1,189 @@
+//! Linux TAP devices as links of a stack, without pnet.
+//!
+//! A TAP device is a virtual Ethernet interface of the host whose other end
+//! is a file descriptor. `TapDevice` opens one through `/dev/net/tun`, so a
+//! stack can be bridged with virtual machines or containers, or two stacks
+//! can be connected through a host bridge in tests, without the raw socket
+//! access pnet needs:
+//!
+//! ```rust,ignore
+//! let tap = TapDevice::open("tap0")?;
+//! let interface = stack.add_datalink(tap.name(), Arc::new(tap))?;
+//! ```
+//!
+//! Opening a device needs `CAP_NET_ADMIN`, or a device created beforehand
+//! for the user with `ip tuntap add tap0 mode tap user <user>`. A device
+//! the kernel creates for `open` goes away again when the `TapDevice` is
+//! dropped. The stack gets a MAC address of its own on the device, the one
+//! the host sees on its end of it belongs to the host.
+
+use {StackError, StackResult};
+use datalink::{self, Datalink};
+use rx;
+use stack::MAX_MTU;
+use tun;
+
+use libc;
+
+use pnet::util::MacAddr;
+
+use rand::{self, Rng};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+/// Length of interface names including the terminating NUL
+const IFNAMSIZ: usize = 16;
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+
+/// Room for an Ethernet header with a VLAN tag in front of the payload
+const FRAME_OVERHEAD: usize = 18;
+
+/// `struct ifreq` with the flags member of its union.
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; IFNAMSIZ],
+    flags: libc::c_short,
+    _union: [u8; 22],
+}
+
+/// A TAP device opened through `/dev/net/tun`. See the module documentation.
+pub struct TapDevice {
+    file: File,
+    name: String,
+    mac: MacAddr,
+    mtu: Option<usize>,
+}
+
+impl TapDevice {
+    /// Opens the TAP device `name`, creating it if it does not exist, with a
+    /// random locally administered MAC address for the stack.
+    pub fn open(name: &str) -> StackResult<TapDevice> {
+        Self::with_mac(name, random_mac())
+    }
+
+    /// Same as `open`, giving the stack the MAC address `mac`. Fails with
+    /// `StackError::InvalidArgument` if `name` is no valid interface name.
+    pub fn with_mac(name: &str, mac: MacAddr) -> StackResult<TapDevice> {
+        if name.is_empty() || name.len() >= IFNAMSIZ || name.contains('\0') ||
+           name.contains('/') {
+            let msg = format!("{:?} is no valid interface name", name);
+            return Err(StackError::InvalidArgument(msg));
+        }
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        let mut request = IfReq {
+            name: [0; IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI,
+            _union: [0; 22],
+        };
+        for (dst, &byte) in request.name.iter_mut().zip(name.as_bytes()) {
+            *dst = byte as libc::c_char;
+        }
+        // The kernel only reads and writes within the ifreq given
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut request) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // The kernel fills in the name it picked for patterns like "tap%d"
+        let name: Vec<u8> = request.name
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as u8)
+            .collect();
+        let name = String::from_utf8_lossy(&name).into_owned();
+        Ok(TapDevice {
+            mtu: datalink::os_mtu(&name),
+            file: file,
+            name: name,
+            mac: mac,
+        })
+    }
+
+    /// The name of the device on the host.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Datalink for TapDevice {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let written = (&self.file).write(frame)?;
+        if written != frame.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Frame partially written"));
+        }
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        tun::poll_readable(&self.file, rx::POLL_INTERVAL_MS)?;
+        let mut buffer = vec![0; MAX_MTU + FRAME_OVERHEAD];
+        let len = (&self.file).read(&mut buffer)?;
+        buffer.truncate(len);
+        Ok(buffer.into_boxed_slice())
+    }
+}
+
+/// Returns a random unicast MAC address from the locally administered range.
+fn random_mac() -> MacAddr {
+    let mut rng = rand::thread_rng();
+    MacAddr::new((rng.gen::<u8>() & 0xfc) | 0x02,
+                 rng.gen(),
+                 rng.gen(),
+                 rng.gen(),
+                 rng.gen(),
+                 rng.gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use StackError;
+    use datalink::Datalink;
+
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn invalid_names() {
+        for name in &["", "sixteen-chars-xx", "tap/0", "tap\0"] {
+            match TapDevice::open(name) {
+                Err(StackError::InvalidArgument(..)) => (),
+                _ => panic!("Expected InvalidArgument for {:?}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn open_device() {
+        let tap = match TapDevice::open("ripstap%d") {
+            Ok(tap) => tap,
+            // Without CAP_NET_ADMIN or /dev/net/tun there is nothing to test
+            Err(StackError::IoError(_)) => return,
+            Err(e) => panic!("Unexpected error {:?}", e),
+        };
+        assert!(tap.name().starts_with("ripstap"));
+        assert_ne!(tap.name(), "ripstap%d");
+        assert_eq!(tap.mtu(), Some(1500));
+        // The device is down, so nothing arrives
+        assert_eq!(tap.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn locally_administered_mac() {
+        for _ in 0..16 {
+            let MacAddr(first, ..) = random_mac();
+            assert_eq!(first & 0x03, 0x02);
+        }
+    }
+}
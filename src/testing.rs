@@ -1,15 +1,36 @@
 use {EthernetChannel, Interface, NetworkStack};
+use clock::Clock;
+use datalink::{self, Datalink};
+use rx;
+
+use ipnetwork::Ipv4Network;
 
 use pnet::datalink::{Channel, dummy};
+use pnet::packet::MutablePacket;
+use pnet::packet::arp::{ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
 
 use std::io;
-use std::sync::mpsc::{Receiver, Sender};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
 
 pub fn dummy_ethernet
     ()
     -> (EthernetChannel, Interface, Sender<io::Result<Box<[u8]>>>, Receiver<Box<[u8]>>)
 {
-    let iface = dummy::dummy_interface(0);
+    dummy_ethernet_indexed(0)
+}
+
+/// Same as `dummy_ethernet` but with a dummy interface unique to `index`, for
+/// setting up stacks with more than one interface.
+pub fn dummy_ethernet_indexed
+    (index: u8)
+    -> (EthernetChannel, Interface, Sender<io::Result<Box<[u8]>>>, Receiver<Box<[u8]>>)
+{
+    let iface = dummy::dummy_interface(index);
     let mac = iface.mac.unwrap();
     let interface = Interface {
         name: iface.name.clone(),
@@ -39,6 +60,136 @@ pub fn dummy_stack
     (stack, interface, inject_handle, read_handle)
 }
 
+/// Same as `dummy_stack`, but with a stack created with
+/// `NetworkStack::new_polled`.
+pub fn dummy_polled_stack
+    ()
+    -> (NetworkStack, Interface, Sender<io::Result<Box<[u8]>>>, Receiver<Box<[u8]>>)
+{
+    let (channel, interface, inject_handle, read_handle) = dummy_ethernet();
+    let mut stack = NetworkStack::new_polled();
+    stack.add_interface(interface.clone(), channel)
+        .expect("Not able to add dummy channel to stack");
+    (stack, interface, inject_handle, read_handle)
+}
+
+/// Same as `dummy_polled_stack`, but taking the time from `clock`, usually a
+/// `clock::MockClock`.
+pub fn dummy_polled_stack_with_clock
+    (clock: Arc<Clock>)
+    -> (NetworkStack, Interface, Sender<io::Result<Box<[u8]>>>, Receiver<Box<[u8]>>)
+{
+    let (channel, interface, inject_handle, read_handle) = dummy_ethernet();
+    let mut stack = NetworkStack::new_polled();
+    stack.set_clock(clock).unwrap();
+    stack.add_interface(interface.clone(), channel)
+        .expect("Not able to add dummy channel to stack");
+    (stack, interface, inject_handle, read_handle)
+}
+
+/// Returns a broadcast Arp request for `target` from 10.0.0.2 at
+/// 02:00:00:00:00:09, to inject into an interface.
+pub fn arp_request(target: Ipv4Addr) -> Box<[u8]> {
+    let sender_mac = MacAddr::new(2, 0, 0, 0, 0, 9);
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
+                             ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(sender_mac);
+        eth_pkg.set_destination(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_operation(ArpOperations::Request);
+        arp_pkg.set_sender_hw_addr(sender_mac);
+        arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 2));
+        arp_pkg.set_target_proto_addr(target);
+    }
+    buffer.into_boxed_slice()
+}
+
+/// Returns two channels connected to each other like the ends of a veth
+/// pair, with interfaces `veth0` and `veth1` for them. What is sent on one
+/// end is received on the other, through queues in the process, so two
+/// stacks can talk to each other without privileges or NICs.
+pub fn veth_pair() -> ((EthernetChannel, Interface), (EthernetChannel, Interface)) {
+    let (a_tx, b_rx) = mpsc::channel();
+    let (b_tx, a_rx) = mpsc::channel();
+    let end = |index: u8, tx, rx| {
+        let interface = Interface {
+            name: format!("veth{}", index),
+            mac: MacAddr::new(2, 0, 0, 0, 0xee, index),
+        };
+        let end = VethEnd {
+            mac: interface.mac,
+            tx: Mutex::new(tx),
+            rx: Mutex::new(rx),
+        };
+        (datalink::channel(Arc::new(end)), interface)
+    };
+    (end(0, a_tx, a_rx), end(1, b_tx, b_rx))
+}
+
+/// Returns 10.0.0.`last`, an address on the network of `connected_pair`.
+pub fn ip(last: u8) -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 0, last)
+}
+
+/// Returns two stacks on the ends of a `veth_pair`, with `ip(a)` and `ip(b)`
+/// in 10.0.0.0/24, and the interfaces they have them on. For tests adding
+/// addresses, routes or groups to the stacks before sharing them, the others
+/// use `connected_stacks`.
+pub fn connected_pair(a: u8, b: u8) -> ((NetworkStack, Interface), (NetworkStack, Interface)) {
+    let (end_a, end_b) = veth_pair();
+    let stack = |last: u8, (channel, interface): (EthernetChannel, Interface)| {
+        let mut stack = NetworkStack::new();
+        stack.add_interface(interface.clone(), channel)
+            .expect("Not able to add veth channel to stack");
+        stack.add_ipv4(&interface, Ipv4Network::new(ip(last), 24).unwrap())
+            .expect("Not able to add address to stack");
+        (stack, interface)
+    };
+    (stack(a, end_a), stack(b, end_b))
+}
+
+/// Same as `connected_pair`, with the stacks ready to be shared.
+pub fn connected_stacks(a: u8, b: u8) -> (Arc<Mutex<NetworkStack>>, Arc<Mutex<NetworkStack>>) {
+    let ((stack_a, _), (stack_b, _)) = connected_pair(a, b);
+    (Arc::new(Mutex::new(stack_a)), Arc::new(Mutex::new(stack_b)))
+}
+
+/// One end of a `veth_pair`.
+struct VethEnd {
+    mac: MacAddr,
+    tx: Mutex<Sender<Box<[u8]>>>,
+    rx: Mutex<Receiver<Box<[u8]>>>,
+}
+
+impl Datalink for VethEnd {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    /// Frames sent after the other end is gone are lost, like on a link
+    /// without a peer.
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.tx.lock().unwrap().send(frame.to_vec().into_boxed_slice()).unwrap_or(());
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let timeout = Duration::from_millis(rx::POLL_INTERVAL_MS);
+        match self.rx.lock().unwrap().recv_timeout(timeout) {
+            Ok(frame) => Ok(frame),
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received"))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "Other end dropped"))
+            }
+        }
+    }
+}
+
 // pub fn dummy_icmp()
 //     -> (Ethernet,
 //         Arc<Mutex<IcmpListenerLookup>>,
@@ -0,0 +1,1065 @@
+//! Trivial File Transfer Protocol, RFC 1350, with the option negotiation of
+//! RFC 2347 and the `blksize`, `timeout` and `tsize` options of RFCs 2348
+//! and 2349, over the stack's own UDP.
+//!
+//! `spawn` serves the files of a directory from `TFTP_PORT` of a local
+//! address, for network boot and provisioning, and `TftpClient` gets and
+//! puts files on a server:
+//!
+//! ```rust,ignore
+//! let config = TftpServerConfig::new(PathBuf::from("/srv/tftp"));
+//! let server = tftp::spawn(stack.clone(), Ipv4Addr::new(10, 0, 0, 1), config)?;
+//!
+//! let server_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), TFTP_PORT);
+//! let config = TftpClientConfig::default();
+//! let client = TftpClient::new(stack.clone(), local_ip, server_addr, config)?;
+//! let image = client.get("firmware.bin")?;
+//! ```
+//!
+//! Every transfer runs over a socket of its own, bound to a random port, the
+//! transfer identifier of the RFC. A block is sent once the one before it is
+//! acknowledged, and sent again when no acknowledgement comes within the
+//! timeout, up to `retries` times. Duplicate acknowledgements are ignored
+//! instead of answered with the next block again, which avoids the
+//! Sorcerer's Apprentice Syndrome of RFC 1350. Block numbers roll over from
+//! 65535 to 0, so files of any size are transferred. Datagrams from ports
+//! other than the one of the transfer are answered with an "Unknown transfer
+//! ID" error.
+//!
+//! Files are transferred in `octet` mode, or in `netascii` mode with line
+//! ends converted to CR LF on the wire.
+//!
+//! The server only serves files under its root, names leading out of it are
+//! refused. It writes files only if its config allows it, and never over an
+//! existing file. Written files are kept in memory until the transfer is
+//! complete, so a failed transfer leaves no partial file behind.
+
+use {NetworkStack, StackError, StackResult};
+use sockopt::{HasSocketOptions, ReadTimeout};
+use udp::UdpSocket;
+
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::u64;
+
+/// The port servers receive requests on.
+pub const TFTP_PORT: u16 = 69;
+
+/// The size of blocks, unless negotiated otherwise.
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// The range of block sizes of RFC 2348.
+pub const MIN_BLOCK_SIZE: usize = 8;
+pub const MAX_BLOCK_SIZE: usize = 65464;
+
+/// How long the thread of `spawn` waits for a request before checking if it
+/// is to stop.
+const POLL_INTERVAL_MS: u64 = 100;
+
+const RRQ: u16 = 1;
+const WRQ: u16 = 2;
+const DATA: u16 = 3;
+const ACK: u16 = 4;
+const ERROR: u16 = 5;
+const OACK: u16 = 6;
+
+const NOT_DEFINED: u16 = 0;
+const FILE_NOT_FOUND: u16 = 1;
+const ACCESS_VIOLATION: u16 = 2;
+const DISK_FULL: u16 = 3;
+const ILLEGAL_OPERATION: u16 = 4;
+const UNKNOWN_TID: u16 = 5;
+const FILE_EXISTS: u16 = 6;
+const OPTION_REFUSED: u16 = 8;
+
+/// How the data of a file is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// As it is
+    Octet,
+    /// As text, with CR LF line ends on the wire
+    Netascii,
+}
+
+impl Mode {
+    fn from_name(name: &str) -> Option<Mode> {
+        match &name.to_lowercase()[..] {
+            "octet" => Some(Mode::Octet),
+            "netascii" => Some(Mode::Netascii),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Mode::Octet => "octet",
+            Mode::Netascii => "netascii",
+        }
+    }
+}
+
+/// What a server started with `spawn` serves, and how patiently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TftpServerConfig {
+    /// The directory holding the files served
+    pub root: PathBuf,
+    /// If clients may write new files
+    pub allow_write: bool,
+    /// How long to wait for a client before sending again, unless it asks
+    /// for another timeout
+    pub timeout: Duration,
+    /// How many times a datagram is sent again before giving up
+    pub retries: usize,
+    /// Largest block size given to clients asking for larger blocks
+    pub max_block_size: usize,
+    /// Largest file clients may write
+    pub max_write_size: u64,
+}
+
+impl TftpServerConfig {
+    /// Serves the files of `root` for reading only, with blocks fitting an
+    /// Ethernet frame at most, waiting a second for clients five times.
+    pub fn new(root: PathBuf) -> TftpServerConfig {
+        TftpServerConfig {
+            root: root,
+            allow_write: false,
+            timeout: Duration::from_secs(1),
+            retries: 5,
+            max_block_size: 1468,
+            max_write_size: 32 << 20,
+        }
+    }
+
+    /// Returns the path of the file `filename` under the root, or `None` if
+    /// it leads out of the root. Leading slashes are ignored.
+    fn path(&self, filename: &str) -> Option<PathBuf> {
+        let relative = Path::new(filename.trim_left_matches('/'));
+        let inside = relative.components().all(|component| match component {
+            Component::Normal(_) | Component::CurDir => true,
+            _ => false,
+        });
+        if inside && relative.file_name().is_some() {
+            Some(self.root.join(relative))
+        } else {
+            None
+        }
+    }
+}
+
+/// The options a transfer uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Options {
+    block_size: usize,
+    timeout: Duration,
+    /// The size of the file, if told
+    transfer_size: Option<u64>,
+}
+
+/// Returns the options a server with `config` uses for a request with
+/// `requested`, and the ones to acknowledge. `file_size` is the size of the
+/// file read, `None` for writes.
+///
+/// Fails with the error to send if the file to write is too large.
+fn negotiate(config: &TftpServerConfig,
+             requested: &[(String, String)],
+             file_size: Option<u64>)
+             -> Result<(Options, Vec<(String, String)>), (u16, String)> {
+    let mut options = Options {
+        block_size: DEFAULT_BLOCK_SIZE,
+        timeout: config.timeout,
+        transfer_size: None,
+    };
+    let mut accepted = Vec::new();
+    for &(ref name, ref value) in requested {
+        let value = match value.parse::<u64>() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let accepted_value = match &name.to_lowercase()[..] {
+            "blksize" if value >= MIN_BLOCK_SIZE as u64 => {
+                let max = cmp::min(config.max_block_size, MAX_BLOCK_SIZE);
+                options.block_size = cmp::min(value, max as u64) as usize;
+                options.block_size as u64
+            }
+            "timeout" if value >= 1 && value <= 255 => {
+                options.timeout = Duration::from_secs(value);
+                value
+            }
+            "tsize" => {
+                let size = match file_size {
+                    Some(size) => size,
+                    None if value > config.max_write_size => {
+                        return Err((DISK_FULL, format!("Files are {} bytes at most",
+                                                       config.max_write_size)));
+                    }
+                    None => value,
+                };
+                options.transfer_size = Some(size);
+                size
+            }
+            _ => continue,
+        };
+        accepted.push((name.clone(), accepted_value.to_string()));
+    }
+    Ok((options, accepted))
+}
+
+/// Stops the server started with `spawn` when dropped. Transfers under way
+/// are completed.
+pub struct TftpServerHandle {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TftpServerHandle {
+    /// Stops taking requests and waits for the thread to quit.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for TftpServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Starts serving the files of `config` from `TFTP_PORT` of the local
+/// address `local_ip`, every transfer from a thread and socket of its own.
+///
+/// Fails with `StackError::InvalidArgument` if the root of `config` is no
+/// directory or its largest block size is below `MIN_BLOCK_SIZE`, and like
+/// `UdpSocket::bind` if the port can not be bound.
+pub fn spawn(stack: Arc<Mutex<NetworkStack>>,
+             local_ip: Ipv4Addr,
+             config: TftpServerConfig)
+             -> StackResult<TftpServerHandle> {
+    if !config.root.is_dir() {
+        let msg = format!("{} is no directory", config.root.display());
+        return Err(StackError::InvalidArgument(msg));
+    }
+    if config.max_block_size < MIN_BLOCK_SIZE {
+        let msg = format!("Blocks must be at least {} bytes", MIN_BLOCK_SIZE);
+        return Err(StackError::InvalidArgument(msg));
+    }
+    let socket = UdpSocket::bind(stack.clone(), SocketAddrV4::new(local_ip, TFTP_PORT))?;
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+    let running = Arc::new(AtomicBool::new(true));
+
+    let thread_running = running.clone();
+    let thread = thread::spawn(move || {
+        let mut buffer = vec![0; 1 << 16];
+        while thread_running.load(Ordering::SeqCst) {
+            let (len, src) = match socket.recv_from(&mut buffer) {
+                Ok((len, SocketAddr::V4(src))) => (len, src),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    warn!("TFTP server: Dropping datagram: {}", e);
+                    continue;
+                }
+            };
+            let request = match Packet::parse(&buffer[..len]) {
+                Some(request @ Packet::Request { .. }) => request,
+                _ => continue,
+            };
+            let (stack, config) = (stack.clone(), config.clone());
+            thread::spawn(move || {
+                let result = Transfer::new(&stack, local_ip, src, true, config.timeout,
+                                           config.retries)
+                    .and_then(|mut transfer| transfer.serve(&config, &request));
+                if let Err(e) = result {
+                    warn!("TFTP server: Transfer with {} failed: {}", src, e);
+                }
+            });
+        }
+    });
+    Ok(TftpServerHandle {
+        running: running,
+        thread: Some(thread),
+    })
+}
+
+/// How a `TftpClient` transfers files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TftpClientConfig {
+    pub mode: Mode,
+    /// The block size to ask for, or `None` for the default
+    pub block_size: Option<usize>,
+    /// How long to wait for the server before sending again
+    pub timeout: Duration,
+    /// The timeout to ask the server to use, in whole seconds, if any
+    pub server_timeout: Option<Duration>,
+    /// How many times a datagram is sent again before giving up
+    pub retries: usize,
+    /// If the size of files is sent along with writes, and asked for with
+    /// reads
+    pub transfer_size: bool,
+}
+
+impl Default for TftpClientConfig {
+    /// Transfers in `octet` mode without options, waiting a second for the
+    /// server five times.
+    fn default() -> TftpClientConfig {
+        TftpClientConfig {
+            mode: Mode::Octet,
+            block_size: None,
+            timeout: Duration::from_secs(1),
+            server_timeout: None,
+            retries: 5,
+            transfer_size: false,
+        }
+    }
+}
+
+/// Gets and puts files on one TFTP server, from a local address of a stack.
+pub struct TftpClient {
+    stack: Arc<Mutex<NetworkStack>>,
+    local_ip: Ipv4Addr,
+    server: SocketAddrV4,
+    config: TftpClientConfig,
+}
+
+impl TftpClient {
+    /// Creates a client of the server at `server`, usually on `TFTP_PORT`,
+    /// sending from `local_ip`.
+    ///
+    /// Fails with `StackError::InvalidArgument` if the block size of
+    /// `config` is out of the range of RFC 2348, or its server timeout is
+    /// not between 1 and 255 seconds.
+    pub fn new(stack: Arc<Mutex<NetworkStack>>,
+               local_ip: Ipv4Addr,
+               server: SocketAddrV4,
+               config: TftpClientConfig)
+               -> StackResult<TftpClient> {
+        if let Some(block_size) = config.block_size {
+            if block_size < MIN_BLOCK_SIZE || block_size > MAX_BLOCK_SIZE {
+                let msg = format!("Invalid block size: {}", block_size);
+                return Err(StackError::InvalidArgument(msg));
+            }
+        }
+        if let Some(timeout) = config.server_timeout {
+            if timeout.as_secs() < 1 || timeout.as_secs() > 255 {
+                let msg = format!("Invalid server timeout: {:?}", timeout);
+                return Err(StackError::InvalidArgument(msg));
+            }
+        }
+        Ok(TftpClient {
+            stack: stack,
+            local_ip: local_ip,
+            server: server,
+            config: config,
+        })
+    }
+
+    pub fn config(&self) -> &TftpClientConfig {
+        &self.config
+    }
+
+    /// Reads the file `filename` of the server.
+    ///
+    /// Fails with `StackError::NotFound` if the server has no such file,
+    /// with the `io::Error` of the other errors the server sends, and with
+    /// `io::ErrorKind::TimedOut` if it stops answering.
+    pub fn get(&self, filename: &str) -> StackResult<Vec<u8>> {
+        let mut transfer = self.transfer()?;
+        let request = self.request(false, filename, 0);
+        let reply = transfer.exchange(&request, |reply| match *reply {
+                Packet::Oack(ref options) => Some(Ok(options.clone())),
+                Packet::Data { block: 1, ref data } => Some(Err(data.clone())),
+                _ => None,
+            })?;
+        let (data, last_block) = match reply {
+            Ok(options) => {
+                let options = self.accept(&mut transfer, &options)?;
+                transfer.receive(Packet::Ack(0), None, options, u64::MAX)?
+            }
+            Err(first) => transfer.receive(request, Some(first), self.defaults(), u64::MAX)?,
+        };
+        transfer.acknowledge(last_block)?;
+        Ok(match self.config.mode {
+            Mode::Octet => data,
+            Mode::Netascii => from_netascii(&data),
+        })
+    }
+
+    /// Writes `data` to the new file `filename` of the server.
+    ///
+    /// Fails with the `io::Error` of the errors the server sends, like
+    /// `io::ErrorKind::AlreadyExists` if it has the file already, and with
+    /// `io::ErrorKind::TimedOut` if it stops answering.
+    pub fn put(&self, filename: &str, data: &[u8]) -> StackResult<()> {
+        let data = match self.config.mode {
+            Mode::Octet => data.to_vec(),
+            Mode::Netascii => to_netascii(data),
+        };
+        let mut transfer = self.transfer()?;
+        let request = self.request(true, filename, data.len() as u64);
+        let reply = transfer.exchange(&request, |reply| match *reply {
+                Packet::Oack(ref options) => Some(Some(options.clone())),
+                Packet::Ack(0) => Some(None),
+                _ => None,
+            })?;
+        let options = match reply {
+            Some(options) => self.accept(&mut transfer, &options)?,
+            None => self.defaults(),
+        };
+        transfer.send(&data, options)
+    }
+
+    fn transfer(&self) -> StackResult<Transfer> {
+        Transfer::new(&self.stack,
+                      self.local_ip,
+                      self.server,
+                      false,
+                      self.config.timeout,
+                      self.config.retries)
+    }
+
+    /// Returns the request for `filename`, telling `size` as transfer size
+    /// of writes.
+    fn request(&self, write: bool, filename: &str, size: u64) -> Packet {
+        let mut options = Vec::new();
+        if let Some(block_size) = self.config.block_size {
+            options.push(("blksize".to_owned(), block_size.to_string()));
+        }
+        if let Some(timeout) = self.config.server_timeout {
+            options.push(("timeout".to_owned(), timeout.as_secs().to_string()));
+        }
+        if self.config.transfer_size {
+            options.push(("tsize".to_owned(), size.to_string()));
+        }
+        Packet::Request {
+            write: write,
+            filename: filename.to_owned(),
+            mode: self.config.mode.name().to_owned(),
+            options: options,
+        }
+    }
+
+    /// Returns the options of transfers the server sends no OACK for.
+    fn defaults(&self) -> Options {
+        Options {
+            block_size: DEFAULT_BLOCK_SIZE,
+            timeout: self.config.timeout,
+            transfer_size: None,
+        }
+    }
+
+    /// Returns the options of the OACK `acknowledged`, refusing it if it has
+    /// options not asked for, or larger blocks.
+    fn accept(&self,
+              transfer: &mut Transfer,
+              acknowledged: &[(String, String)])
+              -> StackResult<Options> {
+        let mut options = self.defaults();
+        for &(ref name, ref value) in acknowledged {
+            let number = value.parse::<u64>().ok();
+            let valid = match (&name.to_lowercase()[..], number) {
+                ("blksize", Some(size)) => {
+                    options.block_size = size as usize;
+                    self.config.block_size.map_or(false, |asked| {
+                        size >= MIN_BLOCK_SIZE as u64 && size <= asked as u64
+                    })
+                }
+                ("timeout", Some(secs)) => {
+                    self.config.server_timeout.map_or(false, |asked| asked.as_secs() == secs)
+                }
+                ("tsize", Some(size)) => {
+                    options.transfer_size = Some(size);
+                    self.config.transfer_size
+                }
+                _ => false,
+            };
+            if !valid {
+                let msg = format!("Option not asked for: {}={}", name, value);
+                return Err(transfer.refuse(OPTION_REFUSED, msg));
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// One side of a transfer, talking to the port of the other side from a
+/// socket of its own.
+struct Transfer {
+    socket: UdpSocket,
+    peer: SocketAddrV4,
+    /// If the port of `peer` is the one of the transfer, rather than the
+    /// port requests are sent to
+    peer_known: bool,
+    timeout: Duration,
+    retries: usize,
+    buffer: Vec<u8>,
+}
+
+impl Transfer {
+    fn new(stack: &Arc<Mutex<NetworkStack>>,
+           local_ip: Ipv4Addr,
+           peer: SocketAddrV4,
+           peer_known: bool,
+           timeout: Duration,
+           retries: usize)
+           -> StackResult<Transfer> {
+        let socket = UdpSocket::bind(stack.clone(), SocketAddrV4::new(local_ip, 0))?;
+        Ok(Transfer {
+            socket: socket,
+            peer: peer,
+            peer_known: peer_known,
+            timeout: timeout,
+            retries: retries,
+            buffer: vec![0; MAX_BLOCK_SIZE + 4],
+        })
+    }
+
+    /// Answers `request` the way a server with `config` does.
+    fn serve(&mut self, config: &TftpServerConfig, request: &Packet) -> StackResult<()> {
+        let (write, filename, mode, requested) = match *request {
+            Packet::Request { write, ref filename, ref mode, ref options } => {
+                (write, filename, mode, options)
+            }
+            _ => return Ok(()),
+        };
+        let mode = match Mode::from_name(mode) {
+            Some(mode) => mode,
+            None => {
+                let msg = format!("Mode not supported: {}", mode);
+                return Err(self.refuse(ILLEGAL_OPERATION, msg));
+            }
+        };
+        let path = match config.path(filename) {
+            Some(path) => path,
+            None => {
+                let msg = format!("Not in the served directory: {}", filename);
+                return Err(self.refuse(ACCESS_VIOLATION, msg));
+            }
+        };
+        if write {
+            self.serve_write(config, &path, mode, requested)
+        } else {
+            self.serve_read(config, &path, mode, requested)
+        }
+    }
+
+    fn serve_read(&mut self,
+                  config: &TftpServerConfig,
+                  path: &Path,
+                  mode: Mode,
+                  requested: &[(String, String)])
+                  -> StackResult<()> {
+        let mut data = Vec::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+            let code = match e.kind() {
+                io::ErrorKind::NotFound => FILE_NOT_FOUND,
+                io::ErrorKind::PermissionDenied => ACCESS_VIOLATION,
+                _ => NOT_DEFINED,
+            };
+            return Err(self.refuse(code, format!("Unable to read {}: {}", path.display(), e)));
+        }
+        if mode == Mode::Netascii {
+            data = to_netascii(&data);
+        }
+        let (options, accepted) = match negotiate(config, requested, Some(data.len() as u64)) {
+            Ok(negotiated) => negotiated,
+            Err((code, msg)) => return Err(self.refuse(code, msg)),
+        };
+        self.timeout = options.timeout;
+        if !accepted.is_empty() {
+            self.exchange(&Packet::Oack(accepted), |reply| match *reply {
+                    Packet::Ack(0) => Some(()),
+                    _ => None,
+                })?;
+        }
+        self.send(&data, options)
+    }
+
+    fn serve_write(&mut self,
+                   config: &TftpServerConfig,
+                   path: &Path,
+                   mode: Mode,
+                   requested: &[(String, String)])
+                   -> StackResult<()> {
+        if !config.allow_write {
+            return Err(self.refuse(ACCESS_VIOLATION, "Writing is not allowed".to_owned()));
+        }
+        if path.exists() {
+            let msg = format!("{} exists already", path.display());
+            return Err(self.refuse(FILE_EXISTS, msg));
+        }
+        let (options, accepted) = match negotiate(config, requested, None) {
+            Ok(negotiated) => negotiated,
+            Err((code, msg)) => return Err(self.refuse(code, msg)),
+        };
+        self.timeout = options.timeout;
+        let prompt = if accepted.is_empty() {
+            Packet::Ack(0)
+        } else {
+            Packet::Oack(accepted)
+        };
+        let (mut data, last_block) = self.receive(prompt, None, options, config.max_write_size)?;
+        if mode == Mode::Netascii {
+            data = from_netascii(&data);
+        }
+        let written = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&data));
+        if let Err(e) = written {
+            let code = if e.kind() == io::ErrorKind::AlreadyExists {
+                FILE_EXISTS
+            } else {
+                NOT_DEFINED
+            };
+            return Err(self.refuse(code, format!("Unable to write {}: {}", path.display(), e)));
+        }
+        // Only acknowledged once written, so a complete transfer is stored
+        self.acknowledge(last_block)?;
+        self.dally(last_block);
+        Ok(())
+    }
+
+    /// Sends `data` in blocks, from block 1, each once the one before it is
+    /// acknowledged. The last block is shorter than the others, empty if
+    /// need be.
+    fn send(&mut self, data: &[u8], options: Options) -> StackResult<()> {
+        let mut block = 0u16;
+        for start in (0..data.len() / options.block_size + 1).map(|i| i * options.block_size) {
+            block = block.wrapping_add(1);
+            let end = cmp::min(start + options.block_size, data.len());
+            let packet = Packet::Data {
+                block: block,
+                data: data[start..end].to_vec(),
+            };
+            self.exchange(&packet, |reply| if *reply == Packet::Ack(block) {
+                    Some(())
+                } else {
+                    None
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Receives blocks until one shorter than the others, sending `prompt`
+    /// for the first one unless it is given as `first`. Returns the data
+    /// with the number of the last block, which is left to `acknowledge`.
+    ///
+    /// Fails once more than `limit` bytes arrive, telling the other side the
+    /// disk is full.
+    fn receive(&mut self,
+               prompt: Packet,
+               first: Option<Vec<u8>>,
+               options: Options,
+               limit: u64)
+               -> StackResult<(Vec<u8>, u16)> {
+        let capacity = cmp::min(options.transfer_size.unwrap_or(0), 1 << 20);
+        let mut data = Vec::with_capacity(capacity as usize);
+        let mut prompt = prompt;
+        let mut first = first;
+        let mut block = 1u16;
+        loop {
+            let chunk = match first.take() {
+                Some(chunk) => chunk,
+                None => {
+                    self.exchange(&prompt, |packet| match *packet {
+                            Packet::Data { block: number, ref data } if number == block => {
+                                Some(data.clone())
+                            }
+                            _ => None,
+                        })?
+                }
+            };
+            if (data.len() + chunk.len()) as u64 > limit {
+                return Err(self.refuse(DISK_FULL, format!("Files are {} bytes at most", limit)));
+            }
+            data.extend_from_slice(&chunk);
+            if chunk.len() < options.block_size {
+                return Ok((data, block));
+            }
+            prompt = Packet::Ack(block);
+            block = block.wrapping_add(1);
+        }
+    }
+
+    /// Acknowledges the last block `last_block`, ending a transfer received
+    /// with `receive`.
+    fn acknowledge(&mut self, last_block: u16) -> StackResult<()> {
+        self.socket.send_to(&Packet::Ack(last_block).to_bytes(), self.peer)?;
+        Ok(())
+    }
+
+    /// Acknowledges the last block `last_block` again if the other side
+    /// sends it again within the timeout, as its acknowledgement was lost.
+    fn dally(&mut self, last_block: u16) {
+        let again = self.wait(&|packet: &Packet| match *packet {
+            Packet::Data { block, .. } if block == last_block => Some(()),
+            _ => None,
+        });
+        if let Ok(Some(())) = again {
+            self.acknowledge(last_block).unwrap_or(());
+        }
+    }
+
+    /// Sends `packet`, then waits for a packet of the other side that
+    /// `take` returns something for, sending `packet` again whenever none
+    /// arrives within the timeout.
+    ///
+    /// Fails with `io::ErrorKind::TimedOut` once `retries` are used up, and
+    /// with what the other side sends as error, see `peer_error`.
+    fn exchange<T, F>(&mut self, packet: &Packet, take: F) -> StackResult<T>
+        where F: Fn(&Packet) -> Option<T>
+    {
+        let bytes = packet.to_bytes();
+        for _ in 0..self.retries + 1 {
+            self.socket.send_to(&bytes, self.peer)?;
+            if let Some(taken) = self.wait(&take)? {
+                return Ok(taken);
+            }
+        }
+        let msg = format!("No answer from {}", self.peer);
+        Err(StackError::IoError(io::Error::new(io::ErrorKind::TimedOut, msg)))
+    }
+
+    /// Waits up to the timeout for a packet of the other side that `take`
+    /// returns something for, ignoring the others.
+    fn wait<T, F>(&mut self, take: &F) -> StackResult<Option<T>>
+        where F: Fn(&Packet) -> Option<T>
+    {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.socket.set_opt(ReadTimeout, Some(deadline - now))?;
+            let (len, src) = match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, SocketAddr::V4(src))) => (len, src),
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if !self.peer_known && src.ip() == self.peer.ip() {
+                self.peer = src;
+                self.peer_known = true;
+            }
+            if src != self.peer {
+                let error = Packet::Error {
+                    code: UNKNOWN_TID,
+                    message: "Unknown transfer ID".to_owned(),
+                };
+                self.socket.send_to(&error.to_bytes(), src).unwrap_or(0);
+                continue;
+            }
+            match Packet::parse(&self.buffer[..len]) {
+                Some(Packet::Error { code, message }) => return Err(peer_error(code, &message)),
+                Some(packet) => {
+                    if let Some(taken) = take(&packet) {
+                        return Ok(Some(taken));
+                    }
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Sends the error `code` with `message` to the other side, ending the
+    /// transfer, and returns it as `StackError::InvalidState`.
+    fn refuse(&mut self, code: u16, message: String) -> StackError {
+        let error = Packet::Error {
+            code: code,
+            message: message.clone(),
+        };
+        self.socket.send_to(&error.to_bytes(), self.peer).unwrap_or(0);
+        StackError::InvalidState(message)
+    }
+}
+
+/// Returns the error of the other side of a transfer having sent the error
+/// `code` with `message`.
+fn peer_error(code: u16, message: &str) -> StackError {
+    let msg = format!("{} (TFTP error {})", message, code);
+    let kind = match code {
+        FILE_NOT_FOUND => return StackError::NotFound(msg),
+        ACCESS_VIOLATION => io::ErrorKind::PermissionDenied,
+        FILE_EXISTS => io::ErrorKind::AlreadyExists,
+        ILLEGAL_OPERATION | OPTION_REFUSED => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    StackError::IoError(io::Error::new(kind, msg))
+}
+
+/// A TFTP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Packet {
+    /// A read or write request, with the options asked for
+    Request {
+        write: bool,
+        filename: String,
+        mode: String,
+        options: Vec<(String, String)>,
+    },
+    Data { block: u16, data: Vec<u8> },
+    Ack(u16),
+    Error { code: u16, message: String },
+    /// The options acknowledged of a request
+    Oack(Vec<(String, String)>),
+}
+
+impl Packet {
+    fn parse(packet: &[u8]) -> Option<Packet> {
+        if packet.len() < 2 {
+            return None;
+        }
+        let opcode = read_u16(packet, 0);
+        match opcode {
+            RRQ | WRQ | OACK => {
+                let strings = match strings(&packet[2..]) {
+                    Some(strings) => strings,
+                    None => return None,
+                };
+                if opcode == OACK {
+                    return pairs(&strings).map(Packet::Oack);
+                }
+                if strings.len() < 2 {
+                    return None;
+                }
+                pairs(&strings[2..]).map(|options| {
+                    Packet::Request {
+                        write: opcode == WRQ,
+                        filename: strings[0].clone(),
+                        mode: strings[1].clone(),
+                        options: options,
+                    }
+                })
+            }
+            DATA if packet.len() >= 4 => {
+                Some(Packet::Data {
+                    block: read_u16(packet, 2),
+                    data: packet[4..].to_vec(),
+                })
+            }
+            ACK if packet.len() >= 4 => Some(Packet::Ack(read_u16(packet, 2))),
+            ERROR if packet.len() >= 4 => {
+                // Some senders leave out the terminating zero
+                let message = packet[4..].split(|byte| *byte == 0).next().unwrap_or(&[]);
+                Some(Packet::Error {
+                    code: read_u16(packet, 2),
+                    message: String::from_utf8_lossy(message).into_owned(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let push_string = |bytes: &mut Vec<u8>, string: &str| {
+            bytes.extend_from_slice(string.as_bytes());
+            bytes.push(0);
+        };
+        match *self {
+            Packet::Request { write, ref filename, ref mode, ref options } => {
+                write_u16(&mut bytes, if write { WRQ } else { RRQ });
+                push_string(&mut bytes, filename);
+                push_string(&mut bytes, mode);
+                for &(ref name, ref value) in options {
+                    push_string(&mut bytes, name);
+                    push_string(&mut bytes, value);
+                }
+            }
+            Packet::Data { block, ref data } => {
+                write_u16(&mut bytes, DATA);
+                write_u16(&mut bytes, block);
+                bytes.extend_from_slice(data);
+            }
+            Packet::Ack(block) => {
+                write_u16(&mut bytes, ACK);
+                write_u16(&mut bytes, block);
+            }
+            Packet::Error { code, ref message } => {
+                write_u16(&mut bytes, ERROR);
+                write_u16(&mut bytes, code);
+                push_string(&mut bytes, message);
+            }
+            Packet::Oack(ref options) => {
+                write_u16(&mut bytes, OACK);
+                for &(ref name, ref value) in options {
+                    push_string(&mut bytes, name);
+                    push_string(&mut bytes, value);
+                }
+            }
+        }
+        bytes
+    }
+}
+
+/// Returns the zero terminated strings `data` is made of.
+fn strings(data: &[u8]) -> Option<Vec<String>> {
+    match data.last() {
+        None => Some(Vec::new()),
+        Some(&0) => {
+            let strings = data[..data.len() - 1]
+                .split(|byte| *byte == 0)
+                .map(|string| String::from_utf8_lossy(string).into_owned())
+                .collect();
+            Some(strings)
+        }
+        Some(_) => None,
+    }
+}
+
+/// Returns `strings` as names and values, if there is a value to every name.
+fn pairs(strings: &[String]) -> Option<Vec<(String, String)>> {
+    if strings.len() % 2 != 0 {
+        return None;
+    }
+    Some(strings.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect())
+}
+
+/// Returns `data` in netascii, with LF sent as CR LF and CR as CR NUL.
+fn to_netascii(data: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(data.len());
+    for byte in data {
+        match *byte {
+            b'\n' => converted.extend_from_slice(b"\r\n"),
+            b'\r' => converted.extend_from_slice(b"\r\0"),
+            byte => converted.push(byte),
+        }
+    }
+    converted
+}
+
+/// Returns the netascii `data` with the line ends of the local system.
+fn from_netascii(data: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(data.len());
+    let mut bytes = data.iter().peekable();
+    while let Some(byte) = bytes.next() {
+        match (*byte, bytes.peek()) {
+            (b'\r', Some(&&b'\n')) => {
+                bytes.next();
+                converted.push(b'\n');
+            }
+            (b'\r', Some(&&0)) => {
+                bytes.next();
+                converted.push(b'\r');
+            }
+            (byte, _) => converted.push(byte),
+        }
+    }
+    converted
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    (data[offset] as u16) << 8 | data[offset + 1] as u16
+}
+
+fn write_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.push((value >> 8) as u8);
+    buffer.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn options(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn packets() {
+        let request = Packet::Request {
+            write: false,
+            filename: "boot/pxelinux.0".to_owned(),
+            mode: "octet".to_owned(),
+            options: options(&[("blksize", "1428"), ("tsize", "0")]),
+        };
+        let bytes = request.to_bytes();
+        assert_eq!(&bytes[..], &b"\0\x01boot/pxelinux.0\0octet\0blksize\01428\0tsize\00\0"[..]);
+        assert_eq!(Packet::parse(&bytes), Some(request));
+        let data = Packet::Data {
+            block: 0x1234,
+            data: vec![1, 2, 3],
+        };
+        assert_eq!(data.to_bytes(), vec![0, 3, 0x12, 0x34, 1, 2, 3]);
+        assert_eq!(Packet::parse(&data.to_bytes()), Some(data));
+        assert_eq!(Packet::parse(&[0, 4, 0, 7]), Some(Packet::Ack(7)));
+        assert_eq!(Packet::parse(&[0, 6, b'a', 0, b'1', 0]),
+                   Some(Packet::Oack(options(&[("a", "1")]))));
+        assert_eq!(Packet::parse(b"\0\x05\0\x01Not found"),
+                   Some(Packet::Error {
+                       code: 1,
+                       message: "Not found".to_owned(),
+                   }));
+        let invalid: &[&[u8]] = &[b"\0",
+                                  b"\0\x01file\0",
+                                  b"\0\x01file\0octet",
+                                  b"\0\x02f\0octet\0blksize\0",
+                                  b"\0\x03\0",
+                                  b"\0\x07\0\0"];
+        for packet in invalid {
+            assert_eq!(Packet::parse(packet), None);
+        }
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut config = TftpServerConfig::new(PathBuf::from("/srv/tftp"));
+        config.max_write_size = 1000;
+        let requested = options(&[("BLKSIZE", "9000"), ("timeout", "3"), ("tsize", "0"),
+                                  ("windowsize", "4")]);
+        let (negotiated, accepted) = negotiate(&config, &requested, Some(4096)).unwrap();
+        assert_eq!(negotiated,
+                   Options {
+                       block_size: 1468,
+                       timeout: Duration::from_secs(3),
+                       transfer_size: Some(4096),
+                   });
+        assert_eq!(accepted, options(&[("BLKSIZE", "1468"), ("timeout", "3"), ("tsize", "4096")]));
+
+        let invalid = options(&[("blksize", "7"), ("timeout", "0"), ("tsize", "x")]);
+        let (negotiated, accepted) = negotiate(&config, &invalid, None).unwrap();
+        assert_eq!((negotiated.block_size, negotiated.timeout), (512, config.timeout));
+        assert!(accepted.is_empty());
+        let (negotiated, _) = negotiate(&config, &options(&[("tsize", "1000")]), None).unwrap();
+        assert_eq!(negotiated.transfer_size, Some(1000));
+        assert_eq!(negotiate(&config, &options(&[("tsize", "1001")]), None).unwrap_err().0,
+                   DISK_FULL);
+    }
+
+    #[test]
+    fn paths() {
+        let config = TftpServerConfig::new(PathBuf::from("/srv/tftp"));
+        assert_eq!(config.path("boot/pxelinux.0"),
+                   Some(PathBuf::from("/srv/tftp/boot/pxelinux.0")));
+        assert_eq!(config.path("/boot/./pxelinux.0"),
+                   Some(PathBuf::from("/srv/tftp/boot/pxelinux.0")));
+        for outside in &["../etc/passwd", "boot/../../etc/passwd", "", "/", "boot/.."] {
+            assert_eq!(config.path(outside), None);
+        }
+    }
+
+    #[test]
+    fn netascii() {
+        let text = b"line\nCR\rCR LF\r\n";
+        let converted = to_netascii(text);
+        assert_eq!(&converted[..], &b"line\r\nCR\r\0CR LF\r\0\r\n"[..]);
+        assert_eq!(&from_netascii(&converted)[..], &text[..]);
+        assert_eq!(&from_netascii(b"lone\r")[..], &b"lone\r"[..]);
+        assert_eq!(Mode::from_name("NetASCII"), Some(Mode::Netascii));
+        assert_eq!(Mode::from_name("mail"), None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Naming and pinning the threads of the stack.
+//!
+//! Every interface runs a thread receiving on its datalink, a thread doing
+//! the rest of its work, and with egress queues, see `qos`, a thread
+//! sending. They are named after their interface, `rips-rx-eth0`,
+//! `rips-eth0` and `rips-tx-eth0`, with another prefix than `rips` if set
+//! with `NetworkStackBuilder::thread_name`. For measurements they can be
+//! pinned to CPUs of their own with `NetworkStackBuilder::pin_threads`.
+//! Interfaces with several rx workers, see `fanout`, run them in threads
+//! numbered from 0, `rips-rxw-eth0-0` and so on.
+//! Pinning is only implemented on Linux, elsewhere it fails with a warning
+//! and the threads run unpinned.
+
+use std::io;
+use std::thread::{self, JoinHandle};
+
+/// Prefix of the names of the threads of the stack by default.
+pub static DEFAULT_PREFIX: &'static str = "rips";
+
+/// The threads of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThreadKind {
+    /// Handles Arp, timers and other work of the interface
+    Interface,
+    /// Receives frames from the datalink
+    Rx,
+    /// Sends the frames in the egress queues
+    Tx,
+    /// Handles the frames received, see `fanout`. Worker `i` is pinned to
+    /// the `i`th of the CPUs, counting round if there are fewer
+    RxWorker,
+}
+
+impl ThreadKind {
+    /// Returns the name of the thread of this kind for `interface_name`.
+    pub fn thread_name(&self, prefix: &str, interface_name: &str) -> String {
+        match *self {
+            ThreadKind::Interface => format!("{}-{}", prefix, interface_name),
+            ThreadKind::Rx => format!("{}-rx-{}", prefix, interface_name),
+            ThreadKind::Tx => format!("{}-tx-{}", prefix, interface_name),
+            ThreadKind::RxWorker => format!("{}-rxw-{}", prefix, interface_name),
+        }
+    }
+}
+
+/// How to spawn a thread.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadConfig {
+    pub name: Option<String>,
+    /// The CPUs the thread may run on, any if empty
+    pub cpus: Vec<usize>,
+}
+
+impl ThreadConfig {
+    /// Spawns a thread running `f`, pinned to `cpus` if possible.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the OS fails to create the thread, like `thread::spawn`.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let mut builder = thread::Builder::new();
+        if let Some(ref name) = self.name {
+            builder = builder.name(name.clone());
+        }
+        let cpus = self.cpus.clone();
+        builder.spawn(move || {
+                if !cpus.is_empty() {
+                    if let Err(e) = pin_current(&cpus) {
+                        warn!("Unable to pin thread to CPUs {:?}: {}", cpus, e);
+                    }
+                }
+                f()
+            })
+            .expect("Unable to spawn thread")
+    }
+}
+
+/// Makes the calling thread only run on `cpus`.
+#[cfg(target_os = "linux")]
+pub fn pin_current(cpus: &[usize]) -> io::Result<()> {
+    use libc;
+    use std::mem;
+
+    let max_cpus = 8 * mem::size_of::<libc::cpu_set_t>();
+    if cpus.iter().any(|&cpu| cpu >= max_cpus) {
+        let msg = format!("CPUs are numbered below {}", max_cpus);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    // A zeroed cpu_set_t is the empty set, and sched_setaffinity only reads
+    // the set it is given
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Makes the calling thread only run on `cpus`. Only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current(_cpus: &[usize]) -> io::Result<()> {
+    let msg = "Pinning threads is not supported on this platform".to_owned();
+    Err(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(ThreadKind::Rx.thread_name("rips", "eth0"), "rips-rx-eth0");
+        assert_eq!(ThreadKind::Interface.thread_name("t", "eth0"), "t-eth0");
+        assert_eq!(ThreadKind::RxWorker.thread_name("rips", "eth0"), "rips-rxw-eth0");
+    }
+
+    #[test]
+    fn spawn_named() {
+        let config = ThreadConfig {
+            name: Some("spawned".to_owned()),
+            cpus: Vec::new(),
+        };
+        let name = config.spawn(|| ::std::thread::current().name().map(|n| n.to_owned()));
+        assert_eq!(name.join().unwrap(), Some("spawned".to_owned()));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn pin_invalid_cpu() {
+        let result = ThreadConfig::default().spawn(|| pin_current(&[1 << 20]));
+        assert_eq!(result.join().unwrap().unwrap_err().kind(),
+                   io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,301 @@
+//! Timeouts run on the thread of an interface.
+//!
+//! Every interface has a hashed timer wheel owned by its thread. Timeouts
+//! are scheduled on it from any thread through the `Timers` handle returned
+//! by `StackInterface::timers`, and their callbacks are called on the thread
+//! of the interface, between the frames it handles. Callbacks should be
+//! short, everything else the thread does waits for them.
+//!
+//! The wheel has `WHEEL_SLOTS` slots of `TICK_MS` milliseconds each. A
+//! timeout is put in the slot of the tick it expires in, so scheduling and
+//! cancelling take constant time however many timeouts are pending, and
+//! timeouts further away than one turn of the wheel just stay in their slot
+//! for more turns. Timeouts fire at the first tick after their deadline, so
+//! up to `TICK_MS` late. The time is read from the `Clock` of the stack,
+//! see `clock`.
+//!
+//! On polled interfaces, see `StackInterface::polled`, timeouts only fire
+//! while the interface is polled.
+
+use clock::Clock;
+use stack::StackInterfaceMsg;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+/// Length of one tick of the timer wheel of an interface.
+pub static TICK_MS: u64 = 10;
+
+/// Number of slots in the timer wheel of an interface, one turn of it is
+/// about five seconds.
+pub static WHEEL_SLOTS: usize = 512;
+
+/// Called once when a timeout expires.
+pub type TimerCallback = Box<FnMut() + Send>;
+
+/// Identifies a scheduled timeout, for cancelling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(usize);
+
+struct Entry {
+    id: TimerId,
+    tick: u64,
+    callback: TimerCallback,
+}
+
+/// A hashed timer wheel. Usually used through `Timers`.
+pub struct TimerWheel {
+    origin: Instant,
+    tick: Duration,
+    slots: Vec<Vec<Entry>>,
+    /// All ticks before this one have been processed
+    next_tick: u64,
+    /// The tick each pending timeout expires in
+    ticks: HashMap<TimerId, u64>,
+}
+
+impl TimerWheel {
+    /// Creates an empty wheel of `slots` slots of length `tick`, starting
+    /// at `origin`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `slots` is zero or `tick` is shorter than a nanosecond.
+    pub fn new(origin: Instant, tick: Duration, slots: usize) -> TimerWheel {
+        assert!(slots > 0, "Need at least one slot");
+        assert!(nanos(tick) > 0, "Tick must not be zero");
+        TimerWheel {
+            origin: origin,
+            tick: tick,
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            next_tick: 0,
+            ticks: HashMap::new(),
+        }
+    }
+
+    /// Creates an empty wheel of `WHEEL_SLOTS` slots of `TICK_MS`, starting
+    /// at `origin`.
+    pub fn starting_at(origin: Instant) -> TimerWheel {
+        TimerWheel::new(origin, Duration::from_millis(TICK_MS), WHEEL_SLOTS)
+    }
+
+    /// Returns the number of pending timeouts.
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    /// Makes `callback` be called once `advance` is called at or after
+    /// `deadline`. Replaces an earlier timeout with the same `id`.
+    pub fn schedule(&mut self, id: TimerId, deadline: Instant, callback: TimerCallback) {
+        self.cancel(id);
+        let tick = if deadline > self.origin {
+            let since_origin = nanos(deadline.duration_since(self.origin));
+            let tick = nanos(self.tick);
+            (since_origin + tick - 1) / tick
+        } else {
+            0
+        };
+        let tick = ::std::cmp::max(tick, self.next_tick);
+        let slot = self.slot(tick);
+        self.slots[slot].push(Entry {
+            id: id,
+            tick: tick,
+            callback: callback,
+        });
+        self.ticks.insert(id, tick);
+    }
+
+    /// Drops the timeout `id` without calling it. Returns false if it was not
+    /// pending, because it already fired or was never scheduled.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let tick = match self.ticks.remove(&id) {
+            Some(tick) => tick,
+            None => return false,
+        };
+        let slot = self.slot(tick);
+        self.slots[slot].retain(|entry| entry.id != id);
+        true
+    }
+
+    /// Calls the callbacks of all timeouts expired at `now`, in the order of
+    /// their deadlines. Returns how many there were.
+    pub fn advance(&mut self, now: Instant) -> usize {
+        if now < self.origin {
+            return 0;
+        }
+        let current = nanos(now.duration_since(self.origin)) / nanos(self.tick);
+        if current < self.next_tick {
+            return 0;
+        }
+        let mut expired = Vec::new();
+        if current - self.next_tick >= self.slots.len() as u64 {
+            // Every slot is due, look at each once
+            for slot in &mut self.slots {
+                expire(slot, current, &mut expired);
+            }
+        } else {
+            for tick in self.next_tick..current + 1 {
+                let slot = (tick % self.slots.len() as u64) as usize;
+                expire(&mut self.slots[slot], current, &mut expired);
+            }
+        }
+        self.next_tick = current + 1;
+        expired.sort_by_key(|entry| (entry.tick, entry.id));
+        for entry in &expired {
+            self.ticks.remove(&entry.id);
+        }
+        let count = expired.len();
+        for mut entry in expired {
+            (entry.callback)();
+        }
+        count
+    }
+
+    /// Returns how long after `now` the next tick, when the next timeout
+    /// might fire, starts. `None` if no timeout is pending.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        if self.ticks.is_empty() {
+            return None;
+        }
+        let next = self.origin + self.tick_duration(self.next_tick);
+        if next > now {
+            Some(next.duration_since(now))
+        } else {
+            Some(Duration::new(0, 0))
+        }
+    }
+
+    fn tick_duration(&self, ticks: u64) -> Duration {
+        let total = nanos(self.tick) * ticks;
+        Duration::new(total / 1_000_000_000, (total % 1_000_000_000) as u32)
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> TimerWheel {
+        TimerWheel::starting_at(Instant::now())
+    }
+}
+
+/// Moves the entries of `slot` expiring at or before `tick` to `expired`.
+fn expire(slot: &mut Vec<Entry>, tick: u64, expired: &mut Vec<Entry>) {
+    let mut i = 0;
+    while i < slot.len() {
+        if slot[i].tick <= tick {
+            expired.push(slot.swap_remove(i));
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+/// Schedules timeouts on the timer wheel of an interface.
+#[derive(Clone)]
+pub struct Timers {
+    tx: Sender<StackInterfaceMsg>,
+    next_id: Arc<AtomicUsize>,
+    clock: Arc<Clock>,
+}
+
+impl Timers {
+    /// Creates the handle of the interface thread receiving on `tx`, with
+    /// delays counted on `clock`.
+    pub fn new(tx: Sender<StackInterfaceMsg>, clock: Arc<Clock>) -> Timers {
+        Timers {
+            tx: tx,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            clock: clock,
+        }
+    }
+
+    /// Makes the interface thread call `callback` once `delay` has passed.
+    /// Nothing is called if the interface is gone by then.
+    pub fn schedule<F>(&self, delay: Duration, callback: F) -> TimerId
+        where F: FnMut() + Send + 'static
+    {
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::SeqCst));
+        let msg = StackInterfaceMsg::AddTimer(id, self.clock.now() + delay, Box::new(callback));
+        if self.tx.send(msg).is_err() {
+            warn!("Unable to schedule a timeout, the interface thread is gone");
+        }
+        id
+    }
+
+    /// Cancels the timeout `id` if it did not fire yet.
+    pub fn cancel(&self, id: TimerId) {
+        self.tx.send(StackInterfaceMsg::CancelTimer(id)).unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn recorder(fired: &Arc<Mutex<Vec<usize>>>, n: usize) -> TimerCallback {
+        let fired = fired.clone();
+        Box::new(move || fired.lock().unwrap().push(n))
+    }
+
+    #[test]
+    fn fire_in_order() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut wheel = TimerWheel::new(start, Duration::from_millis(10), 4);
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        wheel.schedule(TimerId(0), ms(25), recorder(&fired, 0));
+        wheel.schedule(TimerId(1), ms(5), recorder(&fired, 1));
+        // More than a turn of the wheel away
+        wheel.schedule(TimerId(2), ms(95), recorder(&fired, 2));
+        wheel.schedule(TimerId(3), ms(30), recorder(&fired, 3));
+        assert_eq!(wheel.len(), 4);
+        assert_eq!(wheel.next_timeout(start), Some(Duration::from_millis(0)));
+
+        assert_eq!(wheel.advance(ms(9)), 0);
+        assert_eq!(wheel.next_timeout(ms(9)), Some(Duration::from_millis(1)));
+        assert_eq!(wheel.advance(ms(30)), 3);
+        assert_eq!(*fired.lock().unwrap(), vec![1, 0, 3]);
+        assert_eq!(wheel.advance(ms(60)), 0);
+        assert_eq!(wheel.advance(ms(100)), 1);
+        assert_eq!(*fired.lock().unwrap(), vec![1, 0, 3, 2]);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.next_timeout(ms(100)), None);
+    }
+
+    #[test]
+    fn cancel() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut wheel = TimerWheel::new(start, Duration::from_millis(10), 4);
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        wheel.schedule(TimerId(0), ms(20), recorder(&fired, 0));
+        wheel.schedule(TimerId(1), ms(20), recorder(&fired, 1));
+        assert!(wheel.cancel(TimerId(0)));
+        assert!(!wheel.cancel(TimerId(0)));
+        // Rescheduling replaces the timeout
+        wheel.schedule(TimerId(1), ms(40), recorder(&fired, 2));
+        assert_eq!(wheel.advance(ms(30)), 0);
+        // Deadlines that passed fire at the next advance
+        wheel.schedule(TimerId(3), ms(0), recorder(&fired, 3));
+        assert_eq!(wheel.advance(ms(1000)), 2);
+        assert_eq!(*fired.lock().unwrap(), vec![2, 3]);
+        assert!(!wheel.cancel(TimerId(1)));
+    }
+}
@@ -0,0 +1,293 @@
+//! Interfaces exchanging raw IP packets instead of Ethernet frames.
+//!
+//! A Linux tun device, or the file descriptor of a VPN, carries IP packets
+//! without an Ethernet header, and nothing answers Arp on it. Such links
+//! implement `IpLink` and are added with `NetworkStack::add_tun`:
+//!
+//! ```rust,ignore
+//! let link: Arc<IpLink> = Arc::new(FdIpLink::new(tun_file));
+//! let interface = stack.add_tun("tun0", link)?;
+//! stack.add_ipv4(&interface, "10.8.0.2/24".parse().unwrap())?;
+//! ```
+//!
+//! The rest of the stack still works on Ethernet frames. `channel` puts a
+//! placeholder Ethernet header addressed to `MAC` in front of every received
+//! IPv4 packet, and strips it off again before sending. Such interfaces
+//! never resolve next hops with Arp, every packet is sent on the link
+//! directly. Frames of other protocols than IPv4 are dropped on the way out,
+//! and packets of other IP versions are dropped on the way in.
+
+use EthernetChannel;
+use pool::BufferPool;
+use rx;
+
+use pnet::datalink::{EthernetDataLinkChannelIterator, EthernetDataLinkReceiver,
+                     EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use std::io;
+use std::sync::Arc;
+
+/// The MAC address of interfaces added with `NetworkStack::add_tun`, and the
+/// source and destination of the Ethernet headers `channel` makes up.
+pub const MAC: MacAddr = MacAddr(0, 0, 0, 0, 0, 0);
+
+/// Length of the Ethernet header `channel` adds and strips.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+/// A link sending and receiving IP packets without any link layer header.
+pub trait IpLink: Send + Sync {
+    /// The MTU of the link, if it is known. The interface gets `DEFAULT_MTU`
+    /// otherwise.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// Sends the whole IP packet `packet`.
+    fn send(&self, packet: &[u8]) -> io::Result<()>;
+
+    /// Waits for the next IP packet. Should give up with
+    /// `io::ErrorKind::TimedOut` after about `rx::POLL_INTERVAL_MS`, like
+    /// `Datalink::recv`. Any other error stops receiving on the interface.
+    fn recv(&self) -> io::Result<Box<[u8]>>;
+}
+
+/// Returns a channel sending and receiving Ethernet frames through `link`,
+/// see the module documentation.
+pub fn channel(link: Arc<IpLink>) -> EthernetChannel {
+    channel_with_pool(link, BufferPool::default())
+}
+
+/// Same as `channel`, but builds the frames to send in buffers from `pool`.
+pub fn channel_with_pool(link: Arc<IpLink>, pool: BufferPool) -> EthernetChannel {
+    let sender = Sender {
+        link: link.clone(),
+        pool: pool,
+    };
+    EthernetChannel(Box::new(sender), Box::new(Receiver(link)))
+}
+
+struct Sender {
+    link: Arc<IpLink>,
+    pool: BufferPool,
+}
+
+impl Sender {
+    fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        match EthernetPacket::new(frame) {
+            Some(ref packet) if packet.get_ethertype() == EtherTypes::Ipv4 => {
+                self.link.send(packet.payload())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl EthernetDataLinkSender for Sender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = self.pool.get(packet_size);
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            if let Err(e) = self.send_frame(&buffer) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        Some(self.send_frame(packet.packet()))
+    }
+}
+
+struct Receiver(Arc<IpLink>);
+
+impl EthernetDataLinkReceiver for Receiver {
+    fn iter<'a>(&'a mut self) -> Box<EthernetDataLinkChannelIterator + 'a> {
+        Box::new(ReceiverIterator {
+            link: self.0.clone(),
+            current: Vec::new(),
+        })
+    }
+}
+
+struct ReceiverIterator {
+    link: Arc<IpLink>,
+    current: Vec<u8>,
+}
+
+impl<'a> EthernetDataLinkChannelIterator<'a> for ReceiverIterator {
+    fn next(&mut self) -> io::Result<EthernetPacket> {
+        let mut packet = self.link.recv()?;
+        while packet.first().map_or(true, |&byte| byte >> 4 != 4) {
+            packet = self.link.recv()?;
+        }
+        self.current.clear();
+        self.current.resize(ETHERNET_HEADER_LEN, 0);
+        self.current.extend_from_slice(&packet);
+        {
+            let mut frame = MutableEthernetPacket::new(&mut self.current).unwrap();
+            frame.set_destination(MAC);
+            frame.set_source(MAC);
+            frame.set_ethertype(EtherTypes::Ipv4);
+        }
+        Ok(EthernetPacket::new(&self.current).unwrap())
+    }
+}
+
+/// An `IpLink` reading and writing one IP packet at a time on a file, such as
+/// an opened tun device or the descriptor a VPN hands out.
+#[cfg(unix)]
+pub struct FdIpLink {
+    file: ::std::fs::File,
+    mtu: Option<usize>,
+}
+
+#[cfg(unix)]
+impl FdIpLink {
+    pub fn new(file: ::std::fs::File) -> FdIpLink {
+        FdIpLink {
+            file: file,
+            mtu: None,
+        }
+    }
+
+    /// Same as `new`, for links known to have the MTU `mtu`.
+    pub fn with_mtu(file: ::std::fs::File, mtu: usize) -> FdIpLink {
+        FdIpLink {
+            file: file,
+            mtu: Some(mtu),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl IpLink for FdIpLink {
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+
+        let written = (&self.file).write(packet)?;
+        if written != packet.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "Packet partially written"));
+        }
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        use std::io::Read;
+
+        poll_readable(&self.file, rx::POLL_INTERVAL_MS)?;
+        let mut buffer = vec![0; ::stack::MAX_MTU];
+        let len = (&self.file).read(&mut buffer)?;
+        buffer.truncate(len);
+        Ok(buffer.into_boxed_slice())
+    }
+}
+
+/// Waits at most `timeout_ms` for `fd`, like a tun device, to become
+/// readable. Fails with `io::ErrorKind::TimedOut` if it does not.
+#[cfg(unix)]
+pub fn poll_readable<F: ::std::os::unix::io::AsRawFd>(fd: &F, timeout_ms: u64) -> io::Result<()> {
+    use libc;
+
+    let mut fd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Only the one pollfd given is written to
+    match unsafe { libc::poll(&mut fd, 1, timeout_ms as libc::c_int) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Err(io::Error::new(io::ErrorKind::TimedOut, "Nothing to read")),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet::packet::Packet;
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+
+    use super::*;
+
+    struct ChannelLink {
+        sent: Mutex<Sender<Box<[u8]>>>,
+        received: Mutex<Receiver<Box<[u8]>>>,
+    }
+
+    impl IpLink for ChannelLink {
+        fn send(&self, packet: &[u8]) -> io::Result<()> {
+            self.sent.lock().unwrap().send(packet.to_vec().into_boxed_slice()).unwrap();
+            Ok(())
+        }
+
+        fn recv(&self) -> io::Result<Box<[u8]>> {
+            self.received
+                .lock()
+                .unwrap()
+                .recv_timeout(Duration::from_millis(10))
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No packet"))
+        }
+    }
+
+    fn link() -> (EthernetChannel, Receiver<Box<[u8]>>, Sender<Box<[u8]>>) {
+        let (sent, read) = mpsc::channel();
+        let (inject, received) = mpsc::channel();
+        let link = ChannelLink {
+            sent: Mutex::new(sent),
+            received: Mutex::new(received),
+        };
+        (channel(Arc::new(link)), read, inject)
+    }
+
+    #[test]
+    fn strip_header() {
+        let (EthernetChannel(mut tx, _), read, _) = link();
+        for &ethertype in &[EtherTypes::Arp, EtherTypes::Ipv4] {
+            tx.build_and_send(1, 18, &mut |mut frame: MutableEthernetPacket| {
+                    frame.set_ethertype(ethertype);
+                    frame.set_payload(&[0x45, 1, 2, 3]);
+                })
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(&*read.try_recv().unwrap(), &[0x45, 1, 2, 3]);
+        assert!(read.try_recv().is_err());
+    }
+
+    #[test]
+    fn add_header() {
+        let (EthernetChannel(_, mut rx), _, inject) = link();
+        inject.send(vec![0x60, 0, 0, 0].into_boxed_slice()).unwrap();
+        inject.send(vec![0x45, 1, 2, 3].into_boxed_slice()).unwrap();
+        let mut frames = rx.iter();
+        {
+            let frame = frames.next().unwrap();
+            assert_eq!(frame.get_destination(), MAC);
+            assert_eq!(frame.get_ethertype(), EtherTypes::Ipv4);
+            assert_eq!(frame.payload(), &[0x45, 1, 2, 3]);
+        }
+        assert_eq!(frames.next().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
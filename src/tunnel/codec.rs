@@ -0,0 +1,127 @@
+use {EthernetChannel, Interface, NetworkStack, RxError, RxResult, StackResult};
+use stack::DEFAULT_MTU;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use super::{TunnelReceiver, TunnelSender, ethernet_frame, peer_mac};
+
+/// A user supplied transformation of the IPv4 packets going through a point
+/// to point virtual interface, such as encryption or a custom tunneling
+/// header. See `add_codec_interface`.
+pub trait Codec: Send {
+    /// Maximum number of bytes `encode` adds to a packet. The MTU of the
+    /// interface is reduced by this much.
+    fn overhead(&self) -> usize;
+
+    /// Transforms the IPv4 packet `packet`, routed out on the interface, into
+    /// the data to transport to the remote end.
+    fn encode(&mut self, packet: &[u8]) -> Vec<u8>;
+
+    /// Transforms data received from the remote end back into an IPv4
+    /// packet.
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, RxError>;
+}
+
+/// Adds `interface` to `stack` as a virtual point to point interface whose
+/// packets pass through `codec`. Instead of a datalink the interface gets a
+/// `CodecTx` yielding the encoded packets routed out on it and a `CodecRx`
+/// taking encoded packets to receive on it. Transporting the encoded data is
+/// up to the caller, any socket will do.
+///
+/// The MTU of the interface is the default MTU minus the codec overhead.
+pub fn add_codec_interface<C>(stack: &mut NetworkStack,
+                              interface: Interface,
+                              codec: C)
+                              -> StackResult<(CodecTx<C>, CodecRx<C>)>
+    where C: Codec + 'static
+{
+    let mtu = DEFAULT_MTU - codec.overhead();
+    let (channel, codec_tx, codec_rx) = codec_channel(interface.mac, codec);
+    stack.add_interface(interface.clone(), channel)?;
+    stack.interface(&interface)?.set_mtu(mtu)?;
+    Ok((codec_tx, codec_rx))
+}
+
+/// Creates the datalink channel for a codec interface with MAC `mac`,
+/// without adding it to any stack.
+pub fn codec_channel<C>(mac: MacAddr, codec: C) -> (EthernetChannel, CodecTx<C>, CodecRx<C>)
+    where C: Codec
+{
+    let codec = Arc::new(Mutex::new(codec));
+    let (frames_tx, frames_rx) = mpsc::channel();
+    let (inject_tx, inject_rx) = mpsc::channel();
+    let channel = EthernetChannel(Box::new(TunnelSender {
+                                      frames: frames_tx,
+                                      inject: Some(inject_tx.clone()),
+                                  }),
+                                  Box::new(TunnelReceiver { frames: Some(inject_rx) }));
+    let codec_tx = CodecTx {
+        frames: frames_rx,
+        codec: codec.clone(),
+    };
+    let codec_rx = CodecRx {
+        mac: mac,
+        codec: codec,
+        inject: inject_tx,
+    };
+    (channel, codec_tx, codec_rx)
+}
+
+/// The sending end of a codec interface.
+pub struct CodecTx<C: Codec> {
+    frames: Receiver<Box<[u8]>>,
+    codec: Arc<Mutex<C>>,
+}
+
+impl<C: Codec> CodecTx<C> {
+    /// Blocks until a packet is sent out on the interface and returns it
+    /// encoded. Returns `None` if the interface is gone.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        while let Ok(frame) = self.frames.recv() {
+            let eth_pkg = EthernetPacket::new(&frame).unwrap();
+            if eth_pkg.get_ethertype() == EtherTypes::Ipv4 {
+                return Some(self.codec.lock().unwrap().encode(eth_pkg.payload()));
+            }
+        }
+        None
+    }
+}
+
+/// The receiving end of a codec interface.
+pub struct CodecRx<C: Codec> {
+    mac: MacAddr,
+    codec: Arc<Mutex<C>>,
+    inject: Sender<Box<[u8]>>,
+}
+
+impl<C: Codec> Clone for CodecRx<C> {
+    fn clone(&self) -> Self {
+        CodecRx {
+            mac: self.mac,
+            codec: self.codec.clone(),
+            inject: self.inject.clone(),
+        }
+    }
+}
+
+impl<C: Codec> CodecRx<C> {
+    /// Decodes `data` and receives the resulting IPv4 packet on the
+    /// interface.
+    pub fn deliver(&self, data: &[u8]) -> RxResult {
+        let packet = self.codec.lock().unwrap().decode(data)?;
+        if Ipv4Packet::new(&packet).is_none() {
+            return Err(RxError::InvalidLength);
+        }
+        let mut payload = ::BasicPayload::new(&packet);
+        let frame = ethernet_frame(peer_mac(self.mac), self.mac, EtherTypes::Ipv4, &mut payload);
+        self.inject
+            .send(frame)
+            .map_err(|_| RxError::NoListener("Codec interface is gone".to_owned()))
+    }
+}
@@ -0,0 +1,210 @@
+use RxError;
+
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::util::checksum;
+
+use super::Encapsulation;
+
+const CHECKSUM_PRESENT: u16 = 0x8000;
+const ROUTING_PRESENT: u16 = 0x4000;
+const KEY_PRESENT: u16 = 0x2000;
+const SEQUENCE_PRESENT: u16 = 0x1000;
+const VERSION_MASK: u16 = 0x0007;
+
+/// Generic Routing Encapsulation, RFC 2784 with the key and sequence number
+/// extensions of RFC 2890. Compatible with Linux gre devices.
+///
+/// The checksum and key are optional. When a key is configured only packets
+/// carrying that key are accepted, otherwise only packets without a key, the
+/// same as Linux does. Sequence numbers are accepted but not checked.
+#[derive(Debug, Clone, Default)]
+pub struct Gre {
+    key: Option<u32>,
+    checksum: bool,
+}
+
+impl Gre {
+    /// Creates a GRE encapsulation with the key `key`, adding and requiring
+    /// checksums if `checksum` is true.
+    pub fn new(key: Option<u32>, checksum: bool) -> Gre {
+        Gre {
+            key: key,
+            checksum: checksum,
+        }
+    }
+
+    pub fn key(&self) -> Option<u32> {
+        self.key
+    }
+
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.checksum {
+            flags |= CHECKSUM_PRESENT;
+        }
+        if self.key.is_some() {
+            flags |= KEY_PRESENT;
+        }
+        flags
+    }
+}
+
+impl Encapsulation for Gre {
+    fn protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Gre
+    }
+
+    fn overhead(&self) -> usize {
+        header_len(self.flags())
+    }
+
+    fn encapsulate(&mut self, inner: &[u8]) -> Vec<u8> {
+        let flags = self.flags();
+        let header_len = header_len(flags);
+        let mut buffer = vec![0; header_len + inner.len()];
+        write_u16(&mut buffer[0..2], flags);
+        write_u16(&mut buffer[2..4], EtherTypes::Ipv4.0);
+        if let Some(key) = self.key {
+            let offset = if self.checksum { 8 } else { 4 };
+            write_u32(&mut buffer[offset..offset + 4], key);
+        }
+        buffer[header_len..].copy_from_slice(inner);
+        if self.checksum {
+            let csum = checksum(&buffer, 2);
+            write_u16(&mut buffer[4..6], csum);
+        }
+        buffer
+    }
+
+    fn decapsulate(&mut self, outer: &[u8]) -> Result<Vec<u8>, RxError> {
+        if outer.len() < 4 {
+            return Err(RxError::InvalidLength);
+        }
+        let flags = read_u16(&outer[0..2]);
+        if flags & ROUTING_PRESENT != 0 || flags & VERSION_MASK != 0 ||
+           read_u16(&outer[2..4]) != EtherTypes::Ipv4.0 {
+            return Err(RxError::InvalidContent);
+        }
+        let header_len = header_len(flags);
+        if outer.len() < header_len {
+            return Err(RxError::InvalidLength);
+        }
+        let mut offset = 4;
+        if flags & CHECKSUM_PRESENT != 0 {
+            if read_u16(&outer[4..6]) != checksum(outer, 2) {
+                return Err(RxError::InvalidChecksum);
+            }
+            offset += 4;
+        } else if self.checksum {
+            return Err(RxError::InvalidChecksum);
+        }
+        let key = if flags & KEY_PRESENT != 0 {
+            Some(read_u32(&outer[offset..offset + 4]))
+        } else {
+            None
+        };
+        if key != self.key {
+            return Err(RxError::NoListener(format!("GRE key {:?}", key)));
+        }
+        Ok(outer[header_len..].to_vec())
+    }
+}
+
+fn header_len(flags: u16) -> usize {
+    let mut len = 4;
+    if flags & CHECKSUM_PRESENT != 0 {
+        len += 4;
+    }
+    if flags & KEY_PRESENT != 0 {
+        len += 4;
+    }
+    if flags & SEQUENCE_PRESENT != 0 {
+        len += 4;
+    }
+    len
+}
+
+fn read_u16(buffer: &[u8]) -> u16 {
+    ((buffer[0] as u16) << 8) | buffer[1] as u16
+}
+
+fn read_u32(buffer: &[u8]) -> u32 {
+    ((read_u16(&buffer[0..2]) as u32) << 16) | read_u16(&buffer[2..4]) as u32
+}
+
+fn write_u16(buffer: &mut [u8], value: u16) {
+    buffer[0] = (value >> 8) as u8;
+    buffer[1] = value as u8;
+}
+
+fn write_u32(buffer: &mut [u8], value: u32) {
+    write_u16(&mut buffer[0..2], (value >> 16) as u16);
+    write_u16(&mut buffer[2..4], value as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    use RxError;
+
+    use super::*;
+    use super::super::Encapsulation;
+
+    static INNER: [u8; 5] = [0x45, 1, 2, 3, 4];
+
+    #[test]
+    fn plain() {
+        let mut gre = Gre::default();
+        let outer = gre.encapsulate(&INNER);
+        assert_eq!(&outer[..4], &[0, 0, 0x08, 0x00]);
+        assert_eq!(&outer[4..], &INNER);
+        assert_eq!(gre.overhead(), 4);
+        assert_eq!(gre.decapsulate(&outer).unwrap(), INNER.to_vec());
+    }
+
+    #[test]
+    fn key_and_checksum() {
+        let mut gre = Gre::new(Some(0x01020304), true);
+        let outer = gre.encapsulate(&INNER);
+        assert_eq!(gre.overhead(), 12);
+        assert_eq!(outer.len(), 12 + INNER.len());
+        assert_eq!(&outer[..4], &[0xa0, 0, 0x08, 0x00]);
+        assert_eq!(&outer[8..12], &[1, 2, 3, 4]);
+        assert_eq!(checksum(&outer, 2), read_u16(&outer[4..6]));
+        assert_eq!(gre.decapsulate(&outer).unwrap(), INNER.to_vec());
+
+        let mut corrupt = outer.clone();
+        corrupt[14] ^= 0xff;
+        assert_eq!(gre.decapsulate(&corrupt), Err(RxError::InvalidChecksum));
+    }
+
+    #[test]
+    fn key_mismatch() {
+        let outer = Gre::new(Some(7), false).encapsulate(&INNER);
+        assert!(Gre::new(Some(8), false).decapsulate(&outer).is_err());
+        assert!(Gre::default().decapsulate(&outer).is_err());
+        let outer = Gre::default().encapsulate(&INNER);
+        assert!(Gre::new(Some(7), false).decapsulate(&outer).is_err());
+    }
+
+    #[test]
+    fn sequence_number() {
+        let mut outer = vec![0x10, 0, 0x08, 0x00, 0, 0, 0, 42];
+        outer.extend_from_slice(&INNER);
+        assert_eq!(Gre::default().decapsulate(&outer).unwrap(), INNER.to_vec());
+    }
+
+    #[test]
+    fn invalid() {
+        let mut gre = Gre::default();
+        assert_eq!(gre.decapsulate(&[0, 0, 0x08]), Err(RxError::InvalidLength));
+        assert_eq!(gre.decapsulate(&[0, 0, 0x86, 0xdd]), Err(RxError::InvalidContent));
+        assert_eq!(gre.decapsulate(&[0x40, 0, 0x08, 0, 0, 0, 0, 0]),
+                   Err(RxError::InvalidContent));
+        assert_eq!(gre.decapsulate(&[0x20, 0, 0x08, 0]), Err(RxError::InvalidLength));
+    }
+}
@@ -0,0 +1,28 @@
+use RxError;
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+use super::Encapsulation;
+
+/// IP-in-IP encapsulation, RFC 2003. The inner packet is carried as is as the
+/// payload of an outer packet with protocol 4.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ipip;
+
+impl Encapsulation for Ipip {
+    fn protocol(&self) -> IpNextHeaderProtocol {
+        IpNextHeaderProtocols::Ipv4
+    }
+
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    fn encapsulate(&mut self, inner: &[u8]) -> Vec<u8> {
+        inner.to_vec()
+    }
+
+    fn decapsulate(&mut self, outer: &[u8]) -> Result<Vec<u8>, RxError> {
+        Ok(outer.to_vec())
+    }
+}
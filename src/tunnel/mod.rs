@@ -0,0 +1,341 @@
+//! Tunnel interfaces, carrying IPv4 packets inside IPv4 packets to a remote
+//! endpoint.
+//!
+//! A tunnel is added to a `NetworkStack` as an interface of its own, so it is
+//! routed like any other interface. Packets sent out on it are wrapped by its
+//! `Encapsulation` and sent from the local to the remote endpoint through the
+//! same stack. Since tunnels are point to point, Arp requests on them are
+//! answered directly by the tunnel.
+//!
+//! Custom point to point transports, such as encrypted ones, can be built on
+//! `add_codec_interface`, leaving the transport of the encoded packets to the
+//! caller.
+//!
+//! The `vxlan` module instead carries whole Ethernet frames over UDP, giving
+//! interfaces on a virtual LAN shared with any number of remote endpoints.
+
+use {Interface, NetworkStack, RxError, RxResult, StackError, StackResult, TxError};
+use arp::ArpBuilder;
+use ipv4::{self, BasicIpv4Payload, Ipv4Listener, Ipv4Tx};
+use rx::POLL_INTERVAL_MS;
+
+use pnet::datalink::{EthernetDataLinkChannelIterator, EthernetDataLinkReceiver,
+                     EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocol;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::MacAddr;
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use Payload;
+
+mod codec;
+mod gre;
+mod ipip;
+pub mod vxlan;
+
+pub use self::codec::{Codec, CodecRx, CodecTx, add_codec_interface};
+pub use self::gre::Gre;
+pub use self::ipip::Ipip;
+pub use self::vxlan::Vtep;
+
+/// Wraps and unwraps the packets going through a tunnel.
+pub trait Encapsulation: Send {
+    /// The IP protocol of the outer packets.
+    fn protocol(&self) -> IpNextHeaderProtocol;
+
+    /// Number of bytes `encapsulate` adds to each packet, on top of the
+    /// outer IPv4 header. The MTU of the tunnel is reduced by this much.
+    fn overhead(&self) -> usize;
+
+    /// Returns the payload of the outer packet carrying the IPv4 packet
+    /// `inner`.
+    fn encapsulate(&mut self, inner: &[u8]) -> Vec<u8>;
+
+    /// Returns the IPv4 packet carried in `outer`, the payload of a packet
+    /// received from the remote endpoint.
+    fn decapsulate(&mut self, outer: &[u8]) -> Result<Vec<u8>, RxError>;
+}
+
+/// Adds a tunnel interface named `name` to `stack`, sending the packets
+/// routed to it from `local` to `remote` wrapped by `encapsulation`. `local`
+/// must be an address on some other interface of the stack.
+///
+/// The MTU of the tunnel is the MTU towards `remote` when the tunnel is
+/// added, see `NetworkStack::mtu_to`, minus the outer IPv4 header and the
+/// encapsulation overhead. Returns the new interface.
+///
+/// The thread sending the encapsulated packets holds no reference to the
+/// stack, it quits once the stack is dropped or shut down.
+pub fn add_tunnel<E>(stack: Arc<Mutex<NetworkStack>>,
+                     name: &str,
+                     local: Ipv4Addr,
+                     remote: Ipv4Addr,
+                     encapsulation: E)
+                     -> StackResult<Interface>
+    where E: Encapsulation + 'static
+{
+    let protocol = encapsulation.protocol();
+    let interface = Interface {
+        name: name.to_owned(),
+        mac: tunnel_mac(local),
+    };
+    let codec = Encapsulated(encapsulation);
+    let overhead = codec.overhead();
+    let (channel, codec_tx, codec_rx) = codec::codec_channel(interface.mac, codec);
+    {
+        let mut stack = stack.lock().unwrap();
+        let outer_mtu = stack.mtu_to(remote)?;
+        if outer_mtu < overhead + ipv4::MIN_MTU {
+            return Err(StackError::InvalidMtu(outer_mtu.saturating_sub(overhead)));
+        }
+        let mtu = outer_mtu - overhead;
+        let tunnel_rx = TunnelRx {
+            remote: remote,
+            codec_rx: codec_rx,
+        };
+        stack.ipv4_listen(local, protocol, Box::new(tunnel_rx))?;
+        stack.add_interface(interface.clone(), channel)?;
+        stack.interface(&interface)?.set_mtu(mtu)?;
+    }
+
+    let tunnel_tx = TunnelTx {
+        stack: Arc::downgrade(&stack),
+        local: local,
+        remote: remote,
+        protocol: protocol,
+    };
+    thread::spawn(move || tunnel_tx.run(codec_tx));
+    Ok(interface)
+}
+
+/// Adapts an `Encapsulation` to a `Codec`, accounting for the outer IPv4
+/// header in the overhead.
+struct Encapsulated<E: Encapsulation>(E);
+
+impl<E: Encapsulation> Codec for Encapsulated<E> {
+    fn overhead(&self) -> usize {
+        Ipv4Packet::minimum_packet_size() + self.0.overhead()
+    }
+
+    fn encode(&mut self, packet: &[u8]) -> Vec<u8> {
+        self.0.encapsulate(packet)
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, RxError> {
+        self.0.decapsulate(data)
+    }
+}
+
+/// Tunnels have no real link layer, so they get a locally administered MAC
+/// derived from the local endpoint.
+fn tunnel_mac(local: Ipv4Addr) -> MacAddr {
+    let octets = local.octets();
+    MacAddr::new(0x02, 0, octets[0], octets[1], octets[2], octets[3])
+}
+
+/// The MAC the remote end of a tunnel appears to have.
+fn peer_mac(mac: MacAddr) -> MacAddr {
+    let MacAddr(a, b, c, d, e, f) = mac;
+    MacAddr::new(a, b | 0x01, c, d, e, f)
+}
+
+/// Sending half of the channel of a tunnel interface, passing the frames on
+/// to the thread doing the encapsulation. Point to point tunnels set
+/// `inject` to answer Arp requests right away.
+struct TunnelSender {
+    frames: Sender<Box<[u8]>>,
+    inject: Option<Sender<Box<[u8]>>>,
+}
+
+impl TunnelSender {
+    fn send_frame(&mut self, frame: Box<[u8]>) {
+        let reply = {
+            let eth_pkg = EthernetPacket::new(&frame).unwrap();
+            if self.inject.is_some() && eth_pkg.get_ethertype() == EtherTypes::Arp {
+                Some(Self::arp_reply(&eth_pkg))
+            } else {
+                None
+            }
+        };
+        match reply {
+            Some(Some(reply)) => self.inject.as_ref().unwrap().send(reply).unwrap_or(()),
+            Some(None) => (),
+            None => self.frames.send(frame).unwrap_or(()),
+        }
+    }
+
+    fn arp_reply(eth_pkg: &EthernetPacket) -> Option<Box<[u8]>> {
+        let arp_pkg = match ArpPacket::new(eth_pkg.payload()) {
+            Some(arp_pkg) => arp_pkg,
+            None => return None,
+        };
+        if arp_pkg.get_operation() != ArpOperations::Request {
+            return None;
+        }
+        let mac = eth_pkg.get_source();
+        let mut builder = ArpBuilder::new_reply(peer_mac(mac),
+                                                arp_pkg.get_target_proto_addr(),
+                                                mac,
+                                                arp_pkg.get_sender_proto_addr());
+        Some(ethernet_frame(peer_mac(mac), mac, EtherTypes::Arp, &mut builder))
+    }
+}
+
+impl EthernetDataLinkSender for TunnelSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0; packet_size];
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            self.send_frame(buffer.into_boxed_slice());
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        self.send_frame(packet.packet().to_vec().into_boxed_slice());
+        Some(Ok(()))
+    }
+}
+
+/// Receiving half of the channel of a tunnel interface, yielding the frames
+/// injected by `TunnelSender` and `TunnelRx`.
+struct TunnelReceiver {
+    frames: Option<Receiver<Box<[u8]>>>,
+}
+
+impl EthernetDataLinkReceiver for TunnelReceiver {
+    fn iter<'a>(&'a mut self) -> Box<EthernetDataLinkChannelIterator + 'a> {
+        Box::new(TunnelReceiverIterator {
+            frames: self.frames.take().expect("Only one receiver allowed"),
+            current: None,
+        })
+    }
+}
+
+struct TunnelReceiverIterator {
+    frames: Receiver<Box<[u8]>>,
+    current: Option<Box<[u8]>>,
+}
+
+impl<'a> EthernetDataLinkChannelIterator<'a> for TunnelReceiverIterator {
+    fn next(&mut self) -> io::Result<EthernetPacket> {
+        loop {
+            match self.frames.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                // Decapsulated frames come from the remote endpoint, too
+                // short ones are dropped rather than ending the rx thread
+                Ok(frame) => {
+                    if frame.len() < EthernetPacket::minimum_packet_size() {
+                        debug!("Tunnel: Dropping frame of only {} bytes", frame.len());
+                        continue;
+                    }
+                    self.current = Some(frame);
+                    return Ok(EthernetPacket::new(self.current.as_ref().unwrap()).unwrap());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "No frame received"))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // The tunnel is gone, so the rx thread can quit
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Tunnel is gone"));
+                }
+            }
+        }
+    }
+}
+
+/// Hands packets from the remote endpoint to the tunnel interface.
+struct TunnelRx<E: Encapsulation> {
+    remote: Ipv4Addr,
+    codec_rx: CodecRx<Encapsulated<E>>,
+}
+
+impl<E: Encapsulation> Ipv4Listener for TunnelRx<E> {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
+        if ip_pkg.get_source() != self.remote {
+            return Err(RxError::NoListener(format!("Tunnel from {}", ip_pkg.get_source())));
+        }
+        self.codec_rx.deliver(ip_pkg.payload())
+    }
+}
+
+/// Sends the encapsulated packets from the tunnel interface to the remote
+/// endpoint.
+struct TunnelTx {
+    /// Weak, as the stack owns the interface feeding `run`
+    stack: Weak<Mutex<NetworkStack>>,
+    local: Ipv4Addr,
+    remote: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+}
+
+impl TunnelTx {
+    fn run<E: Encapsulation>(self, codec_tx: CodecTx<Encapsulated<E>>) {
+        let mut ipv4_tx = None;
+        while let Some(outer) = codec_tx.recv() {
+            let payload = BasicIpv4Payload::new(self.protocol, &outer);
+            loop {
+                if ipv4_tx.is_none() {
+                    let stack = match self.stack.upgrade() {
+                        Some(stack) => stack,
+                        None => {
+                            debug!("Tunnel to {} is quitting, the stack is gone", self.remote);
+                            return;
+                        }
+                    };
+                    let mut stack = stack.lock().unwrap();
+                    match stack.ipv4_tx_with_src(self.local, self.remote) {
+                        Ok(tx) => ipv4_tx = Some(tx),
+                        Err(e) => {
+                            warn!("Tunnel: No way to reach {}: {:?}", self.remote, e);
+                            break;
+                        }
+                    }
+                }
+                match ipv4_tx.as_mut().unwrap().send(payload.clone()) {
+                    Err(TxError::InvalidTx) => ipv4_tx = None,
+                    Err(e) => {
+                        warn!("Tunnel: Unable to send to {}: {}", self.remote, e);
+                        break;
+                    }
+                    Ok(_) => break,
+                }
+            }
+        }
+        debug!("Tunnel to {} is quitting", self.remote);
+    }
+}
+
+fn ethernet_frame<P: Payload>(src: MacAddr,
+                              dst: MacAddr,
+                              ether_type: ::pnet::packet::ethernet::EtherType,
+                              payload: &mut P)
+                              -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + payload.len()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(src);
+        eth_pkg.set_destination(dst);
+        eth_pkg.set_ethertype(ether_type);
+        payload.build(eth_pkg.payload_mut());
+    }
+    buffer.into_boxed_slice()
+}
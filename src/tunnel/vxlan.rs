@@ -0,0 +1,402 @@
+//! Virtual Extensible LAN, RFC 7348. Ethernet frames are carried in UDP
+//! datagrams between VXLAN tunnel endpoints (VTEPs), with a 24 bit VXLAN
+//! network identifier (VNI) separating the overlay segments.
+
+use {DatalinkTx, EthernetChannel, Interface, NetworkStack, RxError, RxResult, StackError,
+     StackResult, TxError};
+use ethernet::EthernetTxImpl;
+use ipv4::Ipv4TxImpl;
+use rx::POLL_INTERVAL_MS;
+use stack::DEFAULT_MTU;
+use udp::{UdpListener, UdpTx};
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::util::MacAddr;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use super::{TunnelReceiver, TunnelSender};
+
+/// The IANA assigned UDP port for VXLAN.
+pub const VXLAN_PORT: u16 = 4789;
+
+/// Length of the VXLAN header.
+pub const HEADER_LEN: usize = 8;
+
+/// Bytes added to each IPv4 packet sent on a VXLAN interface: the inner
+/// Ethernet header and the VXLAN, UDP and IPv4 headers of the outer packet.
+pub const OVERHEAD: usize = 14 + HEADER_LEN + 8 + 20;
+
+/// Largest valid VNI.
+pub const MAX_VNI: u32 = 0xff_ffff;
+
+/// Flag signaling a valid VNI, the only flag defined.
+const FLAG_VNI: u8 = 0x08;
+
+/// Maximum number of frames to hold per remote VTEP while waiting for a way
+/// to send to it.
+const MAX_PENDING: usize = 64;
+
+/// The MAC learning table of a VXLAN segment, mapping inner MAC addresses to
+/// the VTEP they were last seen behind.
+pub type MacTable = HashMap<MacAddr, Ipv4Addr>;
+
+type OuterTx = UdpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>;
+
+/// Returns the VXLAN header for `vni`.
+pub fn header(vni: u32) -> [u8; HEADER_LEN] {
+    [FLAG_VNI, 0, 0, 0, (vni >> 16) as u8, (vni >> 8) as u8, vni as u8, 0]
+}
+
+/// Parses a VXLAN header. Returns the VNI and the encapsulated frame, or
+/// `None` if `payload` is not a valid VXLAN packet.
+pub fn parse(payload: &[u8]) -> Option<(u32, &[u8])> {
+    if payload.len() < HEADER_LEN + EthernetPacket::minimum_packet_size() ||
+       payload[0] & FLAG_VNI == 0 {
+        return None;
+    }
+    let vni = ((payload[4] as u32) << 16) | ((payload[5] as u32) << 8) | payload[6] as u32;
+    Some((vni, &payload[HEADER_LEN..]))
+}
+
+struct Segment {
+    inject: Sender<Box<[u8]>>,
+    mac_table: Arc<Mutex<MacTable>>,
+}
+
+type Segments = Arc<Mutex<HashMap<u32, Segment>>>;
+
+/// The threads sending the frames of one VXLAN interface.
+struct Threads {
+    msgs: Sender<TxMsg>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+/// A VXLAN tunnel endpoint on a `NetworkStack`. Owns the UDP port and
+/// dispatches incoming packets to the VXLAN interfaces added to it, one per
+/// VNI.
+///
+/// Dropping the VTEP stops the threads of its interfaces. The interfaces
+/// stay in the stack, but no longer send or receive anything.
+pub struct Vtep {
+    stack: Arc<Mutex<NetworkStack>>,
+    local: SocketAddrV4,
+    segments: Segments,
+    stop: Arc<AtomicBool>,
+    threads: Mutex<Vec<Threads>>,
+}
+
+impl Vtep {
+    /// Creates a VTEP on `local`, one of the addresses of `stack`, listening
+    /// on the standard VXLAN port.
+    pub fn bind(stack: Arc<Mutex<NetworkStack>>, local: Ipv4Addr) -> io::Result<Vtep> {
+        Self::bind_to(stack, SocketAddrV4::new(local, VXLAN_PORT))
+    }
+
+    /// Creates a VTEP listening on `local`. Packets are sent to the same
+    /// port on the remote VTEPs.
+    pub fn bind_to(stack: Arc<Mutex<NetworkStack>>, local: SocketAddrV4) -> io::Result<Vtep> {
+        let segments = Arc::new(Mutex::new(HashMap::new()));
+        let listener = VxlanListener { segments: segments.clone() };
+        stack.lock().unwrap().udp_listen(local, listener)?;
+        Ok(Vtep {
+            stack: stack,
+            local: local,
+            segments: segments,
+            stop: Arc::new(AtomicBool::new(false)),
+            threads: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    /// Adds a VXLAN interface for the segment `vni` to the stack. Frames to
+    /// unknown, broadcast and multicast MAC addresses are flooded to all of
+    /// `remotes`, frames to learned MAC addresses go to the VTEP they were
+    /// learned from.
+    ///
+    /// Fails with `StackError::InvalidArgument` if `vni` is out of range, and
+    /// `StackError::AlreadyExists` if it already has an interface on this
+    /// VTEP.
+    pub fn add_interface(&self,
+                         name: &str,
+                         vni: u32,
+                         mac: MacAddr,
+                         remotes: &[Ipv4Addr])
+                         -> StackResult<Interface> {
+        if vni > MAX_VNI {
+            return Err(StackError::InvalidArgument(format!("VNI {} is out of range", vni)));
+        }
+        if self.segments.lock().unwrap().contains_key(&vni) {
+            return Err(StackError::AlreadyExists(format!("Interface for VNI {}", vni)));
+        }
+        let interface = Interface {
+            name: name.to_owned(),
+            mac: mac,
+        };
+        let mac_table = Arc::new(Mutex::new(HashMap::new()));
+        let (frames_tx, frames_rx) = mpsc::channel();
+        let (msg_tx, msg_rx) = mpsc::channel();
+        let (inject_tx, inject_rx) = mpsc::channel();
+        let channel = EthernetChannel(Box::new(TunnelSender {
+                                          frames: frames_tx,
+                                          inject: None,
+                                      }),
+                                      Box::new(TunnelReceiver { frames: Some(inject_rx) }));
+        let preparer = Preparer {
+            stack: Arc::downgrade(&self.stack),
+            local: self.local,
+            ready: msg_tx.clone(),
+        };
+
+        {
+            let mut stack = self.stack.lock().unwrap();
+            stack.add_interface(interface.clone(), channel)?;
+            stack.interface(&interface)?.set_mtu(DEFAULT_MTU - OVERHEAD)?;
+            // Set up the flooding before anything else can hold the stack
+            // lock, sending Arp requests that need flooding.
+            for remote in remotes {
+                msg_tx.send(TxMsg::Ready(*remote, preparer.outer_tx(&mut stack, *remote)))
+                    .unwrap();
+            }
+        }
+        self.segments.lock().unwrap().insert(vni,
+                                             Segment {
+                                                 inject: inject_tx,
+                                                 mac_table: mac_table.clone(),
+                                             });
+
+        let (prepare_tx, prepare_rx) = mpsc::channel();
+        let mut handles = vec![thread::spawn(move || preparer.run(prepare_rx))];
+        let stop = self.stop.clone();
+        let frames_msg_tx = msg_tx.clone();
+        handles.push(thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match frames_rx.recv_timeout(Duration::from_millis(POLL_INTERVAL_MS)) {
+                    Ok(frame) => {
+                        if frames_msg_tx.send(TxMsg::Frame(frame)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        }));
+        let vxlan_tx = VxlanTx {
+            vni: vni,
+            remotes: remotes.to_vec(),
+            mac_table: mac_table,
+            txs: HashMap::new(),
+            pending: HashMap::new(),
+            prepare: prepare_tx,
+        };
+        handles.push(thread::spawn(move || vxlan_tx.run(msg_rx)));
+        self.threads.lock().unwrap().push(Threads {
+            msgs: msg_tx,
+            handles: handles,
+        });
+        Ok(interface)
+    }
+
+    /// Returns a copy of the MAC learning table of the segment `vni`.
+    pub fn mac_table(&self, vni: u32) -> Option<MacTable> {
+        self.segments
+            .lock()
+            .unwrap()
+            .get(&vni)
+            .map(|segment| segment.mac_table.lock().unwrap().clone())
+    }
+}
+
+impl Drop for Vtep {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Without their segments the receivers of the interfaces see their
+        // channel close, which ends the rx threads
+        self.segments.lock().unwrap().clear();
+        for threads in self.threads.get_mut().unwrap().drain(..) {
+            threads.msgs.send(TxMsg::Stop).unwrap_or(());
+            for handle in threads.handles {
+                handle.join().unwrap_or(());
+            }
+        }
+    }
+}
+
+/// Receives the VXLAN packets for all segments of a `Vtep`.
+#[derive(Clone)]
+struct VxlanListener {
+    segments: Segments,
+}
+
+impl UdpListener for VxlanListener {
+    fn recv(&mut self, _time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+        let udp_pkg = UdpPacket::new(packet.payload()).unwrap();
+        let (vni, frame) = match parse(udp_pkg.payload()) {
+            Some(parsed) => parsed,
+            None => return (Err(RxError::InvalidContent), true),
+        };
+        let segments = self.segments.lock().unwrap();
+        let segment = match segments.get(&vni) {
+            Some(segment) => segment,
+            None => return (Err(RxError::NoListener(format!("VNI {}", vni))), true),
+        };
+        let src_mac = EthernetPacket::new(frame).unwrap().get_source();
+        segment.mac_table.lock().unwrap().insert(src_mac, packet.get_source());
+        let result = segment.inject
+            .send(frame.to_vec().into_boxed_slice())
+            .map_err(|_| RxError::NoListener(format!("VNI {} interface is gone", vni)));
+        (result, true)
+    }
+}
+
+enum TxMsg {
+    Frame(Box<[u8]>),
+    Ready(Ipv4Addr, Option<OuterTx>),
+    Stop,
+}
+
+/// Creates the outer `UdpTx`s. Runs in a thread of its own since it needs
+/// the stack lock, which the thread waiting for an Arp reply over the
+/// overlay might be holding. Quits when the `VxlanTx` or the stack is gone.
+struct Preparer {
+    /// Weak, as the stack owns the interface whose frames lead here
+    stack: Weak<Mutex<NetworkStack>>,
+    local: SocketAddrV4,
+    ready: Sender<TxMsg>,
+}
+
+impl Preparer {
+    fn run(self, requests: Receiver<Ipv4Addr>) {
+        while let Ok(remote) = requests.recv() {
+            let stack = match self.stack.upgrade() {
+                Some(stack) => stack,
+                None => break,
+            };
+            let tx = {
+                let mut stack = stack.lock().unwrap();
+                self.outer_tx(&mut stack, remote)
+            };
+            if self.ready.send(TxMsg::Ready(remote, tx)).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn outer_tx(&self, stack: &mut NetworkStack, remote: Ipv4Addr) -> Option<OuterTx> {
+        match stack.ipv4_tx_with_src(*self.local.ip(), remote) {
+            Ok(ipv4_tx) => Some(UdpTx::new(ipv4_tx, self.local.port(), self.local.port())),
+            Err(e) => {
+                warn!("VXLAN: No way to reach VTEP {}: {:?}", remote, e);
+                None
+            }
+        }
+    }
+}
+
+/// Encapsulates and sends the frames of one VXLAN interface.
+struct VxlanTx {
+    vni: u32,
+    remotes: Vec<Ipv4Addr>,
+    mac_table: Arc<Mutex<MacTable>>,
+    txs: HashMap<Ipv4Addr, OuterTx>,
+    pending: HashMap<Ipv4Addr, Vec<Vec<u8>>>,
+    prepare: Sender<Ipv4Addr>,
+}
+
+impl VxlanTx {
+    fn run(mut self, msgs: Receiver<TxMsg>) {
+        while let Ok(msg) = msgs.recv() {
+            match msg {
+                TxMsg::Frame(frame) => self.send_frame(&frame),
+                TxMsg::Ready(remote, Some(tx)) => {
+                    self.txs.insert(remote, tx);
+                    for payload in self.pending.remove(&remote).unwrap_or_default() {
+                        self.send(remote, payload);
+                    }
+                }
+                TxMsg::Ready(remote, None) => {
+                    self.pending.remove(&remote);
+                }
+                TxMsg::Stop => break,
+            }
+        }
+        debug!("VXLAN interface for VNI {} is quitting", self.vni);
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) {
+        let dst = EthernetPacket::new(frame).unwrap().get_destination();
+        let learned = if dst.0 & 0x01 == 0 {
+            self.mac_table.lock().unwrap().get(&dst).cloned()
+        } else {
+            None
+        };
+        let vteps = match learned {
+            Some(vtep) => vec![vtep],
+            None => self.remotes.clone(),
+        };
+        let mut payload = header(self.vni).to_vec();
+        payload.extend_from_slice(frame);
+        for vtep in vteps {
+            self.send(vtep, payload.clone());
+        }
+    }
+
+    fn send(&mut self, remote: Ipv4Addr, payload: Vec<u8>) {
+        let result = match self.txs.get_mut(&remote) {
+            Some(tx) => tx.send(&payload),
+            None => Err(TxError::InvalidTx),
+        };
+        match result {
+            Ok(_) => (),
+            Err(TxError::InvalidTx) => {
+                self.txs.remove(&remote);
+                let pending = self.pending.entry(remote).or_insert_with(Vec::new);
+                if pending.is_empty() {
+                    self.prepare.send(remote).unwrap_or(());
+                }
+                if pending.len() < MAX_PENDING {
+                    pending.push(payload);
+                }
+            }
+            Err(e) => warn!("VXLAN: Unable to send to VTEP {}: {}", remote, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let mut payload = header(0x123456).to_vec();
+        assert_eq!(&payload, &[0x08, 0, 0, 0, 0x12, 0x34, 0x56, 0]);
+        payload.extend_from_slice(&[0; 14]);
+        let (vni, frame) = parse(&payload).unwrap();
+        assert_eq!(vni, 0x123456);
+        assert_eq!(frame.len(), 14);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse(&header(1)).is_none());
+        let mut payload = vec![0; HEADER_LEN + 14];
+        assert!(parse(&payload).is_none());
+        payload[0] = FLAG_VNI;
+        assert!(parse(&payload).is_some());
+    }
+}
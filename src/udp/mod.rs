@@ -1,101 +1,389 @@
 use {NetworkStack, StackError, StackResult, DatalinkTx};
-use {TxError, TxResult};
+use {TxError, TxResult, TxSent};
+use bpf::Program;
+use dns::{self, ToStackAddr};
 use ethernet::EthernetTxImpl;
+use handle;
 use ipv4::Ipv4TxImpl;
+use reactor::{Async, Waker};
+use select::Selectable;
+use shaping::{RateLimit, Shaper};
+use sockopt::{HasSocketOptions, SocketOptions};
 
 use std::collections::HashMap;
 use std::io;
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
-use std::sync::{Arc, Mutex};
-
-use util;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex, RwLock};
 
 mod udp_rx;
 mod udp_tx;
 
-pub use self::udp_rx::{UdpListener, UdpListenerLookup, UdpRx};
+pub use self::udp_rx::{BasicUdpListener, Datagram, OwnedDatagram, UdpCallbackListener,
+                       UdpListener, UdpListenerLookup, UdpRx};
 use self::udp_rx::UdpSocketReader;
-pub use self::udp_tx::{UdpBuilder, UdpTx};
+pub use self::udp_tx::UdpTx;
+pub use proto::udp::UdpBuilder;
+
+/// Maximum number of destination addresses a `UdpSender` keeps tx-objects
+/// for. The cache is flushed when it grows beyond this.
+pub static TX_CACHE_SIZE: usize = 1024;
+
+type CachedTx = UdpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>;
+
+/// Sends UDP datagrams from one local port to destinations given per call.
+/// The tx-object towards each destination address is kept and reused for all
+/// its ports, so routing and Arp are only done again after the stack changed.
+pub struct UdpSender {
+    stack: Arc<Mutex<NetworkStack>>,
+    vrf: Option<String>,
+    src_port: u16,
+    /// The tx-objects, and if their destination is a broadcast address
+    tx_cache: HashMap<Ipv4Addr, (CachedTx, bool)>,
+    options: Arc<RwLock<SocketOptions>>,
+}
+
+impl UdpSender {
+    pub fn new(stack: Arc<Mutex<NetworkStack>>, src_port: u16) -> UdpSender {
+        Self::with_vrf_opt(stack, None, src_port)
+    }
+
+    /// Same as `new`, but routes what it sends with the routing table of the
+    /// VRF `vrf`.
+    pub fn in_vrf(stack: Arc<Mutex<NetworkStack>>, vrf: &str, src_port: u16) -> UdpSender {
+        Self::with_vrf_opt(stack, Some(vrf), src_port)
+    }
+
+    fn with_vrf_opt(stack: Arc<Mutex<NetworkStack>>,
+                    vrf: Option<&str>,
+                    src_port: u16)
+                    -> UdpSender {
+        UdpSender {
+            stack: stack,
+            vrf: vrf.map(|vrf| vrf.to_owned()),
+            src_port: src_port,
+            tx_cache: HashMap::new(),
+            options: Arc::new(RwLock::new(SocketOptions::default())),
+        }
+    }
+
+    /// Returns the name of the VRF this sender routes in, if any.
+    pub fn vrf(&self) -> Option<&str> {
+        self.vrf.as_ref().map(|vrf| vrf.as_str())
+    }
+
+    pub fn src_port(&self) -> u16 {
+        self.src_port
+    }
+
+    /// Sends `payload` to `dst`, creating a new tx-object towards its address
+    /// only if there is no valid one yet.
+    pub fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> StackResult<TxSent> {
+        self.send_vectored_to(&[payload], dst)
+    }
+
+    /// Same as `send_to`, with the payload made of all of `parts`, one after
+    /// the other. They are copied into the frame directly, so a header and a
+    /// body do not have to be concatenated first.
+    ///
+    /// A cached tx-object found invalid is recreated once, as by
+    /// `RefreshingTx::send_with`. If the new one is invalidated before it
+    /// sends, this fails with `TxError::InvalidTx`.
+    pub fn send_vectored_to(&mut self,
+                            parts: &[&[u8]],
+                            dst: SocketAddrV4)
+                            -> StackResult<TxSent> {
+        match self.send_on_cached_tx(parts, dst) {
+            Err(TxError::InvalidTx) => {
+                try!(self.refresh_tx(*dst.ip()));
+                self.send_on_cached_tx(parts, dst).map_err(StackError::TxError)
+            }
+            result => result.map_err(StackError::TxError),
+        }
+    }
+
+    /// Returns the options the datagrams are sent with, shared with clones of
+    /// this sender. See `sockopt`.
+    pub fn options(&self) -> Arc<RwLock<SocketOptions>> {
+        self.options.clone()
+    }
+
+    /// Returns `Async::Ready` once `send_to` will not wait for an Arp reply
+    /// to send to `dst`, else makes `waker` be called when it might not. See
+    /// `NetworkStack::poll_resolve_in_vrf`.
+    pub fn poll_resolve(&mut self, dst: Ipv4Addr, waker: &Waker) -> StackResult<Async<()>> {
+        if self.tx_cache.contains_key(&dst) {
+            return Ok(Async::Ready(()));
+        }
+        let vrf = self.vrf.as_ref().map(|vrf| vrf.as_str());
+        self.stack.lock().unwrap().poll_resolve_in_vrf(vrf, dst, waker)
+    }
+
+    /// Returns `Async::Ready` if the interface sending to `dst` may take a
+    /// frame, else makes `waker` be called when a `send_to` that failed with
+    /// `TxError::WouldBlock` should be retried. See
+    /// `NetworkStack::poll_writable_in_vrf`.
+    pub fn poll_writable(&mut self, dst: Ipv4Addr, waker: &Waker) -> StackResult<Async<()>> {
+        let vrf = self.vrf.as_ref().map(|vrf| vrf.as_str());
+        self.stack.lock().unwrap().poll_writable_in_vrf(vrf, dst, waker)
+    }
+
+    /// Returns the largest payload that is sent to `dst` without being
+    /// fragmented, with the MTUs in effect now.
+    pub fn max_unfragmented(&mut self, dst: Ipv4Addr) -> StackResult<usize> {
+        try!(self.refresh_tx(dst));
+        Ok(self.tx_cache[&dst].0.max_unfragmented())
+    }
+
+    /// Replaces the cached tx-object towards `dst` with a new one.
+    fn refresh_tx(&mut self, dst: Ipv4Addr) -> StackResult<()> {
+        let src_port = self.src_port;
+        let new_udp_tx = {
+            let vrf = self.vrf.as_ref().map(|vrf| vrf.as_str());
+            let udp_tx = |stack: &mut NetworkStack| stack.udp_tx_in_vrf(vrf, dst, src_port, 0);
+            let udp_tx = try!(handle::with_resolved(&self.stack, vrf, dst, udp_tx));
+            (udp_tx, self.stack.lock().unwrap().is_broadcast_in_vrf(vrf, dst))
+        };
+        if self.tx_cache.len() >= TX_CACHE_SIZE {
+            self.tx_cache.clear();
+        }
+        self.tx_cache.insert(dst, new_udp_tx);
+        Ok(())
+    }
+
+    fn send_on_cached_tx(&mut self, parts: &[&[u8]], dst: SocketAddrV4) -> TxResult {
+        if parts.iter().map(|part| part.len()).sum::<usize>() > ::std::u16::MAX as usize {
+            return Err(TxError::TooLargePayload);
+        }
+        let options = *self.options.read().unwrap();
+        if let Some(&mut (ref mut udp_tx, broadcast)) = self.tx_cache.get_mut(dst.ip()) {
+            if broadcast && !options.broadcast {
+                let msg = "Sending to broadcast addresses is not enabled";
+                return Err(TxError::IoError(io::Error::new(io::ErrorKind::PermissionDenied, msg)));
+            }
+            udp_tx.set_ttl(options.ttl_to(*dst.ip()));
+            udp_tx.set_tos(options.tos);
+            udp_tx.send_vectored_to_port(parts, dst.port())
+        } else {
+            // No cached UdpTx is treated as an existing but outdated one
+            Err(TxError::InvalidTx)
+        }
+    }
+}
+
+impl Clone for UdpSender {
+    /// Clones start without any cached tx-objects, and share the options.
+    fn clone(&self) -> UdpSender {
+        UdpSender {
+            options: self.options.clone(),
+            ..Self::with_vrf_opt(self.stack.clone(), self.vrf(), self.src_port)
+        }
+    }
+}
 
 pub struct UdpSocket {
     socket_addr: SocketAddr,
-    stack: Arc<Mutex<NetworkStack>>,
-    tx_cache: HashMap<SocketAddrV4, UdpTx<Ipv4TxImpl<EthernetTxImpl<DatalinkTx>>>>,
+    sender: UdpSender,
     rx: Option<UdpSocketReader>,
+    filter: Arc<RwLock<Option<Program>>>,
+    shaper: Arc<Shaper>,
 }
 
 impl UdpSocket {
-    pub fn bind<A: ToSocketAddrs>(stack: Arc<Mutex<NetworkStack>>,
-                                  addr: A)
-                                  -> io::Result<UdpSocket> {
-        let mut socket_reader = UdpSocketReader::new();
+    /// Binds a socket to `addr`, an address of the stack or a host name
+    /// with one, looked up with the resolver of the stack. See
+    /// `dns::ToStackAddr`.
+    pub fn bind<A: ToStackAddr>(stack: Arc<Mutex<NetworkStack>>, addr: A) -> io::Result<UdpSocket> {
+        Self::bind_vrf_opt(stack, None, addr)
+    }
+
+    /// Binds a socket to `addr` in the VRF `vrf`. The socket only receives
+    /// datagrams arriving on interfaces in that VRF and routes what it sends
+    /// with the routing table of the VRF.
+    pub fn bind_in_vrf<A: ToStackAddr>(stack: Arc<Mutex<NetworkStack>>,
+                                       vrf: &str,
+                                       addr: A)
+                                       -> io::Result<UdpSocket> {
+        Self::bind_vrf_opt(stack, Some(vrf), addr)
+    }
+
+    fn bind_vrf_opt<A: ToStackAddr>(stack: Arc<Mutex<NetworkStack>>,
+                                    vrf: Option<&str>,
+                                    addr: A)
+                                    -> io::Result<UdpSocket> {
+        let addr = try!(dns::resolve(&stack, addr));
+        let options = Arc::new(RwLock::new(SocketOptions::default()));
+        let mut socket_reader = UdpSocketReader::new(options.clone());
         let socket_addr = {
             let mut stack = stack.lock().unwrap();
-            try!(stack.udp_listen(addr, socket_reader.listener()))
+            try!(stack.udp_listen_in_vrf(vrf, addr, socket_reader.listener()))
         };
         Ok(UdpSocket {
             socket_addr: socket_addr,
-            stack: stack,
-            tx_cache: HashMap::new(),
+            sender: UdpSender {
+                options: options,
+                ..UdpSender::with_vrf_opt(stack, vrf, socket_addr.port())
+            },
+            filter: socket_reader.filter(),
+            shaper: Arc::new(Shaper::default()),
             rx: Some(socket_reader),
         })
     }
 
+    /// Returns the name of the VRF this socket is bound in, if any.
+    pub fn vrf(&self) -> Option<&str> {
+        self.sender.vrf()
+    }
+
+    /// Returns the reader of the datagrams received, which clones made with
+    /// `try_clone` don't have.
+    fn reader(&self) -> io::Result<&UdpSocketReader> {
+        self.rx.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Other, "A cloned socket does not receive")
+        })
+    }
+
+    /// Receives a datagram, waiting for one at most as long as the
+    /// `sockopt::ReadTimeout` of this socket.
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.rx.as_ref().unwrap().recv_from(buf)
+        try!(self.reader()).recv_from(buf)
+    }
+
+    /// Same as `recv_from`, but fails with `io::ErrorKind::WouldBlock`
+    /// instead of blocking if no datagram was received yet.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        try!(self.reader()).try_recv_from(buf)
+    }
+
+    /// Returns a file descriptor that is readable while datagrams are
+    /// waiting to be received, for polling this socket with mio or other
+    /// event loops. It stays readable until all of them are received, see
+    /// `reactor` for how to use it edge-triggered. The descriptor is owned by
+    /// the socket and must not be read from or closed.
+    #[cfg(unix)]
+    pub fn readiness_fd(&self) -> io::Result<RawFd> {
+        try!(self.reader()).readiness_fd()
+    }
+
+    /// Same as `recv_from`, but returns `Async::NotReady` instead of blocking
+    /// if no datagram was received yet, and calls `waker` when one is. See
+    /// `reactor`.
+    pub fn poll_recv_from(&self,
+                          buf: &mut [u8],
+                          waker: &Waker)
+                          -> io::Result<Async<(usize, SocketAddr)>> {
+        try!(self.reader()).poll_recv_from(buf, waker)
     }
 
-    pub fn send_to<A: ToSocketAddrs>(&mut self, buf: &[u8], addr: A) -> io::Result<usize> {
-        match try!(util::first_socket_addr(addr)) {
-            SocketAddr::V4(dst) => {
-                self.internal_send(buf, dst)
-                    .map(|_| buf.len())
-                    .map_err(|e| e.into())
+    /// Same as `send_to`, but returns `Async::NotReady` instead of blocking
+    /// while the MAC address of the next hop is resolved, or while an
+    /// interface in non-blocking mode has no room for the datagram, and calls
+    /// `waker` when it might be sent. Still blocks to keep to a rate limit set
+    /// with `set_rate_limit`. See `reactor`.
+    /// Host names are looked up while blocking, like by `send_to`.
+    pub fn poll_send_to<A: ToStackAddr>(&mut self,
+                                        buf: &[u8],
+                                        addr: A,
+                                        waker: &Waker)
+                                        -> io::Result<Async<usize>> {
+        let dst = try!(dns::resolve(&self.sender.stack, addr));
+        if !try!(self.sender.poll_resolve(*dst.ip(), waker)).is_ready() {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            match self.send_to(buf, dst) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result.map(Async::Ready),
             }
-            SocketAddr::V6(_dst) => {
-                Err(io::Error::new(io::ErrorKind::InvalidInput,
-                                   "Rips does not support IPv6 yet".to_owned()))
+            // Retried if the interface became writable in between
+            if !try!(self.sender.poll_writable(*dst.ip(), waker)).is_ready() {
+                return Ok(Async::NotReady);
             }
         }
     }
 
+    /// Sends `buf` to `addr`, an address or a host name looked up with the
+    /// resolver of the stack. See `dns::ToStackAddr`.
+    pub fn send_to<A: ToStackAddr>(&mut self, buf: &[u8], addr: A) -> io::Result<usize> {
+        self.send_vectored_to(&[buf], addr)
+    }
+
+    /// Same as `send_to`, sending one datagram made of all of `bufs`. Returns
+    /// the length of all of them together.
+    pub fn send_vectored_to<A: ToStackAddr>(&mut self,
+                                            bufs: &[&[u8]],
+                                            addr: A)
+                                            -> io::Result<usize> {
+        let dst = try!(dns::resolve(&self.sender.stack, addr));
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        self.shaper.wait(20 + 8 + len);
+        self.sender
+            .send_vectored_to(bufs, dst)
+            .map(|_| len)
+            .map_err(|e| e.into())
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         Ok(self.socket_addr)
     }
 
+    /// Returns a socket sending from the same address and sharing the
+    /// options of this one. Only the original socket receives, the clone
+    /// fails to with an `io::ErrorKind::Other` error and is never readable.
     pub fn try_clone(&self) -> io::Result<UdpSocket> {
         Ok(UdpSocket {
             socket_addr: self.socket_addr,
-            stack: self.stack.clone(),
-            tx_cache: HashMap::new(),
+            sender: self.sender.clone(),
             rx: None,
+            filter: self.filter.clone(),
+            shaper: self.shaper.clone(),
         })
     }
 
-    fn internal_send(&mut self, buf: &[u8], dst: SocketAddrV4) -> StackResult<()> {
-        match self.internal_send_on_cached_tx(buf, dst) {
-            Err(TxError::InvalidTx) => {
-                let (dst_ip, dst_port) = (*dst.ip(), dst.port());
-                let new_udp_tx = {
-                    let mut stack = self.stack.lock().unwrap();
-                    try!(stack.udp_tx(dst_ip, self.socket_addr.port(), dst_port))
-                };
-                self.tx_cache.insert(dst, new_udp_tx);
-                self.internal_send(buf, dst)
-            }
-            result => result.map_err(StackError::TxError),
-        }
+    /// Limits the rate this socket sends at, or removes the limit if `limit`
+    /// is `None`. `send_to` blocks until the datagram fits the limit, see
+    /// `shaping`. The limit is shared with clones of this socket.
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        self.shaper.set_limit(limit);
     }
 
-    fn internal_send_on_cached_tx(&mut self, buf: &[u8], dst: SocketAddrV4) -> TxResult {
-        if buf.len() > ::std::u16::MAX as usize {
-            return Err(TxError::TooLargePayload);
-        }
-        if let Some(udp_tx) = self.tx_cache.get_mut(&dst) {
-            udp_tx.send(buf)
-        } else {
-            // No cached UdpTx is treated as an existing but outdated one
-            Err(TxError::InvalidTx)
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.shaper.limit()
+    }
+
+    /// Sets a program the IPv4 packets of received datagrams have to pass,
+    /// or removes it if `filter` is `None`. Datagrams it does not accept are
+    /// dropped silently. The filter is shared with clones of this socket.
+    pub fn set_filter(&self, filter: Option<Program>) {
+        *self.filter.write().unwrap() = filter;
+    }
+
+    pub fn filter(&self) -> Option<Program> {
+        self.filter.read().unwrap().clone()
+    }
+
+    /// Returns the largest datagram that is sent to `addr` without being
+    /// fragmented, with the MTUs in effect now. Watch the interfaces with
+    /// `StackInterface::watch_link` to learn when it changes.
+    pub fn max_unfragmented<A: ToStackAddr>(&mut self, addr: A) -> io::Result<usize> {
+        let dst = try!(dns::resolve(&self.sender.stack, addr));
+        self.sender.max_unfragmented(*dst.ip()).map_err(|e| e.into())
+    }
+}
+
+impl HasSocketOptions for UdpSocket {
+    /// The options are shared with clones of this socket.
+    fn socket_options(&self) -> &RwLock<SocketOptions> {
+        &self.sender.options
+    }
+}
+
+impl Selectable for UdpSocket {
+    fn poll_readable(&self, waker: &Waker) -> Async<()> {
+        match self.rx {
+            Some(ref rx) => rx.poll_readable(waker),
+            None => Async::NotReady,
         }
     }
 }
@@ -1,5 +1,10 @@
 use {RxError, RxResult};
+use bpf::Program;
+use checksum::{self, RxChecksumControl};
 use ipv4::Ipv4Listener;
+use pool::{BufferPool, PooledBuffer};
+use reactor::{Async, Readiness, Waker, Wakers};
+use sockopt::SocketOptions;
 
 use pnet::packet::Packet;
 use pnet::packet::ipv4::Ipv4Packet;
@@ -8,25 +13,138 @@ use pnet::packet::udp::UdpPacket;
 use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, SocketAddrV4};
-use std::sync::{Arc, Mutex, mpsc};
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex, RwLock, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::SystemTime;
 
 pub trait UdpListener: Send {
     fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool);
 }
 
-pub type UdpListenerLookup = HashMap<u16, Box<UdpListener>>;
+/// The listeners of a `UdpRx` by port. Every listener has a lock of its own,
+/// so datagrams to different ports are handled in parallel.
+pub type UdpListenerLookup = HashMap<u16, Mutex<Box<UdpListener>>>;
+
+/// Sends the payload of every datagram it receives, with the time it was
+/// received and the address it came from, to a channel. Stops listening when
+/// the receiving end of the channel is dropped. Every payload is copied, see
+/// `UdpCallbackListener` for handling them where they were received.
+#[derive(Clone)]
+pub struct BasicUdpListener {
+    tx: mpsc::Sender<(SystemTime, SocketAddrV4, Box<[u8]>)>,
+}
+
+impl BasicUdpListener {
+    pub fn new(tx: mpsc::Sender<(SystemTime, SocketAddrV4, Box<[u8]>)>) -> BasicUdpListener {
+        BasicUdpListener { tx: tx }
+    }
+}
+
+impl UdpListener for BasicUdpListener {
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+        let udp_pkg = UdpPacket::new(packet.payload()).unwrap();
+        let src = SocketAddrV4::new(packet.get_source(), udp_pkg.get_source());
+        let payload = udp_pkg.payload().to_vec().into_boxed_slice();
+        match self.tx.send((time, src, payload)) {
+            Ok(()) => (Ok(()), true),
+            Err(_) => (Err(RxError::NoListener("Remote end closed".to_owned())), false),
+        }
+    }
+}
+
+/// A datagram handed to the closure of a `UdpCallbackListener`. The payload
+/// is borrowed from the frame the datagram arrived in.
+#[derive(Debug)]
+pub struct Datagram<'a> {
+    pub time: SystemTime,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Datagram<'a> {
+    /// Copies the datagram into a buffer from `pool`, for closures that have
+    /// to keep it after they returned.
+    pub fn to_pooled(&self, pool: &BufferPool) -> OwnedDatagram {
+        OwnedDatagram {
+            time: self.time,
+            src: self.src,
+            dst: self.dst,
+            payload: pool.copy_of(self.payload),
+        }
+    }
+}
+
+/// A `Datagram` owning a copy of its payload, see `Datagram::to_pooled`.
+pub struct OwnedDatagram {
+    pub time: SystemTime,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub payload: PooledBuffer,
+}
+
+/// Calls a closure with every datagram it receives, without copying it out
+/// of the received frame. The closure runs on the rx thread of the
+/// interface, so it should return quickly. Unlike `BasicUdpListener`
+/// nothing is allocated per datagram.
+pub struct UdpCallbackListener<F> {
+    callback: Arc<Mutex<F>>,
+}
+
+impl<F> UdpCallbackListener<F>
+    where F: FnMut(Datagram) + Send
+{
+    pub fn new(callback: F) -> UdpCallbackListener<F> {
+        UdpCallbackListener { callback: Arc::new(Mutex::new(callback)) }
+    }
+}
+
+impl<F> Clone for UdpCallbackListener<F> {
+    fn clone(&self) -> UdpCallbackListener<F> {
+        UdpCallbackListener { callback: self.callback.clone() }
+    }
+}
+
+impl<F> UdpListener for UdpCallbackListener<F>
+    where F: FnMut(Datagram) + Send
+{
+    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+        let udp_pkg = UdpPacket::new(packet.payload()).unwrap();
+        let datagram = Datagram {
+            time: time,
+            src: SocketAddrV4::new(packet.get_source(), udp_pkg.get_source()),
+            dst: SocketAddrV4::new(packet.get_destination(), udp_pkg.get_destination()),
+            payload: udp_pkg.payload(),
+        };
+        (&mut *self.callback.lock().unwrap())(datagram);
+        (Ok(()), true)
+    }
+}
 
 pub struct UdpRx {
-    listeners: Arc<Mutex<UdpListenerLookup>>,
+    listeners: Arc<RwLock<UdpListenerLookup>>,
+    checksums: Arc<RxChecksumControl>,
 }
 
 impl UdpRx {
-    pub fn new(listeners: Arc<Mutex<UdpListenerLookup>>) -> UdpRx {
-        UdpRx { listeners: listeners }
+    pub fn new(listeners: Arc<RwLock<UdpListenerLookup>>) -> UdpRx {
+        Self::with_checksums(listeners, Arc::new(RxChecksumControl::default()))
     }
 
-    fn get_port(pkg: &Ipv4Packet) -> Result<u16, RxError> {
+    /// Same as `new`, but only verifies the checksums of the datagrams
+    /// while `checksums` says so.
+    pub fn with_checksums(listeners: Arc<RwLock<UdpListenerLookup>>,
+                          checksums: Arc<RxChecksumControl>)
+                          -> UdpRx {
+        UdpRx {
+            listeners: listeners,
+            checksums: checksums,
+        }
+    }
+
+    fn get_port(&self, pkg: &Ipv4Packet) -> Result<u16, RxError> {
         let payload = pkg.payload();
         if payload.len() < UdpPacket::minimum_packet_size() {
             return Err(RxError::InvalidContent);
@@ -37,6 +155,11 @@ impl UdpRx {
         };
         if length > payload.len() || length < UdpPacket::minimum_packet_size() {
             Err(RxError::InvalidContent)
+        } else if self.checksums.udp() &&
+                  !checksum::is_valid_udp(&payload[..length],
+                                          pkg.get_source(),
+                                          pkg.get_destination()) {
+            Err(RxError::InvalidChecksum)
         } else {
             Ok(port)
         }
@@ -45,10 +168,10 @@ impl UdpRx {
 
 impl Ipv4Listener for UdpRx {
     fn recv(&mut self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
-        let port = try!(Self::get_port(&ip_pkg));
-        let mut listeners = self.listeners.lock().unwrap();
-        if let Some(listener) = listeners.get_mut(&port) {
-            let (result, _resume) = listener.recv(time, &ip_pkg);
+        let port = try!(self.get_port(&ip_pkg));
+        let listeners = self.listeners.read().unwrap();
+        if let Some(listener) = listeners.get(&port) {
+            let (result, _resume) = listener.lock().unwrap().recv(time, &ip_pkg);
             result
             // TODO: When resume turns false, remove this socket.
         } else {
@@ -60,12 +183,32 @@ impl Ipv4Listener for UdpRx {
 #[derive(Clone)]
 pub struct UdpSocketListener {
     chan: mpsc::Sender<(SystemTime, Box<[u8]>)>,
+    filter: Arc<RwLock<Option<Program>>>,
+    /// Woken up by every datagram, see `UdpSocketReader::poll_recv_from`
+    wakers: Arc<Wakers>,
+    readiness: Arc<Readiness>,
+    options: Arc<RwLock<SocketOptions>>,
+    /// Bytes of the packets waiting to be read, see `sockopt::RecvBuffer`
+    queued_bytes: Arc<AtomicUsize>,
 }
 
 impl UdpListener for UdpSocketListener {
     fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+        if let Some(ref filter) = *self.filter.read().unwrap() {
+            if !filter.matches(packet.packet()) {
+                return (Ok(()), true);
+            }
+        }
+        let len = packet.packet().len();
+        if let Some(recv_buffer) = self.options.read().unwrap().recv_buffer {
+            if self.queued_bytes.load(Ordering::SeqCst) + len > recv_buffer {
+                return (Ok(()), true);
+            }
+        }
+        self.queued_bytes.fetch_add(len, Ordering::SeqCst);
         let data = packet.packet().to_vec().into_boxed_slice();
-        let resume = self.chan.send((time, data)).is_ok();
+        let resume = self.readiness.send(&self.chan, (time, data));
+        self.wakers.wake_all();
         (Ok(()), resume)
     }
 }
@@ -76,17 +219,91 @@ pub struct UdpSocketReader {
 }
 
 impl UdpSocketReader {
-    pub fn new() -> UdpSocketReader {
+    /// Creates a reader receiving with the read timeout and receive buffer
+    /// in `options`.
+    pub fn new(options: Arc<RwLock<SocketOptions>>) -> UdpSocketReader {
         let (tx, rx) = mpsc::channel();
         UdpSocketReader {
             port: rx,
-            chan: UdpSocketListener { chan: tx },
+            chan: UdpSocketListener {
+                chan: tx,
+                filter: Arc::new(RwLock::new(None)),
+                wakers: Arc::new(Wakers::default()),
+                readiness: Arc::new(Readiness::default()),
+                options: options,
+                queued_bytes: Arc::new(AtomicUsize::new(0)),
+            },
         }
     }
 
+    /// The filter of the listeners of this reader.
+    pub fn filter(&self) -> Arc<RwLock<Option<Program>>> {
+        self.chan.filter.clone()
+    }
+
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        let (_time, data) = self.port.recv().unwrap();
-        let ipv4_pkg = Ipv4Packet::new(&data).unwrap();
+        let read_timeout = self.chan.options.read().unwrap().read_timeout;
+        let received = match read_timeout {
+            Some(timeout) => self.chan.readiness.recv_timeout(&self.port, timeout),
+            None => Some(self.chan.readiness.recv(&self.port).unwrap()),
+        };
+        match received {
+            Some((_time, data)) => self.read(&data, buf),
+            None => Err(io::Error::new(io::ErrorKind::TimedOut, "No datagram received")),
+        }
+    }
+
+    /// Same as `recv_from`, but fails with `io::ErrorKind::WouldBlock`
+    /// instead of blocking if no datagram was received yet.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.chan.readiness.try_recv(&self.port) {
+            Some((_time, data)) => self.read(&data, buf),
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "No datagram received")),
+        }
+    }
+
+    /// See `Readiness::fd`.
+    #[cfg(unix)]
+    pub fn readiness_fd(&self) -> io::Result<RawFd> {
+        self.chan.readiness.fd()
+    }
+
+    /// Same as `recv_from`, but returns `Async::NotReady` instead of blocking
+    /// if no datagram was received yet, and calls `waker` when one is.
+    pub fn poll_recv_from(&self,
+                          buf: &mut [u8],
+                          waker: &Waker)
+                          -> io::Result<Async<(usize, SocketAddr)>> {
+        if let Some((_time, data)) = self.chan.readiness.try_recv(&self.port) {
+            return self.read(&data, buf).map(Async::Ready);
+        }
+        self.chan.wakers.register(waker.clone());
+        // Check again, a datagram might have arrived before registering
+        match self.chan.readiness.try_recv(&self.port) {
+            Some((_time, data)) => self.read(&data, buf).map(Async::Ready),
+            None => Ok(Async::NotReady),
+        }
+    }
+
+    /// Returns `Async::Ready` if a datagram is waiting to be received, else
+    /// makes `waker` be called when one is. `waker` is only registered once
+    /// however often this is called.
+    pub fn poll_readable(&self, waker: &Waker) -> Async<()> {
+        if self.chan.readiness.queued() > 0 {
+            return Async::Ready(());
+        }
+        self.chan.wakers.register_unique(waker.clone());
+        if self.chan.readiness.queued() > 0 {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+
+    /// Copies the payload of the received packet `data` to `buf`.
+    fn read(&self, data: &[u8], buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.chan.queued_bytes.fetch_sub(data.len(), Ordering::SeqCst);
+        let ipv4_pkg = Ipv4Packet::new(data).unwrap();
         let ip = ipv4_pkg.get_source();
         let udp_pkg = UdpPacket::new(ipv4_pkg.payload()).unwrap();
         let port = udp_pkg.get_source();
@@ -1,16 +1,20 @@
 use {Payload, TxResult};
-use ipv4::{Ipv4Payload, Ipv4Tx};
+use corrupt::UdpCorruption;
+use ethernet::EthernetTx;
+use ipv4::{BasicIpv4Payload, Ipv4Tx, Ipv4TxImpl};
+use proto::udp::UdpBuilder;
 
-use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
-use pnet::packet::udp::{MutableUdpPacket, UdpPacket, ipv4_checksum_adv};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
 
-use std::cmp;
 use std::net::SocketAddrV4;
 
 pub struct UdpTx<T: Ipv4Tx> {
     src: u16,
     dst: u16,
     ipv4: T,
+    corruption: UdpCorruption,
 }
 
 impl<T: Ipv4Tx> UdpTx<T> {
@@ -19,125 +23,65 @@ impl<T: Ipv4Tx> UdpTx<T> {
             src: src,
             dst: dst,
             ipv4: ipv4,
+            corruption: UdpCorruption::default(),
         }
     }
 
-    pub fn send(&mut self, payload: &[u8]) -> TxResult {
-        let src = SocketAddrV4::new(self.ipv4.src(), self.src);
-        let dst = SocketAddrV4::new(self.ipv4.dst(), self.dst);
-        let builder = UdpBuilder::new(src, dst, payload);
-        self.ipv4.send(builder)
+    /// Makes the errors of `corruption` in the datagrams sent from now on,
+    /// see `corrupt`.
+    pub fn set_corruption(&mut self, corruption: UdpCorruption) {
+        self.corruption = corruption;
     }
-}
-
-pub struct UdpBuilder<'a> {
-    src: SocketAddrV4,
-    dst: SocketAddrV4,
-    header_sent: bool,
-    offset: usize,
-    payload: &'a [u8],
-}
 
-impl<'a> UdpBuilder<'a> {
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, payload: &'a [u8]) -> UdpBuilder<'a> {
-        UdpBuilder {
-            src: src,
-            dst: dst,
-            header_sent: false,
-            offset: 0,
-            payload: payload,
-        }
+    pub fn corruption(&self) -> UdpCorruption {
+        self.corruption
     }
-}
 
-impl<'a> Ipv4Payload for UdpBuilder<'a> {
-    fn next_level_protocol(&self) -> IpNextHeaderProtocol {
-        IpNextHeaderProtocols::Udp
+    pub fn send(&mut self, payload: &[u8]) -> TxResult {
+        let dst = self.dst;
+        self.send_to_port(payload, dst)
     }
-}
 
-impl<'a> Payload for UdpBuilder<'a> {
-    fn len(&self) -> usize {
-        UdpPacket::minimum_packet_size() + self.payload.len()
+    /// Same as `send`, but to the port `dst_port` of the same host.
+    pub fn send_to_port(&mut self, payload: &[u8], dst_port: u16) -> TxResult {
+        self.send_vectored_to_port(&[payload], dst_port)
     }
 
-    fn build(&mut self, buffer: &mut [u8]) {
-        let payload_buffer = if !self.header_sent {
-            self.header_sent = true;
-            {
-                let header_buffer = &mut buffer[..UdpPacket::minimum_packet_size()];
-                let mut pkg = MutableUdpPacket::new(header_buffer).unwrap();
-                pkg.set_source(self.src.port());
-                pkg.set_destination(self.dst.port());
-                pkg.set_length(self.len() as u16);
-                let checksum = ipv4_checksum_adv(&pkg.to_immutable(),
-                                                 self.payload,
-                                                 *self.src.ip(),
-                                                 *self.dst.ip());
-                pkg.set_checksum(checksum);
-            }
-            &mut buffer[UdpPacket::minimum_packet_size()..]
-        } else {
-            buffer
-        };
-        let start = self.offset;
-        let len = cmp::min(payload_buffer.len(), self.payload.len() - start);
-        let end = start + len;
-        payload_buffer[..len].copy_from_slice(&self.payload[start..end]);
-        self.offset = end;
+    /// Same as `send`, with the payload made of all of `parts`, one after
+    /// the other.
+    pub fn send_vectored(&mut self, parts: &[&[u8]]) -> TxResult {
+        let dst = self.dst;
+        self.send_vectored_to_port(parts, dst)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use Payload;
-    use pnet::packet::Packet;
-    use pnet::packet::udp::UdpPacket;
-    use std::net::{Ipv4Addr, SocketAddrV4};
-    use super::*;
 
-    lazy_static! {
-        static ref ADDR1: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 99, 250, 15), 8080);
-        static ref ADDR2: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 105), 22);
+    /// Same as `send_vectored`, but to the port `dst_port` of the same host.
+    pub fn send_vectored_to_port(&mut self, parts: &[&[u8]], dst_port: u16) -> TxResult {
+        let src = SocketAddrV4::new(self.ipv4.src(), self.src);
+        let dst = SocketAddrV4::new(self.ipv4.dst(), dst_port);
+        let mut builder = UdpBuilder::vectored(src, dst, parts);
+        if self.corruption == UdpCorruption::default() {
+            return self.ipv4.send(builder);
+        }
+        let mut datagram = vec![0; builder.len()];
+        builder.build(&mut datagram);
+        self.corruption.apply(*src.ip(), *dst.ip(), &mut datagram);
+        self.ipv4.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &datagram))
     }
+}
 
-    #[test]
-    #[should_panic]
-    fn udp_builder_too_short() {
-        let mut buffer = vec![0; 7];
-        let mut builder = UdpBuilder::new(*ADDR1, *ADDR2, &[]);
-        builder.build(&mut buffer);
+impl<T: EthernetTx> UdpTx<Ipv4TxImpl<T>> {
+    /// Returns the largest payload that is sent without being fragmented.
+    pub fn max_unfragmented(&self) -> usize {
+        self.ipv4.mtu() - Ipv4Packet::minimum_packet_size() - UdpPacket::minimum_packet_size()
     }
 
-    #[test]
-    fn udp_builder_header() {
-        let mut buffer = vec![0; 1000];
-        let data = &[3, 2];
-        let mut builder = UdpBuilder::new(*ADDR1, *ADDR2, data);
-        builder.build(&mut buffer);
-
-        let pkg = UdpPacket::new(&buffer).unwrap();
-        assert_eq!(ADDR1.port(), pkg.get_source());
-        assert_eq!(ADDR2.port(), pkg.get_destination());
-        assert_eq!(10, pkg.get_length());
-        assert_eq!(5806, pkg.get_checksum());
-        assert_eq!([3, 2], pkg.payload()[0..2]);
+    /// See `Ipv4TxImpl::set_ttl`.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ipv4.set_ttl(ttl);
     }
 
-    #[test]
-    fn udp_builder_two_build_calls() {
-        let mut buffer = vec![0; 8];
-        let data = &[11, 12, 13, 14, 15, 16, 17, 18, 19];
-        let mut builder = UdpBuilder::new(*ADDR1, *ADDR2, data);
-        // Build header, but we don't test that here
-        builder.build(&mut buffer);
-
-        // Build first 8 bytes of payload
-        builder.build(&mut buffer);
-        assert_eq!([11, 12, 13, 14, 15, 16, 17, 18], buffer[..]);
-
-        // Build last payload byte
-        builder.build(&mut buffer);
-        assert_eq!([19], buffer[..1]);
+    /// See `Ipv4TxImpl::set_tos`.
+    pub fn set_tos(&mut self, tos: u8) {
+        self.ipv4.set_tos(tos);
     }
 }
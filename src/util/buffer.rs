@@ -2,15 +2,19 @@ use std::ops::{Deref, DerefMut};
 
 /// Structure used to reassemble data arriving in fragments.
 /// Supposed to handle out of order arrival, but does not at the moment.
+/// Memory is only allocated for data that has actually been pushed.
 pub struct Buffer {
     data: Vec<u8>,
+    capacity: usize,
     lowest_missing: usize,
 }
 
 impl Buffer {
+    /// Creates a `Buffer` able to hold at most `capacity` bytes.
     pub fn new(capacity: usize) -> Buffer {
         Buffer {
-            data: vec![0; capacity],
+            data: Vec::new(),
+            capacity: capacity,
             lowest_missing: 0,
         }
     }
@@ -18,19 +22,19 @@ impl Buffer {
     /// Push new data to this `Buffer`. Returns the lowest index of missing
     /// data on success.
     /// This is equivalent to the length of the valid data at the start of the
-    /// buffer. Will fail if the given data offset is not valid.
+    /// buffer. Will fail if the given data offset is not valid or the data
+    /// does not fit in the capacity.
     // TODO: Support out of order data
     pub fn push(&mut self, offset: usize, data: &[u8]) -> Result<usize, ()> {
-        if offset == self.lowest_missing {
-            self.lowest_missing += data.len();
-        } else {
+        if offset != self.lowest_missing || offset + data.len() > self.capacity {
             return Err(());
         }
-        self.data[offset..offset + data.len()].copy_from_slice(data);
+        self.data.extend_from_slice(data);
+        self.lowest_missing += data.len();
         Ok(self.lowest_missing)
     }
 
-    /// Consumes the `Buffer` and returns the data in an owned slice
+    /// Consumes the `Buffer` and returns the valid data in an owned `Vec`
     pub fn into_vec(self) -> Vec<u8> {
         self.data
     }
@@ -53,5 +57,18 @@ impl DerefMut for Buffer {
 
 #[cfg(test)]
 mod tests {
-    // TODO: Write unit tests
+    use super::*;
+
+    #[test]
+    fn push_in_order() {
+        let mut testee = Buffer::new(6);
+        assert_eq!(testee.push(0, &[1, 2]), Ok(2));
+        assert_eq!(testee.push(2, &[3, 4]), Ok(4));
+        assert_eq!(&testee[..], &[1, 2, 3, 4]);
+        // Overlapping, gap and too large data
+        assert!(testee.push(3, &[9]).is_err());
+        assert!(testee.push(5, &[9]).is_err());
+        assert!(testee.push(4, &[5, 6, 7]).is_err());
+        assert_eq!(testee.into_vec(), vec![1, 2, 3, 4]);
+    }
 }
@@ -1,5 +1,4 @@
-use std::io;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::any::Any;
 
 // mod cachemap;
 // pub use util::cachemap::CacheMap;
@@ -8,11 +7,13 @@ mod buffer;
 
 pub use util::buffer::Buffer;
 
-pub fn first_socket_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
-    if let Some(addr) = try!(addr.to_socket_addrs()).next() {
-        Ok(addr)
+/// Returns the message of a panic caught with `std::panic::catch_unwind`.
+pub fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
     } else {
-        Err(io::Error::new(io::ErrorKind::InvalidInput,
-                           "Given ToSocketAddrs did not yield any address".to_owned()))
+        "Unknown panic".to_owned()
     }
 }
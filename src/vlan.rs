@@ -0,0 +1,186 @@
+//! IEEE 802.1Q VLAN sub-interfaces.
+//!
+//! A VLAN is added to a `NetworkStack` with `NetworkStack::add_vlan` as an
+//! interface of its own on top of a physical one, so it gets its own
+//! addresses and routes. Frames sent on it are tagged with its VLAN
+//! identifier (VID) and sent out on the parent interface. Tagged frames
+//! received on the parent are untagged and handed to the VLAN with their VID.
+
+use {BasicPayload, RxError, RxResult, Tx};
+use ethernet::EthernetListener;
+use stack::TxBarrier;
+
+use pnet::datalink::{EthernetDataLinkSender, NetworkInterface};
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::time::SystemTime;
+
+/// Length of the 802.1Q tag.
+pub const TAG_LEN: usize = 4;
+
+/// Largest valid VID. 0 and 4095 are reserved.
+pub const MAX_VID: u16 = 4094;
+
+/// Where the untagged frames of each VID on an interface are sent.
+pub type VlanTable = HashMap<u16, Sender<Box<[u8]>>>;
+
+/// Returns `frame` with an 802.1Q tag for `vid` and the priority code point
+/// `pcp` inserted after the MAC addresses.
+pub fn tag(frame: &[u8], vid: u16, pcp: u8) -> Vec<u8> {
+    let tci = ((pcp as u16 & 0x7) << 13) | (vid & 0xfff);
+    let mut tagged = Vec::with_capacity(frame.len() + TAG_LEN);
+    tagged.extend_from_slice(&frame[..12]);
+    tagged.extend_from_slice(&[0x81, 0x00, (tci >> 8) as u8, tci as u8]);
+    tagged.extend_from_slice(&frame[12..]);
+    tagged
+}
+
+/// Parses the 802.1Q tag of `frame`. Returns the VID and the frame without
+/// the tag, or `None` if `frame` is not tagged.
+pub fn untag(frame: &[u8]) -> Option<(u16, Vec<u8>)> {
+    if frame.len() < EthernetPacket::minimum_packet_size() + TAG_LEN ||
+       frame[12..14] != [0x81, 0x00] {
+        return None;
+    }
+    let vid = ((frame[14] as u16) << 8 | frame[15] as u16) & 0xfff;
+    let mut untagged = Vec::with_capacity(frame.len() - TAG_LEN);
+    untagged.extend_from_slice(&frame[..12]);
+    untagged.extend_from_slice(&frame[12 + TAG_LEN..]);
+    Some((vid, untagged))
+}
+
+/// Listens for tagged frames on a parent interface and hands them to the
+/// VLAN sub-interfaces in its `VlanTable`.
+pub struct VlanRx {
+    vlans: Arc<Mutex<VlanTable>>,
+}
+
+impl VlanRx {
+    pub fn new(vlans: Arc<Mutex<VlanTable>>) -> VlanRx {
+        VlanRx { vlans: vlans }
+    }
+}
+
+impl EthernetListener for VlanRx {
+    fn recv(&mut self, _time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        let (vid, frame) = match untag(packet.packet()) {
+            Some(untagged) => untagged,
+            None => return Err(RxError::InvalidLength),
+        };
+        let mut vlans = self.vlans.lock().unwrap();
+        let delivered = match vlans.get(&vid) {
+            Some(inject) => inject.send(frame.into_boxed_slice()).is_ok(),
+            None => false,
+        };
+        if delivered {
+            Ok(())
+        } else {
+            vlans.remove(&vid);
+            Err(RxError::NoListener(format!("Vlan: No interface for VID {}", vid)))
+        }
+    }
+
+    fn ether_type(&self) -> EtherType {
+        EtherTypes::Vlan
+    }
+}
+
+/// Sending half of the channel of a VLAN sub-interface, tagging the frames
+/// and sending them on the parent interface.
+pub struct VlanSender {
+    vid: u16,
+    tx: Arc<Mutex<TxBarrier>>,
+}
+
+impl VlanSender {
+    pub fn new(vid: u16, tx: Arc<Mutex<TxBarrier>>) -> VlanSender {
+        VlanSender { vid: vid, tx: tx }
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let tagged = tag(frame, self.vid, 0);
+        let payload = BasicPayload::new(&tagged);
+        self.tx.lock().unwrap().send(1, tagged.len(), payload).map(|_| ()).map_err(io::Error::from)
+    }
+}
+
+impl EthernetDataLinkSender for VlanSender {
+    fn build_and_send(&mut self,
+                      num_packets: usize,
+                      packet_size: usize,
+                      func: &mut FnMut(MutableEthernetPacket))
+                      -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut buffer = vec![0; packet_size];
+            match MutableEthernetPacket::new(&mut buffer[..]) {
+                Some(packet) => func(packet),
+                None => return None,
+            }
+            if let Err(e) = self.send_frame(&buffer) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self,
+               packet: &EthernetPacket,
+               _dst: Option<NetworkInterface>)
+               -> Option<io::Result<()>> {
+        Some(self.send_frame(packet.packet()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethernet::EthernetListener;
+
+    use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[test]
+    fn tag_and_untag() {
+        let frame = (0..20).collect::<Vec<u8>>();
+        let tagged = tag(&frame, 0x123, 5);
+        assert_eq!(tagged.len(), 24);
+        assert_eq!(&tagged[12..16], &[0x81, 0x00, 0xa1, 0x23]);
+        assert_eq!(&tagged[16..], &frame[12..]);
+        assert_eq!(untag(&tagged), Some((0x123, frame.clone())));
+        assert_eq!(untag(&frame), None);
+    }
+
+    #[test]
+    fn demux_by_vid() {
+        let (inject, frames) = mpsc::channel();
+        let mut vlans = HashMap::new();
+        vlans.insert(10, inject);
+        let vlans = Arc::new(Mutex::new(vlans));
+        let mut testee = VlanRx::new(vlans.clone());
+
+        let frame = vec![0; 60];
+        let tagged = tag(&frame, 10, 0);
+        testee.recv(SystemTime::now(), &EthernetPacket::new(&tagged).unwrap()).unwrap();
+        assert_eq!(&frames.try_recv().unwrap()[..], &frame[..]);
+
+        let tagged = tag(&frame, 11, 0);
+        assert!(testee.recv(SystemTime::now(), &EthernetPacket::new(&tagged).unwrap()).is_err());
+
+        // Dropping the receiving end removes the VLAN
+        drop(frames);
+        let tagged = tag(&frame, 10, 0);
+        assert!(testee.recv(SystemTime::now(), &EthernetPacket::new(&tagged).unwrap()).is_err());
+        assert!(vlans.lock().unwrap().is_empty());
+        assert_eq!(testee.ether_type(), EtherTypes::Vlan);
+    }
+}
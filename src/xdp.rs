@@ -0,0 +1,683 @@
+//! AF_XDP sockets as links of a stack, for high packet rates.
+//!
+//! pnet hands every frame to the kernel with a system call of its own, which
+//! caps the rate a stack can send and receive at well below what the
+//! hardware does. An AF_XDP socket instead shares a region of memory, the
+//! UMEM, and four rings of descriptors with the kernel. Frames are received
+//! and sent by moving descriptors of UMEM frames through the rings, and the
+//! kernel is only woken once for a whole batch of frames.
+//!
+//! ```rust,ignore
+//! let xdp = XdpSocket::open("eth0", XdpConfig::default())?;
+//! let interface = stack.add_datalink("eth0", Arc::new(xdp))?;
+//! ```
+//!
+//! The UMEM is split in two. Half of its frames are lent to the kernel
+//! through the fill ring to receive into, and come back full on the rx ring.
+//! The other half are filled with frames to send and put on the tx ring,
+//! and come back empty on the completion ring. Received frames are copied
+//! out of the UMEM, as `Datalink` hands out frames of their own, while
+//! frames to send are built right in it with `Datalink::build_and_send`.
+//!
+//! The socket only receives frames an XDP program on its interface
+//! redirects to it, through an `XSKMAP` holding `XdpSocket::fd` at the index
+//! of `XdpConfig::queue`. Loading that program, with `libbpf` or `ip link
+//! set dev eth0 xdp obj ...`, is up to the application. Sending needs no
+//! program. Opening needs `CAP_NET_RAW` and Linux 5.4 or later, on 64 bit.
+
+use {Interface, StackError, StackResult};
+use datalink::{self, Datalink};
+use ipv4;
+use rx;
+use tun;
+
+use libc;
+
+use pnet::util::MacAddr;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+use std::sync::atomic::{self, Ordering};
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+const MSG_DONTWAIT: libc::c_int = 0x40;
+
+/// Room for an Ethernet header with a VLAN tag in a UMEM frame
+const FRAME_OVERHEAD: usize = 18;
+
+/// How the kernel moves frames between the UMEM and the interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XdpMode {
+    /// Zero copy if the driver supports it, copying otherwise
+    Auto,
+    /// Copying, works with every interface
+    Copy,
+    /// The driver works on the UMEM directly, fails if it does not support
+    /// that
+    ZeroCopy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XdpConfig {
+    /// The queue of the interface the socket is bound to
+    pub queue: u32,
+    /// Frames in the UMEM, an even number, half of them for receiving
+    pub frames: u32,
+    /// Bytes per UMEM frame, a power of two from 2048 up to the page size
+    pub frame_size: u32,
+    /// Descriptors in each of the rings, a power of two of at least half of
+    /// `frames`
+    pub ring_size: u32,
+    /// Frames taken from the rx ring at a time at most
+    pub batch_size: u32,
+    pub mode: XdpMode,
+}
+
+impl Default for XdpConfig {
+    fn default() -> XdpConfig {
+        XdpConfig {
+            queue: 0,
+            frames: 4096,
+            frame_size: 2048,
+            ring_size: 2048,
+            batch_size: 64,
+            mode: XdpMode::Auto,
+        }
+    }
+}
+
+impl XdpConfig {
+    fn is_valid(&self) -> bool {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        self.frames > 0 && self.frames % 2 == 0 && self.ring_size.is_power_of_two() &&
+        self.ring_size >= self.frames / 2 && self.frame_size.is_power_of_two() &&
+        self.frame_size >= 2048 && self.frame_size <= page_size && self.batch_size > 0
+    }
+}
+
+/// `struct xdp_umem_reg` without the flags added in Linux 5.4.
+#[repr(C)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fill: RingOffset,
+    completion: RingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+/// `struct xdp_desc`, the entries of the rx and tx rings.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// A single producer, single consumer ring shared with the kernel. Only one
+/// side is ever used from here, the kernel is on the other one.
+struct Ring {
+    producer: *mut u32,
+    consumer: *mut u32,
+    entries: *mut u8,
+    size: u32,
+    /// The mapping of the ring, if it was mapped from the socket
+    map: Option<(*mut libc::c_void, usize)>,
+}
+
+impl Ring {
+    /// Maps the ring at `pgoff` of the socket `fd`, with `size` entries of
+    /// `T` at the offsets `offset`.
+    fn map<T>(fd: RawFd, pgoff: libc::off_t, offset: &RingOffset, size: u32) -> io::Result<Ring> {
+        let len = offset.desc as usize + size as usize * mem::size_of::<T>();
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE,
+                       fd,
+                       pgoff)
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let base = map as *mut u8;
+        // The offsets the kernel reported lie within the mapping of len
+        unsafe {
+            Ok(Ring {
+                producer: base.offset(offset.producer as isize) as *mut u32,
+                consumer: base.offset(offset.consumer as isize) as *mut u32,
+                entries: base.offset(offset.desc as isize),
+                size: size,
+                map: Some((map, len)),
+            })
+        }
+    }
+
+    /// Entries a producer may still fill.
+    fn free(&self) -> u32 {
+        let producer = unsafe { ptr::read_volatile(self.producer) };
+        let consumer = unsafe { ptr::read_volatile(self.consumer) };
+        atomic::fence(Ordering::Acquire);
+        self.size - producer.wrapping_sub(consumer)
+    }
+
+    /// Entries a consumer may take.
+    fn available(&self) -> u32 {
+        let producer = unsafe { ptr::read_volatile(self.producer) };
+        let consumer = unsafe { ptr::read_volatile(self.consumer) };
+        atomic::fence(Ordering::Acquire);
+        producer.wrapping_sub(consumer)
+    }
+
+    /// Writes `value` to the `n`th entry after the producer index, which has
+    /// to be below `free`.
+    fn put<T>(&self, n: u32, value: T) {
+        let index = unsafe { ptr::read_volatile(self.producer) }.wrapping_add(n);
+        unsafe { ptr::write(self.entry::<T>(index), value) }
+    }
+
+    /// Reads the `n`th entry after the consumer index, which has to be below
+    /// `available`.
+    fn get<T: Copy>(&self, n: u32) -> T {
+        let index = unsafe { ptr::read_volatile(self.consumer) }.wrapping_add(n);
+        unsafe { ptr::read(self.entry::<T>(index)) }
+    }
+
+    /// Hands the `n` entries written with `put` to the consumer.
+    fn produce(&self, n: u32) {
+        atomic::fence(Ordering::Release);
+        unsafe {
+            let producer = ptr::read_volatile(self.producer);
+            ptr::write_volatile(self.producer, producer.wrapping_add(n));
+        }
+    }
+
+    /// Hands the `n` entries read with `get` back to the producer.
+    fn consume(&self, n: u32) {
+        atomic::fence(Ordering::Release);
+        unsafe {
+            let consumer = ptr::read_volatile(self.consumer);
+            ptr::write_volatile(self.consumer, consumer.wrapping_add(n));
+        }
+    }
+
+    unsafe fn entry<T>(&self, index: u32) -> *mut T {
+        (self.entries as *mut T).offset((index & (self.size - 1)) as isize)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        if let Some((map, len)) = self.map {
+            unsafe { libc::munmap(map, len) };
+        }
+    }
+}
+
+/// The memory holding the frames, registered with the socket.
+struct Umem {
+    area: *mut u8,
+    len: usize,
+}
+
+impl Umem {
+    fn new(len: usize) -> io::Result<Umem> {
+        let area = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                       -1,
+                       0)
+        };
+        if area == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Umem {
+            area: area as *mut u8,
+            len: len,
+        })
+    }
+
+    /// Returns the `len` bytes at `addr`, if they are within the UMEM.
+    ///
+    /// Callers make sure no frame is written while its content is read, by
+    /// only accessing the frames the rings say they own.
+    unsafe fn frame(&self, addr: u64, len: usize) -> Option<&mut [u8]> {
+        if addr as usize + len > self.len {
+            return None;
+        }
+        Some(slice::from_raw_parts_mut(self.area.offset(addr as isize), len))
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.area as *mut libc::c_void, self.len) };
+    }
+}
+
+/// Closes the socket when dropped.
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+struct RxState {
+    rx: Ring,
+    fill: Ring,
+    /// Frames taken from the rx ring and not handed out yet
+    pending: VecDeque<Box<[u8]>>,
+}
+
+struct TxState {
+    tx: Ring,
+    completion: Ring,
+    /// Addresses of the frames neither the kernel nor the tx ring holds
+    free: Vec<u64>,
+}
+
+/// An AF_XDP socket on one queue of an interface. See the module
+/// documentation.
+pub struct XdpSocket {
+    rx: Mutex<RxState>,
+    tx: Mutex<TxState>,
+    umem: Umem,
+    fd: Fd,
+    mac: MacAddr,
+    mtu: Option<usize>,
+    config: XdpConfig,
+}
+
+// The rings and the UMEM are only mapped by the socket owning them. Each
+// ring is only used under the lock of its side, and every UMEM frame is
+// owned by one side at a time, passed on through the rings.
+unsafe impl Send for XdpSocket {}
+unsafe impl Sync for XdpSocket {}
+
+impl XdpSocket {
+    /// Opens an AF_XDP socket on the OS interface `name`. Fails with
+    /// `StackError::NoSuchInterface` if there is no interface `name` with a
+    /// MAC address, and `StackError::InvalidArgument` if `config` is invalid.
+    pub fn open(name: &str, config: XdpConfig) -> StackResult<XdpSocket> {
+        if !config.is_valid() {
+            return Err(StackError::InvalidArgument(format!("Invalid {:?}", config)));
+        }
+        let os_interface = ::pnet::datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == name)
+            .ok_or_else(|| StackError::NoSuchInterface(name.to_owned()))?;
+        let Interface { mac, .. } = ::convert_interface(&os_interface)
+            .map_err(|_| StackError::NoSuchInterface(name.to_owned()))?;
+
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fd = Fd(fd);
+        let umem = Umem::new(config.frames as usize * config.frame_size as usize)?;
+        let umem_reg = UmemReg {
+            addr: umem.area as u64,
+            len: umem.len as u64,
+            chunk_size: config.frame_size,
+            headroom: 0,
+        };
+        setsockopt(&fd, XDP_UMEM_REG, &umem_reg)?;
+        for &ring in &[XDP_UMEM_FILL_RING, XDP_UMEM_COMPLETION_RING, XDP_RX_RING, XDP_TX_RING] {
+            setsockopt(&fd, ring, &config.ring_size)?;
+        }
+        let mut offsets = MmapOffsets::default();
+        let mut len = mem::size_of::<MmapOffsets>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(fd.0,
+                             SOL_XDP,
+                             XDP_MMAP_OFFSETS,
+                             &mut offsets as *mut MmapOffsets as *mut libc::c_void,
+                             &mut len)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if len as usize != mem::size_of::<MmapOffsets>() {
+            let msg = "AF_XDP ring layout of kernels before 5.4";
+            return Err(io::Error::new(io::ErrorKind::Other, msg).into());
+        }
+
+        let size = config.ring_size;
+        let rx = RxState {
+            rx: Ring::map::<XdpDesc>(fd.0, XDP_PGOFF_RX_RING, &offsets.rx, size)?,
+            fill: Ring::map::<u64>(fd.0, XDP_UMEM_PGOFF_FILL_RING, &offsets.fill, size)?,
+            pending: VecDeque::new(),
+        };
+        let tx = TxState {
+            tx: Ring::map::<XdpDesc>(fd.0, XDP_PGOFF_TX_RING, &offsets.tx, size)?,
+            completion: Ring::map::<u64>(fd.0,
+                                         XDP_UMEM_PGOFF_COMPLETION_RING,
+                                         &offsets.completion,
+                                         size)?,
+            free: (config.frames / 2..config.frames)
+                .map(|frame| frame as u64 * config.frame_size as u64)
+                .collect(),
+        };
+        for frame in 0..config.frames / 2 {
+            rx.fill.put(frame, frame as u64 * config.frame_size as u64);
+        }
+        rx.fill.produce(config.frames / 2);
+
+        let addr = SockaddrXdp {
+            family: AF_XDP as u16,
+            flags: match config.mode {
+                XdpMode::Auto => 0,
+                XdpMode::Copy => XDP_COPY,
+                XdpMode::ZeroCopy => XDP_ZEROCOPY,
+            },
+            ifindex: os_interface.index,
+            queue_id: config.queue,
+            shared_umem_fd: 0,
+        };
+        let result = unsafe {
+            libc::bind(fd.0,
+                       &addr as *const SockaddrXdp as *const libc::sockaddr,
+                       mem::size_of::<SockaddrXdp>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let max_mtu = config.frame_size as usize - FRAME_OVERHEAD;
+        let mtu = datalink::os_mtu(name).map(|mtu| cmp::min(mtu, max_mtu));
+        Ok(XdpSocket {
+            rx: Mutex::new(rx),
+            tx: Mutex::new(tx),
+            umem: umem,
+            fd: fd,
+            mac: mac,
+            mtu: mtu.and_then(|mtu| if mtu >= ipv4::MIN_MTU { Some(mtu) } else { None }),
+            config: config,
+        })
+    }
+
+    /// The file descriptor of the socket, for the `XSKMAP` of the XDP
+    /// program redirecting frames to it.
+    pub fn fd(&self) -> RawFd {
+        self.fd.0
+    }
+
+    /// Copies up to `batch_size` frames off the rx ring, giving their UMEM
+    /// frames back to the kernel.
+    fn take_received(&self, rx: &mut RxState) {
+        let n = cmp::min(rx.rx.available(), self.config.batch_size);
+        let frame_mask = !(self.config.frame_size as u64 - 1);
+        for i in 0..n {
+            let desc: XdpDesc = rx.rx.get(i);
+            if let Some(frame) = unsafe { self.umem.frame(desc.addr, desc.len as usize) } {
+                rx.pending.push_back(frame.to_vec().into_boxed_slice());
+            }
+            // There is room, the fill ring holds all receiving frames
+            rx.fill.put(i, desc.addr & frame_mask);
+        }
+        rx.rx.consume(n);
+        rx.fill.produce(n);
+    }
+
+    /// Takes the frames the kernel is done sending off the completion ring.
+    fn reclaim(&self, tx: &mut TxState) {
+        let n = tx.completion.available();
+        for i in 0..n {
+            let addr = tx.completion.get(i);
+            tx.free.push(addr);
+        }
+        tx.completion.consume(n);
+    }
+
+    /// Fills `num_frames` UMEM frames, the `i`th one of `len(i)` bytes with
+    /// `fill(i, frame)`, puts them on the tx ring and wakes the kernel.
+    fn queue_frames<L, F>(&self, num_frames: usize, len: L, mut fill: F) -> io::Result<()>
+        where L: Fn(usize) -> usize,
+              F: FnMut(usize, &mut [u8])
+    {
+        let mut tx = self.tx.lock().unwrap();
+        self.reclaim(&mut tx);
+        let mut queued = 0;
+        for i in 0..num_frames {
+            let len = len(i);
+            if len > self.config.frame_size as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Frame larger than UMEM frames"));
+            }
+            if tx.free.is_empty() || queued == tx.tx.free() {
+                tx.tx.produce(queued);
+                queued = 0;
+                self.kick()?;
+                self.reclaim(&mut tx);
+                if tx.free.is_empty() || tx.tx.free() == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "Tx ring full"));
+                }
+            }
+            let addr = tx.free.pop().unwrap();
+            fill(i, unsafe { self.umem.frame(addr, len) }.unwrap());
+            tx.tx.put(queued,
+                      XdpDesc {
+                          addr: addr,
+                          len: len as u32,
+                          options: 0,
+                      });
+            queued += 1;
+        }
+        tx.tx.produce(queued);
+        self.kick()
+    }
+
+    /// Wakes the kernel to send what is on the tx ring.
+    fn kick(&self) -> io::Result<()> {
+        let result =
+            unsafe { libc::sendto(self.fd.0, ptr::null(), 0, MSG_DONTWAIT, ptr::null(), 0) };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EBUSY) | Some(libc::ENOBUFS) => (),
+                _ => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Datalink for XdpSocket {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.send_batch(&[frame])
+    }
+
+    /// Puts all of `frames` on the tx ring and then wakes the kernel once.
+    /// Fails with `io::ErrorKind::WouldBlock` if the kernel does not free up
+    /// UMEM frames as fast as they are sent.
+    fn send_batch(&self, frames: &[&[u8]]) -> io::Result<()> {
+        self.queue_frames(frames.len(),
+                          |i| frames[i].len(),
+                          |i, buffer| buffer.copy_from_slice(frames[i]))
+    }
+
+    /// Builds the frames in UMEM frames directly, without copying them.
+    fn build_and_send(&self,
+                      num_frames: usize,
+                      frame_size: usize,
+                      build: &mut FnMut(&mut [u8]))
+                      -> Option<io::Result<()>> {
+        Some(self.queue_frames(num_frames, |_| frame_size, |_, buffer| build(buffer)))
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        let mut rx = self.rx.lock().unwrap();
+        if rx.pending.is_empty() {
+            if rx.rx.available() == 0 {
+                tun::poll_readable(&self.fd, rx::POLL_INTERVAL_MS)?;
+            }
+            self.take_received(&mut rx);
+        }
+        rx.pending
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "No frame received"))
+    }
+}
+
+fn setsockopt<T>(fd: &Fd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(fd.0,
+                         SOL_XDP,
+                         name,
+                         value as *const T as *const libc::c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use StackError;
+    use datalink::Datalink;
+
+    use std::io;
+
+    use super::*;
+
+    /// A ring of four entries over the memory `memory`, which holds the
+    /// producer index, the consumer index and the entries.
+    fn ring(memory: &mut [u64; 6]) -> Ring {
+        let base = memory.as_mut_ptr();
+        Ring {
+            producer: base as *mut u32,
+            consumer: unsafe { base.offset(1) } as *mut u32,
+            entries: unsafe { base.offset(2) } as *mut u8,
+            size: 4,
+            map: None,
+        }
+    }
+
+    #[test]
+    fn ring_wraps() {
+        let mut memory = [0; 6];
+        let ring = ring(&mut memory);
+        assert_eq!((ring.free(), ring.available()), (4, 0));
+        for round in 0..3 {
+            for i in 0..3 {
+                ring.put(i, round * 10 + i as u64);
+            }
+            ring.produce(3);
+            assert_eq!((ring.free(), ring.available()), (1, 3));
+            assert_eq!((0..3).map(|i| ring.get(i)).collect::<Vec<u64>>(),
+                       vec![round * 10, round * 10 + 1, round * 10 + 2]);
+            ring.consume(3);
+            assert_eq!(ring.available(), 0);
+        }
+    }
+
+    #[test]
+    fn invalid_config() {
+        let invalid = |config: XdpConfig| match XdpSocket::open("lo", config) {
+            Err(StackError::InvalidArgument(..)) => (),
+            _ => panic!("Expected InvalidArgument for {:?}", config),
+        };
+        invalid(XdpConfig { frames: 7, ..XdpConfig::default() });
+        invalid(XdpConfig { frame_size: 3000, ..XdpConfig::default() });
+        invalid(XdpConfig { frame_size: 1024, ..XdpConfig::default() });
+        invalid(XdpConfig { ring_size: 1000, ..XdpConfig::default() });
+        invalid(XdpConfig { ring_size: 1024, ..XdpConfig::default() });
+        invalid(XdpConfig { batch_size: 0, ..XdpConfig::default() });
+    }
+
+    #[test]
+    fn send_on_loopback() {
+        let config = XdpConfig {
+            frames: 16,
+            ring_size: 8,
+            mode: XdpMode::Copy,
+            ..XdpConfig::default()
+        };
+        let xdp = match XdpSocket::open("lo", config) {
+            Ok(xdp) => xdp,
+            // Without AF_XDP support or CAP_NET_RAW there is nothing to test
+            Err(StackError::IoError(_)) |
+            Err(StackError::NoSuchInterface(..)) => return,
+            Err(e) => panic!("Unexpected error {:?}", e),
+        };
+        // More frames than the UMEM has for sending, reusing completed ones
+        let frame: &[u8] = &[0; 60];
+        for _ in 0..4 {
+            xdp.send_batch(&[frame; 6]).unwrap();
+        }
+        xdp.build_and_send(12, 60, &mut |buffer| buffer[0] = 1).unwrap().unwrap();
+        assert_eq!(xdp.send(&[0; 4096]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        // No XDP program redirects anything to the socket
+        assert_eq!(xdp.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -10,10 +10,11 @@ use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
 use pnet::util::MacAddr;
 
 use rips::testing;
+use rips::link_local::{self, LinkLocalConfig};
 
 use std::io;
 use std::net::Ipv4Addr;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
 use std::time::Duration;
 
@@ -101,7 +102,49 @@ fn arp_locking() {
     assert!(arp_thread_rx.try_recv().is_err());
 }
 
+#[test]
+fn link_local_conflict() {
+    let (stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let stack = Arc::new(Mutex::new(stack));
+    let config = LinkLocalConfig {
+        probe_wait: Duration::from_millis(0),
+        probe_min: Duration::from_millis(200),
+        probe_max: Duration::from_millis(200),
+        announce_wait: Duration::from_millis(200),
+        announce_interval: Duration::from_millis(0),
+        ..LinkLocalConfig::default()
+    };
+    let thread_stack = stack.clone();
+    let thread_interface = interface.clone();
+    let thread_config = config.clone();
+    let handle = thread::spawn(move || {
+        link_local::configure(thread_stack, &thread_interface, &thread_config).unwrap()
+    });
+
+    // Claim the first candidate as soon as it is probed for
+    let probe_u8 = read_handle.recv().unwrap();
+    let probe_eth = EthernetPacket::new(&probe_u8[..]).unwrap();
+    let probe = ArpPacket::new(probe_eth.payload()).unwrap();
+    assert_eq!(Ipv4Addr::new(0, 0, 0, 0), probe.get_sender_proto_addr());
+    let taken_ip = probe.get_target_proto_addr();
+    assert!(link_local::is_assignable(taken_ip));
+    send_arp_reply_from(inject_handle, taken_ip);
+
+    let net = handle.join().unwrap().unwrap();
+    assert!(link_local::is_assignable(net.ip()));
+    assert_ne!(net.ip(), taken_ip);
+    assert_eq!(net.prefix(), 16);
+    assert!(stack.lock().unwrap().interface(&interface).unwrap().has_ipv4(net.ip()));
+
+    // Already configured interfaces are left alone
+    assert_eq!(link_local::configure(stack, &interface, &config).unwrap(), None);
+}
+
 fn send_arp_reply(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
+    send_arp_reply_from(inject_handle, Ipv4Addr::new(10, 0, 0, 1));
+}
+
+fn send_arp_reply_from(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>, ip: Ipv4Addr) {
     // Send the response back to librips
     let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
                              ArpPacket::minimum_packet_size()];
@@ -111,7 +154,7 @@ fn send_arp_reply(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
         let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
         arp_pkg.set_operation(ArpOperations::Reply);
         arp_pkg.set_sender_hw_addr(MacAddr::new(9, 8, 7, 6, 5, 4));
-        arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 1));
+        arp_pkg.set_sender_proto_addr(ip);
     }
     inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
 }
@@ -0,0 +1,104 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::util::MacAddr;
+
+use rips::{NetworkStack, StackError, testing};
+use rips::bond::{Bond, MemberId};
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+type Inject = Sender<io::Result<Box<[u8]>>>;
+
+/// Returns the operation and sender address of the Arp packets received.
+fn arps(read: &Receiver<Box<[u8]>>) -> Vec<(u16, Ipv4Addr)> {
+    thread::sleep(Duration::from_millis(200));
+    read.try_iter()
+        .map(|frame| {
+            let eth_pkg = EthernetPacket::new(&frame).unwrap();
+            let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+            (arp_pkg.get_operation().0, arp_pkg.get_sender_proto_addr())
+        })
+        .collect()
+}
+
+fn bond() -> (Bond, NetworkStack, Vec<Inject>, Vec<Receiver<Box<[u8]>>>) {
+    let bond = Bond::new();
+    let mut injects = vec![];
+    let mut reads = vec![];
+    for i in 0..2 {
+        let (channel, interface, inject, read) = testing::dummy_ethernet_indexed(i);
+        assert_eq!(bond.add_member(&interface.name, channel), MemberId(i as usize));
+        injects.push(inject);
+        reads.push(read);
+    }
+    let mut stack = NetworkStack::new();
+    let interface = bond.add_interface(&mut stack, "bond0", MacAddr::new(2, 0, 0, 0, 0, 0xbb))
+        .unwrap();
+    let config = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+    stack.add_ipv4(&interface, config).unwrap();
+    (bond, stack, injects, reads)
+}
+
+#[test]
+fn failover_on_carrier_loss() {
+    let (bond, _stack, injects, reads) = bond();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let request = ArpOperations::Request.0;
+    let reply = ArpOperations::Reply.0;
+    assert_eq!(bond.active(), Some(MemberId(0)));
+
+    // Only the active member is used
+    injects[0].send(Ok(testing::arp_request(ip))).unwrap();
+    injects[1].send(Ok(testing::arp_request(ip))).unwrap();
+    assert_eq!(arps(&reads[0]), vec![(reply, ip)]);
+    assert!(arps(&reads[1]).is_empty());
+
+    // Losing carrier switches over and announces the address on member 1
+    injects[0].send(Err(io::Error::new(io::ErrorKind::Other, "Carrier lost"))).unwrap();
+    assert_eq!(arps(&reads[1]), vec![(request, ip)]);
+    assert_eq!(bond.active(), Some(MemberId(1)));
+    assert_eq!(bond.carrier(MemberId(0)), Some(false));
+    injects[1].send(Ok(testing::arp_request(ip))).unwrap();
+    assert_eq!(arps(&reads[1]), vec![(reply, ip)]);
+
+    // Member 0 is back, but stays backup
+    injects[0].send(Ok(testing::arp_request(ip))).unwrap();
+    assert!(arps(&reads[0]).is_empty());
+    assert_eq!(bond.carrier(MemberId(0)), Some(true));
+    assert_eq!(bond.active(), Some(MemberId(1)));
+}
+
+#[test]
+fn manual_switchover() {
+    let (bond, mut stack, _injects, reads) = bond();
+    let mac = MacAddr::new(2, 0, 0, 0, 0, 0xbb);
+    match bond.add_interface(&mut stack, "bond1", mac) {
+        Err(StackError::AlreadyExists(..)) => (),
+        _ => panic!("Bond already has an interface"),
+    }
+    assert!(bond.set_carrier(MemberId(2), false).is_err());
+
+    bond.set_carrier(MemberId(1), false).unwrap();
+    assert!(bond.set_active(MemberId(1)).is_err());
+    bond.set_carrier(MemberId(1), true).unwrap();
+    bond.set_active(MemberId(1)).unwrap();
+    assert_eq!(arps(&reads[1]), vec![(ArpOperations::Request.0, Ipv4Addr::new(10, 0, 0, 1))]);
+
+    bond.set_carrier(MemberId(0), false).unwrap();
+    bond.set_carrier(MemberId(1), false).unwrap();
+    assert_eq!(bond.active(), None);
+    bond.set_carrier(MemberId(0), true).unwrap();
+    assert_eq!(bond.active(), Some(MemberId(0)));
+    assert_eq!(bond.members(), vec!["eth0".to_owned(), "eth1".to_owned()]);
+}
@@ -0,0 +1,231 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::{ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::util::{self, MacAddr};
+
+use rips::{NetworkStack, testing};
+use rips::bridge::{Bridge, PortId};
+use rips::bridge::igmp::{self, Message, Querier, SnoopingConfig};
+use rips::bridge::stp::{self, Bpdu, BpduType, BridgeId, PortRole, PortState, PriorityVector,
+                        StpConfig};
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+type Inject = Sender<io::Result<Box<[u8]>>>;
+
+fn frame(src: MacAddr, dst: MacAddr) -> Box<[u8]> {
+    let mut buffer = vec![0; 60];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(src);
+        eth_pkg.set_destination(dst);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+    }
+    buffer.into_boxed_slice()
+}
+
+/// Builds a frame with an IPv4 packet to the multicast `group`.
+fn multicast(group: Ipv4Addr, protocol: IpNextHeaderProtocol, payload: &[u8]) -> Box<[u8]> {
+    let mut buffer = vec![0; 34 + payload.len()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(MacAddr::new(2, 0, 0, 0, 0, 1));
+        eth_pkg.set_destination(igmp::multicast_mac(group));
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + payload.len() as u16);
+        ip_pkg.set_next_level_protocol(protocol);
+        ip_pkg.set_source(Ipv4Addr::new(10, 0, 0, 1));
+        ip_pkg.set_destination(group);
+        ip_pkg.payload_mut().copy_from_slice(payload);
+    }
+    buffer.into_boxed_slice()
+}
+
+fn bridge(ports: u8) -> (Bridge, Vec<Inject>, Vec<Receiver<Box<[u8]>>>) {
+    let bridge = Bridge::new();
+    let mut injects = vec![];
+    let mut reads = vec![];
+    for i in 0..ports {
+        let (channel, interface, inject, read) = testing::dummy_ethernet_indexed(i);
+        assert_eq!(bridge.add_port(&interface.name, channel), PortId(i as usize));
+        injects.push(inject);
+        reads.push(read);
+    }
+    (bridge, injects, reads)
+}
+
+fn received(reads: &[Receiver<Box<[u8]>>]) -> Vec<usize> {
+    thread::sleep(Duration::from_millis(100));
+    reads.iter().map(|read| read.try_iter().count()).collect()
+}
+
+#[test]
+fn learn_and_flood() {
+    let (bridge, injects, reads) = bridge(3);
+    let a = MacAddr::new(2, 0, 0, 0, 0, 1);
+    let b = MacAddr::new(2, 0, 0, 0, 0, 2);
+    let broadcast = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+
+    injects[0].send(Ok(frame(a, broadcast))).unwrap();
+    assert_eq!(received(&reads), vec![0, 1, 1]);
+    // Unknown unicast is flooded too
+    injects[1].send(Ok(frame(b, MacAddr::new(2, 0, 0, 0, 0, 3)))).unwrap();
+    assert_eq!(received(&reads), vec![1, 0, 1]);
+    // Both a and b are learned now
+    injects[1].send(Ok(frame(b, a))).unwrap();
+    injects[0].send(Ok(frame(a, b))).unwrap();
+    assert_eq!(received(&reads), vec![1, 1, 0]);
+    assert_eq!(bridge.fdb().get(&a), Some(&PortId(0)));
+    assert_eq!(bridge.fdb().get(&b), Some(&PortId(1)));
+
+    bridge.set_ageing_time(Duration::new(0, 0));
+    assert!(bridge.fdb().is_empty());
+    injects[1].send(Ok(frame(b, a))).unwrap();
+    assert_eq!(received(&reads), vec![1, 0, 1]);
+}
+
+#[test]
+fn interface_on_bridge() {
+    let (bridge, injects, reads) = bridge(2);
+    let mut stack = NetworkStack::new();
+    let mac = MacAddr::new(2, 0, 0, 0, 0, 0xbb);
+    let interface = bridge.add_interface(&mut stack, "br0", mac).unwrap();
+    assert!(bridge.add_interface(&mut stack, "br1", mac).is_err());
+    let config = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+    stack.add_ipv4(&interface, config).unwrap();
+
+    let peer = MacAddr::new(2, 0, 0, 0, 0, 1);
+    let mut request = vec![0; EthernetPacket::minimum_packet_size() +
+                              ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut request[..]).unwrap();
+        eth_pkg.set_source(peer);
+        eth_pkg.set_destination(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_operation(ArpOperations::Request);
+        arp_pkg.set_sender_hw_addr(peer);
+        arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 2));
+        arp_pkg.set_target_proto_addr(Ipv4Addr::new(10, 0, 0, 1));
+    }
+    injects[1].send(Ok(request.into_boxed_slice())).unwrap();
+    // The request is flooded to port 0, the broadcast reply to both ports
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(reads[0].try_iter().count(), 2);
+    let reply = reads[1].try_recv().unwrap();
+    assert!(reads[1].try_recv().is_err());
+    let eth_pkg = EthernetPacket::new(&reply).unwrap();
+    assert_eq!(eth_pkg.get_source(), mac);
+    let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+    assert_eq!(arp_pkg.get_operation(), ArpOperations::Reply);
+}
+
+#[test]
+fn spanning_tree_blocks_loop() {
+    let (bridge, injects, reads) = bridge(3);
+    bridge.enable_stp(StpConfig::new(MacAddr::new(2, 0, 0, 0, 0, 0xbb)));
+    bridge.set_edge_port(PortId(2), true).unwrap();
+    assert!(bridge.set_edge_port(PortId(3), true).is_err());
+
+    // Ports 0 and 1 are connected to the same switch, the root bridge
+    let root = BridgeId::new(0, MacAddr::new(2, 0, 0, 0, 0, 1));
+    for (i, inject) in injects.iter().take(2).enumerate() {
+        let bpdu = Bpdu {
+            bpdu_type: BpduType::Rst,
+            flags: stp::FLAG_PROPOSAL | 0b11 << 2,
+            vector: PriorityVector {
+                root: root,
+                root_cost: 0,
+                bridge: root,
+                port: 0x8001 + i as u16,
+            },
+            message_age: 0,
+            max_age: 20 * 256,
+            hello_time: 2 * 256,
+            forward_delay: 15 * 256,
+        };
+        inject.send(Ok(bpdu.frame(root.mac()).into_boxed_slice())).unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(bridge.root_bridge(), Some(root));
+    assert_eq!(bridge.root_port(), Some(PortId(0)));
+    let status = bridge.port_status(PortId(1)).unwrap();
+    assert_eq!((status.role, status.state), (PortRole::Alternate, PortState::Discarding));
+    assert_eq!(bridge.port_status(PortId(2)).unwrap().state, PortState::Forwarding);
+
+    // The root port agreed to the proposal
+    let agreed = reads[0].try_iter().filter_map(|frame| Bpdu::parse(&frame)).any(|bpdu| {
+        bpdu.role() == Some(PortRole::Root) && bpdu.flags & stp::FLAG_AGREEMENT != 0
+    });
+    assert!(agreed);
+    for read in &reads {
+        read.try_iter().count();
+    }
+
+    // Nothing is flooded back into the loop, or received on the blocked port
+    let broadcast = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+    injects[0].send(Ok(frame(MacAddr::new(2, 0, 0, 0, 0, 7), broadcast))).unwrap();
+    injects[1].send(Ok(frame(MacAddr::new(2, 0, 0, 0, 0, 8), broadcast))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let data_frames = reads.iter()
+        .map(|read| read.try_iter().filter(|frame| Bpdu::parse(frame).is_none()).count())
+        .collect::<Vec<_>>();
+    assert_eq!(data_frames, vec![0, 0, 1]);
+
+    bridge.disable_stp();
+    assert!(bridge.port_status(PortId(1)).is_none());
+}
+
+#[test]
+fn igmp_snooping() {
+    let (bridge, injects, reads) = bridge(3);
+    let mut config = SnoopingConfig::new();
+    config.querier = Some(Querier {
+        address: Ipv4Addr::new(10, 0, 0, 254),
+        mac: MacAddr::new(2, 0, 0, 0, 0, 0xbb),
+    });
+    bridge.enable_igmp_snooping(config);
+
+    // With no other querier the bridge queries all ports
+    thread::sleep(Duration::from_millis(200));
+    for read in &reads {
+        match Message::parse(&read.try_recv().unwrap()) {
+            Some(Message::Query { source, .. }) => assert_eq!(source, Ipv4Addr::new(10, 0, 0, 254)),
+            _ => panic!("Expected a query"),
+        }
+    }
+
+    let group = Ipv4Addr::new(239, 1, 2, 3);
+    let mut report = vec![igmp::V2_MEMBERSHIP_REPORT, 0, 0, 0, 239, 1, 2, 3];
+    let csum = util::checksum(&report, 1);
+    report[2] = (csum >> 8) as u8;
+    report[3] = csum as u8;
+    injects[1].send(Ok(multicast(group, IpNextHeaderProtocols::Igmp, &report))).unwrap();
+    // The report only goes to router ports, and there are none
+    assert_eq!(received(&reads), vec![0, 0, 0]);
+    assert_eq!(bridge.multicast_groups()[&group], vec![PortId(1)]);
+    assert!(bridge.multicast_router_ports().is_empty());
+
+    let data = multicast(group, IpNextHeaderProtocols::Udp, &[0; 8]);
+    injects[0].send(Ok(data.clone())).unwrap();
+    assert_eq!(received(&reads), vec![0, 1, 0]);
+
+    bridge.disable_igmp_snooping();
+    injects[0].send(Ok(data)).unwrap();
+    assert_eq!(received(&reads), vec![0, 1, 1]);
+}
@@ -0,0 +1,73 @@
+extern crate pnet;
+extern crate rips;
+
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+
+use rips::corrupt::{EthernetCorruption, IcmpCorruption, Ipv4Corruption, UdpCorruption};
+use rips::ethernet::{BasicEthernetPayload, EthernetTx};
+use rips::ipv4::{BasicIpv4Payload, Ipv4Tx};
+use rips::malformed::ParseErrors;
+use rips::sockopt::{HasSocketOptions, ReadTimeout};
+use rips::testing::{self, ip};
+use rips::udp::UdpSocket;
+
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn corrupted_packets() {
+    let ((receiver, interface1), (mut sender, interface2)) = testing::connected_pair(1, 2);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let socket = UdpSocket::bind(receiver.clone(), SocketAddrV4::new(ip(1), 7)).unwrap();
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(500))).unwrap();
+
+    let mut udp_tx = sender.udp_tx(ip(1), 4000, 7).unwrap();
+    udp_tx.set_corruption(UdpCorruption { bad_checksum: true, ..UdpCorruption::default() });
+    udp_tx.send(&[1]).unwrap();
+    udp_tx.set_corruption(UdpCorruption { length: Some(100), ..UdpCorruption::default() });
+    udp_tx.send(&[2]).unwrap();
+    udp_tx.set_corruption(UdpCorruption::default());
+    udp_tx.send(&[3]).unwrap();
+
+    let mut ipv4_tx = sender.ipv4_tx(ip(1)).unwrap();
+    ipv4_tx.set_corruption(Ipv4Corruption { bad_checksum: true, ..Ipv4Corruption::default() });
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &[0; 9])).unwrap();
+    ipv4_tx.set_corruption(Ipv4Corruption { truncate: Some(12), ..Ipv4Corruption::default() });
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &[0; 9])).unwrap();
+    ipv4_tx.set_corruption(Ipv4Corruption { total_length: Some(10), ..Ipv4Corruption::default() });
+    assert_eq!(ipv4_tx.corruption().total_length, Some(10));
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &[0; 9])).unwrap();
+
+    let mut icmp_tx = sender.icmp_tx(ip(1)).unwrap();
+    icmp_tx.set_corruption(IcmpCorruption { bad_checksum: true, truncate: None });
+    icmp_tx.send_echo(&[4]).unwrap();
+
+    let dst_mac = receiver.lock().unwrap().interface(&interface1).unwrap().interface().mac;
+    let mut ethernet_tx = sender.interface(&interface2).unwrap().ethernet_tx(dst_mac);
+    ethernet_tx.set_corruption(EthernetCorruption { ether_type: None, truncate: Some(20) });
+    ethernet_tx.send(1, 20, BasicEthernetPayload::new(EtherTypes::Ipv4, &[0x45; 20])).unwrap();
+
+    // Only the datagram sent without corruption is received
+    let mut buffer = [0; 16];
+    let (len, _) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], &[3]);
+    let expected = ParseErrors {
+        ethernet: 0,
+        arp: 0,
+        ipv4: 4,
+        udp: 2,
+        icmp: 1,
+        checksums: 3,
+    };
+    for _ in 0..100 {
+        if receiver.lock().unwrap().parse_errors() == expected {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(receiver.lock().unwrap().parse_errors(), expected);
+    assert!(socket.recv_from(&mut buffer).is_err());
+}
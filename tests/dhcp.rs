@@ -0,0 +1,319 @@
+extern crate ipnetwork;
+extern crate pnet;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
+use pnet::util::MacAddr;
+
+use rips::{ErrorKind, NetworkStack, StackError, testing};
+use rips::clock::MockClock;
+use rips::dhcp::{self, DhcpClient, DhcpConfig, DhcpOption, Message};
+use rips::dhcp::server::{self, DhcpServerConfig};
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The server end of the dummy interface of a stack.
+struct Server {
+    mac: MacAddr,
+    ip: Ipv4Addr,
+    client: MacAddr,
+    inject: Sender<io::Result<Box<[u8]>>>,
+    read: Receiver<Box<[u8]>>,
+}
+
+impl Server {
+    /// Returns the next message sent to the server port, with the MAC and
+    /// IPv4 addresses it came from and went to.
+    fn recv(&self) -> (MacAddr, Ipv4Addr, Ipv4Addr, Message) {
+        let frame = self.read.recv_timeout(Duration::from_secs(3)).unwrap();
+        self.parse(&frame)
+    }
+
+    /// Same as `recv`, for a polled stack with the clock `clock`. Polls the
+    /// stack and advances the clock by `step` until a message is sent, and
+    /// returns how far the clock was advanced with the message.
+    fn recv_polled(&self,
+                   stack: &Mutex<NetworkStack>,
+                   clock: &MockClock,
+                   step: Duration)
+                   -> (Duration, Message) {
+        let deadline = Instant::now() + Duration::from_secs(3);
+        let mut advanced = Duration::from_secs(0);
+        loop {
+            stack.lock().unwrap().poll_timeout(Duration::from_millis(10));
+            if let Ok(frame) = self.read.try_recv() {
+                return (advanced, self.parse(&frame).3);
+            }
+            assert!(Instant::now() < deadline, "No message was sent");
+            clock.advance(step);
+            advanced += step;
+        }
+    }
+
+    fn parse(&self, frame: &[u8]) -> (MacAddr, Ipv4Addr, Ipv4Addr, Message) {
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        assert_eq!((eth_pkg.get_source(), eth_pkg.get_ethertype()),
+                   (self.client, EtherTypes::Ipv4));
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!(checksum(&ip_pkg), ip_pkg.get_checksum());
+        let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+        assert_eq!((udp_pkg.get_source(), udp_pkg.get_destination()),
+                   (dhcp::CLIENT_PORT, dhcp::SERVER_PORT));
+        let message = Message::parse(udp_pkg.payload()).unwrap();
+        assert_eq!((message.op, message.chaddr), (dhcp::BOOTREQUEST, self.client));
+        (eth_pkg.get_destination(), ip_pkg.get_source(), ip_pkg.get_destination(), message)
+    }
+
+    fn reply(&self, request: &Message, kind: u8, yiaddr: Ipv4Addr, options: &[DhcpOption]) {
+        let mut reply = Message::new(dhcp::BOOTREPLY, request.xid, request.chaddr);
+        reply.yiaddr = yiaddr;
+        reply.options.push(DhcpOption::new(dhcp::MESSAGE_TYPE, vec![kind]));
+        reply.options.push(DhcpOption::ipv4(dhcp::SERVER_ID, self.ip));
+        reply.options.extend_from_slice(options);
+        let (dst_mac, dst) = if request.broadcast {
+            (MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff), Ipv4Addr::new(255, 255, 255, 255))
+        } else {
+            (self.client, request.ciaddr)
+        };
+        let payload = reply.to_bytes();
+        let mut buffer = vec![0; 14 + 28 + payload.len()];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            eth_pkg.set_source(self.mac);
+            eth_pkg.set_destination(dst_mac);
+            eth_pkg.set_ethertype(EtherTypes::Ipv4);
+            let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_ttl(64);
+            ip_pkg.set_total_length(28 + payload.len() as u16);
+            ip_pkg.set_source(self.ip);
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            {
+                let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+                udp_pkg.set_source(dhcp::SERVER_PORT);
+                udp_pkg.set_destination(dhcp::CLIENT_PORT);
+                udp_pkg.set_length(8 + payload.len() as u16);
+                udp_pkg.set_payload(&payload);
+            }
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        self.inject.send(Ok(buffer.into_boxed_slice())).unwrap();
+    }
+}
+
+#[test]
+fn lease_renew_release() {
+    let (stack, interface, inject, read) = testing::dummy_stack();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = Server {
+        mac: MacAddr::new(2, 0, 0, 0, 0, 0xd0),
+        ip: Ipv4Addr::new(10, 0, 0, 1),
+        client: interface.mac,
+        inject: inject,
+        read: read,
+    };
+    let config = DhcpConfig {
+        timeout: Duration::from_secs(1),
+        host_name: Some("rips".to_owned()),
+        ..DhcpConfig::default()
+    };
+    let client = {
+        let stack = stack.clone();
+        let interface = interface.clone();
+        thread::spawn(move || DhcpClient::start(stack, &interface, config))
+    };
+
+    let address = Ipv4Addr::new(10, 0, 0, 5);
+    let broadcast = Ipv4Addr::new(255, 255, 255, 255);
+    let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+    let (dst_mac, src, dst, discover) = server.recv();
+    let broadcast_mac = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+    assert_eq!((dst_mac, src, dst), (broadcast_mac, unspecified, broadcast));
+    assert_eq!(discover.message_type(), Some(dhcp::DISCOVER));
+    assert!(discover.broadcast);
+    assert_eq!(discover.option(dhcp::HOST_NAME), Some(&b"rips"[..]));
+    server.reply(&discover, dhcp::OFFER, address, &[]);
+
+    let (_, _, _, request) = server.recv();
+    assert_eq!((request.message_type(), request.xid), (Some(dhcp::REQUEST), discover.xid));
+    assert_eq!(request.ipv4_option(dhcp::REQUESTED_ADDRESS), Some(address));
+    assert_eq!(request.ipv4_option(dhcp::SERVER_ID), Some(server.ip));
+    let mut dns = vec![10, 0, 0, 53];
+    dns.extend_from_slice(&[10, 0, 0, 54]);
+    let options = [DhcpOption::u32(dhcp::LEASE_TIME, 6),
+                   DhcpOption::u32(dhcp::RENEWAL_TIME, 1),
+                   DhcpOption::ipv4(dhcp::SUBNET_MASK, Ipv4Addr::new(255, 255, 255, 0)),
+                   DhcpOption::ipv4(dhcp::ROUTER, server.ip),
+                   DhcpOption::new(dhcp::DNS_SERVERS, dns)];
+    server.reply(&request, dhcp::ACK, address, &options);
+
+    let mut client = client.join().unwrap().unwrap();
+    let lease = client.lease().unwrap();
+    let net = Ipv4Network::new(address, 24).unwrap();
+    assert_eq!((lease.address, lease.server), (net, server.ip));
+    assert_eq!(lease.dns_servers,
+               vec![Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(10, 0, 0, 54)]);
+    {
+        let mut stack = stack.lock().unwrap();
+        assert_eq!(stack.interface(&interface).unwrap().ipv4_networks(), vec![net]);
+        assert_eq!(stack.routing_table().default_route().unwrap().gw, Some(server.ip));
+    }
+
+    // Renewed at T1 with the server that granted the lease
+    let (dst_mac, src, dst, renewal) = server.recv();
+    assert_eq!((dst_mac, src, dst), (server.mac, address, server.ip));
+    assert_eq!((renewal.message_type(), renewal.ciaddr), (Some(dhcp::REQUEST), address));
+    assert!(!renewal.broadcast);
+    assert_eq!(renewal.option(dhcp::SERVER_ID), None);
+    let options = [DhcpOption::u32(dhcp::LEASE_TIME, 3600), options[2].clone()];
+    server.reply(&renewal, dhcp::ACK, address, &options);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while client.lease().unwrap().lease_time != Duration::from_secs(3600) {
+        assert!(Instant::now() < deadline, "The lease was not renewed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(client.lease().unwrap().address, net);
+    assert!(stack.lock().unwrap().routing_table().default_route().is_some());
+
+    client.release().unwrap();
+    let (dst_mac, _, dst, release) = server.recv();
+    assert_eq!((dst_mac, dst), (server.mac, server.ip));
+    assert_eq!((release.message_type(), release.ciaddr), (Some(dhcp::RELEASE), address));
+    assert!(client.lease().is_none());
+    let mut stack = stack.lock().unwrap();
+    assert!(stack.interface(&interface).unwrap().ipv4_networks().is_empty());
+    assert!(stack.routing_table().default_route().is_none());
+}
+
+#[test]
+fn no_server() {
+    let (stack, interface, _inject, _read) = testing::dummy_stack();
+    let config = DhcpConfig {
+        timeout: Duration::from_millis(50),
+        attempts: 2,
+        ..DhcpConfig::default()
+    };
+    match DhcpClient::start(Arc::new(Mutex::new(stack)), &interface, config) {
+        Err(e @ StackError::IoError(_)) => assert_eq!(e.kind(), ErrorKind::Busy),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Got a lease without a server"),
+    }
+}
+
+#[test]
+fn served_over_veth_pair() {
+    let ((server_channel, server_interface), (client_channel, client_interface)) =
+        testing::veth_pair();
+    let mut server_stack = NetworkStack::new();
+    server_stack.add_interface(server_interface.clone(), server_channel).unwrap();
+    let server_ip = Ipv4Addr::new(10, 0, 0, 1);
+    let net = Ipv4Network::new(server_ip, 24).unwrap();
+    server_stack.add_ipv4(&server_interface, net).unwrap();
+    let mut client_stack = NetworkStack::new();
+    client_stack.add_interface(client_interface.clone(), client_channel).unwrap();
+    let client_stack = Arc::new(Mutex::new(client_stack));
+
+    let mut config = DhcpServerConfig::new(Ipv4Addr::new(10, 0, 0, 100),
+                                           Ipv4Addr::new(10, 0, 0, 199));
+    config.routers = vec![server_ip];
+    config.dns_servers = vec![Ipv4Addr::new(10, 0, 0, 53)];
+    let reserved = Ipv4Addr::new(10, 0, 0, 9);
+    config.reservations.insert(client_interface.mac, reserved);
+    let wrong_ip = Ipv4Addr::new(10, 0, 1, 1);
+    match server::spawn(Arc::new(Mutex::new(NetworkStack::new())), wrong_ip, config.clone()) {
+        Err(StackError::NoSourceAddress(ip)) => assert_eq!(ip, wrong_ip),
+        _ => panic!("Served without the address"),
+    }
+    let mut server = server::spawn(Arc::new(Mutex::new(server_stack)), server_ip, config).unwrap();
+
+    let config = DhcpConfig {
+        timeout: Duration::from_secs(1),
+        host_name: Some("rips".to_owned()),
+        ..DhcpConfig::default()
+    };
+    let mut client = DhcpClient::start(client_stack.clone(), &client_interface, config).unwrap();
+    let lease = client.lease().unwrap();
+    assert_eq!(lease.address, Ipv4Network::new(reserved, 24).unwrap());
+    assert_eq!((lease.server, lease.router()), (server_ip, Some(server_ip)));
+    assert_eq!(lease.dns_servers, vec![Ipv4Addr::new(10, 0, 0, 53)]);
+    assert_eq!(lease.lease_time, Duration::from_secs(3600));
+    let leases = server.leases();
+    assert_eq!(leases.len(), 1);
+    assert_eq!((leases[0].address, leases[0].mac), (reserved, client_interface.mac));
+    assert_eq!(leases[0].host_name, Some("rips".to_owned()));
+
+    // Released over unicast, now that the client has an address
+    client.release().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !server.leases().is_empty() {
+        assert!(Instant::now() < deadline, "The lease was not released");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.stop();
+}
+
+#[test]
+fn renew_on_mock_clock() {
+    let clock = Arc::new(MockClock::new());
+    let (stack, interface, inject, read) = testing::dummy_polled_stack_with_clock(clock.clone());
+    let stack = Arc::new(Mutex::new(stack));
+    let server = Server {
+        mac: MacAddr::new(2, 0, 0, 0, 0, 0xd0),
+        ip: Ipv4Addr::new(10, 0, 0, 1),
+        client: interface.mac,
+        inject: inject,
+        read: read,
+    };
+    let config = DhcpConfig {
+        timeout: Duration::from_secs(4),
+        ..DhcpConfig::default()
+    };
+    let client = {
+        let stack = stack.clone();
+        let interface = interface.clone();
+        thread::spawn(move || DhcpClient::start(stack, &interface, config))
+    };
+
+    // Retransmitted once the clock says the timeout passed
+    let step = Duration::from_millis(500);
+    let (_, discover) = server.recv_polled(&stack, &clock, step);
+    let (advanced, retransmitted) = server.recv_polled(&stack, &clock, step);
+    assert!(advanced >= Duration::from_secs(4), "Retransmitted after {:?}", advanced);
+    assert_eq!(retransmitted.xid, discover.xid);
+    assert!(retransmitted.secs >= 4);
+    let address = Ipv4Addr::new(10, 0, 0, 5);
+    server.reply(&retransmitted, dhcp::OFFER, address, &[]);
+    let (_, request) = server.recv_polled(&stack, &clock, Duration::from_secs(0));
+    let options = [DhcpOption::u32(dhcp::LEASE_TIME, 3600),
+                   DhcpOption::u32(dhcp::RENEWAL_TIME, 600)];
+    server.reply(&request, dhcp::ACK, address, &options);
+    while stack.lock().unwrap().poll_timeout(Duration::from_millis(10)) == 0 {}
+    let mut client = client.join().unwrap().unwrap();
+    assert_eq!(client.lease().unwrap().renewal_time, Duration::from_secs(600));
+
+    // Renewed at T1, however long it really takes
+    let (advanced, renewal) = server.recv_polled(&stack, &clock, Duration::from_secs(60));
+    assert!(advanced >= Duration::from_secs(600), "Renewed after {:?}", advanced);
+    assert_eq!((renewal.message_type(), renewal.ciaddr), (Some(dhcp::REQUEST), address));
+    server.reply(&renewal, dhcp::ACK, address, &[DhcpOption::u32(dhcp::LEASE_TIME, 7200)]);
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while client.lease().unwrap().lease_time != Duration::from_secs(7200) {
+        assert!(Instant::now() < deadline, "The lease was not renewed");
+        stack.lock().unwrap().poll_timeout(Duration::from_millis(10));
+    }
+    client.stop();
+}
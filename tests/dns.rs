@@ -0,0 +1,220 @@
+extern crate ipnetwork;
+extern crate rand;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use rips::{ErrorKind, NetworkStack, StackError, testing};
+use rips::dns::{RecordData, RecordType, Resolver, ResolverConfig};
+use rips::handle::StackHandle;
+use rips::udp::{BasicUdpListener, UdpSocket};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+fn name(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for label in name.split('.') {
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label.as_bytes());
+    }
+    bytes.push(0);
+    bytes
+}
+
+/// Returns the reply to `query` with `rcode` and the answers of names, types
+/// and data.
+fn reply(query: &[u8], rcode: u8, answers: &[(Vec<u8>, u16, Vec<u8>)]) -> Vec<u8> {
+    let mut reply = query.to_vec();
+    reply[2] = 0x81;
+    reply[3] = 0x80 | rcode;
+    reply[7] = answers.len() as u8;
+    for &(ref name, record_type, ref data) in answers {
+        reply.extend_from_slice(name);
+        reply.extend_from_slice(&[(record_type >> 8) as u8, record_type as u8, 0, 1, 0, 0, 0, 30]);
+        reply.extend_from_slice(&[0, data.len() as u8]);
+        reply.extend_from_slice(data);
+    }
+    reply
+}
+
+/// Answers queries on 10.0.0.53 the way a server for example.com would,
+/// and ignores those to 10.0.0.54. Returns the counts of both.
+fn serve(stack: Arc<Mutex<NetworkStack>>) -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
+    let answered = Arc::new(AtomicUsize::new(0));
+    let ignored = Arc::new(AtomicUsize::new(0));
+    let silent = UdpSocket::bind(stack.clone(), "10.0.0.54:53").unwrap();
+    let thread_ignored = ignored.clone();
+    thread::spawn(move || {
+        let mut buffer = [0; 512];
+        while silent.recv_from(&mut buffer).is_ok() {
+            thread_ignored.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    let mut socket = UdpSocket::bind(stack, "10.0.0.53:53").unwrap();
+    let thread_answered = answered.clone();
+    thread::spawn(move || {
+        let mut buffer = [0; 512];
+        while let Ok((len, src)) = socket.recv_from(&mut buffer) {
+            thread_answered.fetch_add(1, Ordering::SeqCst);
+            let query = &buffer[..len];
+            let question = &query[12..len - 4];
+            let record_type = (query[len - 4] as u16) << 8 | query[len - 3] as u16;
+            let reply = if question == &name("www.example.com")[..] && record_type == 1 {
+                let mut target = vec![3];
+                target.extend_from_slice(b"web");
+                target.extend_from_slice(&[0xc0, 16]);
+                let web = vec![0xc0, 12 + question.len() as u8 + 4 + 12];
+                reply(query,
+                      0,
+                      &[(vec![0xc0, 12], 5, target), (web, 1, vec![10, 0, 0, 80])])
+            } else if question == &name("80.0.0.10.in-addr.arpa")[..] && record_type == 12 {
+                reply(query, 0, &[(vec![0xc0, 12], 12, name("www.example.com"))])
+            } else if question == &name("broken.example.com")[..] {
+                reply(query, 2, &[])
+            } else if question.ends_with(&name("example.com")) {
+                reply(query, 0, &[])
+            } else {
+                reply(query, 3, &[])
+            };
+            socket.send_to(&reply, src).unwrap();
+        }
+    });
+    (answered, ignored)
+}
+
+/// Returns a stack with the DNS servers of `serve` and 10.0.0.80, and a
+/// stack with 10.0.0.2 connected to it.
+fn stacks() -> (Arc<Mutex<NetworkStack>>, Arc<Mutex<NetworkStack>>) {
+    let ((mut server_stack, server_interface), (client_stack, _)) = testing::connected_pair(53, 2);
+    for last in &[54, 80] {
+        let net = Ipv4Network::new(testing::ip(*last), 24).unwrap();
+        server_stack.add_ipv4(&server_interface, net).unwrap();
+    }
+    (Arc::new(Mutex::new(server_stack)), Arc::new(Mutex::new(client_stack)))
+}
+
+#[test]
+fn lookups() {
+    let (server_stack, client_stack) = stacks();
+    let (answered, ignored) = serve(server_stack);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let mut config = ResolverConfig::new(vec![Ipv4Addr::new(10, 0, 0, 54),
+                                              Ipv4Addr::new(10, 0, 0, 53)]);
+    config.timeout = Duration::from_millis(200);
+    let resolver = Resolver::new(client_stack.clone(), local_ip, config.clone()).unwrap();
+
+    // Through the alias, after the first server did not answer
+    assert_eq!(resolver.lookup_ipv4("www.example.com.").unwrap(),
+               vec![Ipv4Addr::new(10, 0, 0, 80)]);
+    assert_eq!((answered.load(Ordering::SeqCst), ignored.load(Ordering::SeqCst)), (1, 1));
+    // From the cache
+    let records = resolver.lookup("WWW.example.com", RecordType::A).unwrap();
+    assert_eq!(records[0].data, RecordData::Cname("web.example.com".to_owned()));
+    assert_eq!(&records[1].name[..], "web.example.com");
+    assert!(records[1].ttl <= 30);
+    assert_eq!(answered.load(Ordering::SeqCst), 1);
+
+    let reverse = resolver.reverse(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 80))).unwrap();
+    assert_eq!(reverse, vec!["www.example.com".to_owned()]);
+
+    let before = answered.load(Ordering::SeqCst);
+    for _ in 0..2 {
+        for name in &["missing.org", "mail.example.com"] {
+            match resolver.lookup_ipv4(name) {
+                Err(StackError::NotFound(_)) => (),
+                result => panic!("Unexpected result for {}: {:?}", name, result),
+            }
+        }
+    }
+    assert_eq!(answered.load(Ordering::SeqCst), before + 2);
+    resolver.flush_name("mail.example.com.");
+    assert!(resolver.lookup_ipv4("mail.example.com").is_err());
+    assert!(resolver.lookup_ipv4("missing.org").is_err());
+    assert_eq!(answered.load(Ordering::SeqCst), before + 3);
+    resolver.flush();
+    resolver.lookup_ipv4("www.example.com").unwrap();
+    assert_eq!(answered.load(Ordering::SeqCst), before + 4);
+
+    match resolver.lookup_ipv4("broken.example.com") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Io),
+        Ok(ips) => panic!("Resolved to {:?}", ips),
+    }
+    match resolver.lookup_ipv4("www..example.com") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+        Ok(ips) => panic!("Resolved to {:?}", ips),
+    }
+
+    config.servers.truncate(1);
+    config.attempts = 1;
+    let silent = Resolver::new(client_stack, local_ip, config).unwrap();
+    match silent.lookup_ipv4("www.example.com") {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Busy),
+        Ok(ips) => panic!("Resolved to {:?}", ips),
+    }
+}
+
+#[test]
+fn ids_from_stack_generator() {
+    let (server_stack, client_stack) = stacks();
+    let server = UdpSocket::bind(server_stack, "10.0.0.53:53").unwrap();
+    let seed = [1, 2, 3, 4];
+    client_stack.lock().unwrap().set_rng(XorShiftRng::from_seed(seed));
+    let mut config = ResolverConfig::new(vec![Ipv4Addr::new(10, 0, 0, 53)]);
+    config.timeout = Duration::from_millis(100);
+    config.attempts = 1;
+    let resolver = Resolver::new(client_stack, Ipv4Addr::new(10, 0, 0, 2), config).unwrap();
+    let lookup = thread::spawn(move || resolver.lookup_ipv4("www.example.com").is_err());
+
+    let mut buffer = [0; 512];
+    server.recv_from(&mut buffer).unwrap();
+    let id = (buffer[0] as u16) << 8 | buffer[1] as u16;
+    assert_eq!(id, XorShiftRng::from_seed(seed).gen::<u16>());
+    assert!(lookup.join().unwrap());
+}
+
+#[test]
+fn host_names() {
+    let (server_stack, client_stack) = stacks();
+    let echo = UdpSocket::bind(server_stack.clone(), "10.0.0.80:7").unwrap();
+    serve(server_stack);
+    let mut socket = UdpSocket::bind(client_stack.clone(), "10.0.0.2:0").unwrap();
+    match socket.send_to(&[1], ("www.example.com", 7)) {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::Other),
+        Ok(_) => panic!("Looked up a name without a resolver"),
+    }
+
+    let config = ResolverConfig::new(vec![Ipv4Addr::new(10, 0, 0, 53)]);
+    let resolver = Resolver::new(client_stack.clone(), Ipv4Addr::new(10, 0, 0, 2), config);
+    client_stack.lock().unwrap().set_resolver(Some(resolver.unwrap()));
+    socket.send_to(&[1], ("www.example.com", 7)).unwrap();
+    socket.send_to(&[2], "www.example.com.:7").unwrap();
+    let mut buffer = [0; 16];
+    for expected in 1..3 {
+        let (len, src) = echo.recv_from(&mut buffer).unwrap();
+        assert_eq!((&buffer[..len], src), (&[expected][..], socket.local_addr().unwrap()));
+    }
+    match socket.send_to(&[3], "mail.example.com:7") {
+        Err(e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        Ok(_) => panic!("Sent to a name without an address"),
+    }
+
+    let handle = StackHandle::from_shared(client_stack.clone());
+    let dst = handle.resolve(("www.example.com", 7)).unwrap();
+    assert_eq!(dst, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 80), 7));
+    let mut udp_tx = handle.udp_tx_to("www.example.com:7", 1024).unwrap();
+    udp_tx.send(&[4]).unwrap();
+    assert_eq!(echo.recv_from(&mut buffer).unwrap().0, 1);
+    assert_eq!(buffer[0], 4);
+
+    // Only looked up by sockets, not with the stack locked
+    let listener = BasicUdpListener::new(mpsc::channel().0);
+    assert!(client_stack.lock().unwrap().udp_listen("localhost.example.com:9", listener).is_err());
+}
@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate lazy_static;
+extern crate log;
+extern crate rips;
+
+use log::{Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+
+use rips::dump::DumpConfig;
+use rips::testing;
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.target() == "rips::dump"
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            LOGGED.lock().unwrap().push(format!("{}", record.args()));
+        }
+    }
+}
+
+fn logged(count: usize) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while LOGGED.lock().unwrap().len() < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    LOGGED.lock().unwrap().clone()
+}
+
+#[test]
+fn switched_at_runtime() {
+    log::set_logger(|max_level| {
+            max_level.set(LogLevelFilter::Debug);
+            Box::new(Logger)
+        })
+        .unwrap();
+    let (mut stack, interface, inject_handle, _read_handle) = testing::dummy_stack();
+    let mut frame = vec![0xee; 64];
+    frame[12] = 0x88;
+    frame[13] = 0xb5;
+    inject_handle.send(Ok(frame.clone().into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(LOGGED.lock().unwrap().is_empty());
+
+    let stack_interface = stack.interface(&interface).unwrap();
+    let config = DumpConfig {
+        hex_len: 16,
+        level: LogLevel::Info,
+        ..DumpConfig::default()
+    };
+    stack_interface.set_dump(Some(config));
+    assert!(stack_interface.is_dumping());
+    inject_handle.send(Ok(frame.clone().into_boxed_slice())).unwrap();
+    let lines = logged(1);
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0],
+               format!("{} in 64 bytes: ee:ee:ee:ee:ee:ee > ee:ee:ee:ee:ee:ee, \
+                        ethertype 0x88b5\n\
+                        0000  eeee eeee eeee eeee eeee eeee 88b5 eeee  ................",
+                       interface.name));
+
+    stack_interface.set_dump(None);
+    assert!(!stack_interface.is_dumping());
+    inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(LOGGED.lock().unwrap().len(), 1);
+}
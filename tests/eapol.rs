@@ -0,0 +1,109 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::util::MacAddr;
+
+use rips::{LinkChange, RxResult, TxError, testing};
+use rips::eapol::{self, EapolFrame, Port, Supplicant};
+use rips::ipv4::{BasicIpv4Payload, Ipv4Tx};
+
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+struct TestSupplicant {
+    ports: Sender<Port>,
+    frames: Sender<(u8, Vec<u8>)>,
+}
+
+impl Supplicant for TestSupplicant {
+    fn start(&mut self, port: Port) {
+        port.send(eapol::PAE_GROUP_MAC, eapol::START, &[]).unwrap();
+        self.ports.send(port).unwrap();
+    }
+
+    fn recv(&mut self, _time: SystemTime, frame: &EapolFrame) -> RxResult {
+        self.frames.send((frame.packet_type, frame.body.to_vec())).unwrap();
+        Ok(())
+    }
+}
+
+fn eapol_frame(dst: MacAddr, body: &[u8]) -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + 4 + body.len()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(dst);
+        eth_pkg.set_ethertype(eapol::EAPOL);
+        let data = eth_pkg.payload_mut();
+        data[..4].copy_from_slice(&[eapol::VERSION, eapol::EAP_PACKET, 0, body.len() as u8]);
+        data[4..].copy_from_slice(body);
+    }
+    buffer.into_boxed_slice()
+}
+
+#[test]
+fn supplicant_gates_port() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(dst, MacAddr::new(2, 0, 0, 0, 0, 9));
+
+    let changes = stack.interface(&interface).unwrap().watch_link();
+    let (ports, port) = mpsc::channel();
+    let (frames, frame) = mpsc::channel();
+    let supplicant = TestSupplicant {
+        ports: ports,
+        frames: frames,
+    };
+    stack.interface(&interface).unwrap().set_supplicant(Some(Box::new(supplicant)));
+    let port = port.try_recv().unwrap();
+    assert!(!stack.interface(&interface).unwrap().is_authorized());
+
+    // The EAPOL-Start is sent while nothing else is
+    let start = read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&start).unwrap();
+    assert_eq!(eth_pkg.get_destination(), eapol::PAE_GROUP_MAC);
+    assert_eq!(eth_pkg.get_source(), interface.mac);
+    assert_eq!(eth_pkg.get_ethertype(), eapol::EAPOL);
+    assert_eq!(eth_pkg.payload(), &[eapol::VERSION, eapol::START, 0, 0]);
+    let data = [1, 2, 3];
+    let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &data);
+    match stack.ipv4_tx(dst).unwrap().send(payload.clone()) {
+        Err(TxError::Unauthorized) => (),
+        _ => panic!("Sent on an unauthorized port"),
+    }
+
+    // EAPOL frames reach the supplicant, everything else is dropped
+    inject_handle.send(Ok(testing::arp_request(ip))).unwrap();
+    inject_handle.send(Ok(eapol_frame(eapol::PAE_GROUP_MAC, &[1, 1, 0, 4]))).unwrap();
+    inject_handle.send(Ok(eapol_frame(MacAddr::new(2, 0, 0, 0, 0, 8), &[2]))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(frame.try_recv().unwrap(), (eapol::EAP_PACKET, vec![1, 1, 0, 4]));
+    assert!(frame.try_recv().is_err());
+    assert!(read_handle.try_recv().is_err());
+
+    port.set_authorized(true);
+    assert!(stack.interface(&interface).unwrap().is_authorized());
+    assert_eq!(changes.try_iter().collect::<Vec<_>>(),
+               vec![LinkChange::Authorized(false), LinkChange::Authorized(true)]);
+    stack.ipv4_tx(dst).unwrap().send(payload).unwrap();
+    assert!(read_handle.try_recv().is_ok());
+    inject_handle.send(Ok(testing::arp_request(ip))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let reply = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&reply).unwrap().get_ethertype(), EtherTypes::Arp);
+
+    // Without a supplicant the port is open for good
+    port.set_authorized(false);
+    stack.interface(&interface).unwrap().set_supplicant(None);
+    assert!(!stack.interface(&interface).unwrap().has_supplicant());
+    assert!(stack.interface(&interface).unwrap().is_authorized());
+}
@@ -1,3 +1,59 @@
+extern crate pnet;
+extern crate rips;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use rips::{Interface, Payload, RxResult, StackError, TxSent, testing};
+use rips::ethernet::{self, BasicEthernetListener, BasicEthernetPayload, EthernetPayload,
+                     EthernetTx};
+use rips::ethernet::llc::{LlcFrame, LlcKey, LlcListener, LlcPayload};
+use rips::pcap::{CaptureConfig, Direction, Format, Rotation};
+use rips::rx::RxListener;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+struct Monitor(Sender<MacAddr>);
+
+impl RxListener for Monitor {
+    fn recv(&mut self, _time: SystemTime, packet: &EthernetPacket) -> RxResult {
+        self.0.send(packet.get_destination()).unwrap();
+        Ok(())
+    }
+}
+
+struct Bpdus(Sender<Vec<u8>>);
+
+impl LlcListener for Bpdus {
+    fn recv(&mut self, _time: SystemTime, frame: &LlcFrame) -> RxResult {
+        self.0.send(frame.payload.to_vec()).unwrap();
+        Ok(())
+    }
+
+    fn key(&self) -> LlcKey {
+        LlcKey::Sap(0x42)
+    }
+}
+
+fn llc_frame(mut payload: LlcPayload) -> Box<[u8]> {
+    let mut frame = vec![0; 60];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut frame[..]).unwrap();
+        eth_pkg.set_destination(MacAddr::new(0x01, 0x80, 0xc2, 0, 0, 0));
+        eth_pkg.set_ethertype(payload.ether_type());
+    }
+    payload.build(&mut frame[14..]);
+    frame.into_boxed_slice()
+}
+
 // use pnet::packet::Packet;
 // use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 // use pnet::util::MacAddr;
@@ -25,3 +81,262 @@
 //     assert_eq!(sent_pkg.get_ethertype(), EtherTypes::Rarp);
 //     assert_eq!(sent_pkg.payload()[0], 57);
 // }
+
+#[test]
+fn ethernet_listen() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    let ptp = EtherType(0x88f7);
+    let (tx, rx) = mpsc::channel();
+    stack.ethernet_listen(&interface, BasicEthernetListener::new(ptp, tx.clone())).unwrap();
+    match stack.ethernet_listen(&interface, BasicEthernetListener::new(ptp, tx.clone())) {
+        Err(StackError::AlreadyExists(..)) => (),
+        _ => panic!("EtherType already handled"),
+    }
+    let arp = BasicEthernetListener::new(EtherTypes::Arp, tx);
+    assert!(stack.ethernet_listen(&interface, arp).is_err());
+
+    let mut frame = vec![0; 20];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut frame[..]).unwrap();
+        eth_pkg.set_ethertype(ptp);
+    }
+    frame[14] = 42;
+    inject_handle.send(Ok(frame.clone().into_boxed_slice())).unwrap();
+    let (_, packet) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(packet.get_ethertype(), ptp);
+    assert_eq!(packet.payload()[0], 42);
+
+    assert!(stack.interface(&interface).unwrap().remove_ethernet_listener(ptp).is_some());
+    inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn ethernet_tx_with_src() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let src = MacAddr::new(0, 0, 0x5e, 0, 1, 7);
+    let dst = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+    let mut ethernet_tx = stack.interface(&interface).unwrap().ethernet_tx_with_src(src, dst);
+    ethernet_tx.send(1, 1, BasicEthernetPayload::new(EtherType(0x88b5), &[7])).unwrap();
+
+    let frame = read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&frame).unwrap();
+    assert_eq!(eth_pkg.get_source(), src);
+    assert_eq!(eth_pkg.get_destination(), dst);
+    assert_eq!(eth_pkg.payload()[0], 7);
+}
+
+#[test]
+fn promiscuous_and_monitor() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    let ptp = EtherType(0x88f7);
+    let (tx, rx) = mpsc::channel();
+    stack.ethernet_listen(&interface, BasicEthernetListener::new(ptp, tx)).unwrap();
+    let (monitor_tx, monitor_rx) = mpsc::channel();
+    {
+        let stack_interface = stack.interface(&interface).unwrap();
+        assert!(stack_interface.is_promiscuous());
+        stack_interface.set_promiscuous(false);
+        stack_interface.set_monitor(Some(Box::new(Monitor(monitor_tx))));
+    }
+
+    let other = MacAddr::new(2, 0, 0, 0, 0, 9);
+    for &dst in &[other, interface.mac, MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff)] {
+        let mut frame = vec![0; 20];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut frame[..]).unwrap();
+            eth_pkg.set_destination(dst);
+            eth_pkg.set_ethertype(ptp);
+        }
+        inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+    // Only the frame to the other host is monitored, and it is not handled
+    assert_eq!(monitor_rx.try_iter().collect::<Vec<_>>(), vec![other]);
+    let handled = rx.try_iter().map(|(_, packet)| packet.get_destination()).collect::<Vec<_>>();
+    assert_eq!(handled.len(), 2);
+    assert!(!handled.contains(&other));
+}
+
+#[test]
+fn multicast_filter() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    let ptp = EtherType(0x88f7);
+    let (tx, rx) = mpsc::channel();
+    stack.ethernet_listen(&interface, BasicEthernetListener::new(ptp, tx)).unwrap();
+    let mdns = ethernet::ipv4_multicast_mac(Ipv4Addr::new(224, 0, 0, 251));
+    assert_eq!(mdns, MacAddr::new(0x01, 0x00, 0x5e, 0, 0, 0xfb));
+    let other = MacAddr::new(0x01, 0x00, 0x5e, 0, 0, 0xfc);
+    {
+        let stack_interface = stack.interface(&interface).unwrap();
+        stack_interface.set_promiscuous(false);
+        assert!(stack_interface.join_multicast(MacAddr::new(2, 0, 0, 0, 0, 1)).is_err());
+        stack_interface.join_multicast(mdns).unwrap();
+        stack_interface.join_multicast(mdns).unwrap();
+        stack_interface.leave_multicast(mdns).unwrap();
+        assert_eq!(stack_interface.multicast_macs(), vec![mdns]);
+    }
+
+    let send = |dst| {
+        let mut frame = vec![0; 20];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut frame[..]).unwrap();
+            eth_pkg.set_destination(dst);
+            eth_pkg.set_ethertype(ptp);
+        }
+        inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        rx.try_iter().count()
+    };
+    assert_eq!(send(mdns), 1);
+    assert_eq!(send(other), 0);
+
+    stack.interface(&interface).unwrap().leave_multicast(mdns).unwrap();
+    assert!(stack.interface(&interface).unwrap().leave_multicast(mdns).is_err());
+    assert_eq!(send(mdns), 0);
+}
+
+#[test]
+fn llc_listen() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    let (tx, rx) = mpsc::channel();
+    stack.llc_listen(&interface, Box::new(Bpdus(tx.clone()))).unwrap();
+    assert!(stack.llc_listen(&interface, Box::new(Bpdus(tx))).is_err());
+    let ptp = EtherType(0x88f7);
+    let (ptp_tx, ptp_rx) = mpsc::channel();
+    stack.ethernet_listen(&interface, BasicEthernetListener::new(ptp, ptp_tx)).unwrap();
+
+    inject_handle.send(Ok(llc_frame(LlcPayload::new(0x42, 0x42, &[0, 0, 2])))).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), vec![0, 0, 2]);
+    // RFC 1042 encapsulated frames are handled as Ethernet II
+    inject_handle.send(Ok(llc_frame(LlcPayload::snap(0, ptp.0, &[42])))).unwrap();
+    let (_, packet) = ptp_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(packet.get_ethertype(), ptp);
+    assert_eq!(packet.payload(), &[42]);
+
+    let key = LlcKey::Sap(0x42);
+    assert!(stack.interface(&interface).unwrap().remove_llc_listener(key).is_some());
+    inject_handle.send(Ok(llc_frame(LlcPayload::new(0x42, 0x42, &[0, 0, 2])))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn send_all() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let dst = MacAddr::new(0x02, 0, 0, 0, 0, 9);
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(dst);
+    let sizes = [46, 300, 1500];
+    let data = vec![7; 1500];
+    let payloads = sizes.iter()
+        .map(|&size| BasicEthernetPayload::new(EtherTypes::Ipv4, &data[..size]));
+    let sent = tx.send_all(payloads).unwrap();
+    assert_eq!(sent, TxSent { packets: 3, bytes: 3 * 14 + 46 + 300 + 1500 });
+    for &size in &sizes {
+        let frame = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(frame.len(), 14 + size);
+    }
+    assert!(read_handle.try_recv().is_err());
+}
+
+#[test]
+fn capture() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let path = env::temp_dir().join("rips-test-capture.pcapng");
+    let mut config = CaptureConfig::new(path.clone());
+    config.format = Format::Pcapng;
+    config.snaplen = 20;
+    stack.interface(&interface).unwrap().set_capture(Some(config)).unwrap();
+
+    let dst = MacAddr::new(0x02, 0, 0, 0, 0, 9);
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(dst);
+    tx.send(1, 30, BasicEthernetPayload::new(EtherTypes::Ipv4, &[7; 30])).unwrap();
+    read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    inject_handle.send(Ok(vec![0; 60].into_boxed_slice())).unwrap();
+    let stack_interface = stack.interface(&interface).unwrap();
+    for _ in 0..100 {
+        if stack_interface.capture_stats().unwrap().frames == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let stats = stack_interface.capture_stats().unwrap();
+    assert_eq!((stats.frames, stats.files), (2, 1));
+    stack_interface.set_capture(None).unwrap();
+    assert!(stack_interface.capture_stats().is_none());
+
+    let mut data = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(data.len() as u64, stats.bytes);
+    // The frame sent, cut at the snaplen, comes after the two header blocks
+    let block = &data[28 + 32..];
+    assert_eq!(&block[..4], &[6, 0, 0, 0]);
+    assert_eq!(&block[20..28], &[20, 0, 0, 0, 44, 0, 0, 0]);
+    assert_eq!(&block[28..34], &[2, 0, 0, 0, 0, 9]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn capture_rotation() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    let path = env::temp_dir().join("rips-test-rotation.pcap");
+    let mut config = CaptureConfig::new(path.clone());
+    // The header and two frames of 100 bytes fit in every file
+    config.rotation = Some(Rotation {
+        max_bytes: 24 + 2 * (16 + 100),
+        max_files: 2,
+    });
+    stack.interface(&interface).unwrap().set_capture(Some(config)).unwrap();
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(MacAddr::new(2, 0, 0, 0, 0, 9));
+    for _ in 0..5 {
+        tx.send(1, 86, BasicEthernetPayload::new(EtherTypes::Ipv4, &[0; 86])).unwrap();
+    }
+    let stack_interface = stack.interface(&interface).unwrap();
+    let stats = stack_interface.capture_stats().unwrap();
+    assert_eq!((stats.frames, stats.files), (5, 3));
+    stack_interface.set_capture(None).unwrap();
+
+    let mut rotated = path.clone().into_os_string();
+    rotated.push(".1");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 24 + 16 + 100);
+    assert_eq!(fs::metadata(&rotated).unwrap().len(), 24 + 2 * (16 + 100));
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&rotated).unwrap();
+}
+
+#[test]
+fn observers() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let (seen_tx, seen) = mpsc::channel();
+    let seen_tx = Mutex::new(seen_tx);
+    let observer = move |direction: Direction,
+                         interface: &Interface,
+                         _time: SystemTime,
+                         frame: &EthernetPacket| {
+        let seen = (direction, interface.name.clone(), frame.get_destination());
+        seen_tx.lock().unwrap().send(seen).unwrap();
+    };
+    let id = stack.interface(&interface).unwrap().add_observer(observer);
+
+    let dst = MacAddr::new(0x02, 0, 0, 0, 0, 9);
+    let mut tx = stack.interface(&interface).unwrap().ethernet_tx(dst);
+    tx.send(1, 30, BasicEthernetPayload::new(EtherTypes::Ipv4, &[7; 30])).unwrap();
+    read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let seen_frame = seen.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(seen_frame, (Direction::Outbound, interface.name.clone(), dst));
+    // Seen even though nothing listens for it
+    let mut frame = vec![0; 60];
+    frame[0] = 0x02;
+    inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    let seen_frame = seen.recv_timeout(Duration::from_secs(1)).unwrap();
+    let unknown = MacAddr::new(0x02, 0, 0, 0, 0, 0);
+    assert_eq!(seen_frame, (Direction::Inbound, interface.name.clone(), unknown));
+
+    let stack_interface = stack.interface(&interface).unwrap();
+    assert!(stack_interface.remove_observer(id).is_some());
+    assert!(stack_interface.remove_observer(id).is_none());
+    tx.send(1, 30, BasicEthernetPayload::new(EtherTypes::Ipv4, &[7; 30])).unwrap();
+    read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(seen.recv_timeout(Duration::from_millis(100)).is_err());
+}
@@ -0,0 +1,56 @@
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use rips::failover::{FailoverConfig, FailoverEvent, GatewayMonitor};
+use rips::testing::{self, ip};
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn fail_over_and_back() {
+    let ((mut gateways, gateway_interface), (mut stack, interface)) =
+        testing::connected_pair(1, 2);
+    gateways.add_ipv4(&gateway_interface, Ipv4Network::new(ip(3), 24).unwrap()).unwrap();
+    stack.add_default_route(ip(1), interface.clone(), 0).unwrap();
+    stack.add_default_route(ip(3), interface, 10).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let mut config = FailoverConfig::default();
+    config.interval = Duration::from_millis(20);
+    config.failures = 2;
+    config.probe.echo = false;
+    config.probe.timeout = Duration::from_millis(50);
+    let mut monitor = GatewayMonitor::start(stack.clone(), config).unwrap();
+    let events = monitor.watch();
+    let timeout = Duration::from_secs(5);
+
+    gateways.remove_ipv4(&gateway_interface, ip(1)).unwrap();
+    assert_eq!(events.recv_timeout(timeout), Ok(FailoverEvent::GatewayDown(ip(1))));
+    assert_eq!(events.recv_timeout(timeout),
+               Ok(FailoverEvent::Switched {
+                   from: Some(ip(1)),
+                   to: Some(ip(3)),
+               }));
+    assert_eq!(monitor.down_gateways(), vec![ip(1)]);
+    let dst = Ipv4Addr::from_str("192.168.0.1").unwrap();
+    assert_eq!(stack.lock().unwrap().routing_table().route(dst).unwrap().0, Some(ip(3)));
+
+    gateways.add_ipv4(&gateway_interface, Ipv4Network::new(ip(1), 24).unwrap()).unwrap();
+    assert_eq!(events.recv_timeout(timeout), Ok(FailoverEvent::GatewayUp(ip(1))));
+    assert_eq!(events.recv_timeout(timeout),
+               Ok(FailoverEvent::Switched {
+                   from: Some(ip(3)),
+                   to: Some(ip(1)),
+               }));
+    assert!(monitor.down_gateways().is_empty());
+
+    gateways.remove_ipv4(&gateway_interface, ip(3)).unwrap();
+    assert_eq!(events.recv_timeout(timeout), Ok(FailoverEvent::GatewayDown(ip(3))));
+    monitor.stop();
+    assert!(stack.lock().unwrap().routing_table().is_gateway_up(ip(3)));
+}
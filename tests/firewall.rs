@@ -0,0 +1,51 @@
+extern crate ipnetwork;
+extern crate pnet;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::ip::IpNextHeaderProtocols;
+
+use rips::firewall::{Hook, Match, Rule, RuleCounters, Target};
+use rips::testing;
+use rips::udp::UdpSocket;
+
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn filter_local_traffic() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let firewall = stack.firewall();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    let udp_to = |port| {
+        Match {
+            protocol: Some(IpNextHeaderProtocols::Udp),
+            dst_port: Some(port),
+            ..Match::any()
+        }
+    };
+    let output_rule = firewall.add_rule(Hook::Output, Rule::new(udp_to(9), Target::Drop));
+    let input_rule = firewall.add_rule(Hook::Input, Rule::new(udp_to(1025), Target::Drop));
+
+    let error = client.send_to(&[1], "10.9.0.254:9").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    // Dropped on receive, which the sender does not notice
+    client.send_to(&[2], "10.9.0.254:1025").unwrap();
+    client.send_to(&[3], "10.9.0.254:1024").unwrap();
+
+    let mut buffer = vec![0; 1];
+    server.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(buffer[0], 3);
+    let counters = RuleCounters {
+        packets: 1,
+        bytes: 20 + 8 + 1,
+    };
+    assert_eq!(firewall.counters(output_rule), Some(counters));
+    assert_eq!(firewall.counters(input_rule), Some(counters));
+}
@@ -0,0 +1,98 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::UdpPacket;
+use pnet::util::MacAddr;
+
+use rips::flow::{self, FlowConfig, FlowExporter};
+use rips::testing;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn datagram(dst_mac: MacAddr, src: Ipv4Addr, dst: Ipv4Addr) -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + 20 + 8 + 2];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(dst_mac);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8 + 2);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_pkg.set_source(src);
+        ip_pkg.set_destination(dst);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        ip_pkg.payload_mut().copy_from_slice(&[0x30, 0x39, 0, 9, 0, 10, 0, 0, 1, 2]);
+    }
+    buffer.into_boxed_slice()
+}
+
+#[test]
+fn exports_ended_flows() {
+    let ip = Ipv4Addr::new(10, 9, 0, 254);
+    let peer = Ipv4Addr::new(10, 9, 0, 2);
+    let collector = Ipv4Addr::new(10, 9, 0, 1);
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 16).unwrap()).unwrap();
+    stack.interface(&interface)
+        .unwrap()
+        .arp_table()
+        .insert(collector, MacAddr::new(2, 0, 0, 0, 0, 1));
+    let stack = Arc::new(Mutex::new(stack));
+    let config = FlowConfig {
+        idle_timeout: Duration::from_millis(0),
+        export_interval: Duration::from_millis(10),
+        observation_domain: 3,
+        ..FlowConfig::default()
+    };
+    let mut exporter = FlowExporter::start(stack,
+                                           SocketAddrV4::new(ip, 0),
+                                           SocketAddrV4::new(collector, flow::IPFIX_PORT),
+                                           config)
+        .unwrap();
+    inject_handle.send(Ok(datagram(interface.mac, peer, ip))).unwrap();
+
+    // Records start after the header and the template set
+    let first_record = 16 + 4 + 4 + 12 * 4 + 4;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut found = None;
+    while found.is_none() {
+        assert!(Instant::now() < deadline, "The flow was not exported");
+        let frame = match read_handle.recv_timeout(Duration::from_millis(100)) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+        assert_eq!((ip_pkg.get_destination(), udp_pkg.get_destination()),
+                   (collector, flow::IPFIX_PORT));
+        let message = udp_pkg.payload();
+        assert_eq!(&message[..2], &[0, 10]);
+        assert_eq!(&message[12..16], &[0, 0, 0, 3]);
+        found = message[first_record..]
+            .chunks(49)
+            .find(|record| record[..4] == peer.octets())
+            .map(|record| record.to_vec());
+    }
+    let record = found.unwrap();
+    assert_eq!(&record[4..13], &[10, 9, 0, 254, 17, 0x30, 0x39, 0, 9]);
+    // One packet of 30 bytes, received
+    assert_eq!(&record[15..31],
+               &[0, 0, 0, 0, 0, 0, 0, 30, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(&record[47..], &[0, 1]);
+    assert!(exporter.stats().exported >= 1);
+    exporter.stop();
+}
@@ -0,0 +1,69 @@
+extern crate rand;
+extern crate rips;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use rips::RxError;
+use rips::fuzz::{self, FuzzTarget};
+
+/// A UDP datagram to `port` with the payload `payload`.
+fn datagram(port: u16, payload: &[u8]) -> Vec<u8> {
+    let len = 8 + payload.len();
+    let mut datagram = vec![0x30, 0x39, (port >> 8) as u8, port as u8];
+    datagram.extend_from_slice(&[(len >> 8) as u8, len as u8, 0, 0]);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[test]
+fn valid_inputs() {
+    let mut target = FuzzTarget::new();
+    assert_eq!(target.udp(&datagram(fuzz::UDP_PORT, b"hello")), Ok(()));
+    match target.udp(&datagram(9, b"hello")) {
+        Err(RxError::NoListener(_)) => (),
+        result => panic!("Unexpected result {:?}", result),
+    }
+    assert_eq!(target.icmp(&[8, 0, 0, 0, 0, 1, 0, 1]), Ok(()));
+    assert_eq!(fuzz::udp(&datagram(fuzz::UDP_PORT, &[])), Ok(()));
+}
+
+#[test]
+fn truncated_headers() {
+    let mut target = FuzzTarget::new();
+    assert_eq!(target.arp(&[0, 1, 8, 0]), Err(RxError::InvalidLength));
+    assert_eq!(target.icmp(&[8]), Err(RxError::InvalidLength));
+    // A header claiming 60 bytes in a packet of 20
+    let mut packet = vec![0x4f, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1];
+    packet.extend_from_slice(&[0; 40]);
+    assert_eq!(target.ipv4(&packet), Err(RxError::InvalidLength));
+}
+
+/// Random bytes, and valid packets with random bytes changed.
+#[test]
+fn random_inputs() {
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let valid = [datagram(fuzz::UDP_PORT, b"hello"), vec![8, 0, 0, 0, 0, 1, 0, 1]];
+    for _ in 0..2000 {
+        let mut data = if rng.gen() {
+            let len = rng.gen_range(0, 100);
+            rng.gen_iter().take(len).collect::<Vec<u8>>()
+        } else {
+            let mut data = rng.choose(&valid).unwrap().clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(0, data.len());
+                data[i] = rng.gen();
+            }
+            data
+        };
+        let _ = fuzz::ethernet(&data);
+        let _ = fuzz::arp(&data);
+        let _ = fuzz::udp(&data);
+        let _ = fuzz::icmp(&data);
+        let _ = fuzz::ipv4(&data);
+        // As an IPv4 header of random length, with the rest as the payload
+        if !data.is_empty() {
+            data[0] = 0x40 | (data[0] & 0x0f);
+        }
+        let _ = fuzz::ipv4(&data);
+    }
+}
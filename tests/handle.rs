@@ -0,0 +1,143 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::MutablePacket;
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::util::MacAddr;
+
+use rips::{StackError, StackHandle, TxError, testing};
+use rips::ipv4::{BasicIpv4Payload, Ipv4Tx};
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn arp_reply(src: Ipv4Addr, src_mac: MacAddr, dst: Ipv4Addr) -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
+                             ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(src_mac);
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
+        arp_pkg.set_operation(ArpOperations::Reply);
+        arp_pkg.set_sender_hw_addr(src_mac);
+        arp_pkg.set_sender_proto_addr(src);
+        arp_pkg.set_target_proto_addr(dst);
+    }
+    buffer.into_boxed_slice()
+}
+
+#[test]
+fn arp_wait_does_not_lock() {
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let slow = Ipv4Addr::new(10, 0, 0, 2);
+    let slow_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    let known = Ipv4Addr::new(10, 0, 0, 3);
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(known, MacAddr::new(2, 0, 0, 0, 0, 3));
+    let handle = StackHandle::new(stack);
+
+    let (done, result) = mpsc::channel();
+    let thread_handle = handle.clone();
+    thread::spawn(move || {
+        let mut ipv4_tx = thread_handle.ipv4_tx(slow).unwrap();
+        let data = [1, 2];
+        done.send(ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &data)))
+            .unwrap();
+    });
+    thread::sleep(Duration::from_millis(100));
+    let request = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&request).unwrap().get_ethertype(), EtherTypes::Arp);
+    assert!(result.try_recv().is_err());
+
+    // Other threads can use the stack while the first waits for the reply
+    handle.udp_tx(known, 1024, 53).unwrap().send(&[3]).unwrap();
+    assert!(read_handle.try_recv().is_ok());
+    assert_eq!(handle.lock().interfaces(), vec![interface.clone()]);
+
+    inject_handle.send(Ok(arp_reply(slow, slow_mac, ip))).unwrap();
+    result.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+    let pkg = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(EthernetPacket::new(&pkg).unwrap().get_destination(), slow_mac);
+}
+
+#[test]
+fn refreshing_tx() {
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(peer, MacAddr::new(2, 0, 0, 0, 0, 2));
+    let handle = StackHandle::new(stack);
+
+    let mut udp_tx = handle.udp_tx(peer, 1024, 53).unwrap();
+    let mut refreshing_tx = handle.refreshing_udp_tx(peer, 1024, 53).unwrap();
+    refreshing_tx.send_with(|udp_tx| udp_tx.send(&[1])).unwrap();
+    assert!(read_handle.try_recv().is_ok());
+
+    handle.lock().interface(&interface).unwrap().invalidate_tx();
+    match udp_tx.send(&[2]) {
+        Err(TxError::InvalidTx) => (),
+        _ => panic!("Expected the plain UdpTx to be invalidated"),
+    }
+    assert!(refreshing_tx.get_mut().send(&[2]).is_err());
+    refreshing_tx.send_with(|udp_tx| udp_tx.send(&[3])).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(&pkg[pkg.len() - 1..], &[3]);
+    assert!(read_handle.try_recv().is_err());
+}
+
+#[test]
+fn resolve_timeout() {
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let silent = Ipv4Addr::new(10, 0, 0, 2);
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    let mut handle = StackHandle::new(stack);
+    handle.set_resolve_timeout(Duration::from_millis(50));
+
+    let started = Instant::now();
+    match handle.udp_tx(silent, 1024, 53) {
+        Err(StackError::IoError(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Created a tx-object without an Arp reply"),
+    }
+    assert!(started.elapsed() < Duration::from_secs(1));
+    let request = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&request).unwrap().get_ethertype(), EtherTypes::Arp);
+}
+
+#[test]
+fn tx_route_without_stack() {
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(peer, peer_mac);
+    let handle = StackHandle::new(stack);
+
+    let route = handle.tx_route_in_vrf(None, peer).unwrap();
+    assert_eq!(route.interface(), &interface);
+    // Only the interface is locked to create the tx-object
+    let locked = handle.lock();
+    let data = [1];
+    let mut ipv4_tx = route.ipv4_tx().unwrap();
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Udp, &data)).unwrap();
+    drop(locked);
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg).unwrap().get_destination(), peer_mac);
+}
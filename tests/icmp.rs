@@ -13,8 +13,9 @@ use pnet::util::MacAddr;
 
 use rips::Payload;
 use rips::ethernet::EthernetBuilder;
-use rips::icmp::{BasicIcmpPayload, IcmpBuilder, IcmpListener};
+use rips::icmp::{BasicIcmpListener, BasicIcmpPayload, IcmpBuilder, IcmpListener};
 use rips::ipv4::Ipv4Builder;
+use rips::snapshot::ListenerSnapshot;
 use rips::testing;
 
 use std::net::Ipv4Addr;
@@ -71,3 +72,79 @@ fn recv_icmp() {
     let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
     assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::DestinationUnreachable);
 }
+
+#[test]
+fn basic_listener() {
+    let mac = MacAddr::new(0, 0, 0, 0, 0, 0);
+    let remote_ip = Ipv4Addr::new(10, 1, 2, 3);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+
+    let (tx, rx) = mpsc::channel();
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(local_ip, 24).unwrap()).unwrap();
+    stack.icmp_listen(local_ip, IcmpTypes::EchoReply, BasicIcmpListener::new(tx)).unwrap();
+
+    let data = &[1, 2];
+    let payload_builder = BasicIcmpPayload::new(IcmpTypes::EchoReply, IcmpCodes::NoCode, data);
+    let ipv4_builder = Ipv4Builder::new(remote_ip, local_ip, 0, IcmpBuilder::new(payload_builder));
+    let mut eth_builder = EthernetBuilder::new(mac, mac, ipv4_builder);
+    let mut buffer = vec![0; eth_builder.len()];
+    eth_builder.build(&mut buffer);
+    inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+
+    let (_time, from, packet) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(from, remote_ip);
+    let icmp_pkg = IcmpPacket::new(&packet).unwrap();
+    assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::EchoReply);
+}
+
+/// Returns an Ethernet frame with an echo reply from `src` to `dst`.
+fn echo_reply(src: Ipv4Addr, dst: Ipv4Addr) -> Box<[u8]> {
+    let mac = MacAddr::new(0, 0, 0, 0, 0, 0);
+    let data = [7];
+    let payload_builder = BasicIcmpPayload::new(IcmpTypes::EchoReply, IcmpCodes::NoCode, &data);
+    let ipv4_builder = Ipv4Builder::new(src, dst, 0, IcmpBuilder::new(payload_builder));
+    let mut eth_builder = EthernetBuilder::new(mac, mac, ipv4_builder);
+    let mut buffer = vec![0; eth_builder.len()];
+    eth_builder.build(&mut buffer);
+    buffer.into_boxed_slice()
+}
+
+#[test]
+fn wildcard_listener() {
+    let remote_ip = Ipv4Addr::new(10, 1, 2, 3);
+    let (tx, rx) = mpsc::channel();
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 2), 24).unwrap())
+        .unwrap();
+    let any = Ipv4Addr::new(0, 0, 0, 0);
+    stack.icmp_listen(any, IcmpTypes::EchoReply, BasicIcmpListener::new(tx)).unwrap();
+    // Addresses and interfaces added after listening
+    stack.add_ipv4(&interface, Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 3), 24).unwrap())
+        .unwrap();
+    let (channel, interface2, inject_handle2, _) = testing::dummy_ethernet_indexed(1);
+    stack.add_interface(interface2.clone(), channel).unwrap();
+    stack.add_ipv4(&interface2, Ipv4Network::new(Ipv4Addr::new(10, 5, 0, 1), 24).unwrap())
+        .unwrap();
+
+    for &(handle, dst) in &[(&inject_handle, Ipv4Addr::new(10, 0, 0, 2)),
+                            (&inject_handle, Ipv4Addr::new(10, 0, 0, 3)),
+                            (&inject_handle2, Ipv4Addr::new(10, 5, 0, 1))] {
+        handle.send(Ok(echo_reply(remote_ip, dst))).unwrap();
+        let (_time, from, packet) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(from, remote_ip);
+        assert_eq!(IcmpPacket::new(&packet).unwrap().get_icmp_type(), IcmpTypes::EchoReply);
+    }
+    // Only to local addresses
+    inject_handle.send(Ok(echo_reply(remote_ip, Ipv4Addr::new(10, 0, 0, 4)))).unwrap();
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    let snapshot = stack.snapshot();
+    assert!(snapshot.interfaces.iter().all(|interface| {
+        interface.listeners.iter().any(|listener| match *listener {
+            ListenerSnapshot::Icmp(ip, icmp_type, 1) => {
+                ip == any && icmp_type == IcmpTypes::EchoReply
+            }
+            _ => false,
+        })
+    }));
+}
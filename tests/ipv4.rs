@@ -18,7 +18,7 @@ use rips::ipv4::{BasicIpv4Listener, BasicIpv4Payload, Ipv4Rx, Ipv4Tx, Ipv4TxImpl
 
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::{Arc, Mutex, RwLock, mpsc};
 use std::sync::mpsc::Receiver;
 use std::thread;
 use std::time::Duration;
@@ -50,6 +50,155 @@ fn simple_send() {
     assert_eq!(ip_pkg.payload(), [100, 99]);
 }
 
+#[test]
+fn route_change_after_cached_lookup() {
+    let gw1 = Ipv4Addr::new(10, 0, 0, 1);
+    let gw1_mac = MacAddr::new(9, 0, 0, 0, 0, 1);
+    let gw2 = Ipv4Addr::new(10, 0, 0, 2);
+    let gw2_mac = MacAddr::new(9, 0, 0, 0, 0, 2);
+    let remote_ip = Ipv4Addr::new(8, 8, 8, 8);
+
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    {
+        let arp_table = stack.interface(&interface).unwrap().arp_table();
+        arp_table.insert(gw1, gw1_mac);
+        arp_table.insert(gw2, gw2_mac);
+    }
+    stack.add_ipv4(&interface, Ipv4Network::new(*SRC_IP, 24).unwrap()).unwrap();
+    stack.set_default_route(gw1, interface.clone()).unwrap();
+
+    let data = [100, 99];
+    let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data);
+    stack.ipv4_tx(remote_ip).unwrap().send(payload.clone()).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg[..]).unwrap().get_destination(), gw1_mac);
+
+    // Modify the table directly, bypassing the stack, to check that the
+    // routing decision is not served from a stale cache
+    stack.routing_table().set_default_route(gw2, interface.clone()).unwrap();
+    stack.ipv4_tx(remote_ip).unwrap().send(payload).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg[..]).unwrap().get_destination(), gw2_mac);
+}
+
+#[test]
+fn route_mtu() {
+    let (mut stack, mut ipv4_tx, read_handle) = prepare_ipv4_tx(*LAN_DST_IP, *LAN_DST_MAC);
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+    let data = (0..100).collect::<Vec<u8>>();
+
+    stack.set_route_mtu(net, Some(68)).unwrap();
+    assert!(ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data)).is_err());
+    ipv4_tx = stack.ipv4_tx(*LAN_DST_IP).unwrap();
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data)).unwrap();
+    // 100 bytes split into fragments of at most 48 bytes
+    for _ in 0..3 {
+        let pkg = read_handle.try_recv().unwrap();
+        let eth_pkg = EthernetPacket::new(&pkg[..]).unwrap();
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert!(ip_pkg.get_total_length() <= 68);
+    }
+    assert!(read_handle.try_recv().is_err());
+
+    // The path MTU is used when it is smaller than the route MTU
+    stack.set_path_mtu(*LAN_DST_IP, Some(100)).unwrap();
+    stack.set_route_mtu(net, Some(1500)).unwrap();
+    ipv4_tx = stack.ipv4_tx(*LAN_DST_IP).unwrap();
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data)).unwrap();
+    assert!(read_handle.try_recv().is_ok());
+    assert!(read_handle.try_recv().is_ok());
+    assert!(read_handle.try_recv().is_err());
+}
+
+#[test]
+fn jumbo_frames() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.interface(&interface).unwrap().arp_table().insert(*LAN_DST_IP, *LAN_DST_MAC);
+    stack.add_ipv4(&interface, Ipv4Network::new(*SRC_IP, 24).unwrap()).unwrap();
+    assert!(stack.interface(&interface).unwrap().set_mtu(rips::MAX_MTU + 1).is_err());
+    assert!(stack.interface(&interface).unwrap().set_mtu(67).is_err());
+    assert_eq!(stack.interface(&interface).unwrap().get_mtu(), rips::DEFAULT_MTU);
+
+    stack.interface(&interface).unwrap().set_mtu(rips::MAX_MTU).unwrap();
+    let data = vec![7; rips::MAX_MTU - 20];
+    let mut ipv4_tx = stack.ipv4_tx(*LAN_DST_IP).unwrap();
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data)).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(pkg.len(), 14 + rips::MAX_MTU);
+    let eth_pkg = EthernetPacket::new(&pkg[..]).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_total_length() as usize, rips::MAX_MTU);
+    assert_eq!(ip_pkg.payload(), &data[..]);
+    assert!(read_handle.try_recv().is_err());
+}
+
+#[test]
+fn vrf_isolation() {
+    let dst_mac = MacAddr::new(9, 0, 0, 0, 0, 5);
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let (channel, vrf_interface, _, vrf_read_handle) = testing::dummy_ethernet_indexed(1);
+    stack.add_interface(vrf_interface.clone(), channel).unwrap();
+    stack.create_vrf("red").unwrap();
+    assert!(stack.create_vrf("red").is_err());
+
+    stack.set_interface_vrf(&vrf_interface, Some("red")).unwrap();
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+    stack.add_ipv4(&interface, net).unwrap();
+    stack.add_ipv4(&vrf_interface, net).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(*LAN_DST_IP, *LAN_DST_MAC);
+    stack.interface(&vrf_interface).unwrap().arp_table().insert(*LAN_DST_IP, dst_mac);
+    assert_eq!(stack.interface_vrf(&vrf_interface), Some("red"));
+    assert_eq!(stack.routing_table().routes().len(), 1);
+    assert_eq!(stack.vrf_routing_table("red").unwrap().routes().len(), 1);
+
+    // The same destination is routed differently depending on the VRF
+    let data = [100, 99];
+    let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data);
+    stack.ipv4_tx(*LAN_DST_IP).unwrap().send(payload.clone()).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg[..]).unwrap().get_destination(), *LAN_DST_MAC);
+    stack.ipv4_tx_in_vrf(Some("red"), *LAN_DST_IP).unwrap().send(payload).unwrap();
+    let pkg = vrf_read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg[..]).unwrap().get_destination(), dst_mac);
+
+    let remote_ip = Ipv4Addr::new(8, 8, 8, 8);
+    stack.set_default_route(*LAN_DST_IP, vrf_interface.clone()).unwrap();
+    assert!(stack.ipv4_tx(remote_ip).is_err());
+    assert!(stack.ipv4_tx_in_vrf(Some("red"), remote_ip).is_ok());
+    assert!(stack.ipv4_tx_in_vrf(Some("blue"), remote_ip).is_err());
+
+    // Removing the VRF brings its routes back to the main table
+    stack.remove_vrf("red").unwrap();
+    assert_eq!(stack.interface_vrf(&vrf_interface), None);
+    assert!(stack.ipv4_tx(remote_ip).is_ok());
+}
+
+#[test]
+fn spoofed_source() {
+    let (mut stack, _, read_handle) = prepare_ipv4_tx(*LAN_DST_IP, *LAN_DST_MAC);
+    let spoofed = Ipv4Addr::new(192, 0, 2, 9);
+    assert!(stack.ipv4_tx_with_src(spoofed, *LAN_DST_IP).is_err());
+
+    let mut ipv4_tx = stack.ipv4_tx_with_spoofed_src(spoofed, *LAN_DST_IP).unwrap();
+    assert_eq!(ipv4_tx.src(), spoofed);
+    ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &[100, 99])).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg[..]).unwrap();
+    assert_eq!(eth_pkg.get_destination(), *LAN_DST_MAC);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), spoofed);
+    assert_eq!(ip_pkg.get_destination(), *LAN_DST_IP);
+    assert_eq!(ip_pkg.get_checksum(), checksum(&ip_pkg));
+
+    // Other tx-objects keep the address selected by the routing
+    let data = [1];
+    let payload = BasicIpv4Payload::new(IpNextHeaderProtocols::Igmp, &data);
+    stack.ipv4_tx(*LAN_DST_IP).unwrap().send(payload).unwrap();
+    let pkg = read_handle.try_recv().unwrap();
+    let ip_pkg = Ipv4Packet::new(&pkg[14..]).unwrap();
+    assert_eq!(ip_pkg.get_source(), *SRC_IP);
+}
+
 fn prepare_ipv4_tx
     (dst_ip: Ipv4Addr,
      dst_mac: MacAddr)
@@ -73,13 +222,13 @@ fn custom_igmp_recv() {
     let (tx, rx) = mpsc::channel();
     let ipv4_listener = BasicIpv4Listener::new(tx);
     let mut ipv4_ip_listeners = HashMap::new();
-    ipv4_ip_listeners.insert(IpNextHeaderProtocols::Igmp, ipv4_listener);
+    ipv4_ip_listeners.insert(IpNextHeaderProtocols::Igmp, Mutex::new(ipv4_listener));
 
     let mut ipv4_listeners = HashMap::new();
     ipv4_listeners.insert(*LAN_DST_IP, ipv4_ip_listeners);
 
     let (channel, _interface, inject_handle, _) = testing::dummy_ethernet();
-    let ipv4_rx = Ipv4Rx::new(Arc::new(Mutex::new(ipv4_listeners)));
+    let ipv4_rx = Ipv4Rx::new(Arc::new(RwLock::new(ipv4_listeners)));
     let ethernet_rx = EthernetRx::new(vec![ipv4_rx]);
     rx::spawn(channel.1, ethernet_rx);
 
@@ -0,0 +1,90 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
+use pnet::util::MacAddr;
+
+use rips::{StackError, testing};
+use rips::ethernet::{BasicEthernetListener, BasicEthernetPayload, EthernetTx};
+
+use std::net::Ipv4Addr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn macvlan_arp_reply() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let a = MacAddr::new(2, 0, 0, 0, 0, 0xa);
+    let b = MacAddr::new(2, 0, 0, 0, 0, 0xb);
+    let mv0 = stack.add_macvlan(&interface, "mv0", a).unwrap();
+    let mv1 = stack.add_macvlan(&interface, "mv1", b).unwrap();
+    assert_eq!(mv1.mac, b);
+    match stack.add_macvlan(&interface, "mv2", b) {
+        Err(StackError::AlreadyExists(..)) => (),
+        _ => panic!("MAC already taken"),
+    }
+    assert!(stack.add_macvlan(&interface, "mv2", interface.mac).is_err());
+    assert!(stack.add_macvlan(&interface, "mv1", MacAddr::new(2, 0, 0, 0, 0, 0xc)).is_err());
+    for (mv, ip) in vec![(&mv0, Ipv4Addr::new(10, 0, 0, 10)), (&mv1, Ipv4Addr::new(10, 0, 0, 11))] {
+        stack.add_ipv4(mv, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    }
+
+    // Only the MACVLAN with the address replies, from its own MAC
+    inject_handle.send(Ok(testing::arp_request(Ipv4Addr::new(10, 0, 0, 11)))).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let reply = read_handle.try_recv().unwrap();
+    assert!(read_handle.try_recv().is_err());
+    let eth_pkg = EthernetPacket::new(&reply).unwrap();
+    assert_eq!(eth_pkg.get_source(), b);
+    let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+    assert_eq!(arp_pkg.get_operation(), ArpOperations::Reply);
+    assert_eq!(arp_pkg.get_sender_hw_addr(), b);
+}
+
+#[test]
+fn macvlan_demux() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let a = MacAddr::new(2, 0, 0, 0, 0, 0xa);
+    let b = MacAddr::new(2, 0, 0, 0, 0, 0xb);
+    let broadcast = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+    let mv0 = stack.add_macvlan(&interface, "mv0", a).unwrap();
+    let mv1 = stack.add_macvlan(&interface, "mv1", b).unwrap();
+    let mut macvlans = stack.interface(&interface).unwrap().macvlans();
+    macvlans.sort_by_key(|mac| mac.5);
+    assert_eq!(macvlans, vec![a, b]);
+
+    let ptp = EtherType(0x88f7);
+    let (parent_tx, parent_rx) = mpsc::channel();
+    let (tx, rx) = mpsc::channel();
+    stack.ethernet_listen(&interface, BasicEthernetListener::new(ptp, parent_tx)).unwrap();
+    stack.ethernet_listen(&mv1, BasicEthernetListener::new(ptp, tx)).unwrap();
+    let received = || {
+        thread::sleep(Duration::from_millis(100));
+        (parent_rx.try_iter().count(), rx.try_iter().count(), read_handle.try_iter().count())
+    };
+
+    // From the link, to the MACVLAN only unless it is broadcast
+    for &dst in &[b, broadcast] {
+        let mut frame = vec![0; 60];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut frame[..]).unwrap();
+            eth_pkg.set_destination(dst);
+            eth_pkg.set_ethertype(ptp);
+        }
+        inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    }
+    assert_eq!(received(), (1, 2, 0));
+
+    // Between siblings directly, broadcast also out on the link
+    for &dst in &[b, broadcast] {
+        let mut ethernet_tx = stack.interface(&mv0).unwrap().ethernet_tx(dst);
+        ethernet_tx.send(1, 46, BasicEthernetPayload::new(ptp, &[0; 46])).unwrap();
+    }
+    assert_eq!(received(), (0, 2, 1));
+}
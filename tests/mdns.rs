@@ -0,0 +1,63 @@
+extern crate rips;
+
+use rips::{StackError, testing};
+use rips::dns::mdns::{self, MdnsConfig, MdnsHandle, Service, ServiceInstance};
+
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+fn wait_claimed(handles: &[&MdnsHandle]) {
+    for _ in 0..200 {
+        if handles.iter().all(|handle| handle.is_claimed()) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("Names not claimed");
+}
+
+#[test]
+fn name_defense_and_browsing() {
+    let (stack1, stack2) = testing::connected_stacks(1, 2);
+    let (ip1, ip2) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+    let mut config = MdnsConfig::new("sensor");
+    let mut web = Service::new("Sensor web", "_http._tcp", 80);
+    web.txt = vec!["path=/".to_owned(), "v=1".to_owned()];
+    config.services.push(web);
+    config.services.push(Service::new("Sensor shell", "_ssh._tcp", 22));
+    let mut responder1 = mdns::spawn(stack1.clone(), ip1, config).unwrap();
+    let responder2 = mdns::spawn(stack2.clone(), ip2, MdnsConfig::new("sensor")).unwrap();
+
+    // Both probe for sensor.local, only one keeps it
+    wait_claimed(&[&responder1, &responder2]);
+    thread::sleep(Duration::from_millis(500));
+    wait_claimed(&[&responder1, &responder2]);
+    let mut names = vec![responder1.host_name(), responder2.host_name()];
+    names.sort();
+    assert_eq!(names, vec!["sensor-2.local".to_owned(), "sensor.local".to_owned()]);
+    assert_eq!(responder1.services()[0].instance, "Sensor web");
+
+    let wait = Duration::from_millis(500);
+    let instances = mdns::browse(stack2.clone(), ip2, "_http._tcp", wait).unwrap();
+    assert_eq!(instances,
+               vec![ServiceInstance {
+                        instance: "Sensor web".to_owned(),
+                        service_type: "_http._tcp".to_owned(),
+                        host: Some((responder1.host_name(), 80)),
+                        txt: vec!["path=/".to_owned(), "v=1".to_owned()],
+                        addresses: vec![ip1],
+                    }]);
+    assert!(mdns::browse(stack2.clone(), ip2, "_ipp._tcp", wait).unwrap().is_empty());
+    let addresses = mdns::lookup_host(stack2.clone(), ip2, &responder1.host_name(), wait);
+    assert_eq!(addresses.unwrap(), vec![ip1]);
+    let addresses = mdns::lookup_host(stack1.clone(), ip1, &responder2.host_name(), wait);
+    assert_eq!(addresses.unwrap(), vec![ip2]);
+    match mdns::lookup_host(stack2.clone(), ip2, "printer.local", wait) {
+        Err(StackError::NotFound(_)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+
+    responder1.stop();
+    assert!(mdns::browse(stack2, ip2, "_http._tcp", wait).unwrap().is_empty());
+}
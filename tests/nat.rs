@@ -0,0 +1,105 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::util::MacAddr;
+
+use rips::{ipv4, testing};
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+
+fn send_udp(inject: &Sender<io::Result<Box<[u8]>>>,
+            dst_mac: MacAddr,
+            src: SocketAddrV4,
+            dst: SocketAddrV4) {
+    let mut buffer = vec![0; 14 + 20 + 8 + 3];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(dst_mac);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_total_length(20 + 8 + 3);
+        ip_pkg.set_source(*src.ip());
+        ip_pkg.set_destination(*dst.ip());
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(src.port());
+        udp_pkg.set_destination(dst.port());
+        udp_pkg.set_length(8 + 3);
+        udp_pkg.set_payload(&[1, 2, 3]);
+        let csum = udp::ipv4_checksum(&udp_pkg.to_immutable(), *src.ip(), *dst.ip());
+        udp_pkg.set_checksum(csum);
+    }
+    inject.send(Ok(buffer.into_boxed_slice())).unwrap();
+}
+
+fn assert_udp(frame: &[u8], dst_mac: MacAddr, src: SocketAddrV4, dst: SocketAddrV4) {
+    let eth_pkg = EthernetPacket::new(frame).unwrap();
+    assert_eq!(eth_pkg.get_destination(), dst_mac);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), *src.ip());
+    assert_eq!(ip_pkg.get_destination(), *dst.ip());
+    assert_eq!(ip_pkg.get_ttl(), 63);
+    assert_eq!(ip_pkg.get_checksum(), checksum(&ip_pkg));
+    let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(udp_pkg.get_source(), src.port());
+    assert_eq!(udp_pkg.get_destination(), dst.port());
+    assert_eq!(udp_pkg.get_checksum(),
+               udp::ipv4_checksum(&udp_pkg, *src.ip(), *dst.ip()));
+    assert_eq!(udp_pkg.payload(), &[1, 2, 3]);
+}
+
+#[test]
+fn masquerade() {
+    let host = SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 10), 5000);
+    let server = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+    let external = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 5000);
+    let gw = Ipv4Addr::new(1, 2, 3, 1);
+    let host_mac = MacAddr::new(2, 0, 0, 0, 0, 10);
+    let gw_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+
+    let (mut stack, inside, inside_inject, inside_read) = testing::dummy_stack();
+    let (channel, outside, outside_inject, outside_read) = testing::dummy_ethernet_indexed(1);
+    stack.add_interface(outside.clone(), channel).unwrap();
+    stack.add_ipv4(&inside, Ipv4Network::from_str("192.168.0.1/24").unwrap()).unwrap();
+    stack.add_ipv4(&outside, Ipv4Network::from_str("1.2.3.4/24").unwrap()).unwrap();
+    stack.set_default_route(gw, outside.clone()).unwrap();
+    stack.interface(&inside).unwrap().arp_table().insert(*host.ip(), host_mac);
+    stack.interface(&outside).unwrap().arp_table().insert(gw, gw_mac);
+    let nat = stack.add_nat(&outside).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    ipv4::spawn_forwarding(stack.clone());
+
+    send_udp(&inside_inject, inside.mac, host, server);
+    assert_udp(&outside_read.recv().unwrap(), gw_mac, external, server);
+    let mappings = nat.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].inside, host);
+    assert_eq!(mappings[0].external_port, 5000);
+
+    send_udp(&outside_inject, outside.mac, server, external);
+    assert_udp(&inside_read.recv().unwrap(), host_mac, server, host);
+    let connections = nat.conntrack().connections();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].original.src, host);
+    assert!(connections[0].replied);
+
+    stack.lock().unwrap().disable_forwarding();
+    assert!(!stack.lock().unwrap().is_forwarding());
+}
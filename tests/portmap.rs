@@ -0,0 +1,230 @@
+extern crate rips;
+
+use rips::{ErrorKind, NetworkStack, StackError, ethernet, testing};
+use rips::portmap::{Mapping, PortMapClient, PortMapConfig, Protocol, Version};
+use rips::portmap::ssdp::{self, IGD_SEARCH_TARGET};
+use rips::testing::ip;
+use rips::udp::UdpSocket;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Returns a gateway stack with `ip(gateway)`, in the SSDP group, and a
+/// stack with 10.0.0.2 connected to it.
+fn stacks(gateway: u8) -> (Arc<Mutex<NetworkStack>>, Arc<Mutex<NetworkStack>>) {
+    let ((mut gateway_stack, gateway_interface), (client_stack, _)) =
+        testing::connected_pair(gateway, 2);
+    let group_mac = ethernet::ipv4_multicast_mac(ssdp::group());
+    gateway_stack.interface(&gateway_interface).unwrap().join_multicast(group_mac).unwrap();
+    (Arc::new(Mutex::new(gateway_stack)), Arc::new(Mutex::new(client_stack)))
+}
+
+#[derive(Default)]
+struct Gateway {
+    requests: usize,
+    /// The nonces, for PCP, of the mapped protocols and internal ports
+    mappings: HashMap<(u8, u16), Vec<u8>>,
+}
+
+fn port(packet: &[u8], offset: usize) -> u16 {
+    (packet[offset] as u16) << 8 | packet[offset + 1] as u16
+}
+
+/// Answers mapping requests to `gateway_ip` the way a gateway speaking PCP,
+/// or else only NAT-PMP, would. Drops the first request, and refuses to map
+/// port 9999.
+fn serve(stack: Arc<Mutex<NetworkStack>>, gateway_ip: Ipv4Addr, pcp: bool) -> Arc<Mutex<Gateway>> {
+    let gateway = Arc::new(Mutex::new(Gateway::default()));
+    let thread_gateway = gateway.clone();
+    let mut socket = UdpSocket::bind(stack, SocketAddrV4::new(gateway_ip, 5351)).unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 1100];
+        while let Ok((len, src)) = socket.recv_from(&mut buffer) {
+            let request = &buffer[..len];
+            let mut gateway = thread_gateway.lock().unwrap();
+            gateway.requests += 1;
+            if gateway.requests == 1 {
+                continue;
+            }
+            let reply = if request[0] == 2 && pcp {
+                let mut reply = request.to_vec();
+                reply[1] |= 0x80;
+                let key = (request[36], port(request, 40));
+                let lifetime = (port(request, 4) as u32) << 16 | port(request, 6) as u32;
+                let nonce = request[24..36].to_vec();
+                let known = gateway.mappings.get(&key).map_or(true, |known| *known == nonce);
+                reply[3] = if key.1 == 9999 || !known { 2 } else { 0 };
+                if reply[3] == 0 && lifetime == 0 {
+                    gateway.mappings.remove(&key);
+                } else if reply[3] == 0 {
+                    gateway.mappings.insert(key, nonce);
+                }
+                let lifetime = if lifetime > 3600 { 3600 } else { lifetime };
+                reply[4..8].copy_from_slice(&[0, 0, (lifetime >> 8) as u8, lifetime as u8]);
+                for byte in &mut reply[8..24] {
+                    *byte = 0;
+                }
+                if port(request, 42) == 0 && lifetime > 0 {
+                    reply[42..44].copy_from_slice(&[0x9c, 0x40]);
+                }
+                reply[56..60].copy_from_slice(&[203, 0, 113, 7]);
+                reply
+            } else if request[0] != 0 {
+                vec![0, 0x80 | request[1], 0, 1, 0, 0, 0, 9]
+            } else if request[1] == 0 {
+                let external = if pcp { [203, 0, 113, 7] } else { [198, 51, 100, 9] };
+                let mut reply = vec![0, 0x80, 0, 0, 0, 0, 0, 9];
+                reply.extend_from_slice(&external);
+                reply
+            } else {
+                let key = (request[1], port(request, 4));
+                let mut reply = vec![0, 0x80 | request[1], 0, if key.1 == 9999 { 2 } else { 0 }];
+                reply.extend_from_slice(&[0, 0, 0, 9]);
+                reply.extend_from_slice(&request[4..8]);
+                if port(request, 6) == 0 && request[8..12] != [0, 0, 0, 0] {
+                    reply[10..12].copy_from_slice(&[0xc3, 0x50]);
+                }
+                reply.extend_from_slice(&request[8..12]);
+                if request[8..12] == [0, 0, 0, 0] {
+                    gateway.mappings.remove(&key);
+                } else {
+                    gateway.mappings.insert(key, Vec::new());
+                }
+                reply
+            };
+            socket.send_to(&reply, src).unwrap();
+        }
+    });
+    gateway
+}
+
+fn config() -> PortMapConfig {
+    let mut config = PortMapConfig::default();
+    config.initial_timeout = Duration::from_millis(50);
+    config.attempts = 3;
+    config
+}
+
+#[test]
+fn pcp() {
+    let (gateway_stack, client_stack) = stacks(1);
+    let gateway = serve(gateway_stack, ip(1), true);
+    let client = PortMapClient::new(client_stack, ip(2), ip(1), config()).unwrap();
+    assert_eq!(client.version(), None);
+
+    let mapping = client.map(Protocol::Udp, 4000, 0, Duration::from_secs(7200)).unwrap();
+    assert_eq!(mapping,
+               Mapping {
+                   protocol: Protocol::Udp,
+                   internal_port: 4000,
+                   external: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 40000),
+                   lifetime: Duration::from_secs(3600),
+                   version: Version::Pcp,
+               });
+    assert_eq!(client.version(), Some(Version::Pcp));
+    // Sent again after the first was dropped
+    assert_eq!(gateway.lock().unwrap().requests, 2);
+    // Renewed with the same nonce
+    let renewed = client.map(Protocol::Udp, 4000, 0, Duration::from_secs(60)).unwrap();
+    assert_eq!(renewed.lifetime, Duration::from_secs(60));
+    let tcp = client.map(Protocol::Tcp, 4000, 4000, Duration::from_secs(60)).unwrap();
+    assert_eq!(tcp.external.port(), 4000);
+    assert_eq!(gateway.lock().unwrap().mappings.len(), 2);
+    client.unmap(&mapping).unwrap();
+    client.unmap(&tcp).unwrap();
+    assert!(gateway.lock().unwrap().mappings.is_empty());
+
+    match client.map(Protocol::Udp, 9999, 0, Duration::from_secs(60)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Refused),
+        Ok(mapping) => panic!("Mapped {:?}", mapping),
+    }
+    match client.map(Protocol::Udp, 0, 0, Duration::from_secs(60)) {
+        Err(StackError::InvalidArgument(_)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(client.external_address().unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+}
+
+#[test]
+fn nat_pmp() {
+    let (gateway_stack, client_stack) = stacks(3);
+    let gateway = serve(gateway_stack, ip(3), false);
+    let client = PortMapClient::new(client_stack.clone(), ip(2), ip(3), config()).unwrap();
+
+    let mapping = client.map(Protocol::Tcp, 8080, 80, Duration::from_secs(60)).unwrap();
+    assert_eq!(mapping.external, SocketAddrV4::new(Ipv4Addr::new(198, 51, 100, 9), 80));
+    assert_eq!(mapping.lifetime, Duration::from_secs(60));
+    assert_eq!(mapping.version, Version::NatPmp);
+    assert_eq!(client.version(), Some(Version::NatPmp));
+    let any = client.map(Protocol::Udp, 5000, 0, Duration::from_secs(60)).unwrap();
+    assert_eq!(any.external.port(), 50000);
+    assert_eq!(gateway.lock().unwrap().mappings.len(), 2);
+    client.unmap(&mapping).unwrap();
+    client.unmap(&any).unwrap();
+    assert!(gateway.lock().unwrap().mappings.is_empty());
+    match client.map(Protocol::Tcp, 9999, 0, Duration::from_secs(60)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Refused),
+        Ok(mapping) => panic!("Mapped {:?}", mapping),
+    }
+
+    let mut pcp_only = config();
+    pcp_only.version = Some(Version::Pcp);
+    let client = PortMapClient::new(client_stack.clone(), ip(2), ip(3), pcp_only).unwrap();
+    assert!(client.map(Protocol::Tcp, 8080, 80, Duration::from_secs(60)).is_err());
+
+    let (gateway_stack, client_stack) = stacks(4);
+    let _silent = UdpSocket::bind(gateway_stack, SocketAddrV4::new(ip(4), 5351)).unwrap();
+    let client = PortMapClient::new(client_stack.clone(), ip(2), ip(4), config()).unwrap();
+    match client.map(Protocol::Tcp, 8080, 80, Duration::from_secs(60)) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::Busy),
+        Ok(mapping) => panic!("Mapped {:?}", mapping),
+    }
+    let mut no_attempts = config();
+    no_attempts.attempts = 0;
+    assert!(PortMapClient::new(client_stack, ip(2), ip(4), no_attempts).is_err());
+}
+
+#[test]
+fn ssdp_search() {
+    let (gateway_stack, client_stack) = stacks(1);
+    let mut socket = UdpSocket::bind(gateway_stack, SocketAddrV4::new(ip(1), 1900)).unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 1500];
+        while let Ok((len, src)) = socket.recv_from(&mut buffer) {
+            let request = str::from_utf8(&buffer[..len]).unwrap().to_owned();
+            if !request.starts_with("M-SEARCH * HTTP/1.1\r\n") ||
+               !request.contains(&format!("\r\nST: {}\r\n", IGD_SEARCH_TARGET)) {
+                continue;
+            }
+            let response = format!(concat!("HTTP/1.1 200 OK\r\n",
+                                           "CACHE-CONTROL: max-age=120\r\n",
+                                           "LOCATION: http://10.0.0.1:5000/rootDesc.xml\r\n",
+                                           "ST: {}\r\n",
+                                           "USN: uuid:gateway::{}\r\n\r\n"),
+                                   IGD_SEARCH_TARGET,
+                                   IGD_SEARCH_TARGET);
+            // Twice, as devices often do
+            for _ in 0..2 {
+                socket.send_to(response.as_bytes(), src).unwrap();
+            }
+        }
+    });
+
+    let wait = Duration::from_millis(500);
+    let found = ssdp::search(client_stack.clone(), ip(2), IGD_SEARCH_TARGET, wait).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(&found[0].location[..], "http://10.0.0.1:5000/rootDesc.xml");
+    assert_eq!(found[0].max_age, Some(Duration::from_secs(120)));
+    assert_eq!(found[0].src, SocketAddrV4::new(ip(1), 1900));
+
+    let other = "urn:schemas-upnp-org:device:MediaServer:1";
+    assert!(ssdp::search(client_stack.clone(), ip(2), other, wait).unwrap().is_empty());
+    match ssdp::search(client_stack, ip(2), "ssdp:all\r\nX: y", wait) {
+        Err(StackError::InvalidArgument(_)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
@@ -0,0 +1,223 @@
+extern crate pnet;
+extern crate rips;
+
+use pnet::packet::{MutablePacket, Packet as PnetPacket};
+use pnet::packet::ethernet::{EtherType, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
+use pnet::util::MacAddr;
+
+use rips::{StackError, testing};
+use rips::pppoe::{self, Packet, PppoeConfig, Tag};
+use rips::pppoe::ppp::{self, ConfigOption, ControlPacket};
+use rips::udp::UdpSocket;
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+const SESSION_ID: u16 = 0x42;
+
+/// The access concentrator end of the dummy interface of a stack.
+struct Ac {
+    mac: MacAddr,
+    client: MacAddr,
+    inject: Sender<io::Result<Box<[u8]>>>,
+    read: Receiver<Box<[u8]>>,
+}
+
+impl Ac {
+    fn send(&self, ether_type: EtherType, packet: &Packet) {
+        let data = packet.to_bytes();
+        let mut buffer = vec![0; 14 + data.len()];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            eth_pkg.set_source(self.mac);
+            eth_pkg.set_destination(self.client);
+            eth_pkg.set_ethertype(ether_type);
+            eth_pkg.payload_mut().copy_from_slice(&data);
+        }
+        self.inject.send(Ok(buffer.into_boxed_slice())).unwrap();
+    }
+
+    fn send_control(&self, protocol: u16, packet: &ControlPacket) {
+        self.send(pppoe::SESSION,
+                  &Packet::session(SESSION_ID, protocol, &packet.to_bytes()));
+    }
+
+    fn recv(&self) -> (EtherType, MacAddr, Packet) {
+        let frame = self.read.recv_timeout(Duration::from_secs(2)).unwrap();
+        let eth_pkg = EthernetPacket::new(&frame).unwrap();
+        (eth_pkg.get_ethertype(),
+         eth_pkg.get_destination(),
+         Packet::parse(eth_pkg.payload()).unwrap())
+    }
+
+    fn recv_discovery(&self, code: u8) -> Vec<Tag> {
+        let (ether_type, _, packet) = self.recv();
+        assert_eq!((ether_type, packet.code), (pppoe::DISCOVERY, code));
+        pppoe::parse_tags(&packet.payload).unwrap()
+    }
+
+    fn recv_control(&self, protocol: u16) -> ControlPacket {
+        let (ether_type, dst, packet) = self.recv();
+        assert_eq!((ether_type, dst, packet.session_id), (pppoe::SESSION, self.mac, SESSION_ID));
+        let (received, data) = packet.ppp().unwrap();
+        assert_eq!(received, protocol);
+        ControlPacket::parse(data).unwrap()
+    }
+}
+
+fn configure(code: u8, id: u8, options: &[ConfigOption]) -> ControlPacket {
+    ControlPacket::new(code, id, ppp::options_to_bytes(options))
+}
+
+fn udp_packet(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; 28 + payload.len()];
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut buffer[..]).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_total_length(28 + payload.len() as u16);
+        ip_pkg.set_source(*src.ip());
+        ip_pkg.set_destination(*dst.ip());
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        {
+            let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+            udp_pkg.set_source(src.port());
+            udp_pkg.set_destination(dst.port());
+            udp_pkg.set_length(8 + payload.len() as u16);
+            udp_pkg.set_payload(payload);
+        }
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    buffer
+}
+
+#[test]
+fn pppoe_session() {
+    let (stack, ethernet, inject, read) = testing::dummy_stack();
+    let stack = Arc::new(Mutex::new(stack));
+    let ac = Ac {
+        mac: MacAddr::new(2, 0, 0, 0, 0, 0xac),
+        client: ethernet.mac,
+        inject: inject,
+        read: read,
+    };
+    let mut config = PppoeConfig::default();
+    config.credentials = Some(("user".to_owned(), "secret".to_owned()));
+    config.timeout = Duration::from_secs(1);
+    config.echo_interval = None;
+    let client = {
+        let stack = stack.clone();
+        let ethernet = ethernet.clone();
+        thread::spawn(move || pppoe::connect(stack, &ethernet, "ppp0", &config))
+    };
+
+    // Discovery, with the cookie of the offer echoed in the request
+    let padi = ac.recv_discovery(pppoe::PADI);
+    let host_uniq = padi.iter().find(|tag| tag.kind == pppoe::HOST_UNIQ).unwrap().clone();
+    let cookie = Tag::new(pppoe::AC_COOKIE, vec![1, 2, 3]);
+    let offer = vec![Tag::new(pppoe::SERVICE_NAME, vec![]),
+                     Tag::new(pppoe::AC_NAME, b"ac".to_vec()),
+                     host_uniq.clone(),
+                     cookie.clone()];
+    ac.send(pppoe::DISCOVERY,
+            &Packet::new(pppoe::PADO, 0, pppoe::tags_to_bytes(&offer)));
+    let padr = ac.recv_discovery(pppoe::PADR);
+    assert!(padr.contains(&cookie));
+    let confirmation = vec![Tag::new(pppoe::SERVICE_NAME, vec![]), host_uniq];
+    ac.send(pppoe::DISCOVERY,
+            &Packet::new(pppoe::PADS, SESSION_ID, pppoe::tags_to_bytes(&confirmation)));
+
+    // LCP, requiring PAP
+    let request = ac.recv_control(ppp::LCP);
+    assert_eq!(request.code, ppp::CONFIGURE_REQUEST);
+    ac.send_control(ppp::LCP,
+                    &ControlPacket::new(ppp::CONFIGURE_ACK, request.id, request.data));
+    let options = [ConfigOption::u16(ppp::LCP_MRU, 1492),
+                   ConfigOption::u16(ppp::LCP_AUTH, ppp::PAP),
+                   ConfigOption::u32(ppp::LCP_MAGIC, 0x11223344)];
+    ac.send_control(ppp::LCP, &configure(ppp::CONFIGURE_REQUEST, 1, &options));
+    assert_eq!(ac.recv_control(ppp::LCP).code, ppp::CONFIGURE_ACK);
+    let auth = ac.recv_control(ppp::PAP);
+    assert_eq!(auth, ppp::pap_request(auth.id, "user", "secret"));
+    ac.send_control(ppp::PAP, &ControlPacket::new(ppp::PAP_ACK, auth.id, vec![]));
+
+    // IPCP, assigning an address and DNS servers
+    let request = ac.recv_control(ppp::IPCP);
+    let address = Ipv4Addr::new(10, 0, 0, 2);
+    let peer = Ipv4Addr::new(10, 0, 0, 1);
+    let naks = [ConfigOption::ipv4(ppp::IPCP_ADDRESS, address),
+                ConfigOption::ipv4(ppp::IPCP_PRIMARY_DNS, Ipv4Addr::new(10, 0, 0, 53)),
+                ConfigOption::ipv4(ppp::IPCP_SECONDARY_DNS, Ipv4Addr::new(10, 0, 0, 54))];
+    ac.send_control(ppp::IPCP, &configure(ppp::CONFIGURE_NAK, request.id, &naks));
+    let request = ac.recv_control(ppp::IPCP);
+    assert_eq!(ppp::parse_options(&request.data).unwrap(), naks.to_vec());
+    ac.send_control(ppp::IPCP,
+                    &ControlPacket::new(ppp::CONFIGURE_ACK, request.id, request.data));
+    let options = [ConfigOption::ipv4(ppp::IPCP_ADDRESS, peer)];
+    ac.send_control(ppp::IPCP, &configure(ppp::CONFIGURE_REQUEST, 2, &options));
+    assert_eq!(ac.recv_control(ppp::IPCP).code, ppp::CONFIGURE_ACK);
+
+    let session = client.join().unwrap().unwrap();
+    assert_eq!((session.session_id(), session.access_concentrator()), (SESSION_ID, ac.mac));
+    assert_eq!((session.address(), session.peer()), (address, peer));
+    assert_eq!(session.dns_servers(),
+               &[Ipv4Addr::new(10, 0, 0, 53), Ipv4Addr::new(10, 0, 0, 54)]);
+    let mtu = stack.lock().unwrap().interface(session.interface()).unwrap().get_mtu();
+    assert_eq!(mtu, 1492);
+
+    // Echo requests are answered
+    let echo = ControlPacket::new(ppp::ECHO_REQUEST, 9, vec![0x11, 0x22, 0x33, 0x44]);
+    ac.send_control(ppp::LCP, &echo);
+    let reply = ac.recv_control(ppp::LCP);
+    assert_eq!((reply.code, reply.id), (ppp::ECHO_REPLY, 9));
+
+    // IPv4 goes both ways, out through the default route
+    let local = SocketAddrV4::new(address, 1024);
+    let remote = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 53);
+    let mut socket = UdpSocket::bind(stack.clone(), local).unwrap();
+    socket.send_to(&[1, 2, 3], remote).unwrap();
+    let (ether_type, _, packet) = ac.recv();
+    assert_eq!(ether_type, pppoe::SESSION);
+    let (protocol, data) = packet.ppp().unwrap();
+    assert_eq!(protocol, ppp::IPV4);
+    let ip_pkg = Ipv4Packet::new(data).unwrap();
+    assert_eq!((ip_pkg.get_source(), ip_pkg.get_destination()), (address, *remote.ip()));
+    assert_eq!(UdpPacket::new(ip_pkg.payload()).unwrap().payload(), &[1, 2, 3]);
+
+    let reply = udp_packet(remote, local, &[4, 5]);
+    ac.send(pppoe::SESSION, &Packet::session(SESSION_ID, ppp::IPV4, &reply));
+    let mut buffer = vec![0; 2];
+    let (len, from) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!((&buffer[..len], from), (&[4, 5][..], SocketAddr::V4(remote)));
+
+    session.close().unwrap();
+    assert!(!session.is_up());
+    assert_eq!(ac.recv_control(ppp::LCP).code, ppp::TERMINATE_REQUEST);
+    assert_eq!(ac.recv().2, Packet::new(pppoe::PADT, SESSION_ID, vec![]));
+}
+
+#[test]
+fn pppoe_no_access_concentrator() {
+    let (stack, ethernet, _inject, read) = testing::dummy_stack();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut config = PppoeConfig::default();
+    config.timeout = Duration::from_millis(50);
+    config.attempts = 2;
+    match pppoe::connect(stack.clone(), &ethernet, "ppp0", &config) {
+        Err(StackError::IoError(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+        _ => panic!("Expected a timeout"),
+    }
+    assert_eq!(read.try_iter().count(), 2);
+    // The listeners were removed, so the client can try again
+    assert!(pppoe::connect(stack, &ethernet, "ppp0", &config).is_err());
+    assert_eq!(read.try_iter().count(), 2);
+}
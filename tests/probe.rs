@@ -0,0 +1,153 @@
+extern crate ipnetwork;
+extern crate pnet;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::icmp::IcmpTypes;
+use pnet::packet::ip::IpNextHeaderProtocols;
+
+use rips::{NetworkStack, StackError, checksum, testing};
+use rips::icmp::BasicIcmpListener;
+use rips::ipv4::{BasicIpv4Payload, Ipv4Tx};
+use rips::probe::{self, Method, Outcome, ProbeConfig, ProbeResult};
+use rips::shaping::RateLimit;
+use rips::testing::ip;
+
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Returns a stack with 10.0.0.2, probing, connected to one with 10.0.0.1
+/// and 10.0.0.3, where only 10.0.0.1 answers echo requests. 10.0.0.1 is the
+/// gateway to 192.168.0.0/24.
+fn stacks() -> (Arc<Mutex<NetworkStack>>, Arc<Mutex<NetworkStack>>) {
+    let ((mut target_stack, target_interface), (mut prober_stack, prober_interface)) =
+        testing::connected_pair(1, 2);
+    target_stack.add_ipv4(&target_interface, Ipv4Network::new(ip(3), 24).unwrap()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    target_stack.icmp_listen(ip(1), IcmpTypes::EchoRequest, BasicIcmpListener::new(tx)).unwrap();
+    let target_stack = Arc::new(Mutex::new(target_stack));
+    let thread_stack = target_stack.clone();
+    thread::spawn(move || {
+        for (_, src, request) in rx {
+            let mut reply = request.to_vec();
+            reply[0] = IcmpTypes::EchoReply.0;
+            reply[2] = 0;
+            reply[3] = 0;
+            let sum = checksum::checksum(&reply);
+            reply[2] = (sum >> 8) as u8;
+            reply[3] = sum as u8;
+            let mut ipv4_tx = thread_stack.lock().unwrap().ipv4_tx_with_src(ip(1), src).unwrap();
+            ipv4_tx.send(BasicIpv4Payload::new(IpNextHeaderProtocols::Icmp, &reply)).unwrap();
+        }
+    });
+
+    let remote = Ipv4Network::from_str("192.168.0.0/24").unwrap();
+    prober_stack.routing_table().add_route(remote, Some(ip(1)), prober_interface);
+    (target_stack, Arc::new(Mutex::new(prober_stack)))
+}
+
+fn config() -> ProbeConfig {
+    let mut config = ProbeConfig::default();
+    config.timeout = Duration::from_millis(100);
+    config
+}
+
+fn results(mut results: Vec<ProbeResult>) -> Vec<ProbeResult> {
+    results.sort_by_key(|result| result.target);
+    results
+}
+
+#[test]
+fn echo() {
+    let (_target_stack, prober_stack) = stacks();
+    let mut config = config();
+    config.arp = false;
+    let targets = vec![ip(1), ip(3), ip(4), Ipv4Addr::new(192, 168, 0, 5)];
+    let found = results(probe::sweep(prober_stack, ip(2), targets, config).unwrap().collect());
+    assert_eq!(found.len(), 4);
+    assert_eq!(found[0].target, ip(1));
+    match found[0].outcome {
+        Outcome::Alive { method: Method::Echo, mac: None, .. } => (),
+        ref outcome => panic!("Unexpected outcome: {:?}", outcome),
+    }
+    assert_eq!(found[0].attempts, 1);
+    // Answers Arp requests, but not echo requests
+    assert_eq!(found[1],
+               ProbeResult {
+                   target: ip(3),
+                   outcome: Outcome::TimedOut,
+                   attempts: 2,
+               });
+    assert_eq!(found[2].outcome, Outcome::TimedOut);
+    // Sent to the gateway, which drops it
+    assert_eq!(found[3].target, Ipv4Addr::new(192, 168, 0, 5));
+    assert_eq!(found[3].outcome, Outcome::TimedOut);
+}
+
+#[test]
+fn arp() {
+    let (target_stack, prober_stack) = stacks();
+    let target_interface = target_stack.lock().unwrap().interfaces()[0].clone();
+    let mut config = config();
+    config.echo = false;
+    config.retries = 0;
+    let targets = vec![ip(1), ip(3), ip(4), Ipv4Addr::new(192, 168, 0, 5)];
+    let found = results(probe::sweep(prober_stack, ip(2), targets, config).unwrap().collect());
+    assert_eq!(found.len(), 4);
+    for result in &found[..2] {
+        match result.outcome {
+            Outcome::Alive { method: Method::Arp, mac: Some(mac), .. } => {
+                assert_eq!(mac, target_interface.mac)
+            }
+            ref outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+    assert_eq!(found[2].outcome, Outcome::TimedOut);
+    assert_eq!(found[2].attempts, 1);
+    match found[3].outcome {
+        Outcome::Unprobed(_) => (),
+        ref outcome => panic!("Unexpected outcome: {:?}", outcome),
+    }
+}
+
+#[test]
+fn rate_and_window() {
+    let (_target_stack, prober_stack) = stacks();
+    let mut config = config();
+    config.retries = 0;
+    config.window = 2;
+    config.rate = Some(RateLimit::new(20, 1));
+    let start = Instant::now();
+    let targets = probe::hosts(Ipv4Network::from_str("10.0.0.0/29").unwrap());
+    let sweep = probe::sweep(prober_stack.clone(), ip(2), targets, config).unwrap();
+    let found = results(sweep.collect());
+    // Six attempts, the first two at once and then one every 50 ms
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    let alive: Vec<Ipv4Addr> = found.iter()
+        .filter(|result| match result.outcome {
+            Outcome::Alive { .. } => true,
+            _ => false,
+        })
+        .map(|result| result.target)
+        .collect();
+    assert_eq!(alive, vec![ip(1), ip(3)]);
+    assert_eq!(found.len(), 6);
+
+    let mut none = ProbeConfig::default();
+    none.echo = false;
+    none.arp = false;
+    match probe::sweep(prober_stack.clone(), ip(2), vec![ip(1)], none) {
+        Err(StackError::InvalidArgument(_)) => (),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Probed without a method"),
+    }
+    match probe::sweep(prober_stack, ip(9), vec![ip(1)], ProbeConfig::default()) {
+        Err(StackError::NoSourceAddress(_)) => (),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Probed from an address not in the stack"),
+    }
+}
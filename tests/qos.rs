@@ -0,0 +1,103 @@
+extern crate pnet;
+extern crate rips;
+
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::util::MacAddr;
+
+use rips::ethernet::{BasicEthernetPayload, EthernetTx};
+use rips::qos::{QosConfig, QueueStats};
+use rips::shaping::RateLimit;
+use rips::{TxError, testing};
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[test]
+fn arp_overtakes_bulk() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let stack_interface = stack.interface(&interface).unwrap();
+    stack_interface.set_rate_limit(Some(RateLimit::new(10_000, 100)));
+    stack_interface.set_qos(Some(QosConfig::strict(2)));
+
+    let mut ethernet_tx = stack_interface.ethernet_tx(MacAddr::new(1, 2, 3, 4, 5, 6));
+    let bulk = [0; 100];
+    for _ in 0..3 {
+        let payload = BasicEthernetPayload::new(EtherTypes::Ipv4, &bulk);
+        ethernet_tx.send(1, 100, payload).unwrap();
+    }
+    let src = Ipv4Addr::new(10, 0, 0, 1);
+    stack_interface.arp_request_tx().send(src, Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+
+    let ethertypes = (0..4)
+        .map(|_| {
+            let frame = read_handle.recv().unwrap();
+            EthernetPacket::new(&frame).unwrap().get_ethertype()
+        })
+        .collect::<Vec<_>>();
+    assert!(ethertypes.contains(&EtherTypes::Arp));
+    assert_eq!(ethertypes[3], EtherTypes::Ipv4);
+
+    let stats = stack_interface.qos_stats().unwrap();
+    assert_eq!(stats[0], QueueStats { queued: 0, sent: 1, dropped: 0 });
+    assert_eq!(stats[1], QueueStats { queued: 0, sent: 3, dropped: 0 });
+    stack_interface.set_qos(None);
+    assert!(stack_interface.qos_stats().is_none());
+}
+
+#[test]
+fn nonblocking_backpressure() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let stack_interface = stack.interface(&interface).unwrap();
+    // A frame of 100 bytes every 100 ms
+    stack_interface.set_rate_limit(Some(RateLimit::new(1000, 100)));
+    let mut config = QosConfig::strict(1);
+    config.queue_len = 1;
+    stack_interface.set_qos(Some(config));
+    stack_interface.set_nonblocking(true);
+    assert!(stack_interface.is_nonblocking());
+
+    let mut ethernet_tx = stack_interface.ethernet_tx(MacAddr::new(1, 2, 3, 4, 5, 6));
+    let bulk = [0; 100];
+    let mut sent = 0;
+    loop {
+        let payload = BasicEthernetPayload::new(EtherTypes::Ipv4, &bulk);
+        match ethernet_tx.send(1, 100, payload) {
+            Ok(_) => sent += 1,
+            Err(TxError::WouldBlock) => break,
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+        assert!(sent <= 3, "Sending never blocked");
+    }
+    assert!(!stack_interface.wait_writable(Duration::from_millis(0)));
+    assert!(stack_interface.wait_writable(Duration::from_secs(1)));
+    let payload = BasicEthernetPayload::new(EtherTypes::Ipv4, &bulk);
+    ethernet_tx.send(1, 100, payload).unwrap();
+
+    stack_interface.set_qos(None);
+    assert_eq!(read_handle.try_iter().count(), sent + 1);
+}
+
+#[test]
+fn fifo_backpressure() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let stack_interface = stack.interface(&interface).unwrap();
+    // A frame of 100 bytes every 100 ms
+    stack_interface.set_rate_limit(Some(RateLimit::new(1000, 100)));
+    let mut config = QosConfig::fifo();
+    config.queue_len = 1;
+    stack_interface.set_qos(Some(config));
+
+    let mut ethernet_tx = stack_interface.ethernet_tx(MacAddr::new(1, 2, 3, 4, 5, 6));
+    let bulk = [0; 100];
+    for _ in 0..4 {
+        let payload = BasicEthernetPayload::new(EtherTypes::Ipv4, &bulk);
+        ethernet_tx.send(1, 100, payload).unwrap();
+    }
+    // The senders waited for room instead of dropping
+    let stats = stack_interface.qos_stats().unwrap();
+    assert_eq!(stats[0].dropped, 0);
+    assert!(stats[0].sent >= 3);
+
+    stack_interface.set_qos(None);
+    assert_eq!(read_handle.try_iter().count(), 4);
+}
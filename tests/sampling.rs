@@ -0,0 +1,64 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::util::MacAddr;
+
+use rips::sampling::{self, SamplingConfig, SflowExporter};
+use rips::testing;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[test]
+fn exports_samples() {
+    let ip = Ipv4Addr::new(10, 9, 0, 254);
+    let collector = Ipv4Addr::new(10, 9, 0, 1);
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 16).unwrap()).unwrap();
+    stack.interface(&interface)
+        .unwrap()
+        .arp_table()
+        .insert(collector, MacAddr::new(2, 0, 0, 0, 0, 1));
+    let stack = Arc::new(Mutex::new(stack));
+    let config = SamplingConfig {
+        rate: 1,
+        header_len: 16,
+    };
+    let mut exporter = SflowExporter::start(stack,
+                                            SocketAddrV4::new(ip, 0),
+                                            SocketAddrV4::new(collector, sampling::SFLOW_PORT),
+                                            config)
+        .unwrap();
+    let mut frame = vec![0xee; 64];
+    frame[12] = 0x88;
+    frame[13] = 0xb5;
+    inject_handle.send(Ok(frame.clone().into_boxed_slice())).unwrap();
+
+    let sent = read_handle.recv_timeout(Duration::from_secs(2)).unwrap();
+    let eth_pkg = EthernetPacket::new(&sent).unwrap();
+    assert_eq!(eth_pkg.get_ethertype(), EtherTypes::Ipv4);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!((ip_pkg.get_destination(), udp_pkg.get_destination()),
+               (collector, sampling::SFLOW_PORT));
+    let datagram = udp_pkg.payload();
+    // Version 5, from the agent at the address of the socket, one sample
+    assert_eq!(&datagram[..12], &[0, 0, 0, 5, 0, 0, 0, 1, 10, 9, 0, 254]);
+    assert_eq!(&datagram[24..28], &[0, 0, 0, 1]);
+    // Sampled one in one, from a pool of one frame, on interface 1
+    let sample = &datagram[28..];
+    assert_eq!(&sample[12..24], &[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1]);
+    let record = &sample[40..];
+    assert_eq!(&record[12..16], &[0, 0, 0, 64]);
+    assert_eq!(&record[24..], &frame[..16]);
+    assert_eq!(exporter.drops(), 0);
+    exporter.stop();
+}
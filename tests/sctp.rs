@@ -0,0 +1,128 @@
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use rips::{NetworkStack, StackError, testing};
+use rips::sctp::{Failure, Message, SctpConfig, SctpEndpoint, State};
+use rips::sockopt::{HasSocketOptions, ReadTimeout};
+use rips::testing::ip;
+
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Returns endpoints on two connected stacks, with 10.0.0.1 and 10.0.0.2.
+fn endpoints() -> (SctpEndpoint, SctpEndpoint) {
+    let (stack1, stack2) = testing::connected_stacks(1, 2);
+    let mut config = SctpConfig::default();
+    config.rto_initial = Duration::from_millis(200);
+    config.rto_min = Duration::from_millis(100);
+    config.max_init_retransmits = 3;
+    (SctpEndpoint::bind(stack1, ip(1), config).unwrap(),
+     SctpEndpoint::bind(stack2, ip(2), config).unwrap())
+}
+
+#[test]
+fn invalid_config() {
+    let (stack, _) = testing::connected_stacks(1, 2);
+    let mut config = SctpConfig::default();
+    config.out_streams = 0;
+    match SctpEndpoint::bind(stack.clone(), ip(1), config) {
+        Err(StackError::InvalidArgument(_)) => (),
+        Err(e) => panic!("Unexpected error: {:?}", e),
+        Ok(_) => panic!("Bound an endpoint without streams"),
+    }
+    let _endpoint = SctpEndpoint::bind(stack.clone(), ip(1), SctpConfig::default()).unwrap();
+    assert!(SctpEndpoint::bind(stack, ip(1), SctpConfig::default()).is_err());
+}
+
+#[test]
+fn messages_on_streams() {
+    let (server, client) = endpoints();
+    let listener = server.listen(2905).unwrap();
+    assert_eq!(server.listen(2905).err().unwrap().kind(), io::ErrorKind::AddrInUse);
+    let association = client.connect(0, listener.local_addr()).unwrap();
+    assert_eq!(association.state(), State::Established);
+    assert_eq!(association.streams(), (10, 10));
+    let accepted = listener.accept().unwrap();
+    assert_eq!(accepted.peer_addr(), association.local_addr());
+    assert_eq!(association.peer_addr(), SocketAddrV4::new(ip(1), 2905));
+    assert_eq!(listener.try_accept().err().unwrap().kind(), io::ErrorKind::WouldBlock);
+
+    // Large enough to be split over several packets
+    let large = (0..10000).map(|i| i as u8).collect::<Vec<_>>();
+    association.send(1, b"one").unwrap();
+    association.send(2, &large).unwrap();
+    let mut message = Message::new(1, b"two".to_vec());
+    message.ppid = 51;
+    association.send_message(&message).unwrap();
+    let messages = (0..3).map(|_| accepted.recv().unwrap().unwrap()).collect::<Vec<_>>();
+    assert_eq!(messages,
+               vec![Message::new(1, b"one".to_vec()), Message::new(2, large), message]);
+
+    accepted.send(0, b"reply").unwrap();
+    assert_eq!(association.recv().unwrap().unwrap().data, b"reply".to_vec());
+    assert_eq!(association.send(10, b"x").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    association.flush(Duration::from_secs(2)).unwrap();
+    association.shutdown();
+    accepted.set_opt(ReadTimeout, Some(Duration::from_secs(2))).unwrap();
+    assert_eq!(accepted.recv().unwrap(), None);
+    assert_eq!(association.recv().unwrap(), None);
+    assert_eq!(accepted.failure(), None);
+    assert_eq!(association.send(0, b"late").unwrap_err().kind(), io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn ports_from_local_port_range() {
+    let ((channel1, interface1), (channel2, interface2)) = testing::veth_pair();
+    let mut server_stack = NetworkStack::new();
+    server_stack.add_interface(interface1.clone(), channel1).unwrap();
+    server_stack.add_ipv4(&interface1, Ipv4Network::new(ip(1), 24).unwrap()).unwrap();
+    let mut client_stack = NetworkStack::builder().local_port_range(40000, 40003).build().unwrap();
+    client_stack.add_interface(interface2.clone(), channel2).unwrap();
+    client_stack.add_ipv4(&interface2, Ipv4Network::new(ip(2), 24).unwrap()).unwrap();
+    let server = SctpEndpoint::bind(Arc::new(Mutex::new(server_stack)),
+                                    ip(1),
+                                    SctpConfig::default())
+        .unwrap();
+    let client = SctpEndpoint::bind(Arc::new(Mutex::new(client_stack)),
+                                    ip(2),
+                                    SctpConfig::default())
+        .unwrap();
+    let listener = server.listen(2905).unwrap();
+
+    let associations = (0..3)
+        .map(|_| client.connect(0, listener.local_addr()).unwrap())
+        .collect::<Vec<_>>();
+    let mut ports = associations.iter()
+        .map(|association| association.local_addr().port())
+        .collect::<Vec<_>>();
+    ports.sort();
+    assert_eq!(ports, vec![40000, 40001, 40002]);
+}
+
+#[test]
+fn refused() {
+    let (server, client) = endpoints();
+    let result = client.connect(5000, SocketAddrV4::new(server.local_ip(), 2905));
+    assert_eq!(result.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn abort() {
+    let (server, client) = endpoints();
+    let listener = server.listen(2905).unwrap();
+    let association = client.connect(0, listener.local_addr()).unwrap();
+    let accepted = listener.accept().unwrap();
+    accepted.set_opt(ReadTimeout, Some(Duration::from_millis(50))).unwrap();
+    assert_eq!(accepted.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    assert_eq!(accepted.try_recv().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    association.abort();
+    accepted.set_opt(ReadTimeout, Some(Duration::from_secs(2))).unwrap();
+    assert_eq!(accepted.recv().unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    assert_eq!(accepted.failure(), Some(Failure::Aborted));
+}
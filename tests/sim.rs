@@ -0,0 +1,87 @@
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use rips::clock::Clock;
+use rips::sim::{Simulation, StackId, WireId, WireStats};
+use rips::udp::UdpCallbackListener;
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Sets up two stacks on a wire of 5 ms, with a listener on port 53 of the
+/// second one, and returns what it received when.
+fn two_stacks() -> (Simulation, Arc<Mutex<Vec<(Duration, Vec<u8>)>>>) {
+    let mut sim = Simulation::new();
+    let (a, b) = (sim.add_stack(), sim.add_stack());
+    let (_, a_interface, b_interface) = sim.connect(a, b, Duration::from_millis(5));
+    let a_net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+    let b_net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 2), 24).unwrap();
+    sim.stack(a).add_ipv4(&a_interface, a_net).unwrap();
+    sim.stack(b).add_ipv4(&b_interface, b_net).unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let (clock, start, listener_received) = (sim.clock(), sim.now(), received.clone());
+    let listener = UdpCallbackListener::new(move |datagram| {
+        let elapsed = clock.now() - start;
+        listener_received.lock().unwrap().push((elapsed, datagram.payload.to_vec()));
+    });
+    sim.stack(b).udp_listen("10.0.0.2:53", listener).unwrap();
+    (sim, received)
+}
+
+#[test]
+fn resolves_and_delivers_in_virtual_time() {
+    let (mut sim, received) = two_stacks();
+    let a = StackId(0);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    let real_start = Instant::now();
+
+    // The request gets there after 5 ms, the reply back after 10 ms
+    let resolved = sim.stack(a).resolve_in_vrf(None, dst).unwrap().unwrap();
+    sim.advance(Duration::from_millis(9));
+    assert!(resolved.try_recv().is_err());
+    sim.advance(Duration::from_millis(1));
+    assert!(resolved.try_recv().is_ok());
+
+    sim.stack(a).udp_tx(dst, 1024, 53).unwrap().send(&[1]).unwrap();
+    sim.advance(Duration::from_secs(60));
+    assert_eq!(*received.lock().unwrap(), vec![(Duration::from_millis(15), vec![1])]);
+    assert_eq!(sim.elapsed(), Duration::from_millis(60_010));
+    // A minute of simulation takes no minute
+    assert!(real_start.elapsed() < Duration::from_secs(30));
+}
+
+#[test]
+fn scripted() {
+    let (mut sim, received) = two_stacks();
+    let a = StackId(0);
+    let wire = WireId(0);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    sim.stack(a).resolve_in_vrf(None, dst).unwrap().unwrap();
+    sim.advance(Duration::from_millis(10));
+
+    // Send every 10 ms, and pull the cable from 25 to 45 ms
+    for i in 0..7 {
+        sim.schedule(Duration::from_millis(10 * i), move |sim| {
+            let mut tx = sim.stack(a).udp_tx(dst, 1024, 53).unwrap();
+            tx.send(&[i as u8]).unwrap();
+        });
+    }
+    sim.schedule(Duration::from_millis(25), move |sim| sim.set_connected(wire, false));
+    sim.schedule(Duration::from_millis(45), move |sim| sim.set_connected(wire, true));
+    sim.advance(Duration::from_millis(100));
+
+    let received = received.lock().unwrap();
+    let payloads = received.iter().map(|&(_, ref payload)| payload[0]).collect::<Vec<_>>();
+    assert_eq!(payloads, vec![0, 1, 2, 5, 6]);
+    assert_eq!(received[3].0, Duration::from_millis(10 + 50 + 5));
+    assert_eq!(sim.wire_stats(wire),
+               WireStats {
+                   sent: 2 + 7,
+                   dropped: 2,
+                   delivered: 2 + 5,
+               });
+}
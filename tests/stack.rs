@@ -0,0 +1,937 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperation, ArpOperations, ArpPacket,
+                        MutableArpPacket};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::util::MacAddr;
+
+use rips::{ErrorKind, LinkChange, NetworkStack, RxError, RxResult, StackError, ThreadError,
+           TxError, testing};
+use rips::checksum::RxChecksums;
+use rips::clock::MockClock;
+use rips::config::StackConfig;
+use rips::datalink::Datalink;
+use rips::eapol::{self, EapolFrame, Port, Supplicant};
+use rips::ethernet::{BasicEthernetPayload, EthernetListener, EthernetTx};
+use rips::impair::{Impaired, Impairment};
+use rips::malformed::{Layer, MalformedPolicy, ParseErrors};
+use rips::pcap::{Direction, Format, PcapWriter};
+use rips::qos::QosConfig;
+use rips::replay::{Replay, Timing};
+use rips::shaping::RateLimit;
+use rips::snapshot::ListenerSnapshot;
+use rips::stats::LayerStats;
+use rips::threads::ThreadKind;
+use rips::tun::{self, IpLink};
+use rips::udp::UdpCallbackListener;
+
+use std::error::Error;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+struct SilentSupplicant;
+
+impl Supplicant for SilentSupplicant {
+    fn start(&mut self, _port: Port) {}
+
+    fn recv(&mut self, _time: SystemTime, _frame: &EapolFrame) -> RxResult {
+        Ok(())
+    }
+}
+
+#[test]
+fn shutdown() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(dst, MacAddr::new(2, 0, 0, 0, 0, 2));
+    let mut udp_tx = stack.udp_tx(dst, 1024, 53).unwrap();
+    {
+        let stack_interface = stack.interface(&interface).unwrap();
+        stack_interface.set_rate_limit(Some(RateLimit::new(10_000, 100)));
+        stack_interface.set_qos(Some(QosConfig::strict(2)));
+        let mut ethernet_tx = stack_interface.ethernet_tx(MacAddr::new(2, 0, 0, 0, 0, 2));
+        let bulk = [0; 100];
+        for _ in 0..3 {
+            let payload = BasicEthernetPayload::new(EtherTypes::Ipv4, &bulk);
+            ethernet_tx.send(1, 100, payload).unwrap();
+        }
+        stack_interface.set_supplicant(Some(Box::new(SilentSupplicant)));
+    }
+
+    // The dummy receiver has no read timeout, wake its rx thread up unless
+    // it quit before waiting for a frame
+    let wake_handle = inject_handle.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "Timed out");
+        wake_handle.send(Err(timeout)).unwrap_or(());
+    });
+    stack.shutdown(Duration::from_secs(1)).unwrap();
+
+    // Everything queued is sent, and the supplicant logs off
+    let ethertypes = read_handle.try_iter()
+        .map(|frame| {
+            let eth_pkg = EthernetPacket::new(&frame).unwrap();
+            if eth_pkg.get_ethertype() == eapol::EAPOL {
+                assert_eq!(eth_pkg.payload(), &[eapol::VERSION, eapol::LOGOFF, 0, 0]);
+            }
+            eth_pkg.get_ethertype()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ethertypes.len(), 4);
+    assert!(ethertypes.contains(&eapol::EAPOL));
+
+    assert!(stack.interfaces().is_empty());
+    match udp_tx.send(&[1]) {
+        Err(TxError::InvalidTx) => (),
+        _ => panic!("Sent on a stack that is shut down"),
+    }
+    // The rx thread is gone with its receiver
+    assert!(inject_handle.send(Ok(vec![0; 60].into_boxed_slice())).is_err());
+}
+
+#[test]
+fn shutdown_timeout() {
+    let (mut stack, _interface, _inject_handle, _) = testing::dummy_stack();
+    // Nothing wakes up the rx thread once it waits for a frame
+    thread::sleep(Duration::from_millis(50));
+    match stack.shutdown(Duration::from_millis(100)) {
+        Err(StackError::IoError(ref e)) if e.kind() == io::ErrorKind::TimedOut => (),
+        _ => panic!("Expected the shutdown to time out"),
+    }
+    assert!(stack.interfaces().is_empty());
+}
+
+struct PanickingListener;
+
+impl EthernetListener for PanickingListener {
+    fn recv(&mut self, _time: SystemTime, _packet: &EthernetPacket) -> RxResult {
+        panic!("Listener failed");
+    }
+
+    fn ether_type(&self) -> EtherType {
+        EtherType(0x88b5)
+    }
+}
+
+#[test]
+fn thread_errors() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    let errors = stack.watch_errors();
+    let (channel, interface2, inject_handle2, _) = testing::dummy_ethernet_indexed(1);
+    stack.add_interface(interface2.clone(), channel).unwrap();
+    let interface2_errors = stack.interface(&interface2).unwrap().watch_errors();
+
+    inject_handle.send(Err(io::Error::new(io::ErrorKind::Other, "Carrier lost"))).unwrap();
+    let error = errors.recv_timeout(Duration::from_secs(1)).unwrap();
+    let msg = "Carrier lost".to_owned();
+    assert_eq!(error, ThreadError::Datalink(interface.clone(), io::ErrorKind::Other, msg));
+    // The rx thread is gone
+    assert!(inject_handle.send(Ok(vec![0; 60].into_boxed_slice())).is_err());
+
+    stack.ethernet_listen(&interface2, Box::new(PanickingListener)).unwrap();
+    let mut frame = vec![0; 60];
+    MutableEthernetPacket::new(&mut frame[..]).unwrap().set_ethertype(EtherType(0x88b5));
+    inject_handle2.send(Ok(frame.into_boxed_slice())).unwrap();
+    let error = errors.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(error, ThreadError::Panic(interface2.clone(), "Listener failed".to_owned()));
+    assert_eq!(interface2_errors.recv_timeout(Duration::from_secs(1)).unwrap(), error);
+    assert!(errors.try_recv().is_err());
+}
+
+fn arp(operation: ArpOperation, src: Ipv4Addr, src_mac: MacAddr, dst: Ipv4Addr) -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
+                             ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+        eth_pkg.set_source(src_mac);
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
+        arp_pkg.set_operation(operation);
+        arp_pkg.set_sender_hw_addr(src_mac);
+        arp_pkg.set_sender_proto_addr(src);
+        arp_pkg.set_target_proto_addr(dst);
+    }
+    buffer.into_boxed_slice()
+}
+
+#[test]
+fn polled() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_polled_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    assert!(stack.is_polled());
+    assert!(stack.interface(&interface).unwrap().is_polled());
+
+    // Nothing is handled until polled
+    inject_handle.send(Ok(arp(ArpOperations::Request, peer, peer_mac, ip))).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(read_handle.try_recv().is_err());
+    assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+    let reply = read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&reply).unwrap();
+    let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+    assert_eq!(arp_pkg.get_operation(), ArpOperations::Reply);
+    assert_eq!(arp_pkg.get_target_hw_addr(), peer_mac);
+    assert_eq!(stack.poll_timeout(Duration::from_millis(10)), 0);
+
+    // Resolving waits for the reply to be polled
+    let other = Ipv4Addr::new(10, 0, 0, 3);
+    let other_mac = MacAddr::new(2, 0, 0, 0, 0, 3);
+    let mac = stack.resolve_in_vrf(None, other).unwrap().unwrap();
+    let request = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&request).unwrap().get_ethertype(), EtherTypes::Arp);
+    inject_handle.send(Ok(arp(ArpOperations::Reply, other, other_mac, ip))).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert!(mac.try_recv().is_err());
+    assert_eq!(stack.poll(), 1);
+    assert_eq!(mac.try_recv().unwrap(), other_mac);
+}
+
+#[test]
+fn timers() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    let timers = stack.interface(&interface).unwrap().timers();
+    let (fired_tx, fired) = mpsc::channel();
+    let fired_tx2 = fired_tx.clone();
+    timers.schedule(Duration::from_millis(20), move || fired_tx.send(1).unwrap());
+    let cancelled = timers.schedule(Duration::from_millis(10),
+                                    move || fired_tx2.send(2).unwrap());
+    timers.cancel(cancelled);
+    assert_eq!(fired.recv_timeout(Duration::from_secs(1)), Ok(1));
+    // Both callbacks are dropped now, closing the channel
+    assert!(fired.recv_timeout(Duration::from_secs(1)).is_err());
+}
+
+/// The first fragment of a packet from `src` to `dst` with `identification`.
+fn first_fragment(dst_mac: MacAddr,
+                  src: Ipv4Addr,
+                  dst: Ipv4Addr,
+                  identification: u16)
+                  -> Box<[u8]> {
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + 20 + 8];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(dst_mac);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_identification(identification);
+        ip_pkg.set_flags(rips::ipv4::MORE_FRAGMENTS);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_pkg.set_source(src);
+        ip_pkg.set_destination(dst);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    buffer.into_boxed_slice()
+}
+
+#[test]
+fn parse_errors() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_polled_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    let quarantined = Arc::new(Mutex::new(Vec::new()));
+    let hook_quarantined = quarantined.clone();
+    let hook = move |layer: Layer, _error: &RxError, _time: SystemTime, data: &[u8]| {
+        hook_quarantined.lock().unwrap().push((layer, data.len()));
+    };
+    stack.set_malformed_policy(MalformedPolicy::Quarantine(Arc::new(hook)));
+
+    let short_arp = arp(ArpOperations::Request, peer, MacAddr::new(2, 0, 0, 0, 0, 2), ip);
+    let mut corrupt = first_fragment(interface.mac, peer, ip, 1);
+    corrupt[14 + 10] ^= 0xff;
+    // Unfragmented, with four bytes of UDP header
+    let mut truncated = first_fragment(interface.mac, peer, ip, 2);
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut truncated[14..]).unwrap();
+        ip_pkg.set_flags(0);
+        ip_pkg.set_total_length(20 + 4);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    {
+        let stack_interface = stack.interface(&interface).unwrap();
+        let now = SystemTime::now();
+        assert_eq!(stack_interface.inject(now, &[0; 10]), Err(RxError::InvalidLength));
+        assert_eq!(stack_interface.inject(now, &short_arp[..18]), Err(RxError::InvalidLength));
+        assert_eq!(stack_interface.inject(now, &corrupt), Err(RxError::InvalidChecksum));
+        assert_eq!(stack_interface.inject(now, &truncated), Err(RxError::InvalidContent));
+    }
+    // Frames polled are counted the same
+    inject_handle.send(Ok(truncated.clone())).unwrap();
+    assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+
+    let expected = ParseErrors {
+        ethernet: 1,
+        arp: 1,
+        ipv4: 1,
+        udp: 2,
+        icmp: 0,
+        checksums: 1,
+    };
+    assert_eq!(stack.parse_errors(), expected);
+    assert_eq!(stack.snapshot().interfaces[0].parse_errors, expected);
+    assert_eq!(*quarantined.lock().unwrap(),
+               vec![(Layer::Ethernet, 10),
+                    (Layer::Arp, 18),
+                    (Layer::Ipv4, corrupt.len()),
+                    (Layer::Udp, 24),
+                    (Layer::Udp, 24)]);
+
+    stack.set_malformed_policy(MalformedPolicy::Drop);
+    let stack_interface = stack.interface(&interface).unwrap();
+    assert_eq!(stack_interface.inject(SystemTime::now(), &corrupt),
+               Err(RxError::InvalidChecksum));
+    assert_eq!(stack_interface.parse_errors(), expected);
+    assert_eq!(quarantined.lock().unwrap().len(), 5);
+}
+
+#[test]
+fn rx_checksums() {
+    let (mut stack, interface, _, _) = testing::dummy_polled_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let listener_delivered = delivered.clone();
+    let listener = UdpCallbackListener::new(move |_| {
+        listener_delivered.fetch_add(1, Ordering::SeqCst);
+    });
+    stack.udp_listen(SocketAddrV4::new(ip, 53), listener).unwrap();
+
+    // An empty datagram to port 53, with a checksum that is not its own
+    let mut frame = first_fragment(interface.mac, peer, ip, 1);
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+        ip_pkg.set_flags(0);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        ip_pkg.payload_mut().copy_from_slice(&[0x30, 0x39, 0, 53, 0, 8, 0x12, 0x34]);
+    }
+    let mut corrupt_header = frame.clone();
+    corrupt_header[14 + 10] ^= 0xff;
+
+    let stack_interface = stack.interface(&interface).unwrap();
+    assert_eq!(stack_interface.rx_checksums(), RxChecksums::default());
+    assert_eq!(stack_interface.inject(SystemTime::now(), &frame),
+               Err(RxError::InvalidChecksum));
+    assert_eq!(delivered.load(Ordering::SeqCst), 0);
+    let errors = stack_interface.parse_errors();
+    assert_eq!((errors.udp, errors.checksums), (1, 1));
+
+    stack_interface.set_rx_checksums(RxChecksums { udp: false, ..RxChecksums::default() });
+    assert_eq!(stack_interface.inject(SystemTime::now(), &frame), Ok(()));
+    assert_eq!(stack_interface.inject(SystemTime::now(), &corrupt_header),
+               Err(RxError::InvalidChecksum));
+    stack_interface.set_rx_checksums(RxChecksums::none());
+    assert_eq!(stack_interface.inject(SystemTime::now(), &corrupt_header), Ok(()));
+    assert_eq!(delivered.load(Ordering::SeqCst), 2);
+    let errors = stack_interface.parse_errors();
+    assert_eq!((errors.udp, errors.ipv4, errors.checksums), (1, 1, 2));
+}
+
+#[test]
+fn stats() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_polled_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.udp_listen(SocketAddrV4::new(ip, 53), UdpCallbackListener::new(|_| ())).unwrap();
+
+    // Empty datagrams to port 53 and to the closed port 54
+    let datagram = |port: u8| {
+        let mut frame = first_fragment(interface.mac, peer, ip, port as u16);
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+            ip_pkg.set_flags(0);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+            ip_pkg.payload_mut().copy_from_slice(&[0x30, 0x39, 0, port, 0, 8, 0, 0]);
+        }
+        frame
+    };
+    let mut unknown = datagram(53).to_vec();
+    unknown[12] = 0x88;
+    unknown[13] = 0xb5;
+    inject_handle.send(Ok(arp(ArpOperations::Request, peer, peer_mac, ip))).unwrap();
+    inject_handle.send(Ok(datagram(53))).unwrap();
+    inject_handle.send(Ok(datagram(54))).unwrap();
+    inject_handle.send(Ok(unknown.into_boxed_slice())).unwrap();
+    let mut polled = 0;
+    while polled < 4 {
+        polled += stack.poll_timeout(Duration::from_secs(1));
+    }
+    let reply = read_handle.try_recv().unwrap();
+
+    let other = Ipv4Addr::new(10, 0, 0, 3);
+    let other_mac = MacAddr::new(2, 0, 0, 0, 0, 3);
+    let mac = stack.resolve_in_vrf(None, other).unwrap().unwrap();
+    let request = read_handle.try_recv().unwrap();
+    inject_handle.send(Ok(arp(ArpOperations::Reply, other, other_mac, ip))).unwrap();
+    assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+    assert_eq!(mac.try_recv().unwrap(), other_mac);
+    assert!(stack.resolve_in_vrf(None, other).unwrap().is_none());
+
+    let stats = stack.stats();
+    assert_eq!(stats, stack.interface(&interface).unwrap().stats());
+    assert_eq!(stats, stack.snapshot().interfaces[0].stats);
+    assert_eq!((stats.ethernet.rx_packets, stats.ethernet.rx_dropped), (5, 1));
+    assert_eq!((stats.ethernet.tx_packets, stats.ethernet.tx_bytes),
+               (2, (reply.len() + request.len()) as u64));
+    assert_eq!((stats.arp.rx_packets, stats.arp.rx_bytes, stats.arp.tx_packets), (2, 56, 2));
+    assert_eq!((stats.ipv4.rx_packets, stats.ipv4.rx_bytes, stats.ipv4.rx_dropped), (2, 56, 0));
+    assert_eq!((stats.udp.rx_packets, stats.udp.rx_bytes, stats.udp.rx_dropped), (2, 16, 1));
+    assert_eq!(stats.icmp, LayerStats::default());
+    assert_eq!((stats.arp_cache_hits, stats.arp_cache_misses), (1, 1));
+}
+
+#[test]
+fn latencies() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_polled_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    let mac = stack.resolve_in_vrf(None, peer).unwrap().unwrap();
+    read_handle.try_recv().unwrap();
+    inject_handle.send(Ok(arp(ArpOperations::Reply, peer, peer_mac, ip))).unwrap();
+    assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+    assert_eq!(mac.try_recv().unwrap(), peer_mac);
+
+    stack.icmp_tx(peer).unwrap().send_echo(&[1, 2]).unwrap();
+    let mut reply = read_handle.try_recv().unwrap().to_vec();
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut reply[..]).unwrap();
+        eth_pkg.set_source(peer_mac);
+        eth_pkg.set_destination(interface.mac);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_source(peer);
+        ip_pkg.set_destination(ip);
+        let icmp = ip_pkg.payload_mut();
+        icmp[0] = 0;
+        icmp[2..4].copy_from_slice(&[0, 0]);
+        let csum = rips::checksum::checksum(icmp);
+        icmp[2..4].copy_from_slice(&[(csum >> 8) as u8, csum as u8]);
+    }
+    inject_handle.send(Ok(reply.clone().into_boxed_slice())).unwrap();
+    // A second reply has no request left to match
+    inject_handle.send(Ok(reply.into_boxed_slice())).unwrap();
+    let mut polled = 0;
+    while polled < 2 {
+        polled += stack.poll_timeout(Duration::from_secs(1));
+    }
+
+    let latencies = stack.latencies();
+    assert_eq!(latencies, stack.interface(&interface).unwrap().latencies());
+    assert_eq!(latencies.arp_resolve.count(), 1);
+    assert_eq!(latencies.icmp_echo.count(), 1);
+    assert!(latencies.icmp_echo.max() < 1_000_000);
+}
+
+#[test]
+fn mock_clock() {
+    let clock = Arc::new(MockClock::new());
+    let (mut stack, interface, inject_handle, _) =
+        testing::dummy_polled_stack_with_clock(clock.clone());
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    assert!(stack.set_clock(clock.clone()).is_err());
+
+    // Timers only fire once the clock says so, however long it really takes
+    let (fired_tx, fired) = mpsc::channel();
+    stack.interface(&interface).unwrap().timers().schedule(Duration::from_secs(60), move || {
+        fired_tx.send(()).unwrap();
+    });
+    stack.poll();
+    thread::sleep(Duration::from_millis(20));
+    stack.poll();
+    assert!(fired.try_recv().is_err());
+    clock.advance(Duration::from_secs(60));
+    stack.poll();
+    assert!(fired.try_recv().is_ok());
+
+    // So do packets under reassembly
+    inject_handle.send(Ok(first_fragment(interface.mac, peer, ip, 1))).unwrap();
+    assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+    clock.advance(Duration::from_secs(31));
+    inject_handle.send(Ok(first_fragment(interface.mac, peer, ip, 2))).unwrap();
+    assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+    let stats = stack.interface(&interface).unwrap().reassembly().stats();
+    assert_eq!(stats.timed_out, 1);
+}
+
+#[test]
+fn carrier_failover() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    let (channel, backup, _, backup_read_handle) = testing::dummy_ethernet_indexed(1);
+    stack.add_interface(backup.clone(), channel).unwrap();
+    let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+    let gw = Ipv4Addr::new(10, 1, 0, 254);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    stack.add_ipv4(&interface, net).unwrap();
+    stack.add_ipv4(&backup, Ipv4Network::new(Ipv4Addr::new(10, 1, 0, 1), 24).unwrap()).unwrap();
+    stack.add_route_with_metric(net, Some(gw), backup.clone(), 10);
+    stack.interface(&interface).unwrap().arp_table().insert(dst, MacAddr::new(2, 0, 0, 0, 0, 2));
+    stack.interface(&backup).unwrap().arp_table().insert(gw, MacAddr::new(2, 0, 0, 0, 0, 3));
+    let changes = stack.interface(&interface).unwrap().watch_link();
+
+    let mut udp_tx = stack.udp_tx(dst, 1024, 53).unwrap();
+    udp_tx.send(&[1]).unwrap();
+    assert!(read_handle.try_recv().is_ok());
+
+    stack.set_carrier(&interface, false).unwrap();
+    assert!(!stack.interface(&interface).unwrap().has_carrier());
+    assert_eq!(changes.try_recv().unwrap(), LinkChange::Carrier(false));
+    match udp_tx.send(&[2]) {
+        Err(TxError::InvalidTx) => (),
+        _ => panic!("Expected the tx-object to be invalidated"),
+    }
+    stack.udp_tx(dst, 1024, 53).unwrap().send(&[2]).unwrap();
+    assert!(read_handle.try_recv().is_err());
+    let pkg = backup_read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg).unwrap().get_destination(),
+               MacAddr::new(2, 0, 0, 0, 0, 3));
+
+    // Without either carrier there is no route left
+    stack.set_carrier(&backup, false).unwrap();
+    match stack.udp_tx(dst, 1024, 53) {
+        Err(StackError::NoRouteToHost(ip)) if ip == dst => (),
+        _ => panic!("Expected no route"),
+    }
+    stack.set_carrier(&interface, true).unwrap();
+    assert_eq!(changes.try_recv().unwrap(), LinkChange::Carrier(true));
+    stack.udp_tx(dst, 1024, 53).unwrap().send(&[3]).unwrap();
+    assert!(read_handle.try_recv().is_ok());
+}
+
+#[test]
+fn from_unknown_os_interface() {
+    match rips::NetworkStack::from_os_interface("no-such-interface") {
+        Err(StackError::NoSuchInterface(ref name)) if name == "no-such-interface" => (),
+        _ => panic!("Expected NoSuchInterface"),
+    }
+}
+
+#[test]
+fn apply_config() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    let text = format!("mtu = 1400\n\
+                        [[interface]]\n\
+                        name = \"{}\"\n\
+                        addresses = [\"10.0.0.2/24\"]\n\
+                        [[interface.route]]\n\
+                        net = \"0.0.0.0/0\"\n\
+                        gw = \"10.0.0.1\"\n",
+                       interface.name);
+    let config = StackConfig::from_toml_str(&text).unwrap();
+    config.apply(&mut stack).unwrap();
+    assert_eq!(stack.interface(&interface).unwrap().get_mtu(), 1400);
+    assert!(stack.interface(&interface).unwrap().has_ipv4(Ipv4Addr::new(10, 0, 0, 2)));
+    assert_eq!(stack.routing_table().route(Ipv4Addr::new(1, 2, 3, 4)),
+               Some((Some(Ipv4Addr::new(10, 0, 0, 1)), interface.clone())));
+
+    let config = StackConfig::from_toml_str("[[interface]]\nname = \"eth9\"").unwrap();
+    match config.apply(&mut stack) {
+        Err(StackError::NoSuchInterface(ref name)) if name == "eth9" => (),
+        _ => panic!("Expected NoSuchInterface"),
+    }
+}
+
+#[test]
+fn builder() {
+    let mut stack = NetworkStack::builder()
+        .mtu(1400)
+        .thread_name("builder")
+        .pin_threads(ThreadKind::Interface, vec![0])
+        .timer_tick(Duration::from_millis(1))
+        .build()
+        .unwrap();
+    let (channel, interface, _, _) = testing::dummy_ethernet();
+    stack.add_interface(interface.clone(), channel).unwrap();
+    assert_eq!(stack.interface(&interface).unwrap().get_mtu(), 1400);
+
+    let (name_tx, name) = mpsc::channel();
+    let timers = stack.interface(&interface).unwrap().timers();
+    timers.schedule(Duration::from_millis(1), move || {
+        name_tx.send(thread::current().name().map(|name| name.to_owned())).unwrap();
+    });
+    let expected = format!("builder-{}", interface.name);
+    assert_eq!(name.recv_timeout(Duration::from_secs(1)), Ok(Some(expected)));
+
+    let illegal = |builder: rips::NetworkStackBuilder| match builder.build() {
+        Err(ref e) if e.kind() == ErrorKind::InvalidInput => (),
+        _ => panic!("Expected an invalid argument"),
+    };
+    illegal(NetworkStack::builder().mtu(10_000));
+    illegal(NetworkStack::builder().local_port_range(0, 100));
+    illegal(NetworkStack::builder().local_port_range(5000, 5000));
+    illegal(NetworkStack::builder().timer_tick(Duration::new(0, 0)));
+    illegal(NetworkStack::builder().rx_workers(0));
+}
+
+#[test]
+fn snapshot() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 2);
+    let peer = Ipv4Addr::new(10, 0, 0, 1);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(peer, peer_mac);
+    let (tx, _rx) = mpsc::channel();
+    stack.udp_listen("10.0.0.2:53", rips::udp::BasicUdpListener::new(tx)).unwrap();
+
+    let snapshot = stack.snapshot();
+    assert_eq!(snapshot.interfaces.len(), 1);
+    let snapshot_interface = &snapshot.interfaces[0];
+    assert_eq!(snapshot_interface.name, interface.name);
+    assert_eq!(snapshot_interface.addresses, vec![Ipv4Network::new(ip, 24).unwrap()]);
+    assert_eq!(snapshot_interface.arp, vec![(peer, peer_mac)]);
+    assert_eq!(snapshot_interface.listeners,
+               vec![ListenerSnapshot::Udp(SocketAddrV4::new(ip, 53))]);
+    assert_eq!(snapshot.routes.len(), 1);
+    assert_eq!(snapshot.routes[0].interface, interface);
+
+    let json = snapshot.to_json();
+    assert!(json.contains(r#""addresses":["10.0.0.2/24"]"#));
+    assert!(json.contains(r#"{"protocol":"udp","addr":"10.0.0.2:53"}"#));
+}
+
+/// A backend exchanging its frames with the test through channels.
+struct ChannelDatalink {
+    sent: Mutex<mpsc::Sender<Box<[u8]>>>,
+    received: Mutex<mpsc::Receiver<Box<[u8]>>>,
+}
+
+impl Datalink for ChannelDatalink {
+    fn mac(&self) -> MacAddr {
+        MacAddr::new(2, 0, 0, 0, 0, 9)
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(1400)
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.sent.lock().unwrap().send(frame.to_vec().into_boxed_slice()).unwrap();
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        self.received
+            .lock()
+            .unwrap()
+            .recv_timeout(Duration::from_millis(100))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No frame"))
+    }
+}
+
+#[test]
+fn datalink_backend() {
+    let (sent, read_handle) = mpsc::channel();
+    let (inject_handle, received) = mpsc::channel();
+    let datalink = ChannelDatalink {
+        sent: Mutex::new(sent),
+        received: Mutex::new(received),
+    };
+    let mut stack = NetworkStack::new();
+    let interface = stack.add_datalink("backend0", Arc::new(datalink)).unwrap();
+    assert_eq!(interface.mac, MacAddr::new(2, 0, 0, 0, 0, 9));
+    assert_eq!(stack.interface(&interface).unwrap().get_mtu(), 1400);
+
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    inject_handle.send(arp(ArpOperations::Request, peer, peer_mac, ip)).unwrap();
+    let reply = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&reply).unwrap();
+    let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+    assert_eq!(arp_pkg.get_operation(), ArpOperations::Reply);
+    assert_eq!(arp_pkg.get_target_hw_addr(), peer_mac);
+}
+
+#[test]
+fn impaired_datalink() {
+    let (sent, read_handle) = mpsc::channel();
+    let (inject_handle, received) = mpsc::channel();
+    let datalink = Arc::new(ChannelDatalink {
+        sent: Mutex::new(sent),
+        received: Mutex::new(received),
+    });
+    let delayed = Impairment {
+        latency: Duration::from_millis(100),
+        ..Impairment::default()
+    };
+    let impaired = Arc::new(Impaired::new(datalink, Impairment::default(), delayed));
+    let mut stack = NetworkStack::new();
+    let interface = stack.add_datalink("impaired0", impaired.clone()).unwrap();
+    assert_eq!(stack.interface(&interface).unwrap().get_mtu(), 1400);
+
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    let start = Instant::now();
+    inject_handle.send(arp(ArpOperations::Request, peer, peer_mac, ip)).unwrap();
+    read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // Requests lost on the way are never answered
+    impaired.set_impairment(Direction::Inbound,
+                            Impairment {
+                                loss: 1.0,
+                                ..Impairment::default()
+                            });
+    inject_handle.send(arp(ArpOperations::Request, peer, peer_mac, ip)).unwrap();
+    assert!(read_handle.recv_timeout(Duration::from_millis(300)).is_err());
+    let stats = impaired.stats(Direction::Inbound);
+    assert_eq!((stats.frames, stats.lost), (2, 1));
+    assert_eq!(impaired.stats(Direction::Outbound).frames, 1);
+}
+
+/// A link that never waits for frames, counting how often it is polled.
+struct SpinningDatalink {
+    sent: Mutex<mpsc::Sender<Box<[u8]>>>,
+    received: Mutex<mpsc::Receiver<Box<[u8]>>>,
+    polls: Arc<AtomicUsize>,
+}
+
+impl Datalink for SpinningDatalink {
+    fn mac(&self) -> MacAddr {
+        MacAddr::new(2, 0, 0, 0, 0, 9)
+    }
+
+    fn send(&self, frame: &[u8]) -> io::Result<()> {
+        self.sent.lock().unwrap().send(frame.to_vec().into_boxed_slice()).unwrap();
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        self.polls.fetch_add(1, Ordering::SeqCst);
+        self.received
+            .lock()
+            .unwrap()
+            .try_recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::WouldBlock, "No frame"))
+    }
+}
+
+#[test]
+fn busy_poll() {
+    let (sent, read_handle) = mpsc::channel();
+    let (inject_handle, received) = mpsc::channel();
+    let polls = Arc::new(AtomicUsize::new(0));
+    let datalink = SpinningDatalink {
+        sent: Mutex::new(sent),
+        received: Mutex::new(received),
+        polls: polls.clone(),
+    };
+    let mut stack = NetworkStack::builder().busy_poll(16).build().unwrap();
+    let interface = stack.add_datalink("spin0", Arc::new(datalink)).unwrap();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+
+    // The rx thread keeps asking the link, which never waits
+    thread::sleep(Duration::from_millis(20));
+    assert!(polls.load(Ordering::SeqCst) > 100);
+    inject_handle.send(arp(ArpOperations::Request, peer, peer_mac, ip)).unwrap();
+    let reply = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let eth_pkg = EthernetPacket::new(&reply).unwrap();
+    let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+    assert_eq!(arp_pkg.get_operation(), ArpOperations::Reply);
+    stack.shutdown(Duration::from_secs(1)).unwrap();
+}
+
+#[test]
+fn replay() {
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    let start = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    let mut writer = PcapWriter::new(Vec::new(), Format::Pcapng, 1500, "eth0").unwrap();
+    // Something the stack ignores, and the request the address is added in time for
+    let mut other = arp(ArpOperations::Request, peer, peer_mac, ip).to_vec();
+    other[12] = 0x88;
+    other[13] = 0xb5;
+    writer.write(Direction::Inbound, start, &other).unwrap();
+    let reply = arp(ArpOperations::Reply, ip, MacAddr::new(2, 0, 0, 0, 0, 1), peer);
+    writer.write(Direction::Outbound, start, &reply).unwrap();
+    let request = arp(ArpOperations::Request, peer, peer_mac, ip);
+    writer.write(Direction::Inbound, start + Duration::from_millis(500), &request).unwrap();
+
+    let capture = io::Cursor::new(writer.into_inner());
+    let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+    let replay = Arc::new(Replay::new(capture, mac, Timing::Accelerated(2.0)).unwrap());
+    let mut stack = NetworkStack::new();
+    let interface = stack.add_datalink("replay0", replay.clone()).unwrap();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    for _ in 0..100 {
+        if replay.is_finished() && replay.sent() > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(replay.is_finished());
+    // The frame the capturing host sent was not replayed, the stack replied itself
+    assert_eq!((replay.replayed(), replay.sent()), (2, 1));
+    stack.shutdown(Duration::from_secs(1)).unwrap();
+}
+
+/// A link exchanging its IP packets with the test through channels.
+struct ChannelIpLink {
+    sent: Mutex<mpsc::Sender<Box<[u8]>>>,
+    received: Mutex<mpsc::Receiver<Box<[u8]>>>,
+}
+
+impl IpLink for ChannelIpLink {
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.sent.lock().unwrap().send(packet.to_vec().into_boxed_slice()).unwrap();
+        Ok(())
+    }
+
+    fn recv(&self) -> io::Result<Box<[u8]>> {
+        self.received
+            .lock()
+            .unwrap()
+            .recv_timeout(Duration::from_millis(100))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No packet"))
+    }
+}
+
+#[test]
+fn tun_interface() {
+    let (sent, read_handle) = mpsc::channel();
+    let (inject_handle, received) = mpsc::channel();
+    let link = ChannelIpLink {
+        sent: Mutex::new(sent),
+        received: Mutex::new(received),
+    };
+    let mut stack = NetworkStack::new();
+    let interface = stack.add_tun("tun0", Arc::new(link)).unwrap();
+    assert_eq!(interface.mac, tun::MAC);
+    assert!(stack.interface(&interface).unwrap().is_layer3());
+
+    let ip = Ipv4Addr::new(10, 8, 0, 2);
+    let peer = Ipv4Addr::new(10, 8, 0, 1);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    // Sent right away as a bare IP packet, without resolving the peer first
+    stack.udp_tx(peer, 1024, 53).unwrap().send(&[1, 2, 3]).unwrap();
+    let packet = read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    let ip_pkg = Ipv4Packet::new(&packet).unwrap();
+    assert_eq!(ip_pkg.get_version(), 4);
+    assert_eq!(ip_pkg.get_destination(), peer);
+    assert_eq!(&ip_pkg.payload()[8..], &[1, 2, 3]);
+
+    let (tx, rx) = mpsc::channel();
+    stack.udp_listen("10.8.0.2:53", rips::udp::BasicUdpListener::new(tx)).unwrap();
+    let mut buffer = vec![0; 31];
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(31);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_pkg.set_source(peer);
+        ip_pkg.set_destination(ip);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        ip_pkg.set_payload(&[4, 0, 0, 53, 0, 11, 0, 0, 7, 8, 9]);
+    }
+    inject_handle.send(buffer.into_boxed_slice()).unwrap();
+    let (_, src, payload) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(src, SocketAddrV4::new(peer, 1024));
+    assert_eq!(&*payload, &[7, 8, 9]);
+}
+
+#[test]
+fn veth_pair() {
+    let ((channel_a, interface_a), (channel_b, interface_b)) = testing::veth_pair();
+    assert!(interface_a.mac != interface_b.mac);
+    let mut stack_a = NetworkStack::new();
+    stack_a.add_interface(interface_a.clone(), channel_a).unwrap();
+    let mut stack_b = NetworkStack::new();
+    stack_b.add_interface(interface_b.clone(), channel_b).unwrap();
+    let ip_a = Ipv4Addr::new(10, 0, 0, 1);
+    let ip_b = Ipv4Addr::new(10, 0, 0, 2);
+    stack_a.add_ipv4(&interface_a, Ipv4Network::new(ip_a, 24).unwrap()).unwrap();
+    stack_b.add_ipv4(&interface_b, Ipv4Network::new(ip_b, 24).unwrap()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    stack_b.udp_listen("10.0.0.2:53", rips::udp::BasicUdpListener::new(tx)).unwrap();
+
+    // Stack a resolves stack b with Arp over the pair before sending
+    stack_a.udp_tx(ip_b, 1024, 53).unwrap().send(&[1, 2, 3]).unwrap();
+    let (_, src, payload) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(src, SocketAddrV4::new(ip_a, 1024));
+    assert_eq!(&*payload, &[1, 2, 3]);
+}
+
+#[test]
+fn buffer_pool_reuse() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_polled_stack();
+    let ip = Ipv4Addr::new(10, 0, 0, 1);
+    let peer = Ipv4Addr::new(10, 0, 0, 2);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 24).unwrap()).unwrap();
+    for _ in 0..3 {
+        inject_handle.send(Ok(arp(ArpOperations::Request, peer, peer_mac, ip))).unwrap();
+        assert_eq!(stack.poll_timeout(Duration::from_secs(1)), 1);
+        read_handle.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+    // Only the first frame queued for polling needed a buffer allocated
+    let stats = stack.buffer_pool().stats();
+    assert_eq!((stats.hits, stats.misses), (2, 1));
+    assert!(stats.hit_rate() > 0.6);
+}
+
+#[test]
+fn retryable_errors() {
+    assert!(TxError::WouldBlock.is_retryable());
+    assert!(TxError::BufferFull.is_retryable());
+    assert!(TxError::IoError(io::Error::new(io::ErrorKind::Interrupted, "")).is_retryable());
+    assert!(!TxError::IoError(io::Error::new(io::ErrorKind::Other, "")).is_retryable());
+    assert!(!TxError::Filtered.is_retryable());
+    assert!(!TxError::InvalidTx.is_retryable());
+    assert_eq!(TxError::InvalidTx.kind(), ErrorKind::Outdated);
+
+    let e = StackError::TxError(TxError::BufferFull);
+    assert!(e.is_retryable());
+    assert_eq!(e.cause().unwrap().to_string(), "Insufficient buffer space");
+    let e = StackError::NoSuchInterface("eth9".to_owned());
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert_eq!(e.to_string(), "No such interface: eth9");
+    assert_eq!(io::Error::from(e).kind(), io::ErrorKind::NotFound);
+    let e = StackError::AddressInUse(Ipv4Addr::new(10, 0, 0, 1));
+    assert_eq!((e.kind(), io::Error::from(e).kind()),
+               (ErrorKind::AlreadyExists, io::ErrorKind::AddrInUse));
+}
@@ -0,0 +1,130 @@
+extern crate rips;
+
+use rips::{StackError, testing};
+use rips::sockopt::{HasSocketOptions, ReadTimeout};
+use rips::tftp::{self, Mode, TFTP_PORT, TftpClient, TftpClientConfig, TftpServerConfig};
+use rips::udp::UdpSocket;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Returns an empty directory for the test `name`, holding `files`.
+fn root(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+    let root = env::temp_dir().join(format!("rips-test-tftp-{}", name));
+    fs::remove_dir_all(&root).unwrap_or(());
+    fs::create_dir_all(root.join("boot")).unwrap();
+    for &(name, data) in files {
+        File::create(root.join(name)).unwrap().write_all(data).unwrap();
+    }
+    root
+}
+
+fn server_addr() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), TFTP_PORT)
+}
+
+fn io_kind(result: Result<(), StackError>) -> io::ErrorKind {
+    match result {
+        Err(StackError::IoError(e)) => e.kind(),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+fn recv(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+    let mut buffer = [0; 1024];
+    let (len, src) = socket.recv_from(&mut buffer).unwrap();
+    (buffer[..len].to_vec(), src)
+}
+
+#[test]
+fn transfers() {
+    let image = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+    let block = vec![7; 1024];
+    let files: &[(&str, &[u8])] = &[("boot/image.bin", &image),
+                                    ("block.bin", &block),
+                                    ("notes.txt", b"one\ntwo\n")];
+    let mut config = TftpServerConfig::new(root("transfers", files));
+    config.allow_write = true;
+    config.max_write_size = 5000;
+    let written = config.root.join("uploaded.bin");
+    let (server_stack, client_stack) = testing::connected_stacks(1, 2);
+    let _server = tftp::spawn(server_stack, Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let client = |config: TftpClientConfig| {
+        TftpClient::new(client_stack.clone(), local_ip, server_addr(), config).unwrap()
+    };
+
+    let plain = client(TftpClientConfig::default());
+    assert_eq!(plain.get("boot/image.bin").unwrap(), image);
+    assert_eq!(plain.get("/block.bin").unwrap(), block);
+    let negotiating = client(TftpClientConfig {
+        block_size: Some(9000),
+        server_timeout: Some(Duration::from_secs(2)),
+        transfer_size: true,
+        ..TftpClientConfig::default()
+    });
+    assert_eq!(negotiating.get("boot/image.bin").unwrap(), image);
+    let text = client(TftpClientConfig { mode: Mode::Netascii, ..TftpClientConfig::default() });
+    assert_eq!(text.get("notes.txt").unwrap(), b"one\ntwo\n".to_vec());
+
+    match plain.get("missing.bin") {
+        Err(StackError::NotFound(_)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+    assert_eq!(io_kind(plain.get("../etc/passwd").map(|_| ())),
+               io::ErrorKind::PermissionDenied);
+
+    negotiating.put("uploaded.bin", &image).unwrap();
+    let mut uploaded = Vec::new();
+    File::open(&written).unwrap().read_to_end(&mut uploaded).unwrap();
+    assert_eq!(uploaded, image);
+    assert_eq!(io_kind(plain.put("uploaded.bin", &[1])), io::ErrorKind::AlreadyExists);
+    text.put("notes2.txt", b"three\n").unwrap();
+    assert_eq!(plain.get("notes2.txt").unwrap(), b"three\n".to_vec());
+
+    // Too large, told in advance or noticed on the way
+    let large = vec![0; 6000];
+    assert_eq!(io_kind(negotiating.put("large.bin", &large)), io::ErrorKind::Other);
+    assert_eq!(io_kind(plain.put("large.bin", &large)), io::ErrorKind::Other);
+    assert!(!written.with_file_name("large.bin").exists());
+}
+
+#[test]
+fn retransmission() {
+    let image = vec![1; 1500];
+    let mut config = TftpServerConfig::new(root("retransmission", &[("image.bin", &image)]));
+    config.timeout = Duration::from_millis(300);
+    let (server_stack, client_stack) = testing::connected_stacks(1, 2);
+    let _server = tftp::spawn(server_stack, Ipv4Addr::new(10, 0, 0, 1), config).unwrap();
+    let mut socket = UdpSocket::bind(client_stack.clone(), "10.0.0.2:0").unwrap();
+    socket.set_opt(ReadTimeout, Some(Duration::from_secs(2))).unwrap();
+    socket.send_to(b"\0\x01image.bin\0octet\0", server_addr()).unwrap();
+    let (first, transfer) = recv(&socket);
+    assert_eq!((&first[..4], first.len()), (&[0, 3, 0, 1][..], 516));
+    assert!(transfer != SocketAddr::V4(server_addr()));
+    // Not acknowledged, so sent again
+    assert_eq!(recv(&socket), (first, transfer));
+
+    let mut stranger = UdpSocket::bind(client_stack, "10.0.0.2:0").unwrap();
+    stranger.set_opt(ReadTimeout, Some(Duration::from_secs(2))).unwrap();
+    stranger.send_to(&[0, 4, 0, 1], transfer).unwrap();
+    let (error, _) = recv(&stranger);
+    assert_eq!(&error[..4], &[0, 5, 0, 5]);
+
+    socket.send_to(&[0, 4, 0, 1], transfer).unwrap();
+    let (second, _) = recv(&socket);
+    assert_eq!(&second[..4], &[0, 3, 0, 2]);
+    // A duplicate acknowledgement is not answered with the next block again
+    socket.send_to(&[0, 4, 0, 1], transfer).unwrap();
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(150))).unwrap();
+    assert!(socket.recv_from(&mut [0; 1024]).is_err());
+    socket.set_opt(ReadTimeout, Some(Duration::from_secs(2))).unwrap();
+    socket.send_to(&[0, 4, 0, 2], transfer).unwrap();
+    let (third, _) = recv(&socket);
+    assert_eq!((&third[..4], third.len()), (&[0, 3, 0, 3][..], 4 + 1500 - 1024));
+    socket.send_to(&[0, 4, 0, 3], transfer).unwrap();
+}
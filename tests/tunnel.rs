@@ -0,0 +1,275 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
+use pnet::util::MacAddr;
+
+use rips::{Interface, NetworkStack, RxError, StackError, testing};
+use rips::tunnel::{self, Codec, Ipip, vxlan};
+use rips::udp::UdpSocket;
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+fn ipv4_packet(src: Ipv4Addr,
+               dst: Ipv4Addr,
+               protocol: IpNextHeaderProtocol,
+               payload: &[u8])
+               -> Vec<u8> {
+    let mut buffer = vec![0; 20 + payload.len()];
+    {
+        let mut ip_pkg = MutableIpv4Packet::new(&mut buffer[..]).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_total_length(20 + payload.len() as u16);
+        ip_pkg.set_source(src);
+        ip_pkg.set_destination(dst);
+        ip_pkg.set_next_level_protocol(protocol);
+        ip_pkg.set_payload(payload);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    buffer
+}
+
+fn udp_packet(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; 8 + payload.len()];
+    {
+        let mut udp_pkg = MutableUdpPacket::new(&mut buffer[..]).unwrap();
+        udp_pkg.set_source(src_port);
+        udp_pkg.set_destination(dst_port);
+        udp_pkg.set_length(8 + payload.len() as u16);
+        udp_pkg.set_payload(payload);
+    }
+    buffer
+}
+
+fn ethernet_frame(src: MacAddr, dst: MacAddr, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0; 14 + payload.len()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(src);
+        eth_pkg.set_destination(dst);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        eth_pkg.payload_mut().copy_from_slice(payload);
+    }
+    buffer
+}
+
+#[test]
+fn ipip_tunnel() {
+    let local = Ipv4Addr::new(10, 0, 0, 2);
+    let remote = Ipv4Addr::new(10, 0, 0, 1);
+    let inner_local = Ipv4Addr::new(192, 168, 0, 1);
+    let inner_remote = Ipv4Addr::new(192, 168, 0, 2);
+    let remote_mac = MacAddr::new(9, 8, 7, 6, 5, 4);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(local, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let tunnel_interface = tunnel::add_tunnel(stack.clone(), "ipip0", local, remote, Ipip)
+        .unwrap();
+    {
+        let mut stack = stack.lock().unwrap();
+        stack.add_ipv4(&tunnel_interface, Ipv4Network::from_str("192.168.0.1/30").unwrap())
+            .unwrap();
+        assert_eq!(stack.interface(&tunnel_interface).unwrap().get_mtu(), 1480);
+    }
+
+    // Sending into the tunnel gives an IPv4 in IPv4 packet to the remote endpoint
+    let mut socket = UdpSocket::bind(stack.clone(), "192.168.0.1:1024").unwrap();
+    socket.send_to(&[1, 2, 3], SocketAddrV4::new(inner_remote, 2000)).unwrap();
+    let frame = read_handle.recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&frame).unwrap();
+    assert_eq!(eth_pkg.get_destination(), remote_mac);
+    assert_eq!(eth_pkg.get_ethertype(), EtherTypes::Ipv4);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), local);
+    assert_eq!(ip_pkg.get_destination(), remote);
+    assert_eq!(ip_pkg.get_next_level_protocol(), IpNextHeaderProtocols::Ipv4);
+    let inner_pkg = Ipv4Packet::new(ip_pkg.payload()).unwrap();
+    assert_eq!(inner_pkg.get_source(), inner_local);
+    assert_eq!(inner_pkg.get_destination(), inner_remote);
+    let udp_pkg = UdpPacket::new(inner_pkg.payload()).unwrap();
+    assert_eq!(udp_pkg.get_destination(), 2000);
+    assert_eq!(udp_pkg.payload(), &[1, 2, 3]);
+
+    // Packets from the remote endpoint are decapsulated on the tunnel interface
+    let inner = ipv4_packet(inner_remote,
+                            inner_local,
+                            IpNextHeaderProtocols::Udp,
+                            &udp_packet(2000, 1024, &[4, 5, 6]));
+    let outer = ipv4_packet(remote, local, IpNextHeaderProtocols::Ipv4, &inner);
+    inject_handle.send(Ok(ethernet_frame(remote_mac, interface.mac, &outer).into_boxed_slice()))
+        .unwrap();
+
+    let mut buffer = vec![0; 3];
+    let (len, from) = socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(inner_remote, 2000)));
+    assert_eq!(len, 3);
+    assert_eq!(&buffer, &[4, 5, 6]);
+}
+
+#[test]
+fn tunnel_mtu_follows_outgoing_interface() {
+    let local = Ipv4Addr::new(10, 0, 0, 2);
+    let remote = Ipv4Addr::new(10, 0, 0, 1);
+    let (mut stack, interface, _inject_handle, _read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(local, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().set_mtu(9000).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    let tunnel_interface = tunnel::add_tunnel(stack.clone(), "ipip0", local, remote, Ipip)
+        .unwrap();
+    assert_eq!(stack.lock().unwrap().interface(&tunnel_interface).unwrap().get_mtu(), 8980);
+    let unrouted = Ipv4Addr::new(172, 16, 0, 1);
+    match tunnel::add_tunnel(stack, "ipip1", local, unrouted, Ipip) {
+        Err(StackError::NoRouteToHost(ip)) => assert_eq!(ip, unrouted),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn vxlan_interface() {
+    let local = Ipv4Addr::new(10, 0, 0, 2);
+    let remote = Ipv4Addr::new(10, 0, 0, 1);
+    let inner_local = Ipv4Addr::new(192, 168, 0, 1);
+    let inner_remote = Ipv4Addr::new(192, 168, 0, 2);
+    let remote_mac = MacAddr::new(9, 8, 7, 6, 5, 4);
+    let vxlan_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+    let inner_remote_mac = MacAddr::new(2, 0, 0, 0, 0, 2);
+
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(local, 24).unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(remote, remote_mac);
+    let stack = Arc::new(Mutex::new(stack));
+
+    let vtep = vxlan::Vtep::bind(stack.clone(), local).unwrap();
+    let vxlan_interface = vtep.add_interface("vxlan42", 42, vxlan_mac, &[remote]).unwrap();
+    assert!(vtep.add_interface("vxlan42b", 42, vxlan_mac, &[remote]).is_err());
+    {
+        let mut stack = stack.lock().unwrap();
+        stack.add_ipv4(&vxlan_interface, Ipv4Network::from_str("192.168.0.1/24").unwrap())
+            .unwrap();
+        assert_eq!(stack.interface(&vxlan_interface).unwrap().get_mtu(), 1450);
+    }
+    let mut socket = UdpSocket::bind(stack.clone(), "192.168.0.1:1024").unwrap();
+
+    // A frame from the overlay is delivered, and its source MAC learned
+    let inner = ipv4_packet(inner_remote,
+                            inner_local,
+                            IpNextHeaderProtocols::Udp,
+                            &udp_packet(2000, 1024, &[4, 5, 6]));
+    let mut payload = vxlan::header(42).to_vec();
+    payload.extend_from_slice(&ethernet_frame(inner_remote_mac, vxlan_mac, &inner));
+    let outer = ipv4_packet(remote,
+                            local,
+                            IpNextHeaderProtocols::Udp,
+                            &udp_packet(4789, 4789, &payload));
+    inject_handle.send(Ok(ethernet_frame(remote_mac, interface.mac, &outer).into_boxed_slice()))
+        .unwrap();
+
+    let mut buffer = vec![0; 3];
+    let (len, from) = socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(inner_remote, 2000)));
+    assert_eq!(&buffer[..len], &[4, 5, 6]);
+    assert_eq!(vtep.mac_table(42).unwrap().get(&inner_remote_mac), Some(&remote));
+
+    // Sending to the learned MAC goes to the VTEP it was learned from
+    {
+        let mut stack = stack.lock().unwrap();
+        let arp_table = stack.interface(&vxlan_interface).unwrap().arp_table();
+        arp_table.insert(inner_remote, inner_remote_mac);
+    }
+    socket.send_to(&[1, 2, 3], SocketAddrV4::new(inner_remote, 2000)).unwrap();
+    let frame = read_handle.recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&frame).unwrap();
+    assert_eq!(eth_pkg.get_destination(), remote_mac);
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_source(), local);
+    assert_eq!(ip_pkg.get_destination(), remote);
+    let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(udp_pkg.get_destination(), 4789);
+    let (vni, inner_frame) = vxlan::parse(udp_pkg.payload()).unwrap();
+    assert_eq!(vni, 42);
+    let inner_eth_pkg = EthernetPacket::new(inner_frame).unwrap();
+    assert_eq!(inner_eth_pkg.get_source(), vxlan_mac);
+    assert_eq!(inner_eth_pkg.get_destination(), inner_remote_mac);
+    let inner_ip_pkg = Ipv4Packet::new(inner_eth_pkg.payload()).unwrap();
+    assert_eq!(inner_ip_pkg.get_destination(), inner_remote);
+
+    // Dropping the VTEP stops its threads, so nothing keeps the stack alive
+    let weak_stack = Arc::downgrade(&stack);
+    drop(socket);
+    drop(vtep);
+    drop(stack);
+    assert!(weak_stack.upgrade().is_none());
+}
+
+/// Xors the packets and prefixes them with a marker byte
+struct XorCodec;
+
+impl Codec for XorCodec {
+    fn overhead(&self) -> usize {
+        1
+    }
+
+    fn encode(&mut self, packet: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xaa];
+        data.extend(packet.iter().map(|b| b ^ 0x55));
+        data
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Result<Vec<u8>, RxError> {
+        match data.split_first() {
+            Some((&0xaa, rest)) => Ok(rest.iter().map(|b| b ^ 0x55).collect()),
+            _ => Err(RxError::InvalidContent),
+        }
+    }
+}
+
+#[test]
+fn codec_interface() {
+    let local = Ipv4Addr::new(10, 1, 0, 1);
+    let remote = Ipv4Addr::new(10, 1, 0, 2);
+
+    let mut stack = NetworkStack::new();
+    let interface = Interface::new("xor0".to_owned(), MacAddr::new(2, 0, 0, 0, 0, 1));
+    let (codec_tx, codec_rx) =
+        tunnel::add_codec_interface(&mut stack, interface.clone(), XorCodec).unwrap();
+    stack.add_ipv4(&interface, Ipv4Network::new(local, 30).unwrap()).unwrap();
+    assert_eq!(stack.interface(&interface).unwrap().get_mtu(), 1499);
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, SocketAddrV4::new(local, 1024)).unwrap();
+
+    socket.send_to(&[1, 2, 3], SocketAddrV4::new(remote, 2000)).unwrap();
+    let data = codec_tx.recv().unwrap();
+    assert_eq!(data[0], 0xaa);
+    let packet = XorCodec.decode(&data).unwrap();
+    let ip_pkg = Ipv4Packet::new(&packet).unwrap();
+    assert_eq!(ip_pkg.get_source(), local);
+    assert_eq!(ip_pkg.get_destination(), remote);
+    assert_eq!(UdpPacket::new(ip_pkg.payload()).unwrap().payload(), &[1, 2, 3]);
+
+    let reply = ipv4_packet(remote,
+                            local,
+                            IpNextHeaderProtocols::Udp,
+                            &udp_packet(2000, 1024, &[4, 5, 6]));
+    codec_rx.deliver(&XorCodec.encode(&reply)).unwrap();
+    let mut buffer = vec![0; 3];
+    let (len, from) = socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(remote, 2000)));
+    assert_eq!(&buffer[..len], &[4, 5, 6]);
+    assert_eq!(codec_rx.deliver(&reply), Err(RxError::InvalidContent));
+}
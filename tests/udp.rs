@@ -1,21 +1,36 @@
 extern crate pnet;
 extern crate ipnetwork;
+extern crate rand;
 extern crate rips;
 
 use ipnetwork::Ipv4Network;
 
-use pnet::packet::MutablePacket;
-use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
 use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::ipv4::{MutableIpv4Packet, checksum};
-use pnet::packet::udp::MutableUdpPacket;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
+use pnet::packet::udp::{MutableUdpPacket, UdpPacket};
+use pnet::util::MacAddr;
 
+use rand::{SeedableRng, XorShiftRng};
+
+use rips::{LinkChange, NetworkStack};
+use rips::bpf::Program;
+use rips::perf::{Generator, GeneratorConfig, Sink};
+use rips::reactor::{Async, Waker};
+use rips::select::Selector;
+use rips::shaping::RateLimit;
+use rips::sockopt::{Broadcast, HasSocketOptions, ReadTimeout, RecvBuffer, Tos, Ttl};
 use rips::testing;
-use rips::udp::UdpSocket;
+use rips::udp::{BasicUdpListener, Datagram, UdpCallbackListener, UdpSender, UdpSocket};
 
+use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[test]
 fn socket_listen() {
@@ -55,3 +70,605 @@ fn socket_listen() {
     assert_eq!(&buffer, &[5, 6, 7, 8]);
 
 }
+
+#[test]
+fn socket_bind_in_vrf() {
+    let net = Ipv4Network::from_str("10.9.0.254/16").unwrap();
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    let (channel, vrf_interface, _, _) = testing::dummy_ethernet_indexed(1);
+    stack.add_interface(vrf_interface.clone(), channel).unwrap();
+    stack.create_vrf("red").unwrap();
+    stack.set_interface_vrf(&vrf_interface, Some("red")).unwrap();
+    stack.add_ipv4(&interface, net).unwrap();
+    stack.add_ipv4(&vrf_interface, net).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+
+    // The same address and port can be bound once per VRF
+    let _socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let vrf_socket = UdpSocket::bind_in_vrf(stack.clone(), "red", "10.9.0.254:1024").unwrap();
+    assert_eq!(vrf_socket.vrf(), Some("red"));
+    assert!(UdpSocket::bind_in_vrf(stack.clone(), "red", "10.9.0.254:1024").is_err());
+    assert!(UdpSocket::bind_in_vrf(stack, "blue", "10.9.0.254:1024").is_err());
+}
+
+#[test]
+fn socket_recv_broadcast() {
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:67").unwrap();
+
+    for &dst in &[Ipv4Addr::new(10, 9, 255, 255), Ipv4Addr::new(255, 255, 255, 255)] {
+        let mut buffer = vec![0; 100];
+        {
+            let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            eth_pkg.set_ethertype(EtherTypes::Ipv4);
+            let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+            ip_pkg.set_header_length(5); // 5 is for no option fields
+            ip_pkg.set_total_length(20 + 8 + 1);
+            ip_pkg.set_source(Ipv4Addr::new(0, 0, 0, 0));
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+            let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+            udp_pkg.set_source(68);
+            udp_pkg.set_destination(67);
+            udp_pkg.set_length(8 + 1);
+            udp_pkg.set_payload(&[dst.octets()[1]]);
+        }
+        inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+
+        let mut buffer = vec![0; 1];
+        let (len, _) = socket.recv_from(&mut buffer[..]).unwrap();
+        assert_eq!(len, 1);
+        assert_eq!(buffer[0], dst.octets()[1]);
+    }
+}
+
+#[test]
+fn socket_loopback() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    client.send_to(&[1, 2, 3], "10.9.0.254:1024").unwrap();
+    let mut buffer = vec![0; 3];
+    let (len, from) = server.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(len, 3);
+    assert_eq!(from, client.local_addr().unwrap());
+    assert_eq!(&buffer, &[1, 2, 3]);
+    // Nothing went out on the wire, not even an Arp request
+    assert!(read_handle.try_recv().is_err());
+}
+
+#[test]
+fn socket_loopback_reply() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    // Replying to a local address from within a listener used to deadlock
+    let reply_tx = Mutex::new(stack.udp_tx(local_ip, 1024, 2000).unwrap());
+    let listener = UdpCallbackListener::new(move |datagram: Datagram| {
+        reply_tx.lock().unwrap().send(datagram.payload).unwrap();
+    });
+    stack.udp_listen("10.9.0.254:1024", listener).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:2000").unwrap();
+    client.set_opt(ReadTimeout, Some(Duration::from_secs(1))).unwrap();
+
+    client.send_to(&[1, 2, 3], "10.9.0.254:1024").unwrap();
+    let mut buffer = vec![0; 3];
+    let (len, from) = client.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(len, 3);
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(local_ip, 1024)));
+    assert_eq!(&buffer, &[1, 2, 3]);
+}
+
+#[test]
+fn socket_clone() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let clone = server.try_clone().unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+    client.send_to(&[1], "10.9.0.254:1024").unwrap();
+
+    // Only the original socket receives, the clone fails instead of panicking
+    let (woken_tx, _woken) = mpsc::channel();
+    let woken_tx = Mutex::new(woken_tx);
+    let waker: Waker = Arc::new(move || woken_tx.lock().unwrap().send(()).unwrap_or(()));
+    let mut buf = [0; 1];
+    assert_eq!(clone.recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::Other);
+    assert_eq!(clone.try_recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::Other);
+    assert!(clone.poll_recv_from(&mut buf, &waker).is_err());
+    assert!(clone.readiness_fd().is_err());
+    let selector = Selector::new();
+    assert!(selector.select(&[&clone], Some(Duration::from_millis(10))).is_empty());
+    assert_eq!(server.recv_from(&mut buf).unwrap().0, 1);
+}
+
+#[test]
+fn socket_send_vectored() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    let header = [1, 2, 3];
+    let body = [4, 5, 6, 7, 8];
+    let len = client.send_vectored_to(&[&header, &body], "10.9.0.254:1024").unwrap();
+    assert_eq!(len, 8);
+    let mut buffer = vec![0; 8];
+    let (len, _) = server.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(len, 8);
+    assert_eq!(&buffer, &[1, 2, 3, 4, 5, 6, 7, 8]);
+}
+
+#[test]
+fn socket_filter() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    // Accepts packets of at least 31 bytes, 3 bytes of data
+    let program = Program::parse("{ 0x80, 0, 0, 0 },
+                                  { 0x35, 0, 1, 31 },
+                                  { 0x6, 0, 0, 0xffff },
+                                  { 0x6, 0, 0, 0 },")
+        .unwrap();
+    server.set_filter(Some(program));
+    client.send_to(&[1, 2], "10.9.0.254:1024").unwrap();
+    client.send_to(&[1, 2, 3], "10.9.0.254:1024").unwrap();
+    let mut buffer = vec![0; 3];
+    let (len, _) = server.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(len, 3);
+}
+
+#[test]
+fn socket_rate_limit() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    // Room for one 100 byte packet, then one every 10 ms
+    client.set_rate_limit(Some(RateLimit::new(10_000, 100)));
+    let start = Instant::now();
+    for _ in 0..4 {
+        client.send_to(&[0; 72], "10.9.0.254:1024").unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    let mut buffer = vec![0; 72];
+    for _ in 0..4 {
+        server.recv_from(&mut buffer[..]).unwrap();
+    }
+}
+
+#[test]
+fn socket_ttl_tos() {
+    let dst = Ipv4Addr::new(10, 9, 0, 1);
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(dst, MacAddr::new(2, 0, 0, 0, 0, 1));
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    socket.send_to(&[1], "10.9.0.1:1024").unwrap();
+    socket.try_clone().unwrap().set_opt(Ttl, 64).unwrap();
+    socket.set_opt(Tos, 0xb8).unwrap();
+    assert_eq!(socket.opt(Ttl), 64);
+    socket.send_to(&[2], "10.9.0.1:1024").unwrap();
+
+    for &(ttl, dscp) in &[(40, 0), (64, 46)] {
+        let pkg = read_handle.try_recv().unwrap();
+        let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!((ip_pkg.get_ttl(), ip_pkg.get_dscp()), (ttl, dscp));
+    }
+}
+
+#[test]
+fn socket_send_broadcast() {
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    let error = socket.send_to(&[1], "10.9.255.255:67").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    socket.set_opt(Broadcast, true).unwrap();
+    socket.send_to(&[1], "10.9.255.255:67").unwrap();
+
+    // Sent without Arp
+    let pkg = read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(eth_pkg.get_destination(), MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(ip_pkg.get_destination(), Ipv4Addr::new(10, 9, 255, 255));
+}
+
+#[test]
+fn socket_read_timeout() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+
+    socket.set_opt(ReadTimeout, Some(Duration::from_millis(10))).unwrap();
+    let error = socket.recv_from(&mut [0; 1]).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn socket_recv_buffer() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let server = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    // Room for two packets of 100 bytes
+    server.set_opt(RecvBuffer, Some(250)).unwrap();
+    for i in 0..3 {
+        client.send_to(&[i; 72], "10.9.0.254:1024").unwrap();
+    }
+    let mut buffer = vec![0; 72];
+    for i in 0..2 {
+        server.recv_from(&mut buffer).unwrap();
+        assert_eq!(buffer[0], i);
+    }
+    assert!(server.try_recv_from(&mut buffer).is_err());
+
+    client.send_to(&[3; 72], "10.9.0.254:1024").unwrap();
+    server.recv_from(&mut buffer).unwrap();
+    assert_eq!(buffer[0], 3);
+}
+
+#[test]
+fn socket_follows_mtu() {
+    let dst = Ipv4Addr::new(10, 9, 0, 1);
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    stack.interface(&interface).unwrap().arp_table().insert(dst, MacAddr::new(2, 0, 0, 0, 0, 1));
+    let changes = stack.interface(&interface).unwrap().watch_link();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack.clone(), "10.9.0.254:0").unwrap();
+    assert_eq!(socket.max_unfragmented("10.9.0.1:1024").unwrap(), 1500 - 28);
+
+    {
+        let mut stack = stack.lock().unwrap();
+        let stack_interface = stack.interface(&interface).unwrap();
+        stack_interface.set_mtu(9000).unwrap();
+        stack_interface.set_mtu(9000).unwrap();
+        stack_interface.set_rate_limit(None);
+    }
+    assert_eq!(changes.try_recv().unwrap(), LinkChange::Mtu(9000));
+    assert_eq!(changes.try_recv().unwrap(), LinkChange::RateLimit(None));
+    assert!(changes.try_recv().is_err());
+    assert_eq!(socket.max_unfragmented("10.9.0.1:1024").unwrap(), 9000 - 28);
+    socket.send_to(&[0; 9000 - 28], "10.9.0.1:1024").unwrap();
+}
+
+#[test]
+fn sender_reuses_tx() {
+    let peer1 = Ipv4Addr::new(10, 9, 0, 1);
+    let peer2 = Ipv4Addr::new(10, 9, 0, 2);
+    let (mut stack, interface, _, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    {
+        let arp_table = stack.interface(&interface).unwrap().arp_table();
+        arp_table.insert(peer1, MacAddr::new(2, 0, 0, 0, 0, 1));
+        arp_table.insert(peer2, MacAddr::new(2, 0, 0, 0, 0, 2));
+    }
+    let stack = Arc::new(Mutex::new(stack));
+    let mut sender = UdpSender::new(stack.clone(), 5353);
+    sender.send_to(&[1], SocketAddrV4::new(peer1, 1000)).unwrap();
+
+    // Other ports of a known peer are sent to without touching the stack
+    let (done, result) = mpsc::channel();
+    let guard = stack.lock().unwrap();
+    thread::spawn(move || {
+        sender.send_to(&[2], SocketAddrV4::new(peer1, 2000)).unwrap();
+        done.send(sender).unwrap();
+    });
+    let mut sender = result.recv_timeout(Duration::from_secs(1)).unwrap();
+    drop(guard);
+    sender.send_to(&[3], SocketAddrV4::new(peer2, 3000)).unwrap();
+
+    for &(ip, port) in &[(peer1, 1000), (peer1, 2000), (peer2, 3000)] {
+        let pkg = read_handle.try_recv().unwrap();
+        let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!(ip_pkg.get_destination(), ip);
+        let udp_pkg = UdpPacket::new(ip_pkg.payload()).unwrap();
+        assert_eq!((udp_pkg.get_source(), udp_pkg.get_destination()), (5353, port));
+    }
+}
+
+#[test]
+fn socket_poll() {
+    let ip = Ipv4Addr::new(10, 9, 0, 254);
+    let peer = Ipv4Addr::new(10, 9, 0, 1);
+    let peer_mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::new(ip, 16).unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let (woken_tx, woken) = mpsc::channel();
+    let woken_tx = Mutex::new(woken_tx);
+    let waker: Waker = Arc::new(move || woken_tx.lock().unwrap().send(()).unwrap_or(()));
+
+    let mut buf = [0; 4];
+    assert_eq!(socket.poll_recv_from(&mut buf, &waker).unwrap(), Async::NotReady);
+    let mut buffer = vec![0; 14 + 20 + 8 + 4];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8 + 4);
+        ip_pkg.set_source(peer);
+        ip_pkg.set_destination(ip);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(53);
+        udp_pkg.set_destination(1024);
+        udp_pkg.set_length(8 + 4);
+        udp_pkg.set_payload(&[5, 6, 7, 8]);
+    }
+    inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    woken.recv_timeout(Duration::from_secs(1)).unwrap();
+    let from = SocketAddr::V4(SocketAddrV4::new(peer, 53));
+    assert_eq!(socket.poll_recv_from(&mut buf, &waker).unwrap(), Async::Ready((4, from)));
+    assert_eq!(buf, [5, 6, 7, 8]);
+
+    assert_eq!(socket.try_recv_from(&mut buf).unwrap_err().kind(),
+               io::ErrorKind::WouldBlock);
+    assert!(socket.readiness_fd().is_ok());
+
+    // Sending waits for the Arp reply without blocking
+    assert_eq!(socket.poll_send_to(&[1, 2], from, &waker).unwrap(), Async::NotReady);
+    let request = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&request).unwrap().get_ethertype(), EtherTypes::Arp);
+    let mut buffer = vec![0; 14 + ArpPacket::minimum_packet_size()];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_source(peer_mac);
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
+        arp_pkg.set_operation(ArpOperations::Reply);
+        arp_pkg.set_sender_hw_addr(peer_mac);
+        arp_pkg.set_sender_proto_addr(peer);
+        arp_pkg.set_target_proto_addr(ip);
+    }
+    inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    woken.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(socket.poll_send_to(&[1, 2], from, &waker).unwrap(), Async::Ready(2));
+    let pkg = read_handle.try_recv().unwrap();
+    assert_eq!(EthernetPacket::new(&pkg).unwrap().get_destination(), peer_mac);
+}
+
+#[test]
+fn socket_select() {
+    let (mut stack, interface, _, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let first = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let second = UdpSocket::bind(stack.clone(), "10.9.0.254:1025").unwrap();
+    let mut client = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+
+    let selector = Selector::new();
+    assert!(selector.select(&[&first, &second], Some(Duration::from_millis(10))).is_empty());
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        client.send_to(&[1], "10.9.0.254:1025").unwrap();
+    });
+    assert_eq!(selector.select(&[&first, &second], Some(Duration::from_secs(1))), vec![1]);
+    let mut buffer = [0; 1];
+    second.try_recv_from(&mut buffer).unwrap();
+    assert!(selector.select(&[&first, &second], Some(Duration::from_secs(0))).is_empty());
+}
+
+#[test]
+fn socket_seeded_port() {
+    let bind = || {
+        let (mut stack, interface, _, _) = testing::dummy_stack();
+        stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+        stack.set_rng(XorShiftRng::from_seed([1, 2, 3, 4]));
+        let stack = Arc::new(Mutex::new(stack));
+        let socket = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+        socket.local_addr().unwrap().port()
+    };
+    let port = bind();
+    assert!(port != 0);
+    // The same seed picks the same port
+    assert_eq!(bind(), port);
+}
+
+#[test]
+fn socket_port_range() {
+    let mut stack = NetworkStack::builder().local_port_range(40000, 40001).build().unwrap();
+    let (channel, interface, _, _) = testing::dummy_ethernet();
+    stack.add_interface(interface.clone(), channel).unwrap();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(stack));
+    let socket = UdpSocket::bind(stack, "10.9.0.254:0").unwrap();
+    assert_eq!(socket.local_addr().unwrap().port(), 40000);
+}
+
+#[test]
+fn basic_listener() {
+    let source_ip = Ipv4Addr::new(9, 8, 7, 6);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    stack.udp_listen("10.9.0.254:1024", BasicUdpListener::new(tx)).unwrap();
+
+    let mut buffer = vec![0; 100];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8 + 4);
+        ip_pkg.set_source(source_ip);
+        ip_pkg.set_destination(target_ip);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(9999);
+        udp_pkg.set_destination(1024);
+        udp_pkg.set_length(8 + 4);
+        udp_pkg.set_payload(&[5, 6, 7, 8]);
+    }
+    inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+
+    let (_time, from, payload) = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(from, SocketAddrV4::new(source_ip, 9999));
+    assert_eq!(&payload[..], &[5, 6, 7, 8]);
+}
+
+#[test]
+fn callback_listener() {
+    let source_ip = Ipv4Addr::new(9, 8, 7, 6);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let (mut stack, interface, inject_handle, _) = testing::dummy_stack();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let pool = stack.buffer_pool().clone();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let listener = UdpCallbackListener::new(move |datagram: Datagram| {
+        assert_eq!(datagram.payload, &[5, 6, 7, 8]);
+        tx.lock().unwrap().send(datagram.to_pooled(&pool)).unwrap();
+    });
+    stack.udp_listen("10.9.0.254:1024", listener).unwrap();
+
+    let mut buffer = vec![0; 100];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8 + 4);
+        ip_pkg.set_source(source_ip);
+        ip_pkg.set_destination(target_ip);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(9999);
+        udp_pkg.set_destination(1024);
+        udp_pkg.set_length(8 + 4);
+        udp_pkg.set_payload(&[5, 6, 7, 8]);
+    }
+    inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+
+    let datagram = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(datagram.src, SocketAddrV4::new(source_ip, 9999));
+    assert_eq!(datagram.dst, SocketAddrV4::new(target_ip, 1024));
+    assert_eq!(&datagram.payload[..], &[5, 6, 7, 8]);
+}
+
+#[test]
+fn rx_workers() {
+    let target_ip = Ipv4Addr::new(10, 9, 0, 254);
+
+    let mut stack = NetworkStack::builder().rx_workers(4).build().unwrap();
+    let (channel, interface, inject_handle, _) = testing::dummy_ethernet();
+    stack.add_interface(interface.clone(), channel).unwrap();
+    stack.add_ipv4(&interface, Ipv4Network::from_str("10.9.0.254/16").unwrap()).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+    let listener = UdpCallbackListener::new(move |datagram: Datagram| {
+        tx.lock().unwrap().send((datagram.src.port(), datagram.payload[0])).unwrap();
+    });
+    stack.udp_listen("10.9.0.254:1024", listener).unwrap();
+
+    let ports = [9997, 9998, 9999];
+    for seq in 0..10 {
+        for &port in &ports {
+            let mut buffer = vec![0; 14 + 20 + 8 + 1];
+            {
+                let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+                eth_pkg.set_ethertype(EtherTypes::Ipv4);
+                let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+                ip_pkg.set_version(4);
+                ip_pkg.set_header_length(5);
+                ip_pkg.set_total_length(20 + 8 + 1);
+                ip_pkg.set_source(Ipv4Addr::new(9, 8, 7, 6));
+                ip_pkg.set_destination(target_ip);
+                ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+                let csum = checksum(&ip_pkg.to_immutable());
+                ip_pkg.set_checksum(csum);
+                let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+                udp_pkg.set_source(port);
+                udp_pkg.set_destination(1024);
+                udp_pkg.set_length(8 + 1);
+                udp_pkg.set_payload(&[seq]);
+            }
+            inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+        }
+    }
+
+    let received = (0..30)
+        .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap())
+        .collect::<Vec<_>>();
+    // Datagrams of different flows may be reordered, those of one flow not
+    for &port in &ports {
+        let seqs = received.iter()
+            .filter(|&&(src_port, _)| src_port == port)
+            .map(|&(_, seq)| seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, (0..10).collect::<Vec<_>>());
+    }
+    let stats = stack.interface(&interface).unwrap().rx_worker_stats().unwrap();
+    assert_eq!((stats.queued, stats.dropped), (30, 0));
+}
+
+#[test]
+fn perf_over_veth_pair() {
+    let ((stack_a, _), (mut stack_b, _)) = testing::connected_pair(1, 2);
+    let sink = Sink::new();
+    stack_b.udp_listen("10.0.0.2:5001", sink.clone()).unwrap();
+
+    let config = GeneratorConfig {
+        payload_len: 100,
+        count: Some(200),
+        ..GeneratorConfig::default()
+    };
+    let sender = UdpSender::new(Arc::new(Mutex::new(stack_a)), 5000);
+    let mut generator = Generator::new(sender, SocketAddrV4::from_str("10.0.0.2:5001").unwrap(),
+                                       config);
+    let report = generator.run().unwrap();
+    assert_eq!((report.datagrams, report.bytes), (200, 200 * 100));
+    assert_eq!(report.sent.packets, 200);
+    assert_eq!(report.sent.bytes, 200 * (14 + 20 + 8 + 100));
+    assert!(report.pps() > 0.0);
+
+    let start = Instant::now();
+    while sink.report().datagrams < 200 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let received = sink.report();
+    assert_eq!((received.datagrams, received.expected), (200, 200));
+    assert_eq!((received.lost(), received.reordered), (0, 0));
+}
@@ -0,0 +1,44 @@
+extern crate pnet;
+extern crate ipnetwork;
+extern crate rips;
+
+use ipnetwork::Ipv4Network;
+
+use pnet::packet::Packet;
+use pnet::packet::arp::{ArpOperations, ArpPacket};
+use pnet::packet::ethernet::EthernetPacket;
+
+use rips::{StackError, testing, vlan};
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use std::thread;
+
+#[test]
+fn vlan_arp_reply() {
+    let (mut stack, interface, inject_handle, read_handle) = testing::dummy_stack();
+    let vlan_interface = stack.add_vlan(&interface, "eth0.10", 10).unwrap();
+    assert_eq!(vlan_interface.mac, interface.mac);
+    let config = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+    stack.add_ipv4(&vlan_interface, config).unwrap();
+    match stack.add_vlan(&interface, "eth0.10b", 10) {
+        Err(StackError::AlreadyExists(..)) => (),
+        _ => panic!("VID already taken"),
+    }
+
+    // No reply on the parent itself or on other VLANs
+    let request = testing::arp_request(Ipv4Addr::new(10, 0, 0, 1));
+    inject_handle.send(Ok(request.clone())).unwrap();
+    inject_handle.send(Ok(vlan::tag(&request, 11, 0).into_boxed_slice())).unwrap();
+    inject_handle.send(Ok(vlan::tag(&request, 10, 0).into_boxed_slice())).unwrap();
+    thread::sleep(Duration::new(1, 0));
+
+    let reply = read_handle.try_recv().unwrap();
+    assert!(read_handle.try_recv().is_err());
+    let (vid, reply) = vlan::untag(&reply).unwrap();
+    assert_eq!(vid, 10);
+    let eth_pkg = EthernetPacket::new(&reply).unwrap();
+    let arp_pkg = ArpPacket::new(eth_pkg.payload()).unwrap();
+    assert_eq!(arp_pkg.get_operation(), ArpOperations::Reply);
+    assert_eq!(arp_pkg.get_sender_proto_addr(), Ipv4Addr::new(10, 0, 0, 1));
+}